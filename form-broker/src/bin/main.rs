@@ -10,6 +10,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("Broker endpoints acquired");
     let broker = form_broker::broker::Broker::new(&frontend, &backend).await?;
 
+    let pubsub = form_broker::pubsub::PubSubBroker::new(load_pubsub_config()).await?;
+    tokio::spawn(async move {
+        if let Err(e) = pubsub.start().await {
+            log::error!("Pub/sub broker stopped: {e}");
+        }
+    });
+
+    if let Ok(path) = std::env::var("BROKER_MQTT_CONFIG") {
+        let mut config = form_broker::mqtt_bridge::MqttBridgeConfig::from_file(&path)?;
+        if let Ok(addr) = std::env::var("BROKER_MQTT_ADDR") {
+            config.listen_addr = addr;
+        }
+        let bridge = form_broker::mqtt_bridge::MqttBridge::new(config).await?;
+        tokio::spawn(async move {
+            if let Err(e) = bridge.start().await {
+                log::error!("MQTT bridge stopped: {e}");
+            }
+        });
+    }
+
     broker.start().await?;
 
     Ok(())
@@ -18,3 +38,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn load_or_get_broker_endpoints(_config: Option<PathBuf>) -> (String, String) {
     ("127.0.0.1:5555".to_string(), "127.0.0.1:5556".to_string())
 }
+
+fn load_pubsub_config() -> form_broker::pubsub::PubSubConfig {
+    let mut config = form_broker::pubsub::PubSubConfig::default();
+    if let Ok(addr) = std::env::var("BROKER_PUBSUB_ADDR") {
+        config.listen_addr = addr;
+    }
+    if let Ok(dir) = std::env::var("BROKER_DATA_DIR") {
+        config.data_dir = Some(PathBuf::from(dir));
+    }
+    if let Some(retention) = std::env::var("BROKER_TOPIC_RETENTION").ok().and_then(|r| r.parse().ok()) {
+        config.retention = retention;
+    }
+    config
+}
@@ -0,0 +1,111 @@
+//! Client for the topic based pub/sub broker.
+use std::collections::VecDeque;
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use crate::protocol::{read_frame, write_frame, Frame, StartPosition};
+
+/// A message delivered on a subscribed topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub topic: String,
+    pub offset: u64,
+    pub payload: Vec<u8>,
+}
+
+pub struct BrokerClient {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    /// Deliveries received while waiting for a reply to a request
+    pending: VecDeque<Delivery>,
+}
+
+impl BrokerClient {
+    pub async fn connect(uri: &str) -> std::io::Result<Self> {
+        let (reader, writer) = TcpStream::connect(uri).await?.into_split();
+        Ok(Self { reader, writer, pending: VecDeque::new() })
+    }
+
+    /// Publishes a message and returns the offset the broker stored it at
+    pub async fn publish(&mut self, topic: &str, payload: impl Into<Vec<u8>>) -> std::io::Result<u64> {
+        write_frame(&mut self.writer, &Frame::Publish {
+            topic: topic.to_string(),
+            payload: payload.into(),
+        }).await?;
+
+        match self.next_reply().await? {
+            Frame::Published { offset, .. } => Ok(offset),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Subscribes to `topics`. When `subscription` is set the subscription is
+    /// durable: delivery resumes after the last acknowledged offset and
+    /// `start` only applies to topics the subscription has never seen.
+    pub async fn subscribe(
+        &mut self,
+        subscription: Option<&str>,
+        topics: Vec<String>,
+        start: StartPosition,
+    ) -> std::io::Result<()> {
+        write_frame(&mut self.writer, &Frame::Subscribe {
+            subscription: subscription.map(str::to_string),
+            topics,
+            start,
+        }).await?;
+
+        match self.next_reply().await? {
+            Frame::Subscribed { .. } => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn unsubscribe(&mut self, topics: Vec<String>) -> std::io::Result<()> {
+        write_frame(&mut self.writer, &Frame::Unsubscribe { topics }).await
+    }
+
+    /// Acknowledges every message of `topic` up to and including `offset`
+    pub async fn ack(&mut self, topic: &str, offset: u64) -> std::io::Result<()> {
+        write_frame(&mut self.writer, &Frame::Ack { topic: topic.to_string(), offset }).await
+    }
+
+    /// Waits for the next message on a subscribed topic
+    pub async fn next(&mut self) -> std::io::Result<Delivery> {
+        if let Some(delivery) = self.pending.pop_front() {
+            return Ok(delivery);
+        }
+
+        match self.read().await? {
+            Frame::Deliver { topic, offset, payload } => Ok(Delivery { topic, offset, payload }),
+            Frame::Error { message } => Err(std::io::Error::new(std::io::ErrorKind::Other, message)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Reads frames until one that isn't a delivery arrives, buffering deliveries
+    async fn next_reply(&mut self) -> std::io::Result<Frame> {
+        loop {
+            match self.read().await? {
+                Frame::Deliver { topic, offset, payload } => {
+                    self.pending.push_back(Delivery { topic, offset, payload });
+                }
+                Frame::Error { message } => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, message));
+                }
+                frame => return Ok(frame),
+            }
+        }
+    }
+
+    async fn read(&mut self) -> std::io::Result<Frame> {
+        read_frame(&mut self.reader).await?.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "broker closed the connection")
+        })
+    }
+}
+
+fn unexpected(frame: Frame) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("unexpected frame from broker: {frame:?}")
+    )
+}
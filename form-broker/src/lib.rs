@@ -4,6 +4,12 @@ pub const TOPIC_SIZE_OFFSET: usize = HEADER_SIZE + 8;
 pub mod broker;
 pub mod publisher;
 pub mod subscriber;
+pub mod protocol;
+pub mod topic;
+pub mod pubsub;
+pub mod client;
+pub mod mqtt;
+pub mod mqtt_bridge;
 
 pub mod util {
     use crate::{HEADER_SIZE, TOPIC_SIZE_OFFSET};
@@ -0,0 +1,452 @@
+//! The subset of MQTT 3.1.1 the MQTT bridge speaks: connecting, publishing
+//! at QoS 0 and 1, keep alive pings and (refused) subscriptions.
+//!
+//! Every packet is a one byte header holding the packet type and flags, the
+//! remaining length as a variable length integer of up to 4 bytes, then the
+//! packet's variable header and payload.
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest remaining length the encoding can express
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+pub const PROTOCOL_NAME: &str = "MQTT";
+
+/// Protocol level of MQTT 3.1.1
+pub const PROTOCOL_LEVEL: u8 = 4;
+
+/// SUBACK return code for a subscription the server refused
+pub const SUBACK_FAILURE: u8 = 0x80;
+
+/// CONNACK return codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectReturnCode {
+    Accepted = 0,
+    UnacceptableProtocolVersion = 1,
+    IdentifierRejected = 2,
+    ServerUnavailable = 3,
+    BadUsernameOrPassword = 4,
+    NotAuthorized = 5,
+}
+
+impl ConnectReturnCode {
+    fn from_byte(byte: u8) -> std::io::Result<Self> {
+        Ok(match byte {
+            0 => Self::Accepted,
+            1 => Self::UnacceptableProtocolVersion,
+            2 => Self::IdentifierRejected,
+            3 => Self::ServerUnavailable,
+            4 => Self::BadUsernameOrPassword,
+            5 => Self::NotAuthorized,
+            _ => return Err(invalid(format!("unknown CONNACK return code {byte}"))),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connect {
+    pub protocol_name: String,
+    pub protocol_level: u8,
+    pub clean_session: bool,
+    pub keep_alive: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    /// Present for QoS 1 and 2
+    pub packet_id: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Client -> server: open a session. Will messages are read but ignored.
+    Connect(Connect),
+    /// Server -> client: reply to `Connect`
+    ConnAck { session_present: bool, code: ConnectReturnCode },
+    Publish(Publish),
+    /// Acknowledges a QoS 1 publish
+    PubAck { packet_id: u16 },
+    /// Client -> server: topic filters and their requested QoS
+    Subscribe { packet_id: u16, filters: Vec<(String, u8)> },
+    /// Server -> client: granted QoS or `SUBACK_FAILURE` per filter
+    SubAck { packet_id: u16, return_codes: Vec<u8> },
+    Unsubscribe { packet_id: u16, filters: Vec<String> },
+    UnsubAck { packet_id: u16 },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+fn invalid(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+/// Reads the fields of a packet body in order
+struct Fields<'a> {
+    body: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> std::io::Result<&'a [u8]> {
+        if self.body.len() - self.pos < len {
+            return Err(invalid("packet is shorter than its fields"));
+        }
+        let bytes = &self.body[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> std::io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn binary(&mut self) -> std::io::Result<Vec<u8>> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        String::from_utf8(self.binary()?).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.body[self.pos..].to_vec();
+        self.pos = self.body.len();
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.body.len()
+    }
+}
+
+fn put_binary(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_remaining_length(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+impl Packet {
+    /// Encodes the packet, fixed header included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let header = match self {
+            Packet::Connect(connect) => {
+                put_binary(&mut body, connect.protocol_name.as_bytes());
+                body.push(connect.protocol_level);
+                let mut flags = 0u8;
+                if connect.clean_session {
+                    flags |= 0x02;
+                }
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+                body.push(flags);
+                body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+                put_binary(&mut body, connect.client_id.as_bytes());
+                if let Some(username) = &connect.username {
+                    put_binary(&mut body, username.as_bytes());
+                }
+                if let Some(password) = &connect.password {
+                    put_binary(&mut body, password);
+                }
+                0x10
+            }
+            Packet::ConnAck { session_present, code } => {
+                body.push(*session_present as u8);
+                body.push(*code as u8);
+                0x20
+            }
+            Packet::Publish(publish) => {
+                put_binary(&mut body, publish.topic.as_bytes());
+                if let Some(packet_id) = publish.packet_id {
+                    body.extend_from_slice(&packet_id.to_be_bytes());
+                }
+                body.extend_from_slice(&publish.payload);
+                0x30 | (publish.dup as u8) << 3 | (publish.qos & 0x03) << 1 | publish.retain as u8
+            }
+            Packet::PubAck { packet_id } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                0x40
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                for (filter, qos) in filters {
+                    put_binary(&mut body, filter.as_bytes());
+                    body.push(*qos);
+                }
+                0x82
+            }
+            Packet::SubAck { packet_id, return_codes } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                body.extend_from_slice(return_codes);
+                0x90
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                for filter in filters {
+                    put_binary(&mut body, filter.as_bytes());
+                }
+                0xa2
+            }
+            Packet::UnsubAck { packet_id } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                0xb0
+            }
+            Packet::PingReq => 0xc0,
+            Packet::PingResp => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        put_remaining_length(&mut packet, body.len());
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    /// Decodes a packet from its fixed header byte and body
+    pub fn decode(header: u8, body: &[u8]) -> std::io::Result<Self> {
+        let flags = header & 0x0f;
+        let mut fields = Fields { body, pos: 0 };
+        let packet = match header >> 4 {
+            1 => {
+                let protocol_name = fields.string()?;
+                let protocol_level = fields.u8()?;
+                let connect_flags = fields.u8()?;
+                if connect_flags & 0x01 != 0 {
+                    return Err(invalid("reserved CONNECT flag is set"));
+                }
+                let keep_alive = fields.u16()?;
+                let client_id = fields.string()?;
+                if connect_flags & 0x04 != 0 {
+                    let _will_topic = fields.string()?;
+                    let _will_message = fields.binary()?;
+                }
+                let username = if connect_flags & 0x80 != 0 { Some(fields.string()?) } else { None };
+                let password = if connect_flags & 0x40 != 0 { Some(fields.binary()?) } else { None };
+                Packet::Connect(Connect {
+                    protocol_name,
+                    protocol_level,
+                    clean_session: connect_flags & 0x02 != 0,
+                    keep_alive,
+                    client_id,
+                    username,
+                    password,
+                })
+            }
+            2 => Packet::ConnAck {
+                session_present: fields.u8()? & 0x01 != 0,
+                code: ConnectReturnCode::from_byte(fields.u8()?)?,
+            },
+            3 => {
+                let qos = (flags >> 1) & 0x03;
+                if qos == 3 {
+                    return Err(invalid("PUBLISH with QoS 3"));
+                }
+                let topic = fields.string()?;
+                let packet_id = if qos > 0 { Some(fields.u16()?) } else { None };
+                Packet::Publish(Publish {
+                    topic,
+                    qos,
+                    retain: flags & 0x01 != 0,
+                    dup: flags & 0x08 != 0,
+                    packet_id,
+                    payload: fields.rest(),
+                })
+            }
+            4 => Packet::PubAck { packet_id: fields.u16()? },
+            8 => {
+                let packet_id = fields.u16()?;
+                let mut filters = Vec::new();
+                while !fields.is_empty() {
+                    filters.push((fields.string()?, fields.u8()?));
+                }
+                if filters.is_empty() {
+                    return Err(invalid("SUBSCRIBE without topic filters"));
+                }
+                Packet::Subscribe { packet_id, filters }
+            }
+            9 => Packet::SubAck { packet_id: fields.u16()?, return_codes: fields.rest() },
+            10 => {
+                let packet_id = fields.u16()?;
+                let mut filters = Vec::new();
+                while !fields.is_empty() {
+                    filters.push(fields.string()?);
+                }
+                Packet::Unsubscribe { packet_id, filters }
+            }
+            11 => Packet::UnsubAck { packet_id: fields.u16()? },
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            kind => return Err(invalid(format!("unsupported MQTT packet type {kind}"))),
+        };
+        Ok(packet)
+    }
+}
+
+/// Writes a single packet
+pub async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: &Packet) -> std::io::Result<()> {
+    writer.write_all(&packet.encode()).await?;
+    writer.flush().await
+}
+
+/// Reads a single packet, returns `None` if the peer closed the connection
+/// between packets
+pub async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_packet_size: usize,
+) -> std::io::Result<Option<Packet>> {
+    let header = match reader.read_u8().await {
+        Ok(header) => header,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut len = 0usize;
+    let mut multiplier = 1usize;
+    loop {
+        let byte = reader.read_u8().await?;
+        len += (byte & 0x7f) as usize * multiplier;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        multiplier *= 128;
+        if multiplier > 128 * 128 * 128 {
+            return Err(invalid("remaining length is longer than 4 bytes"));
+        }
+    }
+    if len > max_packet_size {
+        return Err(invalid(format!("packet of {len} bytes exceeds maximum of {max_packet_size}")));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Packet::decode(header, &body).map(Some)
+}
+
+/// Whether a topic filter matches a topic name. `+` matches one level, a
+/// trailing `#` matches the parent level and everything below it. Topics
+/// starting with `$` are not matched by a leading wildcard.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Whether a topic can be published to, topic names can't hold wildcards
+pub fn is_valid_topic_name(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#', '\0'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_packets_round_trip() {
+        let packets = vec![
+            Packet::Connect(Connect {
+                protocol_name: PROTOCOL_NAME.to_string(),
+                protocol_level: PROTOCOL_LEVEL,
+                clean_session: true,
+                keep_alive: 30,
+                client_id: "sensor-1".to_string(),
+                username: Some("devices".to_string()),
+                password: Some(b"secret".to_vec()),
+            }),
+            Packet::ConnAck { session_present: false, code: ConnectReturnCode::BadUsernameOrPassword },
+            Packet::Publish(Publish {
+                topic: "sensors/1/temp".to_string(),
+                qos: 1,
+                retain: false,
+                dup: true,
+                packet_id: Some(7),
+                // Long enough to need a two byte remaining length
+                payload: vec![42; 300],
+            }),
+            Packet::Subscribe { packet_id: 8, filters: vec![("sensors/#".to_string(), 1)] },
+            Packet::SubAck { packet_id: 8, return_codes: vec![SUBACK_FAILURE] },
+            Packet::PingReq,
+            Packet::Disconnect,
+        ];
+
+        let mut bytes = Vec::new();
+        for packet in &packets {
+            bytes.extend(packet.encode());
+        }
+        let mut reader = bytes.as_slice();
+        for packet in packets {
+            assert_eq!(read_packet(&mut reader, 1024).await.unwrap(), Some(packet));
+        }
+        assert_eq!(read_packet(&mut reader, 1024).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_packet_is_rejected() {
+        let packet = Packet::Publish(Publish {
+            topic: "t".to_string(),
+            qos: 0,
+            retain: false,
+            dup: false,
+            packet_id: None,
+            payload: vec![0; 2048],
+        }).encode();
+        assert!(read_packet(&mut packet.as_slice(), 1024).await.is_err());
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("sensors/+/temp", "sensors/1/temp"));
+        assert!(!topic_matches("sensors/+/temp", "sensors/1/2/temp"));
+        assert!(topic_matches("sensors/#", "sensors"));
+        assert!(topic_matches("sensors/#", "sensors/1/temp"));
+        assert!(topic_matches("#", "anything/at/all"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(!topic_matches("sensors/1", "sensors/1/temp"));
+        assert!(!topic_matches("sensors/1/temp", "sensors/1"));
+        assert!(is_valid_topic_name("sensors/1/temp"));
+        assert!(!is_valid_topic_name("sensors/+/temp"));
+    }
+}
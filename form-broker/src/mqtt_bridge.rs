@@ -0,0 +1,551 @@
+//! Lets devices that speak MQTT publish into the form-p2p message queue.
+//!
+//! Clients authenticate with an API key sent as the CONNECT password. Every
+//! publish is checked against the topics the key may publish to, the key's
+//! rate limit and the configured topic mappings, then written to the local
+//! queue under the mapped queue topic. QoS 1 publishes are acknowledged only
+//! once the queue accepted them. Subscribing is not supported, every
+//! SUBSCRIBE is answered with a failure.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use tiny_keccak::{Hasher, Sha3};
+use tokio::io::{AsyncWrite, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use crate::mqtt::{
+    is_valid_topic_name, read_packet, topic_matches, write_packet, ConnectReturnCode, Packet, Publish,
+    PROTOCOL_LEVEL, PROTOCOL_NAME, SUBACK_FAILURE,
+};
+
+pub const DEFAULT_MQTT_ADDR: &str = "0.0.0.0:1883";
+
+/// The form-p2p queue on the same host
+pub const DEFAULT_QUEUE_URL: &str = "http://127.0.0.1:53333";
+
+/// How long a new connection has to send CONNECT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_listen_addr() -> String {
+    DEFAULT_MQTT_ADDR.to_string()
+}
+
+fn default_queue_url() -> String {
+    DEFAULT_QUEUE_URL.to_string()
+}
+
+fn default_max_packet_size() -> usize {
+    1024 * 1024
+}
+
+fn default_topics() -> Vec<String> {
+    vec!["#".to_string()]
+}
+
+fn default_messages_per_second() -> f64 {
+    10.0
+}
+
+fn default_burst() -> u32 {
+    20
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttBridgeConfig {
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: String,
+    /// Base URL of the form-p2p queue API
+    #[serde(default = "default_queue_url")]
+    pub queue_url: String,
+    /// Largest packet a client may send, in bytes
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    #[serde(default)]
+    pub mappings: Vec<TopicMapping>,
+}
+
+impl Default for MqttBridgeConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: default_listen_addr(),
+            queue_url: default_queue_url(),
+            max_packet_size: default_max_packet_size(),
+            api_keys: Vec::new(),
+            mappings: Vec::new(),
+        }
+    }
+}
+
+impl MqttBridgeConfig {
+    /// Reads a JSON config file
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let config = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(config)
+    }
+
+    /// The queue topic and sub topic a publish to `topic` is written to,
+    /// the first matching mapping wins
+    pub fn mapping(&self, topic: &str) -> Option<&TopicMapping> {
+        self.mappings.iter().find(|mapping| topic_matches(&mapping.mqtt_topic, topic))
+    }
+
+    /// The API key a client authenticated with. The username is optional,
+    /// if it is set it has to be the key's name.
+    pub fn authenticate(&self, username: Option<&str>, password: &[u8]) -> Option<&ApiKey> {
+        let digest = hex::encode(Sha256::digest(password));
+        self.api_keys.iter().find(|key| {
+            key.key_sha256.eq_ignore_ascii_case(&digest)
+                && username.map_or(true, |username| username == key.name)
+        })
+    }
+}
+
+/// A credential devices connect with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    /// Hex encoded SHA-256 of the key, the key itself is never stored
+    pub key_sha256: String,
+    /// Topic filters the key may publish to
+    #[serde(default = "default_topics")]
+    pub topics: Vec<String>,
+    /// Sustained publish rate, shared by every connection using the key
+    #[serde(default = "default_messages_per_second")]
+    pub messages_per_second: f64,
+    /// Publishes allowed in a burst above the sustained rate
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+}
+
+impl ApiKey {
+    pub fn may_publish(&self, topic: &str) -> bool {
+        self.topics.iter().any(|filter| topic_matches(filter, topic))
+    }
+}
+
+/// Routes publishes matching an MQTT topic filter to a queue topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicMapping {
+    pub mqtt_topic: String,
+    pub queue_topic: String,
+    /// Sub topic byte consumers of the queue topic dispatch on
+    pub sub_topic: u8,
+}
+
+/// A publish as it is written to the queue, after the sub topic byte
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub client_id: String,
+    /// Name of the API key the client connected with
+    pub api_key: String,
+    pub qos: u8,
+    pub received_at: i64,
+    pub payload: Vec<u8>,
+}
+
+/// Where forwarded publishes go
+#[async_trait]
+pub trait QueueWriter: Send + Sync {
+    async fn write(&self, queue_topic: &str, sub_topic: u8, message: &MqttMessage) -> std::io::Result<()>;
+}
+
+/// Same encoding as form-p2p's `QueueRequest::Write`, which this crate
+/// can't depend on
+#[derive(Serialize)]
+enum QueueRequest {
+    Write { content: Vec<u8>, topic: String },
+}
+
+/// Writes to the form-p2p queue's `write_local` endpoint
+pub struct HttpQueueWriter {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+impl HttpQueueWriter {
+    pub fn new(queue_url: &str) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap_or_default();
+        Self { client, endpoint: format!("{}/queue/write_local", queue_url.trim_end_matches('/')) }
+    }
+}
+
+#[async_trait]
+impl QueueWriter for HttpQueueWriter {
+    async fn write(&self, queue_topic: &str, sub_topic: u8, message: &MqttMessage) -> std::io::Result<()> {
+        let mut hasher = Sha3::v256();
+        let mut topic_hash = [0u8; 32];
+        hasher.update(queue_topic.as_bytes());
+        hasher.finalize(&mut topic_hash);
+        let mut content = vec![sub_topic];
+        content.extend(serde_json::to_vec(message)?);
+        let request = QueueRequest::Write { content, topic: hex::encode(topic_hash) };
+
+        let response = self.client.post(&self.endpoint)
+            .json(&request)
+            .send().await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?
+            .json::<serde_json::Value>().await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        if response == "OpSuccess" {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("queue refused the write: {response}")))
+        }
+    }
+}
+
+/// Token bucket refilled at a fixed rate up to its burst size
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        let capacity = f64::from(burst.max(1));
+        Self { rate, capacity, tokens: capacity, updated: now }
+    }
+
+    /// Takes a token if one is available
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct BridgeState {
+    config: MqttBridgeConfig,
+    writer: Arc<dyn QueueWriter>,
+    /// Rate limits by API key name
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl BridgeState {
+    async fn allow(&self, key: &ApiKey) -> bool {
+        let now = Instant::now();
+        self.buckets.lock().await
+            .entry(key.name.clone())
+            .or_insert_with(|| TokenBucket::new(key.messages_per_second, key.burst, now))
+            .try_take(now)
+    }
+}
+
+pub struct MqttBridge {
+    listener: TcpListener,
+    state: Arc<BridgeState>,
+}
+
+impl MqttBridge {
+    pub async fn new(config: MqttBridgeConfig) -> std::io::Result<Self> {
+        let writer = Arc::new(HttpQueueWriter::new(&config.queue_url));
+        Self::with_writer(config, writer).await
+    }
+
+    pub async fn with_writer(config: MqttBridgeConfig, writer: Arc<dyn QueueWriter>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&config.listen_addr).await?;
+        log::info!("MQTT bridge listening on {}...", config.listen_addr);
+        let state = Arc::new(BridgeState { config, writer, buckets: Mutex::new(HashMap::new()) });
+        Ok(Self { listener, state })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn start(&self) -> std::io::Result<()> {
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            log::info!("Accepted MQTT connection from {addr:?}");
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(state, stream, addr).await {
+                    log::error!("MQTT connection {addr:?} closed with error: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_client(state: Arc<BridgeState>, stream: TcpStream, addr: SocketAddr) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let max_packet_size = state.config.max_packet_size;
+    let connect = match tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut stream, max_packet_size)).await {
+        Ok(Ok(Some(Packet::Connect(connect)))) => connect,
+        Ok(Ok(None)) | Err(_) => return Ok(()),
+        Ok(Ok(Some(packet))) => return Err(protocol_error(format!("expected CONNECT, got {packet:?}"))),
+        Ok(Err(e)) => return Err(e),
+    };
+
+    if connect.protocol_name != PROTOCOL_NAME || connect.protocol_level != PROTOCOL_LEVEL {
+        return refuse(&mut stream, ConnectReturnCode::UnacceptableProtocolVersion).await;
+    }
+    if connect.client_id.is_empty() && !connect.clean_session {
+        return refuse(&mut stream, ConnectReturnCode::IdentifierRejected).await;
+    }
+    let Some(password) = connect.password.as_deref() else {
+        return refuse(&mut stream, ConnectReturnCode::NotAuthorized).await;
+    };
+    let Some(key) = state.config.authenticate(connect.username.as_deref(), password).cloned() else {
+        log::warn!("MQTT client {addr:?} sent an unknown API key");
+        return refuse(&mut stream, ConnectReturnCode::BadUsernameOrPassword).await;
+    };
+    let client_id = if connect.client_id.is_empty() {
+        format!("form-{addr}")
+    } else {
+        connect.client_id
+    };
+
+    write_packet(&mut stream, &Packet::ConnAck { session_present: false, code: ConnectReturnCode::Accepted }).await?;
+    log::info!("MQTT client {client_id} connected from {addr:?} with key {}", key.name);
+
+    // Clients that stop talking for one and a half keep alive periods are gone
+    let idle_timeout = if connect.keep_alive > 0 {
+        Some(Duration::from_millis(u64::from(connect.keep_alive) * 1500))
+    } else {
+        None
+    };
+
+    loop {
+        let packet = match idle_timeout {
+            Some(idle_timeout) => match tokio::time::timeout(idle_timeout, read_packet(&mut stream, max_packet_size)).await {
+                Ok(packet) => packet?,
+                Err(_) => {
+                    log::info!("MQTT client {client_id} missed its keep alive, closing");
+                    return Ok(());
+                }
+            },
+            None => read_packet(&mut stream, max_packet_size).await?,
+        };
+
+        match packet {
+            None | Some(Packet::Disconnect) => return Ok(()),
+            Some(Packet::Publish(publish)) => {
+                if !handle_publish(&state, &mut stream, &key, &client_id, publish).await? {
+                    return Ok(());
+                }
+            }
+            Some(Packet::Subscribe { packet_id, filters }) => {
+                let return_codes = vec![SUBACK_FAILURE; filters.len()];
+                write_packet(&mut stream, &Packet::SubAck { packet_id, return_codes }).await?;
+            }
+            Some(Packet::Unsubscribe { packet_id, .. }) => {
+                write_packet(&mut stream, &Packet::UnsubAck { packet_id }).await?;
+            }
+            Some(Packet::PingReq) => write_packet(&mut stream, &Packet::PingResp).await?,
+            Some(packet) => return Err(protocol_error(format!("unexpected packet from client {client_id}: {packet:?}"))),
+        }
+    }
+}
+
+/// Forwards one publish. Returns false if the connection has to be closed,
+/// which is how MQTT 3.1.1 refuses a publish and how a QoS 1 client learns
+/// to send it again after reconnecting.
+async fn handle_publish<S: AsyncWrite + Unpin>(
+    state: &BridgeState,
+    stream: &mut S,
+    key: &ApiKey,
+    client_id: &str,
+    publish: Publish,
+) -> std::io::Result<bool> {
+    if publish.qos > 1 {
+        log::warn!("MQTT client {client_id} published with QoS {}, only 0 and 1 are supported", publish.qos);
+        return Ok(false);
+    }
+    if !is_valid_topic_name(&publish.topic) || !key.may_publish(&publish.topic) {
+        log::warn!("MQTT client {client_id} may not publish to {:?}", publish.topic);
+        return Ok(false);
+    }
+    if !state.allow(key).await {
+        log::warn!("MQTT client {client_id} exceeded the rate limit of key {}", key.name);
+        return Ok(publish.qos == 0);
+    }
+
+    let Some(mapping) = state.config.mapping(&publish.topic) else {
+        log::warn!("No queue topic is mapped to MQTT topic {:?}, dropping publish", publish.topic);
+        acknowledge(stream, publish.packet_id).await?;
+        return Ok(true);
+    };
+
+    let message = MqttMessage {
+        topic: publish.topic.clone(),
+        client_id: client_id.to_string(),
+        api_key: key.name.clone(),
+        qos: publish.qos,
+        received_at: unix_now(),
+        payload: publish.payload.clone(),
+    };
+    match state.writer.write(&mapping.queue_topic, mapping.sub_topic, &message).await {
+        Ok(()) => {
+            acknowledge(stream, publish.packet_id).await?;
+            Ok(true)
+        }
+        Err(e) => {
+            log::error!("Unable to forward MQTT publish to queue topic {}: {e}", mapping.queue_topic);
+            Ok(publish.qos == 0)
+        }
+    }
+}
+
+/// Sends the PUBACK of a QoS 1 publish
+async fn acknowledge<W: AsyncWrite + Unpin>(stream: &mut W, packet_id: Option<u16>) -> std::io::Result<()> {
+    match packet_id {
+        Some(packet_id) => write_packet(stream, &Packet::PubAck { packet_id }).await,
+        None => Ok(()),
+    }
+}
+
+async fn refuse<W: AsyncWrite + Unpin>(stream: &mut W, code: ConnectReturnCode) -> std::io::Result<()> {
+    write_packet(stream, &Packet::ConnAck { session_present: false, code }).await
+}
+
+fn protocol_error(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::Connect;
+
+    #[derive(Default)]
+    struct RecordingWriter {
+        written: Mutex<Vec<(String, u8, MqttMessage)>>,
+    }
+
+    #[async_trait]
+    impl QueueWriter for RecordingWriter {
+        async fn write(&self, queue_topic: &str, sub_topic: u8, message: &MqttMessage) -> std::io::Result<()> {
+            self.written.lock().await.push((queue_topic.to_string(), sub_topic, message.clone()));
+            Ok(())
+        }
+    }
+
+    fn config() -> MqttBridgeConfig {
+        MqttBridgeConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            api_keys: vec![ApiKey {
+                name: "sensors".to_string(),
+                key_sha256: hex::encode(Sha256::digest(b"secret")),
+                topics: vec!["sensors/#".to_string()],
+                messages_per_second: 0.0,
+                burst: 2,
+            }],
+            mappings: vec![TopicMapping {
+                mqtt_topic: "sensors/+/temp".to_string(),
+                queue_topic: "telemetry".to_string(),
+                sub_topic: 3,
+            }],
+            ..Default::default()
+        }
+    }
+
+    async fn connect(addr: &str, password: &[u8]) -> (TcpStream, Packet) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut stream, &Packet::Connect(Connect {
+            protocol_name: PROTOCOL_NAME.to_string(),
+            protocol_level: PROTOCOL_LEVEL,
+            clean_session: true,
+            keep_alive: 30,
+            client_id: "sensor-1".to_string(),
+            username: None,
+            password: Some(password.to_vec()),
+        })).await.unwrap();
+        let connack = read_packet(&mut stream, 1024).await.unwrap().unwrap();
+        (stream, connack)
+    }
+
+    fn publish(packet_id: u16) -> Packet {
+        Packet::Publish(Publish {
+            topic: "sensors/1/temp".to_string(),
+            qos: 1,
+            retain: false,
+            dup: false,
+            packet_id: Some(packet_id),
+            payload: b"21.5".to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2, start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        assert!(bucket.try_take(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let config = config();
+        assert_eq!(config.authenticate(None, b"secret").map(|key| key.name.as_str()), Some("sensors"));
+        assert_eq!(config.authenticate(Some("sensors"), b"secret").map(|key| key.name.as_str()), Some("sensors"));
+        assert!(config.authenticate(Some("other"), b"secret").is_none());
+        assert!(config.authenticate(None, b"wrong").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publishes_are_forwarded_until_rate_limited() {
+        let writer = Arc::new(RecordingWriter::default());
+        let bridge = MqttBridge::with_writer(config(), writer.clone()).await.unwrap();
+        let addr = bridge.local_addr().unwrap().to_string();
+        tokio::spawn(async move { bridge.start().await });
+
+        let (_, connack) = connect(&addr, b"wrong").await;
+        assert_eq!(connack, Packet::ConnAck { session_present: false, code: ConnectReturnCode::BadUsernameOrPassword });
+
+        let (mut stream, connack) = connect(&addr, b"secret").await;
+        assert_eq!(connack, Packet::ConnAck { session_present: false, code: ConnectReturnCode::Accepted });
+
+        write_packet(&mut stream, &Packet::Subscribe { packet_id: 1, filters: vec![("sensors/#".to_string(), 1)] }).await.unwrap();
+        assert_eq!(
+            read_packet(&mut stream, 1024).await.unwrap(),
+            Some(Packet::SubAck { packet_id: 1, return_codes: vec![SUBACK_FAILURE] })
+        );
+
+        for packet_id in [2, 3] {
+            write_packet(&mut stream, &publish(packet_id)).await.unwrap();
+            assert_eq!(read_packet(&mut stream, 1024).await.unwrap(), Some(Packet::PubAck { packet_id }));
+        }
+        let written = writer.written.lock().await.clone();
+        assert_eq!(written.len(), 2);
+        assert_eq!((written[0].0.as_str(), written[0].1), ("telemetry", 3));
+        assert_eq!(written[0].2.client_id, "sensor-1");
+        assert_eq!(written[0].2.payload, b"21.5".to_vec());
+
+        // The burst is used up and the key never refills, so the bridge
+        // closes the connection instead of acknowledging
+        write_packet(&mut stream, &publish(4)).await.unwrap();
+        assert_eq!(read_packet(&mut stream, 1024).await.unwrap(), None);
+        assert_eq!(writer.written.lock().await.len(), 2);
+    }
+}
@@ -0,0 +1,85 @@
+//! Wire protocol for the topic based pub/sub layer.
+//!
+//! Every frame is a JSON encoded [`Frame`] prefixed with its length as an
+//! 8 byte big endian integer, the same header size used by the legacy
+//! frontend/backend protocol.
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::HEADER_SIZE;
+
+/// Largest frame the broker or a client will accept
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Where a new subscription starts reading a topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum StartPosition {
+    /// Only messages published after subscribing
+    #[default]
+    Latest,
+    /// Every message still retained by the broker
+    Earliest,
+    /// Messages from a specific offset onwards
+    Offset(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Frame {
+    /// Client -> broker: publish a message to a topic
+    Publish { topic: String, payload: Vec<u8> },
+    /// Client -> broker: subscribe to topics. Named subscriptions are
+    /// durable, the broker tracks their acknowledged offsets and resumes
+    /// delivery from them when the subscriber reconnects
+    Subscribe {
+        subscription: Option<String>,
+        topics: Vec<String>,
+        #[serde(default)]
+        start: StartPosition,
+    },
+    /// Client -> broker: stop receiving messages from topics
+    Unsubscribe { topics: Vec<String> },
+    /// Client -> broker: acknowledge every message of a topic up to and
+    /// including `offset`
+    Ack { topic: String, offset: u64 },
+    /// Broker -> client: a publish was stored at `offset`
+    Published { topic: String, offset: u64 },
+    /// Broker -> client: a subscription is active
+    Subscribed { subscription: Option<String>, topics: Vec<String> },
+    /// Broker -> client: a message on a subscribed topic
+    Deliver { topic: String, offset: u64, payload: Vec<u8> },
+    /// Broker -> client: a request could not be handled
+    Error { message: String },
+}
+
+/// Writes a single length prefixed frame
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> std::io::Result<()> {
+    let body = serde_json::to_vec(frame)?;
+    let mut buffer = Vec::with_capacity(HEADER_SIZE + body.len());
+    buffer.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    buffer.extend_from_slice(&body);
+    writer.write_all(&buffer).await?;
+    writer.flush().await
+}
+
+/// Reads a single length prefixed frame, returns `None` if the peer closed
+/// the connection between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_SIZE];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u64::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds maximum of {MAX_FRAME_SIZE}")
+        ));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    let frame = serde_json::from_slice(&body)?;
+    Ok(Some(frame))
+}
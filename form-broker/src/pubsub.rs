@@ -0,0 +1,423 @@
+//! Topic based pub/sub broker with durable subscriptions.
+//!
+//! Unlike the legacy [`Broker`](crate::broker::Broker), which forwards raw
+//! bytes from a frontend socket to whatever subscribers happen to be
+//! connected, the pub/sub broker stores every message in a per topic
+//! [`TopicLog`] and delivers it to subscribers by offset. Named (durable)
+//! subscriptions have their acknowledged offsets tracked by the broker, so a
+//! subscriber that disconnects resumes where it left off and receives every
+//! message it has not acknowledged yet.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use crate::protocol::{read_frame, write_frame, Frame, StartPosition};
+use crate::topic::TopicLog;
+
+/// Default number of messages retained per topic
+pub const DEFAULT_RETENTION: usize = 10_000;
+
+/// Maximum number of messages delivered to a subscriber in one batch
+const DELIVERY_BATCH_SIZE: usize = 256;
+
+const SUBSCRIPTIONS_FILE: &str = "subscriptions.json";
+const TOPICS_DIR: &str = "topics";
+
+#[derive(Debug, Clone)]
+pub struct PubSubConfig {
+    /// Address clients connect to
+    pub listen_addr: String,
+    /// Directory topic logs and durable subscription offsets are persisted
+    /// to. Nothing survives a restart when unset.
+    pub data_dir: Option<PathBuf>,
+    /// Messages retained per topic
+    pub retention: usize,
+}
+
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:5557".to_string(),
+            data_dir: None,
+            retention: DEFAULT_RETENTION,
+        }
+    }
+}
+
+/// A durable subscription: the next offset to deliver for each topic
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DurableSubscription {
+    pub offsets: HashMap<String, u64>,
+}
+
+/// Shared broker state
+pub struct PubSubState {
+    config: PubSubConfig,
+    topics: HashMap<String, TopicLog>,
+    subscriptions: HashMap<String, DurableSubscription>,
+}
+
+impl PubSubState {
+    pub fn new(config: PubSubConfig) -> std::io::Result<Self> {
+        let mut subscriptions = HashMap::new();
+        if let Some(dir) = &config.data_dir {
+            std::fs::create_dir_all(dir.join(TOPICS_DIR))?;
+            let path = dir.join(SUBSCRIPTIONS_FILE);
+            if path.exists() {
+                subscriptions = serde_json::from_slice(&std::fs::read(path)?)?;
+            }
+        }
+
+        Ok(Self {
+            config,
+            topics: HashMap::new(),
+            subscriptions,
+        })
+    }
+
+    /// Returns the log of a topic, opening (or creating) it if needed
+    pub fn topic(&mut self, name: &str) -> std::io::Result<&mut TopicLog> {
+        if !self.topics.contains_key(name) {
+            let log = match &self.config.data_dir {
+                Some(dir) => TopicLog::open(dir.join(TOPICS_DIR).join(topic_file_name(name)), self.config.retention)?,
+                None => TopicLog::new(self.config.retention),
+            };
+            self.topics.insert(name.to_string(), log);
+        }
+        Ok(self.topics.get_mut(name).expect("topic was just inserted"))
+    }
+
+    /// Stores a message and returns its offset
+    pub fn publish(&mut self, topic: &str, payload: Vec<u8>) -> std::io::Result<u64> {
+        self.topic(topic)?.append(payload)
+    }
+
+    /// Resolves the offset a subscriber to `topic` starts reading from
+    pub fn start_offset(
+        &mut self,
+        subscription: Option<&str>,
+        topic: &str,
+        start: StartPosition,
+    ) -> std::io::Result<u64> {
+        if let Some(offset) = subscription
+            .and_then(|name| self.subscriptions.get(name))
+            .and_then(|sub| sub.offsets.get(topic))
+        {
+            return Ok(*offset);
+        }
+
+        let log = self.topic(topic)?;
+        let offset = match start {
+            StartPosition::Latest => log.next_offset(),
+            StartPosition::Earliest => log.earliest_offset(),
+            StartPosition::Offset(offset) => offset,
+        };
+
+        if let Some(name) = subscription {
+            self.subscriptions.entry(name.to_string()).or_default()
+                .offsets.insert(topic.to_string(), offset);
+            self.persist_subscriptions()?;
+        }
+
+        Ok(offset)
+    }
+
+    /// Records that a durable subscription has processed `topic` up to and
+    /// including `offset`
+    pub fn ack(&mut self, subscription: &str, topic: &str, offset: u64) -> std::io::Result<()> {
+        let next = self.subscriptions.entry(subscription.to_string()).or_default()
+            .offsets.entry(topic.to_string()).or_insert(0);
+        if offset + 1 > *next {
+            *next = offset + 1;
+            self.persist_subscriptions()?;
+        }
+        Ok(())
+    }
+
+    pub fn subscription(&self, name: &str) -> Option<&DurableSubscription> {
+        self.subscriptions.get(name)
+    }
+
+    fn persist_subscriptions(&self) -> std::io::Result<()> {
+        if let Some(dir) = &self.config.data_dir {
+            let tmp = dir.join(format!("{SUBSCRIPTIONS_FILE}.tmp"));
+            std::fs::write(&tmp, serde_json::to_vec(&self.subscriptions)?)?;
+            std::fs::rename(tmp, dir.join(SUBSCRIPTIONS_FILE))?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes a topic name so it can be used as a file name
+fn topic_file_name(topic: &str) -> String {
+    let mut name = String::with_capacity(topic.len() + 4);
+    for byte in topic.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{byte:02x}")),
+        }
+    }
+    name.push_str(".log");
+    name
+}
+
+pub struct PubSubBroker {
+    listener: TcpListener,
+    state: Arc<Mutex<PubSubState>>,
+    published: broadcast::Sender<String>,
+}
+
+impl PubSubBroker {
+    pub async fn new(config: PubSubConfig) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&config.listen_addr).await?;
+        log::info!("Pub/sub broker listening on {}...", config.listen_addr);
+        let state = Arc::new(Mutex::new(PubSubState::new(config)?));
+        let (published, _) = broadcast::channel(4096);
+        Ok(Self { listener, state, published })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn start(&self) -> std::io::Result<()> {
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            log::info!("Accepted pub/sub connection from {addr:?}");
+            let session = Session::new(self.state.clone(), self.published.clone());
+            tokio::spawn(async move {
+                if let Err(e) = session.run(stream).await {
+                    log::error!("Pub/sub connection {addr:?} closed with error: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// A single client connection
+struct Session {
+    state: Arc<Mutex<PubSubState>>,
+    published: broadcast::Sender<String>,
+    subscription: Option<String>,
+    /// Next offset to deliver for each subscribed topic
+    cursors: HashMap<String, u64>,
+}
+
+impl Session {
+    fn new(state: Arc<Mutex<PubSubState>>, published: broadcast::Sender<String>) -> Self {
+        Self {
+            state,
+            published,
+            subscription: None,
+            cursors: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, stream: TcpStream) -> std::io::Result<()> {
+        let (mut reader, mut writer) = stream.into_split();
+        let mut notifications = self.published.subscribe();
+
+        // Reading a frame is not cancel safe, so it happens on its own task
+        let (frame_tx, mut frame_rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                match read_frame(&mut reader).await {
+                    Ok(Some(frame)) => {
+                        if frame_tx.send(Ok(frame)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = frame_tx.send(Err(e)).await;
+                        break;
+                    }
+                }
+            }
+        });
+
+        loop {
+            tokio::select! {
+                frame = frame_rx.recv() => {
+                    match frame {
+                        Some(frame) => self.handle_frame(frame?, &mut writer).await?,
+                        None => return Ok(()),
+                    }
+                }
+                notification = notifications.recv() => {
+                    match notification {
+                        Ok(topic) => {
+                            if self.cursors.contains_key(&topic) {
+                                self.deliver(&[topic], &mut writer).await?;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            // Cursors are authoritative, catch up on every topic
+                            log::warn!("Subscriber lagged behind {n} notifications, resyncing");
+                            let topics: Vec<String> = self.cursors.keys().cloned().collect();
+                            self.deliver(&topics, &mut writer).await?;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_frame(&mut self, frame: Frame, writer: &mut OwnedWriteHalf) -> std::io::Result<()> {
+        match frame {
+            Frame::Publish { topic, payload } => {
+                let result = self.state.lock().await.publish(&topic, payload);
+                match result {
+                    Ok(offset) => {
+                        let _ = self.published.send(topic.clone());
+                        write_frame(writer, &Frame::Published { topic, offset }).await
+                    }
+                    Err(e) => send_error(writer, format!("Unable to publish to {topic}: {e}")).await,
+                }
+            }
+            Frame::Subscribe { subscription, topics, start } => {
+                if subscription.is_some() && self.subscription.is_some() && subscription != self.subscription {
+                    return send_error(writer, "Connection is already bound to a different durable subscription").await;
+                }
+                if subscription.is_some() {
+                    self.subscription = subscription.clone();
+                }
+
+                {
+                    let mut state = self.state.lock().await;
+                    for topic in &topics {
+                        match state.start_offset(self.subscription.as_deref(), topic, start) {
+                            Ok(offset) => {
+                                self.cursors.insert(topic.clone(), offset);
+                            }
+                            Err(e) => {
+                                drop(state);
+                                return send_error(writer, format!("Unable to subscribe to {topic}: {e}")).await;
+                            }
+                        }
+                    }
+                }
+
+                log::info!("Subscription {:?} active on topics {:?}", self.subscription, topics);
+                write_frame(writer, &Frame::Subscribed { subscription, topics: topics.clone() }).await?;
+                // Deliver anything already retained past the start offsets
+                self.deliver(&topics, writer).await
+            }
+            Frame::Unsubscribe { topics } => {
+                for topic in &topics {
+                    self.cursors.remove(topic);
+                }
+                Ok(())
+            }
+            Frame::Ack { topic, offset } => {
+                match &self.subscription {
+                    Some(name) => {
+                        let result = self.state.lock().await.ack(name, &topic, offset);
+                        if let Err(e) = result {
+                            return send_error(writer, format!("Unable to record ack for {topic}: {e}")).await;
+                        }
+                        Ok(())
+                    }
+                    None => send_error(writer, "Acks are only accepted on durable subscriptions").await,
+                }
+            }
+            other => send_error(writer, format!("Unexpected frame from client: {other:?}")).await,
+        }
+    }
+
+    /// Sends every retained message past the cursors of `topics`
+    async fn deliver(&mut self, topics: &[String], writer: &mut OwnedWriteHalf) -> std::io::Result<()> {
+        for topic in topics {
+            loop {
+                let Some(cursor) = self.cursors.get(topic).copied() else { break };
+                let records = {
+                    let mut state = self.state.lock().await;
+                    state.topic(topic)?.read_from(cursor, DELIVERY_BATCH_SIZE)
+                };
+                let Some(last) = records.last().map(|r| r.offset) else { break };
+
+                for record in records {
+                    write_frame(writer, &Frame::Deliver {
+                        topic: topic.clone(),
+                        offset: record.offset,
+                        payload: record.payload,
+                    }).await?;
+                }
+                self.cursors.insert(topic.clone(), last + 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn send_error(writer: &mut OwnedWriteHalf, message: impl Into<String>) -> std::io::Result<()> {
+    let message = message.into();
+    log::warn!("{message}");
+    write_frame(writer, &Frame::Error { message }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_durable_subscription_resumes_after_last_ack() {
+        let mut state = PubSubState::new(PubSubConfig::default()).unwrap();
+        for i in 0..3u8 {
+            state.publish("jobs", vec![i]).unwrap();
+        }
+
+        assert_eq!(state.start_offset(Some("worker"), "jobs", StartPosition::Earliest).unwrap(), 0);
+        state.ack("worker", "jobs", 1).unwrap();
+        // Stale acks don't move the offset backwards
+        state.ack("worker", "jobs", 0).unwrap();
+
+        // The stored offset wins over the requested start position
+        assert_eq!(state.start_offset(Some("worker"), "jobs", StartPosition::Latest).unwrap(), 2);
+        assert_eq!(state.start_offset(None, "jobs", StartPosition::Latest).unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_durable_subscriber_receives_unacked_messages_on_reconnect() {
+        use crate::client::BrokerClient;
+
+        let broker = PubSubBroker::new(PubSubConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            ..Default::default()
+        }).await.unwrap();
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.start().await });
+
+        let topics = vec!["events".to_string()];
+        let mut subscriber = BrokerClient::connect(&addr).await.unwrap();
+        subscriber.subscribe(Some("svc"), topics.clone(), StartPosition::Latest).await.unwrap();
+
+        let mut publisher = BrokerClient::connect(&addr).await.unwrap();
+        for i in 0..3 {
+            assert_eq!(publisher.publish("events", format!("event {i}")).await.unwrap(), i);
+        }
+
+        let first = subscriber.next().await.unwrap();
+        assert_eq!(first.payload, b"event 0".to_vec());
+        subscriber.ack(&first.topic, first.offset).await.unwrap();
+        // Frames are handled in order, so a reply to a later request means the ack was recorded
+        subscriber.subscribe(Some("svc"), vec!["barrier".to_string()], StartPosition::Latest).await.unwrap();
+        drop(subscriber);
+
+        // Only the acknowledged message is skipped after reconnecting
+        let mut subscriber = BrokerClient::connect(&addr).await.unwrap();
+        subscriber.subscribe(Some("svc"), topics, StartPosition::Latest).await.unwrap();
+        assert_eq!(subscriber.next().await.unwrap().offset, 1);
+        assert_eq!(subscriber.next().await.unwrap().offset, 2);
+    }
+
+    #[test]
+    fn test_topic_file_name_is_escaped() {
+        assert_eq!(topic_file_name("vmm"), "vmm.log");
+        assert_eq!(topic_file_name("a/b.c"), "a%2fb%2ec.log");
+    }
+}
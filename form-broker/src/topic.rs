@@ -0,0 +1,142 @@
+//! Append only, offset addressed message logs backing each topic.
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// A message stored in a topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: u64,
+    pub payload: Vec<u8>,
+}
+
+/// The retained messages of a single topic. Offsets are assigned in publish
+/// order and never reused; once more than `retention` messages are stored the
+/// oldest ones are dropped from memory.
+pub struct TopicLog {
+    records: VecDeque<Record>,
+    next_offset: u64,
+    retention: usize,
+    file: Option<PathBuf>,
+}
+
+impl TopicLog {
+    pub fn new(retention: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            next_offset: 0,
+            retention: retention.max(1),
+            file: None,
+        }
+    }
+
+    /// Opens a topic log persisted at `path`, replaying any records already
+    /// written to it
+    pub fn open(path: impl AsRef<Path>, retention: usize) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut log = Self::new(retention);
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut header = [0u8; 16];
+            loop {
+                match reader.read_exact(&mut header) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+                let offset = u64::from_be_bytes(header[..8].try_into().expect("8 byte slice"));
+                let len = u64::from_be_bytes(header[8..].try_into().expect("8 byte slice")) as usize;
+                let mut payload = vec![0u8; len];
+                if let Err(e) = reader.read_exact(&mut payload) {
+                    // A partially written trailing record is dropped
+                    log::warn!("Truncated record at offset {offset} in {}: {e}", path.display());
+                    break;
+                }
+                log.push(Record { offset, payload });
+            }
+        }
+        log.file = Some(path);
+        Ok(log)
+    }
+
+    /// Appends a message and returns its offset
+    pub fn append(&mut self, payload: Vec<u8>) -> std::io::Result<u64> {
+        let offset = self.next_offset;
+        if let Some(path) = &self.file {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut buffer = Vec::with_capacity(16 + payload.len());
+            buffer.extend_from_slice(&offset.to_be_bytes());
+            buffer.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            buffer.extend_from_slice(&payload);
+            file.write_all(&buffer)?;
+        }
+        self.push(Record { offset, payload });
+        Ok(offset)
+    }
+
+    /// Returns up to `max` records starting at `offset`. If `offset` has
+    /// already been dropped by retention, reading starts at the oldest
+    /// retained record.
+    pub fn read_from(&self, offset: u64, max: usize) -> Vec<Record> {
+        let start = offset.saturating_sub(self.earliest_offset()) as usize;
+        self.records.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// Offset of the oldest retained record
+    pub fn earliest_offset(&self) -> u64 {
+        self.records.front().map(|r| r.offset).unwrap_or(self.next_offset)
+    }
+
+    /// Offset the next published record will be assigned
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    fn push(&mut self, record: Record) {
+        self.next_offset = record.offset + 1;
+        self.records.push_back(record);
+        while self.records.len() > self.retention {
+            self.records.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets_and_retention() {
+        let mut log = TopicLog::new(3);
+        for i in 0..5u8 {
+            assert_eq!(log.append(vec![i]).unwrap(), i as u64);
+        }
+
+        assert_eq!(log.earliest_offset(), 2);
+        assert_eq!(log.next_offset(), 5);
+        // Reading from a dropped offset starts at the oldest retained record
+        let records = log.read_from(0, 10);
+        assert_eq!(records.iter().map(|r| r.offset).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(log.read_from(4, 10), vec![Record { offset: 4, payload: vec![4] }]);
+        assert!(log.read_from(5, 10).is_empty());
+    }
+
+    #[test]
+    fn test_persisted_log_is_replayed() {
+        let path = std::env::temp_dir().join(format!("form-broker-topic-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = TopicLog::open(&path, 100).unwrap();
+        log.append(b"first".to_vec()).unwrap();
+        log.append(b"second".to_vec()).unwrap();
+        drop(log);
+
+        let mut log = TopicLog::open(&path, 100).unwrap();
+        assert_eq!(log.next_offset(), 2);
+        assert_eq!(log.read_from(1, 1)[0].payload, b"second".to_vec());
+        assert_eq!(log.append(b"third".to_vec()).unwrap(), 2);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
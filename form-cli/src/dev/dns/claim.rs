@@ -0,0 +1,50 @@
+use clap::Args;
+use colored::Colorize;
+use serde_json::{json, Value};
+
+use crate::{dev::manage::schedule::ScheduleAuth, Keystore};
+
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
+
+/// Claim a `<name>.fog` vanity domain for one of your builds
+#[derive(Debug, Clone, Args)]
+pub struct ClaimCommand {
+    /// The name to claim, the domain will be `<name>.fog`
+    #[clap(long, short)]
+    pub name: String,
+    /// The build id of the instances the domain should point to. The
+    /// request must be signed by the owner of the build
+    #[clap(long="build-id", short='b')]
+    pub build_id: String,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+impl ClaimCommand {
+    pub async fn handle_claim_command(&self, provider: String, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let client = self.auth.signed_client(&self.build_id, keystore)?;
+        let resp = client
+            .post(format!("http://{provider}:{STATE_API_PORT}/v1/dns/vanity/claim"))
+            .json(&json!({ "name": self.name, "build_id": self.build_id }))
+            .send().await?
+            .json::<Value>().await?;
+
+        if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+            println!("❌ {}: {}", "Vanity domain claim failed".red(), reason);
+            return Err(reason.to_string().into());
+        }
+
+        let domain = resp.get("domain").and_then(Value::as_str).unwrap_or_default().to_string();
+        println!("\n✅ {}", format!("{} now points to build {}", domain, self.build_id).green().bold());
+        if let Some(targets) = resp.get("targets").and_then(Value::as_array) {
+            let targets = targets.iter().filter_map(Value::as_str).collect::<Vec<_>>();
+            println!("Targets: {}", targets.join(", ").bright_cyan());
+        }
+        println!("\nThe record is being replicated to every node, follow its progress with:");
+        println!("  form dns status --name {}", self.name);
+
+        Ok(())
+    }
+}
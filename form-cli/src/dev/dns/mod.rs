@@ -3,11 +3,17 @@ use add::AddCommand;
 use remove::RemoveCommand;
 use update::UpdateCommand;
 use verify::VerifyCommand;
+use claim::ClaimCommand;
+use release::ReleaseCommand;
+use status::StatusCommand;
 
 pub mod add;
 pub mod remove;
 pub mod update;
 pub mod verify;
+pub mod claim;
+pub mod release;
+pub mod status;
 
 #[derive(Debug, Clone, Subcommand)]
 pub enum DnsCommand {
@@ -15,4 +21,10 @@ pub enum DnsCommand {
     Remove(RemoveCommand),
     Update(UpdateCommand),
     Verify(VerifyCommand),
+    /// Claim a `<name>.fog` vanity domain for a build
+    Claim(ClaimCommand),
+    /// Release a claimed vanity domain
+    Release(ReleaseCommand),
+    /// Show the claim and propagation status of a vanity domain
+    Status(StatusCommand),
 }
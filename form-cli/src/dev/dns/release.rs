@@ -0,0 +1,38 @@
+use clap::Args;
+use colored::Colorize;
+use serde_json::Value;
+
+use crate::{dev::manage::schedule::ScheduleAuth, Keystore};
+
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
+
+/// Release a vanity domain you claimed so it can be claimed again
+#[derive(Debug, Clone, Args)]
+pub struct ReleaseCommand {
+    /// The claimed name, with or without the `.fog` suffix
+    #[clap(long, short)]
+    pub name: String,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+impl ReleaseCommand {
+    pub async fn handle_release_command(&self, provider: String, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let client = self.auth.signed_client(&self.name, keystore)?;
+        let resp = client
+            .post(format!("http://{provider}:{STATE_API_PORT}/v1/dns/vanity/{}/release", self.name))
+            .send().await?
+            .json::<Value>().await?;
+
+        if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+            println!("❌ {}: {}", "Vanity domain release failed".red(), reason);
+            return Err(reason.to_string().into());
+        }
+
+        let domain = resp.get("domain").and_then(Value::as_str).unwrap_or_default();
+        println!("✅ {} {}", domain.bold().bright_yellow(), "has been released".green());
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use clap::Args;
+use colored::Colorize;
+use reqwest::Client;
+use serde_json::Value;
+
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
+
+/// Show who holds a vanity domain and whether it is being served
+#[derive(Debug, Clone, Args)]
+pub struct StatusCommand {
+    /// The name to look up, with or without the `.fog` suffix
+    #[clap(long, short)]
+    pub name: String,
+}
+
+impl StatusCommand {
+    pub async fn handle_status_command(&self, provider: String) -> Result<(), Box<dyn std::error::Error>> {
+        let resp = Client::new()
+            .get(format!("http://{provider}:{STATE_API_PORT}/v1/dns/vanity/{}/status", self.name))
+            .send().await?
+            .json::<Value>().await?;
+
+        if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+            println!("❌ {}: {}", "Unable to get vanity domain status".red(), reason);
+            return Err(reason.to_string().into());
+        }
+
+        let domain = resp.get("domain").and_then(Value::as_str).unwrap_or_default();
+        if !resp.get("claimed").and_then(Value::as_bool).unwrap_or(false) {
+            println!("{} is {}, claim it with:", domain.bold().bright_yellow(), "available".green());
+            println!("  form dns claim --name {} --build-id <BUILD_ID>", self.name);
+            return Ok(());
+        }
+
+        let field = |key: &str| resp.get(key).and_then(Value::as_str).unwrap_or("unknown").to_string();
+        println!("Vanity domain {}:", domain.bold().bright_yellow());
+        println!("  Build:      {}", field("build_id").bright_cyan());
+        println!("  Owner:      {}", field("owner").bright_cyan());
+        if let Some(targets) = resp.get("targets").and_then(Value::as_array) {
+            let targets = targets.iter().filter_map(Value::as_str).collect::<Vec<_>>();
+            println!("  Targets:    {}", targets.join(", ").bright_cyan());
+        }
+        if resp.get("stale").and_then(Value::as_bool).unwrap_or(false) {
+            println!("  Status:     {}", "stale, the instances it points to no longer exist".yellow());
+        } else if resp.get("propagated").and_then(Value::as_bool).unwrap_or(false) {
+            println!("  Status:     {}", "active".green());
+        } else {
+            println!("  Status:     {}", "claimed, waiting for form-dns to pick it up".yellow());
+        }
+
+        Ok(())
+    }
+}
@@ -1,48 +1,32 @@
-use std::{fmt::Debug, path::PathBuf};
-use clap::Args;
+use std::path::PathBuf;
+use clap::{Args, ValueEnum};
 use colored::Colorize;
-use form_types::state::Response;
-use reqwest::Client;
-use serde::{Serialize, Deserialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use dialoguer::{Confirm, theme::ColorfulTheme};
 
-use crate::{default_context, default_formfile};
+use crate::{default_context, default_formfile, dev::manage::schedule::ScheduleAuth, Keystore};
 
-// Define structures to match the API's response format
-#[derive(Debug, Deserialize)]
-pub enum VerificationResult {
-    Verified(FormDnsRecord),
-    RequiresConfig(DnsConfiguration),
-}
-
-#[derive(Debug, Deserialize)]
-pub struct FormDnsRecord {
-    pub domain: String,
-    pub verification_status: Option<String>,
-    pub verification_timestamp: Option<u64>,
-    // Other fields may be included but we don't need them for display
-    #[serde(flatten)]
-    pub other: std::collections::HashMap<String, Value>,
-}
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
 
-#[derive(Debug, Deserialize)]
-pub struct DnsConfiguration {
-    pub record_type: String,
-    pub target: String,
-    pub ttl: u32,
+/// Record the ownership challenge is published in
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum ChallengeMethod {
+    #[default]
+    Txt,
+    Cname,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type")]
-pub enum DomainResponse {
-    Success(Value),
-    Failure(Option<String>),
-    VerificationSuccess(VerificationResult),
-    VerificationFailure(String),
+impl ChallengeMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeMethod::Txt => "txt",
+            ChallengeMethod::Cname => "cname",
+        }
+    }
 }
 
-/// Verify ownership of a domain name
+/// Verify ownership of a domain name before pointing it at your instances
 #[derive(Debug, Clone, Args)]
 pub struct VerifyCommand {
     /// Path to the context directory (e.g., . for current directory)
@@ -50,68 +34,86 @@ pub struct VerifyCommand {
     /// however, you can provide a path to the Formfile.
     #[clap(default_value_os_t = default_context())]
     pub context_dir: PathBuf,
-    
+
     /// The directory where the form pack artifacts can be found
     #[clap(long, short, default_value_os_t = default_formfile(default_context()))]
     pub formfile: PathBuf,
-    
-    /// A hexadecimal or base64 representation of a valid private key for 
-    /// signing the request. Given this is the create command, this will
-    /// be how the network derives ownership of the instance. Authorization
-    /// to other public key/wallet addresses can be granted by the owner
-    /// after creation, however, this key will be the initial owner until
-    /// revoked or changed by a request made with the same signing key
+
+    /// A hexadecimal or base64 representation of a valid private key for
+    /// signing the request. Must be the owner of the build the domain
+    /// will point to
     #[clap(long, short)]
     pub private_key: Option<String>,
-    
+
     /// An altenrative to private key or mnemonic. If you have a keyfile
     /// stored locally, you can use the keyfile to read in your private key
     //TODO: Add support for HSM and other Enclave based key storage
     #[clap(long, short)]
     pub keyfile: Option<String>,
-    
-    /// An alternative to private key or keyfile. If you have a 12 or 24 word 
+
+    /// An alternative to private key or keyfile. If you have a 12 or 24 word
     /// BIP39 compliant mnemonic phrase, you can use it to derive the signing
     /// key for this request
     //TODO: Add support for HSM and other Enclave based key storage
     #[clap(long, short)]
     pub mnemonic: Option<String>,
-    
+
     /// The domain name you want to verify
     #[clap(long="domain", short='d')]
     pub domain_name: String,
-    
-    /// Check verification status instead of initiating verification
+
+    /// The build id of the instances the domain will point to, needed to
+    /// start a verification
+    #[clap(long="build-id", short='b')]
+    pub build_id: Option<String>,
+
+    /// Prove ownership with a TXT record or with a CNAME record
+    #[clap(long, value_enum, default_value_t = ChallengeMethod::Txt)]
+    pub method: ChallengeMethod,
+
+    /// Issue a TLS certificate for the domain once it is added
+    #[clap(long="tls-enabled", short='t', default_value_t=false)]
+    pub ssl_cert: bool,
+
+    /// Check the challenge now instead of starting a verification
     #[clap(long, short)]
     pub check: bool,
-    
+
     /// Skip confirmation prompts
     #[clap(long="yes", short='y', default_value_t=false)]
     pub skip_confirmation: bool,
 }
 
-pub fn print_verification_success(domain: String, record: &FormDnsRecord) {
+pub fn print_verification_success(domain: &str, resp: &Value) {
     println!("\n✅ {}", format!("Domain '{}' is verified!", domain).green().bold());
-    
-    if let Some(timestamp) = record.verification_timestamp {
-        let datetime = chrono::DateTime::<chrono::Utc>::from_timestamp(timestamp as i64, 0)
+
+    if let Some(timestamp) = resp.get("verified_at").and_then(Value::as_i64) {
+        let datetime = chrono::DateTime::<chrono::Utc>::from_timestamp(timestamp, 0)
             .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
             .unwrap_or_else(|| "Unknown time".to_string());
-        
+
         println!("Verified at: {}", datetime);
     }
+
+    let build_id = resp.get("build_id").and_then(Value::as_str).unwrap_or("<BUILD_ID>");
+    println!("\nPoint it at your instances with:");
+    println!("  form dns add --domain {} --build-id {} --public", domain, build_id);
 }
 
-pub fn print_verification_instructions(domain: String, config: &DnsConfiguration) {
-    println!("\n⚠️ {}", format!("Domain '{}' requires additional configuration.", domain).yellow().bold());
-    println!("To verify domain ownership, please configure your DNS settings:");
-    
-    println!("Add a {} record with the following settings:", config.record_type);
-    println!("  Domain: {}", domain);
-    println!("  Value: {}", config.target);
-    println!("  TTL: {} seconds", config.ttl);
-    
-    println!("\nAfter updating your DNS settings, run:");
+pub fn print_verification_instructions(domain: &str, resp: &Value) {
+    let challenge = |key: &str| resp.pointer(&format!("/challenge/{key}")).and_then(Value::as_str).unwrap_or_default().to_string();
+    println!("\n⚠️ {}", format!("Domain '{}' is waiting for verification.", domain).yellow().bold());
+    println!("To prove you own the domain, add this record at your DNS provider:");
+
+    println!("  Type:  {}", challenge("record_type").bright_cyan());
+    println!("  Name:  {}", challenge("name").bright_cyan());
+    println!("  Value: {}", challenge("value").bright_cyan());
+
+    if let Some(error) = resp.get("last_error").and_then(Value::as_str) {
+        println!("\nLast check: {}", error.yellow());
+    }
+
+    println!("\nThe network checks the record every minute. To check it right away, run:");
     println!("  form dns verify --domain {} --check", domain);
 }
 
@@ -127,7 +129,7 @@ Reason: {}
 If you're not sure what to do from here, please consider doing one of the following:
 
     1. Join our discord at {} and go to the {} channel and paste this response
-    2. Submitting an {} on our project github at {} 
+    2. Submitting an {} on our project github at {}
     3. Sending us a direct message on X at {}
 
 Someone from our core team will gladly help you out.
@@ -143,83 +145,64 @@ reason.bold().bright_red(),
 }
 
 impl VerifyCommand {
-    pub async fn handle_verify_command(&self, provider: String) -> Result<(), Box<dyn std::error::Error>> {
-        let domain = self.domain_name.clone();
-        
-        // If check flag is provided, check verification status
-        if self.check {
-            let endpoint = format!("http://{provider}:3004/record/{domain}/check_verification");
-            
-            // Send the request
-            let resp = Client::new()
-                .post(&endpoint)
+    pub async fn handle_verify_command(&self, provider: String, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let domain = self.domain_name.trim_end_matches('.').to_lowercase();
+        let auth = ScheduleAuth {
+            private_key: self.private_key.clone(),
+            keyfile: self.keyfile.clone(),
+            mnemonic: self.mnemonic.clone(),
+        };
+        let client = auth.signed_client(&domain, keystore)?;
+
+        let resp = if self.check {
+            client
+                .post(format!("http://{provider}:{STATE_API_PORT}/v1/dns/domain/{domain}/check"))
                 .send().await?
-                .json::<DomainResponse>().await?;
-                
-            // Handle the response based on the specialized format
-            match resp {
-                DomainResponse::VerificationSuccess(VerificationResult::Verified(record)) => {
-                    print_verification_success(domain, &record);
-                },
-                DomainResponse::VerificationSuccess(VerificationResult::RequiresConfig(config)) => {
-                    print_verification_instructions(domain, &config);
-                },
-                DomainResponse::VerificationFailure(reason) => {
-                    print_verification_failure(reason);
-                },
-                DomainResponse::Success(_) => {
-                    println!("Unexpected success response format.");
-                },
-                DomainResponse::Failure(reason) => {
-                    print_verification_failure(reason.unwrap_or_else(|| "Unknown error".to_string()));
+                .json::<Value>().await?
+        } else {
+            let Some(build_id) = &self.build_id else {
+                return Err("--build-id is required to start a verification, or pass --check".into());
+            };
+
+            // Confirm verification unless --yes flag is used
+            if !self.skip_confirmation {
+                let confirm = Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!("Would you like to verify ownership of domain '{}'?", domain))
+                    .default(true)
+                    .interact()?;
+
+                if !confirm {
+                    println!("Verification cancelled.");
+                    return Ok(());
                 }
             }
-            
-            return Ok(());
-        }
-        
-        // If not checking, initiate verification
-        // Confirm verification unless --yes flag is used
-        if !self.skip_confirmation {
-            let confirm = Confirm::with_theme(&ColorfulTheme::default())
-                .with_prompt(format!("Would you like to verify ownership of domain '{}'?", domain))
-                .default(true)
-                .interact()?;
-            
-            if !confirm {
-                println!("Verification cancelled.");
-                return Ok(());
-            }
+
+            client
+                .post(format!("http://{provider}:{STATE_API_PORT}/v1/dns/domain/verify"))
+                .json(&json!({
+                    "domain": domain,
+                    "build_id": build_id,
+                    "method": self.method.as_str(),
+                    "ssl_cert": self.ssl_cert,
+                }))
+                .send().await?
+                .json::<Value>().await?
+        };
+
+        if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            let reason = resp.get("error").and_then(Value::as_str).unwrap_or("Unknown error").to_string();
+            print_verification_failure(reason.clone());
+            return Err(reason.into());
         }
-        
-        // Construct the request to the verification endpoint
-        let endpoint = format!("http://{provider}:3004/record/{domain}/initiate_verification");
-        
-        // Send the request
-        let resp = Client::new()
-            .post(&endpoint)
-            .send().await?
-            .json::<DomainResponse>().await?;
-            
-        // Handle the response based on the specialized format
-        match resp {
-            DomainResponse::VerificationSuccess(VerificationResult::Verified(record)) => {
-                print_verification_success(domain, &record);
-            },
-            DomainResponse::VerificationSuccess(VerificationResult::RequiresConfig(config)) => {
-                print_verification_instructions(domain, &config);
-            },
-            DomainResponse::VerificationFailure(reason) => {
-                print_verification_failure(reason);
-            },
-            DomainResponse::Success(_) => {
-                println!("Unexpected success response format.");
-            },
-            DomainResponse::Failure(reason) => {
-                print_verification_failure(reason.unwrap_or_else(|| "Unknown error".to_string()));
-            }
+
+        match resp.get("status").and_then(Value::as_str) {
+            Some("verified") => print_verification_success(&domain, &resp),
+            Some("expired") => print_verification_failure(
+                format!("The challenge for {domain} expired, start a new one with --build-id")
+            ),
+            _ => print_verification_instructions(&domain, &resp),
         }
-        
+
         Ok(())
     }
-} 
+}
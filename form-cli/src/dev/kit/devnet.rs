@@ -0,0 +1,320 @@
+use std::{fs::File, net::{SocketAddr, TcpListener, TcpStream}, os::unix::process::CommandExt, path::{Path, PathBuf}, process::{Command, Stdio}, time::{Duration, Instant}};
+use alloy_core::primitives::Address;
+use clap::{Args, Subcommand};
+use colored::*;
+use k256::ecdsa::SigningKey;
+use rand::rngs::OsRng;
+use serde::{Serialize, Deserialize};
+use form_config::OperatorConfig;
+use form_p2p::queue::QUEUE_PORT;
+use crate::{default_data_dir, encrypt_file};
+
+/// Password the devnet operator key is encrypted with unless one is given
+pub const DEVNET_PASSWORD: &str = "formation-devnet";
+
+const STATE_FILE: &str = "devnet.json";
+const OPERATOR_CONFIG_FILE: &str = "operator-config.json";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commands to run the whole stack on this machine as a single node
+#[derive(Clone, Debug, Serialize, Deserialize, Subcommand)]
+pub enum Devnet {
+    /// Start form-state, form-p2p, form-dns, vmm-service (in mock mode)
+    /// and form-pack-manager in the background
+    Up(DevnetUp),
+    /// Stop the services started by `form kit devnet up`
+    Down(DevnetDown),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Args)]
+pub struct DevnetUp {
+    /// Directory the devnet's config, keys and logs are kept in
+    #[clap(long, short, default_value_os_t=default_devnet_dir())]
+    pub dir: PathBuf,
+    /// Directory the service binaries are in, e.g. `target/release`.
+    /// They're looked up on the PATH otherwise. vmm-service should be built
+    /// with the `devnet` feature, form-state runs with its local queue.
+    #[clap(long, short)]
+    pub bin_dir: Option<PathBuf>,
+    /// Password the operator key is encrypted with
+    #[clap(long, short, default_value=DEVNET_PASSWORD)]
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Args)]
+pub struct DevnetDown {
+    #[clap(long, short, default_value_os_t=default_devnet_dir())]
+    pub dir: PathBuf,
+    /// Also remove the devnet's config, keys and logs
+    #[clap(long)]
+    pub purge: bool,
+}
+
+pub fn default_devnet_dir() -> PathBuf {
+    default_data_dir().join("devnet")
+}
+
+/// A service started by `up`, recorded so `down` can stop it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DevnetProcess {
+    pub name: String,
+    pub pid: u32,
+    pub port: u16,
+    pub log: PathBuf,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DevnetState {
+    pub address: String,
+    pub services: Vec<DevnetProcess>,
+}
+
+impl DevnetState {
+    fn load(dir: &Path) -> Option<Self> {
+        serde_json::from_slice(&std::fs::read(dir.join(STATE_FILE)).ok()?).ok()
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(dir.join(STATE_FILE), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+struct ServiceSpec {
+    name: &'static str,
+    binary: &'static str,
+    port: u16,
+    args: Vec<String>,
+    env: Vec<(&'static str, String)>,
+}
+
+/// The services in the order they're started, each one listening on the
+/// port the rest of the stack expects it on
+fn services(config: &Path, password: &str) -> Vec<ServiceSpec> {
+    let config = config.display().to_string();
+    let args = |extra: &[&str]| ["-C", config.as_str(), "-p", password].iter().chain(extra).map(|s| s.to_string()).collect();
+    vec![
+        ServiceSpec {
+            name: "form-state",
+            binary: "form-state",
+            port: 3004,
+            args: args(&[]),
+            env: vec![
+                ("DEV_MODE", "true".to_string()),
+                ("AUTH_MODE", "development".to_string()),
+                ("ALLOW_INTERNAL_ENDPOINTS", "true".to_string()),
+                ("FORM_STATE_QUEUE_MODE", "local".to_string()),
+            ],
+        },
+        ServiceSpec {
+            name: "form-p2p",
+            binary: "form-p2p",
+            port: QUEUE_PORT,
+            args: args(&["run", "--permissive"]),
+            env: vec![],
+        },
+        ServiceSpec {
+            name: "form-dns",
+            binary: "form-dns",
+            port: 3005,
+            args: vec![],
+            env: vec![("FORM_DNS_API_ADDR", "127.0.0.1:3005".to_string())],
+        },
+        ServiceSpec {
+            name: "vmm-service",
+            binary: "vmm-service",
+            port: 3002,
+            args: args(&["run", "--mock"]),
+            env: vec![],
+        },
+        ServiceSpec {
+            name: "form-pack-manager",
+            binary: "form-pack-manager",
+            port: 3003,
+            args: ["--interface", "localhost", "--port", "3003", "--config", config.as_str(), "--password", password]
+                .iter().map(|s| s.to_string()).collect(),
+            env: vec![],
+        },
+    ]
+}
+
+impl DevnetUp {
+    pub async fn handle(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if DevnetState::load(&self.dir).is_some_and(|state| state.services.iter().any(|s| is_running(s.pid))) {
+            return Err(format!("A devnet is already running from {}, stop it with `form kit devnet down`", self.dir.display()).into());
+        }
+        std::fs::create_dir_all(self.dir.join("logs"))?;
+        let config_path = self.dir.join(OPERATOR_CONFIG_FILE);
+        let address = ensure_operator_config(&config_path, &self.password)?;
+
+        let specs = services(&config_path, &self.password);
+        let mut binaries = Vec::with_capacity(specs.len());
+        for spec in &specs {
+            if TcpListener::bind(("127.0.0.1", spec.port)).is_err() {
+                return Err(format!("Port {} needed by {} is already in use", spec.port, spec.name).into());
+            }
+            binaries.push(find_binary(spec.binary, self.bin_dir.as_deref())?);
+        }
+
+        println!("{} single node devnet for operator {}", "Starting".bold().blue(), address.bright_yellow());
+        let mut state = DevnetState { address, services: vec![] };
+        for (spec, binary) in specs.iter().zip(binaries) {
+            match start_service(spec, &binary, &self.dir).await {
+                Ok(process) => {
+                    println!("  {} {:<18} port {:<6} pid {}", "✓".green(), spec.name, spec.port, process.pid);
+                    state.services.push(process);
+                    state.save(&self.dir)?;
+                }
+                Err(e) => {
+                    println!("  {} {:<18} {e}", "✗".red(), spec.name);
+                    println!("{}", "Stopping the services already started...".yellow());
+                    stop_all(&state).await;
+                    let _ = std::fs::remove_file(self.dir.join(STATE_FILE));
+                    return Err(e);
+                }
+            }
+        }
+
+        println!("\n{} Logs are in {}", "Devnet is up.".bold().green(), self.dir.join("logs").display());
+        println!("Point the CLI at it with `form kit init` using host 127.0.0.1, stop it with `form kit devnet down`.");
+        Ok(())
+    }
+}
+
+impl DevnetDown {
+    pub async fn handle(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match DevnetState::load(&self.dir) {
+            Some(state) => {
+                stop_all(&state).await;
+                std::fs::remove_file(self.dir.join(STATE_FILE))?;
+                println!("{}", "Devnet stopped.".bold().green());
+            }
+            None => println!("No devnet is running from {}", self.dir.display()),
+        }
+        if self.purge && self.dir.exists() {
+            std::fs::remove_dir_all(&self.dir)?;
+            println!("Removed {}", self.dir.display());
+        }
+        Ok(())
+    }
+}
+
+/// Writes an operator config with a fresh key unless there is one, and
+/// returns the operator's address
+fn ensure_operator_config(path: &Path, password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(config) = OperatorConfig::from_file(path, true, Some(password)) {
+        return config.address.ok_or_else(|| format!("{} has no address", path.display()).into());
+    }
+    if path.exists() {
+        return Err(format!("Unable to read {}, is the password right?", path.display()).into());
+    }
+
+    let signing_key = SigningKey::random(&mut OsRng);
+    let public_key = signing_key.verifying_key();
+    let address = hex::encode(Address::from_public_key(public_key));
+    let config = OperatorConfig {
+        network_id: 1,
+        keyfile: path.with_file_name("keystore.json"),
+        secret_key: Some(hex::encode(encrypt_file(&signing_key.to_bytes(), password)?)),
+        mnemonic: None,
+        public_key: Some(hex::encode(public_key.to_sec1_bytes())),
+        address: Some(address.clone()),
+        initial_admin_public_key: Some(hex::encode(public_key.to_sec1_bytes())),
+        bootstrap_nodes: vec![],
+        bootstrap_domain: None,
+        is_bootstrap_node: true,
+        region: None,
+        datastore_port: 3004,
+        formnet_join_server_port: 3001,
+        formnet_service_port: 51820,
+        formnet_cidr: None,
+        vmm_service_port: 3002,
+        pack_manager_port: 3003,
+        event_queue_port: QUEUE_PORT,
+        contract_address: None,
+        formnet_refresh: Default::default(),
+        formnet_port_mapping: Default::default(),
+    };
+    std::fs::write(path, serde_json::to_vec_pretty(&config)?)?;
+    Ok(address)
+}
+
+fn find_binary(name: &str, bin_dir: Option<&Path>) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let found = match bin_dir {
+        Some(dir) => Some(dir.join(name)).filter(|path| path.is_file()),
+        None => std::env::var_os("PATH")
+            .and_then(|paths| std::env::split_paths(&paths).map(|dir| dir.join(name)).find(|path| path.is_file())),
+    };
+    found.ok_or_else(|| format!("{name} not found, build the workspace and pass --bin-dir target/release").into())
+}
+
+/// Starts a service in a process group of its own, so it outlives the CLI
+/// and can be stopped along with its children, and waits for its port
+async fn start_service(spec: &ServiceSpec, binary: &Path, dir: &Path) -> Result<DevnetProcess, Box<dyn std::error::Error>> {
+    let log = dir.join("logs").join(format!("{}.log", spec.name));
+    let out = File::create(&log)?;
+    let mut child = Command::new(binary)
+        .args(&spec.args)
+        .envs(spec.env.iter().map(|(k, v)| (*k, v.as_str())))
+        .env("RUST_LOG", "info")
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(out.try_clone()?)
+        .stderr(out)
+        .process_group(0)
+        .spawn()?;
+    let process = DevnetProcess { name: spec.name.to_string(), pid: child.id(), port: spec.port, log };
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let addr = SocketAddr::from(([127, 0, 0, 1], spec.port));
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(format!("exited with {status}, see {}", process.log.display()).into());
+        }
+        if TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_ok() {
+            return Ok(process);
+        }
+        if Instant::now() >= deadline {
+            signal_group(process.pid, "KILL");
+            return Err(format!("didn't listen on port {} within {}s, see {}", spec.port, STARTUP_TIMEOUT.as_secs(), process.log.display()).into());
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Stops the services in the reverse order they were started, killing the
+/// ones that don't exit in time
+async fn stop_all(state: &DevnetState) {
+    for process in state.services.iter().rev() {
+        if !is_running(process.pid) {
+            continue;
+        }
+        signal_group(process.pid, "TERM");
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while is_running(process.pid) && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        if is_running(process.pid) {
+            println!("  {} didn't stop in time, killing it", process.name);
+            signal_group(process.pid, "KILL");
+        } else {
+            println!("  Stopped {}", process.name);
+        }
+    }
+}
+
+fn signal_group(pid: u32, signal: &str) {
+    let _ = Command::new("kill")
+        .args([format!("-{signal}"), "--".to_string(), format!("-{pid}")])
+        .stderr(Stdio::null())
+        .status();
+}
+
+fn is_running(pid: u32) -> bool {
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
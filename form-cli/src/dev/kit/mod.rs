@@ -4,7 +4,9 @@ use clap::Subcommand;
 pub mod init;
 pub mod util;
 pub mod operator;
+pub mod devnet;
 pub use operator::*;
+pub use devnet::*;
 pub use init::*;
 pub use util::*;
 
@@ -12,5 +14,8 @@ pub use util::*;
 pub enum KitCommand {
     Init(Init),
     #[clap(subcommand)]
-    Operator(Operator)
+    Operator(Operator),
+    /// Run a single node devnet of the core services locally
+    #[clap(subcommand)]
+    Devnet(Devnet),
 }
@@ -17,6 +17,12 @@ pub struct Config {
     pub vmm_port: u16,
     pub formnet_port: u16,
     pub join_formnet: bool,
+    /// Ethereum JSON-RPC endpoint used by the wallet commands
+    #[serde(default)]
+    pub rpc_url: Option<String>,
+    /// Address of the staking contract on the chain behind `rpc_url`
+    #[serde(default)]
+    pub staking_contract: Option<String>,
 }
 
 impl Config {
@@ -63,6 +69,8 @@ impl Config {
             vmm_port: init.vmm_port.unwrap_or(3002),
             formnet_port: init.formnet_port.unwrap_or(3001),
             join_formnet: init.join_formnet.unwrap_or(true),
+            rpc_url: None,
+            staking_contract: None,
         }
     }
 }
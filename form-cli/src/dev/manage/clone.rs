@@ -0,0 +1,73 @@
+use clap::Args;
+use colored::*;
+use form_types::{CloneVmRequest, VmmResponse};
+use reqwest::{header::HeaderMap, Client};
+use sha2::{Digest, Sha256};
+use crate::{check_vmm_response, Keystore};
+use super::schedule::ScheduleAuth;
+
+/// Boot a copy of an instance as a new instance.
+///
+/// The disk of the instance is copied as it is, installed packages and
+/// data included, and the copy runs with the same Formfile and secrets. It
+/// joins formnet with its own IP, and gets a new machine-id and SSH host
+/// keys unless `--keep-identity` is given. Needs the Manager permission on
+/// the source, the copy is owned by you.
+#[derive(Clone, Debug, Args)]
+pub struct CloneCommand {
+    /// Build id of the instance to clone
+    #[clap(long, short)]
+    pub build_id: String,
+    /// Name of the copy, lowercase letters, digits and dashes
+    #[clap(long, short)]
+    pub name: String,
+    /// Keep the machine-id and SSH host keys of the source
+    #[clap(long)]
+    pub keep_identity: bool,
+    /// The node the source runs on, defaults to the configured provider
+    #[clap(long)]
+    pub host: Option<String>,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+impl CloneCommand {
+    pub async fn handle(&self, provider: &str, vmm_port: u16, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let host = self.host.as_deref().unwrap_or(provider);
+        let request = CloneVmRequest {
+            source: self.build_id.clone(),
+            name: self.name.clone(),
+            keep_identity: self.keep_identity,
+        };
+        let resp = Client::new()
+            .post(format!("http://{host}:{vmm_port}/v1/clone"))
+            .headers(self.signed_headers(keystore)?)
+            .json(&request)
+            .send().await?
+            .json::<VmmResponse>().await?;
+
+        let clone = check_vmm_response(resp)?;
+        println!(
+            "✅ Cloning {} as {}, build id {}",
+            self.build_id.bright_yellow(),
+            clone.name.bold().bright_cyan(),
+            clone.id.bright_yellow(),
+        );
+        println!("Run `form manage get-ip --build-id {}` once it has booted", clone.id);
+        Ok(())
+    }
+
+    /// Signed the same way as `form manage drain-node`
+    fn signed_headers(&self, keystore: Option<Keystore>) -> Result<HeaderMap, Box<dyn std::error::Error>> {
+        let signing_key = self.auth.get_signing_key(keystore)?;
+        let message = format!("clone:{}:{}:{}", self.build_id, self.name, chrono::Utc::now().timestamp());
+        let message_hash = Sha256::digest(message.as_bytes());
+        let (signature, recovery_id) = signing_key.sign_recoverable(&message_hash)?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature", hex::encode(signature.to_bytes()).parse()?);
+        headers.insert("X-Recovery-Id", recovery_id.to_byte().to_string().parse()?);
+        headers.insert("X-Message", hex::encode(message_hash).parse()?);
+        Ok(headers)
+    }
+}
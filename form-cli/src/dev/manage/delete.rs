@@ -198,10 +198,11 @@ impl DeleteCommand {
         let mut message_code = vec![2]; // Code 2 for delete operation (as seen in handle_message in API)
         message_code.extend(serde_json::to_vec(&delete_vm_request)?);
 
-        let queue_request = QueueRequest::Write {
-            content: message_code,
-            topic: hex::encode(topic_hash)
-        };
+        let queue_request = QueueRequest::signed_write(
+            hex::encode(topic_hash),
+            message_code,
+            &self.get_signing_key(keystore)?,
+        )?;
         
         Ok(queue_request)
     }
@@ -0,0 +1,146 @@
+use std::time::Duration;
+use clap::Args;
+use colored::*;
+use form_types::{DrainPhase, DrainPolicy, DrainRequest, DrainStatus, InstanceDrainState, VmmFailure};
+use reqwest::{header::HeaderMap, Client, StatusCode};
+use sha2::{Digest, Sha256};
+use crate::Keystore;
+use super::schedule::ScheduleAuth;
+
+/// Take a node out of rotation for maintenance.
+///
+/// The node stops accepting new instances, form-state stops placing
+/// instances on it and its running instances are migrated to other nodes
+/// (or stopped, with `--policy stop`). The node stays in maintenance once the
+/// drain is done, until `--cancel`.
+#[derive(Clone, Debug, Args)]
+pub struct DrainNodeCommand {
+    /// The node to drain, defaults to the configured provider
+    #[clap(long)]
+    pub host: Option<String>,
+    /// `migrate` replaces each instance on another node before removing it,
+    /// `stop` stops the instances
+    #[clap(long, default_value = "migrate", value_parser = parse_policy)]
+    pub policy: DrainPolicy,
+    /// Seconds to wait for each replacement before stopping the instance instead
+    #[clap(long)]
+    pub migrate_timeout: Option<u64>,
+    /// Why the node is going into maintenance, recorded in form-state
+    #[clap(long)]
+    pub reason: Option<String>,
+    /// Show the progress of the current drain instead of starting one
+    #[clap(long, conflicts_with = "cancel")]
+    pub status: bool,
+    /// Stop the drain and put the node back into rotation
+    #[clap(long)]
+    pub cancel: bool,
+    /// Keep printing progress until the drain is done
+    #[clap(long, short)]
+    pub watch: bool,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+fn parse_policy(policy: &str) -> Result<DrainPolicy, String> {
+    match policy {
+        "migrate" => Ok(DrainPolicy::Migrate),
+        "stop" => Ok(DrainPolicy::Stop),
+        other => Err(format!("unknown drain policy {other}, expected migrate or stop")),
+    }
+}
+
+impl DrainNodeCommand {
+    pub async fn handle(&self, provider: &str, vmm_port: u16, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let host = self.host.as_deref().unwrap_or(provider);
+        let endpoint = format!("http://{host}:{vmm_port}/v1/admin/drain");
+        let client = Client::new();
+
+        let status = if self.cancel {
+            let headers = self.signed_headers("cancel", keystore)?;
+            parse(client.post(format!("{endpoint}/cancel")).headers(headers).send().await?).await?
+        } else if self.status {
+            let headers = self.signed_headers("status", keystore.clone())?;
+            parse(client.get(&endpoint).headers(headers).send().await?).await?
+        } else {
+            let headers = self.signed_headers("start", keystore.clone())?;
+            let request = DrainRequest {
+                policy: self.policy,
+                migrate_timeout_secs: self.migrate_timeout,
+                reason: self.reason.clone(),
+            };
+            parse(client.post(&endpoint).headers(headers).json(&request).send().await?).await?
+        };
+        print_status(&status);
+
+        if self.watch && status.phase == DrainPhase::Draining {
+            let headers = self.signed_headers("status", keystore)?;
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                let status = parse(client.get(&endpoint).headers(headers.clone()).send().await?).await?;
+                let (done, total) = status.progress();
+                println!("{} {done}/{total} instances drained", "…".bright_blue());
+                if status.phase != DrainPhase::Draining {
+                    print_status(&status);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The vmm API authenticates with `X-Signature`, `X-Recovery-Id` and a
+    /// hex encoded message hash in `X-Message`
+    fn signed_headers(&self, action: &str, keystore: Option<Keystore>) -> Result<HeaderMap, Box<dyn std::error::Error>> {
+        let signing_key = self.auth.get_signing_key(keystore)?;
+        let message = format!("drain:{action}:{}", chrono::Utc::now().timestamp());
+        let message_hash = Sha256::digest(message.as_bytes());
+        let (signature, recovery_id) = signing_key.sign_recoverable(&message_hash)?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature", hex::encode(signature.to_bytes()).parse()?);
+        headers.insert("X-Recovery-Id", recovery_id.to_byte().to_string().parse()?);
+        headers.insert("X-Message", hex::encode(message_hash).parse()?);
+        Ok(headers)
+    }
+}
+
+async fn parse(resp: reqwest::Response) -> Result<DrainStatus, Box<dyn std::error::Error>> {
+    match resp.status() {
+        StatusCode::OK => Ok(resp.json::<DrainStatus>().await?),
+        StatusCode::UNAUTHORIZED => Err("The node rejected the request signature".into()),
+        status => match resp.json::<VmmFailure>().await {
+            Ok(failure) => {
+                println!("❌ {}: {}", "Drain request failed".red(), failure.message);
+                Err(failure.message.into())
+            }
+            Err(_) => Err(format!("Drain request failed with status {status}").into()),
+        },
+    }
+}
+
+fn print_status(status: &DrainStatus) {
+    let (done, total) = status.progress();
+    let phase = match status.phase {
+        DrainPhase::Draining => "draining".yellow(),
+        DrainPhase::Drained => "drained".green(),
+        DrainPhase::Cancelled => "back in rotation".green(),
+    };
+    println!("Node {} is {} ({:?} policy, {done}/{total} instances done)", status.node_id.bright_yellow(), phase, status.policy);
+    if !status.node_marked && status.phase != DrainPhase::Cancelled {
+        println!("⚠️  {}", "form-state has not marked the node as in maintenance yet".yellow());
+    }
+    for (instance_id, instance) in &status.instances {
+        let state = match &instance.state {
+            InstanceDrainState::Pending => "pending".normal(),
+            InstanceDrainState::Migrating => "migrating".yellow(),
+            InstanceDrainState::Migrated { node_id } => format!("migrated to {node_id}").green(),
+            InstanceDrainState::Stopped => "stopped".green(),
+            InstanceDrainState::Failed { reason } => format!("failed: {reason}").red(),
+        };
+        match &instance.detail {
+            Some(detail) => println!("  {instance_id} {state} ({detail})"),
+            None => println!("  {instance_id} {state}"),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use clap::Args;
+use colored::*;
+use futures::{stream::FuturesUnordered, StreamExt};
+use form_state::instances::{Instance, InstanceStatus};
+use form_state::nodes::Node;
+use form_types::{state::{Response, Success}, GuestResponse, OutputStream, VmmFailure, DEFAULT_EXEC_TIMEOUT_SECS};
+use reqwest::{header::HeaderMap, Client};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use crate::{dev::pack::deploy::fetch_instances, Keystore, VmmRequestError};
+use super::schedule::ScheduleAuth;
+
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
+/// Exit code of a command that ran out of time, as `timeout(1)` uses
+const TIMED_OUT_EXIT: i32 = 124;
+/// Exit code of an instance that couldn't be reached, as `ssh` uses
+const UNREACHABLE_EXIT: i32 = 255;
+
+/// Run a command in the instances of a build through their guest agent.
+///
+/// The command runs without a shell, use `-- sh -c '...'` for pipes and
+/// globs. Output is printed as the command writes it, prefixed with the
+/// instance when it runs on several. Needs the Manager permission on the
+/// instances, and every run is recorded in the audit log of their node.
+///
+/// Exits with the command's exit code, the first non-zero one when it ran
+/// on several instances, 124 if it timed out and 255 if an instance
+/// couldn't be reached.
+#[derive(Clone, Debug, Args)]
+pub struct ExecCommand {
+    /// Build id of the instances
+    pub build_id: String,
+    /// Run on one instance only, by instance id or the id of its node
+    #[clap(long)]
+    pub instance: Option<String>,
+    /// Environment variable of the command as KEY=VALUE, may be repeated
+    #[clap(long = "env", short = 'e')]
+    pub env: Vec<String>,
+    /// Seconds the command may run
+    #[clap(long, default_value_t = DEFAULT_EXEC_TIMEOUT_SECS)]
+    pub timeout: u64,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+    /// The program and its arguments
+    #[clap(last = true, required = true)]
+    pub command: Vec<String>,
+}
+
+/// How the command ended on one instance
+enum Outcome {
+    Exited(Option<i32>),
+    TimedOut,
+    Failed(String),
+}
+
+impl Outcome {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Exited(code) => code.unwrap_or(1),
+            Outcome::TimedOut => TIMED_OUT_EXIT,
+            Outcome::Failed(_) => UNREACHABLE_EXIT,
+        }
+    }
+}
+
+impl ExecCommand {
+    /// Runs the command and returns the exit code `form` should exit with
+    pub async fn handle(&self, provider: &str, vmm_port: u16, keystore: Option<Keystore>) -> Result<i32, Box<dyn std::error::Error>> {
+        let env = self.env.iter()
+            .map(|var| var.split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .ok_or_else(|| format!("{var} is not in the form KEY=VALUE")))
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        let targets = self.targets(provider).await?;
+        if targets.is_empty() {
+            return Err(format!("No running instances of {} to run the command on", self.build_id).into());
+        }
+
+        let (program, args) = self.command.split_first().ok_or("No command given")?;
+        let body = json!({
+            "build_id": self.build_id,
+            "program": program,
+            "args": args,
+            "env": env,
+            "timeout_secs": self.timeout,
+        });
+        let headers = self.signed_headers(keystore)?;
+        let prefixed = targets.len() > 1;
+
+        let client = Client::new();
+        let mut runs = targets.iter()
+            .map(|(instance, host)| {
+                let label = if prefixed { Some(short_id(&instance.node_id)) } else { None };
+                let request = client.post(format!("http://{host}:{vmm_port}/v1/guest/exec/stream"))
+                    .headers(headers.clone())
+                    .json(&body);
+                async move {
+                    let outcome = stream_output(request, label.as_deref()).await
+                        .unwrap_or_else(|e| Outcome::Failed(e.to_string()));
+                    (instance, outcome)
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut outcomes = vec![];
+        while let Some((instance, outcome)) = runs.next().await {
+            outcomes.push((instance, outcome));
+        }
+        outcomes.sort_by(|(a, _), (b, _)| a.node_id.cmp(&b.node_id));
+
+        for (instance, outcome) in &outcomes {
+            let node = short_id(&instance.node_id);
+            match outcome {
+                Outcome::Exited(Some(0)) if !prefixed => {}
+                Outcome::Exited(Some(0)) => eprintln!("{} {} exited with 0", "✔".bright_green(), node.bright_yellow()),
+                Outcome::Exited(code) => eprintln!(
+                    "{} {} exited with {}",
+                    "✘".bright_red(), node.bright_yellow(),
+                    code.map_or("a signal".to_string(), |code| code.to_string())
+                ),
+                Outcome::TimedOut => eprintln!("{} {} timed out after {}s", "✘".bright_red(), node.bright_yellow(), self.timeout),
+                Outcome::Failed(reason) => eprintln!("{} {} {reason}", "✘".bright_red(), node.bright_yellow()),
+            }
+        }
+        Ok(outcomes.iter().map(|(_, outcome)| outcome.exit_code()).find(|code| *code != 0).unwrap_or(0))
+    }
+
+    /// The instances to run on and the node each of them runs on
+    async fn targets(&self, provider: &str) -> Result<Vec<(Instance, String)>, Box<dyn std::error::Error>> {
+        let instances: Vec<Instance> = fetch_instances(provider, STATE_API_PORT, &self.build_id).await?
+            .into_iter()
+            .filter(|instance| match &self.instance {
+                Some(id) => instance.instance_id == *id || instance.node_id == *id,
+                None => matches!(instance.status, InstanceStatus::Booting | InstanceStatus::Ready),
+            })
+            .collect();
+        if instances.is_empty() {
+            if let Some(id) = &self.instance {
+                return Err(format!("{} has no instance {id}", self.build_id).into());
+            }
+        }
+
+        let mut targets = vec![];
+        for instance in instances {
+            let resp = Client::new()
+                .get(format!("http://{provider}:{STATE_API_PORT}/node/{}/get", instance.node_id))
+                .send().await?
+                .json::<Response<Node>>().await?;
+            match resp {
+                Response::Success(Success::Some(node)) => targets.push((instance, node.host.to_string())),
+                _ => return Err(format!("Unable to find node {} of instance {}", instance.node_id, instance.instance_id).into()),
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Signed the same way as `form manage clone`
+    fn signed_headers(&self, keystore: Option<Keystore>) -> Result<HeaderMap, Box<dyn std::error::Error>> {
+        let signing_key = self.auth.get_signing_key(keystore)?;
+        let message = format!("exec:{}:{}", self.build_id, chrono::Utc::now().timestamp());
+        let message_hash = Sha256::digest(message.as_bytes());
+        let (signature, recovery_id) = signing_key.sign_recoverable(&message_hash)?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature", hex::encode(signature.to_bytes()).parse()?);
+        headers.insert("X-Recovery-Id", recovery_id.to_byte().to_string().parse()?);
+        headers.insert("X-Message", hex::encode(message_hash).parse()?);
+        Ok(headers)
+    }
+}
+
+fn short_id(id: &str) -> String {
+    id.chars().take(12).collect()
+}
+
+/// Prints the output of a streamed run as it arrives, each line prefixed
+/// with `label` if given
+async fn stream_output(request: reqwest::RequestBuilder, label: Option<&str>) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let resp = request.send().await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        return match resp.json::<VmmFailure>().await {
+            Ok(failure) => Err(Box::new(VmmRequestError(failure))),
+            Err(_) => Err(format!("Request failed with status {status}").into()),
+        };
+    }
+
+    let mut output = LabeledOutput::new(label);
+    let mut body = resp.bytes_stream();
+    let mut pending = Vec::new();
+    while let Some(chunk) = body.next().await {
+        pending.extend_from_slice(&chunk?);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            match serde_json::from_slice::<GuestResponse>(&line)? {
+                GuestResponse::Output { stream, data } => output.write(stream, &data),
+                GuestResponse::Exit { exit_code, timed_out } => {
+                    output.flush();
+                    return Ok(if timed_out { Outcome::TimedOut } else { Outcome::Exited(exit_code) });
+                }
+                GuestResponse::Error { message } => {
+                    output.flush();
+                    return Ok(Outcome::Failed(message));
+                }
+                other => return Err(format!("Unexpected answer from the guest agent: {other:?}").into()),
+            }
+        }
+    }
+    output.flush();
+    Ok(Outcome::Failed("the connection closed before the command exited".to_string()))
+}
+
+/// Writes a command's output to this process's stdout and stderr. Labeled
+/// output is held back until a line is complete, so lines of instances
+/// running at once don't interleave.
+struct LabeledOutput<'a> {
+    label: Option<&'a str>,
+    partial: BTreeMap<bool, String>,
+}
+
+impl<'a> LabeledOutput<'a> {
+    fn new(label: Option<&'a str>) -> Self {
+        Self { label, partial: BTreeMap::new() }
+    }
+
+    fn write(&mut self, stream: OutputStream, data: &str) {
+        let is_stderr = stream == OutputStream::Stderr;
+        let Some(label) = self.label else {
+            print_raw(is_stderr, data);
+            return;
+        };
+        let partial = self.partial.entry(is_stderr).or_default();
+        partial.push_str(data);
+        if let Some(end) = partial.rfind('\n') {
+            let lines: String = partial.drain(..=end).collect();
+            let prefixed: String = lines.lines().map(|line| format!("[{}] {line}\n", label.bright_cyan())).collect();
+            print_raw(is_stderr, &prefixed);
+        }
+    }
+
+    fn flush(&mut self) {
+        for (is_stderr, partial) in std::mem::take(&mut self.partial) {
+            if !partial.is_empty() {
+                let label = self.label.unwrap_or_default();
+                print_raw(is_stderr, &format!("[{}] {partial}\n", label.bright_cyan()));
+            }
+        }
+    }
+}
+
+fn print_raw(is_stderr: bool, data: &str) {
+    if is_stderr {
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(data.as_bytes());
+        let _ = stderr.flush();
+    } else {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(data.as_bytes());
+        let _ = stdout.flush();
+    }
+}
@@ -0,0 +1,125 @@
+use clap::Args;
+use colored::*;
+use serde_json::{json, Value};
+use crate::Keystore;
+use super::schedule::ScheduleAuth;
+
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
+
+/// Freeze new deployments and scaling for maintenance.
+///
+/// Without `--on` or `--off` this shows whether the network or any node is
+/// frozen. While frozen, creating instances and scaling are refused, stopping
+/// and deleting instances keeps working. Changing maintenance is for admins.
+#[derive(Clone, Debug, Args)]
+pub struct MaintenanceCommand {
+    /// Start a freeze
+    #[clap(long, conflicts_with = "off")]
+    pub on: bool,
+    /// Lift the freeze
+    #[clap(long)]
+    pub off: bool,
+    /// Freeze a single node instead of the network
+    #[clap(long)]
+    pub node: Option<String>,
+    /// Why deployments are frozen, shown to everyone whose request is refused
+    #[clap(long)]
+    pub reason: Option<String>,
+    /// Lift the network freeze automatically at this time, an RFC 3339
+    /// timestamp or seconds since the epoch
+    #[clap(long, value_parser = parse_time, conflicts_with = "node")]
+    pub until: Option<i64>,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+fn parse_time(time: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = time.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    chrono::DateTime::parse_from_rfc3339(time)
+        .map(|time| time.timestamp())
+        .map_err(|e| format!("invalid time {time}: {e}"))
+}
+
+impl MaintenanceCommand {
+    pub async fn handle(&self, provider: &str, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let base = format!("http://{provider}:{STATE_API_PORT}/v1");
+        if !self.on && !self.off {
+            let resp = reqwest::get(format!("{base}/maintenance")).await?.json::<Value>().await?;
+            return print_maintenance(&resp);
+        }
+
+        let client = self.auth.signed_client("maintenance", keystore)?;
+        let body = json!({
+            "enabled": self.on,
+            "reason": self.reason,
+            "ends_at": self.until,
+        });
+        let endpoint = match &self.node {
+            Some(node) => format!("{base}/admin/maintenance/node/{node}"),
+            None => format!("{base}/admin/maintenance"),
+        };
+        let resp = client.post(endpoint).json(&body).send().await?.json::<Value>().await?;
+        if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+            println!("❌ {}: {}", "Maintenance request failed".red(), reason);
+            return Err(reason.to_string().into());
+        }
+
+        let target = match &self.node {
+            Some(node) => format!("node {}", node.bright_yellow()),
+            None => "the network".to_string(),
+        };
+        if self.on {
+            println!("✅ Deployments on {target} are {}", "frozen".yellow());
+        } else {
+            println!("✅ Deployments on {target} are {}", "allowed again".green());
+        }
+        Ok(())
+    }
+}
+
+fn print_maintenance(resp: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+        let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+        println!("❌ {}: {}", "Maintenance request failed".red(), reason);
+        return Err(reason.to_string().into());
+    }
+
+    let global = &resp["global"];
+    if resp["frozen"].as_bool().unwrap_or(false) {
+        println!("The network is {}", "frozen for maintenance".yellow());
+        if let Some(reason) = global["reason"].as_str() {
+            println!("  reason: {reason}");
+        }
+        println!("  set by: {}", global["updated_by"].as_str().unwrap_or("unknown"));
+        match global["ends_at"].as_i64().and_then(|ends_at| chrono::DateTime::from_timestamp(ends_at, 0)) {
+            Some(ends_at) => println!("  until:  {}", ends_at.to_rfc3339()),
+            None => println!("  until:  lifted by an admin"),
+        }
+    } else if global["enabled"].as_bool().unwrap_or(false) {
+        match global["starts_at"].as_i64().and_then(|starts_at| chrono::DateTime::from_timestamp(starts_at, 0)) {
+            Some(starts_at) if starts_at > chrono::Utc::now() => {
+                println!("The network is {}, a freeze starts at {}", "open".green(), starts_at.to_rfc3339());
+            }
+            _ => println!("The network is {}", "open".green()),
+        }
+    } else {
+        println!("The network is {}", "open".green());
+    }
+
+    let nodes = resp["nodes"].as_array().cloned().unwrap_or_default();
+    if !nodes.is_empty() {
+        println!("Nodes in maintenance:");
+        for node in nodes {
+            let node_id = node["node_id"].as_str().unwrap_or_default();
+            match node["reason"].as_str() {
+                Some(reason) => println!("  {} ({reason})", node_id.bright_yellow()),
+                None => println!("  {}", node_id.bright_yellow()),
+            }
+        }
+    }
+    Ok(())
+}
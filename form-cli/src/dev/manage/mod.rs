@@ -9,6 +9,12 @@ pub mod commit;
 pub mod config;
 pub mod join;
 pub mod account;
+pub mod schedule;
+pub mod quota;
+pub mod drain;
+pub mod maintenance;
+pub mod clone;
+pub mod exec;
 
 pub use start::StartCommand;
 pub use stop::StopCommand;
@@ -19,6 +25,12 @@ pub use commit::CommitCommand;
 pub use config::ConfigCommand;
 pub use join::{JoinCommand, FormnetUp};
 pub use account::TransferOwnershipCommand;
+pub use schedule::ScheduleCommand;
+pub use quota::QuotaCommand;
+pub use drain::DrainNodeCommand;
+pub use maintenance::MaintenanceCommand;
+pub use clone::CloneCommand;
+pub use exec::ExecCommand;
 
 #[derive(Debug, Subcommand)]
 pub enum ManageCommand {
@@ -37,6 +49,19 @@ pub enum ManageCommand {
     Leave(LeaveCommand),
     /// Transfer ownership of an instance from one account to another
     TransferOwnership(TransferOwnershipCommand),
+    /// Manage scheduled auto-stop, auto-delete and start/stop windows for an instance
+    #[clap(subcommand)]
+    Schedule(ScheduleCommand),
+    /// Show your account's instance, vCPU and memory quota and what is left of it
+    Quota(QuotaCommand),
+    /// Drain a node for maintenance, admins only
+    DrainNode(DrainNodeCommand),
+    /// Show or change the deployment freeze of the network or a node
+    Maintenance(MaintenanceCommand),
+    /// Boot a copy of an instance's disk as a new instance
+    Clone(CloneCommand),
+    /// Run a command in the instances of a build, e.g. `form manage exec <build_id> -- uname -a`
+    Exec(ExecCommand),
 }
 
 
@@ -0,0 +1,53 @@
+use clap::Args;
+use colored::*;
+use form_state::quotas::QuotaReport;
+use serde_json::Value;
+use crate::Keystore;
+use super::schedule::ScheduleAuth;
+
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
+
+/// Show your account's resource quota and how much of it is left
+#[derive(Clone, Debug, Args)]
+pub struct QuotaCommand {
+    /// Show another account's quota, admins only
+    #[clap(long)]
+    pub account: Option<String>,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+impl QuotaCommand {
+    pub async fn handle(&self, provider: &str, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let client = self.auth.signed_client("quota", keystore)?;
+        let mut request = client.get(format!("http://{provider}:{STATE_API_PORT}/v1/quota"));
+        if let Some(account) = &self.account {
+            request = request.query(&[("account", account)]);
+        }
+        let resp = request.send().await?.json::<Value>().await?;
+
+        if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+            println!("❌ {}: {}", "Quota request failed".red(), reason);
+            return Err(reason.to_string().into());
+        }
+        let report: QuotaReport = serde_json::from_value(resp["quota"].clone())?;
+
+        println!("Account: {} ({:?} tier)", report.account.green(), report.tier);
+        match (&report.quota, &report.remaining) {
+            (Some(quota), Some(remaining)) => {
+                println!("{:<10} {:>8} {:>8} {:>10}", "", "used", "limit", "remaining");
+                println!("{:<10} {:>8} {:>8} {:>10}", "instances", report.usage.instances, quota.max_instances, remaining.instances);
+                println!("{:<10} {:>8} {:>8} {:>10}", "vcpus", report.usage.vcpus, quota.max_vcpus, remaining.vcpus);
+                println!("{:<10} {:>8} {:>8} {:>10}", "memory_mb", report.usage.memory_mb, quota.max_memory_mb, remaining.memory_mb);
+            }
+            _ => {
+                println!("No quota, currently using {} instances, {} vCPUs and {} MB of memory",
+                    report.usage.instances, report.usage.vcpus, report.usage.memory_mb);
+            }
+        }
+
+        Ok(())
+    }
+}
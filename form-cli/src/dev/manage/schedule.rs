@@ -0,0 +1,313 @@
+use clap::{Args, Subcommand};
+use colored::*;
+use form_state::instances::{InstanceSchedule, ScheduleWindow};
+use k256::ecdsa::SigningKey;
+use alloy_core::primitives::Address;
+use alloy_signer_local::{coins_bip39::English, MnemonicBuilder};
+use reqwest::Client;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::Keystore;
+
+/// Default port of the form-state API
+const STATE_API_PORT: u16 = 3004;
+
+/// Manage scheduled lifecycle actions (auto-stop, auto-delete and
+/// start/stop windows) for an instance
+#[derive(Debug, Subcommand)]
+pub enum ScheduleCommand {
+    /// Show the current schedule of an instance
+    Show(ScheduleShowCommand),
+    /// Set (replace) the schedule of an instance
+    Set(ScheduleSetCommand),
+    /// Remove the schedule from an instance
+    Clear(ScheduleClearCommand),
+}
+
+/// Signing arguments shared by all schedule subcommands
+#[derive(Clone, Debug, Args)]
+pub struct ScheduleAuth {
+    /// A hexadecimal representation of a valid private key for
+    /// signing the request. Must be the owner of the instance
+    #[clap(long, short)]
+    pub private_key: Option<String>,
+    /// An altenrative to private key or mnemonic. If you have a keyfile
+    /// stored locally, you can use the keyfile to read in your private key
+    //TODO: Add support for HSM and other Enclave based key storage
+    #[clap(long, short)]
+    pub keyfile: Option<String>,
+    /// An alternative to private key or keyfile. If you have a 12 or 24 word
+    /// BIP39 compliant mnemonic phrase, you can use it to derive the signing
+    /// key for this request
+    //TODO: Add support for HSM and other Enclave based key storage
+    #[clap(long, short)]
+    pub mnemonic: Option<String>,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct ScheduleShowCommand {
+    /// The ID of the instance
+    #[clap(long, short)]
+    pub id: String,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct ScheduleSetCommand {
+    /// The ID of the instance
+    #[clap(long, short)]
+    pub id: String,
+    /// Stop the instance every day at this UTC time (HH:MM)
+    #[clap(long)]
+    pub stop_daily_at: Option<String>,
+    /// Delete the instance after it has not been running for this many days
+    #[clap(long)]
+    pub delete_after_idle_days: Option<u32>,
+    /// A start/stop window in the form `DAYS@HH:MM-HH:MM` (UTC), where DAYS
+    /// is a comma separated list of days (sun,mon,...) or ranges (mon-fri).
+    /// Can be provided multiple times, e.g. `--window mon-fri@08:00-18:00`
+    #[clap(long)]
+    pub window: Vec<String>,
+    /// Only replace the schedule if the instance is still at this revision,
+    /// as printed by `form manage schedule show`
+    #[clap(long)]
+    pub if_revision: Option<u64>,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct ScheduleClearCommand {
+    /// The ID of the instance
+    #[clap(long, short)]
+    pub id: String,
+    /// Only remove the schedule if the instance is still at this revision
+    #[clap(long)]
+    pub if_revision: Option<u64>,
+    #[clap(flatten)]
+    pub auth: ScheduleAuth,
+}
+
+impl ScheduleCommand {
+    pub async fn handle(&self, provider: &str, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            ScheduleCommand::Show(cmd) => {
+                let client = cmd.auth.signed_client(&cmd.id, keystore)?;
+                let resp = client
+                    .get(format!("http://{provider}:{STATE_API_PORT}/v1/instance/{}/schedule", cmd.id))
+                    .send()
+                    .await?
+                    .json::<Value>()
+                    .await?;
+                print_schedule_response(&cmd.id, &resp)
+            }
+            ScheduleCommand::Set(cmd) => {
+                let schedule = cmd.build_schedule()?;
+                let client = cmd.auth.signed_client(&cmd.id, keystore)?;
+                let resp = if_revision(client
+                    .post(format!("http://{provider}:{STATE_API_PORT}/v1/instance/{}/schedule/update", cmd.id)), cmd.if_revision)
+                    .json(&Some(schedule))
+                    .send()
+                    .await?
+                    .json::<Value>()
+                    .await?;
+                print_schedule_response(&cmd.id, &resp)
+            }
+            ScheduleCommand::Clear(cmd) => {
+                let client = cmd.auth.signed_client(&cmd.id, keystore)?;
+                let resp = if_revision(client
+                    .post(format!("http://{provider}:{STATE_API_PORT}/v1/instance/{}/schedule/update", cmd.id)), cmd.if_revision)
+                    .json(&Option::<InstanceSchedule>::None)
+                    .send()
+                    .await?
+                    .json::<Value>()
+                    .await?;
+                print_schedule_response(&cmd.id, &resp)
+            }
+        }
+    }
+}
+
+impl ScheduleSetCommand {
+    pub fn build_schedule(&self) -> Result<InstanceSchedule, Box<dyn std::error::Error>> {
+        let schedule = InstanceSchedule {
+            stop_daily_at: self.stop_daily_at.as_deref().map(parse_minute_of_day).transpose()?,
+            delete_after_idle_days: self.delete_after_idle_days,
+            windows: self.window.iter().map(|w| parse_window(w)).collect::<Result<Vec<_>, _>>()?,
+            last_action_at: 0,
+        };
+
+        if schedule.is_empty() {
+            return Err("At least one of --stop-daily-at, --delete-after-idle-days or --window is required. Use `form manage schedule clear` to remove a schedule".into());
+        }
+        schedule.validate()?;
+
+        Ok(schedule)
+    }
+}
+
+impl ScheduleAuth {
+    pub fn get_signing_key(&self, keystore: Option<Keystore>) -> Result<SigningKey, String> {
+        if let Some(pk) = &self.private_key {
+            Ok(SigningKey::from_slice(
+                    &hex::decode(pk)
+                        .map_err(|e| e.to_string())?
+                ).map_err(|e| e.to_string())?
+            )
+        } else if let Some(ks) = keystore {
+            Ok(SigningKey::from_slice(
+                &hex::decode(ks.secret_key)
+                    .map_err(|e| e.to_string())?
+                ).map_err(|e| e.to_string())?
+            )
+        } else if let Some(mnemonic) = &self.mnemonic {
+            Ok(SigningKey::from_slice(&MnemonicBuilder::<English>::default()
+                .phrase(mnemonic)
+                .derivation_path("m/44'/60'/0'/0/0").map_err(|e| e.to_string())?
+                .build().map_err(|e| e.to_string())?.to_field_bytes().to_vec()
+            ).map_err(|e| e.to_string())?)
+
+        } else {
+            Err("A signing key is required, use either private_key, mnemonic or keyfile CLI arg to provide a valid signing key".to_string())
+        }
+    }
+
+    /// Builds a client that authenticates with form-state using the
+    /// `Authorization: Signature <sig>.<recovery_id>.<message>` header
+    pub fn signed_client(&self, instance_id: &str, keystore: Option<Keystore>) -> Result<Client, Box<dyn std::error::Error>> {
+        let signing_key = self.get_signing_key(keystore)?;
+        println!("Request will be signed by address: {:x}", Address::from_private_key(&signing_key));
+
+        let message = format!("{}:{}", instance_id, chrono::Utc::now().timestamp());
+        let message_hash = Sha256::digest(message.as_bytes());
+        let (sig, rec) = signing_key.sign_recoverable(&message_hash)?;
+        let auth_header = format!(
+            "Signature {}.{}.{}",
+            hex::encode(sig.to_vec()),
+            rec.to_byte(),
+            hex::encode(message.as_bytes())
+        );
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            reqwest::header::HeaderValue::from_str(&auth_header)?,
+        );
+
+        Ok(Client::builder().default_headers(headers).build()?)
+    }
+}
+
+fn print_schedule_response(instance_id: &str, resp: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+        let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+        println!("❌ {}: {}", "Schedule request failed".red(), reason);
+        if let Some(current) = resp.get("current_revision").and_then(Value::as_u64) {
+            println!(
+                "   The instance changed since it was read. Check the schedule with {} and retry with {}",
+                "form manage schedule show".bright_blue(),
+                format!("--if-revision {current}").bright_blue(),
+            );
+        }
+        return Err(reason.to_string().into());
+    }
+
+    let schedule = resp.get("schedule")
+        .cloned()
+        .map(serde_json::from_value::<Option<InstanceSchedule>>)
+        .transpose()?
+        .flatten();
+
+    match schedule {
+        Some(schedule) => {
+            println!("Schedule for instance {}:", instance_id.bold().bright_yellow());
+            if let Some(minute) = schedule.stop_daily_at {
+                println!("  Stop daily at:       {} UTC", format_minute_of_day(minute).bright_cyan());
+            }
+            if let Some(days) = schedule.delete_after_idle_days {
+                println!("  Delete after idle:   {} days", days.to_string().bright_cyan());
+            }
+            for window in &schedule.windows {
+                let days: Vec<&str> = window.days.iter().map(|d| DAY_NAMES[*d as usize % 7]).collect();
+                println!(
+                    "  Running window:      {} {}-{} UTC",
+                    days.join(",").bright_cyan(),
+                    format_minute_of_day(window.start_minute).bright_cyan(),
+                    format_minute_of_day(window.end_minute).bright_cyan(),
+                );
+            }
+        }
+        None => println!("Instance {} has no schedule", instance_id.bold().bright_yellow()),
+    }
+    if let Some(revision) = resp.get("revision").and_then(Value::as_u64) {
+        println!("  Revision:            {}", revision.to_string().dimmed());
+    }
+
+    Ok(())
+}
+
+/// Makes a write conditional on the instance revision with `If-Match`
+fn if_revision(request: reqwest::RequestBuilder, revision: Option<u64>) -> reqwest::RequestBuilder {
+    match revision {
+        Some(revision) => request.header(reqwest::header::IF_MATCH, revision.to_string()),
+        None => request,
+    }
+}
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+fn format_minute_of_day(minute: u16) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+/// Parses `HH:MM` into a minute of the day
+fn parse_minute_of_day(s: &str) -> Result<u16, String> {
+    let (h, m) = s.split_once(':').ok_or_else(|| format!("Invalid time '{s}', expected HH:MM"))?;
+    let h: u16 = h.parse().map_err(|_| format!("Invalid hour in '{s}'"))?;
+    let m: u16 = m.parse().map_err(|_| format!("Invalid minute in '{s}'"))?;
+    if h > 23 || m > 59 {
+        return Err(format!("Invalid time '{s}', expected HH:MM"));
+    }
+    Ok(h * 60 + m)
+}
+
+fn parse_day(s: &str) -> Result<u8, String> {
+    let lower = s.trim().to_lowercase();
+    DAY_NAMES.iter()
+        .position(|d| lower.starts_with(d))
+        .map(|d| d as u8)
+        .ok_or_else(|| format!("Invalid day '{s}', expected one of {}", DAY_NAMES.join(", ")))
+}
+
+/// Parses `DAYS@HH:MM-HH:MM`, e.g. `mon-fri@08:00-18:00` or `sat,sun@10:00-14:00`
+fn parse_window(s: &str) -> Result<ScheduleWindow, String> {
+    let (days, times) = s.split_once('@').ok_or_else(|| format!("Invalid window '{s}', expected DAYS@HH:MM-HH:MM"))?;
+    let (start, end) = times.split_once('-').ok_or_else(|| format!("Invalid window '{s}', expected DAYS@HH:MM-HH:MM"))?;
+
+    let mut parsed_days = Vec::new();
+    for part in days.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (parse_day(from)?, parse_day(to)?);
+                let mut day = from;
+                loop {
+                    parsed_days.push(day);
+                    if day == to {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => parsed_days.push(parse_day(part)?),
+        }
+    }
+    parsed_days.sort();
+    parsed_days.dedup();
+
+    Ok(ScheduleWindow {
+        days: parsed_days,
+        start_minute: parse_minute_of_day(start)?,
+        end_minute: parse_minute_of_day(end)?,
+    })
+}
@@ -1,12 +1,13 @@
 use clap::Args;
 use colored::*;
 use form_p2p::queue::{QueueRequest, QueueResponse, QUEUE_PORT};
+use form_p2p::trace::{new_correlation_id, CORRELATION_HEADER};
 use form_types::{StopVmRequest, VmmResponse};
 use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
 use tiny_keccak::{Hasher, Sha3};
 use alloy_core::primitives::Address;
 use alloy_signer_local::{coins_bip39::English, MnemonicBuilder};
-use crate::Keystore;
+use crate::{print_trace_hint, Keystore};
 
 #[derive(Clone, Debug, Args)]
 pub struct StopCommand {
@@ -149,8 +150,10 @@ impl StopCommand {
         
         let queue_request = self.prepare_stop_request_queue(&id, keystore).await?; 
 
+        let correlation_id = new_correlation_id();
         let resp = reqwest::Client::new() 
             .post(&format!("http://{provider}:{}/queue/write_local", QUEUE_PORT))
+            .header(CORRELATION_HEADER, &correlation_id)
             .json(&queue_request)
             .send()
             .await?
@@ -158,6 +161,7 @@ impl StopCommand {
             .await?;
 
         print_stop_queue_response(resp, &id);
+        print_trace_hint(provider, QUEUE_PORT, &correlation_id);
 
         Ok(())
     }
@@ -228,10 +232,11 @@ impl StopCommand {
         let mut message_code = vec![3]; // Code 3 for stop operation (as seen in handle_message in API)
         message_code.extend(serde_json::to_vec(&stop_vm_request)?);
 
-        let queue_request = QueueRequest::Write {
-            content: message_code,
-            topic: hex::encode(topic_hash)
-        };
+        let queue_request = QueueRequest::signed_write(
+            hex::encode(topic_hash),
+            message_code,
+            &self.get_signing_key(keystore)?,
+        )?;
 
         Ok(queue_request)
     }
@@ -3,8 +3,14 @@ pub mod access;
 pub mod manage;
 pub mod kit;
 pub mod dns;
+pub mod vmm_error;
+pub mod queue_trace;
+pub mod plugin;
 
 pub use pack::*;
 pub use access::*;
 pub use kit::*;
 pub use dns::*;
+pub use vmm_error::*;
+pub use queue_trace::*;
+pub use plugin::*;
@@ -4,16 +4,17 @@ use clap::Args;
 use colored::Colorize;
 use crdts::bft_reg::RecoverableSignature;
 use form_p2p::queue::{QueueRequest, QueueResponse};
+use form_p2p::trace::{new_correlation_id, CORRELATION_HEADER};
 use k256::ecdsa::{RecoveryId, SigningKey, VerifyingKey, Signature};
 use tiny_keccak::{Hasher, Sha3};
 use std::path::PathBuf;
 use reqwest::{Client, multipart::Form};
 use form_pack::{
-    formfile::{BuildInstruction, Formfile, FormfileParser}, 
+    formfile::{Formfile, FormfileParser}, 
     manager::{PackBuildRequest, PackRequest, PackResponse}
 };
 use form_pack::pack::Pack;
-use crate::{default_context, default_formfile, Keystore};
+use crate::{default_context, default_formfile, print_trace_hint, Keystore};
 
 
 /// Create a new instance
@@ -143,8 +144,10 @@ impl BuildCommand {
             "📤".bright_blue(),
             "Sending build request...".bold());
 
+        let correlation_id = new_correlation_id();
         let resp: QueueResponse = match Client::new()
             .post(format!("http://{provider}:{queue_port}/queue/write_local"))
+            .header(CORRELATION_HEADER, &correlation_id)
             .json(&request)
             .send()
             .await {
@@ -199,6 +202,7 @@ impl BuildCommand {
             };
 
         print_queue_response(resp, build_id);
+        print_trace_hint(provider, queue_port, &correlation_id);
         Ok(())
     }
 
@@ -239,8 +243,9 @@ impl BuildCommand {
         let artifacts_path = self.build_pack()?;
         let artifact_bytes = std::fs::read(artifacts_path)?;
         let (signature, recovery_id, hash) = self.sign_payload(keystore.clone())?;
+        let signing_key = self.get_signing_key(keystore)?;
         let pack_request = PackRequest {
-            name: hex::encode(self.derive_name(&self.get_signing_key(keystore)?)?), 
+            name: hex::encode(self.derive_name(&signing_key)?), 
             formfile: self.parse_formfile()?,
             artifacts: artifact_bytes, 
         };
@@ -260,10 +265,11 @@ impl BuildCommand {
         let mut message_code = vec![0];
         message_code.extend(serde_json::to_vec(&pack_build_request)?);
 
-        let queue_request = QueueRequest::Write {
-            content: message_code,
-            topic: hex::encode(topic_hash)
-        };
+        let queue_request = QueueRequest::signed_write(
+            hex::encode(topic_hash),
+            message_code,
+            &signing_key,
+        )?;
 
         Ok((queue_request, build_id))
     }
@@ -301,12 +307,7 @@ impl BuildCommand {
         let pack = Pack::new(self.context_dir.clone()).map_err(|e| e.to_string())?;
 
         println!("   {} {}", "•".bright_blue(), "Gathering copy instructions...".dimmed());
-        let copy_instructions = self.parse_formfile()?.build_instructions.iter().filter_map(|inst| {
-            match inst {
-                BuildInstruction::Copy(to, from) => Some((to.clone(), from.clone())),
-                _ => None
-            }
-        }).collect::<Vec<(PathBuf, PathBuf)>>();
+        let copy_instructions = self.parse_formfile()?.context_copies();
 
         if !copy_instructions.is_empty() {
             println!("\n{}", "📋 Copy Instructions:".bold());
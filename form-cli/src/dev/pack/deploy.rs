@@ -0,0 +1,294 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use clap::Args;
+use colored::Colorize;
+use form_p2p::queue::{QueueResponse, QUEUE_PORT};
+use form_p2p::trace::{new_correlation_id, CORRELATION_HEADER};
+use form_state::instances::{Instance, InstanceStatus};
+use form_types::state::{Response as StateResponse, Success};
+use reqwest::Client;
+use crate::{check_vmm_response, default_context, default_formfile, Keystore};
+use super::{BuildCommand, ShipCommand};
+
+/// Builds, ships and waits for a FormPack to boot in a single step.
+///
+/// Every stage that fails returns an error, so `form` exits non-zero and
+/// the command can gate a CI pipeline.
+#[derive(Debug, Clone, Args)]
+pub struct DeployCommand {
+    /// Path to the context directory (e.g., . for current directory)
+    /// This should be the directory containing the Formfile and other artifacts
+    #[clap(default_value_os_t = default_context())]
+    pub context_dir: PathBuf,
+    /// The directory where the form pack artifacts can be found
+    #[clap(long, short, default_value_os_t = default_formfile(default_context()))]
+    pub formfile: PathBuf,
+    /// A hexadecimal representation of a valid private key for signing
+    /// the build and ship requests
+    #[clap(long, short)]
+    pub private_key: Option<String>,
+    /// An altenrative to private key or mnemonic. If you have a keyfile
+    /// stored locally, you can use the keyfile to read in your private key
+    #[clap(long, short)]
+    pub keyfile: Option<String>,
+    /// An alternative to private key or keyfile. A 12 or 24 word BIP39
+    /// compliant mnemonic phrase to derive the signing key from
+    #[clap(long, short)]
+    pub mnemonic: Option<String>,
+    /// Seconds to wait for the build to finish
+    #[clap(long, default_value_t = 900)]
+    pub build_timeout: u64,
+    /// Seconds to wait for the instances to boot after shipping
+    #[clap(long, default_value_t = 600)]
+    pub boot_timeout: u64,
+    /// Seconds between status checks
+    #[clap(long, default_value_t = 5)]
+    pub poll_interval: u64,
+}
+
+/// Where a deploy stage stands after a status check
+#[derive(Debug, PartialEq, Eq)]
+enum StageStatus {
+    Pending,
+    Done,
+    Failed(String),
+}
+
+impl DeployCommand {
+    fn build_command(&self) -> BuildCommand {
+        BuildCommand {
+            context_dir: self.context_dir.clone(),
+            formfile: self.formfile.clone(),
+            private_key: self.private_key.clone(),
+            keyfile: self.keyfile.clone(),
+            mnemonic: self.mnemonic.clone(),
+        }
+    }
+
+    fn ship_command(&self) -> ShipCommand {
+        ShipCommand {
+            context_dir: self.context_dir.clone(),
+            formfile: self.formfile.clone(),
+            private_key: self.private_key.clone(),
+            keyfile: self.keyfile.clone(),
+            mnemonic: self.mnemonic.clone(),
+        }
+    }
+
+    pub async fn handle(
+        &self,
+        provider: &str,
+        formpack_port: u16,
+        vmm_port: u16,
+        state_port: u16,
+        queue: bool,
+        keystore: Option<Keystore>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut build = self.build_command();
+        let signing_key = build.get_signing_key(keystore.clone())?;
+        let build_id = hex::encode(build.derive_name(&signing_key)?);
+
+        println!("\n{} {} {}\n",
+            "🚀".bright_blue(),
+            "Deploying build".bold(),
+            build_id.bright_yellow());
+
+        // Stage 1: submit the build and wait for it to finish
+        let submitted_at = now();
+        print_stage(1, "Submitting build");
+        if queue {
+            let correlation_id = new_correlation_id();
+            let (request, _) = build.pack_build_request_queue(keystore.clone()).await?;
+            let resp: QueueResponse = Client::new()
+                .post(format!("http://{provider}:{QUEUE_PORT}/queue/write_local"))
+                .header(CORRELATION_HEADER, &correlation_id)
+                .json(&request)
+                .send()
+                .await?
+                .json()
+                .await?;
+            check_queue_response(resp, "build")?;
+            println!("   {} {}", "↳".dimmed(), format!("Correlation ID {correlation_id}").dimmed());
+        } else {
+            build.handle(provider, formpack_port, keystore.clone()).await?;
+        }
+
+        print_stage(2, "Waiting for build to complete");
+        let instances = self.wait_for(provider, state_port, &build_id, self.build_timeout, |instances| {
+            build_status(instances, submitted_at)
+        }).await?;
+        println!("   {} {}", "✔".bright_green(), format!("Built {} instance(s)", instances.len()).dimmed());
+
+        // Stage 2: ship the build and wait for the instances to boot
+        let shipped_at = now();
+        print_stage(3, "Shipping build");
+        let mut ship = self.ship_command();
+        if queue {
+            let correlation_id = new_correlation_id();
+            let request = ship.pack_ship_request_queue(keystore.clone()).await?;
+            let resp: QueueResponse = Client::new()
+                .post(format!("http://{provider}:{QUEUE_PORT}/queue/write_local"))
+                .header(CORRELATION_HEADER, &correlation_id)
+                .json(&request)
+                .send()
+                .await?
+                .json()
+                .await?;
+            check_queue_response(resp, "ship")?;
+            println!("   {} {}", "↳".dimmed(), format!("Correlation ID {correlation_id}").dimmed());
+        } else {
+            check_vmm_response(ship.handle(provider, vmm_port, keystore).await?)?;
+        }
+
+        print_stage(4, "Waiting for instances to boot");
+        let instances = self.wait_for(provider, state_port, &build_id, self.boot_timeout, |instances| {
+            boot_status(instances, shipped_at)
+        }).await?;
+
+        print_deployment(&build_id, &instances);
+        Ok(())
+    }
+
+    /// Polls the instances of a build until `check` reports the stage is done or failed
+    async fn wait_for<F>(
+        &self,
+        provider: &str,
+        state_port: u16,
+        build_id: &str,
+        timeout: u64,
+        check: F,
+    ) -> Result<Vec<Instance>, Box<dyn std::error::Error>>
+    where
+        F: Fn(&[Instance]) -> StageStatus,
+    {
+        let started = Instant::now();
+        let mut last_summary = String::new();
+        loop {
+            match fetch_instances(provider, state_port, build_id).await {
+                Ok(instances) => {
+                    let summary = summarize(&instances);
+                    if summary != last_summary {
+                        println!("   {} {}", "•".bright_blue(), summary.dimmed());
+                        last_summary = summary;
+                    }
+                    match check(&instances) {
+                        StageStatus::Done => return Ok(instances),
+                        StageStatus::Failed(reason) => {
+                            println!("   {} {}", "✘".bright_red(), reason.bright_red());
+                            return Err(reason.into());
+                        }
+                        StageStatus::Pending => {}
+                    }
+                }
+                Err(e) => log::warn!("Unable to fetch status for build {build_id}: {e}"),
+            }
+
+            if started.elapsed() >= Duration::from_secs(timeout) {
+                return Err(format!("Timed out after {timeout}s waiting on build {build_id}").into());
+            }
+            tokio::time::sleep(Duration::from_secs(self.poll_interval)).await;
+        }
+    }
+}
+
+pub(crate) async fn fetch_instances(provider: &str, state_port: u16, build_id: &str) -> Result<Vec<Instance>, Box<dyn std::error::Error>> {
+    let resp = Client::new()
+        .get(format!("http://{provider}:{state_port}/instance/{build_id}/get_by_build_id"))
+        .send()
+        .await?
+        .json::<StateResponse<Instance>>()
+        .await?;
+
+    match resp {
+        StateResponse::Success(Success::List(instances)) => Ok(instances),
+        StateResponse::Success(Success::Some(instance)) => Ok(vec![instance]),
+        StateResponse::Success(Success::None) => Ok(vec![]),
+        StateResponse::Failure { reason } => Err(reason.unwrap_or_else(|| "unknown error".to_string()).into()),
+        _ => Err("Unexpected response from state".into()),
+    }
+}
+
+fn check_queue_response(resp: QueueResponse, stage: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match resp {
+        QueueResponse::OpSuccess => Ok(()),
+        QueueResponse::Failure { reason } => Err(format!(
+            "The {stage} request was rejected: {}",
+            reason.unwrap_or_else(|| "no reason given".to_string())
+        ).into()),
+        other => Err(format!("Unexpected response to the {stage} request: {other:?}").into()),
+    }
+}
+
+/// Instances touched before the request was submitted belong to an earlier deploy
+fn current(instances: &[Instance], since: i64) -> Vec<&Instance> {
+    instances.iter().filter(|instance| instance.updated_at >= since).collect()
+}
+
+fn build_status(instances: &[Instance], submitted_at: i64) -> StageStatus {
+    let instances = current(instances, submitted_at);
+    if instances.is_empty() {
+        return StageStatus::Pending;
+    }
+    if instances.iter().any(|instance| instance.status == InstanceStatus::Failed) {
+        return StageStatus::Failed("Build failed, check the build logs for details".to_string());
+    }
+    if instances.iter().all(|instance| instance.status != InstanceStatus::Building) {
+        return StageStatus::Done;
+    }
+    StageStatus::Pending
+}
+
+fn boot_status(instances: &[Instance], shipped_at: i64) -> StageStatus {
+    let instances = current(instances, shipped_at);
+    if instances.iter().any(|instance| instance.status == InstanceStatus::Failed) {
+        return StageStatus::Failed("An instance failed to boot".to_string());
+    }
+    let booted = instances.iter()
+        .any(|instance| instance.status == InstanceStatus::Ready && instance.formnet_ip.is_some());
+    if booted {
+        return StageStatus::Done;
+    }
+    StageStatus::Pending
+}
+
+fn summarize(instances: &[Instance]) -> String {
+    if instances.is_empty() {
+        return "No instances reported yet".to_string();
+    }
+    instances.iter()
+        .map(|instance| format!("{}: {}", &instance.instance_id[..8], instance.status.to_string().trim()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn print_stage(n: u8, label: &str) {
+    println!("{} {}", format!("[{n}/4]").bright_blue(), label.bold());
+}
+
+fn print_deployment(build_id: &str, instances: &[Instance]) {
+    println!("\n{} {}\n",
+        "✨".bright_green(),
+        "Deployment complete!".bold().bright_green());
+
+    println!("{}", "🌐 Instances:".bold());
+    for instance in instances.iter().filter(|instance| instance.status == InstanceStatus::Ready) {
+        let ip = instance.formnet_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "pending".to_string());
+        println!("   {} {} {}", "•".bright_blue(), instance.instance_id[..8].bright_yellow(), ip.bright_blue());
+        if let Some(record) = &instance.dns_record {
+            println!("     {} {}", "dns:".dimmed(), record.domain.bright_blue());
+        }
+    }
+
+    println!("\n{}\n{}\n",
+        "🔑 SSH Access".bold(),
+        format!("   form manage get-ip --build-id {build_id}").bright_blue());
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
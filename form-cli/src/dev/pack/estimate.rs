@@ -0,0 +1,230 @@
+use std::path::PathBuf;
+use clap::Args;
+use colored::Colorize;
+use form_pack::formfile::{Formfile, FormfileParser, SystemConfigOpt};
+use form_state::billing::SubscriptionTier;
+use form_state::pricing::{CostBreakdown, PricedResources, TierPricing, HOURS_PER_MONTH};
+use form_state::quotas::{QuotaReport, ResourceUsage};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::Value;
+use crate::{default_context, default_formfile};
+
+/// Estimates what running a Formfile costs, before building it.
+///
+/// With `--json` the estimate is printed as JSON, and `--max-monthly` makes
+/// the command fail when the estimate is over budget, so it can gate a CI
+/// pipeline.
+#[derive(Debug, Clone, Args)]
+pub struct EstimateCommand {
+    #[clap(default_value_os_t = default_formfile(default_context()))]
+    pub formfile: PathBuf,
+    /// Subscription tier to price with: free, pro, pro-plus, power or
+    /// power-plus. Defaults to the tier of `--account`, or free.
+    #[clap(long)]
+    pub tier: Option<String>,
+    /// Number of instances to run
+    #[clap(long, default_value_t = 1)]
+    pub replicas: u32,
+    /// Check the deployment against this account's quota and current usage
+    #[clap(long)]
+    pub account: Option<String>,
+    /// Fail if the monthly estimate is above this many credits
+    #[clap(long)]
+    pub max_monthly: Option<f64>,
+    /// Print the estimate as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaCheck {
+    /// Whose quota was checked, None for the tier's default quota
+    pub account: Option<String>,
+    pub within_quota: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Estimate {
+    pub name: String,
+    pub tier: SubscriptionTier,
+    pub replicas: u32,
+    pub resources: PricedResources,
+    /// Hourly cost of one instance, by resource
+    pub per_instance: CostBreakdown,
+    pub hourly: f64,
+    pub monthly: f64,
+    pub quota: QuotaCheck,
+    pub max_monthly: Option<f64>,
+    pub within_budget: bool,
+}
+
+fn parse_tier(tier: &str) -> Result<SubscriptionTier, String> {
+    match tier.to_lowercase().replace(['-', '_'], "").as_str() {
+        "free" => Ok(SubscriptionTier::Free),
+        "pro" => Ok(SubscriptionTier::Pro),
+        "proplus" => Ok(SubscriptionTier::ProPlus),
+        "power" => Ok(SubscriptionTier::Power),
+        "powerplus" => Ok(SubscriptionTier::PowerPlus),
+        _ => Err(format!("Unknown tier {tier}, expected free, pro, pro-plus, power or power-plus")),
+    }
+}
+
+fn priced_resources(formfile: &Formfile) -> PricedResources {
+    PricedResources {
+        vcpus: u32::from(formfile.get_vcpus()),
+        memory_mb: formfile.get_memory() as u64,
+        disk_gb: u64::from(formfile.get_storage().unwrap_or(5)),
+        gpus: formfile.system_config.iter().filter_map(|opt| match opt {
+            SystemConfigOpt::Gpu(request) => Some((request.model.clone(), u32::from(request.count))),
+            _ => None,
+        }).collect(),
+    }
+}
+
+impl EstimateCommand {
+    pub async fn handle(&self, provider: &str, state_port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        if self.replicas == 0 {
+            return Err("--replicas must be at least 1".into());
+        }
+        let content = std::fs::read_to_string(&self.formfile)?;
+        let formfile = FormfileParser::new().parse(&content)?;
+        let resources = priced_resources(&formfile);
+        let requested = ResourceUsage {
+            instances: 1,
+            vcpus: resources.vcpus,
+            memory_mb: resources.memory_mb,
+        }.times(self.replicas);
+
+        let client = Client::new();
+        let (report, rejection) = match &self.account {
+            Some(account) => self.check_account(&client, provider, state_port, account, &requested).await?,
+            None => (None, None),
+        };
+        let tier = match (&self.tier, &report) {
+            (Some(tier), _) => parse_tier(tier)?,
+            (None, Some(report)) => report.tier,
+            (None, None) => SubscriptionTier::Free,
+        };
+
+        let pricing = self.fetch_pricing(&client, provider, state_port).await?;
+        let pricing = pricing.into_iter()
+            .find(|pricing| pricing.tier == tier)
+            .ok_or_else(|| format!("The provider has no pricing for the {tier:?} tier"))?;
+
+        let per_instance = pricing.rates.hourly(&resources);
+        let hourly = per_instance.total() * f64::from(self.replicas);
+        let monthly = hourly * HOURS_PER_MONTH;
+        let quota = match (&self.account, rejection) {
+            (Some(account), rejection) => QuotaCheck {
+                account: Some(account.clone()),
+                within_quota: rejection.is_none(),
+                reason: rejection,
+            },
+            (None, _) => {
+                let reason = pricing.quota.check("", &requested).err().map(|e| format!(
+                    "{} {} is over the {:?} tier's quota of {}", e.requested, e.resource, tier, e.limit
+                ));
+                QuotaCheck { account: None, within_quota: reason.is_none(), reason }
+            }
+        };
+        let estimate = Estimate {
+            name: formfile.name.clone(),
+            tier,
+            replicas: self.replicas,
+            resources,
+            per_instance,
+            hourly,
+            monthly,
+            quota,
+            max_monthly: self.max_monthly,
+            within_budget: self.max_monthly.map_or(true, |max| monthly <= max),
+        };
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&estimate)?);
+        } else {
+            print_estimate(&estimate, &pricing);
+        }
+
+        if !estimate.within_budget {
+            return Err(format!(
+                "Estimated {:.2} credits per month, over the budget of {:.2}",
+                estimate.monthly, self.max_monthly.unwrap_or_default()
+            ).into());
+        }
+        Ok(())
+    }
+
+    async fn fetch_pricing(&self, client: &Client, provider: &str, state_port: u16) -> Result<Vec<TierPricing>, Box<dyn std::error::Error>> {
+        let resp = client.get(format!("http://{provider}:{state_port}/v1/pricing"))
+            .send()
+            .await?
+            .json::<Value>()
+            .await?;
+        if !resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+            return Err(format!("Unable to get pricing: {reason}").into());
+        }
+        Ok(serde_json::from_value(resp["pricing"].clone())?)
+    }
+
+    /// The account's quota report, and why the deployment doesn't fit in it
+    async fn check_account(
+        &self,
+        client: &Client,
+        provider: &str,
+        state_port: u16,
+        account: &str,
+        requested: &ResourceUsage,
+    ) -> Result<(Option<QuotaReport>, Option<String>), Box<dyn std::error::Error>> {
+        let resp = client.post(format!("http://{provider}:{state_port}/v1/quota/{account}/check"))
+            .json(requested)
+            .send()
+            .await?
+            .json::<Value>()
+            .await?;
+        if resp.get("success").and_then(Value::as_bool).unwrap_or(false) {
+            return Ok((Some(serde_json::from_value(resp["quota"].clone())?), None));
+        }
+        let reason = resp.get("error").and_then(Value::as_str).unwrap_or("unknown error").to_string();
+        Ok((None, Some(reason)))
+    }
+}
+
+fn print_estimate(estimate: &Estimate, pricing: &TierPricing) {
+    let resources = &estimate.resources;
+    println!("\n{} {} {}\n",
+        "💰".bright_blue(),
+        "Cost estimate for".bold(),
+        estimate.name.bright_yellow());
+
+    println!("{}", format!("📦 Per instance ({:?} tier, {}% discount):", estimate.tier, pricing.discount_percent).bold());
+    println!("   {:<12} {:>10} {:>14}", "resource", "amount", "credits/hour");
+    println!("   {:<12} {:>10} {:>14.2}", "vcpus", resources.vcpus, estimate.per_instance.vcpus);
+    println!("   {:<12} {:>10} {:>14.2}", "memory", format!("{} MB", resources.memory_mb), estimate.per_instance.memory);
+    println!("   {:<12} {:>10} {:>14.2}", "disk", format!("{} GB", resources.disk_gb), estimate.per_instance.disk);
+    for (model, count) in &resources.gpus {
+        println!("   {:<12} {:>10} {:>14.2}", "gpu", format!("{count}x {model}"), f64::from(*count) * pricing.rates.gpu_rate(model));
+    }
+
+    println!("\n{}", format!("📈 Total for {} replica(s):", estimate.replicas).bold());
+    println!("   • Hourly:  {}", format!("{:.2} credits", estimate.hourly).bright_green());
+    println!("   • Monthly: {}", format!("{:.2} credits", estimate.monthly).bright_green());
+    if let Some(max) = estimate.max_monthly {
+        if estimate.within_budget {
+            println!("   • Budget:  {}", format!("within {max:.2} credits").dimmed());
+        } else {
+            println!("   • Budget:  {}", format!("over {max:.2} credits").bright_red());
+        }
+    }
+
+    println!();
+    match (&estimate.quota.within_quota, &estimate.quota.reason) {
+        (true, _) => println!("{} {}", "✔".bright_green(), "Fits within the quota".dimmed()),
+        (false, Some(reason)) => println!("{} {}", "⚠".bright_yellow(), reason.bright_yellow()),
+        (false, None) => println!("{} {}", "⚠".bright_yellow(), "Over quota".bright_yellow()),
+    }
+    println!();
+}
@@ -0,0 +1,319 @@
+use std::path::{Path, PathBuf};
+use clap::Args;
+use colored::Colorize;
+use form_pack::formfile::FormfileParser;
+use form_pack::formignore::FORMIGNORE_FILE;
+use crate::default_context;
+
+/// Generates a Formfile for the project in a directory, without contacting
+/// the network
+#[derive(Debug, Clone, Args)]
+pub struct InitCommand {
+    /// The project directory, the Formfile is written here
+    #[clap(default_value_os_t = default_context())]
+    pub context_dir: PathBuf,
+    /// Name of the build, defaults to the project's package name
+    #[clap(long, short)]
+    pub name: Option<String>,
+    /// Also write a .formignore that leaves dependencies and build output out of the pack
+    #[clap(long)]
+    pub formignore: bool,
+    /// Overwrite an existing Formfile or .formignore
+    #[clap(long)]
+    pub force: bool,
+}
+
+/// Project types `form pack init` recognizes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectKind {
+    Rust { bin: String },
+    Node { has_lockfile: bool, build_script: bool, start_script: bool, main: Option<String> },
+    Python { pyproject: bool, script: Option<String>, module: String },
+    Go { bin: String },
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct DetectedProject {
+    pub name: String,
+    pub kind: ProjectKind,
+}
+
+impl InitCommand {
+    pub async fn handle(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let formfile_path = self.context_dir.join("Formfile");
+        let formignore_path = self.context_dir.join(FORMIGNORE_FILE);
+        if !self.force {
+            for path in [Some(&formfile_path), self.formignore.then_some(&formignore_path)].into_iter().flatten() {
+                if path.exists() {
+                    return Err(format!("{} already exists, use --force to overwrite it", path.display()).into());
+                }
+            }
+        }
+
+        let mut project = detect_project(&self.context_dir)?;
+        if let Some(name) = &self.name {
+            project.name = name.clone();
+        }
+        project.name = sanitize_name(&project.name);
+
+        let formfile = render_formfile(&project);
+        // Never write a template the build would reject
+        FormfileParser::new().parse(&formfile)?;
+        std::fs::write(&formfile_path, formfile)?;
+
+        let mut written = vec![formfile_path.display().to_string()];
+        if self.formignore {
+            std::fs::write(&formignore_path, render_formignore(&project.kind))?;
+            written.push(formignore_path.display().to_string());
+        }
+
+        let mut output = format!("\n{} {}\n\n{}\n   • Project: {}\n   • Name: {}\n",
+            "✨".bright_green(),
+            "Formfile created!".bold().bright_green(),
+            "📦 Detected:".bold(),
+            project.kind.label().bright_yellow(),
+            project.name.bright_yellow());
+
+        output.push_str(&format!("\n{}\n", "📄 Written:".bold()));
+        for path in written {
+            output.push_str(&format!("   • {}\n", path.dimmed()));
+        }
+
+        output.push_str(&format!("\n{}\n{}\n{}\n   {}\n",
+            "🚀 Next Steps:".bold(),
+            "   Review the build commands, resources and the commented USER line,".dimmed(),
+            "   then build with:".dimmed(),
+            "form pack build .".bright_blue()));
+
+        Ok(output)
+    }
+}
+
+impl ProjectKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ProjectKind::Rust { .. } => "Rust (Cargo.toml)",
+            ProjectKind::Node { .. } => "Node.js (package.json)",
+            ProjectKind::Python { pyproject: true, .. } => "Python (pyproject.toml)",
+            ProjectKind::Python { .. } => "Python (requirements.txt)",
+            ProjectKind::Go { .. } => "Go (go.mod)",
+            ProjectKind::Unknown => "Unknown, generic template",
+        }
+    }
+}
+
+/// Inspects the manifests in `dir`, checked in the order Cargo.toml,
+/// package.json, pyproject.toml, requirements.txt and go.mod
+pub fn detect_project(dir: &Path) -> Result<DetectedProject, Box<dyn std::error::Error>> {
+    let dir_name = dir.canonicalize()
+        .ok()
+        .and_then(|dir| dir.file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "app".to_string());
+    let read = |file: &str| std::fs::read_to_string(dir.join(file)).ok();
+
+    if let Some(cargo) = read("Cargo.toml") {
+        let name = toml_string(&cargo, "package", "name").unwrap_or(dir_name);
+        let bin = toml_string(&cargo, "bin", "name").unwrap_or_else(|| name.clone());
+        return Ok(DetectedProject { name, kind: ProjectKind::Rust { bin } });
+    }
+
+    if let Some(package) = read("package.json") {
+        let package: serde_json::Value = serde_json::from_str(&package)?;
+        let name = package["name"].as_str()
+            .map(|name| name.rsplit('/').next().unwrap_or(name).to_string())
+            .unwrap_or(dir_name);
+        return Ok(DetectedProject {
+            name,
+            kind: ProjectKind::Node {
+                has_lockfile: dir.join("package-lock.json").exists(),
+                build_script: package["scripts"]["build"].is_string(),
+                start_script: package["scripts"]["start"].is_string(),
+                main: package["main"].as_str().map(str::to_string),
+            },
+        });
+    }
+
+    let pyproject = read("pyproject.toml");
+    if pyproject.is_some() || dir.join("requirements.txt").exists() {
+        let content = pyproject.clone().unwrap_or_default();
+        let name = toml_string(&content, "project", "name")
+            .or_else(|| toml_string(&content, "tool.poetry", "name"))
+            .unwrap_or(dir_name);
+        let script = toml_first_key(&content, "project.scripts")
+            .or_else(|| toml_first_key(&content, "tool.poetry.scripts"));
+        let module = ["main.py", "app.py"].iter()
+            .find(|file| dir.join(file).exists())
+            .map(|file| file.trim_end_matches(".py").to_string())
+            .unwrap_or_else(|| name.replace('-', "_"));
+        return Ok(DetectedProject {
+            name,
+            kind: ProjectKind::Python { pyproject: pyproject.is_some(), script, module },
+        });
+    }
+
+    if let Some(go_mod) = read("go.mod") {
+        let module = go_mod.lines()
+            .find_map(|line| line.trim().strip_prefix("module "))
+            .map(|module| module.trim().trim_matches('"').to_string());
+        let bin = module.as_deref()
+            .and_then(|module| module.rsplit('/').next())
+            .map(str::to_string)
+            .unwrap_or_else(|| dir_name.clone());
+        return Ok(DetectedProject { name: bin.clone(), kind: ProjectKind::Go { bin } });
+    }
+
+    Ok(DetectedProject { name: dir_name, kind: ProjectKind::Unknown })
+}
+
+/// Reads `key = "value"` from a TOML table. Only plain string values on a
+/// single line are understood, which covers the manifest fields used here.
+fn toml_string(content: &str, table: &str, key: &str) -> Option<String> {
+    let header = format!("[{table}]");
+    let array_header = format!("[[{table}]]");
+    let mut in_table = false;
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_table = line == header || line == array_header;
+            continue;
+        }
+        if !in_table {
+            continue;
+        }
+        if let Some((k, v)) = line.split_once('=') {
+            if k.trim() == key {
+                let v = v.trim();
+                if let Some(value) = v.strip_prefix('"').and_then(|v| v.split('"').next()) {
+                    return Some(value.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn toml_first_key(content: &str, table: &str) -> Option<String> {
+    let header = format!("[{table}]");
+    content.lines()
+        .map(str::trim)
+        .skip_while(|line| *line != header)
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .find_map(|line| line.split_once('=').map(|(k, _)| k.trim().trim_matches('"').to_string()))
+}
+
+fn sanitize_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let name = name.trim_matches('-').to_string();
+    if name.is_empty() { "app".to_string() } else { name }
+}
+
+/// Builds the Formfile. Without COPY instructions the whole project is
+/// packed into WORKDIR, minus anything in .formignore.
+pub fn render_formfile(project: &DetectedProject) -> String {
+    let (vcpus, memory, disk) = match project.kind {
+        // Compiling Rust in the image needs room for the toolchain and target/
+        ProjectKind::Rust { .. } => (2, 4096, 15),
+        ProjectKind::Node { .. } | ProjectKind::Go { .. } => (1, 2048, 10),
+        ProjectKind::Python { .. } | ProjectKind::Unknown => (1, 1024, 5),
+    };
+
+    let mut lines = vec![
+        format!("NAME {}", project.name),
+        String::new(),
+        "# Uncomment to SSH into the instance".to_string(),
+        "# USER username:admin passwd:changeme sudo:true ssh_authorized_keys:\"<your public key>\"".to_string(),
+        String::new(),
+        format!("VCPU {vcpus}"),
+        format!("MEM {memory}"),
+        format!("DISK {disk}"),
+        String::new(),
+    ];
+
+    let (install, run, entrypoint): (Option<&str>, Vec<String>, Option<Vec<String>>) = match &project.kind {
+        ProjectKind::Rust { bin } => (
+            Some("build-essential curl pkg-config libssl-dev"),
+            vec![
+                "curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y --profile minimal".to_string(),
+                "cd /app && /root/.cargo/bin/cargo build --release".to_string(),
+            ],
+            Some(vec![format!("/app/target/release/{bin}")]),
+        ),
+        ProjectKind::Node { has_lockfile, build_script, start_script, main } => {
+            let mut run = vec![format!("cd /app && npm {}", if *has_lockfile { "ci" } else { "install" })];
+            if *build_script {
+                run.push("cd /app && npm run build".to_string());
+            }
+            let entrypoint = if *start_script {
+                vec!["npm".to_string(), "--prefix".to_string(), "/app".to_string(), "start".to_string()]
+            } else {
+                vec!["node".to_string(), format!("/app/{}", main.as_deref().unwrap_or("index.js"))]
+            };
+            (Some("nodejs npm"), run, Some(entrypoint))
+        }
+        ProjectKind::Python { pyproject, script, module } => {
+            let install_deps = if *pyproject { ".venv/bin/pip install ." } else { ".venv/bin/pip install -r requirements.txt" };
+            let entrypoint = match script {
+                Some(script) if *pyproject => vec![format!("/app/.venv/bin/{script}")],
+                _ => vec!["/app/.venv/bin/python".to_string(), "-m".to_string(), module.clone()],
+            };
+            (
+                Some("python3 python3-pip python3-venv"),
+                vec![format!("cd /app && python3 -m venv .venv && {install_deps}")],
+                Some(entrypoint),
+            )
+        }
+        ProjectKind::Go { bin } => (
+            Some("golang-go"),
+            vec![format!("cd /app && go build -o /app/{bin} .")],
+            Some(vec![format!("/app/{bin}")]),
+        ),
+        ProjectKind::Unknown => (None, Vec::new(), None),
+    };
+
+    lines.push("WORKDIR /app".to_string());
+    lines.push(String::new());
+    if let Some(packages) = install {
+        lines.push(format!("INSTALL {packages}"));
+        lines.push(String::new());
+    }
+    for command in run {
+        lines.push(format!("RUN {command}"));
+    }
+    if !lines.last().is_some_and(String::is_empty) {
+        lines.push(String::new());
+    }
+
+    match entrypoint {
+        Some(args) => {
+            let args: Vec<String> = args.iter().map(|arg| format!("\"{arg}\"")).collect();
+            lines.push(format!("ENTRYPOINT [{}]", args.join(", ")));
+        }
+        None => {
+            lines.push("# Set the command that starts your app".to_string());
+            lines.push("# ENTRYPOINT [\"/app/start.sh\"]".to_string());
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+pub fn render_formignore(kind: &ProjectKind) -> String {
+    let mut patterns = vec![".git/", ".env", "*.log", ".DS_Store"];
+    patterns.extend(match kind {
+        ProjectKind::Rust { .. } => vec!["target/"],
+        ProjectKind::Node { .. } => vec!["node_modules/", "dist/", "build/", ".next/"],
+        ProjectKind::Python { .. } => vec!["__pycache__/", "*.pyc", ".venv/", "venv/", "*.egg-info/", ".pytest_cache/"],
+        ProjectKind::Go { .. } => vec!["bin/"],
+        ProjectKind::Unknown => vec![],
+    });
+
+    let mut content = String::from("# Left out when `form pack build` packs this directory\n");
+    for pattern in patterns {
+        content.push_str(pattern);
+        content.push('\n');
+    }
+    content
+}
@@ -7,6 +7,10 @@ use dry_run::DryRunCommand;
 use status::StatusCommand;
 use clap::Args;
 use wizard::WizardCommand;
+use deploy::DeployCommand;
+use init::InitCommand;
+use watch::WatchCommand;
+use verify::VerifyCommand;
 
 pub mod build;
 pub mod validate;
@@ -14,6 +18,11 @@ pub mod ship;
 pub mod dry_run;
 pub mod status;
 pub mod wizard;
+pub mod deploy;
+pub mod init;
+pub mod watch;
+pub mod estimate;
+pub mod verify;
 
 pub use build::*;
 pub use validate::*;
@@ -21,6 +30,11 @@ pub use ship::*;
 pub use dry_run::*;
 pub use status::*;
 pub use wizard::*;
+pub use deploy::*;
+pub use init::*;
+pub use watch::*;
+pub use estimate::*;
+pub use verify::*;
 
 pub fn default_formfile(context: PathBuf) -> PathBuf {
     context.join("Formfile")
@@ -32,6 +46,8 @@ pub fn default_context() -> PathBuf {
 
 #[derive(Debug, Clone, Subcommand)]
 pub enum PackCommand {
+    /// Generates a Formfile for the project in the current directory
+    Init(InitCommand),
     /// Builds a FormPack from a directory
     Build(BuildCommand),
     /// Gets the status of a particular build
@@ -46,4 +62,12 @@ pub enum PackCommand {
     /// Interactive wizard to create and deploy an agent
     #[clap(name = "wizard")]
     Wizard(WizardCommand),
+    /// Builds, ships and waits for a FormPack to boot in one step
+    Deploy(DeployCommand),
+    /// Syncs changed files into a running DEVSYNC instance as you edit
+    Watch(WatchCommand),
+    /// Estimates what running a Formfile costs per hour and month
+    Estimate(EstimateCommand),
+    /// Verifies the signed manifest of a build against known nodes
+    Verify(VerifyCommand),
 }
\ No newline at end of file
@@ -4,11 +4,12 @@ use alloy_signer_local::{coins_bip39::English, MnemonicBuilder};
 use clap::Args;
 use colored::Colorize;
 use form_p2p::queue::{QueueRequest, QueueResponse, QUEUE_PORT};
+use form_p2p::trace::{new_correlation_id, CORRELATION_HEADER};
 use form_pack::formfile::{Formfile, FormfileParser};
 use form_types::{CreateVmRequest, VmmResponse};
 use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
 use tiny_keccak::{Hasher, Sha3};
-use crate::{default_context, default_formfile, Keystore};
+use crate::{default_context, default_formfile, print_trace_hint, Keystore};
 
 
 #[derive(Debug, Clone,  Args)]
@@ -162,8 +163,10 @@ impl ShipCommand {
     pub async fn handle_queue(&mut self, provider: &str, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
         let queue_request = self.pack_ship_request_queue(keystore).await?; 
 
+        let correlation_id = new_correlation_id();
         let resp = reqwest::Client::new() 
             .post(&format!("http://{provider}:{}/queue/write_local", QUEUE_PORT))
+            .header(CORRELATION_HEADER, &correlation_id)
             .json(&queue_request)
             .send()
             .await?
@@ -171,6 +174,7 @@ impl ShipCommand {
             .await?;
 
         print_ship_queue_response(resp);
+        print_trace_hint(provider, QUEUE_PORT, &correlation_id);
 
         Ok(())
     }
@@ -241,7 +245,8 @@ impl ShipCommand {
 
     pub async fn pack_ship_request_queue(&mut self, keystore: Option<Keystore>) -> Result<QueueRequest, Box<dyn std::error::Error>> {
         let (signature, recovery_id, hash) = self.sign_payload(keystore.clone())?;
-        let name = hex::encode(self.derive_name(&self.get_signing_key(keystore)?)?);
+        let signing_key = self.get_signing_key(keystore)?;
+        let name = hex::encode(self.derive_name(&signing_key)?);
         println!("Instance name: {name}");
         let create_vm_request = CreateVmRequest {
             name, 
@@ -267,10 +272,11 @@ impl ShipCommand {
         let mut message_code = vec![0];
         message_code.extend(serde_json::to_vec(&create_vm_request)?);
 
-        let queue_request = QueueRequest::Write {
-            content: message_code,
-            topic: hex::encode(topic_hash)
-        };
+        let queue_request = QueueRequest::signed_write(
+            hex::encode(topic_hash),
+            message_code,
+            &signing_key,
+        )?;
 
         Ok(queue_request)
     }
@@ -2,6 +2,8 @@ use clap::Args;
 use colored::Colorize;
 use form_types::state::{Response as StateResponse, Success};
 use form_state::instances::Instance;
+use form_state::instance_health::{HealthStatus, InstanceHealth};
+use form_state::build_manifests::{ImageScan, SignedBuildManifest, VulnerabilitySeverity};
 use reqwest::Client;
 use tabled::{Table, Tabled, settings::Style};
 use std::collections::HashMap;
@@ -47,10 +49,134 @@ impl StatusCommand {
 
         print_pack_status(status, self.build_id.clone());
 
+        // Nodes running an older form-state don't serve health
+        let health = match Client::new()
+            .get(&format!("http://{provider}:{port}/instance/list/health"))
+            .query(&[("build_id", &self.build_id)])
+            .send().await
+        {
+            Ok(resp) => resp.json::<StateResponse<InstanceHealth>>().await.ok(),
+            Err(_) => None,
+        };
+        if let Some(StateResponse::Success(Success::List(health))) = health {
+            print_health(&health);
+        }
+
+        // Builds without a manifest yet, or from nodes without a signing key,
+        // have no provenance or scan to show
+        let manifest = match Client::new()
+            .get(&format!("http://{provider}:{port}/v1/build/{}/manifest", self.build_id))
+            .send().await
+        {
+            Ok(resp) => resp.json::<serde_json::Value>().await.ok(),
+            Err(_) => None,
+        };
+        let signed = manifest
+            .and_then(|resp| serde_json::from_value::<SignedBuildManifest>(resp["manifest"].clone()).ok());
+        if let Some(signed) = &signed {
+            super::verify::print_manifest(signed);
+            println!("   Run `form pack verify {}` to check the signature\n", self.build_id);
+        }
+        if let Some(scan) = signed.as_ref().and_then(|signed| signed.manifest.scan.as_ref()) {
+            print_scan_summary(scan);
+        }
+
         Ok(())
     }
 }
 
+#[derive(Tabled)]
+struct HealthEntry {
+    #[tabled(rename = "Instance ID")]
+    instance_id: String,
+    #[tabled(rename = "Health")]
+    health: String,
+    #[tabled(rename = "Reasons")]
+    reasons: String,
+}
+
+pub fn print_health(health: &[InstanceHealth]) {
+    if health.is_empty() {
+        return;
+    }
+
+    let entries: Vec<HealthEntry> = health.iter().map(|health| HealthEntry {
+        instance_id: health.instance_id.chars().take(8).collect(),
+        health: match health.status {
+            HealthStatus::Healthy => health.status.to_string().bright_green().to_string(),
+            HealthStatus::Degraded => health.status.to_string().bright_yellow().to_string(),
+            HealthStatus::Unhealthy => health.status.to_string().bright_red().to_string(),
+        },
+        reasons: if health.reasons.is_empty() {
+            "-".to_string()
+        } else {
+            health.reasons.iter().map(|reason| reason.message.as_str()).collect::<Vec<_>>().join("\n")
+        },
+    }).collect();
+
+    println!("{}\n", "🩺 Instance Health".bold());
+    let mut table = Table::new(&entries);
+    table.with(Style::modern());
+    println!("{table}\n");
+}
+
+#[derive(Tabled)]
+struct ScanFinding {
+    #[tabled(rename = "Severity")]
+    severity: String,
+    #[tabled(rename = "ID")]
+    id: String,
+    #[tabled(rename = "Package")]
+    package: String,
+    #[tabled(rename = "Installed")]
+    installed: String,
+    #[tabled(rename = "Fixed In")]
+    fixed: String,
+}
+
+pub fn print_scan_summary(scan: &ImageScan) {
+    let counts = scan.counts();
+    let summary = [
+        VulnerabilitySeverity::Critical,
+        VulnerabilitySeverity::High,
+        VulnerabilitySeverity::Medium,
+        VulnerabilitySeverity::Low,
+        VulnerabilitySeverity::Unknown,
+    ].iter()
+        .map(|severity| format!("{} {severity}", counts.get(severity).copied().unwrap_or(0)))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    println!("{} {}\n   {}\n",
+        "🛡  Vulnerability Scan".bold(),
+        format!("({})", scan.scanner).dimmed(),
+        summary);
+
+    let serious: Vec<ScanFinding> = scan.at_least(VulnerabilitySeverity::High)
+        .into_iter()
+        .take(10)
+        .map(|finding| ScanFinding {
+            severity: finding.severity.to_string(),
+            id: finding.id.clone(),
+            package: finding.package.clone(),
+            installed: finding.installed_version.clone(),
+            fixed: finding.fixed_version.clone().unwrap_or_else(|| "-".to_string()),
+        })
+        .collect();
+    if !serious.is_empty() {
+        let mut table = Table::new(&serious);
+        table.with(Style::modern());
+        println!("{table}\n");
+    }
+
+    if let Some(violation) = &scan.policy_violation {
+        println!("{}\n   {}\n{}\n",
+            "❌ Blocked by Vulnerability Policy".bright_red(),
+            violation.bright_yellow(),
+            "   Update the affected packages in your Formfile and rebuild.".dimmed());
+    }
+}
+
 pub fn print_pack_status(status: StateResponse<Instance>, build_id: String) {
     match status {
         StateResponse::Success(Success::List(instances)) => {
@@ -108,7 +234,7 @@ pub fn print_pack_status(status: StateResponse<Instance>, build_id: String) {
                     "   Run this command again to check for updates.".dimmed());
             }
 
-            if status_groups.contains_key("Built") || status_groups.contains_key("Created") {
+            if status_groups.contains_key("Built") {
                 println!("{}\n{}\n{}\n",
                     "✨ Ready to Ship".bright_green(),
                     "   To deploy your instances, run:".dimmed(),
@@ -142,7 +268,7 @@ pub fn print_pack_status(status: StateResponse<Instance>, build_id: String) {
                 }
             }
 
-            if status_groups.contains_key("Started") {
+            if status_groups.contains_key("Booting") || status_groups.contains_key("Ready") {
                 println!("{}\n{}\n{}\n",
                     "🚀 Instances Running".bright_green(),
                     "   To get updated formnet IP addresses, run:".dimmed(),
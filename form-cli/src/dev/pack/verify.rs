@@ -0,0 +1,162 @@
+use std::path::PathBuf;
+use clap::Args;
+use colored::Colorize;
+use form_pack::formfile::FormfileParser;
+use form_state::build_manifests::{canonical_digest, file_digest, ImageVerification, SignedBuildManifest};
+use form_state::nodes::Node;
+use form_types::state::{Response as StateResponse, Success};
+use reqwest::Client;
+
+/// Verifies the signed manifest of a build.
+///
+/// The builder node signs a manifest with the digests of the Formfile, the
+/// builder image and the produced disk image. This re-fetches the manifest
+/// from form-state, checks its signature and that the signer is a node the
+/// network knows. Pass `--formfile` to check the image was built from your
+/// Formfile, and `--image` to check a local copy of the disk image. The
+/// checks nodes made of the image before booting it are listed as well.
+#[derive(Debug, Clone, Args)]
+pub struct VerifyCommand {
+    /// The build ID returned by `form pack build`
+    pub build_id: String,
+    /// Check the manifest against this Formfile. Formfiles with DEVSYNC
+    /// are stamped with the owner on the builder and won't match.
+    #[clap(long)]
+    pub formfile: Option<PathBuf>,
+    /// Check the manifest against this disk image
+    #[clap(long)]
+    pub image: Option<PathBuf>,
+}
+
+impl VerifyCommand {
+    pub async fn handle(&self, provider: &str, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let resp = Client::new()
+            .get(format!("http://{provider}:{port}/v1/build/{}/manifest", self.build_id))
+            .send().await?
+            .json::<serde_json::Value>().await?;
+        if !resp["success"].as_bool().unwrap_or(false) {
+            let reason = resp["error"].as_str().unwrap_or("no manifest");
+            println!("❌ {}: {reason}", "Unable to fetch the build manifest".red());
+            return Err(reason.to_string().into());
+        }
+        let signed: SignedBuildManifest = serde_json::from_value(resp["manifest"].clone())?;
+
+        print_manifest(&signed);
+
+        let mut failures = vec![];
+        let mut check = |name: &str, result: Result<String, String>| match result {
+            Ok(detail) => println!("   {} {name}: {}", "✔".bright_green(), detail.dimmed()),
+            Err(reason) => {
+                println!("   {} {name}: {}", "✘".bright_red(), reason.bright_red());
+                failures.push(name.to_string());
+            }
+        };
+
+        println!("{}", "🔏 Verification".bold());
+        check("build id", if signed.manifest.build_id == self.build_id {
+            Ok(self.build_id.clone())
+        } else {
+            Err(format!("manifest is for {}", signed.manifest.build_id))
+        });
+        check("signature", signed.verify().map(|()| format!("signed by {}", signed.manifest.builder_node)));
+        check("builder", known_node(provider, port, &signed.manifest.builder_node).await
+            .map(|node| format!("registered node owned by {}", node.node_owner)));
+
+        if let Some(path) = &self.formfile {
+            let content = std::fs::read_to_string(path)?;
+            let formfile = FormfileParser::new().parse(&content)?;
+            let digest = canonical_digest(&formfile)?;
+            check("formfile", matches(&digest, &signed.manifest.formfile_digest));
+        }
+        if let Some(path) = &self.image {
+            let digest = file_digest(path)?;
+            check("image", matches(&digest, &signed.manifest.image_digest));
+        }
+        if let Some(violation) = signed.manifest.scan.as_ref().and_then(|scan| scan.policy_violation.as_ref()) {
+            println!("   {} the image is blocked by the vulnerability policy: {}", "⚠".bright_yellow(), violation);
+        }
+        println!();
+        print_boot_checks(provider, port, &self.build_id).await;
+
+        if failures.is_empty() {
+            println!("✅ Build {} is signed by a known node", self.build_id.bright_yellow());
+            Ok(())
+        } else {
+            println!("❌ {} {}", "Verification failed:".bright_red(), failures.join(", "));
+            Err(format!("Build {} failed verification: {}", self.build_id, failures.join(", ")).into())
+        }
+    }
+}
+
+fn matches(local: &str, signed: &str) -> Result<String, String> {
+    if local.eq_ignore_ascii_case(signed) {
+        Ok(local.to_string())
+    } else {
+        Err(format!("local digest {local} doesn't match {signed}"))
+    }
+}
+
+/// The node record of the builder, node ids are stored with and without a
+/// 0x prefix
+async fn known_node(provider: &str, port: u16, node_id: &str) -> Result<Node, String> {
+    let bare = node_id.trim_start_matches("0x");
+    for id in [bare.to_string(), format!("0x{bare}")] {
+        let resp = Client::new()
+            .get(format!("http://{provider}:{port}/v1/node/{id}/get"))
+            .send().await
+            .map_err(|e| format!("unable to reach form-state: {e}"))?
+            .json::<StateResponse<Node>>().await
+            .map_err(|e| format!("invalid response from form-state: {e}"))?;
+        if let StateResponse::Success(Success::Some(node)) = resp {
+            return Ok(node);
+        }
+    }
+    Err(format!("{node_id} is not a registered node"))
+}
+
+/// The digests and signature of a build manifest
+pub fn print_manifest(signed: &SignedBuildManifest) {
+    let manifest = &signed.manifest;
+    let built_at = chrono::DateTime::from_timestamp(manifest.built_at, 0)
+        .map(|built_at| built_at.to_rfc3339())
+        .unwrap_or_else(|| manifest.built_at.to_string());
+    println!("{}", "📜 Build Manifest".bold());
+    println!("   builder:     {}", manifest.builder_node.bright_yellow());
+    println!("   built at:    {built_at}");
+    println!("   formfile:    {}", manifest.formfile_digest);
+    println!("   base image:  {}", manifest.base_image_digest);
+    println!("   image:       {}", manifest.image_digest);
+    if let Some(verity) = &manifest.verity {
+        println!("   verity root: {} ({}, {} blocks)", verity.root_hash, verity.hash_algorithm, verity.data_blocks);
+    }
+    println!("   signature:   {}", signed.signature.dimmed());
+    println!();
+}
+
+/// How the nodes asked to boot the build found its image
+async fn print_boot_checks(provider: &str, port: u16, build_id: &str) {
+    let verifications = match Client::new()
+        .get(format!("http://{provider}:{port}/v1/build/{build_id}/verification"))
+        .send().await
+    {
+        Ok(resp) => resp.json::<serde_json::Value>().await.ok()
+            .and_then(|resp| serde_json::from_value::<Vec<ImageVerification>>(resp["verifications"].clone()).ok())
+            .unwrap_or_default(),
+        Err(_) => vec![],
+    };
+    if verifications.is_empty() {
+        return;
+    }
+    println!("{}", "🖥  Boot checks".bold());
+    for check in verifications {
+        let method = match &check.root_hash {
+            Some(root_hash) => format!("dm-verity {}", &root_hash[..root_hash.len().min(16)]),
+            None => "image digest".to_string(),
+        };
+        match &check.error {
+            None => println!("   {} {}: {}", "✔".bright_green(), check.node_id, method.dimmed()),
+            Some(error) => println!("   {} {}: {}", "✘".bright_red(), check.node_id, error.bright_red()),
+        }
+    }
+    println!();
+}
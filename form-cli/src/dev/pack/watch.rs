@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use clap::Args;
+use colored::Colorize;
+use form_pack::formignore::FormIgnore;
+use form_types::{FileManifest, SignedSyncRequest, SyncAction, SyncFile, SyncRequest, SyncResponse};
+use k256::ecdsa::SigningKey;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use crate::{default_context, default_formfile, Keystore};
+use super::BuildCommand;
+
+/// Mirrors the build context into a running dev instance as it changes.
+///
+/// The instance has to be built from a Formfile with `DEVSYNC`. Only files
+/// whose hashes differ from the instance's copy are sent, paths matched by
+/// `.formignore` are neither sent nor deleted on the instance.
+#[derive(Debug, Clone, Args)]
+pub struct WatchCommand {
+    /// Path to the context directory (e.g., . for current directory)
+    #[clap(default_value_os_t = default_context())]
+    pub context_dir: PathBuf,
+    /// The Formfile the instance was built from
+    #[clap(long, short, default_value_os_t = default_formfile(default_context()))]
+    pub formfile: PathBuf,
+    /// A hexadecimal representation of the private key that owns the build
+    #[clap(long, short)]
+    pub private_key: Option<String>,
+    /// An altenrative to private key or mnemonic. If you have a keyfile
+    /// stored locally, you can use the keyfile to read in your private key
+    #[clap(long, short)]
+    pub keyfile: Option<String>,
+    /// An alternative to private key or keyfile. A 12 or 24 word BIP39
+    /// compliant mnemonic phrase to derive the signing key from
+    #[clap(long, short)]
+    pub mnemonic: Option<String>,
+    /// The node running the instance, defaults to the configured provider
+    #[clap(long)]
+    pub host: Option<String>,
+    /// Milliseconds between scans of the context
+    #[clap(long, default_value_t = 1000)]
+    pub interval: u64,
+    /// Apply changes without restarting the app
+    #[clap(long)]
+    pub no_restart: bool,
+}
+
+impl WatchCommand {
+    fn build_command(&self) -> BuildCommand {
+        BuildCommand {
+            context_dir: self.context_dir.clone(),
+            formfile: self.formfile.clone(),
+            private_key: self.private_key.clone(),
+            keyfile: self.keyfile.clone(),
+            mnemonic: self.mnemonic.clone(),
+        }
+    }
+
+    pub async fn handle(&self, provider: &str, formpack_port: u16, keystore: Option<Keystore>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut build = self.build_command();
+        let signing_key = build.get_signing_key(keystore)?;
+        let build_id = hex::encode(build.derive_name(&signing_key)?);
+        if build.parse_formfile()?.get_dev_sync().is_none() {
+            return Err("The Formfile has no DEVSYNC directive, add one and rebuild to sync files into the instance".into());
+        }
+        let host = self.host.as_deref().unwrap_or(provider);
+        let endpoint = format!("http://{host}:{formpack_port}/v1/dev/{build_id}/sync");
+        let ignore = FormIgnore::load(&self.context_dir)?;
+
+        let mut remote = match send(&endpoint, &signing_key, &build_id, SyncAction::Manifest).await? {
+            SyncResponse::Manifest(manifest) => manifest,
+            other => return Err(unexpected(other).into()),
+        };
+        println!("\n{} {} {}\n",
+            "👀".bright_blue(),
+            "Watching".bold(),
+            self.context_dir.display().to_string().bright_yellow());
+
+        loop {
+            let (local, modes) = scan(&self.context_dir, &ignore)?;
+            let (changed, mut deleted) = local.diff(&remote);
+            // Files the context ignores belong to the instance, e.g. build output
+            deleted.retain(|path| !ignore.is_ignored_file(path));
+
+            if !changed.is_empty() || !deleted.is_empty() {
+                let files = changed.iter()
+                    .map(|path| Ok(SyncFile {
+                        path: path.clone(),
+                        mode: modes.get(path).copied().unwrap_or(0o644),
+                        data: base64::engine::general_purpose::STANDARD.encode(std::fs::read(self.context_dir.join(path))?),
+                    }))
+                    .collect::<Result<Vec<_>, std::io::Error>>()?;
+                let action = SyncAction::Apply { files, deleted: deleted.clone(), restart: !self.no_restart };
+                match send(&endpoint, &signing_key, &build_id, action).await {
+                    Ok(SyncResponse::Applied { written, deleted: removed, restarted }) => {
+                        println!("{} {} written, {} deleted{}",
+                            "✔".bright_green(),
+                            written,
+                            removed,
+                            if restarted { ", app restarted" } else { "" });
+                        for path in &changed {
+                            remote.files.insert(path.clone(), local.files[path].clone());
+                        }
+                        for path in &deleted {
+                            remote.files.remove(path);
+                        }
+                    }
+                    // The next scan retries whatever didn't make it
+                    Ok(other) => println!("{} {}", "✘".bright_red(), unexpected(other)),
+                    Err(e) => println!("{} Sync failed: {e}", "✘".bright_red()),
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(self.interval)).await;
+        }
+    }
+}
+
+async fn send(endpoint: &str, signing_key: &SigningKey, build_id: &str, action: SyncAction) -> Result<SyncResponse, Box<dyn std::error::Error>> {
+    let request = serde_json::to_string(&SyncRequest {
+        build_id: build_id.to_string(),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        action,
+    })?;
+    let (signature, recovery_id) = signing_key.sign_recoverable(request.as_bytes())?;
+    let signed = SignedSyncRequest {
+        request,
+        signature: hex::encode(signature.to_bytes()),
+        recovery_id: recovery_id.to_byte(),
+    };
+    Ok(Client::new().post(endpoint).json(&signed).send().await?.json().await?)
+}
+
+fn unexpected(response: SyncResponse) -> String {
+    match response {
+        SyncResponse::Failure { reason } => format!("The instance refused the sync: {reason}"),
+        other => format!("Unexpected response from the instance: {other:?}"),
+    }
+}
+
+/// Hashes the files in the context that aren't ignored, along with their
+/// permission bits
+fn scan(context: &Path, ignore: &FormIgnore) -> Result<(FileManifest, HashMap<String, u32>), std::io::Error> {
+    let mut manifest = FileManifest::default();
+    let mut modes = HashMap::new();
+    scan_dir(context, Path::new(""), ignore, &mut manifest, &mut modes)?;
+    Ok((manifest, modes))
+}
+
+fn scan_dir(
+    dir: &Path,
+    relative: &Path,
+    ignore: &FormIgnore,
+    manifest: &mut FileManifest,
+    modes: &mut HashMap<String, u32>,
+) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let relative = relative.join(entry.file_name());
+        if ignore.is_ignored(&relative, file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_dir() {
+            scan_dir(&entry.path(), &relative, ignore, manifest, modes)?;
+        } else if file_type.is_file() {
+            let path = relative.to_string_lossy().replace('\\', "/");
+            let hash = Sha256::digest(std::fs::read(entry.path())?);
+            modes.insert(path.clone(), entry.metadata()?.permissions().mode());
+            manifest.files.insert(path, hex::encode(hash));
+        }
+    }
+    Ok(())
+}
@@ -4,7 +4,7 @@ use colored::Colorize;
 use dialoguer::{theme::ColorfulTheme, Input, Select, Confirm, MultiSelect};
 use form_pack::formfile::{Formfile, FormfileParser, BuildInstruction, Entrypoint, EntrypointBuilder, SystemConfigOpt, User, UserBuilder};
 use std::fs;
-use crate::Keystore;
+use crate::{check_vmm_response, Keystore};
 use super::{BuildCommand, ShipCommand};
 
 /// Interactive wizard to create and deploy an agent
@@ -431,7 +431,7 @@ impl WizardCommand {
                     mnemonic: None,
                 };
                 
-                ship_cmd.handle(provider, vmm_port, keystore).await?;
+                check_vmm_response(ship_cmd.handle(provider, vmm_port, keystore).await?)?;
                 
                 println!("\n{} {}\n", 
                     "🎉".bright_green(), 
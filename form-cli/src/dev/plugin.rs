@@ -0,0 +1,466 @@
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use clap::{Args, Subcommand};
+use colored::*;
+use k256::ecdsa::SigningKey;
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use crate::{Config, Keystore};
+
+/// Plugins are executables named `form-<name>`, run as `form <name> ...`
+pub const PLUGIN_PREFIX: &str = "form-";
+
+/// Environment variable holding the JSON encoded `PluginContext`
+pub const PLUGIN_CONTEXT_ENV: &str = "FORM_PLUGIN_CONTEXT";
+
+/// Bumped whenever a field of `PluginContext` changes meaning or goes away.
+/// New fields may be added without a bump.
+pub const PLUGIN_CONTEXT_VERSION: u32 = 1;
+
+/// Formation's own executables that share the plugin prefix, never treated
+/// as plugins when found on PATH
+const SERVICE_BINARIES: &[&str] = &[
+    "broker", "build-server", "config-wizard", "dns", "fuzzing", "mcp", "metrics-reporter",
+    "network-setup", "node-metrics", "p2p", "pack", "pack-manager", "rplb", "state",
+    "usage-events", "vm-metrics",
+];
+
+/// Where `form plugin install` puts plugins, searched before PATH
+pub fn plugin_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("plugins")
+}
+
+/// Describes a plugin for `form --help` and `form plugin list`. Read from
+/// `form-<name>.manifest.json` next to the executable.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PluginManifest {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub about: Option<String>,
+    #[serde(default)]
+    pub commands: Vec<PluginCommandHelp>,
+    /// The plugin signs with the user's key through the keystore broker,
+    /// `form` asks for the keystore password before running it
+    #[serde(default)]
+    pub keystore: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PluginCommandHelp {
+    pub name: String,
+    #[serde(default)]
+    pub about: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Plugin {
+    pub name: String,
+    pub path: PathBuf,
+    pub manifest: Option<PluginManifest>,
+}
+
+impl Plugin {
+    pub fn about(&self) -> &str {
+        self.manifest.as_ref().and_then(|manifest| manifest.about.as_deref()).unwrap_or("")
+    }
+
+    pub fn wants_keystore(&self) -> bool {
+        self.manifest.as_ref().map_or(false, |manifest| manifest.keystore)
+    }
+
+    /// Runs the plugin with the inherited terminal and returns its exit code.
+    /// With a keystore, the plugin can sign through a broker socket that
+    /// lives as long as the plugin runs.
+    pub async fn run(
+        &self,
+        args: &[String],
+        mut context: PluginContext,
+        keystore: Option<Keystore>,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        let broker = match keystore {
+            Some(keystore) => Some(KeystoreBroker::start(keystore)?),
+            None => None,
+        };
+        context.plugin = self.name.clone();
+        context.keystore_socket = broker.as_ref().map(|broker| broker.socket.clone());
+
+        let status = tokio::process::Command::new(&self.path)
+            .args(args)
+            .env(PLUGIN_CONTEXT_ENV, serde_json::to_string(&context)?)
+            .status()
+            .await
+            .map_err(|e| format!("Unable to run plugin {}: {e}", self.path.display()))?;
+        drop(broker);
+        Ok(status.code().unwrap_or(1))
+    }
+}
+
+fn manifest_path(executable: &Path) -> PathBuf {
+    let mut name = executable.file_name().unwrap_or_default().to_os_string();
+    name.push(".manifest.json");
+    executable.with_file_name(name)
+}
+
+fn read_manifest(executable: &Path) -> Option<PluginManifest> {
+    let data = std::fs::read(manifest_path(executable)).ok()?;
+    match serde_json::from_slice(&data) {
+        Ok(manifest) => Some(manifest),
+        Err(e) => {
+            log::warn!("Ignoring invalid plugin manifest for {}: {e}", executable.display());
+            None
+        }
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Plugin names are what follows the prefix, lowercase words joined by dashes
+pub fn is_valid_plugin_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Every plugin in `plugin_dir` and on PATH. When a name shows up more
+/// than once the first one found is used, so installed plugins win over PATH.
+pub fn discover_plugins(plugin_dir: &Path) -> Vec<Plugin> {
+    let mut dirs = vec![(plugin_dir.to_path_buf(), true)];
+    if let Some(path) = std::env::var_os("PATH") {
+        dirs.extend(std::env::split_paths(&path).map(|dir| (dir, false)));
+    }
+
+    let mut seen = HashSet::new();
+    let mut plugins = Vec::new();
+    for (dir, installed) in dirs {
+        let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+        let mut found: Vec<(String, PathBuf)> = entries.filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().to_str()?.strip_prefix(PLUGIN_PREFIX)?.to_string();
+            Some((name, entry.path()))
+        }).collect();
+        found.sort();
+
+        for (name, path) in found {
+            if !is_valid_plugin_name(&name) || !is_executable(&path) {
+                continue;
+            }
+            if !installed && SERVICE_BINARIES.contains(&name.as_str()) {
+                continue;
+            }
+            if seen.insert(name.clone()) {
+                let manifest = read_manifest(&path);
+                plugins.push(Plugin { name, path, manifest });
+            }
+        }
+    }
+    plugins
+}
+
+/// Plugin section appended to `form --help`
+pub fn plugin_help(plugins: &[Plugin]) -> Option<String> {
+    if plugins.is_empty() {
+        return None;
+    }
+    let width = plugins.iter().map(|plugin| plugin.name.len()).max().unwrap_or(0);
+    let mut help = String::from("Plugins:\n");
+    for plugin in plugins {
+        help.push_str(&format!("  {:width$}  {}\n", plugin.name, plugin.about()));
+    }
+    Some(help)
+}
+
+/// Handed to every plugin as JSON in `FORM_PLUGIN_CONTEXT`. Plugins should
+/// check `version` and ignore fields they don't know.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PluginContext {
+    pub version: u32,
+    pub cli_version: String,
+    /// Name the plugin was invoked as
+    pub plugin: String,
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub keystore_dir: PathBuf,
+    /// The formkit config file, if there is one
+    pub config_file: Option<PathBuf>,
+    /// The provider commands are sent to, the first of `hosts`
+    pub provider: String,
+    pub hosts: Vec<String>,
+    pub ports: PluginPorts,
+    pub debug: bool,
+    /// Unix socket of the keystore broker, set for plugins whose manifest
+    /// asks for keystore access. The raw key is never handed out.
+    pub keystore_socket: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PluginPorts {
+    pub pack_manager: u16,
+    pub vmm: u16,
+    pub formnet: u16,
+    pub state: u16,
+    pub queue: u16,
+}
+
+impl PluginContext {
+    pub fn new(config: &Config, config_file: Option<PathBuf>, debug: bool) -> Self {
+        Self {
+            version: PLUGIN_CONTEXT_VERSION,
+            cli_version: env!("CARGO_PKG_VERSION").to_string(),
+            plugin: String::new(),
+            config_dir: config.config_dir.clone(),
+            data_dir: config.data_dir.clone(),
+            keystore_dir: config.keystore_path.clone(),
+            config_file,
+            provider: config.hosts.first().cloned().unwrap_or_else(|| "127.0.0.1".to_string()),
+            hosts: config.hosts.clone(),
+            ports: PluginPorts {
+                pack_manager: config.pack_manager_port,
+                vmm: config.vmm_port,
+                formnet: config.formnet_port,
+                state: 3004,
+                queue: form_p2p::queue::QUEUE_PORT,
+            },
+            debug,
+            keystore_socket: None,
+        }
+    }
+}
+
+/// One request per line on the keystore broker socket
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum KeystoreRequest {
+    /// The account address and public key
+    Address,
+    /// Signs a 32 byte hex encoded digest, the plugin hashes the message
+    /// the same way the API it calls expects
+    SignDigest { digest: String },
+}
+
+/// One response line per request
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum KeystoreResponse {
+    Address { address: String, public_key: String },
+    Signature { signature: String, recovery_id: u8 },
+    Error { message: String },
+}
+
+/// Answers signing requests from a plugin over a Unix socket in a private
+/// temporary directory. Dropping the broker stops it and removes the socket.
+pub struct KeystoreBroker {
+    pub socket: PathBuf,
+    dir: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl KeystoreBroker {
+    pub fn start(keystore: Keystore) -> Result<Self, Box<dyn std::error::Error>> {
+        let signing_key = SigningKey::from_slice(&hex::decode(&keystore.secret_key)?)?;
+        let dir = std::env::temp_dir().join(format!("form-plugin-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir)?;
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        let socket = dir.join("keystore.sock");
+        let listener = UnixListener::bind(&socket)?;
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let signing_key = signing_key.clone();
+                let address = keystore.address.clone();
+                let public_key = keystore.public_key.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_keystore(stream, &signing_key, &address, &public_key).await {
+                        log::error!("Keystore broker connection failed: {e}");
+                    }
+                });
+            }
+        });
+
+        Ok(Self { socket, dir, task })
+    }
+}
+
+impl Drop for KeystoreBroker {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn serve_keystore(
+    stream: UnixStream,
+    signing_key: &SigningKey,
+    address: &str,
+    public_key: &str,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<KeystoreRequest>(&line) {
+            Ok(KeystoreRequest::Address) => KeystoreResponse::Address {
+                address: address.to_string(),
+                public_key: public_key.to_string(),
+            },
+            Ok(KeystoreRequest::SignDigest { digest }) => sign_digest(signing_key, &digest)
+                .unwrap_or_else(|message| KeystoreResponse::Error { message }),
+            Err(e) => KeystoreResponse::Error { message: format!("Invalid request: {e}") },
+        };
+        let mut line = serde_json::to_vec(&response)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+    Ok(())
+}
+
+fn sign_digest(signing_key: &SigningKey, digest: &str) -> Result<KeystoreResponse, String> {
+    let digest = hex::decode(digest.trim_start_matches("0x")).map_err(|e| e.to_string())?;
+    if digest.len() != 32 {
+        return Err(format!("Digest has to be 32 bytes, got {}", digest.len()));
+    }
+    let (signature, recovery_id) = signing_key.sign_prehash_recoverable(&digest).map_err(|e| e.to_string())?;
+    Ok(KeystoreResponse::Signature {
+        signature: hex::encode(signature.to_bytes()),
+        recovery_id: recovery_id.to_byte(),
+    })
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PluginCommand {
+    /// List installed plugins and `form-<name>` plugins found on PATH
+    List(PluginListCommand),
+    /// Install a plugin executable from a local path or an http(s) URL
+    Install(PluginInstallCommand),
+    /// Run a plugin, `form <name> ..` is short for `form plugin run <name> ..`
+    Run(PluginRunCommand),
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct PluginRunCommand {
+    pub name: String,
+    /// Passed to the plugin unchanged
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct PluginListCommand {
+    /// Also list the subcommands each plugin's manifest describes
+    #[clap(long, short)]
+    pub verbose: bool,
+}
+
+impl PluginListCommand {
+    pub fn handle(&self, plugin_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let plugins = discover_plugins(plugin_dir);
+        if plugins.is_empty() {
+            println!("No plugins found in {} or on PATH", plugin_dir.display());
+            return Ok(());
+        }
+
+        for plugin in plugins {
+            let version = plugin.manifest.as_ref().and_then(|manifest| manifest.version.clone()).unwrap_or_default();
+            println!("{} {} {}", plugin.name.bold().bright_blue(), version.dimmed(), plugin.about());
+            println!("    {}", plugin.path.display().to_string().dimmed());
+            if plugin.wants_keystore() {
+                println!("    {}", "signs with your keystore".yellow());
+            }
+            if self.verbose {
+                for command in plugin.manifest.iter().flat_map(|manifest| manifest.commands.iter()) {
+                    println!("    form {} {}  {}", plugin.name, command.name, command.about.as_deref().unwrap_or(""));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct PluginInstallCommand {
+    /// Path or http(s) URL of the plugin executable
+    pub source: String,
+    /// Name to install the plugin as, defaults to the source file name
+    /// without its `form-` prefix
+    #[clap(long, short)]
+    pub name: Option<String>,
+    /// Path or URL of the plugin manifest. For a local source,
+    /// `<source>.manifest.json` is picked up if it exists.
+    #[clap(long, short)]
+    pub manifest: Option<String>,
+    /// Replace an installed plugin of the same name
+    #[clap(long, short)]
+    pub force: bool,
+}
+
+impl PluginInstallCommand {
+    /// Installs into `plugin_dir`, refusing names that would be shadowed by
+    /// one of the CLI's own commands
+    pub async fn handle(&self, plugin_dir: &Path, builtins: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => {
+                let file_name = self.source.rsplit('/').next().unwrap_or_default();
+                file_name.strip_prefix(PLUGIN_PREFIX).unwrap_or(file_name).to_string()
+            }
+        };
+        if !is_valid_plugin_name(&name) {
+            return Err(format!("Invalid plugin name {name:?}, use lowercase letters, digits and dashes").into());
+        }
+        if builtins.contains(&name) {
+            return Err(format!("{name} is a built in form command, install the plugin under another --name").into());
+        }
+
+        let executable = plugin_dir.join(format!("{PLUGIN_PREFIX}{name}"));
+        if executable.exists() && !self.force {
+            return Err(format!("Plugin {name} is already installed at {}, use --force to replace it", executable.display()).into());
+        }
+
+        let manifest = match &self.manifest {
+            Some(source) => Some(fetch(source).await?),
+            None if !is_url(&self.source) => std::fs::read(manifest_path(Path::new(&self.source))).ok(),
+            None => None,
+        };
+        if let Some(manifest) = &manifest {
+            serde_json::from_slice::<PluginManifest>(manifest)
+                .map_err(|e| format!("Invalid plugin manifest: {e}"))?;
+        }
+        let binary = fetch(&self.source).await?;
+
+        std::fs::create_dir_all(plugin_dir)?;
+        let tmp = plugin_dir.join(format!(".{PLUGIN_PREFIX}{name}.tmp"));
+        std::fs::write(&tmp, &binary)?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::rename(&tmp, &executable)?;
+        match manifest {
+            Some(manifest) => std::fs::write(manifest_path(&executable), manifest)?,
+            None => {
+                let _ = std::fs::remove_file(manifest_path(&executable));
+            }
+        }
+
+        println!("Installed plugin {} to {}", name.bold().bright_blue(), executable.display());
+        println!("Run it with {}", format!("form {name}").yellow());
+        Ok(())
+    }
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+async fn fetch(source: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if is_url(source) {
+        let resp = reqwest::get(source).await?.error_for_status()?;
+        Ok(resp.bytes().await?.to_vec())
+    } else {
+        Ok(std::fs::read(source).map_err(|e| format!("Unable to read {source}: {e}"))?)
+    }
+}
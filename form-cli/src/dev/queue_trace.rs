@@ -0,0 +1,9 @@
+use colored::Colorize;
+
+/// Tells the user how to follow a queued request through the network
+pub fn print_trace_hint(provider: &str, queue_port: u16, correlation_id: &str) {
+    println!("{}\n{}\n{}\n",
+        format!("🔗 Correlation ID: {}", correlation_id.bright_yellow()).bold(),
+        "   To trace the request across services, run:".dimmed(),
+        format!("   curl http://{provider}:{queue_port}/trace/{correlation_id}").bright_blue());
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+use form_types::{VmResponse, VmmErrorCode, VmmFailure, VmmResponse};
+
+/// A request the vmm-service refused, displayed with what to do about it
+#[derive(Debug)]
+pub struct VmmRequestError(pub VmmFailure);
+
+impl VmmRequestError {
+    /// Process exit code for the failure, following sysexits(3)
+    pub fn exit_code(&self) -> i32 {
+        match self.0.code {
+            VmmErrorCode::InvalidRequest => 65,
+            VmmErrorCode::ImageNotFound => 66,
+            VmmErrorCode::InstanceNotFound => 68,
+            VmmErrorCode::QuotaExceeded => 69,
+            VmmErrorCode::NodeCapacity | VmmErrorCode::NodeDraining | VmmErrorCode::DeploymentsFrozen => 75,
+            VmmErrorCode::AuthFailed => 77,
+            VmmErrorCode::Internal => 1,
+        }
+    }
+
+    fn hint(&self) -> &'static str {
+        match self.0.code {
+            VmmErrorCode::AuthFailed => "check that you are signing with the key that owns the instance, or ask its owner for access",
+            VmmErrorCode::QuotaExceeded => "run `form manage quota` to see your usage, then delete instances you no longer need or upgrade your plan",
+            VmmErrorCode::ImageNotFound => "run `form pack build` again and wait for it to finish before shipping",
+            VmmErrorCode::NodeCapacity => "the node is out of memory, lower MEMORY in your Formfile or try again later",
+            VmmErrorCode::NodeDraining => "the node is in maintenance, try again later or use another provider",
+            VmmErrorCode::DeploymentsFrozen => "admins froze deployments for maintenance, `form manage maintenance` shows until when",
+            VmmErrorCode::InstanceNotFound => "check the build id, `form pack status` lists your builds",
+            VmmErrorCode::InvalidRequest => "run `form pack validate` to check your Formfile",
+            VmmErrorCode::Internal => "this is likely a problem with the node, try again or report it",
+        }
+    }
+}
+
+impl fmt::Display for VmmRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n  hint: {}", self.0, self.hint())
+    }
+}
+
+impl std::error::Error for VmmRequestError {}
+
+/// Turns a vmm-service response into its result
+pub fn check_vmm_response(resp: VmmResponse) -> Result<VmResponse, VmmRequestError> {
+    match resp {
+        VmmResponse::Success(vm) => Ok(vm),
+        VmmResponse::Failure(failure) => Err(VmmRequestError(failure)),
+    }
+}
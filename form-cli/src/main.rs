@@ -1,10 +1,11 @@
 use std::path::PathBuf;
 use form_types::state::{Response, Success};
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use dialoguer::{theme::ColorfulTheme, Confirm};
 use colored::*;
 use form_cli::{
-    decrypt_file, default_config_dir, default_data_dir, default_keystore_dir, join_formnet, operator_config, Config, DnsCommand, Init, Keystore, KitCommand, manage::ManageCommand, Operator, PackCommand, WalletCommand
+    decrypt_file, default_config_dir, default_data_dir, default_keystore_dir, join_formnet, Devnet, operator_config, Config, DnsCommand, Init, Keystore, KitCommand, manage::ManageCommand, Operator, PackCommand, WalletCommand, check_vmm_response, VmmRequestError,
+    PluginCommand, PluginContext, discover_plugins, plugin_dir, plugin_help
 };
 use form_p2p::queue::QUEUE_PORT;
 use formnet::{leave, uninstall};
@@ -90,16 +91,47 @@ pub enum FormCommand {
     /// access within formnet
     #[clap(subcommand)]
     Dns(DnsCommand),
+    /// Commands related to listing and installing plugins, third party
+    /// `form-<name>` executables that run as `form <name>`
+    #[clap(subcommand)]
+    Plugin(PluginCommand),
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut parser = Form::parse();
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{} {e}", "Error:".red());
+        let code = e.downcast_ref::<VmmRequestError>().map(VmmRequestError::exit_code).unwrap_or(1);
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let plugins = discover_plugins(&plugin_dir(&default_data_dir()));
+    let mut command = Form::command();
+    if let Some(help) = plugin_help(&plugins) {
+        command = command.after_help(help);
+    }
+    let builtins = builtin_commands();
+    let mut args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    // `form <name> ..` runs the plugin as `form plugin run <name> ..`
+    if let Some(name) = args.get(1).and_then(|arg| arg.to_str()) {
+        if !builtins.iter().any(|builtin| builtin == name) && plugins.iter().any(|plugin| plugin.name == name) {
+            args.splice(1..1, ["plugin".into(), "run".into()]);
+        }
+    }
+    let mut parser = Form::from_arg_matches(&command.get_matches_from(args))?;
     // Attempt to load form kit
     // if none provided, prompt to run init
     match parser.command {
         FormCommand::Pack(ref pack_command) => {
             match pack_command {
+                PackCommand::Init(init_command) => {
+                    let resp = init_command.handle().await?;
+                    for line in resp.lines() {
+                        println!("{line}")
+                    }
+                }
                 PackCommand::Build(build_command) => {
                     println!("Attempting to acquire config and keystore");
                     let (config, keystore) = load_config_and_keystore(&parser).await?;
@@ -129,7 +161,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     if parser.queue {
                         let _ = ship_command.clone().handle_queue(&provider, Some(keystore)).await?;
                     } else {
-                        let _ = ship_command.clone().handle(&provider, config.pack_manager_port, Some(keystore)).await?;
+                        let resp = ship_command.clone().handle(&provider, config.pack_manager_port, Some(keystore)).await?;
+                        check_vmm_response(resp)?;
                     }
                 }
                 PackCommand::Status(status_command) => {
@@ -142,6 +175,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let provider = config.hosts[0].clone();
                     wizard_command.handle(&provider, config.pack_manager_port, config.vmm_port, Some(keystore)).await?;
                 }
+                PackCommand::Deploy(deploy_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    deploy_command.handle(&provider, config.pack_manager_port, config.vmm_port, 3004, parser.queue, Some(keystore)).await?;
+                }
+                PackCommand::Watch(watch_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    watch_command.handle(&provider, config.pack_manager_port, Some(keystore)).await?;
+                }
+                PackCommand::Estimate(estimate_command) => {
+                    let config = load_config(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    estimate_command.handle(&provider, 3004).await?;
+                }
+                PackCommand::Verify(verify_command) => {
+                    let config = load_config(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    verify_command.handle(&provider, 3004).await?;
+                }
             }
         }
         FormCommand::Kit(ref mut kit_command) => {
@@ -163,6 +216,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         }
                     }
                 }
+                KitCommand::Devnet(sub) => {
+                    match sub {
+                        Devnet::Up(up) => up.handle().await?,
+                        Devnet::Down(down) => down.handle().await?,
+                    }
+                }
             }
         }
         FormCommand::Manage(ref manage_command) => {
@@ -257,7 +316,7 @@ ips_string.yellow(),
                         stop_command.handle_queue(&provider, Some(keystore)).await?;
                     } else {
                         let resp = stop_command.handle(&provider, config.vmm_port, Some(keystore)).await?;
-                        println!("Response: {:?}", resp);
+                        println!("Response: {:?}", check_vmm_response(resp)?);
                     }
                 }
                 ManageCommand::Start(start_command) => {
@@ -267,7 +326,7 @@ ips_string.yellow(),
                         start_command.handle_queue(&provider, Some(keystore)).await?;
                     } else {
                         let resp = start_command.handle(&provider, config.vmm_port, Some(keystore)).await?;
-                        println!("Response: {:?}", resp);
+                        println!("Response: {:?}", check_vmm_response(resp)?);
                     }
                 }
                 ManageCommand::Delete(delete_command) => {
@@ -277,7 +336,7 @@ ips_string.yellow(),
                         delete_command.handle_queue(&provider, Some(keystore)).await?;
                     } else {
                         let resp = delete_command.handle(&provider, config.vmm_port, Some(keystore)).await?;
-                        println!("Response: {:?}", resp);
+                        println!("Response: {:?}", check_vmm_response(resp)?);
                     }
                 }
                 ManageCommand::Commit(commit_command) => {
@@ -289,11 +348,61 @@ ips_string.yellow(),
                         commit_command.handle(&provider, config.vmm_port).await?;
                     }
                 }
+                ManageCommand::Schedule(schedule_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    schedule_command.handle(&provider, Some(keystore)).await?;
+                }
+                ManageCommand::Quota(quota_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    quota_command.handle(&provider, Some(keystore)).await?;
+                }
+                ManageCommand::DrainNode(drain_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    drain_command.handle(&provider, config.vmm_port, Some(keystore)).await?;
+                }
+                ManageCommand::Maintenance(maintenance_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    maintenance_command.handle(&provider, Some(keystore)).await?;
+                }
+                ManageCommand::Clone(clone_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    clone_command.handle(&provider, config.vmm_port, Some(keystore)).await?;
+                }
+                ManageCommand::Exec(exec_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    let provider = config.hosts[0].clone();
+                    let code = exec_command.handle(&provider, config.vmm_port, Some(keystore)).await?;
+                    if code != 0 {
+                        std::process::exit(code);
+                    }
+                }
+                _ => {}
+            }
+        }
+        FormCommand::Wallet(ref wallet_command) => {
+            match wallet_command {
+                WalletCommand::Balance(balance_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    balance_command.handle(&config, &keystore).await?;
+                }
+                WalletCommand::Send(send_command) => {
+                    let (config, keystore) = load_config_and_keystore(&parser).await?;
+                    send_command.handle(&config, &keystore).await?;
+                }
+                WalletCommand::Export(export_command) => {
+                    let (_, keystore) = load_config_and_keystore(&parser).await?;
+                    export_command.handle(&keystore)?;
+                }
                 _ => {}
             }
         }
         FormCommand::Dns(ref dns_command) => {
-            let (config, _) = load_config_and_keystore(&parser).await?;
+            let (config, keystore) = load_config_and_keystore(&parser).await?;
             let provider = config.hosts[0].clone();
             
             match dns_command {
@@ -307,7 +416,30 @@ ips_string.yellow(),
                     remove_command.handle_remove_command(provider).await?;
                 },
                 DnsCommand::Verify(verify_command) => {
-                    verify_command.handle_verify_command(provider).await?;
+                    verify_command.handle_verify_command(provider, Some(keystore)).await?;
+                }
+                DnsCommand::Claim(claim_command) => {
+                    claim_command.handle_claim_command(provider, Some(keystore)).await?;
+                }
+                DnsCommand::Release(release_command) => {
+                    release_command.handle_release_command(provider, Some(keystore)).await?;
+                }
+                DnsCommand::Status(status_command) => {
+                    status_command.handle_status_command(provider).await?;
+                }
+            }
+        }
+        FormCommand::Plugin(ref plugin_command) => {
+            match plugin_command {
+                PluginCommand::List(list_command) => {
+                    list_command.handle(&plugin_dir(&parser.data_dir))?;
+                }
+                PluginCommand::Install(install_command) => {
+                    install_command.handle(&plugin_dir(&parser.data_dir), &builtins).await?;
+                }
+                PluginCommand::Run(run_command) => {
+                    let code = run_plugin(&parser, &run_command.name, &run_command.args).await?;
+                    std::process::exit(code);
                 }
             }
         }
@@ -317,6 +449,37 @@ ips_string.yellow(),
     Ok(())
 }
 
+/// Names plugins can't take, they would be shadowed
+fn builtin_commands() -> Vec<String> {
+    Form::command().get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .chain(std::iter::once("help".to_string()))
+        .collect()
+}
+
+/// Runs the `form-<name>` plugin. Plugins don't get the setup prompts of the
+/// built in commands, a missing formkit config falls back to the command
+/// line defaults.
+async fn run_plugin(parser: &Form, name: &str, args: &[String]) -> Result<i32, Box<dyn std::error::Error>> {
+    let plugin = discover_plugins(&plugin_dir(&parser.data_dir))
+        .into_iter()
+        .find(|plugin| plugin.name == name)
+        .ok_or_else(|| format!("No form-{name} plugin was found, see `form plugin list`"))?;
+
+    let config_file = formkit_config_path();
+    let (config, config_file) = match std::fs::read_to_string(&config_file) {
+        Ok(data) => (serde_json::from_str(&data)?, Some(config_file)),
+        Err(_) => (default_config(parser), None),
+    };
+    let keystore = if plugin.wants_keystore() {
+        Some(load_keystore(parser, &config).await?)
+    } else {
+        None
+    };
+
+    plugin.run(args, PluginContext::new(&config, config_file, parser.debug), keystore).await
+}
+
 pub async fn load_config_and_keystore(parser: &Form) -> Result<(Config, Keystore), Box<dyn std::error::Error>> {
     println!("loading config");
     let config = load_config(parser).await?;
@@ -349,10 +512,8 @@ pub async fn load_keystore(parser: &Form, config: &Config) -> Result<Keystore, B
 }
 
 pub async fn load_config(parser: &Form) -> Result<Config, Box<dyn std::error::Error>> {
-    let home = std::env::var("HOME").unwrap_or(".".to_string());
     let formkit_config: Config = {
-        let path = std::env::var("FORMKIT").unwrap_or(format!("{home}/.config/form/config.json"));
-        let formkit_config_data = std::fs::read_to_string(path);
+        let formkit_config_data = std::fs::read_to_string(formkit_config_path());
         match formkit_config_data {
             Ok(data) => serde_json::from_str(&data)?,
             Err(_) => {
@@ -364,17 +525,7 @@ pub async fn load_config(parser: &Form) -> Result<Config, Box<dyn std::error::Er
                         config
                 } else {
                     println!("{}", "WARNING!: Using defaults which may not be set up properly, and may lead to errors when building, shipping, and managing your instances".yellow());
-                    let config = Config {
-                        config_dir: parser.config_dir.clone(),
-                        data_dir: parser.data_dir.clone(),
-                        keystore_path: parser.keystore_dir.clone(),
-                        hosts: vec![parser.provider.clone()],
-                        pack_manager_port: parser.formpack_port,
-                        vmm_port: parser.vmm_port,
-                        formnet_port: parser.formnet_port,
-                        join_formnet: true,
-                    };
-                    config
+                    default_config(parser)
                 }
             }
         }
@@ -382,3 +533,24 @@ pub async fn load_config(parser: &Form) -> Result<Config, Box<dyn std::error::Er
 
     Ok(formkit_config)
 }
+
+fn formkit_config_path() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or(".".to_string());
+    PathBuf::from(std::env::var("FORMKIT").unwrap_or(format!("{home}/.config/form/config.json")))
+}
+
+/// The config built from command line arguments when there is no formkit config
+fn default_config(parser: &Form) -> Config {
+    Config {
+        config_dir: parser.config_dir.clone(),
+        data_dir: parser.data_dir.clone(),
+        keystore_path: parser.keystore_dir.clone(),
+        hosts: vec![parser.provider.clone()],
+        pack_manager_port: parser.formpack_port,
+        vmm_port: parser.vmm_port,
+        formnet_port: parser.formnet_port,
+        join_formnet: true,
+        rpc_url: None,
+        staking_contract: None,
+    }
+}
@@ -0,0 +1,55 @@
+use alloy_core::primitives::{keccak256, utils::format_ether, Address, Bytes, U256};
+use clap::Args;
+use colored::Colorize;
+use serde::{Serialize, Deserialize};
+use crate::{Config, Keystore};
+use super::{resolve_rpc_url, rpc::RpcClient};
+
+/// View function on the staking contract returning an operator's bonded stake
+pub const STAKE_OF_SIGNATURE: &str = "stakeOf(address)";
+
+#[derive(Clone, Debug, Serialize, Deserialize, Args)]
+pub struct BalanceCommand {
+    /// Address to look up, defaults to your wallet
+    #[clap(long, short)]
+    pub address: Option<String>,
+    /// Ethereum JSON-RPC endpoint, defaults to `rpc_url` in your formkit config
+    #[clap(long)]
+    pub rpc_url: Option<String>,
+    /// Staking contract address, defaults to `staking_contract` in your formkit config
+    #[clap(long)]
+    pub staking_contract: Option<String>,
+}
+
+impl BalanceCommand {
+    pub async fn handle(&self, config: &Config, keystore: &Keystore) -> Result<(), Box<dyn std::error::Error>> {
+        let rpc = RpcClient::new(resolve_rpc_url(&self.rpc_url, config)?);
+        let address: Address = self.address.as_deref().unwrap_or(&keystore.address).parse()?;
+
+        let chain_id = rpc.chain_id().await?;
+        let native = rpc.balance(address).await?;
+        println!("Wallet {} on chain {}:", address.to_string().bold().bright_yellow(), chain_id);
+        println!("  Native:     {} ETH", format_ether(native).bright_cyan());
+
+        let staking_contract = self.staking_contract.clone().or_else(|| config.staking_contract.clone());
+        match staking_contract {
+            Some(contract) => {
+                let staked = stake_of(&rpc, contract.parse()?, address).await?;
+                println!("  Staked:     {} ETH", format_ether(staked).bright_cyan());
+            }
+            None => println!("  Staked:     {}", "no staking contract configured".yellow()),
+        }
+
+        Ok(())
+    }
+}
+
+async fn stake_of(rpc: &RpcClient, contract: Address, operator: Address) -> Result<U256, Box<dyn std::error::Error>> {
+    let mut calldata = keccak256(STAKE_OF_SIGNATURE.as_bytes())[..4].to_vec();
+    calldata.extend_from_slice(operator.into_word().as_slice());
+    let result = rpc.eth_call(contract, Bytes::from(calldata)).await?;
+    if result.len() < 32 {
+        return Err(format!("Unexpected {STAKE_OF_SIGNATURE} response from {contract}").into());
+    }
+    Ok(U256::from_be_slice(&result[..32]))
+}
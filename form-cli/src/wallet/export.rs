@@ -0,0 +1,56 @@
+use std::path::PathBuf;
+use alloy_signer_local::PrivateKeySigner;
+use clap::{Args, ValueEnum};
+use colored::Colorize;
+use dialoguer::{theme::ColorfulTheme, Password};
+use serde::{Serialize, Deserialize};
+use crate::Keystore;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ValueEnum)]
+pub enum ExportFormat {
+    /// Encrypted JSON keystore (version 3), as read by geth, foundry and most wallets
+    KeystoreV3,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Args)]
+pub struct ExportCommand {
+    #[clap(long, value_enum, default_value="keystore-v3")]
+    pub format: ExportFormat,
+    /// Directory the exported file is written to
+    #[clap(long, short, default_value=".")]
+    pub out_dir: PathBuf,
+    /// File name of the export, defaults to the wallet address
+    #[clap(long, short)]
+    pub name: Option<String>,
+}
+
+impl ExportCommand {
+    pub fn handle(&self, keystore: &Keystore) -> Result<(), Box<dyn std::error::Error>> {
+        match self.format {
+            ExportFormat::KeystoreV3 => {
+                // The export gets its own password, it doesn't need to match
+                // the one protecting the form keystore
+                let password: String = Password::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Password for the exported keystore")
+                    .with_confirmation("Confirm the password", "Passwords do not match")
+                    .interact()?;
+                let secret_key = hex::decode(keystore.secret_key.trim_start_matches("0x"))?;
+                let name = self.name.clone().unwrap_or_else(|| keystore.address.trim_start_matches("0x").to_lowercase());
+                std::fs::create_dir_all(&self.out_dir)?;
+                let (signer, _) = PrivateKeySigner::encrypt_keystore(
+                    &self.out_dir,
+                    &mut rand::thread_rng(),
+                    secret_key,
+                    password,
+                    Some(&name),
+                )?;
+                println!(
+                    "✅ Exported {} to {}",
+                    signer.address().to_string().bold().bright_yellow(),
+                    self.out_dir.join(&name).display().to_string().bright_cyan(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use clap::Subcommand;
+use serde::{Serialize, Deserialize};
+use balance::BalanceCommand;
+use send::SendCommand;
+use export::ExportCommand;
+use crate::Config;
+
+pub mod rpc;
+pub mod balance;
+pub mod send;
+pub mod export;
+
+#[derive(Clone, Debug, Serialize, Deserialize, Subcommand)]
+pub enum WalletCommand {
+    New,
+    Get,
+    /// Show the native and staked balance of your wallet
+    Balance(BalanceCommand),
+    /// Sign a transaction with your wallet and broadcast it
+    Send(SendCommand),
+    /// Export your wallet for use with other Ethereum tooling
+    Export(ExportCommand),
+}
+
+/// The RPC URL passed on the command line, falling back to the one in the
+/// formkit config
+pub fn resolve_rpc_url(rpc_url: &Option<String>, config: &Config) -> Result<String, Box<dyn std::error::Error>> {
+    rpc_url.clone()
+        .or_else(|| config.rpc_url.clone())
+        .ok_or_else(|| "No RPC URL configured, pass --rpc-url or set rpc_url in your formkit config".into())
+}
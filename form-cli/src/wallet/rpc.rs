@@ -0,0 +1,79 @@
+use alloy_core::primitives::{Address, Bytes, U256};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+/// Minimal Ethereum JSON-RPC client for the wallet commands
+pub struct RpcClient {
+    url: String,
+    client: Client,
+}
+
+impl RpcClient {
+    pub fn new(url: String) -> Self {
+        Self { url, client: Client::new() }
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, Box<dyn std::error::Error>> {
+        let resp = self.client
+            .post(&self.url)
+            .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+            .send().await?
+            .json::<Value>().await?;
+
+        if let Some(error) = resp.get("error") {
+            let message = error.get("message").and_then(Value::as_str).unwrap_or("unknown error");
+            return Err(format!("{method} failed: {message}").into());
+        }
+        resp.get("result").cloned().ok_or_else(|| format!("{method} returned no result").into())
+    }
+
+    pub async fn chain_id(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(parse_quantity(&self.call("eth_chainId", json!([])).await?)?.to::<u64>())
+    }
+
+    pub async fn balance(&self, address: Address) -> Result<U256, Box<dyn std::error::Error>> {
+        parse_quantity(&self.call("eth_getBalance", json!([address, "latest"])).await?)
+    }
+
+    pub async fn nonce(&self, address: Address) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(parse_quantity(&self.call("eth_getTransactionCount", json!([address, "pending"])).await?)?.to::<u64>())
+    }
+
+    /// Base fee of the latest block
+    pub async fn base_fee(&self) -> Result<u128, Box<dyn std::error::Error>> {
+        let block = self.call("eth_getBlockByNumber", json!(["latest", false])).await?;
+        let base_fee = block.get("baseFeePerGas").ok_or("Chain does not support EIP-1559 fees")?;
+        Ok(parse_quantity(base_fee)?.to::<u128>())
+    }
+
+    pub async fn max_priority_fee(&self) -> Result<u128, Box<dyn std::error::Error>> {
+        Ok(parse_quantity(&self.call("eth_maxPriorityFeePerGas", json!([])).await?)?.to::<u128>())
+    }
+
+    pub async fn estimate_gas(&self, from: Address, to: Address, value: U256, data: &Bytes) -> Result<u64, Box<dyn std::error::Error>> {
+        let estimate = self.call("eth_estimateGas", json!([{
+            "from": from,
+            "to": to,
+            "value": value,
+            "data": data,
+        }])).await?;
+        Ok(parse_quantity(&estimate)?.to::<u64>())
+    }
+
+    pub async fn eth_call(&self, to: Address, data: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        let result = self.call("eth_call", json!([{ "to": to, "data": data }, "latest"])).await?;
+        let hex = result.as_str().ok_or("eth_call returned a non string result")?;
+        Ok(hex.parse::<Bytes>()?)
+    }
+
+    /// Broadcasts a signed transaction, returning its hash
+    pub async fn send_raw_transaction(&self, raw: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        let hash = self.call("eth_sendRawTransaction", json!([format!("0x{}", hex::encode(raw))])).await?;
+        hash.as_str().map(String::from).ok_or_else(|| "eth_sendRawTransaction returned a non string result".into())
+    }
+}
+
+fn parse_quantity(value: &Value) -> Result<U256, Box<dyn std::error::Error>> {
+    let quantity = value.as_str().ok_or("Expected a hex quantity")?;
+    Ok(U256::from_str_radix(quantity.trim_start_matches("0x"), 16)?)
+}
@@ -0,0 +1,110 @@
+use alloy_consensus::{SignableTransaction, TxEip1559, TxEnvelope};
+use alloy_core::primitives::{utils::{format_ether, parse_ether}, Address, Bytes, TxKind, U256};
+use alloy_eips::eip2718::Encodable2718;
+use alloy_network::TxSignerSync;
+use alloy_signer_local::PrivateKeySigner;
+use clap::Args;
+use colored::Colorize;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use serde::{Serialize, Deserialize};
+use crate::{Config, Keystore};
+use super::{resolve_rpc_url, rpc::RpcClient};
+
+/// Sign an EIP-1559 transaction with your wallet and broadcast it. Fields
+/// that aren't given are filled in from the chain.
+#[derive(Clone, Debug, Serialize, Deserialize, Args)]
+pub struct SendCommand {
+    /// Recipient address
+    #[clap(long, short)]
+    pub to: String,
+    /// Amount to send in ETH, e.g. `0.5`
+    #[clap(long, short, default_value="0")]
+    pub value: String,
+    /// Hex encoded calldata
+    #[clap(long, short)]
+    pub data: Option<String>,
+    #[clap(long)]
+    pub nonce: Option<u64>,
+    #[clap(long)]
+    pub gas_limit: Option<u64>,
+    /// Max fee per gas in wei, defaults to twice the current base fee plus the priority fee
+    #[clap(long)]
+    pub max_fee: Option<u128>,
+    /// Max priority fee per gas in wei
+    #[clap(long)]
+    pub priority_fee: Option<u128>,
+    /// Ethereum JSON-RPC endpoint, defaults to `rpc_url` in your formkit config
+    #[clap(long)]
+    pub rpc_url: Option<String>,
+    /// Print the signed raw transaction instead of broadcasting it
+    #[clap(long, default_value_t=false)]
+    pub sign_only: bool,
+    /// Broadcast without asking for confirmation
+    #[clap(long, short, default_value_t=false)]
+    pub yes: bool,
+}
+
+impl SendCommand {
+    pub async fn handle(&self, config: &Config, keystore: &Keystore) -> Result<(), Box<dyn std::error::Error>> {
+        let rpc = RpcClient::new(resolve_rpc_url(&self.rpc_url, config)?);
+        let signer = PrivateKeySigner::from_slice(&hex::decode(keystore.secret_key.trim_start_matches("0x"))?)?;
+        let from = signer.address();
+        let to: Address = self.to.parse()?;
+        let value: U256 = parse_ether(&self.value)?;
+        let input: Bytes = match &self.data {
+            Some(data) => data.parse()?,
+            None => Bytes::new(),
+        };
+
+        let chain_id = rpc.chain_id().await?;
+        let nonce = match self.nonce {
+            Some(nonce) => nonce,
+            None => rpc.nonce(from).await?,
+        };
+        let max_priority_fee_per_gas = match self.priority_fee {
+            Some(fee) => fee,
+            None => rpc.max_priority_fee().await?,
+        };
+        let max_fee_per_gas = match self.max_fee {
+            Some(fee) => fee,
+            None => rpc.base_fee().await? * 2 + max_priority_fee_per_gas,
+        };
+        let gas_limit = match self.gas_limit {
+            Some(gas) => gas,
+            None => rpc.estimate_gas(from, to, value, &input).await?,
+        };
+
+        let mut tx = TxEip1559 {
+            chain_id,
+            nonce,
+            gas_limit,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            to: TxKind::Call(to),
+            value,
+            access_list: Default::default(),
+            input,
+        };
+        let signature = signer.sign_transaction_sync(&mut tx)?;
+        let raw = TxEnvelope::from(tx.into_signed(signature)).encoded_2718();
+
+        if self.sign_only {
+            println!("0x{}", hex::encode(&raw));
+            return Ok(());
+        }
+
+        println!("Sending {} ETH from {} to {} on chain {}", format_ether(value).bright_cyan(), from.to_string().bright_yellow(), to.to_string().bright_yellow(), chain_id);
+        println!("  Nonce: {nonce}, gas limit: {gas_limit}, max fee: {max_fee_per_gas} wei");
+        if !self.yes && !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Broadcast this transaction?")
+            .default(false)
+            .interact()? {
+            println!("Transaction was not broadcast");
+            return Ok(());
+        }
+
+        let hash = rpc.send_raw_transaction(&raw).await?;
+        println!("✅ Transaction {}: {}", "broadcast".green(), hash.bold());
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! Encrypted provisioning bundles for operators running many nodes
+//!
+//! A bundle holds a template operator config and one key per node, all
+//! derived from a single mnemonic at `m/44'/60'/0'/0/<index>`. The mnemonic
+//! itself never goes into the bundle, so it can stay offline while the
+//! bundle is copied to the hosts. On each host `provision --from-bundle`
+//! picks its node out of the bundle and writes a ready to use operator
+//! config.
+//!
+//! The bundle is encrypted with the same Argon2 + AES-256-GCM scheme as the
+//! keystore, under a password of its own.
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use alloy::signers::local::coins_bip39::{English, Mnemonic};
+use anyhow::{anyhow, Result};
+use k256::ecdsa::SigningKey;
+use serde::{Deserialize, Serialize};
+use crate::keys::KeySet;
+use crate::{decrypt_file, encrypt_file, OperatorConfig};
+
+/// Format version written into new bundles
+pub const BUNDLE_VERSION: u16 = 1;
+/// Default location of a bundle created by `bundle`
+pub const DEFAULT_BUNDLE_PATH: &str = "./secrets/provisioning.bundle";
+/// Marks a file as a provisioning bundle before anything is decrypted
+const BUNDLE_MAGIC: &[u8] = b"FORMBUNDLE1\n";
+
+/// HD path of the key of the node at `index`
+pub fn node_derivation_path(index: u32) -> String {
+    format!("m/44'/60'/0'/0/{index}")
+}
+
+/// A node to add to a bundle, `name[@region]` on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeSpec {
+    pub name: String,
+    pub region: Option<String>,
+    pub is_bootstrap_node: bool,
+}
+
+impl std::str::FromStr for NodeSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, region) = match s.split_once('@') {
+            Some((name, region)) => (name, Some(region.trim().to_string()).filter(|r| !r.is_empty())),
+            None => (s, None),
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("{s} has no node name"));
+        }
+        Ok(Self { name: name.to_string(), region, is_bootstrap_node: false })
+    }
+}
+
+/// A node's entry in a bundle
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BundleNode {
+    pub name: String,
+    pub index: u32,
+    pub derivation_path: String,
+    pub region: Option<String>,
+    pub is_bootstrap_node: bool,
+    pub secret_key: String,
+    pub public_key: String,
+    pub address: String,
+}
+
+impl BundleNode {
+    /// Derives the key of `spec` at `index`
+    pub fn derive(mnemonic: &Mnemonic<English>, password: Option<&str>, index: u32, spec: NodeSpec) -> Result<Self> {
+        let derivation_path = node_derivation_path(index);
+        let xpriv = mnemonic.derive_key(derivation_path.as_str(), password)?;
+        let signing_key: &SigningKey = xpriv.as_ref();
+        let keyset = KeySet::from_private_key(signing_key.clone());
+        Ok(Self {
+            name: spec.name,
+            index,
+            derivation_path,
+            region: spec.region,
+            is_bootstrap_node: spec.is_bootstrap_node,
+            secret_key: keyset.secret_key,
+            public_key: keyset.public_key,
+            address: keyset.address,
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProvisioningBundle {
+    pub version: u16,
+    pub created_at: u64,
+    /// Every node's config starts from this, without any keys
+    pub template: OperatorConfig,
+    pub nodes: Vec<BundleNode>,
+}
+
+impl ProvisioningBundle {
+    /// Derives a key for each node, starting at HD index `start_index`.
+    /// Adding nodes to a fleet later needs a `start_index` past the indices
+    /// already handed out, or nodes end up sharing keys.
+    pub fn new(
+        mut template: OperatorConfig,
+        mnemonic: &Mnemonic<English>,
+        password: Option<&str>,
+        nodes: Vec<NodeSpec>,
+        start_index: u32,
+    ) -> Result<Self> {
+        if nodes.is_empty() {
+            return Err(anyhow!("A bundle needs at least one node"));
+        }
+        let mut names = HashSet::new();
+        if let Some(duplicate) = nodes.iter().find(|node| !names.insert(node.name.as_str())) {
+            return Err(anyhow!("Node {} is listed more than once", duplicate.name));
+        }
+
+        template.secret_key = None;
+        template.mnemonic = None;
+        template.public_key = None;
+        template.address = None;
+        template.region = None;
+        template.is_bootstrap_node = false;
+
+        let nodes = nodes.into_iter().enumerate().map(|(i, spec)| {
+            let index = start_index.checked_add(i as u32).ok_or_else(|| anyhow!("HD index out of range"))?;
+            BundleNode::derive(mnemonic, password, index, spec)
+        }).collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            version: BUNDLE_VERSION,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            template,
+            nodes,
+        })
+    }
+
+    pub fn encrypt(&self, password: &str) -> Result<Vec<u8>> {
+        let encrypted = encrypt_file(&serde_json::to_vec(self)?, password).map_err(|e| anyhow!("Unable to encrypt bundle: {e}"))?;
+        Ok([BUNDLE_MAGIC, &encrypted].concat())
+    }
+
+    pub fn decrypt(bytes: &[u8], password: &str) -> Result<Self> {
+        let encrypted = bytes.strip_prefix(BUNDLE_MAGIC).ok_or_else(|| anyhow!("Not a provisioning bundle"))?;
+        let plaintext = decrypt_file(encrypted, password)
+            .map_err(|_| anyhow!("Unable to decrypt bundle, the password is wrong or the file is damaged"))?;
+        let bundle: Self = serde_json::from_slice(&plaintext)?;
+        if bundle.version > BUNDLE_VERSION {
+            return Err(anyhow!("Bundle version {} is newer than this tool supports ({BUNDLE_VERSION})", bundle.version));
+        }
+        Ok(bundle)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>, password: &str) -> Result<Self> {
+        Self::decrypt(&std::fs::read(path)?, password)
+    }
+
+    pub fn node(&self, name: &str) -> Option<&BundleNode> {
+        self.nodes.iter().find(|node| node.name == name)
+    }
+
+    /// The operator config of node `name`. The node's key is checked against
+    /// the address it was bundled with.
+    pub fn node_config(&self, name: &str) -> Result<OperatorConfig> {
+        let node = self.node(name).ok_or_else(|| {
+            let names: Vec<&str> = self.nodes.iter().map(|node| node.name.as_str()).collect();
+            anyhow!("No node {name} in the bundle, it has {}", names.join(", "))
+        })?;
+        let keyset = KeySet::from_private_key(SigningKey::from_slice(&hex::decode(&node.secret_key)?)?);
+        if keyset.address != node.address {
+            return Err(anyhow!("The key of node {name} doesn't match its address {}", node.address));
+        }
+
+        let mut config = self.template.clone();
+        config.secret_key = Some(keyset.secret_key);
+        config.public_key = Some(keyset.public_key);
+        config.address = Some(keyset.address);
+        config.region = node.region.clone();
+        config.is_bootstrap_node = node.is_bootstrap_node;
+        Ok(config)
+    }
+}
+
+/// Writes a node config provisioned from a bundle. An existing file is only
+/// replaced with `force`. With `password` the secret key is encrypted the
+/// way `OperatorConfig::from_file` expects it.
+pub fn write_node_config(config: &OperatorConfig, path: impl AsRef<Path>, password: Option<&str>, force: bool) -> Result<()> {
+    let path = path.as_ref();
+    if path.exists() && !force {
+        return Err(anyhow!("{} already exists, pass --force to replace it", path.display()));
+    }
+
+    let mut config = config.clone();
+    if let (Some(password), Some(secret_key)) = (password, &config.secret_key) {
+        let encrypted = encrypt_file(&hex::decode(secret_key)?, password).map_err(|e| anyhow!("{e}"))?;
+        config.secret_key = Some(hex::encode(encrypted));
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&config)?)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: OperatorConfig,
+    }
+
+    #[test]
+    fn test_bundle_roundtrip() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let mnemonic = Mnemonic::<English>::new_from_phrase(phrase).unwrap();
+        let template = Cli::parse_from(["test", "--event-queue-port", "3005", "-S", "00aa", "--region", "us-east"]).config;
+        let mut nodes: Vec<NodeSpec> = vec!["edge-1@eu-west".parse().unwrap(), "edge-2".parse().unwrap()];
+        nodes[1].is_bootstrap_node = true;
+
+        let bundle = ProvisioningBundle::new(template.clone(), &mnemonic, None, nodes.clone(), 0).unwrap();
+        assert!(bundle.template.secret_key.is_none());
+        assert_ne!(bundle.nodes[0].address, bundle.nodes[1].address);
+        assert_eq!(bundle.nodes[1].derivation_path, "m/44'/60'/0'/0/1");
+
+        // Keys only depend on the mnemonic and the index
+        let again = ProvisioningBundle::new(template.clone(), &mnemonic, None, nodes[1..].to_vec(), 1).unwrap();
+        assert_eq!(again.nodes[0].secret_key, bundle.nodes[1].secret_key);
+
+        let encrypted = bundle.encrypt("bundle password").unwrap();
+        assert!(ProvisioningBundle::decrypt(&encrypted, "wrong").is_err());
+        let decrypted = ProvisioningBundle::decrypt(&encrypted, "bundle password").unwrap();
+
+        let config = decrypted.node_config("edge-1").unwrap();
+        assert_eq!(config.address.as_deref(), Some(bundle.nodes[0].address.as_str()));
+        assert_eq!(config.region.as_deref(), Some("eu-west"));
+        assert!(!config.is_bootstrap_node);
+        assert_eq!(config.event_queue_port, 3005);
+        assert!(decrypted.node_config("edge-2").unwrap().is_bootstrap_node);
+        assert!(decrypted.node_config("edge-3").is_err());
+
+        let duplicate = vec![nodes[0].clone(), nodes[0].clone()];
+        assert!(ProvisioningBundle::new(template, &mnemonic, None, duplicate, 0).is_err());
+    }
+}
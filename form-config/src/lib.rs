@@ -9,6 +9,9 @@ use serde::{Serialize, Deserialize};
 use anyhow::{anyhow, Result};
 use clap::Args;
 
+pub mod bundle;
+pub mod remote;
+
 #[derive(Debug, Clone, Serialize, Deserialize, Args)]
 pub struct OperatorConfig {
     #[clap(long, short='n', default_value="1")]
@@ -39,7 +42,7 @@ pub struct OperatorConfig {
     pub formnet_join_server_port: u16,
     #[clap(long="formnet-service-port", short='f', default_value="51820")]
     pub formnet_service_port: u16,
-    #[clap(long="formnet-cidr", help="The CIDR for the formnet network (e.g., 10.42.0.0/16)")]
+    #[clap(long="formnet-cidr", help="The CIDR for the formnet network (e.g., 10.42.0.0/16, fd12:3456:789a::/48, or \"ula\" to generate an IPv6 unique local prefix)")]
     pub formnet_cidr: Option<String>,
     #[clap(long="vmm-service-port", short='v', default_value="3002")]
     pub vmm_service_port: u16,
@@ -48,7 +51,103 @@ pub struct OperatorConfig {
     #[clap(long="event-queue-port", short='e', aliases=["mempool-port", "event-pool-port", "mempool", "events"])]
     pub event_queue_port: u16,
     #[clap(long="contract", short='c', aliases=["staking-contract", "avs-contract"])]
-    pub contract_address: Option<String>
+    pub contract_address: Option<String>,
+    #[clap(flatten)]
+    #[serde(default)]
+    pub formnet_refresh: FormnetRefreshConfig,
+    #[clap(flatten)]
+    #[serde(default)]
+    pub formnet_port_mapping: FormnetPortMappingConfig,
+}
+
+/// How often formnet refreshes its peers, and what it refreshes. Configs
+/// written before it was added get the defaults. A running node's values can
+/// be changed through its formnet API on `/refresh`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Args)]
+#[serde(default)]
+pub struct FormnetRefreshConfig {
+    /// Seconds between fetches of the peer list
+    #[clap(long="formnet-refresh-interval", default_value_t=FormnetRefreshConfig::DEFAULT_REFRESH_INTERVAL_SECS)]
+    pub refresh_interval_secs: u64,
+    /// Seconds without a handshake after which a peer counts as disconnected
+    #[clap(long="formnet-handshake-timeout", default_value_t=FormnetRefreshConfig::DEFAULT_HANDSHAKE_TIMEOUT_SECS)]
+    pub handshake_timeout_secs: u64,
+    /// Seconds between re-resolutions of disconnected peers' endpoint hostnames, 0 for never
+    #[clap(long="formnet-endpoint-refresh", default_value_t=FormnetRefreshConfig::DEFAULT_ENDPOINT_REFRESH_SECS)]
+    pub endpoint_refresh_secs: u64,
+    /// Seconds between rotations of the node's WireGuard key, 0 for never
+    #[clap(long="formnet-rekey-interval", default_value_t=0)]
+    pub rekey_interval_secs: u64,
+}
+
+impl FormnetRefreshConfig {
+    pub const DEFAULT_REFRESH_INTERVAL_SECS: u64 = 60;
+    /// WireGuard's REJECT_AFTER_TIME
+    pub const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 180;
+    pub const DEFAULT_ENDPOINT_REFRESH_SECS: u64 = 300;
+    /// Shortest re-key cadence, a rotation needs its grace window to pass
+    /// before the next one is accepted
+    pub const MIN_REKEY_INTERVAL_SECS: u64 = 600;
+
+    pub fn validate(&self) -> Result<()> {
+        if self.refresh_interval_secs == 0 {
+            return Err(anyhow!("The refresh interval must be at least a second"));
+        }
+        if self.handshake_timeout_secs < 10 {
+            return Err(anyhow!("The handshake timeout must be at least 10 seconds"));
+        }
+        if self.rekey_interval_secs != 0 && self.rekey_interval_secs < Self::MIN_REKEY_INTERVAL_SECS {
+            return Err(anyhow!("The re-key interval must be 0 or at least {} seconds", Self::MIN_REKEY_INTERVAL_SECS));
+        }
+        Ok(())
+    }
+}
+
+impl Default for FormnetRefreshConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval_secs: Self::DEFAULT_REFRESH_INTERVAL_SECS,
+            handshake_timeout_secs: Self::DEFAULT_HANDSHAKE_TIMEOUT_SECS,
+            endpoint_refresh_secs: Self::DEFAULT_ENDPOINT_REFRESH_SECS,
+            rekey_interval_secs: 0,
+        }
+    }
+}
+
+/// Whether formnet asks the local router to forward its WireGuard port over
+/// NAT-PMP or UPnP at startup, for nodes behind a home router. Off by
+/// default, a node with a public address or a forwarded port doesn't need it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Args)]
+#[serde(default)]
+pub struct FormnetPortMappingConfig {
+    /// Map the formnet port on the gateway with NAT-PMP or UPnP
+    #[clap(long="formnet-port-mapping", default_value_t=false)]
+    pub enabled: bool,
+    /// Lifetime in seconds requested for the mapping, it is renewed halfway through
+    #[clap(long="formnet-port-mapping-lease", default_value_t=FormnetPortMappingConfig::DEFAULT_LEASE_SECS)]
+    pub lease_secs: u32,
+}
+
+impl FormnetPortMappingConfig {
+    pub const DEFAULT_LEASE_SECS: u32 = 3600;
+    /// Shortest lease, routers may drop shorter ones before they're renewed
+    pub const MIN_LEASE_SECS: u32 = 120;
+
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled && self.lease_secs < Self::MIN_LEASE_SECS {
+            return Err(anyhow!("The port mapping lease must be at least {} seconds", Self::MIN_LEASE_SECS));
+        }
+        Ok(())
+    }
+}
+
+impl Default for FormnetPortMappingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lease_secs: Self::DEFAULT_LEASE_SECS,
+        }
+    }
 }
 
 impl OperatorConfig {
@@ -432,6 +531,19 @@ mod prompts {
         Ok(is_bootstrap)
     }
 
+    pub fn port_mapping(theme: &ColorfulTheme) -> Result<bool> {
+        println!("\n{}", "Formnet Port Mapping".bold().green());
+        println!("Nodes behind a home router can ask it to forward the formnet port over NAT-PMP or UPnP.");
+        println!("Skip this if the node has a public address or the port is already forwarded.");
+
+        let enabled = Confirm::with_theme(theme)
+            .with_prompt("Map the formnet port on the router automatically?")
+            .default(false)
+            .interact()?;
+
+        Ok(enabled)
+    }
+
     pub fn region(theme: &ColorfulTheme) -> Result<Option<String>> {
         println!("\n{}", "Node Region Configuration".bold().green());
         println!("The geographic region helps optimize network connectivity.");
@@ -493,6 +605,7 @@ mod prompts {
         println!("\n{}", "Formnet CIDR Configuration".bold().green());
         println!("Configure the IP address range for the internal Formnet VPN (e.g., 10.42.0.0/16).");
         println!("Ensure this does not conflict with existing networks in your environment.");
+        println!("Enter \"ula\" to generate a random IPv6 unique local (fd00::/8) /48 for an IPv6 overlay.");
         
         let cidr: String = Input::with_theme(theme)
             .with_prompt("Enter Formnet CIDR (e.g., 10.42.0.0/16, leave empty for none/default behavior elsewhere)")
@@ -546,6 +659,7 @@ pub fn run_config_wizard() -> Result<OperatorConfig> {
     let bootstrap_nodes = prompts::bootstrap_nodes(&theme)?;
     let bootstrap_domain = prompts::bootstrap_domain(&theme)?;
     let is_bootstrap_node = prompts::bootstrap_role(&theme)?;
+    let port_mapping = prompts::port_mapping(&theme)?;
     let region = prompts::region(&theme)?;
     
     // Service ports
@@ -581,6 +695,11 @@ pub fn run_config_wizard() -> Result<OperatorConfig> {
         event_queue_port,
         contract_address,
         formnet_cidr,
+        formnet_refresh: FormnetRefreshConfig::default(),
+        formnet_port_mapping: FormnetPortMappingConfig {
+            enabled: port_mapping,
+            ..Default::default()
+        },
     };
 
     Ok(config)
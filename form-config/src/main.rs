@@ -2,7 +2,8 @@ use std::path::PathBuf;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use dialoguer::{theme::ColorfulTheme, Confirm, Input};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password};
+use form_config::bundle::{self, NodeSpec, ProvisioningBundle};
 use form_config::*;
 
 #[derive(Parser)]
@@ -21,6 +22,106 @@ enum Commands {
         #[command(subcommand)]
         action: BootstrapCommands,
     },
+    /// Push fleet-wide settings to form-state, signed by an admin key
+    Push {
+        /// JSON file with the settings to share. An operator config file can
+        /// be used as is, keys and other per-host fields are left out
+        #[arg(long, short)]
+        file: PathBuf,
+
+        /// Hex encoded admin private key
+        #[arg(long="private-key", short='k')]
+        private_key: Option<String>,
+
+        /// Operator config to take the admin key from when no private key is given
+        #[arg(long, short, default_value = "./secrets/.operator-config.json")]
+        config: PathBuf,
+
+        /// Password of an encrypted operator config
+        #[arg(long, short)]
+        password: Option<String>,
+
+        /// Only push if the fleet config is still at this version
+        #[arg(long="expected-version")]
+        expected_version: Option<u64>,
+
+        /// form-state API endpoint
+        #[arg(long="state-url", short, default_value = "http://localhost:3004")]
+        state_url: String,
+    },
+    /// Pull the fleet-wide settings from form-state
+    Pull {
+        /// Write the settings to this file instead of printing them
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// form-state API endpoint
+        #[arg(long="state-url", short, default_value = "http://localhost:3004")]
+        state_url: String,
+    },
+    /// Create an encrypted provisioning bundle with a key for every node,
+    /// all derived from one mnemonic
+    Bundle {
+        /// Operator config every node's config starts from. Its keys,
+        /// region and bootstrap role are left out
+        #[arg(long, short, default_value = "./secrets/.operator-config.json")]
+        template: PathBuf,
+
+        /// A node to add, as `name` or `name@region`. Can be repeated
+        #[arg(long = "node", short, required = true)]
+        nodes: Vec<NodeSpec>,
+
+        /// Name of a node that serves as a bootstrap node. Can be repeated
+        #[arg(long = "bootstrap")]
+        bootstrap: Vec<String>,
+
+        /// HD index of the first node. Use the next free index when adding
+        /// nodes to a fleet that already has keys from this mnemonic
+        #[arg(long = "start-index", default_value_t = 0)]
+        start_index: u32,
+
+        /// Mnemonic the node keys are derived from, prompted for when not given
+        #[arg(long)]
+        mnemonic: Option<String>,
+
+        /// Password of the mnemonic, if it has one
+        #[arg(long = "mnemonic-password")]
+        mnemonic_password: Option<String>,
+
+        /// Password the bundle is encrypted with, prompted for when not given
+        #[arg(long, short)]
+        password: Option<String>,
+
+        /// Where to write the bundle
+        #[arg(long, short, default_value = bundle::DEFAULT_BUNDLE_PATH)]
+        output: PathBuf,
+    },
+    /// Write this node's operator config
+    Provision {
+        /// Provisioning bundle created with `bundle`
+        #[arg(long = "from-bundle")]
+        from_bundle: PathBuf,
+
+        /// Name of this node in the bundle
+        #[arg(long, short)]
+        node: String,
+
+        /// Password of the bundle, prompted for when not given
+        #[arg(long, short)]
+        password: Option<String>,
+
+        /// Where to write the operator config
+        #[arg(long, short, default_value = "./secrets/.operator-config.json")]
+        output: PathBuf,
+
+        /// Encrypt the node's secret key in the config with this password
+        #[arg(long = "encrypt-password")]
+        encrypt_password: Option<String>,
+
+        /// Replace an existing operator config
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -74,6 +175,16 @@ async fn main() -> Result<()> {
     match cli.command {
         Some(Commands::Wizard) => run_wizard(),
         Some(Commands::Bootstrap { action }) => manage_bootstrap_nodes(action).await,
+        Some(Commands::Push { file, private_key, config, password, expected_version, state_url }) => {
+            push_fleet_config(file, private_key, config, password, expected_version, state_url).await
+        }
+        Some(Commands::Pull { output, state_url }) => pull_fleet_config(output, state_url).await,
+        Some(Commands::Bundle { template, nodes, bootstrap, start_index, mnemonic, mnemonic_password, password, output }) => {
+            create_bundle(template, nodes, bootstrap, start_index, mnemonic, mnemonic_password, password, output)
+        }
+        Some(Commands::Provision { from_bundle, node, password, output, encrypt_password, force }) => {
+            provision_from_bundle(from_bundle, node, password, output, encrypt_password, force)
+        }
         None => run_wizard(),
     }
 }
@@ -114,6 +225,126 @@ fn run_wizard() -> Result<()> {
     Ok(())
 }
 
+/// Push fleet-wide settings to form-state
+async fn push_fleet_config(
+    file: PathBuf,
+    private_key: Option<String>,
+    config: PathBuf,
+    password: Option<String>,
+    expected_version: Option<u64>,
+    state_url: String,
+) -> Result<()> {
+    let shared: remote::SharedConfig = serde_json::from_slice(&std::fs::read(&file)?)?;
+    if shared.is_empty() {
+        return Err(anyhow::anyhow!("{} doesn't contain any fleet-wide settings", file.display()));
+    }
+
+    let private_key = match private_key {
+        Some(key) => key,
+        None => OperatorConfig::from_file(&config, password.is_some(), password.as_deref())?
+            .secret_key
+            .ok_or_else(|| anyhow::anyhow!("{} has no secret key", config.display()))?,
+    };
+    let signing_key = k256::ecdsa::SigningKey::from_slice(&hex::decode(private_key.trim_start_matches("0x"))?)?;
+
+    let request = remote::PushConfigRequest { config: shared, expected_version };
+    let pushed = remote::push_remote_config(&state_url, &signing_key, &request).await?;
+    println!("✅ Fleet config version {} pushed to {}", pushed.version, state_url);
+    Ok(())
+}
+
+/// Pull the fleet-wide settings from form-state
+async fn pull_fleet_config(output: Option<PathBuf>, state_url: String) -> Result<()> {
+    let Some(remote) = remote::fetch_remote_config(&state_url).await? else {
+        println!("No fleet config has been pushed to {} yet.", state_url);
+        return Ok(());
+    };
+
+    let shared = serde_json::to_string_pretty(&remote.config)?;
+    match output {
+        Some(path) => {
+            std::fs::write(&path, shared)?;
+            println!("✅ Fleet config version {} written to {}", remote.version, path.display());
+        }
+        None => println!("{}", shared),
+    }
+    println!("Version {} pushed by {} at {}", remote.version, remote.updated_by, remote.updated_at);
+    Ok(())
+}
+
+/// Create an encrypted provisioning bundle
+#[allow(clippy::too_many_arguments)]
+fn create_bundle(
+    template: PathBuf,
+    mut nodes: Vec<NodeSpec>,
+    bootstrap: Vec<String>,
+    start_index: u32,
+    mnemonic: Option<String>,
+    mnemonic_password: Option<String>,
+    password: Option<String>,
+    output: PathBuf,
+) -> Result<()> {
+    if let Some(unknown) = bootstrap.iter().find(|name| !nodes.iter().any(|node| &node.name == *name)) {
+        return Err(anyhow::anyhow!("Bootstrap node {} isn't one of the bundled nodes", unknown));
+    }
+    for node in nodes.iter_mut() {
+        node.is_bootstrap_node = bootstrap.contains(&node.name);
+    }
+
+    let template: OperatorConfig = serde_json::from_slice(&std::fs::read(&template)?)?;
+    let theme = ColorfulTheme::default();
+    let phrase = match mnemonic {
+        Some(phrase) => phrase,
+        None => Password::with_theme(&theme).with_prompt("Mnemonic phrase to derive the node keys from").interact()?,
+    };
+    let mnemonic = alloy::signers::local::coins_bip39::Mnemonic::new_from_phrase(phrase.trim())?;
+    let password = match password {
+        Some(password) => password,
+        None => Password::with_theme(&theme)
+            .with_prompt("Password to encrypt the bundle with")
+            .with_confirmation("Confirm the password", "Passwords do not match")
+            .interact()?,
+    };
+
+    let bundle = ProvisioningBundle::new(template, &mnemonic, mnemonic_password.as_deref(), nodes, start_index)?;
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&output, bundle.encrypt(&password)?)?;
+
+    println!("✅ Bundle with {} nodes written to {}", bundle.nodes.len(), output.display());
+    for node in &bundle.nodes {
+        println!("  {:<20} 0x{} ({})", node.name, node.address, node.derivation_path);
+    }
+    let next = bundle.nodes.iter().map(|node| node.index).max().unwrap_or(start_index) + 1;
+    println!("Use --start-index {} when bundling more nodes from this mnemonic.", next);
+    Ok(())
+}
+
+/// Write a node's operator config from a provisioning bundle
+fn provision_from_bundle(
+    from_bundle: PathBuf,
+    node: String,
+    password: Option<String>,
+    output: PathBuf,
+    encrypt_password: Option<String>,
+    force: bool,
+) -> Result<()> {
+    let password = match password {
+        Some(password) => password,
+        None => Password::with_theme(&ColorfulTheme::default()).with_prompt("Bundle password").interact()?,
+    };
+    let bundle = ProvisioningBundle::from_file(&from_bundle, &password)?;
+    let config = bundle.node_config(&node)?;
+    bundle::write_node_config(&config, &output, encrypt_password.as_deref(), force)?;
+
+    println!("✅ Config for {} (0x{}) written to {}", node, config.address.unwrap_or_default(), output.display());
+    if encrypt_password.is_some() {
+        println!("The secret key is encrypted, start the node with the same password.");
+    }
+    Ok(())
+}
+
 /// Manage bootstrap nodes in the DNS
 async fn manage_bootstrap_nodes(action: BootstrapCommands) -> Result<()> {
     // Define data structures for API requests/responses
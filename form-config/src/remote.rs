@@ -0,0 +1,360 @@
+//! Fleet-wide operator settings managed through form-state
+//!
+//! Operator config files are per host and drift over time. The non-secret,
+//! fleet-wide part of the config ([`SharedConfig`]) can instead be stored in
+//! form-state and fetched at startup. Keys, addresses, the bootstrap role
+//! and the region always stay local.
+//!
+//! Values are resolved in this order, later sources win:
+//!
+//! 1. The local operator config file
+//! 2. The fleet config from form-state, or the cached copy when form-state
+//!    can't be reached
+//! 3. The local overrides file
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use k256::ecdsa::SigningKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tiny_keccak::{Hasher, Sha3};
+use crate::OperatorConfig;
+
+/// Default location of the cached fleet config
+pub const DEFAULT_CACHE_PATH: &str = "/var/lib/formation/config/remote-config.json";
+/// Default interval between fleet config polls
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The operator settings that are shared by every node of the fleet
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_id: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial_admin_public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap_nodes: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap_domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formnet_cidr: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datastore_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formnet_join_server_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formnet_service_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vmm_service_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack_manager_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_queue_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract_address: Option<String>,
+}
+
+impl SharedConfig {
+    /// The shared settings of a local operator config
+    pub fn from_operator_config(config: &OperatorConfig) -> Self {
+        Self {
+            network_id: Some(config.network_id),
+            initial_admin_public_key: config.initial_admin_public_key.clone(),
+            bootstrap_nodes: Some(config.bootstrap_nodes.clone()),
+            bootstrap_domain: config.bootstrap_domain.clone(),
+            formnet_cidr: config.formnet_cidr.clone(),
+            datastore_port: Some(config.datastore_port),
+            formnet_join_server_port: Some(config.formnet_join_server_port),
+            formnet_service_port: Some(config.formnet_service_port),
+            vmm_service_port: Some(config.vmm_service_port),
+            pack_manager_port: Some(config.pack_manager_port),
+            event_queue_port: Some(config.event_queue_port),
+            contract_address: config.contract_address.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Overwrites the settings of `config` that are set here
+    pub fn apply(&self, config: &mut OperatorConfig) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        fn set_opt<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *target = value.clone();
+            }
+        }
+
+        set(&mut config.network_id, &self.network_id);
+        set_opt(&mut config.initial_admin_public_key, &self.initial_admin_public_key);
+        set(&mut config.bootstrap_nodes, &self.bootstrap_nodes);
+        set_opt(&mut config.bootstrap_domain, &self.bootstrap_domain);
+        set_opt(&mut config.formnet_cidr, &self.formnet_cidr);
+        set(&mut config.datastore_port, &self.datastore_port);
+        set(&mut config.formnet_join_server_port, &self.formnet_join_server_port);
+        set(&mut config.formnet_service_port, &self.formnet_service_port);
+        set(&mut config.vmm_service_port, &self.vmm_service_port);
+        set(&mut config.pack_manager_port, &self.pack_manager_port);
+        set(&mut config.event_queue_port, &self.event_queue_port);
+        set_opt(&mut config.contract_address, &self.contract_address);
+    }
+}
+
+/// A versioned fleet config as stored in form-state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteConfig {
+    /// Incremented on every push
+    pub version: u64,
+    pub config: SharedConfig,
+    /// Address of the admin that pushed this version
+    pub updated_by: String,
+    pub updated_at: i64,
+}
+
+impl RemoteConfig {
+    /// Whether this config replaces `other`. Concurrent pushes of the same
+    /// version are ordered by time and then by author so every node keeps
+    /// the same one.
+    pub fn supersedes(&self, other: &RemoteConfig) -> bool {
+        (self.version, self.updated_at, &self.updated_by) > (other.version, other.updated_at, &other.updated_by)
+    }
+}
+
+/// Body of the form-state fleet config endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteConfigResponse {
+    pub success: bool,
+    #[serde(default)]
+    pub config: Option<RemoteConfig>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Body of a fleet config push
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushConfigRequest {
+    pub config: SharedConfig,
+    /// Rejects the push if the fleet config changed since this version was pulled
+    #[serde(default)]
+    pub expected_version: Option<u64>,
+}
+
+/// Where the fleet config comes from and where it is cached
+#[derive(Debug, Clone)]
+pub struct RemoteConfigOptions {
+    /// Base URL of the form-state API, e.g. `http://10.0.0.1:3004`
+    pub state_url: String,
+    pub cache_path: PathBuf,
+    pub overrides_path: Option<PathBuf>,
+    pub poll_interval: Duration,
+}
+
+impl RemoteConfigOptions {
+    /// Remote config is enabled by setting `FORM_CONFIG_REMOTE_URL`, and
+    /// `FORM_CONFIG_CACHE_PATH`, `FORM_CONFIG_OVERRIDES_PATH` and
+    /// `FORM_CONFIG_POLL_SECS` adjust it
+    pub fn from_env() -> Option<Self> {
+        let state_url = std::env::var("FORM_CONFIG_REMOTE_URL").ok().filter(|url| !url.is_empty())?;
+        Some(Self {
+            state_url,
+            cache_path: std::env::var("FORM_CONFIG_CACHE_PATH")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from(DEFAULT_CACHE_PATH)),
+            overrides_path: std::env::var("FORM_CONFIG_OVERRIDES_PATH").ok().map(PathBuf::from),
+            poll_interval: std::env::var("FORM_CONFIG_POLL_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_POLL_INTERVAL),
+        })
+    }
+}
+
+fn endpoint(state_url: &str, path: &str) -> String {
+    format!("{}/v1/config/{}", state_url.trim_end_matches('/'), path)
+}
+
+/// Fetches the current fleet config, `None` if none was pushed yet
+pub async fn fetch_remote_config(state_url: &str) -> Result<Option<RemoteConfig>> {
+    let response = reqwest::Client::new()
+        .get(endpoint(state_url, "get"))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?
+        .json::<RemoteConfigResponse>()
+        .await?;
+    if !response.success {
+        return Err(anyhow!(response.error.unwrap_or_else(|| "Unable to fetch fleet config".to_string())));
+    }
+    Ok(response.config)
+}
+
+/// Pushes a new fleet config, signed by an admin key
+pub async fn push_remote_config(
+    state_url: &str,
+    signing_key: &SigningKey,
+    request: &PushConfigRequest,
+) -> Result<RemoteConfig> {
+    let body = serde_json::to_vec(request)?;
+    let response = reqwest::Client::new()
+        .post(endpoint(state_url, "set"))
+        .header(reqwest::header::AUTHORIZATION, sign_request(signing_key, "POST", "/v1/config/set", &body)?)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await?
+        .json::<RemoteConfigResponse>()
+        .await?;
+    match response {
+        RemoteConfigResponse { success: true, config: Some(config), .. } => Ok(config),
+        response => Err(anyhow!(response.error.unwrap_or_else(|| "Unable to push fleet config".to_string()))),
+    }
+}
+
+/// Signs the canonical digest of a form-state request, this must match
+/// `form_state::auth::digest`
+fn sign_request(signing_key: &SigningKey, method: &str, path: &str, body: &[u8]) -> Result<String> {
+    let mut body_hash = [0u8; 32];
+    let mut hasher = Sha3::v256();
+    hasher.update(body);
+    hasher.finalize(&mut body_hash);
+    let message = format!("formation-request-v1\n{method}\n{path}\n{}", hex::encode(body_hash));
+    let (signature, recovery_id) = signing_key.sign_recoverable(&Sha256::digest(message.as_bytes()))?;
+    Ok(format!(
+        "Signature {}.{}.{}",
+        hex::encode(signature.to_bytes()),
+        recovery_id.to_byte(),
+        hex::encode(message.as_bytes())
+    ))
+}
+
+pub fn load_cached_config(path: impl AsRef<Path>) -> Result<Option<RemoteConfig>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn save_cached_config(path: impl AsRef<Path>, config: &RemoteConfig) -> Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(config)?)?;
+    Ok(())
+}
+
+/// Applies the fleet config and the local overrides to `config`, returning
+/// the fleet config that was used
+pub async fn resolve_config(
+    config: &mut OperatorConfig,
+    options: &RemoteConfigOptions,
+) -> Result<Option<RemoteConfig>> {
+    let remote = match fetch_remote_config(&options.state_url).await {
+        Ok(Some(remote)) => {
+            if let Err(e) = save_cached_config(&options.cache_path, &remote) {
+                eprintln!("Unable to cache fleet config at {}: {e}", options.cache_path.display());
+            }
+            Some(remote)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Unable to fetch fleet config from {}, using cached copy: {e}", options.state_url);
+            load_cached_config(&options.cache_path)?
+        }
+    };
+
+    if let Some(remote) = &remote {
+        remote.config.apply(config);
+    }
+    if let Some(path) = &options.overrides_path {
+        let overrides: SharedConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+        overrides.apply(config);
+    }
+    Ok(remote)
+}
+
+/// Polls form-state for fleet config changes. The receiver is updated with
+/// every newer version, which is also written to the cache.
+pub fn watch_remote_config(
+    options: RemoteConfigOptions,
+    current: Option<RemoteConfig>,
+) -> tokio::sync::watch::Receiver<Option<RemoteConfig>> {
+    let (tx, rx) = tokio::sync::watch::channel(current);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(options.poll_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let latest = match fetch_remote_config(&options.state_url).await {
+                Ok(Some(latest)) => latest,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Unable to poll fleet config from {}: {e}", options.state_url);
+                    continue;
+                }
+            };
+            let changed = match tx.borrow().as_ref() {
+                Some(current) => latest.supersedes(current),
+                None => true,
+            };
+            if !changed {
+                continue;
+            }
+            if let Err(e) = save_cached_config(&options.cache_path, &latest) {
+                eprintln!("Unable to cache fleet config at {}: {e}", options.cache_path.display());
+            }
+            if tx.send(Some(latest)).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: OperatorConfig,
+    }
+
+    #[test]
+    fn test_shared_config_precedence() {
+        let mut config = Cli::parse_from(["test", "--event-queue-port", "3005", "--region", "us-east"]).config;
+        let remote = SharedConfig {
+            bootstrap_nodes: Some(vec!["10.0.0.1".to_string()]),
+            datastore_port: Some(4004),
+            vmm_service_port: Some(4002),
+            ..Default::default()
+        };
+        let overrides = SharedConfig { vmm_service_port: Some(5002), ..Default::default() };
+
+        remote.apply(&mut config);
+        overrides.apply(&mut config);
+        assert_eq!(config.bootstrap_nodes, vec!["10.0.0.1".to_string()]);
+        assert_eq!(config.datastore_port, 4004);
+        assert_eq!(config.vmm_service_port, 5002);
+        assert_eq!(config.event_queue_port, 3005);
+        assert_eq!(config.region.as_deref(), Some("us-east"));
+
+        // An operator config file can be pushed as is, only the shared fields are kept
+        let shared: SharedConfig = serde_json::from_value(serde_json::to_value(&config).unwrap()).unwrap();
+        assert_eq!(shared, SharedConfig::from_operator_config(&config));
+
+        let older = RemoteConfig { version: 1, config: remote, updated_by: "a".into(), updated_at: 10 };
+        let newer = RemoteConfig { version: 2, updated_at: 5, ..older.clone() };
+        assert!(newer.supersedes(&older));
+        assert!(!older.supersedes(&newer));
+        assert!(!older.supersedes(&older));
+    }
+}
@@ -49,6 +49,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 longitude: -74.0060,
                 country_code: Some("US".to_string()),
                 region_code: Some("NY".to_string()),
+                asn: None,
             };
             
             let los_angeles = GeoLocation {
@@ -56,6 +57,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 longitude: -118.2437,
                 country_code: Some("US".to_string()),
                 region_code: Some("CA".to_string()),
+                asn: None,
             };
             
             let distance = calculate_distance(&new_york, &los_angeles);
@@ -74,6 +76,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 longitude: -0.1278,
                 country_code: Some("GB".to_string()),
                 region_code: Some("ENG".to_string()),
+                asn: None,
             };
             
             println!("\nTesting proximity from London:");
@@ -0,0 +1,307 @@
+//! Per-domain query analytics for builders.
+//!
+//! Every query for a name the store answers is counted in hourly buckets
+//! by the client's country and autonomous system, the transport, the query
+//! type and whether the client is on formnet. Client addresses are never
+//! kept, only the counters. Reports fold countries and networks seen fewer
+//! than `MIN_REPORTED_QUERIES` times into `other`, so a single client
+//! can't be picked out, and buckets older than the retention are dropped.
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use crate::is_formnet_ip;
+
+/// Hours of analytics kept, 0 turns analytics off
+pub const DNS_ANALYTICS_RETENTION_ENV: &str = "FORM_DNS_ANALYTICS_RETENTION_HOURS";
+
+pub const DEFAULT_RETENTION_HOURS: u64 = 7 * 24;
+
+pub const BUCKET_SECS: u64 = 3600;
+
+/// Countries and networks with fewer queries in a report are counted as
+/// `other`
+pub const MIN_REPORTED_QUERIES: u64 = 5;
+
+/// Domains tracked before the least recently queried are dropped
+const MAX_TRACKED_DOMAINS: usize = 10_000;
+
+/// Distinct keys of a breakdown in one bucket, further keys count as `other`
+const MAX_KEYS_PER_BREAKDOWN: usize = 256;
+
+const OTHER: &str = "other";
+const UNKNOWN: &str = "unknown";
+
+static DNS_ANALYTICS: OnceCell<DnsAnalytics> = OnceCell::new();
+
+/// One answered query, reduced to what is counted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuerySample {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    /// `udp`, `tcp`, `tls`, `https` or `quic`
+    pub transport: String,
+    pub query_type: String,
+    pub formnet: bool,
+}
+
+impl QuerySample {
+    /// Locates the client through the geolocation providers, formnet
+    /// clients are not located
+    pub fn from_client(client: IpAddr, transport: impl ToString, query_type: impl ToString) -> Self {
+        let formnet = is_formnet_ip(client);
+        let location = (!formnet && !client.is_loopback())
+            .then(|| crate::geo_util::get_client_location(client))
+            .flatten();
+        Self {
+            country: location.as_ref().and_then(|location| location.country_code.clone()),
+            asn: location.and_then(|location| location.asn),
+            transport: transport.to_string().to_lowercase(),
+            query_type: query_type.to_string(),
+            formnet,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Bucket {
+    total: u64,
+    countries: HashMap<String, u64>,
+    networks: HashMap<String, u64>,
+    transports: HashMap<String, u64>,
+    query_types: HashMap<String, u64>,
+    client_types: HashMap<String, u64>,
+}
+
+fn count(counts: &mut HashMap<String, u64>, key: String) {
+    let key = if counts.len() >= MAX_KEYS_PER_BREAKDOWN && !counts.contains_key(&key) {
+        OTHER.to_string()
+    } else {
+        key
+    };
+    *counts.entry(key).or_default() += 1;
+}
+
+impl Bucket {
+    fn add(&mut self, sample: QuerySample) {
+        self.total += 1;
+        count(&mut self.countries, sample.country.unwrap_or_else(|| UNKNOWN.to_string()));
+        count(&mut self.networks, sample.asn.map_or_else(|| UNKNOWN.to_string(), |asn| format!("AS{asn}")));
+        count(&mut self.transports, sample.transport);
+        count(&mut self.query_types, sample.query_type);
+        count(&mut self.client_types, if sample.formnet { "formnet" } else { "public" }.to_string());
+    }
+}
+
+/// Queries for a domain over a time range
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainAnalytics {
+    pub domain: String,
+    /// Unix timestamps of the first bucket and the end of the range
+    pub from: u64,
+    pub to: u64,
+    pub bucket_secs: u64,
+    pub total: u64,
+    /// Queries per bucket, by the bucket's start
+    pub series: BTreeMap<u64, u64>,
+    pub countries: BTreeMap<String, u64>,
+    pub networks: BTreeMap<String, u64>,
+    pub transports: BTreeMap<String, u64>,
+    pub query_types: BTreeMap<String, u64>,
+    /// `formnet` or `public`
+    pub client_types: BTreeMap<String, u64>,
+}
+
+fn merge(into: &mut BTreeMap<String, u64>, counts: HashMap<String, u64>) {
+    for (key, queries) in counts {
+        *into.entry(key).or_default() += queries;
+    }
+}
+
+/// Folds keys with fewer than `min` queries into `other`
+fn suppress(counts: BTreeMap<String, u64>, min: u64) -> BTreeMap<String, u64> {
+    let mut reported = BTreeMap::new();
+    for (key, queries) in counts {
+        let key = if queries < min { OTHER.to_string() } else { key };
+        *reported.entry(key).or_default() += queries;
+    }
+    reported
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+pub struct DnsAnalytics {
+    retention_hours: u64,
+    domains: Mutex<HashMap<String, BTreeMap<u64, Bucket>>>,
+}
+
+impl DnsAnalytics {
+    pub fn new(retention_hours: u64) -> Self {
+        Self { retention_hours, domains: Mutex::new(HashMap::new()) }
+    }
+
+    /// Retention from `FORM_DNS_ANALYTICS_RETENTION_HOURS`
+    pub fn from_env() -> Self {
+        let retention_hours = std::env::var(DNS_ANALYTICS_RETENTION_ENV).ok()
+            .and_then(|hours| hours.parse().ok())
+            .unwrap_or(DEFAULT_RETENTION_HOURS);
+        Self::new(retention_hours)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.retention_hours > 0
+    }
+
+    pub fn retention_hours(&self) -> u64 {
+        self.retention_hours
+    }
+
+    fn oldest_kept(&self, now: u64) -> u64 {
+        let bucket = now - now % BUCKET_SECS;
+        bucket.saturating_sub((self.retention_hours - 1) * BUCKET_SECS)
+    }
+
+    pub fn record(&self, domain: &str, sample: QuerySample, now: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let domain = normalize_domain(domain);
+        let oldest = self.oldest_kept(now);
+        let mut domains = self.domains.lock().unwrap();
+        if domains.len() >= MAX_TRACKED_DOMAINS && !domains.contains_key(&domain) {
+            domains.retain(|_, buckets| {
+                buckets.retain(|start, _| *start >= oldest);
+                !buckets.is_empty()
+            });
+            // Still full, the domain queried longest ago makes room
+            if domains.len() >= MAX_TRACKED_DOMAINS {
+                let stalest = domains.iter()
+                    .min_by_key(|(_, buckets)| buckets.keys().next_back().copied())
+                    .map(|(domain, _)| domain.clone());
+                if let Some(stalest) = stalest {
+                    domains.remove(&stalest);
+                }
+            }
+        }
+
+        let buckets = domains.entry(domain).or_default();
+        buckets.retain(|start, _| *start >= oldest);
+        buckets.entry(now - now % BUCKET_SECS).or_default().add(sample);
+    }
+
+    /// Queries for `domain` in the last `hours`, at most the retention
+    pub fn report(&self, domain: &str, hours: u64, now: u64) -> DomainAnalytics {
+        let domain = normalize_domain(domain);
+        let hours = hours.clamp(1, self.retention_hours.max(1));
+        let from = (now - now % BUCKET_SECS).saturating_sub((hours - 1) * BUCKET_SECS);
+        let mut report = DomainAnalytics {
+            domain: domain.clone(),
+            from,
+            to: now,
+            bucket_secs: BUCKET_SECS,
+            ..Default::default()
+        };
+
+        let buckets: Vec<(u64, Bucket)> = self.domains.lock().unwrap().get(&domain)
+            .map(|buckets| buckets.range(from..).map(|(start, bucket)| (*start, bucket.clone())).collect())
+            .unwrap_or_default();
+        let (mut countries, mut networks) = (BTreeMap::new(), BTreeMap::new());
+        for (start, bucket) in buckets {
+            report.total += bucket.total;
+            report.series.insert(start, bucket.total);
+            merge(&mut countries, bucket.countries);
+            merge(&mut networks, bucket.networks);
+            merge(&mut report.transports, bucket.transports);
+            merge(&mut report.query_types, bucket.query_types);
+            merge(&mut report.client_types, bucket.client_types);
+        }
+        report.countries = suppress(countries, MIN_REPORTED_QUERIES);
+        report.networks = suppress(networks, MIN_REPORTED_QUERIES);
+        report
+    }
+
+    /// Forgets the analytics of a deleted domain
+    pub fn remove(&self, domain: &str) {
+        self.domains.lock().unwrap().remove(&normalize_domain(domain));
+    }
+}
+
+/// Initialize the global analytics, false if they already were
+pub fn init_dns_analytics(analytics: DnsAnalytics) -> bool {
+    DNS_ANALYTICS.set(analytics).is_ok()
+}
+
+/// The global analytics, with the retention from the environment if they
+/// weren't initialized
+pub fn get_dns_analytics() -> &'static DnsAnalytics {
+    DNS_ANALYTICS.get_or_init(DnsAnalytics::from_env)
+}
+
+/// Counts a query for `domain` now
+pub fn record_query(domain: &str, sample: QuerySample) {
+    get_dns_analytics().record(domain, sample, unix_now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(country: &str, asn: u32, transport: &str) -> QuerySample {
+        QuerySample {
+            country: Some(country.to_string()),
+            asn: Some(asn),
+            transport: transport.to_string(),
+            query_type: "A".to_string(),
+            formnet: false,
+        }
+    }
+
+    #[test]
+    fn test_small_buckets_are_reported_as_other() {
+        let analytics = DnsAnalytics::new(24);
+        let now = 10 * BUCKET_SECS + 60;
+        for _ in 0..6 {
+            analytics.record("App.Example.", sample("DE", 3320, "udp"), now);
+        }
+        for _ in 0..2 {
+            analytics.record("app.example", sample("NZ", 9500, "tcp"), now - BUCKET_SECS);
+        }
+        analytics.record("app.example", QuerySample { formnet: true, country: None, asn: None, ..sample("", 0, "udp") }, now);
+
+        let report = analytics.report("app.example", 24, now);
+        assert_eq!(report.total, 9);
+        assert_eq!(report.series.get(&(10 * BUCKET_SECS)), Some(&7));
+        assert_eq!(report.series.get(&(9 * BUCKET_SECS)), Some(&2));
+        assert_eq!(report.countries.get("DE"), Some(&6));
+        assert_eq!(report.countries.get("NZ"), None);
+        assert_eq!(report.countries.get(OTHER), Some(&3));
+        assert_eq!(report.networks.get("AS3320"), Some(&6));
+        assert_eq!(report.transports.get("tcp"), Some(&2));
+        assert_eq!(report.client_types.get("formnet"), Some(&1));
+
+        // A report of the last hour only covers the current bucket
+        assert_eq!(analytics.report("app.example", 1, now).total, 7);
+    }
+
+    #[test]
+    fn test_buckets_past_retention_are_dropped() {
+        let analytics = DnsAnalytics::new(2);
+        analytics.record("app.example", sample("DE", 3320, "udp"), 0);
+        analytics.record("app.example", sample("DE", 3320, "udp"), 2 * BUCKET_SECS);
+        let report = analytics.report("app.example", 48, 2 * BUCKET_SECS);
+        assert_eq!(report.total, 1);
+        assert_eq!(report.from, BUCKET_SECS);
+
+        let disabled = DnsAnalytics::new(0);
+        disabled.record("app.example", sample("DE", 3320, "udp"), 0);
+        assert_eq!(disabled.report("app.example", 1, 0).total, 0);
+    }
+}
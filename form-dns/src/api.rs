@@ -1,8 +1,17 @@
-use std::{collections::hash_map::Entry, net::{IpAddr, Ipv4Addr, SocketAddr}};
+use std::net::{IpAddr, SocketAddr};
 
+use crate::auth::{authorize_admin, authorize_build, authorize_record, AuthError, Caller};
+use crate::health::{EndpointCheck, EndpointHealth};
 use crate::store::{FormDnsRecord, SharedStore, VerificationResult, VerificationStatus};
+use crate::streams::StreamRoute;
+use crate::dnssec::{export_ds, key_dir, DsExport};
+use crate::geo_provider::{save_provider_configs, GeoChainStatus, GeoProviderConfig, GEO_PROVIDERS_PATH};
+use crate::geo_util::get_geo_resolver;
+use crate::query_policy::{get_query_policy, save_query_policy, QueryPolicyConfig, QueryPolicyStats, Violation, QUERY_POLICY_PATH};
+use crate::analytics::{get_dns_analytics, DomainAnalytics};
+use crate::bulk::{apply_bulk, BulkRequest, BulkResponse};
 use serde::{Serialize, Deserialize};
-use axum::{extract::{Path, State}, routing::{delete, get, post}, Json, Router};
+use axum::{extract::{Path, Query, State}, routing::{delete, get, post}, Json, Router};
 use tokio::net::TcpListener;
 use trust_dns_proto::rr::RecordType;
 
@@ -13,12 +22,28 @@ pub fn build_routes(state: SharedStore) -> Router {
         .route("/record/:domain/delete", delete(delete_record))
         .route("/record/:domain/get", get(get_record))
         .route("/record/list", get(list_records))
+        .route("/record/bulk", post(bulk_records))
         .route("/server/create", post(new_server))
         .route("/record/:domain/initiate_verification", post(initiate_verification))
         .route("/record/:domain/check_verification", post(check_verification))
+        .route("/record/:domain/health_check/set", post(set_health_check))
+        .route("/record/:domain/health_check/delete", delete(remove_health_check))
+        .route("/record/:domain/health", get(record_health))
+        .route("/record/:domain/stream/set", post(set_stream_route))
+        .route("/record/:domain/stream/:port/delete", delete(remove_stream_route))
+        .route("/record/:domain/stream", get(record_stream_routes))
+        .route("/stream/list", get(list_stream_routes))
         .route("/bootstrap/add", post(add_bootstrap_node))
         .route("/bootstrap/remove", post(remove_bootstrap_node))
         .route("/bootstrap/list", get(list_bootstrap_nodes))
+        .route("/dnssec/:zone/ds", get(zone_ds))
+        .route("/geo/providers", get(geo_providers))
+        .route("/geo/providers/set", post(set_geo_providers))
+        .route("/geo/reload", post(reload_geo_providers))
+        .route("/policy", get(query_policy))
+        .route("/policy/set", post(set_query_policy))
+        .route("/policy/violations", get(query_policy_violations))
+        .route("/analytics/dns/:domain", get(domain_analytics))
         .with_state(state)
 }
 
@@ -40,6 +65,13 @@ pub enum DomainRequest {
     },
 }
 
+/// Query of `/record/create`. Accounts have to name the build the record
+/// points to, and own it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CreateRecordQuery {
+    pub build_id: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum DomainResponse {
     Success(Success),
@@ -55,6 +87,76 @@ pub enum Success {
     List(Vec<(String, FormDnsRecord)>)
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BulkOperationsResponse {
+    /// Applied, validated by a dry run, or refused with the operations that
+    /// failed marked
+    Success(BulkResponse),
+    /// The request as a whole was invalid
+    Failure(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HealthCheckResponse {
+    Success,
+    Failure(String),
+    Status {
+        check: Option<EndpointCheck>,
+        endpoints: Vec<(IpAddr, EndpointHealth)>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum StreamRouteResponse {
+    Success(StreamRoute),
+    Failure(String),
+    List(Vec<StreamRoute>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DnssecResponse {
+    Ds(DsExport),
+    Failure(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum GeoProvidersResponse {
+    Status(GeoChainStatus),
+    /// Providers whose data was reloaded
+    Reloaded(Vec<String>),
+    Failure(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum QueryPolicyResponse {
+    Policy {
+        config: QueryPolicyConfig,
+        stats: QueryPolicyStats,
+    },
+    /// Newest first
+    Violations(Vec<Violation>),
+    Failure(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AnalyticsResponse {
+    Report(DomainAnalytics),
+    Failure(String),
+}
+
+/// Query of `/analytics/dns/:domain`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AnalyticsQuery {
+    /// Hours back from now, 24 by default
+    pub hours: Option<u64>,
+}
+
+/// Query of `/policy/violations`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ViolationsQuery {
+    pub limit: Option<usize>,
+}
+
 // New data types for bootstrap node management
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BootstrapNodeRequest {
@@ -82,225 +184,108 @@ pub struct BootstrapNodeInfo {
 
 async fn create_record(
     State(state): State<SharedStore>,
+    caller: Caller,
+    Query(query): Query<CreateRecordQuery>,
     Json(request): Json<DomainRequest>,
-) -> Json<DomainResponse> {
-    log::info!("Received Create request..."); 
-    match request {
-        DomainRequest::Create { domain, record_type, ip_addr, cname_target, ssl_cert } => {
-            log::info!("Create request for {domain}: {record_type}..."); 
-            log::info!("Create ips?: {ip_addr:?}...");
-            log::info!("Create CNAME target?: {cname_target:?}...");
-            let record = match record_type {
-                RecordType::A => {
-                    let (formnet_ip, public_ip) = if !ip_addr.is_empty() {
-                        let mut formnet_ips = vec![];
-                        let mut public_ips = vec![];
-                        for addr in ip_addr { 
-                            match addr.ip() {
-                                IpAddr::V4(v4) if v4.octets()[0] == 10 => {
-                                    log::info!("Formnet IP: {v4}..."); 
-                                    formnet_ips.push(addr);
-                                }
-                                IpAddr::V4(v4) => {
-                                    log::info!("Public IP: {v4}..."); 
-                                    public_ips.push(addr);
-                                }
-                                _ => return Json(DomainResponse::Failure(Some("IPV6 Addresses are not valid for A record".to_string()))),
-                            }
-                        }
-                        (formnet_ips, public_ips)
-                    } else {
-                        return Json(DomainResponse::Failure(Some("A Record update requires an IP Address be provided".to_string())));
-                    };
-                    FormDnsRecord {
-                        domain: domain.clone(),
-                        record_type,
-                        formnet_ip,
-                        public_ip,
-                        cname_target: None,
-                        ssl_cert,
-                        ttl: 3600,
-                        verification_status: Some(VerificationStatus::NotVerified),
-                        verification_timestamp: None,
-                    }
-                }
-                RecordType::AAAA => {
-                    let public_ip = if !ip_addr.is_empty() {
-                        let mut public_ips = vec![];
-                        for addr in ip_addr {
-                            match addr.ip() {
-                                IpAddr::V6(v6) => {
-                                    log::info!("Public IP: {v6}..."); 
-                                    public_ips.push(addr);
-                                }
-                                _ => {
-                                    return Json(DomainResponse::Failure(Some("AAAA Record requires a V6 IP Address".to_string())));
-                                }
-                            }
-                        }
-                        public_ips
-                    } else {
-                        return Json(DomainResponse::Failure(Some("AAAA Record update requires an IP address to be provided".to_string())));
-                    };
-                    FormDnsRecord {
-                        domain: domain.clone(),
-                        record_type,
-                        formnet_ip: vec![],
-                        public_ip,
-                        cname_target: None,
-                        ssl_cert,
-                        ttl: 3600,
-                        verification_status: Some(VerificationStatus::NotVerified),
-                        verification_timestamp: None,
-                    }
-                }
-                RecordType::CNAME => {
-                    let cname_target = if let Some(ref target) = cname_target {
-                        log::info!("CNAME Target: {target}..."); 
-                        cname_target.clone()
-                    } else {
-                        return Json(DomainResponse::Failure(Some("CNAME Record update requires a CNAME target be provided".to_string())));
-                    };
-
-                    FormDnsRecord {
-                        domain: domain.clone(),
-                        record_type,
-                        formnet_ip: vec![],
-                        public_ip: vec![],
-                        cname_target,
-                        ssl_cert,
-                        ttl: 3600,
-                        verification_status: Some(VerificationStatus::NotVerified),
-                        verification_timestamp: None,
-                    }
-                }
-                _ => return Json(DomainResponse::Failure(Some(format!("Sorry, the record type {record_type} is not currently supported"))))
-            };
-
-            log::info!("Build record: {record:?}...");
-            let mut guard = state.write().await;
-            log::info!("Adding record for {domain}...");
-            guard.insert(&domain, record).await;
-            drop(guard);
-            log::info!("Domain {domain} record added successfully...");
-            return Json(DomainResponse::Success(Success::None))
-        },
-        _ => return Json(DomainResponse::Failure(Some("Invalid request for endpoint /record/create".to_string())))
+) -> Result<Json<DomainResponse>, AuthError> {
+    let DomainRequest::Create { domain, .. } = &request else {
+        return Ok(Json(DomainResponse::Failure(Some("Invalid request for endpoint /record/create".to_string()))));
+    };
+    let domain = domain.clone();
+    authorize_build(&caller, query.build_id.as_deref()).await?;
+    let exists = state.read().await.get(&domain.trim_end_matches('.').to_lowercase()).is_some();
+    if exists {
+        authorize_record(&caller, &state, &domain).await?;
     }
+
+    let resp = insert_record(state.clone(), request).await;
+    if let (Caller::Account(owner), DomainResponse::Success(_)) = (&caller, &resp.0) {
+        state.write().await.set_owner(&domain, owner);
+    }
+    Ok(resp)
+}
+
+async fn insert_record(state: SharedStore, request: DomainRequest) -> Json<DomainResponse> {
+    log::info!("Received Create request..."); 
+    let DomainRequest::Create { domain, record_type, ip_addr, cname_target, ssl_cert } = request else {
+        return Json(DomainResponse::Failure(Some("Invalid request for endpoint /record/create".to_string())));
+    };
+    log::info!("Create request for {domain}: {record_type}, ips {ip_addr:?}, CNAME target {cname_target:?}...");
+    let record = match FormDnsRecord::build(&domain, record_type, ip_addr, cname_target, ssl_cert) {
+        Ok(record) => record,
+        Err(e) => return Json(DomainResponse::Failure(Some(e))),
+    };
+
+    log::info!("Build record: {record:?}...");
+    state.write().await.insert(&domain, record).await;
+    log::info!("Domain {domain} record added successfully...");
+    Json(DomainResponse::Success(Success::None))
 }
 
 async fn update_record(
     State(state): State<SharedStore>,
+    caller: Caller,
     Path(domain): Path<String>,
     Json(request): Json<DomainRequest>,
-) -> Json<DomainResponse> {
+) -> Result<Json<DomainResponse>, AuthError> {
+    authorize_record(&caller, &state, &domain).await?;
+    Ok(apply_update(state, domain, request).await)
+}
+
+async fn apply_update(state: SharedStore, domain: String, request: DomainRequest) -> Json<DomainResponse> {
     log::info!("Received Update request for {domain}...");
+    let DomainRequest::Update { replace, record_type, ip_addr, cname_target, ssl_cert } = request else {
+        return Json(DomainResponse::Failure(Some("Invalid request for endpoint /record/update".to_string())));
+    };
     let mut guard = state.write().await;
-    match request {
-        DomainRequest::Update { replace, record_type, ip_addr, cname_target, ssl_cert} => {
-            let record = match record_type {
-                RecordType::A => {
-                    let record = if let Entry::Occupied(ref mut entry) = guard.entry(&domain) {
-                        let record = entry.get_mut();
-                        record.record_type = record_type;
-                        let (formnet_ips, public_ips) = if !ip_addr.is_empty() {
-                            let mut formnet_ips = vec![]; 
-                            let mut public_ips = vec![];
-                            for addr in ip_addr {
-                                match addr.ip() {
-                                    IpAddr::V4(ip) => {
-                                        if ip.octets()[0] == 10 {
-                                            formnet_ips.push(addr);
-                                        } else {
-                                            public_ips.push(addr);
-                                        }
-                                    }
-                                    _ => return Json(DomainResponse::Failure(Some("A Records require an IPV4 address".to_string())))
-                                }
-                            }
-                            (formnet_ips, public_ips)
-                        } else if !ip_addr.is_empty() {
-                            (vec![], ip_addr)
-                        } else {
-                            return Json(DomainResponse::Failure(Some("A Record update must include an IP Address".to_string())))
-                        };
-                        if replace {
-                            record.formnet_ip = formnet_ips;
-                            record.public_ip = public_ips;
-                            record.ssl_cert = ssl_cert;
-                        } else {
-                            record.formnet_ip.extend(formnet_ips);
-                            record.public_ip.extend(public_ips);
-                            record.ssl_cert = ssl_cert;
-                        }
-                        record.clone()
-                    } else {
-                        return Json(DomainResponse::Failure(Some("A Record updates can only occur if the record exists, use /record/create endpoint instead".to_string())))
-                    };
-                    record
-                }
-                RecordType::AAAA => {
-                    let record = if let Entry::Occupied(ref mut entry) = guard.entry(&domain) {
-                        let record = entry.get_mut();
-                        record.record_type = record_type;
-                        if !ip_addr.is_empty() {
-                            record.public_ip.extend(ip_addr);
-                            record.ssl_cert = ssl_cert;
-                        } else {
-                            return Json(DomainResponse::Failure(Some("AAAA Record updates must include an IP Address".to_string())));
-                        }
-                        record.clone()
-                    } else {
-                        return Json(DomainResponse::Failure(Some("AAAA Record update can only occur if the record exists, use /record/create endpoint instead".to_string())))
-                    };
-                    record
-                }
-                RecordType::CNAME => {
-                    let record = if let Entry::Occupied(ref mut entry) = guard.entry(&domain) {
-                        let record = entry.get_mut();
-                        record.record_type = record_type;
-                        if let Some(ref _target) = cname_target {
-                            record.cname_target = cname_target.clone();
-                            record.ssl_cert = ssl_cert;
-                        } else {
-                            return Json(DomainResponse::Failure(Some("CNAME Record update must include a CNAME target".to_string())))
-                        }
-                        record.clone()
-                    } else {
-                        return Json(DomainResponse::Failure(Some("CNAME record updates can only occur if the record exists, use /record/create endpoint instead".to_string()))) 
-                    };
-                    record
-                }
-                _ => return Json(DomainResponse::Failure(Some(format!("Sorry, the record type {record_type} is not currently supported"))))
-
-            };
-            log::info!("Successfully built record {record:?}");
-            guard.insert(&domain, record).await;
-            drop(guard);
-            log::info!("Successfully updated record for {domain}");
-            return Json(DomainResponse::Success(Success::None))
-        }
-        _ => return Json(DomainResponse::Failure(Some("Invalid request for endpoint /record/create".to_string())))
+    let Some(mut record) = guard.get(&domain) else {
+        return Json(DomainResponse::Failure(Some(format!(
+            "{record_type} Record updates can only occur if the record exists, use /record/create endpoint instead"
+        ))));
+    };
+    if let Err(e) = record.apply_update(replace, record_type, ip_addr, cname_target, ssl_cert) {
+        return Json(DomainResponse::Failure(Some(e)));
     }
+    log::info!("Successfully built record {record:?}");
+    guard.insert(&domain, record).await;
+    drop(guard);
+    log::info!("Successfully updated record for {domain}");
+    Json(DomainResponse::Success(Success::None))
 }
 
 async fn delete_record(
     State(state): State<SharedStore>,
+    caller: Caller,
     Path(domain): Path<String>,
-) -> Json<DomainResponse> {
+) -> Result<Json<DomainResponse>, AuthError> {
     log::info!("Received request to delete record for {domain}...");
+    authorize_record(&caller, &state, &domain).await?;
     let mut guard = state.write().await;
     let removed = guard.remove(&domain);
+    guard.remove_stream_routes(&domain).await;
     drop(guard);
+    get_dns_analytics().remove(&domain);
     log::info!("Successfully removed record for {domain}...");
 
     match removed {
-        Some(ip_addr) => return Json(DomainResponse::Success(Success::Some(ip_addr))),
-        None => return Json(DomainResponse::Failure(Some(format!("No record for domain {domain}"))))
+        Some(ip_addr) => Ok(Json(DomainResponse::Success(Success::Some(ip_addr)))),
+        None => Ok(Json(DomainResponse::Failure(Some(format!("No record for domain {domain}")))))
     }
 
 }
 
+async fn bulk_records(
+    State(state): State<SharedStore>,
+    caller: Caller,
+    Json(request): Json<BulkRequest>,
+) -> Json<BulkOperationsResponse> {
+    log::info!("Received bulk request with {} operations, dry run: {}", request.operations.len(), request.dry_run);
+    match apply_bulk(&state, &caller, request).await {
+        Ok(response) => Json(BulkOperationsResponse::Success(response)),
+        Err(e) => Json(BulkOperationsResponse::Failure(e)),
+    }
+}
+
 async fn get_record(
     State(state): State<SharedStore>,
     Path(domain): Path<String>
@@ -332,31 +317,35 @@ async fn list_records(
 
 async fn new_server(
     State(state): State<SharedStore>,
-    Json(ip_addr): Json<Ipv4Addr>
-) -> Json<()> {
+    caller: Caller,
+    Json(ip_addr): Json<IpAddr>
+) -> Result<Json<()>, AuthError> {
+    authorize_admin(&caller).await?;
     let mut guard = state.write().await;
     if let Err(e) = guard.add_server(ip_addr) {
         log::error!("Error trying to add server {}: {}", ip_addr.clone(), e);
     }
 
-    Json(())
+    Ok(Json(()))
 }
 
 /// Endpoint to initiate domain verification
 async fn initiate_verification(
     State(state): State<SharedStore>,
+    caller: Caller,
     Path(domain): Path<String>,
-) -> Json<DomainResponse> {
+) -> Result<Json<DomainResponse>, AuthError> {
     log::info!("Received initiate_verification request for domain: {}", domain);
+    authorize_record(&caller, &state, &domain).await?;
     
     let mut store = state.write().await;
     match store.initiate_verification(&domain).await {
         Ok(result) => {
-            Json(DomainResponse::VerificationSuccess(result))
+            Ok(Json(DomainResponse::VerificationSuccess(result)))
         },
         Err(err) => {
             log::error!("Domain verification initiation failed: {}", err);
-            Json(DomainResponse::VerificationFailure(err))
+            Ok(Json(DomainResponse::VerificationFailure(err)))
         }
     }
 }
@@ -364,27 +353,252 @@ async fn initiate_verification(
 /// Endpoint to check verification status
 async fn check_verification(
     State(state): State<SharedStore>,
+    caller: Caller,
     Path(domain): Path<String>,
-) -> Json<DomainResponse> {
+) -> Result<Json<DomainResponse>, AuthError> {
     log::info!("Received check_verification request for domain: {}", domain);
+    authorize_record(&caller, &state, &domain).await?;
     
     let mut store = state.write().await;
     match store.check_verification(&domain).await {
         Ok(result) => {
-            Json(DomainResponse::VerificationSuccess(result))
+            Ok(Json(DomainResponse::VerificationSuccess(result)))
         },
         Err(err) => {
             log::error!("Domain verification check failed: {}", err);
-            Json(DomainResponse::VerificationFailure(err))
+            Ok(Json(DomainResponse::VerificationFailure(err)))
+        }
+    }
+}
+
+/// Configure the health check for a record, replacing any existing one
+async fn set_health_check(
+    State(state): State<SharedStore>,
+    caller: Caller,
+    Path(domain): Path<String>,
+    Json(check): Json<EndpointCheck>,
+) -> Result<Json<HealthCheckResponse>, AuthError> {
+    log::info!("Received request to set health check for {domain}: {check:?}");
+    authorize_record(&caller, &state, &domain).await?;
+    if let Err(e) = check.validate() {
+        return Ok(Json(HealthCheckResponse::Failure(e)));
+    }
+
+    let key = domain.trim_end_matches('.').to_lowercase();
+    let mut guard = state.write().await;
+    if guard.get(&key).is_none() {
+        return Ok(Json(HealthCheckResponse::Failure(format!("Record does not exist for domain {domain}"))));
+    }
+    guard.set_health_check(&key, check);
+
+    // Results of the previous check don't apply to the new one
+    if let Some(health_repo) = guard.get_health_repository() {
+        health_repo.write().await.clear_endpoints(&key);
+    }
+
+    Ok(Json(HealthCheckResponse::Success))
+}
+
+/// Remove the health check of a record, restoring any withdrawn addresses
+async fn remove_health_check(
+    State(state): State<SharedStore>,
+    caller: Caller,
+    Path(domain): Path<String>,
+) -> Result<Json<HealthCheckResponse>, AuthError> {
+    log::info!("Received request to remove health check for {domain}");
+    authorize_record(&caller, &state, &domain).await?;
+    let key = domain.trim_end_matches('.').to_lowercase();
+    let mut guard = state.write().await;
+    if guard.remove_health_check(&key).is_none() {
+        return Ok(Json(HealthCheckResponse::Failure(format!("No health check for domain {domain}"))));
+    }
+
+    if let Some(health_repo) = guard.get_health_repository() {
+        health_repo.write().await.clear_endpoints(&key);
+    }
+
+    Ok(Json(HealthCheckResponse::Success))
+}
+
+/// The health check of a record and the health of each checked address
+async fn record_health(
+    State(state): State<SharedStore>,
+    Path(domain): Path<String>,
+) -> Json<HealthCheckResponse> {
+    let key = domain.trim_end_matches('.').to_lowercase();
+    let guard = state.read().await;
+    let check = guard.health_check(&key);
+    let endpoints = match guard.get_health_repository() {
+        Some(health_repo) => health_repo.read().await.endpoint_statuses(&key),
+        None => vec![],
+    };
+
+    Json(HealthCheckResponse::Status { check, endpoints })
+}
+
+/// Expose a TCP or UDP service of the record's instances on a public port,
+/// replacing the record's route on the same port
+async fn set_stream_route(
+    State(state): State<SharedStore>,
+    caller: Caller,
+    Path(domain): Path<String>,
+    Json(mut route): Json<StreamRoute>,
+) -> Result<Json<StreamRouteResponse>, AuthError> {
+    log::info!("Received request to set stream route for {domain}: {route:?}");
+    authorize_record(&caller, &state, &domain).await?;
+    let key = domain.trim_end_matches('.').to_lowercase();
+    route.domain = key.clone();
+
+    let mut guard = state.write().await;
+    if guard.get(&key).is_none() {
+        return Ok(Json(StreamRouteResponse::Failure(format!("Record does not exist for domain {domain}"))));
+    }
+    match guard.set_stream_route(route.clone()).await {
+        Ok(()) => Ok(Json(StreamRouteResponse::Success(route))),
+        Err(e) => Ok(Json(StreamRouteResponse::Failure(e))),
+    }
+}
+
+/// Stop exposing the record on a stream port
+async fn remove_stream_route(
+    State(state): State<SharedStore>,
+    caller: Caller,
+    Path((domain, port)): Path<(String, u16)>,
+) -> Result<Json<StreamRouteResponse>, AuthError> {
+    log::info!("Received request to remove stream route for {domain} on port {port}");
+    authorize_record(&caller, &state, &domain).await?;
+    match state.write().await.remove_stream_route(&domain, port).await {
+        Some(route) => Ok(Json(StreamRouteResponse::Success(route))),
+        None => Ok(Json(StreamRouteResponse::Failure(format!("No stream route for {domain} on port {port}")))),
+    }
+}
+
+async fn record_stream_routes(
+    State(state): State<SharedStore>,
+    Path(domain): Path<String>,
+) -> Json<StreamRouteResponse> {
+    Json(StreamRouteResponse::List(state.read().await.stream_routes(Some(&domain))))
+}
+
+async fn list_stream_routes(State(state): State<SharedStore>) -> Json<StreamRouteResponse> {
+    Json(StreamRouteResponse::List(state.read().await.stream_routes(None)))
+}
+
+/// The DS record to add to the parent of a DNSSEC signed zone
+async fn zone_ds(Path(zone): Path<String>) -> Json<DnssecResponse> {
+    match export_ds(&zone, &key_dir()) {
+        Ok(ds) => Json(DnssecResponse::Ds(ds)),
+        Err(e) => Json(DnssecResponse::Failure(e.to_string())),
+    }
+}
+
+/// The geolocation provider chain with the counters of each provider
+async fn geo_providers() -> Json<GeoProvidersResponse> {
+    Json(GeoProvidersResponse::Status(get_geo_resolver().providers().status()))
+}
+
+/// Replaces the geolocation provider chain and saves it for the next start
+async fn set_geo_providers(
+    caller: Caller,
+    Json(configs): Json<Vec<GeoProviderConfig>>,
+) -> Result<Json<GeoProvidersResponse>, AuthError> {
+    authorize_admin(&caller).await?;
+    let providers = get_geo_resolver().providers();
+    let result = tokio::task::spawn_blocking(move || {
+        providers.configure(configs.clone())?;
+        save_provider_configs(GEO_PROVIDERS_PATH, &configs)
+            .map_err(|e| format!("Providers applied but not saved to {GEO_PROVIDERS_PATH}: {e}"))?;
+        Ok::<_, String>(providers.status())
+    }).await;
+
+    Ok(Json(match result {
+        Ok(Ok(status)) => {
+            log::info!("Geolocation providers set to {:?}", status.providers.iter().map(|p| &p.name).collect::<Vec<_>>());
+            GeoProvidersResponse::Status(status)
         }
+        Ok(Err(e)) => GeoProvidersResponse::Failure(e),
+        Err(e) => GeoProvidersResponse::Failure(e.to_string()),
+    }))
+}
+
+/// Reloads the geolocation databases that changed on disk without waiting
+/// for the next periodic check
+async fn reload_geo_providers(caller: Caller) -> Result<Json<GeoProvidersResponse>, AuthError> {
+    authorize_admin(&caller).await?;
+    let providers = get_geo_resolver().providers();
+    Ok(Json(match tokio::task::spawn_blocking(move || providers.reload_changed()).await {
+        Ok(reloaded) => GeoProvidersResponse::Reloaded(reloaded),
+        Err(e) => GeoProvidersResponse::Failure(e.to_string()),
+    }))
+}
+
+/// The rate limits and policy zones in use, with the violation counters
+async fn query_policy(caller: Caller) -> Result<Json<QueryPolicyResponse>, AuthError> {
+    authorize_admin(&caller).await?;
+    let policy = get_query_policy();
+    Ok(Json(QueryPolicyResponse::Policy { config: policy.config(), stats: policy.stats() }))
+}
+
+/// Replaces the rate limits and policy zones and saves them for the next start
+async fn set_query_policy(
+    caller: Caller,
+    Json(config): Json<QueryPolicyConfig>,
+) -> Result<Json<QueryPolicyResponse>, AuthError> {
+    authorize_admin(&caller).await?;
+    let policy = get_query_policy();
+    if let Err(e) = policy.configure(config.clone()) {
+        return Ok(Json(QueryPolicyResponse::Failure(e)));
     }
+    log::info!(
+        "Query policy set to {} queries per second and {} policy zones",
+        config.rate_limit.queries_per_second, config.zones.len()
+    );
+    if let Err(e) = save_query_policy(QUERY_POLICY_PATH, &config) {
+        return Ok(Json(QueryPolicyResponse::Failure(
+            format!("Policy applied but not saved to {QUERY_POLICY_PATH}: {e}")
+        )));
+    }
+    Ok(Json(QueryPolicyResponse::Policy { config, stats: policy.stats() }))
+}
+
+async fn query_policy_violations(
+    caller: Caller,
+    Query(query): Query<ViolationsQuery>,
+) -> Result<Json<QueryPolicyResponse>, AuthError> {
+    authorize_admin(&caller).await?;
+    let limit = query.limit.unwrap_or(100);
+    Ok(Json(QueryPolicyResponse::Violations(get_query_policy().violations(limit))))
+}
+
+/// Where a record's queries came from, for its owner and admins
+async fn domain_analytics(
+    State(state): State<SharedStore>,
+    caller: Caller,
+    Path(domain): Path<String>,
+    Query(query): Query<AnalyticsQuery>,
+) -> Result<Json<AnalyticsResponse>, AuthError> {
+    authorize_record(&caller, &state, &domain).await?;
+    let analytics = get_dns_analytics();
+    if !analytics.is_enabled() {
+        return Ok(Json(AnalyticsResponse::Failure("DNS analytics are turned off on this node".to_string())));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Ok(Json(AnalyticsResponse::Report(analytics.report(&domain, query.hours.unwrap_or(24), now))))
 }
 
 /// Add a new bootstrap node to the bootstrap domain
 async fn add_bootstrap_node(
     State(state): State<SharedStore>,
+    caller: Caller,
     Json(request): Json<BootstrapNodeRequest>,
-) -> Json<BootstrapNodeResponse> {
+) -> Result<Json<BootstrapNodeResponse>, AuthError> {
+    authorize_admin(&caller).await?;
+    Ok(insert_bootstrap_node(state, request).await)
+}
+
+async fn insert_bootstrap_node(state: SharedStore, request: BootstrapNodeRequest) -> Json<BootstrapNodeResponse> {
     log::info!("Received request to add bootstrap node: {} at {}", 
                request.node_id, request.ip_address);
     
@@ -443,8 +657,14 @@ async fn add_bootstrap_node(
 /// Remove a bootstrap node from the bootstrap domain
 async fn remove_bootstrap_node(
     State(state): State<SharedStore>,
+    caller: Caller,
     Json(request): Json<BootstrapNodeRequest>,
-) -> Json<BootstrapNodeResponse> {
+) -> Result<Json<BootstrapNodeResponse>, AuthError> {
+    authorize_admin(&caller).await?;
+    Ok(drop_bootstrap_node(state, request).await)
+}
+
+async fn drop_bootstrap_node(state: SharedStore, request: BootstrapNodeRequest) -> Json<BootstrapNodeResponse> {
     log::info!("Received request to remove bootstrap node: {}", request.ip_address);
     
     let domain = "bootstrap.formation.cloud";
@@ -526,13 +746,14 @@ async fn list_bootstrap_nodes(
 
 pub async fn serve_api(state: SharedStore) -> Result<(), Box<dyn std::error::Error>> {
     log::info!("Launching DNS server API");
-    let listener = TcpListener::bind("127.0.0.1:3005").await?;
-    log::info!("Binding listener to localhost port 3005...");
+    let addr = std::env::var("FORM_DNS_API_ADDR").unwrap_or_else(|_| "127.0.0.1:3005".to_string());
+    let listener = TcpListener::bind(&addr).await?;
+    log::info!("Binding listener to {addr}...");
     let routes = build_routes(state);
     log::info!("Building endpoints...");
 
-    log::info!("DNS server api listening on localhost:3005...");
-    axum::serve(listener, routes).await?;
+    log::info!("DNS server api listening on {addr}...");
+    axum::serve(listener, routes.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
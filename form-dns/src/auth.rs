@@ -0,0 +1,191 @@
+//! Authentication and record ownership for the DNS API.
+//!
+//! Requests from the node itself (form-state replicating records it has
+//! already authorized, operator tooling) are trusted. Anyone else has to
+//! sign the request with the same `X-Signature`, `X-Recovery-Id` and
+//! `X-Message` headers the vmm-service takes, and can only change records
+//! they own unless they are a network admin.
+
+use std::net::SocketAddr;
+use alloy_primitives::Address;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use serde_json::{json, Value};
+
+use crate::store::SharedStore;
+
+/// Base URL of the local form-state API, used to check build ownership and
+/// admin status
+const STATE_API: &str = "http://127.0.0.1:3004/v1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Missing signature headers (X-Signature, X-Recovery-Id, X-Message)")]
+    MissingSignature,
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Unable to verify ownership: {0}")]
+    State(String),
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::MissingSignature | Self::InvalidSignature(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::State(_) => StatusCode::BAD_GATEWAY,
+        };
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+/// Who is calling the API
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Caller {
+    /// A service running on this node
+    Node,
+    /// A signed request, with the lowercase hex address of the signer
+    Account(String),
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Caller
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let is_loopback = parts.extensions.get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(addr)| addr.ip().is_loopback());
+        if is_loopback && !parts.headers.contains_key("X-Signature") {
+            return Ok(Caller::Node);
+        }
+
+        let address = recover_signer(&parts.headers)?;
+        Ok(Caller::Account(hex::encode(address.as_slice())))
+    }
+}
+
+/// Recovers the address that signed the request headers
+pub fn recover_signer(headers: &HeaderMap) -> Result<Address, AuthError> {
+    let header = |name: &str| {
+        headers.get(name)
+            .ok_or(AuthError::MissingSignature)?
+            .to_str()
+            .map_err(|_| AuthError::InvalidSignature(format!("{name} is not valid text")))
+    };
+
+    let signature = hex::decode(header("X-Signature")?)
+        .map_err(|_| AuthError::InvalidSignature("X-Signature is not hex".into()))?;
+    let signature = Signature::try_from(signature.as_slice())
+        .map_err(|e| AuthError::InvalidSignature(e.to_string()))?;
+    let recovery_id = header("X-Recovery-Id")?.parse::<u8>().ok()
+        .and_then(RecoveryId::from_byte)
+        .ok_or_else(|| AuthError::InvalidSignature("X-Recovery-Id is not a recovery id".into()))?;
+    let message = header("X-Message")?;
+    let message = hex::decode(message.strip_prefix("0x").unwrap_or(message))
+        .map_err(|_| AuthError::InvalidSignature("X-Message is not a hex hash".into()))?;
+
+    let key = VerifyingKey::recover_from_msg(&message, &signature, recovery_id)
+        .map_err(|e| AuthError::InvalidSignature(e.to_string()))?;
+    Ok(Address::from_public_key(&key))
+}
+
+fn normalize(address: &str) -> String {
+    address.trim_start_matches("0x").to_lowercase()
+}
+
+async fn state_get(path: &str) -> Result<Value, AuthError> {
+    reqwest::get(format!("{STATE_API}{path}"))
+        .await
+        .map_err(|e| AuthError::State(e.to_string()))?
+        .json::<Value>()
+        .await
+        .map_err(|e| AuthError::State(e.to_string()))
+}
+
+/// Whether form-state knows the address as a network admin
+pub async fn is_network_admin(address: &str) -> Result<bool, AuthError> {
+    let resp = state_get(&format!("/account/{address}/is_global_admin")).await?;
+    Ok(resp["is_global_admin"].as_bool().unwrap_or(false))
+}
+
+/// Whether the address owns the instances of a build in form-state
+pub async fn owns_build(address: &str, build_id: &str) -> Result<bool, AuthError> {
+    let resp = state_get(&format!("/instance/{build_id}/get_by_build_id")).await?;
+    let instances = resp["Success"]["List"].as_array().cloned().unwrap_or_default();
+    Ok(!instances.is_empty() && instances.iter().all(|instance| {
+        instance["instance_owner"].as_str().is_some_and(|owner| normalize(owner) == normalize(address))
+    }))
+}
+
+/// Checks that the caller may change the record for `domain`. Records
+/// without an owner belong to the node and only admins may change them.
+pub async fn authorize_record(caller: &Caller, store: &SharedStore, domain: &str) -> Result<(), AuthError> {
+    let Caller::Account(address) = caller else {
+        return Ok(());
+    };
+    let owner = store.read().await.owner(domain);
+    if owner.is_some_and(|owner| owner == normalize(address)) {
+        return Ok(());
+    }
+    authorize_admin(caller).await
+}
+
+/// Checks that the caller may manage the server itself
+pub async fn authorize_admin(caller: &Caller) -> Result<(), AuthError> {
+    match caller {
+        Caller::Node => Ok(()),
+        Caller::Account(address) if is_network_admin(address).await? => Ok(()),
+        Caller::Account(address) => Err(AuthError::Forbidden(
+            format!("Address {address} is not permitted to make this change")
+        )),
+    }
+}
+
+/// Checks that the caller may create a record pointing at `build_id`
+pub async fn authorize_build(caller: &Caller, build_id: Option<&str>) -> Result<(), AuthError> {
+    let Caller::Account(address) = caller else {
+        return Ok(());
+    };
+    match build_id {
+        Some(build_id) if owns_build(address, build_id).await? => Ok(()),
+        Some(build_id) => match authorize_admin(caller).await {
+            Ok(()) => Ok(()),
+            Err(_) => Err(AuthError::Forbidden(format!("Address {address} does not own build {build_id}"))),
+        },
+        None => authorize_admin(caller).await
+            .map_err(|_| AuthError::Forbidden("Records created by accounts must name a build_id they own".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+
+    #[test]
+    fn test_recover_signer() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let hash = [3u8; 32];
+        let (signature, recovery_id) = key.sign_recoverable(&hash).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature", hex::encode(signature.to_bytes()).parse().unwrap());
+        headers.insert("X-Recovery-Id", recovery_id.to_byte().to_string().parse().unwrap());
+        headers.insert("X-Message", format!("0x{}", hex::encode(hash)).parse().unwrap());
+        assert_eq!(recover_signer(&headers).unwrap(), Address::from_public_key(key.verifying_key()));
+
+        headers.remove("X-Recovery-Id");
+        assert!(matches!(recover_signer(&headers), Err(AuthError::MissingSignature)));
+    }
+}
@@ -1,6 +1,6 @@
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use trust_dns_client::client::AsyncClient;
-use trust_dns_proto::rr::rdata::CNAME;
+use trust_dns_proto::rr::rdata::{CNAME, PTR};
 use trust_dns_server::authority::{
     Authority, LookupOptions, UpdateResult, ZoneType, LookupError, MessageRequest,
     UpdateRequest
@@ -10,24 +10,38 @@ use trust_dns_proto::rr::{
     RecordType, RData, Record, RecordSet, LowerName, Name
 };
 use trust_dns_server::authority::LookupObject;
-use crate::store::{FormDnsRecord, SharedStore, VerificationStatus};
+use trust_dns_proto::rr::dnssec::SupportedAlgorithms;
+use crate::store::{ptr_query_ip, FlattenedTarget, FormDnsRecord, SharedStore, VerificationStatus};
 use anyhow::Result;
 use trust_dns_client::client::ClientHandle;
 use crate::health::SharedIpHealthRepository;
+use crate::dnssec::ZoneSigners;
+use crate::is_formnet_ip;
+use crate::query_policy::{get_query_policy, PolicyAction, QueryDecision};
+use crate::analytics::{get_dns_analytics, record_query, QuerySample};
+
+/// TTL of PTR answers, peers come and go so they aren't cached for long
+const PTR_TTL: u32 = 60;
 
 #[derive(Clone)]
 pub struct SimpleLookup {
     records: RecordSet,
     additionals: Option<RecordSet>,
+    /// Whether the RRSIGs of the records are part of the answer
+    dnssec: bool,
 }
 
 impl SimpleLookup {
     pub fn from_record_set(rrset: RecordSet) -> Self {
-        Self { records: rrset, additionals: None }
+        Self { records: rrset, additionals: None, dnssec: false }
     }
 
     pub fn with_additionals(rrset: RecordSet, additionals: RecordSet) -> Self {
-        Self { records: rrset, additionals: Some(additionals) }
+        Self { records: rrset, additionals: Some(additionals), dnssec: false }
+    }
+
+    pub fn signed(rrset: RecordSet) -> Self {
+        Self { records: rrset, additionals: None, dnssec: true }
     }
 }
 
@@ -37,6 +51,7 @@ pub struct FormAuthority {
     store: SharedStore,
     fallback_client: AsyncClient,
     health_repository: Option<SharedIpHealthRepository>,
+    zone_signers: ZoneSigners,
 }
 
 impl FormAuthority {
@@ -48,6 +63,7 @@ impl FormAuthority {
             store,
             fallback_client,
             health_repository: None,
+            zone_signers: ZoneSigners::default(),
         }
     }
 
@@ -57,6 +73,68 @@ impl FormAuthority {
         self
     }
 
+    /// Sign the answers of the zones in `signers`
+    pub fn with_dnssec(mut self, signers: ZoneSigners) -> Self {
+        self.zone_signers = signers;
+        self
+    }
+
+    /// Answers a query from the store, signing the answer if the name is in
+    /// a DNSSEC zone and the resolver asked for DNSSEC records. Names in a
+    /// signed zone are never forwarded upstream.
+    async fn lookup_authoritative(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        src: Option<IpAddr>,
+        lookup_options: LookupOptions,
+    ) -> Result<SimpleLookup, LookupError> {
+        match get_query_policy().check(src, &name.to_string(), std::time::Instant::now()) {
+            QueryDecision::Allow => {}
+            QueryDecision::RateLimited => return Err(LookupError::ResponseCode(ResponseCode::Refused)),
+            QueryDecision::Blocked { action: PolicyAction::Nodata, .. } => return Err(LookupError::NameExists),
+            QueryDecision::Blocked { .. } => return Err(LookupError::ResponseCode(ResponseCode::NXDomain)),
+        }
+
+        let signer = match self.zone_signers.signer_for(name) {
+            Some(signer) => signer.clone(),
+            None => {
+                if let Some(rrset) = self.lookup_local(&name.to_string(), rtype, src).await {
+                    return Ok(SimpleLookup::from_record_set(rrset));
+                }
+                let rrset = self.lookup_fallback(name, rtype).await?;
+                return Ok(SimpleLookup::from_record_set(rrset));
+            }
+        };
+
+        let apex = LowerName::new(signer.zone()) == *name;
+        let rrset = if rtype == RecordType::DNSKEY && apex {
+            Some(signer.dnskey_rrset().map_err(|e| {
+                log::error!("Unable to sign DNSKEY of {}: {e}", signer.zone());
+                LookupError::ResponseCode(ResponseCode::ServFail)
+            })?)
+        } else {
+            self.lookup_local(&name.to_string(), rtype, src).await
+        };
+
+        match rrset {
+            Some(mut rrset) if lookup_options.is_dnssec() && rrset.record_type() != RecordType::DNSKEY => {
+                signer.sign_rrset(&mut rrset).map_err(|e| {
+                    log::error!("Unable to sign {} {}: {e}", rrset.name(), rrset.record_type());
+                    LookupError::ResponseCode(ResponseCode::ServFail)
+                })?;
+                Ok(SimpleLookup::signed(rrset))
+            }
+            Some(rrset) if lookup_options.is_dnssec() => Ok(SimpleLookup::signed(rrset)),
+            Some(rrset) => Ok(SimpleLookup::from_record_set(rrset)),
+            // With DNSSEC every name in the zone exists and the NSEC from
+            // get_nsec_records lists the types it has
+            None if lookup_options.is_dnssec() => Err(LookupError::NameExists),
+            None if apex || self.store.read().await.resolve(&name.to_string()).is_some() => Err(LookupError::NameExists),
+            None => Err(LookupError::ResponseCode(ResponseCode::NXDomain)),
+        }
+    }
+
     async fn lookup_local(
         &self,
         name: &str,
@@ -66,21 +144,32 @@ impl FormAuthority {
         log::info!("trimming name");
         let key = name.trim_end_matches('.').to_lowercase();
         log::info!("trimmed name: {key}");
+        if rtype == RecordType::PTR {
+            return self.lookup_reverse(&key).await;
+        }
 
         let record_opt = {
             let guard = self.store.read().await;
-            guard.get(&key)
+            match guard.resolve(&key) {
+                // Address queries for a CNAME are answered with the addresses
+                // of its target, which is what lets apex domains use one
+                Some(record) if record.record_type == RecordType::CNAME && matches!(rtype, RecordType::A | RecordType::AAAA) => {
+                    match guard.flatten(&key) {
+                        Some(FlattenedTarget::Local(target)) => Some(target),
+                        Some(FlattenedTarget::External(target)) => {
+                            drop(guard);
+                            return self.lookup_flattened_upstream(&key, &target, rtype).await;
+                        }
+                        None => None,
+                    }
+                }
+                record => record,
+            }
         };
         log::info!("retrieved record {record_opt:?}");
 
         if let Some(record) = record_opt {
-            let is_formnet = {
-                match src {
-                    Some(IpAddr::V4(addr)) => addr.octets()[0] == 10,
-                    Some(IpAddr::V6(_)) => false,
-                    None => false,
-                }
-            };
+            let is_formnet = src.is_some_and(is_formnet_ip);
             log::info!("Request is formnet? {is_formnet}");
             let mut ips = if is_formnet {
                 if !record.formnet_ip.is_empty() {
@@ -109,9 +198,14 @@ impl FormAuthority {
                 // Extract IPs without port for health check
                 let ip_addrs: Vec<IpAddr> = ips.iter().map(|addr| addr.ip()).collect();
                 
-                // Get filtered IPs based on health status
+                // Get filtered IPs based on node health and, for records
+                // with a health check, the health of each address
+                let record_key = record.domain.trim_end_matches('.').to_lowercase();
                 let health_repo_guard = health_repo.read().await;
-                let filtered_ips = health_repo_guard.filter_available_ips(&ip_addrs);
+                let filtered_ips: Vec<IpAddr> = health_repo_guard.filter_available_ips(&ip_addrs)
+                    .into_iter()
+                    .filter(|ip| health_repo_guard.is_endpoint_available(&record_key, ip))
+                    .collect();
                 
                 if filtered_ips.len() < ip_addrs.len() {
                     log::info!(
@@ -243,6 +337,21 @@ impl FormAuthority {
         None
     }
 
+    /// Answers `in-addr.arpa` queries with every domain the store points at
+    /// the address, so `dig -x` shows which peer or instance owns a formnet IP
+    async fn lookup_reverse(&self, name: &str) -> Option<RecordSet> {
+        let ip = ptr_query_ip(name)?;
+        let domains = self.store.read().await.reverse_lookup(ip);
+        let rr_name = Name::from_utf8(name).ok()?;
+        let mut rrset = RecordSet::new(&rr_name, RecordType::PTR, PTR_TTL);
+        for domain in domains {
+            if let Ok(target) = Name::from_utf8(&domain) {
+                rrset.insert(Record::from_rdata(rr_name.clone(), PTR_TTL, RData::PTR(PTR(target))), 0);
+            }
+        }
+        (!rrset.is_empty()).then_some(rrset)
+    }
+
     async fn lookup_upstream(
         &self,
         name: &LowerName,
@@ -287,6 +396,37 @@ impl FormAuthority {
         Ok(rrset)
     }
 
+    /// Resolves a CNAME target that lives outside of the store and answers
+    /// with its records under the queried name
+    async fn lookup_flattened_upstream(
+        &self,
+        name: &str,
+        target: &str,
+        rtype: RecordType,
+    ) -> Option<RecordSet> {
+        let target_name = LowerName::new(&Name::from_utf8(target).ok()?);
+        let rrset = match self.lookup_upstream(&target_name, rtype).await {
+            Ok(rrset) => rrset,
+            Err(e) => {
+                log::warn!("Unable to flatten CNAME {name} -> {target}: {e:?}");
+                return None;
+            }
+        };
+
+        let mut flattened = RecordSet::new(&Name::from_utf8(name).ok()?, rtype, rrset.ttl());
+        for record in rrset.records_without_rrsigs() {
+            if let Some(rdata) = record.data() {
+                flattened.add_rdata(rdata.clone());
+            }
+        }
+
+        if flattened.is_empty() {
+            None
+        } else {
+            Some(flattened)
+        }
+    }
+
     async fn lookup_fallback(
         &self,
         name: &LowerName,
@@ -387,6 +527,8 @@ impl FormAuthority {
                     }
                 },
                 (RecordType::AAAA, Some(&RData::AAAA(v6))) => {
+                    let addr = SocketAddr::V6(SocketAddrV6::new(v6.into(), 80, 0, 0));
+                    let is_formnet = is_formnet_ip(IpAddr::V6(v6.into()));
                     if ttl == 0 {
                         if store_guard.remove(&domain).is_some() {
                             changed = true;
@@ -394,8 +536,8 @@ impl FormAuthority {
                             let record = FormDnsRecord {
                                 domain: domain.clone(),
                                 record_type: rtype,
-                                formnet_ip: vec![],
-                                public_ip: vec![SocketAddr::V6(SocketAddrV6::new(v6.into(), 80, 0, 0))],
+                                formnet_ip: if is_formnet { vec![addr] } else { vec![] },
+                                public_ip: if !is_formnet { vec![addr] } else { vec![] },
                                 cname_target: None,
                                 ssl_cert: false,
                                 ttl: 3600,
@@ -407,13 +549,13 @@ impl FormAuthority {
                         }
                     } else {
                         if let Some(mut record) = store_guard.get(&domain) {
+                            if is_formnet {
+                                record.formnet_ip.push(addr);
+                            } else {
+                                record.public_ip.push(addr);
+                            }
                             let form_record = FormDnsRecord {
                                 record_type: rtype,
-                                formnet_ip: vec![],
-                                public_ip: {
-                                    record.public_ip.push(SocketAddr::V6(SocketAddrV6::new(v6.into(), 80, 0, 0)));
-                                    record.public_ip
-                                },
                                 ttl,
                                 ..record
                             };
@@ -423,8 +565,8 @@ impl FormAuthority {
                             let record = FormDnsRecord {
                                 domain: domain.clone(),
                                 record_type: rtype,
-                                formnet_ip: vec![],
-                                public_ip: vec![SocketAddr::V6(SocketAddrV6::new(v6.into(), 80, 0, 0))],
+                                formnet_ip: if is_formnet { vec![addr] } else { vec![] },
+                                public_ip: if !is_formnet { vec![addr] } else { vec![] },
                                 cname_target: None,
                                 ssl_cert: false,
                                 ttl: 3600,
@@ -496,6 +638,9 @@ impl LookupObject for SimpleLookup {
     }
 
     fn iter(&self) -> Box<dyn Iterator<Item = &'_ Record> + Send + '_> {
+        if self.dnssec {
+            return Box::new(self.records.records_with_rrsigs(SupportedAlgorithms::all()));
+        }
         Box::new(
             self.records.records_without_rrsigs()
         )
@@ -506,6 +651,7 @@ impl LookupObject for SimpleLookup {
             return Some(Box::new(SimpleLookup {
                 records: adds,
                 additionals: None,
+                dnssec: self.dnssec,
             }))
         }
         None
@@ -537,41 +683,55 @@ impl Authority for FormAuthority {
         &self.origin
     }
 
-    fn lookup<'life0,'life1,'async_trait>(&'life0 self,name: &'life1 LowerName,rtype:RecordType,_lookup_options:LookupOptions,) ->  ::core::pin::Pin<Box<dyn ::core::future::Future<Output = std::result::Result<Self::Lookup,LookupError> > + ::core::marker::Send+'async_trait> >where 'life0:'async_trait,'life1:'async_trait,Self:'async_trait {
+    fn lookup<'life0,'life1,'async_trait>(&'life0 self,name: &'life1 LowerName,rtype:RecordType,lookup_options:LookupOptions,) ->  ::core::pin::Pin<Box<dyn ::core::future::Future<Output = std::result::Result<Self::Lookup,LookupError> > + ::core::marker::Send+'async_trait> >where 'life0:'async_trait,'life1:'async_trait,Self:'async_trait {
         Box::pin(async move {
-            let name_str = name.to_string();
-            if let Some(rrset) = self.lookup_local(&name_str, rtype, None).await {
-                return Ok(SimpleLookup::from_record_set(rrset));
-            }
-
-            match self.lookup_fallback(name, rtype).await {
-                Ok(rr) => Ok(SimpleLookup::from_record_set(rr)),
-                Err(e) => Err(e),
-            }
+            self.lookup_authoritative(name, rtype, None, lookup_options).await
         })
     }
 
-    fn search<'life0,'life1,'async_trait>(&'life0 self,request:trust_dns_server::server::RequestInfo<'life1> ,_lookup_options:LookupOptions,) ->  ::core::pin::Pin<Box<dyn ::core::future::Future<Output = std::result::Result<Self::Lookup,LookupError> > + ::core::marker::Send+'async_trait> >where 'life0:'async_trait,'life1:'async_trait,Self:'async_trait {
+    fn search<'life0,'life1,'async_trait>(&'life0 self,request:trust_dns_server::server::RequestInfo<'life1> ,lookup_options:LookupOptions,) ->  ::core::pin::Pin<Box<dyn ::core::future::Future<Output = std::result::Result<Self::Lookup,LookupError> > + ::core::marker::Send+'async_trait> >where 'life0:'async_trait,'life1:'async_trait,Self:'async_trait {
         Box::pin(async move {
             let src = request.src;
             let rtype = request.query.query_type();
             let name = request.query.name();
-            if let Some(rrset) = self.lookup_local(&name.to_string(), rtype, Some(src.ip())).await {
-                log::info!("Found record in local, returning...");
-                return Ok(SimpleLookup::from_record_set(rrset));
-            }
-
-            log::info!("Unable to find record in checking fallback...");
-            match self.lookup_fallback(name.into(), rtype).await {
-                Ok(rr) => Ok(SimpleLookup::from_record_set(rr)),
-                Err(e) => Err(e),
+            let result = self.lookup_authoritative(name.into(), rtype, Some(src.ip()), lookup_options).await;
+
+            // Queries are counted for the record that answers them, so a
+            // wildcard's analytics cover every name below it. Queries
+            // refused by the rate limit aren't counted.
+            let refused = matches!(result, Err(LookupError::ResponseCode(ResponseCode::Refused)));
+            if get_dns_analytics().is_enabled() && !refused {
+                let record = self.store.read().await.resolve(&name.to_string());
+                if let Some(record) = record {
+                    record_query(&record.domain, QuerySample::from_client(src.ip(), request.protocol, rtype));
+                }
             }
+            result
         })
     }
 
-    fn get_nsec_records<'life0,'life1,'async_trait>(&'life0 self,_name: &'life1 LowerName,_lookup_options:LookupOptions,) ->  ::core::pin::Pin<Box<dyn ::core::future::Future<Output = std::result::Result<Self::Lookup,LookupError> > + ::core::marker::Send+'async_trait> >where 'life0:'async_trait,'life1:'async_trait,Self:'async_trait {
+    fn get_nsec_records<'life0,'life1,'async_trait>(&'life0 self,name: &'life1 LowerName,lookup_options:LookupOptions,) ->  ::core::pin::Pin<Box<dyn ::core::future::Future<Output = std::result::Result<Self::Lookup,LookupError> > + ::core::marker::Send+'async_trait> >where 'life0:'async_trait,'life1:'async_trait,Self:'async_trait {
         Box::pin(async move {
-            Err(LookupError::ResponseCode(ResponseCode::NXDomain))
+            let signer = match self.zone_signers.signer_for(name) {
+                Some(signer) if lookup_options.is_dnssec() => signer.clone(),
+                _ => return Err(LookupError::ResponseCode(ResponseCode::NXDomain)),
+            };
+
+            let mut types = match self.store.read().await.resolve(&name.to_string()) {
+                Some(record) => vec![record.record_type],
+                None => vec![],
+            };
+            if LowerName::new(signer.zone()) == *name {
+                types.push(RecordType::DNSKEY);
+            }
+
+            let owner = Name::from(name);
+            signer.nsec_rrset(&owner, &types)
+                .map(SimpleLookup::signed)
+                .map_err(|e| {
+                    log::error!("Unable to sign NSEC for {owner}: {e}");
+                    LookupError::ResponseCode(ResponseCode::ServFail)
+                })
         })
     }
 }
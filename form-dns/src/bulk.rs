@@ -0,0 +1,301 @@
+//! Bulk record changes.
+//!
+//! `POST /record/bulk` takes a list of creations, updates and deletions and
+//! applies all of them or none. Each operation is authorized as its single
+//! record endpoint would, then validated in order against the store with the
+//! earlier operations of the list applied, so a list can create a record and
+//! update it. The store stays locked from validation until the changes are
+//! applied, no other change lands in between. With `dry_run` the list is only
+//! validated.
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::RecordType;
+
+use crate::analytics::get_dns_analytics;
+use crate::auth::{authorize_build, authorize_record, AuthError, Caller};
+use crate::store::{FormDnsRecord, SharedStore};
+
+/// Most operations one request may hold
+pub const MAX_BULK_OPERATIONS: usize = 500;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BulkOperation {
+    Create {
+        domain: String,
+        record_type: RecordType,
+        #[serde(default)]
+        ip_addr: Vec<SocketAddr>,
+        #[serde(default)]
+        cname_target: Option<String>,
+        #[serde(default)]
+        ssl_cert: bool,
+        /// Build the record points to, required of accounts as on `/record/create`
+        #[serde(default)]
+        build_id: Option<String>,
+    },
+    Update {
+        domain: String,
+        #[serde(default)]
+        replace: bool,
+        record_type: RecordType,
+        #[serde(default)]
+        ip_addr: Vec<SocketAddr>,
+        #[serde(default)]
+        cname_target: Option<String>,
+        #[serde(default)]
+        ssl_cert: bool,
+    },
+    Delete {
+        domain: String,
+    },
+}
+
+impl BulkOperation {
+    pub fn domain(&self) -> &str {
+        match self {
+            Self::Create { domain, .. } | Self::Update { domain, .. } | Self::Delete { domain } => domain,
+        }
+    }
+
+    /// The key the store holds the record under
+    fn key(&self) -> String {
+        self.domain().trim_end_matches('.').to_lowercase()
+    }
+}
+
+/// Body of `/record/bulk`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BulkRequest {
+    pub operations: Vec<BulkOperation>,
+    /// Only validate, nothing is applied
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Outcome of one operation, in the order of the request
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulkItemResult {
+    pub index: usize,
+    pub domain: String,
+    /// The record after the operation, `None` for deletions and failures
+    pub record: Option<FormDnsRecord>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BulkResponse {
+    /// Whether the changes were made. False on a dry run and whenever an
+    /// operation failed, in which case nothing was changed.
+    pub applied: bool,
+    pub dry_run: bool,
+    pub results: Vec<BulkItemResult>,
+}
+
+impl BulkResponse {
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|result| result.error.is_some()).count()
+    }
+}
+
+/// The records a list of operations leaves behind, by store key, `None` for
+/// removed ones
+pub type BulkPlan = BTreeMap<String, Option<FormDnsRecord>>;
+
+/// Runs the operations against the records `current` returns, without
+/// changing anything. Operations that fail don't affect the ones after them.
+pub fn plan(
+    operations: &[BulkOperation],
+    current: impl Fn(&str) -> Option<FormDnsRecord>,
+) -> (Vec<BulkItemResult>, BulkPlan) {
+    let mut changes = BulkPlan::new();
+    let mut results = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.iter().enumerate() {
+        let key = operation.key();
+        let existing = match changes.get(&key) {
+            Some(change) => change.clone(),
+            None => current(&key),
+        };
+        let outcome = match operation.clone() {
+            BulkOperation::Create { domain, record_type, ip_addr, cname_target, ssl_cert, .. } => {
+                FormDnsRecord::build(&domain, record_type, ip_addr, cname_target, ssl_cert).map(Some)
+            }
+            BulkOperation::Update { replace, record_type, ip_addr, cname_target, ssl_cert, .. } => match existing {
+                Some(mut record) => record.apply_update(replace, record_type, ip_addr, cname_target, ssl_cert)
+                    .map(|()| Some(record)),
+                None => Err(format!("No record for domain {}, create it first", operation.domain())),
+            },
+            BulkOperation::Delete { .. } => match existing {
+                Some(_) => Ok(None),
+                None => Err(format!("No record for domain {}", operation.domain())),
+            },
+        };
+        let (record, error) = match outcome {
+            Ok(record) => {
+                changes.insert(key, record.clone());
+                (record, None)
+            }
+            Err(error) => (None, Some(error)),
+        };
+        results.push(BulkItemResult { index, domain: operation.domain().to_string(), record, error });
+    }
+    (results, changes)
+}
+
+/// Authorizes every operation, failures are reported per operation. A
+/// record created earlier in the list may be changed by the same caller.
+async fn authorize(caller: &Caller, store: &SharedStore, operations: &[BulkOperation]) -> Vec<Option<AuthError>> {
+    let mut created = HashSet::new();
+    let mut denied = Vec::with_capacity(operations.len());
+    for operation in operations {
+        let key = operation.key();
+        let exists = store.read().await.get(&key).is_some();
+        let result = match operation {
+            BulkOperation::Create { build_id, .. } => {
+                let result = match authorize_build(caller, build_id.as_deref()).await {
+                    Ok(()) if exists => authorize_record(caller, store, &key).await,
+                    result => result,
+                };
+                if result.is_ok() {
+                    created.insert(key);
+                }
+                result
+            }
+            _ if created.contains(&key) => Ok(()),
+            _ => authorize_record(caller, store, &key).await,
+        };
+        denied.push(result.err());
+    }
+    denied
+}
+
+/// Authorizes, validates and, unless it's a dry run or something failed,
+/// applies the operations
+pub async fn apply_bulk(store: &SharedStore, caller: &Caller, request: BulkRequest) -> Result<BulkResponse, String> {
+    if request.operations.is_empty() {
+        return Err("No operations given".to_string());
+    }
+    if request.operations.len() > MAX_BULK_OPERATIONS {
+        return Err(format!("At most {MAX_BULK_OPERATIONS} operations may be applied at once"));
+    }
+
+    let denied = authorize(caller, store, &request.operations).await;
+    let mut guard = store.write().await;
+    let (mut results, changes) = plan(&request.operations, |key| guard.get(key));
+    for (result, denied) in results.iter_mut().zip(denied) {
+        if let Some(denied) = denied {
+            result.record = None;
+            result.error = Some(denied.to_string());
+        }
+    }
+
+    let mut response = BulkResponse { applied: false, dry_run: request.dry_run, results };
+    if request.dry_run || response.failed() > 0 {
+        return Ok(response);
+    }
+
+    for (key, change) in changes {
+        match change {
+            Some(record) => guard.insert(&key, record).await,
+            None => {
+                guard.remove(&key);
+                guard.remove_stream_routes(&key).await;
+                get_dns_analytics().remove(&key);
+            }
+        }
+    }
+    if let Caller::Account(owner) = caller {
+        for operation in request.operations.iter().filter(|operation| matches!(operation, BulkOperation::Create { .. })) {
+            guard.set_owner(operation.domain(), owner);
+        }
+    }
+    response.applied = true;
+    log::info!("Applied {} bulk record operations", response.results.len());
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn create(domain: &str, ip: &str) -> BulkOperation {
+        BulkOperation::Create {
+            domain: domain.to_string(),
+            record_type: RecordType::A,
+            ip_addr: vec![SocketAddr::new(ip.parse().unwrap(), 80)],
+            cname_target: None,
+            ssl_cert: false,
+            build_id: None,
+        }
+    }
+
+    fn update(domain: &str, ip: &str) -> BulkOperation {
+        BulkOperation::Update {
+            domain: domain.to_string(),
+            replace: true,
+            record_type: RecordType::A,
+            ip_addr: vec![SocketAddr::new(ip.parse().unwrap(), 80)],
+            cname_target: None,
+            ssl_cert: false,
+        }
+    }
+
+    fn delete(domain: &str) -> BulkOperation {
+        BulkOperation::Delete { domain: domain.to_string() }
+    }
+
+    #[test]
+    fn test_plan_applies_operations_in_order() {
+        let existing: HashMap<String, FormDnsRecord> = [(
+            "old.example".to_string(),
+            FormDnsRecord::build("old.example", RecordType::A, vec!["203.0.113.1:80".parse().unwrap()], None, false).unwrap(),
+        )].into();
+        let operations = vec![
+            create("New.Example.", "203.0.113.2"),
+            update("new.example", "10.0.0.5"),
+            delete("old.example"),
+        ];
+        let (results, changes) = plan(&operations, |key| existing.get(key).cloned());
+
+        assert!(results.iter().all(|result| result.error.is_none()));
+        let new = changes["new.example"].as_ref().unwrap();
+        assert_eq!(new.formnet_ip, vec!["10.0.0.5:80".parse().unwrap()]);
+        assert!(new.public_ip.is_empty());
+        assert_eq!(changes["old.example"], None);
+        assert_eq!(results[2].record, None);
+    }
+
+    #[test]
+    fn test_plan_reports_each_failure() {
+        let operations = vec![
+            delete("missing.example"),
+            create("v6.example", "2001:db8::1"),
+            create("ok.example", "203.0.113.3"),
+            delete("ok.example"),
+            update("ok.example", "203.0.113.4"),
+        ];
+        let (results, changes) = plan(&operations, |_| None);
+
+        let failed: Vec<usize> = results.iter().filter(|result| result.error.is_some()).map(|result| result.index).collect();
+        assert_eq!(failed, vec![0, 1, 4]);
+        assert!(results[1].error.as_deref().unwrap().contains("IPV6"));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes["ok.example"], None);
+    }
+
+    #[test]
+    fn test_operations_are_tagged() {
+        let request: BulkRequest = serde_json::from_str(r#"{
+            "dry_run": true,
+            "operations": [
+                {"op": "create", "domain": "a.example", "record_type": "CNAME", "cname_target": "b.example"},
+                {"op": "delete", "domain": "c.example"}
+            ]
+        }"#).unwrap();
+        assert!(request.dry_run);
+        assert!(matches!(&request.operations[0], BulkOperation::Create { record_type: RecordType::CNAME, .. }));
+        assert_eq!(request.operations[1].domain(), "c.example");
+    }
+}
@@ -0,0 +1,336 @@
+//! DNSSEC for the zones Formation is authoritative for.
+//!
+//! Answers are built per query (health filtering, geo sorting, CNAME
+//! flattening), so instead of signing a zone file up front every answer is
+//! signed when it is served. Signatures are cached per RRset and reused
+//! until the RRset changes or the signature gets close to expiring, so a
+//! record change is re-signed on its next lookup. Each zone has a single
+//! ECDSA P-256 key acting as both KSK and ZSK, generated on first start and
+//! kept on disk; its DS record is exported for delegation from the parent.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, DS, NSEC, SIG};
+use trust_dns_proto::rr::dnssec::{tbs, Algorithm, DigestType, KeyPair, Private, SigSigner};
+use trust_dns_proto::rr::{DNSClass, LowerName, Name, RData, Record, RecordSet, RecordType};
+
+/// Directory the zone keys are kept in
+pub const DEFAULT_KEY_DIR: &str = "/var/lib/formation/dns/keys";
+/// Comma separated zones to sign, e.g. `fog,formation.cloud`
+pub const DNSSEC_ZONES_ENV: &str = "FORM_DNS_DNSSEC_ZONES";
+pub const DNSSEC_KEY_DIR_ENV: &str = "FORM_DNS_KEY_DIR";
+
+const ALGORITHM: Algorithm = Algorithm::ECDSAP256SHA256;
+/// How long a signature is valid for
+const SIGNATURE_VALIDITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Cached signatures with less validity than this left are replaced
+const RESIGN_BEFORE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Inception is backdated to tolerate resolvers with a slow clock
+const INCEPTION_SKEW: Duration = Duration::from_secs(60 * 60);
+const DNSKEY_TTL: u32 = 3600;
+const NSEC_TTL: u32 = 60;
+
+struct CachedSignature {
+    digest: u64,
+    expires_at: u64,
+    rrsig: Record,
+}
+
+/// Signs the answers of one zone
+pub struct ZoneSigner {
+    zone: Name,
+    dnskey: DNSKEY,
+    signer: SigSigner,
+    key_tag: u16,
+    signatures: Mutex<HashMap<(LowerName, RecordType), CachedSignature>>,
+}
+
+impl ZoneSigner {
+    pub fn new(zone: Name, pkcs8: &[u8]) -> Result<Self> {
+        let zone = zone.to_lowercase();
+        let key = KeyPair::<Private>::from_pkcs8(pkcs8, ALGORITHM)?;
+        // The single key signs the zone and is the secure entry point the
+        // parent's DS points at, so it gets flags 257
+        let dnskey = DNSKEY::new(true, true, false, ALGORITHM, key.to_public_bytes()?);
+        let signer = SigSigner::dnssec(dnskey.clone(), key, zone.clone(), SIGNATURE_VALIDITY);
+        let key_tag = signer.calculate_key_tag()?;
+        Ok(Self { zone, dnskey, signer, key_tag, signatures: Mutex::new(HashMap::new()) })
+    }
+
+    /// Loads the zone's key from `key_dir`, generating and storing a new one
+    /// if there is none yet
+    pub fn load_or_generate(zone: Name, key_dir: &Path) -> Result<Self> {
+        let path = key_path(key_dir, &zone);
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("Generating DNSSEC key for {zone} at {}", path.display());
+                let pkcs8 = KeyPair::<Private>::generate_pkcs8(ALGORITHM)?;
+                write_key(&path, &pkcs8)?;
+                pkcs8
+            }
+            Err(e) => return Err(anyhow!("Unable to read DNSSEC key {}: {e}", path.display())),
+        };
+        Self::new(zone, &pkcs8)
+    }
+
+    pub fn zone(&self) -> &Name {
+        &self.zone
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        self.key_tag
+    }
+
+    pub fn contains(&self, name: &LowerName) -> bool {
+        LowerName::new(&self.zone).zone_of(name)
+    }
+
+    /// Adds an RRSIG to `rrset`, reusing the cached signature if the RRset
+    /// hasn't changed since it was signed
+    pub fn sign_rrset(&self, rrset: &mut RecordSet) -> Result<()> {
+        let now = unix_now();
+        let key = (LowerName::new(rrset.name()), rrset.record_type());
+        let digest = rrset_digest(rrset);
+
+        let mut signatures = self.signatures.lock().map_err(|_| anyhow!("DNSSEC signature cache poisoned"))?;
+        if let Some(cached) = signatures.get(&key) {
+            if cached.digest == digest && cached.expires_at > now + RESIGN_BEFORE_EXPIRY.as_secs() {
+                rrset.insert_rrsig(cached.rrsig.clone());
+                return Ok(());
+            }
+        }
+
+        let rrsig = self.rrsig(rrset, now)?;
+        signatures.insert(key, CachedSignature {
+            digest,
+            expires_at: now + SIGNATURE_VALIDITY.as_secs(),
+            rrsig: rrsig.clone(),
+        });
+        rrset.insert_rrsig(rrsig);
+        Ok(())
+    }
+
+    fn rrsig(&self, rrset: &RecordSet, now: u64) -> Result<Record> {
+        let inception = now.saturating_sub(INCEPTION_SKEW.as_secs()) as u32;
+        let expiration = (now + SIGNATURE_VALIDITY.as_secs()) as u32;
+        let records: Vec<&Record> = rrset.records_without_rrsigs().collect();
+        let tbs = tbs::rrset_tbs(
+            rrset.name(),
+            DNSClass::IN,
+            rrset.name().num_labels(),
+            rrset.record_type(),
+            ALGORITHM,
+            rrset.ttl(),
+            expiration,
+            inception,
+            self.key_tag,
+            self.signer.signer_name(),
+            &records,
+        )?;
+        let signature = self.signer.sign(&tbs)?;
+
+        let mut rrsig = Record::with(rrset.name().clone(), RecordType::RRSIG, rrset.ttl());
+        rrsig.set_data(Some(RData::DNSSEC(DNSSECRData::SIG(SIG::new(
+            rrset.record_type(),
+            ALGORITHM,
+            rrset.name().num_labels(),
+            rrset.ttl(),
+            expiration,
+            inception,
+            self.key_tag,
+            self.signer.signer_name().clone(),
+            signature,
+        )))));
+        Ok(rrsig)
+    }
+
+    /// The zone's signed DNSKEY RRset, served at the apex
+    pub fn dnskey_rrset(&self) -> Result<RecordSet> {
+        let mut rrset = RecordSet::new(&self.zone, RecordType::DNSKEY, DNSKEY_TTL);
+        rrset.add_rdata(RData::DNSSEC(DNSSECRData::DNSKEY(self.dnskey.clone())));
+        self.sign_rrset(&mut rrset)?;
+        Ok(rrset)
+    }
+
+    /// Signed NSEC denying every type at `name` but `types`. Names that
+    /// don't exist are answered as existing with no types ("black lies"),
+    /// so the record only covers `name` itself and can't be used to walk
+    /// the zone.
+    pub fn nsec_rrset(&self, name: &Name, types: &[RecordType]) -> Result<RecordSet> {
+        let next = Name::from_ascii(format!("\\000.{}", name.to_lowercase()))?;
+        let mut type_bit_maps = types.to_vec();
+        type_bit_maps.extend([RecordType::RRSIG, RecordType::NSEC]);
+        type_bit_maps.sort_by_key(|rtype| u16::from(*rtype));
+        type_bit_maps.dedup();
+
+        let mut rrset = RecordSet::new(name, RecordType::NSEC, NSEC_TTL);
+        rrset.add_rdata(RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(next, type_bit_maps))));
+        self.sign_rrset(&mut rrset)?;
+        Ok(rrset)
+    }
+
+    /// The DS record for the parent zone
+    pub fn ds(&self) -> Result<DsExport> {
+        let digest = self.dnskey.to_digest(&self.zone, DigestType::SHA256)?;
+        let digest_hex: String = digest.as_ref().iter().map(|b| format!("{b:02X}")).collect();
+        let ds = DS::new(self.key_tag, ALGORITHM, DigestType::SHA256, digest.as_ref().to_vec());
+        Ok(DsExport {
+            zone: self.zone.to_string(),
+            key_tag: ds.key_tag(),
+            algorithm: u8::from(ALGORITHM),
+            digest_type: 2,
+            record: format!("{} {} IN DS {} {} 2 {}", self.zone, DNSKEY_TTL, ds.key_tag(), u8::from(ALGORITHM), digest_hex),
+            digest: digest_hex,
+        })
+    }
+}
+
+/// A zone's DS record, served on `/dnssec/:zone/ds`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DsExport {
+    pub zone: String,
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    /// Hex encoded SHA-256 digest of the DNSKEY
+    pub digest: String,
+    /// Presentation format, ready to add to the parent zone
+    pub record: String,
+}
+
+/// The signers of every DNSSEC enabled zone
+#[derive(Clone, Default)]
+pub struct ZoneSigners {
+    signers: Vec<Arc<ZoneSigner>>,
+}
+
+impl ZoneSigners {
+    /// Loads or generates the keys of `zones`
+    pub fn load(zones: &[String], key_dir: &Path) -> Result<Self> {
+        let mut signers = Vec::new();
+        for zone in zones {
+            let name = Name::from_utf8(zone)?.to_lowercase();
+            let signer = ZoneSigner::load_or_generate(name, key_dir)?;
+            log::info!("DNSSEC enabled for {} with key tag {}", signer.zone(), signer.key_tag());
+            signers.push(Arc::new(signer));
+        }
+        Ok(Self { signers })
+    }
+
+    /// Loads the zones listed in `FORM_DNS_DNSSEC_ZONES`, keys are kept in
+    /// `FORM_DNS_KEY_DIR`
+    pub fn from_env() -> Result<Self> {
+        let zones: Vec<String> = std::env::var(DNSSEC_ZONES_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .map(|zone| zone.trim().to_string())
+            .filter(|zone| !zone.is_empty())
+            .collect();
+        Self::load(&zones, &key_dir())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signers.is_empty()
+    }
+
+    /// The signer of the most specific zone `name` is in
+    pub fn signer_for(&self, name: &LowerName) -> Option<&Arc<ZoneSigner>> {
+        self.signers.iter()
+            .filter(|signer| signer.contains(name))
+            .max_by_key(|signer| signer.zone().num_labels())
+    }
+}
+
+pub fn key_dir() -> PathBuf {
+    std::env::var(DNSSEC_KEY_DIR_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_KEY_DIR))
+}
+
+/// Where the key of `zone` is kept
+pub fn key_path(key_dir: &Path, zone: &Name) -> PathBuf {
+    let zone = zone.to_lowercase().to_string();
+    key_dir.join(format!("{}.pk8", zone.trim_end_matches('.')))
+}
+
+/// Exports the DS record of a zone from its stored key
+pub fn export_ds(zone: &str, key_dir: &Path) -> Result<DsExport> {
+    let zone = Name::from_utf8(zone)?.to_lowercase();
+    let path = key_path(key_dir, &zone);
+    let pkcs8 = std::fs::read(&path)
+        .map_err(|e| anyhow!("No DNSSEC key for {zone} at {}: {e}", path.display()))?;
+    ZoneSigner::new(zone, &pkcs8)?.ds()
+}
+
+fn write_key(path: &Path, pkcs8: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, pkcs8)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Order independent digest of an RRset's TTL and data, answers are geo
+/// sorted so the same RRset comes back in different orders
+fn rrset_digest(rrset: &RecordSet) -> u64 {
+    let mut rdata: Vec<String> = rrset.records_without_rrsigs()
+        .filter_map(|record| record.data().map(|data| data.to_string()))
+        .collect();
+    rdata.sort();
+    let mut hasher = DefaultHasher::new();
+    rrset.ttl().hash(&mut hasher);
+    rdata.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use trust_dns_proto::rr::rdata::A;
+
+    #[test]
+    fn test_rrsets_are_resigned_when_they_change() {
+        let pkcs8 = KeyPair::<Private>::generate_pkcs8(ALGORITHM).unwrap();
+        let signer = ZoneSigner::new(Name::from_utf8("fog.").unwrap(), &pkcs8).unwrap();
+        let name = Name::from_utf8("app.fog.").unwrap();
+        assert!(signer.contains(&LowerName::new(&name)));
+        assert!(!signer.contains(&LowerName::new(&Name::from_utf8("example.com.").unwrap())));
+
+        let rrsig_of = |ip: Ipv4Addr| {
+            let mut rrset = RecordSet::new(&name, RecordType::A, 60);
+            rrset.add_rdata(RData::A(A(ip)));
+            signer.sign_rrset(&mut rrset).unwrap();
+            rrset.rrsigs().to_vec()
+        };
+        let first = rrsig_of(Ipv4Addr::new(203, 0, 113, 1));
+        assert_eq!(first.len(), 1);
+        // Unchanged RRsets reuse the cached signature
+        assert_eq!(rrsig_of(Ipv4Addr::new(203, 0, 113, 1)), first);
+        // A changed RRset gets a new one
+        assert_ne!(rrsig_of(Ipv4Addr::new(203, 0, 113, 2)), first);
+
+        let ds = signer.ds().unwrap();
+        assert_eq!(ds.key_tag, signer.key_tag());
+        assert_eq!(ds.digest.len(), 64);
+        assert!(ds.record.starts_with("fog. 3600 IN DS"));
+    }
+}
@@ -0,0 +1,626 @@
+//! Pluggable geolocation providers
+//!
+//! Locations come from a chain of providers that is tried in order, so a
+//! remote API can answer for addresses the local MaxMind database doesn't
+//! know, or stand in while the database is missing. MaxMind databases are
+//! reloaded when the file on disk changes and the chain itself can be
+//! replaced through `PUT /geo/providers`, neither needs a restart. Each
+//! provider counts its hits, misses and errors and the accuracy radius of
+//! its answers, reported by `GET /geo/providers`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::geolocation::{GeoLocation, GeoLocationError, GeoMatch, GeoResolver};
+
+/// Where the provider chain set through the API is kept across restarts
+pub const GEO_PROVIDERS_PATH: &str = "/etc/formation/geo/providers.json";
+
+/// How often MaxMind databases are checked for changes
+pub const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long answers of a remote API are kept unless configured otherwise
+pub const DEFAULT_REMOTE_CACHE_TTL_SECS: u64 = 3600;
+
+const DEFAULT_REMOTE_TIMEOUT_MS: u64 = 2000;
+const MAX_REMOTE_CACHE_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum GeoProviderConfig {
+    /// A MaxMind City database, reloaded when the file changes
+    Mmdb { path: String },
+    /// A JSON API queried with `url`, `{ip}` is replaced with the address.
+    /// Fields are JSON pointers into the response.
+    Http {
+        url: String,
+        #[serde(default = "default_latitude_field")]
+        latitude_field: String,
+        #[serde(default = "default_longitude_field")]
+        longitude_field: String,
+        #[serde(default)]
+        country_field: Option<String>,
+        #[serde(default)]
+        region_field: Option<String>,
+        #[serde(default)]
+        accuracy_field: Option<String>,
+        /// The autonomous system number, `AS` prefixes are stripped
+        #[serde(default)]
+        asn_field: Option<String>,
+        /// Sent with every request, e.g. an API key
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        #[serde(default)]
+        cache_ttl_secs: Option<u64>,
+    },
+}
+
+fn default_latitude_field() -> String {
+    "/latitude".to_string()
+}
+
+fn default_longitude_field() -> String {
+    "/longitude".to_string()
+}
+
+impl GeoProviderConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            GeoProviderConfig::Mmdb { path } if path.trim().is_empty() => Err("mmdb provider needs a path".to_string()),
+            GeoProviderConfig::Http { url, .. } if !url.contains("{ip}") => Err(format!("{url} has no {{ip}} placeholder")),
+            GeoProviderConfig::Http { url, .. } if !url.starts_with("http://") && !url.starts_with("https://") => {
+                Err(format!("{url} is not an http(s) url"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn build(&self) -> Arc<dyn GeoProvider> {
+        match self {
+            GeoProviderConfig::Mmdb { path } => Arc::new(MmdbProvider::new(path)),
+            GeoProviderConfig::Http { .. } => Arc::new(HttpProvider::new(self.clone())),
+        }
+    }
+}
+
+/// Counters of one provider
+#[derive(Default)]
+pub struct ProviderStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+    accuracy_sum_km: AtomicU64,
+    accuracy_samples: AtomicU64,
+}
+
+impl ProviderStats {
+    fn record(&self, result: &Result<GeoMatch, GeoLocationError>) {
+        match result {
+            Ok(found) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                if let Some(radius) = found.accuracy_radius_km {
+                    self.accuracy_sum_km.fetch_add(radius as u64, Ordering::Relaxed);
+                    self.accuracy_samples.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(GeoLocationError::LocationNotFound) | Err(GeoLocationError::Pending) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> GeoProviderStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let errors = self.errors.load(Ordering::Relaxed);
+        let samples = self.accuracy_samples.load(Ordering::Relaxed);
+        GeoProviderStats {
+            hits,
+            misses,
+            errors,
+            hit_rate: match hits + misses + errors {
+                0 => 0.0,
+                lookups => hits as f64 / lookups as f64,
+            },
+            avg_accuracy_radius_km: (samples > 0)
+                .then(|| self.accuracy_sum_km.load(Ordering::Relaxed) as f64 / samples as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GeoProviderStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub errors: u64,
+    pub hit_rate: f64,
+    /// Average radius of the answers that reported one
+    pub avg_accuracy_radius_km: Option<f64>,
+}
+
+pub trait GeoProvider: Send + Sync {
+    /// `<type>:<path or url>`
+    fn name(&self) -> String;
+
+    fn lookup(&self, ip: IpAddr) -> Result<GeoMatch, GeoLocationError>;
+
+    /// Whether the provider can answer at all
+    fn is_ready(&self) -> bool;
+
+    /// Picks up changed data, true if anything was reloaded
+    fn reload_if_changed(&self) -> bool {
+        false
+    }
+
+    /// When the provider's data was last loaded, in seconds since the epoch
+    fn loaded_at(&self) -> Option<u64> {
+        None
+    }
+
+    fn stats(&self) -> &ProviderStats;
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[derive(Default)]
+struct MmdbState {
+    resolver: Option<GeoResolver>,
+    /// Modification time and size of the loaded file
+    loaded_stamp: Option<(SystemTime, u64)>,
+    loaded_at: Option<u64>,
+}
+
+/// A MaxMind database on disk
+pub struct MmdbProvider {
+    path: PathBuf,
+    state: RwLock<MmdbState>,
+    stats: ProviderStats,
+}
+
+impl MmdbProvider {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let provider = Self {
+            path: path.as_ref().to_path_buf(),
+            state: RwLock::new(MmdbState::default()),
+            stats: ProviderStats::default(),
+        };
+        if !provider.reload_if_changed() {
+            warn!("No MaxMind database loaded from {}", provider.path.display());
+        }
+        provider
+    }
+}
+
+impl GeoProvider for MmdbProvider {
+    fn name(&self) -> String {
+        format!("mmdb:{}", self.path.display())
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Result<GeoMatch, GeoLocationError> {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        match &state.resolver {
+            Some(resolver) => resolver.get_match(ip),
+            None => Err(GeoLocationError::DatabaseNotFound(self.path.display().to_string())),
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).resolver.is_some()
+    }
+
+    /// A file that can't be read or parsed, e.g. one that's still being
+    /// copied, leaves the loaded database in place and is retried later
+    fn reload_if_changed(&self) -> bool {
+        let Some(stamp) = std::fs::metadata(&self.path).ok()
+            .and_then(|meta| Some((meta.modified().ok()?, meta.len())))
+        else {
+            return false;
+        };
+        if self.state.read().unwrap_or_else(|e| e.into_inner()).loaded_stamp == Some(stamp) {
+            return false;
+        }
+
+        match GeoResolver::new(&self.path) {
+            Ok(resolver) => {
+                let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+                state.resolver = Some(resolver);
+                state.loaded_stamp = Some(stamp);
+                state.loaded_at = Some(now());
+                info!("Reloaded MaxMind database {}", self.path.display());
+                true
+            }
+            Err(e) => {
+                error!("Unable to load MaxMind database {}: {e}", self.path.display());
+                false
+            }
+        }
+    }
+
+    fn loaded_at(&self) -> Option<u64> {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).loaded_at
+    }
+
+    fn stats(&self) -> &ProviderStats {
+        &self.stats
+    }
+}
+
+type RemoteCache = HashMap<IpAddr, (Option<GeoMatch>, Instant)>;
+
+/// A remote JSON API. Lookups never wait on the network, an address that
+/// isn't cached yet is fetched in the background and missed until then so
+/// the chain can fall through to the next provider.
+pub struct HttpProvider {
+    config: GeoProviderConfig,
+    client: reqwest::Client,
+    cache: Arc<Mutex<RemoteCache>>,
+    pending: Arc<Mutex<HashSet<IpAddr>>>,
+    cache_ttl: Duration,
+    stats: Arc<ProviderStats>,
+}
+
+impl HttpProvider {
+    pub fn new(config: GeoProviderConfig) -> Self {
+        let (timeout_ms, cache_ttl_secs) = match &config {
+            GeoProviderConfig::Http { timeout_ms, cache_ttl_secs, .. } => (*timeout_ms, *cache_ttl_secs),
+            GeoProviderConfig::Mmdb { .. } => (None, None),
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_REMOTE_TIMEOUT_MS)))
+            .build()
+            .unwrap_or_default();
+        Self {
+            config,
+            client,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            cache_ttl: Duration::from_secs(cache_ttl_secs.unwrap_or(DEFAULT_REMOTE_CACHE_TTL_SECS)),
+            stats: Arc::new(ProviderStats::default()),
+        }
+    }
+
+    fn fetch_in_background(&self, ip: IpAddr) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if !self.pending.lock().unwrap_or_else(|e| e.into_inner()).insert(ip) {
+            return;
+        }
+        let GeoProviderConfig::Http { url, headers, .. } = &self.config else {
+            return;
+        };
+        let mut request = self.client.get(url.replace("{ip}", &ip.to_string()));
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let (config, cache, pending, stats) = (self.config.clone(), self.cache.clone(), self.pending.clone(), self.stats.clone());
+
+        runtime.spawn(async move {
+            let result = async {
+                let body: Value = request.send().await?.error_for_status()?.json().await?;
+                Ok::<_, reqwest::Error>(body)
+            }.await;
+            match result {
+                Ok(body) => {
+                    let found = parse_remote_location(&config, &body);
+                    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+                    if cache.len() >= MAX_REMOTE_CACHE_ENTRIES {
+                        cache.clear();
+                    }
+                    cache.insert(ip, (found, Instant::now()));
+                }
+                Err(e) => {
+                    stats.errors.fetch_add(1, Ordering::Relaxed);
+                    warn!("Remote geolocation lookup of {ip} failed: {e}");
+                }
+            }
+            pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&ip);
+        });
+    }
+}
+
+/// Reads a location out of a remote API response, `None` if it has no
+/// usable coordinates
+pub fn parse_remote_location(config: &GeoProviderConfig, body: &Value) -> Option<GeoMatch> {
+    let GeoProviderConfig::Http { latitude_field, longitude_field, country_field, region_field, accuracy_field, asn_field, .. } = config else {
+        return None;
+    };
+    let number = |pointer: &str| match body.pointer(pointer)? {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    };
+    let text = |pointer: &Option<String>| pointer.as_deref()
+        .and_then(|p| body.pointer(p)?.as_str())
+        .filter(|s| !s.is_empty())
+        .map(str::to_string);
+
+    let (latitude, longitude) = (number(latitude_field)?, number(longitude_field)?);
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return None;
+    }
+    Some(GeoMatch {
+        location: GeoLocation {
+            latitude,
+            longitude,
+            country_code: text(country_field),
+            region_code: text(region_field),
+            asn: asn_field.as_deref().and_then(|p| match body.pointer(p)? {
+                Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+                Value::String(s) => s.trim_start_matches("AS").parse().ok(),
+                _ => None,
+            }),
+        },
+        accuracy_radius_km: accuracy_field.as_deref().and_then(number).map(|r| r.clamp(0.0, u16::MAX as f64) as u16),
+    })
+}
+
+impl GeoProvider for HttpProvider {
+    fn name(&self) -> String {
+        match &self.config {
+            GeoProviderConfig::Http { url, .. } => format!("http:{url}"),
+            GeoProviderConfig::Mmdb { path } => format!("mmdb:{path}"),
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Result<GeoMatch, GeoLocationError> {
+        let cached = self.cache.lock().unwrap_or_else(|e| e.into_inner()).get(&ip)
+            .filter(|(_, fetched)| fetched.elapsed() < self.cache_ttl)
+            .map(|(found, _)| found.clone());
+        match cached {
+            Some(Some(found)) => Ok(found),
+            Some(None) => Err(GeoLocationError::LocationNotFound),
+            None => {
+                self.fetch_in_background(ip);
+                Err(GeoLocationError::Pending)
+            }
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        true
+    }
+
+    fn stats(&self) -> &ProviderStats {
+        &self.stats
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeoProviderStatus {
+    pub name: String,
+    pub ready: bool,
+    pub loaded_at: Option<u64>,
+    pub stats: GeoProviderStats,
+}
+
+/// Response of `GET /geo/providers`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeoChainStatus {
+    pub lookups: u64,
+    /// Lookups no provider could answer
+    pub unresolved: u64,
+    /// Lookups answered by a provider other than the first
+    pub fallbacks: u64,
+    pub providers: Vec<GeoProviderStatus>,
+}
+
+/// The providers in the order they're asked
+pub struct GeoProviderChain {
+    configs: RwLock<Vec<GeoProviderConfig>>,
+    providers: RwLock<Vec<Arc<dyn GeoProvider>>>,
+    lookups: AtomicU64,
+    unresolved: AtomicU64,
+    fallbacks: AtomicU64,
+}
+
+impl GeoProviderChain {
+    pub fn new(configs: Vec<GeoProviderConfig>) -> Self {
+        let chain = Self {
+            configs: RwLock::new(Vec::new()),
+            providers: RwLock::new(Vec::new()),
+            lookups: AtomicU64::new(0),
+            unresolved: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+        };
+        if let Err(e) = chain.configure(configs) {
+            error!("Invalid geolocation providers: {e}");
+        }
+        chain
+    }
+
+    /// Replaces the providers. Providers whose config didn't change are kept
+    /// along with their data and counters.
+    pub fn configure(&self, configs: Vec<GeoProviderConfig>) -> Result<(), String> {
+        configs.iter().try_for_each(GeoProviderConfig::validate)?;
+        let mut current_configs = self.configs.write().unwrap_or_else(|e| e.into_inner());
+        let mut providers = self.providers.write().unwrap_or_else(|e| e.into_inner());
+        let next: Vec<Arc<dyn GeoProvider>> = configs.iter().map(|config| {
+            current_configs.iter().position(|c| c == config)
+                .map(|i| providers[i].clone())
+                .unwrap_or_else(|| config.build())
+        }).collect();
+        *providers = next;
+        *current_configs = configs;
+        Ok(())
+    }
+
+    pub fn configs(&self) -> Vec<GeoProviderConfig> {
+        self.configs.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn providers(&self) -> Vec<Arc<dyn GeoProvider>> {
+        self.providers.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Whether any provider can answer
+    pub fn is_available(&self) -> bool {
+        self.providers().iter().any(|provider| provider.is_ready())
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Result<GeoMatch, GeoLocationError> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        for (i, provider) in self.providers().iter().enumerate() {
+            let result = provider.lookup(ip);
+            provider.stats().record(&result);
+            if result.is_ok() {
+                if i > 0 {
+                    self.fallbacks.fetch_add(1, Ordering::Relaxed);
+                }
+                return result;
+            }
+        }
+        self.unresolved.fetch_add(1, Ordering::Relaxed);
+        Err(GeoLocationError::LocationNotFound)
+    }
+
+    pub fn get_location(&self, ip: IpAddr) -> Result<GeoLocation, GeoLocationError> {
+        self.lookup(ip).map(|found| found.location)
+    }
+
+    /// Reloads the providers whose data changed and returns their names
+    pub fn reload_changed(&self) -> Vec<String> {
+        self.providers().iter()
+            .filter(|provider| provider.reload_if_changed())
+            .map(|provider| provider.name())
+            .collect()
+    }
+
+    pub fn status(&self) -> GeoChainStatus {
+        GeoChainStatus {
+            lookups: self.lookups.load(Ordering::Relaxed),
+            unresolved: self.unresolved.load(Ordering::Relaxed),
+            fallbacks: self.fallbacks.load(Ordering::Relaxed),
+            providers: self.providers().iter().map(|provider| GeoProviderStatus {
+                name: provider.name(),
+                ready: provider.is_ready(),
+                loaded_at: provider.loaded_at(),
+                stats: provider.stats().snapshot(),
+            }).collect(),
+        }
+    }
+}
+
+/// The chain saved at `path`, if there is one
+pub fn load_provider_configs(path: impl AsRef<Path>) -> Option<Vec<GeoProviderConfig>> {
+    let contents = std::fs::read_to_string(path.as_ref()).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(configs) => Some(configs),
+        Err(e) => {
+            error!("Ignoring invalid geolocation providers in {}: {e}", path.as_ref().display());
+            None
+        }
+    }
+}
+
+pub fn save_provider_configs(path: impl AsRef<Path>, configs: &[GeoProviderConfig]) -> std::io::Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(configs)?)
+}
+
+/// Checks the chain for changed databases every `interval`
+pub fn spawn_reload_watcher(chain: Arc<GeoProviderChain>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let chain = chain.clone();
+            match tokio::task::spawn_blocking(move || chain.reload_changed()).await {
+                Ok(reloaded) if !reloaded.is_empty() => info!("Reloaded geolocation providers: {}", reloaded.join(", ")),
+                Ok(_) => {}
+                Err(e) => error!("Geolocation reload check failed: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedProvider {
+        answer: Option<GeoMatch>,
+        stats: ProviderStats,
+    }
+
+    impl GeoProvider for FixedProvider {
+        fn name(&self) -> String {
+            "fixed".to_string()
+        }
+
+        fn lookup(&self, _ip: IpAddr) -> Result<GeoMatch, GeoLocationError> {
+            self.answer.clone().ok_or(GeoLocationError::LocationNotFound)
+        }
+
+        fn is_ready(&self) -> bool {
+            true
+        }
+
+        fn stats(&self) -> &ProviderStats {
+            &self.stats
+        }
+    }
+
+    #[test]
+    fn test_remote_location_and_fallback() {
+        let config: GeoProviderConfig = serde_json::from_str(
+            r#"{"type":"http","url":"https://geo.example/{ip}","latitude_field":"/loc/lat","longitude_field":"/loc/lon","country_field":"/cc","accuracy_field":"/radius","asn_field":"/as"}"#
+        ).unwrap();
+        assert!(config.validate().is_ok());
+        let body = serde_json::json!({"loc": {"lat": 51.5, "lon": "-0.12"}, "cc": "GB", "radius": 20, "as": "AS2856"});
+        let found = parse_remote_location(&config, &body).unwrap();
+        assert_eq!((found.location.latitude, found.location.longitude), (51.5, -0.12));
+        assert_eq!(found.location.country_code.as_deref(), Some("GB"));
+        assert_eq!(found.accuracy_radius_km, Some(20));
+        assert_eq!(found.location.asn, Some(2856));
+        assert!(parse_remote_location(&config, &serde_json::json!({"loc": {"lat": 200, "lon": 0}})).is_none());
+
+        let missing = GeoProviderConfig::Http {
+            url: "https://geo.example/".to_string(),
+            latitude_field: default_latitude_field(),
+            longitude_field: default_longitude_field(),
+            country_field: None,
+            region_field: None,
+            accuracy_field: None,
+            asn_field: None,
+            headers: BTreeMap::new(),
+            timeout_ms: None,
+            cache_ttl_secs: None,
+        };
+        assert!(missing.validate().is_err());
+
+        // The first provider misses, the second answers
+        let chain = GeoProviderChain::new(vec![]);
+        *chain.providers.write().unwrap() = vec![
+            Arc::new(FixedProvider { answer: None, stats: ProviderStats::default() }) as Arc<dyn GeoProvider>,
+            Arc::new(FixedProvider { answer: Some(found), stats: ProviderStats::default() }),
+        ];
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(chain.get_location(ip).unwrap().country_code.as_deref(), Some("GB"));
+        let status = chain.status();
+        assert_eq!((status.lookups, status.fallbacks, status.unresolved), (1, 1, 0));
+        assert_eq!(status.providers[0].stats.misses, 1);
+        assert_eq!(status.providers[1].stats.avg_accuracy_radius_km, Some(20.0));
+
+        // A missing database isn't fatal, the chain just has nothing to ask
+        let chain = GeoProviderChain::new(vec![GeoProviderConfig::Mmdb { path: "/nonexistent/geo.mmdb".to_string() }]);
+        assert!(!chain.is_available());
+        assert!(chain.get_location(ip).is_err());
+        assert_eq!(chain.status().providers[0].stats.errors, 1);
+    }
+}
@@ -1,6 +1,7 @@
-use crate::geolocation::{GeoLocation, GeoResolver};
+use crate::geolocation::GeoLocation;
+use crate::geo_provider::{GeoProviderChain, GeoProviderConfig};
 use std::net::IpAddr;
-use std::path::Path;
+use std::sync::Arc;
 use trust_dns_proto::rr::RecordType;
 use log::{debug, error, info};
 
@@ -34,6 +35,7 @@ pub struct GeoResolverConfig {
     pub region_bias_factor: f64,    // Adjustment factor for same-region preference (0.0-1.0)
     pub max_distance_km: Option<f64>, // Maximum distance in km to consider (None = unlimited)
     pub distance_weights: DistanceWeightStrategy, // Strategy for weighting distances
+    pub providers: Vec<GeoProviderConfig>, // Lookup chain, empty = the MaxMind database at db_path
 }
 
 /// Strategy for how distance affects node selection
@@ -60,66 +62,79 @@ impl Default for GeoResolverConfig {
             region_bias_factor: 0.8,
             max_distance_km: None,
             distance_weights: DistanceWeightStrategy::Linear,
+            providers: Vec::new(),
         }
     }
 }
 
-/// GeoDnsResolver wraps a GeoResolver with DNS-specific functionality
+/// GeoDnsResolver wraps a chain of geo providers with DNS-specific functionality
 pub struct GeoDnsResolver {
-    geo_resolver: Option<GeoResolver>,
+    providers: Arc<GeoProviderChain>,
     config: GeoResolverConfig,
 }
 
 impl GeoDnsResolver {
     /// Create a new GeoResolver
     pub fn new(config: GeoResolverConfig) -> Self {
-        let geo_resolver = if config.enabled {
-            match GeoResolver::new(Path::new(&config.db_path)) {
-                Ok(resolver) => {
-                    info!("GeoResolver initialized successfully");
-                    Some(resolver)
-                },
-                Err(e) => {
-                    error!("Failed to initialize GeoResolver: {}", e);
-                    None
-                }
-            }
-        } else {
+        let configs = if !config.enabled {
             info!("GeoResolver disabled in configuration");
-            None
+            Vec::new()
+        } else if config.providers.is_empty() {
+            vec![GeoProviderConfig::Mmdb { path: config.db_path.clone() }]
+        } else {
+            config.providers.clone()
         };
+        let providers = Arc::new(GeoProviderChain::new(configs));
+        if config.enabled && !providers.is_available() {
+            error!("Failed to initialize GeoResolver: no geo provider is ready");
+        } else if config.enabled {
+            info!("GeoResolver initialized successfully");
+        }
         
         Self {
-            geo_resolver,
+            providers,
             config,
         }
     }
+
+    /// The provider chain lookups go through
+    pub fn providers(&self) -> Arc<GeoProviderChain> {
+        self.providers.clone()
+    }
+
+    /// Whether any provider can resolve locations
+    pub fn is_available(&self) -> bool {
+        self.providers.is_available()
+    }
     
     /// Get client location from IP address
     pub fn get_client_location(&self, client_ip: IpAddr) -> Option<GeoLocation> {
-        if let Some(resolver) = &self.geo_resolver {
-            match resolver.get_location(client_ip) {
-                Ok(location) => {
-                    debug!("Client location resolved: {:?} for IP {}", location, client_ip);
-                    return Some(location);
-                },
-                Err(e) => {
-                    debug!("Could not resolve client location for IP {}: {}", client_ip, e);
-                }
+        match self.providers.get_location(client_ip) {
+            Ok(location) => {
+                debug!("Client location resolved: {:?} for IP {}", location, client_ip);
+                Some(location)
+            },
+            Err(e) => {
+                debug!("Could not resolve client location for IP {}: {}", client_ip, e);
+                None
             }
         }
-        None
     }
     
     /// Sort IPs by proximity to client location
     pub fn sort_ips_by_proximity(&self, 
                               client_location: &GeoLocation,
                               ips: Vec<IpAddr>) -> Vec<IpAddr> {
-        if let Some(resolver) = &self.geo_resolver {
-            let sorted = resolver.find_nearest(client_location, &ips);
-            return sorted.into_iter().map(|(ip, _)| ip).collect();
-        }
-        ips
+        let mut located: Vec<(IpAddr, f64)> = ips.iter()
+            .map(|&ip| {
+                let distance = self.providers.get_location(ip)
+                    .map(|location| crate::geolocation::calculate_distance(client_location, &location))
+                    .unwrap_or(f64::MAX);
+                (ip, distance)
+            })
+            .collect();
+        located.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        located.into_iter().map(|(ip, _)| ip).collect()
     }
     
     /// Select and sort IPs by geographic proximity using configured strategy
@@ -127,16 +142,14 @@ impl GeoDnsResolver {
                               client_location: &GeoLocation,
                               ips: Vec<IpAddr>,
                               limit: Option<usize>) -> Vec<IpAddr> {
-        if ips.is_empty() || self.geo_resolver.is_none() {
+        if ips.is_empty() || !self.is_available() {
             return ips;
         }
         
-        let resolver = self.geo_resolver.as_ref().unwrap();
-        
         // Step 1: Map IPs to ProximityEntry with geo data
         let mut entries: Vec<ProximityEntry> = Vec::with_capacity(ips.len());
         for ip in ips {
-            let (distance, region_code, country_code) = match resolver.get_location(ip) {
+            let (distance, region_code, country_code) = match self.providers.get_location(ip) {
                 Ok(location) => {
                     let distance = crate::geolocation::calculate_distance(client_location, &location);
                     (Some(distance), location.region_code, location.country_code)
@@ -301,7 +314,7 @@ mod tests {
             };
             
             let resolver = GeoDnsResolver::new(config);
-            if resolver.geo_resolver.is_some() {
+            if resolver.is_available() {
                 return resolver;
             }
         }
@@ -317,7 +330,7 @@ mod tests {
     fn test_get_client_location() {
         let resolver = get_test_resolver();
         
-        if resolver.geo_resolver.is_none() {
+        if !resolver.is_available() {
             println!("Skipping test_get_client_location: No MaxMind database available");
             return;
         }
@@ -334,7 +347,7 @@ mod tests {
     fn test_proximity_selection_strategies() {
         let resolver = get_test_resolver();
         
-        if resolver.geo_resolver.is_none() {
+        if !resolver.is_available() {
             println!("Skipping test_proximity_selection_strategies: No MaxMind database available");
             return;
         }
@@ -373,7 +386,7 @@ mod tests {
             config.db_path = resolver.config.db_path.clone();
             
             let strategy_resolver = GeoDnsResolver::new(config);
-            if strategy_resolver.geo_resolver.is_none() {
+            if !strategy_resolver.is_available() {
                 continue;
             }
             
@@ -407,7 +420,7 @@ mod tests {
     fn test_region_bias() {
         let resolver = get_test_resolver();
         
-        if resolver.geo_resolver.is_none() {
+        if !resolver.is_available() {
             println!("Skipping test_region_bias: No MaxMind database available");
             return;
         }
@@ -446,7 +459,7 @@ mod tests {
             config.db_path = resolver.config.db_path.clone();
             
             let bias_resolver = GeoDnsResolver::new(config);
-            if bias_resolver.geo_resolver.is_none() {
+            if !bias_resolver.is_available() {
                 continue;
             }
             
@@ -11,6 +11,16 @@ pub struct GeoLocation {
     pub longitude: f64,
     pub country_code: Option<String>,
     pub region_code: Option<String>,
+    /// Autonomous system of the address, if the provider knows it
+    pub asn: Option<u32>,
+}
+
+/// A location along with how precise the provider says it is
+#[derive(Debug, Clone)]
+pub struct GeoMatch {
+    pub location: GeoLocation,
+    /// Radius in km around the coordinates the address is likely in
+    pub accuracy_radius_km: Option<u16>,
 }
 
 /// Error types for geolocation operations
@@ -24,6 +34,12 @@ pub enum GeoLocationError {
     
     #[error("Database file not found at: {0}")]
     DatabaseNotFound(String),
+
+    #[error("Location lookup is still in progress")]
+    Pending,
+
+    #[error("Provider error: {0}")]
+    Provider(String),
 }
 
 /// Resolver for IP geolocation using MaxMind database
@@ -52,6 +68,11 @@ impl GeoResolver {
     
     /// Get location information for an IP address
     pub fn get_location(&self, ip: IpAddr) -> Result<GeoLocation, GeoLocationError> {
+        self.get_match(ip).map(|m| m.location)
+    }
+
+    /// Get location information for an IP address along with its accuracy
+    pub fn get_match(&self, ip: IpAddr) -> Result<GeoMatch, GeoLocationError> {
         let city: geoip2::City = self.reader.lookup(ip)
             .map_err(|e| {
                 debug!("MaxMind lookup failed for IP {}: {}", ip, e);
@@ -78,11 +99,16 @@ impl GeoResolver {
             .and_then(|s| s.iso_code)
             .map(|s| s.to_string());
             
-        Ok(GeoLocation {
-            latitude,
-            longitude,
-            country_code,
-            region_code,
+        Ok(GeoMatch {
+            location: GeoLocation {
+                latitude,
+                longitude,
+                country_code,
+                region_code,
+                // City databases don't carry the autonomous system
+                asn: None,
+            },
+            accuracy_radius_km: location.accuracy_radius,
         })
     }
     
@@ -149,6 +175,7 @@ mod tests {
             longitude: -74.0060,
             country_code: Some("US".to_string()),
             region_code: Some("NY".to_string()),
+            asn: None,
         };
         
         // Los Angeles
@@ -157,6 +184,7 @@ mod tests {
             longitude: -118.2437,
             country_code: Some("US".to_string()),
             region_code: Some("CA".to_string()),
+            asn: None,
         };
         
         let distance = calculate_distance(&new_york, &los_angeles);
@@ -4,6 +4,7 @@ use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
 use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 
 /// Simple health status - binary available/unavailable
 #[derive(Debug, Clone)]
@@ -12,12 +13,95 @@ pub enum IpHealthStatus {
     Unavailable { since: SystemTime, reason: String },
 }
 
+/// Protocol used to probe the addresses of a record
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckProtocol {
+    /// The address accepts a TCP connection
+    Tcp,
+    /// A GET for `path` answers with `expected_status`, or any 2xx
+    Http {
+        path: String,
+        #[serde(default)]
+        expected_status: Option<u16>,
+    },
+}
+
+/// Health check configured for a user record. Every address of the record
+/// is probed separately and withdrawn from answers while it is failing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndpointCheck {
+    pub protocol: CheckProtocol,
+    /// Port to probe, defaults to the port of each record address
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default = "default_check_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_check_timeout")]
+    pub timeout_secs: u64,
+    /// Consecutive failures before an address is withdrawn
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    /// Consecutive successes before a withdrawn address is restored
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+}
+
+fn default_check_interval() -> u64 { 10 }
+fn default_check_timeout() -> u64 { 3 }
+fn default_unhealthy_threshold() -> u32 { 3 }
+fn default_healthy_threshold() -> u32 { 2 }
+
+impl EndpointCheck {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 || self.timeout_secs == 0 {
+            return Err("interval_secs and timeout_secs must be at least 1".to_string());
+        }
+        if self.timeout_secs > self.interval_secs {
+            return Err("timeout_secs can't be longer than interval_secs".to_string());
+        }
+        if self.unhealthy_threshold == 0 || self.healthy_threshold == 0 {
+            return Err("Thresholds must be at least 1".to_string());
+        }
+        if let CheckProtocol::Http { path, .. } = &self.protocol {
+            if !path.starts_with('/') {
+                return Err(format!("HTTP check path {path} must start with /"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Health of one address of a record with a health check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointHealth {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub last_error: Option<String>,
+    pub last_checked: Option<SystemTime>,
+}
+
+impl Default for EndpointHealth {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            last_error: None,
+            last_checked: None,
+        }
+    }
+}
+
 /// Repository for tracking IP health status
 #[allow(unused)]
 #[derive(Debug)]
 pub struct IpHealthRepository {
     /// Map of IP addresses to their health status
     health_map: HashMap<IpAddr, IpHealthStatus>,
+    /// Health of the addresses of records with a health check, by record domain
+    endpoint_map: HashMap<(String, IpAddr), EndpointHealth>,
     /// Maximum time since last heartbeat before a node is considered unhealthy
     heartbeat_timeout: Duration,
 }
@@ -26,6 +110,7 @@ impl IpHealthRepository {
     pub fn new(heartbeat_timeout: Duration) -> Self {
         Self {
             health_map: HashMap::new(),
+            endpoint_map: HashMap::new(),
             heartbeat_timeout,
         }
     }
@@ -90,6 +175,59 @@ impl IpHealthRepository {
             .collect()
     }
 
+    /// Record the result of a health check against `ip` for `domain`.
+    /// Returns true if the address was withdrawn or restored by this result.
+    pub fn record_check(&mut self, domain: &str, ip: IpAddr, result: Result<(), String>, check: &EndpointCheck) -> bool {
+        let health = self.endpoint_map.entry((domain.to_string(), ip)).or_default();
+        health.last_checked = Some(SystemTime::now());
+        match result {
+            Ok(()) => {
+                health.consecutive_failures = 0;
+                health.consecutive_successes += 1;
+                health.last_error = None;
+                if !health.healthy && health.consecutive_successes >= check.healthy_threshold {
+                    info!("Health check for {} at {} recovered, restoring it", domain, ip);
+                    health.healthy = true;
+                    return true;
+                }
+            }
+            Err(e) => {
+                health.consecutive_successes = 0;
+                health.consecutive_failures += 1;
+                health.last_error = Some(e);
+                if health.healthy && health.consecutive_failures >= check.unhealthy_threshold {
+                    warn!("Health check for {} at {} failing, withdrawing it: {:?}", domain, ip, health.last_error);
+                    health.healthy = false;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Check if an address of `domain` passes its health check. Addresses
+    /// that were never checked are available.
+    pub fn is_endpoint_available(&self, domain: &str, ip: &IpAddr) -> bool {
+        match self.endpoint_map.get(&(domain.to_string(), *ip)) {
+            Some(health) => health.healthy,
+            None => true,
+        }
+    }
+
+    /// Health of every checked address of `domain`
+    pub fn endpoint_statuses(&self, domain: &str) -> Vec<(IpAddr, EndpointHealth)> {
+        self.endpoint_map
+            .iter()
+            .filter(|((d, _), _)| d == domain)
+            .map(|((_, ip), health)| (*ip, health.clone()))
+            .collect()
+    }
+
+    /// Forget the check results of `domain`, restoring all of its addresses
+    pub fn clear_endpoints(&mut self, domain: &str) {
+        self.endpoint_map.retain(|(d, _), _| d != domain);
+    }
+
     /// Clear unavailable IPs that have been in that state longer than the specified duration
     pub fn clear_stale_unavailable(&mut self, stale_after: Duration) {
         let now = SystemTime::now();
@@ -252,4 +390,37 @@ mod tests {
         repo.mark_available(unhealthy_ip1);
         assert!(repo.is_available(&unhealthy_ip1));
     }
+
+    #[test]
+    fn test_endpoint_checks_withdraw_and_restore() {
+        let mut repo = IpHealthRepository::new(Duration::from_secs(60));
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+        let check = EndpointCheck {
+            protocol: CheckProtocol::Tcp,
+            port: None,
+            interval_secs: 10,
+            timeout_secs: 3,
+            unhealthy_threshold: 2,
+            healthy_threshold: 2,
+        };
+        assert!(check.validate().is_ok());
+
+        // One failure isn't enough to withdraw the address
+        assert!(!repo.record_check("app.example", ip, Err("refused".to_string()), &check));
+        assert!(repo.is_endpoint_available("app.example", &ip));
+        assert!(repo.record_check("app.example", ip, Err("refused".to_string()), &check));
+        assert!(!repo.is_endpoint_available("app.example", &ip));
+        // Other records served from the same IP are unaffected
+        assert!(repo.is_endpoint_available("other.example", &ip));
+
+        assert!(!repo.record_check("app.example", ip, Ok(()), &check));
+        assert!(!repo.is_endpoint_available("app.example", &ip));
+        assert!(repo.record_check("app.example", ip, Ok(()), &check));
+        assert!(repo.is_endpoint_available("app.example", &ip));
+
+        repo.record_check("app.example", ip, Err("refused".to_string()), &check);
+        repo.record_check("app.example", ip, Err("refused".to_string()), &check);
+        repo.clear_endpoints("app.example");
+        assert!(repo.is_endpoint_available("app.example", &ip));
+    }
 } 
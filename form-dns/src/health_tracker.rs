@@ -1,13 +1,17 @@
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
-use tokio::time;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
 use reqwest::Client;
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-use crate::health::SharedIpHealthRepository;
+use crate::health::{CheckProtocol, EndpointCheck, SharedIpHealthRepository};
+use crate::store::SharedStore;
+use form_types::state::{Response as StateResponse, Success};
 
 // Default values
 pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
@@ -47,6 +51,24 @@ pub struct FormStateResponse {
     pub nodes: Vec<NodeHeartbeat>,
 }
 
+/// Reason form-state gives for an instance's health
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceHealthReason {
+    pub message: String,
+}
+
+/// Health of an instance from form-state's `/instance/list/health`, only the
+/// fields failover needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceHealth {
+    pub instance_id: String,
+    pub formnet_ip: Option<IpAddr>,
+    /// `Healthy`, `Degraded` or `Unhealthy`
+    pub status: String,
+    #[serde(default)]
+    pub reasons: Vec<InstanceHealthReason>,
+}
+
 /// Health tracker service that monitors node health and updates the IP health repository
 #[allow(unused)]
 pub struct HealthTracker {
@@ -110,6 +132,16 @@ impl HealthTracker {
         for node in nodes {
             self.process_node_heartbeat(node).await?;
         }
+
+        // Instances are failed over on their own, on top of their nodes
+        match self.fetch_instance_health().await {
+            Ok(instances) => {
+                for instance in instances {
+                    self.process_instance_health(instance).await;
+                }
+            }
+            Err(e) => warn!("Unable to fetch instance health from form-state: {}", e),
+        }
         
         // Clean stale entries
         let mut repo = self.health_repo.write().await;
@@ -132,6 +164,40 @@ impl HealthTracker {
         Ok(response.nodes)
     }
     
+    /// Fetch the health of every instance from form-state API
+    async fn fetch_instance_health(&self) -> Result<Vec<InstanceHealth>, Box<dyn std::error::Error + Send + Sync>> {
+        let url = format!("{}/instance/list/health", self.form_state_api);
+        let response = self.http_client
+            .get(&url)
+            .send()
+            .await?
+            .json::<StateResponse<InstanceHealth>>()
+            .await?;
+
+        match response {
+            StateResponse::Success(Success::List(instances)) => Ok(instances),
+            StateResponse::Failure { reason } => Err(reason.unwrap_or_else(|| "unknown error".to_string()).into()),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Withdraw the formnet address of an unhealthy instance from answers,
+    /// restore it once the instance serves again
+    async fn process_instance_health(&self, instance: InstanceHealth) {
+        let Some(ip) = instance.formnet_ip else {
+            return;
+        };
+        let mut repo = self.health_repo.write().await;
+        if instance.status == "Unhealthy" {
+            if repo.is_available(&ip) {
+                let reasons: Vec<&str> = instance.reasons.iter().map(|reason| reason.message.as_str()).collect();
+                repo.mark_unavailable(ip, format!("Instance {} is unhealthy: {}", instance.instance_id, reasons.join(", ")));
+            }
+        } else if !repo.is_available(&ip) {
+            repo.mark_available(ip);
+        }
+    }
+
     /// Process a node heartbeat and update health status
     async fn process_node_heartbeat(&self, node: NodeHeartbeat) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         // Try to parse the public IP
@@ -193,10 +259,128 @@ pub async fn start_health_tracker(
     health_repo
 }
 
+/// Probes the addresses of user records that have a health check and
+/// withdraws the failing ones from answers until they recover
+pub struct EndpointChecker {
+    store: SharedStore,
+    health_repo: SharedIpHealthRepository,
+    http_client: Client,
+    /// When each record was last checked, by record domain
+    last_run: HashMap<String, Instant>,
+}
+
+impl EndpointChecker {
+    pub fn new(store: SharedStore, health_repo: SharedIpHealthRepository) -> Self {
+        Self {
+            store,
+            health_repo,
+            http_client: Client::new(),
+            last_run: HashMap::new(),
+        }
+    }
+
+    /// Start the endpoint checking service
+    pub async fn start_monitoring(&mut self) {
+        info!("Starting endpoint health check loop");
+        // Checks run on their own intervals, this only decides how often
+        // they are looked at
+        let mut interval = time::interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+            self.run_due_checks().await;
+        }
+    }
+
+    async fn run_due_checks(&mut self) {
+        let checked = self.store.read().await.checked_records();
+        self.last_run.retain(|domain, _| checked.iter().any(|(checked_domain, _, _)| checked_domain == domain));
+
+        let now = Instant::now();
+        let mut probes = JoinSet::new();
+        for (domain, record, check) in checked {
+            let due = match self.last_run.get(&domain) {
+                Some(last) => now.duration_since(*last) >= Duration::from_secs(check.interval_secs),
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+            self.last_run.insert(domain.clone(), now);
+
+            let host = domain.trim_start_matches("*.").to_string();
+            for addr in record.public_ip.iter().chain(record.formnet_ip.iter()) {
+                let target = SocketAddr::new(addr.ip(), check.port.unwrap_or(addr.port()));
+                let client = self.http_client.clone();
+                let domain = domain.clone();
+                let host = host.clone();
+                let check = check.clone();
+                probes.spawn(async move {
+                    let result = probe_endpoint(&client, &host, target, &check).await;
+                    (domain, target.ip(), check, result)
+                });
+            }
+        }
+
+        while let Some(joined) = probes.join_next().await {
+            match joined {
+                Ok((domain, ip, check, result)) => {
+                    debug!("Health check for {} at {}: {:?}", domain, ip, result);
+                    let mut repo = self.health_repo.write().await;
+                    repo.record_check(&domain, ip, result, &check);
+                }
+                Err(e) => error!("Health check task failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Runs a single check against `target`, `host` is sent as the HTTP host
+async fn probe_endpoint(client: &Client, host: &str, target: SocketAddr, check: &EndpointCheck) -> Result<(), String> {
+    let timeout = Duration::from_secs(check.timeout_secs);
+    match &check.protocol {
+        CheckProtocol::Tcp => match time::timeout(timeout, TcpStream::connect(target)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(format!("TCP connect failed: {e}")),
+            Err(_) => Err(format!("TCP connect timed out after {}s", check.timeout_secs)),
+        },
+        CheckProtocol::Http { path, expected_status } => {
+            let response = client
+                .get(format!("http://{target}{path}"))
+                .header(reqwest::header::HOST, host)
+                .timeout(timeout)
+                .send()
+                .await
+                .map_err(|e| format!("HTTP request failed: {e}"))?;
+            let status = response.status();
+            let passed = match expected_status {
+                Some(expected) => status.as_u16() == *expected,
+                None => status.is_success(),
+            };
+            if passed {
+                Ok(())
+            } else {
+                Err(format!("Unexpected HTTP status {status}"))
+            }
+        }
+    }
+}
+
+/// Start probing the user records in `store` that have a health check,
+/// recording the results in `health_repo`
+pub fn start_endpoint_checker(store: SharedStore, health_repo: SharedIpHealthRepository) {
+    info!("Starting endpoint health checker");
+    let mut checker = EndpointChecker::new(store, health_repo);
+    tokio::spawn(async move {
+        checker.start_monitoring().await;
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::net::Ipv4Addr;
+    use crate::health::IpHealthStatus;
 
     #[tokio::test]
     async fn test_process_node_heartbeat() {
@@ -248,4 +432,28 @@ mod tests {
         let ip2 = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
         assert!(!repo.is_available(&ip2));
     }
+
+    #[tokio::test]
+    async fn test_process_instance_health() {
+        let health_repo = crate::health::create_shared_repository(Duration::from_secs(60));
+        let tracker = HealthTracker::new("http://example.com".to_string(), health_repo.clone(), None, None, None);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7));
+        let instance = |status: &str| InstanceHealth {
+            instance_id: "instance1".to_string(),
+            formnet_ip: Some(ip),
+            status: status.to_string(),
+            reasons: vec![InstanceHealthReason { message: "Node node1 is down, 5 heartbeats missed".to_string() }],
+        };
+
+        tracker.process_instance_health(instance("Unhealthy")).await;
+        assert!(!health_repo.read().await.is_available(&ip));
+        match health_repo.read().await.get_status(&ip) {
+            Some(IpHealthStatus::Unavailable { reason, .. }) => assert!(reason.contains("heartbeats missed")),
+            other => panic!("expected the address to be withdrawn, got {:?}", other),
+        }
+
+        // Degraded instances keep serving
+        tracker.process_instance_health(instance("Degraded")).await;
+        assert!(health_repo.read().await.is_available(&ip));
+    }
 } 
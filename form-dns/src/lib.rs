@@ -1,14 +1,30 @@
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
 pub mod store;
 pub mod proxy;
 pub mod authority;
 pub mod api;
+pub mod auth;
 pub mod geolocation;
 pub mod geo_resolver;
+pub mod geo_provider;
 pub mod geo_util;
 pub mod health;
 pub mod health_tracker;
+pub mod dnssec;
+pub mod streams;
+pub mod query_policy;
+pub mod analytics;
+pub mod bulk;
+
+/// Returns true if `ip` is a formnet overlay address, either in the IPv4
+/// overlay (10.0.0.0/8) or in an IPv6 unique local overlay (fc00::/7).
+pub fn is_formnet_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.octets()[0] == 10,
+        IpAddr::V6(v6) => (v6.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
 
 pub fn resolvectl_domain() -> Result<(), Box<dyn std::error::Error>> {
     let output = std::process::Command::new("resolvectl")
@@ -28,7 +44,7 @@ pub fn resolvectl_domain() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-pub fn resolvectl_dns(ips: Vec<Ipv4Addr>) -> Result<(), Box<dyn std::error::Error>> {
+pub fn resolvectl_dns(ips: Vec<IpAddr>) -> Result<(), Box<dyn std::error::Error>> {
     let mut command = std::process::Command::new("resolvectl");
     command.arg("dns").arg("-p").arg("5453").arg("formnet");
 
@@ -78,3 +94,16 @@ pub fn resolvectl_flush_cache() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_formnet_ip() {
+        assert!(is_formnet_ip("10.42.0.5".parse().unwrap()));
+        assert!(is_formnet_ip("fd3a:1b2c:4d5e::1".parse().unwrap()));
+        assert!(!is_formnet_ip("192.168.1.1".parse().unwrap()));
+        assert!(!is_formnet_ip("2606:4700:4700::1111".parse().unwrap()));
+    }
+}
@@ -6,7 +6,13 @@ use form_dns::api::serve_api;
 use form_dns::proxy::IntegratedProxy;
 use form_dns::store::{DnsStore, SharedStore};
 use form_dns::authority::FormAuthority;
+use form_dns::dnssec::ZoneSigners;
 use form_dns::health_tracker;
+use form_dns::geo_provider::{load_provider_configs, spawn_reload_watcher, GEO_PROVIDERS_PATH, RELOAD_CHECK_INTERVAL};
+use form_dns::geo_resolver::GeoResolverConfig;
+use form_dns::geo_util;
+use form_dns::query_policy::{init_query_policy, load_query_policy, QUERY_POLICY_PATH};
+use form_dns::analytics::{init_dns_analytics, DnsAnalytics};
 use form_rplb::config::ProxyConfig;
 use form_rplb::resolver::TlsManager;
 use tokio::net::UdpSocket;
@@ -24,7 +30,8 @@ async fn main() -> anyhow::Result<()> {
     resolvectl_domain().map_err(|e| anyhow::anyhow!(e.to_string()))?;
 
     let (tx, rx) = tokio::sync::mpsc::channel(1024);
-    let dns_store = DnsStore::new(tx.clone());
+    let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(1024);
+    let dns_store = DnsStore::new(tx.clone()).with_stream_sender(stream_tx);
     
     log::info!("Set up DNS store");
 
@@ -41,9 +48,28 @@ async fn main() -> anyhow::Result<()> {
     // Connect health repository to DNS store
     let dns_store_with_health = dns_store.with_health_repository(health_repo.clone());
     let store: SharedStore = Arc::new(RwLock::new(dns_store_with_health));
+    health_tracker::start_endpoint_checker(store.clone(), health_repo.clone());
     
     log::info!("Connected health repository to DNS store");
 
+    // Geolocation providers saved through the API take precedence over the default database
+    geo_util::init_geo_resolver(GeoResolverConfig {
+        providers: load_provider_configs(GEO_PROVIDERS_PATH).unwrap_or_default(),
+        ..GeoResolverConfig::default()
+    });
+    spawn_reload_watcher(geo_util::get_geo_resolver().providers(), RELOAD_CHECK_INTERVAL);
+
+    // Rate limits and policy zones saved through the API, the default limits otherwise
+    init_query_policy(load_query_policy(QUERY_POLICY_PATH).unwrap_or_default());
+
+    let analytics = DnsAnalytics::from_env();
+    if analytics.is_enabled() {
+        log::info!("Keeping {} hours of DNS query analytics", analytics.retention_hours());
+    } else {
+        log::warn!("DNS query analytics are turned off");
+    }
+    init_dns_analytics(analytics);
+
     // Add bootstrap domain configuration
     {
         log::info!("Configuring bootstrap domain...");
@@ -86,7 +112,7 @@ async fn main() -> anyhow::Result<()> {
         if let Err(e) = reverse_proxy.bind().await {
             eprintln!("Error attempting to bind http and/or https listener: {e}");
         };
-        if let Err(e) = reverse_proxy.run(rx).await {
+        if let Err(e) = reverse_proxy.run(rx, stream_rx).await {
             eprintln!("Error in run process for Reverse Proxy: {e}");
         }
     });
@@ -113,9 +139,15 @@ async fn main() -> anyhow::Result<()> {
     log::warn!("Setting authority origin to root...");
     let origin = Name::root();
     
+    let zone_signers = ZoneSigners::from_env()?;
+    if zone_signers.is_empty() {
+        log::warn!("No DNSSEC zones configured, answers are unsigned");
+    }
+
     // Create the authority with health repository integration
     let auth = FormAuthority::new(origin, store.clone(), fallback_client)
-        .with_health_repository(health_repo);
+        .with_health_repository(health_repo)
+        .with_dnssec(zone_signers);
 
     log::info!("Created FormAuthority with health repository integration");
     log::debug!("Wrapping authority in an Atomic Reference Counter...");
@@ -1,14 +1,15 @@
 use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::{Mutex, mpsc::Receiver}};
-use form_rplb::{backend::Backend, config::ProxyConfig, error::ProxyError, protocol::{Protocol, TlsConfig}, proxy::{DomainProtocols, ReverseProxy}, resolver::TlsManager};
+use form_rplb::{backend::Backend, balancer::LoadBalancingStrategy, config::ProxyConfig, error::ProxyError, protocol::{Protocol, TlsConfig}, proxy::{DomainProtocols, ReverseProxy}, resolver::TlsManager, stream::StreamProxy};
 use tokio::net::TcpListener;
 
-use crate::store::{FormDnsRecord, SharedStore};
+use crate::store::{FlattenedTarget, FormDnsRecord, SharedStore};
 
 pub struct IntegratedProxy {
     pub store: SharedStore,
     pub reverse_proxy: Arc<ReverseProxy>,
     pub tls_manager: Arc<Mutex<TlsManager>>,
+    pub streams: Arc<StreamProxy>,
     domain_protocols: Arc<Mutex<HashMap<String, DomainProtocols>>>,
     http_listener: Option<TcpListener>,
     tls_listener: Option<TcpListener>,
@@ -20,6 +21,7 @@ impl Clone for IntegratedProxy {
             store: self.store.clone(),
             reverse_proxy: self.reverse_proxy.clone(),
             tls_manager: self.tls_manager.clone(),
+            streams: self.streams.clone(),
             domain_protocols: self.domain_protocols.clone(),
             http_listener: None,
             tls_listener: None,
@@ -34,11 +36,13 @@ impl IntegratedProxy {
         config: ProxyConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         log::info!("Building ReverseProxy from ProxyConfig");
+        let streams = Arc::new(StreamProxy::new(config.connection_timeout, config.buffer_size));
         let reverse_proxy = Arc::new(ReverseProxy::new(config));
         Ok(Self {
             store,
             reverse_proxy,
             tls_manager: Arc::new(Mutex::new(tls_manager)),
+            streams,
             domain_protocols: Arc::new(Mutex::new(HashMap::new())),
             http_listener: None,
             tls_listener: None,
@@ -69,6 +73,12 @@ impl IntegratedProxy {
         Ok(())
     }
 
+    /// Changes how a domain's connections are spread over its instances,
+    /// takes effect for the next connection
+    pub async fn configure_load_balancing(&self, domain: &str, strategy: LoadBalancingStrategy) {
+        self.reverse_proxy.set_load_balancing(domain, strategy).await;
+    }
+
     async fn create_backends_for_domain(
         &self,
         domain: &str,
@@ -116,7 +126,7 @@ impl IntegratedProxy {
 
     pub async fn add_routes(&self, domain: &str) -> Result<(), Box<dyn std::error::Error>> {
         let dns_guard = self.store.read().await;
-        if let Some(record) = dns_guard.get(domain) {
+        if let Some(FlattenedTarget::Local(record)) = dns_guard.flatten(domain) {
             let mut should_enable_tls = domain.ends_with(".fog") || self.tls_manager.lock().await.domains.contains_key(domain);
             if !should_enable_tls {
                 if record.ssl_cert {
@@ -142,6 +152,22 @@ impl IntegratedProxy {
         Ok(())
     }
 
+    /// Brings the listener of a stream port in line with the routes the store
+    /// holds for it, starting, updating or stopping it
+    pub async fn sync_stream_port(&self, listen_port: u16) {
+        let mapping = self.store.read().await.stream_mapping(listen_port);
+        match mapping {
+            Some(mapping) => {
+                if let Err(e) = self.streams.set_mapping(mapping).await {
+                    log::error!("Unable to serve stream port {listen_port}: {e}");
+                }
+            }
+            None => {
+                self.streams.remove_mapping(listen_port).await;
+            }
+        }
+    }
+
     pub async fn handle_http(&self, mut stream: TcpStream) -> Result<(), ProxyError> {
         let buffer_size = self.reverse_proxy.config().buffer_size;
         let mut buffer = vec![0; buffer_size];
@@ -200,10 +226,19 @@ impl IntegratedProxy {
         }
     }
 
-    pub async fn run(&self, mut rx: Receiver<FormDnsRecord>) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn run(
+        &self,
+        mut rx: Receiver<FormDnsRecord>,
+        mut stream_rx: Receiver<u16>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let http_listener = self.http_listener.as_ref().ok_or("HTTP Listener not initialized")?;
         let tls_listener = self.tls_listener.as_ref().ok_or("TLS Listener not initialized")?;
 
+        let ports: Vec<u16> = self.store.read().await.stream_routes(None).iter().map(|route| route.listen_port).collect();
+        for port in ports {
+            self.sync_stream_port(port).await;
+        }
+
         loop {
             tokio::select! {
                 Ok((stream, _)) = http_listener.accept() => {
@@ -227,6 +262,14 @@ impl IntegratedProxy {
                     if let Err(e) = self.add_routes(&domain).await {
                         eprintln!("Error trying to add new route to proxy for domain {domain}: {e}");
                     }
+                    // Stream routes follow the record's addresses
+                    let ports = self.store.read().await.stream_ports(&domain);
+                    for port in ports {
+                        self.sync_stream_port(port).await;
+                    }
+                }
+                Some(port) = stream_rx.recv() => {
+                    self.sync_stream_port(port).await;
                 }
             }
         }
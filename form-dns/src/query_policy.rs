@@ -0,0 +1,444 @@
+//! Abuse mitigation for the resolver: per-client query rate limits and
+//! response policy zones.
+//!
+//! Every client address gets a token bucket refilled at
+//! `queries_per_second`, queries that find it empty are refused. Response
+//! policy zones list domains to answer with NXDOMAIN or an empty answer
+//! instead of resolving them, either for every client or only for formnet
+//! clients, the resolvers inside VMs. Both kinds of violation are logged and
+//! the most recent ones kept for the API.
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use crate::is_formnet_ip;
+
+/// Where the policy set through the API is saved
+pub const QUERY_POLICY_PATH: &str = "/etc/formation/dns/query_policy.json";
+
+pub const DEFAULT_QUERIES_PER_SECOND: u32 = 50;
+pub const DEFAULT_BURST: u32 = 100;
+
+/// Violations kept for `/policy/violations`
+pub const MAX_VIOLATIONS: usize = 1000;
+
+/// Clients tracked before idle buckets are dropped
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// A rate limited client is logged at most once per window, with the number
+/// of queries refused since
+const RATE_LIMIT_LOG_WINDOW: Duration = Duration::from_secs(10);
+
+static QUERY_POLICY: OnceCell<QueryPolicy> = OnceCell::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Sustained queries per second allowed per client address, 0 disables
+    /// rate limiting
+    pub queries_per_second: u32,
+    /// Queries a client can make at once after being idle
+    pub burst: u32,
+    /// Addresses that are never limited. Loopback addresses never are.
+    #[serde(default)]
+    pub exempt: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self { queries_per_second: DEFAULT_QUERIES_PER_SECOND, burst: DEFAULT_BURST, exempt: vec![] }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneScope {
+    #[default]
+    All,
+    /// Only clients with a formnet address, the resolvers in VMs
+    Formnet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    /// Answer that the name doesn't exist
+    Nxdomain,
+    /// Answer that the name has no records of the queried type
+    Nodata,
+    /// Resolve normally, to exempt names from a broader rule
+    Passthru,
+}
+
+/// `example.com` matches only that name, `*.example.com` every name below it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub pattern: String,
+    pub action: PolicyAction,
+}
+
+impl PolicyRule {
+    /// How specific the match of `name` is, None if it doesn't match. An
+    /// exact match beats any wildcard.
+    fn match_len(&self, name: &str) -> Option<usize> {
+        let pattern = normalize_name(&self.pattern);
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => name.strip_suffix(suffix)
+                .filter(|rest| rest.ends_with('.'))
+                .map(|_| suffix.len()),
+            None => (name == pattern).then_some(usize::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyZone {
+    pub name: String,
+    #[serde(default)]
+    pub scope: ZoneScope,
+    pub rules: Vec<PolicyRule>,
+}
+
+impl PolicyZone {
+    fn applies_to(&self, client: Option<IpAddr>) -> bool {
+        match self.scope {
+            ZoneScope::All => true,
+            ZoneScope::Formnet => client.map_or(false, is_formnet_ip),
+        }
+    }
+
+    /// The most specific rule matching `name`
+    fn rule_for(&self, name: &str) -> Option<&PolicyRule> {
+        self.rules.iter()
+            .filter_map(|rule| Some((rule.match_len(name)?, rule)))
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, rule)| rule)
+    }
+}
+
+/// What is saved to `QUERY_POLICY_PATH` and set through the API
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryPolicyConfig {
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Checked in order, the first zone with a matching rule decides
+    #[serde(default)]
+    pub zones: Vec<PolicyZone>,
+}
+
+impl QueryPolicyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rate_limit.queries_per_second > 0 && self.rate_limit.burst == 0 {
+            return Err("burst must be at least 1 when rate limiting is enabled".to_string());
+        }
+        for zone in &self.zones {
+            if zone.name.trim().is_empty() {
+                return Err("Policy zones need a name".to_string());
+            }
+            for rule in &zone.rules {
+                let pattern = normalize_name(&rule.pattern);
+                let labels = pattern.strip_prefix("*.").unwrap_or(&pattern);
+                if labels.is_empty() || labels.contains('*') || labels.split('.').any(str::is_empty) {
+                    return Err(format!("Invalid pattern {} in zone {}", rule.pattern, zone.name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryDecision {
+    Allow,
+    /// The client is over its rate limit, the query is refused
+    RateLimited,
+    Blocked { zone: String, action: PolicyAction },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum ViolationKind {
+    /// `refused` queries were refused since the client was last logged
+    RateLimited { refused: u64 },
+    Blocked { zone: String, pattern: String, action: PolicyAction },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    /// Unix timestamp in seconds
+    pub at: u64,
+    pub client: Option<IpAddr>,
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ViolationKind,
+}
+
+/// Counters since the service started
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryPolicyStats {
+    pub rate_limited: u64,
+    pub blocked: u64,
+    pub tracked_clients: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Queries refused since the client was last logged
+    refused: u64,
+    logged: Option<Instant>,
+}
+
+pub struct QueryPolicy {
+    config: RwLock<QueryPolicyConfig>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    violations: Mutex<VecDeque<Violation>>,
+    stats: Mutex<QueryPolicyStats>,
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_lowercase()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl QueryPolicy {
+    pub fn new(config: QueryPolicyConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            buckets: Mutex::new(HashMap::new()),
+            violations: Mutex::new(VecDeque::new()),
+            stats: Mutex::new(QueryPolicyStats::default()),
+        }
+    }
+
+    pub fn config(&self) -> QueryPolicyConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the policy. Buckets are kept so a change doesn't reset
+    /// clients that are being limited.
+    pub fn configure(&self, config: QueryPolicyConfig) -> Result<(), String> {
+        config.validate()?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> QueryPolicyStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.tracked_clients = self.buckets.lock().unwrap().len();
+        stats
+    }
+
+    /// The most recent violations, newest first
+    pub fn violations(&self, limit: usize) -> Vec<Violation> {
+        self.violations.lock().unwrap().iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Decides how to answer a query for `name` from `client`. Queries
+    /// without a client address are never rate limited.
+    pub fn check(&self, client: Option<IpAddr>, name: &str, now: Instant) -> QueryDecision {
+        let config = self.config.read().unwrap();
+        let name = normalize_name(name);
+        if let Some(ip) = client {
+            if !self.take_token(&config.rate_limit, ip, &name, now) {
+                return QueryDecision::RateLimited;
+            }
+        }
+
+        let matched = config.zones.iter()
+            .filter(|zone| zone.applies_to(client))
+            .find_map(|zone| Some((zone, zone.rule_for(&name)?)));
+        match matched {
+            Some((zone, rule)) if rule.action != PolicyAction::Passthru => {
+                log::warn!("Blocked query for {name} from {client:?} by {} in policy zone {}", rule.pattern, zone.name);
+                self.stats.lock().unwrap().blocked += 1;
+                self.record(Violation {
+                    at: unix_now(),
+                    client,
+                    name,
+                    kind: ViolationKind::Blocked {
+                        zone: zone.name.clone(),
+                        pattern: rule.pattern.clone(),
+                        action: rule.action,
+                    },
+                });
+                QueryDecision::Blocked { zone: zone.name.clone(), action: rule.action }
+            }
+            _ => QueryDecision::Allow,
+        }
+    }
+
+    fn take_token(&self, limit: &RateLimitConfig, ip: IpAddr, name: &str, now: Instant) -> bool {
+        if limit.queries_per_second == 0 || ip.is_loopback() || limit.exempt.contains(&ip) {
+            return true;
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&ip) {
+            // A bucket that would have refilled holds nothing worth keeping
+            let refill = Duration::from_secs_f64(f64::from(limit.burst) / f64::from(limit.queries_per_second));
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < refill);
+        }
+        let burst = f64::from(limit.burst);
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now, refused: 0, logged: None });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * f64::from(limit.queries_per_second)).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        bucket.refused += 1;
+        self.stats.lock().unwrap().rate_limited += 1;
+        let due = bucket.logged.map_or(true, |logged| now.saturating_duration_since(logged) >= RATE_LIMIT_LOG_WINDOW);
+        if due {
+            let refused = std::mem::take(&mut bucket.refused);
+            bucket.logged = Some(now);
+            drop(buckets);
+            log::warn!("Rate limiting {ip}, {refused} queries refused, latest for {name}");
+            self.record(Violation {
+                at: unix_now(),
+                client: Some(ip),
+                name: name.to_string(),
+                kind: ViolationKind::RateLimited { refused },
+            });
+        }
+        false
+    }
+
+    fn record(&self, violation: Violation) {
+        let mut violations = self.violations.lock().unwrap();
+        if violations.len() >= MAX_VIOLATIONS {
+            violations.pop_front();
+        }
+        violations.push_back(violation);
+    }
+}
+
+/// Initialize the global query policy, false if it already was
+pub fn init_query_policy(config: QueryPolicyConfig) -> bool {
+    QUERY_POLICY.set(QueryPolicy::new(config)).is_ok()
+}
+
+/// The global query policy, the default one if it wasn't initialized
+pub fn get_query_policy() -> &'static QueryPolicy {
+    QUERY_POLICY.get_or_init(|| QueryPolicy::new(QueryPolicyConfig::default()))
+}
+
+pub fn load_query_policy(path: impl AsRef<Path>) -> Option<QueryPolicyConfig> {
+    let contents = std::fs::read_to_string(path.as_ref()).ok()?;
+    match serde_json::from_str::<QueryPolicyConfig>(&contents) {
+        Ok(config) if config.validate().is_ok() => Some(config),
+        Ok(config) => {
+            log::error!("Ignoring invalid query policy in {}: {:?}", path.as_ref().display(), config.validate().err());
+            None
+        }
+        Err(e) => {
+            log::error!("Ignoring invalid query policy in {}: {e}", path.as_ref().display());
+            None
+        }
+    }
+}
+
+pub fn save_query_policy(path: impl AsRef<Path>, config: &QueryPolicyConfig) -> std::io::Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, action: PolicyAction) -> PolicyRule {
+        PolicyRule { pattern: pattern.to_string(), action }
+    }
+
+    #[test]
+    fn test_rate_limit_refills() {
+        let policy = QueryPolicy::new(QueryPolicyConfig {
+            rate_limit: RateLimitConfig { queries_per_second: 2, burst: 3, exempt: vec!["192.0.2.9".parse().unwrap()] },
+            zones: vec![],
+        });
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(policy.check(Some(client), "example.com.", start), QueryDecision::Allow);
+        }
+        assert_eq!(policy.check(Some(client), "example.com.", start), QueryDecision::RateLimited);
+        assert_eq!(policy.check(Some(client), "example.com.", start), QueryDecision::RateLimited);
+        // Half a second refills one query
+        let later = start + Duration::from_millis(500);
+        assert_eq!(policy.check(Some(client), "example.com.", later), QueryDecision::Allow);
+        assert_eq!(policy.check(Some(client), "example.com.", later), QueryDecision::RateLimited);
+
+        // Other clients have their own bucket, exempt and loopback clients none
+        assert_eq!(policy.check(Some("192.0.2.2".parse().unwrap()), "example.com.", start), QueryDecision::Allow);
+        for _ in 0..10 {
+            assert_eq!(policy.check(Some("192.0.2.9".parse().unwrap()), "example.com.", start), QueryDecision::Allow);
+            assert_eq!(policy.check(Some("127.0.0.1".parse().unwrap()), "example.com.", start), QueryDecision::Allow);
+        }
+
+        let stats = policy.stats();
+        assert_eq!(stats.rate_limited, 3);
+        assert_eq!(stats.tracked_clients, 2);
+        // Only the first refusal of the window is recorded
+        let violations = policy.violations(10);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, ViolationKind::RateLimited { refused: 1 });
+    }
+
+    #[test]
+    fn test_policy_zones() {
+        let policy = QueryPolicy::new(QueryPolicyConfig {
+            rate_limit: RateLimitConfig { queries_per_second: 0, ..Default::default() },
+            zones: vec![
+                PolicyZone {
+                    name: "vm-malware".to_string(),
+                    scope: ZoneScope::Formnet,
+                    rules: vec![
+                        rule("*.malware.example", PolicyAction::Nxdomain),
+                        rule("cdn.malware.example", PolicyAction::Passthru),
+                    ],
+                },
+                PolicyZone {
+                    name: "global".to_string(),
+                    scope: ZoneScope::All,
+                    rules: vec![rule("tracker.example", PolicyAction::Nodata)],
+                },
+            ],
+        });
+        let vm: IpAddr = "10.0.0.5".parse().unwrap();
+        let public: IpAddr = "203.0.113.7".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(
+            policy.check(Some(vm), "C2.Malware.Example.", now),
+            QueryDecision::Blocked { zone: "vm-malware".to_string(), action: PolicyAction::Nxdomain }
+        );
+        // The wildcard doesn't cover the zone's own name, and an exact passthru wins
+        assert_eq!(policy.check(Some(vm), "malware.example.", now), QueryDecision::Allow);
+        assert_eq!(policy.check(Some(vm), "cdn.malware.example.", now), QueryDecision::Allow);
+        // Formnet zones don't apply to other clients
+        assert_eq!(policy.check(Some(public), "c2.malware.example.", now), QueryDecision::Allow);
+        assert_eq!(
+            policy.check(Some(public), "tracker.example.", now),
+            QueryDecision::Blocked { zone: "global".to_string(), action: PolicyAction::Nodata }
+        );
+        assert_eq!(policy.check(None, "sub.tracker.example.", now), QueryDecision::Allow);
+        assert_eq!(policy.stats().blocked, 2);
+        assert_eq!(policy.violations(1)[0].name, "tracker.example");
+
+        let mut invalid = policy.config();
+        invalid.zones[1].rules.push(rule("bad.*.example", PolicyAction::Nxdomain));
+        assert!(policy.configure(invalid).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use std::collections::hash_map::{Entry, Iter};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::collections::HashMap;
 use tokio::sync::{RwLock, mpsc::Sender};
 use std::sync::Arc;
@@ -12,8 +12,10 @@ use trust_dns_client::rr::DNSClass;
 use std::str::FromStr;
 use std::time::Duration;
 
-use crate::resolvectl_dns;
-use crate::health::SharedIpHealthRepository;
+use crate::{is_formnet_ip, resolvectl_dns};
+use crate::health::{EndpointCheck, SharedIpHealthRepository};
+use crate::streams::{build_mapping, StreamRoute};
+use form_rplb::stream::StreamMapping;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct FormDnsRecord {
@@ -28,6 +30,110 @@ pub struct FormDnsRecord {
     pub verification_timestamp: Option<u64>,
 }
 
+impl FormDnsRecord {
+    /// A new record for `/record/create`. A records take IPv4 and AAAA
+    /// records IPv6 addresses, split into formnet and public ones, CNAME
+    /// records a target.
+    pub fn build(
+        domain: &str,
+        record_type: RecordType,
+        ip_addr: Vec<SocketAddr>,
+        cname_target: Option<String>,
+        ssl_cert: bool,
+    ) -> Result<Self, String> {
+        let (formnet_ip, public_ip, cname_target) = match record_type {
+            RecordType::A => {
+                if ip_addr.is_empty() {
+                    return Err("A Record update requires an IP Address be provided".to_string());
+                }
+                if ip_addr.iter().any(|addr| !addr.is_ipv4()) {
+                    return Err("IPV6 Addresses are not valid for A record".to_string());
+                }
+                let (formnet_ip, public_ip) = ip_addr.into_iter().partition(|addr| is_formnet_ip(addr.ip()));
+                (formnet_ip, public_ip, None)
+            }
+            RecordType::AAAA => {
+                if ip_addr.is_empty() {
+                    return Err("AAAA Record update requires an IP address to be provided".to_string());
+                }
+                if ip_addr.iter().any(|addr| !addr.is_ipv6()) {
+                    return Err("AAAA Record requires a V6 IP Address".to_string());
+                }
+                let (formnet_ip, public_ip) = ip_addr.into_iter().partition(|addr| is_formnet_ip(addr.ip()));
+                (formnet_ip, public_ip, None)
+            }
+            RecordType::CNAME => {
+                if cname_target.is_none() {
+                    return Err("CNAME Record update requires a CNAME target be provided".to_string());
+                }
+                (vec![], vec![], cname_target)
+            }
+            _ => return Err(format!("Sorry, the record type {record_type} is not currently supported")),
+        };
+        Ok(Self {
+            domain: domain.to_string(),
+            record_type,
+            formnet_ip,
+            public_ip,
+            cname_target,
+            ssl_cert,
+            ttl: 3600,
+            verification_status: Some(VerificationStatus::NotVerified),
+            verification_timestamp: None,
+        })
+    }
+
+    /// Applies `/record/:domain/update`. A records replace their addresses
+    /// with `replace`, other updates add to them.
+    pub fn apply_update(
+        &mut self,
+        replace: bool,
+        record_type: RecordType,
+        ip_addr: Vec<SocketAddr>,
+        cname_target: Option<String>,
+        ssl_cert: bool,
+    ) -> Result<(), String> {
+        match record_type {
+            RecordType::A => {
+                if ip_addr.is_empty() {
+                    return Err("A Record update must include an IP Address".to_string());
+                }
+                if ip_addr.iter().any(|addr| !addr.is_ipv4()) {
+                    return Err("A Records require an IPV4 address".to_string());
+                }
+                let (formnet_ip, public_ip): (Vec<SocketAddr>, Vec<SocketAddr>) = ip_addr.into_iter()
+                    .partition(|addr| is_formnet_ip(addr.ip()));
+                if replace {
+                    self.formnet_ip = formnet_ip;
+                    self.public_ip = public_ip;
+                } else {
+                    self.formnet_ip.extend(formnet_ip);
+                    self.public_ip.extend(public_ip);
+                }
+            }
+            RecordType::AAAA => {
+                if ip_addr.is_empty() {
+                    return Err("AAAA Record updates must include an IP Address".to_string());
+                }
+                let (formnet_ip, public_ip): (Vec<SocketAddr>, Vec<SocketAddr>) = ip_addr.into_iter()
+                    .partition(|addr| is_formnet_ip(addr.ip()));
+                self.formnet_ip.extend(formnet_ip);
+                self.public_ip.extend(public_ip);
+            }
+            RecordType::CNAME => {
+                if cname_target.is_none() {
+                    return Err("CNAME Record update must include a CNAME target".to_string());
+                }
+                self.cname_target = cname_target;
+            }
+            _ => return Err(format!("Sorry, the record type {record_type} is not currently supported")),
+        }
+        self.record_type = record_type;
+        self.ssl_cert = ssl_cert;
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum VerificationStatus {
     Pending,
@@ -44,12 +150,37 @@ pub enum FormTarget {
     None
 }
 
+/// Longest chain of CNAME records followed when flattening
+pub const MAX_CNAME_CHAIN: usize = 8;
+
+/// Where a CNAME chain ends up
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlattenedTarget {
+    /// A record held by this store with addresses to answer with
+    Local(FormDnsRecord),
+    /// A name outside of the store that has to be resolved upstream
+    External(String),
+}
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct DnsStore {
-    servers: Vec<Ipv4Addr>,
+    servers: Vec<IpAddr>,
     records: HashMap<String, FormDnsRecord>,
+    /// Health checks for user records, by record domain
+    #[serde(default)]
+    health_checks: HashMap<String, EndpointCheck>,
+    /// Address of the account that owns each record, by record domain.
+    /// Records created by the node's own services have none.
+    #[serde(default)]
+    owners: HashMap<String, String>,
+    /// Non-HTTP services exposed through the stream proxy
+    #[serde(default)]
+    stream_routes: Vec<StreamRoute>,
     #[serde(skip)]
     sender: Option<Sender<FormDnsRecord>>,
+    /// Tells the proxy which listen ports have to be reconfigured
+    #[serde(skip)]
+    stream_sender: Option<Sender<u16>>,
     #[serde(skip)]
     health_repository: Option<SharedIpHealthRepository>,
 }
@@ -59,11 +190,20 @@ impl DnsStore {
         Self {
             servers: Vec::new(),
             records: HashMap::new(),
+            health_checks: HashMap::new(),
+            owners: HashMap::new(),
+            stream_routes: Vec::new(),
             sender: Some(sender),
+            stream_sender: None,
             health_repository: None,
         }
     }
 
+    pub fn with_stream_sender(mut self, stream_sender: Sender<u16>) -> Self {
+        self.stream_sender = Some(stream_sender);
+        self
+    }
+
     pub fn with_health_repository(mut self, health_repository: SharedIpHealthRepository) -> Self {
         self.health_repository = Some(health_repository);
         self
@@ -73,7 +213,7 @@ impl DnsStore {
         self.health_repository.clone()
     }
 
-    pub fn add_server(&mut self, server: Ipv4Addr) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn add_server(&mut self, server: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
         self.servers.push(server);
         let all_servers = self.servers.clone();
         resolvectl_dns(all_servers)?;
@@ -93,26 +233,14 @@ impl DnsStore {
     }
 
     pub fn lookup(&self, domain: &str, src: IpAddr) -> FormTarget {
-        let key = domain.trim_end_matches('.').to_lowercase(); 
-        let record = self.records.get(&key);
-        if let Some(rec) = record {
+        let record = self.resolve(domain);
+        if let Some(rec) = &record {
             match rec.record_type {
                 RecordType::A => {
-                    match src {
-                        IpAddr::V4(addr) => {
-                            if addr.octets()[0] == 10 {
-                                if !rec.formnet_ip.is_empty() {
-                                    return FormTarget::A(rec.formnet_ip.clone())
-                                } else if !rec.public_ip.is_empty() {
-                                    return FormTarget::A(rec.public_ip.clone())
-                                }
-                            } else if !rec.public_ip.is_empty() {
-                                return FormTarget::A(rec.public_ip.clone())
-                            }
-                        }
-                        IpAddr::V6(_) => if !rec.public_ip.is_empty() {
-                            return FormTarget::A(rec.public_ip.clone())
-                        }
+                    if is_formnet_ip(src) && !rec.formnet_ip.is_empty() {
+                        return FormTarget::A(rec.formnet_ip.clone())
+                    } else if !rec.public_ip.is_empty() {
+                        return FormTarget::A(rec.public_ip.clone())
                     }
                 }
                 RecordType::CNAME => {
@@ -121,29 +249,12 @@ impl DnsStore {
                     }
                 }
                 RecordType::AAAA => {
-                    match src {
-                        IpAddr::V4(addr) => {
-                            if addr.octets()[0] == 10 {
-                                if !rec.formnet_ip.is_empty() {
-                                    let mut ips = rec.formnet_ip.clone();
-                                    if !rec.public_ip.is_empty(){
-                                        ips.extend(rec.public_ip.clone());
-                                    }
-                                    return FormTarget::AAAA(ips)
-                                } else if !rec.public_ip.is_empty() {
-                                    return FormTarget::AAAA(rec.public_ip.clone())
-                                }
-                            } else {
-                                if !rec.public_ip.is_empty() {
-                                    return FormTarget::AAAA(rec.public_ip.clone())
-                                }
-                            }
-                        }
-                        IpAddr::V6(_) => {
-                            if !rec.public_ip.is_empty() {
-                                return FormTarget::AAAA(rec.public_ip.clone())
-                            }
-                        }
+                    if is_formnet_ip(src) && !rec.formnet_ip.is_empty() {
+                        let mut ips = rec.formnet_ip.clone();
+                        ips.extend(rec.public_ip.clone());
+                        return FormTarget::AAAA(ips)
+                    } else if !rec.public_ip.is_empty() {
+                        return FormTarget::AAAA(rec.public_ip.clone())
                     }
                 }
                 _ => return FormTarget::None
@@ -156,10 +267,161 @@ impl DnsStore {
         self.records.get(domain).cloned()
     }
 
+    /// Finds the record for `domain`, falling back to the closest enclosing
+    /// wildcard, so `*.app.example` answers for `api.app.example` and
+    /// `a.b.app.example` unless they have records of their own
+    pub fn resolve(&self, domain: &str) -> Option<FormDnsRecord> {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        if let Some(record) = self.records.get(&key) {
+            return Some(record.clone());
+        }
+
+        let labels: Vec<&str> = key.split('.').collect();
+        (1..labels.len()).find_map(|i| {
+            self.records.get(&format!("*.{}", labels[i..].join("."))).cloned()
+        })
+    }
+
+    /// Follows CNAME records held by this store until one with addresses is
+    /// found. Lets apex domains, which can't have a CNAME next to their other
+    /// records, answer address queries with the addresses of the target.
+    pub fn flatten(&self, domain: &str) -> Option<FlattenedTarget> {
+        let mut record = self.resolve(domain)?;
+        for _ in 0..MAX_CNAME_CHAIN {
+            if record.record_type != RecordType::CNAME {
+                return Some(FlattenedTarget::Local(record));
+            }
+            let target = record.cname_target.clone()?;
+            match self.resolve(&target) {
+                Some(next) => record = next,
+                None => return Some(FlattenedTarget::External(target.trim_end_matches('.').to_string())),
+            }
+        }
+
+        log::warn!("CNAME chain for {domain} is longer than {MAX_CNAME_CHAIN} records, not flattening");
+        None
+    }
+
+    /// Domains with a record pointing at `ip`, answers PTR queries
+    pub fn reverse_lookup(&self, ip: IpAddr) -> Vec<String> {
+        let mut domains: Vec<String> = self.records.iter()
+            .filter(|(domain, record)| {
+                !domain.starts_with("*.")
+                    && record.formnet_ip.iter().chain(record.public_ip.iter()).any(|addr| addr.ip() == ip)
+            })
+            .map(|(domain, _)| domain.clone())
+            .collect();
+        domains.sort();
+        domains
+    }
+
     pub fn remove(&mut self, domain: &str) -> Option<FormDnsRecord> {
+        self.health_checks.remove(domain);
+        self.owners.remove(domain);
         self.records.remove(domain)
     }
 
+    pub fn set_owner(&mut self, domain: &str, owner: &str) {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        self.owners.insert(key, owner.to_lowercase());
+    }
+
+    pub fn owner(&self, domain: &str) -> Option<String> {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        self.owners.get(&key).cloned()
+    }
+
+    pub fn set_health_check(&mut self, domain: &str, check: EndpointCheck) {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        self.health_checks.insert(key, check);
+    }
+
+    pub fn remove_health_check(&mut self, domain: &str) -> Option<EndpointCheck> {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        self.health_checks.remove(&key)
+    }
+
+    pub fn health_check(&self, domain: &str) -> Option<EndpointCheck> {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        self.health_checks.get(&key).cloned()
+    }
+
+    /// Every record with a health check, by domain, along with the check
+    pub fn checked_records(&self) -> Vec<(String, FormDnsRecord, EndpointCheck)> {
+        self.health_checks.iter()
+            .filter_map(|(domain, check)| {
+                self.records.get(domain).map(|record| (domain.clone(), record.clone(), check.clone()))
+            })
+            .collect()
+    }
+
+    /// Adds a stream route or replaces the record's route on the same port
+    pub async fn set_stream_route(&mut self, mut route: StreamRoute) -> Result<(), String> {
+        route.domain = route.domain.trim_end_matches('.').to_lowercase();
+        route.validate()?;
+        if let Some(reason) = self.stream_routes.iter().find_map(|other| route.conflict(other)) {
+            return Err(reason);
+        }
+        let port = route.listen_port;
+        self.stream_routes.retain(|other| !(other.domain == route.domain && other.listen_port == port));
+        self.stream_routes.push(route);
+        self.notify_stream_port(port).await;
+        Ok(())
+    }
+
+    pub async fn remove_stream_route(&mut self, domain: &str, listen_port: u16) -> Option<StreamRoute> {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        let index = self.stream_routes.iter()
+            .position(|route| route.domain == key && route.listen_port == listen_port)?;
+        let route = self.stream_routes.remove(index);
+        self.notify_stream_port(listen_port).await;
+        Some(route)
+    }
+
+    /// Removes every stream route of a record, when the record goes away
+    pub async fn remove_stream_routes(&mut self, domain: &str) -> Vec<StreamRoute> {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        let (removed, kept) = std::mem::take(&mut self.stream_routes).into_iter()
+            .partition(|route| route.domain == key);
+        self.stream_routes = kept;
+        for route in &removed {
+            self.notify_stream_port(route.listen_port).await;
+        }
+        removed
+    }
+
+    /// Stream routes of one record, or all of them
+    pub fn stream_routes(&self, domain: Option<&str>) -> Vec<StreamRoute> {
+        let key = domain.map(|domain| domain.trim_end_matches('.').to_lowercase());
+        self.stream_routes.iter()
+            .filter(|route| key.is_none() || key.as_ref() == Some(&route.domain))
+            .cloned()
+            .collect()
+    }
+
+    /// Listen ports with a route to the record, whose backends follow it
+    pub fn stream_ports(&self, domain: &str) -> Vec<u16> {
+        let key = domain.trim_end_matches('.').to_lowercase();
+        self.stream_routes.iter()
+            .filter(|route| route.domain == key)
+            .map(|route| route.listen_port)
+            .collect()
+    }
+
+    /// What the proxy should serve on a listen port, None if nothing
+    pub fn stream_mapping(&self, listen_port: u16) -> Option<StreamMapping> {
+        build_mapping(listen_port, &self.stream_routes, |domain| match self.flatten(domain) {
+            Some(FlattenedTarget::Local(record)) => Some(record),
+            _ => None,
+        })
+    }
+
+    async fn notify_stream_port(&self, listen_port: u16) {
+        if let Some(sender) = &self.stream_sender {
+            let _ = sender.send(listen_port).await;
+        }
+    }
+
     pub fn entry(&mut self, domain: &str) -> Entry<'_, String, FormDnsRecord> {
         self.records.entry(domain.to_string())
     }
@@ -382,3 +644,72 @@ pub struct DnsConfiguration {
 }
 
 pub type SharedStore = Arc<RwLock<DnsStore>>;
+
+/// The IPv4 address a `d.c.b.a.in-addr.arpa` name asks about
+pub fn ptr_query_ip(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let octets = name.strip_suffix(".in-addr.arpa")?
+        .split('.')
+        .rev()
+        .map(|octet| octet.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::from(octets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(domain: &str, record_type: RecordType, ip: Option<&str>, cname_target: Option<&str>) -> FormDnsRecord {
+        FormDnsRecord {
+            domain: domain.to_string(),
+            record_type,
+            public_ip: ip.map(|ip| vec![SocketAddr::new(ip.parse().unwrap(), 80)]).unwrap_or_default(),
+            formnet_ip: vec![],
+            cname_target: cname_target.map(str::to_string),
+            ssl_cert: false,
+            ttl: 3600,
+            verification_status: None,
+            verification_timestamp: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_and_cname_flattening() {
+        let mut store = DnsStore::default();
+        store.insert("*.app.example", record("*.app.example", RecordType::A, Some("203.0.113.1"), None)).await;
+        store.insert("api.app.example", record("api.app.example", RecordType::A, Some("203.0.113.2"), None)).await;
+        store.insert("example.com", record("example.com", RecordType::CNAME, None, Some("www.app.example."))).await;
+        store.insert("other.com", record("other.com", RecordType::CNAME, None, Some("target.elsewhere.net"))).await;
+        store.insert("loop.com", record("loop.com", RecordType::CNAME, None, Some("loop.com"))).await;
+
+        // Exact records win over the wildcard
+        assert_eq!(store.resolve("api.app.example.").unwrap().domain, "api.app.example");
+        assert_eq!(store.resolve("a.b.app.example").unwrap().domain, "*.app.example");
+        assert!(store.resolve("app.example").is_none());
+
+        match store.flatten("example.com") {
+            Some(FlattenedTarget::Local(target)) => assert_eq!(target.domain, "*.app.example"),
+            other => panic!("expected a local target, got {other:?}"),
+        }
+        assert_eq!(store.flatten("other.com"), Some(FlattenedTarget::External("target.elsewhere.net".to_string())));
+        assert_eq!(store.flatten("loop.com"), None);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_lookup() {
+        let mut store = DnsStore::default();
+        store.insert("alice.formnet.fog", record("alice.formnet.fog", RecordType::A, Some("10.0.0.2"), None)).await;
+        store.insert("shop.fog", record("shop.fog", RecordType::A, Some("10.0.0.2"), None)).await;
+        store.insert("*.shop.fog", record("*.shop.fog", RecordType::A, Some("10.0.0.2"), None)).await;
+
+        let ip = "10.0.0.2".parse().unwrap();
+        assert_eq!(store.reverse_lookup(ip), vec!["alice.formnet.fog".to_string(), "shop.fog".to_string()]);
+        assert!(store.reverse_lookup("10.0.0.3".parse().unwrap()).is_empty());
+
+        assert_eq!(ptr_query_ip("2.0.0.10.in-addr.arpa."), Some(ip));
+        assert_eq!(ptr_query_ip("0.10.in-addr.arpa"), None);
+        assert_eq!(ptr_query_ip("alice.formnet.fog"), None);
+    }
+}
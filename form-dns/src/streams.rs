@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use form_rplb::stream::{StreamMapping, StreamProtocol, StreamRouting, StreamTarget};
+use serde::{Deserialize, Serialize};
+
+use crate::store::FormDnsRecord;
+
+/// Ports the node's own services listen on, which stream routes can't take
+pub const RESERVED_PORTS: [u16; 7] = [22, 53, 80, 443, 3004, 3005, 5453];
+
+/// How connections arriving on a stream port are told apart
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamRoutingMode {
+    /// The port belongs to one record
+    #[default]
+    Port,
+    /// Records share the port and TLS clients are routed by the server name
+    /// they ask for, which is the record's domain. TCP only.
+    Sni,
+}
+
+/// Exposes a non-HTTP service of a record's instances on a public port
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamRoute {
+    /// Record whose instances receive the traffic, taken from the path when
+    /// set through the API
+    #[serde(default)]
+    pub domain: String,
+    pub listen_port: u16,
+    pub protocol: StreamProtocol,
+    /// Port the service listens on in the instances
+    pub backend_port: u16,
+    #[serde(default)]
+    pub routing: StreamRoutingMode,
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout_secs: u64,
+    /// Bytes per second across all connections of the route, uncapped if unset
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+}
+
+fn default_idle_timeout() -> u64 { 300 }
+
+impl StreamRoute {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.listen_port == 0 || self.backend_port == 0 {
+            return Err("listen_port and backend_port must be set".to_string());
+        }
+        if RESERVED_PORTS.contains(&self.listen_port) {
+            return Err(format!("Port {} is reserved for the node's own services", self.listen_port));
+        }
+        if self.protocol == StreamProtocol::Udp && self.routing == StreamRoutingMode::Sni {
+            return Err("UDP routes can't be routed by server name".to_string());
+        }
+        if self.idle_timeout_secs == 0 {
+            return Err("idle_timeout_secs must be at least 1".to_string());
+        }
+        if self.bandwidth_limit == Some(0) {
+            return Err("bandwidth_limit must be at least 1 byte per second".to_string());
+        }
+        Ok(())
+    }
+
+    /// Why the route can't share its listen port with `other`, if it can't
+    pub fn conflict(&self, other: &StreamRoute) -> Option<String> {
+        if self.listen_port != other.listen_port || self.domain == other.domain {
+            return None;
+        }
+        if self.protocol != other.protocol || self.routing != other.routing {
+            return Some(format!(
+                "Port {} is already used by {} with {:?} {:?} routing",
+                self.listen_port, other.domain, other.protocol, other.routing
+            ));
+        }
+        match self.routing {
+            StreamRoutingMode::Port => Some(format!("Port {} is already mapped to {}", self.listen_port, other.domain)),
+            StreamRoutingMode::Sni => None,
+        }
+    }
+
+    /// Where the route sends traffic given the current record. Formnet
+    /// addresses are preferred, the instances are reached over the overlay.
+    pub fn target(&self, record: &FormDnsRecord) -> StreamTarget {
+        let addresses = if record.formnet_ip.is_empty() { &record.public_ip } else { &record.formnet_ip };
+        let mut backends: Vec<SocketAddr> = addresses.iter()
+            .map(|addr| SocketAddr::new(addr.ip(), self.backend_port))
+            .collect();
+        backends.dedup();
+        StreamTarget {
+            backends,
+            idle_timeout: Duration::from_secs(self.idle_timeout_secs),
+            bandwidth_limit: self.bandwidth_limit,
+        }
+    }
+}
+
+/// Builds the proxy mapping of one port from its routes. Routes whose record
+/// no longer resolves get no backends, so their connections are refused.
+pub fn build_mapping<'a>(
+    listen_port: u16,
+    routes: impl IntoIterator<Item = &'a StreamRoute>,
+    resolve: impl Fn(&str) -> Option<FormDnsRecord>,
+) -> Option<StreamMapping> {
+    let routes: Vec<_> = routes.into_iter().filter(|route| route.listen_port == listen_port).collect();
+    let first = routes.first()?;
+    let target = |route: &StreamRoute| match resolve(&route.domain) {
+        Some(record) => route.target(&record),
+        None => StreamTarget {
+            backends: vec![],
+            idle_timeout: Duration::from_secs(route.idle_timeout_secs),
+            bandwidth_limit: route.bandwidth_limit,
+        },
+    };
+    let routing = match first.routing {
+        StreamRoutingMode::Port => StreamRouting::Port(target(first)),
+        StreamRoutingMode::Sni => StreamRouting::Sni(
+            routes.iter()
+                .map(|route| (route.domain.clone(), target(route)))
+                .collect::<HashMap<_, _>>()
+        ),
+    };
+    Some(StreamMapping { listen_port, protocol: first.protocol, routing })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_proto::rr::RecordType;
+
+    fn route(domain: &str, listen_port: u16, routing: StreamRoutingMode) -> StreamRoute {
+        StreamRoute {
+            domain: domain.to_string(),
+            listen_port,
+            protocol: StreamProtocol::Tcp,
+            backend_port: 5432,
+            routing,
+            idle_timeout_secs: 60,
+            bandwidth_limit: None,
+        }
+    }
+
+    #[test]
+    fn test_route_conflicts() {
+        let db = route("db.fog", 15432, StreamRoutingMode::Port);
+        assert!(db.validate().is_ok());
+        assert!(route("db.fog", 443, StreamRoutingMode::Port).validate().is_err());
+        assert!(StreamRoute { protocol: StreamProtocol::Udp, ..route("db.fog", 15432, StreamRoutingMode::Sni) }.validate().is_err());
+
+        assert!(route("other.fog", 15432, StreamRoutingMode::Port).conflict(&db).is_some());
+        assert!(route("other.fog", 15432, StreamRoutingMode::Sni).conflict(&db).is_some());
+        assert!(route("other.fog", 15433, StreamRoutingMode::Port).conflict(&db).is_none());
+        assert!(route("db.fog", 15432, StreamRoutingMode::Port).conflict(&db).is_none());
+
+        let shared = route("a.fog", 6000, StreamRoutingMode::Sni);
+        assert!(route("b.fog", 6000, StreamRoutingMode::Sni).conflict(&shared).is_none());
+    }
+
+    #[test]
+    fn test_build_mapping() {
+        let record = FormDnsRecord {
+            domain: "a.fog".to_string(),
+            record_type: RecordType::A,
+            public_ip: vec!["203.0.113.5:80".parse().unwrap()],
+            formnet_ip: vec!["10.0.0.5:80".parse().unwrap(), "10.0.0.6:443".parse().unwrap()],
+            cname_target: None,
+            ssl_cert: false,
+            ttl: 60,
+            verification_status: None,
+            verification_timestamp: None,
+        };
+        let routes = vec![route("a.fog", 6000, StreamRoutingMode::Sni), route("b.fog", 6000, StreamRoutingMode::Sni)];
+        let resolve = |domain: &str| (domain == "a.fog").then(|| record.clone());
+
+        let mapping = build_mapping(6000, &routes, resolve).unwrap();
+        let StreamRouting::Sni(targets) = mapping.routing else {
+            panic!("expected SNI routing");
+        };
+        assert_eq!(targets["a.fog"].backends, vec!["10.0.0.5:5432".parse::<SocketAddr>().unwrap(), "10.0.0.6:5432".parse().unwrap()]);
+        assert!(targets["b.fog"].backends.is_empty());
+        assert!(build_mapping(6001, &routes, resolve).is_none());
+    }
+}
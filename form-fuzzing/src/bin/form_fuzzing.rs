@@ -0,0 +1,218 @@
+// form-fuzzing/src/bin/form_fuzzing.rs
+//! Corpus-driven fuzzing runner and crash reproduction tool
+//!
+//! `form-fuzzing run <target>` fuzzes a byte-oriented target from its
+//! persistent corpus, minimizing and deduplicating any crash it finds.
+//! `form-fuzzing replay <artifact>` re-executes a stored crash and exits with
+//! status 1 if it still reproduces, so it can drive `git bisect run`.
+
+use clap::{Parser, Subcommand};
+use form_fuzzing::corpus::Corpus;
+use form_fuzzing::instrumentation::coverage;
+use form_fuzzing::minimize;
+use form_fuzzing::mutators::{ByteMutator, Mutator};
+use form_fuzzing::reporters::crash::{run_catching, CrashArtifact, CrashStore, RecordedCrash};
+use form_fuzzing::targets::{find_target, FuzzTarget, TARGETS};
+use form_fuzzing::utils;
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+#[derive(Parser)]
+#[command(name = "form-fuzzing", about = "Formation Network fuzzing runner")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the available byte-oriented targets
+    List,
+    /// Fuzz a target from its persistent corpus
+    Run {
+        /// Name of the target
+        target: String,
+        /// Number of test cases to execute (defaults to FORM_FUZZING_MAX_ITERATIONS)
+        #[arg(long)]
+        iterations: Option<usize>,
+    },
+    /// Add files to the corpus of a target
+    Seed {
+        /// Name of the target
+        target: String,
+        /// Input files to add
+        files: Vec<PathBuf>,
+    },
+    /// Re-execute a crash artifact (or a raw input file with --target)
+    Replay {
+        /// Crash artifact or raw input file
+        artifact: PathBuf,
+        /// Target to run a raw input file against
+        #[arg(long)]
+        target: Option<String>,
+    },
+    /// Minimize the input of a crash artifact in place
+    Minimize {
+        /// Crash artifact
+        artifact: PathBuf,
+    },
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::List => {
+            for target in TARGETS {
+                println!("{:<20} {}", target.name, target.description);
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Run { target, iterations } => {
+            get_target(&target).and_then(|target| run(target, iterations.unwrap_or_else(utils::get_max_iterations)))
+        }
+        Command::Seed { target, files } => get_target(&target).and_then(|target| seed(target, &files)),
+        Command::Replay { artifact, target } => replay(&artifact, target.as_deref()),
+        Command::Minimize { artifact } => minimize_artifact(&artifact),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn get_target(name: &str) -> Result<&'static FuzzTarget, String> {
+    find_target(name).ok_or_else(|| {
+        let names: Vec<&str> = TARGETS.iter().map(|t| t.name).collect();
+        format!("Unknown target '{}', expected one of: {}", name, names.join(", "))
+    })
+}
+
+fn run(target: &FuzzTarget, iterations: usize) -> Result<ExitCode, String> {
+    let corpus = Corpus::open(target.name);
+    let crashes = CrashStore::open(target.name);
+    let mutator = ByteMutator::new();
+
+    let mut inputs = corpus.load().map_err(|e| format!("Failed to load corpus: {}", e))?;
+    if inputs.is_empty() {
+        inputs.push(b"{}".to_vec());
+    }
+    println!("Fuzzing {} with {} corpus inputs for {} iterations", target.name, inputs.len(), iterations);
+
+    let mut rng = rand::thread_rng();
+    let mut new_crashes = 0;
+    let mut duplicate_crashes = 0;
+    let start = Instant::now();
+
+    for i in 0..iterations {
+        let mut input = inputs[rng.gen_range(0..inputs.len())].clone();
+        mutator.mutate_multiple(&mut input, rng.gen_range(1..=4));
+
+        let edges_before = coverage::get_coverage_count();
+        match crashes.run(&input, target.run) {
+            Ok(Some(RecordedCrash::New(path))) => {
+                new_crashes += 1;
+                println!("New crash saved to {}", path.display());
+                // Keep crashing inputs as regression seeds
+                let _ = corpus.add(&input);
+            }
+            Ok(Some(RecordedCrash::Duplicate(_))) => duplicate_crashes += 1,
+            Ok(None) => {
+                // Keep inputs that reached new code
+                if coverage::get_coverage_count() > edges_before {
+                    if let Ok(Some(_)) = corpus.add(&input) {
+                        inputs.push(input);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to record crash: {}", e),
+        }
+
+        if i > 0 && i % 1000 == 0 {
+            println!("Completed {} iterations...", i);
+        }
+    }
+
+    println!("\n=== {} Fuzzing Summary ===", target.name);
+    println!("Iterations:        {}", iterations);
+    println!("Corpus size:       {}", inputs.len());
+    println!("New crashes:       {}", new_crashes);
+    println!("Duplicate crashes: {}", duplicate_crashes);
+    println!("Elapsed time:      {:.2?}", start.elapsed());
+
+    Ok(if new_crashes > 0 { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+fn seed(target: &FuzzTarget, files: &[PathBuf]) -> Result<ExitCode, String> {
+    let corpus = Corpus::open(target.name);
+    for file in files {
+        let data = std::fs::read(file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        match corpus.add(&data).map_err(|e| e.to_string())? {
+            Some(path) => println!("Added {} as {}", file.display(), path.display()),
+            None => println!("{} is already in the corpus", file.display()),
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn replay(path: &Path, target: Option<&str>) -> Result<ExitCode, String> {
+    let (fuzz_target, input, expected_hash) = match CrashArtifact::load(path) {
+        Ok(artifact) => {
+            let input = artifact.input_bytes().map_err(|e| e.to_string())?;
+            let name = target.unwrap_or(&artifact.target);
+            (get_target(name)?, input, Some(artifact.stack_hash))
+        }
+        Err(_) => {
+            let name = target.ok_or("Raw input files require --target")?;
+            let input = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            (get_target(name)?, input, None)
+        }
+    };
+
+    println!("Replaying {} bytes against {}", input.len(), fuzz_target.name);
+    match run_catching(|| (fuzz_target.run)(&input)) {
+        Ok(()) => {
+            println!("No crash");
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(crash) => {
+            println!("Crash: {}", crash.message);
+            println!("Stack hash: {}", crash.stack_hash);
+            if let Some(expected) = expected_hash {
+                if expected != crash.stack_hash {
+                    println!("Note: stack hash differs from the artifact ({}), this may be a different bug", expected);
+                }
+            }
+            println!("{}", crash.backtrace);
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn minimize_artifact(path: &Path) -> Result<ExitCode, String> {
+    let mut artifact = CrashArtifact::load(path).map_err(|e| format!("Failed to load artifact: {}", e))?;
+    let target = get_target(&artifact.target)?;
+    let input = artifact.input_bytes().map_err(|e| e.to_string())?;
+
+    let reproduces = |candidate: &[u8]| {
+        matches!(run_catching(|| (target.run)(candidate)), Err(c) if c.stack_hash == artifact.stack_hash)
+    };
+    if !reproduces(&input) {
+        return Err(format!("Artifact no longer reproduces crash {}", artifact.stack_hash));
+    }
+
+    let minimized = minimize::minimize(&input, minimize::DEFAULT_MAX_ATTEMPTS, reproduces);
+    println!("Minimized input from {} to {} bytes", input.len(), minimized.len());
+
+    artifact.input = hex::encode(&minimized);
+    artifact.minimized = true;
+    artifact.save(path).map_err(|e| e.to_string())?;
+    Ok(ExitCode::SUCCESS)
+}
@@ -259,6 +259,7 @@ fn create_random_request() -> NodeMetricsRequest {
             NodeMetricsRequest::Heartbeat {
                 node_id,
                 timestamp: chrono::Utc::now().timestamp(),
+                queue: None,
             }
         }
     }
@@ -0,0 +1,107 @@
+// form-fuzzing/src/corpus.rs
+//! Persistent, content addressed corpus storage per fuzzing target
+//!
+//! Every input is stored under the hash of its contents, so the same input is
+//! never stored twice and a corpus directory produces the same set of inputs
+//! on every machine that loads it.
+
+use crate::utils;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 64-bit FNV-1a hash. Used instead of `DefaultHasher` because its output must
+/// be stable across Rust versions and machines (corpus file names and crash
+/// stack hashes are compared between CI runs).
+pub fn fnv1a64(data: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    data.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+/// Corpus of inputs for a single fuzzing target
+pub struct Corpus {
+    target: String,
+    dir: PathBuf,
+}
+
+impl Corpus {
+    /// Open the corpus of a target in the configured corpus directory
+    pub fn open(target: &str) -> Self {
+        Self::open_in(target, utils::get_corpus_dir(target))
+    }
+
+    /// Open the corpus of a target stored in `dir`
+    pub fn open_in(target: &str, dir: impl AsRef<Path>) -> Self {
+        Self {
+            target: target.to_string(),
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Name of the target this corpus belongs to
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Directory the corpus is stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path an input is (or would be) stored at
+    pub fn path_for(&self, data: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:016x}.bin", fnv1a64(data)))
+    }
+
+    /// Add an input to the corpus. Returns the path it was stored at, or
+    /// `None` if the corpus already contained it.
+    pub fn add(&self, data: &[u8]) -> io::Result<Option<PathBuf>> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path_for(data);
+        if path.exists() {
+            return Ok(None);
+        }
+
+        // Write to a temporary file first so a killed fuzzer never leaves a
+        // truncated input behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        Ok(Some(path))
+    }
+
+    /// Whether the corpus already contains an input
+    pub fn contains(&self, data: &[u8]) -> bool {
+        self.path_for(data).exists()
+    }
+
+    /// Load every input in the corpus, sorted by file name so runs over the
+    /// same corpus are reproducible
+    pub fn load(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut paths = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+                .collect::<Vec<_>>(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        paths.sort();
+
+        paths.iter().map(fs::read).collect()
+    }
+
+    /// Number of inputs in the corpus
+    pub fn len(&self) -> usize {
+        self.load().map(|inputs| inputs.len()).unwrap_or(0)
+    }
+
+    /// Whether the corpus is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
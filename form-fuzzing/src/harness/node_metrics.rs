@@ -122,7 +122,7 @@ impl NodeMetricsFuzzHarness {
                 NodeMetricsResponse::Success
             },
             
-            NodeMetricsRequest::Heartbeat { node_id, timestamp } => {
+            NodeMetricsRequest::Heartbeat { node_id, timestamp, .. } => {
                 // Validate the node ID
                 if node_id.is_empty() {
                     return NodeMetricsResponse::Error {
@@ -149,6 +149,17 @@ impl NodeMetricsFuzzHarness {
                 
                 NodeMetricsResponse::Success
             },
+
+            NodeMetricsRequest::Connectivity { node_id, .. } => {
+                // Check if the node exists
+                if !store.capabilities.contains_key(node_id) {
+                    return NodeMetricsResponse::Error {
+                        error: format!("Node {} not found", node_id),
+                    };
+                }
+
+                NodeMetricsResponse::Success
+            },
         }
     }
     
@@ -301,6 +312,7 @@ mod tests {
         let heartbeat_request = NodeMetricsRequest::Heartbeat {
             node_id: node_id.clone(),
             timestamp: 12345,
+            queue: None,
         };
         
         // Process the heartbeat request
@@ -366,6 +378,7 @@ mod tests {
         let heartbeat_request = NodeMetricsRequest::Heartbeat {
             node_id,
             timestamp: -1,
+            queue: None,
         };
         
         let response = harness.process_request(&heartbeat_request);
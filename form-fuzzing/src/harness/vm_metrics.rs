@@ -140,6 +140,7 @@ impl VmMetricsFuzzHarness {
             memory_usage_bps: 6000, // 60% in basis points
             temperature_deci_c: 650, // 65.0°C
             power_draw_deci_w: 2500, // 250.0W
+            ..Default::default()
         }];
         
         // Create load metrics
@@ -159,6 +160,7 @@ impl VmMetricsFuzzHarness {
             network,
             gpus,
             load,
+            balloon: Default::default(),
         }
     }
     
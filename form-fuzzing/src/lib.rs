@@ -3,6 +3,9 @@
 
 pub mod constants;
 pub mod utils;
+pub mod corpus;
+pub mod minimize;
+pub mod targets;
 
 // Core modules
 pub mod generators;
@@ -0,0 +1,108 @@
+// form-fuzzing/src/minimize.rs
+//! Test-case minimization for crashing inputs
+//!
+//! Implements delta debugging (ddmin): chunks of the input are removed as long
+//! as the remaining input still reproduces the same crash, with the chunk size
+//! halved whenever no chunk can be removed. A final pass tries to simplify the
+//! bytes that are left.
+
+/// Upper bound on the number of times the input is executed while minimizing
+pub const DEFAULT_MAX_ATTEMPTS: usize = 10_000;
+
+/// Minimize `input` while `still_crashes` holds for the candidate
+///
+/// `still_crashes` must return true only if the candidate reproduces the
+/// original crash (e.g. the same stack hash), otherwise minimization can drift
+/// to an unrelated bug.
+pub fn minimize<F>(input: &[u8], max_attempts: usize, mut still_crashes: F) -> Vec<u8>
+where
+    F: FnMut(&[u8]) -> bool,
+{
+    let mut current = input.to_vec();
+    let mut attempts = 0;
+    let mut granularity = 2usize;
+
+    // Remove chunks
+    while current.len() >= 2 && attempts < max_attempts {
+        let chunk_size = current.len().div_ceil(granularity);
+        let mut reduced = false;
+        let mut start = 0;
+
+        while start < current.len() && attempts < max_attempts {
+            let end = (start + chunk_size).min(current.len());
+            let mut candidate = Vec::with_capacity(current.len() - (end - start));
+            candidate.extend_from_slice(&current[..start]);
+            candidate.extend_from_slice(&current[end..]);
+
+            attempts += 1;
+            if still_crashes(&candidate) {
+                current = candidate;
+                reduced = true;
+            } else {
+                start = end;
+            }
+        }
+
+        if reduced {
+            granularity = (granularity - 1).max(2);
+        } else if chunk_size == 1 {
+            break;
+        } else {
+            granularity = (granularity * 2).min(current.len());
+        }
+    }
+
+    // Simplify the remaining bytes
+    for i in 0..current.len() {
+        for replacement in [0u8, b'0', b' '] {
+            if attempts >= max_attempts || current[i] == replacement {
+                continue;
+            }
+            let mut candidate = current.clone();
+            candidate[i] = replacement;
+            attempts += 1;
+            if still_crashes(&candidate) {
+                current = candidate;
+                break;
+            }
+        }
+    }
+
+    log::debug!("Minimized input from {} to {} bytes in {} attempts", input.len(), current.len(), attempts);
+    current
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimize_keeps_crashing_substring() {
+        let input = b"some padding before CRASH and some padding after".to_vec();
+        let minimized = minimize(&input, DEFAULT_MAX_ATTEMPTS, |candidate| {
+            candidate.windows(5).any(|w| w == b"CRASH")
+        });
+
+        assert_eq!(minimized, b"CRASH".to_vec());
+    }
+
+    #[test]
+    fn test_minimize_simplifies_bytes() {
+        let minimized = minimize(&[9, 9, 200], DEFAULT_MAX_ATTEMPTS, |candidate| {
+            candidate.len() == 3 && candidate[2] > 100
+        });
+
+        assert_eq!(minimized, vec![0, 0, 200]);
+    }
+
+    #[test]
+    fn test_minimize_respects_attempt_budget() {
+        let mut calls = 0;
+        minimize(&[1; 64], 5, |_| {
+            calls += 1;
+            false
+        });
+
+        assert_eq!(calls, 5);
+    }
+}
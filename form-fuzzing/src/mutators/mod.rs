@@ -91,3 +91,49 @@ impl Mutator<u32> for NumericMutator {
         *input = *input + 1;
     }
 } 
+
+/// A havoc-style mutator for raw byte inputs
+pub struct ByteMutator;
+
+impl ByteMutator {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Mutator<Vec<u8>> for ByteMutator {
+    fn mutate(&self, input: &mut Vec<u8>) {
+        use rand::Rng;
+        const INTERESTING: [u8; 8] = [0, 1, 0x7f, 0x80, 0xff, b'"', b'{', b'}'];
+
+        let mut rng = rand::thread_rng();
+        if input.is_empty() {
+            input.push(rng.gen());
+            return;
+        }
+
+        let pos = rng.gen_range(0..input.len());
+        match rng.gen_range(0..6) {
+            // Flip a bit
+            0 => input[pos] ^= 1 << rng.gen_range(0..8),
+            // Replace with a random byte
+            1 => input[pos] = rng.gen(),
+            // Replace with an interesting byte
+            2 => input[pos] = INTERESTING[rng.gen_range(0..INTERESTING.len())],
+            // Insert a byte
+            3 => input.insert(pos, rng.gen()),
+            // Remove a range
+            4 => {
+                let end = rng.gen_range(pos..input.len()) + 1;
+                input.drain(pos..end);
+            }
+            // Duplicate a range
+            _ => {
+                let end = rng.gen_range(pos..input.len()) + 1;
+                let chunk = input[pos..end].to_vec();
+                let at = rng.gen_range(0..=input.len());
+                input.splice(at..at, chunk);
+            }
+        }
+    }
+}
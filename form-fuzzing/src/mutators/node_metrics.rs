@@ -442,6 +442,7 @@ impl Mutator<NodeMetricsRequest> for NodeMetricsRequestMutator {
                 NodeMetricsRequest::SetInitialMetrics { node_id, .. } => node_id.clone(),
                 NodeMetricsRequest::UpdateMetrics { node_id, .. } => node_id.clone(),
                 NodeMetricsRequest::Heartbeat { node_id, .. } => node_id.clone(),
+                NodeMetricsRequest::Connectivity { node_id, .. } => node_id.clone(),
             };
             
             *request = match new_type {
@@ -458,6 +459,7 @@ impl Mutator<NodeMetricsRequest> for NodeMetricsRequestMutator {
                 _ => NodeMetricsRequest::Heartbeat {
                     node_id,
                     timestamp: chrono::Utc::now().timestamp(),
+                    queue: None,
                 },
             };
         }
@@ -466,7 +468,8 @@ impl Mutator<NodeMetricsRequest> for NodeMetricsRequestMutator {
         match request {
             NodeMetricsRequest::SetInitialMetrics { node_id, .. } |
             NodeMetricsRequest::UpdateMetrics { node_id, .. } |
-            NodeMetricsRequest::Heartbeat { node_id, .. } => {
+            NodeMetricsRequest::Heartbeat { node_id, .. } |
+            NodeMetricsRequest::Connectivity { node_id, .. } => {
                 if rng.gen_bool(0.1) {  // 10% chance to mutate node_id
                     match rng.gen_range(0..5) {
                         0 => *node_id = "".to_string(),  // Empty node_id (error case)
@@ -514,6 +517,12 @@ impl Mutator<NodeMetricsRequest> for NodeMetricsRequestMutator {
                     }
                 }
             }
+            NodeMetricsRequest::Connectivity { connectivity, .. } => {
+                // Potentially drop the peer list
+                if rng.gen_bool(0.3) {  // 30% chance
+                    connectivity.peers.clear();
+                }
+            }
         }
     }
 }
@@ -570,6 +579,7 @@ mod tests {
         let mut request = NodeMetricsRequest::Heartbeat {
             node_id: "test-node".to_string(),
             timestamp: 12345,
+            queue: None,
         };
         mutator.mutate(&mut request);
         
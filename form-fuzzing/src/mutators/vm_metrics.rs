@@ -217,6 +217,7 @@ impl Mutator<SystemMetrics> for VmMetricsMutator {
                             memory_usage_bps: rng.gen_range(0..10000),
                             temperature_deci_c: rng.gen_range(300..900), // 30°C to 90°C
                             power_draw_deci_w: rng.gen_range(500..3000), // 50W to 300W
+                            ..Default::default()
                         });
                     }
                     2 => {
@@ -314,6 +315,7 @@ impl Mutator<SystemMetrics> for VmMetricsMutator {
                     memory_usage_bps: rng.gen_range(0..10000),
                     temperature_deci_c: rng.gen_range(300..900), // 30°C to 90°C
                     power_draw_deci_w: rng.gen_range(500..3000), // 50W to 300W
+                    ..Default::default()
                 });
                 
                 // Load
@@ -354,6 +356,7 @@ mod tests {
                 load5: 0,
                 load15: 0,
             },
+            balloon: Default::default(),
         };
         
         // Apply the mutation
@@ -0,0 +1,317 @@
+// form-fuzzing/src/reporters/crash.rs
+//! Crash capture, deduplication and artifact storage
+//!
+//! Test cases are executed under `catch_unwind` with a panic hook that records
+//! the panic message and backtrace. Crashes are identified by a hash of the
+//! top frames of their stack, so the same bug found through different inputs
+//! is stored once, and every stored artifact holds everything `form-fuzzing
+//! replay` needs to reproduce it.
+
+use crate::corpus::fnv1a64;
+use crate::minimize;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+/// Number of frames (after skipping panic machinery) that make up a stack hash
+const STACK_HASH_FRAMES: usize = 8;
+
+/// Frames from these crates are part of the panic machinery and don't identify a bug
+const IGNORED_FRAME_PREFIXES: &[&str] = &[
+    "std::", "core::", "alloc::", "rust_begin_unwind", "__rust", "<alloc::",
+    "<core::", "<std::", "form_fuzzing::reporters::crash::run_catching",
+];
+
+thread_local! {
+    static LAST_PANIC: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// A crash observed while executing a test case
+#[derive(Debug, Clone)]
+pub struct CrashInfo {
+    /// Panic message
+    pub message: String,
+    /// Full backtrace at the panic site
+    pub backtrace: String,
+    /// Hash of the top frames of the backtrace
+    pub stack_hash: String,
+}
+
+/// Install the panic hook that records panics for `run_catching`. Panics on
+/// other threads are still reported by the previous hook.
+pub fn install_panic_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let message = match info.payload().downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => match info.payload().downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "Box<dyn Any>".to_string(),
+                },
+            };
+            let message = match info.location() {
+                Some(location) => format!("{} at {}:{}", message, location.file(), location.line()),
+                None => message,
+            };
+            let backtrace = Backtrace::force_capture().to_string();
+
+            let captured = LAST_PANIC.with(|last| {
+                let mut last = last.borrow_mut();
+                if let Some(slot) = last.as_mut() {
+                    *slot = (message.clone(), backtrace.clone());
+                    true
+                } else {
+                    false
+                }
+            });
+            if !captured {
+                previous(info);
+            }
+        }));
+    });
+}
+
+/// Execute a test case, returning the crash it caused, if any
+pub fn run_catching<F: FnOnce()>(f: F) -> Result<(), CrashInfo> {
+    install_panic_hook();
+    // An empty slot marks this thread as capturing panics
+    LAST_PANIC.with(|last| *last.borrow_mut() = Some((String::new(), String::new())));
+
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    let captured = LAST_PANIC.with(|last| last.borrow_mut().take());
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(_) => {
+            let (message, backtrace) = captured.unwrap_or_default();
+            Err(CrashInfo {
+                stack_hash: stack_hash(&backtrace, &message),
+                message,
+                backtrace,
+            })
+        }
+    }
+}
+
+/// Compute the stack hash of a backtrace
+///
+/// Only symbol names are hashed (not addresses or line numbers) so the hash is
+/// stable across rebuilds. Falls back to the panic message when the backtrace
+/// has no usable frames, e.g. when built without debug info.
+pub fn stack_hash(backtrace: &str, message: &str) -> String {
+    let frames: Vec<&str> = backtrace
+        .lines()
+        .filter_map(|line| {
+            let (index, symbol) = line.trim().split_once(": ")?;
+            index.parse::<usize>().ok()?;
+            Some(strip_symbol_hash(symbol.trim()))
+        })
+        .filter(|symbol| !IGNORED_FRAME_PREFIXES.iter().any(|prefix| symbol.starts_with(prefix)))
+        .take(STACK_HASH_FRAMES)
+        .collect();
+
+    let key = if frames.is_empty() {
+        // Without frames, drop the location so the hash survives code moving around
+        message.split(" at ").next().unwrap_or(message).to_string()
+    } else {
+        frames.join("\n")
+    };
+
+    format!("{:016x}", fnv1a64(key.as_bytes()))
+}
+
+/// Strip the `::h0123456789abcdef` suffix rustc appends to symbol names
+fn strip_symbol_hash(symbol: &str) -> &str {
+    match symbol.rsplit_once("::h") {
+        Some((name, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => name,
+        _ => symbol,
+    }
+}
+
+/// A stored, reproducible crash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashArtifact {
+    /// Fuzzing target that crashed
+    pub target: String,
+    /// Stack hash used for deduplication
+    pub stack_hash: String,
+    /// Panic message
+    pub message: String,
+    /// Backtrace of the first occurrence
+    pub backtrace: String,
+    /// Hex encoded crashing input
+    pub input: String,
+    /// Whether `input` has been minimized
+    pub minimized: bool,
+    /// Number of times this crash has been hit
+    pub occurrences: u64,
+    /// Unix timestamp of the first occurrence
+    pub first_seen: u64,
+}
+
+impl CrashArtifact {
+    /// Load an artifact from disk
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Save the artifact to disk
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)
+    }
+
+    /// Decode the crashing input
+    pub fn input_bytes(&self) -> io::Result<Vec<u8>> {
+        hex::decode(&self.input).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Outcome of recording a crash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordedCrash {
+    /// First occurrence of this stack hash, stored at the given path
+    New(PathBuf),
+    /// A crash with this stack hash was already stored at the given path
+    Duplicate(PathBuf),
+}
+
+/// Stores crash artifacts for a target, deduplicated by stack hash
+pub struct CrashStore {
+    target: String,
+    dir: PathBuf,
+    minimize: bool,
+}
+
+impl CrashStore {
+    /// Open the crash store of a target in the configured artifacts directory.
+    /// New crashes are minimized unless `FORM_FUZZING_ENABLE_MINIMIZE=0`.
+    pub fn open(target: &str) -> Self {
+        let minimize = std::env::var("FORM_FUZZING_ENABLE_MINIMIZE")
+            .map(|v| v != "0" && v != "false")
+            .unwrap_or(true);
+        Self::open_in(target, utils::get_artifacts_dir(target), minimize)
+    }
+
+    /// Open the crash store of a target stored in `dir`
+    pub fn open_in(target: &str, dir: impl AsRef<Path>, minimize: bool) -> Self {
+        Self {
+            target: target.to_string(),
+            dir: dir.as_ref().to_path_buf(),
+            minimize,
+        }
+    }
+
+    /// Path of the artifact for a stack hash
+    pub fn path_for(&self, stack_hash: &str) -> PathBuf {
+        self.dir.join(format!("crash-{}.json", stack_hash))
+    }
+
+    /// Execute a test case and record it if it crashes
+    pub fn run<F: Fn(&[u8])>(&self, input: &[u8], f: F) -> io::Result<Option<RecordedCrash>> {
+        match run_catching(|| f(input)) {
+            Ok(()) => Ok(None),
+            Err(crash) => self.record(input, crash, f).map(Some),
+        }
+    }
+
+    /// Record a crash. New crashes are minimized with `f` before they are
+    /// stored, duplicates only bump the occurrence count.
+    pub fn record<F: Fn(&[u8])>(&self, input: &[u8], crash: CrashInfo, f: F) -> io::Result<RecordedCrash> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path_for(&crash.stack_hash);
+
+        if path.exists() {
+            let mut artifact = CrashArtifact::load(&path)?;
+            artifact.occurrences += 1;
+            artifact.save(&path)?;
+            log::info!("Duplicate crash {} in {} ({} occurrences)", crash.stack_hash, self.target, artifact.occurrences);
+            return Ok(RecordedCrash::Duplicate(path));
+        }
+
+        let input = if self.minimize {
+            minimize::minimize(input, minimize::DEFAULT_MAX_ATTEMPTS, |candidate| {
+                matches!(run_catching(|| f(candidate)), Err(c) if c.stack_hash == crash.stack_hash)
+            })
+        } else {
+            input.to_vec()
+        };
+
+        let artifact = CrashArtifact {
+            target: self.target.clone(),
+            stack_hash: crash.stack_hash.clone(),
+            message: crash.message,
+            backtrace: crash.backtrace,
+            input: hex::encode(&input),
+            minimized: self.minimize,
+            occurrences: 1,
+            first_seen: utils::get_timestamp_string().parse().unwrap_or_default(),
+        };
+        artifact.save(&path)?;
+        log::error!("New crash {} in {}: {} (saved to {})", crash.stack_hash, self.target, artifact.message, path.display());
+
+        Ok(RecordedCrash::New(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crashes_on_marker(input: &[u8]) {
+        if input.windows(3).any(|w| w == b"BUG") {
+            panic!("found a bug");
+        }
+    }
+
+    #[test]
+    fn test_run_catching_reports_panics() {
+        assert!(run_catching(|| crashes_on_marker(b"fine")).is_ok());
+
+        let crash = run_catching(|| crashes_on_marker(b"a BUG here")).unwrap_err();
+        assert!(crash.message.starts_with("found a bug"));
+        assert_eq!(crash.stack_hash.len(), 16);
+    }
+
+    #[test]
+    fn test_stack_hash_ignores_addresses_and_panic_frames() {
+        let a = "   0: std::panicking::begin_panic\n   1: my_crate::parse::h0123456789abcdef\n             at src/lib.rs:10:5\n   2: my_crate::main";
+        let b = "   0: core::panicking::panic\n   1: my_crate::parse::hfedcba9876543210\n             at src/lib.rs:12:9\n   2: my_crate::main";
+        let c = "   0: std::panicking::begin_panic\n   1: my_crate::other\n   2: my_crate::main";
+
+        assert_eq!(stack_hash(a, ""), stack_hash(b, ""));
+        assert_ne!(stack_hash(a, ""), stack_hash(c, ""));
+    }
+
+    #[test]
+    fn test_crash_store_minimizes_and_deduplicates() {
+        let dir = std::env::temp_dir().join(format!("form-fuzzing-crashes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = CrashStore::open_in("test", &dir, true);
+
+        let first = store.run(b"xxxxBUGxxxx", crashes_on_marker).unwrap();
+        let path = match first {
+            Some(RecordedCrash::New(path)) => path,
+            other => panic!("expected a new crash, got {:?}", other),
+        };
+        let artifact = CrashArtifact::load(&path).unwrap();
+        assert_eq!(artifact.input_bytes().unwrap(), b"BUG".to_vec());
+
+        let second = store.run(b"BUG again", crashes_on_marker).unwrap();
+        assert_eq!(second, Some(RecordedCrash::Duplicate(path.clone())));
+        assert_eq!(CrashArtifact::load(&path).unwrap().occurrences, 2);
+        assert_eq!(store.run(b"fine", crashes_on_marker).unwrap(), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
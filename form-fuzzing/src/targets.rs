@@ -0,0 +1,96 @@
+// form-fuzzing/src/targets.rs
+//! Byte-oriented fuzzing targets
+//!
+//! Each target decodes raw bytes into inputs for real Formation code and
+//! exercises it. Because a target is a plain `fn(&[u8])`, any input found by a
+//! fuzzer (and stored in the corpus or a crash artifact) can be replayed
+//! exactly by `form-fuzzing replay`.
+
+use crate::generators::vm_management::generate_create_vm_request;
+use form_state::accounts::Account;
+use form_state::instances::Instance;
+
+/// A replayable fuzzing target
+pub struct FuzzTarget {
+    /// Name used for the corpus and artifacts directories
+    pub name: &'static str,
+    /// What the target exercises
+    pub description: &'static str,
+    /// Executes one test case, panics on a bug
+    pub run: fn(&[u8]),
+}
+
+/// All registered targets
+pub const TARGETS: &[FuzzTarget] = &[
+    FuzzTarget {
+        name: "state_instance",
+        description: "Instance deserialization, scaling policy and schedule validation",
+        run: fuzz_state_instance,
+    },
+    FuzzTarget {
+        name: "state_account",
+        description: "Account deserialization and credit eligibility checks",
+        run: fuzz_state_account,
+    },
+    FuzzTarget {
+        name: "vm_create_request",
+        description: "VM creation request decoding from raw bytes",
+        run: fuzz_vm_create_request,
+    },
+];
+
+/// Look up a target by name
+pub fn find_target(name: &str) -> Option<&'static FuzzTarget> {
+    TARGETS.iter().find(|target| target.name == name)
+}
+
+fn fuzz_state_instance(data: &[u8]) {
+    let Ok(instance) = serde_json::from_slice::<Instance>(data) else { return };
+
+    if let Some(policy) = &instance.scaling_policy {
+        let _ = policy.validate();
+    }
+    if let Some(schedule) = &instance.schedule {
+        let _ = schedule.validate();
+    }
+    let _ = instance.due_scheduled_action(instance.updated_at);
+
+    // Whatever deserializes must serialize back to the same value
+    let encoded = serde_json::to_vec(&instance).expect("deserialized instance must serialize");
+    let decoded: Instance = serde_json::from_slice(&encoded).expect("serialized instance must deserialize");
+    assert_eq!(
+        serde_json::to_value(&instance).ok(),
+        serde_json::to_value(&decoded).ok(),
+        "instance changed across a serialization round trip"
+    );
+}
+
+fn fuzz_state_account(data: &[u8]) {
+    let Ok(account) = serde_json::from_slice::<Account>(data) else { return };
+
+    let _ = account.available_credits();
+    let _ = account.can_use_tokens("fuzz-model", data.len() as u64, account.credits);
+    let _ = account.can_hire_additional_agent();
+
+    let encoded = serde_json::to_vec(&account).expect("deserialized account must serialize");
+    let _: Account = serde_json::from_slice(&encoded).expect("serialized account must deserialize");
+}
+
+fn fuzz_vm_create_request(data: &[u8]) {
+    let request = generate_create_vm_request(data);
+    let _ = format!("{:?}", request);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_names_are_unique_and_findable() {
+        for target in TARGETS {
+            assert!(find_target(target.name).is_some());
+            assert_eq!(TARGETS.iter().filter(|t| t.name == target.name).count(), 1);
+        }
+        assert!(find_target("missing").is_none());
+    }
+}
@@ -111,13 +111,12 @@ pub fn is_feature_enabled(feature: &str) -> bool {
 }
 
 /// Saves data to a corpus file for later use
+///
+/// Inputs are stored under the hash of their contents, so saving the same
+/// input twice returns the existing path.
 pub fn save_to_corpus(target: &str, data: &[u8]) -> Result<PathBuf, std::io::Error> {
-    let corpus_dir = get_corpus_dir(target);
-    let filename = create_timestamped_filename("corpus", "bin");
-    let path = corpus_dir.join(filename);
-    
-    fs::write(&path, data)?;
-    Ok(path)
+    let corpus = crate::corpus::Corpus::open(target);
+    Ok(corpus.add(data)?.unwrap_or_else(|| corpus.path_for(data)))
 }
 
 /// Load corpus files for a target
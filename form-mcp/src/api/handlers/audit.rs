@@ -0,0 +1,70 @@
+// Audit handlers for the MCP server API
+//
+// This module contains handlers for querying and exporting the audit trail
+// of tool invocations. Agents see their own records; callers with the
+// `audit:read` permission can see and export everyone's.
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::api::handlers::ApiResponse;
+use crate::api::handlers::sessions::caller_id;
+use crate::auth::{check_authorization, AuthData};
+use crate::models::audit::{AuditQuery, AuditRecord, AuditStore};
+
+/// Data structure for audit query response
+#[derive(Serialize)]
+pub struct AuditListResponse {
+    pub records: Vec<AuditRecord>,
+}
+
+/// Restrict the query to the caller's own records unless it may read all of them
+async fn scoped_query(req: &HttpRequest, mut query: AuditQuery) -> AuditQuery {
+    let auth_data = req.extensions().get::<AuthData>().cloned();
+    let can_read_all = match &auth_data {
+        Some(auth_data) => check_authorization(auth_data, "audit", "read").await.unwrap_or(false),
+        // Authentication is disabled
+        None => true,
+    };
+    if !can_read_all {
+        query.agent_id = Some(caller_id(req));
+    }
+    query
+}
+
+/// Handler for querying audit records, newest first
+pub async fn list_audit_records(
+    req: HttpRequest,
+    store: web::Data<Arc<AuditStore>>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    let query = scoped_query(&req, query.into_inner()).await;
+    let records = store.query(&query).await;
+    HttpResponse::Ok().json(ApiResponse::success(AuditListResponse { records }))
+}
+
+/// Handler for exporting audit records as JSON lines, oldest first
+pub async fn export_audit_records(
+    req: HttpRequest,
+    store: web::Data<Arc<AuditStore>>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    let query = scoped_query(&req, query.into_inner()).await;
+    let mut body = String::new();
+    for record in store.query(&query).await.into_iter().rev() {
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                body.push_str(&line);
+                body.push('\n');
+            }
+            Err(e) => log::error!("Failed to serialize audit record {}: {}", record.id, e),
+        }
+    }
+
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "application/x-ndjson"))
+        .insert_header((CONTENT_DISPOSITION, "attachment; filename=\"mcp-audit.jsonl\""))
+        .body(body)
+}
@@ -3,9 +3,11 @@
 // This module contains handlers for authentication-related API endpoints,
 // such as login and token validation.
 
+use std::sync::Arc;
 use actix_web::{web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use crate::api::handlers::ApiResponse;
+use crate::config::Settings;
 use crate::auth::{create_token, verify_token};
 use crate::auth::keypair::KeyPair;
 use crate::auth::signature::{sign_message, verify_signature};
@@ -94,6 +96,72 @@ pub async fn login(
     }
 }
 
+/// Session issued by form-state for a verified passkey assertion
+#[derive(Deserialize)]
+struct PasskeySession {
+    success: bool,
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    expires_at: Option<i64>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Handler for passkey login
+///
+/// The browser's WebAuthn assertion is forwarded to form-state, which checks
+/// it against the passkeys linked to the account. The MCP token expires with
+/// the form-state passkey session.
+pub async fn passkey_login(
+    settings: web::Data<Arc<Settings>>,
+    req: web::Json<serde_json::Value>,
+) -> impl Responder {
+    let url = format!("{}/v1/passkey/login/finish", settings.billing.state_url.trim_end_matches('/'));
+    let session = match reqwest::Client::new().post(&url).json(&req.into_inner()).send().await {
+        Ok(resp) => match resp.json::<PasskeySession>().await {
+            Ok(session) => session,
+            Err(err) => {
+                return HttpResponse::BadGateway().json(ApiResponse::<()>::error(format!("Invalid response from form-state: {}", err)));
+            }
+        },
+        Err(err) => {
+            return HttpResponse::BadGateway().json(ApiResponse::<()>::error(format!("Unable to reach form-state: {}", err)));
+        }
+    };
+
+    let (address, expires_at) = match session {
+        PasskeySession { success: true, address: Some(address), expires_at: Some(expires_at), .. } => (address, expires_at),
+        session => {
+            let error = session.error.unwrap_or_else(|| "Passkey login failed".to_string());
+            return HttpResponse::Unauthorized().json(ApiResponse::<()>::error(error));
+        }
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let expires_at = expires_at.max(0) as u64;
+    if expires_at <= now {
+        return HttpResponse::Unauthorized().json(ApiResponse::<()>::error("Passkey session already expired"));
+    }
+
+    // Same placeholder secret as the signature login
+    let secret = b"your-secret-key-which-should-be-very-long-and-complex";
+    match create_token(&address, vec!["user".to_string()], secret, expires_at - now) {
+        Ok(token) => HttpResponse::Ok().json(ApiResponse::success(LoginResponse {
+            token,
+            user_id: address,
+            expires_at,
+            permissions: vec!["tools:read".to_string(), "tools:execute".to_string()],
+        })),
+        Err(err) => {
+            HttpResponse::InternalServerError().json(ApiResponse::<()>::error(format!("Failed to create token: {}", err)))
+        }
+    }
+}
+
 /// Handler for token validation endpoint
 pub async fn validate_token(
     req: web::Json<ValidateTokenRequest>,
@@ -6,6 +6,9 @@
 pub mod tools;
 pub mod operations;
 pub mod auth;
+pub mod sessions;
+pub mod audit;
+pub mod rate_limits;
 
 /// Common response structure for API endpoints
 #[derive(serde::Serialize)]
@@ -4,11 +4,14 @@
 // such as checking the status of long-running operations.
 
 use actix_web::{web, HttpResponse, Responder};
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use actix_web::web::Bytes;
 use serde::Serialize;
 use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
 
 use crate::api::handlers::ApiResponse;
-use crate::models::operations::OperationsRepository;
+use crate::models::operations::{Operation, OperationExecutor, OperationsRepository};
 
 /// Data structure for operation status response
 #[derive(Serialize)]
@@ -16,6 +19,7 @@ pub struct OperationStatus {
     pub id: String,
     pub status: String,
     pub progress: Option<f32>,
+    pub message: Option<String>,
     pub result: Option<serde_json::Value>,
     pub error: Option<String>,
 }
@@ -79,4 +83,82 @@ pub async fn list_operations(
     HttpResponse::Ok().json(ApiResponse::success(OperationListResponse {
         operations: operation_statuses,
     }))
-} 
\ No newline at end of file
+}
+
+/// Format an operation update as a server-sent event
+fn operation_event(operation: &Operation) -> Bytes {
+    let data = serde_json::to_string(&operation.to_api_response()).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", operation.status, data))
+}
+
+/// Handler streaming progress of an operation as server-sent events
+///
+/// The current state is sent immediately, followed by every update until the
+/// operation completes, fails or is cancelled.
+pub async fn operation_events(
+    repository: web::Data<Arc<OperationsRepository>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let operation_id = path.into_inner();
+    let repository = repository.get_ref().clone();
+
+    // Subscribe before reading the snapshot so no update is missed in between
+    let receiver = repository.subscribe();
+    let operation = match repository.get_operation(&operation_id).await {
+        Some(operation) => operation,
+        None => {
+            return HttpResponse::NotFound().json(ApiResponse::<()>::error(
+                format!("Operation with ID '{}' not found", operation_id)
+            ));
+        }
+    };
+
+    let stream = futures_util::stream::unfold(
+        (receiver, repository, Some(operation), false),
+        move |(mut receiver, repository, pending, finished)| {
+            let operation_id = operation_id.clone();
+            async move {
+                if let Some(operation) = pending {
+                    let finished = operation.is_finished();
+                    return Some((Ok::<_, actix_web::Error>(operation_event(&operation)), (receiver, repository, None, finished)));
+                }
+                if finished {
+                    return None;
+                }
+                loop {
+                    let update = match receiver.recv().await {
+                        Ok(operation) if operation.id == operation_id => operation,
+                        Ok(_) => continue,
+                        // Fell behind, resync from the repository
+                        Err(RecvError::Lagged(_)) => repository.get_operation(&operation_id).await?,
+                        Err(RecvError::Closed) => return None,
+                    };
+                    let finished = update.is_finished();
+                    return Some((Ok(operation_event(&update)), (receiver, repository, None, finished)));
+                }
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(stream)
+}
+
+/// Handler for cancelling a queued or running operation
+pub async fn cancel_operation(
+    executor: web::Data<OperationExecutor>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let operation_id = path.into_inner();
+
+    match executor.cancel(&operation_id).await {
+        Ok(()) => HttpResponse::Accepted().json(ApiResponse::success(serde_json::json!({
+            "id": operation_id,
+            "message": "Cancellation requested",
+        }))),
+        Err(e) => HttpResponse::Conflict().json(ApiResponse::<()>::error(e)),
+    }
+}
+
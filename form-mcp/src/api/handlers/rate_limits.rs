@@ -0,0 +1,29 @@
+// Rate limit handlers for the MCP server API
+//
+// This module contains the admin handler reporting throttled calls.
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use std::sync::Arc;
+
+use crate::api::handlers::ApiResponse;
+use crate::auth::{check_authorization, AuthData};
+use crate::ratelimit::RateLimiter;
+
+/// Admin handler for the counts of allowed and throttled calls
+pub async fn rate_limit_metrics(
+    req: HttpRequest,
+    limiter: web::Data<Arc<RateLimiter>>,
+) -> impl Responder {
+    let auth_data = req.extensions().get::<AuthData>().cloned();
+    let authorized = match &auth_data {
+        Some(auth_data) => check_authorization(auth_data, "rate_limits", "read").await.unwrap_or(false),
+        None => false,
+    };
+    if !authorized {
+        return HttpResponse::Forbidden().json(ApiResponse::<()>::error(
+            "Admin permission is required to read rate limit metrics"
+        ));
+    }
+
+    HttpResponse::Ok().json(ApiResponse::success(limiter.metrics()))
+}
@@ -0,0 +1,169 @@
+// Session handlers for the MCP server API
+//
+// This module contains handlers for creating, inspecting, updating and
+// expiring the sessions agents use to carry context across tool calls.
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api::handlers::ApiResponse;
+use crate::auth::AuthData;
+use crate::models::sessions::{Session, SessionStore};
+
+/// Data structure for session responses
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub defaults: HashMap<String, serde_json::Value>,
+    pub context: HashMap<String, String>,
+    pub pending_operation: Option<String>,
+    pub last_tool: Option<String>,
+    /// Unix timestamp the session was created at
+    pub created_at: u64,
+    /// Unix timestamp the session expires at unless it is used again
+    pub expires_at: u64,
+}
+
+/// Data structure for session list response
+#[derive(Serialize)]
+pub struct SessionListResponse {
+    pub sessions: Vec<SessionResponse>,
+}
+
+/// Request for creating a session
+#[derive(Deserialize, Default)]
+pub struct CreateSessionRequest {
+    /// Seconds the session lives without being used
+    pub ttl_secs: Option<u64>,
+    /// Initial tool parameter defaults
+    #[serde(default)]
+    pub defaults: HashMap<String, serde_json::Value>,
+    /// Initial tool context
+    #[serde(default)]
+    pub context: HashMap<String, String>,
+}
+
+/// Request for changing a session's context
+#[derive(Deserialize, Default)]
+pub struct UpdateSessionRequest {
+    /// Defaults to set or replace
+    #[serde(default)]
+    pub defaults: HashMap<String, serde_json::Value>,
+    /// Context values to set or replace
+    #[serde(default)]
+    pub context: HashMap<String, String>,
+    /// Default and context keys to remove
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Forget the pending operation
+    #[serde(default)]
+    pub clear_pending_operation: bool,
+}
+
+/// ID of the authenticated caller
+pub(crate) fn caller_id(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<AuthData>()
+        .map(|auth| auth.user_id.clone())
+        .unwrap_or_else(|| "test_user".to_string()) // Placeholder when auth is disabled
+}
+
+/// Look up a session owned by the caller
+pub(crate) async fn owned_session(store: &SessionStore, id: &str, user_id: &str) -> Result<Session, HttpResponse> {
+    match store.get_session(id).await {
+        Some(session) if session.user_id == user_id => Ok(session),
+        // Sessions of other users are reported as missing
+        _ => Err(HttpResponse::NotFound().json(ApiResponse::<()>::error(
+            format!("Session with ID '{}' not found", id)
+        ))),
+    }
+}
+
+/// Handler for creating a session
+pub async fn create_session(
+    http_req: HttpRequest,
+    store: web::Data<Arc<SessionStore>>,
+    req: web::Json<CreateSessionRequest>,
+) -> impl Responder {
+    let req = req.into_inner();
+    let mut session = store.create_session(caller_id(&http_req), req.ttl_secs.map(Duration::from_secs)).await;
+    if !req.defaults.is_empty() || !req.context.is_empty() {
+        session.defaults = req.defaults;
+        session.context = req.context;
+        if let Err(e) = store.update_session(session.clone()).await {
+            return HttpResponse::InternalServerError().json(ApiResponse::<()>::error(e));
+        }
+    }
+    HttpResponse::Created().json(ApiResponse::success(session.to_api_response()))
+}
+
+/// Handler for inspecting a session
+pub async fn get_session(
+    http_req: HttpRequest,
+    store: web::Data<Arc<SessionStore>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match owned_session(&store, &path.into_inner(), &caller_id(&http_req)).await {
+        Ok(session) => HttpResponse::Ok().json(ApiResponse::success(session.to_api_response())),
+        Err(response) => response,
+    }
+}
+
+/// Handler for listing the caller's sessions
+pub async fn list_sessions(
+    http_req: HttpRequest,
+    store: web::Data<Arc<SessionStore>>,
+) -> impl Responder {
+    let sessions = store.get_sessions_by_user(&caller_id(&http_req)).await
+        .iter()
+        .map(Session::to_api_response)
+        .collect();
+    HttpResponse::Ok().json(ApiResponse::success(SessionListResponse { sessions }))
+}
+
+/// Handler for changing a session's defaults and context
+pub async fn update_session(
+    http_req: HttpRequest,
+    store: web::Data<Arc<SessionStore>>,
+    path: web::Path<String>,
+    req: web::Json<UpdateSessionRequest>,
+) -> impl Responder {
+    let mut session = match owned_session(&store, &path.into_inner(), &caller_id(&http_req)).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    let req = req.into_inner();
+    for key in &req.remove {
+        session.defaults.remove(key);
+        session.context.remove(key);
+    }
+    session.defaults.extend(req.defaults);
+    session.context.extend(req.context);
+    if req.clear_pending_operation {
+        session.pending_operation = None;
+    }
+    session.touch();
+
+    match store.update_session(session.clone()).await {
+        Ok(()) => HttpResponse::Ok().json(ApiResponse::success(session.to_api_response())),
+        Err(e) => HttpResponse::NotFound().json(ApiResponse::<()>::error(e)),
+    }
+}
+
+/// Handler for expiring a session
+pub async fn expire_session(
+    http_req: HttpRequest,
+    store: web::Data<Arc<SessionStore>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let session = match owned_session(&store, &path.into_inner(), &caller_id(&http_req)).await {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    store.expire_session(&session.id).await;
+    HttpResponse::NoContent().finish()
+}
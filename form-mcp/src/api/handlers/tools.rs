@@ -3,17 +3,20 @@
 // This module contains handlers for tool-related API endpoints,
 // such as listing available tools and executing a tool.
 
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
 use serde::Deserialize;
 use std::sync::Arc;
 use uuid::Uuid;
-use tokio::task;
-use serde_json::json;
 
-use crate::tools::{ToolRegistry, ToolRequest, ToolContext, ToolResponse};
+use crate::tools::{ManifestLoader, ToolRegistry, ToolRequest, ToolContext, ToolResponse};
+use crate::auth::{check_authorization, AuthData};
 use crate::api::handlers::ApiResponse;
+use crate::api::handlers::sessions::{caller_id, owned_session};
 use crate::errors::ToolError;
-use crate::models::operations::{OperationsRepository, Operation};
+use crate::models::operations::{OperationExecutor, OperationsRepository};
+use crate::models::sessions::{Session, SessionStore};
+use crate::models::audit::{AuditRecord, AuditStatus, AuditStore};
+use crate::billing::QuotaEnforcer;
 
 /// Query parameters for tool listing
 #[derive(Deserialize, Default)]
@@ -62,6 +65,8 @@ pub struct ExecuteToolRequest {
     pub parameters: serde_json::Value,
     /// Optional context data
     pub context: Option<std::collections::HashMap<String, String>>,
+    /// Session whose defaults and context apply to this call
+    pub session_id: Option<String>,
 }
 
 /// Response for an asynchronous tool execution
@@ -72,13 +77,56 @@ pub struct AsyncToolResponse {
     pub message: String,
 }
 
+/// Convert a tool error into an HTTP response
+fn tool_error_response(tool_name: &str, error: ToolError) -> HttpResponse {
+    match error {
+        ToolError::NotFound(_) => 
+            HttpResponse::NotFound().json(ApiResponse::<()>::error(format!("Tool '{}' not found", tool_name))),
+        ToolError::InvalidParameters(msg) => 
+            HttpResponse::BadRequest().json(ApiResponse::<()>::error(msg)),
+        ToolError::ExecutionFailed(msg) => 
+            HttpResponse::InternalServerError().json(ApiResponse::<()>::error(msg)),
+        ToolError::Timeout => 
+            HttpResponse::GatewayTimeout().json(ApiResponse::<()>::error("Tool execution timed out")),
+        ToolError::Forbidden(msg) =>
+            HttpResponse::Forbidden().json(ApiResponse::<()>::error(msg)),
+        ToolError::QuotaExceeded { code, message, details } =>
+            HttpResponse::PaymentRequired().json(ApiResponse {
+                status: "error".to_string(),
+                data: Some(serde_json::json!({ "code": code, "details": details })),
+                message: Some(message),
+            }),
+        ToolError::Maintenance { message, details } =>
+            HttpResponse::ServiceUnavailable().json(ApiResponse {
+                status: "error".to_string(),
+                data: Some(serde_json::json!({ "code": "DEPLOYMENTS_FROZEN", "maintenance": details })),
+                message: Some(message),
+            }),
+        ToolError::RegistrationFailed(_) => 
+            HttpResponse::InternalServerError().json(ApiResponse::<()>::error("Internal server error")),
+    }
+}
+
+/// Audit status of a call that ended in `error`. Errors raised before the tool
+/// ran mean the call was refused rather than failed.
+fn error_status(error: &ToolError) -> AuditStatus {
+    match error {
+        ToolError::InvalidParameters(_) | ToolError::Forbidden(_) | ToolError::QuotaExceeded { .. } | ToolError::Maintenance { .. } => AuditStatus::Rejected,
+        _ => AuditStatus::Failed,
+    }
+}
+
 /// Handler for executing a specific tool
 pub async fn execute_tool(
+    http_req: HttpRequest,
     registry: web::Data<Arc<ToolRegistry>>,
-    operations_repo: web::Data<Arc<OperationsRepository>>,
+    executor: web::Data<OperationExecutor>,
+    quota: web::Data<Arc<QuotaEnforcer>>,
+    sessions: web::Data<Arc<SessionStore>>,
+    operations: web::Data<Arc<OperationsRepository>>,
+    audit: web::Data<Arc<AuditStore>>,
     path: web::Path<String>,
     req: web::Json<ExecuteToolRequest>,
-    // Later, we would add user authentication info here
 ) -> impl Responder {
     let tool_name = path.into_inner();
     
@@ -90,99 +138,145 @@ pub async fn execute_tool(
         )),
     };
     
+    // Quotas are enforced per agent, so identify the caller from its authentication data
+    let user_id = caller_id(&http_req);
+    
+    // Fill in what the agent left out from its session
+    let mut parameters = req.parameters.clone();
+    let mut request_context = req.context.clone();
+    let mut session = match &req.session_id {
+        Some(session_id) => match owned_session(&sessions, session_id, &user_id).await {
+            Ok(session) => Some(session),
+            Err(response) => return response,
+        },
+        None => None,
+    };
+    if let Some(session) = session.as_mut() {
+        if let Some(operation_id) = session.pending_operation.clone() {
+            match operations.get_operation(&operation_id).await {
+                Some(operation) => session.settle_pending_operation(&operation),
+                None => session.pending_operation = None,
+            }
+        }
+        let injected = session.apply_defaults(&tool.definition(), &mut parameters);
+        if !injected.is_empty() {
+            log::debug!("Session {} filled {} for tool '{}'", session.id, injected.join(", "), tool_name);
+        }
+        request_context = Some(session.merged_context(req.context.as_ref()));
+    }
+    
     // Create a tool request
     let tool_request = ToolRequest {
         name: tool_name.clone(),
-        parameters: req.parameters.clone(),
-        context: req.context.clone(),
+        parameters,
+        context: request_context.clone(),
     };
     
-    // Create a tool context (in a real implementation, this would use authentication data)
     let context = ToolContext {
-        user_id: "test_user".to_string(), // Placeholder, would come from auth
+        user_id,
         request_id: Uuid::new_v4().to_string(),
-        context: req.context.clone().unwrap_or_default(),
+        context: request_context.unwrap_or_default(),
         is_admin: true, // Placeholder, would come from auth
+        progress: None,
     };
     
     // Check if the tool is marked as long running
     let is_long_running = tool.definition().is_long_running.unwrap_or(false);
+    let audit_entry = AuditRecord::new(&context, &tool_request, AuditStatus::Queued);
     
     if is_long_running {
-        // Create an operation to track the tool execution
-        let operation = Operation::new(context.user_id.clone(), tool_name.clone());
-        let operation_id = operation.id.clone();
-        
-        // Store the operation
-        operations_repo.add_operation(operation).await;
-        
-        // Clone dependencies for async task
-        let registry_clone = registry.get_ref().clone();
-        let tool_request_clone = tool_request.clone();
-        let context_clone = context.clone();
-        let operations_repo_clone = operations_repo.get_ref().clone();
-        let operation_id_clone = operation_id.clone();
-        
-        // Spawn an async task to execute the tool
-        task::spawn(async move {
-            // Get the operation and mark it as running
-            if let Some(mut operation) = operations_repo_clone.get_operation(&operation_id_clone).await {
-                operation.mark_running();
-                if let Err(e) = operations_repo_clone.update_operation(operation).await {
-                    log::error!("Failed to update operation status to running: {}", e);
-                    return;
-                }
-                
-                // Execute the tool
-                let result = crate::tools::execute_tool(registry_clone, tool_request_clone, context_clone).await;
-                
-                // Update operation with result
-                if let Some(mut operation) = operations_repo_clone.get_operation(&operation_id_clone).await {
-                    match result {
-                        Ok(response) => {
-                            operation.mark_completed(json!(response));
-                            if let Err(e) = operations_repo_clone.update_operation(operation).await {
-                                log::error!("Failed to update operation status to completed: {}", e);
-                            }
-                        },
-                        Err(error) => {
-                            let error_msg = format!("Tool execution failed: {}", error);
-                            operation.mark_failed(error_msg);
-                            if let Err(e) = operations_repo_clone.update_operation(operation).await {
-                                log::error!("Failed to update operation status to failed: {}", e);
-                            }
-                        }
-                    }
+        // Queue the execution on the operation executor
+        match executor.submit(tool_request, context).await {
+            Ok(operation_id) => {
+                audit.record(audit_entry.with_operation(operation_id.clone())).await;
+                if let Some(session) = session.as_mut() {
+                    session.record_invocation(&tool_name, Some(operation_id.clone()), None);
+                    save_session(&sessions, session).await;
                 }
+                HttpResponse::Accepted().json(ApiResponse::success(AsyncToolResponse {
+                    operation_id,
+                    status: "queued".to_string(),
+                    message: format!("Tool '{}' execution has been queued", tool_name),
+                }))
             }
-        });
-        
-        // Return immediate response with operation ID
-        HttpResponse::Accepted().json(ApiResponse::success(AsyncToolResponse {
-            operation_id,
-            status: "queued".to_string(),
-            message: format!("Tool '{}' execution has been queued", tool_name),
-        }))
+            Err(error @ ToolError::QuotaExceeded { .. }) => {
+                audit.record(audit_entry.outcome(AuditStatus::Rejected, Some(error.to_string()))).await;
+                tool_error_response(&tool_name, error)
+            }
+            Err(error) => {
+                audit.record(audit_entry.outcome(error_status(&error), Some(error.to_string()))).await;
+                HttpResponse::InternalServerError().json(ApiResponse::<()>::error(
+                    format!("Failed to queue tool '{}': {}", tool_name, error)
+                ))
+            }
+        }
     } else {
+        // Check the caller's quota before running the tool
+        if let Err(error) = quota.check(&context, &tool_request).await {
+            audit.record(audit_entry.outcome(AuditStatus::Rejected, Some(error.to_string()))).await;
+            return tool_error_response(&tool_name, error);
+        }
+        
         // Execute the tool synchronously for non-long-running tools
+        let metering = (tool_request.clone(), context.clone());
         match crate::tools::execute_tool(registry.get_ref().clone(), tool_request, context).await {
-            Ok(response) => HttpResponse::Ok().json(ApiResponse::success(response)),
-            Err(error) => {
-                match error {
-                    ToolError::NotFound(_) => 
-                        HttpResponse::NotFound().json(ApiResponse::<()>::error(format!("Tool '{}' not found", tool_name))),
-                    ToolError::InvalidParameters(msg) => 
-                        HttpResponse::BadRequest().json(ApiResponse::<()>::error(msg)),
-                    ToolError::ExecutionFailed(msg) => 
-                        HttpResponse::InternalServerError().json(ApiResponse::<()>::error(msg)),
-                    ToolError::Timeout => 
-                        HttpResponse::GatewayTimeout().json(ApiResponse::<()>::error("Tool execution timed out")),
-                    ToolError::Forbidden(msg) =>
-                        HttpResponse::Forbidden().json(ApiResponse::<()>::error(msg)),
-                    ToolError::RegistrationFailed(_) => 
-                        HttpResponse::InternalServerError().json(ApiResponse::<()>::error("Internal server error")),
+            Ok(response) => {
+                let status = if response.error.is_none() { AuditStatus::Succeeded } else { AuditStatus::Failed };
+                audit.record(audit_entry.outcome(status, response.error.clone())).await;
+                if response.error.is_none() {
+                    quota.meter(&metering.1, &metering.0).await;
+                    if let Some(session) = session.as_mut() {
+                        session.record_invocation(&tool_name, None, response.result.as_ref());
+                        save_session(&sessions, session).await;
+                    }
                 }
+                HttpResponse::Ok().json(ApiResponse::success(response))
+            }
+            Err(error) => {
+                audit.record(audit_entry.outcome(error_status(&error), Some(error.to_string()))).await;
+                tool_error_response(&tool_name, error)
             }
         }
     }
-} 
\ No newline at end of file
+}
+
+/// Store what a tool call changed in its session, the call itself already succeeded
+async fn save_session(sessions: &SessionStore, session: &Session) {
+    if let Err(e) = sessions.update_session(session.clone()).await {
+        log::warn!("Failed to update session after tool call: {}", e);
+    }
+}
+
+/// Response for a manifest reload
+#[derive(serde::Serialize)]
+pub struct ReloadManifestsResponse {
+    pub tools: Vec<String>,
+}
+
+/// Admin handler for reloading tools from external manifests
+pub async fn reload_manifests(
+    req: HttpRequest,
+    loader: web::Data<Arc<ManifestLoader>>,
+) -> impl Responder {
+    let auth_data = req.extensions().get::<AuthData>().cloned();
+    let authorized = match &auth_data {
+        Some(auth_data) => check_authorization(auth_data, "tools", "reload").await.unwrap_or(false),
+        None => false,
+    };
+    if !authorized {
+        return HttpResponse::Forbidden().json(ApiResponse::<()>::error(
+            "Admin permission is required to reload tool manifests"
+        ));
+    }
+
+    match loader.load() {
+        Ok(tools) => {
+            log::info!("Reloaded {} manifest tools", tools.len());
+            HttpResponse::Ok().json(ApiResponse::success(ReloadManifestsResponse { tools }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::error(
+            format!("Failed to reload tool manifests: {}", e)
+        )),
+    }
+}
+
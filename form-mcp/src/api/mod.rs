@@ -12,7 +12,12 @@ use actix_web::middleware::Compress;
 use actix_cors::Cors;
 use log::info;
 use crate::config::Settings;
-use crate::tools::ToolRegistry;
+use crate::tools::{ManifestLoader, ToolRegistry};
+use crate::models::operations::{OperationExecutor, create_repository};
+use crate::models::sessions::create_store;
+use crate::models::audit;
+use crate::billing::QuotaEnforcer;
+use crate::ratelimit::{RateLimitMiddleware, RateLimiter};
 use crate::auth;
 
 /// Initialize the API server with the appropriate routes and middleware
@@ -20,8 +25,52 @@ pub async fn init_server(
     settings: Arc<Settings>,
     tool_registry: Arc<ToolRegistry>,
 ) -> std::io::Result<()> {
+    // Load tools described by external manifests
+    let manifest_loader = ManifestLoader::new(
+        settings.tools.manifest_dir.as_ref().map(std::path::PathBuf::from),
+        tool_registry.clone(),
+    );
+    match manifest_loader.load() {
+        Ok(loaded) if !loaded.is_empty() => info!("Loaded {} manifest tools: {}", loaded.len(), loaded.join(", ")),
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to load tool manifests: {}", e),
+    }
+    let manifest_loader_data = web::Data::new(Arc::new(manifest_loader));
+    
+    // Create the rate limiter every API call is charged to
+    let rate_limiter = Arc::new(RateLimiter::new(settings.rate_limits.clone()));
+    info!("Rate limiting enabled: {}", rate_limiter.is_enabled());
+    let rate_limiter_data = web::Data::new(rate_limiter.clone());
+    
+    // Create the quota enforcer used to check and meter tool invocations
+    let quota = Arc::new(QuotaEnforcer::new(settings.billing.clone()).with_rate_limiter(rate_limiter.clone()));
+    info!("Quota enforcement enabled: {}", quota.is_enabled());
+    let quota_data = web::Data::new(quota.clone());
+    
+    // Create the audit trail every tool invocation is recorded in
+    let audit_store = audit::create_store(settings.audit.clone());
+    info!("Audit export to the queue enabled: {}", settings.audit.export_enabled);
+    let audit_store_data = web::Data::new(audit_store.clone());
+    
+    // Create the operations repository and executor shared by all workers
+    let operations_repository = create_repository();
+    let executor = OperationExecutor::new(
+        operations_repository.clone(),
+        tool_registry.clone(),
+        settings.operations.max_concurrent,
+    ).with_quota(quota).with_audit(audit_store);
+    let operations_repository_data = web::Data::new(operations_repository);
+    let executor_data = web::Data::new(executor);
+    
+    // Create the session store agents keep their context in
+    let session_store_data = web::Data::new(create_store(
+        std::time::Duration::from_secs(settings.sessions.default_ttl_secs),
+        std::time::Duration::from_secs(settings.sessions.max_ttl_secs),
+    ));
+    
     // Create a tool registry data object
     let tool_registry_data = web::Data::new(tool_registry);
+    let settings_data = web::Data::new(settings.clone());
     
     // Get server settings
     let host = settings.server.host.clone();
@@ -55,12 +104,29 @@ pub async fn init_server(
         App::new()
             // Register the tool registry
             .app_data(tool_registry_data.clone())
+            // Register the operations repository and executor
+            .app_data(operations_repository_data.clone())
+            .app_data(executor_data.clone())
+            // Register the session store
+            .app_data(session_store_data.clone())
+            // Register the quota enforcer
+            .app_data(quota_data.clone())
+            // Register the audit store
+            .app_data(audit_store_data.clone())
+            // Register the manifest loader used by the admin reload endpoint
+            .app_data(manifest_loader_data.clone())
+            // Register the settings, the passkey login forwards to form-state
+            .app_data(settings_data.clone())
+            // Register the rate limiter used by the admin metrics endpoint
+            .app_data(rate_limiter_data.clone())
             // Set request timeout
             .app_data(web::PayloadConfig::new(settings.server.request_timeout as usize))
             // Enable compression
             .wrap(Compress::default())
             // Add CORS middleware
             .wrap(cors)
+            // Add rate limiting middleware, it runs after authentication
+            .wrap(RateLimitMiddleware::new(rate_limiter.clone()))
             // Add authentication middleware
             .wrap(auth_middleware.clone())
             // Configure routes
@@ -4,15 +4,13 @@
 
 use actix_web::{web, HttpResponse, Responder};
 use crate::api::health_check;
-use crate::api::handlers::{tools, operations, auth};
-use crate::models::operations::{OperationsRepository, create_repository};
+use crate::api::handlers::{tools, operations, auth, sessions, audit, rate_limits};
 
 /// Configure API routes for the MCP server
+///
+/// The operations repository, executor, session store and audit store are
+/// shared across workers and registered as app data in `init_server`.
 pub fn configure(cfg: &mut web::ServiceConfig) {
-    // Create and register the operations repository
-    let operations_repository = create_repository();
-    cfg.app_data(web::Data::new(operations_repository));
-    
     cfg
         // Health check endpoint
         .route("/health", web::get().to(health_check))
@@ -25,6 +23,7 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
                     web::scope("/auth")
                         .route("/login", web::post().to(auth::login))
                         .route("/validate", web::post().to(auth::validate_token))
+                        .route("/passkey", web::post().to(auth::passkey_login))
                 )
                 
                 // Tool discovery and execution
@@ -33,7 +32,27 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
                 
                 // Operation status endpoints
                 .route("/operations/{id}", web::get().to(operations::get_operation_status))
+                .route("/operations/{id}/events", web::get().to(operations::operation_events))
+                .route("/operations/{id}/cancel", web::post().to(operations::cancel_operation))
                 .route("/operations", web::get().to(operations::list_operations))
+                
+                // Session endpoints
+                .route("/sessions", web::post().to(sessions::create_session))
+                .route("/sessions", web::get().to(sessions::list_sessions))
+                .route("/sessions/{id}", web::get().to(sessions::get_session))
+                .route("/sessions/{id}", web::patch().to(sessions::update_session))
+                .route("/sessions/{id}/expire", web::post().to(sessions::expire_session))
+                
+                // Audit trail endpoints
+                .route("/audit", web::get().to(audit::list_audit_records))
+                .route("/audit/export", web::get().to(audit::export_audit_records))
+                
+                // Administration endpoints
+                .service(
+                    web::scope("/admin")
+                        .route("/tools/reload", web::post().to(tools::reload_manifests))
+                        .route("/rate-limits", web::get().to(rate_limits::rate_limit_metrics))
+                )
         )
         
         // Version endpoint
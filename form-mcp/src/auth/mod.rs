@@ -227,7 +227,7 @@ where
         Box::pin(async move {
             // Skip authentication for certain paths
             let path = req.path();
-            if path == "/health" || path == "/api/v1/health" || path.starts_with("/public") {
+            if path == "/health" || path == "/api/v1/health" || path == "/api/auth/passkey" || path.starts_with("/public") {
                 return svc.call(req).await;
             }
             
@@ -1,6 +1,18 @@
 // Billing module for the MCP server
 //
 // This module handles billing and payment integration for the MCP server.
+// Tool invocations are checked against the caller's quota in form-state
+// before they run and metered as usage events after they succeed.
+
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::BillingSettings;
+use crate::errors::ToolError;
+use crate::ratelimit::RateLimiter;
+use crate::tools::{ToolContext, ToolRequest};
 
 /// Represents a billing record for resource usage
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -19,4 +31,229 @@ pub struct BillingRecord {
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
-// The full billing system will be implemented in future sub-tasks 
\ No newline at end of file
+/// Eligibility check sent to form-state before a tool runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EligibilityCheck {
+    /// Name of the tool being invoked
+    pub operation: String,
+    /// Credits the invocation will cost
+    pub credit_cost: u64,
+    /// Model the tool will use, if any
+    pub model_id: Option<String>,
+}
+
+/// Usage event reported to form-state after a tool succeeds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolUsageEvent {
+    /// Service reporting the usage
+    pub source: String,
+    /// Name of the tool that was invoked
+    pub operation: String,
+    /// Credits consumed by the invocation
+    pub credits: u64,
+    /// Model used by the tool, if any
+    pub model_id: Option<String>,
+}
+
+/// Enforces per-agent quotas for tool invocations using the form-state billing API
+pub struct QuotaEnforcer {
+    client: reqwest::Client,
+    settings: BillingSettings,
+    /// Told the tier form-state reports for each agent
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl QuotaEnforcer {
+    /// Create a new quota enforcer
+    pub fn new(settings: BillingSettings) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+        Self { client, settings, rate_limiter: None }
+    }
+
+    /// Pass the tiers of agents on to the rate limiter
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Whether quotas are enforced
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    /// Credit cost of invoking a tool
+    pub fn tool_cost(&self, tool_name: &str) -> u64 {
+        self.settings.tool_costs
+            .get(tool_name)
+            .copied()
+            .unwrap_or(self.settings.default_tool_cost)
+    }
+
+    /// Check that the caller can afford the tool invocation
+    ///
+    /// Returns `ToolError::QuotaExceeded` with the structured reason from
+    /// form-state if the caller is out of credits, over a tier limit or not
+    /// allowed to use the requested model.
+    pub async fn check(&self, context: &ToolContext, request: &ToolRequest) -> Result<(), ToolError> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+
+        let check = EligibilityCheck {
+            operation: request.name.clone(),
+            credit_cost: self.tool_cost(&request.name),
+            model_id: model_id(request),
+        };
+
+        let response = self.client
+            .post(self.url(&context.user_id, "eligibility"))
+            .json(&check)
+            .send()
+            .await
+            .map_err(|e| ToolError::ExecutionFailed(format!("Billing service unavailable: {}", e)))?;
+
+        if response.status().is_success() {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                let body: Value = response.json().await.unwrap_or_default();
+                // Accounts without a subscription are on the free tier
+                let tier = body.get("tier").and_then(Value::as_str).unwrap_or("free");
+                rate_limiter.set_tier(&context.user_id, tier);
+            }
+            return Ok(());
+        }
+
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or_default();
+        if status.is_server_error() {
+            return Err(ToolError::ExecutionFailed(format!(
+                "Billing service error: {}",
+                body.get("message").and_then(Value::as_str).unwrap_or("unknown error")
+            )));
+        }
+
+        Err(quota_error(&request.name, body))
+    }
+
+    /// Meter a successful tool invocation against the caller's account
+    ///
+    /// Failures are logged rather than returned, the tool has already run.
+    pub async fn meter(&self, context: &ToolContext, request: &ToolRequest) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        let event = ToolUsageEvent {
+            source: "form-mcp".to_string(),
+            operation: request.name.clone(),
+            credits: self.tool_cost(&request.name),
+            model_id: model_id(request),
+        };
+
+        let result = self.client
+            .post(self.url(&context.user_id, "usage"))
+            .json(&event)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(e) = result {
+            log::error!(
+                "Failed to meter usage of tool '{}' for {} (request {}): {}",
+                request.name, context.user_id, context.request_id, e
+            );
+        }
+    }
+
+    fn url(&self, user_id: &str, endpoint: &str) -> String {
+        format!("{}/v1/billing/{}/{}", self.settings.state_url.trim_end_matches('/'), user_id, endpoint)
+    }
+}
+
+/// Model requested by a tool invocation, passed as the `model_id` parameter
+fn model_id(request: &ToolRequest) -> Option<String> {
+    request.parameters
+        .get("model_id")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/// Build a `QuotaExceeded` error from a form-state eligibility error body
+fn quota_error(tool_name: &str, body: Value) -> ToolError {
+    let code = body.get("error")
+        .and_then(Value::as_str)
+        .unwrap_or("quota_exceeded")
+        .to_string();
+    let message = body.get("message")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("Quota exceeded for tool '{}'", tool_name));
+    let mut details = body.get("details").cloned().unwrap_or_else(|| json!({}));
+    if let Some(details) = details.as_object_mut() {
+        details.insert("tool".to_string(), json!(tool_name));
+    }
+
+    ToolError::QuotaExceeded { code, message, details }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_cost_uses_override_or_default() {
+        let settings = BillingSettings {
+            default_tool_cost: 2,
+            tool_costs: [("vm_create".to_string(), 10)].into_iter().collect(),
+            ..BillingSettings::default()
+        };
+        let enforcer = QuotaEnforcer::new(settings);
+
+        assert_eq!(enforcer.tool_cost("vm_create"), 10);
+        assert_eq!(enforcer.tool_cost("vm_list"), 2);
+    }
+
+    #[test]
+    fn test_quota_error_from_eligibility_response() {
+        let body = json!({
+            "error": "insufficient_credits",
+            "message": "Insufficient credits to perform this operation",
+            "details": { "required_credits": 10, "available_credits": 3 }
+        });
+
+        match quota_error("vm_create", body) {
+            ToolError::QuotaExceeded { code, message, details } => {
+                assert_eq!(code, "insufficient_credits");
+                assert_eq!(message, "Insufficient credits to perform this operation");
+                assert_eq!(details["required_credits"], 10);
+                assert_eq!(details["tool"], "vm_create");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_enforcer_allows_everything() {
+        let settings = BillingSettings {
+            enabled: false,
+            ..BillingSettings::default()
+        };
+        let enforcer = QuotaEnforcer::new(settings);
+        let context = ToolContext {
+            user_id: "0xabc".to_string(),
+            request_id: "req".to_string(),
+            context: Default::default(),
+            is_admin: false,
+            progress: None,
+        };
+        let request = ToolRequest {
+            name: "vm_create".to_string(),
+            parameters: json!({}),
+            context: None,
+        };
+
+        assert!(enforcer.check(&context, &request).await.is_ok());
+    }
+}
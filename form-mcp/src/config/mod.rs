@@ -5,7 +5,7 @@
 
 mod settings;
 
-pub use settings::Settings;
+pub use settings::{AuditSettings, BillingSettings, RateLimitSettings, Settings, TierLimits};
 
 use std::path::Path;
 use std::sync::Arc;
@@ -2,6 +2,7 @@
 //
 // This module contains the settings and configuration for the MCP server.
 
+use std::collections::HashMap;
 use std::path::Path;
 use anyhow::Result;
 use serde::{Serialize, Deserialize};
@@ -79,6 +80,144 @@ impl Default for DatabaseSettings {
     }
 }
 
+/// Long-running operation settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperationsSettings {
+    /// Maximum number of operations executed concurrently
+    pub max_concurrent: usize,
+}
+
+impl Default for OperationsSettings {
+    fn default() -> Self {
+        Self {
+            max_concurrent: defaults::MAX_CONCURRENT_OPERATIONS,
+        }
+    }
+}
+
+/// Agent session settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    /// Seconds a session lives without being used, unless it asks for another TTL
+    pub default_ttl_secs: u64,
+    /// Longest TTL a session may ask for
+    pub max_ttl_secs: u64,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            default_ttl_secs: defaults::SESSION_TTL_SECS,
+            max_ttl_secs: defaults::MAX_SESSION_TTL_SECS,
+        }
+    }
+}
+
+/// Tool registry settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolSettings {
+    /// Directory containing JSON/TOML tool manifests loaded at startup
+    pub manifest_dir: Option<String>,
+}
+
+/// Billing and quota enforcement settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BillingSettings {
+    /// Enforce quotas and meter tool usage against form-state
+    pub enabled: bool,
+    /// Base URL of the form-state API
+    pub state_url: String,
+    /// Credit cost of a tool invocation without an explicit cost
+    pub default_tool_cost: u64,
+    /// Credit cost per tool name
+    pub tool_costs: HashMap<String, u64>,
+}
+
+impl Default for BillingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            state_url: defaults::STATE_API_URL.to_string(),
+            default_tool_cost: defaults::TOOL_CREDIT_COST,
+            tool_costs: HashMap::new(),
+        }
+    }
+}
+
+/// Audit trail settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditSettings {
+    /// Seconds audit records are kept queryable
+    pub retention_secs: u64,
+    /// Most records kept, the oldest are dropped first
+    pub max_records: usize,
+    /// Export records to the form-p2p queue for central aggregation
+    pub export_enabled: bool,
+    /// Base URL of the form-p2p queue API
+    pub queue_url: String,
+    /// Seconds between exports
+    pub export_interval_secs: u64,
+    /// Most records written to the queue in one message
+    pub export_batch_size: usize,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            retention_secs: defaults::AUDIT_RETENTION_SECS,
+            max_records: defaults::AUDIT_MAX_RECORDS,
+            export_enabled: true,
+            queue_url: defaults::QUEUE_API_URL.to_string(),
+            export_interval_secs: 30,
+            export_batch_size: 500,
+        }
+    }
+}
+
+/// Limits of one subscription tier
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TierLimits {
+    /// Sustained requests per second
+    pub requests_per_second: f64,
+    /// Requests that may be made at once above the sustained rate
+    pub burst: u32,
+    /// Requests per UTC day, unlimited if unset
+    #[serde(default)]
+    pub daily_budget: Option<u64>,
+}
+
+/// Per-agent rate limits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Throttle agents that exceed their tier's limits
+    pub enabled: bool,
+    /// Tier of agents whose tier form-state hasn't reported yet
+    pub default_tier: String,
+    /// Limits by lowercase tier name, as form-state names the tiers
+    pub tiers: HashMap<String, TierLimits>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let tier = |requests_per_second, burst, daily_budget| TierLimits { requests_per_second, burst, daily_budget };
+        Self {
+            enabled: true,
+            default_tier: "free".to_string(),
+            tiers: HashMap::from([
+                ("free".to_string(), tier(2.0, 10, Some(2_000))),
+                ("pro".to_string(), tier(5.0, 20, Some(20_000))),
+                ("proplus".to_string(), tier(10.0, 40, Some(50_000))),
+                ("power".to_string(), tier(20.0, 80, Some(200_000))),
+                ("powerplus".to_string(), tier(50.0, 200, None)),
+            ]),
+        }
+    }
+}
+
 /// Main settings structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
@@ -90,6 +229,24 @@ pub struct Settings {
     pub auth: AuthSettings,
     /// Database settings
     pub database: DatabaseSettings,
+    /// Operation executor settings
+    #[serde(default)]
+    pub operations: OperationsSettings,
+    /// Agent session settings
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    /// Tool registry settings
+    #[serde(default)]
+    pub tools: ToolSettings,
+    /// Billing settings
+    #[serde(default)]
+    pub billing: BillingSettings,
+    /// Audit trail settings
+    #[serde(default)]
+    pub audit: AuditSettings,
+    /// Rate limit settings
+    #[serde(default)]
+    pub rate_limits: RateLimitSettings,
     /// Log level
     pub log_level: String,
 }
@@ -101,6 +258,12 @@ impl Default for Settings {
             server: ServerSettings::default(),
             auth: AuthSettings::default(),
             database: DatabaseSettings::default(),
+            operations: OperationsSettings::default(),
+            sessions: SessionSettings::default(),
+            tools: ToolSettings::default(),
+            billing: BillingSettings::default(),
+            audit: AuditSettings::default(),
+            rate_limits: RateLimitSettings::default(),
             log_level: "info".to_string(),
         }
     }
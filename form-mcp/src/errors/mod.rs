@@ -93,6 +93,22 @@ pub enum ToolError {
     
     #[error("Forbidden: {0}")]
     Forbidden(String),
+
+    #[error("Quota exceeded: {message}")]
+    QuotaExceeded {
+        /// Machine readable reason (e.g. "insufficient_credits")
+        code: String,
+        message: String,
+        /// Structured details returned by the billing service
+        details: serde_json::Value,
+    },
+
+    #[error("{message}")]
+    Maintenance {
+        message: String,
+        /// The freeze as reported by form-state
+        details: serde_json::Value,
+    },
 }
 
 // Implement ResponseError for ServerError to convert it to HTTP responses
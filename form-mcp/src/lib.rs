@@ -10,6 +10,7 @@ pub mod events;
 pub mod models;
 pub mod config;
 pub mod billing;
+pub mod ratelimit;
 pub mod errors;
 
 use std::sync::Arc;
@@ -28,6 +29,22 @@ pub mod defaults {
     pub const REQUEST_TIMEOUT_SECS: u64 = 60;
     /// Default number of worker threads (0 = auto)
     pub const WORKERS: usize = 0;
+    /// Default number of long-running operations executed concurrently
+    pub const MAX_CONCURRENT_OPERATIONS: usize = 4;
+    /// Default seconds an agent session lives without being used
+    pub const SESSION_TTL_SECS: u64 = 1800;
+    /// Longest TTL a session may ask for
+    pub const MAX_SESSION_TTL_SECS: u64 = 86_400;
+    /// Default URL of the form-state API used for billing
+    pub const STATE_API_URL: &str = "http://127.0.0.1:3004";
+    /// Default credit cost of a tool invocation
+    pub const TOOL_CREDIT_COST: u64 = 1;
+    /// Default seconds audit records are kept (90 days)
+    pub const AUDIT_RETENTION_SECS: u64 = 90 * 86_400;
+    /// Default number of audit records kept in memory
+    pub const AUDIT_MAX_RECORDS: usize = 100_000;
+    /// Default URL of the form-p2p queue API audit records are exported to
+    pub const QUEUE_API_URL: &str = "http://127.0.0.1:53333";
 }
 
 /// Gracefully shuts down the MCP server
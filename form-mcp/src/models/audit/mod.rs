@@ -0,0 +1,183 @@
+// Audit module
+//
+// This module records every tool invocation made by an agent so operators
+// can see what agents did to their infrastructure. Records are kept in a
+// queryable store for the configured retention and exported to the form-p2p
+// queue for central aggregation.
+
+mod store;
+#[cfg(test)]
+mod tests;
+
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::models::operations::OperationStatus;
+use crate::tools::{ToolContext, ToolRequest};
+
+pub use store::{AuditStore, create_store};
+
+/// Outcome of a tool invocation
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditStatus {
+    /// The tool ran and reported success
+    Succeeded,
+    /// The tool ran and failed
+    Failed,
+    /// The call was refused before the tool ran, e.g. by quotas or validation
+    Rejected,
+    /// The call was queued as a long-running operation
+    Queued,
+    /// The operation was cancelled before it finished
+    Cancelled,
+}
+
+impl AuditStatus {
+    /// The status recording how an operation ended, None while it is still going
+    pub fn from_operation(status: &OperationStatus) -> Option<Self> {
+        match status {
+            OperationStatus::Completed => Some(Self::Succeeded),
+            OperationStatus::Failed => Some(Self::Failed),
+            OperationStatus::Cancelled => Some(Self::Cancelled),
+            OperationStatus::Queued | OperationStatus::Running => None,
+        }
+    }
+}
+
+/// One tool invocation, or the end of the operation it started
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditRecord {
+    /// Unique identifier for the record
+    pub id: String,
+    /// Unix timestamp of the invocation or outcome
+    pub timestamp: u64,
+    /// User ID of the agent that made the call
+    pub agent_id: String,
+    /// Session the call was made in, if any
+    pub session_id: Option<String>,
+    /// Name of the tool invoked
+    pub tool: String,
+    /// SHA-256 of the parameters, so calls can be correlated without storing
+    /// secrets agents pass to tools
+    pub parameters_hash: String,
+    /// Outcome of the call
+    pub status: AuditStatus,
+    /// Operation tracking the call, for long-running tools
+    pub operation_id: Option<String>,
+    /// Request ID the call was executed with
+    pub request_id: String,
+    /// Why the call failed or was rejected
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// Create a record of a call made with `context`
+    pub fn new(context: &ToolContext, request: &ToolRequest, status: AuditStatus) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            timestamp: unix_now(),
+            agent_id: context.user_id.clone(),
+            session_id: context.context.get("session_id").cloned(),
+            tool: request.name.clone(),
+            parameters_hash: hash_parameters(&request.parameters),
+            status,
+            operation_id: None,
+            request_id: context.request_id.clone(),
+            error: None,
+        }
+    }
+
+    /// Attach the operation tracking the call
+    pub fn with_operation(mut self, operation_id: impl Into<String>) -> Self {
+        self.operation_id = Some(operation_id.into());
+        self
+    }
+
+    /// Attach the reason the call failed
+    pub fn with_error(mut self, error: impl Into<String>) -> Self {
+        self.error = Some(error.into());
+        self
+    }
+
+    /// A later record of the same call with another outcome
+    pub fn outcome(&self, status: AuditStatus, error: Option<String>) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            timestamp: unix_now(),
+            status,
+            error,
+            ..self.clone()
+        }
+    }
+}
+
+/// Hex SHA-256 of the parameters' JSON. Object keys are serialized in sorted
+/// order, so the same parameters always hash the same.
+pub fn hash_parameters(parameters: &Value) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(canonical_json(parameters).as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<_> = map.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys.into_iter()
+                .map(|key| format!("{}:{}", Value::String(key.clone()), canonical_json(&map[key])))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Filter for querying and exporting audit records
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    /// Only records of this agent
+    pub agent_id: Option<String>,
+    /// Only records of this tool
+    pub tool: Option<String>,
+    /// Only records with this outcome
+    pub status: Option<AuditStatus>,
+    /// Only records of this operation
+    pub operation_id: Option<String>,
+    /// Only records at or after this Unix timestamp
+    pub since: Option<u64>,
+    /// Only records before this Unix timestamp
+    pub until: Option<u64>,
+    /// Return at most this many records, newest first
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    /// Check if a record passes the filter
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        fn allows<T>(filter: &Option<T>, check: impl FnOnce(&T) -> bool) -> bool {
+            filter.as_ref().map_or(true, check)
+        }
+        allows(&self.agent_id, |agent_id| &record.agent_id == agent_id)
+            && allows(&self.tool, |tool| &record.tool == tool)
+            && allows(&self.status, |status| &record.status == status)
+            && allows(&self.operation_id, |id| record.operation_id.as_ref() == Some(id))
+            && allows(&self.since, |since| record.timestamp >= *since)
+            && allows(&self.until, |until| record.timestamp < *until)
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,199 @@
+// Audit store
+//
+// This module keeps audit records in memory for the configured retention and
+// ships them in batches to the form-p2p queue, where they are aggregated
+// across nodes. Records that fail to export are retried on the next run.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::{json, Value};
+use tiny_keccak::{Hasher, Sha3};
+use tokio::sync::{Mutex, RwLock};
+
+use super::{unix_now, AuditQuery, AuditRecord};
+use crate::config::AuditSettings;
+
+/// Queue topic audit records are exported on
+pub const AUDIT_TOPIC: &str = "mcp_audit";
+
+/// Store for audit records
+#[derive(Debug, Clone)]
+pub struct AuditStore {
+    records: Arc<RwLock<VecDeque<AuditRecord>>>,
+    /// Records not yet exported, oldest first
+    pending_export: Arc<Mutex<VecDeque<AuditRecord>>>,
+    settings: AuditSettings,
+    client: reqwest::Client,
+}
+
+impl AuditStore {
+    /// Create a new audit store, starting the retention and export tasks
+    pub fn new(settings: AuditSettings) -> Self {
+        let store = Self {
+            records: Arc::new(RwLock::new(VecDeque::new())),
+            pending_export: Arc::new(Mutex::new(VecDeque::new())),
+            settings,
+            client: reqwest::Client::new(),
+        };
+
+        store.start_background_tasks();
+
+        store
+    }
+
+    /// Add a record to the store and the export backlog
+    pub async fn record(&self, record: AuditRecord) {
+        log::debug!(
+            "Audit: agent {} invoked '{}' ({:?}{})",
+            record.agent_id,
+            record.tool,
+            record.status,
+            record.operation_id.as_ref().map(|id| format!(", operation {}", id)).unwrap_or_default(),
+        );
+
+        if self.settings.export_enabled {
+            let mut pending = self.pending_export.lock().await;
+            pending.push_back(record.clone());
+            // An unreachable queue must not grow the backlog without bound
+            while pending.len() > self.settings.max_records {
+                pending.pop_front();
+                log::warn!("Audit export backlog is full, dropped the oldest record");
+            }
+        }
+
+        let mut records = self.records.write().await;
+        records.push_back(record);
+        while records.len() > self.settings.max_records {
+            records.pop_front();
+        }
+    }
+
+    /// Records matching the query, newest first
+    pub async fn query(&self, query: &AuditQuery) -> Vec<AuditRecord> {
+        let records = self.records.read().await;
+        let matching = records.iter().rev().filter(|record| query.matches(record)).cloned();
+        match query.limit {
+            Some(limit) => matching.take(limit).collect(),
+            None => matching.collect(),
+        }
+    }
+
+    /// Drop records older than the retention period
+    pub async fn apply_retention(&self, now: u64) -> usize {
+        let cutoff = now.saturating_sub(self.settings.retention_secs);
+        let mut records = self.records.write().await;
+        let before = records.len();
+        records.retain(|record| record.timestamp >= cutoff);
+        before - records.len()
+    }
+
+    /// Number of records waiting to be exported
+    pub async fn pending_exports(&self) -> usize {
+        self.pending_export.lock().await.len()
+    }
+
+    /// Write the next batch of pending records to the queue, returning how
+    /// many were exported. A failed batch is put back for the next attempt.
+    pub async fn export_pending(&self) -> Result<usize, String> {
+        let batch: Vec<AuditRecord> = {
+            let mut pending = self.pending_export.lock().await;
+            let n = pending.len().min(self.settings.export_batch_size.max(1));
+            pending.drain(..n).collect()
+        };
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        match self.write_to_queue(&batch).await {
+            Ok(()) => Ok(batch.len()),
+            Err(e) => {
+                let mut pending = self.pending_export.lock().await;
+                for record in batch.into_iter().rev() {
+                    pending.push_front(record);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Write a batch with the queue's `write_local` request, which takes the
+    /// JSON of the batch as bytes under the hashed topic
+    async fn write_to_queue(&self, batch: &[AuditRecord]) -> Result<(), String> {
+        let content = serde_json::to_vec(batch).map_err(|e| e.to_string())?;
+        let request = json!({
+            "Write": {
+                "content": content,
+                "topic": topic_hash(AUDIT_TOPIC),
+            }
+        });
+
+        let response: Value = self.client
+            .post(format!("{}/queue/write_local", self.settings.queue_url.trim_end_matches('/')))
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match response {
+            Value::String(ref variant) if variant == "OpSuccess" => Ok(()),
+            Value::Object(ref map) if map.contains_key("Failure") => {
+                Err(format!("Queue rejected audit records: {}", map["Failure"]))
+            }
+            other => Err(format!("Unexpected queue response: {}", other)),
+        }
+    }
+
+    /// Start the retention and export tasks
+    fn start_background_tasks(&self) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                let dropped = store.apply_retention(unix_now()).await;
+                if dropped > 0 {
+                    log::info!("Dropped {} audit records past retention", dropped);
+                }
+            }
+        });
+
+        if !self.settings.export_enabled {
+            return;
+        }
+        let store = self.clone();
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(store.settings.export_interval_secs.max(1));
+            loop {
+                tokio::time::sleep(interval).await;
+                // Drain the backlog while the queue keeps accepting batches
+                loop {
+                    match store.export_pending().await {
+                        Ok(0) => break,
+                        Ok(n) => log::debug!("Exported {} audit records", n),
+                        Err(e) => {
+                            log::warn!("Failed to export audit records: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Hex SHA3-256 of a topic name, the form topics are addressed by in the queue
+fn topic_hash(topic: &str) -> String {
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(topic.as_bytes());
+    hasher.finalize(&mut hash);
+    hex::encode(hash)
+}
+
+/// Create a new shared audit store
+pub fn create_store(settings: AuditSettings) -> Arc<AuditStore> {
+    Arc::new(AuditStore::new(settings))
+}
@@ -0,0 +1,80 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::collections::HashMap;
+    use crate::config::AuditSettings;
+    use crate::models::audit::{hash_parameters, AuditQuery, AuditRecord, AuditStatus, AuditStore};
+    use crate::tools::{ToolContext, ToolRequest};
+
+    fn invocation(user_id: &str, tool: &str) -> (ToolContext, ToolRequest) {
+        let context = ToolContext {
+            user_id: user_id.to_string(),
+            request_id: "req-1".to_string(),
+            context: HashMap::from([("session_id".to_string(), "session-1".to_string())]),
+            is_admin: false,
+            progress: None,
+        };
+        let request = ToolRequest {
+            name: tool.to_string(),
+            parameters: json!({ "vm_id": "vm-1", "force": true }),
+            context: None,
+        };
+        (context, request)
+    }
+
+    fn settings() -> AuditSettings {
+        AuditSettings {
+            retention_secs: 3600,
+            max_records: 3,
+            export_enabled: false,
+            ..AuditSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_parameters_hash_ignores_key_order() {
+        let a = hash_parameters(&json!({ "a": 1, "b": { "c": [1, 2], "d": "x" } }));
+        let b = hash_parameters(&json!({ "b": { "d": "x", "c": [1, 2] }, "a": 1 }));
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, hash_parameters(&json!({ "a": 2 })));
+    }
+
+    #[tokio::test]
+    async fn test_query_and_retention() {
+        let store = AuditStore::new(settings());
+
+        let (context, request) = invocation("alice", "vm_delete");
+        let queued = AuditRecord::new(&context, &request, AuditStatus::Queued).with_operation("op-1");
+        assert_eq!(queued.session_id.as_deref(), Some("session-1"));
+        store.record(queued.clone()).await;
+        store.record(queued.outcome(AuditStatus::Failed, Some("boom".to_string()))).await;
+
+        let (context, request) = invocation("bob", "vm_list");
+        let mut old = AuditRecord::new(&context, &request, AuditStatus::Succeeded);
+        old.timestamp -= 7200;
+        store.record(old).await;
+
+        let alice = store.query(&AuditQuery { agent_id: Some("alice".to_string()), ..Default::default() }).await;
+        assert_eq!(alice.len(), 2);
+        // Newest first, both records point at the same operation
+        assert_eq!(alice[0].status, AuditStatus::Failed);
+        assert_eq!(alice[0].operation_id, alice[1].operation_id);
+        assert_eq!(alice[0].parameters_hash, alice[1].parameters_hash);
+
+        let failed = store.query(&AuditQuery { status: Some(AuditStatus::Failed), ..Default::default() }).await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(store.query(&AuditQuery { limit: Some(1), ..Default::default() }).await.len(), 1);
+
+        // Bob's record is past the hour of retention
+        assert_eq!(store.apply_retention(crate::models::audit::unix_now()).await, 1);
+        assert_eq!(store.query(&AuditQuery::default()).await.len(), 2);
+
+        // Only the newest max_records are kept
+        for _ in 0..3 {
+            store.record(queued.clone()).await;
+        }
+        assert_eq!(store.query(&AuditQuery::default()).await.len(), 3);
+        assert_eq!(store.pending_exports().await, 0);
+    }
+}
@@ -3,7 +3,9 @@
 // This module defines the data models used throughout the MCP server,
 // including MCP protocol structures and internal data representations.
 
+pub mod audit;
 pub mod operations;
+pub mod sessions;
 
 /// Represents a resource in the MCP protocol
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
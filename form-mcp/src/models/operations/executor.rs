@@ -0,0 +1,220 @@
+// Operations executor
+//
+// This module runs long-running tool executions in the background with a
+// bounded worker pool, supports cancellation and lets tools publish progress
+// through the operations repository.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde_json::json;
+use tokio::sync::{watch, RwLock, Semaphore};
+
+use super::{Operation, OperationsRepository};
+use crate::billing::QuotaEnforcer;
+use crate::models::audit::{AuditRecord, AuditStatus, AuditStore};
+use crate::errors::ToolError;
+use crate::tools::{ToolContext, ToolRegistry, ToolRequest};
+
+/// Handle passed to tools (via `ToolContext`) for reporting progress on an operation
+#[derive(Clone)]
+pub struct ProgressReporter {
+    repository: Arc<OperationsRepository>,
+    operation_id: String,
+}
+
+impl ProgressReporter {
+    /// Create a new progress reporter for an operation
+    pub fn new(repository: Arc<OperationsRepository>, operation_id: String) -> Self {
+        Self { repository, operation_id }
+    }
+
+    /// ID of the operation this reporter updates
+    pub fn operation_id(&self) -> &str {
+        &self.operation_id
+    }
+
+    /// Report progress (0.0 to 1.0) and a description of the current step
+    pub async fn report(&self, progress: f32, message: impl Into<String>) {
+        if let Some(mut operation) = self.repository.get_operation(&self.operation_id).await {
+            // Progress from a tool that is racing a cancellation must not revive the operation
+            if operation.is_finished() {
+                return;
+            }
+            operation.report_progress(progress, message.into());
+            if let Err(e) = self.repository.update_operation(operation).await {
+                log::error!("Failed to report progress for operation {}: {}", self.operation_id, e);
+            }
+        }
+    }
+}
+
+/// Executes long-running tools as operations
+#[derive(Clone)]
+pub struct OperationExecutor {
+    repository: Arc<OperationsRepository>,
+    registry: Arc<ToolRegistry>,
+    workers: Arc<Semaphore>,
+    cancellations: Arc<RwLock<HashMap<String, watch::Sender<bool>>>>,
+    quota: Option<Arc<QuotaEnforcer>>,
+    audit: Option<Arc<AuditStore>>,
+}
+
+impl OperationExecutor {
+    /// Create a new executor running at most `max_concurrent` operations at a time
+    pub fn new(
+        repository: Arc<OperationsRepository>,
+        registry: Arc<ToolRegistry>,
+        max_concurrent: usize,
+    ) -> Self {
+        Self {
+            repository,
+            registry,
+            workers: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            cancellations: Arc::new(RwLock::new(HashMap::new())),
+            quota: None,
+            audit: None,
+        }
+    }
+
+    /// Record how each operation ends in the audit trail
+    pub fn with_audit(mut self, audit: Arc<AuditStore>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Check quotas before queueing operations and meter them when they complete
+    pub fn with_quota(mut self, quota: Arc<QuotaEnforcer>) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// The repository operations are tracked in
+    pub fn repository(&self) -> Arc<OperationsRepository> {
+        self.repository.clone()
+    }
+
+    /// Queue a tool execution and return the ID of the operation tracking it
+    pub async fn submit(&self, request: ToolRequest, context: ToolContext) -> Result<String, ToolError> {
+        if self.registry.get_tool(&request.name).is_none() {
+            return Err(ToolError::NotFound(request.name.clone()));
+        }
+
+        if let Some(quota) = &self.quota {
+            quota.check(&context, &request).await?;
+        }
+
+        let operation = Operation::new(context.user_id.clone(), request.name.clone());
+        let operation_id = operation.id.clone();
+
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        self.cancellations.write().await.insert(operation_id.clone(), cancel_tx);
+        self.repository.add_operation(operation).await;
+
+        let executor = self.clone();
+        let id = operation_id.clone();
+        tokio::spawn(async move {
+            executor.run(id.clone(), request, context, cancel_rx).await;
+            executor.cancellations.write().await.remove(&id);
+        });
+
+        Ok(operation_id)
+    }
+
+    /// Request cancellation of a queued or running operation
+    pub async fn cancel(&self, operation_id: &str) -> Result<(), String> {
+        let cancellations = self.cancellations.read().await;
+        match cancellations.get(operation_id) {
+            Some(cancel_tx) => {
+                cancel_tx.send(true)
+                    .map_err(|_| format!("Operation with ID '{}' has already finished", operation_id))
+            }
+            None => Err(format!("Operation with ID '{}' is not running", operation_id)),
+        }
+    }
+
+    /// Run an operation to completion, failure or cancellation
+    async fn run(
+        &self,
+        operation_id: String,
+        request: ToolRequest,
+        mut context: ToolContext,
+        mut cancel_rx: watch::Receiver<bool>,
+    ) {
+        let audit_entry = AuditRecord::new(&context, &request, AuditStatus::Queued).with_operation(operation_id.clone());
+
+        // Wait for a free worker
+        let _permit = tokio::select! {
+            permit = self.workers.clone().acquire_owned() => match permit {
+                Ok(permit) => permit,
+                Err(_) => {
+                    self.finish_audited(&operation_id, &audit_entry, |op| op.mark_failed("Executor has shut down".to_string())).await;
+                    return;
+                }
+            },
+            _ = cancel_rx.changed() => {
+                self.finish_audited(&operation_id, &audit_entry, |op| op.mark_cancelled()).await;
+                return;
+            }
+        };
+
+        match self.repository.get_operation(&operation_id).await {
+            Some(mut operation) => {
+                operation.mark_running();
+                if let Err(e) = self.repository.update_operation(operation).await {
+                    log::error!("Failed to update operation status to running: {}", e);
+                    return;
+                }
+            }
+            None => return,
+        }
+
+        context.progress = Some(ProgressReporter::new(self.repository.clone(), operation_id.clone()));
+        let metering = self.quota.clone().map(|quota| (quota, request.clone(), context.clone()));
+
+        tokio::select! {
+            result = crate::tools::execute_tool(self.registry.clone(), request, context) => {
+                match result {
+                    Ok(response) if response.error.is_none() => {
+                        if let Some((quota, request, context)) = &metering {
+                            quota.meter(context, request).await;
+                        }
+                        self.finish_audited(&operation_id, &audit_entry, |op| op.mark_completed(json!(response))).await;
+                    }
+                    Ok(response) => {
+                        let error = response.error.unwrap_or_default();
+                        self.finish_audited(&operation_id, &audit_entry, |op| op.mark_failed(format!("Tool execution failed: {}", error))).await;
+                    }
+                    Err(error) => {
+                        self.finish_audited(&operation_id, &audit_entry, |op| op.mark_failed(format!("Tool execution failed: {}", error))).await;
+                    }
+                }
+            }
+            _ = cancel_rx.changed() => {
+                log::info!("Operation {} cancelled", operation_id);
+                self.finish_audited(&operation_id, &audit_entry, |op| op.mark_cancelled()).await;
+            }
+        }
+    }
+
+    /// Apply a terminal state to an operation and persist it
+    async fn finish(&self, operation_id: &str, apply: impl FnOnce(&mut Operation)) -> Option<Operation> {
+        let mut operation = self.repository.get_operation(operation_id).await?;
+        apply(&mut operation);
+        if let Err(e) = self.repository.update_operation(operation.clone()).await {
+            log::error!("Failed to update final status of operation {}: {}", operation_id, e);
+        }
+        Some(operation)
+    }
+
+    /// Finish an operation and record how it ended against the audit record
+    /// of its submission
+    async fn finish_audited(&self, operation_id: &str, entry: &AuditRecord, apply: impl FnOnce(&mut Operation)) {
+        let operation = self.finish(operation_id, apply).await;
+        let (Some(audit), Some(operation)) = (&self.audit, operation) else {
+            return;
+        };
+        if let Some(status) = AuditStatus::from_operation(&operation.status) {
+            audit.record(entry.outcome(status, operation.error.clone())).await;
+        }
+    }
+}
@@ -3,6 +3,7 @@
 // This module provides types and functions for managing long-running operations.
 
 mod repository;
+mod executor;
 #[cfg(test)]
 mod tests;
 
@@ -11,6 +12,7 @@ use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 
 pub use repository::{OperationsRepository, create_repository};
+pub use executor::{OperationExecutor, ProgressReporter};
 
 /// Status of an operation
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -52,6 +54,9 @@ pub struct Operation {
     pub status: OperationStatus,
     /// Progress of the operation (0.0 to 1.0)
     pub progress: Option<f32>,
+    /// Human readable description of the current step
+    #[serde(default)]
+    pub message: Option<String>,
     /// Result of the operation (if completed)
     pub result: Option<serde_json::Value>,
     /// Error message (if failed)
@@ -76,6 +81,7 @@ impl Operation {
             tool_name,
             status: OperationStatus::Queued,
             progress: Some(0.0),
+            message: None,
             result: None,
             error: None,
             created_at: now,
@@ -97,6 +103,20 @@ impl Operation {
         self.updated_at = SystemTime::now();
     }
     
+    /// Update the progress of the operation along with a description of the current step
+    pub fn report_progress(&mut self, progress: f32, message: String) {
+        self.update_progress(progress);
+        self.message = Some(message);
+    }
+    
+    /// Check if the operation has reached a terminal state
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            OperationStatus::Completed | OperationStatus::Failed | OperationStatus::Cancelled
+        )
+    }
+    
     /// Mark the operation as completed
     pub fn mark_completed(&mut self, result: serde_json::Value) {
         let now = SystemTime::now();
@@ -149,6 +169,7 @@ impl Operation {
             id: self.id.clone(),
             status: self.status.to_string(),
             progress: self.progress,
+            message: self.message.clone(),
             result: self.result.clone(),
             error: self.error.clone(),
         }
@@ -4,23 +4,29 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use std::time::{Duration, SystemTime};
 
 use super::Operation;
 
+/// Capacity of the operation update channel
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
 /// Repository for managing operations
 #[derive(Debug, Clone)]
 pub struct OperationsRepository {
     operations: Arc<RwLock<HashMap<String, Operation>>>,
+    events: broadcast::Sender<Operation>,
     cleanup_interval: Duration,
 }
 
 impl OperationsRepository {
     /// Create a new operations repository
     pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
         let repo = Self {
             operations: Arc::new(RwLock::new(HashMap::new())),
+            events,
             cleanup_interval: Duration::from_secs(300), // 5 minutes
         };
         
@@ -34,7 +40,9 @@ impl OperationsRepository {
     pub async fn add_operation(&self, operation: Operation) -> String {
         let id = operation.id.clone();
         let mut operations = self.operations.write().await;
-        operations.insert(id.clone(), operation);
+        operations.insert(id.clone(), operation.clone());
+        // No subscribers is not an error
+        let _ = self.events.send(operation);
         id
     }
     
@@ -58,13 +66,23 @@ impl OperationsRepository {
     pub async fn update_operation(&self, operation: Operation) -> Result<(), String> {
         let mut operations = self.operations.write().await;
         if operations.contains_key(&operation.id) {
-            operations.insert(operation.id.clone(), operation);
+            operations.insert(operation.id.clone(), operation.clone());
+            let _ = self.events.send(operation);
             Ok(())
         } else {
             Err(format!("Operation with ID '{}' not found", operation.id))
         }
     }
     
+    /// Subscribe to operation updates
+    ///
+    /// Every added or updated operation is published to subscribers.
+    /// Subscribers that fall behind receive `RecvError::Lagged` and should
+    /// re-read the operation from the repository.
+    pub fn subscribe(&self) -> broadcast::Receiver<Operation> {
+        self.events.subscribe()
+    }
+    
     /// Remove an operation from the repository
     pub async fn remove_operation(&self, id: &str) -> Option<Operation> {
         let mut operations = self.operations.write().await;
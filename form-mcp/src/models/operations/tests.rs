@@ -92,4 +92,102 @@ mod tests {
         let retrieved = repo.get_operation(&op_id).await;
         assert!(retrieved.is_none(), "Operation should have been cleaned up after expiration");
     }
-} 
\ No newline at end of file
+    
+    /// Tool that reports progress and then waits for `delay` before completing
+    struct SlowTool {
+        delay: Duration,
+    }
+    
+    #[async_trait::async_trait]
+    impl crate::tools::Tool for SlowTool {
+        fn definition(&self) -> crate::tools::ToolDefinition {
+            crate::tools::ToolDefinition {
+                name: "slow".to_string(),
+                description: "Test tool".to_string(),
+                version: "0.1.0".to_string(),
+                parameters: vec![],
+                return_type: "object".to_string(),
+                tags: vec![],
+                is_long_running: Some(true),
+            }
+        }
+        
+        async fn execute(&self, _params: serde_json::Value, context: crate::tools::ToolContext) -> crate::tools::ToolResult {
+            context.report_progress(0.5, "halfway").await;
+            sleep(self.delay).await;
+            Ok(json!({"done": true}))
+        }
+    }
+    
+    fn executor_with_slow_tool(delay: Duration) -> crate::models::operations::OperationExecutor {
+        let registry = std::sync::Arc::new(crate::tools::ToolRegistry::new());
+        registry.register_tool(std::sync::Arc::new(SlowTool { delay })).unwrap();
+        crate::models::operations::OperationExecutor::new(create_repository(), registry, 1)
+    }
+    
+    fn slow_request() -> (crate::tools::ToolRequest, crate::tools::ToolContext) {
+        let request = crate::tools::ToolRequest {
+            name: "slow".to_string(),
+            parameters: json!({}),
+            context: None,
+        };
+        let context = crate::tools::ToolContext {
+            user_id: "test-user".to_string(),
+            request_id: "request".to_string(),
+            context: Default::default(),
+            is_admin: false,
+            progress: None,
+        };
+        (request, context)
+    }
+    
+    #[tokio::test]
+    async fn test_executor_streams_progress_until_completed() {
+        let executor = executor_with_slow_tool(Duration::from_millis(10));
+        let repo = executor.repository();
+        let mut updates = repo.subscribe();
+        
+        let (request, context) = slow_request();
+        let op_id = executor.submit(request, context).await.unwrap();
+        
+        let mut seen_progress = false;
+        loop {
+            let op = tokio::time::timeout(Duration::from_secs(5), updates.recv()).await.unwrap().unwrap();
+            assert_eq!(op.id, op_id);
+            if op.message.as_deref() == Some("halfway") && op.status == OperationStatus::Running {
+                seen_progress = true;
+            }
+            if op.is_finished() {
+                assert_eq!(op.status, OperationStatus::Completed);
+                break;
+            }
+        }
+        assert!(seen_progress, "Progress update should have been published");
+    }
+    
+    #[tokio::test]
+    async fn test_executor_cancels_running_operation() {
+        let executor = executor_with_slow_tool(Duration::from_secs(60));
+        let repo = executor.repository();
+        
+        let (request, context) = slow_request();
+        let op_id = executor.submit(request, context).await.unwrap();
+        
+        // Wait for the operation to start running
+        while repo.get_operation(&op_id).await.unwrap().status != OperationStatus::Running {
+            sleep(Duration::from_millis(5)).await;
+        }
+        
+        executor.cancel(&op_id).await.unwrap();
+        
+        let mut status = OperationStatus::Running;
+        for _ in 0..100 {
+            status = repo.get_operation(&op_id).await.unwrap().status;
+            if status != OperationStatus::Running {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(status, OperationStatus::Cancelled);
+    }
+}
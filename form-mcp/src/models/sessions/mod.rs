@@ -0,0 +1,167 @@
+// Sessions module
+//
+// This module provides conversational context that agents carry across tool
+// calls: parameter defaults such as the selected build or target node, extra
+// tool context, and the operation the agent is waiting on.
+
+mod store;
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::models::operations::{Operation, OperationStatus};
+use crate::tools::ToolDefinition;
+
+pub use store::{SessionStore, create_store};
+
+/// Result fields that become defaults for later calls in the session, so an
+/// agent that just built or created something can refer to it implicitly
+const REMEMBERED_RESULT_FIELDS: &[&str] = &["build_id", "node_id", "instance_id", "vm_id"];
+
+/// Context shared by a sequence of tool calls from one agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Unique identifier for the session
+    pub id: String,
+    /// User ID that owns the session
+    pub user_id: String,
+    /// Values filled into tool parameters the caller leaves out
+    pub defaults: HashMap<String, Value>,
+    /// Additional contextual data passed to every tool call
+    pub context: HashMap<String, String>,
+    /// Long-running operation most recently started in the session
+    pub pending_operation: Option<String>,
+    /// Name of the last tool invoked in the session
+    pub last_tool: Option<String>,
+    /// When the session was created
+    pub created_at: SystemTime,
+    /// When the session was last used or changed
+    pub updated_at: SystemTime,
+    /// How long the session lives without being used
+    pub ttl: Duration,
+}
+
+impl Session {
+    /// Create a new session
+    pub fn new(user_id: String, ttl: Duration) -> Self {
+        let now = SystemTime::now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            user_id,
+            defaults: HashMap::new(),
+            context: HashMap::new(),
+            pending_operation: None,
+            last_tool: None,
+            created_at: now,
+            updated_at: now,
+            ttl,
+        }
+    }
+
+    /// Mark the session as used, extending its lifetime
+    pub fn touch(&mut self) {
+        self.updated_at = SystemTime::now();
+    }
+
+    /// Check if the session has been idle for longer than its TTL
+    pub fn is_expired(&self) -> bool {
+        match self.updated_at.elapsed() {
+            Ok(idle) => idle > self.ttl,
+            Err(_) => false,
+        }
+    }
+
+    /// When the session expires unless it is used again
+    pub fn expires_at(&self) -> SystemTime {
+        self.updated_at + self.ttl
+    }
+
+    /// Fill parameters of `definition` missing from `params` with the
+    /// session's defaults, returning the names that were filled
+    pub fn apply_defaults(&self, definition: &ToolDefinition, params: &mut Value) -> Vec<String> {
+        if params.is_null() {
+            *params = Value::Object(Default::default());
+        }
+        let Some(params) = params.as_object_mut() else {
+            return Vec::new();
+        };
+
+        let mut injected = Vec::new();
+        for parameter in &definition.parameters {
+            if params.get(&parameter.name).is_some_and(|value| !value.is_null()) {
+                continue;
+            }
+            if let Some(value) = self.defaults.get(&parameter.name) {
+                params.insert(parameter.name.clone(), value.clone());
+                injected.push(parameter.name.clone());
+            }
+        }
+        injected
+    }
+
+    /// Context for a tool call, values given with the call win over the session's
+    pub fn merged_context(&self, request_context: Option<&HashMap<String, String>>) -> HashMap<String, String> {
+        let mut context = self.context.clone();
+        context.insert("session_id".to_string(), self.id.clone());
+        if let Some(request_context) = request_context {
+            context.extend(request_context.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        context
+    }
+
+    /// Remember what a tool call did for the next call in the session
+    pub fn record_invocation(&mut self, tool_name: &str, operation_id: Option<String>, result: Option<&Value>) {
+        self.last_tool = Some(tool_name.to_string());
+        if operation_id.is_some() {
+            self.pending_operation = operation_id;
+        }
+        if let Some(result) = result {
+            self.remember_result(result);
+        }
+        self.touch();
+    }
+
+    /// Once the pending operation has finished, remember its result like
+    /// that of a synchronous call and stop tracking it
+    pub fn settle_pending_operation(&mut self, operation: &Operation) {
+        if self.pending_operation.as_deref() != Some(operation.id.as_str()) || !operation.is_finished() {
+            return;
+        }
+        if operation.status == OperationStatus::Completed {
+            if let Some(result) = &operation.result {
+                self.remember_result(result);
+            }
+        }
+        self.pending_operation = None;
+    }
+
+    fn remember_result(&mut self, result: &Value) {
+        for field in REMEMBERED_RESULT_FIELDS {
+            if let Some(value) = result.get(*field).filter(|value| value.is_string()) {
+                self.defaults.insert(field.to_string(), value.clone());
+            }
+        }
+    }
+
+    /// Convert to API response format
+    pub fn to_api_response(&self) -> crate::api::handlers::sessions::SessionResponse {
+        let secs = |time: SystemTime| time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        crate::api::handlers::sessions::SessionResponse {
+            id: self.id.clone(),
+            defaults: self.defaults.clone(),
+            context: self.context.clone(),
+            pending_operation: self.pending_operation.clone(),
+            last_tool: self.last_tool.clone(),
+            created_at: secs(self.created_at),
+            expires_at: secs(self.expires_at()),
+        }
+    }
+}
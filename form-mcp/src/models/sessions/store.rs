@@ -0,0 +1,102 @@
+// Session store
+//
+// This module provides an in-memory store for agent sessions that drops
+// them once they have been idle for longer than their TTL.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+use super::Session;
+
+/// Store for agent sessions
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    sessions: Arc<RwLock<HashMap<String, Session>>>,
+    default_ttl: Duration,
+    max_ttl: Duration,
+    cleanup_interval: Duration,
+}
+
+impl SessionStore {
+    /// Create a new session store
+    pub fn new(default_ttl: Duration, max_ttl: Duration) -> Self {
+        let store = Self {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            default_ttl,
+            max_ttl: max_ttl.max(default_ttl),
+            cleanup_interval: Duration::from_secs(60),
+        };
+
+        // Start background cleanup task
+        store.start_cleanup_task();
+
+        store
+    }
+
+    /// Create a session for a user, the TTL is capped at the store's maximum
+    pub async fn create_session(&self, user_id: String, ttl: Option<Duration>) -> Session {
+        let ttl = ttl.unwrap_or(self.default_ttl).min(self.max_ttl);
+        let session = Session::new(user_id, ttl);
+        self.sessions.write().await.insert(session.id.clone(), session.clone());
+        session
+    }
+
+    /// Get a live session by ID
+    pub async fn get_session(&self, id: &str) -> Option<Session> {
+        let sessions = self.sessions.read().await;
+        sessions.get(id).filter(|session| !session.is_expired()).cloned()
+    }
+
+    /// Get the live sessions of a user
+    pub async fn get_sessions_by_user(&self, user_id: &str) -> Vec<Session> {
+        let sessions = self.sessions.read().await;
+        sessions
+            .values()
+            .filter(|session| session.user_id == user_id && !session.is_expired())
+            .cloned()
+            .collect()
+    }
+
+    /// Update a session, fails if it has expired in the meantime
+    pub async fn update_session(&self, session: Session) -> Result<(), String> {
+        let mut sessions = self.sessions.write().await;
+        match sessions.get(&session.id) {
+            Some(existing) if !existing.is_expired() => {
+                sessions.insert(session.id.clone(), session);
+                Ok(())
+            }
+            _ => Err(format!("Session with ID '{}' not found", session.id)),
+        }
+    }
+
+    /// Expire a session immediately
+    pub async fn expire_session(&self, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.write().await;
+        sessions.remove(id)
+    }
+
+    /// Clean up expired sessions
+    pub async fn cleanup(&self) {
+        let mut sessions = self.sessions.write().await;
+        sessions.retain(|_, session| !session.is_expired());
+    }
+
+    /// Start the background cleanup task
+    fn start_cleanup_task(&self) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let interval = store.cleanup_interval;
+            loop {
+                tokio::time::sleep(interval).await;
+                store.cleanup().await;
+            }
+        });
+    }
+}
+
+/// Create a new shared session store
+pub fn create_store(default_ttl: Duration, max_ttl: Duration) -> Arc<SessionStore> {
+    Arc::new(SessionStore::new(default_ttl, max_ttl))
+}
@@ -0,0 +1,94 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::time::Duration;
+    use crate::models::operations::Operation;
+    use crate::models::sessions::{Session, create_store};
+    use crate::tools::{ToolDefinition, ToolParameter};
+
+    fn parameter(name: &str) -> ToolParameter {
+        ToolParameter {
+            name: name.to_string(),
+            description: String::new(),
+            required: true,
+            parameter_type: "string".to_string(),
+            default: None,
+            enum_values: None,
+        }
+    }
+
+    fn ship_definition() -> ToolDefinition {
+        ToolDefinition {
+            name: "form_pack_ship".to_string(),
+            description: String::new(),
+            version: "1.0.0".to_string(),
+            parameters: vec![parameter("build_id"), parameter("instance_name")],
+            return_type: "object".to_string(),
+            tags: vec![],
+            is_long_running: Some(true),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_store_ttl() {
+        let store = create_store(Duration::from_secs(60), Duration::from_secs(120));
+
+        let session = store.create_session("agent".to_string(), Some(Duration::from_secs(3600))).await;
+        assert_eq!(session.ttl, Duration::from_secs(120));
+        assert!(store.get_session(&session.id).await.is_some());
+        assert_eq!(store.get_sessions_by_user("agent").await.len(), 1);
+        assert!(store.get_sessions_by_user("other").await.is_empty());
+
+        let short = store.create_session("agent".to_string(), Some(Duration::from_millis(10))).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(store.get_session(&short.id).await.is_none());
+        assert!(store.update_session(short).await.is_err());
+
+        assert!(store.expire_session(&session.id).await.is_some());
+        assert!(store.get_session(&session.id).await.is_none());
+    }
+
+    #[test]
+    fn test_session_defaults() {
+        let mut session = Session::new("agent".to_string(), Duration::from_secs(60));
+        session.record_invocation("form_pack_build", Some("op-1".to_string()), Some(&json!({
+            "build_id": "abc123",
+            "status": "queued"
+        })));
+        assert_eq!(session.pending_operation.as_deref(), Some("op-1"));
+        assert_eq!(session.defaults.get("build_id"), Some(&json!("abc123")));
+        assert!(!session.defaults.contains_key("status"));
+
+        let mut params = json!({ "instance_name": "web" });
+        let injected = session.apply_defaults(&ship_definition(), &mut params);
+        assert_eq!(injected, vec!["build_id".to_string()]);
+        assert_eq!(params, json!({ "build_id": "abc123", "instance_name": "web" }));
+
+        // Explicit parameters are never overridden
+        let mut params = json!({ "build_id": "other" });
+        assert!(session.apply_defaults(&ship_definition(), &mut params).is_empty());
+        assert_eq!(params["build_id"], json!("other"));
+
+        session.context.insert("region".to_string(), "eu".to_string());
+        let request_context = [("region".to_string(), "us".to_string())].into_iter().collect();
+        let context = session.merged_context(Some(&request_context));
+        assert_eq!(context.get("region").map(String::as_str), Some("us"));
+        assert_eq!(context.get("session_id"), Some(&session.id));
+    }
+
+    #[test]
+    fn test_session_settles_pending_operation() {
+        let mut session = Session::new("agent".to_string(), Duration::from_secs(60));
+        let mut operation = Operation::new("agent".to_string(), "form_pack_build".to_string());
+        session.record_invocation("form_pack_build", Some(operation.id.clone()), None);
+
+        operation.mark_running();
+        session.settle_pending_operation(&operation);
+        assert_eq!(session.pending_operation.as_ref(), Some(&operation.id));
+
+        operation.mark_completed(json!({ "build_id": "abc123" }));
+        session.settle_pending_operation(&operation);
+        assert!(session.pending_operation.is_none());
+        assert_eq!(session.defaults.get("build_id"), Some(&json!("abc123")));
+    }
+}
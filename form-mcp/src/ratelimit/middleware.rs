@@ -0,0 +1,141 @@
+// Rate limiting middleware
+//
+// Runs after authentication, so calls are charged to the authenticated
+// agent. Throttled calls never reach the handlers.
+
+use std::rc::Rc;
+use std::sync::Arc;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue, RETRY_AFTER},
+    Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::{ok, LocalBoxFuture, Ready};
+
+use crate::api::handlers::ApiResponse;
+use crate::auth::AuthData;
+use super::{Decision, RateLimiter, Throttle, ThrottleReason};
+
+/// Calls left today, on calls of identities with a daily budget
+const DAILY_REMAINING: &str = "x-ratelimit-daily-remaining";
+
+/// Paths that are never limited
+fn is_exempt(path: &str) -> bool {
+    matches!(path, "/health" | "/version")
+}
+
+/// The identity a call is charged to, None for calls that aren't limited
+fn identity(req: &ServiceRequest) -> Option<String> {
+    match req.extensions().get::<AuthData>() {
+        Some(auth) if auth.permissions.iter().any(|permission| permission == "admin") => None,
+        Some(auth) => Some(auth.user_id.clone()),
+        None => Some(format!("ip:{}", req.connection_info().realip_remote_addr().unwrap_or("unknown"))),
+    }
+}
+
+/// The `429` of a throttled call
+pub fn throttled_response(throttle: &Throttle) -> HttpResponse {
+    let message = match throttle.reason {
+        ThrottleReason::RateLimited => format!(
+            "Rate limit of the {} tier exceeded, retry in {}s", throttle.tier, throttle.retry_after_secs
+        ),
+        ThrottleReason::DailyBudgetExhausted => format!(
+            "Daily budget of the {} tier exhausted, retry in {}s", throttle.tier, throttle.retry_after_secs
+        ),
+    };
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, throttle.retry_after_secs.to_string()))
+        .json(ApiResponse {
+            status: "error".to_string(),
+            data: Some(serde_json::json!({
+                "code": throttle.reason.as_str(),
+                "tier": throttle.tier,
+                "retry_after_secs": throttle.retry_after_secs,
+                "limits": {
+                    "requests_per_second": throttle.requests_per_second,
+                    "burst": throttle.burst,
+                    "daily_budget": throttle.daily_budget,
+                },
+            })),
+            message: Some(message),
+        })
+}
+
+/// Middleware charging every API call to its identity's limits
+#[derive(Clone)]
+pub struct RateLimitMiddleware {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimitMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddlewareService {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddlewareService<S> {
+    service: Rc<S>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let identity = if self.limiter.is_enabled() && !is_exempt(req.path()) {
+            identity(&req)
+        } else {
+            None
+        };
+        let decision = identity.as_deref().map(|identity| (identity, self.limiter.check(identity)));
+
+        let remaining_today = match decision {
+            Some((identity, Decision::Throttled(throttle))) => {
+                log::debug!("Throttled {} {} of {}: {}", req.method(), req.path(), identity, throttle.reason.as_str());
+                let response = throttled_response(&throttle).map_into_right_body();
+                return Box::pin(async move { Ok(req.into_response(response)) });
+            }
+            Some((_, Decision::Allowed { remaining_today })) => remaining_today,
+            None => None,
+        };
+
+        Box::pin(async move {
+            let mut res = svc.call(req).await?;
+            if let Some(remaining) = remaining_today {
+                if let Ok(value) = HeaderValue::from_str(&remaining.to_string()) {
+                    res.headers_mut().insert(HeaderName::from_static(DAILY_REMAINING), value);
+                }
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,339 @@
+// Rate limiting module for the MCP server
+//
+// Every API call is charged to the identity making it, the authenticated
+// agent or, without one, the client address. Each identity has a token
+// bucket refilled at its tier's `requests_per_second` that holds up to
+// `burst` calls, and a `daily_budget` of calls per UTC day. Calls over either
+// are answered with `429 Too Many Requests` and a `Retry-After`.
+//
+// Tiers are the form-state subscription tiers. An agent's tier is learned
+// from the billing eligibility checks of its tool calls, until then it is
+// limited as `default_tier`.
+
+pub mod middleware;
+
+pub use middleware::RateLimitMiddleware;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::config::{RateLimitSettings, TierLimits};
+
+/// Decisions between sweeps of idle identities
+const SWEEP_EVERY: u64 = 1024;
+/// Identities whose throttled calls are counted one by one, the others are
+/// counted together
+const MAX_TRACKED_IDENTITIES: usize = 1000;
+
+/// Why a call was throttled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleReason {
+    /// Calls came faster than the tier's rate and burst allow
+    RateLimited,
+    /// The tier's calls for the day are used up
+    DailyBudgetExhausted,
+}
+
+impl ThrottleReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RateLimited => "rate_limited",
+            Self::DailyBudgetExhausted => "daily_budget_exhausted",
+        }
+    }
+}
+
+/// A call that may not be made yet
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Throttle {
+    pub reason: ThrottleReason,
+    pub tier: String,
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub daily_budget: Option<u64>,
+    /// Seconds until the call would be allowed
+    pub retry_after_secs: u64,
+}
+
+/// Outcome of charging a call to an identity
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Allowed {
+        /// Calls left today, None without a daily budget
+        remaining_today: Option<u64>,
+    },
+    Throttled(Throttle),
+}
+
+/// Counts of throttled calls since the server started
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RateLimitMetrics {
+    pub allowed: u64,
+    pub throttled: u64,
+    pub throttled_by_reason: BTreeMap<ThrottleReason, u64>,
+    pub throttled_by_tier: BTreeMap<String, u64>,
+    /// Throttled calls of each identity, identities past the first
+    /// `MAX_TRACKED_IDENTITIES` are counted under `other`
+    pub throttled_by_identity: BTreeMap<String, u64>,
+}
+
+impl RateLimitMetrics {
+    fn record_throttle(&mut self, identity: &str, throttle: &Throttle) {
+        self.throttled += 1;
+        *self.throttled_by_reason.entry(throttle.reason).or_default() += 1;
+        *self.throttled_by_tier.entry(throttle.tier.clone()).or_default() += 1;
+        let key = if self.throttled_by_identity.contains_key(identity)
+            || self.throttled_by_identity.len() < MAX_TRACKED_IDENTITIES
+        {
+            identity
+        } else {
+            "other"
+        };
+        *self.throttled_by_identity.entry(key.to_string()).or_default() += 1;
+    }
+}
+
+/// Calls of one identity
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    day: NaiveDate,
+    used_today: u64,
+}
+
+impl Bucket {
+    fn new(limits: &TierLimits, now: Instant, today: NaiveDate) -> Self {
+        Self { tokens: limits.burst as f64, refilled: now, day: today, used_today: 0 }
+    }
+
+    fn refill(&mut self, limits: &TierLimits, now: Instant, today: NaiveDate) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.requests_per_second).min(limits.burst as f64);
+        self.refilled = now;
+        if self.day != today {
+            self.day = today;
+            self.used_today = 0;
+        }
+    }
+
+    /// Idle long enough to be full again, with nothing counted today
+    fn is_idle(&self, limits: &TierLimits, now: Instant, today: NaiveDate) -> bool {
+        let refill_secs = limits.burst as f64 / limits.requests_per_second.max(f64::MIN_POSITIVE);
+        (self.day != today || self.used_today == 0)
+            && now.saturating_duration_since(self.refilled).as_secs_f64() >= refill_secs
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    buckets: HashMap<String, Bucket>,
+    tiers: HashMap<String, String>,
+    metrics: RateLimitMetrics,
+    decisions: u64,
+}
+
+/// Per-identity rate limits and daily budgets
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self { settings, state: Mutex::new(LimiterState::default()) }
+    }
+
+    /// Whether calls are limited
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    /// Records the tier form-state reported for an agent
+    pub fn set_tier(&self, identity: &str, tier: &str) {
+        let tier = tier.to_lowercase();
+        if !self.settings.tiers.contains_key(&tier) {
+            log::warn!("No rate limits are configured for tier '{}' of {}, using the default tier", tier, identity);
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.tiers.insert(identity.to_string(), tier);
+    }
+
+    /// The tier an identity is limited as and its limits
+    fn limits_of(&self, state: &LimiterState, identity: &str) -> (String, TierLimits) {
+        let tier = state.tiers.get(identity).unwrap_or(&self.settings.default_tier);
+        match self.settings.tiers.get(tier) {
+            Some(limits) => (tier.clone(), *limits),
+            // A default tier without limits leaves calls unlimited
+            None => (tier.clone(), TierLimits { requests_per_second: f64::INFINITY, burst: u32::MAX, daily_budget: None }),
+        }
+    }
+
+    /// Charges a call to an identity
+    pub fn check(&self, identity: &str) -> Decision {
+        self.check_at(identity, Instant::now(), Utc::now().date_naive())
+    }
+
+    pub fn check_at(&self, identity: &str, now: Instant, today: NaiveDate) -> Decision {
+        if !self.settings.enabled {
+            return Decision::Allowed { remaining_today: None };
+        }
+
+        let mut guard = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let state = &mut *guard;
+        let (tier, limits) = self.limits_of(state, identity);
+        state.decisions += 1;
+        if state.decisions % SWEEP_EVERY == 0 {
+            self.sweep(state, now, today);
+        }
+
+        let bucket = state.buckets
+            .entry(identity.to_string())
+            .or_insert_with(|| Bucket::new(&limits, now, today));
+        bucket.refill(&limits, now, today);
+
+        let throttled = if limits.daily_budget.is_some_and(|budget| bucket.used_today >= budget) {
+            Some((ThrottleReason::DailyBudgetExhausted, seconds_until_tomorrow()))
+        } else if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / limits.requests_per_second;
+            Some((ThrottleReason::RateLimited, wait.ceil().max(1.0) as u64))
+        } else {
+            None
+        };
+
+        match throttled {
+            Some((reason, retry_after_secs)) => {
+                let throttle = Throttle {
+                    reason,
+                    tier,
+                    requests_per_second: limits.requests_per_second,
+                    burst: limits.burst,
+                    daily_budget: limits.daily_budget,
+                    retry_after_secs,
+                };
+                state.metrics.record_throttle(identity, &throttle);
+                Decision::Throttled(throttle)
+            }
+            None => {
+                bucket.tokens -= 1.0;
+                bucket.used_today += 1;
+                let remaining_today = limits.daily_budget.map(|budget| budget.saturating_sub(bucket.used_today));
+                state.metrics.allowed += 1;
+                Decision::Allowed { remaining_today }
+            }
+        }
+    }
+
+    /// Forgets identities whose buckets hold nothing worth keeping
+    fn sweep(&self, state: &mut LimiterState, now: Instant, today: NaiveDate) {
+        let idle: Vec<String> = state.buckets.iter()
+            .filter(|(identity, bucket)| bucket.is_idle(&self.limits_of(state, identity).1, now, today))
+            .map(|(identity, _)| identity.clone())
+            .collect();
+        for identity in idle {
+            state.buckets.remove(&identity);
+        }
+    }
+
+    /// Counts of allowed and throttled calls
+    pub fn metrics(&self) -> RateLimitMetrics {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).metrics.clone()
+    }
+}
+
+fn seconds_until_tomorrow() -> u64 {
+    let now = Utc::now();
+    let tomorrow = (now.date_naive() + chrono::Days::new(1)).and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+    (tomorrow - now).to_std().unwrap_or(Duration::from_secs(1)).as_secs().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_second: f64, burst: u32, daily_budget: Option<u64>) -> RateLimiter {
+        let mut settings = RateLimitSettings::default();
+        settings.tiers.insert("free".to_string(), TierLimits { requests_per_second, burst, daily_budget });
+        RateLimiter::new(settings)
+    }
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, d).unwrap()
+    }
+
+    fn reason(decision: Decision) -> Option<ThrottleReason> {
+        match decision {
+            Decision::Allowed { .. } => None,
+            Decision::Throttled(throttle) => Some(throttle.reason),
+        }
+    }
+
+    #[test]
+    fn test_burst_then_sustained_rate() {
+        let limiter = limiter(2.0, 3, None);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(reason(limiter.check_at("0xagent", start, day(1))), None);
+        }
+        match limiter.check_at("0xagent", start, day(1)) {
+            Decision::Throttled(throttle) => {
+                assert_eq!(throttle.reason, ThrottleReason::RateLimited);
+                assert_eq!(throttle.retry_after_secs, 1);
+                assert_eq!(throttle.tier, "free");
+            }
+            other => panic!("expected a throttle, got {:?}", other),
+        }
+        // Other identities have buckets of their own
+        assert_eq!(reason(limiter.check_at("0xother", start, day(1))), None);
+
+        // Half a second refills one call at 2 per second
+        let later = start + Duration::from_millis(500);
+        assert_eq!(reason(limiter.check_at("0xagent", later, day(1))), None);
+        assert_eq!(reason(limiter.check_at("0xagent", later, day(1))), Some(ThrottleReason::RateLimited));
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.allowed, 5);
+        assert_eq!(metrics.throttled, 2);
+        assert_eq!(metrics.throttled_by_identity["0xagent"], 2);
+        assert_eq!(metrics.throttled_by_reason[&ThrottleReason::RateLimited], 2);
+    }
+
+    #[test]
+    fn test_daily_budget_resets_each_day() {
+        let limiter = limiter(100.0, 100, Some(2));
+        let now = Instant::now();
+        assert_eq!(limiter.check_at("0xagent", now, day(1)), Decision::Allowed { remaining_today: Some(1) });
+        assert_eq!(limiter.check_at("0xagent", now, day(1)), Decision::Allowed { remaining_today: Some(0) });
+        assert_eq!(reason(limiter.check_at("0xagent", now, day(1))), Some(ThrottleReason::DailyBudgetExhausted));
+        assert_eq!(limiter.check_at("0xagent", now, day(2)), Decision::Allowed { remaining_today: Some(1) });
+    }
+
+    #[test]
+    fn test_reported_tier_sets_limits() {
+        let mut settings = RateLimitSettings::default();
+        settings.tiers.insert("free".to_string(), TierLimits { requests_per_second: 1.0, burst: 1, daily_budget: None });
+        settings.tiers.insert("pro".to_string(), TierLimits { requests_per_second: 1.0, burst: 2, daily_budget: None });
+        let limiter = RateLimiter::new(settings);
+        let now = Instant::now();
+
+        limiter.set_tier("0xpaid", "Pro");
+        // Unknown tiers leave the default in place
+        limiter.set_tier("0xfree", "Enterprise");
+        for identity in ["0xpaid", "0xfree"] {
+            assert_eq!(reason(limiter.check_at(identity, now, day(1))), None);
+        }
+        assert_eq!(reason(limiter.check_at("0xpaid", now, day(1))), None);
+        assert_eq!(reason(limiter.check_at("0xfree", now, day(1))), Some(ThrottleReason::RateLimited));
+        assert_eq!(limiter.metrics().throttled_by_tier["free"], 1);
+
+        let disabled = RateLimiter::new(RateLimitSettings { enabled: false, ..RateLimitSettings::default() });
+        for _ in 0..100 {
+            assert_eq!(reason(disabled.check_at("0xagent", now, day(1))), None);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+// Deployment freeze check
+//
+// Tools that create or ship instances ask form-state whether admins have
+// frozen deployments before doing anything, so the agent gets the reason
+// instead of a queued request that is refused later.
+
+use reqwest::{Client, StatusCode};
+use serde_json::Value;
+
+use crate::errors::ToolError;
+
+const STATE_PORT: u16 = 3004;
+
+/// Fails with `ToolError::Maintenance` while deployments are frozen. An
+/// unreachable form-state doesn't block the tool, the freeze is enforced
+/// again where the instance is created.
+pub async fn check_deployment_freeze(client: &Client) -> Result<(), ToolError> {
+    let response = match client
+        .get(format!("http://127.0.0.1:{}/v1/maintenance/check", STATE_PORT))
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            log::warn!("Unable to check for a deployment freeze: {}", e);
+            return Ok(());
+        }
+    };
+
+    if response.status() != StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(());
+    }
+    let body = response.json::<Value>().await.unwrap_or_default();
+    Err(ToolError::Maintenance {
+        message: body["error"].as_str().unwrap_or("Deployments are frozen for maintenance").to_string(),
+        details: body["maintenance"].clone(),
+    })
+}
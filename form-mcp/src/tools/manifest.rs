@@ -0,0 +1,405 @@
+// Manifest tools module
+//
+// This module implements tools described by external manifests (JSON or TOML)
+// instead of being compiled into the server. Each manifest entry maps a tool
+// name and parameter schema to a Formation API call template, so new network
+// capabilities can be exposed to agents without recompiling form-mcp.
+//
+// Example (TOML):
+//
+// [[tools]]
+// name = "dns.list"
+// description = "List DNS records"
+// tags = ["dns"]
+//
+// [tools.request]
+// method = "GET"
+// url = "http://127.0.0.1:3005/record/list?owner={{user_id}}"
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use reqwest::{Client, Method};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use crate::errors::ToolError;
+use crate::tools::{Tool, ToolContext, ToolDefinition, ToolParameter, ToolResult};
+use crate::tools::registry::ToolRegistry;
+
+/// Default timeout for manifest tool requests in seconds
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// A manifest file containing one or more tool descriptors
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ToolManifest {
+    /// Tools described by this manifest
+    #[serde(default)]
+    pub tools: Vec<ManifestToolSpec>,
+}
+
+/// Descriptor of a single manifest tool
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestToolSpec {
+    /// Name of the tool
+    pub name: String,
+    /// Description of the tool
+    pub description: String,
+    /// Version of the tool
+    #[serde(default = "default_version")]
+    pub version: String,
+    /// Parameters for the tool
+    #[serde(default)]
+    pub parameters: Vec<ToolParameter>,
+    /// Return type description
+    #[serde(default = "default_return_type")]
+    pub return_type: String,
+    /// Tags for categorizing the tool
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Whether this tool should run through the operations system
+    #[serde(default)]
+    pub is_long_running: Option<bool>,
+    /// The Formation API call the tool is mapped to
+    pub request: RequestTemplate,
+}
+
+/// Template for the HTTP request a manifest tool performs.
+///
+/// `{{name}}` placeholders in the url, headers and body are replaced with the
+/// tool parameter of the same name, or with `user_id` / `request_id` from the
+/// tool context. A body string consisting only of a placeholder is replaced by
+/// the parameter's JSON value, preserving its type.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequestTemplate {
+    /// HTTP method
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Request URL
+    pub url: String,
+    /// Request headers
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// JSON request body
+    #[serde(default)]
+    pub body: Option<Value>,
+    /// Request timeout in seconds
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+fn default_version() -> String {
+    "0.1.0".to_string()
+}
+
+fn default_return_type() -> String {
+    "object".to_string()
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Tool backed by a manifest descriptor
+pub struct ManifestTool {
+    spec: ManifestToolSpec,
+    method: Method,
+    http_client: Client,
+}
+
+impl ManifestTool {
+    /// Create a tool from a manifest descriptor
+    pub fn new(spec: ManifestToolSpec) -> Result<Self, ToolError> {
+        if spec.name.trim().is_empty() {
+            return Err(ToolError::RegistrationFailed("Manifest tool name cannot be empty".to_string()));
+        }
+
+        let method = Method::from_bytes(spec.request.method.to_uppercase().as_bytes())
+            .map_err(|_| ToolError::RegistrationFailed(
+                format!("Invalid HTTP method '{}' for tool '{}'", spec.request.method, spec.name)
+            ))?;
+
+        Ok(Self {
+            spec,
+            method,
+            http_client: Client::new(),
+        })
+    }
+
+    /// Build the substitution values for the templates
+    fn template_values(&self, params: &Value, context: &ToolContext) -> HashMap<String, Value> {
+        let mut values = HashMap::new();
+        values.insert("user_id".to_string(), json!(context.user_id));
+        values.insert("request_id".to_string(), json!(context.request_id));
+
+        for param in &self.spec.parameters {
+            let value = params.get(&param.name)
+                .cloned()
+                .or_else(|| param.default.clone());
+            if let Some(value) = value {
+                values.insert(param.name.clone(), value);
+            }
+        }
+
+        values
+    }
+}
+
+/// Render a value for substitution inside a string
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Replace `{{name}}` placeholders in a string template
+pub fn render_string(template: &str, values: &HashMap<String, Value>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + 2 + end].trim();
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(&values.get(name).map(value_to_string).unwrap_or_default());
+        rest = &rest[start + 2 + end + 2..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Replace `{{name}}` placeholders in a JSON body template
+pub fn render_value(template: &Value, values: &HashMap<String, Value>) -> Value {
+    match template {
+        Value::String(s) => {
+            let trimmed = s.trim();
+            if trimmed.starts_with("{{") && trimmed.ends_with("}}") && trimmed.matches("{{").count() == 1 {
+                let name = trimmed[2..trimmed.len() - 2].trim();
+                values.get(name).cloned().unwrap_or(Value::Null)
+            } else {
+                Value::String(render_string(s, values))
+            }
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| render_value(v, values)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter().map(|(k, v)| (k.clone(), render_value(v, values))).collect()
+        ),
+        other => other.clone(),
+    }
+}
+
+#[async_trait]
+impl Tool for ManifestTool {
+    fn definition(&self) -> ToolDefinition {
+        let mut tags = self.spec.tags.clone();
+        if !tags.iter().any(|t| t == "manifest") {
+            tags.push("manifest".to_string());
+        }
+
+        ToolDefinition {
+            name: self.spec.name.clone(),
+            description: self.spec.description.clone(),
+            version: self.spec.version.clone(),
+            parameters: self.spec.parameters.clone(),
+            return_type: self.spec.return_type.clone(),
+            tags,
+            is_long_running: self.spec.is_long_running,
+        }
+    }
+
+    async fn execute(&self, params: Value, context: ToolContext) -> ToolResult {
+        self.validate_params(&params)?;
+
+        let values = self.template_values(&params, &context);
+        let template = &self.spec.request;
+        let url = render_string(&template.url, &values);
+        let timeout = Duration::from_secs(template.timeout_secs.unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS));
+
+        let mut request = self.http_client
+            .request(self.method.clone(), &url)
+            .timeout(timeout);
+        for (name, value) in &template.headers {
+            request = request.header(name.as_str(), render_string(value, &values));
+        }
+        if let Some(body) = &template.body {
+            request = request.json(&render_value(body, &values));
+        }
+
+        let response = request.send().await.map_err(|e| {
+            if e.is_timeout() {
+                ToolError::Timeout
+            } else {
+                ToolError::ExecutionFailed(format!("Request to {} failed: {}", url, e))
+            }
+        })?;
+
+        let status = response.status();
+        let text = response.text().await
+            .map_err(|e| ToolError::ExecutionFailed(format!("Failed to read response: {}", e)))?;
+        let body = serde_json::from_str::<Value>(&text).unwrap_or(Value::String(text));
+
+        if !status.is_success() {
+            return Err(ToolError::ExecutionFailed(
+                format!("{} returned {}: {}", url, status, value_to_string(&body))
+            ));
+        }
+
+        Ok(body)
+    }
+}
+
+/// Parse a manifest file based on its extension
+pub fn parse_manifest(path: &Path) -> Result<ToolManifest, ToolError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| ToolError::RegistrationFailed(format!("Failed to read {}: {}", path.display(), e)))?;
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&content)
+            .map_err(|e| ToolError::RegistrationFailed(format!("Invalid manifest {}: {}", path.display(), e))),
+        Some("toml") => toml::from_str(&content)
+            .map_err(|e| ToolError::RegistrationFailed(format!("Invalid manifest {}: {}", path.display(), e))),
+        _ => Err(ToolError::RegistrationFailed(
+            format!("Unsupported manifest format: {}", path.display())
+        )),
+    }
+}
+
+/// Loads manifest tools from a directory into the registry
+pub struct ManifestLoader {
+    dir: Option<PathBuf>,
+    registry: Arc<ToolRegistry>,
+}
+
+impl ManifestLoader {
+    /// Create a loader for the given manifest directory
+    pub fn new(dir: Option<PathBuf>, registry: Arc<ToolRegistry>) -> Self {
+        Self { dir, registry }
+    }
+
+    /// (Re)load every `*.json` and `*.toml` manifest in the directory,
+    /// replacing previously loaded manifest tools.
+    ///
+    /// Invalid manifests are skipped and logged. Returns the names of the
+    /// tools that were registered.
+    pub fn load(&self) -> Result<Vec<String>, ToolError> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| ToolError::RegistrationFailed(format!("Failed to read manifest directory {}: {}", dir.display(), e)))?;
+
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("json") | Some("toml")))
+            .collect();
+        paths.sort();
+
+        let mut tools: Vec<Arc<dyn Tool>> = Vec::new();
+        for path in paths {
+            let manifest = match parse_manifest(&path) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    log::error!("Skipping tool manifest: {}", e);
+                    continue;
+                }
+            };
+            for spec in manifest.tools {
+                match ManifestTool::new(spec) {
+                    Ok(tool) => tools.push(Arc::new(tool)),
+                    Err(e) => log::error!("Skipping manifest tool in {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        self.registry.replace_manifest_tools(tools)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str) -> ManifestToolSpec {
+        ManifestToolSpec {
+            name: name.to_string(),
+            description: "test".to_string(),
+            version: default_version(),
+            parameters: vec![],
+            return_type: default_return_type(),
+            tags: vec![],
+            is_long_running: None,
+            request: RequestTemplate {
+                method: default_method(),
+                url: "http://127.0.0.1/{{id}}".to_string(),
+                headers: HashMap::new(),
+                body: None,
+                timeout_secs: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_render_templates() {
+        let mut values = HashMap::new();
+        values.insert("id".to_string(), json!("abc"));
+        values.insert("count".to_string(), json!(3));
+
+        assert_eq!(render_string("http://host/{{id}}/x?n={{ count }}", &values), "http://host/abc/x?n=3");
+        assert_eq!(render_string("{{missing}}-{{id}}", &values), "-abc");
+
+        let body = json!({"count": "{{count}}", "label": "item-{{id}}", "list": ["{{id}}"], "none": "{{missing}}"});
+        assert_eq!(
+            render_value(&body, &values),
+            json!({"count": 3, "label": "item-abc", "list": ["abc"], "none": null})
+        );
+    }
+
+    #[test]
+    fn test_parse_toml_manifest() {
+        let manifest: ToolManifest = toml::from_str(r#"
+            [[tools]]
+            name = "dns.list"
+            description = "List DNS records"
+
+            [tools.request]
+            url = "http://127.0.0.1:3005/record/list"
+        "#).unwrap();
+        assert_eq!(manifest.tools.len(), 1);
+        assert_eq!(manifest.tools[0].request.method, "GET");
+        assert!(ManifestTool::new(manifest.tools[0].clone()).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_method_rejected() {
+        let mut spec = spec("bad");
+        spec.request.method = "NOT A METHOD".to_string();
+        assert!(ManifestTool::new(spec).is_err());
+    }
+
+    #[test]
+    fn test_replace_manifest_tools() {
+        let registry = ToolRegistry::new();
+        registry.register_tool(Arc::new(ManifestTool::new(spec("builtin")).unwrap())).unwrap();
+
+        let loaded = registry.replace_manifest_tools(vec![
+            Arc::new(ManifestTool::new(spec("first")).unwrap()),
+            Arc::new(ManifestTool::new(spec("builtin")).unwrap()),
+        ]).unwrap();
+        assert_eq!(loaded, vec!["first".to_string()]);
+
+        let loaded = registry.replace_manifest_tools(vec![
+            Arc::new(ManifestTool::new(spec("second")).unwrap()),
+        ]).unwrap();
+        assert_eq!(loaded, vec!["second".to_string()]);
+        assert!(registry.get_tool("first").is_none());
+        assert!(registry.get_tool("builtin").is_some());
+    }
+}
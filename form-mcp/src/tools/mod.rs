@@ -8,12 +8,16 @@ pub mod network;
 pub mod metrics;
 pub mod registry;
 pub mod pack;
+pub mod manifest;
+pub mod maintenance;
 
 pub use registry::{ToolRegistry, Tool, ToolDefinition, ToolParameter, ToolResult};
+pub use manifest::{ManifestLoader, ManifestTool, ToolManifest};
 
 use std::sync::Arc;
 use serde::{Serialize, Deserialize};
 use crate::errors::ToolError;
+use crate::models::operations::ProgressReporter;
 
 /// ToolContext holds contextual information for tool execution
 #[derive(Clone)]
@@ -26,6 +30,18 @@ pub struct ToolContext {
     pub context: std::collections::HashMap<String, String>,
     /// Whether the user has admin privileges
     pub is_admin: bool,
+    /// Progress reporter, set when the tool runs as a long-running operation
+    pub progress: Option<ProgressReporter>,
+}
+
+impl ToolContext {
+    /// Report progress (0.0 to 1.0) for the current step if this execution is
+    /// tracked as an operation, otherwise this is a no-op
+    pub async fn report_progress(&self, progress: f32, message: impl Into<String>) {
+        if let Some(reporter) = &self.progress {
+            reporter.report(progress, message).await;
+        }
+    }
 }
 
 /// ToolRequest represents a request to execute a tool
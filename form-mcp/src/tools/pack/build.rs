@@ -158,6 +158,7 @@ impl PackBuildTool {
             .map_err(|e| ToolError::ExecutionFailed(format!("Failed to serialize queue request: {}", e)))?;
         
         // Send to queue
+        context.report_progress(0.5, "Submitting build request to the queue").await;
         let endpoint = format!("http://127.0.0.1:{}/queue", QUEUE_PORT);
         let response = self.http_client.post(&endpoint)
             .body(queue_json)
@@ -243,6 +244,7 @@ impl Tool for PackBuildTool {
         }
         
         // Submit build request
+        context.report_progress(0.1, format!("Preparing build with {} context files", context_files.len())).await;
         self.submit_build_request(formfile_content, context_files, &context).await
     }
 } 
\ No newline at end of file
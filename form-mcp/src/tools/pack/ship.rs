@@ -13,6 +13,7 @@ use uuid::Uuid;
 use crate::errors::ToolError;
 use crate::tools::{Tool, ToolContext, ToolDefinition, ToolParameter, ToolResult};
 use crate::tools::registry::ToolRegistry;
+use crate::tools::maintenance::check_deployment_freeze;
 
 // Constants for API endpoints
 const QUEUE_PORT: u16 = 53333;
@@ -127,6 +128,8 @@ impl PackShipTool {
         vm_config: Option<VMConfig>,
         context: &ToolContext
     ) -> Result<Value, ToolError> {
+        check_deployment_freeze(&self.http_client).await?;
+
         // Generate deployment request
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
             .map_err(|_| ToolError::ExecutionFailed("Failed to get system time".to_string()))?
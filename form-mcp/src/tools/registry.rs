@@ -3,7 +3,7 @@
 // This module defines the tool registry system which manages tool registration
 // and discovery for the MCP server.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
 use async_trait::async_trait;
 use serde::{Serialize, Deserialize};
@@ -86,6 +86,8 @@ pub trait Tool: Send + Sync {
 /// ToolRegistry manages tool registration and discovery
 pub struct ToolRegistry {
     tools: RwLock<HashMap<String, Arc<dyn Tool>>>,
+    /// Names of tools that were loaded from external manifests
+    manifest_tools: RwLock<HashSet<String>>,
 }
 
 impl ToolRegistry {
@@ -93,6 +95,7 @@ impl ToolRegistry {
     pub fn new() -> Self {
         Self {
             tools: RwLock::new(HashMap::new()),
+            manifest_tools: RwLock::new(HashSet::new()),
         }
     }
     
@@ -115,6 +118,38 @@ impl ToolRegistry {
         Ok(())
     }
     
+    /// Replace all manifest-loaded tools with the given set
+    ///
+    /// Previously loaded manifest tools are removed first. Manifest tools
+    /// cannot shadow compiled-in tools; conflicting names are skipped.
+    /// Returns the names of the tools that were registered.
+    pub fn replace_manifest_tools(&self, new_tools: Vec<Arc<dyn Tool>>) -> Result<Vec<String>, ToolError> {
+        let mut tools = self.tools.write().map_err(|_| {
+            ToolError::RegistrationFailed("Failed to acquire write lock".to_string())
+        })?;
+        let mut manifest_tools = self.manifest_tools.write().map_err(|_| {
+            ToolError::RegistrationFailed("Failed to acquire write lock".to_string())
+        })?;
+        
+        for name in manifest_tools.drain() {
+            tools.remove(&name);
+        }
+        
+        let mut registered = Vec::new();
+        for tool in new_tools {
+            let name = tool.definition().name;
+            if tools.contains_key(&name) {
+                log::warn!("Skipping manifest tool '{}': a tool with that name is already registered", name);
+                continue;
+            }
+            tools.insert(name.clone(), tool);
+            manifest_tools.insert(name.clone());
+            registered.push(name);
+        }
+        
+        Ok(registered)
+    }
+    
     /// Get a tool by name
     pub fn get_tool(&self, name: &str) -> Option<Arc<dyn Tool>> {
         self.tools.read().ok()?.get(name).cloned()
@@ -38,10 +38,18 @@ pub enum QueueResponse {
 /// Instance status enum
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum InstanceStatus {
+    Requested,
     Building,
-    Created,
-    Started,
+    Built,
+    #[serde(alias = "Created")]
+    Booting,
+    #[serde(alias = "Started")]
+    Ready,
     Stopped,
+    #[serde(alias = "CriticalError")]
+    Failed,
+    #[serde(alias = "Killed")]
+    Deleted,
 }
 
 /// VM Instance for state storage
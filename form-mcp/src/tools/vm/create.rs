@@ -14,6 +14,7 @@ use rand::Rng;
 use crate::errors::ToolError;
 use crate::tools::{Tool, ToolContext, ToolDefinition, ToolParameter, ToolResult};
 use crate::tools::registry::ToolRegistry;
+use crate::tools::maintenance::check_deployment_freeze;
 
 // Constants for API endpoints
 const QUEUE_PORT: u16 = 53333;
@@ -82,10 +83,18 @@ pub struct Instance {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum InstanceStatus {
+    Requested,
     Building,
-    Created,
-    Started,
+    Built,
+    #[serde(alias = "Created")]
+    Booting,
+    #[serde(alias = "Started")]
+    Ready,
     Stopped,
+    #[serde(alias = "CriticalError")]
+    Failed,
+    #[serde(alias = "Killed")]
+    Deleted,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -160,12 +169,16 @@ impl VMCreateTool {
             },
         };
         
+        check_deployment_freeze(&self.http_client).await?;
+
         // Try direct API endpoint first (preferred)
+        context.report_progress(0.3, "Submitting instance to the state API").await;
         match self.create_vm_api(instance.clone()).await {
             Ok(result) => return Ok(result),
             Err(e) => {
                 log::warn!("API endpoint failed, falling back to queue: {}", e);
                 // Fall back to queue if API fails
+                context.report_progress(0.6, "State API unavailable, submitting instance to the queue").await;
                 self.create_vm_queue(instance).await
             }
         }
@@ -395,6 +408,8 @@ impl Tool for VMCreateTool {
             metadata,
         };
         
+        context.report_progress(0.1, "Validated VM configuration").await;
+        
         // Generate a unique build ID for this VM
         let random_id = rand::random::<u32>();
         let build_id = format!("{}-{}", name, random_id);
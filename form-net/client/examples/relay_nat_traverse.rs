@@ -122,6 +122,7 @@ fn create_test_peer(name: &str, id: &str, pubkey: &str, endpoints: Vec<Endpoint>
         is_redeemed: true,
         invite_expires: None,
         candidates: endpoints,
+        retiring_key: None,
     };
     
     Peer {
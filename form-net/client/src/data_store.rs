@@ -163,6 +163,7 @@ mod tests {
                 persistent_keepalive_interval: None,
                 invite_expires: None,
                 candidates: vec![],
+                retiring_key: None,
             },
         }]
     });
@@ -51,6 +51,10 @@ pub async fn add_peer(
         });
     }
 
+    if matches!(peer_type, PeerType::Operator) {
+        check_operator_admission(peer_id).await?;
+    }
+
     let peer_contents_req = build_peer(
         &peers_from_db,
         peer_type,
@@ -91,6 +95,25 @@ pub async fn add_peer(
     })
 }
 
+/// Rejects operators that form-state doesn't admit, e.g. because they have no
+/// stake in the staking contract or were slashed
+async fn check_operator_admission(operator: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let admission = reqwest::Client::new()
+        .get(format!("http://127.0.0.1:3004/v1/staking/{operator}/admission"))
+        .send()
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+
+    if admission.get("admitted").and_then(serde_json::Value::as_bool).unwrap_or(false) {
+        return Ok(());
+    }
+
+    let reason = admission.get("reason").and_then(serde_json::Value::as_str).unwrap_or("operator is not staked");
+    log::warn!("Rejecting join request from operator {operator}: {reason}");
+    Err(Box::new(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("Operator not admitted: {reason}"))))
+}
+
 pub async fn build_peer(
     peers: &[Peer<String>],
     peer_type: &PeerType,
@@ -127,6 +150,7 @@ pub async fn build_peer(
         persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
         invite_expires: Some(SystemTime::now() + invite_duration),
         candidates: vec![],
+        retiring_key: None,
     })
 }
 
@@ -4,12 +4,14 @@ use formnet_server::{db::CrdtMap, DatabasePeer};
 use serde::{Serialize, Deserialize};
 use shared::{Endpoint, NetworkOpts, Peer, PeerContents, PeerDiff};
 use tokio::{net::TcpListener, sync::RwLock};
-use axum::{extract::{ConnectInfo, Path, State}, routing::{get, post}, Json, Router};
+use axum::{extract::{ConnectInfo, Path, Query, State}, routing::{get, post}, Json, Router};
 use wireguard_control::{AllowedIp, Backend, Device, DeviceUpdate, InterfaceName, PeerConfigBuilder};
 
-use crate::{add_peer, handle_leave_request};
+use form_node_metrics::connectivity::ConnectivityMetrics;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{add_peer, bandwidth::{BandwidthSummary, SharedBandwidth}, handle_leave_request, handle_rotate_request, get_refresh_config, update_refresh_config, peer_metrics::{run_peer_metrics, SharedConnectivity}, spawn_retired_key_sweeper};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum HealthStatus {
     Healthy,
@@ -26,6 +28,12 @@ pub struct HealthResponse {
     uptime: Option<u64>
 }
 
+impl HealthResponse {
+    pub fn status(&self) -> &HealthStatus {
+        &self.status
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BootstrapInfo {
     pub id: String,
@@ -55,7 +63,9 @@ pub enum JoinResponse {
 #[derive(Clone, Debug)]
 pub struct FormnetApiState {
     pub info: BootstrapInfo,
-    pub endpoints: Arc<RwLock<HashMap<String, SocketAddr>>>
+    pub endpoints: Arc<RwLock<HashMap<String, SocketAddr>>>,
+    pub connectivity: SharedConnectivity,
+    pub bandwidth: SharedBandwidth,
 }
 
 
@@ -63,16 +73,25 @@ pub async fn server(
     bootstrap_info: BootstrapInfo,
     endpoints: Arc<RwLock<HashMap<String, SocketAddr>>>
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let bootstrap_info = Arc::new(RwLock::new(FormnetApiState { info: bootstrap_info, endpoints}));
+    let connectivity = SharedConnectivity::default();
+    let bandwidth = SharedBandwidth::default();
+    tokio::spawn(run_peer_metrics(bootstrap_info.id.clone(), connectivity.clone(), bandwidth.clone()));
+    let bootstrap_info = Arc::new(RwLock::new(FormnetApiState { info: bootstrap_info, endpoints, connectivity, bandwidth }));
+    spawn_retired_key_sweeper().await;
 
     let router = Router::new()
         .route("/health", get(health))
         .route("/join", post(join))
         .route("/leave", post(handle_leave_request))
+        .route("/rotate", post(handle_rotate_request))
+        .route("/refresh", get(get_refresh_config).post(update_refresh_config))
         .route("/fetch", get(members))
         .route("/bootstrap", get(bootstrap))
         .route("/:ip/candidates", post(candidates))
-        .with_state(bootstrap_info);
+        .route("/metrics/peers", get(peer_metrics))
+        .route("/metrics/bandwidth", get(peer_bandwidth))
+        .with_state(bootstrap_info)
+        .nest("/admin", formnet_server::admin_router());
 
     let listener = TcpListener::bind("0.0.0.0:51820").await?;
 
@@ -112,9 +131,20 @@ async fn join(
     } 
 }
 
+/// Sent by peers with their fetches, so a peer that expiry marked stale is
+/// re-enabled once it is back
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FetchParams {
+    pub pubkey: Option<String>,
+}
+
 async fn members(
     State(state): State<Arc<RwLock<FormnetApiState>>>,
+    Query(params): Query<FetchParams>,
 ) -> Json<Response> {
+    if let Some(pubkey) = params.pubkey {
+        tokio::spawn(resurrect(pubkey));
+    }
     if let Ok(ref mut peers) = DatabasePeer::<String, CrdtMap>::list().await{
         inject_endpoints(state.clone(), peers).await;
         Json(Response::Fetch(peers.iter().map(|p| p.inner.clone()).collect()))
@@ -123,6 +153,39 @@ async fn members(
     }
 }
 
+/// Asks form-state to re-enable the peer with this key if it went stale
+async fn resurrect(pubkey: String) {
+    let resp = reqwest::Client::new()
+        .post("http://127.0.0.1:3004/v1/peer/resurrect")
+        .json(&serde_json::json!({ "public_key": pubkey }))
+        .send()
+        .await;
+    if let Err(e) = resp.and_then(|resp| resp.error_for_status()) {
+        log::warn!("Unable to report fetch from peer {pubkey}: {e}");
+    }
+}
+
+async fn peer_metrics(
+    State(state): State<Arc<RwLock<FormnetApiState>>>
+) -> Json<ConnectivityMetrics> {
+    let connectivity = state.read().await.connectivity.clone();
+    let metrics = connectivity.read().await.clone();
+    Json(metrics)
+}
+
+/// Overlay traffic per peer over the accounting windows, heaviest first
+async fn peer_bandwidth(
+    State(state): State<Arc<RwLock<FormnetApiState>>>
+) -> Json<BandwidthSummary> {
+    let bandwidth = state.read().await.bandwidth.clone();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let summary = bandwidth.read().await.summary(now);
+    Json(summary)
+}
+
 async fn bootstrap(
     State(info): State<Arc<RwLock<FormnetApiState>>>
 ) -> Json<Response> {
@@ -158,7 +221,7 @@ async fn candidates(
     if let Ok(ip) = ip.parse::<IpAddr>() {
         if let Ok(device) = Device::get(&InterfaceName::from_str("formnet").unwrap(), NetworkOpts::default().backend) {
             if let Some(peer_info) = device.peers.iter().find(|p| {
-                p.config.allowed_ips.contains(&AllowedIp { address: ip, cidr: 32 })
+                p.config.allowed_ips.contains(&AllowedIp { address: ip, cidr: if ip.is_ipv4() { 32 } else { 128 } })
             }) {
                 log::info!("Parsed IP address");
                 if let Some(current_endpoint) = peer_info.config.endpoint {
@@ -171,6 +234,8 @@ async fn candidates(
                                 log::info!("Current endpoint is stale");
                                 stale_endpoint = true;
                             }
+                            // Prefer IPv4 candidates, but IPv6-only peers still
+                            // need an endpoint
                             let best_candidate = contents.iter().find(|ep| {
                                 match ep.resolve() {
                                     Ok(resolved) => {
@@ -179,7 +244,7 @@ async fn candidates(
                                     }
                                     _ => false
                                 }
-                            });
+                            }).or_else(|| contents.iter().find(|ep| ep.resolve().is_ok()));
                             let current_endpoint = if stale_endpoint && best_candidate.is_some() {
                                 best_candidate.unwrap().clone()
                             } else {
@@ -0,0 +1,234 @@
+//! Per peer bandwidth accounting for fair-use enforcement.
+//!
+//! Each connectivity sample carries the WireGuard transfer counters of every
+//! peer. The ledger turns consecutive samples into byte deltas, keeps them for
+//! the longest window and sums them over 5 minute, 1 hour and 24 hour windows
+//! for the local API. The traffic since the last publish is handed out as a
+//! `PeerBandwidthEvent` so the network can enforce fair-use policies.
+use std::{collections::{BTreeMap, VecDeque}, sync::Arc};
+use form_node_metrics::connectivity::ConnectivityMetrics;
+use form_usage_events::{EventPublisher, PeerBandwidth, PeerBandwidthEvent, UsagePeriod};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+/// The windows traffic is summed over, by name and length in seconds
+pub const WINDOWS: [(&str, i64); 3] = [("5m", 300), ("1h", 3_600), ("24h", 86_400)];
+/// Deltas older than the longest window are dropped
+const RETENTION_SECS: i64 = 86_400;
+
+pub type SharedBandwidth = Arc<RwLock<BandwidthLedger>>;
+
+/// Bytes exchanged with a peer over one window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowTotals {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl WindowTotals {
+    pub fn total(&self) -> u64 {
+        self.rx_bytes + self.tx_bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerBandwidthSummary {
+    pub peer_id: String,
+    pub formnet_ip: Option<String>,
+    /// Totals keyed by window name, see `WINDOWS`
+    pub windows: BTreeMap<String, WindowTotals>,
+}
+
+/// What the local API serves, peers ordered by their 24 hour traffic
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthSummary {
+    pub generated_at: i64,
+    pub peers: Vec<PeerBandwidthSummary>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PeerLedger {
+    formnet_ip: Option<String>,
+    /// Counters of the last sample, to take the next delta against
+    last_rx: u64,
+    last_tx: u64,
+    last_seen: i64,
+    /// `(sampled_at, rx delta, tx delta)`, oldest first
+    deltas: VecDeque<(i64, u64, u64)>,
+    /// Traffic not yet published
+    pending: WindowTotals,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLedger {
+    peers: BTreeMap<String, PeerLedger>,
+    /// Start of the period the pending traffic covers
+    pending_since: Option<i64>,
+}
+
+impl BandwidthLedger {
+    /// Records the traffic since the previous sample. Counters that went
+    /// backwards were reset with the interface, so the new value is the delta.
+    pub fn record(&mut self, sample: &ConnectivityMetrics) {
+        self.pending_since.get_or_insert(sample.updated_at);
+        for (peer_id, quality) in &sample.peers {
+            let ledger = match self.peers.get_mut(peer_id) {
+                Some(ledger) => ledger,
+                None => {
+                    // The first sample of a peer only sets the baseline
+                    self.peers.insert(peer_id.clone(), PeerLedger {
+                        formnet_ip: quality.formnet_ip.clone(),
+                        last_rx: quality.rx_bytes,
+                        last_tx: quality.tx_bytes,
+                        last_seen: quality.sampled_at,
+                        ..Default::default()
+                    });
+                    continue;
+                }
+            };
+            let delta = |current: u64, last: u64| current.checked_sub(last).unwrap_or(current);
+            let rx = delta(quality.rx_bytes, ledger.last_rx);
+            let tx = delta(quality.tx_bytes, ledger.last_tx);
+            ledger.last_rx = quality.rx_bytes;
+            ledger.last_tx = quality.tx_bytes;
+            ledger.last_seen = quality.sampled_at;
+            if quality.formnet_ip.is_some() {
+                ledger.formnet_ip = quality.formnet_ip.clone();
+            }
+            if rx > 0 || tx > 0 {
+                ledger.deltas.push_back((quality.sampled_at, rx, tx));
+                ledger.pending.rx_bytes += rx;
+                ledger.pending.tx_bytes += tx;
+            }
+        }
+        self.prune(sample.updated_at);
+    }
+
+    /// Drops deltas past the retention and peers gone for as long
+    fn prune(&mut self, now: i64) {
+        for ledger in self.peers.values_mut() {
+            while ledger.deltas.front().is_some_and(|(at, _, _)| now - at > RETENTION_SECS) {
+                ledger.deltas.pop_front();
+            }
+        }
+        self.peers.retain(|_, ledger| now - ledger.last_seen <= RETENTION_SECS || ledger.pending.total() > 0);
+    }
+
+    /// Traffic per peer over each window ending at `now`
+    pub fn summary(&self, now: i64) -> BandwidthSummary {
+        let mut peers: Vec<_> = self.peers.iter().map(|(peer_id, ledger)| {
+            let windows = WINDOWS.iter().map(|(name, length)| {
+                let totals = ledger.deltas.iter()
+                    .filter(|(at, _, _)| now - at < *length)
+                    .fold(WindowTotals::default(), |mut totals, (_, rx, tx)| {
+                        totals.rx_bytes += rx;
+                        totals.tx_bytes += tx;
+                        totals
+                    });
+                (name.to_string(), totals)
+            }).collect();
+            PeerBandwidthSummary { peer_id: peer_id.clone(), formnet_ip: ledger.formnet_ip.clone(), windows }
+        }).collect();
+
+        let longest = WINDOWS[WINDOWS.len() - 1].0;
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.windows.get(longest).map_or(0, WindowTotals::total)));
+        BandwidthSummary { generated_at: now, peers }
+    }
+
+    /// Takes the traffic recorded since the last call as a usage event, or
+    /// None if there was none
+    pub fn take_pending(&mut self, node_id: &str, now: i64) -> Option<PeerBandwidthEvent> {
+        let start = self.pending_since.replace(now)?;
+        let peers: Vec<_> = self.peers.iter_mut()
+            .filter(|(_, ledger)| ledger.pending.total() > 0)
+            .map(|(peer_id, ledger)| {
+                let pending = std::mem::take(&mut ledger.pending);
+                PeerBandwidth {
+                    peer_id: peer_id.clone(),
+                    formnet_ip: ledger.formnet_ip.clone(),
+                    rx_bytes: pending.rx_bytes,
+                    tx_bytes: pending.tx_bytes,
+                }
+            })
+            .collect();
+        if peers.is_empty() {
+            return None;
+        }
+        Some(PeerBandwidthEvent::new(node_id.to_string(), UsagePeriod { start, end: now }, peers))
+    }
+
+    /// Puts an event that couldn't be published back, so the next one covers it
+    pub fn restore(&mut self, event: PeerBandwidthEvent) {
+        self.pending_since = Some(self.pending_since.map_or(event.period.start, |since| since.min(event.period.start)));
+        for peer in event.peers {
+            let ledger = self.peers.entry(peer.peer_id).or_default();
+            ledger.pending.rx_bytes += peer.rx_bytes;
+            ledger.pending.tx_bytes += peer.tx_bytes;
+        }
+    }
+}
+
+/// Publishes the pending traffic, keeping it for the next attempt on failure
+pub async fn publish_bandwidth(node_id: &str, ledger: &SharedBandwidth, publisher: &EventPublisher, now: i64) {
+    let Some(event) = ledger.write().await.take_pending(node_id, now) else {
+        return;
+    };
+    if let Err(e) = publisher.publish_bandwidth(event.clone()).await {
+        log::warn!("Unable to publish peer bandwidth: {e}");
+        ledger.write().await.restore(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use form_node_metrics::connectivity::PeerQuality;
+
+    fn sample(at: i64, counters: &[(&str, u64, u64)]) -> ConnectivityMetrics {
+        let peers = counters.iter().map(|(peer_id, rx_bytes, tx_bytes)| {
+            (peer_id.to_string(), PeerQuality {
+                peer_id: peer_id.to_string(),
+                formnet_ip: None,
+                endpoint: None,
+                rtt_us: None,
+                jitter_us: None,
+                loss_bps: 0,
+                last_handshake: None,
+                rx_bytes: *rx_bytes,
+                tx_bytes: *tx_bytes,
+                rx_bytes_per_sec: 0,
+                tx_bytes_per_sec: 0,
+                sampled_at: at,
+            })
+        }).collect();
+        ConnectivityMetrics { peers, updated_at: at }
+    }
+
+    #[test]
+    fn test_windows_and_pending_traffic() {
+        let mut ledger = BandwidthLedger::default();
+        ledger.record(&sample(0, &[("a", 1_000, 1_000), ("b", 0, 0)]));
+        ledger.record(&sample(1_000, &[("a", 1_500, 1_100), ("b", 10, 0)]));
+        // a's counters reset with the interface
+        ledger.record(&sample(3_500, &[("a", 200, 0), ("b", 20, 5)]));
+
+        let summary = ledger.summary(3_500);
+        assert_eq!(summary.peers[0].peer_id, "a");
+        assert_eq!(summary.peers[0].windows["24h"], WindowTotals { rx_bytes: 700, tx_bytes: 100 });
+        assert_eq!(summary.peers[0].windows["1h"], WindowTotals { rx_bytes: 700, tx_bytes: 100 });
+        assert_eq!(summary.peers[0].windows["5m"], WindowTotals { rx_bytes: 200, tx_bytes: 0 });
+        assert_eq!(summary.peers[1].windows["24h"], WindowTotals { rx_bytes: 20, tx_bytes: 5 });
+
+        let event = ledger.take_pending("node", 3_500).unwrap();
+        assert_eq!((event.period.start, event.period.end), (0, 3_500));
+        assert_eq!(event.total_bytes(), 825);
+        assert!(ledger.take_pending("node", 3_600).is_none());
+
+        // A failed publish is folded into the next event
+        ledger.restore(event);
+        ledger.record(&sample(3_700, &[("a", 300, 0), ("b", 20, 5)]));
+        let event = ledger.take_pending("node", 3_700).unwrap();
+        assert_eq!(event.period.start, 0);
+        assert_eq!(event.total_bytes(), 925);
+    }
+}
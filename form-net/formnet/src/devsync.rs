@@ -0,0 +1,263 @@
+//! The file sync agent inside instances built with `DEVSYNC`.
+//!
+//! `form pack watch` sends the instance's owner-signed sync requests to
+//! form-pack on the provider, which forwards them here over formnet. The
+//! agent answers with a manifest of the files under the instance's WORKDIR
+//! so the CLI only ships files whose hashes differ, writes the delta it
+//! receives and restarts the app the way the Formfile asks.
+use std::{
+    fs,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use alloy_core::primitives::Address;
+use axum::{extract::{DefaultBodyLimit, State}, routing::post, Json, Router};
+use base64::Engine;
+use form_types::{
+    is_safe_sync_path, DevRestart, DevSyncConfig, FileManifest, SignedSyncRequest, SyncAction,
+    SyncFile, SyncRequest, SyncResponse, DEV_SYNC_CONFIG_PATH, DEV_SYNC_MAX_SKEW_SECS, DEV_SYNC_PORT,
+};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use ring::digest;
+use thiserror::Error;
+use tokio::process::Command;
+
+/// Largest delta the agent accepts in one request
+const MAX_DELTA_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum DevSyncError {
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("{0} is not allowed to sync this instance")]
+    NotOwner(String),
+    #[error("request is for build {0}")]
+    WrongBuild(String),
+    #[error("request timestamp is more than {DEV_SYNC_MAX_SKEW_SECS}s off")]
+    Stale,
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("{0} is outside the sync root")]
+    InvalidPath(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+struct Agent {
+    config: DevSyncConfig,
+    build_id: String,
+}
+
+/// Serves sync requests until the process is stopped
+pub async fn run_dev_sync() -> Result<(), Box<dyn std::error::Error>> {
+    let config: DevSyncConfig = serde_json::from_str(&fs::read_to_string(DEV_SYNC_CONFIG_PATH)?)?;
+    let build_id = fs::read_to_string("/etc/build_id")?.trim().to_string();
+    log::info!("Syncing {} for {} into {}", build_id, config.owner, config.root);
+
+    let app = Router::new()
+        .route("/sync", post(handle_sync))
+        .layer(DefaultBodyLimit::max(MAX_DELTA_SIZE))
+        .with_state(Arc::new(Agent { config, build_id }));
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", DEV_SYNC_PORT)).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn handle_sync(
+    State(agent): State<Arc<Agent>>,
+    Json(signed): Json<SignedSyncRequest>,
+) -> Json<SyncResponse> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let request = match verify_request(&signed, &agent.config.owner, &agent.build_id, now) {
+        Ok(request) => request,
+        Err(e) => {
+            log::warn!("Refused sync request: {e}");
+            return Json(SyncResponse::Failure { reason: e.to_string() });
+        }
+    };
+
+    let root = Path::new(&agent.config.root);
+    let response = match request.action {
+        SyncAction::Manifest => build_manifest(root).map(SyncResponse::Manifest),
+        SyncAction::Apply { files, deleted, restart } => {
+            match apply_delta(root, &files, &deleted) {
+                Ok(()) => {
+                    log::info!("Wrote {} and removed {} files", files.len(), deleted.len());
+                    let restarted = restart && restart_app(&agent.config.restart).await;
+                    Ok(SyncResponse::Applied { written: files.len(), deleted: deleted.len(), restarted })
+                }
+                Err(e) => Err(e),
+            }
+        }
+    };
+    Json(response.unwrap_or_else(|e| SyncResponse::Failure { reason: e.to_string() }))
+}
+
+/// Checks that `signed` comes from `owner`, targets `build_id` and is fresh
+pub fn verify_request(
+    signed: &SignedSyncRequest,
+    owner: &str,
+    build_id: &str,
+    now: u64,
+) -> Result<SyncRequest, DevSyncError> {
+    let signature = hex::decode(&signed.signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| DevSyncError::InvalidSignature("malformed signature".to_string()))?;
+    let recovery_id = RecoveryId::from_byte(signed.recovery_id)
+        .ok_or_else(|| DevSyncError::InvalidSignature("invalid recovery id".to_string()))?;
+    let key = VerifyingKey::recover_from_msg(signed.request.as_bytes(), &signature, recovery_id)
+        .map_err(|e| DevSyncError::InvalidSignature(e.to_string()))?;
+    let signer = hex::encode(Address::from_public_key(&key));
+    if !signer.eq_ignore_ascii_case(owner.trim_start_matches("0x")) {
+        return Err(DevSyncError::NotOwner(signer));
+    }
+
+    let request: SyncRequest = serde_json::from_str(&signed.request)
+        .map_err(|e| DevSyncError::InvalidRequest(e.to_string()))?;
+    if request.build_id != build_id {
+        return Err(DevSyncError::WrongBuild(request.build_id));
+    }
+    if request.timestamp.abs_diff(now) > DEV_SYNC_MAX_SKEW_SECS {
+        return Err(DevSyncError::Stale);
+    }
+    Ok(request)
+}
+
+/// Hashes every regular file under `root`, a missing root is empty
+pub fn build_manifest(root: &Path) -> Result<FileManifest, DevSyncError> {
+    let mut manifest = FileManifest::default();
+    if root.is_dir() {
+        add_dir(root, "", &mut manifest)?;
+    }
+    Ok(manifest)
+}
+
+fn add_dir(dir: &Path, prefix: &str, manifest: &mut FileManifest) -> Result<(), DevSyncError> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let relative = if prefix.is_empty() { name } else { format!("{prefix}/{name}") };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            add_dir(&entry.path(), &relative, manifest)?;
+        } else if file_type.is_file() {
+            let hash = digest::digest(&digest::SHA256, &fs::read(entry.path())?);
+            manifest.files.insert(relative, hex::encode(hash.as_ref()));
+        }
+    }
+    Ok(())
+}
+
+/// Writes `files` and removes `deleted` under `root`. Every path is checked
+/// before anything is touched, so a bad request changes nothing.
+pub fn apply_delta(root: &Path, files: &[SyncFile], deleted: &[String]) -> Result<(), DevSyncError> {
+    if let Some(path) = files.iter().map(|f| &f.path).chain(deleted).find(|p| !is_safe_sync_path(p)) {
+        return Err(DevSyncError::InvalidPath(path.clone()));
+    }
+    let contents = files.iter()
+        .map(|file| base64::engine::general_purpose::STANDARD.decode(&file.data)
+            .map_err(|e| DevSyncError::InvalidRequest(format!("{}: {e}", file.path))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (file, data) in files.iter().zip(contents) {
+        let target = root.join(&file.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Replace the file in one step so the app never reads half of it
+        let staged = target.with_file_name(format!(
+            ".{}.form-sync",
+            target.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+        ));
+        fs::write(&staged, data)?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(file.mode & 0o7777))?;
+        fs::rename(&staged, &target)?;
+    }
+    for path in deleted {
+        match fs::remove_file(root.join(path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+async fn restart_app(restart: &DevRestart) -> bool {
+    let command = match restart {
+        DevRestart::Service => "systemctl restart form-app.service",
+        DevRestart::Command(command) => command.as_str(),
+        DevRestart::None => return false,
+    };
+    match Command::new("sh").args(["-c", command]).output().await {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            log::error!("`{command}` exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+            false
+        }
+        Err(e) => {
+            log::error!("Unable to run `{command}`: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+
+    fn sign(key: &SigningKey, request: &SyncRequest) -> SignedSyncRequest {
+        let request = serde_json::to_string(request).unwrap();
+        let (signature, recovery_id) = key.sign_recoverable(request.as_bytes()).unwrap();
+        SignedSyncRequest {
+            request,
+            signature: hex::encode(signature.to_bytes()),
+            recovery_id: recovery_id.to_byte(),
+        }
+    }
+
+    #[test]
+    fn test_verify_request() {
+        let key = SigningKey::random(&mut rand_core::OsRng);
+        let owner = hex::encode(Address::from_private_key(&key));
+        let request = SyncRequest { build_id: "build".to_string(), timestamp: 1_000, action: SyncAction::Manifest };
+        let signed = sign(&key, &request);
+
+        assert_eq!(verify_request(&signed, &format!("0x{owner}"), "build", 1_100).unwrap(), request);
+        assert!(matches!(verify_request(&signed, &owner, "other", 1_000), Err(DevSyncError::WrongBuild(_))));
+        assert!(matches!(verify_request(&signed, &owner, "build", 1_000 + DEV_SYNC_MAX_SKEW_SECS + 1), Err(DevSyncError::Stale)));
+
+        let stranger = SigningKey::random(&mut rand_core::OsRng);
+        assert!(matches!(verify_request(&sign(&stranger, &request), &owner, "build", 1_000), Err(DevSyncError::NotOwner(_))));
+
+        let mut tampered = signed.clone();
+        tampered.request = tampered.request.replace("1000", "1001");
+        assert!(verify_request(&tampered, &owner, "build", 1_000).is_err());
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("old.txt"), "old").unwrap();
+        let file = |path: &str, data: &str| SyncFile {
+            path: path.to_string(),
+            mode: 0o644,
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+        };
+
+        apply_delta(root.path(), &[file("src/main.py", "print(1)")], &["old.txt".to_string()]).unwrap();
+        let manifest = build_manifest(root.path()).unwrap();
+        assert_eq!(manifest.files.keys().collect::<Vec<_>>(), vec!["src/main.py"]);
+        assert_eq!(fs::read_to_string(root.path().join("src/main.py")).unwrap(), "print(1)");
+
+        // Nothing is written when any path escapes the root
+        let result = apply_delta(root.path(), &[file("new.txt", "x"), file("../escape.txt", "x")], &[]);
+        assert!(matches!(result, Err(DevSyncError::InvalidPath(_))));
+        assert!(!root.path().join("new.txt").exists());
+        assert!(apply_delta(root.path(), &[], &["/etc/passwd".to_string()]).is_err());
+    }
+}
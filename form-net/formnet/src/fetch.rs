@@ -884,6 +884,8 @@ pub async fn start_relay_monitoring(
     // Set up a timer to run health checks periodically
     let mut interval = tokio::time::interval(Duration::from_secs(30));
     
+    let probe_manager = relay_manager.clone();
+    
     // Spawn a background task for relay monitoring
     tokio::spawn(async move {
         loop {
@@ -895,6 +897,19 @@ pub async fn start_relay_monitoring(
         }
     });
     
+    // Probe known relays for latency and load in a separate task, so a slow
+    // probe round doesn't hold up heartbeats
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(crate::relay::manager::RELAY_PROBE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match probe_manager.probe_relays().await {
+                Ok(answered) => log::debug!("{} relays answered latency probes", answered),
+                Err(e) => log::error!("Error probing relays: {}", e),
+            }
+        }
+    });
+    
     Ok(())
 }
 
@@ -1112,8 +1127,13 @@ pub async fn fetch(
             log::warn!("Failed to list peers from database - bootstrap node may not have any peers yet");
         }
     } else {
-        // Normal mode for non-bootstrap nodes: fetch from bootstrap node
-        let bootstrap_resp = Client::new().get(format!("http://{external}/fetch")).send();
+        // Normal mode for non-bootstrap nodes: fetch from bootstrap node.
+        // Our key lets the bootstrap re-enable us if we were marked stale.
+        let own_pubkey = wireguard_control::Key::from_base64(&config.private_key)
+            .map(|key| key.get_public().to_base64())
+            .ok();
+        let fetch_query: Vec<(&str, String)> = own_pubkey.into_iter().map(|key| ("pubkey", key)).collect();
+        let bootstrap_resp = Client::new().get(format!("http://{external}/fetch")).query(&fetch_query).send();
         match bootstrap_resp.await {
             Ok(resp) => {
                 if let Err(e) = handle_server_response(resp, &interface, network, data_dir.clone(), interface_up, external.to_string(), config.address.to_string(), host_port, hosts_path.clone(), &mut connection_cache).await {
@@ -1127,7 +1147,7 @@ pub async fn fetch(
                 for admin in admins {
                     if let Some(ref external) = &admin.endpoint {
                         if let Ok(endpoint) = external.resolve() {
-                            if let Ok(resp) = Client::new().get(format!("http://{endpoint}/fetch")).send().await {
+                            if let Ok(resp) = Client::new().get(format!("http://{endpoint}/fetch")).query(&fetch_query).send().await {
                                 match handle_server_response(
                                     resp, 
                                     &interface, 
@@ -1536,6 +1556,7 @@ async fn try_server_nat_traversal(
                     is_redeemed: true,
                     invite_expires: None,
                     candidates: vec![],
+                    retiring_key: None,
                 }
             }
         }).collect();
@@ -1621,10 +1642,7 @@ pub async fn fetch_server(
         log::info!("{}", "peers are already up to date");
     }
 
-    let candidates: Vec<Endpoint> = get_local_addrs()?
-        .filter(|ip| !NatOpts::default().is_excluded(*ip))
-        .map(|addr| SocketAddr::from((addr, device.listen_port.unwrap_or(51820))).into())
-        .collect::<Vec<Endpoint>>();
+    let candidates = gather_candidates(device.listen_port.unwrap_or(51820))?;
     log::info!(
         "reporting {} interface address{} as NAT traversal candidates",
         candidates.len(),
@@ -1692,13 +1710,37 @@ pub async fn fetch_server(
     Ok(())
 }
 
+/// Collects NAT traversal candidates: the endpoint mapped on the gateway, if
+/// any, then every interface address (IPv4 and global IPv6) plus the public
+/// addresses of this host that aren't on an interface
+pub fn gather_candidates(listen_port: u16) -> Result<Vec<Endpoint>, Box<dyn std::error::Error>> {
+    let nat_opts = NatOpts::default();
+    let mut addrs: Vec<IpAddr> = get_local_addrs()?
+        .filter(|ip| !nat_opts.is_excluded(*ip))
+        .collect();
+    for ip in shared::get_public_addrs() {
+        if !addrs.contains(&ip) && !nat_opts.is_excluded(ip) {
+            addrs.push(ip);
+        }
+    }
+
+    let mut candidates: Vec<Endpoint> = crate::port_mapping::mapped_endpoint()
+        .map(Endpoint::from)
+        .into_iter()
+        .collect();
+    for addr in addrs {
+        let candidate = Endpoint::from(SocketAddr::from((addr, listen_port)));
+        if !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    Ok(candidates)
+}
+
 pub async fn report_initial_candidates(bootstraps: Vec<String>, my_ip: String) -> Result<(), Box<dyn std::error::Error>> {
     let device = Device::get(&InterfaceName::from_str("formnet")?, Backend::default())?;
     log::info!("Getting candidates...");
-    let candidates: Vec<Endpoint> = get_local_addrs()?
-        .filter(|ip| !NatOpts::default().is_excluded(*ip))
-        .map(|addr| SocketAddr::from((addr, device.listen_port.unwrap_or(51820))).into())
-        .collect::<Vec<Endpoint>>();
+    let candidates = gather_candidates(device.listen_port.unwrap_or(51820))?;
 
     log::info!(
         "reporting {} interface address{} as NAT traversal candidates",
@@ -1727,10 +1769,7 @@ pub async fn report_initial_candidates(bootstraps: Vec<String>, my_ip: String) -
 pub async fn report_candidates(admins: Vec<String>, my_ip: String) -> Result<(), Box<dyn std::error::Error>> { 
     let device = Device::get(&InterfaceName::from_str("formnet")?, Backend::default())?;
     log::info!("Getting candidates...");
-    let candidates: Vec<Endpoint> = get_local_addrs()?
-        .filter(|ip| !NatOpts::default().is_excluded(*ip))
-        .map(|addr| SocketAddr::from((addr, device.listen_port.unwrap_or(51820))).into())
-        .collect::<Vec<Endpoint>>();
+    let candidates = gather_candidates(device.listen_port.unwrap_or(51820))?;
     log::info!(
         "reporting {} interface address{} as NAT traversal candidates",
         candidates.len(),
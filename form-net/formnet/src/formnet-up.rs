@@ -1,4 +1,4 @@
-use std::time::Duration;
+use form_config::FormnetRefreshConfig;
 use colored::Colorize;
 use daemonize::Daemonize;
 use formnet::up;
@@ -22,7 +22,8 @@ fn main() {
             let rt = Runtime::new().expect("unable to launch tokio runtime");
             rt.block_on(async {
                 if let Err(e) = up(
-                    Some(Duration::from_secs(60)),
+                    Some(FormnetRefreshConfig::default()),
+                    None,
                     None,
                 ).await {
                     println!("{}: {}", "Error trying to bring formnet up".yellow(), e.to_string().red());
@@ -39,7 +40,8 @@ fn main() {
     #[cfg(not(target_os = "linux"))]
     rt.block_on(async {
         if let Err(e) = up(
-            Some(Duration::from_secs(60)),
+            Some(FormnetRefreshConfig::default()),
+            None,
             None,
         ).await {
             println!("{}: {}", "Error trying to bring formnet up".yellow(), e.to_string().red());
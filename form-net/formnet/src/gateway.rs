@@ -0,0 +1,500 @@
+//! A SOCKS5 and HTTP CONNECT gateway into formnet for machines that can't
+//! join it themselves.
+//!
+//! The gateway runs on a node that is already a member. Every client has a
+//! username and password, a list of overlay CIDRs (and optionally ports) it
+//! may connect to, and an optional bandwidth cap shared by all of its
+//! connections. Destinations outside the formnet CIDR are always refused so
+//! the gateway can't be used as an open proxy.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use base64::Engine;
+use ipnet::IpNet;
+use ring::{constant_time::verify_slices_are_equal, digest};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+pub const DEFAULT_GATEWAY_CONFIG: &str = "/etc/formnet/gateway.json";
+
+/// How long a client gets to authenticate and name its destination
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest HTTP CONNECT request head the gateway reads
+const MAX_HTTP_HEAD: usize = 8 * 1024;
+const COPY_BUFFER: usize = 16 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayConfig {
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// The formnet CIDR, no destination outside of it is proxied
+    pub overlay: IpNet,
+    pub clients: Vec<GatewayClient>,
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 1080))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayClient {
+    pub username: String,
+    /// Hex encoded SHA-256 of the client's password
+    pub password_sha256: String,
+    pub allow: Vec<GatewayRule>,
+    /// Combined limit for all of the client's connections, in each direction
+    #[serde(default)]
+    pub bandwidth_bytes_per_sec: Option<u64>,
+}
+
+/// Destinations a client may reach, any port if `ports` is empty
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayRule {
+    pub cidr: IpNet,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+impl GatewayConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, GatewayError> {
+        let config: Self = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        for client in &config.clients {
+            if hex::decode(&client.password_sha256).map(|hash| hash.len()) != Ok(32) {
+                return Err(GatewayError::Config(format!(
+                    "password_sha256 of {} is not a hex SHA-256 hash", client.username
+                )));
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum GatewayError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid gateway config: {0}")]
+    Config(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Client handshake failed: {0}")]
+    Protocol(String),
+    #[error("Authentication failed for {0:?}")]
+    Unauthorized(String),
+    #[error("{0} may not connect to {1}")]
+    Denied(String, SocketAddr),
+    #[error("Unable to reach {0}: {1}")]
+    Unreachable(String, String),
+}
+
+/// A token bucket holding at most one second of traffic. Tokens may go
+/// negative, a caller that overdraws waits until the debt is paid back.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self { rate: bytes_per_sec.max(1), tokens: bytes_per_sec as f64, updated: Instant::now() }
+    }
+
+    /// Takes `bytes` at `now` and returns how long to wait before sending them
+    pub fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// A configured client along with the limiter its connections share
+struct ClientState {
+    client: GatewayClient,
+    password_sha256: Vec<u8>,
+    limiter: Option<Arc<Mutex<RateLimiter>>>,
+}
+
+pub struct Gateway {
+    overlay: IpNet,
+    clients: HashMap<String, ClientState>,
+}
+
+impl Gateway {
+    pub fn new(config: &GatewayConfig) -> Self {
+        let clients = config.clients.iter().map(|client| {
+            let state = ClientState {
+                client: client.clone(),
+                password_sha256: hex::decode(&client.password_sha256).unwrap_or_default(),
+                limiter: client.bandwidth_bytes_per_sec.map(|rate| Arc::new(Mutex::new(RateLimiter::new(rate)))),
+            };
+            (client.username.clone(), state)
+        }).collect();
+        Self { overlay: config.overlay, clients }
+    }
+
+    fn authenticate(&self, username: &str, password: &[u8]) -> Result<&ClientState, GatewayError> {
+        let hash = digest::digest(&digest::SHA256, password);
+        self.clients.get(username)
+            .filter(|state| verify_slices_are_equal(hash.as_ref(), &state.password_sha256).is_ok())
+            .ok_or_else(|| GatewayError::Unauthorized(username.to_string()))
+    }
+
+    /// Whether the client may open a connection to `dest`
+    pub fn allows(&self, username: &str, dest: SocketAddr) -> bool {
+        self.overlay.contains(&dest.ip()) && self.clients.get(username).is_some_and(|state| {
+            state.client.allow.iter().any(|rule| {
+                rule.cidr.contains(&dest.ip()) && (rule.ports.is_empty() || rule.ports.contains(&dest.port()))
+            })
+        })
+    }
+
+    /// Resolves `host` and checks the first address the client is allowed to reach
+    async fn authorize(&self, username: &str, host: &str, port: u16) -> Result<SocketAddr, GatewayError> {
+        let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => tokio::net::lookup_host((host, port)).await
+                .map_err(|e| GatewayError::Unreachable(host.to_string(), e.to_string()))?
+                .collect(),
+        };
+        let denied = addrs.first().copied().unwrap_or(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port));
+        addrs.into_iter()
+            .find(|addr| self.allows(username, *addr))
+            .ok_or_else(|| GatewayError::Denied(username.to_string(), denied))
+    }
+}
+
+/// Serves the gateway until the process is stopped
+pub async fn serve_gateway(config: GatewayConfig) -> Result<(), GatewayError> {
+    let listener = TcpListener::bind(config.listen).await?;
+    let gateway = Arc::new(Gateway::new(&config));
+    log::info!(
+        "formnet gateway listening on {} for {} clients into {}",
+        config.listen, config.clients.len(), config.overlay
+    );
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(&gateway, stream).await {
+                log::warn!("Gateway connection from {peer} closed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_client(gateway: &Gateway, stream: TcpStream) -> Result<(), GatewayError> {
+    let mut first = [0u8; 1];
+    stream.peek(&mut first).await?;
+    let mut stream = BufReader::new(stream);
+
+    let handshake = async {
+        if first[0] == 0x05 {
+            socks5_handshake(gateway, &mut stream).await
+        } else {
+            http_connect_handshake(gateway, &mut stream).await
+        }
+    };
+    let (username, upstream) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await
+        .map_err(|_| GatewayError::Protocol("timed out".into()))??;
+
+    let limiter = gateway.clients.get(&username).and_then(|state| state.limiter.clone());
+    let (mut client_read, mut client_write) = tokio::io::split(stream);
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+    tokio::select! {
+        res = throttled_copy(&mut client_read, &mut upstream_write, limiter.clone()) => res?,
+        res = throttled_copy(&mut upstream_read, &mut client_write, limiter) => res?,
+    };
+    Ok(())
+}
+
+async fn throttled_copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiter: Option<Arc<Mutex<RateLimiter>>>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; COPY_BUFFER];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        if let Some(limiter) = &limiter {
+            let wait = limiter.lock().await.reserve(n, Instant::now());
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        writer.write_all(&buf[..n]).await?;
+    }
+}
+
+async fn connect(dest: SocketAddr) -> Result<TcpStream, GatewayError> {
+    tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(dest)).await
+        .map_err(|_| GatewayError::Unreachable(dest.to_string(), "timed out".into()))?
+        .map_err(|e| GatewayError::Unreachable(dest.to_string(), e.to_string()))
+}
+
+mod socks {
+    pub const VERSION: u8 = 0x05;
+    pub const AUTH_VERSION: u8 = 0x01;
+    pub const METHOD_USER_PASS: u8 = 0x02;
+    pub const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+    pub const CMD_CONNECT: u8 = 0x01;
+    pub const ATYP_IPV4: u8 = 0x01;
+    pub const ATYP_DOMAIN: u8 = 0x03;
+    pub const ATYP_IPV6: u8 = 0x04;
+    pub const REP_SUCCEEDED: u8 = 0x00;
+    pub const REP_NOT_ALLOWED: u8 = 0x02;
+    pub const REP_HOST_UNREACHABLE: u8 = 0x04;
+    pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+    pub const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+}
+
+async fn socks5_reply<S: AsyncWrite + Unpin>(stream: &mut S, rep: u8) -> std::io::Result<()> {
+    stream.write_all(&[socks::VERSION, rep, 0x00, socks::ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await
+}
+
+async fn read_string<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, GatewayError> {
+    let len = stream.read_u8().await? as usize;
+    let mut value = vec![0u8; len];
+    stream.read_exact(&mut value).await?;
+    Ok(value)
+}
+
+/// RFC 1928 CONNECT with RFC 1929 username/password authentication
+async fn socks5_handshake<S>(gateway: &Gateway, stream: &mut S) -> Result<(String, TcpStream), GatewayError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&socks::METHOD_USER_PASS) {
+        stream.write_all(&[socks::VERSION, socks::METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(GatewayError::Protocol("client does not offer username/password authentication".into()));
+    }
+    stream.write_all(&[socks::VERSION, socks::METHOD_USER_PASS]).await?;
+
+    if stream.read_u8().await? != socks::AUTH_VERSION {
+        return Err(GatewayError::Protocol("unsupported authentication version".into()));
+    }
+    let username = String::from_utf8_lossy(&read_string(stream).await?).into_owned();
+    let password = read_string(stream).await?;
+    if let Err(e) = gateway.authenticate(&username, &password) {
+        stream.write_all(&[socks::AUTH_VERSION, 0x01]).await?;
+        return Err(e);
+    }
+    stream.write_all(&[socks::AUTH_VERSION, 0x00]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[1] != socks::CMD_CONNECT {
+        socks5_reply(stream, socks::REP_COMMAND_NOT_SUPPORTED).await?;
+        return Err(GatewayError::Protocol(format!("unsupported command {}", request[1])));
+    }
+    let host = match request[3] {
+        socks::ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        socks::ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        socks::ATYP_DOMAIN => String::from_utf8_lossy(&read_string(stream).await?).into_owned(),
+        atyp => {
+            socks5_reply(stream, socks::REP_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(GatewayError::Protocol(format!("unsupported address type {atyp}")));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    let dest = match gateway.authorize(&username, &host, port).await {
+        Ok(dest) => dest,
+        Err(e) => {
+            let rep = match e {
+                GatewayError::Denied(..) => socks::REP_NOT_ALLOWED,
+                _ => socks::REP_HOST_UNREACHABLE,
+            };
+            socks5_reply(stream, rep).await?;
+            return Err(e);
+        }
+    };
+    match connect(dest).await {
+        Ok(upstream) => {
+            socks5_reply(stream, socks::REP_SUCCEEDED).await?;
+            log::info!("Gateway client {username} connected to {dest}");
+            Ok((username, upstream))
+        }
+        Err(e) => {
+            socks5_reply(stream, socks::REP_HOST_UNREACHABLE).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Parses the head of an HTTP CONNECT request into its target and the
+/// credentials from its `Proxy-Authorization: Basic` header
+pub fn parse_http_connect(head: &str) -> Result<(String, u16, Option<(String, Vec<u8>)>), GatewayError> {
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    if parts.next() != Some("CONNECT") {
+        return Err(GatewayError::Protocol("only CONNECT requests are supported".into()));
+    }
+    let authority = parts.next().ok_or_else(|| GatewayError::Protocol("missing CONNECT target".into()))?;
+    let (host, port) = authority.rsplit_once(':')
+        .ok_or_else(|| GatewayError::Protocol(format!("CONNECT target {authority} has no port")))?;
+    let port = port.parse::<u16>()
+        .map_err(|_| GatewayError::Protocol(format!("invalid port in {authority}")))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+
+    let credentials = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Basic "))
+        .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| {
+            let split = decoded.iter().position(|b| *b == b':')?;
+            Some((String::from_utf8_lossy(&decoded[..split]).into_owned(), decoded[split + 1..].to_vec()))
+        });
+    Ok((host, port, credentials))
+}
+
+async fn http_connect_handshake<S>(gateway: &Gateway, stream: &mut S) -> Result<(String, TcpStream), GatewayError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_HEAD {
+            stream.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n").await?;
+            return Err(GatewayError::Protocol("request head too large".into()));
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    let (host, port, credentials) = match parse_http_connect(&String::from_utf8_lossy(&head)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            stream.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n\r\n").await?;
+            return Err(e);
+        }
+    };
+    let Some((username, password)) = credentials else {
+        stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"formnet\"\r\n\r\n").await?;
+        return Err(GatewayError::Unauthorized(String::new()));
+    };
+    if let Err(e) = gateway.authenticate(&username, &password) {
+        stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"formnet\"\r\n\r\n").await?;
+        return Err(e);
+    }
+
+    let dest = match gateway.authorize(&username, &host, port).await {
+        Ok(dest) => dest,
+        Err(e) => {
+            let status: &[u8] = match e {
+                GatewayError::Denied(..) => b"HTTP/1.1 403 Forbidden\r\n\r\n",
+                _ => b"HTTP/1.1 502 Bad Gateway\r\n\r\n",
+            };
+            stream.write_all(status).await?;
+            return Err(e);
+        }
+    };
+    match connect(dest).await {
+        Ok(upstream) => {
+            stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+            log::info!("Gateway client {username} connected to {dest}");
+            Ok((username, upstream))
+        }
+        Err(e) => {
+            stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway() -> Gateway {
+        let config: GatewayConfig = serde_json::from_value(serde_json::json!({
+            "overlay": "10.42.0.0/16",
+            "clients": [{
+                "username": "ci",
+                "password_sha256": hex::encode(digest::digest(&digest::SHA256, b"hunter2")),
+                "allow": [
+                    { "cidr": "10.42.1.0/24", "ports": [443] },
+                    { "cidr": "10.42.2.7/32" },
+                    { "cidr": "0.0.0.0/0" }
+                ],
+                "bandwidth_bytes_per_sec": 1000
+            }]
+        })).unwrap();
+        Gateway::new(&config)
+    }
+
+    #[test]
+    fn test_gateway_acl() {
+        let gateway = gateway();
+        assert!(gateway.authenticate("ci", b"hunter2").is_ok());
+        assert!(gateway.authenticate("ci", b"hunter3").is_err());
+        assert!(gateway.authenticate("other", b"hunter2").is_err());
+
+        assert!(gateway.allows("ci", "10.42.1.5:443".parse().unwrap()));
+        assert!(!gateway.allows("ci", "10.42.1.5:22".parse().unwrap()));
+        assert!(gateway.allows("ci", "10.42.2.7:22".parse().unwrap()));
+        // A rule wider than the overlay doesn't open the gateway to the internet
+        assert!(!gateway.allows("ci", "1.1.1.1:443".parse().unwrap()));
+        assert!(!gateway.allows("other", "10.42.1.5:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter { rate: 1000, tokens: 1000.0, updated: start };
+        assert_eq!(limiter.reserve(1000, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+        // Half a second later the debt is paid and nothing has built up
+        assert_eq!(limiter.reserve(0, start + Duration::from_millis(500)), Duration::ZERO);
+        assert_eq!(limiter.reserve(250, start + Duration::from_millis(750)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_parse_http_connect() {
+        let head = "CONNECT 10.42.1.5:443 HTTP/1.1\r\nHost: 10.42.1.5:443\r\nProxy-Authorization: Basic Y2k6aHVudGVyMg==\r\n\r\n";
+        let (host, port, credentials) = parse_http_connect(head).unwrap();
+        assert_eq!((host.as_str(), port), ("10.42.1.5", 443));
+        assert_eq!(credentials, Some(("ci".to_string(), b"hunter2".to_vec())));
+
+        assert!(parse_http_connect("GET / HTTP/1.1\r\n\r\n").is_err());
+        assert_eq!(parse_http_connect("CONNECT [fd00::1]:80 HTTP/1.1\r\n\r\n").unwrap().2, None);
+    }
+}
@@ -0,0 +1,432 @@
+//! The guest agent, run inside every instance.
+//!
+//! It listens on vsock for requests from vmm-service on the host: running a
+//! command, with its output returned at the end or streamed as it's written,
+//! reading a file or reporting the guest's health. Reports to the
+//! host, such as the end of the boot, go the other way over the same device.
+//! Both directions carry the token vmm-service wrote to
+//! `GUEST_AGENT_TOKEN_PATH` when it created the instance.
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use base64::Engine;
+use form_types::{
+    guest_token_matches, GuestCommand, ATTESTATION_REPORT_DATA_SIZE, GuestEvent, GuestHealth, GuestReport, GuestRequest,
+    GuestResponse, OutputStream, DEFAULT_EXEC_TIMEOUT_SECS, DEFAULT_FETCH_LIMIT, GUEST_AGENT_PORT,
+    GUEST_AGENT_TOKEN_PATH, HOST_CID, HOST_REPORT_PORT, MAX_GUEST_MESSAGE_SIZE,
+};
+use socket2::{Domain, SockAddr, Socket, Type};
+use crate::NETWORK_NAME;
+
+/// Accept connections from any CID, only the host can reach the guest
+const VMADDR_CID_ANY: u32 = u32::MAX;
+
+/// Output kept of each of a command's stdout and stderr
+const MAX_EXEC_OUTPUT: u64 = 4 * 1024 * 1024;
+
+/// Largest piece of output a streamed command's `Output` line carries
+const OUTPUT_CHUNK: usize = 16 * 1024;
+
+/// Time the host gets to send a request once it has connected
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// configfs-tsm directory attestation reports are requested under
+const TSM_REPORT_DIR: &str = "/sys/kernel/config/tsm/report";
+
+static NEXT_REPORT: AtomicU64 = AtomicU64::new(0);
+
+fn read_token() -> io::Result<String> {
+    Ok(fs::read_to_string(GUEST_AGENT_TOKEN_PATH)?.trim().to_string())
+}
+
+/// Serves host requests until the process is stopped
+pub fn run_guest_agent() -> io::Result<()> {
+    let token = read_token()?;
+    let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+    socket.bind(&SockAddr::vsock(VMADDR_CID_ANY, GUEST_AGENT_PORT))?;
+    socket.listen(16)?;
+    log::info!("Guest agent listening on vsock port {GUEST_AGENT_PORT}");
+
+    loop {
+        let (conn, _) = socket.accept()?;
+        let token = token.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(conn, &token) {
+                log::warn!("Error serving guest agent request: {e}");
+            }
+        });
+    }
+}
+
+fn serve(conn: Socket, token: &str) -> io::Result<()> {
+    conn.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&conn);
+    let response = match read_message::<GuestRequest>(&mut reader) {
+        Ok(request) if guest_token_matches(token, &request.token) => match request.command {
+            GuestCommand::ExecStream { program, args, env, timeout_secs } => {
+                log::info!("Streaming {program} {args:?} for the host");
+                let timeout = Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_EXEC_TIMEOUT_SECS));
+                return exec_stream(&program, &args, env, timeout, |response| write_message(&mut &conn, &response))
+                    .or_else(|e| write_message(&mut &conn, &GuestResponse::Error { message: e.to_string() }));
+            }
+            command => handle(command),
+        },
+        Ok(_) => GuestResponse::Error { message: "invalid guest agent token".to_string() },
+        Err(e) => GuestResponse::Error { message: e.to_string() },
+    };
+    write_message(&mut &conn, &response)
+}
+
+fn handle(command: GuestCommand) -> GuestResponse {
+    let result = match command {
+        GuestCommand::Exec { program, args, env, timeout_secs }
+        | GuestCommand::ExecStream { program, args, env, timeout_secs } => {
+            log::info!("Running {program} {args:?} for the host");
+            let timeout = Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_EXEC_TIMEOUT_SECS));
+            exec(&program, &args, env, timeout)
+        }
+        GuestCommand::FetchFile { path, max_bytes } => fetch_file(&path, max_bytes.unwrap_or(DEFAULT_FETCH_LIMIT)),
+        GuestCommand::Health => health().map(GuestResponse::Health),
+        GuestCommand::AttestationReport { report_data } => attestation_report(Path::new(TSM_REPORT_DIR), &report_data),
+    };
+    result.unwrap_or_else(|e| GuestResponse::Error { message: e.to_string() })
+}
+
+fn exec(
+    program: &str,
+    args: &[String],
+    env: impl IntoIterator<Item = (String, String)>,
+    timeout: Duration,
+) -> io::Result<GuestResponse> {
+    let mut child = Command::new(program)
+        .args(args)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().map(collect_output);
+    let stderr = child.stderr.take().map(collect_output);
+
+    let deadline = Instant::now() + timeout;
+    let (status, timed_out) = loop {
+        if let Some(status) = child.try_wait()? {
+            break (Some(status), false);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break (None, true);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let output = |handle: Option<thread::JoinHandle<String>>| handle.and_then(|h| h.join().ok()).unwrap_or_default();
+    Ok(GuestResponse::Exec {
+        exit_code: status.and_then(|status| status.code()),
+        stdout: output(stdout),
+        stderr: output(stderr),
+        timed_out,
+    })
+}
+
+/// Reads a pipe to the end on a thread of its own, so a chatty command
+/// can't block on a full pipe, keeping the first `MAX_EXEC_OUTPUT` bytes
+fn collect_output(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut kept = Vec::new();
+        let _ = (&mut pipe).take(MAX_EXEC_OUTPUT).read_to_end(&mut kept);
+        let _ = io::copy(&mut pipe, &mut io::sink());
+        String::from_utf8_lossy(&kept).into_owned()
+    })
+}
+
+/// Runs a program, handing `send` an `Output` for each piece of output as
+/// it's written and the `Exit` once the program ended. A failed `send`
+/// means the host is gone, the program is killed.
+fn exec_stream(
+    program: &str,
+    args: &[String],
+    env: impl IntoIterator<Item = (String, String)>,
+    timeout: Duration,
+    mut send: impl FnMut(GuestResponse) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (tx, rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward_output(stdout, OutputStream::Stdout, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_output(stderr, OutputStream::Stderr, tx.clone());
+    }
+    drop(tx);
+
+    // The channel disconnects once both pipes are closed
+    let deadline = Instant::now() + timeout;
+    let mut timed_out = false;
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((stream, data)) => {
+                if let Err(e) = send(GuestResponse::Output { stream, data }) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(e);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break;
+            }
+        }
+    }
+
+    let status = loop {
+        if !timed_out {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+        }
+        if timed_out || Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            timed_out = true;
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    send(GuestResponse::Exit { exit_code: status.and_then(|status| status.code()), timed_out })
+}
+
+/// Sends what is written to a pipe on `tx` from a thread of its own, in
+/// pieces of at most `OUTPUT_CHUNK` bytes that don't split a character
+fn forward_output(mut pipe: impl Read + Send + 'static, stream: OutputStream, tx: mpsc::Sender<(OutputStream, String)>) {
+    thread::spawn(move || {
+        let mut buf = vec![0u8; OUTPUT_CHUNK];
+        let mut pending = Vec::new();
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            pending.extend_from_slice(&buf[..n]);
+            let data = take_utf8(&mut pending);
+            if !data.is_empty() && tx.send((stream, data)).is_err() {
+                return;
+            }
+        }
+        if !pending.is_empty() {
+            let _ = tx.send((stream, String::from_utf8_lossy(&pending).into_owned()));
+        }
+    });
+}
+
+/// Takes the text off the front of `pending`, leaving a character whose
+/// bytes haven't all arrived yet. Invalid bytes are replaced.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let complete = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        // Only an incomplete character at the end is held back
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+    pending.drain(..complete);
+    text
+}
+
+fn fetch_file(path: &str, max_bytes: u64) -> io::Result<GuestResponse> {
+    if !Path::new(path).is_absolute() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{path} is not an absolute path")));
+    }
+    let file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut content = Vec::new();
+    file.take(max_bytes).read_to_end(&mut content)?;
+    Ok(GuestResponse::File {
+        path: path.to_string(),
+        truncated: (content.len() as u64) < size,
+        content: base64::engine::general_purpose::STANDARD.encode(&content),
+        size,
+    })
+}
+
+fn health() -> io::Result<GuestHealth> {
+    let uptime = fs::read_to_string("/proc/uptime")?;
+    let (memory_total_kb, memory_available_kb) = parse_meminfo(&fs::read_to_string("/proc/meminfo")?);
+    Ok(GuestHealth {
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: uptime.split_whitespace().next().and_then(|s| s.parse::<f64>().ok()).unwrap_or_default() as u64,
+        load_average: parse_loadavg(&fs::read_to_string("/proc/loadavg")?),
+        memory_total_kb,
+        memory_available_kb,
+        formnet_up: Path::new("/sys/class/net").join(NETWORK_NAME).exists(),
+    })
+}
+
+/// Requests a report from the SEV-SNP or TDX guest driver. Each request gets
+/// its own configfs entry, which is removed again once the report is read.
+fn attestation_report(tsm_dir: &Path, report_data: &str) -> io::Result<GuestResponse> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let data = engine.decode(report_data.trim())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("report_data is not base64: {e}")))?;
+    if data.len() > ATTESTATION_REPORT_DATA_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("report_data is {} bytes, at most {ATTESTATION_REPORT_DATA_SIZE} fit", data.len()),
+        ));
+    }
+    let mut inblob = [0u8; ATTESTATION_REPORT_DATA_SIZE];
+    inblob[..data.len()].copy_from_slice(&data);
+
+    if !tsm_dir.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "this instance is not a confidential VM"));
+    }
+    let entry = tsm_dir.join(format!("form-{}-{}", std::process::id(), NEXT_REPORT.fetch_add(1, Ordering::Relaxed)));
+    fs::create_dir(&entry)?;
+    let result = (|| {
+        fs::write(entry.join("inblob"), inblob)?;
+        let report = fs::read(entry.join("outblob"))?;
+        let certificates = fs::read(entry.join("auxblob")).ok().filter(|blob| !blob.is_empty());
+        Ok(GuestResponse::Attestation {
+            provider: fs::read_to_string(entry.join("provider"))?.trim().to_string(),
+            report: engine.encode(report),
+            certificates: certificates.map(|blob| engine.encode(blob)),
+        })
+    })();
+    let _ = fs::remove_dir(&entry);
+    result
+}
+
+/// `MemTotal` and `MemAvailable` of `/proc/meminfo`, in kB
+pub fn parse_meminfo(meminfo: &str) -> (u64, u64) {
+    let field = |name: &str| meminfo.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next()?.parse().ok())
+        .unwrap_or_default();
+    (field("MemTotal"), field("MemAvailable"))
+}
+
+pub fn parse_loadavg(loadavg: &str) -> [f64; 3] {
+    let mut load = [0.0; 3];
+    for (slot, value) in load.iter_mut().zip(loadavg.split_whitespace()) {
+        *slot = value.parse().unwrap_or_default();
+    }
+    load
+}
+
+/// Sends `event` to vmm-service on the host and waits for it to be accepted
+pub fn report_to_host(event: GuestEvent) -> io::Result<()> {
+    let report = GuestReport { token: read_token()?, event };
+    let conn = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+    conn.connect_timeout(&SockAddr::vsock(HOST_CID, HOST_REPORT_PORT), REPORT_TIMEOUT)?;
+    conn.set_read_timeout(Some(REPORT_TIMEOUT))?;
+    write_message(&mut &conn, &report)?;
+
+    match read_message::<GuestResponse>(&mut BufReader::new(&conn))? {
+        GuestResponse::Ack => Ok(()),
+        GuestResponse::Error { message } => Err(io::Error::new(io::ErrorKind::PermissionDenied, message)),
+        other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected answer {other:?}"))),
+    }
+}
+
+fn read_message<T: serde::de::DeserializeOwned>(reader: &mut impl BufRead) -> io::Result<T> {
+    let mut line = String::new();
+    reader.take(MAX_GUEST_MESSAGE_SIZE as u64).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message is incomplete or too large"));
+    }
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message<T: serde::Serialize>(writer: &mut impl Write, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_agent_commands() {
+        let meminfo = "MemTotal:        2014540 kB\nMemFree:          120000 kB\nMemAvailable:    1500000 kB\n";
+        assert_eq!(parse_meminfo(meminfo), (2014540, 1500000));
+        assert_eq!(parse_loadavg("0.52 0.30 0.11 1/120 4242\n"), [0.52, 0.30, 0.11]);
+
+        let response = handle(GuestCommand::Exec {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), "echo out; echo err >&2; exit 3".to_string()],
+            env: Default::default(),
+            timeout_secs: Some(5),
+        });
+        assert_eq!(response, GuestResponse::Exec {
+            exit_code: Some(3),
+            stdout: "out\n".to_string(),
+            stderr: "err\n".to_string(),
+            timed_out: false,
+        });
+
+        let mut streamed = vec![];
+        let script = "echo out; echo err >&2; exit 3".to_string();
+        exec_stream("sh", &["-c".to_string(), script], [], Duration::from_secs(5), |response| {
+            streamed.push(response);
+            Ok(())
+        }).unwrap();
+        let output = |wanted: OutputStream| streamed.iter().filter_map(|response| match response {
+            GuestResponse::Output { stream, data } if *stream == wanted => Some(data.as_str()),
+            _ => None,
+        }).collect::<String>();
+        assert_eq!((output(OutputStream::Stdout).as_str(), output(OutputStream::Stderr).as_str()), ("out\n", "err\n"));
+        assert_eq!(streamed.last(), Some(&GuestResponse::Exit { exit_code: Some(3), timed_out: false }));
+
+        let mut last = None;
+        exec_stream("sleep", &["5".to_string()], [], Duration::from_millis(200), |response| {
+            last = Some(response);
+            Ok(())
+        }).unwrap();
+        assert_eq!(last, Some(GuestResponse::Exit { exit_code: None, timed_out: true }));
+
+        // A character split between two reads is held back until it's whole
+        let mut pending = "é".as_bytes()[..1].to_vec();
+        assert_eq!(take_utf8(&mut pending), "");
+        pending.extend_from_slice(&"é".as_bytes()[1..]);
+        assert_eq!(take_utf8(&mut pending), "é");
+        assert!(pending.is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"hello world").unwrap();
+        match handle(GuestCommand::FetchFile { path: path.display().to_string(), max_bytes: Some(5) }) {
+            GuestResponse::File { content, size, truncated, .. } => {
+                assert_eq!(content, base64::engine::general_purpose::STANDARD.encode(b"hello"));
+                assert_eq!((size, truncated), (11, true));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(matches!(
+            handle(GuestCommand::FetchFile { path: "relative".to_string(), max_bytes: None }),
+            GuestResponse::Error { .. }
+        ));
+
+        let too_long = base64::engine::general_purpose::STANDARD.encode([0u8; 65]);
+        assert_eq!(attestation_report(dir.path(), &too_long).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let missing = dir.path().join("no-tsm");
+        assert_eq!(attestation_report(&missing, "").unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+}
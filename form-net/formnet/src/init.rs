@@ -5,7 +5,6 @@ use shared::{CidrContents, IpNetExt, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL
 use shared::{wg, NetworkOpts};
 use formnet_server::{db::CrdtMap, initialize::DbInitData, ConfigFile, DatabaseCidr, DatabasePeer};
 use ipnet::IpNet;
-use publicip::Preference;
 use shared::{Endpoint, Interface};
 use wireguard_control::{InterfaceName, KeyPair};
 
@@ -22,7 +21,8 @@ pub async fn init(address: String, formnet_cidr_str: String, current_node_is_adm
         )
     })?;
 
-    let root_cidr: IpNet = formnet_cidr_str.parse()?;
+    let root_cidr: IpNet = shared::parse_formnet_cidr(&formnet_cidr_str, &address)?;
+    log::info!("formnet root cidr: {}", root_cidr);
 
     let name: Interface = InterfaceName::from_str("formnet")?.into();
 
@@ -35,11 +35,20 @@ pub async fn init(address: String, formnet_cidr_str: String, current_node_is_adm
 
     log::info!("listen port: {}", listen_port);
 
+    // Prefer an IPv4 endpoint and fall back to IPv6 on IPv6-only hosts, but
+    // report every public address so dual-stack peers can use either
+    let public_ips = shared::get_public_addrs();
     let endpoint: Endpoint = {
-        let ip = publicip::get_any(Preference::Ipv4)
+        let ip = public_ips.iter()
+            .find(|ip| ip.is_ipv4())
+            .or_else(|| public_ips.first())
+            .copied()
             .ok_or_else(|| Box::new(std::io::Error::new(std::io::ErrorKind::Other, "couldn't get external IP")))?;
         SocketAddr::new(ip, listen_port).into()
-    }; 
+    };
+    let candidates: Vec<Endpoint> = public_ips.iter()
+        .map(|ip| SocketAddr::new(*ip, listen_port).into())
+        .collect();
 
     let our_ip = root_cidr
         .hosts()
@@ -73,7 +82,8 @@ pub async fn init(address: String, formnet_cidr_str: String, current_node_is_adm
     populate_crdt_datastore(
         db_init_data,
         address,
-        current_node_is_admin
+        current_node_is_admin,
+        candidates,
     ).await?;
 
     // After creating config and database, actually create the WireGuard interface
@@ -144,7 +154,8 @@ pub async fn ensure_crdt_datastore() -> Result<(), Box<dyn std::error::Error>> {
 async fn populate_crdt_datastore(
     db_init_data: DbInitData,
     server_name: String,
-    current_node_is_admin: bool
+    current_node_is_admin: bool,
+    candidates: Vec<Endpoint>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     log::info!("Creating root cidr");
     let root_cidr = DatabaseCidr::<String, CrdtMap>::create(
@@ -175,7 +186,8 @@ async fn populate_crdt_datastore(
             is_redeemed: true,
             persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
             invite_expires: None,
-            candidates: vec![],
+            candidates,
+            retiring_key: None,
         }
     ).await?;
 
@@ -1,5 +1,6 @@
 use std::{net::{IpAddr, SocketAddr}, path::PathBuf, str::FromStr, thread, time::Duration};
-use form_types::{BootCompleteRequest, PeerType, VmmResponse};
+use form_config::FormnetRefreshConfig;
+use form_types::{BootCompleteRequest, GuestEvent, PeerType, VmmResponse};
 use formnet_server::ConfigFile;
 use ipnet::IpNet;
 use reqwest::Client;
@@ -9,6 +10,8 @@ use wireguard_control::{Device, InterfaceName, KeyPair};
 use tokio::net::lookup_host;
 use crate::{api::{BootstrapInfo, JoinResponse as BootstrapResponse, Response}, fetch, report_initial_candidates, up, CONFIG_DIR, DATA_DIR, NETWORK_NAME};
 use crate::bootstrap::register_bootstrap_node;
+use crate::join_policy::{select_bootstraps, BootstrapFailure, JoinError, JoinOutcome, JoinRetryPolicy};
+use crate::guest_agent::report_to_host;
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -128,47 +131,54 @@ async fn check_already_joined(bootstrap: Vec<String>, id: &str) -> Result<(bool,
     Ok((false, None))
 }
 
-async fn try_get_bootstrap_info(bootstrap: Vec<String>) -> Result<BootstrapInfo, Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let mut iter = bootstrap.iter();
-    let mut bootstrap_info: Option<BootstrapInfo> = None;
-    while let Some(dial) = iter.next() {
-        match client.get(format!("http://{dial}:51820/bootstrap"))
-            .send().await {
-                Ok(resp) => match resp.json::<Response>().await {
-                    Ok(Response::Bootstrap(info)) => {
-                        log::info!("Received bootstrap info from bootstrap node {dial}");
-                        log::info!("Bootstrap info: {info:?}");
-                        bootstrap_info = Some(info.clone());
-                        break;
-                    }
-                    Err(e) => {
-                        log::error!("Error deserializing response from {dial}: {e}");
-                        continue;
-                    }
-                    _ => {
-                        log::error!("Recieved invalid variant for join request");
-                        continue;
-                    }
-                }
-                Err(e) => {
-                    log::error!("Error dialing {dial}: {e}");
-                }
-            }
+async fn try_get_bootstrap_info(dial: &str) -> Result<BootstrapInfo, String> {
+    let resp = Client::new().get(format!("http://{dial}:51820/bootstrap"))
+        .send().await
+        .map_err(|e| format!("Error dialing {dial}: {e}"))?;
+    match resp.json::<Response>().await {
+        Ok(Response::Bootstrap(info)) => {
+            log::info!("Received bootstrap info from bootstrap node {dial}");
+            log::info!("Bootstrap info: {info:?}");
+            Ok(info)
+        }
+        Ok(other) => Err(format!("Received invalid variant for bootstrap request from {dial}: {other:?}")),
+        Err(e) => Err(format!("Error deserializing response from {dial}: {e}")),
     }
+}
 
-    if bootstrap_info.is_none() {
-        return Err(
-            Box::new(
-                std::io::Error::new(
-                    std::io::ErrorKind::Other, 
-                    "Was unable to acquire bootstrap information from any bootstrap nodes provided"
-                )
-            )
-        );
+/// Joins through the first bootstrap node that admits the peer, going over
+/// all of them for up to `policy.max_rounds` rounds
+async fn join_through_bootstraps(
+    bootstraps: &[String],
+    request: &BootstrapInfo,
+    keypair: &KeyPair,
+    policy: &JoinRetryPolicy,
+) -> Result<JoinOutcome, JoinError> {
+    let mut failures = Vec::new();
+    for round in 0..policy.max_rounds {
+        if round > 0 {
+            let backoff = policy.backoff(round - 1);
+            log::warn!("No bootstrap node admitted {} in round {round}, retrying in {backoff:?}", request.id);
+            tokio::time::sleep(backoff).await;
+        }
+
+        for dial in select_bootstraps(bootstraps, policy).await {
+            let result = match try_get_bootstrap_info(&dial).await {
+                Ok(info) => try_join_formnet(info, request.clone(), keypair.clone()).await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(ip) => return Ok(JoinOutcome { ip, bootstrap: Some(dial), failures }),
+                Err(reason) => {
+                    log::error!("Unable to join formnet through {dial}: {reason}");
+                    failures.push(BootstrapFailure { dial, round, reason });
+                }
+            }
+        }
     }
 
-    Ok(bootstrap_info.unwrap())
+    Err(JoinError::Exhausted { rounds: policy.max_rounds, failures })
 }
 
 fn write_config_file(
@@ -249,7 +259,8 @@ async fn try_join_formnet(
     request: BootstrapInfo,
     keypair: KeyPair
 ) -> Result<IpAddr, Box<dyn std::error::Error>> {
-    let dial = bootstrap_info.external_endpoint.unwrap();
+    let dial = bootstrap_info.external_endpoint
+        .ok_or_else(|| other_err("Bootstrap info has no external endpoint"))?;
     log::info!("Attempting to dial {dial}");
     match Client::new().post(&format!("http://{dial}/join"))
     .json(&request)
@@ -311,7 +322,9 @@ fn build_join_request(
                 cidr_id: "formnet".to_string(),
                 pubkey: keypair.public.to_base64(),
                 internal_endpoint: None,
-                external_endpoint: Some(SocketAddr::new(publicip, 51820)),
+                external_endpoint: Some(
+                    crate::port_mapping::mapped_endpoint().unwrap_or_else(|| SocketAddr::new(publicip, 51820))
+                ),
             })
         },
         PeerType::User => {
@@ -387,12 +400,18 @@ pub async fn request_to_join(
     public_ip: Option<String>,
     is_bootstrap_node: Option<bool>,
     region: Option<String>,
-) -> Result<IpAddr, Box<dyn std::error::Error>> {
+) -> Result<JoinOutcome, JoinError> {
     // Resolve any domain names in the bootstrap list to IP addresses
     let resolved_bootstrap = resolve_bootstrap_domains(bootstrap).await;
+    if resolved_bootstrap.is_empty() {
+        return Err(JoinError::NoBootstraps);
+    }
+    let policy = JoinRetryPolicy::default();
     
-    // Check if we're already joined with this ID
-    let (already_joined, ip) = check_already_joined(resolved_bootstrap.clone(), &address).await?;
+    // Check if we're already joined with this ID, asking the healthiest
+    // bootstrap nodes first
+    let ordered_bootstrap = select_bootstraps(&resolved_bootstrap, &policy).await;
+    let (already_joined, ip) = check_already_joined(ordered_bootstrap, &address).await?;
     
     if already_joined {
         log::info!("Already joined as {address}, bringing up interface only");
@@ -402,7 +421,7 @@ pub async fn request_to_join(
             if let Some(ip_addr) = ip {
                 // Try to get our public IP if not provided
                 let actual_public_ip = if let Some(public_ip_str) = public_ip {
-                    public_ip_str.parse()?
+                    public_ip_str.parse().map_err(|e| JoinError::Other(format!("Invalid public ip {public_ip_str}: {e}")))?
                 } else {
                     // Try to detect public IP
                     match publicip::get_any(publicip::Preference::Ipv4) {
@@ -434,27 +453,29 @@ pub async fn request_to_join(
             }
         }
         
-        return Ok(ip.unwrap());
+        let ip = ip.ok_or_else(|| JoinError::Other(format!("{address} is a member but has no formnet ip")))?;
+        return Ok(JoinOutcome { ip, bootstrap: None, failures: vec![] });
     }
     
     // Generate a wireguard keypair
     let keypair = wireguard_control::KeyPair::generate();
     
-    // Retrieve bootstrap information
-    let bootstrap_info = try_get_bootstrap_info(resolved_bootstrap.clone()).await?;
-    
     // Create join request
     let request = build_join_request(peer_type, keypair.clone(), address.clone(), public_ip.clone())?;
     
-    // Join the network
-    let result = try_join_formnet(bootstrap_info, request, keypair.clone()).await?;
-    log::info!("Successfully joined formnet, ip {:?}", result);
+    // Join the network through the healthiest bootstrap node that admits us
+    let outcome = join_through_bootstraps(&resolved_bootstrap, &request, &keypair, &policy).await?;
+    let result = outcome.ip;
+    log::info!("Successfully joined formnet through {:?}, ip {:?}", outcome.bootstrap, result);
+    if !outcome.failures.is_empty() {
+        log::warn!("{} attempts failed before the join succeeded: {:?}", outcome.failures.len(), outcome.failures);
+    }
     
     // If this is a bootstrap node, register it with the DNS service
     if is_bootstrap_node == Some(true) {
         // Try to get our public IP if not provided
         let actual_public_ip = if let Some(public_ip_str) = public_ip {
-            public_ip_str.parse()?
+            public_ip_str.parse().map_err(|e| JoinError::Other(format!("Invalid public ip {public_ip_str}: {e}")))?
         } else {
             // Try to detect public IP
             match publicip::get_any(publicip::Preference::Ipv4) {
@@ -485,7 +506,7 @@ pub async fn request_to_join(
         }
     }
     
-    Ok(result)
+    Ok(outcome)
 }
 
 pub async fn user_join_formnet(address: String, provider: String, public_ip: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
@@ -500,16 +521,17 @@ pub async fn vm_join_formnet() -> Result<(), Box<dyn std::error::Error>> {
     let name = std::fs::read_to_string("/etc/vm_name")?;
     let build_id = std::fs::read_to_string("/etc/build_id")?;
     match request_to_join(vec![host_public_ip.clone()], name.clone(), form_types::PeerType::Instance, None, None, None).await {
-        Ok(ip)=> {
+        Ok(outcome)=> {
             log::info!("Received invitation");
-            let formnet_ip = ip; 
+            let formnet_ip = outcome.ip; 
             log::info!("extracted formnet IP for {name}: {formnet_ip}");
             log::info!("Attempting to redeem invite");
             log::info!("Spawning thread to bring formnet up");
             let _ = tokio::time::sleep(Duration::from_secs(5)).await;
             let handle = tokio::spawn(async move {
                 if let Err(e) = up(
-                    Some(Duration::from_secs(60)),
+                    Some(FormnetRefreshConfig::default()),
+                    None,
                     None,
                 ).await {
                     log::error!("Error bringing formnet up: {e}");
@@ -517,28 +539,40 @@ pub async fn vm_join_formnet() -> Result<(), Box<dyn std::error::Error>> {
             });
 
             log::info!("Building request to inform VMM service that the boot process has completed for {name}");
-            // Send message to VMM api.
-            let request = BootCompleteRequest {
-                name: name.clone(),
-                build_id: build_id.clone(),
-                formnet_ip: formnet_ip.to_string()
+            // Report over the guest agent channel, fall back to the VMM api
+            // for hosts without it
+            let event = GuestEvent::BootComplete {
+                name: name.trim().to_string(),
+                build_id: build_id.trim().to_string(),
+                formnet_ip: formnet_ip.to_string(),
             };
-
-            log::info!("Sending BootCompleteRequest {request:?} to http://{host_public_ip}:3002/vm/boot_complete endpoint");
-
-            match Client::new().post(&format!("http://{host_public_ip}:3002/vm/boot_complete"))
-                .json(&request)
-                .send()
-                .await {
-
-                Ok(r) => {
-                    log::info!("recevied response from {host_public_ip}:3002");
-                    log::info!("Response: {r:?}");
-                    log::info!("Response status: {:?}", r.status());
-                    log::info!("Response contents: {:?}", r.json::<VmmResponse>().await?);
-                }
-                Err(e) => {
-                    log::info!("Error sending BootCompleteRequest to {host_public_ip}:3002: {e}");
+            match tokio::task::spawn_blocking(move || report_to_host(event)).await {
+                Ok(Ok(())) => log::info!("Reported boot completion for {name} over vsock"),
+                result => {
+                    log::warn!("Unable to report boot completion over vsock, falling back to http: {result:?}");
+                    let request = BootCompleteRequest {
+                        name: name.clone(),
+                        build_id: build_id.clone(),
+                        formnet_ip: formnet_ip.to_string()
+                    };
+
+                    log::info!("Sending BootCompleteRequest {request:?} to http://{host_public_ip}:3002/vm/boot_complete endpoint");
+
+                    match Client::new().post(&format!("http://{host_public_ip}:3002/vm/boot_complete"))
+                        .json(&request)
+                        .send()
+                        .await {
+
+                        Ok(r) => {
+                            log::info!("recevied response from {host_public_ip}:3002");
+                            log::info!("Response: {r:?}");
+                            log::info!("Response status: {:?}", r.status());
+                            log::info!("Response contents: {:?}", r.json::<VmmResponse>().await?);
+                        }
+                        Err(e) => {
+                            log::info!("Error sending BootCompleteRequest to {host_public_ip}:3002: {e}");
+                        }
+                    }
                 }
             }
 
@@ -0,0 +1,236 @@
+//! Which bootstrap node a join goes to and how often it is retried.
+//!
+//! Before joining, every bootstrap node is asked for its `/health`. Healthy
+//! nodes are tried first, in a random order weighted towards the ones that
+//! answered fastest, so joins spread over the bootstrap list instead of all
+//! landing on (or stalling behind) its first entry. Nodes that didn't answer
+//! are still tried last. A round that fails on every node is retried after
+//! an exponential backoff, and the failures of every attempt are reported
+//! back to the caller.
+use std::{net::IpAddr, time::{Duration, Instant}};
+use futures::future::join_all;
+use rand::Rng;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::api::{HealthStatus, Response};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JoinRetryPolicy {
+    /// Rounds over all bootstrap nodes before the join fails
+    pub max_rounds: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long a bootstrap node gets to answer the health probe
+    pub probe_timeout: Duration,
+}
+
+impl Default for JoinRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_rounds: 4,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(30),
+            probe_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+impl JoinRetryPolicy {
+    /// Delay before round `round + 1`, doubling from `initial_backoff`
+    pub fn backoff(&self, round: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(round))
+            .min(self.max_backoff)
+    }
+}
+
+/// Result of probing a bootstrap node's `/health`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootstrapCandidate {
+    pub dial: String,
+    pub status: Option<HealthStatus>,
+    pub latency: Option<Duration>,
+}
+
+impl BootstrapCandidate {
+    /// Selection weight, 0 for nodes that didn't answer or report themselves
+    /// unhealthy. Faster nodes get a larger weight, degraded ones a quarter.
+    pub fn weight(&self) -> u32 {
+        let latency_ms = self.latency.map(|latency| latency.as_millis() as u32).unwrap_or(u32::MAX);
+        let base = 10_000 / latency_ms.saturating_add(10).max(1);
+        match self.status {
+            Some(HealthStatus::Healthy) => base.max(1),
+            Some(HealthStatus::Degraded { .. }) => (base / 4).max(1),
+            Some(HealthStatus::Unhealthy { .. }) | None => 0,
+        }
+    }
+}
+
+/// A failed attempt against one bootstrap node
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootstrapFailure {
+    pub dial: String,
+    pub round: u32,
+    pub reason: String,
+}
+
+/// A successful join, along with the bootstrap nodes that failed before it
+#[derive(Clone, Debug)]
+pub struct JoinOutcome {
+    pub ip: IpAddr,
+    /// The bootstrap node that admitted the peer, None if it was already a member
+    pub bootstrap: Option<String>,
+    pub failures: Vec<BootstrapFailure>,
+}
+
+#[derive(Error, Debug)]
+pub enum JoinError {
+    #[error("No bootstrap nodes were provided")]
+    NoBootstraps,
+
+    #[error("Unable to join formnet through any of {} bootstrap nodes after {rounds} rounds", distinct_dials(.failures))]
+    Exhausted {
+        rounds: u32,
+        failures: Vec<BootstrapFailure>,
+    },
+
+    #[error("{0}")]
+    Other(String),
+}
+
+impl JoinError {
+    /// Every failed attempt that led to this error
+    pub fn failures(&self) -> &[BootstrapFailure] {
+        match self {
+            JoinError::Exhausted { failures, .. } => failures,
+            _ => &[],
+        }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for JoinError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        JoinError::Other(e.to_string())
+    }
+}
+
+fn distinct_dials(failures: &[BootstrapFailure]) -> usize {
+    let mut dials: Vec<&str> = failures.iter().map(|failure| failure.dial.as_str()).collect();
+    dials.sort_unstable();
+    dials.dedup();
+    dials.len()
+}
+
+/// Probes the health of every bootstrap node concurrently
+pub async fn probe_bootstraps(bootstraps: &[String], timeout: Duration) -> Vec<BootstrapCandidate> {
+    let client = Client::builder().timeout(timeout).build().unwrap_or_default();
+    let probes = bootstraps.iter().map(|dial| {
+        let client = client.clone();
+        async move {
+            let started = Instant::now();
+            let response = client.get(format!("http://{dial}:51820/health")).send().await;
+            let latency = started.elapsed();
+            let status = match response {
+                Ok(resp) => match resp.json::<Response>().await {
+                    Ok(Response::Health(health)) => Some(health.status().clone()),
+                    Ok(other) => {
+                        log::warn!("Bootstrap node {dial} answered the health probe with {other:?}");
+                        None
+                    }
+                    Err(e) => {
+                        log::warn!("Unable to read health of bootstrap node {dial}: {e}");
+                        None
+                    }
+                },
+                Err(e) => {
+                    log::warn!("Health probe of bootstrap node {dial} failed: {e}");
+                    None
+                }
+            };
+            BootstrapCandidate {
+                dial: dial.clone(),
+                latency: status.as_ref().map(|_| latency),
+                status,
+            }
+        }
+    });
+    join_all(probes).await
+}
+
+/// Orders the candidates for a join round: nodes with a weight in a
+/// weighted random order, then the rest shuffled
+pub fn order_candidates<R: Rng>(candidates: &[BootstrapCandidate], rng: &mut R) -> Vec<String> {
+    // Weighted sampling without replacement, each node is keyed by
+    // u^(1/weight) and the largest keys go first
+    let mut weighted: Vec<(f64, &BootstrapCandidate)> = candidates.iter()
+        .filter(|candidate| candidate.weight() > 0)
+        .map(|candidate| (rng.gen::<f64>().powf(1.0 / f64::from(candidate.weight())), candidate))
+        .collect();
+    weighted.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut fallback: Vec<(f64, &BootstrapCandidate)> = candidates.iter()
+        .filter(|candidate| candidate.weight() == 0)
+        .map(|candidate| (rng.gen::<f64>(), candidate))
+        .collect();
+    fallback.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    weighted.into_iter()
+        .chain(fallback)
+        .map(|(_, candidate)| candidate.dial.clone())
+        .collect()
+}
+
+/// Probes the bootstrap nodes and returns the order to try them in
+pub async fn select_bootstraps(bootstraps: &[String], policy: &JoinRetryPolicy) -> Vec<String> {
+    let candidates = probe_bootstraps(bootstraps, policy.probe_timeout).await;
+    let healthy = candidates.iter().filter(|candidate| candidate.weight() > 0).count();
+    log::info!("{healthy} of {} bootstrap nodes are healthy", candidates.len());
+    order_candidates(&candidates, &mut rand::thread_rng())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn candidate(dial: &str, status: Option<HealthStatus>, latency_ms: u64) -> BootstrapCandidate {
+        BootstrapCandidate {
+            dial: dial.to_string(),
+            latency: status.as_ref().map(|_| Duration::from_millis(latency_ms)),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_unreachable_bootstraps_are_tried_last() {
+        let candidates = vec![
+            candidate("dead", None, 0),
+            candidate("unhealthy", Some(HealthStatus::Unhealthy { reason: "db".to_string() }), 5),
+            candidate("slow", Some(HealthStatus::Degraded { reason: "load".to_string() }), 900),
+            candidate("fast", Some(HealthStatus::Healthy), 20),
+        ];
+        assert_eq!(candidates[0].weight(), 0);
+        assert_eq!(candidates[1].weight(), 0);
+        assert!(candidates[3].weight() > candidates[2].weight());
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut fast_first = 0;
+        for _ in 0..200 {
+            let order = order_candidates(&candidates, &mut rng);
+            assert_eq!(order.len(), 4);
+            assert!(order[..2].contains(&"fast".to_string()));
+            assert!(order[..2].contains(&"slow".to_string()));
+            if order[0] == "fast" {
+                fast_first += 1;
+            }
+        }
+        assert!(fast_first > 150);
+
+        let policy = JoinRetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_secs(2));
+        assert_eq!(policy.backoff(2), Duration::from_secs(8));
+        assert_eq!(policy.backoff(10), policy.max_backoff);
+    }
+}
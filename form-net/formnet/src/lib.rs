@@ -3,22 +3,34 @@ pub mod add_peer;
 pub mod add_cidr;
 pub mod serve;
 pub mod join;
+pub mod join_policy;
 pub mod up;
 pub mod fetch;
 pub mod redeem;
 pub mod add_assoc;
 pub mod leave;
+pub mod rotate;
+pub mod refresh;
 pub mod resolve;
 pub mod api;
 pub mod relay;
 pub mod nat_relay;
 pub mod bootstrap;
+pub mod peer_metrics;
+pub mod bandwidth;
+pub mod readiness;
+pub mod devsync;
+pub mod guest_agent;
+pub mod gateway;
+pub mod port_mapping;
 
 pub use init::*;
 pub use add_peer::*;
 pub use serve::*;
 pub use join::*;
 pub use leave::*;
+pub use rotate::*;
+pub use refresh::*;
 pub use up::*;
 pub use fetch::*;
 pub use redeem::*;
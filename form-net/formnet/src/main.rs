@@ -38,7 +38,20 @@ enum Membership {
     #[command(alias="dev")]
     User(UserOpts),
     #[command(alias="vm")]
-    Instance
+    Instance,
+    /// Run the instance's HEALTHCHECK and report its readiness to the host
+    Health,
+    /// Apply file deltas from `form pack watch` to an instance built with DEVSYNC
+    #[command(name="dev-sync")]
+    DevSync,
+    /// Serve the host's guest agent requests over vsock
+    #[command(name="guest-agent")]
+    GuestAgent,
+    /// Replace this node's WireGuard key, peers accept the old key during the grace period
+    #[command(name="rotate-keys", alias="rotate")]
+    RotateKeys(RotateKeysOpts),
+    /// Proxy SOCKS5 and HTTP CONNECT clients into formnet
+    Gateway(GatewayOpts),
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -95,6 +108,30 @@ struct OperatorLeaveOpts {
     password: Option<String>,
 }
 
+#[derive(Clone, Debug, Args)]
+struct RotateKeysOpts {
+    /// The path to the operator config file 
+    #[arg(long="config-path", short='C', aliases=["config", "config-file"], default_value_os_t=PathBuf::from(".operator-config.json"))]
+    config_path: PathBuf,
+    #[arg(short, long, default_value="true")]
+    encrypted: bool,
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Seconds that peers keep accepting the old key
+    #[arg(long="grace-period", default_value_t=shared::KEY_ROTATION_GRACE_PERIOD.as_secs())]
+    grace_period: u64,
+}
+
+#[derive(Clone, Debug, Args)]
+struct GatewayOpts {
+    /// The path to the gateway's client and ACL config
+    #[arg(long="config-path", short='C', aliases=["config", "config-file"], default_value=formnet::gateway::DEFAULT_GATEWAY_CONFIG)]
+    config_path: PathBuf,
+    /// Overrides the listen address in the config
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+}
+
 #[derive(Clone, Debug, Args)]
 struct UserOpts {
     #[arg(alias="endpoint")]
@@ -157,6 +194,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         log::info!("This node is designated as an admin node.");
                     }
 
+                    // Ask the router to forward the WireGuard port before joining, so the
+                    // mapped endpoint is what gets advertised
+                    let port_mapping = op_config.formnet_port_mapping;
+                    if port_mapping.enabled {
+                        if let Err(e) = port_mapping.validate() {
+                            log::error!("Invalid formnet port mapping config: {e}");
+                            return Ok(());
+                        }
+                        match formnet::port_mapping::map_port(51820, port_mapping.lease_secs).await {
+                            Ok(mapping) => log::info!("Mapped the formnet port to {} over {}", mapping.external, mapping.protocol),
+                            Err(e) => log::warn!("Failed to map the formnet port on the gateway, peers will fall back to NAT traversal and relays: {e}"),
+                        }
+                        tokio::spawn(formnet::port_mapping::maintain(51820, port_mapping.lease_secs));
+                    }
+
                     // Build bootstrap list, combining user-provided bootstraps with the bootstrap domain
                     let mut bootstraps = parser.bootstraps.clone();
                     if bootstraps.is_empty() {
@@ -171,10 +223,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     // If no bootstraps are specified, initialize the node without joining
                     if bootstraps.is_empty() {
                         log::info!("No bootstraps specified, initializing node without joining");
-                        if let Err(e) = formnet::init::init(address.clone(), formnet_cidr_to_use.clone(), current_node_is_admin).await {
-                            log::error!("Error in formnet init... {e}");
-                            return Ok(());
-                        }
+                        let formnet_ip = match formnet::init::init(address.clone(), formnet_cidr_to_use.clone(), current_node_is_admin).await {
+                            Ok(ip) => ip,
+                            Err(e) => {
+                                log::error!("Error in formnet init... {e}");
+                                return Ok(());
+                            }
+                        };
                         
                         // Ensure admin account is set up in form-state
                         let admin_key_to_ensure = op_config.initial_admin_public_key.as_deref().unwrap_or(&address).to_string();
@@ -210,9 +265,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             }
                         };
                         
-                        // For bootstrap node, formnet IP is 10.0.0.1
-                        let formnet_ip = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
-                        
                         // Create bootstrap info with actual endpoint information
                         let bootstrap_info = api::BootstrapInfo {
                             id: address.clone(),
@@ -220,7 +272,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             cidr_id: "".to_string(),
                             pubkey: secret_key_string.clone(),
                             internal_endpoint: Some(formnet_ip), // Use formnet IP for internal endpoint
-                            external_endpoint: Some(SocketAddr::new(pub_ip, 51820)), // Use detected public IP for external endpoint
+                            // Use the mapped endpoint, or else the detected public IP, for the external endpoint
+                            external_endpoint: Some(formnet::port_mapping::mapped_endpoint().unwrap_or(SocketAddr::new(pub_ip, 51820))),
                         };
                         let endpoints = Arc::new(RwLock::new(HashMap::new()));
                         
@@ -235,9 +288,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         
                         // Run the up function in the main task
                         log::info!("Starting formnet up process for bootstrap node");
-                        if let Err(e) = up(Some(Duration::from_secs(60)), None).await {
+                        if let Err(e) = up(Some(op_config.formnet_refresh), Some(sk.clone()), None).await {
                             log::error!("Error in bootstrap formnet up: {}", e);
                         }
+                        release_port_mapping().await;
                         
                         return Ok(());
                     }
@@ -266,7 +320,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         Some(op_config.is_bootstrap_node),
                         op_config.region.clone(),
                     ).await {
-                        Ok(ip) => {
+                        Ok(outcome) => {
+                            let ip = outcome.ip;
                             log::info!("Successfully joined with IP {}", ip);
                             
                             // Start API server in a separate task
@@ -306,12 +361,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             
                             // Run the up function in the main task
                             log::info!("Starting formnet up process");
-                            if let Err(e) = up(Some(Duration::from_secs(60)), None).await {
+                            if let Err(e) = up(Some(op_config.formnet_refresh), Some(sk.clone()), None).await {
                                 log::error!("Error in formnet up: {}", e);
                             }
+                            release_port_mapping().await;
                         }
                         Err(e) => {
                             log::error!("Failed to join: {}", e);
+                            for failure in e.failures() {
+                                log::error!("Round {} through {}: {}", failure.round, failure.dial, failure.reason);
+                            }
+                            release_port_mapping().await;
                             return Ok(());
                         }
                     }
@@ -363,6 +423,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     match leave(vec![], address).await {
                         Ok(_) => {
                             log::info!("Node successfully left the network");
+
+                            release_port_mapping().await;
                             
                             // Ensure formnet interface is down and services are stopped
                             if let Err(e) = formnet::uninstall().await {
@@ -415,7 +477,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Membership::Instance => {
             vm_join_formnet().await?;
         }
+        Membership::Health => {
+            formnet::readiness::run_health_probe().await?;
+        }
+        Membership::DevSync => {
+            formnet::devsync::run_dev_sync().await?;
+        }
+        Membership::GuestAgent => {
+            tokio::task::spawn_blocking(formnet::guest_agent::run_guest_agent).await??;
+        }
+        Membership::RotateKeys(opts) => {
+            let op_config = match OperatorConfig::from_file(
+                opts.config_path,
+                opts.encrypted,
+                opts.password.as_deref(),
+            ).ok() {
+                Some(c) => c,
+                None => {
+                    log::error!("Could not retrieve operator configuration");
+                    return Ok(());
+                }
+            };
+
+            let Some(secret_key) = op_config.secret_key else {
+                log::error!("Operator config must contain a secret key");
+                return Ok(());
+            };
+            let sk = SigningKey::from_slice(&hex::decode(&secret_key)?)?;
+
+            let expires_at = formnet::rotate_keys(&sk, Duration::from_secs(opts.grace_period)).await?;
+            println!(
+                "Rotated {} key, peers accept the old key until {}",
+                "WireGuard".bold().bright_blue(),
+                expires_at.to_string().bold().bright_yellow()
+            );
+        }
+        Membership::Gateway(opts) => {
+            let mut config = formnet::gateway::GatewayConfig::from_file(&opts.config_path)?;
+            if let Some(listen) = opts.listen {
+                config.listen = listen;
+            }
+            formnet::gateway::serve_gateway(config).await?;
+        }
     }
 
     Ok(())
 }
+
+/// Removes the formnet port mapping from the gateway, if one was made
+async fn release_port_mapping() {
+    match formnet::port_mapping::release().await {
+        Ok(Some(mapping)) => log::info!("Removed the formnet port mapping {} over {}", mapping.external, mapping.protocol),
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to remove the formnet port mapping, it lapses with its lease: {e}"),
+    }
+}
@@ -0,0 +1,204 @@
+//! Per peer connection quality for the formnet interface.
+//!
+//! Every sample combines the WireGuard transfer and handshake counters of
+//! each peer with a short ping probe over the tunnel. The latest sample is
+//! served on the local API and a summary is published to form-state on a
+//! slower interval, where the placement engine and DNS use it. Every sample
+//! also feeds the bandwidth ledger, see `crate::bandwidth`.
+use std::{collections::{BTreeMap, HashMap}, net::IpAddr, str::FromStr, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use form_node_metrics::{connectivity::{ConnectivityMetrics, PeerQuality}, NodeMetricsRequest};
+use form_usage_events::EventPublisher;
+use formnet_server::{db::CrdtMap, DatabasePeer};
+use futures::future::join_all;
+use shared::NetworkOpts;
+use tokio::{process::Command, sync::RwLock};
+use wireguard_control::{Device, InterfaceName};
+
+use crate::{bandwidth::{publish_bandwidth, SharedBandwidth}, NETWORK_NAME};
+
+/// How often the peers are sampled
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the latest sample is published to form-state
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(300);
+/// Probe packets sent to each peer per sample
+const PROBE_COUNT: u32 = 3;
+
+pub type SharedConnectivity = Arc<RwLock<ConnectivityMetrics>>;
+
+/// Result of a single ping probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeResult {
+    pub rtt_us: Option<u64>,
+    pub jitter_us: Option<u64>,
+    pub loss_bps: u32,
+}
+
+/// Samples the formnet peers until the process exits, keeping `metrics`
+/// and `bandwidth` up to date and publishing them for `node_id`
+pub async fn run_peer_metrics(node_id: String, metrics: SharedConnectivity, bandwidth: SharedBandwidth) {
+    let publisher = EventPublisher::new().with_default_circuit_breaker();
+    let mut sample_interval = tokio::time::interval(SAMPLE_INTERVAL);
+    let mut last_publish: Option<tokio::time::Instant> = None;
+    loop {
+        sample_interval.tick().await;
+        let previous = metrics.read().await.clone();
+        let sample = match sample_peers(&previous).await {
+            Ok(sample) => sample,
+            Err(e) => {
+                log::warn!("Unable to sample formnet peers: {e}");
+                continue;
+            }
+        };
+        *metrics.write().await = sample.clone();
+        bandwidth.write().await.record(&sample);
+
+        if last_publish.is_some_and(|at| at.elapsed() < PUBLISH_INTERVAL) {
+            continue;
+        }
+        publish_bandwidth(&node_id, &bandwidth, &publisher, sample.updated_at).await;
+        let request = NodeMetricsRequest::Connectivity { node_id: node_id.clone(), connectivity: sample };
+        match form_node_metrics::util::write_to_queue(request).await {
+            Ok(()) => last_publish = Some(tokio::time::Instant::now()),
+            Err(e) => log::warn!("Unable to publish peer connectivity: {e}"),
+        }
+    }
+}
+
+async fn sample_peers(previous: &ConnectivityMetrics) -> Result<ConnectivityMetrics, Box<dyn std::error::Error>> {
+    let device = Device::get(&InterfaceName::from_str(NETWORK_NAME)?, NetworkOpts::default().backend)?;
+    let names: HashMap<String, (String, IpAddr)> = DatabasePeer::<String, CrdtMap>::list().await?
+        .into_iter()
+        .map(|peer| (peer.inner.public_key.clone(), (peer.inner.id.clone(), peer.inner.ip)))
+        .collect();
+
+    let now = unix_now();
+    let probes = device.peers.iter().map(|peer| {
+        let public_key = peer.config.public_key.to_base64();
+        let ip = names.get(&public_key).map(|(_, ip)| *ip)
+            .or_else(|| peer.config.allowed_ips.first().map(|allowed| allowed.address));
+        async move {
+            match ip {
+                Some(ip) => probe(ip).await,
+                None => ProbeResult { rtt_us: None, jitter_us: None, loss_bps: 10_000 },
+            }
+        }
+    });
+    let probes = join_all(probes).await;
+
+    let mut peers = BTreeMap::new();
+    for (peer, probe) in device.peers.iter().zip(probes) {
+        let public_key = peer.config.public_key.to_base64();
+        let (peer_id, formnet_ip) = match names.get(&public_key) {
+            Some((id, ip)) => (id.clone(), Some(ip.to_string())),
+            None => (public_key, None),
+        };
+        let last_handshake = peer.stats.last_handshake_time
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs() as i64);
+
+        // Rates are against the previous sample, counters reset when the
+        // interface comes back up so a drop is treated as a fresh start
+        let (rx_bytes_per_sec, tx_bytes_per_sec) = match previous.peers.get(&peer_id) {
+            Some(last) if now > last.sampled_at => {
+                let elapsed = (now - last.sampled_at) as u64;
+                (
+                    peer.stats.rx_bytes.saturating_sub(last.rx_bytes) / elapsed,
+                    peer.stats.tx_bytes.saturating_sub(last.tx_bytes) / elapsed,
+                )
+            }
+            _ => (0, 0),
+        };
+
+        peers.insert(peer_id.clone(), PeerQuality {
+            peer_id,
+            formnet_ip,
+            endpoint: peer.config.endpoint.map(|endpoint| endpoint.to_string()),
+            rtt_us: probe.rtt_us,
+            jitter_us: probe.jitter_us,
+            loss_bps: probe.loss_bps,
+            last_handshake,
+            rx_bytes: peer.stats.rx_bytes,
+            tx_bytes: peer.stats.tx_bytes,
+            rx_bytes_per_sec,
+            tx_bytes_per_sec,
+            sampled_at: now,
+        });
+    }
+
+    Ok(ConnectivityMetrics { peers, updated_at: now })
+}
+
+/// Pings a peer over the tunnel, a peer that can't be pinged counts as
+/// fully lossy rather than failing the whole sample
+async fn probe(ip: IpAddr) -> ProbeResult {
+    let output = Command::new("ping")
+        .args(["-c", &PROBE_COUNT.to_string(), "-W", "1", "-q", &ip.to_string()])
+        .output()
+        .await;
+    match output {
+        Ok(output) => parse_ping_output(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            log::warn!("Unable to probe {ip}: {e}");
+            ProbeResult { rtt_us: None, jitter_us: None, loss_bps: 10_000 }
+        }
+    }
+}
+
+/// Parses the summary of `ping -q`, e.g.
+/// `3 packets transmitted, 3 received, 0% packet loss, time 2003ms`
+/// `rtt min/avg/max/mdev = 0.045/0.061/0.080/0.014 ms`
+pub fn parse_ping_output(output: &str) -> ProbeResult {
+    let loss_bps = output.lines()
+        .find_map(|line| {
+            line.split(',')
+                .find(|part| part.contains("packet loss"))
+                .and_then(|part| part.trim().split('%').next())
+                .and_then(|loss| loss.parse::<f64>().ok())
+        })
+        .map(|loss| (loss * 100.0).round() as u32)
+        .unwrap_or(10_000);
+
+    let stats: Option<Vec<f64>> = output.lines()
+        .find(|line| line.starts_with("rtt") || line.starts_with("round-trip"))
+        .and_then(|line| line.split('=').nth(1))
+        .and_then(|values| values.split_whitespace().next())
+        .map(|values| values.split('/').filter_map(|value| value.parse::<f64>().ok()).collect());
+
+    let to_us = |ms: f64| (ms * 1000.0).round() as u64;
+    match stats {
+        Some(stats) if stats.len() == 4 => ProbeResult {
+            rtt_us: Some(to_us(stats[1])),
+            jitter_us: Some(to_us(stats[3])),
+            loss_bps,
+        },
+        _ => ProbeResult { rtt_us: None, jitter_us: None, loss_bps },
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ping_output() {
+        let output = "PING 10.0.0.2 (10.0.0.2) 56(84) bytes of data.\n\n\
+            --- 10.0.0.2 ping statistics ---\n\
+            3 packets transmitted, 2 received, 33.3333% packet loss, time 2003ms\n\
+            rtt min/avg/max/mdev = 0.045/1.250/2.080/0.514 ms\n";
+        assert_eq!(parse_ping_output(output), ProbeResult {
+            rtt_us: Some(1250),
+            jitter_us: Some(514),
+            loss_bps: 3333,
+        });
+
+        let unreachable = "3 packets transmitted, 0 received, 100% packet loss, time 2040ms\n";
+        assert_eq!(parse_ping_output(unreachable), ProbeResult { rtt_us: None, jitter_us: None, loss_bps: 10_000 });
+    }
+}
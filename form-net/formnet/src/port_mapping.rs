@@ -0,0 +1,693 @@
+//! Port mapping on the local gateway.
+//!
+//! A node behind a home router can't be reached on its WireGuard port until
+//! the router forwards it. When `formnet_port_mapping` is enabled in the
+//! operator config, formnet asks the default gateway for a UDP mapping at
+//! startup, over NAT-PMP (RFC 6886) first and UPnP IGD second. The mapped
+//! external endpoint is advertised when joining and reported as the first
+//! NAT traversal candidate, so peers try it before hole punching and the
+//! relays.
+//!
+//! Mappings are leased. `maintain` renews the lease halfway through and maps
+//! the port again if the gateway forgot it. The mapping is also written to
+//! `DATA_DIR`, so `formnet operator leave`, which runs in its own process,
+//! can `release` it.
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{atomic::{AtomicBool, Ordering}, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use url::Url;
+
+use crate::DATA_DIR;
+
+pub const NAT_PMP_PORT: u16 = 5351;
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const IGD_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const MAPPING_FILE: &str = "port-mapping.json";
+const MAPPING_DESCRIPTION: &str = "formnet";
+/// UPnP error of gateways that only accept mappings without a lease
+const ONLY_PERMANENT_LEASES: u16 = 725;
+/// How often a permanent UPnP mapping is asserted again, in case the
+/// gateway restarted and lost it
+const PERMANENT_RENEW_SECS: u64 = 3600;
+/// Wait before retrying a renewal or mapping that failed
+const RETRY_SECS: u64 = 30;
+const NAT_PMP_ATTEMPTS: u32 = 4;
+const NAT_PMP_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+static MAPPING: Lazy<RwLock<Option<PortMapping>>> = Lazy::new(Default::default);
+static RELEASED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Error)]
+pub enum PortMappingError {
+    #[error("no default IPv4 gateway found")]
+    NoGateway,
+    #[error("NAT-PMP: {0}")]
+    NatPmp(String),
+    #[error("UPnP: {0}")]
+    Upnp(String),
+    #[error("UPnP error {code}: {description}")]
+    UpnpFault { code: u16, description: String },
+    #[error("the gateway's external address {0} is not public, it is behind another NAT")]
+    NotPublic(Ipv4Addr),
+    #[error("no gateway answered over NAT-PMP ({nat_pmp}) or UPnP ({upnp})")]
+    Unavailable { nat_pmp: Box<PortMappingError>, upnp: Box<PortMappingError> },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MappingProtocol {
+    NatPmp,
+    Upnp,
+}
+
+impl std::fmt::Display for MappingProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NatPmp => write!(f, "NAT-PMP"),
+            Self::Upnp => write!(f, "UPnP"),
+        }
+    }
+}
+
+/// The WAN connection service of a UPnP gateway
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpnpService {
+    pub service_type: String,
+    pub control_url: String,
+}
+
+/// A UDP port forwarded by the gateway to this host
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    pub gateway: Ipv4Addr,
+    /// Set for UPnP mappings
+    pub service: Option<UpnpService>,
+    /// Address of this host on the gateway's network
+    pub local_ip: Ipv4Addr,
+    pub internal_port: u16,
+    pub external: SocketAddr,
+    /// Lease granted by the gateway, 0 for a permanent mapping
+    pub lease_secs: u32,
+    /// Unix seconds the mapping was made or last renewed
+    pub renewed_at: u64,
+}
+
+impl PortMapping {
+    /// Unix seconds the lease runs out, `None` for a permanent mapping
+    pub fn expires_at(&self) -> Option<u64> {
+        (self.lease_secs != 0).then(|| self.renewed_at + self.lease_secs as u64)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at().is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Time until the mapping should be renewed, halfway through its lease
+    pub fn renew_in(&self, now: u64) -> Duration {
+        let renew_at = match self.lease_secs {
+            0 => self.renewed_at + PERMANENT_RENEW_SECS,
+            lease_secs => self.renewed_at + lease_secs as u64 / 2,
+        };
+        Duration::from_secs(renew_at.saturating_sub(now))
+    }
+}
+
+/// The external endpoint of the current mapping, if it hasn't expired
+pub fn mapped_endpoint() -> Option<SocketAddr> {
+    current()
+        .filter(|mapping| !mapping.is_expired(now_secs()))
+        .map(|mapping| mapping.external)
+}
+
+/// The current mapping of this process
+pub fn current() -> Option<PortMapping> {
+    MAPPING.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Maps `internal_port` on the default gateway, over NAT-PMP or else UPnP,
+/// and keeps the mapping as the current one
+pub async fn map_port(internal_port: u16, lease_secs: u32) -> Result<PortMapping, PortMappingError> {
+    let gateway = default_gateway().ok_or(PortMappingError::NoGateway)?;
+    let local_ip = local_ip_towards(gateway).await?;
+    let mapping = match nat_pmp::map(gateway, local_ip, internal_port, internal_port, lease_secs).await {
+        Ok(mapping) => mapping,
+        Err(nat_pmp) => {
+            log::debug!("NAT-PMP mapping through {gateway} failed, trying UPnP: {nat_pmp}");
+            match upnp::map(gateway, local_ip, internal_port, internal_port, lease_secs).await {
+                Ok(mapping) => mapping,
+                Err(upnp) => {
+                    return Err(PortMappingError::Unavailable { nat_pmp: Box::new(nat_pmp), upnp: Box::new(upnp) });
+                }
+            }
+        }
+    };
+
+    if let IpAddr::V4(external) = mapping.external.ip() {
+        if !is_public(external) {
+            if let Err(e) = delete(&mapping).await {
+                log::warn!("Failed to remove the unusable mapping {}: {e}", mapping.external);
+            }
+            return Err(PortMappingError::NotPublic(external));
+        }
+    }
+    store(Some(mapping.clone()));
+    Ok(mapping)
+}
+
+/// Renews `mapping` on the gateway it was made on, asking for the same
+/// external port
+pub async fn renew(mapping: &PortMapping) -> Result<PortMapping, PortMappingError> {
+    let lease_secs = mapping.lease_secs;
+    let renewed = match mapping.protocol {
+        MappingProtocol::NatPmp => {
+            nat_pmp::map(mapping.gateway, mapping.local_ip, mapping.internal_port, mapping.external.port(), lease_secs).await?
+        }
+        MappingProtocol::Upnp => match &mapping.service {
+            Some(service) => upnp::add(mapping.gateway, service.clone(), mapping.local_ip, mapping.internal_port, mapping.external.port(), lease_secs).await?,
+            None => upnp::map(mapping.gateway, mapping.local_ip, mapping.internal_port, mapping.external.port(), lease_secs).await?,
+        },
+    };
+    store(Some(renewed.clone()));
+    Ok(renewed)
+}
+
+/// Keeps the mapping alive until `release`. Leases are renewed halfway
+/// through, and the port is mapped again if there is no mapping, e.g.
+/// because the first attempt at startup failed or the lease ran out.
+pub async fn maintain(internal_port: u16, lease_secs: u32) {
+    loop {
+        let wait = match current() {
+            Some(mapping) => mapping.renew_in(now_secs()),
+            None => Duration::from_secs(RETRY_SECS),
+        };
+        tokio::time::sleep(wait).await;
+        if RELEASED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let result = match current() {
+            Some(mapping) => renew(&mapping).await,
+            None => map_port(internal_port, lease_secs).await,
+        };
+        match result {
+            Ok(mapping) => log::debug!("Port mapping {} renewed over {}", mapping.external, mapping.protocol),
+            Err(e) => {
+                log::warn!("Failed to renew the formnet port mapping, retrying in {RETRY_SECS}s: {e}");
+                if current().is_some_and(|mapping| mapping.is_expired(now_secs())) {
+                    log::warn!("The formnet port mapping expired");
+                    *MAPPING.write().unwrap_or_else(|e| e.into_inner()) = None;
+                }
+                tokio::time::sleep(Duration::from_secs(RETRY_SECS)).await;
+            }
+        }
+    }
+}
+
+/// Removes the mapping from the gateway and stops `maintain`. Falls back to
+/// the mapping saved to disk when this process didn't make one. Returns the
+/// mapping that was removed.
+pub async fn release() -> Result<Option<PortMapping>, PortMappingError> {
+    RELEASED.store(true, Ordering::Relaxed);
+    let mapping = match current().or_else(load) {
+        Some(mapping) => mapping,
+        None => return Ok(None),
+    };
+    store(None);
+    delete(&mapping).await?;
+    Ok(Some(mapping))
+}
+
+async fn delete(mapping: &PortMapping) -> Result<(), PortMappingError> {
+    match mapping.protocol {
+        MappingProtocol::NatPmp => nat_pmp::delete(mapping.gateway, mapping.internal_port).await,
+        MappingProtocol::Upnp => match &mapping.service {
+            Some(service) => upnp::delete(service, mapping.external.port()).await,
+            None => Err(PortMappingError::Upnp("mapping has no control URL".to_string())),
+        },
+    }
+}
+
+fn mapping_path() -> PathBuf {
+    PathBuf::from(DATA_DIR).join(MAPPING_FILE)
+}
+
+/// Replaces the current mapping in memory and on disk
+fn store(mapping: Option<PortMapping>) {
+    let path = mapping_path();
+    let written = match &mapping {
+        Some(mapping) => serde_json::to_vec_pretty(mapping)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| {
+                std::fs::create_dir_all(DATA_DIR)?;
+                std::fs::write(&path, bytes)
+            }),
+        None => match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    };
+    if let Err(e) = written {
+        log::warn!("Failed to update {}: {e}", path.display());
+    }
+    *MAPPING.write().unwrap_or_else(|e| e.into_inner()) = mapping;
+}
+
+fn load() -> Option<PortMapping> {
+    let bytes = std::fs::read(mapping_path()).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Whether peers on the internet can reach `ip`, false for private, shared
+/// (carrier-grade NAT) and other special-purpose addresses
+fn is_public(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || (a == 100 && (b & 0xc0) == 64))
+}
+
+fn default_gateway() -> Option<Ipv4Addr> {
+    parse_default_gateway(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+/// The gateway of the default route in the format of `/proc/net/route`
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    const RTF_UP: u32 = 0x1;
+    const RTF_GATEWAY: u32 = 0x2;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (destination, gateway, flags) = (fields.get(1)?, fields.get(2)?, fields.get(3)?);
+        let flags = u32::from_str_radix(flags, 16).ok()?;
+        if *destination != "00000000" || flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+            return None;
+        }
+        // The kernel prints the address as a host order integer
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+/// The local address packets to `gateway` leave from
+async fn local_ip_towards(gateway: Ipv4Addr) -> Result<Ipv4Addr, PortMappingError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) => Err(PortMappingError::Io(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("expected an IPv4 address towards {gateway}, got {ip}"),
+        ))),
+    }
+}
+
+mod nat_pmp {
+    use super::*;
+
+    const VERSION: u8 = 0;
+    const OP_EXTERNAL_ADDRESS: u8 = 0;
+    const OP_MAP_UDP: u8 = 1;
+    const RESPONSE: u8 = 128;
+
+    pub fn encode_map_request(internal_port: u16, external_port: u16, lifetime: u32) -> [u8; 12] {
+        let mut request = [0u8; 12];
+        request[0] = VERSION;
+        request[1] = OP_MAP_UDP;
+        request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+        request[6..8].copy_from_slice(&external_port.to_be_bytes());
+        request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+        request
+    }
+
+    /// Checks the header of a response to `op` and returns its body, the
+    /// bytes after the epoch
+    fn response_body(response: &[u8], op: u8) -> Result<&[u8], PortMappingError> {
+        if response.len() < 8 {
+            return Err(PortMappingError::NatPmp(format!("response of {} bytes is too short", response.len())));
+        }
+        if response[0] != VERSION || response[1] != RESPONSE + op {
+            return Err(PortMappingError::NatPmp(format!("unexpected response {}/{}", response[0], response[1])));
+        }
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        let reason = match result {
+            0 => return Ok(&response[8..]),
+            1 => "unsupported version",
+            2 => "not authorized, mapping is disabled on the gateway",
+            3 => "network failure",
+            4 => "out of resources",
+            5 => "unsupported opcode",
+            _ => "unknown result code",
+        };
+        Err(PortMappingError::NatPmp(format!("{reason} ({result})")))
+    }
+
+    pub fn parse_external_address(response: &[u8]) -> Result<Ipv4Addr, PortMappingError> {
+        let body = response_body(response, OP_EXTERNAL_ADDRESS)?;
+        let octets: [u8; 4] = body.get(..4)
+            .and_then(|octets| octets.try_into().ok())
+            .ok_or_else(|| PortMappingError::NatPmp("external address response is truncated".to_string()))?;
+        Ok(Ipv4Addr::from(octets))
+    }
+
+    /// The internal port, mapped external port and lifetime of a mapping
+    /// response
+    pub fn parse_map_response(response: &[u8]) -> Result<(u16, u16, u32), PortMappingError> {
+        let body = response_body(response, OP_MAP_UDP)?;
+        if body.len() < 8 {
+            return Err(PortMappingError::NatPmp("mapping response is truncated".to_string()));
+        }
+        Ok((
+            u16::from_be_bytes([body[0], body[1]]),
+            u16::from_be_bytes([body[2], body[3]]),
+            u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+        ))
+    }
+
+    /// Sends `request` to the gateway, retransmitting with a doubling
+    /// timeout as RFC 6886 asks, but giving up after a few seconds
+    async fn exchange(gateway: Ipv4Addr, request: &[u8]) -> Result<Vec<u8>, PortMappingError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect((gateway, NAT_PMP_PORT)).await?;
+        let mut timeout = NAT_PMP_INITIAL_TIMEOUT;
+        let mut buf = [0u8; 16];
+        for _ in 0..NAT_PMP_ATTEMPTS {
+            socket.send(request).await?;
+            if let Ok(received) = tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+                return Ok(buf[..received?].to_vec());
+            }
+            timeout *= 2;
+        }
+        Err(PortMappingError::NatPmp(format!("no answer from {gateway}")))
+    }
+
+    pub async fn map(
+        gateway: Ipv4Addr,
+        local_ip: Ipv4Addr,
+        internal_port: u16,
+        external_port: u16,
+        lease_secs: u32,
+    ) -> Result<PortMapping, PortMappingError> {
+        let external_ip = parse_external_address(&exchange(gateway, &[VERSION, OP_EXTERNAL_ADDRESS]).await?)?;
+        let response = exchange(gateway, &encode_map_request(internal_port, external_port, lease_secs)).await?;
+        let (_, external_port, lifetime) = parse_map_response(&response)?;
+        if lifetime == 0 {
+            return Err(PortMappingError::NatPmp(format!("{gateway} granted no lease")));
+        }
+        Ok(PortMapping {
+            protocol: MappingProtocol::NatPmp,
+            gateway,
+            service: None,
+            local_ip,
+            internal_port,
+            external: SocketAddr::new(IpAddr::V4(external_ip), external_port),
+            lease_secs: lifetime,
+            renewed_at: now_secs(),
+        })
+    }
+
+    pub async fn delete(gateway: Ipv4Addr, internal_port: u16) -> Result<(), PortMappingError> {
+        parse_map_response(&exchange(gateway, &encode_map_request(internal_port, 0, 0)).await?)?;
+        Ok(())
+    }
+}
+
+mod upnp {
+    use super::*;
+
+    const WAN_SERVICES: [&str; 2] = ["WANIPConnection", "WANPPPConnection"];
+
+    fn client() -> Result<Client, PortMappingError> {
+        Ok(Client::builder().timeout(HTTP_TIMEOUT).no_proxy().build()?)
+    }
+
+    /// The `LOCATION` header of an SSDP response
+    pub fn parse_ssdp_location(response: &str) -> Option<String> {
+        response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+        })
+    }
+
+    /// Text of the first `<name>` element in `xml`
+    pub fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+        let open = format!("<{name}>");
+        let close = format!("</{name}>");
+        let start = xml.find(&open)? + open.len();
+        let end = start + xml[start..].find(&close)?;
+        Some(xml[start..end].trim())
+    }
+
+    /// The WAN connection service of a device description fetched from
+    /// `location`, with its control URL made absolute
+    pub fn find_wan_service(description: &str, location: &Url) -> Option<UpnpService> {
+        let base = element(description, "URLBase")
+            .and_then(|base| Url::parse(base).ok())
+            .unwrap_or_else(|| location.clone());
+        description.split("<service>").skip(1).find_map(|service| {
+            let service = service.split("</service>").next()?;
+            let service_type = element(service, "serviceType")?;
+            if !WAN_SERVICES.iter().any(|wan| service_type.contains(wan)) {
+                return None;
+            }
+            let control_url = base.join(element(service, "controlURL")?).ok()?;
+            Some(UpnpService { service_type: service_type.to_string(), control_url: control_url.to_string() })
+        })
+    }
+
+    pub fn soap_envelope(service_type: &str, action: &str, args: &[(&str, String)]) -> String {
+        let args: String = args.iter().map(|(name, value)| format!("<{name}>{value}</{name}>")).collect();
+        format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service_type}\">{args}</u:{action}></s:Body></s:Envelope>"
+        )
+    }
+
+    /// The `UPnPError` of a SOAP fault, if the body is one
+    pub fn parse_fault(body: &str) -> Option<PortMappingError> {
+        let code = element(body, "errorCode")?.parse().ok()?;
+        let description = element(body, "errorDescription").unwrap_or_default().to_string();
+        Some(PortMappingError::UpnpFault { code, description })
+    }
+
+    /// Searches the gateway's network for internet gateway devices and
+    /// returns the description location of the one at `gateway`
+    async fn discover(gateway: Ipv4Addr) -> Result<Url, PortMappingError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {IGD_SEARCH_TARGET}\r\n\r\n"
+        );
+        socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+
+        let deadline = tokio::time::Instant::now() + SSDP_TIMEOUT;
+        let mut buf = [0u8; 2048];
+        loop {
+            let (received, from) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+                .map_err(|_| PortMappingError::Upnp(format!("no internet gateway device answered at {gateway}")))??;
+            if from.ip() != IpAddr::V4(gateway) {
+                continue;
+            }
+            let location = parse_ssdp_location(&String::from_utf8_lossy(&buf[..received]))
+                .and_then(|location| Url::parse(&location).ok());
+            if let Some(location) = location {
+                return Ok(location);
+            }
+        }
+    }
+
+    async fn call(service: &UpnpService, action: &str, args: &[(&str, String)]) -> Result<String, PortMappingError> {
+        let response = client()?
+            .post(&service.control_url)
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#{action}\"", service.service_type))
+            .body(soap_envelope(&service.service_type, action, args))
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if status.is_success() {
+            return Ok(body);
+        }
+        Err(parse_fault(&body).unwrap_or_else(|| PortMappingError::Upnp(format!("{action} failed with {status}"))))
+    }
+
+    pub async fn map(
+        gateway: Ipv4Addr,
+        local_ip: Ipv4Addr,
+        internal_port: u16,
+        external_port: u16,
+        lease_secs: u32,
+    ) -> Result<PortMapping, PortMappingError> {
+        let location = discover(gateway).await?;
+        let description = client()?.get(location.clone()).send().await?.text().await?;
+        let service = find_wan_service(&description, &location)
+            .ok_or_else(|| PortMappingError::Upnp(format!("{location} has no WAN connection service")))?;
+        add(gateway, service, local_ip, internal_port, external_port, lease_secs).await
+    }
+
+    /// Adds the mapping on a known service. Gateways that only take
+    /// permanent mappings are asked again without a lease.
+    pub async fn add(
+        gateway: Ipv4Addr,
+        service: UpnpService,
+        local_ip: Ipv4Addr,
+        internal_port: u16,
+        external_port: u16,
+        lease_secs: u32,
+    ) -> Result<PortMapping, PortMappingError> {
+        let body = call(&service, "GetExternalIPAddress", &[]).await?;
+        let external_ip: Ipv4Addr = element(&body, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| PortMappingError::Upnp("gateway reported no external address".to_string()))?;
+
+        let args = |lease_secs: u32| [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", "UDP".to_string()),
+            ("NewInternalPort", internal_port.to_string()),
+            ("NewInternalClient", local_ip.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", MAPPING_DESCRIPTION.to_string()),
+            ("NewLeaseDuration", lease_secs.to_string()),
+        ];
+        let lease_secs = match call(&service, "AddPortMapping", &args(lease_secs)).await {
+            Ok(_) => lease_secs,
+            Err(PortMappingError::UpnpFault { code: ONLY_PERMANENT_LEASES, .. }) if lease_secs != 0 => {
+                log::info!("{} only accepts permanent port mappings", service.control_url);
+                call(&service, "AddPortMapping", &args(0)).await?;
+                0
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(PortMapping {
+            protocol: MappingProtocol::Upnp,
+            gateway,
+            service: Some(service),
+            local_ip,
+            internal_port,
+            external: SocketAddr::new(IpAddr::V4(external_ip), external_port),
+            lease_secs,
+            renewed_at: now_secs(),
+        })
+    }
+
+    pub async fn delete(service: &UpnpService, external_port: u16) -> Result<(), PortMappingError> {
+        call(service, "DeletePortMapping", &[
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", "UDP".to_string()),
+        ]).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            formnet\t00002A0A\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+            eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n";
+        assert_eq!(parse_default_gateway(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+
+        let no_default = "Iface\tDestination\tGateway \tFlags\n\
+            eth0\t0001A8C0\t00000000\t0001\n";
+        assert_eq!(parse_default_gateway(no_default), None);
+    }
+
+    #[test]
+    fn test_nat_pmp_messages() {
+        assert_eq!(
+            nat_pmp::encode_map_request(51820, 51820, 3600),
+            [0, 1, 0, 0, 0xca, 0x6c, 0xca, 0x6c, 0, 0, 0x0e, 0x10],
+        );
+
+        let external = [0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7];
+        assert_eq!(nat_pmp::parse_external_address(&external).unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+
+        let mapped = [0, 129, 0, 0, 0, 0, 0, 9, 0xca, 0x6c, 0xca, 0x6d, 0, 0, 0x07, 0x08];
+        assert_eq!(nat_pmp::parse_map_response(&mapped).unwrap(), (51820, 51821, 1800));
+
+        let refused = [0, 129, 0, 2, 0, 0, 0, 9, 0xca, 0x6c, 0, 0, 0, 0, 0, 0];
+        assert!(nat_pmp::parse_map_response(&refused).unwrap_err().to_string().contains("not authorized"));
+        assert!(nat_pmp::parse_map_response(&external).is_err());
+    }
+
+    #[test]
+    fn test_upnp_discovery_parsing() {
+        let ssdp = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        assert_eq!(upnp::parse_ssdp_location(ssdp).as_deref(), Some("http://192.168.1.1:5000/rootDesc.xml"));
+
+        let description = r#"<root><device><serviceList>
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType><controlURL>/ctl/L3F</controlURL></service>
+            </serviceList><deviceList><device><serviceList>
+            <service>
+              <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+              <controlURL>/ctl/IPConn</controlURL>
+            </service>
+            </serviceList></device></deviceList></device></root>"#;
+        let location = Url::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(
+            upnp::find_wan_service(description, &location),
+            Some(UpnpService {
+                service_type: "urn:schemas-upnp-org:service:WANIPConnection:1".to_string(),
+                control_url: "http://192.168.1.1:5000/ctl/IPConn".to_string(),
+            }),
+        );
+
+        let fault = "<s:Envelope><s:Body><s:Fault><detail><UPnPError><errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>";
+        assert!(matches!(upnp::parse_fault(fault), Some(PortMappingError::UpnpFault { code: ONLY_PERMANENT_LEASES, .. })));
+    }
+
+    #[test]
+    fn test_lease_renewal() {
+        let mapping = PortMapping {
+            protocol: MappingProtocol::NatPmp,
+            gateway: Ipv4Addr::new(192, 168, 1, 1),
+            service: None,
+            local_ip: Ipv4Addr::new(192, 168, 1, 20),
+            internal_port: 51820,
+            external: "203.0.113.7:51820".parse().unwrap(),
+            lease_secs: 3600,
+            renewed_at: 1_000,
+        };
+        assert_eq!(mapping.renew_in(1_000), Duration::from_secs(1800));
+        assert_eq!(mapping.renew_in(3_000), Duration::ZERO);
+        assert!(!mapping.is_expired(4_599));
+        assert!(mapping.is_expired(4_600));
+
+        let permanent = PortMapping { lease_secs: 0, ..mapping };
+        assert_eq!(permanent.expires_at(), None);
+        assert_eq!(permanent.renew_in(1_000), Duration::from_secs(PERMANENT_RENEW_SECS));
+
+        assert!(!is_public(Ipv4Addr::new(198, 51, 100, 1)));
+        assert!(is_public(Ipv4Addr::new(8, 8, 8, 8)));
+        assert!(!is_public(Ipv4Addr::new(100, 72, 0, 1)));
+        assert!(!is_public(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+}
@@ -0,0 +1,160 @@
+//! Readiness probing inside instances that declare a `HEALTHCHECK`.
+//!
+//! The probe written to the image at build time is run on its interval and
+//! vmm-service on the host is told whenever the app becomes ready or starts
+//! failing. vmm-service holds back the instance's DNS record until the first
+//! ready report.
+use std::time::{Duration, Instant};
+use form_types::{HealthCheck, HealthProbe, ReadinessRequest, VmmResponse, HEALTH_CHECK_PATH};
+use reqwest::Client;
+use tokio::process::Command;
+
+/// Readiness as seen by the probe loop
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadinessState {
+    pub ready: bool,
+    pub consecutive_failures: u32,
+    /// Whether the current run of failures has already been reported
+    failure_reported: bool,
+}
+
+impl ReadinessState {
+    /// Records a probe result and returns true if it changed what the host
+    /// should be told. Failures during the start period aren't `counted`.
+    pub fn record(&mut self, passed: bool, counted: bool, retries: u32) -> bool {
+        if passed {
+            self.consecutive_failures = 0;
+            self.failure_reported = false;
+            let changed = !self.ready;
+            self.ready = true;
+            return changed;
+        }
+        if !counted {
+            return false;
+        }
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= retries && !self.failure_reported {
+            self.ready = false;
+            self.failure_reported = true;
+            return true;
+        }
+        false
+    }
+}
+
+/// Probes the app until the process is stopped
+pub async fn run_health_probe() -> Result<(), Box<dyn std::error::Error>> {
+    let check: HealthCheck = serde_json::from_str(&std::fs::read_to_string(HEALTH_CHECK_PATH)?)?;
+    let host = std::env::var("HOST_BRIDGE_IP")?;
+    let name = std::fs::read_to_string("/etc/vm_name")?.trim().to_string();
+    let build_id = std::fs::read_to_string("/etc/build_id")?.trim().to_string();
+    log::info!("Probing {name} with {check:?}");
+
+    let started = Instant::now();
+    let start_period = Duration::from_secs(check.start_period_secs);
+    let mut state = ReadinessState::default();
+    let mut pending: Option<ReadinessRequest> = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(check.interval_secs));
+    loop {
+        interval.tick().await;
+        let result = probe(&check).await;
+        if let Err(reason) = &result {
+            log::warn!("Health probe failed: {reason}");
+        }
+        if state.record(result.is_ok(), started.elapsed() >= start_period, check.retries) {
+            log::info!("{name} is {}", if state.ready { "ready" } else { "unhealthy" });
+            pending = Some(ReadinessRequest {
+                build_id: build_id.clone(),
+                name: name.clone(),
+                ready: state.ready,
+                consecutive_failures: state.consecutive_failures,
+                detail: result.err(),
+            });
+        }
+
+        // A report that couldn't be delivered is retried on the next tick
+        if let Some(request) = &pending {
+            match report(&host, request).await {
+                Ok(()) => pending = None,
+                Err(e) => log::warn!("Unable to report readiness to {host}: {e}"),
+            }
+        }
+    }
+}
+
+/// Runs the probe once, the error is the reason it failed
+pub async fn probe(check: &HealthCheck) -> Result<(), String> {
+    let timeout = Duration::from_secs(check.timeout_secs);
+    match &check.probe {
+        HealthProbe::Command(command) => {
+            let output = tokio::time::timeout(
+                timeout,
+                Command::new("sh").args(["-c", command]).kill_on_drop(true).output()
+            ).await
+                .map_err(|_| format!("`{command}` timed out after {}s", check.timeout_secs))?
+                .map_err(|e| format!("Unable to run `{command}`: {e}"))?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(format!("`{command}` exited with {}: {}", output.status, stderr.trim()))
+            }
+        }
+        HealthProbe::Http { port, path } => {
+            let url = format!("http://127.0.0.1:{port}{path}");
+            let response = Client::builder().timeout(timeout).build()
+                .map_err(|e| e.to_string())?
+                .get(&url)
+                .send()
+                .await
+                .map_err(|e| format!("GET {url} failed: {e}"))?;
+            let status = response.status();
+            if status.is_success() || status.is_redirection() {
+                Ok(())
+            } else {
+                Err(format!("GET {url} returned {status}"))
+            }
+        }
+    }
+}
+
+async fn report(host: &str, request: &ReadinessRequest) -> Result<(), Box<dyn std::error::Error>> {
+    let response = Client::new().post(format!("http://{host}:3002/v1/readiness"))
+        .json(request)
+        .send()
+        .await?
+        .json::<VmmResponse>()
+        .await?;
+    match response {
+        VmmResponse::Success(_) => Ok(()),
+        VmmResponse::Failure(failure) => Err(Box::new(failure)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness_transitions() {
+        let mut state = ReadinessState::default();
+        // Failures in the start period are ignored
+        assert!(!state.record(false, false, 2));
+        assert_eq!(state.consecutive_failures, 0);
+
+        assert!(state.record(true, false, 2));
+        assert!(state.ready);
+        assert!(!state.record(true, true, 2));
+
+        assert!(!state.record(false, true, 2));
+        assert!(state.ready);
+        assert!(state.record(false, true, 2));
+        assert!(!state.ready);
+        // The same run of failures is only reported once
+        assert!(!state.record(false, true, 2));
+        assert_eq!(state.consecutive_failures, 3);
+
+        assert!(state.record(true, true, 2));
+        assert_eq!(state.consecutive_failures, 0);
+    }
+}
@@ -0,0 +1,195 @@
+//! Refresh cadence of the `up` loop
+//!
+//! `up` fetches the peer list every `refresh_interval_secs`. On top of it,
+//! every `endpoint_refresh_secs` it re-resolves the endpoint hostnames of
+//! peers it hasn't had a handshake with in `handshake_timeout_secs`, so a
+//! peer behind a dynamic DNS name is found again after its address changed,
+//! and every `rekey_interval_secs` it rotates the node's WireGuard key.
+//!
+//! The values start from the operator config and are held for the process,
+//! `GET /refresh` on the local API returns them and `POST /refresh` replaces
+//! them. A change wakes the loop, so it applies without waiting out the
+//! previous interval.
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use axum::{extract::ConnectInfo, Json};
+use client::data_store::DataStore;
+use form_config::FormnetRefreshConfig;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use shared::NetworkOpts;
+use tokio::sync::Notify;
+use wireguard_control::{Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
+use crate::{DATA_DIR, NETWORK_NAME};
+
+static REFRESH: Lazy<RwLock<FormnetRefreshConfig>> = Lazy::new(Default::default);
+static CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// The values the `up` loop currently runs with
+pub fn refresh_config() -> FormnetRefreshConfig {
+    *REFRESH.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Replaces the values and wakes the `up` loop
+pub fn set_refresh_config(config: FormnetRefreshConfig) -> Result<(), String> {
+    config.validate().map_err(|e| e.to_string())?;
+    let previous = std::mem::replace(&mut *REFRESH.write().unwrap_or_else(|e| e.into_inner()), config);
+    if previous != config {
+        log::info!("formnet refresh config is now {config:?}");
+        CHANGED.notify_waiters();
+    }
+    Ok(())
+}
+
+/// Waits for the next `set_refresh_config` that changes the values
+pub async fn refresh_config_changed() {
+    CHANGED.notified().await
+}
+
+/// What the `up` loop has to do besides fetching, by when it last did it
+#[derive(Debug, Clone)]
+pub struct RefreshSchedule {
+    last_endpoint_refresh: Instant,
+    last_rekey: Instant,
+}
+
+impl RefreshSchedule {
+    pub fn new(now: Instant) -> Self {
+        Self { last_endpoint_refresh: now, last_rekey: now }
+    }
+
+    /// Whether endpoints are due for re-resolution, marking them done if so
+    pub fn endpoints_due(&mut self, config: &FormnetRefreshConfig, now: Instant) -> bool {
+        Self::due(&mut self.last_endpoint_refresh, config.endpoint_refresh_secs, now)
+    }
+
+    /// Whether the key is due for rotation. It's only marked done by
+    /// `rekeyed`, so a failed rotation is retried on the next round.
+    pub fn rekey_due(&self, config: &FormnetRefreshConfig, now: Instant) -> bool {
+        config.rekey_interval_secs != 0
+            && now.duration_since(self.last_rekey) >= Duration::from_secs(config.rekey_interval_secs)
+    }
+
+    pub fn rekeyed(&mut self, now: Instant) {
+        self.last_rekey = now;
+    }
+
+    fn due(last: &mut Instant, every_secs: u64, now: Instant) -> bool {
+        if every_secs == 0 || now.duration_since(*last) < Duration::from_secs(every_secs) {
+            return false;
+        }
+        *last = now;
+        true
+    }
+}
+
+/// Re-resolves the endpoint hostnames of peers without a handshake in
+/// `handshake_timeout` and points the interface at the addresses that
+/// changed. Returns how many peers were updated.
+pub fn refresh_endpoints(handshake_timeout: Duration) -> Result<usize, Box<dyn std::error::Error>> {
+    let interface = InterfaceName::from_str(NETWORK_NAME)?;
+    let backend = NetworkOpts::default().backend;
+    let device = Device::get(&interface, backend)?;
+    let store = DataStore::<String>::open_or_create(&PathBuf::from(DATA_DIR), &interface)?;
+
+    let mut update = DeviceUpdate::new();
+    let mut updated = 0;
+    for peer in store.peers() {
+        // Endpoints given as addresses have nothing to re-resolve
+        let Some(endpoint) = peer.endpoint.as_ref().filter(|e| e.to_string().parse::<SocketAddr>().is_err()) else {
+            continue;
+        };
+        let Ok(key) = Key::from_base64(&peer.public_key) else {
+            continue;
+        };
+        let Some(info) = device.peers.iter().find(|info| info.config.public_key == key) else {
+            continue;
+        };
+        let since_handshake = info.stats.last_handshake_time
+            .and_then(|t| t.elapsed().ok())
+            .unwrap_or(Duration::MAX);
+        if since_handshake <= handshake_timeout {
+            continue;
+        }
+        match endpoint.resolve() {
+            Ok(addr) if info.config.endpoint != Some(addr) => {
+                log::info!("Endpoint {endpoint} of {} now resolves to {addr}", peer.id);
+                update = update.add_peer(PeerConfigBuilder::new(&key).set_endpoint(addr));
+                updated += 1;
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Unable to re-resolve endpoint {endpoint} of {}: {e}", peer.id),
+        }
+    }
+    if updated > 0 {
+        update.apply(&interface, backend)?;
+    }
+    Ok(updated)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RefreshResponse {
+    Config(FormnetRefreshConfig),
+    Failure { reason: String },
+}
+
+pub async fn get_refresh_config() -> Json<RefreshResponse> {
+    Json(RefreshResponse::Config(refresh_config()))
+}
+
+/// Only taken from the node itself, the API is reachable by every peer
+pub async fn update_refresh_config(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(config): Json<FormnetRefreshConfig>,
+) -> Json<RefreshResponse> {
+    if !addr.ip().is_loopback() {
+        log::warn!("Rejected refresh config change from {addr}");
+        return Json(RefreshResponse::Failure { reason: "The refresh config can only be changed from the node".to_string() });
+    }
+    match set_refresh_config(config) {
+        Ok(()) => Json(RefreshResponse::Config(config)),
+        Err(reason) => Json(RefreshResponse::Failure { reason }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule_follows_config() {
+        let start = Instant::now();
+        let mut config = FormnetRefreshConfig { endpoint_refresh_secs: 300, rekey_interval_secs: 0, ..Default::default() };
+        let mut schedule = RefreshSchedule::new(start);
+
+        assert!(!schedule.endpoints_due(&config, start + Duration::from_secs(299)));
+        assert!(schedule.endpoints_due(&config, start + Duration::from_secs(300)));
+        // Done until another interval passed
+        assert!(!schedule.endpoints_due(&config, start + Duration::from_secs(301)));
+        assert!(!schedule.rekey_due(&config, start + Duration::from_secs(86_400)));
+
+        // A failed rotation stays due, a done one waits out the interval
+        config.rekey_interval_secs = 3600;
+        let later = start + Duration::from_secs(3600);
+        assert!(schedule.rekey_due(&config, later));
+        assert!(schedule.rekey_due(&config, later + Duration::from_secs(60)));
+        schedule.rekeyed(later);
+        assert!(!schedule.rekey_due(&config, later + Duration::from_secs(60)));
+
+        config.endpoint_refresh_secs = 0;
+        assert!(!schedule.endpoints_due(&config, start + Duration::from_secs(86_400)));
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        let config = refresh_config();
+        assert!(set_refresh_config(FormnetRefreshConfig { refresh_interval_secs: 0, ..config }).is_err());
+        assert!(set_refresh_config(FormnetRefreshConfig { rekey_interval_secs: 60, ..config }).is_err());
+        assert_eq!(refresh_config(), config);
+    }
+}
@@ -39,6 +39,10 @@ const HIGH_LATENCY_THRESHOLD: u32 = 200;
 /// Default maximum acceptable load (0-100)
 const MAX_ACCEPTABLE_LOAD: u8 = 80;
 
+/// How much higher another relay has to score before replacing the one in
+/// use, so relays with similar scores don't flap on every measurement
+const SWITCH_MARGIN: f32 = 0.1;
+
 /// Configuration for bootstrap relay nodes
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BootstrapConfig {
@@ -405,12 +409,34 @@ impl RelayRegistry {
         }
     }
 
+    /// Lower a relay's reliability after a failed probe. Unlike
+    /// `update_relay` this doesn't refresh the relay, so relays that stop
+    /// answering still age out and get pruned.
+    pub fn record_probe_failure(&mut self, pubkey: &[u8]) {
+        if let Some(relay) = self.relays.get_mut(&hex::encode(pubkey)) {
+            relay.update_reliability(false);
+        }
+    }
+
     /// Select the best relay for communicating with a specific peer
     pub fn select_best_relay(
         &self,
         target_peer_pubkey: &[u8],
         required_capabilities: u32,
         preferred_region: Option<&str>
+    ) -> Option<RelayNodeInfo> {
+        self.select_relay_with_hysteresis(target_peer_pubkey, required_capabilities, preferred_region, None)
+    }
+
+    /// Select the best relay, but keep `current` unless another relay beats
+    /// its score by at least `SWITCH_MARGIN`. `current` is dropped right away
+    /// once it no longer qualifies, e.g. because it became overloaded.
+    pub fn select_relay_with_hysteresis(
+        &self,
+        target_peer_pubkey: &[u8],
+        required_capabilities: u32,
+        preferred_region: Option<&str>,
+        current: Option<&[u8; 32]>
     ) -> Option<RelayNodeInfo> {
         if self.relays.is_empty() {
             return None;
@@ -422,7 +448,7 @@ impl RelayRegistry {
                 // Filter by required capabilities
                 (relay.capabilities & required_capabilities) == required_capabilities &&
                 // Filter by load (avoid overloaded relays)
-                relay.load <= MAX_ACCEPTABLE_LOAD
+                relay.utilization() <= MAX_ACCEPTABLE_LOAD as f32 / 100.0
             })
             .cloned()
             .collect();
@@ -439,15 +465,22 @@ impl RelayRegistry {
         };
 
         // Score and select the best relay
-        candidates.into_iter()
+        let scored: Vec<(RelayNodeInfo, f32)> = candidates.into_iter()
             .map(|relay| {
                 let score = self.score_relay(&relay, preferred_region, target_region);
                 (relay, score)
             })
+            .collect();
+        let best = scored.iter()
             .max_by(|(_, score1), (_, score2)| {
                 score1.partial_cmp(score2).unwrap_or(std::cmp::Ordering::Equal)
-            })
-            .map(|(relay, _)| relay)
+            })?;
+
+        let incumbent = current.and_then(|pubkey| scored.iter().find(|(relay, _)| &relay.pubkey == pubkey));
+        match incumbent {
+            Some((relay, score)) if best.1 < score + SWITCH_MARGIN => Some(relay.clone()),
+            _ => Some(best.0.clone()),
+        }
     }
 
     /// Score a relay based on multiple factors
@@ -469,8 +502,8 @@ impl RelayRegistry {
             score += latency_score * LATENCY_WEIGHT;
         }
 
-        // Score based on load (lower is better)
-        let load_score = 1.0 - (relay.load as f32 / 100.0);
+        // Score based on advertised sessions, bandwidth and load (lower is better)
+        let load_score = 1.0 - relay.utilization();
         score += load_score * LOAD_WEIGHT;
 
         // Score based on capabilities (more is better)
@@ -690,6 +723,31 @@ impl SharedRelayRegistry {
         Ok(registry.select_best_relay(target_peer_pubkey, required_capabilities, preferred_region))
     }
     
+    /// Select the best relay, preferring to keep `current`
+    pub fn select_relay_with_hysteresis(
+        &self,
+        target_peer_pubkey: &[u8],
+        required_capabilities: u32,
+        preferred_region: Option<&str>,
+        current: Option<&[u8; 32]>
+    ) -> Result<Option<RelayNodeInfo>> {
+        let registry = self.inner.read().map_err(|_| 
+            RelayError::Protocol("Failed to acquire read lock on relay registry".into()))?;
+            
+        Ok(registry.select_relay_with_hysteresis(target_peer_pubkey, required_capabilities, preferred_region, current))
+    }
+    
+    /// Record a relay that didn't answer a probe
+    pub fn record_probe_failure(&self, pubkey: &[u8]) -> Result<()> {
+        match self.inner.write() {
+            Ok(mut registry) => {
+                registry.record_probe_failure(pubkey);
+                Ok(())
+            },
+            Err(_) => Err(RelayError::Protocol("Failed to acquire write lock on relay registry".into())),
+        }
+    }
+    
     /// Get a scored list of relays matching criteria
     pub fn get_scored_relays(
         &self,
@@ -1026,4 +1084,34 @@ mod tests {
         let score_region_mismatch = registry.score_relay(&low_latency_relay, Some("eu-west"), None);
         assert!(score_region_match > score_region_mismatch);
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_relay_selection_hysteresis() {
+        let mut registry = RelayRegistry::new();
+        
+        let mut fast = create_test_relay(1, vec!["192.168.1.1:8080"], 10);
+        fast.latency = Some(50);
+        let mut current = create_test_relay(2, vec!["192.168.1.2:8080"], 10);
+        current.latency = Some(70);
+        registry.register_relay(fast.clone());
+        registry.register_relay(current.clone());
+        
+        // Without a current relay the better one wins, with one a small
+        // difference isn't worth switching for
+        assert_eq!(registry.select_best_relay(&[0; 32], 0, None).unwrap().pubkey, fast.pubkey);
+        let selected = registry.select_relay_with_hysteresis(&[0; 32], 0, None, Some(&current.pubkey));
+        assert_eq!(selected.unwrap().pubkey, current.pubkey);
+        
+        // A clearly better relay replaces the current one
+        registry.update_relay(&fast.pubkey, |r| r.latency = Some(10)).unwrap();
+        registry.update_relay(&current.pubkey, |r| r.latency = Some(150)).unwrap();
+        let selected = registry.select_relay_with_hysteresis(&[0; 32], 0, None, Some(&current.pubkey));
+        assert_eq!(selected.unwrap().pubkey, fast.pubkey);
+        
+        // A relay with all its sessions in use is no longer a candidate
+        registry.update_relay(&fast.pubkey, |r| r.active_sessions = 10).unwrap();
+        assert_eq!(registry.get_relay(&fast.pubkey).unwrap().utilization(), 1.0);
+        let selected = registry.select_relay_with_hysteresis(&[0; 32], 0, None, Some(&fast.pubkey));
+        assert_eq!(selected.unwrap().pubkey, current.pubkey);
+    }
+}
@@ -17,7 +17,7 @@ use url::Host;
 use log::{debug, info, warn};
 
 use crate::relay::{
-    ConnectionRequest, ConnectionStatus, RelayError, RelayMessage,
+    ConnectionRequest, ConnectionStatus, DiscoveryQuery, RelayError, RelayMessage,
     RelayNodeInfo, Result, SharedRelayRegistry, RelayPacket
 };
 
@@ -83,6 +83,12 @@ const LATENCY_SAMPLE_COUNT: usize = 20;
 /// Latency multiplier for timeout calculations (timeout = avg_latency * multiplier)
 const LATENCY_TIMEOUT_MULTIPLIER: f64 = 2.5;
 
+/// How often known relays are probed for latency and load
+pub const RELAY_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of relays probed in one round
+const MAX_PROBED_RELAYS: usize = 20;
+
 /// Connection attempt status
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ConnectionAttemptStatus {
@@ -299,6 +305,10 @@ pub struct RelayManager {
     
     /// Adaptive timeout configuration
     config: crate::relay::service::RelayConfig,
+    
+    /// Relay of the last successful connection, kept unless another relay
+    /// is clearly better
+    preferred_relay: Arc<RwLock<Option<[u8; 32]>>>,
 }
 
 /// Relay packet receiver
@@ -659,6 +669,7 @@ impl RelayManager {
             local_pubkey,
             latency_trackers: Arc::new(RwLock::new(HashMap::new())),
             config,
+            preferred_relay: Arc::new(RwLock::new(None)),
         }
     }
     
@@ -673,6 +684,7 @@ impl RelayManager {
             local_pubkey,
             latency_trackers: Arc::new(RwLock::new(HashMap::new())),
             config,
+            preferred_relay: Arc::new(RwLock::new(None)),
         }
     }
     
@@ -1032,11 +1044,14 @@ impl RelayManager {
                 .ok_or_else(|| RelayError::Protocol("Session lookup failed".into()));
         }
         
-        // Get a relay node from the registry
-        let relay_info = match self.relay_registry.select_best_relay(
+        // Get a relay node from the registry, sticking with the last relay
+        // that worked unless another one scores clearly better
+        let preferred = self.preferred_relay.read().ok().and_then(|preferred| *preferred);
+        let relay_info = match self.relay_registry.select_relay_with_hysteresis(
             &target_pubkey,
             required_capabilities,
-            preferred_region
+            preferred_region,
+            preferred.as_ref()
         )? {
             Some(relay) => relay,
             None => return Err(RelayError::Protocol(
@@ -1045,7 +1060,85 @@ impl RelayManager {
         };
         
         // Try to connect through this relay
-        self.try_connect_via_relay(target_pubkey.as_ref(), &relay_info).await
+        let result = self.try_connect_via_relay(target_pubkey.as_ref(), &relay_info).await;
+        if let Ok(mut preferred) = self.preferred_relay.write() {
+            match &result {
+                Ok(_) => *preferred = Some(relay_info.pubkey),
+                Err(_) if *preferred == Some(relay_info.pubkey) => *preferred = None,
+                Err(_) => {}
+            }
+        }
+        result
+    }
+    
+    /// Probe the known relays, recording their latency and the load they
+    /// advertise so relay selection can prefer close, underloaded relays.
+    /// Returns the number of relays that answered.
+    pub async fn probe_relays(&self) -> Result<usize> {
+        let relays = self.relay_registry.find_relays(None, 0, MAX_PROBED_RELAYS)?;
+        let mut answered = 0;
+        
+        for relay in relays {
+            match self.probe_relay(&relay).await {
+                Ok((latency_ms, advertised)) => {
+                    answered += 1;
+                    self.record_connection_latency(&relay.pubkey, latency_ms);
+                    self.relay_registry.update_relay(&relay.pubkey, |r| {
+                        if let Some(info) = advertised {
+                            r.load = info.load;
+                            r.max_sessions = info.max_sessions;
+                            r.active_sessions = info.active_sessions;
+                            r.bandwidth_bps = info.bandwidth_bps;
+                            r.bandwidth_budget_bps = info.bandwidth_budget_bps;
+                        }
+                        r.update_reliability(true);
+                    })?;
+                }
+                Err(e) => {
+                    debug!("Probe of relay {} failed: {}", hex::encode(relay.pubkey), e);
+                    self.relay_registry.record_probe_failure(&relay.pubkey)?;
+                }
+            }
+        }
+        
+        Ok(answered)
+    }
+    
+    /// Send a discovery query to a relay and time its answer. The relay's
+    /// own entry in the response carries its current capacity.
+    async fn probe_relay(&self, relay_info: &RelayNodeInfo) -> Result<(u64, Option<RelayNodeInfo>)> {
+        let endpoint: SocketAddr = relay_info.endpoints.first()
+            .ok_or_else(|| RelayError::Protocol("Relay has no endpoints".into()))?
+            .parse()
+            .map_err(|_| RelayError::Protocol(format!("Invalid endpoint: {}", relay_info.endpoints[0])))?;
+        
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await.map_err(RelayError::Io)?;
+        socket.connect(endpoint).await.map_err(RelayError::Io)?;
+        
+        let query = DiscoveryQuery::new(self.local_pubkey, 1);
+        let nonce = query.nonce;
+        let started = Instant::now();
+        socket.send(&RelayMessage::DiscoveryQuery(query).serialize()?).await.map_err(RelayError::Io)?;
+        
+        let timeout = self.get_adaptive_timeout(&relay_info.pubkey);
+        let mut buf = [0u8; 4096];
+        let response = tokio::time::timeout(timeout, async {
+            loop {
+                let len = socket.recv(&mut buf).await.map_err(RelayError::Io)?;
+                // Skip stray packets and answers to earlier probes
+                if let Ok(RelayMessage::DiscoveryResponse(response)) = RelayMessage::deserialize(&buf[..len]) {
+                    if response.request_nonce == nonce {
+                        return Ok::<_, RelayError>(response);
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| RelayError::Protocol(format!("Relay probe timed out after {:?}", timeout)))??;
+        
+        let latency_ms = started.elapsed().as_millis() as u64;
+        let advertised = response.relays.into_iter().find(|r| r.pubkey == relay_info.pubkey);
+        Ok((latency_ms, advertised))
     }
     
     /// Get adaptive timeout based on relay public key and config settings
@@ -374,7 +374,7 @@ pub struct RelayNodeInfo {
     pub load: u8,
     
     /// Estimated latency in milliseconds
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub latency: Option<u32>,
     
     /// Maximum concurrent sessions supported
@@ -388,12 +388,24 @@ pub struct RelayNodeInfo {
     pub reliability: u8,
     
     /// Last connection success/failure timestamp
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub last_result_time: Option<u64>,
     
     /// Last measured packet loss percentage (0-100)
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub packet_loss: Option<u8>,
+    
+    /// Sessions the relay is currently carrying
+    #[serde(default)]
+    pub active_sessions: u32,
+    
+    /// Bandwidth the relay is currently forwarding, in bits per second
+    #[serde(default)]
+    pub bandwidth_bps: u64,
+    
+    /// Most bandwidth the relay will forward, in bits per second (None if unlimited)
+    #[serde(default)]
+    pub bandwidth_budget_bps: Option<u64>,
 }
 
 impl RelayNodeInfo {
@@ -411,6 +423,9 @@ impl RelayNodeInfo {
             reliability: 100, // Start with perfect reliability until proven otherwise
             last_result_time: None,
             packet_loss: None,
+            active_sessions: 0,
+            bandwidth_bps: 0,
+            bandwidth_budget_bps: None,
         }
     }
     
@@ -442,6 +457,30 @@ impl RelayNodeInfo {
         self
     }
     
+    /// Set the advertised capacity use
+    pub fn with_capacity(mut self, active_sessions: u32, bandwidth_bps: u64, bandwidth_budget_bps: Option<u64>) -> Self {
+        self.active_sessions = active_sessions;
+        self.bandwidth_bps = bandwidth_bps;
+        self.bandwidth_budget_bps = bandwidth_budget_bps;
+        self
+    }
+    
+    /// Fraction of the relay's capacity in use (0.0-1.0). Sessions, bandwidth
+    /// and the load factor are compared and the busiest one counts.
+    pub fn utilization(&self) -> f32 {
+        let sessions = if self.max_sessions > 0 {
+            self.active_sessions as f32 / self.max_sessions as f32
+        } else {
+            0.0
+        };
+        let bandwidth = match self.bandwidth_budget_bps {
+            Some(budget) if budget > 0 => self.bandwidth_bps as f32 / budget as f32,
+            _ => 0.0,
+        };
+        
+        sessions.max(bandwidth).max(self.load as f32 / 100.0).min(1.0)
+    }
+    
     /// Update relay reliability based on connection success or failure
     pub fn update_reliability(&mut self, success: bool) {
         // Get current timestamp
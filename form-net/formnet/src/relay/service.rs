@@ -904,10 +904,14 @@ impl RelayNode {
             reliability: 100, // Default to full reliability
             last_result_time: None,
             packet_loss: None,
+            active_sessions: self.sessions.read().unwrap().len() as u32,
+            bandwidth_bps: 0,
+            bandwidth_budget_bps: self.config.limits.max_bandwidth_bps.map(|bytes| bytes * 8),
         };
         
         // Update with real stats if available
         let stats = self.stats.read().unwrap();
+        node_info.bandwidth_bps = stats.current_bandwidth_bps;
         if stats.active_sessions > 0 {
             // Calculate actual load based on stats
             let active_sessions = stats.active_sessions as u8;
@@ -975,6 +979,11 @@ impl RelayNode {
             return Err(RelayError::ResourceLimit("Packet rate limit exceeded".into()));
         }
         
+        // Latency probes from RelayManager are JSON encoded RelayMessages
+        if let Ok(RelayMessage::DiscoveryQuery(query)) = RelayMessage::deserialize(data) {
+            return Self::process_discovery_query(socket, query, src_addr, stats, config, true);
+        }
+        
         // Try to deserialize as a relay packet
         if let Ok(packet) = bincode::deserialize::<RelayPacket>(data) {
             return Self::process_relay_packet(socket, packet, src_addr, sessions, stats);
@@ -1004,7 +1013,7 @@ impl RelayNode {
         
         // Try to deserialize as a discovery query
         if let Ok(query) = bincode::deserialize::<DiscoveryQuery>(data) {
-            return Self::process_discovery_query(socket, query, src_addr, stats, config);
+            return Self::process_discovery_query(socket, query, src_addr, stats, config, false);
         }
         
         // Unknown packet type
@@ -1484,13 +1493,14 @@ impl RelayNode {
         Ok(())
     }
     
-    /// Process a discovery query
+    /// Process a discovery query, answering in the encoding it arrived in
     fn process_discovery_query(
         socket: &Arc<UdpSocket>,
         query: DiscoveryQuery,
         src_addr: SocketAddr,
         stats: &Arc<RwLock<RelayStats>>,
-        config: &RelayConfig
+        config: &RelayConfig,
+        json: bool
     ) -> Result<()> {
         // Validate the query
         if !query.is_valid() {
@@ -1536,6 +1546,9 @@ impl RelayNode {
             reliability: 100,
             last_result_time: None,
             packet_loss: None,
+            active_sessions: stats_guard.active_sessions as u32,
+            bandwidth_bps: stats_guard.current_bandwidth_bps,
+            bandwidth_budget_bps: config.limits.max_bandwidth_bps.map(|bytes| bytes * 8),
         };
         
         drop(stats_guard);
@@ -1552,8 +1565,12 @@ impl RelayNode {
         };
         
         // Send the response
-        let response_data = bincode::serialize(&response)
-            .map_err(|e| RelayError::Serialization(e))?;
+        let response_data = if json {
+            RelayMessage::DiscoveryResponse(response).serialize()?
+        } else {
+            bincode::serialize(&response)
+                .map_err(|e| RelayError::Serialization(e))?
+        };
             
         socket.send_to(&response_data, src_addr)
             .map_err(|e| RelayError::Io(e))?;
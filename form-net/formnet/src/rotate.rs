@@ -0,0 +1,230 @@
+//! WireGuard key rotation
+//!
+//! A node generates a new keypair and publishes it in a `KeyRotationContents`
+//! signed by its operator key. The receiving node checks the signature against
+//! the peer id, swaps the peer's public key in the datastore and keeps the old
+//! one as a `RetiringKey`. Peers keep the old key on their interface until the
+//! grace window ends, after which the sweeper drops it from the datastore.
+use std::{path::PathBuf, str::FromStr, time::{Duration, SystemTime, UNIX_EPOCH}};
+use alloy_core::primitives::Address;
+use axum::Json;
+use formnet_server::{db::CrdtMap, ConfigFile, DatabasePeer};
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+use shared::{
+    KeyRotationContents, NetworkOpts, KEY_ROTATION_GRACE_PERIOD, KEY_ROTATION_MAX_AGE,
+    KEY_ROTATION_MAX_GRACE_PERIOD,
+};
+use wireguard_control::{DeviceUpdate, InterfaceName, Key, KeyPair};
+use crate::{api::BootstrapInfo, CONFIG_DIR, NETWORK_NAME};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RotateResponse {
+    Success {
+        /// When peers stop accepting the old key, seconds since the unix epoch
+        expires_at: u64,
+    },
+    Failure { reason: String },
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Build and sign a rotation request for the peer owned by `signing_key`
+pub fn sign_rotation(
+    signing_key: &SigningKey,
+    old_public_key: String,
+    new_public_key: String,
+    grace_period: Duration,
+) -> Result<KeyRotationContents, Box<dyn std::error::Error>> {
+    let mut contents = KeyRotationContents {
+        peer_id: hex::encode(Address::from_private_key(signing_key)),
+        old_public_key,
+        new_public_key,
+        issued_at: now_secs(),
+        grace_period_secs: grace_period.as_secs(),
+        signature: String::new(),
+        recovery_id: 0,
+    };
+    let (signature, recovery_id) = signing_key.sign_recoverable(&contents.signing_payload())?;
+    contents.signature = hex::encode(signature.to_bytes());
+    contents.recovery_id = recovery_id.to_byte();
+    Ok(contents)
+}
+
+/// Check the request is fresh, well formed and signed by the operator that owns the peer.
+/// Returns the grace period to apply.
+pub fn verify_rotation(contents: &KeyRotationContents, now: u64) -> Result<Duration, String> {
+    if contents.issued_at > now + 30 || now.saturating_sub(contents.issued_at) > KEY_ROTATION_MAX_AGE.as_secs() {
+        return Err("Rotation request is stale or issued in the future".to_string());
+    }
+    if Key::from_base64(&contents.new_public_key).is_err() {
+        return Err("New public key is not a valid WireGuard key".to_string());
+    }
+    if contents.new_public_key == contents.old_public_key {
+        return Err("New public key must differ from the current key".to_string());
+    }
+
+    let grace_period = match contents.grace_period_secs {
+        0 => KEY_ROTATION_GRACE_PERIOD,
+        secs => Duration::from_secs(secs),
+    };
+    if grace_period > KEY_ROTATION_MAX_GRACE_PERIOD {
+        return Err(format!(
+            "Grace period may not exceed {} seconds",
+            KEY_ROTATION_MAX_GRACE_PERIOD.as_secs()
+        ));
+    }
+
+    let signature_bytes = hex::decode(&contents.signature)
+        .map_err(|e| format!("Invalid signature encoding: {e}"))?;
+    let signature = Signature::from_slice(&signature_bytes)
+        .map_err(|e| format!("Invalid signature: {e}"))?;
+    let recovery_id = RecoveryId::from_byte(contents.recovery_id)
+        .ok_or_else(|| "Invalid recovery id".to_string())?;
+    let verifying_key = VerifyingKey::recover_from_msg(&contents.signing_payload(), &signature, recovery_id)
+        .map_err(|e| format!("Unable to recover signer: {e}"))?;
+    let signer = hex::encode(Address::from_public_key(&verifying_key));
+    if !signer.eq_ignore_ascii_case(contents.peer_id.trim_start_matches("0x")) {
+        return Err("Rotation must be signed by the operator that owns the peer".to_string());
+    }
+
+    Ok(grace_period)
+}
+
+pub async fn handle_rotate_request(
+    Json(contents): Json<KeyRotationContents>,
+) -> Json<RotateResponse> {
+    log::info!("Received key rotation request for peer {}", contents.peer_id);
+    let now = now_secs();
+    let grace_period = match verify_rotation(&contents, now) {
+        Ok(grace_period) => grace_period,
+        Err(reason) => {
+            log::warn!("Rejected key rotation for {}: {reason}", contents.peer_id);
+            return Json(RotateResponse::Failure { reason });
+        }
+    };
+
+    let mut peer = match DatabasePeer::<String, CrdtMap>::get(contents.peer_id.clone()).await {
+        Ok(peer) => peer,
+        Err(e) => return Json(RotateResponse::Failure { reason: format!("Unknown peer: {e}") }),
+    };
+    if peer.public_key != contents.old_public_key {
+        return Json(RotateResponse::Failure {
+            reason: "Old public key does not match the peer's current key".to_string(),
+        });
+    }
+    if peer.retiring_key.as_ref().is_some_and(|k| !k.is_expired(SystemTime::now())) {
+        return Json(RotateResponse::Failure {
+            reason: "A previous rotation is still in its grace window".to_string(),
+        });
+    }
+
+    let expires_at = now + grace_period.as_secs();
+    match peer.rotate_key(contents.new_public_key.clone(), expires_at).await {
+        Ok(()) => {
+            log::info!("Rotated WireGuard key of peer {}, old key retires at {expires_at}", contents.peer_id);
+            Json(RotateResponse::Success { expires_at })
+        }
+        Err(e) => Json(RotateResponse::Failure { reason: e.to_string() }),
+    }
+}
+
+/// Periodically drop retiring keys whose grace window has passed
+pub async fn spawn_retired_key_sweeper() {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            match DatabasePeer::<String, CrdtMap>::retire_expired_keys().await {
+                Ok(0) => {}
+                Ok(n) => log::info!("Retired {n} expired WireGuard keys."),
+                Err(e) => log::error!("Failed to retire expired WireGuard keys: {}", e),
+            }
+        }
+    });
+}
+
+/// Rotate this node's WireGuard key: publish the new key, then switch the
+/// local interface and config over to it. Returns when the old key expires.
+pub async fn rotate_keys(
+    signing_key: &SigningKey,
+    grace_period: Duration,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let interface = InterfaceName::from_str(NETWORK_NAME)?;
+    let config_path = PathBuf::from(CONFIG_DIR).join(NETWORK_NAME).with_extension("conf");
+    let mut config = ConfigFile::from_file(&config_path)?;
+
+    let old_public_key = Key::from_base64(&config.private_key)?.get_public().to_base64();
+    let keypair = KeyPair::generate();
+    let contents = sign_rotation(
+        signing_key,
+        old_public_key,
+        keypair.public.to_base64(),
+        grace_period,
+    )?;
+
+    // Bootstrap nodes handle the request themselves, other nodes ask their bootstrap
+    let api = match &config.bootstrap {
+        Some(bootstrap) => {
+            let info: BootstrapInfo = serde_json::from_slice(&hex::decode(bootstrap)?)?;
+            let external = info.external_endpoint.ok_or("Bootstrap peer has no external endpoint")?;
+            format!("http://{external}")
+        }
+        None => "http://127.0.0.1:51820".to_string(),
+    };
+
+    log::info!("Publishing new WireGuard key for {} to {api}", contents.peer_id);
+    let expires_at = match Client::new()
+        .post(format!("{api}/rotate"))
+        .json(&contents)
+        .send()
+        .await?
+        .json::<RotateResponse>()
+        .await?
+    {
+        RotateResponse::Success { expires_at } => expires_at,
+        RotateResponse::Failure { reason } => return Err(reason.into()),
+    };
+
+    // Save the new key before touching the interface so a restart comes back with it
+    config.private_key = keypair.private.to_base64();
+    config.write_to_path(&config_path)?;
+    DeviceUpdate::new()
+        .set_private_key(keypair.private)
+        .apply(&interface, NetworkOpts::default().backend)?;
+    log::info!("Switched {NETWORK_NAME} to the new key, peers accept the old key until {expires_at}");
+
+    Ok(expires_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_signature_roundtrip() {
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        let old = KeyPair::generate().public.to_base64();
+        let new = KeyPair::generate().public.to_base64();
+        let contents = sign_rotation(&signing_key, old, new, Duration::from_secs(120)).unwrap();
+
+        let now = now_secs();
+        assert_eq!(verify_rotation(&contents, now), Ok(Duration::from_secs(120)));
+
+        let mut forged = contents.clone();
+        forged.peer_id = hex::encode(Address::from_private_key(&SigningKey::random(&mut rand::thread_rng())));
+        assert!(verify_rotation(&forged, now).is_err());
+
+        let mut tampered = contents.clone();
+        tampered.grace_period_secs = 600;
+        assert!(verify_rotation(&tampered, now).is_err());
+
+        assert!(verify_rotation(&contents, now + KEY_ROTATION_MAX_AGE.as_secs() + 1).is_err());
+    }
+}
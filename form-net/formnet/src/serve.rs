@@ -12,10 +12,10 @@ use std::{net::SocketAddr, ops::Deref};
 use formnet_server::{ConfigFile, VERSION};
 use formnet_server::{db::CrdtMap, DatabasePeer};
 use ipnet::IpNet;
-use shared::{get_local_addrs, wg, Endpoint, NetworkOpts, PeerContents};
+use shared::{wg, Endpoint, NetworkOpts, PeerContents};
 use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, PeerConfigBuilder};
 use crate::api::{server, BootstrapInfo, Response};
-use crate::{fetch_server, CONFIG_DIR};
+use crate::{fetch_server, gather_candidates, CONFIG_DIR};
 
 pub async fn serve(
     interface: &str,
@@ -106,9 +106,7 @@ pub async fn serve(
 
     log::info!("{} peers added to wireguard interface.", peers.len());
 
-    let candidates: Vec<Endpoint> = get_local_addrs()?
-        .map(|addr| SocketAddr::from((addr, config.listen_port.unwrap())).into())
-        .collect();
+    let candidates: Vec<Endpoint> = gather_candidates(config.listen_port.unwrap())?;
     let num_candidates = candidates.len();
     let myself = peers
         .iter_mut()
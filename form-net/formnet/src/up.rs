@@ -1,12 +1,23 @@
-use std::{path::PathBuf, time::Duration};
+use std::{path::PathBuf, time::{Duration, Instant}};
 use client::util::all_installed;
-use crate::{fetch, CONFIG_DIR};
+use form_config::FormnetRefreshConfig;
+use k256::ecdsa::SigningKey;
+use shared::KEY_ROTATION_GRACE_PERIOD;
+use crate::{fetch, refresh_config, refresh_config_changed, refresh_endpoints, rotate_keys, set_refresh_config, RefreshSchedule, CONFIG_DIR};
 
 
+/// Brings formnet up and, given a refresh config, keeps it up to date on the
+/// cadence it sets until an error. Re-keying needs the operator's
+/// `signing_key`, it's skipped without one.
 pub async fn up(
-    loop_interval: Option<Duration>,
+    refresh: Option<FormnetRefreshConfig>,
+    signing_key: Option<SigningKey>,
     hosts_path: Option<PathBuf>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(config) = refresh {
+        set_refresh_config(config)?;
+    }
+    let mut schedule = RefreshSchedule::new(Instant::now());
     loop {
         log::info!("acquiring interfaces");
         let interfaces = all_installed(&PathBuf::from(CONFIG_DIR))?;
@@ -14,12 +25,33 @@ pub async fn up(
 
         fetch(hosts_path.clone()).await?;
 
-        match loop_interval {
-            Some(interval) => std::thread::sleep(interval),
-            None => break,
+        if refresh.is_none() {
+            break;
+        }
+        let config = refresh_config();
+        let now = Instant::now();
+        if schedule.endpoints_due(&config, now) {
+            match refresh_endpoints(Duration::from_secs(config.handshake_timeout_secs)) {
+                Ok(0) => {}
+                Ok(n) => log::info!("Updated {n} re-resolved peer endpoints"),
+                Err(e) => log::error!("Failed to re-resolve peer endpoints: {e}"),
+            }
+        }
+        if let Some(signing_key) = signing_key.as_ref().filter(|_| schedule.rekey_due(&config, now)) {
+            match rotate_keys(signing_key, KEY_ROTATION_GRACE_PERIOD).await {
+                Ok(expires_at) => {
+                    log::info!("Scheduled WireGuard key rotation done, the old key retires at {expires_at}");
+                    schedule.rekeyed(now);
+                }
+                Err(e) => log::error!("Scheduled WireGuard key rotation failed, retrying next round: {e}"),
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(config.refresh_interval_secs)) => {}
+            _ = refresh_config_changed() => log::info!("Refresh config changed, refreshing now"),
         }
     }
 
     Ok(())
 }
-
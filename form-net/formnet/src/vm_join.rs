@@ -14,9 +14,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let name = std::fs::read_to_string("/etc/vm_name")?;
     let build_id = std::fs::read_to_string("/etc/build_id")?;
     match request_to_join(vec![host_public_ip.clone()], name.clone(), form_types::PeerType::Instance, None, None, None).await {
-        Ok(ip)=> {
+        Ok(outcome)=> {
             log::info!("Received invitation");
-            let formnet_ip = ip; 
+            let formnet_ip = outcome.ip; 
             log::info!("extracted formnet IP for {name}: {formnet_ip}");
             log::info!("Attempting to redeem invite");
             log::info!("Spawning thread to bring formnet up");
@@ -5,6 +5,7 @@ use shared::Peer;
 use crate::{DatastoreContext, Session};
 
 pub mod admin;
+pub mod rest;
 pub mod user;
 
 /// Inject the collected endpoints from the WG interface into a list of peers.
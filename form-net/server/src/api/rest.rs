@@ -0,0 +1,295 @@
+//! Authenticated REST API for managing peers and CIDRs on the CRDT datastore.
+//!
+//! Requests are authenticated with the same ECDSA signature scheme as the
+//! form-state API. The signer must be an enabled admin peer, requests from
+//! localhost are trusted like the rest of the node's local services.
+use std::net::IpAddr;
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use form_state::auth::{ecdsa_auth_middleware, RecoveredAddress};
+use ipnet::IpNet;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use shared::{CidrContents, Endpoint, Hostname, IpNetExt, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
+
+use crate::{
+    db::{CrdtMap, DatabaseCidr, DatabasePeer},
+    ServerError,
+};
+
+/// The root CIDR created when the network is initialized
+const ROOT_CIDR_ID: &str = "formnet";
+
+type ApiResponse = (StatusCode, Json<Value>);
+
+#[derive(Debug, Deserialize)]
+pub struct AddPeerRequest {
+    pub name: Hostname,
+    /// WireGuard public key (base64)
+    pub public_key: String,
+    /// Defaults to the root CIDR
+    pub cidr_id: Option<String>,
+    /// Defaults to the first free address in the CIDR
+    pub ip: Option<IpAddr>,
+    #[serde(default)]
+    pub is_admin: bool,
+    pub endpoint: Option<Endpoint>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenamePeerRequest {
+    pub name: Hostname,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddCidrRequest {
+    pub name: String,
+    pub cidr: IpNet,
+    /// Defaults to the root CIDR
+    pub parent: Option<String>,
+}
+
+/// Routes are relative, nest them under the prefix of the hosting server
+pub fn admin_router() -> Router {
+    Router::new()
+        .route("/peers", get(list_peers).post(add_peer))
+        .route("/peers/:id/rename", post(rename_peer))
+        .route("/peers/:id/disable", post(disable_peer))
+        .route("/cidrs", get(list_cidrs).post(add_cidr))
+        .route("/cidrs/:id", delete(delete_cidr))
+        .layer(middleware::from_fn(ecdsa_auth_middleware))
+}
+
+fn failure(status: StatusCode, error: impl ToString) -> ApiResponse {
+    (status, Json(json!({ "success": false, "error": error.to_string() })))
+}
+
+fn server_failure(e: ServerError) -> ApiResponse {
+    let status = StatusCode::from_u16(hyper::StatusCode::from(&e).as_u16())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    failure(status, e)
+}
+
+/// Only enabled admin peers may change the topology
+async fn authorize(caller: &Option<RecoveredAddress>) -> Result<(), ApiResponse> {
+    let Some(caller) = caller else {
+        return Ok(());
+    };
+
+    match DatabasePeer::<String, CrdtMap>::get(caller.as_hex()).await {
+        Ok(peer) if peer.is_admin && !peer.is_disabled => Ok(()),
+        _ => {
+            log::warn!("Rejected admin API request from 0x{}", caller.as_hex());
+            Err(failure(StatusCode::FORBIDDEN, "Only admin peers may manage the network"))
+        }
+    }
+}
+
+async fn list_peers(Extension(caller): Extension<Option<RecoveredAddress>>) -> ApiResponse {
+    if let Err(response) = authorize(&caller).await {
+        return response;
+    }
+
+    match DatabasePeer::<String, CrdtMap>::list().await {
+        Ok(peers) => {
+            let peers = peers.into_iter().map(|p| p.inner).collect::<Vec<_>>();
+            (StatusCode::OK, Json(json!({ "success": true, "peers": peers })))
+        }
+        Err(e) => server_failure(e),
+    }
+}
+
+async fn add_peer(
+    Extension(caller): Extension<Option<RecoveredAddress>>,
+    Json(request): Json<AddPeerRequest>,
+) -> ApiResponse {
+    if let Err(response) = authorize(&caller).await {
+        return response;
+    }
+    if wireguard_control::Key::from_base64(&request.public_key).is_err() {
+        return failure(StatusCode::BAD_REQUEST, "Invalid WireGuard public key");
+    }
+
+    let peers = match DatabasePeer::<String, CrdtMap>::list().await {
+        Ok(peers) => peers,
+        Err(e) => return server_failure(e),
+    };
+    if peers.iter().any(|p| p.id == request.name.to_string()) {
+        return failure(StatusCode::CONFLICT, format!("Peer {} already exists", request.name));
+    }
+    if peers.iter().any(|p| p.public_key == request.public_key) {
+        return failure(StatusCode::CONFLICT, "Public key is already in use");
+    }
+
+    let cidr_id = request.cidr_id.unwrap_or_else(|| ROOT_CIDR_ID.to_string());
+    let cidr = match DatabaseCidr::<String, CrdtMap>::get(cidr_id.clone()).await {
+        Ok(cidr) => cidr,
+        Err(e) => return server_failure(e),
+    };
+    let ip = match request.ip {
+        Some(ip) if peers.iter().any(|p| p.ip == ip) => {
+            return failure(StatusCode::CONFLICT, format!("{ip} is already assigned"));
+        }
+        Some(ip) => ip,
+        None => match cidr.cidr.hosts()
+            .filter(|ip| cidr.cidr.is_assignable(ip))
+            .find(|ip| !peers.iter().any(|p| p.ip == *ip))
+        {
+            Some(ip) => ip,
+            None => return failure(StatusCode::CONFLICT, format!("No free addresses left in {}", cidr.name)),
+        },
+    };
+
+    let contents = PeerContents {
+        name: request.name,
+        ip,
+        cidr_id,
+        public_key: request.public_key,
+        endpoint: request.endpoint,
+        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        is_admin: request.is_admin,
+        is_disabled: false,
+        is_redeemed: true,
+        invite_expires: None,
+        candidates: vec![],
+        retiring_key: None,
+    };
+
+    // Peers pick the new entry up on their next fetch
+    match DatabasePeer::<String, CrdtMap>::create(contents).await {
+        Ok(peer) => {
+            log::info!("Admin API: added peer {} at {}", peer.id, peer.ip);
+            (StatusCode::CREATED, Json(json!({ "success": true, "peer": peer.inner })))
+        }
+        Err(e) => server_failure(e),
+    }
+}
+
+/// Peer records are keyed by name, so a rename writes the record under the
+/// new name before removing the old one.
+async fn rename_peer(
+    Extension(caller): Extension<Option<RecoveredAddress>>,
+    Path(id): Path<String>,
+    Json(request): Json<RenamePeerRequest>,
+) -> ApiResponse {
+    if let Err(response) = authorize(&caller).await {
+        return response;
+    }
+
+    let peer = match DatabasePeer::<String, CrdtMap>::get(id.clone()).await {
+        Ok(peer) => peer,
+        Err(e) => return server_failure(e),
+    };
+    if DatabasePeer::<String, CrdtMap>::get(request.name.to_string()).await.is_ok() {
+        return failure(StatusCode::CONFLICT, format!("Peer {} already exists", request.name));
+    }
+
+    let contents = PeerContents {
+        name: request.name,
+        ..peer.contents.clone()
+    };
+    let renamed = match DatabasePeer::<String, CrdtMap>::create(contents).await {
+        Ok(renamed) => renamed,
+        Err(e) => return server_failure(e),
+    };
+    if let Err(e) = DatabasePeer::<String, CrdtMap>::delete(id.clone()).await {
+        log::error!("Admin API: renamed {id} to {} but could not remove the old record: {e}", renamed.id);
+        return server_failure(e);
+    }
+
+    log::info!("Admin API: renamed peer {id} to {}", renamed.id);
+    (StatusCode::OK, Json(json!({ "success": true, "peer": renamed.inner })))
+}
+
+async fn disable_peer(
+    Extension(caller): Extension<Option<RecoveredAddress>>,
+    Path(id): Path<String>,
+) -> ApiResponse {
+    if let Err(response) = authorize(&caller).await {
+        return response;
+    }
+    if caller.as_ref().is_some_and(|c| c.as_hex() == id) {
+        return failure(StatusCode::BAD_REQUEST, "Admins cannot disable themselves");
+    }
+
+    match DatabasePeer::<String, CrdtMap>::disable(id.clone()).await {
+        Ok(()) => {
+            log::info!("Admin API: disabled peer {id}");
+            (StatusCode::OK, Json(json!({ "success": true, "id": id })))
+        }
+        Err(e) => server_failure(e),
+    }
+}
+
+async fn list_cidrs(Extension(caller): Extension<Option<RecoveredAddress>>) -> ApiResponse {
+    if let Err(response) = authorize(&caller).await {
+        return response;
+    }
+
+    match DatabaseCidr::<String, CrdtMap>::list().await {
+        Ok(cidrs) => (StatusCode::OK, Json(json!({ "success": true, "cidrs": cidrs }))),
+        Err(e) => server_failure(e),
+    }
+}
+
+async fn add_cidr(
+    Extension(caller): Extension<Option<RecoveredAddress>>,
+    Json(request): Json<AddCidrRequest>,
+) -> ApiResponse {
+    if let Err(response) = authorize(&caller).await {
+        return response;
+    }
+
+    let contents = CidrContents {
+        name: request.name,
+        cidr: request.cidr,
+        parent: Some(request.parent.unwrap_or_else(|| ROOT_CIDR_ID.to_string())),
+    };
+    match DatabaseCidr::<String, CrdtMap>::create(contents).await {
+        Ok(cidr) => {
+            log::info!("Admin API: added CIDR {} ({})", cidr.name, cidr.cidr);
+            (StatusCode::CREATED, Json(json!({ "success": true, "cidr": cidr })))
+        }
+        Err(e) => server_failure(e),
+    }
+}
+
+async fn delete_cidr(
+    Extension(caller): Extension<Option<RecoveredAddress>>,
+    Path(id): Path<String>,
+) -> ApiResponse {
+    if let Err(response) = authorize(&caller).await {
+        return response;
+    }
+    if id == ROOT_CIDR_ID {
+        return failure(StatusCode::BAD_REQUEST, "The root CIDR cannot be deleted");
+    }
+
+    let (peers, cidrs) = match (
+        DatabasePeer::<String, CrdtMap>::list().await,
+        DatabaseCidr::<String, CrdtMap>::list().await,
+    ) {
+        (Ok(peers), Ok(cidrs)) => (peers, cidrs),
+        (Err(e), _) | (_, Err(e)) => return server_failure(e),
+    };
+    if peers.iter().any(|p| p.cidr_id == id && !p.is_disabled) {
+        return failure(StatusCode::CONFLICT, "CIDR still has peers assigned to it");
+    }
+    if cidrs.iter().any(|c| c.parent.as_deref() == Some(id.as_str())) {
+        return failure(StatusCode::CONFLICT, "CIDR still has child CIDRs");
+    }
+
+    match DatabaseCidr::<String, CrdtMap>::delete(id.clone()).await {
+        Ok(()) => {
+            log::info!("Admin API: deleted CIDR {id}");
+            (StatusCode::OK, Json(json!({ "success": true, "id": id })))
+        }
+        Err(e) => server_failure(e),
+    }
+}
@@ -6,7 +6,7 @@ use form_types::state::{Response, Success};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use rusqlite::{params, types::Type, Connection};
-use shared::{IpNetExt, Peer, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
+use shared::{IpNetExt, Peer, PeerContents, RetiringKey, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
 use tiny_keccak::{Hasher, Sha3};
 use std::{
     fmt::Display, marker::PhantomData, net::IpAddr, ops::{Deref, DerefMut}, time::{Duration, SystemTime}
@@ -504,6 +504,101 @@ impl DatabasePeer<String, CrdtMap> {
             }
         }
     }
+
+    /// Replace the peer's WireGuard key. The current key is kept as a
+    /// `RetiringKey` so other peers accept it until `expires_at`.
+    pub async fn rotate_key(&mut self, new_public_key: String, expires_at: u64) -> Result<(), ServerError> {
+        if new_public_key == self.contents.public_key {
+            log::warn!("Rotation for {} reuses the current public key", self.id);
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let new_contents = PeerContents {
+            public_key: new_public_key,
+            retiring_key: Some(RetiringKey {
+                public_key: self.contents.public_key.clone(),
+                expires_at,
+            }),
+            ..self.contents.clone()
+        };
+
+        Self::submit(PeerRequest::Update(new_contents.clone())).await?;
+        self.contents = new_contents;
+        Ok(())
+    }
+
+    /// Drop retiring keys whose grace window has passed.
+    pub async fn retire_expired_keys() -> Result<usize, ServerError> {
+        let now = SystemTime::now();
+        let mut retired = 0;
+        for peer in Self::list().await? {
+            if !peer.contents.retiring_key.as_ref().is_some_and(|k| k.is_expired(now)) {
+                continue;
+            }
+            let new_contents = PeerContents {
+                retiring_key: None,
+                ..peer.contents.clone()
+            };
+            Self::submit(PeerRequest::Update(new_contents)).await?;
+            log::info!("Retired previous WireGuard key of peer {}", peer.id);
+            retired += 1;
+        }
+
+        Ok(retired)
+    }
+
+    /// Remove the peer record entirely, unlike `disable` which keeps it around
+    pub async fn delete(id: String) -> Result<(), ServerError> {
+        Self::submit(PeerRequest::Delete(id)).await
+    }
+
+    async fn submit(request: PeerRequest) -> Result<(), ServerError> {
+        #[cfg(feature = "devnet")]
+        {
+            let route = match request {
+                PeerRequest::Join(_) => "create",
+                PeerRequest::Delete(_) => "delete",
+                _ => "update",
+            };
+            let resp = reqwest::Client::new()
+                .post(format!("http://127.0.0.1:3004/user/{route}"))
+                .json(&request)
+                .send()
+                .await.map_err(|e| {
+                    log::error!("API request failed: {}", e);
+                    ServerError::InvalidQuery
+                })?
+                .json::<Response<Peer<String>>>()
+                .await.map_err(|e| {
+                    log::error!("Failed to parse API response: {}", e);
+                    ServerError::NotFound
+                })?;
+
+            match resp {
+                Response::Success(_) => Ok(()),
+                _ => Err(ServerError::NotFound),
+            }
+        }
+
+        #[cfg(not(feature = "devnet"))]
+        {
+            let request = Self::build_peer_queue_request(request)
+                .map_err(|_| ServerError::InvalidQuery)?;
+
+            let resp = reqwest::Client::new()
+                .post(format!("http://127.0.0.1:{}/queue/write_local", QUEUE_PORT))
+                .json(&request)
+                .send()
+                .await.map_err(|_| ServerError::NotFound)?
+                .json::<QueueResponse>()
+                .await.map_err(|_| ServerError::NotFound)?;
+
+            match resp {
+                QueueResponse::OpSuccess => Ok(()),
+                _ => Err(ServerError::NotFound),
+            }
+        }
+    }
 }
 
 impl DatabasePeer<i64, Sqlite> {
@@ -703,6 +798,7 @@ impl DatabasePeer<i64, Sqlite> {
                 is_redeemed,
                 invite_expires,
                 candidates,
+                retiring_key: None,
             },
         }
         .into())
@@ -103,6 +103,7 @@ pub fn populate_sql_database(conn: &Connection, db_init_data: DbInitData) -> Res
             persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
             invite_expires: None,
             candidates: vec![],
+            retiring_key: None,
         },
     )
     .map_err(|_| anyhow!("failed to create innernet peer."))?;
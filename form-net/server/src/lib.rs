@@ -25,6 +25,7 @@ pub mod initialize;
 mod test;
 mod util;
 
+pub use api::rest::admin_router;
 pub use db::{DatabaseCidr, DatabasePeer};
 pub use error::ServerError;
 use shared::{prompts, wg, CidrTree, Error, Interface};
@@ -274,6 +274,7 @@ pub fn peer_contents(
         is_redeemed: true,
         invite_expires: None,
         candidates: vec![],
+        retiring_key: None,
     })
 }
 
@@ -21,6 +21,12 @@ pub use types::*;
 pub const REDEEM_TRANSITION_WAIT: Duration = Duration::from_secs(5);
 pub const PERSISTENT_KEEPALIVE_INTERVAL_SECS: u16 = 25;
 pub const INNERNET_PUBKEY_HEADER: &str = "X-Innernet-Server-Key";
+/// How long peers accept a rotated-out WireGuard key unless the request asks otherwise
+pub const KEY_ROTATION_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+/// Upper bound on the grace window a rotation may request
+pub const KEY_ROTATION_MAX_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+/// Signed rotation requests are only accepted this long after they were issued
+pub const KEY_ROTATION_MAX_AGE: Duration = Duration::from_secs(5 * 60);
 
 pub fn ensure_dirs_exist(dirs: &[&Path]) -> Result<(), WrappedIoError> {
     for dir in dirs {
@@ -141,3 +147,79 @@ impl IpNetExt for IpNet {
             }
     }
 }
+
+/// Prefix length of a generated unique local overlay network
+pub const ULA_PREFIX_LEN: u8 = 48;
+
+/// Generates a unique local IPv6 /48 (RFC 4193) for an IPv6 overlay.
+///
+/// The 40 bit global ID is derived from the current time, the process ID and
+/// `seed` (e.g. the node's address) so that independently initialized
+/// networks are unlikely to collide if they are ever joined.
+pub fn generate_ula_cidr(seed: &str) -> IpNet {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    seed.hash(&mut hasher);
+    let global_id = hasher.finish() & 0xff_ffff_ffff;
+
+    let network = Ipv6Addr::new(
+        0xfd00 | (global_id >> 32) as u16,
+        (global_id >> 16) as u16,
+        global_id as u16,
+        0, 0, 0, 0, 0,
+    );
+    IpNet::new(IpAddr::V6(network), ULA_PREFIX_LEN).expect("ULA prefix length is valid")
+}
+
+/// Parses a formnet CIDR, where `ula` requests a newly generated unique local
+/// IPv6 network (see [`generate_ula_cidr`]).
+pub fn parse_formnet_cidr(cidr: &str, seed: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    if cidr.eq_ignore_ascii_case("ula") {
+        Ok(generate_ula_cidr(seed))
+    } else {
+        cidr.parse()
+    }
+}
+
+/// Public addresses of this host in both address families, as seen from the
+/// internet. Used alongside interface addresses as NAT traversal candidates,
+/// since a NATed host's public IPv4 never shows up on an interface.
+pub fn get_public_addrs() -> Vec<IpAddr> {
+    let (v4, v6) = publicip::get_both();
+    v4.map(IpAddr::V4).into_iter().chain(v6.map(IpAddr::V6)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_ula_cidr() {
+        let cidr = generate_ula_cidr("node");
+        assert_eq!(cidr.prefix_len(), ULA_PREFIX_LEN);
+        match cidr.network() {
+            IpAddr::V6(network) => assert_eq!(network.segments()[0] & 0xff00, 0xfd00),
+            IpAddr::V4(_) => panic!("ULA network must be IPv6"),
+        }
+
+        let first_host = cidr.hosts().find(|ip| cidr.is_assignable(ip)).unwrap();
+        assert!(cidr.contains(&first_host));
+    }
+
+    #[test]
+    fn test_parse_formnet_cidr() {
+        assert_eq!(
+            parse_formnet_cidr("10.42.0.0/16", "node").unwrap(),
+            "10.42.0.0/16".parse::<IpNet>().unwrap()
+        );
+        assert!(parse_formnet_cidr("ULA", "node").unwrap().network().is_ipv6());
+        assert!(parse_formnet_cidr("not a cidr", "node").is_err());
+    }
+}
@@ -370,6 +370,7 @@ pub fn add_peer<T: Display + Clone + PartialEq>(
         persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
         invite_expires: Some(SystemTime::now() + invite_expires.into()),
         candidates: vec![],
+        retiring_key: None,
     };
 
     Ok(
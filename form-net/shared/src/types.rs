@@ -298,6 +298,56 @@ pub struct RedeemContents {
     pub public_key: String,
 }
 
+/// A WireGuard public key that a peer has rotated away from. Other peers keep
+/// accepting it until `expires_at` (seconds since the unix epoch) so that
+/// in-flight sessions survive the switch to the new key.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RetiringKey {
+    pub public_key: String,
+    pub expires_at: u64,
+}
+
+impl RetiringKey {
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        now >= self.expires_at
+    }
+}
+
+/// A request to replace a peer's WireGuard key, signed by the operator key
+/// that owns the peer.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct KeyRotationContents {
+    pub peer_id: String,
+    pub old_public_key: String,
+    pub new_public_key: String,
+    /// Seconds since the unix epoch, requests older than `KEY_ROTATION_MAX_AGE` are rejected
+    pub issued_at: u64,
+    /// How long peers keep accepting the old key
+    pub grace_period_secs: u64,
+    /// Hex encoded ECDSA signature over `signing_payload()`
+    pub signature: String,
+    pub recovery_id: u8,
+}
+
+impl KeyRotationContents {
+    /// The bytes the operator signs. Covers every field but the signature itself.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        format!(
+            "formnet-key-rotation:{}:{}:{}:{}:{}",
+            self.peer_id,
+            self.old_public_key,
+            self.new_public_key,
+            self.issued_at,
+            self.grace_period_secs
+        )
+        .into_bytes()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
 pub struct InstallOpts {
     /// Set a specific interface name
@@ -553,6 +603,9 @@ pub struct PeerContents<T: Display + Clone + PartialEq> {
     pub invite_expires: Option<SystemTime>,
     #[serde(default)]
     pub candidates: Vec<Endpoint>,
+    /// The previous key during a key rotation, see `RetiringKey`
+    #[serde(default)]
+    pub retiring_key: Option<RetiringKey>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -983,6 +1036,7 @@ mod tests {
                 is_redeemed: true,
                 invite_expires: None,
                 candidates: vec![],
+                retiring_key: None,
             },
         };
         let builder =
@@ -1018,6 +1072,7 @@ mod tests {
                 is_redeemed: true,
                 invite_expires: None,
                 candidates: vec![],
+                retiring_key: None,
             },
         };
         let builder =
@@ -1053,6 +1108,7 @@ mod tests {
                 is_redeemed: true,
                 invite_expires: None,
                 candidates: vec![],
+                retiring_key: None,
             },
         };
         let builder =
@@ -1,7 +1,7 @@
 use crate::{Error, IoErrorContext, NetworkOpts, Peer, PeerDiff};
 use ipnet::IpNet;
 use std::{
-    fmt::Display, io, net::{IpAddr, SocketAddr}, time::Duration
+    fmt::Display, io, net::{IpAddr, SocketAddr}, time::{Duration, SystemTime}
 };
 use wireguard_control::{
     Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
@@ -204,9 +204,17 @@ impl DeviceExt for Device {
         });
 
         // Remove any peers on the interface that aren't in the server's peer list any more.
+        // A key that a peer is rotating away from stays until its grace window ends, so
+        // sessions using it keep working while the new key propagates.
+        let now = SystemTime::now();
         let removals = existing_peers.iter().filter_map(|existing| {
             let public_key = existing.config.public_key.to_base64();
-            if peers.iter().any(|p| p.public_key == public_key) {
+            if peers.iter().any(|p| {
+                p.public_key == public_key
+                    || p.retiring_key
+                        .as_ref()
+                        .is_some_and(|k| k.public_key == public_key && !k.is_expired(now))
+            }) {
                 None
             } else {
                 PeerDiff::new(Some(existing), None).unwrap()
@@ -0,0 +1,60 @@
+// benchmark.rs
+// Short CPU and memory benchmarks run once at startup, so placement can tell
+// a fast node from a slow one with the same core count and memory size.
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+/// How long each benchmark runs for
+const RUN_TIME: Duration = Duration::from_millis(250);
+/// Buffer hashed by the CPU benchmark
+const HASH_BLOCK_BYTES: usize = 1024 * 1024;
+/// Buffer copied by the memory benchmark, larger than any CPU cache
+const COPY_BLOCK_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BenchmarkScores {
+    /// Single core SHA-256 throughput in MB/s
+    pub cpu_sha256_mbps: u64,
+    /// Memory copy throughput in MB/s
+    pub memory_copy_mbps: u64,
+}
+
+impl BenchmarkScores {
+    /// Runs the benchmarks, which takes about half a second
+    pub fn run() -> Self {
+        Self {
+            cpu_sha256_mbps: cpu_sha256_mbps(),
+            memory_copy_mbps: memory_copy_mbps(),
+        }
+    }
+}
+
+fn mbps(bytes: usize, elapsed: Duration) -> u64 {
+    (bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64().max(f64::EPSILON)).round() as u64
+}
+
+fn cpu_sha256_mbps() -> u64 {
+    let block = vec![0x5au8; HASH_BLOCK_BYTES];
+    let start = Instant::now();
+    let mut hashed = 0;
+    while start.elapsed() < RUN_TIME {
+        std::hint::black_box(Sha256::digest(&block));
+        hashed += block.len();
+    }
+    mbps(hashed, start.elapsed())
+}
+
+fn memory_copy_mbps() -> u64 {
+    let source = vec![0xa5u8; COPY_BLOCK_BYTES];
+    let mut destination = vec![0u8; COPY_BLOCK_BYTES];
+    let start = Instant::now();
+    let mut copied = 0;
+    while start.elapsed() < RUN_TIME {
+        destination.copy_from_slice(std::hint::black_box(&source));
+        std::hint::black_box(&destination);
+        copied += source.len();
+    }
+    mbps(copied, start.elapsed())
+}
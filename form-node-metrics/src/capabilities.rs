@@ -3,6 +3,9 @@ use nvml_wrapper::Nvml;
 use serde::{Serialize, Deserialize};
 use pnet::datalink;
 
+use crate::benchmark::BenchmarkScores;
+use crate::network::{NatType, NetworkProfile};
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct NodeCapabilities {
     pub cpu_model: String,
@@ -14,7 +17,15 @@ pub struct NodeCapabilities {
     pub tpm: Option<TpmInfo>,
     pub sgx: Option<SgxInfo>,
     pub sev: Option<SevInfo>,
+    pub tdx: Option<TdxInfo>,
     pub virtualization_type: Option<String>,
+    /// Scores of the benchmarks run at startup
+    #[serde(default)]
+    pub benchmark: Option<BenchmarkScores>,
+    /// Public address, NAT and bandwidth as probed against the bootstrap
+    /// nodes at startup
+    #[serde(default)]
+    pub network: Option<NetworkProfile>,
 }
 
 // Optionally, an implementation to gather this info at startup:
@@ -59,10 +70,29 @@ impl NodeCapabilities {
             network_interfaces,
             tpm,
             sgx: None,
-            sev: None,
+            sev: detect_sev(),
+            tdx: detect_tdx(),
             virtualization_type: Some(virtualization_type),
+            benchmark: Some(BenchmarkScores::run()),
+            network: None,
         }
     }
+
+    /// Public bandwidth in Mbit/s: what the network probe measured, or the
+    /// link speed of the fastest active interface if it measured nothing
+    pub fn bandwidth_mbps(&self) -> Option<u64> {
+        self.network.as_ref()
+            .and_then(NetworkProfile::bandwidth_mbps)
+            .or_else(|| self.network_interfaces.iter()
+                .filter(|iface| iface.is_active)
+                .filter_map(|iface| iface.link_speed_mbps)
+                .max())
+    }
+
+    /// Whether the node has a public address that isn't behind NAT
+    pub fn is_publicly_reachable(&self) -> bool {
+        self.network.as_ref().is_some_and(|network| network.nat == NatType::Open)
+    }
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -336,3 +366,71 @@ pub struct SevInfo {
     // Potentially a list of extended features, if the SEV crate provides them
     pub platform_status: Option<String>, // e.g. "Initialized", "Working", etc.
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TdxInfo {
+    pub supported: bool,          // CPU advertises TDX host support (tdx_host_platform)
+    pub kvm_enabled: bool,        // kvm_intel was loaded with tdx=Y, so TDs can be launched
+    pub module_version: Option<String>, // version of the TDX module loaded by the kernel
+}
+
+impl TdxInfo {
+    pub fn can_launch(&self) -> bool {
+        self.supported && self.kvm_enabled
+    }
+}
+
+impl SevInfo {
+    pub fn can_launch_snp(&self) -> bool {
+        self.supported && self.sev_snp_supported
+    }
+}
+
+/// Whether a boolean kvm module parameter is switched on
+fn kvm_param_enabled(module: &str, param: &str) -> bool {
+    std::fs::read_to_string(format!("/sys/module/{module}/parameters/{param}"))
+        .is_ok_and(|value| matches!(value.trim(), "Y" | "y" | "1"))
+}
+
+fn cpu_flags() -> Vec<String> {
+    std::fs::read_to_string("/proc/cpuinfo")
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| line.strip_prefix("flags")?.trim_start().strip_prefix(':').map(str::to_string))
+        .map(|flags| flags.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// SEV support as KVM sees it: the flags of kvm_amd only read `Y` when the
+/// CPU, the firmware and the BIOS all allow it
+fn detect_sev() -> Option<SevInfo> {
+    if !std::path::Path::new("/sys/module/kvm_amd").exists() {
+        return None;
+    }
+    let supported = kvm_param_enabled("kvm_amd", "sev");
+    Some(SevInfo {
+        supported,
+        sev_es_supported: supported && kvm_param_enabled("kvm_amd", "sev_es"),
+        sev_snp_supported: supported && kvm_param_enabled("kvm_amd", "sev_snp"),
+        firmware_version: None,
+        api_major: None,
+        api_minor: None,
+        min_api_major: None,
+        min_api_minor: None,
+        platform_status: std::path::Path::new("/dev/sev").exists().then(|| "Available".to_string()),
+    })
+}
+
+fn detect_tdx() -> Option<TdxInfo> {
+    if !std::path::Path::new("/sys/module/kvm_intel").exists() {
+        return None;
+    }
+    let supported = cpu_flags().iter().any(|flag| flag == "tdx_host_platform");
+    Some(TdxInfo {
+        supported,
+        kvm_enabled: kvm_param_enabled("kvm_intel", "tdx"),
+        module_version: std::fs::read_to_string("/sys/firmware/tdx/tdx_module/version")
+            .ok()
+            .map(|version| version.trim().to_string()),
+    })
+}
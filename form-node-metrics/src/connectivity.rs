@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/// Connection quality to a single formnet peer, as measured by formnet on
+/// this node. Times are in microseconds and loss in basis points so the
+/// summary can live in the replicated node record.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerQuality {
+    /// Formnet peer id (node address or instance name)
+    pub peer_id: String,
+    /// The peer's formnet address
+    pub formnet_ip: Option<String>,
+    /// The endpoint the WireGuard session is currently using
+    pub endpoint: Option<String>,
+    /// Average round trip time of the last probe
+    pub rtt_us: Option<u64>,
+    /// Mean deviation of the round trip time of the last probe
+    pub jitter_us: Option<u64>,
+    /// Share of probe packets lost, 10_000 is 100%
+    pub loss_bps: u32,
+    /// Unix timestamp of the last WireGuard handshake
+    pub last_handshake: Option<i64>,
+    /// Bytes received from the peer since the interface came up
+    pub rx_bytes: u64,
+    /// Bytes sent to the peer since the interface came up
+    pub tx_bytes: u64,
+    pub rx_bytes_per_sec: u64,
+    pub tx_bytes_per_sec: u64,
+    /// Unix timestamp of the sample
+    pub sampled_at: i64,
+}
+
+impl PeerQuality {
+    /// A peer is reachable if it answered probes or shook hands recently
+    pub fn is_reachable(&self, now: i64, handshake_ttl: i64) -> bool {
+        let recent_handshake = match self.last_handshake {
+            Some(handshake) => now - handshake <= handshake_ttl,
+            None => false,
+        };
+        (self.rtt_us.is_some() && self.loss_bps < 10_000) || recent_handshake
+    }
+}
+
+/// Per peer connection quality reported by a node
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectivityMetrics {
+    pub peers: BTreeMap<String, PeerQuality>,
+    /// Unix timestamp of the newest sample
+    pub updated_at: i64,
+}
+
+impl ConnectivityMetrics {
+    pub fn rtt_to(&self, peer_id: &str) -> Option<u64> {
+        self.peers.get(peer_id).and_then(|peer| peer.rtt_us)
+    }
+
+    /// Median round trip time across the peers that answered probes, a
+    /// rough measure of how central the node is in the network
+    pub fn median_rtt_us(&self) -> Option<u64> {
+        let mut rtts: Vec<u64> = self.peers.values().filter_map(|peer| peer.rtt_us).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        rtts.sort_unstable();
+        Some(rtts[rtts.len() / 2])
+    }
+
+    /// Average probe loss across all peers, in basis points
+    pub fn average_loss_bps(&self) -> u32 {
+        if self.peers.is_empty() {
+            return 0;
+        }
+        let total: u64 = self.peers.values().map(|peer| u64::from(peer.loss_bps)).sum();
+        (total / self.peers.len() as u64) as u32
+    }
+}
@@ -0,0 +1,253 @@
+use std::{collections::BTreeSet, process::Command, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+/// ATA attributes read from the SMART attribute table
+const ATA_REALLOCATED_SECTORS: u64 = 5;
+const ATA_PENDING_SECTORS: u64 = 197;
+/// Remaining life attributes, the normalized value counts down from 100
+const ATA_WEAR_ATTRIBUTES: [u64; 3] = [177, 231, 233];
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskProtocol {
+    Ata,
+    Nvme,
+    Scsi,
+    #[default]
+    Unknown,
+}
+
+/// SMART health of one disk. Fields a disk doesn't report are None.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DiskHealth {
+    /// Device path, e.g. /dev/nvme0
+    pub device: String,
+    pub model: Option<String>,
+    pub protocol: DiskProtocol,
+    /// Whether the disk's own overall health assessment passed
+    pub smart_passed: Option<bool>,
+    /// in °C
+    pub temperature: Option<u32>,
+    pub reallocated_sectors: Option<u64>,
+    /// Sectors waiting to be reallocated after read errors
+    pub pending_sectors: Option<u64>,
+    /// Share of the rated endurance used, may go past 100
+    pub wear_percent: Option<u32>,
+    /// Unrecovered data integrity errors reported by NVMe disks
+    pub media_errors: Option<u64>,
+    pub power_on_hours: Option<u64>,
+}
+
+impl DiskHealth {
+    /// Reads the JSON `smartctl --json --all` prints for a device
+    pub fn from_smartctl(device: &str, report: &Value) -> Self {
+        let protocol = match report["device"]["protocol"].as_str().unwrap_or_default().to_lowercase().as_str() {
+            "ata" => DiskProtocol::Ata,
+            "nvme" => DiskProtocol::Nvme,
+            "scsi" => DiskProtocol::Scsi,
+            _ => DiskProtocol::Unknown,
+        };
+
+        let attributes = report["ata_smart_attributes"]["table"].as_array().cloned().unwrap_or_default();
+        let attribute = |id: u64| attributes.iter().find(|attribute| attribute["id"].as_u64() == Some(id));
+        let raw = |id: u64| attribute(id).and_then(|attribute| attribute["raw"]["value"].as_u64());
+
+        let nvme = &report["nvme_smart_health_information_log"];
+        let ata_wear = ATA_WEAR_ATTRIBUTES.iter()
+            .find_map(|id| attribute(*id))
+            .and_then(|attribute| attribute["value"].as_u64())
+            .map(|remaining| 100u64.saturating_sub(remaining) as u32);
+
+        DiskHealth {
+            device: device.to_string(),
+            model: report["model_name"].as_str().map(str::to_string),
+            protocol,
+            smart_passed: report["smart_status"]["passed"].as_bool(),
+            temperature: report["temperature"]["current"].as_u64().map(|t| t as u32),
+            reallocated_sectors: raw(ATA_REALLOCATED_SECTORS),
+            pending_sectors: raw(ATA_PENDING_SECTORS),
+            wear_percent: nvme["percentage_used"].as_u64().map(|p| p as u32).or(ata_wear),
+            media_errors: nvme["media_errors"].as_u64(),
+            power_on_hours: report["power_on_time"]["hours"].as_u64(),
+        }
+    }
+
+    /// What about the disk needs an operator's attention
+    pub fn alerts(&self, thresholds: &DiskThresholds) -> Vec<DiskAlert> {
+        let mut alerts = Vec::new();
+        let mut raise = |kind: DiskAlertKind, message: String| alerts.push(DiskAlert {
+            device: self.device.clone(),
+            kind,
+            message,
+        });
+
+        if self.smart_passed == Some(false) {
+            raise(DiskAlertKind::SmartFailed, format!("{} failed its SMART self-assessment", self.device));
+        }
+        if let Some(sectors) = self.reallocated_sectors.filter(|s| *s > thresholds.max_reallocated_sectors) {
+            raise(DiskAlertKind::ReallocatedSectors, format!("{} has reallocated {} sectors", self.device, sectors));
+        }
+        if let Some(sectors) = self.pending_sectors.filter(|s| *s > 0) {
+            raise(DiskAlertKind::PendingSectors, format!("{} has {} sectors pending reallocation", self.device, sectors));
+        }
+        if let Some(errors) = self.media_errors.filter(|e| *e > 0) {
+            raise(DiskAlertKind::MediaErrors, format!("{} reported {} media errors", self.device, errors));
+        }
+        if let Some(wear) = self.wear_percent.filter(|w| *w >= thresholds.max_wear_percent) {
+            raise(DiskAlertKind::Worn, format!("{} has used {}% of its rated endurance", self.device, wear));
+        }
+        if let Some(temperature) = self.temperature.filter(|t| *t >= thresholds.max_temperature) {
+            raise(DiskAlertKind::Overheating, format!("{} is at {}°C", self.device, temperature));
+        }
+        alerts
+    }
+}
+
+/// Values past which a disk is reported
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiskThresholds {
+    pub max_temperature: u32,
+    pub max_wear_percent: u32,
+    pub max_reallocated_sectors: u64,
+}
+
+impl Default for DiskThresholds {
+    fn default() -> Self {
+        Self {
+            max_temperature: 60,
+            max_wear_percent: 90,
+            max_reallocated_sectors: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskAlertKind {
+    SmartFailed,
+    ReallocatedSectors,
+    PendingSectors,
+    MediaErrors,
+    Worn,
+    Overheating,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DiskAlert {
+    pub device: String,
+    pub kind: DiskAlertKind,
+    pub message: String,
+}
+
+/// Called with every alert when it is first raised
+pub type DiskAlertHook = Box<dyn Fn(&DiskAlert) + Send + Sync>;
+
+/// Reads SMART data with smartctl on its own, slower cadence than the other
+/// metrics and raises alerts as disks cross the thresholds
+pub struct DiskHealthMonitor {
+    interval: Duration,
+    thresholds: DiskThresholds,
+    hooks: Vec<DiskAlertHook>,
+    last_scan: Option<Instant>,
+    disks: Vec<DiskHealth>,
+    /// Alerts raised and not cleared yet, so each one only fires once
+    active: BTreeSet<(String, DiskAlertKind)>,
+}
+
+impl DiskHealthMonitor {
+    pub fn new(interval: Duration, thresholds: DiskThresholds) -> Self {
+        Self {
+            interval,
+            thresholds,
+            hooks: Vec::new(),
+            last_scan: None,
+            disks: Vec::new(),
+            active: BTreeSet::new(),
+        }
+    }
+
+    /// Adds a hook run for each newly raised alert, alerts are always logged
+    pub fn with_hook(mut self, hook: DiskAlertHook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Sends each newly raised alert as JSON to `url`
+    pub fn with_webhook(self, url: String) -> Self {
+        let client = reqwest::Client::new();
+        self.with_hook(Box::new(move |alert| {
+            let Ok(handle) = tokio::runtime::Handle::try_current() else {
+                return;
+            };
+            let request = client.post(&url).json(alert);
+            handle.spawn(async move {
+                if let Err(e) = request.send().await {
+                    log::error!("Unable to send disk alert: {e}");
+                }
+            });
+        }))
+    }
+
+    /// The latest disk health, rescanned if the interval passed
+    pub fn poll(&mut self) -> Vec<DiskHealth> {
+        if self.last_scan.map_or(true, |last| last.elapsed() >= self.interval) {
+            self.last_scan = Some(Instant::now());
+            match scan() {
+                Ok(disks) => {
+                    self.disks = disks;
+                    self.raise_alerts();
+                }
+                Err(e) => log::debug!("Unable to read SMART data: {e}"),
+            }
+        }
+        self.disks.clone()
+    }
+
+    fn raise_alerts(&mut self) {
+        let alerts: Vec<DiskAlert> = self.disks.iter().flat_map(|disk| disk.alerts(&self.thresholds)).collect();
+        let current: BTreeSet<_> = alerts.iter().map(|alert| (alert.device.clone(), alert.kind)).collect();
+        for alert in alerts.iter().filter(|alert| !self.active.contains(&(alert.device.clone(), alert.kind))) {
+            log::warn!("Disk alert: {}", alert.message);
+            for hook in &self.hooks {
+                hook(alert);
+            }
+        }
+        for (device, kind) in self.active.difference(&current) {
+            log::info!("Disk alert {:?} on {} cleared", kind, device);
+        }
+        self.active = current;
+    }
+}
+
+fn smartctl(args: &[&str]) -> Result<Value, String> {
+    let output = Command::new("smartctl")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Unable to run smartctl: {e}"))?;
+    // The exit status is a bit mask that is also set for failing disks, the
+    // JSON is printed either way
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("Unable to parse smartctl output: {e}"))
+}
+
+/// Reads the health of every disk smartctl finds
+pub fn scan() -> Result<Vec<DiskHealth>, String> {
+    let devices = smartctl(&["--scan", "--json"])?;
+    let devices = devices["devices"].as_array().cloned().unwrap_or_default();
+    Ok(devices.iter().filter_map(|device| {
+        let name = device["name"].as_str()?;
+        let mut args = vec!["--all", "--json"];
+        if let Some(kind) = device["type"].as_str() {
+            args.extend(["--device", kind]);
+        }
+        args.push(name);
+        match smartctl(&args) {
+            Ok(report) => Some(DiskHealth::from_smartctl(name, &report)),
+            Err(e) => {
+                log::warn!("Unable to read SMART data of {name}: {e}");
+                None
+            }
+        }
+    }).collect())
+}
@@ -1,16 +1,35 @@
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use form_p2p::{queue::QUEUE_PORT, status::QueueHealth};
+use reqwest::Client;
 use tokio::time::interval;
 use crate::{util::write_to_queue, NodeMetricsRequest};
 
 pub async fn heartbeat(refresh: Duration, node_id: String) {
     let mut interval = interval(refresh);
+    let client = Client::new();
     loop {
         interval.tick().await;
         if let Ok(timestamp) = SystemTime::now().duration_since(UNIX_EPOCH) {
-            let heartbeat_request = NodeMetricsRequest::Heartbeat { node_id: node_id.clone(), timestamp: timestamp.as_secs() as i64 };
+            let queue = match queue_health(&client).await {
+                Ok(health) => Some(health),
+                Err(e) => {
+                    log::warn!("Unable to read queue status for heartbeat: {e}");
+                    None
+                }
+            };
+            let heartbeat_request = NodeMetricsRequest::Heartbeat { node_id: node_id.clone(), timestamp: timestamp.as_secs() as i64, queue };
             if let Err(e) = write_to_queue(heartbeat_request).await {
                 log::error!("Error writing to queue: {e}");
             }
         }
     }
 }
+
+async fn queue_health(client: &Client) -> Result<QueueHealth, reqwest::Error> {
+    client.get(format!("http://127.0.0.1:{QUEUE_PORT}/admin/status/summary"))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await?
+        .json::<QueueHealth>()
+        .await
+}
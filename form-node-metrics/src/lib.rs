@@ -1,9 +1,13 @@
 use serde::{Serialize, Deserialize};
 
+pub mod benchmark;
 pub mod capabilities;
 pub mod capacity;
 pub mod metrics;
+pub mod connectivity;
+pub mod disk_health;
 pub mod heartbeat;
+pub mod network;
 pub mod util;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -21,5 +25,13 @@ pub enum NodeMetricsRequest {
     Heartbeat {
         node_id: String,
         timestamp: i64,
-    }
+        /// Replication health of the node's queue, if it could be read
+        #[serde(default)]
+        queue: Option<form_p2p::status::QueueHealth>,
+    },
+    /// Per peer connection quality measured by formnet on the node
+    Connectivity {
+        node_id: String,
+        connectivity: crate::connectivity::ConnectivityMetrics,
+    },
 }
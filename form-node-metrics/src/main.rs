@@ -2,7 +2,7 @@ use std::{path::PathBuf, time::Duration};
 use alloy_primitives::Address;
 use clap::Parser;
 use form_config::OperatorConfig;
-use form_node_metrics::{capabilities::NodeCapabilities, capacity::start_capacity_monitor, heartbeat::heartbeat, metrics::start_metrics_monitor, util::{report_initial_metrics, report_metrics}};
+use form_node_metrics::{capabilities::NodeCapabilities, capacity::start_capacity_monitor, disk_health::{DiskHealthMonitor, DiskThresholds}, heartbeat::heartbeat, metrics::start_metrics_monitor, network::NetworkProfile, util::{report_initial_metrics, report_metrics}};
 use k256::ecdsa::SigningKey;
 use tokio::sync::broadcast::channel;
 
@@ -13,8 +13,23 @@ pub struct Cli {
     #[clap(long, short, default_value_t=true)]
     encrypted: bool,
     #[clap(long, short='P')]
-    password: String
-    
+    password: String,
+    /// Seconds between SMART reads of the disks
+    #[clap(long, default_value_t=300)]
+    smart_interval: u64,
+    /// Disk temperature in °C past which an alert is raised
+    #[clap(long, default_value_t=60)]
+    disk_max_temperature: u32,
+    /// Share of rated disk endurance used past which an alert is raised
+    #[clap(long, default_value_t=90)]
+    disk_max_wear: u32,
+    /// URL disk alerts are POSTed to as JSON when raised
+    #[clap(long)]
+    disk_alert_webhook: Option<String>,
+    /// MiB transferred each way to measure bandwidth against a bootstrap
+    /// node at startup, 0 to skip the measurement
+    #[clap(long, default_value_t=16)]
+    bandwidth_probe_mb: u64,
 }
 
 #[tokio::main]
@@ -29,9 +44,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let (tx, mut rx) = channel(2);
 
-    let capabilities = NodeCapabilities::collect();
+    let mut capabilities = NodeCapabilities::collect();
+    capabilities.network = Some(NetworkProfile::probe(&config.bootstrap_nodes, parser.bandwidth_probe_mb << 20).await);
     let capacity = start_capacity_monitor(Duration::from_secs(30)).await;
-    let metrics = start_metrics_monitor(Duration::from_secs(30)).await;
+    let thresholds = DiskThresholds {
+        max_temperature: parser.disk_max_temperature,
+        max_wear_percent: parser.disk_max_wear,
+        ..Default::default()
+    };
+    let mut disks = DiskHealthMonitor::new(Duration::from_secs(parser.smart_interval), thresholds);
+    if let Some(url) = parser.disk_alert_webhook.clone() {
+        disks = disks.with_webhook(url);
+    }
+    let metrics = start_metrics_monitor(Duration::from_secs(30), disks).await;
 
     report_initial_metrics(capabilities, capacity.clone(), node_id.clone()).await;
 
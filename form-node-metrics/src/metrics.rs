@@ -5,7 +5,9 @@ use sysinfo::{ProcessesToUpdate, System};
 use nvml_wrapper::Nvml;
 use tokio::{sync::Mutex, time::interval};  // using NVML for GPU metrics (optional feature)
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+use crate::disk_health::{DiskHealth, DiskHealthMonitor};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct NodeMetrics {
     // Load averages (1, 5, 15 minute)
     pub load_avg_1: i64,
@@ -25,18 +27,23 @@ pub struct NodeMetrics {
     pub cpu_temperature: Option<u32>,   // in °C
     pub gpu_temperature: Option<u32>,   // in °C (if applicable)
     pub power_usage_watts: Option<u32>, // in Watts (if available)
+
+    // SMART health per disk, refreshed less often than the rest
+    #[serde(default)]
+    pub disk_health: Vec<DiskHealth>,
 }
 
 pub struct MetricsCollector {
     sys: System,
     nvml: Option<Nvml>,   // NVML handle for GPU info (if available)
     last_update: std::time::Instant,
-    interval: Duration
+    interval: Duration,
+    disks: DiskHealthMonitor,
 }
 
 impl MetricsCollector {
     /// Create a new MetricsCollector, initializing system info and NVML (if enabled).
-    pub fn new(refresh: Duration) -> Self {
+    pub fn new(refresh: Duration, disks: DiskHealthMonitor) -> Self {
         let mut sys = System::new_all();  // initialize and load all info once
         sys.refresh_all();               // initial refresh to populate data
         let nvml = Nvml::init().ok();
@@ -44,7 +51,8 @@ impl MetricsCollector {
             sys,
             nvml,
             last_update: std::time::Instant::now(),
-            interval: refresh
+            interval: refresh,
+            disks,
         }
     }
 
@@ -114,6 +122,8 @@ impl MetricsCollector {
             }
         }
 
+        let disk_health = self.disks.poll();
+
         self.last_update = Instant::now();
 
         // Build the NodeMetrics struct with collected values
@@ -129,12 +139,13 @@ impl MetricsCollector {
             cpu_temperature: cpu_temp,
             gpu_temperature: gpu_temp,
             power_usage_watts: power_watts,
+            disk_health,
         }
     }
 }
 
-pub async fn start_metrics_monitor(refresh: Duration) -> Arc<Mutex<NodeMetrics>> {
-    let metrics_collector = Arc::new(Mutex::new(MetricsCollector::new(refresh.clone())));
+pub async fn start_metrics_monitor(refresh: Duration, disks: DiskHealthMonitor) -> Arc<Mutex<NodeMetrics>> {
+    let metrics_collector = Arc::new(Mutex::new(MetricsCollector::new(refresh.clone(), disks)));
 
     let mut guard = metrics_collector.lock().await;
     let node_metrics = Arc::new(Mutex::new(guard.collect()));
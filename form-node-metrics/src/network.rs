@@ -0,0 +1,197 @@
+// network.rs
+// Probes the node's public network once at startup: the address bootstrap
+// nodes see it connect from, how its NAT maps ports, and the throughput of
+// a transfer to and from a bootstrap node. Placement and relay selection use
+// the result to tell well connected nodes from ones behind NAT.
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pnet::datalink;
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpSocket;
+
+/// Port of the form-state API on the bootstrap nodes
+const STATE_API_PORT: u16 = 3004;
+/// Timeout of the address probes
+const ADDR_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeout of each bandwidth transfer
+const BANDWIDTH_PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest transfer a bootstrap node serves or accepts
+pub const MAX_PROBE_BYTES: u64 = 64 * 1024 * 1024;
+
+type ProbeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How the node is reachable from the internet
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatType {
+    /// The public address is on one of the node's interfaces
+    Open,
+    /// Behind NAT that maps a local port to the same public port whatever
+    /// the destination, peers reach it after punching a hole
+    EndpointIndependent,
+    /// Behind NAT that maps every destination to another public port,
+    /// peers usually need a relay to reach it
+    EndpointDependent,
+    /// Behind NAT, but a single bootstrap node answered so the mapping
+    /// couldn't be told
+    Undetermined,
+    /// No bootstrap node answered
+    #[default]
+    Unknown,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NetworkProfile {
+    /// The address bootstrap nodes see the node connect from
+    pub public_ip: Option<String>,
+    pub nat: NatType,
+    /// Whether the public port of the probes was their local port
+    pub port_preserved: Option<bool>,
+    /// Throughput from and to a bootstrap node in Mbit/s
+    pub download_mbps: Option<u64>,
+    pub upload_mbps: Option<u64>,
+    /// Bootstrap node the throughput was measured against
+    pub probed_against: Option<String>,
+    /// Unix timestamp of the probe
+    pub probed_at: i64,
+}
+
+impl NetworkProfile {
+    /// Probes through `bootstrap_nodes`, transferring `probe_bytes` each way
+    /// to measure bandwidth. A `probe_bytes` of 0 skips the transfers.
+    pub async fn probe(bootstrap_nodes: &[String], probe_bytes: u64) -> Self {
+        let mut profile = Self {
+            probed_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64),
+            ..Default::default()
+        };
+        if bootstrap_nodes.is_empty() {
+            log::info!("No bootstrap nodes configured, skipping the network probe");
+            return profile;
+        }
+
+        // Every probe leaves from the port the first one was given, so the
+        // public ports show how the NAT maps it
+        let mut local_port = 0;
+        let mut observed = vec![];
+        let mut answered = None;
+        for host in bootstrap_nodes {
+            match observed_addr(host, local_port).await {
+                Ok((local, public)) => {
+                    local_port = local.port();
+                    observed.push(public);
+                    answered.get_or_insert(host.clone());
+                }
+                Err(e) => log::warn!("Unable to probe the public address through {host}: {e}"),
+            }
+            if observed.len() == 2 {
+                break;
+            }
+        }
+
+        let local_ips: Vec<IpAddr> = datalink::interfaces().iter()
+            .flat_map(|iface| iface.ips.iter().map(|ip| ip.ip()))
+            .collect();
+        let (nat, port_preserved) = classify(&local_ips, local_port, &observed);
+        profile.public_ip = observed.first().map(|addr| addr.ip().to_string());
+        profile.nat = nat;
+        profile.port_preserved = port_preserved;
+
+        let Some(host) = answered else {
+            return profile;
+        };
+        if probe_bytes > 0 {
+            let client = Client::new();
+            let bytes = probe_bytes.min(MAX_PROBE_BYTES);
+            match download_mbps(&client, &host, bytes).await {
+                Ok(mbps) => profile.download_mbps = Some(mbps),
+                Err(e) => log::warn!("Unable to measure download bandwidth from {host}: {e}"),
+            }
+            match upload_mbps(&client, &host, bytes).await {
+                Ok(mbps) => profile.upload_mbps = Some(mbps),
+                Err(e) => log::warn!("Unable to measure upload bandwidth to {host}: {e}"),
+            }
+            profile.probed_against = Some(host);
+        }
+        log::info!("Network probe: {profile:?}");
+        profile
+    }
+
+    /// The lower of the measured download and upload throughput
+    pub fn bandwidth_mbps(&self) -> Option<u64> {
+        match (self.download_mbps, self.upload_mbps) {
+            (Some(download), Some(upload)) => Some(download.min(upload)),
+            (download, upload) => download.or(upload),
+        }
+    }
+}
+
+/// NAT behaviour from the public addresses bootstrap nodes saw for
+/// connections from `local_port`
+fn classify(local_ips: &[IpAddr], local_port: u16, observed: &[SocketAddr]) -> (NatType, Option<bool>) {
+    let Some(first) = observed.first() else {
+        return (NatType::Unknown, None);
+    };
+    let port_preserved = observed.iter().all(|addr| addr.port() == local_port);
+    let nat = if local_ips.contains(&first.ip()) {
+        NatType::Open
+    } else if observed.len() < 2 {
+        NatType::Undetermined
+    } else if observed.iter().all(|addr| addr == first) {
+        NatType::EndpointIndependent
+    } else {
+        NatType::EndpointDependent
+    };
+    (nat, Some(port_preserved))
+}
+
+/// Connects to the bootstrap node's form-state from `local_port`, 0 for
+/// any, and returns the local address and the address the node saw
+async fn observed_addr(host: &str, local_port: u16) -> Result<(SocketAddr, SocketAddr), ProbeError> {
+    let remote = tokio::net::lookup_host((host, STATE_API_PORT)).await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or("no IPv4 address")?;
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(SocketAddr::from(([0, 0, 0, 0], local_port)))?;
+    let mut stream = tokio::time::timeout(ADDR_PROBE_TIMEOUT, socket.connect(remote)).await??;
+    let local = stream.local_addr()?;
+
+    // reqwest can't pin the local port, so the request is written by hand
+    let request = format!("GET /v1/probe/addr HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    tokio::time::timeout(ADDR_PROBE_TIMEOUT, stream.read_to_end(&mut response)).await??;
+    let response = String::from_utf8_lossy(&response);
+    let (_, body) = response.split_once("\r\n\r\n").ok_or("malformed response")?;
+    let body: serde_json::Value = serde_json::from_str(body)?;
+    let public = body["addr"].as_str().ok_or("no address in response")?.parse()?;
+    Ok((local, public))
+}
+
+fn megabits_per_sec(bytes: usize, elapsed: Duration) -> u64 {
+    (bytes as f64 * 8.0 / 1_000_000.0 / elapsed.as_secs_f64().max(f64::EPSILON)).round() as u64
+}
+
+async fn download_mbps(client: &Client, host: &str, bytes: u64) -> Result<u64, ProbeError> {
+    let start = Instant::now();
+    let body = client.get(format!("http://{host}:{STATE_API_PORT}/v1/probe/download?bytes={bytes}"))
+        .timeout(BANDWIDTH_PROBE_TIMEOUT)
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+    Ok(megabits_per_sec(body.len(), start.elapsed()))
+}
+
+async fn upload_mbps(client: &Client, host: &str, bytes: u64) -> Result<u64, ProbeError> {
+    let payload = vec![0u8; bytes as usize];
+    let start = Instant::now();
+    client.post(format!("http://{host}:{STATE_API_PORT}/v1/probe/upload"))
+        .timeout(BANDWIDTH_PROBE_TIMEOUT)
+        .body(payload)
+        .send().await?
+        .error_for_status()?;
+    Ok(megabits_per_sec(bytes as usize, start.elapsed()))
+}
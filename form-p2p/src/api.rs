@@ -1,5 +1,5 @@
-use std::sync::Arc;
-use axum::{body::Body, extract::{Path, State}, routing::{get, post}, Json, Router};
+use std::{net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
+use axum::{body::Body, extract::{ConnectInfo, Path, State}, http::HeaderMap, routing::{get, post}, Json, Router};
 use crdts::{bft_topic_queue::TopicQueue, merkle_reg::Sha3Hash};
 use form_types::state::{Response as StateResponse, Success};
 use reqwest::Client;
@@ -12,7 +12,13 @@ use axum::{
 };
 use bytes::Bytes;
 use futures::StreamExt;
-use crate::{db::{store_topic_queue, open_db}, queue::{FormMQ, QueueRequest, QueueResponse, QUEUE_PORT}};
+use crate::{
+    db::{store_topic_queue, open_db},
+    delta::{build_chunk, topic_heads, DeltaChunk, DeltaRequest},
+    queue::{FormMQ, QueueRequest, QueueResponse, QUEUE_PORT},
+    status::{unix_now, BootstrapState, ForceSyncReport, QueueHealth, QueueStatus},
+    trace::{message_key, new_correlation_id, Trace, TraceEvent, TraceRecord, TraceRegistration, CORRELATION_HEADER},
+};
 use std::path::PathBuf;
 use lazy_static::lazy_static;
 use redb::Database;
@@ -22,7 +28,99 @@ lazy_static! {
 }
 
 
+/// Timeout of each delta sync chunk
+const DELTA_CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Catches the queue up with `dial`, recording the outcome for
+/// `/admin/status`. Only the topics this node is behind on are pulled, the
+/// full queue is pulled from peers that don't serve delta sync.
 pub async fn bootstrap_topic_queue(dial: String, queue: Arc<RwLock<FormMQ<Vec<u8>>>>) -> Result<(), Box<dyn std::error::Error>> {
+    let result = match delta_sync(&dial, &queue).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            log::info!("{dial} doesn't serve delta sync, pulling the full queue");
+            match fetch_topic_queue(&dial).await {
+                Ok((received, bytes)) => {
+                    let mut guard = queue.write().await;
+                    guard.merge(received);
+                    guard.sync_mut().record_bytes(&dial, bytes);
+                    Ok(())
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Err(e) => Err(e.to_string()),
+    };
+
+    let mut guard = queue.write().await;
+    match result {
+        Ok(()) => {
+            guard.sync_mut().record_merge(&dial);
+            Ok(())
+        }
+        Err(reason) => {
+            guard.sync_mut().record_merge_failure(&dial, reason.clone());
+            Err(reason.into())
+        }
+    }
+}
+
+/// Pulls the topics this node is behind on from `dial` chunk by chunk,
+/// resuming after the last chunk merged if an earlier sync was interrupted.
+/// Returns false if `dial` doesn't serve delta sync.
+async fn delta_sync(dial: &str, queue: &Arc<RwLock<FormMQ<Vec<u8>>>>) -> Result<bool, Box<dyn std::error::Error>> {
+    let (heads, mut after) = {
+        let guard = queue.read().await;
+        (topic_heads(guard.queue()), guard.sync().delta_cursor(dial).cloned())
+    };
+    if let Some(cursor) = &after {
+        log::info!("Resuming delta sync with {dial} after topic {cursor}");
+    }
+
+    let client = Client::new();
+    let (mut topics, mut total_bytes) = (0, 0);
+    loop {
+        // Topics up to the cursor were handled by earlier chunks
+        let request = DeltaRequest {
+            heads: heads.iter()
+                .filter(|(topic, _)| after.as_ref().map_or(true, |after| *topic > after))
+                .map(|(topic, head)| (topic.clone(), head.clone()))
+                .collect(),
+            after: after.clone(),
+            max_bytes: None,
+        };
+        let resp = client.post(format!("http://{dial}:{QUEUE_PORT}/queue/sync/delta"))
+            .timeout(DELTA_CHUNK_TIMEOUT)
+            .json(&request)
+            .send()
+            .await?;
+        if resp.status().as_u16() == 404 {
+            return Ok(false);
+        }
+        if !resp.status().is_success() {
+            return Err(format!("Delta sync request failed with status:{}", resp.status()).into());
+        }
+        let bytes = resp.bytes().await?;
+        let chunk = serde_json::from_slice::<DeltaChunk>(&bytes)?;
+        topics += chunk.topics.len();
+        total_bytes += bytes.len() as u64;
+
+        let mut guard = queue.write().await;
+        guard.merge_delta(chunk.topics);
+        guard.sync_mut().record_bytes(dial, bytes.len() as u64);
+        match chunk.next {
+            Some(next) => {
+                guard.sync_mut().set_delta_cursor(dial, next.clone());
+                after = Some(next);
+            }
+            None => break,
+        }
+    }
+    log::info!("Delta synced {topics} topics from {dial} in {total_bytes} bytes");
+    Ok(true)
+}
+
+async fn fetch_topic_queue(dial: &str) -> Result<(TopicQueue<Vec<u8>>, u64), Box<dyn std::error::Error>> {
     let client = Client::new();
     let url = format!("http://{dial}:{QUEUE_PORT}/queue/get");
     let resp = client.get(url).send().await?;
@@ -42,11 +140,7 @@ pub async fn bootstrap_topic_queue(dial: String, queue: Arc<RwLock<FormMQ<Vec<u8
     }
 
     if !bytes.is_empty() {
-        let received = serde_json::from_slice::<TopicQueue<Vec<u8>>>(&bytes)?;
-        let mut guard = queue.write().await;
-        guard.merge(received);
-        drop(guard);
-        return Ok(())
+        return Ok((serde_json::from_slice::<TopicQueue<Vec<u8>>>(&bytes)?, bytes.len() as u64))
     }
 
     return Err(format!("Bytes were empty after stream").into());
@@ -63,19 +157,43 @@ pub fn build_routes(state: Arc<RwLock<FormMQ<Vec<u8>>>>) -> Router {
         .route("/queue/:topic/:idx/get_after", get(get_topic_after))
         .route("/queue/:topic/:idx/:n/get_n_after", get(get_topic_n_after))
         .route("/queue/get", get(get_all))
+        .route("/queue/sync/delta", post(get_delta))
         .route("/queue/joined_formnet", post(complete_bootstrap))
+        .route("/admin/status", get(admin_status))
+        .route("/admin/status/summary", get(admin_status_summary))
+        .route("/admin/force-sync", post(force_sync))
+        .route("/admin/force-sync/:peer", post(force_sync_peer))
+        .route("/trace/record", post(record_trace))
+        .route("/trace/register", post(register_trace))
+        .route("/trace/:correlation_id", get(get_trace))
+        .route("/trace/:correlation_id/local", get(get_local_trace))
         .with_state(state)
 }
 
 pub async fn serve(state: Arc<RwLock<FormMQ<Vec<u8>>>>, bind: u16) -> Result<(), Box<dyn std::error::Error>> { 
     let tcp_listener = TcpListener::bind(format!("0.0.0.0:{bind}")).await?;
-    if let Err(e) = axum::serve(tcp_listener, build_routes(state)).await {
+    let routes = build_routes(state).into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(tcp_listener, routes).await {
         return Err(Box::new(e))
     }
 
     Ok(())
 }
 
+/// Keep the topic policies in line with form-state, where the set of active
+/// nodes lives
+pub async fn sync_topic_policies(state: Arc<RwLock<FormMQ<Vec<u8>>>>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let result = state.read().await.fetch_policies().await;
+        match result {
+            Ok(policies) => state.write().await.set_policies(policies),
+            Err(e) => log::warn!("Unable to sync topic policies from form-state: {e}"),
+        }
+    }
+}
+
 pub async fn health_check(
 ) -> String {
     "OK".to_string()
@@ -91,12 +209,24 @@ pub async fn complete_bootstrap(
                 Ok(r) => {
                     match r {
                         StateResponse::Success(Success::List(peers)) => {
-                            let mut operator_iter = peers.iter();
-                            while let Some(operator) = operator_iter.next() {
-                                if let Ok(_) = bootstrap_topic_queue(operator.ip.to_string(), state.clone()).await {
+                            let operators: Vec<String> = peers.iter().map(|operator| operator.ip.to_string()).collect();
+                            state.write().await.sync_mut().set_bootstrap(BootstrapState::Pending {
+                                peers: operators.clone(),
+                                started_at: unix_now(),
+                            });
+                            for operator in &operators {
+                                if bootstrap_topic_queue(operator.clone(), state.clone()).await.is_ok() {
+                                    state.write().await.sync_mut().set_bootstrap(BootstrapState::Complete {
+                                        peer: operator.clone(),
+                                        completed_at: unix_now(),
+                                    });
                                     return;
                                 }
                             }
+                            state.write().await.sync_mut().set_bootstrap(BootstrapState::Failed {
+                                peers: operators,
+                                failed_at: unix_now(),
+                            });
                         }
                         _ => {
                             log::error!("Received response {r:?}. Invalid response for /user/list_admin response");
@@ -113,15 +243,82 @@ pub async fn complete_bootstrap(
         }
 }
 
+pub async fn admin_status(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>
+) -> Json<QueueStatus> {
+    Json(state.read().await.status())
+}
+
+pub async fn admin_status_summary(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>
+) -> Json<QueueHealth> {
+    Json(state.read().await.status().summary())
+}
+
+/// Catch up with every active node. Only accepted from the local
+/// host since it fans out a request to the whole network.
+pub async fn force_sync(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Result<Json<ForceSyncReport>, (StatusCode, String)> {
+    if !addr.ip().is_loopback() {
+        return Err((StatusCode::FORBIDDEN, "force-sync is only accepted from the local host".to_string()));
+    }
+    let peers = match FormMQ::get_peers().await {
+        Ok(peers) => peers.into_iter().map(|peer| peer.to_string()).collect(),
+        Err(e) => return Err((StatusCode::BAD_GATEWAY, format!("Unable to list active peers: {e}"))),
+    };
+    Ok(Json(sync_with_peers(state, peers).await))
+}
+
+pub async fn force_sync_peer(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(peer): Path<String>,
+) -> Result<Json<ForceSyncReport>, (StatusCode, String)> {
+    if !addr.ip().is_loopback() {
+        return Err((StatusCode::FORBIDDEN, "force-sync is only accepted from the local host".to_string()));
+    }
+    if peer.parse::<IpAddr>().is_err() {
+        return Err((StatusCode::BAD_REQUEST, format!("{peer} is not an IP address")));
+    }
+    Ok(Json(sync_with_peers(state, vec![peer]).await))
+}
+
+async fn sync_with_peers(state: Arc<RwLock<FormMQ<Vec<u8>>>>, peers: Vec<String>) -> ForceSyncReport {
+    let mut report = ForceSyncReport::default();
+    for peer in peers {
+        match bootstrap_topic_queue(peer.clone(), state.clone()).await {
+            Ok(()) => report.synced.push(peer),
+            Err(e) => {
+                log::warn!("Force sync with {peer} failed: {e}");
+                report.failed.insert(peer, e.to_string());
+            }
+        }
+    }
+    if !report.synced.is_empty() {
+        let queue = state.read().await.queue().clone();
+        let _ = store_topic_queue(&DB_HANDLE, "form-queue", &queue);
+    }
+    report
+}
+
 pub async fn write_op(
     State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(request): Json<QueueRequest>
 ) -> Json<QueueResponse> {
     let mut queue = state.write().await;
     match request {
         QueueRequest::Op(op) => {
+            if let Err(e) = queue.authorize_op(&op) {
+                log::warn!("Rejected queue op: {e}");
+                return Json(QueueResponse::Failure { reason: Some(e.to_string()) })
+            }
             queue.apply(op.clone());
-            queue.op_success(op);
+            if queue.op_success(op) {
+                queue.sync_mut().record_op(&addr.ip().to_string());
+            }
             drop(queue);
             let queue = state.read().await.queue().clone();
             let _ = store_topic_queue(&DB_HANDLE, "form-queue", &queue);
@@ -134,36 +331,55 @@ pub async fn write_op(
 }
 pub async fn write_local(
     State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(request): Json<QueueRequest>
 ) -> Json<QueueResponse> {
-    log::info!("Received write local request");
+    let correlation_id = headers.get(CORRELATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 64)
+        .map(str::to_string)
+        .unwrap_or_else(new_correlation_id);
+    log::info!("Received write local request {correlation_id}");
     let mut queue = state.write().await;
-    match request {
-        QueueRequest::Write { content, topic } => {
-            log::info!("For topic: {topic:?}");
-            match queue.write_local(topic, content) {
-                Ok(op) => if queue.op_success(op.clone()) {
-                    tokio::spawn(async move {
-                        if let Err(e) = FormMQ::broadcast_op(op.clone()).await {
-                            eprintln!("Error broadcasting op: {e}");
-                        }
-                    });
-                    drop(queue);
-                    let inner_state = state.clone();
-                    tokio::spawn(async move {
-                        let queue = inner_state.read().await.queue().clone();
-                        let _ = store_topic_queue(&DB_HANDLE, "form-queue", &queue);
-                    });
-                    return Json(QueueResponse::OpSuccess)
-                } else {
-                    return Json(QueueResponse::Failure { reason: Some(format!("Error trying to write local: Op not successfully written to queue.")) })
-                }
-                Err(e) => return Json(QueueResponse::Failure { reason: Some(format!("Error trying to write local: {e}")) })
-            }
-        }
+    let (content, topic, signature) = match request {
+        QueueRequest::Write { content, topic } => (content, topic, None),
+        QueueRequest::SignedWrite { content, topic, signature } => (content, topic, Some(signature)),
         _ => {
-            return Json(QueueResponse::Failure { reason: Some("Invalid request for write_op endpoint".to_string()) })
+            return Json(QueueResponse::Failure { reason: Some("Invalid request for write_local endpoint".to_string()) })
         }
+    };
+    if let Err(e) = queue.authorize_write(&topic, &content, signature.as_ref(), addr.ip().is_loopback()) {
+        log::warn!("Rejected write from {addr} to topic {topic}: {e}");
+        return Json(QueueResponse::Failure { reason: Some(e.to_string()) })
+    }
+    log::info!("For topic: {topic:?}");
+    let key = message_key(&content);
+    match queue.write_local(topic, content) {
+        Ok(op) => if queue.op_success(op.clone()) {
+            let node_id = queue.node_id().to_string();
+            queue.trace_mut().register(&key, &correlation_id);
+            queue.trace_mut().record(&key, "form-p2p", "enqueued", &node_id, Some(format!("written by {}", addr.ip())));
+            let registration = TraceRegistration { key, correlation_id, origin: node_id };
+            tokio::spawn(async move {
+                if let Err(e) = FormMQ::broadcast_op(op.clone()).await {
+                    eprintln!("Error broadcasting op: {e}");
+                }
+                if let Err(e) = broadcast_registration(&registration).await {
+                    log::warn!("Unable to share correlation ID {}: {e}", registration.correlation_id);
+                }
+            });
+            drop(queue);
+            let inner_state = state.clone();
+            tokio::spawn(async move {
+                let queue = inner_state.read().await.queue().clone();
+                let _ = store_topic_queue(&DB_HANDLE, "form-queue", &queue);
+            });
+            return Json(QueueResponse::OpSuccess)
+        } else {
+            return Json(QueueResponse::Failure { reason: Some(format!("Error trying to write local: Op not successfully written to queue.")) })
+        }
+        Err(e) => return Json(QueueResponse::Failure { reason: Some(format!("Error trying to write local: {e}")) })
     }
 }
 pub async fn get_topic_all(
@@ -279,3 +495,111 @@ pub async fn get_all(
         .body(body.into_data_stream())
         .unwrap()
 }
+
+/// The topics a peer is missing or behind on, one chunk at a time
+pub async fn get_delta(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    Json(request): Json<DeltaRequest>,
+) -> Json<DeltaChunk> {
+    let queue = state.read().await;
+    let chunk = build_chunk(queue.queue(), &request);
+    log::info!(
+        "Serving delta sync chunk of {} topics, {} up to date, more: {}",
+        chunk.topics.len(), chunk.up_to_date, chunk.next.is_some()
+    );
+    Json(chunk)
+}
+
+/// Tells the peers the correlation ID of a message just broadcast to them
+async fn broadcast_registration(registration: &TraceRegistration) -> Result<(), Box<dyn std::error::Error>> {
+    let client = Client::new();
+    for peer in FormMQ::get_peers().await? {
+        if let Err(e) = client.post(format!("http://{peer}:{QUEUE_PORT}/trace/register"))
+            .timeout(Duration::from_secs(5))
+            .json(registration)
+            .send()
+            .await
+        {
+            log::warn!("Unable to send correlation ID to {peer}: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Records what a service on this node did with a message. Only accepted
+/// from the local host, returns the message's correlation ID if it has one.
+pub async fn record_trace(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(record): Json<TraceRecord>,
+) -> Result<Json<Option<String>>, (StatusCode, String)> {
+    if !addr.ip().is_loopback() {
+        return Err((StatusCode::FORBIDDEN, "trace events are only accepted from the local host".to_string()));
+    }
+    let mut queue = state.write().await;
+    let node_id = queue.node_id().to_string();
+    let correlation_id = queue.trace_mut().record(&record.key, &record.service, &record.stage, &node_id, record.detail);
+    Ok(Json(correlation_id))
+}
+
+pub async fn register_trace(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    Json(registration): Json<TraceRegistration>,
+) -> StatusCode {
+    let mut queue = state.write().await;
+    let node_id = queue.node_id().to_string();
+    let trace = queue.trace_mut();
+    if trace.correlation_id(&registration.key).is_some() {
+        return StatusCode::OK;
+    }
+    trace.register(&registration.key, &registration.correlation_id);
+    trace.record(&registration.key, "form-p2p", "replicated", &node_id, Some(format!("from {}", registration.origin)));
+    StatusCode::OK
+}
+
+pub async fn get_local_trace(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    Path(correlation_id): Path<String>,
+) -> Json<Vec<TraceEvent>> {
+    Json(state.read().await.trace().events(&correlation_id))
+}
+
+/// The journey of a message across every active node, oldest event first
+pub async fn get_trace(
+    State(state): State<Arc<RwLock<FormMQ<Vec<u8>>>>>,
+    Path(correlation_id): Path<String>,
+) -> Result<Json<Trace>, (StatusCode, String)> {
+    let mut trace = Trace {
+        correlation_id: correlation_id.clone(),
+        events: state.read().await.trace().events(&correlation_id),
+        ..Default::default()
+    };
+    let peers = FormMQ::get_peers().await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Unable to list active peers: {e}")))?;
+
+    let client = Client::new();
+    for peer in peers {
+        let events = async {
+            client.get(format!("http://{peer}:{QUEUE_PORT}/trace/{correlation_id}/local"))
+                .timeout(Duration::from_secs(5))
+                .send()
+                .await?
+                .json::<Vec<TraceEvent>>()
+                .await
+        };
+        match events.await {
+            Ok(events) => trace.events.extend(events),
+            Err(e) => {
+                trace.unreachable.insert(peer.to_string(), e.to_string());
+            }
+        }
+    }
+
+    // The active peers usually include this node
+    trace.events.sort_by(|a, b| (a.at, &a.node_id, &a.service, &a.stage).cmp(&(b.at, &b.node_id, &b.service, &b.stage)));
+    trace.events.dedup();
+    if trace.events.is_empty() {
+        return Err((StatusCode::NOT_FOUND, format!("No events recorded for {correlation_id}")));
+    }
+    Ok(Json(trace))
+}
@@ -0,0 +1,172 @@
+//! Per-topic write authorization
+//!
+//! Publishers sign `sha3(topic || content)` with their ECDSA key and FormMQ
+//! recovers the signer before accepting the write. Which signers may write to
+//! a topic is decided by a `TopicPolicies` map that is synced from form-state.
+//! Writes from services on the same host are attributed to the local node.
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use alloy_primitives::Address;
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+
+/// Topics anyone holding a key may publish to, handlers check ownership of
+/// the individual requests themselves
+pub const USER_TOPICS: &[&str] = &["vmm", "pack", "pack.build", "pack.ship"];
+
+/// Topics only active nodes may publish to
+pub const NODE_TOPICS: &[&str] = &["state", "global_crdt_ops", "usage_events", "node_events"];
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WriteSignature {
+    /// Hex encoded 64 byte signature
+    pub signature: String,
+    pub recovery_id: u8,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TopicPolicy {
+    AnySigner,
+    Nodes,
+    Addresses(BTreeSet<String>),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TopicPolicies {
+    /// Keyed by the hex encoded topic hash, as it appears in queue requests.
+    /// Topics without an entry are restricted to nodes.
+    pub topics: HashMap<String, TopicPolicy>,
+    /// Operator addresses of the active nodes
+    pub nodes: BTreeSet<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WriteAuthError {
+    MissingSignature,
+    InvalidSignature(String),
+    Unauthorized { signer: String, topic: String },
+}
+
+impl fmt::Display for WriteAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteAuthError::MissingSignature => write!(f, "Remote writes must be signed by the publisher"),
+            WriteAuthError::InvalidSignature(e) => write!(f, "Invalid write signature: {e}"),
+            WriteAuthError::Unauthorized { signer, topic } => {
+                write!(f, "0x{signer} is not allowed to write to topic {topic}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WriteAuthError {}
+
+/// Hex encoded sha3 hash of a topic name, the form topics take on the wire
+pub fn topic_key(name: &str) -> String {
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(name.as_bytes());
+    hasher.finalize(&mut hash);
+    hex::encode(hash)
+}
+
+fn write_digest(topic: &str, content: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(topic.as_bytes());
+    hasher.update(content);
+    hasher.finalize(&mut hash);
+    hash
+}
+
+fn normalize(address: &str) -> String {
+    address.trim_start_matches("0x").to_lowercase()
+}
+
+pub fn sign_write(
+    signing_key: &SigningKey,
+    topic: &str,
+    content: &[u8],
+) -> Result<WriteSignature, Box<dyn std::error::Error>> {
+    let (signature, recovery_id) = signing_key.sign_prehash_recoverable(&write_digest(topic, content))?;
+    Ok(WriteSignature {
+        signature: hex::encode(signature.to_bytes()),
+        recovery_id: recovery_id.to_byte(),
+    })
+}
+
+/// Recover the hex address (without 0x) that signed the write
+pub fn recover_writer(topic: &str, content: &[u8], signature: &WriteSignature) -> Result<String, WriteAuthError> {
+    let bytes = hex::decode(&signature.signature)
+        .map_err(|e| WriteAuthError::InvalidSignature(e.to_string()))?;
+    let sig = Signature::from_slice(&bytes)
+        .map_err(|e| WriteAuthError::InvalidSignature(e.to_string()))?;
+    let recovery_id = RecoveryId::from_byte(signature.recovery_id)
+        .ok_or_else(|| WriteAuthError::InvalidSignature("invalid recovery id".to_string()))?;
+    let verifying_key = VerifyingKey::recover_from_prehash(&write_digest(topic, content), &sig, recovery_id)
+        .map_err(|e| WriteAuthError::InvalidSignature(e.to_string()))?;
+    Ok(hex::encode(Address::from_public_key(&verifying_key)))
+}
+
+impl TopicPolicies {
+    /// The policies a network starts with, nodes are filled in by form-state
+    pub fn network_defaults(nodes: impl IntoIterator<Item = String>) -> Self {
+        let topics = USER_TOPICS.iter()
+            .map(|t| (topic_key(t), TopicPolicy::AnySigner))
+            .chain(NODE_TOPICS.iter().map(|t| (topic_key(t), TopicPolicy::Nodes)))
+            .collect();
+        Self {
+            topics,
+            nodes: nodes.into_iter().map(|n| normalize(&n)).collect(),
+        }
+    }
+
+    pub fn is_node(&self, address: &str) -> bool {
+        self.nodes.contains(&normalize(address))
+    }
+
+    pub fn authorize(&self, topic: &str, signer: &str) -> Result<(), WriteAuthError> {
+        let allowed = match self.topics.get(topic).unwrap_or(&TopicPolicy::Nodes) {
+            TopicPolicy::AnySigner => true,
+            TopicPolicy::Nodes => self.is_node(signer),
+            TopicPolicy::Addresses(addresses) => addresses.contains(&normalize(signer)),
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(WriteAuthError::Unauthorized { signer: normalize(signer), topic: topic.to_string() })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_topic_policies() {
+        let node = SigningKey::random(&mut thread_rng());
+        let user = SigningKey::random(&mut thread_rng());
+        let node_address = hex::encode(Address::from_private_key(&node));
+        let user_address = hex::encode(Address::from_private_key(&user));
+        let policies = TopicPolicies::network_defaults(vec![format!("0x{node_address}")]);
+
+        let content = b"some message".to_vec();
+        let state = topic_key("state");
+        let signature = sign_write(&user, &state, &content).unwrap();
+        let signer = recover_writer(&state, &content, &signature).unwrap();
+        assert_eq!(signer, user_address);
+        assert!(policies.authorize(&state, &signer).is_err());
+        assert!(policies.authorize(&state, &node_address).is_ok());
+        assert!(policies.authorize(&topic_key("vmm"), &signer).is_ok());
+        assert!(policies.authorize(&topic_key("unlisted"), &signer).is_err());
+
+        // The signature covers the topic
+        let moved = recover_writer(&topic_key("vmm"), &content, &signature).ok();
+        assert_ne!(moved, Some(user_address));
+    }
+}
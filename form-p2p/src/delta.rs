@@ -0,0 +1,183 @@
+//! Delta sync of the queue between nodes.
+//!
+//! A node that joins or catches up sends the vector clock of every topic it
+//! holds to `POST /queue/sync/delta`. The peer answers with the topics whose
+//! clock isn't covered by the one it was sent, in chunks of about
+//! `max_bytes`, so topics the node already has are never transferred. Each
+//! chunk carries the cursor to ask for the next one, which the node keeps
+//! until the sync completes and resumes from if it was interrupted.
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use crdts::{bft_topic_queue::TopicQueue, map::Entry, BFTQueue, CvRDT, VClock};
+use serde::{Deserialize, Serialize};
+
+/// Size of a chunk when the request doesn't set one
+pub const DEFAULT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+/// Largest chunk a node serves
+pub const MAX_CHUNK_BYTES: usize = 32 * 1024 * 1024;
+
+pub type TopicEntry = Entry<BFTQueue<Vec<u8>>, String>;
+
+/// Body of `POST /queue/sync/delta`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeltaRequest {
+    /// Clock of each topic the requesting node holds
+    pub heads: BTreeMap<String, VClock<String>>,
+    /// Last topic of the previous chunk, `None` for the first
+    #[serde(default)]
+    pub after: Option<String>,
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeltaChunk {
+    /// Topics the requesting node is missing or behind on
+    pub topics: Vec<(String, TopicEntry)>,
+    /// Topics of the range the requesting node was already up to date on
+    pub up_to_date: u64,
+    /// Cursor of the next chunk, `None` once every topic was sent
+    pub next: Option<String>,
+}
+
+/// Clock of each topic in the queue
+pub fn topic_heads(queue: &TopicQueue<Vec<u8>>) -> BTreeMap<String, VClock<String>> {
+    queue.topics.entries.iter()
+        .map(|(topic, entry)| (topic.clone(), entry.clock.clone()))
+        .collect()
+}
+
+/// The chunk of topics after `request.after` the requesting node needs. A
+/// chunk holds at least one topic however large it is.
+pub fn build_chunk(queue: &TopicQueue<Vec<u8>>, request: &DeltaRequest) -> DeltaChunk {
+    let max_bytes = request.max_bytes.unwrap_or(DEFAULT_CHUNK_BYTES).clamp(1, MAX_CHUNK_BYTES);
+    let start = match &request.after {
+        Some(after) => Bound::Excluded(after.clone()),
+        None => Bound::Unbounded,
+    };
+
+    let mut chunk = DeltaChunk { topics: vec![], up_to_date: 0, next: None };
+    let mut size = 0;
+    for (topic, entry) in queue.topics.entries.range((start, Bound::Unbounded)) {
+        if request.heads.get(topic).map_or(false, |head| *head >= entry.clock) {
+            chunk.up_to_date += 1;
+            continue;
+        }
+        let entry_size = serde_json::to_vec(entry).map(|bytes| bytes.len()).unwrap_or(0);
+        if !chunk.topics.is_empty() && size + entry_size > max_bytes {
+            chunk.next = chunk.topics.last().map(|(topic, _)| topic.clone());
+            break;
+        }
+        size += entry_size;
+        chunk.topics.push((topic.clone(), entry.clone()));
+    }
+    chunk
+}
+
+/// Merges the topics of a chunk into the queue. Topics are merged one by
+/// one rather than as a map, the chunk's topics are a subset of the peer's
+/// and merging them as a map would read the rest as removed.
+pub fn merge_chunk(queue: &mut TopicQueue<Vec<u8>>, topics: Vec<(String, TopicEntry)>) {
+    for (topic, received) in topics {
+        queue.topics.clock.merge(received.clock.clone());
+        match queue.topics.entries.get_mut(&topic) {
+            Some(entry) => {
+                entry.clock.merge(received.clock);
+                entry.val.merge(received.val);
+            }
+            None => {
+                queue.topics.entries.insert(topic, received);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::Address;
+    use crdts::CmRDT;
+    use k256::ecdsa::SigningKey;
+    use rand::thread_rng;
+
+    fn write(queue: &mut TopicQueue<Vec<u8>>, sk: &SigningKey, topic: &str, count: usize) {
+        let actor = hex::encode(Address::from_private_key(sk));
+        for n in 0..count {
+            let content = format!("{topic} message {n}").into_bytes();
+            let op = queue.enqueue(topic.to_string(), content, actor.clone(), sk.clone()).unwrap();
+            queue.apply(op);
+        }
+    }
+
+    fn counts(queue: &TopicQueue<Vec<u8>>) -> BTreeMap<String, usize> {
+        queue.topics.entries.iter().map(|(topic, entry)| (topic.clone(), entry.val.read().len())).collect()
+    }
+
+    /// Pulls every chunk `source` serves into `target`, returning how many
+    /// topics were transferred
+    fn sync(source: &TopicQueue<Vec<u8>>, target: &mut TopicQueue<Vec<u8>>, max_bytes: usize) -> usize {
+        let heads = topic_heads(target);
+        let mut after = None;
+        let mut transferred = 0;
+        loop {
+            let chunk = build_chunk(source, &DeltaRequest { heads: heads.clone(), after, max_bytes: Some(max_bytes) });
+            transferred += chunk.topics.len();
+            merge_chunk(target, chunk.topics);
+            match chunk.next {
+                Some(next) => after = Some(next),
+                None => return transferred,
+            }
+        }
+    }
+
+    #[test]
+    fn test_delta_only_sends_topics_behind() {
+        let sk = SigningKey::random(&mut thread_rng());
+        let mut source = TopicQueue::new();
+        for topic in ["alpha", "beta", "gamma"] {
+            write(&mut source, &sk, topic, 5);
+        }
+        let mut target = source.clone();
+        write(&mut source, &sk, "beta", 2);
+        write(&mut source, &sk, "delta", 3);
+
+        let chunk = build_chunk(&source, &DeltaRequest { heads: topic_heads(&target), ..Default::default() });
+        let sent: Vec<&str> = chunk.topics.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(sent, vec!["beta", "delta"]);
+        assert_eq!(chunk.up_to_date, 2);
+        assert_eq!(chunk.next, None);
+
+        merge_chunk(&mut target, chunk.topics);
+        assert_eq!(counts(&target), counts(&source));
+    }
+
+    #[test]
+    fn test_delta_resumes_from_cursor() {
+        let sk = SigningKey::random(&mut thread_rng());
+        let mut source = TopicQueue::new();
+        for topic in ["a", "b", "c", "d"] {
+            write(&mut source, &sk, topic, 3);
+        }
+
+        // A chunk too small for two topics holds one
+        let first = build_chunk(&source, &DeltaRequest { max_bytes: Some(1), ..Default::default() });
+        assert_eq!(first.topics.len(), 1);
+        assert_eq!(first.next.as_deref(), Some("a"));
+
+        // Resuming after "a" on a node that merged it sends the rest
+        let mut target = TopicQueue::new();
+        merge_chunk(&mut target, first.topics);
+        let rest = build_chunk(&source, &DeltaRequest {
+            heads: topic_heads(&target),
+            after: first.next,
+            max_bytes: None,
+        });
+        let sent: Vec<&str> = rest.topics.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(sent, vec!["b", "c", "d"]);
+
+        let mut fresh = TopicQueue::new();
+        assert_eq!(sync(&source, &mut fresh, 1), 4);
+        assert_eq!(counts(&fresh), counts(&source));
+        assert_eq!(sync(&source, &mut fresh, 1), 0);
+    }
+}
@@ -1,3 +1,7 @@
 pub mod api;
+pub mod auth;
 pub mod queue;
 pub mod db;
+pub mod delta;
+pub mod status;
+pub mod trace;
@@ -2,11 +2,9 @@ use alloy_primitives::Address;
 use futures::{stream::FuturesUnordered, StreamExt};
 use k256::ecdsa::SigningKey;
 use clap::{Parser, Subcommand};
-use crdts::bft_topic_queue::TopicQueue;
-use form_p2p::queue::{FormMQ, QUEUE_PORT};
-use reqwest::Client;
+use form_p2p::{api::bootstrap_topic_queue, queue::{FormMQ, QUEUE_PORT}, status::{unix_now, BootstrapState}};
 use tokio::sync::RwLock;
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use form_config::OperatorConfig;
 
 #[derive(Parser, Debug)]
@@ -39,6 +37,9 @@ pub enum CliCommand {
         /// Message broker Publish Address
         #[arg(long, short)]
         pub_addr: Option<String>,
+        /// Accept writes to any topic without checking the publisher, for local development
+        #[arg(long, default_value="false")]
+        permissive: bool,
     },
     /// Show service status
     #[command(name = "status")]
@@ -56,7 +57,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = CliArgs::parse();
     let config = OperatorConfig::from_file(args.config, args.encrypted, args.password.as_deref()).ok();
     match args.command {
-        CliCommand::Run { signing_key, sub_addr: _, pub_addr: _ } => {
+        CliCommand::Run { signing_key, sub_addr: _, pub_addr: _, permissive } => {
             log::info!("Acquiring signing key");
             let signing_key = if signing_key.is_none() {
                 let config = config.clone().unwrap();
@@ -73,19 +74,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 )
             );
             log::info!("Building shared queue");
-            let queue = Arc::new(RwLock::new(FormMQ::new(address, signing_key, String::new())));
-            if let Some(config) = config {
+            let queue = Arc::new(RwLock::new(
+                FormMQ::new(address, signing_key, "127.0.0.1:3004".to_string()).permissive(permissive)
+            ));
+            if permissive {
+                log::warn!("Running in permissive mode, topic write authorization is disabled");
+            } else {
+                tokio::spawn(form_p2p::api::sync_topic_policies(queue.clone(), Duration::from_secs(30)));
+            }
+            if let Some(config) = config.filter(|config| !config.bootstrap_nodes.is_empty()) {
+                queue.write().await.sync_mut().set_bootstrap(BootstrapState::Pending {
+                    peers: config.bootstrap_nodes.clone(),
+                    started_at: unix_now(),
+                });
                 let mut fut = FuturesUnordered::new();
-                for bootstrap in config.bootstrap_nodes {
-                    fut.push(bootstrap_topic_queue(bootstrap, queue.clone()));
+                for bootstrap in config.bootstrap_nodes.clone() {
+                    let queue = queue.clone();
+                    fut.push(async move {
+                        let result = bootstrap_topic_queue(bootstrap.clone(), queue).await;
+                        (bootstrap, result)
+                    });
                 }
 
-                while let Some(complete) = fut.next().await {
+                let mut completed_from = None;
+                while let Some((bootstrap, complete)) = fut.next().await {
                     match complete {
-                        Ok(()) => log::info!("Completed bootstrap successfully"),
+                        Ok(()) => {
+                            log::info!("Completed bootstrap successfully");
+                            completed_from.get_or_insert(bootstrap);
+                        }
                         Err(e) => log::error!("Was unable to acquire Queue from one bootstrap node: {e}")
                     }
                 }
+                let bootstrap = match completed_from {
+                    Some(peer) => BootstrapState::Complete { peer, completed_at: unix_now() },
+                    None => BootstrapState::Failed { peers: config.bootstrap_nodes, failed_at: unix_now() },
+                };
+                queue.write().await.sync_mut().set_bootstrap(bootstrap);
             }
             let (shutdown_tx, _) = tokio::sync::broadcast::channel(1024);
             let inner_queue = queue.clone();
@@ -105,34 +130,3 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
-
-pub async fn bootstrap_topic_queue(dial: String, queue: Arc<RwLock<FormMQ<Vec<u8>>>>) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let url = format!("http://{dial}:{QUEUE_PORT}/queue/get");
-    let resp = client.get(url).send().await?;
-
-    if !resp.status().is_success() {
-        return Err(format!("Request failed with status:{}", resp.status()).into());
-    }
-
-    let mut bytes = vec![];
-    let mut stream = resp.bytes_stream();
-
-    while let Some(chunk) = stream.next().await {
-        match chunk {
-            Ok(b) => bytes.extend_from_slice(&b),
-            Err(e) => eprintln!("Error receiving chunk: {}", e)
-        }
-    }
-
-    if !bytes.is_empty() {
-        let received = serde_json::from_slice::<TopicQueue<Vec<u8>>>(&bytes)?;
-        let mut guard = queue.write().await;
-        guard.merge(received);
-        drop(guard);
-        return Ok(())
-    }
-
-    return Err(format!("Bytes were empty after stream").into());
-
-}
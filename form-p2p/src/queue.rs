@@ -5,6 +5,10 @@ use form_types::state::{Response, Success};
 use shared::Peer;
 use serde::{Deserialize, Serialize};
 use reqwest::Client;
+use crate::auth::{recover_writer, sign_write, TopicPolicies, WriteAuthError, WriteSignature};
+use crate::delta::{merge_chunk, TopicEntry};
+use crate::status::{QueueStatus, SyncTracker};
+use crate::trace::TraceLog;
 
 pub const QUEUE_PORT: u16 = 53333;
 pub type QueueOp<T> = Op<String, BFTQueue<T>, String>; 
@@ -15,6 +19,23 @@ pub enum QueueRequest {
     Write {
         content: Vec<u8>,
         topic: String,
+    },
+    /// A write signed by the publisher, required for writes from other hosts
+    SignedWrite {
+        content: Vec<u8>,
+        topic: String,
+        signature: WriteSignature,
+    }
+}
+
+impl QueueRequest {
+    pub fn signed_write(
+        topic: String,
+        content: Vec<u8>,
+        signing_key: &SigningKey,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let signature = sign_write(signing_key, &topic, &content)?;
+        Ok(QueueRequest::SignedWrite { content, topic, signature })
     }
 }
 
@@ -34,7 +55,12 @@ pub struct FormMQ<T: Sha3Hash + Default + Debug + Clone + Ord> {
     node_id: String,
     pk: String,
     state_uri: String,
-    client: Client
+    client: Client,
+    policies: TopicPolicies,
+    /// Skip write authorization, for local development only
+    permissive: bool,
+    sync: SyncTracker,
+    trace: TraceLog,
 }
 
 impl FormMQ<Vec<u8>> {
@@ -44,18 +70,111 @@ impl FormMQ<Vec<u8>> {
             node_id,
             pk,
             state_uri,
-            client: Client::new()
+            client: Client::new(),
+            policies: TopicPolicies::default(),
+            permissive: false,
+            sync: SyncTracker::default(),
+            trace: TraceLog::default(),
         }
     }
 
+    pub fn permissive(mut self, permissive: bool) -> Self {
+        self.permissive = permissive;
+        self
+    }
+
+    pub fn set_policies(&mut self, policies: TopicPolicies) {
+        self.policies = policies;
+    }
+
+    /// Check the publisher of a write may use the topic. Unsigned writes are
+    /// only accepted from the local host and are attributed to this node.
+    pub fn authorize_write(
+        &self,
+        topic: &str,
+        content: &[u8],
+        signature: Option<&WriteSignature>,
+        local: bool,
+    ) -> Result<(), WriteAuthError> {
+        if self.permissive {
+            return Ok(());
+        }
+
+        let signer = match signature {
+            Some(signature) => recover_writer(topic, content, signature)?,
+            None if local => self.node_id.clone(),
+            None => return Err(WriteAuthError::MissingSignature),
+        };
+        if signer.eq_ignore_ascii_case(&self.node_id) {
+            return Ok(());
+        }
+        self.policies.authorize(topic, &signer)
+    }
+
+    /// Ops are forwarded by the node that accepted the write, so only active
+    /// nodes may submit them
+    pub fn authorize_op(&self, op: &QueueOp<Vec<u8>>) -> Result<(), WriteAuthError> {
+        if self.permissive {
+            return Ok(());
+        }
+
+        match op {
+            Op::Up { dot, key, .. } if !self.policies.is_node(&dot.actor) => {
+                Err(WriteAuthError::Unauthorized { signer: dot.actor.clone(), topic: key.clone() })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fetch the topic policies from form-state
+    pub async fn fetch_policies(&self) -> Result<TopicPolicies, Box<dyn std::error::Error>> {
+        let state_uri = if self.state_uri.is_empty() { "127.0.0.1:3004" } else { &self.state_uri };
+        let policies = self.client
+            .get(format!("http://{state_uri}/queue/topic_policies"))
+            .send()
+            .await?
+            .json::<TopicPolicies>()
+            .await?;
+        Ok(policies)
+    }
+
     pub fn merge(&mut self, other: TopicQueue<Vec<u8>>) {
         self.queue.merge(other);
     }
 
+    /// Merges topics received from a delta sync
+    pub fn merge_delta(&mut self, topics: Vec<(String, TopicEntry)>) {
+        merge_chunk(&mut self.queue, topics);
+    }
+
     pub fn queue(&self) -> &TopicQueue<Vec<u8>> {
         &self.queue
     }
 
+    pub fn sync(&self) -> &SyncTracker {
+        &self.sync
+    }
+
+    pub fn sync_mut(&mut self) -> &mut SyncTracker {
+        &mut self.sync
+    }
+
+    pub fn trace(&self) -> &TraceLog {
+        &self.trace
+    }
+
+    pub fn trace_mut(&mut self) -> &mut TraceLog {
+        &mut self.trace
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        QueueStatus::new(self.node_id.clone(), &self.queue, &self.sync)
+    }
+
     pub fn read(&self, topic: String) -> Option<Vec<Message<Vec<u8>>>> {
         if let Some(ref queue) = &self.queue.read_topic(&topic) {
             return Some(queue.read().iter().map(|m| m.to_owned().clone()).collect())
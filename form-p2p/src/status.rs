@@ -0,0 +1,222 @@
+//! Replication status of the local queue.
+//!
+//! Tracks when the queue last merged with each peer, whether the initial
+//! bootstrap from the configured nodes has completed, and how many messages
+//! are held per topic, so operators can tell if a node's queue has diverged
+//! from the rest of the network.
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crdts::bft_topic_queue::TopicQueue;
+use serde::{Deserialize, Serialize};
+
+/// A peer that hasn't merged for this long is reported as lagging
+pub const LAG_THRESHOLD_SECS: i64 = 300;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum BootstrapState {
+    /// No bootstrap nodes are configured
+    #[default]
+    NotConfigured,
+    /// Waiting on the full queue from the bootstrap nodes
+    Pending { peers: Vec<String>, started_at: i64 },
+    Complete { peer: String, completed_at: i64 },
+    /// None of the bootstrap nodes returned a queue
+    Failed { peers: Vec<String>, failed_at: i64 },
+}
+
+impl BootstrapState {
+    pub fn is_complete(&self) -> bool {
+        matches!(self, BootstrapState::Complete { .. })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerSync {
+    /// Unix timestamp of the last completed sync from the peer
+    pub last_merged_at: Option<i64>,
+    /// Unix timestamp of the last op the peer forwarded
+    pub last_op_at: Option<i64>,
+    pub ops_received: u64,
+    /// Error of the last failed merge, cleared by the next successful one
+    pub last_error: Option<String>,
+    /// Bytes of queue data received from the peer by syncs
+    #[serde(default)]
+    pub bytes_received: u64,
+}
+
+impl PeerSync {
+    /// Unix timestamp of the latest data received from the peer
+    pub fn last_seen(&self) -> Option<i64> {
+        self.last_merged_at.max(self.last_op_at)
+    }
+}
+
+/// Sync bookkeeping held alongside the queue
+#[derive(Clone, Debug, Default)]
+pub struct SyncTracker {
+    peers: BTreeMap<String, PeerSync>,
+    bootstrap: BootstrapState,
+    /// Where an interrupted delta sync with each peer resumes
+    delta_cursors: BTreeMap<String, String>,
+}
+
+impl SyncTracker {
+    pub fn record_merge(&mut self, peer: &str) {
+        let entry = self.peers.entry(peer.to_string()).or_default();
+        entry.last_merged_at = Some(unix_now());
+        entry.last_error = None;
+        self.delta_cursors.remove(peer);
+    }
+
+    pub fn record_bytes(&mut self, peer: &str, bytes: u64) {
+        self.peers.entry(peer.to_string()).or_default().bytes_received += bytes;
+    }
+
+    pub fn delta_cursor(&self, peer: &str) -> Option<&String> {
+        self.delta_cursors.get(peer)
+    }
+
+    pub fn set_delta_cursor(&mut self, peer: &str, cursor: String) {
+        self.delta_cursors.insert(peer.to_string(), cursor);
+    }
+
+    pub fn record_merge_failure(&mut self, peer: &str, error: String) {
+        self.peers.entry(peer.to_string()).or_default().last_error = Some(error);
+    }
+
+    pub fn record_op(&mut self, peer: &str) {
+        let entry = self.peers.entry(peer.to_string()).or_default();
+        entry.last_op_at = Some(unix_now());
+        entry.ops_received += 1;
+    }
+
+    pub fn bootstrap(&self) -> &BootstrapState {
+        &self.bootstrap
+    }
+
+    pub fn set_bootstrap(&mut self, state: BootstrapState) {
+        self.bootstrap = state;
+    }
+
+    pub fn peers(&self) -> &BTreeMap<String, PeerSync> {
+        &self.peers
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerStatus {
+    #[serde(flatten)]
+    pub sync: PeerSync,
+    /// Seconds since anything was received from the peer
+    pub lag_secs: Option<i64>,
+    pub lagging: bool,
+}
+
+/// Served on `/admin/status`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueueStatus {
+    pub node_id: String,
+    /// Message count per topic hash
+    pub topics: BTreeMap<String, u64>,
+    pub total_messages: u64,
+    pub peers: BTreeMap<String, PeerStatus>,
+    pub bootstrap: BootstrapState,
+    pub generated_at: i64,
+}
+
+impl QueueStatus {
+    pub fn new(node_id: String, queue: &TopicQueue<Vec<u8>>, tracker: &SyncTracker) -> Self {
+        let now = unix_now();
+        let topics: BTreeMap<String, u64> = queue.topics.entries.iter()
+            .map(|(topic, entry)| (topic.clone(), entry.val.read().len() as u64))
+            .collect();
+        let peers = tracker.peers().iter()
+            .map(|(peer, sync)| {
+                let lag_secs = sync.last_seen().map(|seen| now.saturating_sub(seen));
+                let lagging = match lag_secs {
+                    Some(lag) => lag > LAG_THRESHOLD_SECS,
+                    None => true,
+                };
+                (peer.clone(), PeerStatus { sync: sync.clone(), lag_secs, lagging })
+            })
+            .collect();
+
+        Self {
+            node_id,
+            total_messages: topics.values().sum(),
+            topics,
+            peers,
+            bootstrap: tracker.bootstrap().clone(),
+            generated_at: now,
+        }
+    }
+
+    pub fn summary(&self) -> QueueHealth {
+        QueueHealth {
+            total_messages: self.total_messages,
+            topics: self.topics.len() as u64,
+            bootstrap_complete: self.bootstrap.is_complete(),
+            last_sync_at: self.peers.values().filter_map(|peer| peer.sync.last_seen()).max(),
+            peers: self.peers.len() as u64,
+            lagging_peers: self.peers.values().filter(|peer| peer.lagging).count() as u64,
+        }
+    }
+}
+
+/// Compact queue health carried on node heartbeats
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct QueueHealth {
+    pub total_messages: u64,
+    pub topics: u64,
+    pub bootstrap_complete: bool,
+    /// Unix timestamp of the latest data received from any peer
+    pub last_sync_at: Option<i64>,
+    pub peers: u64,
+    pub lagging_peers: u64,
+}
+
+/// Result of a `/admin/force-sync` request, keyed by peer
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ForceSyncReport {
+    pub synced: Vec<String>,
+    pub failed: BTreeMap<String, String>,
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_reports_lagging_peers() {
+        let mut tracker = SyncTracker::default();
+        tracker.set_delta_cursor("10.0.0.2", "topic".to_string());
+        tracker.record_bytes("10.0.0.2", 512);
+        tracker.record_merge("10.0.0.2");
+        assert_eq!(tracker.delta_cursor("10.0.0.2"), None);
+        tracker.record_merge_failure("10.0.0.3", "connection refused".to_string());
+        tracker.record_op("10.0.0.2");
+        tracker.set_bootstrap(BootstrapState::Complete { peer: "10.0.0.2".to_string(), completed_at: unix_now() });
+
+        let status = QueueStatus::new("node".to_string(), &TopicQueue::new(), &tracker);
+        assert_eq!(status.total_messages, 0);
+        assert!(!status.peers["10.0.0.2"].lagging);
+        assert_eq!(status.peers["10.0.0.2"].sync.ops_received, 1);
+        assert_eq!(status.peers["10.0.0.2"].sync.bytes_received, 512);
+        assert!(status.peers["10.0.0.3"].lagging);
+        assert_eq!(status.peers["10.0.0.3"].sync.last_error.as_deref(), Some("connection refused"));
+
+        let summary = status.summary();
+        assert!(summary.bootstrap_complete);
+        assert_eq!(summary.peers, 2);
+        assert_eq!(summary.lagging_peers, 1);
+        assert!(summary.last_sync_at.is_some());
+    }
+}
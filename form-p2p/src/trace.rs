@@ -0,0 +1,216 @@
+//! Correlation IDs for queue messages.
+//!
+//! A correlation ID is assigned when a message is written to the queue, taken
+//! from the `x-correlation-id` header of the write or generated. Messages are
+//! identified by the hash of their content, which is the same on every node,
+//! so the services consuming them can report what they did with a message
+//! without the ID being part of it. Each node keeps the events it saw and
+//! `/trace/<correlation_id>` gathers them from the whole network.
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+use crate::queue::QUEUE_PORT;
+
+/// Header a correlation ID is passed in when writing to the queue
+pub const CORRELATION_HEADER: &str = "x-correlation-id";
+
+/// Traces kept per node before the oldest is evicted
+pub const MAX_TRACES: usize = 10_000;
+
+/// Events kept per trace, a message that keeps failing stops adding events
+pub const MAX_EVENTS_PER_TRACE: usize = 64;
+
+pub fn new_correlation_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// Hex SHA3-256 of a message's content, how a message is recognised on every
+/// node it is replicated to
+pub fn message_key(content: &[u8]) -> String {
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(content);
+    hasher.finalize(&mut hash);
+    hex::encode(hash)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// One step of a message's journey
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    pub correlation_id: String,
+    /// e.g. form-p2p, form-state, form-pack, vmm-service
+    pub service: String,
+    /// e.g. enqueued, replicated, received, processed, failed
+    pub stage: String,
+    pub node_id: String,
+    /// Unix timestamp in milliseconds
+    pub at: u64,
+    pub detail: Option<String>,
+}
+
+/// Body of `POST /trace/record`, sent by the services on the same node
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// `message_key` of the message's content
+    pub key: String,
+    pub service: String,
+    pub stage: String,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/// Body of `POST /trace/register`, sent to the peers an op is broadcast to so
+/// they know the correlation ID of the message it carries
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraceRegistration {
+    pub key: String,
+    pub correlation_id: String,
+    /// Node the message was written on
+    pub origin: String,
+}
+
+/// Events of one correlation ID gathered from the network, oldest first
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Trace {
+    pub correlation_id: String,
+    pub events: Vec<TraceEvent>,
+    /// Peers that couldn't be asked for their events
+    pub unreachable: BTreeMap<String, String>,
+}
+
+/// The trace events seen on this node, bounded in the number of traces
+#[derive(Clone, Debug, Default)]
+pub struct TraceLog {
+    events: BTreeMap<String, Vec<TraceEvent>>,
+    /// Message key to correlation ID
+    keys: BTreeMap<String, String>,
+    /// Message keys in the order they were registered, for eviction
+    order: VecDeque<String>,
+}
+
+impl TraceLog {
+    /// Associates a message with a correlation ID, evicting the oldest trace
+    /// once the log is full
+    pub fn register(&mut self, key: &str, correlation_id: &str) {
+        if self.keys.contains_key(key) {
+            return;
+        }
+        self.keys.insert(key.to_string(), correlation_id.to_string());
+        self.order.push_back(key.to_string());
+        while self.order.len() > MAX_TRACES {
+            if let Some(evicted) = self.order.pop_front() {
+                if let Some(id) = self.keys.remove(&evicted) {
+                    if !self.keys.values().any(|other| *other == id) {
+                        self.events.remove(&id);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn correlation_id(&self, key: &str) -> Option<&String> {
+        self.keys.get(key)
+    }
+
+    /// Records an event for a registered message, returning its correlation
+    /// ID. Events of unknown messages are dropped.
+    pub fn record(
+        &mut self,
+        key: &str,
+        service: &str,
+        stage: &str,
+        node_id: &str,
+        detail: Option<String>,
+    ) -> Option<String> {
+        let correlation_id = self.keys.get(key)?.clone();
+        let events = self.events.entry(correlation_id.clone()).or_default();
+        if events.len() < MAX_EVENTS_PER_TRACE {
+            events.push(TraceEvent {
+                correlation_id: correlation_id.clone(),
+                service: service.to_string(),
+                stage: stage.to_string(),
+                node_id: node_id.to_string(),
+                at: unix_millis(),
+                detail,
+            });
+        }
+        Some(correlation_id)
+    }
+
+    pub fn events(&self, correlation_id: &str) -> Vec<TraceEvent> {
+        self.events.get(correlation_id).cloned().unwrap_or_default()
+    }
+}
+
+/// Records what a service did with a message on the local queue, returning
+/// the message's correlation ID for the service's own logs. Tracing never
+/// holds up or fails the caller, so errors only yield None.
+pub async fn record(content: &[u8], service: &str, stage: &str, detail: Option<String>) -> Option<String> {
+    let record = TraceRecord {
+        key: message_key(content),
+        service: service.to_string(),
+        stage: stage.to_string(),
+        detail,
+    };
+    reqwest::Client::new()
+        .post(format!("http://127.0.0.1:{QUEUE_PORT}/trace/record"))
+        .timeout(Duration::from_secs(2))
+        .json(&record)
+        .send()
+        .await
+        .ok()?
+        .json::<Option<String>>()
+        .await
+        .ok()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_registered_messages() {
+        let mut log = TraceLog::default();
+        let key = message_key(b"\x01{\"instance\":\"a\"}");
+        assert!(log.record(&key, "form-state", "received", "node-a", None).is_none());
+
+        log.register(&key, "abc");
+        log.register(&key, "ignored");
+        assert_eq!(log.correlation_id(&key), Some(&"abc".to_string()));
+        assert_eq!(log.record(&key, "form-state", "processed", "node-a", None), Some("abc".to_string()));
+
+        let events = log.events("abc");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].service, "form-state");
+        assert_eq!(events[0].stage, "processed");
+        assert!(log.events("ignored").is_empty());
+    }
+
+    #[test]
+    fn test_log_is_bounded() {
+        let mut log = TraceLog::default();
+        let first = message_key(b"0");
+        log.register(&first, "first");
+        log.record(&first, "form-p2p", "enqueued", "node-a", None);
+        for i in 1..=MAX_TRACES {
+            log.register(&message_key(i.to_string().as_bytes()), &i.to_string());
+        }
+        assert!(log.correlation_id(&first).is_none());
+        assert!(log.events("first").is_empty());
+
+        let last = message_key(MAX_TRACES.to_string().as_bytes());
+        for _ in 0..MAX_EVENTS_PER_TRACE + 5 {
+            log.record(&last, "form-pack", "failed", "node-a", None);
+        }
+        assert_eq!(log.events(&MAX_TRACES.to_string()).len(), MAX_EVENTS_PER_TRACE);
+    }
+}
@@ -58,7 +58,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     log::info!("form-pack-manager Node ID: {}", node_id);
     log::info!("form-pack-manager listening on: {}", addr);
 
-    let manager = FormPackManager::new(addr, node_id);
+    let manager = FormPackManager::new(addr, node_id).with_signing_key(pk);
     let (tx, rx) = channel(1);
     tokio::task::spawn(async move {
         if let Err(e) = manager.run(rx).await {
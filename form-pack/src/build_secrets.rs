@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Where `RUN --secret` steps find their secrets in the image. It is a tmpfs
+/// mounted for the duration of the step, so nothing is written to the disk.
+pub const SECRETS_MOUNT: &str = "/run/form-secrets";
+
+/// Shorter values are too likely to occur in the image by chance to be
+/// checked for leaks
+pub const MIN_CHECKED_SECRET_LEN: usize = 8;
+
+const SCAN_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Environment variable of the virt-customize script a secret is passed in,
+/// base64 encoded so it can be spliced into a quoted command
+pub fn secret_env_var(name: &str) -> String {
+    format!("FORM_BUILD_SECRET_{name}")
+}
+
+/// The script's environment holding the given secrets
+pub fn script_env(secrets: &BTreeMap<String, String>) -> Vec<(String, String)> {
+    secrets.iter()
+        .map(|(name, value)| (secret_env_var(name), STANDARD.encode(value)))
+        .collect()
+}
+
+/// Wraps a RUN command so the secrets are written to a fresh tmpfs and
+/// exported only while it runs. The result goes inside the single quotes of
+/// `--run-command`; the values are expanded from the script's environment by
+/// the host shell, so they never appear in the script itself.
+pub fn wrap_command(command: &str, secrets: &[String]) -> String {
+    let mut wrapped = format!("mkdir -p {SECRETS_MOUNT} && mount -t tmpfs -o size=1m,mode=0700 tmpfs {SECRETS_MOUNT}");
+    for name in secrets {
+        wrapped.push_str(&format!(
+            r#" && printf %s '"${}"' | base64 -d > {SECRETS_MOUNT}/{name} && export {name}="$(cat {SECRETS_MOUNT}/{name})""#,
+            secret_env_var(name)
+        ));
+    }
+    wrapped.push_str(&format!(
+        " && {{ {command} ; }}; status=$?; umount {SECRETS_MOUNT}; rmdir {SECRETS_MOUNT}; exit $status"
+    ));
+    wrapped
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    let Some((first, rest)) = needle.split_first() else {
+        return false;
+    };
+    let mut offset = 0;
+    while let Some(position) = haystack[offset..].iter().position(|byte| byte == first) {
+        let start = offset + position;
+        if haystack[start + 1..].starts_with(rest) {
+            return true;
+        }
+        offset = start + 1;
+    }
+    false
+}
+
+/// Names of the secrets whose values are found anywhere in the image,
+/// including deleted files that still occupy free blocks
+pub fn find_leaked_secrets(image: &Path, secrets: &BTreeMap<String, String>) -> std::io::Result<Vec<String>> {
+    let checked: Vec<(&String, &[u8])> = secrets.iter()
+        .filter(|(_, value)| value.len() >= MIN_CHECKED_SECRET_LEN)
+        .map(|(name, value)| (name, value.as_bytes()))
+        .collect();
+    let Some(longest) = checked.iter().map(|(_, value)| value.len()).max() else {
+        return Ok(Vec::new());
+    };
+
+    let mut file = File::open(image)?;
+    let mut leaked = Vec::new();
+    let mut buffer = Vec::with_capacity(SCAN_CHUNK_SIZE + longest);
+    let mut chunk = vec![0u8; SCAN_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        for (name, value) in &checked {
+            if !leaked.contains(*name) && contains(&buffer, value) {
+                leaked.push((*name).clone());
+            }
+        }
+        // Keep the tail so values spanning two chunks are found
+        let keep = buffer.len().min(longest - 1);
+        buffer.drain(..buffer.len() - keep);
+    }
+    Ok(leaked)
+}
+
+/// Plaintext values of the build's secrets, resolved from the local
+/// form-state like vmm-service does at boot
+pub async fn resolve_build_secrets(
+    build_id: &str,
+    names: &[String],
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Send + Sync>> {
+    if names.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let resp: serde_json::Value = reqwest::Client::new()
+        .get(format!("http://127.0.0.1:3004/v1/secrets/{build_id}/resolve"))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if !resp["success"].as_bool().unwrap_or(false) {
+        return Err(format!("Unable to resolve secrets for {build_id}: {}", resp["error"]).into());
+    }
+
+    let mut secrets: BTreeMap<String, String> = serde_json::from_value(resp["secrets"].clone())?;
+    secrets.retain(|name, _| names.contains(name));
+    if let Some(missing) = names.iter().find(|name| !secrets.contains_key(*name)) {
+        return Err(format!("Build secret {missing} is not set, create it with the secrets API before building").into());
+    }
+    Ok(secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_wrap_command() {
+        let wrapped = wrap_command("npm ci", &["NPM_TOKEN".to_string()]);
+        assert!(wrapped.starts_with("mkdir -p /run/form-secrets && mount -t tmpfs"));
+        assert!(wrapped.contains(r#"printf %s '"$FORM_BUILD_SECRET_NPM_TOKEN"' | base64 -d > /run/form-secrets/NPM_TOKEN"#));
+        assert!(wrapped.contains(r#"export NPM_TOKEN="$(cat /run/form-secrets/NPM_TOKEN)" && { npm ci ; }"#));
+        assert!(wrapped.ends_with("umount /run/form-secrets; rmdir /run/form-secrets; exit $status"));
+
+        let secrets = BTreeMap::from([("NPM_TOKEN".to_string(), "a'b".to_string())]);
+        assert_eq!(script_env(&secrets), vec![("FORM_BUILD_SECRET_NPM_TOKEN".to_string(), "YSdi".to_string())]);
+    }
+
+    #[test]
+    fn test_find_leaked_secrets() -> std::io::Result<()> {
+        let mut image = tempfile::NamedTempFile::new()?;
+        let mut content = vec![0u8; SCAN_CHUNK_SIZE - 4];
+        content.extend_from_slice(b"npm_s3cr3t_token");
+        content.extend_from_slice(&[0u8; 64]);
+        image.write_all(&content)?;
+
+        let secrets = BTreeMap::from([
+            ("NPM_TOKEN".to_string(), "npm_s3cr3t_token".to_string()),
+            ("PIP_TOKEN".to_string(), "pip_s3cr3t_token".to_string()),
+            ("SHORT".to_string(), "\0\0".to_string()),
+        ]);
+        assert_eq!(find_leaked_secrets(image.path(), &secrets)?, vec!["NPM_TOKEN".to_string()]);
+        Ok(())
+    }
+}
@@ -1,13 +1,14 @@
-use crate::formfile::Formfile;
-use form_state::nodes::Node;
+use crate::formfile::{ConfidentialMode, Formfile};
+use form_state::node_matching::{ConfidentialCompute, GpuRequirement, NodeMatch, NodeRequirements};
 use form_types::state::{Response as StateResponse, Success};
 use reqwest::Client;
-use std::collections::{HashMap, BTreeMap};
+use std::collections::BTreeMap;
 use std::error::Error;
-use log::{info, warn, error, debug};
+use log::{info, warn, error};
 
 /// A utility for matching workload requirements against node capabilities and capacity
-/// and determining which node is responsible for a workload
+/// and determining which node is responsible for a workload. The matching itself is
+/// done by form-state's `/nodes/match`.
 pub struct CapabilityMatcher {
     form_state_url: String,
     http_client: Client,
@@ -17,7 +18,7 @@ impl CapabilityMatcher {
     /// Create a new CapabilityMatcher with the given form-state URL
     pub fn new(form_state_url: Option<String>) -> Self {
         let form_state_url = form_state_url.unwrap_or_else(|| 
-            std::env::var("FORM_STATE_URL").unwrap_or_else(|_| "http://127.0.0.1:63210".to_string())
+            std::env::var("FORM_STATE_URL").unwrap_or_else(|_| "http://127.0.0.1:3004".to_string())
         );
         
         Self {
@@ -28,21 +29,12 @@ impl CapabilityMatcher {
 
     /// Check if the local node has the capability and capacity to handle the workload
     pub async fn is_local_node_capable(&self, formfile: &Formfile, node_id: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
-        let node = self.get_node(node_id).await?;
-        
-        match node {
-            Some(node) => {
-                let (is_capable, reason) = self.check_node_capability(&node, formfile);
-                if !is_capable {
-                    info!("Local node is not capable of handling workload: {}", reason);
-                }
-                Ok(is_capable)
-            },
-            None => {
-                warn!("Could not find local node with ID {} in form-state", node_id);
-                Ok(false)
-            }
+        let candidates = self.get_capable_nodes(formfile).await?;
+        let is_capable = candidates.iter().any(|candidate| candidate.node_id == node_id);
+        if !is_capable {
+            info!("Local node {} is not among the {} nodes matching the workload", node_id, candidates.len());
         }
+        Ok(is_capable)
     }
 
     /// Check if the local node is responsible for handling this workload
@@ -54,12 +46,6 @@ impl CapabilityMatcher {
         local_node_id: &str, 
         build_id: &str
     ) -> Result<bool, Box<dyn Error + Send + Sync>> {
-        // First check if the local node is capable
-        if !self.is_local_node_capable(formfile, local_node_id).await? {
-            return Ok(false);
-        }
-        
-        // Get all capable nodes
         let capable_nodes = self.get_capable_nodes(formfile).await?;
         
         if capable_nodes.is_empty() {
@@ -67,6 +53,12 @@ impl CapabilityMatcher {
             return Ok(false);
         }
         
+        // The local node has to be capable itself
+        if !capable_nodes.iter().any(|node| node.node_id == local_node_id) {
+            info!("Local node {} is not capable of handling the workload", local_node_id);
+            return Ok(false);
+        }
+        
         let responsible_nodes = self.select_responsible_nodes(capable_nodes, build_id, 1);
         if responsible_nodes.is_empty() {
             warn!("No responsible nodes determined for build {}", build_id);
@@ -93,12 +85,6 @@ impl CapabilityMatcher {
         build_id: &str,
         cluster_size: usize
     ) -> Result<bool, Box<dyn Error + Send + Sync>> {
-        // First check if the local node is capable
-        if !self.is_local_node_capable(formfile, local_node_id).await? {
-            return Ok(false);
-        }
-        
-        // Get all capable nodes
         let capable_nodes = self.get_capable_nodes(formfile).await?;
         
         if capable_nodes.is_empty() {
@@ -106,6 +92,12 @@ impl CapabilityMatcher {
             return Ok(false);
         }
         
+        // The local node has to be capable itself
+        if !capable_nodes.iter().any(|node| node.node_id == local_node_id) {
+            info!("Local node {} is not capable of handling the workload", local_node_id);
+            return Ok(false);
+        }
+        
         // If we have fewer nodes than the requested cluster size, all capable nodes form the cluster
         let effective_cluster_size = if capable_nodes.len() < cluster_size {
             capable_nodes.len()
@@ -134,14 +126,16 @@ impl CapabilityMatcher {
     }
     
     /// Select the responsible nodes for a workload using XOR of build_id and node_id
-    /// Returns the top N nodes with the lowest XOR values
-    fn select_responsible_nodes(&self, nodes: Vec<Node>, build_id: &str, count: usize) -> Vec<Node> {
+    /// Returns the top N nodes with the lowest XOR values. The XOR, rather than the
+    /// match ranking, decides, since it doesn't shift as utilization changes and every
+    /// node has to come to the same answer.
+    fn select_responsible_nodes(&self, nodes: Vec<NodeMatch>, build_id: &str, count: usize) -> Vec<NodeMatch> {
         if nodes.is_empty() || count == 0 {
             return Vec::new();
         }
         
         // Calculate XOR values for each node
-        let mut node_xor_values: BTreeMap<u64, Node> = BTreeMap::new();
+        let mut node_xor_values: BTreeMap<u64, NodeMatch> = BTreeMap::new();
         
         for node in nodes {
             let xor_value = self.calculate_xor_value(&node.node_id, build_id);
@@ -174,161 +168,57 @@ impl CapabilityMatcher {
         hasher.finish()
     }
 
-    /// Get a list of all nodes from form-state that are capable of handling the workload
-    pub async fn get_capable_nodes(&self, formfile: &Formfile) -> Result<Vec<Node>, Box<dyn Error + Send + Sync>> {
-        let nodes = self.get_all_nodes().await?;
-        
-        let capable_nodes: Vec<Node> = nodes.into_iter()
-            .filter(|node| {
-                let (is_capable, reason) = self.check_node_capability(node, formfile);
-                if !is_capable {
-                    debug!("Node {} is not capable: {}", node.node_id, reason);
-                }
-                is_capable
-            })
-            .collect();
-            
-        info!("Found {} nodes capable of handling workload", capable_nodes.len());
-        Ok(capable_nodes)
-    }
+    /// The requirements form-state matches nodes against
+    pub fn requirements(formfile: &Formfile) -> NodeRequirements {
+        // Entries are `model` or `model:count`
+        let gpus = formfile.get_gpu_devices().unwrap_or_default().into_iter().map(|gpu_req| {
+            let (model, count) = gpu_req.split_once(':')
+                .map_or((gpu_req.as_str(), 1), |(model, count)| (model, count.parse().unwrap_or(1)));
+            GpuRequirement { model: model.to_string(), count }
+        }).collect();
 
-    /// Check if a node can handle the workload defined in the formfile
-    /// Returns (is_capable, reason) where reason is a string explaining why the node is not capable (if applicable)
-    fn check_node_capability(&self, node: &Node, formfile: &Formfile) -> (bool, String) {
-        // Check CPU requirements
-        let vcpus = formfile.get_vcpus() as usize;
-        if node.capabilities.cpu_cores < vcpus {
-            return (false, format!("Node has {} CPU cores, but workload requires {}", 
-                node.capabilities.cpu_cores, vcpus));
+        NodeRequirements {
+            vcpus: formfile.get_vcpus() as u32,
+            memory_mb: formfile.get_memory() as u64,
+            disk_gb: formfile.get_storage().unwrap_or(0) as u64,
+            gpus,
+            region: None,
+            confidential: formfile.get_confidential().map(|mode| match mode {
+                ConfidentialMode::SevSnp => ConfidentialCompute::SevSnp,
+                ConfidentialMode::Tdx => ConfidentialCompute::Tdx,
+            }),
+            min_bandwidth_mbps: None,
+            public_ip: false,
+            account: None,
+            limit: None,
         }
-        
-        // Check if the node has enough available CPU capacity
-        if ((node.capacity.cpu_available_cores / 1000) as usize) < vcpus {
-            return (false, format!("Node only has {} available CPU cores, but workload requires {}", 
-                node.capacity.cpu_available_cores / 1000, vcpus));
-        }
-        
-        // Check memory requirements
-        let memory_mb = formfile.get_memory();
-        let memory_bytes = memory_mb as u64 * 1024 * 1024; // Convert MB to bytes
-        if node.capacity.memory_available_bytes < memory_bytes {
-            return (false, format!("Node only has {} MB available memory, but workload requires {} MB",
-                node.capacity.memory_available_bytes / (1024 * 1024), memory_mb));
-        }
-        
-        // Check storage requirements
-        if let Some(storage_gb) = formfile.get_storage() {
-            let storage_bytes = storage_gb as u64 * 1024 * 1024 * 1024; // Convert GB to bytes
-            if node.capacity.storage_available_bytes < storage_bytes {
-                return (false, format!("Node only has {} GB available storage, but workload requires {} GB",
-                    node.capacity.storage_available_bytes / (1024 * 1024 * 1024), storage_gb));
-            }
-        }
-        
-        // Check GPU requirements
-        if let Some(gpu_devices) = formfile.get_gpu_devices() {
-            if gpu_devices.is_empty() {
-                // No GPU required
-                return (true, String::new());
-            }
-            
-            if node.capabilities.gpu_models.is_empty() {
-                return (false, "Workload requires GPU but node has no GPUs".to_string());
-            }
-            
-            // Parse GPU requirements from the formfile
-            let mut required_gpus: HashMap<String, u8> = HashMap::new();
-            for gpu_req in gpu_devices {
-                let parts: Vec<&str> = gpu_req.split(':').collect();
-                let model = parts[0].to_string();
-                let count: u8 = if parts.len() > 1 {
-                    parts[1].parse().unwrap_or(1)
-                } else {
-                    1
-                };
-                
-                *required_gpus.entry(model).or_insert(0) += count;
-            }
-            
-            // Check if the node has the required GPUs
-            let mut available_gpus: HashMap<String, u8> = HashMap::new();
-            for gpu in &node.capabilities.gpu_models {
-                if let Some(model) = &gpu.model {
-                    *available_gpus.entry(model.clone()).or_insert(0) += gpu.count as u8;
-                }
-            }
-            
-            for (model, count) in required_gpus {
-                match available_gpus.get(&model) {
-                    Some(available_count) if *available_count >= count => {
-                        // GPU requirement satisfied
-                    },
-                    Some(available_count) => {
-                        return (false, format!("Node has {} of GPU {}, but workload requires {}", 
-                            available_count, model, count));
-                    },
-                    None => {
-                        return (false, format!("Node has no GPU of model {}", model));
-                    }
-                }
-            }
-        }
-        
-        // All requirements are satisfied
-        (true, String::new())
     }
 
-    /// Get a specific node from form-state
-    async fn get_node(&self, node_id: &str) -> Result<Option<Node>, Box<dyn Error + Send + Sync>> {
-        let url = format!("{}/nodes/{}", self.form_state_url, node_id);
-        
-        match self.http_client.get(&url).send().await {
-            Ok(response) => {
-                match response.json::<StateResponse<Node>>().await {
-                    Ok(StateResponse::Success(Success::Some(node))) => {
-                        Ok(Some(node))
-                    },
-                    Ok(_) => {
-                        warn!("Unexpected response format when getting node {}", node_id);
-                        Ok(None)
-                    },
-                    Err(e) => {
-                        error!("Error deserializing response for node {}: {}", node_id, e);
-                        Err(Box::new(e))
-                    }
-                }
-            },
-            Err(e) => {
-                error!("Error fetching node {}: {}", node_id, e);
-                Err(Box::new(e))
-            }
-        }
-    }
+    /// Get the nodes capable of handling the workload from form-state, best match first
+    pub async fn get_capable_nodes(&self, formfile: &Formfile) -> Result<Vec<NodeMatch>, Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/v1/nodes/match", self.form_state_url);
+        let requirements = Self::requirements(formfile);
 
-    /// Get all nodes from form-state
-    async fn get_all_nodes(&self) -> Result<Vec<Node>, Box<dyn Error + Send + Sync>> {
-        let url = format!("{}/nodes/list", self.form_state_url);
-        
-        match self.http_client.get(&url).send().await {
-            Ok(response) => {
-                match response.json::<StateResponse<Node>>().await {
-                    Ok(StateResponse::Success(Success::List(nodes))) => {
-                        Ok(nodes)
-                    },
-                    Ok(_) => {
-                        warn!("Unexpected response format when listing nodes");
-                        Ok(Vec::new())
-                    },
-                    Err(e) => {
-                        error!("Error deserializing node list response: {}", e);
-                        Err(Box::new(e))
-                    }
-                }
+        let response = self.http_client.post(&url).json(&requirements).send().await.map_err(|e| {
+            error!("Error matching nodes against {:?}: {}", requirements, e);
+            e
+        })?;
+        match response.json::<StateResponse<NodeMatch>>().await {
+            Ok(StateResponse::Success(Success::List(candidates))) => {
+                info!("Found {} nodes capable of handling workload", candidates.len());
+                Ok(candidates)
+            },
+            Ok(StateResponse::Failure { reason }) => {
+                Err(format!("form-state could not match nodes: {}", reason.unwrap_or_default()).into())
+            },
+            Ok(_) => {
+                warn!("Unexpected response format when matching nodes");
+                Ok(Vec::new())
             },
             Err(e) => {
-                error!("Error fetching node list: {}", e);
+                error!("Error deserializing node match response: {}", e);
                 Err(Box::new(e))
             }
         }
     }
-} 
\ No newline at end of file
+}
@@ -3,6 +3,8 @@ use serde_json::Value;
 use sha_crypt::{sha512_crypt_b64, Sha512Params};
 use serde::{Serialize, Deserialize};
 use std::{collections::{HashMap, HashSet}, path::{Component, PathBuf}};
+pub use form_types::healthcheck::{HealthCheck, HealthProbe};
+pub use form_types::devsync::{DevRestart, DevSync};
 
 pub struct FormfileParser {
     current_line: usize,
@@ -14,6 +16,10 @@ pub struct FormfileParser {
     system_config: Vec<SystemConfigOpt>,
     users: Vec<User>,
     workdir: Option<PathBuf>,
+    build_secrets: Vec<String>,
+    stages: Vec<BuildStage>,
+    /// Name of the stage being parsed and the stage it starts from
+    stage: Option<(String, Option<String>)>,
 }
 
 impl FormfileParser {
@@ -28,6 +34,9 @@ impl FormfileParser {
             system_config: Vec::new(),
             users: Vec::new(),
             workdir: None,
+            build_secrets: Vec::new(),
+            stages: Vec::new(),
+            stage: None,
         }
     }
 
@@ -78,13 +87,21 @@ impl FormfileParser {
             "COPY" => self.parse_copy(args)?,
             "INSTALL" => self.parse_install(args)?,
             "ENV" => self.parse_env(args)?,
+            "SECRET" => self.parse_secret(args)?,
             "USER" => self.parse_user(args)?,
             "VCPU" | "CPU" | "CORES" => self.parse_vcpus(args)?,
             "MEMORY" | "MEM" | "MBS" => self.parse_memory(args)?,
             "DISK" | "STORAGE" => self.parse_disk(args)?,
             "GPU" => self.parse_gpu(args)?,
+            "BANDWIDTH" => self.parse_bandwidth(args)?,
+            "MEMORY_TIER" => self.parse_memory_tier(args)?,
+            "CONFIDENTIAL" => self.parse_confidential(args)?,
+            "FIREWALL" => self.parse_firewall(args)?,
             "WORKDIR" => self.parse_workdir(args)?,
             "ENTRYPOINT" => self.parse_entrypoint(args)?,
+            "HEALTHCHECK" => self.parse_healthcheck(args)?,
+            "DEVSYNC" => self.parse_devsync(args)?,
+            "STAGE" => self.parse_stage(args)?,
             _ => {}
         }
 
@@ -137,13 +154,76 @@ impl FormfileParser {
     }
 
     fn parse_run(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
-        self.instructions.push(
-            BuildInstruction::Run(args.to_string())
-        );
+        let Some(secrets) = args.strip_prefix("--secret=") else {
+            self.instructions.push(
+                BuildInstruction::Run(args.to_string())
+            );
+            return Ok(());
+        };
+
+        let (secrets, command) = secrets.split_once(' ').unwrap_or((secrets, ""));
+        let command = command.trim();
+        if command.is_empty() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("RUN on line {} has secrets but no command. Expected: RUN --secret=NAME[,NAME] <command>", self.current_line)
+            )));
+        }
+        let secrets: Vec<String> = secrets.split(',').map(|name| name.trim().to_string()).collect();
+        if let Some(name) = secrets.iter().find(|name| !self.build_secrets.contains(name)) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("RUN on line {} uses secret {:?}, which has no SECRET declaration before it", self.current_line, name)
+            )));
+        }
+        self.instructions.push(BuildInstruction::RunWithSecrets(secrets, command.to_string()));
         Ok(())
     }
 
+    /// Declares a build secret. Its value is the build's secret of the same
+    /// name, which is only exposed to `RUN --secret=NAME` steps.
+    pub fn parse_secret(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let name = args.trim();
+        if name.contains(char::is_whitespace) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Invalid SECRET format on line {}. Expected: SECRET NAME", self.current_line)
+            )));
+        }
+        // The secret is exported under its own name during RUN steps
+        let (name, _) = self.parse_env_pair(&format!("{name}="))?;
+        if self.build_secrets.contains(&name) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("SECRET {} on line {} is already declared", name, self.current_line)
+            )));
+        }
+        if self.env_keys().any(|key| *key == name) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("SECRET {} on line {} is also set with ENV, which would bake it into the image", name, self.current_line)
+            )));
+        }
+        self.build_secrets.push(name);
+        Ok(())
+    }
+
+    fn env_keys(&self) -> impl Iterator<Item = &String> {
+        let staged = self.stages.iter().flat_map(|stage| stage.instructions.iter());
+        staged.chain(self.instructions.iter()).filter_map(|instruction| match instruction {
+            BuildInstruction::Env(var) => Some(&var.key),
+            _ => None,
+        })
+    }
+
     fn parse_copy(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (source_stage, args) = match args.trim_start().strip_prefix("--from=") {
+            Some(rest) => {
+                let (stage, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                (Some(stage.to_string()), rest)
+            }
+            None => (None, args),
+        };
         let parts: Vec<&str> = args.split_whitespace().map(|s| s).collect();
         if parts.len() < 2 {
             return Err(
@@ -166,12 +246,79 @@ impl FormfileParser {
         }
         let from = parts[0];
         let to = parts[1];
+        let Some(stage) = source_stage else {
+            self.instructions.push(
+                BuildInstruction::Copy(PathBuf::from(from), PathBuf::from(to))
+            );
+            return Ok(());
+        };
+
+        if !self.stages.iter().any(|built| built.name == stage) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("COPY --from={} on line {} names no stage before it", stage, self.current_line)
+            )));
+        }
+        if !from.starts_with('/') {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("COPY --from={} on line {} has to copy an absolute path out of the stage", stage, self.current_line)
+            )));
+        }
         self.instructions.push(
-            BuildInstruction::Copy(PathBuf::from(from), PathBuf::from(to))
+            BuildInstruction::CopyFrom(stage, PathBuf::from(from), PathBuf::from(to))
         );
         Ok(())
     }
 
+    /// Starts a named stage. The build instructions after it belong to the
+    /// stage until the next STAGE line, the last stage is the image. A stage
+    /// starts from the base image or, with FROM, from an earlier stage.
+    pub fn parse_stage(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = args.split_whitespace().collect();
+        let (name, from) = match parts.as_slice() {
+            [name] => (*name, None),
+            [name, keyword, from] if keyword.eq_ignore_ascii_case("FROM") => (*name, Some(from.to_string())),
+            _ => return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Invalid STAGE format on line {}. Expected: STAGE NAME [FROM STAGE]", self.current_line)
+            ))),
+        };
+        if !is_valid_stage_name(name) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Invalid STAGE name {:?} on line {}. Use lowercase letters, digits, '-', '_' and '.'", name, self.current_line)
+            )));
+        }
+
+        match self.stage.take() {
+            Some((previous, previous_from)) => self.stages.push(BuildStage {
+                name: previous,
+                from: previous_from,
+                instructions: std::mem::take(&mut self.instructions),
+            }),
+            None if !self.instructions.is_empty() => return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("STAGE on line {} follows build instructions of no stage. In a multi-stage Formfile every build instruction belongs to a stage", self.current_line)
+            ))),
+            None => {}
+        }
+        if self.stages.iter().any(|stage| stage.name == name) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("STAGE {} on line {} is already declared", name, self.current_line)
+            )));
+        }
+        if let Some(from) = from.as_ref().filter(|from| !self.stages.iter().any(|stage| stage.name == **from)) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("STAGE {} on line {} starts from {}, which is no stage before it", name, self.current_line, from)
+            )));
+        }
+        self.stage = Some((name.to_string(), from));
+        Ok(())
+    }
+
     fn split_command_string(&self, input: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let mut result = Vec::new();
         let mut current = String::new();
@@ -792,6 +939,14 @@ impl FormfileParser {
         }
 
         let (key, value) = self.parse_env_pair(parts[0])?;
+        if self.build_secrets.contains(&key) {
+            return Err(Box::new(
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("ENV {} on line {} would bake the build secret of the same name into the image", key, self.current_line)
+                )
+            ));
+        }
 
         let env_var = EnvVariable {
             key,
@@ -1003,6 +1158,236 @@ impl FormfileParser {
         Ok(())
     }
 
+    pub fn parse_bandwidth(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Parse format like "ingress=100 egress=50", values are in Mbps
+        let mut ingress_mbps = None;
+        let mut egress_mbps = None;
+        for arg in args.split_whitespace() {
+            let (key, value) = arg.split_once('=').ok_or(
+                Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Invalid BANDWIDTH argument on line {}: {}. Expected ingress=<mbps> or egress=<mbps>", self.current_line, arg)
+                ))
+            )?;
+            let mbps = value.parse::<u32>().map_err(|_| {
+                Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Invalid BANDWIDTH value on line {}: {}. Must be a number of Mbps", self.current_line, value)
+                ))
+            })?;
+            if mbps == 0 {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Invalid BANDWIDTH value on line {}: caps must be at least 1 Mbps", self.current_line)
+                )));
+            }
+            match key.to_lowercase().as_str() {
+                "ingress" | "in" => ingress_mbps = Some(mbps),
+                "egress" | "out" => egress_mbps = Some(mbps),
+                _ => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("Unknown BANDWIDTH direction on line {}: {}. Expected ingress or egress", self.current_line, key)
+                    )));
+                }
+            }
+        }
+
+        if ingress_mbps.is_none() && egress_mbps.is_none() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("BANDWIDTH requires at least one of ingress=<mbps> or egress=<mbps>: line {}", self.current_line)
+            )));
+        }
+
+        self.system_config.push(SystemConfigOpt::Bandwidth(BandwidthLimit { ingress_mbps, egress_mbps }));
+        Ok(())
+    }
+
+    pub fn parse_memory_tier(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Parse format like "burstable", decides how much memory the host may reclaim
+        let tier = match args.trim().to_lowercase().as_str() {
+            "guaranteed" => MemoryTier::Guaranteed,
+            "standard" => MemoryTier::Standard,
+            "burstable" => MemoryTier::Burstable,
+            other => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Invalid MEMORY_TIER on line {}: {}. Expected guaranteed, standard or burstable", self.current_line, other)
+                )));
+            }
+        };
+        self.system_config.push(SystemConfigOpt::MemoryTier(tier));
+        Ok(())
+    }
+
+    pub fn parse_confidential(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Parse format like "sev-snp", launches the instance as a confidential VM
+        let mode = match args.trim().to_lowercase().as_str() {
+            "sev-snp" | "sev_snp" | "snp" => ConfidentialMode::SevSnp,
+            "tdx" => ConfidentialMode::Tdx,
+            other => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Invalid CONFIDENTIAL on line {}: {}. Expected sev-snp or tdx", self.current_line, other)
+                )));
+            }
+        };
+        self.system_config.push(SystemConfigOpt::Confidential(mode));
+        Ok(())
+    }
+
+    pub fn parse_firewall(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Parse format like "allow tcp 80,443", "deny egress udp 1000-2000"
+        // or "formnet-only"
+        let invalid = |msg: String| Box::new(std::io::Error::new(std::io::ErrorKind::Other, msg));
+        let mut parts = args.split_whitespace().peekable();
+        let action = match parts.next() {
+            Some("formnet-only") => {
+                self.system_config.push(SystemConfigOpt::FormnetOnly);
+                return Ok(());
+            }
+            Some("allow") => FirewallAction::Allow,
+            Some("deny") => FirewallAction::Deny,
+            other => return Err(invalid(format!(
+                "Invalid FIREWALL action on line {}: {:?}. Expected allow, deny or formnet-only", self.current_line, other
+            ))),
+        };
+
+        let direction = match parts.peek() {
+            Some(&"ingress") => { parts.next(); TrafficDirection::Ingress }
+            Some(&"egress") => { parts.next(); TrafficDirection::Egress }
+            _ => TrafficDirection::Ingress,
+        };
+
+        let protocol = match parts.next() {
+            Some("tcp") => FirewallProtocol::Tcp,
+            Some("udp") => FirewallProtocol::Udp,
+            Some("any") => FirewallProtocol::Any,
+            other => return Err(invalid(format!(
+                "Invalid FIREWALL protocol on line {}: {:?}. Expected tcp, udp or any", self.current_line, other
+            ))),
+        };
+
+        let mut ports = Vec::new();
+        for spec in parts.flat_map(|p| p.split(',')).filter(|p| !p.is_empty()) {
+            let range = match spec.split_once('-') {
+                Some((start, end)) => PortRange {
+                    start: start.parse().map_err(|_| invalid(format!("Invalid port on line {}: {}", self.current_line, start)))?,
+                    end: end.parse().map_err(|_| invalid(format!("Invalid port on line {}: {}", self.current_line, end)))?,
+                },
+                None => {
+                    let port = spec.parse().map_err(|_| invalid(format!("Invalid port on line {}: {}", self.current_line, spec)))?;
+                    PortRange { start: port, end: port }
+                }
+            };
+            if range.start == 0 || range.start > range.end {
+                return Err(invalid(format!("Invalid port range on line {}: {}", self.current_line, spec)));
+            }
+            ports.push(range);
+        }
+
+        if ports.is_empty() && protocol != FirewallProtocol::Any {
+            return Err(invalid(format!(
+                "FIREWALL rules for tcp or udp require at least one port: line {}", self.current_line
+            )));
+        }
+
+        self.system_config.push(SystemConfigOpt::Firewall(FirewallRule { action, direction, protocol, ports }));
+        Ok(())
+    }
+
+    pub fn parse_healthcheck(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Parse format like "--interval=10s --retries=5 HTTP 8080 /healthz"
+        // or "--timeout=3s CMD pg_isready -U postgres"
+        let invalid = |msg: String| Box::new(std::io::Error::new(std::io::ErrorKind::Other, msg));
+        let mut check = HealthCheck::new(HealthProbe::Command(String::new()));
+        let mut rest = args.trim();
+        while let Some(option) = rest.strip_prefix("--") {
+            let (option, remaining) = option.split_once(char::is_whitespace).unwrap_or((option, ""));
+            rest = remaining.trim_start();
+            let (key, value) = option.split_once('=').ok_or_else(|| invalid(format!(
+                "Invalid HEALTHCHECK option on line {}: --{}. Expected --<option>=<value>", self.current_line, option
+            )))?;
+            match key {
+                "interval" => check.interval_secs = self.parse_probe_duration(value)?,
+                "timeout" => check.timeout_secs = self.parse_probe_duration(value)?,
+                "start-period" => check.start_period_secs = self.parse_probe_duration(value)?,
+                "retries" => check.retries = value.parse().map_err(|_| invalid(format!(
+                    "Invalid HEALTHCHECK retries on line {}: {}", self.current_line, value
+                )))?,
+                _ => return Err(invalid(format!(
+                    "Unknown HEALTHCHECK option on line {}: --{}. Expected interval, timeout, retries or start-period", self.current_line, key
+                ))),
+            }
+        }
+
+        let (kind, probe) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let probe = probe.trim();
+        check.probe = match kind {
+            "CMD" if !probe.is_empty() => HealthProbe::Command(probe.to_string()),
+            "HTTP" => {
+                let mut parts = probe.split_whitespace();
+                let port = parts.next()
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .filter(|port| *port != 0)
+                    .ok_or_else(|| invalid(format!("HEALTHCHECK HTTP requires a port: line {}", self.current_line)))?;
+                let path = parts.next().unwrap_or("/");
+                if !path.starts_with('/') {
+                    return Err(invalid(format!("Invalid HEALTHCHECK path on line {}: {}. Must start with /", self.current_line, path)));
+                }
+                HealthProbe::Http { port, path: path.to_string() }
+            }
+            _ => return Err(invalid(format!(
+                "Invalid HEALTHCHECK on line {}: {}. Expected CMD <command> or HTTP <port> [path]", self.current_line, args
+            ))),
+        };
+
+        if check.interval_secs == 0 || check.timeout_secs == 0 || check.retries == 0 {
+            return Err(invalid(format!(
+                "HEALTHCHECK interval, timeout and retries must be greater than 0: line {}", self.current_line
+            )));
+        }
+
+        self.system_config.retain(|opt| !matches!(opt, SystemConfigOpt::HealthCheck(_)));
+        self.system_config.push(SystemConfigOpt::HealthCheck(check));
+        Ok(())
+    }
+
+    pub fn parse_devsync(&mut self, args: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Parse format like "SERVICE", "CMD pm2 reload all" or "NONE"
+        let (kind, command) = args.trim().split_once(char::is_whitespace).unwrap_or((args.trim(), ""));
+        let command = command.trim();
+        let restart = match kind {
+            "SERVICE" if command.is_empty() => DevRestart::Service,
+            "NONE" if command.is_empty() => DevRestart::None,
+            "CMD" if !command.is_empty() => DevRestart::Command(command.to_string()),
+            _ => return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Invalid DEVSYNC on line {}: {}. Expected SERVICE, CMD <command> or NONE", self.current_line, args)
+            ))),
+        };
+
+        self.system_config.retain(|opt| !matches!(opt, SystemConfigOpt::DevSync(_)));
+        self.system_config.push(SystemConfigOpt::DevSync(DevSync { restart, owner: None }));
+        Ok(())
+    }
+
+    /// Parses a duration like "30s", "2m" or "45" into seconds
+    fn parse_probe_duration(&self, value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let (number, multiplier) = if let Some(minutes) = value.strip_suffix('m') {
+            (minutes, 60)
+        } else {
+            (value.strip_suffix('s').unwrap_or(value), 1)
+        };
+        number.parse::<u64>()
+            .map(|n| n * multiplier)
+            .map_err(|_| Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Invalid HEALTHCHECK duration on line {}: {}. Expected seconds like 30s or minutes like 2m", self.current_line, value)
+            )) as Box<dyn std::error::Error>)
+    }
+
     pub fn build_formfile(&self) -> Result<Formfile, Box<dyn std::error::Error>> {
         let name = self.name.clone().ok_or(
             Box::new(
@@ -1024,10 +1409,18 @@ impl FormfileParser {
             system_config: self.system_config.clone(),
             users: self.users.clone(),
             workdir,
+            build_secrets: self.build_secrets.clone(),
+            stages: self.stages.clone(),
+            base_stage: self.stage.as_ref().and_then(|(_, from)| from.clone()),
         })
     }
 }
 
+fn is_valid_stage_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.'))
+}
+
 
 /// Represents a complete parsed Formfile with all of its instructions
 /// and configurations
@@ -1044,7 +1437,18 @@ pub struct Formfile {
     /// User configurations
     pub users: Vec<User>,
     /// Working directory for the application
-    pub workdir: PathBuf
+    pub workdir: PathBuf,
+    /// Names of the build's secrets that `RUN --secret` steps may use. The
+    /// values are resolved on the build node and never enter the image.
+    #[serde(default)]
+    pub build_secrets: Vec<String>,
+    /// Stages of a multi-stage Formfile before the last one, in order. The
+    /// last stage is the image, its instructions are `build_instructions`.
+    #[serde(default)]
+    pub stages: Vec<BuildStage>,
+    /// The stage the image starts from, None for the base image
+    #[serde(default)]
+    pub base_stage: Option<String>,
 }
 
 impl Formfile {
@@ -1056,6 +1460,13 @@ impl Formfile {
                 "system_config": self.system_config.iter().map(|opt| opt.to_json()).collect::<Vec<String>>(),
                 "users": self.users.iter().map(|user| user.to_json()).collect::<Vec<String>>(),
                 "workdir": self.workdir.to_string_lossy(),
+                "build_secrets": self.build_secrets,
+                "stages": self.stages.iter().map(|stage| serde_json::json!({
+                    "name": stage.name,
+                    "from": stage.from,
+                    "build_instructions": stage.instructions.iter().map(|inst| inst.to_json()).collect::<Vec<String>>(),
+                })).collect::<Vec<Value>>(),
+                "base_stage": self.base_stage,
             }
         }).to_string()
     }
@@ -1106,6 +1517,83 @@ impl Formfile {
         }).cloned()
     }
 
+    /// Get the bandwidth caps specified in the Formfile, later BANDWIDTH
+    /// lines override the directions they set
+    pub fn get_bandwidth(&self) -> BandwidthLimit {
+        self.system_config.iter().fold(BandwidthLimit::default(), |acc, opt| {
+            match opt {
+                SystemConfigOpt::Bandwidth(limit) => BandwidthLimit {
+                    ingress_mbps: limit.ingress_mbps.or(acc.ingress_mbps),
+                    egress_mbps: limit.egress_mbps.or(acc.egress_mbps),
+                },
+                _ => acc,
+            }
+        })
+    }
+
+    /// Get the memory tier specified in the Formfile, the last MEMORY_TIER
+    /// line wins and `Standard` is used when there is none
+    pub fn get_memory_tier(&self) -> MemoryTier {
+        self.system_config.iter().rev().find_map(|opt| {
+            match opt {
+                SystemConfigOpt::MemoryTier(tier) => Some(*tier),
+                _ => None,
+            }
+        }).unwrap_or_default()
+    }
+
+    /// Get the confidential computing technology the instance must be
+    /// launched with, if any, the last CONFIDENTIAL line wins
+    pub fn get_confidential(&self) -> Option<ConfidentialMode> {
+        self.system_config.iter().rev().find_map(|opt| {
+            match opt {
+                SystemConfigOpt::Confidential(mode) => Some(*mode),
+                _ => None,
+            }
+        })
+    }
+
+    /// Get the firewall rules specified in the Formfile, in order
+    pub fn get_firewall_rules(&self) -> Vec<FirewallRule> {
+        self.system_config.iter().filter_map(|opt| {
+            match opt {
+                SystemConfigOpt::Firewall(rule) => Some(rule.clone()),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// The `HEALTHCHECK` gating the instance's readiness, None if the
+    /// instance is ready as soon as it boots
+    pub fn get_health_check(&self) -> Option<HealthCheck> {
+        self.system_config.iter().find_map(|opt| match opt {
+            SystemConfigOpt::HealthCheck(check) => Some(check.clone()),
+            _ => None,
+        })
+    }
+
+    /// The `DEVSYNC` settings, None if files can't be synced into the instance
+    pub fn get_dev_sync(&self) -> Option<DevSync> {
+        self.system_config.iter().find_map(|opt| match opt {
+            SystemConfigOpt::DevSync(sync) => Some(sync.clone()),
+            _ => None,
+        })
+    }
+
+    /// Records the build's owner as the only address allowed to sync files
+    /// into its instances, a no-op without `DEVSYNC`
+    pub fn set_dev_sync_owner(&mut self, owner: &str) {
+        for opt in &mut self.system_config {
+            if let SystemConfigOpt::DevSync(sync) = opt {
+                sync.owner = Some(owner.to_string());
+            }
+        }
+    }
+
+    pub fn is_formnet_only(&self) -> bool {
+        self.system_config.iter().any(|opt| matches!(opt, SystemConfigOpt::FormnetOnly))
+    }
+
     pub fn get_description(&self) -> Option<&str> {
         self.description.as_deref()
     }
@@ -1117,6 +1605,44 @@ impl Formfile {
     pub fn is_model_required(&self) -> bool {
         self.model_required
     }
+
+    /// The COPY instructions of every stage, as (from, to). Their sources
+    /// are what the build context has to provide.
+    pub fn context_copies(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.stages.iter()
+            .flat_map(|stage| stage.instructions.iter())
+            .chain(self.build_instructions.iter())
+            .filter_map(|inst| match inst {
+                BuildInstruction::Copy(from, to) => Some((from.clone(), to.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A named stage of a multi-stage Formfile. It's built into an image of its
+/// own, which later stages start from or copy files out of.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildStage {
+    pub name: String,
+    /// The stage it starts from, None for the base image
+    pub from: Option<String>,
+    pub instructions: Vec<BuildInstruction>,
+}
+
+impl BuildStage {
+    /// The stages this one has to wait for
+    pub fn dependencies(&self) -> impl Iterator<Item = &str> {
+        self.from.as_deref().into_iter().chain(copy_sources(&self.instructions))
+    }
+}
+
+/// The stages `COPY --from` instructions copy out of
+pub fn copy_sources(instructions: &[BuildInstruction]) -> impl Iterator<Item = &str> {
+    instructions.iter().filter_map(|inst| match inst {
+        BuildInstruction::CopyFrom(stage, ..) => Some(stage.as_str()),
+        _ => None,
+    })
 }
 
 /// Instructions that are executed during teh image build phase
@@ -1124,12 +1650,19 @@ impl Formfile {
 pub enum BuildInstruction {
     /// Run a command in the image as root
     Run(String),
+    /// Run a command with the named build secrets exported as environment
+    /// variables and readable under `/run/form-secrets`, a tmpfs that is
+    /// unmounted when the command exits
+    RunWithSecrets(Vec<String>, String),
     /// Copy files from the build context to a temporary artifacts
     /// directory that will be tarballed.
     /// if none is provided a default . will be added, and ALL files
     /// from the directory will be copied into the artifacts directory,
     /// tarballed and then copied into the WORKDIR
     Copy(PathBuf, PathBuf),
+    /// Copy a path out of the image of an earlier stage, as
+    /// `COPY --from=<stage> <from> <to>`
+    CopyFrom(String, PathBuf, PathBuf),
     /// Install system packages, this can be done with Run command as well,
     /// however, this particular command ONLY installs packages using apt-get
     Install(InstallOpts),
@@ -1151,11 +1684,23 @@ impl BuildInstruction {
                 map.insert("build_instruction".to_string(), serde_json::json!(build_inst_map));
                 Value::Object(map).to_string()
             }
+            Self::RunWithSecrets(secrets, run) => {
+                build_inst_map.insert("run".to_string(), serde_json::json!(run));
+                build_inst_map.insert("secrets".to_string(), serde_json::json!(secrets));
+                map.insert("build_instruction".to_string(), serde_json::json!(build_inst_map));
+                Value::Object(map).to_string()
+            }
             Self::Copy(from, to) => {
                 build_inst_map.insert("copy".to_string(), serde_json::json!([from.to_string_lossy(), to.to_string_lossy()])); 
                 map.insert("build_instruction".to_string(), serde_json::json!(build_inst_map));
                 Value::Object(map).to_string()
             }
+            Self::CopyFrom(stage, from, to) => {
+                build_inst_map.insert("copy".to_string(), serde_json::json!([from.to_string_lossy(), to.to_string_lossy()]));
+                build_inst_map.insert("stage".to_string(), serde_json::json!(stage));
+                map.insert("build_instruction".to_string(), serde_json::json!(build_inst_map));
+                Value::Object(map).to_string()
+            }
             Self::Install(opts) => {
                 build_inst_map.insert("install".to_string(), serde_json::json!({"packages": opts.packages}));
                 map.insert("build_instruction".to_string(), serde_json::json!(build_inst_map));
@@ -1209,6 +1754,19 @@ pub enum SystemConfigOpt {
     Disk(u16),
     // Devices (GPUs, etc.)
     Gpu(GpuRequest), // Model and quantity of GPUs requested
+    // Networking
+    Bandwidth(BandwidthLimit),
+    Firewall(FirewallRule),
+    /// Only allow traffic to and from formnet addresses
+    FormnetOnly,
+    /// How much of the instance's memory the host may reclaim under pressure
+    MemoryTier(MemoryTier),
+    /// Launch as a confidential VM with encrypted memory
+    Confidential(ConfidentialMode),
+    /// Probe that decides when the app is ready
+    HealthCheck(HealthCheck),
+    /// Incremental file sync for iterating on a running instance
+    DevSync(DevSync),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1219,6 +1777,88 @@ pub struct GpuRequest {
     pub count: u8,
 }
 
+/// Bandwidth caps for the instance's network interface, in Mbps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimit {
+    pub ingress_mbps: Option<u32>,
+    pub egress_mbps: Option<u32>,
+}
+
+/// How much of an instance's memory the host may reclaim with the memory
+/// balloon when the node is under memory pressure
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryTier {
+    /// Memory is never reclaimed
+    Guaranteed,
+    #[default]
+    Standard,
+    /// Memory is reclaimed first and the most aggressively
+    Burstable,
+}
+
+/// Hardware memory encryption an instance is launched with. Only nodes whose
+/// CPU and kernel support it are eligible to run the instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfidentialMode {
+    /// AMD SEV-SNP
+    SevSnp,
+    /// Intel Trust Domain Extensions
+    Tdx,
+}
+
+impl std::fmt::Display for ConfidentialMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SevSnp => write!(f, "sev-snp"),
+            Self::Tdx => write!(f, "tdx"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallAction {
+    Allow,
+    Deny,
+}
+
+/// Direction of traffic relative to the instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrafficDirection {
+    Ingress,
+    Egress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallProtocol {
+    Tcp,
+    Udp,
+    Any,
+}
+
+/// Inclusive range of ports, a single port has `start == end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// A firewall rule, rules are evaluated in the order they appear in the
+/// Formfile and the first match wins
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirewallRule {
+    pub action: FirewallAction,
+    pub direction: TrafficDirection,
+    pub protocol: FirewallProtocol,
+    /// Empty matches every port, only valid with `FirewallProtocol::Any`
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+}
+
 impl SystemConfigOpt {
     pub fn to_json(&self) -> String {
         let mut map = serde_json::Map::new();
@@ -1237,6 +1877,28 @@ impl SystemConfigOpt {
                 opts_map.insert("gpu_model".to_string(), serde_json::json!(request.model));
                 opts_map.insert("gpu_count".to_string(), serde_json::json!(request.count));
             }
+            Self::Bandwidth(limit) => {
+                opts_map.insert("bandwidth".to_string(), serde_json::json!(limit));
+            }
+            Self::Firewall(rule) => {
+                opts_map.insert("firewall".to_string(), serde_json::json!(rule));
+            }
+            Self::FormnetOnly => {
+                opts_map.insert("formnet_only".to_string(), serde_json::json!(true));
+            }
+            Self::MemoryTier(tier) => {
+                opts_map.insert("memory_tier".to_string(), serde_json::json!(tier));
+            }
+            Self::Confidential(mode) => {
+                opts_map.insert("confidential".to_string(), serde_json::json!(mode));
+            }
+            Self::HealthCheck(check) => {
+                opts_map.insert("healthcheck".to_string(), serde_json::json!(check));
+            }
+            Self::DevSync(sync) => {
+                // The owner is filled in after the request is signed
+                opts_map.insert("devsync".to_string(), serde_json::json!(sync.restart));
+            }
         }
         map.insert("system_config".to_string(), serde_json::json!(opts_map));
         Value::Object(map).to_string()
@@ -1951,4 +2613,170 @@ ENTRYPOINT ["python3", "/app/agent.py"]
 
         Ok(())
     }
+
+    #[test]
+    fn test_network_directives() -> Result<(), Box<dyn std::error::Error>> {
+        let content = r#"
+NAME web
+BANDWIDTH ingress=100 egress=50
+FIREWALL allow tcp 80,443
+FIREWALL deny egress udp 1000-2000
+FIREWALL deny any
+FIREWALL formnet-only
+"#;
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse(content)?;
+
+        assert_eq!(formfile.get_bandwidth(), BandwidthLimit { ingress_mbps: Some(100), egress_mbps: Some(50) });
+        assert!(formfile.is_formnet_only());
+        let rules = formfile.get_firewall_rules();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].ports, vec![PortRange { start: 80, end: 80 }, PortRange { start: 443, end: 443 }]);
+        assert_eq!(rules[1].direction, TrafficDirection::Egress);
+        assert_eq!(rules[1].ports, vec![PortRange { start: 1000, end: 2000 }]);
+        assert_eq!(rules[2].protocol, FirewallProtocol::Any);
+
+        assert_eq!(formfile.get_memory_tier(), MemoryTier::Standard);
+
+        let mut parser = FormfileParser::new();
+        assert!(parser.parse_bandwidth("ingress=0").is_err());
+        assert!(parser.parse_bandwidth("sideways=10").is_err());
+        assert!(parser.parse_firewall("allow tcp").is_err());
+        assert!(parser.parse_firewall("allow tcp 2000-1000").is_err());
+        assert!(parser.parse_firewall("permit tcp 80").is_err());
+        assert!(parser.parse_memory_tier("premium").is_err());
+
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME cache\nMEMORY_TIER burstable\n")?;
+        assert_eq!(formfile.get_memory_tier(), MemoryTier::Burstable);
+        assert_eq!(formfile.get_confidential(), None);
+
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME vault\nCONFIDENTIAL sev-snp\n")?;
+        assert_eq!(formfile.get_confidential(), Some(ConfidentialMode::SevSnp));
+        assert!(parser.parse_confidential("sgx").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_healthcheck_parsing() -> Result<(), Box<dyn std::error::Error>> {
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME api\nHEALTHCHECK --interval=10s --retries=5 --start-period=2m HTTP 8080 /healthz\n")?;
+        assert_eq!(formfile.get_health_check(), Some(HealthCheck {
+            probe: HealthProbe::Http { port: 8080, path: "/healthz".to_string() },
+            interval_secs: 10,
+            timeout_secs: 5,
+            retries: 5,
+            start_period_secs: 120,
+        }));
+
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME db\nHEALTHCHECK --timeout=3 CMD pg_isready -U postgres\n")?;
+        let check = formfile.get_health_check().unwrap();
+        assert_eq!(check.probe, HealthProbe::Command("pg_isready -U postgres".to_string()));
+        assert_eq!(check.timeout_secs, 3);
+
+        let mut parser = FormfileParser::new();
+        assert!(parser.parse("NAME plain\n")?.get_health_check().is_none());
+        assert!(parser.parse_healthcheck("CMD").is_err());
+        assert!(parser.parse_healthcheck("HTTP").is_err());
+        assert!(parser.parse_healthcheck("HTTP 8080 healthz").is_err());
+        assert!(parser.parse_healthcheck("--interval=0s CMD true").is_err());
+        assert!(parser.parse_healthcheck("--every=5s CMD true").is_err());
+        assert!(parser.parse_healthcheck("TCP 5432").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_devsync_parsing() -> Result<(), Box<dyn std::error::Error>> {
+        let mut parser = FormfileParser::new();
+        let mut formfile = parser.parse("NAME api\nDEVSYNC CMD pm2 reload all\n")?;
+        assert_eq!(formfile.get_dev_sync(), Some(DevSync {
+            restart: DevRestart::Command("pm2 reload all".to_string()),
+            owner: None,
+        }));
+        formfile.set_dev_sync_owner("abcd");
+        assert_eq!(formfile.get_dev_sync().unwrap().owner.as_deref(), Some("abcd"));
+
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME api\nDEVSYNC NONE\nDEVSYNC SERVICE\n")?;
+        assert_eq!(formfile.get_dev_sync().unwrap().restart, DevRestart::Service);
+
+        let mut parser = FormfileParser::new();
+        let mut formfile = parser.parse("NAME plain\n")?;
+        formfile.set_dev_sync_owner("abcd");
+        assert!(formfile.get_dev_sync().is_none());
+        assert!(parser.parse_devsync("CMD").is_err());
+        assert!(parser.parse_devsync("SERVICE now").is_err());
+        assert!(parser.parse_devsync("RESTART").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_parsing() -> Result<(), Box<dyn std::error::Error>> {
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME api\nSECRET NPM_TOKEN\nSECRET PIP_INDEX\nRUN --secret=NPM_TOKEN,PIP_INDEX npm ci && pip install -r requirements.txt\nRUN ls\n")?;
+        assert_eq!(formfile.build_secrets, vec!["NPM_TOKEN".to_string(), "PIP_INDEX".to_string()]);
+        assert!(matches!(
+            &formfile.build_instructions[0],
+            BuildInstruction::RunWithSecrets(secrets, command) if secrets.len() == 2 && command == "npm ci && pip install -r requirements.txt"
+        ));
+        assert!(matches!(&formfile.build_instructions[1], BuildInstruction::Run(command) if command == "ls"));
+
+        let mut parser = FormfileParser::new();
+        assert!(parser.parse("NAME api\nRUN --secret=NPM_TOKEN npm ci\n").is_err());
+        let mut parser = FormfileParser::new();
+        assert!(parser.parse("NAME api\nSECRET NPM_TOKEN\nRUN --secret=NPM_TOKEN\n").is_err());
+        let mut parser = FormfileParser::new();
+        assert!(parser.parse("NAME api\nSECRET NPM_TOKEN\nENV NPM_TOKEN=abc\n").is_err());
+        let mut parser = FormfileParser::new();
+        assert!(parser.parse("NAME api\nENV NPM_TOKEN=abc\nSECRET NPM_TOKEN\n").is_err());
+        let mut parser = FormfileParser::new();
+        assert!(parser.parse("NAME api\nSECRET NPM_TOKEN\nSECRET NPM_TOKEN\n").is_err());
+        assert!(parser.parse_secret("1TOKEN").is_err());
+        assert!(parser.parse_secret("A B").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_parsing() -> Result<(), Box<dyn std::error::Error>> {
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME app\nSTAGE toolchain\nINSTALL build-essential\nSTAGE build from toolchain\nCOPY ./src /src\nRUN make -C /src\nSTAGE app FROM toolchain\nCOPY --from=build /src/app /usr/bin\nENTRYPOINT [\"/usr/bin/app\"]\n")?;
+        assert_eq!(formfile.stages.len(), 2);
+        assert_eq!(formfile.stages[0].name, "toolchain");
+        assert_eq!(formfile.stages[0].from, None);
+        assert!(matches!(&formfile.stages[0].instructions[..], [BuildInstruction::Install(_)]));
+        assert_eq!(formfile.stages[1].from.as_deref(), Some("toolchain"));
+        assert_eq!(formfile.stages[1].dependencies().collect::<Vec<_>>(), vec!["toolchain"]);
+        assert_eq!(formfile.base_stage.as_deref(), Some("toolchain"));
+        assert!(matches!(
+            &formfile.build_instructions[0],
+            BuildInstruction::CopyFrom(stage, from, to) if stage == "build" && from == &PathBuf::from("/src/app") && to == &PathBuf::from("/usr/bin")
+        ));
+        assert_eq!(formfile.context_copies(), vec![(PathBuf::from("./src"), PathBuf::from("/src"))]);
+
+        // Single-stage Formfiles are unchanged
+        let mut parser = FormfileParser::new();
+        let formfile = parser.parse("NAME app\nCOPY ./app /app\n")?;
+        assert!(formfile.stages.is_empty() && formfile.base_stage.is_none());
+
+        for invalid in [
+            "NAME app\nRUN ls\nSTAGE build\n",
+            "NAME app\nSTAGE build\nSTAGE build\n",
+            "NAME app\nSTAGE app FROM build\n",
+            "NAME app\nSTAGE build\nCOPY --from=build /out /srv\n",
+            "NAME app\nSTAGE build\nSTAGE app\nCOPY --from=build out /srv\n",
+            "NAME app\nSTAGE Build\n",
+            "NAME app\nSTAGE build AS app\n",
+        ] {
+            let mut parser = FormfileParser::new();
+            assert!(parser.parse(invalid).is_err(), "{invalid:?} should be rejected");
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,183 @@
+//! `.formignore` support for build contexts.
+//!
+//! The file lists paths that are left out of a pack, one pattern per line,
+//! using a subset of the `.gitignore` syntax:
+//!
+//! * blank lines and lines starting with `#` are skipped
+//! * `*` matches any run of characters within a path component, `?` one
+//! * a trailing `/` only matches directories
+//! * a pattern with a leading or inner `/` is anchored to the context root,
+//!   otherwise it matches a file or directory of that name at any depth
+use std::{fs, io, path::Path};
+
+pub const FORMIGNORE_FILE: &str = ".formignore";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FormIgnore {
+    patterns: Vec<IgnorePattern>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct IgnorePattern {
+    components: Vec<String>,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl FormIgnore {
+    /// Reads the `.formignore` in `context_dir`, a missing file ignores nothing
+    pub fn load(context_dir: impl AsRef<Path>) -> io::Result<Self> {
+        match fs::read_to_string(context_dir.as_ref().join(FORMIGNORE_FILE)) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let patterns = content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let dir_only = line.ends_with('/');
+                let line = line.trim_end_matches('/');
+                let anchored = line.contains('/');
+                let components: Vec<String> = line.split('/')
+                    .filter(|c| !c.is_empty() && *c != ".")
+                    .map(str::to_string)
+                    .collect();
+                (!components.is_empty()).then_some(IgnorePattern { components, anchored, dir_only })
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether `relative`, a path inside the context, should be left out
+    pub fn is_ignored(&self, relative: impl AsRef<Path>, is_dir: bool) -> bool {
+        let components: Vec<String> = relative.as_ref()
+            .components()
+            .filter_map(|c| match c {
+                std::path::Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        let Some(name) = components.last() else {
+            return false;
+        };
+
+        self.patterns.iter().any(|pattern| {
+            if pattern.dir_only && !is_dir {
+                return false;
+            }
+            if pattern.anchored {
+                pattern.components.len() == components.len()
+                    && pattern.components.iter().zip(&components).all(|(p, c)| wildcard_match(p, c))
+            } else {
+                pattern.components.len() == 1 && wildcard_match(&pattern.components[0], name)
+            }
+        })
+    }
+
+    /// Whether the file at `relative` is left out, either itself or because
+    /// one of the directories it's in is
+    pub fn is_ignored_file(&self, relative: impl AsRef<Path>) -> bool {
+        let relative = relative.as_ref();
+        relative.ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .any(|dir| self.is_ignored(dir, true))
+            || self.is_ignored(relative, false)
+    }
+
+    /// Copies `source`, found at `relative` inside the context, to `dest`
+    /// skipping ignored entries. Ignored directories aren't descended into.
+    pub fn copy_dir(
+        &self,
+        source: impl AsRef<Path>,
+        relative: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&dest)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            let is_dir = entry.file_type()?.is_dir();
+            let relative = relative.as_ref().join(entry.file_name());
+            if self.is_ignored(&relative, is_dir) {
+                continue;
+            }
+            if is_dir {
+                self.copy_dir(entry.path(), &relative, dest.as_ref().join(entry.file_name()))?;
+            } else {
+                fs::copy(entry.path(), dest.as_ref().join(entry.file_name()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name index it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    n = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formignore_patterns() {
+        let ignore = FormIgnore::parse("
+            # build output
+            target/
+            *.log
+            /secrets/dev.env
+            node_modules
+        ");
+
+        assert!(ignore.is_ignored("target", true));
+        assert!(ignore.is_ignored("crates/api/target", true));
+        assert!(!ignore.is_ignored("target", false));
+        assert!(ignore.is_ignored("logs/server.log", false));
+        assert!(!ignore.is_ignored("server.log.txt", false));
+        assert!(ignore.is_ignored("secrets/dev.env", false));
+        assert!(!ignore.is_ignored("app/secrets/dev.env", false));
+        assert!(ignore.is_ignored("web/node_modules", true));
+        assert!(!ignore.is_ignored("src/main.rs", false));
+        assert!(ignore.is_ignored_file("web/node_modules/react/index.js"));
+        assert!(ignore.is_ignored_file("target/debug/app"));
+        assert!(!ignore.is_ignored_file("src/target.rs"));
+        assert!(FormIgnore::parse("\n# nothing\n").is_empty());
+    }
+}
@@ -8,10 +8,13 @@ use futures::{StreamExt, TryStreamExt};
 use tiny_keccak::{Sha3, Hasher};
 use crate::{auth::RecoveredAddress, manager::FormPackManager};
 use crate::types::response::PackResponse;
+use crate::types::status::PackBuildStatus;
 use crate::monitor::FormPackMonitor;
 use crate::helpers::api::write::{write_pack_status_started, write_pack_status_failed, write_pack_status_completed};
+use crate::helpers::queue::write::write_build_manifest;
+use crate::build_secrets::resolve_build_secrets;
 use crate::formfile::Formfile;
-use log::{info, error};
+use log::{info, warn, error};
 
 pub(crate) async fn handle_pack(
     State(manager): State<Arc<Mutex<FormPackManager>>>,
@@ -79,7 +82,7 @@ pub(crate) async fn handle_pack(
     }
 
     println!("Reading metadata into Formfile struct...");
-    let formfile: Formfile = match std::fs::read_to_string(&metadata_path)
+    let mut formfile: Formfile = match std::fs::read_to_string(&metadata_path)
         .and_then(|s| serde_json::from_str(&s)
             .map_err(|e| {
                 println!("Error reading metadata: {e}");
@@ -99,37 +102,115 @@ pub(crate) async fn handle_pack(
     hasher.finalize(&mut hash);
 
     let build_id_hex = hex::encode(hash);
-
-    info!("Building FormPackMonitor for agent name: {}, calculated build_id_hex: {}", formfile.name, build_id_hex);
-    let mut monitor = match FormPackMonitor::new().await {
-        Ok(monitor) => monitor,
-        Err(e) => {
-            error!("(handle_pack) Error building monitor: {}", e);
-            return Json(PackResponse::Failure);
-        }
-    }; 
+    formfile.set_dev_sync_owner(&recovered_address.as_hex());
 
     let guard = manager.lock().await;
     let node_id = guard.node_id.clone();
+    let scheduler = guard.scheduler.clone();
+    let signing_key = guard.signing_key.clone();
+    let scan_policy = guard.scan_policy.clone();
+    let verity_policy = guard.verity_policy.clone();
     drop(guard);
-    
-    let _ = write_pack_status_started(formfile.clone(), build_id_hex.clone(), node_id.clone(), recovered_address.as_hex()).await;
-    info!("Attempting to build image for agent name: {}, using build_id_hex: {} as vm_name for monitor", formfile.name, build_id_hex);
-    
-    match monitor.build_image(
-        node_id.clone(),
-        build_id_hex.clone(),
-        formfile.clone(),
-        artifacts_path,
-    ).await {
-        Ok(_res) => {
-            let _ = write_pack_status_completed(formfile.clone(), build_id_hex.clone(), node_id.clone(), recovered_address.as_hex()).await;
-            Json(PackResponse::Success)
-        },
+
+    let artifacts_size = std::fs::metadata(&artifacts_path).map(|m| m.len()).unwrap_or(0);
+    if !scheduler.limits().artifacts_fit(artifacts_size) {
+        error!("(handle_pack) Artifacts for build {} exceed the scratch disk quota ({} bytes)", build_id_hex, artifacts_size);
+        return Json(PackResponse::Failure);
+    }
+
+    let (ticket, position) = match scheduler.enqueue(&build_id_hex).await {
+        Ok(queued) => queued,
         Err(e) => {
-            error!("(handle_pack) Error building image: {}", e);
-            let _ = write_pack_status_failed(&formfile, recovered_address.as_hex(), build_id_hex.clone(), node_id.clone(), e.to_string()).await;
-            Json(PackResponse::Failure)
+            error!("(handle_pack) Unable to schedule build {}: {}", build_id_hex, e);
+            return Json(PackResponse::Failure);
         }
-    }
+    };
+    info!("Queued build {} for agent name: {} at position {}", build_id_hex, formfile.name, position);
+
+    let owner = recovered_address.as_hex();
+    let _ = write_pack_status_started(formfile.clone(), build_id_hex.clone(), node_id.clone(), owner.clone()).await;
+
+    let build_id = build_id_hex.clone();
+    tokio::spawn(async move {
+        // The tempdir holds the artifacts, keep it alive until the build is done
+        let _packdir = packdir;
+        let mut permit = ticket.wait().await;
+
+        let secrets = match resolve_build_secrets(&build_id, &formfile.build_secrets).await {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                error!("(handle_pack) {}", e);
+                let _ = write_pack_status_failed(&formfile, owner, build_id, node_id, e.to_string()).await;
+                permit.finish(Err(e.to_string()));
+                return;
+            }
+        };
+
+        info!("Building FormPackMonitor for agent name: {}, build_id_hex: {}", formfile.name, build_id);
+        let mut monitor = match FormPackMonitor::new(scheduler.limits()).await {
+            Ok(monitor) => monitor,
+            Err(e) => {
+                error!("(handle_pack) Error building monitor: {}", e);
+                let _ = write_pack_status_failed(&formfile, owner, build_id, node_id, e.to_string()).await;
+                permit.finish(Err(e.to_string()));
+                return;
+            }
+        };
+
+        info!("Attempting to build image for agent name: {}, using build_id_hex: {} as vm_name for monitor", formfile.name, build_id);
+        match monitor.build_image(
+            node_id.clone(),
+            build_id.clone(),
+            formfile.clone(),
+            artifacts_path,
+            &scan_policy,
+            secrets,
+        ).await {
+            Ok(mut scan) => {
+                let violation = scan.as_mut().and_then(|scan| {
+                    scan.policy_violation = scan_policy.evaluate(scan);
+                    scan.policy_violation.clone()
+                });
+                if let Some(signing_key) = &signing_key {
+                    match write_build_manifest(
+                        build_id.clone(),
+                        &formfile,
+                        monitor.base_image_digest(),
+                        node_id.clone(),
+                        scan,
+                        &verity_policy,
+                        signing_key,
+                    ).await {
+                        Ok(manifest) => permit.attach_manifest(manifest),
+                        Err(e) => {
+                            error!("(handle_pack) Unable to publish build manifest: {}", e);
+                            let _ = write_pack_status_failed(&formfile, owner, build_id.clone(), node_id.clone(), e.to_string()).await;
+                            permit.finish(Err(e.to_string()));
+                            return;
+                        }
+                    }
+                } else {
+                    warn!("(handle_pack) No signing key configured, {} has no build manifest", build_id);
+                }
+                if let Some(reason) = violation {
+                    error!("(handle_pack) {}", reason);
+                    let _ = write_pack_status_failed(&formfile, owner, build_id.clone(), node_id.clone(), reason.clone()).await;
+                    permit.finish(Err(reason));
+                    return;
+                }
+                let _ = write_pack_status_completed(formfile.clone(), build_id.clone(), node_id.clone(), owner).await;
+                permit.finish(Ok(()));
+            },
+            Err(e) => {
+                error!("(handle_pack) Error building image: {}", e);
+                let _ = write_pack_status_failed(&formfile, owner, build_id.clone(), node_id.clone(), e.to_string()).await;
+                permit.finish(Err(e.to_string()));
+            }
+        }
+    });
+
+    Json(PackResponse::Status(PackBuildStatus::Queued {
+        build_id: build_id_hex,
+        position,
+    }))
 }
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use axum::{Json, extract::{Path, State}};
+use alloy_primitives::Address;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use reqwest::Client;
+use form_state::instances::Instance;
+use form_types::state::{Success, Response as StateResponse};
+use form_types::{SignedSyncRequest, SyncRequest, SyncResponse, DEV_SYNC_PORT};
+use crate::helpers::utils::build_instance_id;
+use crate::manager::FormPackManager;
+use log::{info, warn};
+
+/// Largest delta form-pack forwards to an instance
+pub const MAX_SYNC_DELTA_SIZE: usize = 256 * 1024 * 1024;
+
+/// How long an instance gets to apply a delta and restart its app
+const SYNC_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Forwards an owner-signed sync request to the dev instance of `build_id`
+/// on this node. The instance checks the signature again itself, the checks
+/// here only keep requests that can't succeed off formnet.
+pub(crate) async fn handle_sync(
+    State(manager): State<Arc<Mutex<FormPackManager>>>,
+    Path(build_id): Path<String>,
+    Json(signed): Json<SignedSyncRequest>,
+) -> Json<SyncResponse> {
+    let node_id = manager.lock().await.node_id.clone();
+    match forward_sync(&node_id, &build_id, &signed).await {
+        Ok(response) => Json(response),
+        Err(reason) => {
+            warn!("(handle_sync) Refused sync for build {}: {}", build_id, reason);
+            Json(SyncResponse::Failure { reason })
+        }
+    }
+}
+
+async fn forward_sync(node_id: &str, build_id: &str, signed: &SignedSyncRequest) -> Result<SyncResponse, String> {
+    let request: SyncRequest = serde_json::from_str(&signed.request).map_err(|e| e.to_string())?;
+    if request.build_id != build_id {
+        return Err(format!("Request is signed for build {}", request.build_id));
+    }
+    let signer = recover_signer(signed)?;
+
+    let instance_id = build_instance_id(node_id.to_string(), build_id.to_string()).map_err(|e| e.to_string())?;
+    let instance = match Client::new()
+        .get(format!("http://127.0.0.1:3004/instance/{instance_id}/get"))
+        .send().await.map_err(|e| e.to_string())?
+        .json::<StateResponse<Instance>>().await.map_err(|e| e.to_string())? {
+            StateResponse::Success(Success::Some(instance)) => instance,
+            _ => return Err(format!("Build {build_id} has no instance on this node")),
+    };
+    if !instance.instance_owner.trim_start_matches("0x").eq_ignore_ascii_case(&signer) {
+        return Err(format!("{signer} does not own instance {instance_id}"));
+    }
+    let ip = instance.formnet_ip.ok_or_else(|| format!("Instance {instance_id} has no formnet address yet"))?;
+
+    info!("(handle_sync) Forwarding sync for build {} to {}", build_id, ip);
+    Client::builder().timeout(SYNC_TIMEOUT).build().map_err(|e| e.to_string())?
+        .post(format!("http://{ip}:{DEV_SYNC_PORT}/sync"))
+        .json(signed)
+        .send().await.map_err(|e| format!("Instance {instance_id} is unreachable, was it built with DEVSYNC? {e}"))?
+        .json::<SyncResponse>().await.map_err(|e| e.to_string())
+}
+
+fn recover_signer(signed: &SignedSyncRequest) -> Result<String, String> {
+    let signature = Signature::from_slice(&hex::decode(&signed.signature).map_err(|e| e.to_string())?)
+        .map_err(|e| e.to_string())?;
+    let recovery_id = RecoveryId::from_byte(signed.recovery_id).ok_or("invalid recovery id")?;
+    let key = VerifyingKey::recover_from_msg(signed.request.as_bytes(), &signature, recovery_id)
+        .map_err(|e| e.to_string())?;
+    Ok(hex::encode(Address::from_public_key(&key)))
+}
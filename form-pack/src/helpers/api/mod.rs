@@ -1,7 +1,7 @@
 use crate::manager::FormPackManager;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use axum::{Router, routing::{post, get}, middleware};
+use axum::{Router, routing::{post, get}, middleware, extract::DefaultBodyLimit};
 use std::net::SocketAddr;
 use crate::auth::ecdsa_auth_middleware;
 
@@ -10,6 +10,7 @@ pub mod build;
 pub mod health;
 pub mod status;
 pub mod write;
+pub mod devsync;
 
 pub(crate) async fn serve(addr: String, manager: Arc<Mutex<FormPackManager>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("Building routes...");
@@ -40,8 +41,15 @@ async fn build_routes(manager: Arc<Mutex<FormPackManager>>) -> Router {
         .route("/:build_id/get_status", get(status::get_status))
         .layer(middleware::from_fn_with_state(manager.clone(), ecdsa_auth_middleware))
         .with_state(manager.clone()); // Apply state to the core routes
+
+    // Sync requests carry the owner's signature in the body, the instance
+    // verifies it again before touching any files
+    let dev_routes = Router::new()
+        .route("/dev/:build_id/sync", post(devsync::handle_sync))
+        .layer(DefaultBodyLimit::max(devsync::MAX_SYNC_DELTA_SIZE))
+        .with_state(manager.clone());
     
     // Create the final app router with the /v1 prefix
     Router::new()
-        .nest("/v1", core_api_routes)
+        .nest("/v1", core_api_routes.merge(dev_routes))
 }
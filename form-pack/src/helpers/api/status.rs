@@ -1,8 +1,24 @@
-use axum::{Json, extract::Path};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{Json, extract::{Path, State}};
+use crate::manager::FormPackManager;
+use crate::scheduler::BuildState;
 use crate::types::response::PackResponse;
+use crate::types::status::PackBuildStatus;
 
 pub(crate) async fn get_status(
-    Path(_build_id): Path<String>,
+    State(manager): State<Arc<Mutex<FormPackManager>>>,
+    Path(build_id): Path<String>,
 ) -> Json<PackResponse> {
-    Json(PackResponse::Failure)
+    let scheduler = manager.lock().await.scheduler.clone();
+    match scheduler.status(&build_id).await {
+        Some(BuildState::Queued { position }) => Json(PackResponse::Status(PackBuildStatus::Queued { build_id, position })),
+        Some(BuildState::Running) => Json(PackResponse::Status(PackBuildStatus::Started(build_id))),
+        Some(BuildState::Completed) => match scheduler.manifest(&build_id).await {
+            Some(manifest) => Json(PackResponse::Status(PackBuildStatus::Signed { build_id, manifest })),
+            None => Json(PackResponse::Success),
+        },
+        Some(BuildState::Failed { reason }) => Json(PackResponse::Status(PackBuildStatus::Failed { build_id, reason })),
+        None => Json(PackResponse::Failure),
+    }
 }
@@ -412,12 +412,12 @@ pub async fn write_pack_status_failed(
         }
     }
 
-    instance_to_process.status = InstanceStatus::CriticalError; // Set to CriticalError
+    instance_to_process.status = InstanceStatus::Failed;
     instance_to_process.updated_at = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?.as_secs() as i64;
     // Potentially add reason to instance metadata if the struct supports it
     // instance_to_process.metadata.insert("failure_reason".to_string(), reason.clone()); 
 
-    // Update or Create the instance with CriticalError status
+    // Update or Create the instance with Failed status
     let (instance_call_url, use_create_for_instance) = if instance_needs_creation {
         (format!("{}/instance/create", FORM_STATE_URL), true)
     } else {
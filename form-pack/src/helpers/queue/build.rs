@@ -1,15 +1,18 @@
 use tempfile::tempdir;
+use k256::ecdsa::SigningKey;
 use std::io::Write;
 use std::fs::OpenOptions;
 use crate::formfile::Formfile;
 use crate::types::request::PackBuildRequest;
 use crate::monitor::FormPackMonitor;
-use crate::manager::FormPackManager;
-use crate::helpers::queue::write::{write_pack_status_completed, write_pack_status_failed, write_pack_status_started};
+use crate::scheduler::BuildScheduler;
+use crate::scanner::ScanPolicy;
+use crate::verity::VerityPolicy;
+use crate::build_secrets::resolve_build_secrets;
+use crate::helpers::queue::write::{write_build_manifest, write_pack_status_completed, write_pack_status_failed, write_pack_status_started};
+
+pub async fn handle_pack_request(node_id: String, scheduler: BuildScheduler, signing_key: Option<SigningKey>, scan_policy: ScanPolicy, verity_policy: VerityPolicy, message: PackBuildRequest) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
-pub async fn handle_pack_request(manager: &mut FormPackManager, message: PackBuildRequest) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let node_id = manager.node_id.clone();
-    
     // First check if we're responsible for this workload using the capability matcher
     println!("Checking if this node is responsible for handling the workload...");
     let formfile = &message.request.formfile;
@@ -38,7 +41,27 @@ pub async fn handle_pack_request(manager: &mut FormPackManager, message: PackBui
     }
     
     // If we get here, we're responsible for the workload
-    write_pack_status_started(&message, node_id).await?;
+    if !scheduler.limits().artifacts_fit(message.request.artifacts.len() as u64) {
+        let reason = "Build artifacts exceed the scratch disk quota".to_string();
+        println!("{}", reason);
+        write_pack_status_failed(&message, reason).await?;
+        return Ok(());
+    }
+
+    let ticket = match scheduler.enqueue(&build_id).await {
+        Ok((ticket, position)) => {
+            println!("Build {build_id} queued at position {position}...");
+            ticket
+        }
+        Err(e) => {
+            let reason = e.to_string();
+            println!("{}", reason);
+            write_pack_status_failed(&message, reason).await?;
+            return Ok(());
+        }
+    };
+    write_pack_status_started(&message, node_id.clone()).await?;
+    let mut permit = ticket.wait().await;
     let packdir = tempdir()?;
 
     println!("Created temporary directory to put artifacts into...");
@@ -56,7 +79,7 @@ pub async fn handle_pack_request(manager: &mut FormPackManager, message: PackBui
     file.write_all(&message.request.artifacts)?;
 
     println!("Reading Formfile json metadata into Formfile struct...");
-    let formfile: Formfile = std::fs::read_to_string(&metadata_path)
+    let mut formfile: Formfile = std::fs::read_to_string(&metadata_path)
         .and_then(|s| serde_json::from_str(&s)
             .map_err(|_| {
                 std::io::Error::from(
@@ -64,13 +87,26 @@ pub async fn handle_pack_request(manager: &mut FormPackManager, message: PackBui
                 )
             })
         )?; 
+    formfile.set_dev_sync_owner(&message.signer_hex()?);
+
+    let secrets = match resolve_build_secrets(&message.request.name, &formfile.build_secrets).await {
+        Ok(secrets) => secrets,
+        Err(e) => {
+            let err_msg = e.to_string();
+            println!("{}", err_msg);
+            permit.finish(Err(err_msg.clone()));
+            write_pack_status_failed(&message, err_msg).await?;
+            return Err(e);
+        }
+    };
 
     println!("Building FormPackMonitor for {} build...", formfile.name);
-    let mut monitor = match FormPackMonitor::new().await {
+    let mut monitor = match FormPackMonitor::new(scheduler.limits()).await {
         Ok(m) => m,
         Err(e) => {
             let err_msg = format!("Failed to create FormPackMonitor: {}", e);
             println!("{}", err_msg);
+            permit.finish(Err(err_msg.clone()));
             write_pack_status_failed(&message, err_msg).await?;
             return Err(e);
         }
@@ -78,18 +114,56 @@ pub async fn handle_pack_request(manager: &mut FormPackManager, message: PackBui
     
     println!("Attempting to build image for {}...", formfile.name);
     match monitor.build_image(
-        manager.node_id.clone(),
+        node_id.clone(),
         message.request.name.clone(),
-        formfile,
+        formfile.clone(),
         artifacts_path,
+        &scan_policy,
+        secrets,
     ).await {
-        Ok(_) => {
-            write_pack_status_completed(&message, manager.node_id.clone()).await?;
+        Ok(mut scan) => {
+            // The manifest records the violation too, so vmm-service won't
+            // boot the image even if it is shipped anyway
+            let violation = scan.as_mut().and_then(|scan| {
+                scan.policy_violation = scan_policy.evaluate(scan);
+                scan.policy_violation.clone()
+            });
+            if let Some(signing_key) = &signing_key {
+                match write_build_manifest(
+                    message.request.name.clone(),
+                    &formfile,
+                    monitor.base_image_digest(),
+                    node_id.clone(),
+                    scan,
+                    &verity_policy,
+                    signing_key,
+                ).await {
+                    Ok(manifest) => permit.attach_manifest(manifest),
+                    Err(e) => {
+                        let err_msg = format!("Unable to publish build manifest: {}", e);
+                        println!("{}", err_msg);
+                        permit.finish(Err(err_msg.clone()));
+                        write_pack_status_failed(&message, err_msg).await?;
+                        return Err(e);
+                    }
+                }
+            } else {
+                println!("No signing key configured, {} has no build manifest", message.request.name);
+            }
+            if let Some(reason) = violation {
+                println!("{}", reason);
+                permit.finish(Err(reason.clone()));
+                write_pack_status_failed(&message, reason).await?;
+                return Ok(());
+            }
+            permit.finish(Ok(()));
+            write_pack_status_completed(&message, node_id).await?;
             Ok(())
         },
         Err(e) => {
             let err_msg = format!("Image build failed: {}", e);
             println!("{}", err_msg);
+            permit.finish(Err(err_msg.clone()));
             write_pack_status_failed(&message, err_msg).await?;
             Err(e)
         }
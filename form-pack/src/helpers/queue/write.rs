@@ -1,6 +1,7 @@
 
-use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
 use alloy_primitives::Address;
+use std::path::PathBuf;
 use std::time::{UNIX_EPOCH, SystemTime};
 use reqwest::Client;
 use tiny_keccak::{Sha3, Hasher};
@@ -9,9 +10,13 @@ use form_state::datastore::{AgentRequest, InstanceRequest, AccountRequest};
 use form_state::agent::AIAgent;
 use form_state::instances::InstanceStatus;
 use form_state::instances::Instance;
+use form_state::build_manifests::{canonical_digest, file_digest, BuildManifest, ImageScan, SignedBuildManifest};
 use form_types::state::{Success, Response as StateResponse};
 use form_p2p::queue::{QueueResponse, QueueRequest};
 use form_p2p::queue::QUEUE_PORT;
+use crate::formfile::Formfile;
+use crate::manager::VM_IMAGE_PATH;
+use crate::verity::{format_hash_tree, VerityPolicy};
 use crate::types::status::PackBuildStatus;
 use crate::types::response::PackBuildResponse;
 use crate::types::request::PackBuildRequest;
@@ -243,6 +248,55 @@ pub async fn write_pack_status_completed(
     Ok(())
 }
 
+/// Signs the manifest of a freshly built image with the node key and
+/// publishes it to form-state, vmm-service won't boot the image without it.
+/// The image's hash tree is formatted first if the verity policy asks for
+/// one. Returns the signed manifest so the build status can carry it.
+pub async fn write_build_manifest(
+    build_id: String,
+    formfile: &Formfile,
+    base_image_digest: Option<String>,
+    node_id: String,
+    scan: Option<ImageScan>,
+    verity_policy: &VerityPolicy,
+    signing_key: &SigningKey,
+) -> Result<SignedBuildManifest, Box<dyn std::error::Error + Send + Sync>> {
+    let base_image_digest = base_image_digest.ok_or(
+        Box::new(
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Unable to determine the builder image digest"
+            )
+        )
+    )?;
+
+    let image_path = PathBuf::from(VM_IMAGE_PATH).join(&build_id).with_extension("raw");
+    let verity = if verity_policy.enabled {
+        Some(format_hash_tree(&image_path).await?)
+    } else {
+        None
+    };
+    let image_digest = tokio::task::spawn_blocking(move || file_digest(image_path)).await??;
+
+    let manifest = BuildManifest {
+        build_id,
+        formfile_digest: canonical_digest(formfile)?,
+        base_image_digest,
+        image_digest,
+        builder_node: node_id,
+        built_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+        scan,
+        verity,
+    };
+    log::info!("Signing build manifest for {}", manifest.build_id);
+    let signed = SignedBuildManifest::sign(manifest, signing_key)?;
+
+    #[cfg(not(feature = "devnet"))]
+    write_to_queue(signed.clone(), 11, "state").await?;
+
+    Ok(signed)
+}
+
 pub async fn write_pack_status_failed(
     message: &PackBuildRequest,
     reason: String
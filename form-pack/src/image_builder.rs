@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use axum::extract::Path as AxumPath;
@@ -5,8 +6,11 @@ use axum::{routing::post, Json, Router};
 use serde_json::Value;
 use std::io::Write;
 use serde::{Serialize, Deserialize};
+use crate::build_secrets::{find_leaked_secrets, script_env, wrap_command, MIN_CHECKED_SECRET_LEN};
 use crate::formfile::{BuildInstruction, Entrypoint, EnvScope, EnvVariable, Formfile, User};
-use log::{info, error};
+use crate::stages::{build_stages, StagePolicy, STAGE_FILES_DIR};
+use form_types::{DevSyncConfig, DEV_SYNC_CONFIG_PATH, HEALTH_CHECK_PATH};
+use log::{info, warn, error};
 
 pub const IMAGE_PATH: &str = "/img/jammy-server-cloudimg-amd64.raw";
 
@@ -128,9 +132,14 @@ impl VirtCustomize {
     }
 
     pub fn build(self) -> Result<String, Box<dyn std::error::Error>> {
+        self.build_for(Path::new(IMAGE_PATH))
+    }
+
+    /// Builds the script customizing `image` instead of the build's image
+    pub fn build_for(self, image: &Path) -> Result<String, Box<dyn std::error::Error>> {
         let mut command = format!(r#"#!/bin/bash"#);
         command.push_str("\n");
-        command.push_str(&format!(r#"virt-customize -a {IMAGE_PATH} \"#)); 
+        command.push_str(&format!(r#"virt-customize -a {} \"#, image.display())); 
         for arg in self.commands {
             command.push_str("\n");
             command.push_str(&format!(r#"{arg} \"#));
@@ -139,6 +148,16 @@ impl VirtCustomize {
     }
 }
 
+/// Body of a build request to the build server. Deliberately not `Debug`,
+/// the secret values must not end up in the logs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BuildRequest {
+    pub formfile: Formfile,
+    /// Values of the Formfile's `build_secrets`
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum FormfileResponse {
     Success,
@@ -178,19 +197,50 @@ async fn handle_ping() -> Json<Value> {
 
 async fn handle_formfile(
     AxumPath((build_id, instance_id)): AxumPath<(String, String)>,
-    Json(formfile): Json<Formfile>,
+    Json(request): Json<BuildRequest>,
 ) -> Json<FormfileResponse> {
     info!("Received /formfile request for build_id: {}, instance_id: {}", build_id, instance_id);
+    let BuildRequest { formfile, secrets } = request;
     info!("Parsed Formfile content: {:#?}", formfile);
+    if let Some(missing) = formfile.build_secrets.iter().find(|name| !secrets.contains_key(*name)) {
+        error!("No value was provided for build secret {}", missing);
+        return Json(FormfileResponse::Failure);
+    }
 
     println!("Received formfile: {formfile:?}");
     let formfile = formfile;
     let workdir = formfile.workdir.clone().to_string_lossy().into_owned();
     info!("Target workdir for build: {}", workdir);
+    let policy = StagePolicy::from_env();
+    let mut stage_images = match build_stages(&formfile, &secrets, &policy).await {
+        Ok(images) => images,
+        Err(e) => {
+            error!("Error building the stages of {}: {}", build_id, e);
+            return Json(FormfileResponse::Failure);
+        }
+    };
+    if let Some(base) = &formfile.base_stage {
+        info!("Starting the image from stage {}", base);
+        if let Err(e) = stage_images.copy_to(base, Path::new(IMAGE_PATH)).await {
+            error!("Error copying the image of stage {}: {}", base, e);
+            return Json(FormfileResponse::Failure);
+        }
+    }
+    let copied = PathBuf::from(STAGE_FILES_DIR).join(&instance_id);
+    if let Err(e) = stage_images.extract_copies(&formfile.build_instructions, &copied).await {
+        error!("Error copying files out of stages: {}", e);
+        return Json(FormfileResponse::Failure);
+    }
+
     println!("Request... Building command");
-    let mut command = VirtCustomize::new()
-        .run_command("growpart /dev/sda 1")
-        .run_command("resize2fs /dev/sda1")
+    let mut command = VirtCustomize::new();
+    // A stage's image has been grown already
+    if formfile.base_stage.is_none() {
+        command = command
+            .run_command("growpart /dev/sda 1")
+            .run_command("resize2fs /dev/sda1");
+    }
+    let mut command = command
         .ssh_keygen()
         .mkdir(&workdir)
         .write("/etc/vm_name", &instance_id)
@@ -226,66 +276,54 @@ async fn handle_formfile(
         command = command.copy_in("/artifacts", &workdir);
     }
 
-    for instruction in &formfile.build_instructions {
-        info!("Processing build instruction: {:?}", instruction);
+    for (index, instruction) in formfile.build_instructions.iter().enumerate() {
         println!("Discovered instruction: {instruction:?}...");
-        match instruction {
-            BuildInstruction::Install(opts) => { 
-                info!("Adding install command for packages: {:?}", opts.packages);
-                command = command.install(&opts.packages);
-            },
-            BuildInstruction::Run(cmd) => { 
-                info!("Adding run command: {}", cmd);
-                command = command.run_command(cmd); 
-            } 
-            BuildInstruction::Copy(from, to) => { 
-                let from_str = from.to_string_lossy();
-                let to_str = to.to_string_lossy();
-                info!("Adding copy command from: {} to: {}", from_str, to_str);
-                let from_abs = {
-                    match from.as_path()
-                        .strip_prefix("./")
-                        .or_else(|_| {
-                            from.as_path()
-                                .strip_prefix("/")
-                                .or_else(|_| {
-                                    Ok::<&Path, Box<dyn std::error::Error>>(from.as_path())
-                                })
-                        }) {
-                            Ok(f) => PathBuf::from("/artifacts").join(f.to_path_buf()).to_string_lossy().into_owned(),
-                            Err(e) => {
-                                error!("Error trying to convert COPY source path to absolute: {}. Original: {}", e, from_str);
-                                return Json(FormfileResponse::Failure);
-                            }
-                        }
+        command = add_instruction(command, index, instruction, &copied);
+        println!("added instruction: {instruction:?} to command...");
+    }
+
+    if let Some(check) = formfile.get_health_check() {
+        info!("Writing health check {:?} and enabling form-health.service", check);
+        let definition = match serde_json::to_string(&check) {
+            Ok(definition) => definition,
+            Err(e) => {
+                error!("Error serializing health check: {}", e);
+                return Json(FormfileResponse::Failure);
+            }
+        };
+        // The definition is written inside single quotes by virt-customize
+        command = command.write(HEALTH_CHECK_PATH, &definition.replace('\'', r"'\''"));
+        command = command.write("/etc/systemd/system/form-health.service", &write_form_health());
+        command = command.chmod(644, "/etc/systemd/system/form-health.service");
+        command = command.run_command("systemctl enable form-health.service");
+    }
+
+    if let Some(sync) = formfile.get_dev_sync() {
+        match sync.owner {
+            Some(owner) => {
+                info!("Writing dev sync config for {} and enabling form-dev-sync.service", owner);
+                let config = DevSyncConfig { root: workdir.clone(), restart: sync.restart, owner };
+                let config = match serde_json::to_string(&config) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        error!("Error serializing dev sync config: {}", e);
+                        return Json(FormfileResponse::Failure);
+                    }
                 };
-                info!("Absolute COPY source: {}", from_abs);
-                command = command.copy_in(&from_abs, &to_str); 
-            },
-            BuildInstruction::Entrypoint(entrypoint) => {
-                info!("Processing ENTRYPOINT: command='{}', args='{:?}'", entrypoint.command(), entrypoint.args());
-                let entrypoint_service_content = build_entrypoint(entrypoint);
-                if !entrypoint_service_content.is_empty() {
-                    info!("Writing systemd service for entrypoint: form-app.service");
-                    command = command.write("/etc/systemd/system/form-app.service", &entrypoint_service_content);
-                    command = command.chmod(644, "/etc/systemd/system/form-app.service");
-                    command = command.run_command("systemctl enable form-app.service");
-                } else {
-                    info!("Entrypoint is empty, skipping systemd service creation.");
-                }
-            },
-            BuildInstruction::Env(envvar) => { 
-                info!("Adding ENV: {:?}", envvar);
-                let (path, line) = add_env_var(envvar.clone()); 
-                command = command.append_line(&path, &line);
+                command = command.write(DEV_SYNC_CONFIG_PATH, &config.replace('\'', r"'\''"));
+                command = command.write("/etc/systemd/system/form-dev-sync.service", &write_form_dev_sync());
+                command = command.chmod(644, "/etc/systemd/system/form-dev-sync.service");
+                command = command.run_command("systemctl enable form-dev-sync.service");
             }
-            BuildInstruction::Expose(_) => { 
-                info!("Processing EXPOSE (currently a no-op in virt-customize stage)");
-            } 
+            None => warn!("DEVSYNC has no owner, file sync stays disabled for {}", instance_id),
         }
-        println!("added instruction: {instruction:?} to command...");
     }
 
+    info!("Enabling form-guest-agent.service");
+    command = command.write("/etc/systemd/system/form-guest-agent.service", &write_form_guest_agent());
+    command = command.chmod(644, "/etc/systemd/system/form-guest-agent.service");
+    command = command.run_command("systemctl enable form-guest-agent.service");
+
     info!("Finalizing virt-customize commands with netplan and formnet enablement.");
     command = command.run_command("netplan apply");
     command = command.run_command("systemctl enable formnet-join.service");
@@ -333,8 +371,10 @@ async fn handle_formfile(
     }
 
     info!("Attempting to run /scripts/run-virt-customize.sh");
+    // The script only refers to the secrets, their values are in its environment
     let output = Command::new("bash")
         .arg("/scripts/run-virt-customize.sh")
+        .envs(script_env(&secrets))
         .output();
 
     info!("virt-customize script execution finished.");
@@ -368,11 +408,108 @@ async fn handle_formfile(
         }
     }
 
+    if !secrets.is_empty() {
+        for (name, value) in &secrets {
+            if value.len() < MIN_CHECKED_SECRET_LEN {
+                warn!("Build secret {} is shorter than {} bytes and can't be checked for leaks", name, MIN_CHECKED_SECRET_LEN);
+            }
+        }
+        info!("Checking the image for leaked build secrets");
+        match find_leaked_secrets(Path::new(IMAGE_PATH), &secrets) {
+            Ok(leaked) if leaked.is_empty() => info!("No build secrets found in the image"),
+            Ok(leaked) => {
+                error!("Build secrets {:?} were written into the image, discarding it", leaked);
+                let _ = std::fs::remove_file(IMAGE_PATH);
+                return Json(FormfileResponse::Failure);
+            }
+            Err(e) => {
+                error!("Unable to check the image for leaked build secrets: {}", e);
+                let _ = std::fs::remove_file(IMAGE_PATH);
+                return Json(FormfileResponse::Failure);
+            }
+        }
+    }
+
     return Json(FormfileResponse::Success);
 }
 
 fn no_copy(formfile: &Formfile) -> bool {
-    formfile.build_instructions.iter().any(|inst| matches!(inst, BuildInstruction::Copy(..)))
+    !formfile.context_copies().is_empty()
+        || formfile.build_instructions.iter().any(|inst| matches!(inst, BuildInstruction::CopyFrom(..)))
+}
+
+/// Adds the build instruction at `index` of its stage to a script. The
+/// sources of `COPY --from` have to be copied out of their stage into
+/// `copied` beforehand, by `StageImages::extract_copies`.
+pub(crate) fn add_instruction(
+    mut command: VirtCustomize,
+    index: usize,
+    instruction: &BuildInstruction,
+    copied: &Path,
+) -> VirtCustomize {
+    info!("Processing build instruction: {:?}", instruction);
+    match instruction {
+        BuildInstruction::Install(opts) => {
+            info!("Adding install command for packages: {:?}", opts.packages);
+            command = command.install(&opts.packages);
+        },
+        BuildInstruction::Run(cmd) => {
+            info!("Adding run command: {}", cmd);
+            command = command.run_command(cmd);
+        }
+        BuildInstruction::RunWithSecrets(names, cmd) => {
+            info!("Adding run command with secrets {:?}: {}", names, cmd);
+            command = command.run_command(&wrap_command(cmd, names));
+        }
+        BuildInstruction::Copy(from, to) => {
+            let from_abs = artifact_path(Path::new("/artifacts"), from);
+            info!("Adding copy command from: {} to: {}", from_abs.display(), to.display());
+            command = command.copy_in(&from_abs.to_string_lossy(), &to.to_string_lossy());
+        },
+        BuildInstruction::CopyFrom(stage, from, to) => {
+            let from_copied = copied_path(copied, index, from);
+            info!("Adding copy of {} from stage {} to: {}", from.display(), stage, to.display());
+            command = command.copy_in(&from_copied.to_string_lossy(), &to.to_string_lossy());
+        }
+        BuildInstruction::Entrypoint(entrypoint) => {
+            info!("Processing ENTRYPOINT: command='{}', args='{:?}'", entrypoint.command(), entrypoint.args());
+            let entrypoint_service_content = build_entrypoint(entrypoint);
+            if !entrypoint_service_content.is_empty() {
+                info!("Writing systemd service for entrypoint: form-app.service");
+                command = command.write("/etc/systemd/system/form-app.service", &entrypoint_service_content);
+                command = command.chmod(644, "/etc/systemd/system/form-app.service");
+                command = command.run_command("systemctl enable form-app.service");
+            } else {
+                info!("Entrypoint is empty, skipping systemd service creation.");
+            }
+        },
+        BuildInstruction::Env(envvar) => {
+            info!("Adding ENV: {:?}", envvar);
+            let (path, line) = add_env_var(envvar.clone());
+            command = command.append_line(&path, &line);
+        }
+        BuildInstruction::Expose(_) => {
+            info!("Processing EXPOSE (currently a no-op in virt-customize stage)");
+        }
+    }
+    command
+}
+
+/// Where the source of a COPY instruction is among the uploaded artifacts
+pub(crate) fn artifact_path(artifacts: &Path, from: &Path) -> PathBuf {
+    let relative = from.strip_prefix("./")
+        .or_else(|_| from.strip_prefix("/"))
+        .unwrap_or(from);
+    artifacts.join(relative)
+}
+
+/// Where the source of the `COPY --from` at `index` is copied out to
+pub(crate) fn copied_path(copied: &Path, index: usize, from: &Path) -> PathBuf {
+    let dir = copied.join(index.to_string());
+    match from.file_name() {
+        Some(name) => dir.join(name),
+        None => dir,
+    }
 }
 
 fn add_env_var(envvar: EnvVariable) -> (String, String) {
@@ -478,6 +615,62 @@ WantedBy=multi-user.target
 "#, get_host_ip())
 }
 
+fn write_form_health() -> String {
+    format!(r#"[Unit]
+Description=Form App Health Check
+After=formnet-join.service form-app.service
+
+[Service]
+Type=simple
+Environment="HOST_BRIDGE_IP={}"
+ExecStart=/usr/bin/formnet health
+Restart=always
+RestartSec=5
+StandardOutput=append:/var/log/form-health.log
+StandardError=append:/var/log/form-health.log
+
+[Install]
+WantedBy=multi-user.target
+"#, get_host_ip())
+}
+
+fn write_form_dev_sync() -> String {
+    r#"[Unit]
+Description=Form Dev Sync Agent
+After=formnet-join.service
+
+[Service]
+Type=simple
+ExecStart=/usr/bin/formnet dev-sync
+Restart=always
+RestartSec=5
+StandardOutput=append:/var/log/form-dev-sync.log
+StandardError=append:/var/log/form-dev-sync.log
+
+[Install]
+WantedBy=multi-user.target
+"#.to_string()
+}
+
+fn write_form_guest_agent() -> String {
+    r#"[Unit]
+Description=Form Guest Agent
+# The agent token is written by cloud-init
+After=cloud-init.service
+
+[Service]
+Type=simple
+ExecStart=/usr/bin/formnet guest-agent
+Restart=always
+RestartSec=5
+StandardOutput=append:/var/log/form-guest-agent.log
+StandardError=append:/var/log/form-guest-agent.log
+
+[Install]
+WantedBy=multi-user.target
+"#.to_string()
+}
+
 fn get_host_ip() -> String {
     std::env::var("HOST_BRIDGE_IP").unwrap()
 }
@@ -1,8 +1,14 @@
 pub mod manager;
 pub mod monitor;
+pub mod scheduler;
+pub mod scanner;
+pub mod verity;
+pub mod stages;
 pub mod image_builder;
+pub mod build_secrets;
 pub mod pack;
 pub mod formfile;
+pub mod formignore;
 pub mod capability_matcher;
 pub mod types;
 pub mod helpers;
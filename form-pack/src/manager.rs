@@ -2,6 +2,7 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration; 
+use k256::ecdsa::SigningKey;
 use tokio::sync::broadcast::Receiver;
 use serde::{Serialize, Deserialize};
 use tokio::sync::Mutex;
@@ -11,6 +12,9 @@ use crate::helpers::api::serve;
 use crate::helpers::queue::write::write_pack_status_failed;
 use crate::helpers::queue::build::handle_pack_request;
 use crate::helpers::queue::read::read_from_queue;
+use crate::scheduler::{BuildLimits, BuildScheduler};
+use crate::scanner::ScanPolicy;
+use crate::verity::VerityPolicy;
 
 pub const VM_IMAGE_PATH: &str = "/var/lib/formation/vm-images/";
 
@@ -20,16 +24,43 @@ pub struct FormVmmService(SocketAddr);
 pub struct FormPackManager {
     addr: SocketAddr,
     pub(crate) node_id: String,
+    pub(crate) scheduler: BuildScheduler,
+    /// Which built images may be deployed, checked after each build
+    pub(crate) scan_policy: ScanPolicy,
+    /// Whether built images get a dm-verity hash tree
+    pub(crate) verity_policy: VerityPolicy,
+    /// Node key used to sign build manifests
+    pub(crate) signing_key: Option<SigningKey>,
 }
 
 impl FormPackManager {
     pub fn new(addr: SocketAddr, node_id: String,) -> Self {
+        Self::with_limits(addr, node_id, BuildLimits::from_env())
+    }
+
+    pub fn with_limits(addr: SocketAddr, node_id: String, limits: BuildLimits) -> Self {
+        log::info!("Build limits: {limits:?}");
+        let scan_policy = ScanPolicy::from_env();
+        log::info!("Image scan policy: {scan_policy:?}");
+        let verity_policy = VerityPolicy::from_env();
+        log::info!("Image verity policy: {verity_policy:?}");
         Self {
             addr,
-            node_id
+            node_id,
+            scheduler: BuildScheduler::new(limits),
+            scan_policy,
+            verity_policy,
+            signing_key: None,
         }
     }
 
+    /// Sign the manifests of the images this node builds with `signing_key`.
+    /// Without it builds still succeed but vmm-service won't boot them.
+    pub fn with_signing_key(mut self, signing_key: SigningKey) -> Self {
+        self.signing_key = Some(signing_key);
+        self
+    }
+
     pub async fn run(self, mut shutdown: Receiver<()>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let addr = self.addr.to_string();
         let pack_manager = Arc::new(Mutex::new(self));
@@ -46,10 +77,20 @@ impl FormPackManager {
             tokio::select! {
                 Ok(messages) = read_from_queue(Some(n), None) => {
                     for message in &messages {
-                        let mut manager = pack_manager.lock().await;
-                        if let Err(e) = manager.handle_message(message.to_vec()).await {
-                            eprintln!("Error handling message: {e}");
-                        };
+                        let correlation_id = form_p2p::trace::record(message, "form-pack", "received", None).await;
+                        let trace = correlation_id.as_deref().unwrap_or("untraced");
+                        let manager = pack_manager.lock().await;
+                        let result = manager.handle_message(message.to_vec()).await;
+                        drop(manager);
+                        match result {
+                            Ok(()) => {
+                                form_p2p::trace::record(message, "form-pack", "processed", None).await;
+                            }
+                            Err(e) => {
+                                eprintln!("Error handling message {trace}: {e}");
+                                form_p2p::trace::record(message, "form-pack", "failed", Some(e.to_string())).await;
+                            }
+                        }
                     }
                     n += messages.len();
                 },
@@ -65,16 +106,27 @@ impl FormPackManager {
         Ok(())
     }
 
-    pub async fn handle_message(&mut self, message: Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn handle_message(&self, message: Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let subtopic = message[0];
         let request = &message[1..];
         match subtopic {
             0 =>  {
                 let msg: PackBuildRequest = serde_json::from_slice(request)?; 
-                if let Err(e) = handle_pack_request(self, msg.clone()).await {
-                    write_pack_status_failed(&msg, e.to_string()).await?;
-                    return Err(e)
-                }
+                // Builds wait on the scheduler, so run them off the queue loop
+                let node_id = self.node_id.clone();
+                let scheduler = self.scheduler.clone();
+                let signing_key = self.signing_key.clone();
+                let scan_policy = self.scan_policy.clone();
+                let verity_policy = self.verity_policy.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_pack_request(node_id, scheduler, signing_key, scan_policy, verity_policy, msg.clone()).await {
+                        eprintln!("Error handling pack request: {e}");
+                        form_p2p::trace::record(&message, "form-pack", "build_failed", Some(e.to_string())).await;
+                        if let Err(e) = write_pack_status_failed(&msg, e.to_string()).await {
+                            eprintln!("Error writing pack status: {e}");
+                        }
+                    }
+                });
             }
             1 => {
                 let _msg: PackBuildResponse = serde_json::from_slice(request)?;
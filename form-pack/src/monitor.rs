@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::{Cursor, Read};
 use std::time::Duration;
@@ -6,10 +7,14 @@ use tokio::time::sleep;
 use flate2::read::GzDecoder;
 use reqwest::{Client, header::HeaderMap};
 use futures::StreamExt;
-use bollard::{Docker, exec::CreateExecOptions, container::{DownloadFromContainerOptions, UploadToContainerOptions, CreateContainerOptions, Config}, models::{DeviceMapping, HostConfig, PortBinding}};
+use bollard::{Docker, exec::{CreateExecOptions, StartExecResults}, container::{LogOutput, DownloadFromContainerOptions, UploadToContainerOptions, CreateContainerOptions, Config}, models::{DeviceMapping, HostConfig}};
 use crate::helpers::utils::{is_gzip, build_instance_id, get_host_bridge_ip};
-use crate::image_builder::IMAGE_PATH;
+use crate::image_builder::{BuildRequest, FormfileResponse, IMAGE_PATH};
 use crate::formfile::Formfile;
+use crate::scanner::{parse_trivy_report, scan_script, ScanPolicy};
+use crate::scheduler::BuildLimits;
+use crate::stages::{host_cache_dir, StagePolicy, STAGE_CACHE_DIR};
+use form_state::build_manifests::ImageScan;
 use log::{info, warn, error};
 
 pub struct FormPackMonitor {
@@ -19,10 +24,12 @@ pub struct FormPackMonitor {
     build_server_id: Option<String>,
     build_server_uri: String,
     build_server_client: Client,
+    /// Image id of the builder image the build container runs
+    base_image_digest: Option<String>,
 }
 
 impl FormPackMonitor {
-    pub async fn new() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn new(limits: &BuildLimits) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         println!("Building default monitor...");
         let mut monitor = Self {
             docker: Docker::connect_with_local_defaults()?,
@@ -31,13 +38,17 @@ impl FormPackMonitor {
             build_server_id: None,
             build_server_uri: String::new(),
             build_server_client: Client::new(),
+            base_image_digest: None,
         };
 
         println!("Attempting to start build container...");
-        let (container_id, container_name, container_ip) = monitor.start_build_container().await?;
+        let (container_id, container_name, container_ip) = monitor.start_build_container(limits).await?;
         monitor.container_id = Some(container_id.clone());
         monitor.container_name = Some(container_name.clone());
         monitor.build_server_uri = format!("http://{container_ip}:{}", 8080);
+        // The image the container was started from, rather than whatever the
+        // tag points at by the time the build finishes
+        monitor.base_image_digest = monitor.docker.inspect_container(&container_name, None).await?.image;
 
         Ok(monitor)
     }
@@ -46,13 +57,19 @@ impl FormPackMonitor {
         &self.container_id
     }
 
+    pub fn base_image_digest(&self) -> Option<String> {
+        self.base_image_digest.clone()
+    }
+
     pub async fn build_image(
         &mut self,
         node_id: String,
         vm_name: String,
         formfile: Formfile,
         artifacts: PathBuf,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        scan_policy: &ScanPolicy,
+        secrets: BTreeMap<String, String>,
+    ) -> Result<Option<ImageScan>, Box<dyn std::error::Error + Send + Sync>> {
         let container_id = self.container_id.take().ok_or(
             Box::new(
                 std::io::Error::new(
@@ -69,10 +86,25 @@ impl FormPackMonitor {
             println!("Starting build server for {}", formfile.name);
             self.start_build_server(&container_id).await?;
             println!("Requesting image build for {}", formfile.name);
-            self.execute_build(node_id.clone(), vm_name.clone(), &formfile).await?;
+            self.execute_build(node_id.clone(), vm_name.clone(), &formfile, secrets).await?;
+            let scan = if scan_policy.enabled {
+                println!("Scanning image for {} for vulnerabilities", formfile.name);
+                match self.scan_image(&container_id).await {
+                    Ok(scan) => Some(scan),
+                    Err(e) if scan_policy.fail_on.is_some() => {
+                        return Err(format!("Unable to scan image against the vulnerability policy: {e}").into());
+                    }
+                    Err(e) => {
+                        warn!("Unable to scan image for {}, continuing without findings: {e}", formfile.name);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
             self.extract_disk_image(&container_id, vm_name.clone()).await?;
             println!("Image build completed for {} successfully", formfile.name);
-            Ok(())
+            Ok(scan)
         }.await;
 
         println!("Cleaning up container {container_id}...");
@@ -86,23 +118,16 @@ impl FormPackMonitor {
         build_result
     }
 
-    pub async fn start_build_container(&self) -> Result<(String, String, String), Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn start_build_container(&self, limits: &BuildLimits) -> Result<(String, String, String), Box<dyn std::error::Error + Send + Sync>> {
         let container_name = format!("form-pack-builder-{}", uuid::Uuid::new_v4());
         let options = Some(CreateContainerOptions {
             name: container_name.clone(), 
             platform: None,
         });
 
-        let ports = Some([(
-            "8080/tcp".to_string(),
-            Some(vec![PortBinding {
-                host_ip: Some("0.0.0.0".to_string()),
-                host_port: Some("8080".to_string())
-            }]),
-        )].into_iter().collect());
-
-        let host_config = HostConfig {
-            port_bindings: ports,
+        // The build server is reached on the container ip, so no host port is
+        // published and concurrent builds don't contend for 8080
+        let mut host_config = HostConfig {
             devices: Some(vec![DeviceMapping {
                 path_on_host: Some("/dev/kvm".to_string()),
                 path_in_container: Some("/dev/kvm".to_string()),
@@ -110,15 +135,21 @@ impl FormPackMonitor {
             }]),
             ..Default::default()
         };
+        limits.apply(&mut host_config);
+        // Stage images are cached across builds in a host directory
+        host_config.binds = Some(vec![format!("{}:{}", host_cache_dir(), STAGE_CACHE_DIR)]);
 
         let host_ip_var = format!("HOST_BRIDGE_IP={}", get_host_bridge_ip()?);
+        let stage_vars = StagePolicy::from_env().to_env();
+        let mut env = vec![host_ip_var.as_str()];
+        env.extend(stage_vars.iter().map(String::as_str));
         println!("Build HostConfig: {host_config:?}");
         let config = Config {
             image: Some("form-build-server:latest"),
             cmd: None, 
             tty: Some(true),
             host_config: Some(host_config),
-            env: Some(vec![&host_ip_var]),
+            env: Some(env),
             ..Default::default()
         };
 
@@ -256,23 +287,64 @@ impl FormPackMonitor {
         node_id: String,
         vm_name: String,
         formfile: &Formfile,
+        secrets: BTreeMap<String, String>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         println!("Sending Formfile {formfile:?} for {} to build_server: {}", formfile.name, self.build_server_uri);
         let instance_id = build_instance_id(node_id, vm_name.clone())?; 
 
         let mut request = self.build_server_client
             .post(format!("{}/{}/{}/formfile", self.build_server_uri, vm_name, instance_id))
-            .json(formfile);
+            .json(&BuildRequest { formfile: formfile.clone(), secrets });
         
         let headers = HeaderMap::new();
         request = request.headers(headers);
         
         let resp = request.send().await?;
         println!("Received response: {resp:?}");
+        if let FormfileResponse::Failure = resp.json::<FormfileResponse>().await? {
+            return Err(format!("Build server failed to build the image for {}", formfile.name).into());
+        }
 
         Ok(())
     }
 
+    /// Runs the scanner against the built image inside the build container
+    pub async fn scan_image(&self, container_id: &str) -> Result<ImageScan, Box<dyn std::error::Error + Send + Sync>> {
+        let script = scan_script();
+        let exec_opts = CreateExecOptions {
+            cmd: Some(vec!["sh", "-c", script.as_str()]),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            privileged: Some(true),
+            ..Default::default()
+        };
+        let exec = self.docker.create_exec(container_id, exec_opts).await?;
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        if let StartExecResults::Attached { mut output, .. } = self.docker.start_exec(&exec.id, None).await? {
+            while let Some(chunk) = output.next().await {
+                match chunk? {
+                    LogOutput::StdOut { message } => stdout.extend_from_slice(&message),
+                    LogOutput::StdErr { message } => stderr.extend_from_slice(&message),
+                    _ => {}
+                }
+            }
+        }
+
+        let exit_code = self.docker.inspect_exec(&exec.id).await?.exit_code;
+        if exit_code != Some(0) {
+            return Err(format!(
+                "scanner exited with {exit_code:?}: {}",
+                String::from_utf8_lossy(&stderr).trim()
+            ).into());
+        }
+
+        let scan = parse_trivy_report(&stdout)?;
+        info!("(Monitor) {} found {} vulnerabilities: {:?}", scan.scanner, scan.findings.len(), scan.counts());
+        Ok(scan)
+    }
+
     pub async fn extract_disk_image(
         &self,
         container_name: &str,
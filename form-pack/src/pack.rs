@@ -3,7 +3,7 @@ use flate2::write::GzEncoder;
 use flate2::Compression;
 use tar::Builder;
 use serde::{Serialize, Deserialize};
-use crate::{formfile::Formfile, image_builder::copy_dir_recursively};
+use crate::{formfile::Formfile, formignore::FormIgnore};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FormPack {
@@ -32,10 +32,11 @@ impl Pack {
         &self,
         copy_instructions: &[(PathBuf, PathBuf)],
     ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let ignore = FormIgnore::load(&self.context_dir)?;
         if copy_instructions.is_empty() {
             println!("No COPY instructions provided in Formfile...");
             println!("Copying entire directory...");
-            copy_dir_recursively(&self.context_dir, &self.artifact_dir)?;
+            ignore.copy_dir(&self.context_dir, "", &self.artifact_dir)?;
         } else {
             println!("COPY instruction(s) found in Formfile...");
             for (from, to) in copy_instructions {
@@ -55,7 +56,7 @@ impl Pack {
                 if source.is_dir() {
                     println!("{from:?} is a directory, copying recursively to {to:?}...");
                     fs::create_dir_all(&dest)?;
-                    copy_dir_recursively(&source, &dest)?;
+                    ignore.copy_dir(&source, from, &dest)?;
                 } else {
                     if let Some(parent) = dest.parent() {
                         fs::create_dir_all(parent)?;
@@ -0,0 +1,223 @@
+//! Vulnerability scanning of built images
+//!
+//! Once the build server has finished customizing the disk image, the monitor
+//! mounts it read-only inside the build container and runs trivy against the
+//! rootfs, which reads the dpkg database and any language lockfiles. The
+//! findings are stored in the signed build manifest. A `ScanPolicy` decides
+//! which findings fail the build; vmm-service refuses to boot images whose
+//! manifest records a policy violation.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use form_state::build_manifests::{ImageScan, Vulnerability, VulnerabilitySeverity};
+use serde::{Deserialize, Serialize};
+use crate::image_builder::IMAGE_PATH;
+
+/// Where the image is mounted inside the build container while scanning
+const SCAN_MOUNT: &str = "/mnt/form-scan";
+
+/// Which images may be deployed, read from `FORM_PACK_SCAN_*`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanPolicy {
+    /// Scan images after they are built
+    pub enabled: bool,
+    /// Fail builds with findings at this severity or worse. Builds also fail
+    /// if the scan can't run, since the policy can't be checked.
+    pub fail_on: Option<VulnerabilitySeverity>,
+    /// Advisory ids accepted regardless of severity
+    pub ignore: Vec<String>,
+}
+
+impl Default for ScanPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            fail_on: Some(VulnerabilitySeverity::Critical),
+            ignore: vec![],
+        }
+    }
+}
+
+impl ScanPolicy {
+    /// `FORM_PACK_SCAN=false` turns scanning off, `FORM_PACK_SCAN_FAIL_ON`
+    /// takes a severity or `none` to only report, and
+    /// `FORM_PACK_SCAN_IGNORE` a comma separated list of advisory ids
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let enabled = std::env::var("FORM_PACK_SCAN")
+            .map_or(defaults.enabled, |v| !matches!(v.to_ascii_lowercase().as_str(), "false" | "0" | "off"));
+        let fail_on = match std::env::var("FORM_PACK_SCAN_FAIL_ON") {
+            Ok(v) if v.eq_ignore_ascii_case("none") => None,
+            Ok(v) => match v.parse() {
+                Ok(severity) => Some(severity),
+                Err(e) => {
+                    log::warn!("{e}, failing builds on {:?} findings", defaults.fail_on);
+                    defaults.fail_on
+                }
+            },
+            Err(_) => defaults.fail_on,
+        };
+        let ignore = std::env::var("FORM_PACK_SCAN_IGNORE")
+            .map(|v| v.split(',').map(str::trim).filter(|id| !id.is_empty()).map(String::from).collect())
+            .unwrap_or_default();
+        Self { enabled, fail_on, ignore }
+    }
+
+    /// Why the scan fails the policy, if it does
+    pub fn evaluate(&self, scan: &ImageScan) -> Option<String> {
+        let fail_on = self.fail_on?;
+        let blocking: Vec<_> = scan.at_least(fail_on)
+            .into_iter()
+            .filter(|finding| !self.ignore.iter().any(|id| id.eq_ignore_ascii_case(&finding.id)))
+            .collect();
+        if blocking.is_empty() {
+            return None;
+        }
+
+        let mut ids: Vec<_> = blocking.iter().take(5).map(|f| format!("{} in {}", f.id, f.package)).collect();
+        if blocking.len() > ids.len() {
+            ids.push(format!("{} more", blocking.len() - ids.len()));
+        }
+        Some(format!(
+            "Image has {} vulnerabilit{} of {fail_on} severity or worse ({})",
+            blocking.len(),
+            if blocking.len() == 1 { "y" } else { "ies" },
+            ids.join(", "),
+        ))
+    }
+}
+
+/// The script the monitor runs in the build container, prints trivy's JSON
+/// report on stdout
+pub fn scan_script() -> String {
+    format!(
+        "set -e\n\
+         mkdir -p {SCAN_MOUNT}\n\
+         guestmount -a {IMAGE_PATH} -i --ro {SCAN_MOUNT}\n\
+         trap 'guestunmount {SCAN_MOUNT}' EXIT\n\
+         trivy rootfs --quiet --scanners vuln --format json {SCAN_MOUNT}\n"
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyReport {
+    #[serde(default)]
+    trivy: Option<TrivyInfo>,
+    #[serde(default)]
+    results: Vec<TrivyResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyInfo {
+    version: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyResult {
+    #[serde(default)]
+    vulnerabilities: Vec<TrivyVulnerability>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyVulnerability {
+    #[serde(rename = "VulnerabilityID")]
+    vulnerability_id: String,
+    pkg_name: String,
+    installed_version: String,
+    #[serde(default)]
+    fixed_version: Option<String>,
+    severity: String,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+/// Turns trivy's JSON report into an `ImageScan`
+pub fn parse_trivy_report(report: &[u8]) -> Result<ImageScan, serde_json::Error> {
+    let report: TrivyReport = serde_json::from_slice(report)?;
+    let findings = report.results
+        .into_iter()
+        .flat_map(|result| result.vulnerabilities)
+        .map(|v| Vulnerability {
+            severity: v.severity.parse().unwrap_or(VulnerabilitySeverity::Unknown),
+            id: v.vulnerability_id,
+            package: v.pkg_name,
+            installed_version: v.installed_version,
+            fixed_version: v.fixed_version.filter(|fixed| !fixed.is_empty()),
+            title: v.title,
+        })
+        .collect();
+
+    Ok(ImageScan {
+        scanner: report.trivy.map_or("trivy".to_string(), |info| format!("trivy {}", info.version)),
+        scanned_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64),
+        findings,
+        policy_violation: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = r#"{
+        "SchemaVersion": 2,
+        "ArtifactName": "/mnt/form-scan",
+        "Trivy": { "Version": "0.56.2" },
+        "Results": [
+            {
+                "Target": "/mnt/form-scan (ubuntu 22.04)",
+                "Class": "os-pkgs",
+                "Vulnerabilities": [
+                    {
+                        "VulnerabilityID": "CVE-2024-0001",
+                        "PkgName": "openssl",
+                        "InstalledVersion": "3.0.2-0ubuntu1.15",
+                        "FixedVersion": "3.0.2-0ubuntu1.16",
+                        "Severity": "CRITICAL",
+                        "Title": "openssl: remote code execution"
+                    },
+                    {
+                        "VulnerabilityID": "CVE-2024-0002",
+                        "PkgName": "bash",
+                        "InstalledVersion": "5.1-6ubuntu1",
+                        "FixedVersion": "",
+                        "Severity": "LOW"
+                    }
+                ]
+            },
+            { "Target": "app/package-lock.json", "Class": "lang-pkgs" }
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_trivy_report() {
+        let scan = parse_trivy_report(REPORT.as_bytes()).unwrap();
+        assert_eq!(scan.scanner, "trivy 0.56.2");
+        assert_eq!(scan.findings.len(), 2);
+        assert_eq!(scan.findings[0].severity, VulnerabilitySeverity::Critical);
+        assert_eq!(scan.findings[0].fixed_version.as_deref(), Some("3.0.2-0ubuntu1.16"));
+        assert_eq!(scan.findings[1].severity, VulnerabilitySeverity::Low);
+        assert_eq!(scan.findings[1].fixed_version, None);
+    }
+
+    #[test]
+    fn test_policy_gates() {
+        let scan = parse_trivy_report(REPORT.as_bytes()).unwrap();
+
+        let violation = ScanPolicy::default().evaluate(&scan).unwrap();
+        assert!(violation.contains("1 vulnerability of critical severity"));
+        assert!(violation.contains("CVE-2024-0001 in openssl"));
+
+        let ignoring = ScanPolicy { ignore: vec!["cve-2024-0001".to_string()], ..Default::default() };
+        assert_eq!(ignoring.evaluate(&scan), None);
+
+        let strict = ScanPolicy { fail_on: Some(VulnerabilitySeverity::Low), ..Default::default() };
+        assert!(strict.evaluate(&scan).unwrap().starts_with("Image has 2 vulnerabilities"));
+
+        let report_only = ScanPolicy { fail_on: None, ..Default::default() };
+        assert_eq!(report_only.evaluate(&scan), None);
+    }
+}
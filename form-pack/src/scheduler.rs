@@ -0,0 +1,392 @@
+//! Build scheduling and resource limits
+//!
+//! Builds run in privileged containers that mount and modify disk images, so
+//! an unbounded number of them can exhaust the host. The scheduler admits at
+//! most `max_concurrent_builds` at a time, queues the rest in FIFO order (up
+//! to `max_queued_builds`) and tracks each build's state so clients can poll
+//! their position. Every build container is started with the cgroup CPU,
+//! memory and pid limits and the scratch disk quota from `BuildLimits`.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use bollard::models::HostConfig;
+use form_state::build_manifests::SignedBuildManifest;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+
+/// Number of finished builds whose final state is kept for status queries
+const FINISHED_HISTORY: usize = 256;
+
+/// Resource limits applied to the build scheduler and each build container
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildLimits {
+    /// Builds allowed to run at the same time
+    pub max_concurrent_builds: usize,
+    /// Builds allowed to wait for a slot, further requests are rejected
+    pub max_queued_builds: usize,
+    /// CPU cap per build container, in cores
+    pub cpus: Option<f64>,
+    /// Memory cap per build container, swap is disabled
+    pub memory_mb: Option<u64>,
+    /// Cap on the writable layer of each build container, and on uploaded artifacts
+    pub disk_quota_mb: Option<u64>,
+    /// Cap on the number of processes in each build container
+    pub pids_limit: Option<i64>,
+}
+
+impl Default for BuildLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent_builds: 2,
+            max_queued_builds: 32,
+            cpus: Some(2.0),
+            memory_mb: Some(4096),
+            disk_quota_mb: None,
+            pids_limit: Some(1024),
+        }
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|value| value.parse().ok())
+}
+
+impl BuildLimits {
+    /// Read limits from `FORM_PACK_*` environment variables, falling back to
+    /// the defaults. A value of `0` removes an optional cap.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            max_concurrent_builds: env_var("FORM_PACK_MAX_CONCURRENT_BUILDS")
+                .unwrap_or(defaults.max_concurrent_builds)
+                .max(1),
+            max_queued_builds: env_var("FORM_PACK_MAX_QUEUED_BUILDS").unwrap_or(defaults.max_queued_builds),
+            cpus: env_var::<f64>("FORM_PACK_BUILD_CPUS").map_or(defaults.cpus, |v| (v > 0.0).then_some(v)),
+            memory_mb: env_var::<u64>("FORM_PACK_BUILD_MEMORY_MB").map_or(defaults.memory_mb, |v| (v > 0).then_some(v)),
+            disk_quota_mb: env_var::<u64>("FORM_PACK_BUILD_DISK_QUOTA_MB").map_or(defaults.disk_quota_mb, |v| (v > 0).then_some(v)),
+            pids_limit: env_var::<i64>("FORM_PACK_BUILD_PIDS_LIMIT").map_or(defaults.pids_limit, |v| (v > 0).then_some(v)),
+        }
+    }
+
+    /// Apply the per-build limits to a container's host config. Docker
+    /// enforces them through the container's cgroup; the disk quota needs a
+    /// storage driver that supports `size` (overlay2 on xfs with pquota).
+    pub fn apply(&self, host_config: &mut HostConfig) {
+        if let Some(cpus) = self.cpus {
+            host_config.nano_cpus = Some((cpus * 1_000_000_000.0) as i64);
+        }
+        if let Some(memory_mb) = self.memory_mb {
+            let bytes = (memory_mb << 20) as i64;
+            host_config.memory = Some(bytes);
+            host_config.memory_swap = Some(bytes);
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            host_config.pids_limit = Some(pids_limit);
+        }
+        if let Some(disk_quota_mb) = self.disk_quota_mb {
+            host_config.storage_opt = Some(HashMap::from([
+                ("size".to_string(), format!("{disk_quota_mb}M")),
+            ]));
+        }
+    }
+
+    /// Whether uploaded artifacts of this size fit in the scratch quota
+    pub fn artifacts_fit(&self, size_bytes: u64) -> bool {
+        self.disk_quota_mb.map_or(true, |quota| size_bytes <= quota << 20)
+    }
+}
+
+/// State of a build known to the scheduler
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuildState {
+    /// Waiting for a slot, `position` 1 is next in line
+    Queued { position: usize },
+    Running,
+    Completed,
+    Failed { reason: String },
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ScheduleError {
+    #[error("Build {0} is already queued or running")]
+    AlreadyScheduled(String),
+    #[error("Build queue is full ({0} builds waiting), try again later")]
+    QueueFull(usize),
+}
+
+#[derive(Default)]
+struct SchedulerInner {
+    queue: VecDeque<String>,
+    running: Vec<String>,
+    finished: VecDeque<(String, BuildState)>,
+    /// Signed manifests of the finished builds that have one
+    manifests: HashMap<String, SignedBuildManifest>,
+}
+
+/// Admits builds up to the concurrency limit and queues the rest
+#[derive(Clone)]
+pub struct BuildScheduler {
+    limits: BuildLimits,
+    slots: Arc<Semaphore>,
+    inner: Arc<Mutex<SchedulerInner>>,
+}
+
+/// A queued build, turns into a `BuildPermit` once a slot frees up
+pub struct BuildTicket {
+    scheduler: BuildScheduler,
+    build_id: String,
+}
+
+/// A running build. Dropping the permit frees its slot; call `finish` to
+/// record how the build ended.
+pub struct BuildPermit {
+    scheduler: BuildScheduler,
+    build_id: String,
+    state: Option<BuildState>,
+    manifest: Option<SignedBuildManifest>,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl BuildScheduler {
+    pub fn new(limits: BuildLimits) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(limits.max_concurrent_builds.max(1))),
+            limits,
+            inner: Arc::new(Mutex::new(SchedulerInner::default())),
+        }
+    }
+
+    pub fn limits(&self) -> &BuildLimits {
+        &self.limits
+    }
+
+    /// Put a build in the queue. Returns its ticket and its position, where
+    /// `1` means it starts as soon as a slot is free.
+    pub async fn enqueue(&self, build_id: &str) -> Result<(BuildTicket, usize), ScheduleError> {
+        let mut inner = self.inner.lock().await;
+        if inner.queue.iter().chain(inner.running.iter()).any(|id| id == build_id) {
+            return Err(ScheduleError::AlreadyScheduled(build_id.to_string()));
+        }
+        if inner.queue.len() >= self.limits.max_queued_builds {
+            return Err(ScheduleError::QueueFull(inner.queue.len()));
+        }
+        inner.finished.retain(|(id, _)| id != build_id);
+        inner.manifests.remove(build_id);
+        inner.queue.push_back(build_id.to_string());
+        let position = inner.queue.len();
+        log::info!("Queued build {build_id} at position {position} ({} running)", inner.running.len());
+
+        Ok((BuildTicket { scheduler: self.clone(), build_id: build_id.to_string() }, position))
+    }
+
+    /// Current state of a build, `None` if the scheduler doesn't know it
+    pub async fn status(&self, build_id: &str) -> Option<BuildState> {
+        let inner = self.inner.lock().await;
+        if let Some(index) = inner.queue.iter().position(|id| id == build_id) {
+            return Some(BuildState::Queued { position: index + 1 });
+        }
+        if inner.running.iter().any(|id| id == build_id) {
+            return Some(BuildState::Running);
+        }
+        inner.finished.iter().rev().find(|(id, _)| id == build_id).map(|(_, state)| state.clone())
+    }
+
+    /// The signed manifest of a finished build, returned to the client with
+    /// its status
+    pub async fn manifest(&self, build_id: &str) -> Option<SignedBuildManifest> {
+        self.inner.lock().await.manifests.get(build_id).cloned()
+    }
+
+    async fn start(&self, build_id: &str) {
+        let mut inner = self.inner.lock().await;
+        inner.queue.retain(|id| id != build_id);
+        inner.running.push(build_id.to_string());
+    }
+
+    async fn cancel(&self, build_id: &str) {
+        self.inner.lock().await.queue.retain(|id| id != build_id);
+    }
+
+    fn release(&self, build_id: String, state: BuildState, manifest: Option<SignedBuildManifest>) {
+        let inner = self.inner.clone();
+        let update = async move {
+            let mut inner = inner.lock().await;
+            inner.running.retain(|id| *id != build_id);
+            if let Some(manifest) = manifest {
+                inner.manifests.insert(build_id.clone(), manifest);
+            }
+            inner.finished.push_back((build_id, state));
+            while inner.finished.len() > FINISHED_HISTORY {
+                if let Some((evicted, _)) = inner.finished.pop_front() {
+                    inner.manifests.remove(&evicted);
+                }
+            }
+        };
+        // Permits are dropped from sync code, so record the result in the background
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(update);
+        }
+    }
+}
+
+impl BuildTicket {
+    pub fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    /// Wait until the build may run. The semaphore is fair, so builds
+    /// start in the order they were queued.
+    pub async fn wait(self) -> BuildPermit {
+        let slot = self.scheduler.slots.clone()
+            .acquire_owned()
+            .await
+            .expect("build scheduler semaphore is never closed");
+        self.scheduler.start(&self.build_id).await;
+        log::info!("Starting build {}", self.build_id);
+        BuildPermit {
+            scheduler: self.scheduler.clone(),
+            build_id: self.build_id.clone(),
+            state: Some(BuildState::Failed { reason: "Build was interrupted".to_string() }),
+            manifest: None,
+            _slot: slot,
+        }
+    }
+
+    /// Leave the queue without running
+    pub async fn cancel(self) {
+        self.scheduler.cancel(&self.build_id).await;
+    }
+}
+
+impl BuildPermit {
+    pub fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    /// Keep the signed manifest of the image so status queries return it
+    pub fn attach_manifest(&mut self, manifest: SignedBuildManifest) {
+        self.manifest = Some(manifest);
+    }
+
+    /// Record how the build ended and free its slot
+    pub fn finish(mut self, result: Result<(), String>) {
+        self.state = Some(match result {
+            Ok(()) => BuildState::Completed,
+            Err(reason) => BuildState::Failed { reason },
+        });
+    }
+}
+
+impl Drop for BuildPermit {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            self.scheduler.release(std::mem::take(&mut self.build_id), state, self.manifest.take());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(max_concurrent_builds: usize, max_queued_builds: usize) -> BuildLimits {
+        BuildLimits { max_concurrent_builds, max_queued_builds, ..Default::default() }
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn test_builds_run_in_order_up_to_the_limit() {
+        let scheduler = BuildScheduler::new(limits(1, 4));
+        let (first, position) = scheduler.enqueue("a").await.unwrap();
+        assert_eq!(position, 1);
+        let (second, position) = scheduler.enqueue("b").await.unwrap();
+        assert_eq!(position, 2);
+
+        let running = first.wait().await;
+        assert_eq!(scheduler.status("a").await, Some(BuildState::Running));
+        assert_eq!(scheduler.status("b").await, Some(BuildState::Queued { position: 1 }));
+
+        let waiting = tokio::spawn(second.wait());
+        settle().await;
+        assert!(!waiting.is_finished());
+
+        running.finish(Ok(()));
+        let running = waiting.await.unwrap();
+        settle().await;
+        assert_eq!(scheduler.status("a").await, Some(BuildState::Completed));
+        assert_eq!(scheduler.status("b").await, Some(BuildState::Running));
+
+        running.finish(Err("boom".to_string()));
+        settle().await;
+        assert_eq!(scheduler.status("b").await, Some(BuildState::Failed { reason: "boom".to_string() }));
+    }
+
+    #[tokio::test]
+    async fn test_queue_limits() {
+        let scheduler = BuildScheduler::new(limits(1, 1));
+        let (ticket, _) = scheduler.enqueue("a").await.unwrap();
+        assert_eq!(scheduler.enqueue("a").await.err(), Some(ScheduleError::AlreadyScheduled("a".to_string())));
+        assert_eq!(scheduler.enqueue("b").await.err(), Some(ScheduleError::QueueFull(1)));
+
+        ticket.cancel().await;
+        assert_eq!(scheduler.status("a").await, None);
+        assert!(scheduler.enqueue("b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_completed_builds_keep_their_manifest() {
+        use form_state::build_manifests::BuildManifest;
+
+        let scheduler = BuildScheduler::new(limits(1, 4));
+        let manifest = SignedBuildManifest {
+            manifest: BuildManifest {
+                build_id: "a".to_string(),
+                formfile_digest: "aa".to_string(),
+                base_image_digest: "sha256:bb".to_string(),
+                image_digest: "cc".to_string(),
+                builder_node: "node".to_string(),
+                built_at: 10,
+                scan: None,
+                verity: None,
+            },
+            signature: "00".to_string(),
+            recovery_id: 0,
+        };
+
+        let (ticket, _) = scheduler.enqueue("a").await.unwrap();
+        let mut permit = ticket.wait().await;
+        permit.attach_manifest(manifest.clone());
+        permit.finish(Ok(()));
+        settle().await;
+        assert_eq!(scheduler.manifest("a").await, Some(manifest));
+
+        // Rebuilding drops the manifest of the previous image
+        let (_ticket, _) = scheduler.enqueue("a").await.unwrap();
+        assert_eq!(scheduler.manifest("a").await, None);
+    }
+
+    #[test]
+    fn test_limits_apply_to_host_config() {
+        let limits = BuildLimits {
+            cpus: Some(1.5),
+            memory_mb: Some(512),
+            disk_quota_mb: Some(2048),
+            pids_limit: None,
+            ..Default::default()
+        };
+        let mut host_config = HostConfig::default();
+        limits.apply(&mut host_config);
+
+        assert_eq!(host_config.nano_cpus, Some(1_500_000_000));
+        assert_eq!(host_config.memory, Some(512 << 20));
+        assert_eq!(host_config.memory_swap, Some(512 << 20));
+        assert_eq!(host_config.pids_limit, None);
+        assert_eq!(host_config.storage_opt.unwrap().get("size").map(String::as_str), Some("2048M"));
+        assert!(limits.artifacts_fit(2048 << 20));
+        assert!(!limits.artifacts_fit((2048 << 20) + 1));
+    }
+}
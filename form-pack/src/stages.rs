@@ -0,0 +1,596 @@
+//! Multi-stage builds
+//!
+//! A Formfile with `STAGE` lines is built in stages. Every stage but the
+//! last is built into an image of its own, starting from the base image or
+//! the image of the stage it names with `FROM`, and later stages copy files
+//! out of it with `COPY --from`. The last stage is built into the build's
+//! image as a single-stage Formfile is.
+//!
+//! The stages form a graph by the stages each of them starts from and copies
+//! from. Stages the image doesn't need are skipped, the others are built as
+//! soon as the stages they need are, up to `FORM_PACK_STAGE_PARALLELISM` at
+//! once.
+//!
+//! Stage images are cached by a hash of everything that goes into them: the
+//! base image or the hash of the stage they start from, their instructions,
+//! the artifacts they copy and the hashes of the stages they copy from. The
+//! cache is a host directory mounted into every build container, so a stage
+//! shared by builds, like one installing a toolchain, is built once. Stages
+//! that use build secrets are never cached. The least recently used images
+//! beyond `FORM_PACK_MAX_CACHED_STAGES` are removed after each build.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{info, warn};
+use tiny_keccak::{Hasher, Sha3};
+use crate::build_secrets::script_env;
+use crate::formfile::{copy_sources, BuildInstruction, BuildStage, Formfile};
+use crate::image_builder::{add_instruction, artifact_path, copied_path, VirtCustomize, IMAGE_PATH};
+
+/// The stage cache inside build containers
+pub const STAGE_CACHE_DIR: &str = "/var/lib/form-pack/stages";
+/// Where files copied out of stages are kept during a build
+pub const STAGE_FILES_DIR: &str = "/tmp/form-pack-stage-files";
+/// The host directory mounted as the stage cache by default
+pub const DEFAULT_HOST_STAGE_CACHE_DIR: &str = "/var/lib/formation/pack-manager/stages";
+pub const DEFAULT_STAGE_PARALLELISM: usize = 4;
+pub const DEFAULT_MAX_CACHED_STAGES: usize = 16;
+
+/// Part of every stage's cache key, bump it when the way stages are built
+/// changes
+const STAGE_FORMAT: &str = "form-pack-stage-v1";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StagePolicy {
+    /// Stages built at once
+    pub parallelism: usize,
+    /// Stage images kept in the cache
+    pub max_cached: usize,
+}
+
+impl Default for StagePolicy {
+    fn default() -> Self {
+        Self {
+            parallelism: DEFAULT_STAGE_PARALLELISM,
+            max_cached: DEFAULT_MAX_CACHED_STAGES,
+        }
+    }
+}
+
+impl StagePolicy {
+    /// Read from `FORM_PACK_STAGE_PARALLELISM` and
+    /// `FORM_PACK_MAX_CACHED_STAGES`, defaults for unset or invalid values
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<usize>().ok());
+        Self {
+            parallelism: var("FORM_PACK_STAGE_PARALLELISM").unwrap_or(defaults.parallelism).max(1),
+            max_cached: var("FORM_PACK_MAX_CACHED_STAGES").unwrap_or(defaults.max_cached),
+        }
+    }
+
+    /// The environment that gives a build container this policy
+    pub fn to_env(&self) -> Vec<String> {
+        vec![
+            format!("FORM_PACK_STAGE_PARALLELISM={}", self.parallelism),
+            format!("FORM_PACK_MAX_CACHED_STAGES={}", self.max_cached),
+        ]
+    }
+}
+
+/// The host directory mounted as the stage cache of build containers,
+/// `FORM_PACK_STAGE_CACHE_DIR` if set
+pub fn host_cache_dir() -> String {
+    std::env::var("FORM_PACK_STAGE_CACHE_DIR").unwrap_or_else(|_| DEFAULT_HOST_STAGE_CACHE_DIR.to_string())
+}
+
+/// The stages the image needs, in Formfile order, and the stages each of
+/// them needs
+#[derive(Clone, Debug)]
+pub struct StageGraph {
+    stages: Vec<BuildStage>,
+    dependencies: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl StageGraph {
+    /// Fails if a stage needs one that isn't declared before it, which also
+    /// rules out cycles
+    pub fn new(formfile: &Formfile) -> Result<Self, String> {
+        let mut declared = BTreeSet::new();
+        for stage in &formfile.stages {
+            if let Some(missing) = stage.dependencies().find(|dependency| !declared.contains(*dependency)) {
+                return Err(format!("Stage {} needs {missing}, which is no stage before it", stage.name));
+            }
+            if !declared.insert(stage.name.as_str()) {
+                return Err(format!("Stage {} is declared twice", stage.name));
+            }
+        }
+
+        let image_needs = formfile.base_stage.as_deref().into_iter().chain(copy_sources(&formfile.build_instructions));
+        let mut needed: BTreeSet<&str> = BTreeSet::new();
+        let mut pending: Vec<&str> = image_needs.collect();
+        while let Some(name) = pending.pop() {
+            let Some(stage) = formfile.stages.iter().find(|stage| stage.name == name) else {
+                return Err(format!("The image needs {name}, which is no stage of the Formfile"));
+            };
+            if needed.insert(name) {
+                pending.extend(stage.dependencies());
+            }
+        }
+
+        let stages: Vec<BuildStage> = formfile.stages.iter()
+            .filter(|stage| needed.contains(stage.name.as_str()))
+            .cloned()
+            .collect();
+        let dependencies = stages.iter()
+            .map(|stage| (stage.name.clone(), stage.dependencies().map(str::to_string).collect()))
+            .collect();
+        Ok(Self { stages, dependencies })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn stages(&self) -> &[BuildStage] {
+        &self.stages
+    }
+
+    /// Stages that haven't been started and whose dependencies are done
+    pub fn ready<'a>(&'a self, done: &'a BTreeSet<String>, started: &'a BTreeSet<String>) -> impl Iterator<Item = &'a BuildStage> {
+        self.stages.iter().filter(move |stage| {
+            !started.contains(&stage.name) && self.dependencies[&stage.name].iter().all(|dependency| done.contains(dependency))
+        })
+    }
+
+    /// The cache key of each stage, None for stages that can't be cached.
+    /// `base` identifies the base image, `artifacts` is where COPY sources
+    /// are read from.
+    pub fn cache_keys(&self, base: &str, artifacts: &Path) -> std::io::Result<BTreeMap<String, Option<String>>> {
+        let mut keys: BTreeMap<String, Option<String>> = BTreeMap::new();
+        for stage in &self.stages {
+            let key = stage_key(stage, base, artifacts, &keys)?;
+            keys.insert(stage.name.clone(), key);
+        }
+        Ok(keys)
+    }
+}
+
+fn stage_key(
+    stage: &BuildStage,
+    base: &str,
+    artifacts: &Path,
+    keys: &BTreeMap<String, Option<String>>,
+) -> std::io::Result<Option<String>> {
+    let mut hasher = Sha3::v256();
+    hasher.update(STAGE_FORMAT.as_bytes());
+    match &stage.from {
+        Some(from) => match keys.get(from).cloned().flatten() {
+            Some(key) => hasher.update(format!("stage:{key}").as_bytes()),
+            None => return Ok(None),
+        },
+        None => hasher.update(format!("base:{base}").as_bytes()),
+    }
+
+    for instruction in &stage.instructions {
+        match instruction {
+            // The values of secrets can't be part of a key stored in the clear
+            BuildInstruction::RunWithSecrets(..) => return Ok(None),
+            // Ports are held in a set, which serializes in no fixed order
+            BuildInstruction::Expose(ports) => {
+                let mut ports: Vec<u16> = ports.iter().copied().collect();
+                ports.sort_unstable();
+                hasher.update(format!("expose:{ports:?}").as_bytes());
+            }
+            BuildInstruction::Copy(from, _) => {
+                hasher.update(&serde_json::to_vec(instruction)?);
+                digest_path(&mut hasher, &artifact_path(artifacts, from))?;
+            }
+            BuildInstruction::CopyFrom(source, ..) => {
+                let Some(key) = keys.get(source).cloned().flatten() else {
+                    return Ok(None);
+                };
+                hasher.update(&serde_json::to_vec(instruction)?);
+                hasher.update(key.as_bytes());
+            }
+            _ => hasher.update(&serde_json::to_vec(instruction)?),
+        }
+    }
+
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+    Ok(Some(hex::encode(hash)))
+}
+
+/// Hashes the names and contents of the files under `path`
+fn digest_path(hasher: &mut Sha3, path: &Path) -> std::io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        // The build fails on it, there's nothing to cache
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            hasher.update(b"missing");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if metadata.is_dir() {
+        let mut entries: Vec<_> = std::fs::read_dir(path)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        hasher.update(format!("dir:{}", entries.len()).as_bytes());
+        for entry in entries {
+            hasher.update(entry.file_name().to_string_lossy().as_bytes());
+            digest_path(hasher, &entry.path())?;
+        }
+    } else if metadata.file_type().is_symlink() {
+        hasher.update(format!("link:{}", std::fs::read_link(path)?.display()).as_bytes());
+    } else {
+        hasher.update(format!("file:{}", metadata.len()).as_bytes());
+        let mut file = std::fs::File::open(path)?;
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+    }
+    Ok(())
+}
+
+/// Identifies the base image by its size and modification time, those of
+/// the build server image's layer in a fresh build container
+fn base_image_id(image: &Path) -> std::io::Result<String> {
+    let metadata = std::fs::metadata(image)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    Ok(format!("{}:{modified}", metadata.len()))
+}
+
+/// The images of the stages a build needed. Images that aren't cached and
+/// the files copied out of them are removed when it's dropped.
+#[derive(Debug, Default)]
+pub struct StageImages {
+    images: BTreeMap<String, PathBuf>,
+    scratch: Vec<PathBuf>,
+}
+
+impl StageImages {
+    pub fn get(&self, stage: &str) -> Option<&Path> {
+        self.images.get(stage).map(PathBuf::as_path)
+    }
+
+    /// Replaces `image` with a copy of the stage's image
+    pub async fn copy_to(&self, stage: &str, image: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let source = self.get(stage).ok_or_else(|| format!("Stage {stage} wasn't built"))?;
+        copy_image(source, image).await
+    }
+
+    /// Copies the sources of the `COPY --from` instructions out of their
+    /// stages into `dir`, where `add_instruction` expects them
+    pub async fn extract_copies(
+        &mut self,
+        instructions: &[BuildInstruction],
+        dir: &Path,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if copy_sources(instructions).next().is_some() {
+            self.scratch.push(dir.to_path_buf());
+        }
+        for (index, instruction) in instructions.iter().enumerate() {
+            let BuildInstruction::CopyFrom(stage, from, _) = instruction else {
+                continue;
+            };
+            let image = self.get(stage).ok_or_else(|| format!("Stage {stage} wasn't built"))?.to_path_buf();
+            copy_out(&image, from, &copied_path(dir, index, Path::new("")), stage).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StageImages {
+    fn drop(&mut self) {
+        for path in self.scratch.drain(..) {
+            let removed = if path.is_dir() {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+            if let Err(e) = removed {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Unable to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+async fn copy_image(from: &Path, to: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let output = tokio::process::Command::new("cp")
+        .arg("--sparse=always")
+        .arg(from)
+        .arg(to)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(format!("Unable to copy {}: {}", from.display(), String::from_utf8_lossy(&output.stderr)).into());
+    }
+    Ok(())
+}
+
+/// Copies `from` in a stage's image into the directory `to`
+async fn copy_out(image: &Path, from: &Path, to: &Path, stage: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tokio::fs::create_dir_all(to).await?;
+    let output = tokio::process::Command::new("virt-copy-out")
+        .arg("-a")
+        .arg(image)
+        .arg(from)
+        .arg(to)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(format!(
+            "Unable to copy {} out of stage {stage}: {}",
+            from.display(), String::from_utf8_lossy(&output.stderr)
+        ).into());
+    }
+    Ok(())
+}
+
+/// Builds the stages the image needs, independent ones at once, reusing
+/// cached images of stages built before
+pub async fn build_stages(
+    formfile: &Formfile,
+    secrets: &BTreeMap<String, String>,
+    policy: &StagePolicy,
+) -> Result<StageImages, Box<dyn std::error::Error + Send + Sync>> {
+    let graph = StageGraph::new(formfile)?;
+    let mut images = StageImages::default();
+    if graph.is_empty() {
+        return Ok(images);
+    }
+
+    let cache_dir = PathBuf::from(STAGE_CACHE_DIR);
+    tokio::fs::create_dir_all(&cache_dir).await?;
+    let keys = graph.cache_keys(&base_image_id(Path::new(IMAGE_PATH))?, Path::new("/artifacts"))?;
+    info!("Building stages {:?} of {}", graph.stages().iter().map(|stage| &stage.name).collect::<Vec<_>>(), formfile.name);
+
+    let mut started = BTreeSet::new();
+    let mut done = BTreeSet::new();
+    let mut running = FuturesUnordered::new();
+    loop {
+        // A cached stage is done at once and may make others ready
+        let mut progressed = true;
+        while progressed && running.len() < policy.parallelism {
+            progressed = false;
+            let ready: Vec<BuildStage> = graph.ready(&done, &started).cloned().collect();
+            for stage in ready {
+                if running.len() >= policy.parallelism {
+                    break;
+                }
+                started.insert(stage.name.clone());
+                let key = keys.get(&stage.name).cloned().flatten();
+                let cached = key.as_ref().map(|key| cache_dir.join(format!("{key}.raw")));
+                if let Some(image) = cached.as_ref().filter(|image| image.exists()) {
+                    info!("Using the cached image of stage {}", stage.name);
+                    touch(image);
+                    images.images.insert(stage.name.clone(), image.clone());
+                    done.insert(stage.name);
+                    progressed = true;
+                    continue;
+                }
+
+                let image = match cached {
+                    Some(image) => image,
+                    None => {
+                        let image = cache_dir.join(format!("scratch-{}.raw", uuid::Uuid::new_v4()));
+                        images.scratch.push(image.clone());
+                        image
+                    }
+                };
+                let parent = match &stage.from {
+                    Some(from) => images.images[from].clone(),
+                    None => PathBuf::from(IMAGE_PATH),
+                };
+                let sources: BTreeMap<String, PathBuf> = copy_sources(&stage.instructions)
+                    .map(|source| (source.to_string(), images.images[source].clone()))
+                    .collect();
+                let secrets = secrets.clone();
+                running.push(async move {
+                    let result = build_stage(&stage, &parent, sources, &image, &secrets).await;
+                    (stage.name, image, result)
+                });
+            }
+        }
+
+        let Some((name, image, result)) = running.next().await else {
+            break;
+        };
+        if let Err(e) = result {
+            return Err(format!("Stage {name} failed: {e}").into());
+        }
+        info!("Built stage {name}");
+        images.images.insert(name.clone(), image);
+        done.insert(name);
+    }
+
+    if done.len() != graph.stages().len() {
+        return Err("Not every stage could be built".into());
+    }
+    prune_cache(&cache_dir, policy.max_cached);
+    Ok(images)
+}
+
+/// Builds a stage into `image`, which only appears once the stage is built
+async fn build_stage(
+    stage: &BuildStage,
+    parent: &Path,
+    sources: BTreeMap<String, PathBuf>,
+    image: &Path,
+    secrets: &BTreeMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let partial = image.with_extension(format!("partial-{}", uuid::Uuid::new_v4()));
+    let files = PathBuf::from(STAGE_FILES_DIR).join(partial.file_name().unwrap_or_default());
+    let mut scratch = StageImages { images: sources, scratch: vec![partial.clone(), files.clone()] };
+
+    copy_image(parent, &partial).await?;
+    scratch.extract_copies(&stage.instructions, &files).await?;
+
+    let mut command = VirtCustomize::new();
+    if stage.from.is_none() {
+        command = command
+            .run_command("growpart /dev/sda 1")
+            .run_command("resize2fs /dev/sda1");
+    }
+    command = command.run_command("apt-get -y update");
+    for (index, instruction) in stage.instructions.iter().enumerate() {
+        command = add_instruction(command, index, instruction, &files);
+    }
+    let script = command.build_for(&partial).map_err(|e| e.to_string())?;
+    tokio::fs::create_dir_all(&files).await?;
+    let script_path = files.join("build-stage.sh");
+    tokio::fs::write(&script_path, script).await?;
+
+    info!("Building stage {}", stage.name);
+    let output = tokio::process::Command::new("bash")
+        .arg(&script_path)
+        .envs(script_env(secrets))
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).into_owned().into());
+    }
+    tokio::fs::rename(&partial, image).await?;
+    Ok(())
+}
+
+/// Marks a cached image as used, the cache is pruned by last use
+fn touch(image: &Path) {
+    let touched = std::fs::File::options()
+        .append(true)
+        .open(image)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = touched {
+        warn!("Unable to mark {} as used: {}", image.display(), e);
+    }
+}
+
+/// Removes the least recently used stage images beyond `keep`
+fn prune_cache(dir: &Path, keep: usize) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Unable to read the stage cache: {}", e);
+            return;
+        }
+    };
+    let mut cached: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension().map_or(false, |ext| ext == "raw")
+                && !path.file_name().map_or(false, |name| name.to_string_lossy().starts_with("scratch-"))
+        })
+        .filter_map(|path| Some((std::fs::metadata(&path).ok()?.modified().ok()?, path)))
+        .collect();
+    cached.sort_by(|a, b| b.0.cmp(&a.0));
+    for (_, path) in cached.into_iter().skip(keep) {
+        info!("Removing stage image {} from the cache", path.display());
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Unable to remove stage image {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formfile::FormfileParser;
+
+    fn parse(content: &str) -> Formfile {
+        FormfileParser::new().parse(content).unwrap()
+    }
+
+    const FORMFILE: &str = "NAME app
+STAGE toolchain
+INSTALL build-essential
+STAGE build FROM toolchain
+COPY ./src /src
+RUN make -C /src
+STAGE docs FROM toolchain
+RUN make -C /src docs
+STAGE assets
+RUN mkdir -p /assets
+STAGE app
+COPY --from=build /src/app /usr/bin
+COPY --from=assets /assets /srv
+";
+
+    fn names<'a>(stages: impl Iterator<Item = &'a BuildStage>) -> Vec<&'a str> {
+        stages.map(|stage| stage.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_graph_skips_unneeded_stages_and_orders_the_rest() {
+        let graph = StageGraph::new(&parse(FORMFILE)).unwrap();
+        assert_eq!(names(graph.stages().iter()), vec!["toolchain", "build", "assets"]);
+
+        let mut done = BTreeSet::new();
+        let mut started = BTreeSet::new();
+        // Independent stages are ready together
+        assert_eq!(names(graph.ready(&done, &started)), vec!["toolchain", "assets"]);
+
+        started.extend(["toolchain".to_string(), "assets".to_string()]);
+        assert!(graph.ready(&done, &started).next().is_none());
+        done.insert("toolchain".to_string());
+        assert_eq!(names(graph.ready(&done, &started)), vec!["build"]);
+
+        let single = StageGraph::new(&parse("NAME app\nRUN ls\n")).unwrap();
+        assert!(single.is_empty());
+    }
+
+    #[test]
+    fn test_graph_rejects_unknown_stages() {
+        let mut formfile = parse(FORMFILE);
+        formfile.stages[0].from = Some("app".to_string());
+        assert!(StageGraph::new(&formfile).is_err());
+
+        let mut formfile = parse(FORMFILE);
+        formfile.base_stage = Some("missing".to_string());
+        assert!(StageGraph::new(&formfile).is_err());
+    }
+
+    #[test]
+    fn test_cache_keys_follow_inputs() {
+        let artifacts = tempfile::tempdir().unwrap();
+        std::fs::create_dir(artifacts.path().join("src")).unwrap();
+        std::fs::write(artifacts.path().join("src/main.c"), "int main() {}").unwrap();
+
+        let graph = StageGraph::new(&parse(FORMFILE)).unwrap();
+        let keys = graph.cache_keys("base-1", artifacts.path()).unwrap();
+        assert!(keys.values().all(Option::is_some));
+        assert_eq!(keys, graph.cache_keys("base-1", artifacts.path()).unwrap());
+
+        // A changed source only invalidates the stages that copy it
+        std::fs::write(artifacts.path().join("src/main.c"), "int main() { return 1; }").unwrap();
+        let changed = graph.cache_keys("base-1", artifacts.path()).unwrap();
+        assert_eq!(changed["toolchain"], keys["toolchain"]);
+        assert_eq!(changed["assets"], keys["assets"]);
+        assert_ne!(changed["build"], keys["build"]);
+
+        // A new base image invalidates every stage
+        let rebased = graph.cache_keys("base-2", artifacts.path()).unwrap();
+        assert!(graph.stages().iter().all(|stage| rebased[&stage.name] != changed[&stage.name]));
+
+        // Stages using secrets aren't cached, nor the ones they lead to
+        let graph = StageGraph::new(&parse(
+            "NAME app\nSECRET TOKEN\nSTAGE deps\nRUN --secret=TOKEN fetch\nSTAGE build FROM deps\nRUN make\nSTAGE app\nCOPY --from=build /out /srv\n"
+        )).unwrap();
+        let keys = graph.cache_keys("base-1", artifacts.path()).unwrap();
+        assert_eq!(keys["deps"], None);
+        assert_eq!(keys["build"], None);
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Serialize, Deserialize};
 use crdts::bft_reg::RecoverableSignature;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use alloy_primitives::Address;
 use crate::formfile::Formfile;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -9,6 +11,21 @@ pub struct PackBuildRequest {
     pub request: PackRequest,
 }
 
+impl PackBuildRequest {
+    /// Hex address of the key that signed the request
+    pub fn signer_hex(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let recovery_id = RecoveryId::from_byte(self.sig.rec).ok_or(
+            Box::new(std::io::Error::new(std::io::ErrorKind::Other, "invalid recovery id"))
+        )?;
+        let pk = VerifyingKey::recover_from_msg(
+            &self.hash,
+            &Signature::from_slice(&hex::decode(&self.sig.sig)?)?,
+            recovery_id
+        )?;
+        Ok(hex::encode(Address::from_public_key(&pk)))
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PackRequest {
     pub name: String,
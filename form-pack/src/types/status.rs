@@ -1,13 +1,23 @@
 use form_state::{instances::Instance, agent::AIAgent, model::AIModel};
+use form_state::build_manifests::SignedBuildManifest;
 use serde::{Serialize, Deserialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PackBuildStatus {
+    Queued {
+        build_id: String,
+        position: usize,
+    },
     Started(String),
     Failed {
         build_id: String,
         reason: String, 
     },
+    /// The image is built, with the manifest the builder signed for it
+    Signed {
+        build_id: String,
+        manifest: SignedBuildManifest,
+    },
     Completed {
         instance: Instance,
         agent: Option<AIAgent>,
@@ -0,0 +1,62 @@
+//! dm-verity hash trees of built images
+//!
+//! Raw images sit on the host between the build and the boot, where they
+//! can be modified. With `FORM_PACK_VERITY=true` the builder formats a
+//! dm-verity hash tree for each image with `veritysetup`, kept next to the
+//! image as `<build id>.verity`, and records its root hash in the signed
+//! build manifest. vmm-service checks the image against the tree before
+//! booting it.
+use std::path::Path;
+use form_state::build_manifests::{verity_hash_tree_path, VerityInfo};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerityPolicy {
+    /// Format a hash tree for each built image
+    pub enabled: bool,
+}
+
+impl VerityPolicy {
+    /// `FORM_PACK_VERITY=true` turns hash tree generation on
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("FORM_PACK_VERITY")
+            .map_or(false, |v| matches!(v.to_ascii_lowercase().as_str(), "true" | "1" | "on"));
+        Self { enabled }
+    }
+}
+
+/// Formats the hash tree of an image, replacing one left by an earlier
+/// build of it
+pub async fn format_hash_tree(image: &Path) -> Result<VerityInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let tree = verity_hash_tree_path(image);
+    match tokio::fs::remove_file(&tree).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Box::new(e)),
+        _ => {}
+    }
+
+    let output = tokio::process::Command::new("veritysetup")
+        .arg("format")
+        .arg(image)
+        .arg(&tree)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(format!(
+            "veritysetup format exited with {:?}: {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ).into());
+    }
+
+    let verity = VerityInfo::from_format_output(&String::from_utf8_lossy(&output.stdout))?;
+    // Blocks past the last whole one aren't covered by the tree
+    let size = tokio::fs::metadata(image).await?.len();
+    if size != verity.data_bytes() {
+        return Err(format!(
+            "{} is {size} bytes, not a multiple of the {} byte verity block size",
+            image.display(), verity.data_block_size
+        ).into());
+    }
+    log::info!("Formatted the hash tree of {}, root hash {}", image.display(), verity.root_hash);
+    Ok(verity)
+}
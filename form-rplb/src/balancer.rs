@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex, RwLock},
+};
+
+/// How a domain's connections are spread over its backend addresses
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum LoadBalancingStrategy {
+    #[default]
+    RoundRobin,
+    /// The address with the fewest connections currently being proxied
+    LeastConnections,
+    /// Sticky sessions, clients with the same key keep landing on the same
+    /// address for as long as it stays in the backend
+    ConsistentHash(HashKey),
+}
+
+/// What a consistent hash keys clients on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashKey {
+    ClientIp,
+    /// Value of the named cookie, clients without it are keyed on their IP
+    Cookie(String),
+}
+
+/// What the proxy knows about a client when it picks a backend
+#[derive(Clone, Debug, Default)]
+pub struct ClientContext {
+    pub addr: Option<SocketAddr>,
+    pub cookies: HashMap<String, String>,
+}
+
+impl ClientContext {
+    pub fn new(addr: Option<SocketAddr>) -> Self {
+        Self { addr, cookies: HashMap::new() }
+    }
+
+    /// Builds the context from the head of a plain text HTTP request
+    pub fn from_http_request(addr: Option<SocketAddr>, request: &str) -> Self {
+        let cookies = request.lines()
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim().eq_ignore_ascii_case("cookie").then_some(value)
+            })
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+
+        Self { addr, cookies }
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.addr.map(|addr| addr.ip())
+    }
+
+    fn session_key(&self, key: &HashKey) -> Option<String> {
+        match key {
+            HashKey::Cookie(name) => self.cookies.get(name).cloned()
+                .or_else(|| self.ip().map(|ip| ip.to_string())),
+            HashKey::ClientIp => self.ip().map(|ip| ip.to_string()),
+        }
+    }
+}
+
+/// Per domain balancing state. It outlives route updates so counters and
+/// the strategy aren't reset every time the domain's backends change.
+#[derive(Debug, Default)]
+pub struct Balancer {
+    strategy: RwLock<LoadBalancingStrategy>,
+    next: AtomicUsize,
+    active: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+}
+
+impl Balancer {
+    pub fn new(strategy: LoadBalancingStrategy) -> Self {
+        Self {
+            strategy: RwLock::new(strategy),
+            ..Default::default()
+        }
+    }
+
+    pub fn strategy(&self) -> LoadBalancingStrategy {
+        self.strategy.read().map(|strategy| strategy.clone()).unwrap_or_default()
+    }
+
+    pub fn set_strategy(&self, strategy: LoadBalancingStrategy) {
+        if let Ok(mut guard) = self.strategy.write() {
+            *guard = strategy;
+        }
+    }
+
+    /// Connections currently proxied to each address
+    pub fn active_connections(&self) -> HashMap<SocketAddr, usize> {
+        self.active.lock().map(|active| active.clone()).unwrap_or_default()
+    }
+
+    /// Picks an address for the client. The connection counts towards the
+    /// address until the returned guard is dropped.
+    pub fn select(&self, addresses: &[SocketAddr], client: &ClientContext) -> Option<(SocketAddr, ConnectionGuard)> {
+        if addresses.is_empty() {
+            return None;
+        }
+
+        let selected = match self.strategy() {
+            LoadBalancingStrategy::RoundRobin => self.round_robin(addresses),
+            LoadBalancingStrategy::LeastConnections => self.least_connections(addresses),
+            LoadBalancingStrategy::ConsistentHash(key) => match client.session_key(&key) {
+                Some(session) => rendezvous(addresses, &session),
+                None => self.round_robin(addresses),
+            },
+        };
+
+        Some((selected, ConnectionGuard::new(selected, self.active.clone())))
+    }
+
+    fn round_robin(&self, addresses: &[SocketAddr]) -> SocketAddr {
+        addresses[self.next.fetch_add(1, Ordering::Relaxed) % addresses.len()]
+    }
+
+    fn least_connections(&self, addresses: &[SocketAddr]) -> SocketAddr {
+        let active = self.active_connections();
+        // Ties are broken round robin so idle backends all get traffic
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..addresses.len())
+            .map(|i| addresses[(offset + i) % addresses.len()])
+            .min_by_key(|addr| active.get(addr).copied().unwrap_or(0))
+            .unwrap_or(addresses[0])
+    }
+}
+
+/// Rendezvous hashing, the address with the highest score for the key
+/// wins, so only the sessions of an added or removed address move
+fn rendezvous(addresses: &[SocketAddr], key: &str) -> SocketAddr {
+    addresses.iter()
+        .copied()
+        .max_by_key(|addr| fnv1a(format!("{key}/{addr}").as_bytes()))
+        .unwrap_or(addresses[0])
+}
+
+/// FNV-1a, stable across builds unlike the std hasher, so every proxy node
+/// sends a session to the same backend
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// An open connection to a backend address
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    addr: SocketAddr,
+    active: Arc<Mutex<HashMap<SocketAddr, usize>>>,
+}
+
+impl ConnectionGuard {
+    fn new(addr: SocketAddr, active: Arc<Mutex<HashMap<SocketAddr, usize>>>) -> Self {
+        if let Ok(mut guard) = active.lock() {
+            *guard.entry(addr).or_insert(0) += 1;
+        }
+        Self { addr, active }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = self.active.lock() {
+            if let Some(count) = guard.get_mut(&self.addr) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    guard.remove(&self.addr);
+                }
+            }
+        }
+    }
+}
@@ -1,11 +1,26 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 use tokio_rustls::rustls::ClientConfig;
 
+use crate::balancer::LoadBalancingStrategy;
+
 #[derive(Clone, Debug)]
 pub struct ProxyConfig {
     pub client_tls_config: Option<ClientConfig>,
     pub connection_timeout: Duration,
     pub buffer_size: usize,
+    /// Strategy for domains without one of their own
+    pub load_balancing: LoadBalancingStrategy,
+    /// Per domain strategies, these can be changed at runtime through
+    /// `ReverseProxy::set_load_balancing`
+    pub domain_load_balancing: HashMap<String, LoadBalancingStrategy>,
+}
+
+impl ProxyConfig {
+    pub fn load_balancing_for(&self, domain: &str) -> LoadBalancingStrategy {
+        self.domain_load_balancing.get(domain)
+            .cloned()
+            .unwrap_or_else(|| self.load_balancing.clone())
+    }
 }
 
 impl Default for ProxyConfig {
@@ -14,6 +29,8 @@ impl Default for ProxyConfig {
             client_tls_config: None,
             connection_timeout: Duration::from_secs(30),
             buffer_size: 8192,
+            load_balancing: LoadBalancingStrategy::default(),
+            domain_load_balancing: HashMap::new(),
         }
     }
 }
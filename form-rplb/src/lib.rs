@@ -1,8 +1,10 @@
 pub mod protocol;
 pub mod backend;
+pub mod balancer;
 pub mod config;
 pub mod proxy;
 pub mod error;
 pub mod certs;
 pub mod keys;
 pub mod resolver;
+pub mod stream;
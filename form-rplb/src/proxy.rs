@@ -1,4 +1,4 @@
-use crate::{backend::Backend, config::ProxyConfig, error::ProxyError, protocol::{Protocol, TlsConfig}};
+use crate::{backend::Backend, balancer::{Balancer, ClientContext, ConnectionGuard, LoadBalancingStrategy}, config::ProxyConfig, error::ProxyError, protocol::{Protocol, TlsConfig}};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream
 };
@@ -6,7 +6,6 @@ use tokio_rustls_acme::tokio_rustls::{rustls::ServerConfig, server::TlsStream};
 use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::sync::RwLock;
 use futures::future::try_join_all;
-use rand::seq::SliceRandom;
 
 #[derive(Debug, Clone, Default)]
 pub struct DomainProtocols {
@@ -29,6 +28,7 @@ pub struct ProxyBackends {
 #[derive(Clone, Debug)]
 pub struct ReverseProxy {
     routes: Arc<RwLock<HashMap<String, ProxyBackends>>>,
+    balancers: Arc<RwLock<HashMap<String, Arc<Balancer>>>>,
     config: ProxyConfig,
 }
 
@@ -36,6 +36,7 @@ impl ReverseProxy {
     pub fn new(config: ProxyConfig) -> Self {
         Self {
             routes: Arc::new(RwLock::new(HashMap::new())),
+            balancers: Arc::new(RwLock::new(HashMap::new())),
             config,
         }
     }
@@ -136,54 +137,91 @@ impl ReverseProxy {
                 udp: None,
             }
         };
-        routes.insert(domain, proxy_backend);
+        routes.insert(domain.clone(), proxy_backend);
+        drop(routes);
+        self.balancer(&domain).await;
     }
 
     pub async fn remove_route(&self, domain: &str) -> Option<ProxyBackends> {
         let mut routes = self.routes.write().await;
+        self.balancers.write().await.remove(domain);
         routes.remove(domain)
     }
 
+    /// The domain's balancer, created with the configured strategy on first use
+    async fn balancer(&self, domain: &str) -> Arc<Balancer> {
+        if let Some(balancer) = self.balancers.read().await.get(domain) {
+            return balancer.clone();
+        }
+        self.balancers.write().await
+            .entry(domain.to_string())
+            .or_insert_with(|| Arc::new(Balancer::new(self.config.load_balancing_for(domain))))
+            .clone()
+    }
+
+    pub async fn load_balancing(&self, domain: &str) -> LoadBalancingStrategy {
+        self.balancer(domain).await.strategy()
+    }
+
+    /// Switches the strategy of a domain, connections already proxied stay
+    /// on their backend
+    pub async fn set_load_balancing(&self, domain: &str, strategy: LoadBalancingStrategy) {
+        log::info!("Setting load balancing for {domain} to {strategy:?}");
+        self.balancer(domain).await.set_strategy(strategy);
+    }
+
     pub async fn get_route(&self, domain: &str) -> Option<ProxyBackends> {
         let routes = self.routes.read().await;
         routes.get(domain).cloned()
     }
 
     pub async fn select_backend(&self, domain: &str, protocol: Protocol) -> Result<SocketAddr, ProxyError> {
-        let routes = self.routes.read().await;
-        let backend = routes.get(domain)
-            .ok_or_else(|| ProxyError::NoBackend(domain.to_string()))?;
-
-        match protocol {
-            Protocol::HTTP => {
-                if backend.domain_protocols.force_tls {
-                    if let Some(tls_backend) = backend.tls.clone() {
-                        return tls_backend.addresses().choose(&mut rand::thread_rng())
-                            .copied().ok_or_else(|| ProxyError::NoBackend(format!("Missing TLS backend but force_tls is true for {domain}")))
+        let (addr, _guard) = self.select_backend_for(domain, protocol, &ClientContext::default()).await?;
+        Ok(addr)
+    }
+
+    /// Picks a backend address for the client with the domain's load
+    /// balancing strategy. The returned guard should be held for as long as
+    /// the connection is proxied.
+    pub async fn select_backend_for(
+        &self,
+        domain: &str,
+        protocol: Protocol,
+        client: &ClientContext,
+    ) -> Result<(SocketAddr, ConnectionGuard), ProxyError> {
+        let (addresses, missing) = {
+            let routes = self.routes.read().await;
+            let backend = routes.get(domain)
+                .ok_or_else(|| ProxyError::NoBackend(domain.to_string()))?;
+
+            match protocol {
+                Protocol::HTTP => {
+                    if backend.domain_protocols.force_tls {
+                        let tls_backend = backend.tls.clone()
+                            .ok_or_else(|| ProxyError::NoBackend("Missing TLS backend but force_tls is true".to_string()))?;
+                        (tls_backend.addresses(), format!("Missing TLS backend but force_tls is true for {domain}"))
                     } else {
-                        return Err(ProxyError::NoBackend("Missing TLS backend but force_tls is true".to_string()))
+                        (backend.http.addresses(), format!("Missing HTTP backend for {domain}"))
                     }
-                } else {
-                    return backend.http.addresses().choose(&mut rand::thread_rng())
-                        .copied().ok_or_else(|| ProxyError::NoBackend(format!("Missing HTTP backend for {domain}")))
+                }
+                Protocol::HTTPS(_config) => {
+                    let tls_backend = backend.tls.clone().ok_or_else(|| ProxyError::NoBackend(format!("Missing TLS backend for {domain}")))?;
+                    (tls_backend.addresses(), format!("Missing TLS backend for {domain}"))
+                }
+                Protocol::TCP => {
+                    let tcp_backend = backend.tcp.clone().ok_or_else(|| ProxyError::NoBackend(format!("Missing TCP backend for {domain}")))?;
+                    (tcp_backend.addresses(), format!("Missing TCP backend for {domain}"))
+                }
+                Protocol::UDP => {
+                    let udp_backend = backend.udp.clone().ok_or_else(|| ProxyError::NoBackend(format!("Missing UDP backend for {domain}")))?;
+                    (udp_backend.addresses(), format!("Missing UDP backend for {domain}"))
                 }
             }
-            Protocol::HTTPS(_config) => {
-                let tls_backend = backend.tls.clone().ok_or_else(|| ProxyError::NoBackend(format!("Missing TLS backend for {domain}")))?;
-                return tls_backend.addresses().choose(&mut rand::thread_rng()).copied()
-                    .ok_or_else(|| ProxyError::NoBackend(format!("Missing TLS backend for {domain}")))
-            }
-            Protocol::TCP => {
-                let tcp_backend = backend.tcp.clone().ok_or_else(|| ProxyError::NoBackend(format!("Missing TCP backend for {domain}")))?;
-                return tcp_backend.addresses().choose(&mut rand::thread_rng()).copied()
-                    .ok_or_else(|| ProxyError::NoBackend(format!("Missing TCP backend for {domain}")))
-            }
-            Protocol::UDP => {
-                let udp_backend = backend.udp.clone().ok_or_else(|| ProxyError::NoBackend(format!("Missing UDP backend for {domain}")))?;
-                return udp_backend.addresses().choose(&mut rand::thread_rng()).copied()
-                    .ok_or_else(|| ProxyError::NoBackend(format!("Missing UDP backend for {domain}")))
-            }
-        }
+        };
+
+        self.balancer(domain).await
+            .select(&addresses, client)
+            .ok_or(ProxyError::NoBackend(missing))
     }
 
     pub async fn get_backend(&self, domain: &str) -> Result<ProxyBackends, ProxyError> {
@@ -204,7 +242,8 @@ impl ReverseProxy {
         log::info!("HTTP Request received");
         log::info!("Extracted domain {domain}...");
 
-        let backend_addr = self.select_backend(&domain, Protocol::HTTP).await?;
+        let client = ClientContext::from_http_request(client_stream.peer_addr().ok(), &request);
+        let (backend_addr, _connection) = self.select_backend_for(&domain, Protocol::HTTP, &client).await?;
         log::info!("Selected backend {backend_addr}...");
         log::info!("Buildingg backend stream...");
         let mut backend_stream = tokio::time::timeout(
@@ -251,13 +290,18 @@ impl ReverseProxy {
         let n = stream.read(&mut buffer).await?;
         log::info!("Read {n} bytes from client stream");
 
-        let backend_addr = self.select_backend(
+        let client = ClientContext::from_http_request(
+            stream.get_ref().0.peer_addr().ok(),
+            &String::from_utf8_lossy(&buffer[..n]),
+        );
+        let (backend_addr, _connection) = self.select_backend_for(
             domain,
             Protocol::HTTPS(
                 TlsConfig::new(
                     config.clone()
                 )
-            )
+            ),
+            &client,
         ).await?;
         log::info!("Selected {backend_addr} as backend address..");
         let mut backend_stream = tokio::time::timeout(
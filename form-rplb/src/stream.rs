@@ -0,0 +1,446 @@
+//! Layer 4 proxying for services that don't speak HTTP.
+//!
+//! Each mapping owns a listen port and forwards raw TCP connections or UDP
+//! datagrams to backend instances. TCP ports can route on the server name of
+//! a TLS ClientHello, which is peeked at and passed through untouched, so
+//! several services can share a port without the proxy terminating TLS.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{error::ProxyError, proxy::extract_sni};
+
+/// How long a TCP connection may take to send enough of its ClientHello for
+/// the server name to be read
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most of a ClientHello peeked at for its server name
+const MAX_CLIENT_HELLO: usize = 16 * 1024;
+/// Largest datagram relayed in either direction
+const MAX_DATAGRAM: usize = 65_535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Where the traffic of a mapping, or of one server name on it, goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamTarget {
+    pub backends: Vec<SocketAddr>,
+    /// Connections and UDP sessions without traffic for this long are closed
+    pub idle_timeout: Duration,
+    /// Bytes per second shared by all connections to the target, in both
+    /// directions. None leaves the traffic uncapped.
+    pub bandwidth_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamRouting {
+    /// Everything arriving on the port goes to one target
+    Port(StreamTarget),
+    /// TLS connections go to the target of the server name they ask for,
+    /// by lowercase name. TCP only.
+    Sni(HashMap<String, StreamTarget>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamMapping {
+    pub listen_port: u16,
+    pub protocol: StreamProtocol,
+    pub routing: StreamRouting,
+}
+
+/// Token bucket holding up to one second of traffic
+#[derive(Debug)]
+struct RateLimiter {
+    bytes_per_sec: f64,
+    state: StdMutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self { bytes_per_sec, state: StdMutex::new((bytes_per_sec, Instant::now())) }
+    }
+
+    /// Takes `bytes` from the bucket, waiting for as long as it is in debt
+    async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let (available, refilled_at) = &mut *state;
+            let now = Instant::now();
+            *available = (*available + now.duration_since(*refilled_at).as_secs_f64() * self.bytes_per_sec)
+                .min(self.bytes_per_sec);
+            *refilled_at = now;
+            *available -= bytes as f64;
+            if *available < 0.0 {
+                Duration::from_secs_f64(-*available / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A target as the listener uses it, with its round robin position and
+/// bandwidth bucket
+#[derive(Debug)]
+struct ActiveTarget {
+    target: StreamTarget,
+    next: AtomicUsize,
+    limiter: Option<RateLimiter>,
+}
+
+impl ActiveTarget {
+    fn new(target: StreamTarget) -> Self {
+        let limiter = target.bandwidth_limit.map(RateLimiter::new);
+        Self { target, next: AtomicUsize::new(0), limiter }
+    }
+
+    /// Backends in the order they should be tried for the next connection
+    fn backends(&self) -> Vec<SocketAddr> {
+        let backends = &self.target.backends;
+        if backends.is_empty() {
+            return vec![];
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % backends.len();
+        backends.iter().cycle().skip(start).take(backends.len()).copied().collect()
+    }
+
+    async fn throttle(&self, bytes: usize) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(bytes).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ActiveRouting {
+    Port(Arc<ActiveTarget>),
+    Sni(HashMap<String, Arc<ActiveTarget>>),
+}
+
+impl ActiveRouting {
+    fn new(routing: StreamRouting) -> Self {
+        match routing {
+            StreamRouting::Port(target) => Self::Port(Arc::new(ActiveTarget::new(target))),
+            StreamRouting::Sni(targets) => Self::Sni(
+                targets.into_iter()
+                    .map(|(name, target)| (name.to_lowercase(), Arc::new(ActiveTarget::new(target))))
+                    .collect()
+            ),
+        }
+    }
+}
+
+struct Listener {
+    mapping: StreamMapping,
+    routing: Arc<RwLock<ActiveRouting>>,
+    task: JoinHandle<()>,
+}
+
+/// Runs the listeners of all stream mappings. Mappings can be added, changed
+/// and removed while connections are being served; changing the routing of a
+/// port keeps its listener and open connections.
+pub struct StreamProxy {
+    connection_timeout: Duration,
+    buffer_size: usize,
+    listeners: Mutex<HashMap<u16, Listener>>,
+}
+
+impl StreamProxy {
+    pub fn new(connection_timeout: Duration, buffer_size: usize) -> Self {
+        Self { connection_timeout, buffer_size, listeners: Mutex::new(HashMap::new()) }
+    }
+
+    pub async fn mappings(&self) -> Vec<StreamMapping> {
+        let mut mappings: Vec<_> = self.listeners.lock().await.values()
+            .map(|listener| listener.mapping.clone())
+            .collect();
+        mappings.sort_by_key(|mapping| mapping.listen_port);
+        mappings
+    }
+
+    /// Starts listening for the mapping, or updates the routing of the port
+    /// if it is already listening with the same protocol
+    pub async fn set_mapping(&self, mapping: StreamMapping) -> Result<(), ProxyError> {
+        if mapping.protocol == StreamProtocol::Udp && matches!(mapping.routing, StreamRouting::Sni(_)) {
+            return Err(ProxyError::InvalidRequest(
+                format!("UDP port {} can't be routed by server name", mapping.listen_port)
+            ));
+        }
+
+        let mut listeners = self.listeners.lock().await;
+        if let Some(listener) = listeners.get_mut(&mapping.listen_port) {
+            if listener.mapping.protocol == mapping.protocol && !listener.task.is_finished() {
+                *listener.routing.write().await = ActiveRouting::new(mapping.routing.clone());
+                listener.mapping = mapping;
+                return Ok(());
+            }
+        }
+        if let Some(listener) = listeners.remove(&mapping.listen_port) {
+            listener.task.abort();
+        }
+
+        let routing = Arc::new(RwLock::new(ActiveRouting::new(mapping.routing.clone())));
+        let addr = SocketAddr::from(([0, 0, 0, 0], mapping.listen_port));
+        let task = match mapping.protocol {
+            StreamProtocol::Tcp => {
+                let listener = TcpListener::bind(addr).await?;
+                tokio::spawn(serve_tcp(listener, routing.clone(), self.connection_timeout, self.buffer_size))
+            }
+            StreamProtocol::Udp => {
+                let socket = Arc::new(UdpSocket::bind(addr).await?);
+                tokio::spawn(serve_udp(socket, routing.clone()))
+            }
+        };
+        log::info!("Listening for {:?} stream mapping on port {}", mapping.protocol, mapping.listen_port);
+        listeners.insert(mapping.listen_port, Listener { mapping, routing, task });
+        Ok(())
+    }
+
+    /// Stops listening on the port. Open connections are left to finish.
+    pub async fn remove_mapping(&self, listen_port: u16) -> Option<StreamMapping> {
+        let listener = self.listeners.lock().await.remove(&listen_port)?;
+        listener.task.abort();
+        Some(listener.mapping)
+    }
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    routing: Arc<RwLock<ActiveRouting>>,
+    connection_timeout: Duration,
+    buffer_size: usize,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("Error accepting stream connection: {e}");
+                continue;
+            }
+        };
+        let routing = routing.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp(stream, routing, connection_timeout, buffer_size).await {
+                log::warn!("Error proxying stream connection from {peer}: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_tcp(
+    client: TcpStream,
+    routing: Arc<RwLock<ActiveRouting>>,
+    connection_timeout: Duration,
+    buffer_size: usize,
+) -> Result<(), ProxyError> {
+    let target = {
+        let routing = routing.read().await;
+        match &*routing {
+            ActiveRouting::Port(target) => target.clone(),
+            ActiveRouting::Sni(targets) => {
+                let server_name = peek_sni(&client).await?.to_lowercase();
+                targets.get(&server_name)
+                    .cloned()
+                    .ok_or(ProxyError::NoBackend(server_name))?
+            }
+        }
+    };
+
+    let mut last_error = None;
+    for backend in target.backends() {
+        match tokio::time::timeout(connection_timeout, TcpStream::connect(backend)).await {
+            Ok(Ok(upstream)) => return pipe_tcp(client, upstream, &target, buffer_size).await,
+            Ok(Err(e)) => last_error = Some(e.to_string()),
+            Err(_) => last_error = Some(format!("connecting to {backend} timed out")),
+        }
+    }
+    Err(ProxyError::NoBackend(last_error.unwrap_or_else(|| "no backends configured".to_string())))
+}
+
+/// Reads the server name of the ClientHello without consuming it, so the
+/// handshake reaches the backend intact
+async fn peek_sni(stream: &TcpStream) -> Result<String, ProxyError> {
+    let mut buffer = vec![0; MAX_CLIENT_HELLO];
+    let peek = async {
+        let mut last_len = 0;
+        loop {
+            let n = stream.peek(&mut buffer).await?;
+            if n == 0 {
+                return Err(ProxyError::InvalidRequest("Connection closed before the ClientHello".into()));
+            }
+            if let Ok(server_name) = extract_sni(&buffer[..n]) {
+                return Ok(server_name);
+            }
+            if n == buffer.len() {
+                return Err(ProxyError::InvalidRequest("No SNI in the ClientHello".into()));
+            }
+            if n == last_len {
+                // Peek returns at once while no new data has arrived
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            last_len = n;
+        }
+    };
+    tokio::time::timeout(SNI_PEEK_TIMEOUT, peek).await
+        .map_err(|_| ProxyError::InvalidRequest("Timed out waiting for the ClientHello".into()))?
+}
+
+/// Copies traffic both ways until both sides are done or neither has sent
+/// anything for the idle timeout
+async fn pipe_tcp(
+    client: TcpStream,
+    upstream: TcpStream,
+    target: &ActiveTarget,
+    buffer_size: usize,
+) -> Result<(), ProxyError> {
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+    let mut client_buffer = vec![0; buffer_size];
+    let mut upstream_buffer = vec![0; buffer_size];
+    let (mut client_open, mut upstream_open) = (true, true);
+
+    while client_open || upstream_open {
+        tokio::select! {
+            read = client_read.read(&mut client_buffer), if client_open => {
+                let n = read?;
+                if n == 0 {
+                    client_open = false;
+                    upstream_write.shutdown().await?;
+                    continue;
+                }
+                target.throttle(n).await;
+                upstream_write.write_all(&client_buffer[..n]).await?;
+            }
+            read = upstream_read.read(&mut upstream_buffer), if upstream_open => {
+                let n = read?;
+                if n == 0 {
+                    upstream_open = false;
+                    client_write.shutdown().await?;
+                    continue;
+                }
+                target.throttle(n).await;
+                client_write.write_all(&upstream_buffer[..n]).await?;
+            }
+            _ = tokio::time::sleep(target.target.idle_timeout) => {
+                log::debug!("Closing idle stream connection");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+struct UdpSession {
+    upstream: Arc<UdpSocket>,
+    last_seen: Arc<StdMutex<Instant>>,
+}
+
+/// Relays datagrams through one upstream socket per client address, so
+/// replies find their way back. Sessions end after the idle timeout.
+async fn serve_udp(socket: Arc<UdpSocket>, routing: Arc<RwLock<ActiveRouting>>) {
+    let sessions: Arc<Mutex<HashMap<SocketAddr, UdpSession>>> = Arc::new(Mutex::new(HashMap::new()));
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        let (n, client) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("Error receiving stream datagram: {e}");
+                continue;
+            }
+        };
+        let target = match &*routing.read().await {
+            ActiveRouting::Port(target) => target.clone(),
+            ActiveRouting::Sni(_) => continue,
+        };
+
+        let session = {
+            let mut guard = sessions.lock().await;
+            match guard.get(&client) {
+                Some(session) => Some((session.upstream.clone(), session.last_seen.clone())),
+                None => match open_udp_session(&socket, &sessions, client, &target).await {
+                    Ok(session) => {
+                        let handles = (session.upstream.clone(), session.last_seen.clone());
+                        guard.insert(client, session);
+                        Some(handles)
+                    }
+                    Err(e) => {
+                        log::warn!("Unable to open UDP session for {client}: {e}");
+                        None
+                    }
+                },
+            }
+        };
+        let Some((upstream, last_seen)) = session else {
+            continue;
+        };
+
+        *last_seen.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        target.throttle(n).await;
+        if let Err(e) = upstream.send(&buffer[..n]).await {
+            log::warn!("Error relaying datagram from {client}: {e}");
+        }
+    }
+}
+
+async fn open_udp_session(
+    socket: &Arc<UdpSocket>,
+    sessions: &Arc<Mutex<HashMap<SocketAddr, UdpSession>>>,
+    client: SocketAddr,
+    target: &Arc<ActiveTarget>,
+) -> Result<UdpSession, ProxyError> {
+    let backend = target.backends().into_iter().next()
+        .ok_or_else(|| ProxyError::NoBackend("no backends configured".to_string()))?;
+    let bind: SocketAddr = if backend.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let upstream = Arc::new(UdpSocket::bind(bind).await?);
+    upstream.connect(backend).await?;
+    let last_seen = Arc::new(StdMutex::new(Instant::now()));
+
+    let (socket, sessions, target) = (socket.clone(), sessions.clone(), target.clone());
+    let (reply_upstream, reply_last_seen) = (upstream.clone(), last_seen.clone());
+    tokio::spawn(async move {
+        let idle_timeout = target.target.idle_timeout;
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        loop {
+            match tokio::time::timeout(idle_timeout, reply_upstream.recv(&mut buffer)).await {
+                Ok(Ok(n)) => {
+                    *reply_last_seen.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+                    target.throttle(n).await;
+                    if let Err(e) = socket.send_to(&buffer[..n], client).await {
+                        log::warn!("Error relaying datagram to {client}: {e}");
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::warn!("Error receiving datagram from {backend}: {e}");
+                    break;
+                }
+                Err(_) => {
+                    let idle = reply_last_seen.lock().unwrap_or_else(|e| e.into_inner()).elapsed();
+                    if idle >= idle_timeout {
+                        break;
+                    }
+                }
+            }
+        }
+        sessions.lock().await.remove(&client);
+    });
+
+    Ok(UdpSession { upstream, last_seen })
+}
@@ -0,0 +1,401 @@
+// form-state/src/access_policies.rs
+// Token-gated access policies. The owner of an agent or build can require
+// callers to hold ERC-20 or ERC-721 tokens before they may hire the agent or
+// reach the build's instances. Balances are read with `balanceOf` over the
+// configured RPC endpoint and cached for a short while, and a policy that
+// can't be checked denies access.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::Mutex;
+use crate::datastore::DataStore;
+use crate::staking::{decode_amount, normalize_operator, rpc_call};
+
+/// Key under which the access policies are persisted in the node's db
+pub const ACCESS_POLICIES_DB_KEY: &str = "access_policies";
+
+pub const MAX_CONDITIONS: usize = 10;
+
+/// `balanceOf(address)`, the same selector for ERC-20 and ERC-721
+const BALANCE_OF_SELECTOR: &str = "70a08231";
+
+/// What a policy guards
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "id")]
+pub enum PolicyTarget {
+    /// Hiring the agent and running its tasks
+    Agent(String),
+    /// Accessing the build's instances
+    Build(String),
+}
+
+impl PolicyTarget {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PolicyTarget::Agent(_) => "agent",
+            PolicyTarget::Build(_) => "build",
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            PolicyTarget::Agent(id) | PolicyTarget::Build(id) => id,
+        }
+    }
+
+    pub fn parse(kind: &str, id: String) -> Option<Self> {
+        match kind {
+            "agent" => Some(PolicyTarget::Agent(id)),
+            "build" => Some(PolicyTarget::Build(id)),
+            _ => None,
+        }
+    }
+
+    /// A target has at most one policy
+    pub fn policy_id(&self) -> String {
+        format!("{}-{}", self.kind(), self.id())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenStandard {
+    Erc20,
+    Erc721,
+}
+
+/// Holding at least `min_balance` of a token. For ERC-20 tokens the balance
+/// is in the token's smallest unit, for ERC-721 it is the number of tokens.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TokenCondition {
+    pub standard: TokenStandard,
+    /// Token contract, lowercase hex without `0x`
+    pub contract: String,
+    pub min_balance: u128,
+}
+
+/// Whether every condition must hold or any one of them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyMode {
+    #[default]
+    All,
+    Any,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessPolicy {
+    pub policy_id: String,
+    pub target: PolicyTarget,
+    /// Account that set the policy, it is never gated by it
+    pub owner: String,
+    pub conditions: Vec<TokenCondition>,
+    #[serde(default)]
+    pub require: PolicyMode,
+    pub created_at: i64,
+    pub updated_at: i64,
+    /// Deleted policies are kept so the deletion replicates
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+/// Body of `POST /v1/access_policies/create`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessPolicyRequest {
+    pub target: PolicyTarget,
+    pub conditions: Vec<TokenCondition>,
+    #[serde(default)]
+    pub require: PolicyMode,
+}
+
+impl AccessPolicy {
+    /// Validates a request into a policy owned by `owner`
+    pub fn new(owner: &str, request: AccessPolicyRequest, now: i64) -> Result<Self, String> {
+        if request.conditions.is_empty() {
+            return Err("A policy needs at least one condition".to_string());
+        }
+        if request.conditions.len() > MAX_CONDITIONS {
+            return Err(format!("A policy can have at most {MAX_CONDITIONS} conditions"));
+        }
+        let conditions = request.conditions.into_iter().map(|mut condition| {
+            condition.contract = normalize_contract(&condition.contract)?;
+            if condition.min_balance == 0 {
+                return Err(format!("The minimum balance of {} must be above 0", condition.contract));
+            }
+            Ok(condition)
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self {
+            policy_id: request.target.policy_id(),
+            target: request.target,
+            owner: normalize_operator(owner),
+            conditions,
+            require: request.require,
+            created_at: now,
+            updated_at: now,
+            deleted: false,
+        })
+    }
+
+    /// Decides access from the caller's balances, keyed by contract. A
+    /// missing balance couldn't be read and fails its condition.
+    pub fn evaluate(&self, balances: &BTreeMap<String, u128>) -> Result<(), String> {
+        let failed: Vec<String> = self.conditions.iter()
+            .filter(|condition| balances.get(&condition.contract).map_or(true, |balance| *balance < condition.min_balance))
+            .map(|condition| match balances.get(&condition.contract) {
+                Some(balance) => format!(
+                    "holds {balance} of {:?} token 0x{}, needs {}",
+                    condition.standard, condition.contract, condition.min_balance
+                ),
+                None => format!("balance of token 0x{} could not be checked", condition.contract),
+            })
+            .collect();
+
+        let allowed = match self.require {
+            PolicyMode::All => failed.is_empty(),
+            PolicyMode::Any => failed.len() < self.conditions.len(),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!("Access to {} {} is token gated: {}", self.target.kind(), self.target.id(), failed.join("; ")))
+        }
+    }
+}
+
+/// Validates a contract address into lowercase hex without `0x`
+pub fn normalize_contract(contract: &str) -> Result<String, String> {
+    let normalized = normalize_operator(contract);
+    if normalized.len() != 40 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{contract} is not a contract address"));
+    }
+    Ok(normalized)
+}
+
+/// Every access policy, replicated between nodes with the newest update winning
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AccessPolicyStore {
+    policies: BTreeMap<String, AccessPolicy>,
+}
+
+impl AccessPolicyStore {
+    /// The policy with this id, unless it was deleted
+    pub fn get(&self, policy_id: &str) -> Option<&AccessPolicy> {
+        self.policies.get(policy_id).filter(|policy| !policy.deleted)
+    }
+
+    pub fn for_target(&self, target: &PolicyTarget) -> Option<&AccessPolicy> {
+        self.get(&target.policy_id())
+    }
+
+    /// When the policy was last updated or deleted
+    pub fn latest_update(&self, policy_id: &str) -> Option<i64> {
+        self.policies.get(policy_id).map(|policy| policy.updated_at)
+    }
+
+    pub fn by_owner(&self, owner: &str) -> Vec<&AccessPolicy> {
+        let owner = normalize_operator(owner);
+        self.policies.values().filter(|policy| !policy.deleted && policy.owner == owner).collect()
+    }
+
+    /// Stores a policy unless a newer one is held. A deletion wins over an
+    /// update made in the same second. Returns true if it was stored.
+    pub fn upsert(&mut self, policy: AccessPolicy) -> bool {
+        if let Some(current) = self.policies.get(&policy.policy_id) {
+            if (current.updated_at, current.deleted) >= (policy.updated_at, policy.deleted) {
+                return false;
+            }
+        }
+        self.policies.insert(policy.policy_id.clone(), policy);
+        true
+    }
+
+    pub fn merge(&mut self, other: AccessPolicyStore) {
+        for policy in other.policies.into_values() {
+            self.upsert(policy);
+        }
+    }
+}
+
+/// Balances read from the chain, kept for `ttl` seconds. Node local.
+#[derive(Clone, Debug, Default)]
+pub struct BalanceCache {
+    /// (contract, holder) to balance and when it was read
+    entries: BTreeMap<(String, String), (u128, i64)>,
+}
+
+impl BalanceCache {
+    pub fn get(&self, contract: &str, holder: &str, now: i64, ttl: i64) -> Option<u128> {
+        self.entries.get(&(contract.to_string(), holder.to_string()))
+            .filter(|(_, read_at)| now - read_at < ttl)
+            .map(|(balance, _)| *balance)
+    }
+
+    pub fn insert(&mut self, contract: &str, holder: &str, balance: u128, now: i64, ttl: i64) {
+        self.entries.retain(|_, (_, read_at)| now - *read_at < ttl);
+        self.entries.insert((contract.to_string(), holder.to_string()), (balance, now));
+    }
+}
+
+/// RPC endpoint and cache lifetime for balance checks
+#[derive(Clone, Debug)]
+pub struct TokenGateConfig {
+    pub rpc_url: Option<String>,
+    pub cache_ttl: Duration,
+}
+
+impl TokenGateConfig {
+    /// `TOKEN_GATE_RPC_URL`, falling back to `STAKING_RPC_URL`, and
+    /// `TOKEN_GATE_CACHE_SECS` (default 300)
+    pub fn from_env() -> Self {
+        let rpc_url = ["TOKEN_GATE_RPC_URL", "STAKING_RPC_URL"].iter()
+            .find_map(|name| std::env::var(name).ok().filter(|url| !url.is_empty()));
+        let cache_secs = std::env::var("TOKEN_GATE_CACHE_SECS").ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(300);
+        Self { rpc_url, cache_ttl: Duration::from_secs(cache_secs) }
+    }
+}
+
+/// Call data of `balanceOf(holder)`
+pub fn balance_of_call(holder: &str) -> String {
+    format!("0x{BALANCE_OF_SELECTOR}{:0>64}", normalize_operator(holder))
+}
+
+async fn balance_of(client: &reqwest::Client, rpc_url: &str, contract: &str, holder: &str) -> Result<u128, String> {
+    let result = rpc_call(client, rpc_url, "eth_call", json!([
+        { "to": format!("0x{contract}"), "data": balance_of_call(holder) },
+        "latest",
+    ])).await.map_err(|e| e.to_string())?;
+    result.as_str()
+        .and_then(decode_amount)
+        .ok_or_else(|| format!("Invalid balanceOf response from 0x{contract}: {result}"))
+}
+
+/// Checks `caller` against the target's policy. Targets without a policy, the
+/// policy's owner and admins are always allowed. The datastore isn't locked
+/// while balances are read from the chain.
+pub async fn authorize_access(
+    state: &Arc<Mutex<DataStore>>,
+    target: &PolicyTarget,
+    caller: &str,
+) -> Result<(), String> {
+    let caller = normalize_operator(caller);
+    let config = TokenGateConfig::from_env();
+    let ttl = config.cache_ttl.as_secs() as i64;
+    let now = chrono::Utc::now().timestamp();
+
+    let (policy, mut balances) = {
+        let datastore = state.lock().await;
+        let Some(policy) = datastore.access_policies.for_target(target).cloned() else {
+            return Ok(());
+        };
+        if policy.owner == caller || datastore.network_state.is_admin_address(&caller) {
+            return Ok(());
+        }
+        let balances: BTreeMap<String, u128> = policy.conditions.iter()
+            .filter_map(|condition| {
+                datastore.token_balances.get(&condition.contract, &caller, now, ttl)
+                    .map(|balance| (condition.contract.clone(), balance))
+            })
+            .collect();
+        (policy, balances)
+    };
+
+    let missing: Vec<String> = policy.conditions.iter()
+        .map(|condition| condition.contract.clone())
+        .filter(|contract| !balances.contains_key(contract))
+        .collect();
+    if !missing.is_empty() {
+        match &config.rpc_url {
+            Some(rpc_url) => {
+                let client = reqwest::Client::new();
+                let mut read = Vec::new();
+                for contract in missing {
+                    match balance_of(&client, rpc_url, &contract, &caller).await {
+                        Ok(balance) => read.push((contract, balance)),
+                        Err(e) => log::warn!("Unable to read the balance of {caller} on 0x{contract}: {e}"),
+                    }
+                }
+                let mut datastore = state.lock().await;
+                for (contract, balance) in read {
+                    datastore.token_balances.insert(&contract, &caller, balance, now, ttl);
+                    balances.insert(contract, balance);
+                }
+            }
+            None => log::warn!("{} has an access policy but no TOKEN_GATE_RPC_URL is set", policy.policy_id),
+        }
+    }
+
+    policy.evaluate(&balances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN_A: &str = "0x00000000000000000000000000000000000000Aa";
+    const TOKEN_B: &str = "00000000000000000000000000000000000000bb";
+
+    fn policy(require: PolicyMode) -> AccessPolicy {
+        AccessPolicy::new("0xOwner", AccessPolicyRequest {
+            target: PolicyTarget::Agent("agent-1".to_string()),
+            conditions: vec![
+                TokenCondition { standard: TokenStandard::Erc20, contract: TOKEN_A.to_string(), min_balance: 1_000 },
+                TokenCondition { standard: TokenStandard::Erc721, contract: TOKEN_B.to_string(), min_balance: 1 },
+            ],
+            require,
+        }, 100).unwrap()
+    }
+
+    #[test]
+    fn test_policy_validation() {
+        let policy = policy(PolicyMode::All);
+        assert_eq!(policy.policy_id, "agent-agent-1");
+        assert_eq!(policy.owner, "owner");
+        assert_eq!(policy.conditions[0].contract, "00000000000000000000000000000000000000aa");
+
+        let invalid = AccessPolicy::new("owner", AccessPolicyRequest {
+            target: PolicyTarget::Build("build".to_string()),
+            conditions: vec![TokenCondition { standard: TokenStandard::Erc20, contract: "0x1234".to_string(), min_balance: 1 }],
+            require: PolicyMode::All,
+        }, 0);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let a = "00000000000000000000000000000000000000aa".to_string();
+        let b = TOKEN_B.to_string();
+        let balances = BTreeMap::from([(a.clone(), 5_000), (b.clone(), 0)]);
+
+        assert!(policy(PolicyMode::All).evaluate(&balances).is_err());
+        assert!(policy(PolicyMode::Any).evaluate(&balances).is_ok());
+
+        let unreadable = BTreeMap::from([(b, 3)]);
+        assert!(policy(PolicyMode::All).evaluate(&unreadable).unwrap_err().contains("could not be checked"));
+        assert!(policy(PolicyMode::Any).evaluate(&BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_store_and_cache() {
+        let mut store = AccessPolicyStore::default();
+        let original = policy(PolicyMode::All);
+        assert!(store.upsert(original.clone()));
+
+        let mut deleted = original.clone();
+        deleted.deleted = true;
+        assert!(store.upsert(deleted));
+        assert!(!store.upsert(original));
+        assert!(store.for_target(&PolicyTarget::Agent("agent-1".to_string())).is_none());
+
+        let mut cache = BalanceCache::default();
+        cache.insert("token", "holder", 7, 1_000, 60);
+        assert_eq!(cache.get("token", "holder", 1_059, 60), Some(7));
+        assert_eq!(cache.get("token", "holder", 1_060, 60), None);
+        assert_eq!(balance_of_call("0xAB"), format!("0x70a08231{}ab", "0".repeat(62)));
+    }
+}
@@ -0,0 +1,489 @@
+// form-state/src/account_deletion.rs
+// Deletion of an account and the data held about it. A deletion is scheduled
+// first and only carried out once its grace period has passed, so the owner
+// can cancel it and download an archive of their data in the meantime. The
+// node that accepted the request runs the purge: owned instances are deleted,
+// DNS records and domains released, secrets and passkeys dropped and usage
+// kept for billing retention is re-keyed to an anonymous id.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::accounts::Account;
+use crate::billing::{reports, webhooks::{self, WebhookEndpoint}};
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::{store_value, write_datastore};
+use crate::domain_verification::{save_verification, DomainVerification, DomainVerificationStatus};
+use crate::instances::{Instance, InstanceStatus};
+use crate::organizations::{normalize_address, Organization, OrgRole};
+use crate::secrets::{SecretMetadata, SecretRequest};
+use crate::usage_rollups::{self, RollupGrouping, RollupQuery, RollupRow};
+
+/// Key under which the account deletions are persisted in the node's db
+pub const ACCOUNT_DELETIONS_DB_KEY: &str = "accounts/deletions";
+
+/// How long a scheduled deletion can be cancelled before it is carried out
+pub const DEFAULT_GRACE_PERIOD_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Ordered so a deletion that was carried out wins over a concurrent update
+/// of the same second that wasn't
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionStatus {
+    Scheduled,
+    Cancelled,
+    Purged,
+}
+
+/// What a purge removed, kept on the deletion as proof it was carried out
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PurgeSummary {
+    pub instances_deleted: usize,
+    pub dns_records_removed: usize,
+    pub domains_released: usize,
+    pub secrets_deleted: usize,
+    pub passkeys_revoked: usize,
+    pub webhooks_removed: usize,
+    pub organizations_left: usize,
+    pub usage_rows_anonymized: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountDeletion {
+    /// Address of the account as it is stored
+    pub address: String,
+    pub status: DeletionStatus,
+    /// Node that carries out the purge once the grace period is over
+    pub issued_by: String,
+    pub requested_at: i64,
+    /// Earliest time the purge runs
+    pub purge_after: i64,
+    pub updated_at: i64,
+    /// Id the account's retained usage is re-keyed to, random so it can't be
+    /// traced back to the address
+    pub anonymized_id: String,
+    #[serde(default)]
+    pub purged_at: Option<i64>,
+    #[serde(default)]
+    pub summary: Option<PurgeSummary>,
+    /// Why the last purge attempt failed
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+impl AccountDeletion {
+    pub fn new(address: &str, issued_by: String, grace_period_secs: i64, now: i64) -> Self {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        Self {
+            address: address.to_string(),
+            status: DeletionStatus::Scheduled,
+            issued_by,
+            requested_at: now,
+            purge_after: now + grace_period_secs,
+            updated_at: now,
+            anonymized_id: format!("deleted-{}", hex::encode(id)),
+            purged_at: None,
+            summary: None,
+            last_error: None,
+        }
+    }
+
+    pub fn is_due(&self, now: i64) -> bool {
+        self.status == DeletionStatus::Scheduled && now >= self.purge_after
+    }
+
+    pub fn cancel(&mut self, now: i64) -> Result<(), String> {
+        if self.status != DeletionStatus::Scheduled {
+            return Err(format!("The deletion of {} can no longer be cancelled", self.address));
+        }
+        self.status = DeletionStatus::Cancelled;
+        self.updated_at = now;
+        Ok(())
+    }
+
+    fn complete(&mut self, summary: PurgeSummary, now: i64) {
+        self.status = DeletionStatus::Purged;
+        self.purged_at = Some(now);
+        self.updated_at = now;
+        self.summary = Some(summary);
+        self.last_error = None;
+    }
+}
+
+/// Every account deletion, replicated between nodes with the newest update
+/// winning. Purged entries are kept as tombstones.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AccountDeletionStore {
+    deletions: BTreeMap<String, AccountDeletion>,
+}
+
+impl AccountDeletionStore {
+    pub fn get(&self, address: &str) -> Option<&AccountDeletion> {
+        self.deletions.get(&normalize_address(address))
+    }
+
+    /// Stores a deletion unless a newer one for the account is held. Returns
+    /// true if it was stored.
+    pub fn upsert(&mut self, deletion: AccountDeletion) -> bool {
+        let key = normalize_address(&deletion.address);
+        if let Some(current) = self.deletions.get(&key) {
+            if (current.updated_at, current.status) >= (deletion.updated_at, deletion.status) {
+                return false;
+            }
+        }
+        self.deletions.insert(key, deletion);
+        true
+    }
+
+    pub fn merge(&mut self, other: AccountDeletionStore) {
+        for deletion in other.deletions.into_values() {
+            self.upsert(deletion);
+        }
+    }
+
+    /// Whether the account is waiting to be purged
+    pub fn is_scheduled(&self, address: &str) -> bool {
+        self.get(address).is_some_and(|deletion| deletion.status == DeletionStatus::Scheduled)
+    }
+
+    /// Deletions past their grace period that `node_id` is responsible for
+    pub fn due_for(&self, node_id: &str, now: i64) -> Vec<AccountDeletion> {
+        self.deletions.values()
+            .filter(|deletion| deletion.issued_by == node_id && deletion.is_due(now))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deletions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deletions.is_empty()
+    }
+}
+
+/// Everything held about an account, handed to its owner before the purge
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccountArchive {
+    pub generated_at: i64,
+    pub account: Account,
+    pub instances: Vec<Instance>,
+    pub organizations: Vec<Organization>,
+    pub domains: Vec<DomainVerification>,
+    pub secrets: Vec<SecretMetadata>,
+    pub webhooks: Vec<WebhookEndpoint>,
+    pub usage: Vec<RollupRow>,
+    #[serde(default)]
+    pub deletion: Option<AccountDeletion>,
+}
+
+fn owns(owner: &str, address: &str) -> bool {
+    normalize_address(owner) == normalize_address(address)
+}
+
+/// Instances the account owns itself, instances owned through an
+/// organization stay with the organization
+fn owned_instances(datastore: &DataStore, address: &str, account: Option<&Account>) -> Vec<Instance> {
+    let mut ids: BTreeSet<String> = account.map(|account| account.owned_instances.clone()).unwrap_or_default();
+    ids.extend(
+        datastore.instance_state.get_instances_by_owner(address)
+            .into_iter()
+            .map(|instance| instance.instance_id),
+    );
+    ids.into_iter()
+        .filter(|id| datastore.organization_state.organization_owning_instance(id).is_none())
+        .filter_map(|id| datastore.instance_state.get_instance(id))
+        .collect()
+}
+
+fn webhooks_of(datastore: &DataStore, address: &str) -> Vec<WebhookEndpoint> {
+    datastore.webhooks.endpoints.values()
+        .filter(|endpoint| endpoint.account.as_deref().is_some_and(|account| owns(account, address)) || owns(&endpoint.created_by, address))
+        .cloned()
+        .collect()
+}
+
+/// Collects the archive of an account, None if the account doesn't exist
+pub fn build_archive(datastore: &DataStore, address: &str, now: i64) -> Option<AccountArchive> {
+    let account = datastore.account_state.get_account(address)?;
+    let instances = owned_instances(datastore, address, Some(&account));
+    let build_ids: BTreeSet<&String> = instances.iter().map(|instance| &instance.build_id).collect();
+    let usage = datastore.usage_rollups.query(&RollupQuery {
+        group_by: RollupGrouping::Instance,
+        account_id: Some(address.to_string()),
+        ..Default::default()
+    });
+
+    Some(AccountArchive {
+        generated_at: now,
+        organizations: datastore.organization_state.organizations_for_member(address),
+        domains: datastore.domain_verifications.by_owner(address).into_iter().cloned().collect(),
+        secrets: build_ids.into_iter().flat_map(|build_id| datastore.secret_state.list_for_build(build_id)).collect(),
+        webhooks: webhooks_of(datastore, address).iter().map(WebhookEndpoint::redacted).collect(),
+        usage,
+        deletion: datastore.account_deletions.get(address).cloned(),
+        instances,
+        account,
+    })
+}
+
+/// Why an account can't be scheduled for deletion, if it can't. An account
+/// that is the only owner of an organization with other members has to hand
+/// the organization over first.
+pub fn deletion_blocker(datastore: &DataStore, address: &str) -> Option<String> {
+    datastore.organization_state.organizations_for_member(address)
+        .into_iter()
+        .find(|org| {
+            org.role_of(address) == Some(&OrgRole::Owner)
+                && org.members.len() > 1
+                && org.members.values().filter(|role| **role == OrgRole::Owner).count() == 1
+        })
+        .map(|org| format!("{} is the only owner of organization {}, transfer it before deleting the account", address, org.org_id))
+}
+
+/// Re-keys the usage of a deleted account and drops its webhooks. Both are
+/// kept per node, so every node applies this when it learns of the purge.
+pub fn purge_local_data(datastore: &mut DataStore, deletion: &AccountDeletion) -> (usize, usize) {
+    let usage_rows = datastore.usage_rollups.anonymize_account(&deletion.address, &deletion.anonymized_id);
+    if usage_rows > 0 {
+        usage_rollups::persist(&datastore.usage_rollups);
+    }
+
+    let endpoints: Vec<String> = webhooks_of(datastore, &deletion.address).into_iter().map(|endpoint| endpoint.id).collect();
+    for id in &endpoints {
+        datastore.webhooks.remove(id);
+    }
+    let pending = datastore.webhooks.pending.len();
+    datastore.webhooks.pending.retain(|delivery| !owns(&delivery.event.account, &deletion.address));
+    if !endpoints.is_empty() || datastore.webhooks.pending.len() != pending {
+        webhooks::persist(&datastore.webhooks);
+    }
+
+    let subscriptions: Vec<String> = datastore.reports.for_account(&deletion.address).into_iter().map(|subscription| subscription.id.clone()).collect();
+    for id in &subscriptions {
+        datastore.reports.remove(id);
+    }
+    if !subscriptions.is_empty() {
+        reports::persist(&datastore.reports);
+    }
+
+    (usage_rows, endpoints.len())
+}
+
+/// Carries out a deletion. Steps that fail are logged and skipped so one
+/// unreachable service doesn't keep the rest of the data around; only a
+/// failure to remove the account itself fails the purge.
+pub async fn purge_account(datastore: &mut DataStore, deletion: &AccountDeletion, now: i64) -> Result<PurgeSummary, String> {
+    let address = deletion.address.clone();
+    let account = datastore.account_state.get_account(&address);
+    let mut summary = PurgeSummary::default();
+
+    let instances = owned_instances(datastore, &address, account.as_ref());
+    let mut domains: BTreeSet<String> = instances.iter()
+        .filter_map(|instance| instance.dns_record.as_ref().map(|record| record.domain.clone()))
+        .collect();
+    let build_ids: BTreeSet<String> = instances.iter().map(|instance| instance.build_id.clone()).collect();
+
+    for mut instance in instances {
+        instance.instance_owner = deletion.anonymized_id.clone();
+        instance.status = InstanceStatus::Deleted;
+        instance.updated_at = now;
+        let op = datastore.instance_state.update_instance_local(instance.clone());
+        match datastore.handle_instance_op(op).await {
+            Ok(()) => summary.instances_deleted += 1,
+            Err(e) => log::error!("Unable to delete instance {} of {}: {e}", instance.instance_id, address),
+        }
+    }
+
+    let verifications: Vec<DomainVerification> = datastore.domain_verifications.by_owner(&address).into_iter().cloned().collect();
+    for mut verification in verifications {
+        if verification.status == DomainVerificationStatus::Verified {
+            domains.insert(verification.domain.clone());
+        }
+        verification.owner = deletion.anonymized_id.clone();
+        verification.status = DomainVerificationStatus::Expired;
+        verification.updated_at = now;
+        save_verification(datastore, verification).await;
+        summary.domains_released += 1;
+    }
+
+    for domain in domains {
+        if datastore.network_state.dns_state.zones.get(&domain).val.is_none() {
+            continue;
+        }
+        match datastore.handle_dns_delete(domain.clone()).await {
+            Ok(()) => summary.dns_records_removed += 1,
+            Err(e) => log::error!("Unable to remove DNS record {domain} of {address}: {e}"),
+        }
+    }
+
+    for build_id in &build_ids {
+        for secret in datastore.secret_state.list_for_build(build_id) {
+            if datastore.secret_state.remove(build_id, &secret.name).is_none() {
+                continue;
+            }
+            let request = SecretRequest::Delete {
+                build_id: build_id.clone(),
+                name: secret.name.clone(),
+                version: secret.version,
+            };
+            crate::helpers::secrets::commit_secret_change(datastore, request).await;
+            summary.secrets_deleted += 1;
+        }
+    }
+
+    for mut org in datastore.organization_state.organizations_for_member(&address) {
+        if org.members.len() == 1 {
+            let op = datastore.organization_state.remove_organization_local(org.org_id.clone());
+            if let Err(e) = datastore.handle_organization_op(op).await {
+                log::error!("Unable to remove organization {} of {}: {e}", org.org_id, address);
+                continue;
+            }
+        } else {
+            if let Err(e) = org.remove_member(&address) {
+                log::error!("Unable to remove {} from organization {}: {e}", address, org.org_id);
+                continue;
+            }
+            let op = datastore.organization_state.update_organization_local(org.clone());
+            if let Err(e) = datastore.handle_organization_op(op).await {
+                log::error!("Unable to update organization {}: {e}", org.org_id);
+                continue;
+            }
+        }
+        summary.organizations_left += 1;
+    }
+
+    let (usage_rows, webhooks) = purge_local_data(datastore, deletion);
+    summary.usage_rows_anonymized = usage_rows;
+    summary.webhooks_removed = webhooks;
+
+    // Passkeys are stored on the account and go with it
+    if let Some(account) = account {
+        summary.passkeys_revoked = account.passkeys.len();
+        datastore.handle_account_delete(account.address.clone()).await
+            .map_err(|e| format!("Unable to remove account {address}: {e}"))?;
+    }
+    if let Err(e) = write_datastore(&DB_HANDLE, &datastore.clone()) {
+        log::error!("Unable to write datastore after purging {address}: {e}");
+    }
+
+    Ok(summary)
+}
+
+/// Stores a deletion, persists the store and sends it to the other nodes
+pub async fn save_deletion(datastore: &mut DataStore, deletion: AccountDeletion) {
+    datastore.account_deletions.upsert(deletion.clone());
+    if let Err(e) = store_value(&DB_HANDLE, ACCOUNT_DELETIONS_DB_KEY, &datastore.account_deletions) {
+        log::error!("Unable to persist account deletions: {e}");
+    }
+    if let Err(e) = datastore.broadcast::<form_types::state::Response<AccountDeletion>>(deletion, "v1/account/deletion/replicate").await {
+        log::error!("Unable to replicate account deletion: {e}");
+    }
+}
+
+/// Configuration for the purge task
+#[derive(Clone, Debug)]
+pub struct AccountPurgeConfig {
+    pub check_interval: Duration,
+}
+
+impl Default for AccountPurgeConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(300),
+        }
+    }
+}
+
+pub async fn run_account_purger(
+    datastore: Arc<Mutex<DataStore>>,
+    config: AccountPurgeConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting account purger, checking deletions every {:?}", config.check_interval);
+    let mut check = tokio::time::interval(config.check_interval);
+
+    loop {
+        tokio::select! {
+            _ = check.tick() => {
+                purge_due(datastore.clone()).await;
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Purges every deletion this node issued whose grace period is over
+pub async fn purge_due(datastore: Arc<Mutex<DataStore>>) {
+    let now = chrono::Utc::now().timestamp();
+    let mut guard = datastore.lock().await;
+    let due = guard.account_deletions.due_for(&guard.node_state.node_id, now);
+
+    for mut deletion in due {
+        match purge_account(&mut guard, &deletion, now).await {
+            Ok(summary) => {
+                log::info!("Purged account {}: {:?}", deletion.address, summary);
+                deletion.complete(summary, now);
+            }
+            Err(e) => {
+                log::error!("Unable to purge account {}: {e}", deletion.address);
+                deletion.last_error = Some(e);
+                deletion.updated_at = now;
+            }
+        }
+        save_deletion(&mut guard, deletion).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deletion_lifecycle() {
+        let mut deletion = AccountDeletion::new("0xabcdef", "node".to_string(), 100, 1_000);
+        assert!(deletion.anonymized_id.starts_with("deleted-"));
+        assert!(!deletion.anonymized_id.contains("abcdef"));
+        assert!(!deletion.is_due(1_099));
+        assert!(deletion.is_due(1_100));
+
+        let mut cancelled = deletion.clone();
+        assert!(cancelled.cancel(1_050).is_ok());
+        assert!(!cancelled.is_due(2_000));
+        assert!(cancelled.cancel(1_060).is_err());
+
+        deletion.complete(PurgeSummary { instances_deleted: 2, ..Default::default() }, 1_100);
+        assert_eq!(deletion.status, DeletionStatus::Purged);
+        assert!(deletion.cancel(1_200).is_err());
+    }
+
+    #[test]
+    fn test_deletion_store() {
+        let mut store = AccountDeletionStore::default();
+        let scheduled = AccountDeletion::new("0xabc", "node".to_string(), 100, 1_000);
+        assert!(store.upsert(scheduled.clone()));
+        assert!(store.is_scheduled("0xABC"));
+        assert!(store.due_for("node", 1_050).is_empty());
+        assert_eq!(store.due_for("node", 1_100).len(), 1);
+        assert!(store.due_for("other-node", 1_100).is_empty());
+
+        let mut purged = scheduled.clone();
+        purged.complete(PurgeSummary::default(), 1_000);
+        assert!(store.upsert(purged));
+        // A stale cancellation of the same second doesn't undo the purge
+        let mut cancelled = scheduled;
+        cancelled.cancel(1_000).unwrap();
+        assert!(!store.upsert(cancelled));
+        assert_eq!(store.get("abc").unwrap().status, DeletionStatus::Purged);
+        assert!(!store.is_scheduled("abc"));
+    }
+}
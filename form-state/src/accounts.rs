@@ -3,7 +3,9 @@ use serde::{Serialize, Deserialize};
 use k256::ecdsa::SigningKey;
 use crdts::{Map, BFTReg, map::Op, bft_reg::Update, CmRDT};
 use chrono::Utc;
+use crate::auth::webauthn::PasskeyCredential;
 use crate::billing::{SubscriptionInfo, UsageTracker};
+use crate::quotas::ResourceQuota;
 use crate::Actor;
 
 pub type AccountOp = Op<String, BFTReg<Account, Actor>, Actor>;
@@ -42,12 +44,21 @@ pub struct Account {
     /// Set of agent IDs that are currently hired by this account
     #[serde(default)]
     pub hired_agents: BTreeSet<String>,
+    /// Passkeys linked to this account, keyed by credential id
+    #[serde(default)]
+    pub passkeys: BTreeMap<String, PasskeyCredential>,
+    /// Resource quota set by an admin, replacing the subscription tier's
+    #[serde(default)]
+    pub resource_quota: Option<ResourceQuota>,
     /// Creation timestamp
     #[serde(default)]
     pub created_at: i64,
     /// Last update timestamp
     #[serde(default)]
     pub updated_at: i64,
+    /// Bumped by every write, see `revisions`
+    #[serde(default)]
+    pub revision: u64,
 }
 
 /// Defines the level of authorization an account has for an instance
@@ -92,8 +103,11 @@ impl Account {
             usage: Some(UsageTracker::new()), // Initialize with default usage tracker
             credits: initial_credits,
             hired_agents: BTreeSet::new(),
+            passkeys: BTreeMap::new(),
+            resource_quota: None,
             created_at: now,
             updated_at: now,
+            revision: 0,
         }
     }
 
@@ -491,8 +505,11 @@ impl Default for Account {
             usage: Some(UsageTracker::new()),
             credits: 100, // Default credits
             hired_agents: BTreeSet::new(),
+            passkeys: BTreeMap::new(),
+            resource_quota: None,
             created_at: now,
             updated_at: now,
+            revision: 0,
         }
     }
 }
@@ -521,12 +538,13 @@ impl AccountState {
     }
     
     /// Update an account locally and return the operation
-    pub fn update_account_local(&mut self, account: Account) -> AccountOp {
+    pub fn update_account_local(&mut self, mut account: Account) -> AccountOp {
         let add_ctx = self.map.read_ctx().derive_add_ctx(self.node_id.clone());
         let signing_key = SigningKey::from_slice(
             &hex::decode(self.pk.clone())
                 .expect("PANIC: Invalid SigningKey Cannot Decode from Hex"))
                 .expect("PANIC: Invalid SigningKey cannot recover from Bytes");
+        account.revision = self.next_account_revision(&account.address);
                 
         self.map.update(account.address.clone(), add_ctx, |reg, _ctx| {
             reg.update(account, self.node_id.clone(), signing_key)
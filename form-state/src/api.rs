@@ -12,6 +12,7 @@ use axum::{
     response::{Response, IntoResponse},
     http::{Request, StatusCode},
     body::Body,
+    extract::DefaultBodyLimit,
 };
 use serde::{Serialize, Deserialize};
 use crate::helpers::{
@@ -21,17 +22,37 @@ use crate::helpers::{
     account::*, 
     agent::*, 
     model::*,
+    staking::*,
+    secrets::*,
+    organizations::*,
+    dns::*,
+    passkeys::*,
+    fleet_config::*,
+    build_manifests::*,
+    marketplace::*,
+    usage::*,
+    quotas::*,
+    backup::*,
+    access_policies::*,
+    peer_expiry::*,
+    maintenance::*,
+    reservations::*,
+    probe::*,
     agent_gateway::run_agent_task_handler,
 };
 use crate::auth::{
     RecoveredAddress, ecdsa_auth_middleware, active_node_auth_middleware
 };
+use crate::access_policies::{authorize_access, PolicyTarget};
+use crate::auth::cache::{verify_cache_stats, VerifyCacheStats};
 
 use serde_json::json;
 use crate::billing::middleware::EligibilityError;
+use crate::billing::handlers::{check_account_eligibility, stripe_webhook, meter_account_usage, register_webhook, list_webhooks, delete_webhook, list_webhook_deliveries, record_invoice, create_report, list_reports, delete_report, preview_report};
 use hex;
-use form_node_metrics::{capabilities::NodeCapabilities, capacity::NodeCapacity, metrics::NodeMetrics};
+use form_node_metrics::{capabilities::NodeCapabilities, capacity::NodeCapacity, metrics::NodeMetrics, network::MAX_PROBE_BYTES};
 use crate::tasks::{TaskStatus as FormStateTaskStatus, TaskId as FormStateTaskId};
+use form_p2p::auth::TopicPolicies;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -62,6 +83,17 @@ async fn health_check() -> Json<HealthResponse> {
     })
 }
 
+/// Hit rate and size of the signature verification cache
+async fn verify_cache() -> Json<VerifyCacheStats> {
+    Json(verify_cache_stats())
+}
+
+/// Verification time by outcome, in builds with the `auth-timing` feature
+#[cfg(feature = "auth-timing")]
+async fn auth_timing() -> Json<std::collections::BTreeMap<String, crate::auth::timing::TimingStats>> {
+    Json(crate::auth::timing::timing_report())
+}
+
 // Node authentication middleware to verify formation node key
 async fn node_auth_middleware(
     State(state): State<Arc<Mutex<DataStore>>>,
@@ -226,6 +258,9 @@ pub fn is_public_endpoint(path: &str) -> bool {
     false
 }
 
+/// Largest archive `/admin/restore` accepts
+const MAX_BACKUP_SIZE: usize = 512 * 1024 * 1024;
+
 pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
     
     // Define public routes (no authentication required)
@@ -239,14 +274,31 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .route("/bootstrap/cidr_state", get(cidr_state))
         .route("/bootstrap/assoc_state", get(assoc_state))
         .route("/bootstrap/ensure_admin_account", post(ensure_admin_account))
+        .route("/passkey/login/begin", post(passkey_login_begin))
+        .route("/passkey/login/finish", post(passkey_login_finish))
+        .route("/config/get", get(get_fleet_config))
+        .route("/maintenance", get(get_maintenance))
+        .route("/maintenance/check", get(check_maintenance))
+        .route("/probe/addr", get(probe_addr))
+        .route("/probe/download", get(probe_download))
+        .route("/probe/upload", post(probe_upload).layer(DefaultBodyLimit::max(MAX_PROBE_BYTES as usize)))
+        .route("/auth/verify_cache", get(verify_cache))
+        .route("/billing/stripe/webhook", post(stripe_webhook))
         .route("/agents", get(list_agents))
         .route("/agents/:id", get(get_agent))
         .route("/models", get(list_model))
         .route("/models/:id", get(get_model))
+        .route("/marketplace/search", get(search_listings))
+        .route("/marketplace/listing/:listing_id", get(get_listing))
         .route("/node/list", get(list_nodes))
+        .route("/nodes/match", post(find_matching_nodes))
         .route("/instance/:instance_id/metrics", get(get_instance_metrics))
         .route("/instance/list/metrics", get(list_instance_metrics))
+        .route("/instance/:instance_id/health", get(get_instance_health))
+        .route("/instance/list/health", get(list_instance_health))
         .route("/cluster/:build_id/metrics", get(get_cluster_metrics));
+    #[cfg(feature = "auth-timing")]
+    let public_api = public_api.route("/auth/timing", get(auth_timing));
     
     let network_writers_api = Router::new()
         .route("/user/create", post(create_user))
@@ -268,8 +320,21 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .route("/node/:id/get", get(get_node))
         .route("/node/:id/delete", post(delete_node))
         .route("/node/:id/report_metrics", post(report_node_metrics))
+        .route("/node/:id/maintenance", post(set_node_maintenance))
         .route("/user/redeem", post(redeem_invite))
         .route("/task/update_status", post(update_task_status_handler)) // Task update endpoint
+        .route("/billing/:address/usage", post(meter_account_usage))
+        .route("/secrets/replicate", post(replicate_secret))
+        .route("/secrets/:build_id/resolve", get(resolve_secrets))
+        .route("/config/replicate", post(replicate_fleet_config))
+        .route("/marketplace/replicate", post(replicate_listing))
+        .route("/dns/domain/replicate", post(replicate_domain_verification))
+        .route("/account/deletion/replicate", post(replicate_account_deletion))
+        .route("/access_policies/replicate", post(replicate_access_policy))
+        .route("/peer/expiry/replicate", post(replicate_stale_mark))
+        .route("/maintenance/replicate", post(replicate_maintenance))
+        .route("/reservations/replicate", post(replicate_reservation))
+        .route("/peer/resurrect", post(resurrect_peer))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             node_auth_middleware, // Admin auth for these writer APIs
@@ -282,6 +347,8 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .route("/user/list", get(list_users))
         .route("/user/list_admin", get(list_admin))
         .route("/peer/list_active", get(list_active_peers))
+        .route("/peer/list_stale", get(list_stale_peers))
+        .route("/queue/topic_policies", get(topic_policies))
         .route("/user/:cidr/list", get(list_by_cidr))        
         .route("/cidr/:id/get", get(get_cidr))
         .route("/cidr/list", get(list_cidr))
@@ -290,14 +357,29 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .route("/dns/:domain/:build_id/request_public", post(request_public))
         .route("/dns/:domain/get", get(get_dns_record))
         .route("/dns/:node_ip/list", get(get_dns_records_by_node_ip))
+        .route("/dns/:ip/reverse", get(get_dns_records_by_ip))
         .route("/dns/list", get(list_dns_records))
+        .route("/dns/vanity/:name/status", get(vanity_domain_status))
         .route("/node/:id/metrics", get(get_node_metrics))
         .route("/node/list/metrics", get(list_node_metrics))
+        .route("/node/:id/connectivity", get(get_node_connectivity))
+        .route("/node/:id/instances", get(list_node_instances))
+        .route("/node/list/connectivity", get(list_node_connectivity))
+        .route("/build/:build_id/manifest", get(get_build_manifest))
+        .route("/build/:build_id/verification", get(get_image_verifications))
         .route("/task/:task_id/is_responsible/:node_id_to_check", get(check_task_responsibility))
         .route("/tasks", get(list_tasks_handler)) // Task query endpoints
         .route("/task/:task_id/get", get(get_task_handler))
+        .route("/billing/:address/eligibility", post(check_account_eligibility))
+        .route("/quota/:address/check", post(check_account_quota))
+        .route("/pricing", get(get_pricing))
+        .route("/access_policies/:kind/:id/check/:address", get(check_access_policy))
         .route("/node/:id/operator-key", post(add_node_operator_key))
-        .route("/node/:id/operator-key/:key", post(remove_node_operator_key));
+        .route("/node/:id/operator-key/:key", post(remove_node_operator_key))
+        .route("/staking/list", get(list_operator_stakes))
+        .route("/staking/:operator/get", get(get_operator_stake))
+        .route("/staking/:operator/admission", get(check_operator_admission))
+        .route("/org/instance/:instance_id/authorization/:address", get(get_org_instance_authorization));
         
     let account_api = Router::new()
         .route("/account/:address/get", get(get_account))
@@ -305,8 +387,33 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .route("/account/create", post(create_account))
         .route("/account/update", post(update_account))
         .route("/account/delete", post(delete_account))
+        .route("/account/:address/deletion", get(account_deletion_status))
+        .route("/account/:address/deletion/request", post(request_account_deletion))
+        .route("/account/:address/deletion/cancel", post(cancel_account_deletion))
+        .route("/account/:address/export", get(export_account_data))
         .route("/account/:address/is_global_admin", get(is_global_admin_handler))
         .route("/account/transfer-ownership", post(transfer_instance_ownership))
+        .route("/passkey/register/begin", post(passkey_register_begin))
+        .route("/passkey/register/finish", post(passkey_register_finish))
+        .route("/passkey/list", get(passkey_list))
+        .route("/passkey/:credential_id/remove", post(passkey_remove))
+        .route("/usage/rollups", get(get_usage_rollups))
+        .route("/quota", get(get_quota))
+        .route("/quota/:address/set", post(set_account_quota))
+        .route("/reservations", get(list_reservations))
+        .route("/reservations/quote", post(quote_reservation))
+        .route("/reservations/create", post(create_reservation))
+        .route("/reservations/:reservation_id", get(get_reservation))
+        .route("/reservations/:reservation_id/cancel", post(cancel_reservation))
+        .route("/billing/webhooks", get(list_webhooks))
+        .route("/billing/webhooks/create", post(register_webhook))
+        .route("/billing/webhooks/:id/delete", post(delete_webhook))
+        .route("/billing/webhooks/:id/deliveries", get(list_webhook_deliveries))
+        .route("/billing/:address/invoice", post(record_invoice))
+        .route("/billing/reports", get(list_reports))
+        .route("/billing/reports/create", post(create_report))
+        .route("/billing/reports/:id/delete", post(delete_report))
+        .route("/billing/reports/:id/preview", get(preview_report))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             ecdsa_auth_middleware
@@ -318,14 +425,46 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .route("/instance/:instance_id/delete", post(delete_instance))
         .route("/instance/list", get(list_instances))
         .route("/instance/:instance_id/get", get(get_instance))
+        .route("/instance/:instance_id/history", get(get_instance_history))
         .route("/instance/:build_id/get_by_build_id", get(get_instance_by_build_id))
         .route("/instance/:build_id/get_instance_ips", get(get_instance_ips))
+        .route("/cluster/:build_id/scaling_policy", get(get_scaling_policy))
+        .route("/cluster/:build_id/scaling_policy/update", post(update_scaling_policy))
+        .route("/instance/:instance_id/schedule", get(get_instance_schedule))
+        .route("/instance/:instance_id/schedule/update", post(update_instance_schedule))
+        .route("/dns/vanity/claim", post(claim_vanity_domain))
+        .route("/dns/vanity/:name/release", post(release_vanity_domain))
+        .route("/dns/domain/verify", post(request_domain_verification))
+        .route("/dns/domain/:domain/verification", get(domain_verification_status))
+        .route("/dns/domain/:domain/check", post(check_domain_verification))
+        .route("/secrets/:build_id/create", post(create_secret))
+        .route("/secrets/:build_id/list", get(list_secrets))
+        .route("/secrets/:build_id/:name/rotate", post(rotate_secret))
+        .route("/secrets/:build_id/:name/delete", post(delete_secret))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            ecdsa_auth_middleware
+        ));
+
+    let organization_api = Router::new()
+        .route("/org/create", post(create_organization))
+        .route("/org/list", get(list_organizations))
+        .route("/org/:org_id/get", get(get_organization))
+        .route("/org/:org_id/invite", post(invite_member))
+        .route("/org/:org_id/accept", post(accept_invitation))
+        .route("/org/:org_id/member/:address/remove", post(remove_member))
+        .route("/org/:org_id/member/:address/role", post(set_member_role))
+        .route("/org/:org_id/billing", post(set_billing_account))
+        .route("/org/:org_id/instance/add", post(add_org_instance))
+        .route("/org/:org_id/instance/:instance_id/remove", post(remove_org_instance))
+        .route("/org/:org_id/delete", post(delete_organization))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             ecdsa_auth_middleware
         ));
 
     let api_routes = Router::new()
+        .route("/config/set", post(set_fleet_config))
         .route("/agents/create", post(create_agent))
         .route("/agents/update", post(update_agent))
         .route("/agents/delete", post(delete_agent))
@@ -335,11 +474,32 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .route("/models/update", post(update_model))
         .route("/models/delete", post(delete_model))
         .route("/models/:id/inference", post(checked_model_inference))
+        .route("/marketplace/publish", post(publish_listing))
+        .route("/marketplace/moderation", get(list_moderation_queue))
+        .route("/marketplace/listing/:listing_id/moderate", post(moderate_listing))
+        .route("/marketplace/listing/:listing_id/withdraw", post(withdraw_listing))
+        .route("/marketplace/listing/:listing_id/hire", post(hire_listing))
+        .route("/marketplace/listing/:listing_id/usage", post(record_listing_usage))
+        .route("/access_policies", get(list_access_policies))
+        .route("/access_policies/create", post(create_access_policy))
+        .route("/access_policies/:policy_id", get(get_access_policy))
+        .route("/access_policies/:policy_id/delete", post(delete_access_policy))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             ecdsa_auth_middleware
         ));
     
+    let admin_api = Router::new()
+        .route("/admin/backup", get(export_backup))
+        .route("/admin/restore", post(restore_backup))
+        .route("/admin/maintenance", post(set_maintenance))
+        .route("/admin/maintenance/node/:id", post(set_node_freeze))
+        .layer(DefaultBodyLimit::max(MAX_BACKUP_SIZE))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            ecdsa_auth_middleware
+        ));
+
     let devnet_gossip_api = Router::new()
         .route("/apply_op", post(devnet_apply_op_handler))
         .layer(middleware::from_fn_with_state(
@@ -354,12 +514,18 @@ pub fn app(state: Arc<Mutex<DataStore>>) -> Router {
         .merge(network_readers_api)
         .merge(account_api)
         .merge(instance_api)  
+        .merge(organization_api)
         .merge(api_routes)
+        .merge(admin_api)
         .nest("/devnet_gossip", devnet_gossip_api); // Devnet gossip is also under /v1
     
-    // Create the final app router with the /v1 prefix for all formation state routes
+    // Serve every route under /v1, and at the unversioned paths existing
+    // clients still use until they are sunset
+    let unversioned_router = v1_router.clone()
+        .layer(middleware::from_fn(crate::versioning::unversioned_headers));
     Router::new()
-        .nest("/v1", v1_router) 
+        .nest(crate::versioning::CURRENT.prefix, v1_router)
+        .merge(unversioned_router)
         .with_state(state) // Apply state to the top-level router for handlers that extract it directly
 }
 
@@ -382,6 +548,26 @@ pub async fn run_api(datastore: Arc<Mutex<DataStore>>) -> Result<(), Box<dyn std
     Ok(())
 }
 
+/// Processes a message pulled from the queue, reporting the outcome to the
+/// queue's trace log under the message's correlation ID
+async fn process_traced_message(message: Vec<u8>, datastore: Arc<Mutex<DataStore>>) {
+    let correlation_id = form_p2p::trace::record(&message, "form-state", "received", None).await;
+    let trace = correlation_id.as_deref().unwrap_or("untraced");
+    log::info!("pulled message from queue [{trace}]");
+    let content = message.clone();
+    // The error isn't Send, so it can't be held across the await below
+    let result = process_message(message, datastore).await.map_err(|e| e.to_string());
+    match result {
+        Ok(()) => {
+            form_p2p::trace::record(&content, "form-state", "processed", None).await;
+        }
+        Err(e) => {
+            eprintln!("Error processing message [{trace}]: {e}");
+            form_p2p::trace::record(&content, "form-state", "failed", Some(e)).await;
+        }
+    }
+}
+
 /// Run the queue reader without the API server
 pub async fn run_queue_reader(datastore: Arc<Mutex<DataStore>>, mut shutdown: tokio::sync::broadcast::Receiver<()>) -> Result<(), Box<dyn std::error::Error>> {
     log::info!("Running queue reader only...");
@@ -393,13 +579,7 @@ pub async fn run_queue_reader(datastore: Arc<Mutex<DataStore>>, mut shutdown: to
             Ok(messages) = DataStore::read_from_queue(Some(n), None) => {
                 n += messages.len();
                 for message in messages {
-                    log::info!("pulled message from queue");
-                    let ds = datastore.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = process_message(message, ds).await {
-                            eprintln!("Error processing message: {e}");
-                        }
-                    });
+                    tokio::spawn(process_traced_message(message, datastore.clone()));
                 }
             }
             _ = tokio::time::sleep(Duration::from_millis(polling_interval)) => {
@@ -439,13 +619,7 @@ pub async fn run(datastore: Arc<Mutex<DataStore>>, mut shutdown: tokio::sync::br
             Ok(messages) = DataStore::read_from_queue(Some(n), None) => {
                 n += messages.len();
                 for message in messages {
-                    log::info!("pulled message from queue");
-                    let ds = datastore.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = process_message(message, ds).await {
-                            eprintln!("Error processing message: {e}");
-                        }
-                    });
+                    tokio::spawn(process_traced_message(message, datastore.clone()));
                 }
             }
             _ = tokio::time::sleep(Duration::from_millis(polling_interval)) => {
@@ -466,11 +640,17 @@ async fn checked_agent_hire(
     Path(agent_id): Path<String>,
     payload: Json<serde_json::Value>,
 ) -> Result<Response, EligibilityError> {
-    // Run eligibility check first
-    let mut datastore = state.lock().await;
-    
     // Get the account address
     let account_address = recovered.as_hex();
+
+    // The agent's access policy is checked before the lock is taken, it may
+    // read balances from the chain
+    authorize_access(&state, &PolicyTarget::Agent(agent_id.clone()), &account_address)
+        .await
+        .map_err(EligibilityError::OperationNotAllowed)?;
+
+    // Run eligibility check first
+    let mut datastore = state.lock().await;
     
     // Check if the agent exists
     if datastore.agent_state.get_agent(&agent_id).is_none() {
@@ -747,6 +927,21 @@ async fn list_active_peers(State(state): State<Arc<Mutex<DataStore>>>) -> impl I
     (StatusCode::OK, Json(active_peer_ips))
 }
 
+/// Write policies for the message queue, active peers are the nodes allowed
+/// to publish to node-only topics
+async fn topic_policies(State(state): State<Arc<Mutex<DataStore>>>) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let active_nodes = datastore.network_state.peers.iter()
+        .filter_map(|entry| {
+            let (_, reg) = entry.val;
+            reg.val().map(|reg_val| reg_val.value())
+        })
+        .filter(|peer| !peer.is_disabled)
+        .map(|peer| peer.id.clone())
+        .collect::<Vec<String>>();
+    (StatusCode::OK, Json(TopicPolicies::network_defaults(active_nodes)))
+}
+
 async fn check_task_responsibility(
     State(state): State<Arc<Mutex<DataStore>>>,
     Path((task_id, node_id_to_check)): Path<(String, String)>,
@@ -814,9 +1009,9 @@ async fn report_node_metrics(
 }
 
 #[derive(Serialize, Deserialize, Debug)] // Add Debug for logging
-struct DevnetGossipOpContainer {
-    op_type: String, // e.g., "PeerOp", "NodeOp"
-    op_payload_json: String, // The specific Op (PeerOp, NodeOp, etc.) serialized as JSON string
+pub(crate) struct DevnetGossipOpContainer {
+    pub(crate) op_type: String, // e.g., "PeerOp", "NodeOp"
+    pub(crate) op_payload_json: String, // The specific Op (PeerOp, NodeOp, etc.) serialized as JSON string
 }
 
 async fn devnet_apply_op_handler(
@@ -900,6 +1095,27 @@ async fn devnet_apply_op_handler(
                 }
             }
         }
+        "OrganizationOp" => {
+            match serde_json::from_str::<crate::organizations::OrganizationOp>(&payload.op_payload_json) {
+                Ok(organization_op) => {
+                    datastore.organization_state.organization_op(organization_op);
+                    match crate::db::write_datastore(&crate::datastore::DB_HANDLE, &*datastore) {
+                        Ok(_) => {
+                            log::info!("DEVNET: Successfully applied and persisted OrganizationOp.");
+                            (StatusCode::OK, Json(json!({"status": "success", "message": "OrganizationOp applied."})))
+                        }
+                        Err(e) => {
+                            log::error!("DEVNET: Failed to persist datastore after applying OrganizationOp: {}", e);
+                            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"status": "error", "message": "Failed to persist state after applying OrganizationOp"})))
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("DEVNET: Failed to deserialize OrganizationOp: {}", e);
+                    (StatusCode::BAD_REQUEST, Json(json!({"status": "error", "message": "Failed to deserialize OrganizationOp"})))
+                }
+            }
+        }
         "CidrOp" => {
             match serde_json::from_str::<crate::network::CidrOp<String>>(&payload.op_payload_json) {
                 Ok(cidr_op) => {
@@ -0,0 +1,203 @@
+//! Cache of recovered signers
+//!
+//! Clients tend to sign one message and send it with many requests, so hot
+//! endpoints run the same secp256k1 recovery over and over. With
+//! `FORM_AUTH_VERIFY_CACHE` set to a number of entries, recovered addresses
+//! are kept in a bounded LRU keyed by a digest of the signature, recovery id
+//! and message. Recovery is deterministic, so a hit returns exactly what
+//! recovering again would. Entries expire after `FORM_AUTH_VERIFY_CACHE_TTL`
+//! seconds (300 by default). Failed recoveries are never cached.
+//!
+//! form-state and vmm-service share this cache, each under its own scheme so
+//! their message formats can't collide.
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use alloy_primitives::Address;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// How long a recovered signer is cached unless configured otherwise
+pub const DEFAULT_VERIFY_CACHE_TTL_SECONDS: u64 = 300;
+
+static VERIFY_CACHE: Lazy<Option<VerificationCache>> = Lazy::new(VerificationCache::from_env);
+
+struct CachedSigner {
+    address: Address,
+    cached_at: Instant,
+}
+
+pub struct VerificationCache {
+    entries: Mutex<LruCache<[u8; 32], CachedSigner>>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Counters of a verification cache, `GET /v1/auth/verify_cache` on
+/// form-state and vmm-service
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VerifyCacheStats {
+    pub enabled: bool,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped because they outlived the TTL
+    pub expired: u64,
+    /// Entries dropped to make room for new ones
+    pub evictions: u64,
+    /// Share of lookups answered from the cache, 0 before the first lookup
+    pub hit_rate: f64,
+}
+
+impl VerificationCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Built from `FORM_AUTH_VERIFY_CACHE` and `FORM_AUTH_VERIFY_CACHE_TTL`,
+    /// `None` unless the first is a positive number
+    pub fn from_env() -> Option<Self> {
+        let capacity = std::env::var("FORM_AUTH_VERIFY_CACHE").ok()?.parse::<usize>().ok()?;
+        let ttl = std::env::var("FORM_AUTH_VERIFY_CACHE_TTL").ok()
+            .and_then(|ttl| ttl.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_VERIFY_CACHE_TTL_SECONDS);
+        let capacity = NonZeroUsize::new(capacity)?;
+        log::info!("Caching up to {capacity} recovered signers for {ttl}s");
+        Some(Self::new(capacity, Duration::from_secs(ttl)))
+    }
+
+    pub fn key(scheme: &str, signature: &[u8], recovery_id: u8, message: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in [scheme.as_bytes(), signature, message] {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        hasher.update([recovery_id]);
+        hasher.finalize().into()
+    }
+
+    /// The cached signer for `key`, or the result of `recover`, which is
+    /// cached if it succeeds
+    pub fn get_or_recover<E>(&self, key: [u8; 32], recover: impl FnOnce() -> Result<Address, E>) -> Result<Address, E> {
+        if let Some(address) = self.lookup(&key) {
+            return Ok(address);
+        }
+        let address = recover()?;
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() == entries.cap().get() && !entries.contains(&key) {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.put(key, CachedSigner { address, cached_at: Instant::now() });
+        Ok(address)
+    }
+
+    fn lookup(&self, key: &[u8; 32]) -> Option<Address> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let cached = entries.get(key).map(|entry| (entry.address, entry.cached_at.elapsed() < self.ttl));
+        match cached {
+            Some((address, true)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(address)
+            }
+            Some((_, false)) => {
+                entries.pop(key);
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn stats(&self) -> VerifyCacheStats {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        VerifyCacheStats {
+            enabled: true,
+            capacity: entries.cap().get(),
+            ttl_secs: self.ttl.as_secs(),
+            entries: entries.len(),
+            hits,
+            misses,
+            expired: self.expired.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            hit_rate: match hits + misses {
+                0 => 0.0,
+                lookups => hits as f64 / lookups as f64,
+            },
+        }
+    }
+}
+
+/// Recovers a signer through the process wide cache if it's enabled. The
+/// hardened mode of `super::timing` bypasses it, a hit would tell that the
+/// signature was seen before.
+pub fn cached_recover<E>(
+    scheme: &str,
+    signature: &[u8],
+    recovery_id: u8,
+    message: &[u8],
+    recover: impl FnOnce() -> Result<Address, E>,
+) -> Result<Address, E> {
+    match VERIFY_CACHE.as_ref().filter(|_| !super::timing::hardening().enabled) {
+        Some(cache) => cache.get_or_recover(VerificationCache::key(scheme, signature, recovery_id, message), recover),
+        None => recover(),
+    }
+}
+
+pub fn verify_cache_stats() -> VerifyCacheStats {
+    VERIFY_CACHE.as_ref().map(VerificationCache::stats).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verification_cache() {
+        let cache = VerificationCache::new(NonZeroUsize::new(2).unwrap(), Duration::from_secs(60));
+        let signer = Address::repeat_byte(0x11);
+        let key = |message: &[u8]| VerificationCache::key("state", &[1, 2, 3], 0, message);
+
+        assert_eq!(cache.get_or_recover(key(b"a"), || Ok::<_, ()>(signer)), Ok(signer));
+        // A hit doesn't recover again
+        assert_eq!(cache.get_or_recover(key(b"a"), || Err(())), Ok(signer));
+        // Failures aren't cached
+        assert_eq!(cache.get_or_recover(key(b"b"), || Err(())), Err(()));
+        assert_eq!(cache.get_or_recover(key(b"b"), || Ok::<_, ()>(signer)), Ok(signer));
+        cache.get_or_recover(key(b"c"), || Ok::<_, ()>(signer)).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries, stats.evictions), (1, 4, 2, 1));
+        assert_eq!(stats.hit_rate, 0.2);
+
+        // Schemes and recovery ids are part of the key
+        assert_ne!(VerificationCache::key("state", &[1], 0, b"a"), VerificationCache::key("vmm", &[1], 0, b"a"));
+        assert_ne!(VerificationCache::key("state", &[1], 0, b"a"), VerificationCache::key("state", &[1], 1, b"a"));
+
+        let expiring = VerificationCache::new(NonZeroUsize::new(2).unwrap(), Duration::ZERO);
+        expiring.get_or_recover(key(b"a"), || Ok::<_, ()>(signer)).unwrap();
+        assert_eq!(expiring.get_or_recover(key(b"a"), || Err(())), Err(()));
+        assert_eq!(expiring.stats().expired, 1);
+    }
+}
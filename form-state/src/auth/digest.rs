@@ -0,0 +1,167 @@
+//! Request digests bind a signature to the request it authenticates
+//!
+//! A bare signed message says nothing about the request it is attached to,
+//! so a captured `Authorization` header can be replayed with another method,
+//! path or body. Clients that sign the canonical digest of the request
+//! instead are verified against the request they actually sent:
+//!
+//! ```text
+//! formation-request-v1
+//! <METHOD>
+//! <path and query>
+//! <hex SHA3-256 of the body>
+//! ```
+//!
+//! Unbound messages are still accepted unless `AUTH_REQUIRE_REQUEST_DIGEST`
+//! is set, so existing clients keep working while they migrate.
+use k256::ecdsa::SigningKey;
+use sha2::{Digest, Sha256};
+use tiny_keccak::{Hasher, Sha3};
+use super::ecdsa::SignatureError;
+use super::timing::ct_eq;
+
+/// First line of every canonical request message
+pub const REQUEST_DIGEST_PREFIX: &str = "formation-request-v1";
+
+/// The parts of a request a signature is bound to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestDigest {
+    pub method: String,
+    /// Path and query as sent on the wire, including the `/v1` prefix
+    pub path: String,
+    pub body_sha3: [u8; 32],
+}
+
+impl RequestDigest {
+    pub fn new(method: &str, path: &str, body: &[u8]) -> Self {
+        Self {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+            body_sha3: body_sha3(body),
+        }
+    }
+
+    /// The canonical message clients sign
+    pub fn message(&self) -> Vec<u8> {
+        format!(
+            "{REQUEST_DIGEST_PREFIX}\n{}\n{}\n{}",
+            self.method, self.path, hex::encode(self.body_sha3)
+        ).into_bytes()
+    }
+
+    /// Compares every part in full, whichever differs
+    pub fn matches(&self, other: &RequestDigest) -> bool {
+        let method = ct_eq(self.method.as_bytes(), other.method.as_bytes());
+        let path = ct_eq(self.path.as_bytes(), other.path.as_bytes());
+        let body = ct_eq(&self.body_sha3, &other.body_sha3);
+        method & path & body
+    }
+
+    /// Parses a signed message, returns `None` for messages that don't bind a request
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(message).ok()?;
+        let mut lines = message.split('\n');
+        if lines.next()? != REQUEST_DIGEST_PREFIX {
+            return None;
+        }
+        let method = lines.next()?.to_string();
+        let path = lines.next()?.to_string();
+        let body_sha3 = hex::decode(lines.next()?).ok()?.try_into().ok()?;
+        if lines.next().is_some() {
+            return None;
+        }
+        Some(Self { method, path, body_sha3 })
+    }
+}
+
+pub fn body_sha3(body: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(body);
+    hasher.finalize(&mut hash);
+    hash
+}
+
+/// Builds the `Authorization` header value for a request, signing its digest
+pub fn sign_request(
+    signing_key: &SigningKey,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<String, k256::ecdsa::Error> {
+    let message = RequestDigest::new(method, path, body).message();
+    let (signature, recovery_id) = signing_key.sign_recoverable(&Sha256::digest(&message))?;
+    Ok(format!(
+        "Signature {}.{}.{}",
+        hex::encode(signature.to_bytes()),
+        recovery_id.to_byte(),
+        hex::encode(&message)
+    ))
+}
+
+/// Whether unbound signatures are rejected, set `AUTH_REQUIRE_REQUEST_DIGEST=true`
+/// once every client signs request digests
+pub fn digest_required() -> bool {
+    std::env::var("AUTH_REQUIRE_REQUEST_DIGEST")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Checks a signed message against the request it arrived with
+pub fn verify_request_digest(
+    message: &[u8],
+    method: &str,
+    path: &str,
+    body: &[u8],
+    strict: bool,
+) -> Result<(), SignatureError> {
+    match RequestDigest::parse(message) {
+        Some(signed) if signed.matches(&RequestDigest::new(method, path, body)) => Ok(()),
+        Some(signed) => {
+            log::warn!(
+                "Signed request digest {} {} does not match the request {} {}",
+                signed.method, signed.path, method, path
+            );
+            Err(SignatureError::DigestMismatch)
+        }
+        None if strict => Err(SignatureError::DigestRequired),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ecdsa::{extract_signature_parts, recover_address};
+    use alloy_primitives::Address;
+    use axum::http::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn test_request_digest_binding() {
+        let key = SigningKey::random(&mut rand::thread_rng());
+        let body = br#"{"name":"test"}"#;
+        let header = sign_request(&key, "post", "/v1/account/create", body).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_str(&header).unwrap());
+        let (signature, recovery_id, message) = extract_signature_parts(&headers).unwrap();
+        let address = recover_address(&signature, recovery_id, &message).unwrap();
+        assert_eq!(address, Address::from_private_key(&key));
+
+        assert!(verify_request_digest(&message, "POST", "/v1/account/create", body, true).is_ok());
+        assert!(matches!(
+            verify_request_digest(&message, "POST", "/v1/account/delete", body, false),
+            Err(SignatureError::DigestMismatch)
+        ));
+        assert!(matches!(
+            verify_request_digest(&message, "POST", "/v1/account/create", b"{}", false),
+            Err(SignatureError::DigestMismatch)
+        ));
+
+        assert!(verify_request_digest(b"legacy message", "GET", "/v1/account/list", b"", false).is_ok());
+        assert!(matches!(
+            verify_request_digest(b"legacy message", "GET", "/v1/account/list", b"", true),
+            Err(SignatureError::DigestRequired)
+        ));
+    }
+}
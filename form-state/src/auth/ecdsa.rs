@@ -1,6 +1,7 @@
 use axum::{
     async_trait,
-    extract::{FromRequestParts, Request, ConnectInfo},
+    body::Body,
+    extract::{FromRequestParts, OriginalUri, Request, ConnectInfo},
     http::{request::Parts, StatusCode, HeaderMap},
     response::{IntoResponse, Response},
     Json,
@@ -16,6 +17,7 @@ use log;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use super::digest;
 /// Error type for signature verification failures
 #[derive(Debug, Serialize)]
 pub enum SignatureError {
@@ -24,6 +26,10 @@ pub enum SignatureError {
     InvalidMessage,
     RecoveryFailed,
     InvalidFormat,
+    /// The signed request digest is for a different method, path or body
+    DigestMismatch,
+    /// The signature doesn't bind the request and unbound signatures are rejected
+    DigestRequired,
 }
 
 impl IntoResponse for SignatureError {
@@ -34,6 +40,8 @@ impl IntoResponse for SignatureError {
             Self::InvalidMessage => (StatusCode::BAD_REQUEST, "Invalid message format"),
             Self::RecoveryFailed => (StatusCode::UNAUTHORIZED, "Failed to recover public key"),
             Self::InvalidFormat => (StatusCode::BAD_REQUEST, "Invalid signature format"),
+            Self::DigestMismatch => (StatusCode::UNAUTHORIZED, "Signature does not match the request"),
+            Self::DigestRequired => (StatusCode::UNAUTHORIZED, "Signature must bind the request method, path and body"),
         };
 
         let body = Json(json!({
@@ -49,6 +57,8 @@ impl IntoResponse for SignatureError {
 pub struct RecoveredAddress {
     pub address: Address,
     pub message: Vec<u8>,
+    /// The request carried a passkey session token rather than a signature
+    pub via_passkey: bool,
 }
 
 impl RecoveredAddress {
@@ -101,6 +111,12 @@ pub fn extract_signature_parts(headers: &HeaderMap) -> Result<(Vec<u8>, Recovery
 
 /// Recover an address from a signature, recovery ID, and message
 pub fn recover_address(signature_bytes: &[u8], recovery_id: RecoveryId, message: &[u8]) -> Result<Address, SignatureError> {
+    super::cache::cached_recover("state", signature_bytes, recovery_id.to_byte(), message, || {
+        recover_address_uncached(signature_bytes, recovery_id, message)
+    })
+}
+
+fn recover_address_uncached(signature_bytes: &[u8], recovery_id: RecoveryId, message: &[u8]) -> Result<Address, SignatureError> {
     // Create a recoverable signature
     let signature = Signature::try_from(signature_bytes)
         .map_err(|_| SignatureError::InvalidSignature)?;
@@ -138,6 +154,11 @@ where
     type Rejection = SignatureError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(session) = crate::auth::webauthn::session_address(&parts.headers) {
+            let address = session.map_err(|_| SignatureError::InvalidSignature)?;
+            return Ok(RecoveredAddress { address, message: vec![], via_passkey: true });
+        }
+
         let (signature_bytes, recovery_id, message) = extract_signature_parts(&parts.headers)?;
         
         let address = recover_address(&signature_bytes, recovery_id, &message)?;
@@ -145,6 +166,7 @@ where
         Ok(RecoveredAddress {
             address,
             message,
+            via_passkey: false,
         })
     }
 }
@@ -189,33 +211,111 @@ pub async fn ecdsa_auth_middleware(
         request.extensions_mut().insert(None::<RecoveredAddress>);
         return Ok(next.run(request).await);
     }
-    
+
+    #[cfg(feature = "auth-timing")]
+    let started = std::time::Instant::now();
+    let verified = authenticate(request).await;
+    #[cfg(feature = "auth-timing")]
+    super::timing::record(&verification_outcome(&verified), started.elapsed());
+
+    match verified {
+        Ok(request) => Ok(next.run(request).await),
+        Err(e) => {
+            super::timing::jitter_rejection().await;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(feature = "auth-timing")]
+fn verification_outcome<T>(verified: &Result<T, SignatureError>) -> String {
+    match verified {
+        Ok(_) => "accepted".to_string(),
+        Err(e) => format!("{e:?}"),
+    }
+}
+
+/// Verifies the passkey session or signature of a request and hands it back
+/// with the recovered address in its extensions
+async fn authenticate(mut request: Request) -> Result<Request, SignatureError> {
     let headers = request.headers().clone();
+    if let Some(session) = crate::auth::webauthn::session_address(&headers) {
+        let address = session.map_err(|e| {
+            log::warn!("ECDSA_AUTH: Rejected passkey session: {e}");
+            SignatureError::InvalidSignature
+        })?;
+        request.extensions_mut().insert(Some(
+            RecoveredAddress {
+                address,
+                message: vec![],
+                via_passkey: true,
+            }
+        ));
+        return Ok(request);
+    }
+
     if let Ok((signature_bytes, recovery_id, message)) = extract_signature_parts(&headers) {
         // Recover the address - this just verifies the signature is valid
         log::debug!("ECDSA_AUTH: Recovering address from signature.");
         let address = recover_address(&signature_bytes, recovery_id, &message)?;
+        let mut request = verify_request_binding(request, &message).await?;
         request.extensions_mut().insert(Some(
             RecoveredAddress {
                 address,
                 message,
+                via_passkey: false,
             }
         ));
         // Authentication successful - let the handler handle authorization
-        return Ok(next.run(request).await);
+        Ok(request)
     } else {
         log::warn!("ECDSA_AUTH: Invalid signature format.");
-        return Err(SignatureError::MissingSignature)
+        Err(SignatureError::MissingSignature)
     }
 }
 
+/// Checks a signed message against the method, path and body of the request
+/// and hands back the request with its body restored
+async fn verify_request_binding(request: Request, message: &[u8]) -> Result<Request, SignatureError> {
+    let strict = digest::digest_required();
+    if !strict && digest::RequestDigest::parse(message).is_none() {
+        return Ok(request);
+    }
+
+    // Nested routers only see the path below their mount point
+    let uri = request.extensions().get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let path = uri.path_and_query().map_or_else(|| uri.path().to_string(), |p| p.to_string());
+    let method = request.method().as_str().to_string();
+
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await
+        .map_err(|_| SignatureError::InvalidMessage)?;
+    digest::verify_request_digest(message, &method, &path, &body, strict)?;
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
 /// Middleware to authenticate requests from active (non-disabled) Formation nodes.
 /// Expects an ECDSA signature in the Authorization header, similar to ecdsa_auth_middleware.
 pub async fn active_node_auth_middleware(
     axum::extract::State(state): axum::extract::State<Arc<Mutex<crate::datastore::DataStore>>>, // Fully qualified State
-    mut req: Request<axum::body::Body>, 
+    req: Request<axum::body::Body>,
     next: axum::middleware::Next,
 ) -> Result<Response, StatusCode> {
+    match authenticate_active_node(&state, req).await {
+        Ok(req) => Ok(next.run(req).await),
+        Err(status) => {
+            super::timing::jitter_rejection().await;
+            Err(status)
+        }
+    }
+}
+
+async fn authenticate_active_node(
+    state: &Mutex<crate::datastore::DataStore>,
+    mut req: Request<axum::body::Body>,
+) -> Result<Request<axum::body::Body>, StatusCode> {
     let headers = req.headers().clone();
     log::debug!("ACTIVE_NODE_AUTH: Checking for active node auth.");
     let (signature_bytes, recovery_id, message_to_verify) = 
@@ -257,8 +357,9 @@ pub async fn active_node_auth_middleware(
                     req.extensions_mut().insert(RecoveredAddress {
                         address: recovered_eth_address,
                         message: message_to_verify.to_vec(), // Pass along the verified message if needed by handler
+                        via_passkey: false,
                     });
-                    Ok(next.run(req).await)
+                    Ok(req)
                 } else {
                     log::warn!("ACTIVE_NODE_AUTH: Auth failed: Peer 0x{} is disabled.", recovered_address_hex);
                     Err(StatusCode::FORBIDDEN)
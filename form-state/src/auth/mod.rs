@@ -1,4 +1,8 @@
 pub mod ecdsa;
+pub mod digest;
+pub mod webauthn;
+pub mod cache;
+pub mod timing;
 
 pub use ecdsa::{
     RecoveredAddress,
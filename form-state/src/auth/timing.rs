@@ -0,0 +1,199 @@
+//! Timing side-channel hardening of request verification
+//!
+//! Digests, challenges and addresses checked while verifying a request are
+//! compared with `ct_eq` and `addresses_eq`, which take as long wherever the
+//! inputs differ. That holds in every mode.
+//!
+//! `AUTH_HARDENED=true` turns on the hardened mode on top of it:
+//!
+//! - the recovered signer cache is bypassed, so a signature that was seen
+//!   before takes as long to verify as a new one
+//! - rejections are held back by a random delay of up to
+//!   `AUTH_RESPONSE_JITTER_MS` milliseconds, 0 by default, which blurs the
+//!   difference between the ways a request can fail
+//!
+//! Builds with the `auth-timing` feature also record how long each
+//! verification took by outcome, served on `GET /v1/auth/timing`, and
+//! `tests/auth_timing.rs` checks the comparisons stay constant time.
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use subtle::ConstantTimeEq;
+
+/// Longest jitter that can be configured
+pub const MAX_JITTER_MS: u64 = 1000;
+
+static HARDENING: Lazy<HardeningConfig> = Lazy::new(HardeningConfig::from_env);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HardeningConfig {
+    pub enabled: bool,
+    /// Upper bound of the delay added to rejections, 0 for none
+    pub jitter_max_ms: u64,
+}
+
+impl HardeningConfig {
+    /// Read from `AUTH_HARDENED` and `AUTH_RESPONSE_JITTER_MS`
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("AUTH_HARDENED")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        let jitter_max_ms = std::env::var("AUTH_RESPONSE_JITTER_MS").ok()
+            .and_then(|ms| ms.parse::<u64>().ok())
+            .unwrap_or(0)
+            .min(MAX_JITTER_MS);
+        if enabled {
+            log::info!("Hardened request verification, rejections jittered by up to {jitter_max_ms}ms");
+        }
+        Self { enabled, jitter_max_ms }
+    }
+
+    /// A random delay for a rejection, `None` outside the hardened mode
+    pub fn jitter(&self) -> Option<Duration> {
+        (self.enabled && self.jitter_max_ms > 0)
+            .then(|| Duration::from_micros(rand::thread_rng().gen_range(0..=self.jitter_max_ms * 1000)))
+    }
+}
+
+/// The process wide configuration
+pub fn hardening() -> HardeningConfig {
+    *HARDENING
+}
+
+/// Waits out the rejection jitter of the hardened mode
+pub async fn jitter_rejection() {
+    if let Some(delay) = hardening().jitter() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Equality that doesn't stop at the first differing byte. Only the lengths
+/// are compared early, they aren't secret.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Compares two hex addresses ignoring case and a `0x` prefix, in constant
+/// time for addresses of the same length
+pub fn addresses_eq(a: &str, b: &str) -> bool {
+    let a = a.trim_start_matches("0x").as_bytes().to_ascii_lowercase();
+    let b = b.trim_start_matches("0x").as_bytes().to_ascii_lowercase();
+    ct_eq(&a, &b)
+}
+
+#[cfg(feature = "auth-timing")]
+pub use instrumentation::*;
+
+#[cfg(feature = "auth-timing")]
+mod instrumentation {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use once_cell::sync::Lazy;
+    use serde::{Deserialize, Serialize};
+
+    /// Samples kept per outcome, the oldest half is dropped past it
+    const MAX_SAMPLES: usize = 100_000;
+
+    static SAMPLES: Lazy<Mutex<BTreeMap<String, Vec<u64>>>> = Lazy::new(Default::default);
+
+    /// Spread of the verification time of one outcome, in nanoseconds
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct TimingStats {
+        pub samples: usize,
+        pub mean_ns: f64,
+        pub stddev_ns: f64,
+        pub median_ns: u64,
+        pub p99_ns: u64,
+        pub min_ns: u64,
+        pub max_ns: u64,
+    }
+
+    impl TimingStats {
+        pub fn of(samples: &[u64]) -> Self {
+            if samples.is_empty() {
+                return Self::default();
+            }
+            let mut sorted = samples.to_vec();
+            sorted.sort_unstable();
+            let count = sorted.len() as f64;
+            let mean = sorted.iter().map(|ns| *ns as f64).sum::<f64>() / count;
+            let variance = sorted.iter().map(|ns| (*ns as f64 - mean).powi(2)).sum::<f64>() / count;
+            Self {
+                samples: sorted.len(),
+                mean_ns: mean,
+                stddev_ns: variance.sqrt(),
+                median_ns: sorted[sorted.len() / 2],
+                p99_ns: sorted[(sorted.len() * 99 / 100).min(sorted.len() - 1)],
+                min_ns: sorted[0],
+                max_ns: sorted[sorted.len() - 1],
+            }
+        }
+
+        /// How many times slower the slower median of the two is, 1.0 when
+        /// they are equal. CI fails a comparison above its threshold.
+        pub fn median_ratio(&self, other: &TimingStats) -> f64 {
+            let (fast, slow) = if self.median_ns <= other.median_ns {
+                (self.median_ns, other.median_ns)
+            } else {
+                (other.median_ns, self.median_ns)
+            };
+            slow as f64 / fast.max(1) as f64
+        }
+    }
+
+    pub fn record(outcome: &str, elapsed: Duration) {
+        let mut samples = SAMPLES.lock().unwrap_or_else(|e| e.into_inner());
+        let outcome = samples.entry(outcome.to_string()).or_default();
+        if outcome.len() >= MAX_SAMPLES {
+            outcome.drain(..MAX_SAMPLES / 2);
+        }
+        outcome.push(elapsed.as_nanos().min(u64::MAX as u128) as u64);
+    }
+
+    /// Runs `verify` and records how long it took under the outcome
+    /// `outcome_of` gives its result
+    pub fn measure<T>(outcome_of: impl FnOnce(&T) -> String, verify: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = verify();
+        let elapsed = started.elapsed();
+        record(&outcome_of(&result), elapsed);
+        result
+    }
+
+    /// Stats of every outcome recorded so far
+    pub fn timing_report() -> BTreeMap<String, TimingStats> {
+        SAMPLES.lock().unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(outcome, samples)| (outcome.clone(), TimingStats::of(samples)))
+            .collect()
+    }
+
+    pub fn reset_timing() {
+        SAMPLES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_comparisons() {
+        assert!(ct_eq(b"digest", b"digest"));
+        assert!(!ct_eq(b"digest", b"digesT"));
+        assert!(!ct_eq(b"digest", b"diges"));
+        assert!(addresses_eq("0xAbCd12", "abcd12"));
+        assert!(!addresses_eq("0xabcd12", "abcd13"));
+    }
+
+    #[test]
+    fn test_jitter_only_in_hardened_mode() {
+        assert_eq!(HardeningConfig { enabled: false, jitter_max_ms: 50 }.jitter(), None);
+        assert_eq!(HardeningConfig { enabled: true, jitter_max_ms: 0 }.jitter(), None);
+        for _ in 0..100 {
+            let delay = HardeningConfig { enabled: true, jitter_max_ms: 5 }.jitter().unwrap();
+            assert!(delay <= Duration::from_millis(5));
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! WebAuthn (passkey) authentication for browser based clients
+//!
+//! Browsers can't produce the secp256k1 signatures the rest of the API
+//! expects, so accounts can link passkeys instead. Registration must be
+//! requested by the account itself and stores the credential's P-256 public
+//! key on the account. A verified assertion is exchanged for a short lived
+//! session token that is accepted wherever a signature is, as
+//! `Authorization: Passkey <token>`.
+//!
+//! Only the `none` attestation format is supported: clients send the public
+//! key returned by `AuthenticatorAttestationResponse.getPublicKey()` instead
+//! of the CBOR attestation object.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use alloy_primitives::Address;
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use once_cell::sync::Lazy;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use super::timing::ct_eq;
+
+/// COSE algorithm identifier for ECDSA with P-256 and SHA-256
+pub const COSE_ALG_ES256: i64 = -7;
+/// How long a registration or login challenge can be answered
+pub const CHALLENGE_TTL_SECONDS: i64 = 300;
+/// How long a passkey session is valid
+pub const SESSION_TTL_SECONDS: i64 = 12 * 60 * 60;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+
+static CHALLENGES: Lazy<Mutex<HashMap<String, PendingChallenge>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static SESSIONS: Lazy<Mutex<HashMap<String, PasskeySession>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebAuthnError {
+    Encoding(String),
+    ClientData(String),
+    UnknownChallenge,
+    InvalidSession,
+    OriginNotAllowed(String),
+    RelyingPartyMismatch,
+    UserNotPresent,
+    UserNotVerified,
+    UnsupportedAlgorithm(i64),
+    InvalidPublicKey,
+    InvalidSignature,
+    CounterRegression { stored: u32, received: u32 },
+}
+
+impl std::fmt::Display for WebAuthnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Encoding(e) => write!(f, "Invalid encoding: {e}"),
+            Self::ClientData(e) => write!(f, "Invalid client data: {e}"),
+            Self::UnknownChallenge => write!(f, "Unknown or expired challenge"),
+            Self::InvalidSession => write!(f, "Unknown or expired passkey session"),
+            Self::OriginNotAllowed(origin) => write!(f, "Origin {origin} is not allowed"),
+            Self::RelyingPartyMismatch => write!(f, "Authenticator data is for a different relying party"),
+            Self::UserNotPresent => write!(f, "The authenticator did not report user presence"),
+            Self::UserNotVerified => write!(f, "The authenticator did not verify the user"),
+            Self::UnsupportedAlgorithm(alg) => write!(f, "Unsupported public key algorithm {alg}, only ES256 (-7) is supported"),
+            Self::InvalidPublicKey => write!(f, "Invalid credential public key"),
+            Self::InvalidSignature => write!(f, "Invalid assertion signature"),
+            Self::CounterRegression { stored, received } => {
+                write!(f, "Signature counter went from {stored} to {received}, the credential may be cloned")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WebAuthnError {}
+
+/// Relying party settings, read from `WEBAUTHN_RP_ID`, `WEBAUTHN_RP_NAME`,
+/// `WEBAUTHN_ORIGINS` (comma separated) and `WEBAUTHN_REQUIRE_USER_VERIFICATION`
+#[derive(Debug, Clone)]
+pub struct WebAuthnConfig {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub allowed_origins: Vec<String>,
+    pub require_user_verification: bool,
+}
+
+impl WebAuthnConfig {
+    pub fn from_env() -> Self {
+        let rp_id = std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string());
+        let allowed_origins = std::env::var("WEBAUTHN_ORIGINS")
+            .map(|origins| origins.split(',').map(|o| o.trim().to_string()).filter(|o| !o.is_empty()).collect())
+            .unwrap_or_else(|_| vec![format!("https://{rp_id}"), "http://localhost:3000".to_string()]);
+        Self {
+            rp_name: std::env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| "Formation".to_string()),
+            require_user_verification: std::env::var("WEBAUTHN_REQUIRE_USER_VERIFICATION")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            rp_id,
+            allowed_origins,
+        }
+    }
+}
+
+/// A passkey linked to an account
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PasskeyCredential {
+    /// Base64url credential id chosen by the authenticator
+    pub credential_id: String,
+    /// Base64url DER encoded SubjectPublicKeyInfo of the P-256 key
+    pub public_key: String,
+    pub sign_count: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub created_at: i64,
+    #[serde(default)]
+    pub last_used_at: Option<i64>,
+}
+
+/// What a challenge was issued for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChallengePurpose {
+    Registration { address: String },
+    /// Logins may be restricted to an account's credentials
+    Authentication { address: Option<String> },
+}
+
+#[derive(Debug, Clone)]
+struct PendingChallenge {
+    purpose: ChallengePurpose,
+    expires_at: i64,
+}
+
+#[derive(Debug, Clone)]
+struct PasskeySession {
+    address: String,
+    expires_at: i64,
+}
+
+/// The `response` of a `navigator.credentials.create()` call, base64url encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrationResponse {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    /// Output of `getPublicKey()`
+    pub public_key: String,
+    /// Output of `getPublicKeyAlgorithm()`
+    pub public_key_algorithm: i64,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// The `response` of a `navigator.credentials.get()` call, base64url encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssertionResponse {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+fn decode(field: &str, value: &str) -> Result<Vec<u8>, WebAuthnError> {
+    URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))
+        .map_err(|e| WebAuthnError::Encoding(format!("{field}: {e}")))
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn session_key(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Issues a challenge for a registration or login ceremony
+pub fn issue_challenge(purpose: ChallengePurpose) -> String {
+    let challenge = random_token();
+    let mut challenges = CHALLENGES.lock().unwrap_or_else(|e| e.into_inner());
+    let now = now();
+    challenges.retain(|_, pending| pending.expires_at > now);
+    challenges.insert(challenge.clone(), PendingChallenge { purpose, expires_at: now + CHALLENGE_TTL_SECONDS });
+    challenge
+}
+
+/// Consumes the challenge a client answered, challenges can only be used once
+pub fn take_challenge(client_data_json: &str) -> Result<(String, ChallengePurpose), WebAuthnError> {
+    let client_data: ClientData = serde_json::from_slice(&decode("client_data_json", client_data_json)?)
+        .map_err(|e| WebAuthnError::ClientData(e.to_string()))?;
+    let pending = CHALLENGES.lock().unwrap_or_else(|e| e.into_inner())
+        .remove(&client_data.challenge)
+        .filter(|pending| pending.expires_at > now())
+        .ok_or(WebAuthnError::UnknownChallenge)?;
+    Ok((client_data.challenge, pending.purpose))
+}
+
+/// Issues a session token for an account that completed a login ceremony
+pub fn create_session(address: &str) -> (String, i64) {
+    let token = random_token();
+    let expires_at = now() + SESSION_TTL_SECONDS;
+    let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let now = now();
+    sessions.retain(|_, session| session.expires_at > now);
+    sessions.insert(session_key(&token), PasskeySession { address: address.to_string(), expires_at });
+    (token, expires_at)
+}
+
+/// Ends every session of an account, used when one of its passkeys is removed
+pub fn revoke_sessions(address: &str) {
+    SESSIONS.lock().unwrap_or_else(|e| e.into_inner())
+        .retain(|_, session| session.address != address);
+}
+
+/// The account behind an `Authorization: Passkey <token>` header. Returns
+/// `None` when the request doesn't carry a passkey session at all.
+pub fn session_address(headers: &HeaderMap) -> Option<Result<Address, WebAuthnError>> {
+    let token = headers.get("authorization")?.to_str().ok()?.strip_prefix("Passkey ")?.trim();
+    let session = SESSIONS.lock().unwrap_or_else(|e| e.into_inner())
+        .get(&session_key(token))
+        .filter(|session| session.expires_at > now())
+        .cloned();
+    Some(
+        session.ok_or(WebAuthnError::InvalidSession)
+            .and_then(|session| {
+                session.address.trim_start_matches("0x").parse::<Address>()
+                    .map_err(|e| WebAuthnError::Encoding(e.to_string()))
+            })
+    )
+}
+
+/// Checks the parts of a ceremony that registration and login share and
+/// returns the authenticator's signature counter
+fn verify_ceremony(
+    config: &WebAuthnConfig,
+    ceremony: &str,
+    expected_challenge: &str,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+) -> Result<u32, WebAuthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|e| WebAuthnError::ClientData(e.to_string()))?;
+    if client_data.ceremony != ceremony {
+        return Err(WebAuthnError::ClientData(format!("expected type {ceremony}, got {}", client_data.ceremony)));
+    }
+    if !ct_eq(client_data.challenge.as_bytes(), expected_challenge.as_bytes()) {
+        return Err(WebAuthnError::UnknownChallenge);
+    }
+    if !config.allowed_origins.iter().any(|origin| origin == &client_data.origin) {
+        return Err(WebAuthnError::OriginNotAllowed(client_data.origin));
+    }
+
+    // rpIdHash (32) | flags (1) | signCount (4) | ...
+    if authenticator_data.len() < 37 {
+        return Err(WebAuthnError::Encoding("authenticator data is too short".to_string()));
+    }
+    if !ct_eq(&authenticator_data[..32], &Sha256::digest(config.rp_id.as_bytes())) {
+        return Err(WebAuthnError::RelyingPartyMismatch);
+    }
+    let flags = authenticator_data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(WebAuthnError::UserNotPresent);
+    }
+    if config.require_user_verification && flags & FLAG_USER_VERIFIED == 0 {
+        return Err(WebAuthnError::UserNotVerified);
+    }
+    let mut counter = [0u8; 4];
+    counter.copy_from_slice(&authenticator_data[33..37]);
+    Ok(u32::from_be_bytes(counter))
+}
+
+/// Verifies a registration ceremony and returns the credential to store
+pub fn verify_registration(
+    config: &WebAuthnConfig,
+    expected_challenge: &str,
+    response: &RegistrationResponse,
+) -> Result<PasskeyCredential, WebAuthnError> {
+    if response.public_key_algorithm != COSE_ALG_ES256 {
+        return Err(WebAuthnError::UnsupportedAlgorithm(response.public_key_algorithm));
+    }
+    if decode("credential_id", &response.credential_id)?.is_empty() {
+        return Err(WebAuthnError::Encoding("credential_id is empty".to_string()));
+    }
+    let sign_count = verify_ceremony(
+        config,
+        "webauthn.create",
+        expected_challenge,
+        &decode("client_data_json", &response.client_data_json)?,
+        &decode("authenticator_data", &response.authenticator_data)?,
+    )?;
+    VerifyingKey::from_public_key_der(&decode("public_key", &response.public_key)?)
+        .map_err(|_| WebAuthnError::InvalidPublicKey)?;
+
+    Ok(PasskeyCredential {
+        credential_id: response.credential_id.trim_end_matches('=').to_string(),
+        public_key: response.public_key.trim_end_matches('=').to_string(),
+        sign_count,
+        name: response.name.clone(),
+        created_at: now(),
+        last_used_at: None,
+    })
+}
+
+/// Verifies a login assertion against a stored credential and returns the
+/// new signature counter
+pub fn verify_assertion(
+    config: &WebAuthnConfig,
+    expected_challenge: &str,
+    credential: &PasskeyCredential,
+    response: &AssertionResponse,
+) -> Result<u32, WebAuthnError> {
+    let client_data_json = decode("client_data_json", &response.client_data_json)?;
+    let authenticator_data = decode("authenticator_data", &response.authenticator_data)?;
+    let sign_count = verify_ceremony(config, "webauthn.get", expected_challenge, &client_data_json, &authenticator_data)?;
+
+    let key = VerifyingKey::from_public_key_der(&decode("public_key", &credential.public_key)?)
+        .map_err(|_| WebAuthnError::InvalidPublicKey)?;
+    let signature = Signature::from_der(&decode("signature", &response.signature)?)
+        .map_err(|_| WebAuthnError::InvalidSignature)?;
+    let mut signed = authenticator_data;
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    key.verify(&signed, &signature).map_err(|_| WebAuthnError::InvalidSignature)?;
+
+    // Authenticators that don't keep a counter always report 0
+    if (sign_count != 0 || credential.sign_count != 0) && sign_count <= credential.sign_count {
+        return Err(WebAuthnError::CounterRegression { stored: credential.sign_count, received: sign_count });
+    }
+    Ok(sign_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, SigningKey};
+    use p256::pkcs8::EncodePublicKey;
+
+    fn config() -> WebAuthnConfig {
+        WebAuthnConfig {
+            rp_id: "formation.cloud".to_string(),
+            rp_name: "Formation".to_string(),
+            allowed_origins: vec!["https://formation.cloud".to_string()],
+            require_user_verification: false,
+        }
+    }
+
+    fn authenticator_data(rp_id: &str, flags: u8, counter: u32) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&counter.to_be_bytes());
+        data
+    }
+
+    fn client_data(ceremony: &str, challenge: &str, origin: &str) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::json!({ "type": ceremony, "challenge": challenge, "origin": origin }).to_string())
+    }
+
+    #[test]
+    fn test_registration_and_assertion() {
+        let config = config();
+        let key = SigningKey::random(&mut rand::thread_rng());
+        let public_key = URL_SAFE_NO_PAD.encode(key.verifying_key().to_public_key_der().unwrap().as_bytes());
+
+        let registration = RegistrationResponse {
+            credential_id: URL_SAFE_NO_PAD.encode(b"credential"),
+            client_data_json: client_data("webauthn.create", "register", "https://formation.cloud"),
+            authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data("formation.cloud", FLAG_USER_PRESENT, 0)),
+            public_key,
+            public_key_algorithm: COSE_ALG_ES256,
+            name: None,
+        };
+        let credential = verify_registration(&config, "register", &registration).unwrap();
+        assert!(verify_registration(&config, "other", &registration).is_err());
+        let wrong_rp = RegistrationResponse {
+            authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data("evil.example", FLAG_USER_PRESENT, 0)),
+            ..registration.clone()
+        };
+        assert_eq!(verify_registration(&config, "register", &wrong_rp), Err(WebAuthnError::RelyingPartyMismatch));
+
+        let sign = |counter: u32, origin: &str| {
+            let auth_data = authenticator_data("formation.cloud", FLAG_USER_PRESENT | FLAG_USER_VERIFIED, counter);
+            let client_data_json = client_data("webauthn.get", "login", origin);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(URL_SAFE_NO_PAD.decode(&client_data_json).unwrap()));
+            let signature: Signature = key.sign(&signed);
+            AssertionResponse {
+                credential_id: credential.credential_id.clone(),
+                client_data_json,
+                authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+                signature: URL_SAFE_NO_PAD.encode(signature.to_der().as_bytes()),
+            }
+        };
+
+        assert_eq!(verify_assertion(&config, "login", &credential, &sign(5, "https://formation.cloud")), Ok(5));
+        assert!(matches!(
+            verify_assertion(&config, "login", &credential, &sign(5, "https://evil.example")),
+            Err(WebAuthnError::OriginNotAllowed(_))
+        ));
+        let used = PasskeyCredential { sign_count: 5, ..credential.clone() };
+        assert!(matches!(
+            verify_assertion(&config, "login", &used, &sign(5, "https://formation.cloud")),
+            Err(WebAuthnError::CounterRegression { .. })
+        ));
+
+        let mut tampered = sign(6, "https://formation.cloud");
+        tampered.authenticator_data = URL_SAFE_NO_PAD.encode(authenticator_data("formation.cloud", FLAG_USER_PRESENT, 7));
+        assert_eq!(verify_assertion(&config, "login", &credential, &tampered), Err(WebAuthnError::InvalidSignature));
+    }
+}
@@ -0,0 +1,493 @@
+// form-state/src/autoscaler.rs
+// Closed-loop autoscaling: consumes usage events published by form-vm-metrics,
+// evaluates each build's ScalingPolicy and emits create/delete commands to the vmm queue.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use form_types::{CreateVmRequest, DeleteVmRequest};
+use form_usage_events::{SignedUsageEvent, UsageEvent};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::datastore::DataStore;
+use crate::instances::{Instance, InstanceStatus, ScalingPolicy};
+use crate::quotas::ResourceUsage;
+use crate::tasks::calculate_poc_score;
+
+/// Queue topic form-vm-metrics publishes usage events to
+pub const USAGE_EVENTS_TOPIC: &str = "usage_events";
+/// Queue topic the vmm-service reads VM lifecycle commands from
+pub const VMM_TOPIC: &str = "vmm";
+/// vmm-service subtopic for `CreateVmRequest`
+pub const VMM_CREATE_SUBTOPIC: u8 = 0;
+/// vmm-service subtopic for `DeleteVmRequest`
+pub const VMM_DELETE_SUBTOPIC: u8 = 2;
+/// vmm-service subtopic for `StopVmRequest`
+pub const VMM_STOP_SUBTOPIC: u8 = 3;
+/// vmm-service subtopic for `StartVmRequest`
+pub const VMM_START_SUBTOPIC: u8 = 5;
+
+/// Configuration for the autoscaler evaluation loop
+#[derive(Clone, Debug)]
+pub struct AutoscalerConfig {
+    /// How often scaling policies are evaluated
+    pub evaluation_interval: Duration,
+    /// How often the usage event queue is polled
+    pub poll_interval: Duration,
+    /// Samples older than this (in seconds) are ignored when averaging CPU usage
+    pub sample_ttl_seconds: i64,
+}
+
+impl Default for AutoscalerConfig {
+    fn default() -> Self {
+        Self {
+            evaluation_interval: Duration::from_secs(30),
+            poll_interval: Duration::from_millis(500),
+            sample_ttl_seconds: 120,
+        }
+    }
+}
+
+/// A single CPU utilization sample for an instance
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsageSample {
+    pub cpu_percent: f64,
+    pub timestamp: i64,
+}
+
+/// Latest usage sample per instance, built from the usage event stream
+#[derive(Clone, Debug, Default)]
+pub struct UsageSamples {
+    samples: HashMap<String, UsageSample>,
+}
+
+impl UsageSamples {
+    /// Records the CPU utilization reported by a usage event, keeping only the newest sample per instance
+    pub fn record(&mut self, event: &UsageEvent) {
+        let sample = UsageSample {
+            cpu_percent: event.metrics.cpu_percent_avg,
+            timestamp: event.period.end,
+        };
+
+        match self.samples.get(&event.instance_id) {
+            Some(existing) if existing.timestamp > sample.timestamp => {}
+            _ => {
+                self.samples.insert(event.instance_id.clone(), sample);
+            }
+        }
+    }
+
+    /// Returns the average CPU utilization (0-100) across the given instances,
+    /// considering only samples newer than `ttl_seconds`
+    pub fn average_cpu<'a>(
+        &self,
+        instance_ids: impl IntoIterator<Item = &'a String>,
+        now: i64,
+        ttl_seconds: i64,
+    ) -> Option<u32> {
+        let fresh: Vec<f64> = instance_ids.into_iter().filter_map(|id| {
+            self.samples.get(id)
+                .filter(|s| now - s.timestamp <= ttl_seconds)
+                .map(|s| s.cpu_percent)
+        }).collect();
+
+        if fresh.is_empty() {
+            return None;
+        }
+
+        let avg = fresh.iter().sum::<f64>() / fresh.len() as f64;
+        Some(avg.clamp(0.0, 100.0).round() as u32)
+    }
+
+    /// Drops samples that are older than `ttl_seconds`
+    pub fn prune(&mut self, now: i64, ttl_seconds: i64) {
+        self.samples.retain(|_, s| now - s.timestamp <= ttl_seconds);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+/// The action the autoscaler decided to take for a build
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScalingDecision {
+    /// Launch `count` additional instances of the build
+    ScaleOut { build_id: String, count: u32 },
+    /// Delete the listed instances of the build
+    ScaleIn { build_id: String, instance_ids: Vec<String> },
+}
+
+/// Returns the scaling policy for a build, preferring the template instance's copy
+pub fn build_scaling_policy(instances: &[Instance]) -> Option<ScalingPolicy> {
+    let template_id = instances.iter()
+        .find_map(|i| i.cluster.template_instance_id().cloned());
+
+    if let Some(template_id) = template_id {
+        if let Some(policy) = instances.iter()
+            .find(|i| i.instance_id == template_id)
+            .and_then(|i| i.cluster.scaling_policy().cloned()) {
+            return Some(policy);
+        }
+    }
+
+    instances.iter().find_map(|i| i.cluster.scaling_policy().cloned())
+}
+
+/// Instances that count towards a build's current capacity
+pub fn active_instances(instances: &[Instance]) -> Vec<&Instance> {
+    instances.iter().filter(|i| {
+        !matches!(i.status, InstanceStatus::Deleted | InstanceStatus::Failed)
+    }).collect()
+}
+
+/// Evaluates a build's scaling policy against its current average CPU utilization.
+///
+/// Cooldowns and min/max bounds are enforced by `ScalingPolicy::get_target_instance_count`.
+pub fn evaluate_build(
+    build_id: &str,
+    instances: &[Instance],
+    avg_cpu: u32,
+    now: i64,
+) -> Option<ScalingDecision> {
+    let policy = build_scaling_policy(instances)?;
+    let active = active_instances(instances);
+    let current = active.len() as u32;
+
+    // Always restore the minimum before looking at utilization
+    if current < policy.min_instances() {
+        return Some(ScalingDecision::ScaleOut {
+            build_id: build_id.to_string(),
+            count: policy.min_instances() - current,
+        });
+    }
+
+    let target = policy.get_target_instance_count(current, avg_cpu, now)?;
+
+    if target > current {
+        Some(ScalingDecision::ScaleOut {
+            build_id: build_id.to_string(),
+            count: target - current,
+        })
+    } else if target < current {
+        let instance_ids = select_instances_to_remove(&active, (current - target) as usize);
+        if instance_ids.is_empty() {
+            return None;
+        }
+        Some(ScalingDecision::ScaleIn {
+            build_id: build_id.to_string(),
+            instance_ids,
+        })
+    } else {
+        None
+    }
+}
+
+/// Picks the newest non-template instances to remove when scaling in
+fn select_instances_to_remove(active: &[&Instance], count: usize) -> Vec<String> {
+    let template_id = active.iter().find_map(|i| i.cluster.template_instance_id().cloned());
+    let mut candidates: Vec<&&Instance> = active.iter()
+        .filter(|i| Some(&i.instance_id) != template_id.as_ref())
+        .collect();
+
+    candidates.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    candidates.into_iter()
+        .take(count)
+        .map(|i| i.instance_id.clone())
+        .collect()
+}
+
+/// Only one state node should act on a given build, chosen by the lowest PoC score
+pub fn is_responsible_for_build(build_id: &str, local_node_id: &str, node_ids: &[String]) -> bool {
+    if node_ids.is_empty() {
+        return true;
+    }
+
+    node_ids.iter()
+        .min_by_key(|id| calculate_poc_score(build_id, id))
+        .map_or(true, |id| id == local_node_id)
+}
+
+/// Runs the autoscaler until a shutdown signal is received
+pub async fn run_autoscaler(
+    datastore: Arc<Mutex<DataStore>>,
+    config: AutoscalerConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting autoscaler (evaluation interval: {:?})", config.evaluation_interval);
+    let mut samples = UsageSamples::default();
+    let mut n = 0;
+    let mut evaluation = tokio::time::interval(config.evaluation_interval);
+
+    loop {
+        tokio::select! {
+            Ok(messages) = DataStore::read_topic_from_queue(USAGE_EVENTS_TOPIC, Some(n), None) => {
+                n += messages.len();
+                for message in messages {
+                    if message.is_empty() {
+                        continue;
+                    }
+                    // Scaling only reads the load, so the attestation isn't checked here
+                    match serde_json::from_slice::<SignedUsageEvent>(&message[1..]) {
+                        Ok(signed) => samples.record(&signed.event),
+                        Err(_) => match serde_json::from_slice::<UsageEvent>(&message[1..]) {
+                            Ok(event) => samples.record(&event),
+                            Err(e) => log::warn!("Autoscaler unable to decode usage event: {e}"),
+                        },
+                    }
+                }
+            }
+            _ = evaluation.tick() => {
+                if let Err(e) = evaluate_all(datastore.clone(), &mut samples, &config).await {
+                    log::error!("Autoscaler evaluation failed: {e}");
+                }
+            }
+            _ = tokio::time::sleep(config.poll_interval) => {}
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates every build that has a scaling policy and acts on the resulting decisions
+pub async fn evaluate_all(
+    datastore: Arc<Mutex<DataStore>>,
+    samples: &mut UsageSamples,
+    config: &AutoscalerConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now().timestamp();
+    samples.prune(now, config.sample_ttl_seconds);
+
+    let mut guard = datastore.lock().await;
+    if let Err(freeze) = guard.check_deployments(None, now) {
+        log::info!("Autoscaler paused: {freeze}");
+        return Ok(());
+    }
+    let local_node_id = guard.node_state.node_id.clone();
+    let node_ids: Vec<String> = guard.node_state.list_nodes().into_iter().map(|n| n.node_id).collect();
+
+    let mut builds: BTreeMap<String, Vec<Instance>> = BTreeMap::new();
+    for ctx in guard.instance_state.map.iter() {
+        let (_, reg) = ctx.val;
+        if let Some(val) = reg.val() {
+            let instance = val.value();
+            builds.entry(instance.build_id.clone()).or_default().push(instance);
+        }
+    }
+
+    for (build_id, instances) in builds {
+        if build_scaling_policy(&instances).is_none() {
+            continue;
+        }
+
+        if !is_responsible_for_build(&build_id, &local_node_id, &node_ids) {
+            continue;
+        }
+
+        let ids: Vec<String> = active_instances(&instances).iter().map(|i| i.instance_id.clone()).collect();
+        let avg_cpu = match samples.average_cpu(ids.iter(), now, config.sample_ttl_seconds) {
+            Some(cpu) => cpu,
+            None => {
+                log::debug!("Autoscaler has no fresh usage samples for build {build_id}, skipping");
+                continue;
+            }
+        };
+
+        let Some(decision) = evaluate_build(&build_id, &instances, avg_cpu, now) else {
+            continue;
+        };
+
+        log::info!("Autoscaler decision for build {build_id} at {avg_cpu}% CPU: {decision:?}");
+        if let ScalingDecision::ScaleOut { count, .. } = &decision {
+            // Every instance of a build has the same owner and resources
+            if let Some(template) = instances.first() {
+                let requested = ResourceUsage::of(&template.resources).times(*count);
+                if let Err(e) = guard.check_quota(&template.instance_owner, &requested, None) {
+                    log::warn!("Autoscaler not scaling out build {build_id}: {e}");
+                    continue;
+                }
+            }
+        }
+        if let Err(e) = emit_decision(&decision, &instances).await {
+            log::error!("Autoscaler failed to emit {decision:?}: {e}");
+            continue;
+        }
+
+        record_decision(&mut guard, &decision, instances, now).await?;
+    }
+
+    Ok(())
+}
+
+/// Writes the VM lifecycle commands for a decision to the vmm queue
+pub async fn emit_decision(
+    decision: &ScalingDecision,
+    instances: &[Instance],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match decision {
+        ScalingDecision::ScaleOut { build_id, count } => {
+            let template_id = instances.iter().find_map(|i| i.cluster.template_instance_id().cloned());
+            let template = instances.iter()
+                .find(|i| Some(&i.instance_id) == template_id.as_ref())
+                .or_else(|| instances.iter().max_by_key(|i| i.updated_at))
+                .ok_or_else(|| Box::new(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No template instance available for build {build_id}")
+                )))?;
+
+            for _ in 0..*count {
+                let request = CreateVmRequest {
+                    name: build_id.clone(),
+                    formfile: template.formfile.clone(),
+                    owner: template.instance_owner.clone(),
+                };
+                DataStore::write_to_queue(request, VMM_CREATE_SUBTOPIC, VMM_TOPIC.to_string()).await?;
+            }
+        }
+        ScalingDecision::ScaleIn { build_id, instance_ids } => {
+            for id in instance_ids {
+                let request = DeleteVmRequest {
+                    id: id.clone(),
+                    name: build_id.clone(),
+                };
+                DataStore::write_to_queue(request, VMM_DELETE_SUBTOPIC, VMM_TOPIC.to_string()).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stamps the scale time on every instance's policy so cooldowns replicate across the network
+async fn record_decision(
+    datastore: &mut DataStore,
+    decision: &ScalingDecision,
+    instances: Vec<Instance>,
+    now: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for mut instance in instances {
+        match decision {
+            ScalingDecision::ScaleOut { .. } => instance.cluster.record_scale_out(now),
+            ScalingDecision::ScaleIn { .. } => instance.cluster.record_scale_in(now),
+        }
+        instance.updated_at = now;
+        datastore.handle_instance_update(instance).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instances::InstanceCluster;
+    use form_usage_events::{UsageMetrics, UsagePeriod};
+
+    fn instance(id: &str, created_at: i64, policy: Option<ScalingPolicy>) -> Instance {
+        Instance {
+            instance_id: id.to_string(),
+            build_id: "build".to_string(),
+            created_at,
+            status: InstanceStatus::Ready,
+            cluster: InstanceCluster {
+                scaling_policy: policy,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn event(instance_id: &str, cpu: f64, end: i64) -> UsageEvent {
+        UsageEvent {
+            event_type: "resource_usage".to_string(),
+            version: "1.0".to_string(),
+            timestamp: end,
+            instance_id: instance_id.to_string(),
+            user_id: "user".to_string(),
+            org_id: None,
+            metrics: UsageMetrics {
+                cpu_seconds: 0,
+                cpu_percent_avg: cpu,
+                memory_gb: 0.0,
+                memory_percent: 0.0,
+                storage_gb: 0.0,
+                network_egress_mb: 0.0,
+                network_ingress_mb: 0.0,
+                gpu_seconds: 0,
+                disk_read_bytes_per_sec: 0,
+                disk_write_bytes_per_sec: 0,
+                disk_iops: 0,
+                network_egress_bytes_per_sec: 0,
+                network_ingress_bytes_per_sec: 0,
+                gpus: Vec::new(),
+            },
+            period: UsagePeriod { start: end - 30, end },
+        }
+    }
+
+    #[test]
+    fn test_average_cpu_ignores_stale_samples() {
+        let mut samples = UsageSamples::default();
+        samples.record(&event("a", 90.0, 1000));
+        samples.record(&event("b", 50.0, 500));
+        let ids = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(samples.average_cpu(ids.iter(), 1010, 120), Some(90));
+        samples.prune(1010, 120);
+        assert_eq!(samples.len(), 1);
+    }
+
+    #[test]
+    fn test_scale_out_above_target() {
+        let policy = ScalingPolicy::new(1, 4, 50, 300, 120);
+        let instances = vec![instance("a", 1, Some(policy.clone())), instance("b", 2, Some(policy))];
+        let decision = evaluate_build("build", &instances, 100, 10_000);
+        assert_eq!(decision, Some(ScalingDecision::ScaleOut { build_id: "build".to_string(), count: 2 }));
+    }
+
+    #[test]
+    fn test_scale_in_removes_newest_instances() {
+        let policy = ScalingPolicy::new(1, 4, 70, 300, 120);
+        let instances = vec![
+            instance("a", 1, Some(policy.clone())),
+            instance("b", 2, Some(policy.clone())),
+            instance("c", 3, Some(policy)),
+        ];
+        match evaluate_build("build", &instances, 10, 10_000) {
+            Some(ScalingDecision::ScaleIn { instance_ids, .. }) => {
+                assert_eq!(instance_ids.first().map(String::as_str), Some("c"));
+                assert!(!instance_ids.contains(&"a".to_string()));
+            }
+            other => panic!("expected scale in, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_cooldown_blocks_scale_out() {
+        let mut policy = ScalingPolicy::new(1, 4, 50, 300, 120);
+        policy.record_scale_out(9_950);
+        let instances = vec![instance("a", 1, Some(policy))];
+        assert_eq!(evaluate_build("build", &instances, 100, 10_000), None);
+    }
+
+    #[test]
+    fn test_no_policy_no_decision() {
+        let instances = vec![instance("a", 1, None)];
+        assert_eq!(evaluate_build("build", &instances, 100, 10_000), None);
+    }
+
+    #[test]
+    fn test_single_responsible_node() {
+        let nodes = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        let responsible: Vec<&String> = nodes.iter()
+            .filter(|n| is_responsible_for_build("build", n, &nodes))
+            .collect();
+        assert_eq!(responsible.len(), 1);
+    }
+}
@@ -0,0 +1,499 @@
+// form-state/src/backup.rs
+// Portable, encrypted backups of the replicated datastore state. Archives are
+// encrypted with an operator supplied key rather than the node key, so they
+// can be restored on a replacement node, and signed by the node that made
+// them so a restore can tell where they came from.
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use alloy_primitives::Address;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::datastore::{DataStore, MergeableState};
+
+/// Current archive format
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+/// Domain separator for the fingerprint that identifies a backup key
+const KEY_ID_CONTEXT: &[u8] = b"formation-backup-key-v1";
+/// File name prefix of scheduled backups, retention only touches these
+const BACKUP_FILE_PREFIX: &str = "form-state-backup-";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("FORM_BACKUP_KEY is not set or is not 32 hex encoded bytes")]
+    NoKey,
+    #[error("Unsupported backup format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Backup was encrypted with key {archive}, the configured key is {configured}")]
+    WrongKey { archive: String, configured: String },
+    #[error("Invalid backup signature: {0}")]
+    InvalidSignature(String),
+    #[error("Backup was signed by {0}, which is not a trusted signer")]
+    UntrustedSigner(String),
+    #[error("Unable to decrypt backup")]
+    Decryption,
+    #[error("Unable to encrypt backup")]
+    Encryption,
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("S3 upload failed: {0}")]
+    Upload(String),
+}
+
+/// The authenticated, unencrypted part of an archive
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupHeader {
+    pub version: u32,
+    pub created_at: i64,
+    /// Hex address of the node that made the backup
+    pub signer: String,
+    /// Fingerprint of the key the state is encrypted with
+    pub key_id: String,
+    /// Hex encoded 96-bit nonce
+    pub nonce: String,
+}
+
+/// A signed, encrypted export of a node's `MergeableState`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackupArchive {
+    pub header: BackupHeader,
+    /// Base64 encoded AES-256-GCM ciphertext of the state's JSON
+    pub ciphertext: String,
+    pub signature: String,
+    pub recovery_id: u8,
+}
+
+/// Key that archives are encrypted with
+#[derive(Clone)]
+pub struct BackupKey([u8; 32]);
+
+impl std::fmt::Debug for BackupKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("BackupKey").field(&self.id()).finish()
+    }
+}
+
+impl BackupKey {
+    pub fn from_hex(key: &str) -> Result<Self, BackupError> {
+        let bytes = hex::decode(key.trim().trim_start_matches("0x")).map_err(|_| BackupError::NoKey)?;
+        Ok(Self(bytes.try_into().map_err(|_| BackupError::NoKey)?))
+    }
+
+    /// Reads the key from `FORM_BACKUP_KEY`
+    pub fn from_env() -> Result<Self, BackupError> {
+        Self::from_hex(&std::env::var("FORM_BACKUP_KEY").map_err(|_| BackupError::NoKey)?)
+    }
+
+    /// Fingerprint of the key, safe to publish
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(KEY_ID_CONTEXT);
+        hasher.update(self.0);
+        hex::encode(&hasher.finalize()[..8])
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&self.0).expect("backup keys are 32 bytes")
+    }
+}
+
+/// What signing covers, the header is also the ciphertext's associated data
+fn signed_bytes(header: &BackupHeader, ciphertext: &str) -> Result<Vec<u8>, BackupError> {
+    let mut bytes = serde_json::to_vec(header)?;
+    bytes.extend_from_slice(ciphertext.as_bytes());
+    Ok(bytes)
+}
+
+impl BackupArchive {
+    /// Encrypts `state` with `key` and signs it with the node key
+    pub fn seal(state: &MergeableState, key: &BackupKey, signing_key: &SigningKey, created_at: i64) -> Result<Self, BackupError> {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let header = BackupHeader {
+            version: BACKUP_FORMAT_VERSION,
+            created_at,
+            signer: hex::encode(Address::from_private_key(signing_key)),
+            key_id: key.id(),
+            nonce: hex::encode(nonce),
+        };
+
+        let plaintext = serde_json::to_vec(state)?;
+        let aad = serde_json::to_vec(&header)?;
+        let ciphertext = key.cipher()
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad: &aad })
+            .map_err(|_| BackupError::Encryption)?;
+        let ciphertext = STANDARD.encode(ciphertext);
+
+        let (signature, recovery_id) = signing_key.sign_recoverable(&signed_bytes(&header, &ciphertext)?)
+            .map_err(|e| BackupError::InvalidSignature(e.to_string()))?;
+        Ok(Self {
+            header,
+            ciphertext,
+            signature: hex::encode(signature.to_bytes()),
+            recovery_id: recovery_id.to_byte(),
+        })
+    }
+
+    /// Checks the signature and returns the signer's hex address
+    pub fn verify_signature(&self) -> Result<String, BackupError> {
+        let invalid = |e: String| BackupError::InvalidSignature(e);
+        let signature = hex::decode(&self.signature).map_err(|e| invalid(e.to_string()))?;
+        let signature = Signature::try_from(signature.as_slice()).map_err(|e| invalid(e.to_string()))?;
+        let recovery_id = RecoveryId::from_byte(self.recovery_id)
+            .ok_or_else(|| invalid(format!("bad recovery id {}", self.recovery_id)))?;
+        let key = VerifyingKey::recover_from_msg(&signed_bytes(&self.header, &self.ciphertext)?, &signature, recovery_id)
+            .map_err(|e| invalid(e.to_string()))?;
+
+        let signer = hex::encode(Address::from_public_key(&key));
+        if signer != self.header.signer.trim_start_matches("0x").to_lowercase() {
+            return Err(invalid(format!("signed by {signer}, not {}", self.header.signer)));
+        }
+        Ok(signer)
+    }
+
+    /// Verifies and decrypts the archive. `trusted` decides whether the
+    /// signer's address may restore into this node.
+    pub fn open(&self, key: &BackupKey, trusted: impl Fn(&str) -> bool) -> Result<MergeableState, BackupError> {
+        if self.header.version != BACKUP_FORMAT_VERSION {
+            return Err(BackupError::UnsupportedVersion(self.header.version));
+        }
+        if self.header.key_id != key.id() {
+            return Err(BackupError::WrongKey { archive: self.header.key_id.clone(), configured: key.id() });
+        }
+        let signer = self.verify_signature()?;
+        if !trusted(&signer) {
+            return Err(BackupError::UntrustedSigner(signer));
+        }
+
+        let nonce = hex::decode(&self.header.nonce).map_err(|_| BackupError::Decryption)?;
+        if nonce.len() != 12 {
+            return Err(BackupError::Decryption);
+        }
+        let ciphertext = STANDARD.decode(&self.ciphertext).map_err(|_| BackupError::Decryption)?;
+        let aad = serde_json::to_vec(&self.header)?;
+        let plaintext = key.cipher()
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &aad })
+            .map_err(|_| BackupError::Decryption)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+/// Addresses whose backups this node restores: itself, nodes and their
+/// operator keys known to the current state, network admins, and anything
+/// listed in `FORM_BACKUP_TRUSTED_SIGNERS`
+pub fn is_trusted_signer(datastore: &DataStore, signer: &str) -> bool {
+    let signer = signer.trim_start_matches("0x").to_lowercase();
+    let matches = |address: &str| address.trim_start_matches("0x").to_lowercase() == signer;
+    matches(&datastore.node_state.node_id)
+        || datastore.network_state.is_admin_address(&signer)
+        || datastore.node_state.list_nodes().iter().any(|node| {
+            matches(&node.node_id) || node.operator_keys.iter().any(|key| matches(key))
+        })
+        || std::env::var("FORM_BACKUP_TRUSTED_SIGNERS").unwrap_or_default()
+            .split(',')
+            .any(|address| !address.trim().is_empty() && matches(address.trim()))
+}
+
+/// Keys added, removed and changed in one collection by a restore
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollectionDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl CollectionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Entries of a serialized CRDT map by key, compared by value only so
+/// clocks that moved without a change don't show up
+fn entries(collection: &Value) -> BTreeMap<String, &Value> {
+    collection.get("entries")
+        .and_then(Value::as_object)
+        .map(|entries| entries.iter().map(|(key, entry)| (key.clone(), entry.get("val").unwrap_or(entry))).collect())
+        .unwrap_or_default()
+}
+
+/// Compares the current state with a backup, per collection. `removed` are
+/// entries the backup doesn't have, a restore merges and keeps them.
+pub fn diff_states(current: &MergeableState, backup: &MergeableState) -> Result<BTreeMap<String, CollectionDiff>, BackupError> {
+    let current = serde_json::to_value(current)?;
+    let backup = serde_json::to_value(backup)?;
+    let (Some(current), Some(backup)) = (current.as_object(), backup.as_object()) else {
+        return Ok(BTreeMap::new());
+    };
+
+    let mut diff = BTreeMap::new();
+    for (name, backup_collection) in backup {
+        let Some(current_collection) = current.get(name) else { continue };
+        if backup_collection.get("entries").is_none() {
+            continue;
+        }
+        let before = entries(current_collection);
+        let after = entries(backup_collection);
+        let mut collection = CollectionDiff::default();
+        for (key, value) in &after {
+            match before.get(key) {
+                None => collection.added.push(key.clone()),
+                Some(existing) if existing != value => collection.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        collection.removed = before.keys().filter(|key| !after.contains_key(*key)).cloned().collect();
+        if !collection.is_empty() {
+            diff.insert(name.clone(), collection);
+        }
+    }
+    Ok(diff)
+}
+
+/// Seals the current state of `datastore`
+pub async fn create_backup(datastore: &Arc<Mutex<DataStore>>, key: &BackupKey) -> Result<BackupArchive, Box<dyn std::error::Error + Send + Sync>> {
+    let (state, pk): (MergeableState, String) = {
+        let guard = datastore.lock().await;
+        (guard.clone().into(), guard.network_state.pk.clone())
+    };
+    let signing_key = SigningKey::from_slice(&hex::decode(pk.trim_start_matches("0x"))?)?;
+    Ok(BackupArchive::seal(&state, key, &signing_key, chrono::Utc::now().timestamp())?)
+}
+
+/// Where a bucket lives and the credentials to write to it
+#[derive(Clone, Debug)]
+pub struct S3Target {
+    /// e.g. `https://s3.us-east-1.amazonaws.com`, objects are addressed path style
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl S3Target {
+    /// Reads `FORM_BACKUP_S3_*` and the standard AWS credential variables,
+    /// `None` unless a bucket is configured
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let bucket = var("FORM_BACKUP_S3_BUCKET")?;
+        let region = var("FORM_BACKUP_S3_REGION").or_else(|| var("AWS_REGION")).unwrap_or_else(|| "us-east-1".to_string());
+        Some(Self {
+            endpoint: var("FORM_BACKUP_S3_ENDPOINT").unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com")),
+            bucket,
+            region,
+            prefix: var("FORM_BACKUP_S3_PREFIX").unwrap_or_default(),
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+        })
+    }
+
+    /// Uploads `body` as `name` with a SigV4 signed PUT
+    pub async fn upload(&self, client: &reqwest::Client, name: &str, body: Vec<u8>) -> Result<(), BackupError> {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let url = url::Url::parse(&self.endpoint).map_err(|e| BackupError::Upload(e.to_string()))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let path = format!("/{}/{}{}", self.bucket, self.prefix, name);
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let canonical_request = format!(
+            "PUT\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = hmac_sha256(format!("AWS4{}", self.secret_access_key).as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        let resp = client.put(format!("{}{path}", self.endpoint.trim_end_matches('/')))
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("authorization", format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                self.access_key_id
+            ))
+            .body(body)
+            .send().await
+            .map_err(|e| BackupError::Upload(e.to_string()))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            return Err(BackupError::Upload(format!("{status}: {}", resp.text().await.unwrap_or_default())));
+        }
+        Ok(())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Configuration for scheduled backups
+#[derive(Clone, Debug)]
+pub struct BackupScheduleConfig {
+    pub key: BackupKey,
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// Number of local backups to keep
+    pub retain: usize,
+    pub s3: Option<S3Target>,
+}
+
+impl BackupScheduleConfig {
+    /// Builds the schedule from `FORM_BACKUP_*` environment variables.
+    /// Returns `None` unless `FORM_BACKUP_DIR` and a valid `FORM_BACKUP_KEY`
+    /// are set.
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var("FORM_BACKUP_DIR").ok().filter(|s| !s.is_empty())?;
+        let key = match BackupKey::from_env() {
+            Ok(key) => key,
+            Err(e) => {
+                log::error!("Scheduled backups are disabled: {e}");
+                return None;
+            }
+        };
+        let parse = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        Some(Self {
+            key,
+            dir: PathBuf::from(dir),
+            interval: Duration::from_secs(parse("FORM_BACKUP_INTERVAL_SECS").unwrap_or(86_400).max(60)),
+            retain: parse("FORM_BACKUP_RETAIN").unwrap_or(7).max(1) as usize,
+            s3: S3Target::from_env(),
+        })
+    }
+}
+
+/// File name of a backup made at `created_at`, sortable by age
+pub fn backup_file_name(created_at: i64) -> String {
+    format!("{BACKUP_FILE_PREFIX}{created_at:012}.json")
+}
+
+/// Backup files in `files` beyond the newest `retain`
+pub fn expired_backups(mut files: Vec<String>, retain: usize) -> Vec<String> {
+    files.retain(|name| name.starts_with(BACKUP_FILE_PREFIX));
+    files.sort();
+    let keep = files.len().saturating_sub(retain);
+    files.truncate(keep);
+    files
+}
+
+async fn run_scheduled_backup(
+    datastore: &Arc<Mutex<DataStore>>,
+    config: &BackupScheduleConfig,
+    client: &reqwest::Client,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let archive = create_backup(datastore, &config.key).await?;
+    let name = backup_file_name(archive.header.created_at);
+    let body = serde_json::to_vec(&archive)?;
+
+    tokio::fs::create_dir_all(&config.dir).await?;
+    tokio::fs::write(config.dir.join(&name), &body).await?;
+
+    let mut files = Vec::new();
+    let mut dir = tokio::fs::read_dir(&config.dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        files.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    for expired in expired_backups(files, config.retain) {
+        if let Err(e) = tokio::fs::remove_file(config.dir.join(&expired)).await {
+            log::warn!("Unable to remove expired backup {expired}: {e}");
+        }
+    }
+
+    if let Some(s3) = &config.s3 {
+        s3.upload(client, &name, body).await?;
+    }
+    Ok(name)
+}
+
+/// Writes a backup every `interval`, pruning old ones and uploading each to S3 if configured
+pub async fn run_backup_scheduler(
+    datastore: Arc<Mutex<DataStore>>,
+    config: BackupScheduleConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!(
+        "Starting backup scheduler: every {:?} to {}, keeping {}{}",
+        config.interval, config.dir.display(), config.retain,
+        config.s3.as_ref().map(|s3| format!(", uploading to s3://{}/{}", s3.bucket, s3.prefix)).unwrap_or_default()
+    );
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match run_scheduled_backup(&datastore, &config, &client).await {
+                    Ok(name) => log::info!("Wrote backup {name}"),
+                    Err(e) => log::error!("Scheduled backup failed: {e}"),
+                }
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_round_trip() {
+        let signing_key = SigningKey::from_slice(&[5u8; 32]).unwrap();
+        let pk = hex::encode(signing_key.to_bytes());
+        let node = hex::encode(Address::from_private_key(&signing_key));
+        let state: MergeableState = DataStore::new(node.clone(), pk).into();
+
+        let key = BackupKey::from_hex(&hex::encode([1u8; 32])).unwrap();
+        let archive = BackupArchive::seal(&state, &key, &signing_key, 1_700_000_000).unwrap();
+        let archive: BackupArchive = serde_json::from_slice(&serde_json::to_vec(&archive).unwrap()).unwrap();
+
+        let restored = archive.open(&key, |signer| signer == node).unwrap();
+        assert!(diff_states(&state, &restored).unwrap().is_empty());
+
+        let other_key = BackupKey::from_hex(&hex::encode([2u8; 32])).unwrap();
+        assert!(matches!(archive.open(&other_key, |_| true), Err(BackupError::WrongKey { .. })));
+        assert!(matches!(archive.open(&key, |_| false), Err(BackupError::UntrustedSigner(_))));
+
+        let mut tampered = archive.clone();
+        tampered.header.created_at += 1;
+        assert!(matches!(tampered.open(&key, |_| true), Err(BackupError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn test_expired_backups() {
+        let files = vec![
+            backup_file_name(3),
+            "notes.txt".to_string(),
+            backup_file_name(1),
+            backup_file_name(2),
+        ];
+        assert_eq!(expired_backups(files, 2), vec![backup_file_name(1)]);
+        assert!(expired_backups(vec![backup_file_name(1)], 2).is_empty());
+    }
+}
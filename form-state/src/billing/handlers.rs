@@ -4,9 +4,11 @@
 //! 1. Checking subscription status
 //! 2. Managing credits
 //! 3. Viewing usage statistics
+//! 4. Managing billing webhooks
+//! 5. Scheduling usage reports
 
 use axum::{
-    extract::{State, Json},
+    extract::{State, Json, Path},
     http::StatusCode,
     response::IntoResponse,
 };
@@ -18,6 +20,11 @@ use serde_json::json;
 use crate::datastore::DataStore;
 use crate::billing::{SubscriptionInfo, SubscriptionStatus, SubscriptionTier};
 use crate::auth::RecoveredAddress;
+use crate::billing::middleware::{check_operation_credits, EligibilityError, OperationType};
+use crate::billing::stripe::{self, StripeEvent, StripeSubscription};
+use crate::billing::webhooks::{self, WebhookEndpoint, WebhookEventType};
+use crate::billing::reports::{self, ReportSubscription, ReportSubscriptionRequest, SmtpConfig};
+use crate::usage_rollups::normalize_account_id;
 
 /// Response for usage statistics
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -128,6 +135,10 @@ pub struct ApiProcessStripeCheckoutSession {
     
     /// Credits to add (optional)
     pub credits_added: Option<u64>,
+
+    /// Invoice issued for the session (optional)
+    #[serde(default)]
+    pub invoice: Option<ApiInvoice>,
 }
 
 /// Request for verifying subscription
@@ -330,22 +341,92 @@ pub async fn verify_subscription(
     )
 }
 
-/// Handler for webhook events from Stripe
+/// Handler for webhook events from Stripe. Only non-2xx responses are
+/// retried by Stripe, so events that can't be applied yet, like one for an
+/// account that doesn't exist yet, aren't recorded as processed.
 pub async fn stripe_webhook(
     State(state): State<Arc<Mutex<DataStore>>>,
     headers: axum::http::HeaderMap,
-    body: String,
+    body: axum::body::Bytes,
 ) -> impl IntoResponse {
-    // In the new architecture, webhook handling happens in the frontend
-    // This endpoint just receives the final processed data
-    
-    // For now just return a success response
+    let failure = |status: StatusCode, error: String| (status, Json(json!({ "success": false, "error": error })));
+
+    let Some(secret) = std::env::var("STRIPE_WEBHOOK_SECRET").ok().filter(|secret| !secret.is_empty()) else {
+        log::error!("Received a Stripe webhook but STRIPE_WEBHOOK_SECRET is not set");
+        return failure(StatusCode::SERVICE_UNAVAILABLE, "Stripe webhooks are not configured".to_string());
+    };
+    let signature = headers.get("Stripe-Signature").and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if let Err(e) = stripe::verify_signature(&secret, signature, &body, chrono::Utc::now().timestamp()) {
+        log::warn!("Rejected Stripe webhook: {e}");
+        return failure(StatusCode::BAD_REQUEST, e.to_string());
+    }
+    let event: StripeEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, format!("Invalid Stripe event: {e}")),
+    };
+
+    let mut datastore = state.lock().await;
+    if datastore.stripe_events.is_processed(&event.id) {
+        log::info!("Stripe event {} was already processed", event.id);
+        return (StatusCode::OK, Json(json!({ "success": true, "duplicate": true })));
+    }
+    if !event.is_subscription_lifecycle() {
+        log::debug!("Ignoring Stripe event {} of type {}", event.id, event.event_type);
+        datastore.stripe_events.record(&event.id);
+        stripe::persist_event_log(&datastore.stripe_events);
+        return (StatusCode::OK, Json(json!({ "success": true, "ignored": true })));
+    }
+
+    let subscription: StripeSubscription = match serde_json::from_value(event.data.object.clone()) {
+        Ok(subscription) => subscription,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, format!("Invalid Stripe subscription: {e}")),
+    };
+    if datastore.stripe_events.is_stale(&subscription.id, event.created) {
+        log::info!("Skipping Stripe event {}, a newer one was applied to {}", event.id, subscription.id);
+        datastore.stripe_events.record(&event.id);
+        stripe::persist_event_log(&datastore.stripe_events);
+        return (StatusCode::OK, Json(json!({ "success": true, "stale": true })));
+    }
+
+    let account = match subscription.account_id() {
+        Some(account_id) => datastore.account_state.get_account(account_id),
+        None => datastore.account_state.list_accounts().into_iter().find(|account| {
+            account.subscription.as_ref().and_then(|s| s.stripe_customer_id.as_deref()) == Some(subscription.customer.as_str())
+        }),
+    };
+    let Some(mut account) = account else {
+        log::warn!("No account for Stripe subscription {} of customer {}", subscription.id, subscription.customer);
+        return failure(StatusCode::NOT_FOUND, "Account not found".to_string());
+    };
+    let info = match subscription.apply_to(account.subscription.as_ref()) {
+        Ok(info) => info,
+        Err(e) => {
+            log::error!("Unable to apply Stripe event {}: {e}", event.id);
+            return failure(StatusCode::UNPROCESSABLE_ENTITY, e.to_string());
+        }
+    };
+
+    log::info!(
+        "Stripe event {} sets the subscription of {} to {:?} {:?}",
+        event.id, account.address, info.tier, info.status
+    );
+    account.subscription = Some(info.clone());
+    account.updated_at = chrono::Utc::now().timestamp();
+    let op = datastore.account_state.update_account_local(account.clone());
+    if let Err(err) = datastore.handle_account_op(op).await {
+        log::error!("Failed to update account: {}", err);
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, "Failed to update account".to_string());
+    }
+    datastore.stripe_events.record_applied(&event.id, &subscription.id, event.created);
+    stripe::persist_event_log(&datastore.stripe_events);
+
     (
-        StatusCode::OK, 
+        StatusCode::OK,
         Json(json!({
             "success": true,
-            "received": true,
-            "message": "Webhook processing should be done in frontend"
+            "account": account.address,
+            "tier": info.tier,
+            "status": info.status,
         }))
     )
 }
@@ -375,6 +456,10 @@ pub async fn process_stripe_checkout_session(
     
     // In the new architecture, checkout processing happens in the frontend
     // This endpoint just receives the processed data
+
+    if let Some(invoice) = &request.invoice {
+        emit_invoice_created(&mut datastore, &request.account_id, invoice);
+    }
     
     // Example update for subscription
     if let Some(subscription_info) = request.subscription_info {
@@ -427,6 +512,16 @@ pub async fn process_stripe_checkout_session(
         );
     }
     
+    if request.invoice.is_some() {
+        return (
+            StatusCode::OK,
+            Json(json!({
+                "success": true,
+                "message": "Invoice recorded"
+            }))
+        );
+    }
+
     // If neither subscription nor credits were provided
     (
         StatusCode::BAD_REQUEST,
@@ -435,4 +530,418 @@ pub async fn process_stripe_checkout_session(
             "error": "No subscription or credits data provided"
         }))
     )
-} 
\ No newline at end of file
+}
+
+/// Pre-flight eligibility check made by a network service (e.g. form-mcp)
+/// on behalf of an account before performing a billable operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EligibilityCheckRequest {
+    /// Name of the operation being checked (e.g. the tool name)
+    pub operation: String,
+
+    /// Credits the operation is expected to cost
+    pub credit_cost: u64,
+
+    /// Model the operation will use, if any
+    #[serde(default)]
+    pub model_id: Option<String>,
+
+    /// Estimated input tokens for model operations
+    #[serde(default)]
+    pub estimated_input_tokens: u64,
+
+    /// Estimated output tokens for model operations
+    #[serde(default)]
+    pub estimated_output_tokens: u64,
+}
+
+/// Response for a successful eligibility check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EligibilityCheckResponse {
+    /// Whether the operation may proceed
+    pub eligible: bool,
+
+    /// Credits available to the account
+    pub available_credits: u64,
+
+    /// Subscription tier of the account, if subscribed
+    pub tier: Option<SubscriptionTier>,
+}
+
+/// Usage of a billable operation reported by a network service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeterUsageRequest {
+    /// Service reporting the usage (e.g. "form-mcp")
+    pub source: String,
+
+    /// Name of the operation that was performed
+    pub operation: String,
+
+    /// Credits consumed by the operation
+    pub credits: u64,
+
+    /// Model used by the operation, if any
+    #[serde(default)]
+    pub model_id: Option<String>,
+
+    /// Input tokens consumed
+    #[serde(default)]
+    pub input_tokens: u64,
+
+    /// Output tokens consumed
+    #[serde(default)]
+    pub output_tokens: u64,
+
+    /// Instance the usage was incurred on, usage of organization owned
+    /// instances is charged to the organization's billing account
+    #[serde(default)]
+    pub instance_id: Option<String>,
+}
+
+/// Returns an error if the account's subscription does not allow usage
+fn check_subscription_active(account: &crate::accounts::Account) -> Result<(), EligibilityError> {
+    if let Some(subscription) = &account.subscription {
+        match subscription.status {
+            SubscriptionStatus::Active | SubscriptionStatus::Trial => {}
+            SubscriptionStatus::PastDue => {
+                log::warn!("Account {} has past due subscription", account.address);
+            }
+            _ => return Err(EligibilityError::InactiveSubscription),
+        }
+    }
+    Ok(())
+}
+
+/// Handler for checking whether an account can perform a billable operation
+///
+/// Checks subscription status, credits remaining, tier limits and model access.
+/// Failures are returned as structured `EligibilityError` responses.
+pub async fn check_account_eligibility(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(address): Path<String>,
+    Json(request): Json<EligibilityCheckRequest>,
+) -> Result<Json<EligibilityCheckResponse>, EligibilityError> {
+    let datastore = state.lock().await;
+    let account = datastore.account_state.get_account(&address)
+        .ok_or_else(|| EligibilityError::AccountNotFound(address.clone()))?;
+
+    check_subscription_active(&account)?;
+
+    if let Some(model_id) = &request.model_id {
+        check_operation_credits(&account, OperationType::TokenConsumption {
+            model_id: model_id.clone(),
+            input_tokens: request.estimated_input_tokens,
+            output_tokens: request.estimated_output_tokens,
+        })?;
+    }
+
+    check_operation_credits(&account, OperationType::Custom {
+        name: request.operation.clone(),
+        credit_cost: request.credit_cost,
+    })?;
+
+    Ok(Json(EligibilityCheckResponse {
+        eligible: true,
+        available_credits: account.available_credits(),
+        tier: account.subscription.as_ref().map(|s| s.tier),
+    }))
+}
+
+/// Handler for metering usage of a billable operation against an account
+pub async fn meter_account_usage(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(address): Path<String>,
+    Json(request): Json<MeterUsageRequest>,
+) -> Result<Json<serde_json::Value>, EligibilityError> {
+    let mut datastore = state.lock().await;
+    let org_billing_account = request.instance_id.as_deref()
+        .and_then(|id| datastore.organization_state.billing_account_for_instance(id));
+    let address = match org_billing_account {
+        Some(billing_account) => {
+            log::info!("Charging usage by {} to organization billing account {}", address, billing_account);
+            billing_account
+        }
+        None => address,
+    };
+    // Organization billing accounts are stored without the 0x prefix
+    let mut account = datastore.account_state.get_account(&address)
+        .or_else(|| datastore.account_state.get_account(&format!("0x{}", address)))
+        .ok_or_else(|| EligibilityError::AccountNotFound(address.clone()))?;
+
+    if let Some(model_id) = &request.model_id {
+        account.usage_tracker().record_token_usage(model_id, request.input_tokens, request.output_tokens);
+    }
+
+    // Subscription credits cover usage within the period, pay-as-you-go credits are deducted
+    let subscribed = account.subscription.as_ref().is_some_and(|s| {
+        matches!(s.status, SubscriptionStatus::Active | SubscriptionStatus::Trial | SubscriptionStatus::PastDue)
+    });
+    if !subscribed && request.credits > 0 && !account.deduct_credits(request.credits) {
+        return Err(EligibilityError::InsufficientCredits {
+            required: request.credits,
+            available: account.available_credits(),
+        });
+    }
+
+    let op = datastore.account_state.update_account_local(account.clone());
+    if let Err(e) = datastore.handle_account_op(op).await {
+        log::error!("Failed to record usage for account {}: {}", address, e);
+        return Err(EligibilityError::DatabaseError(e.to_string()));
+    }
+
+    log::info!(
+        "Metered {} credits for {} ({}) on account {}",
+        request.credits, request.operation, request.source, address
+    );
+
+    Ok(Json(json!({
+        "success": true,
+        "credits_charged": request.credits,
+        "available_credits": account.available_credits()
+    })))
+}
+
+
+/// Request for registering a billing webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiRegisterWebhook {
+    /// URL the events are POSTed to
+    pub url: String,
+
+    /// Account to receive events for, every account if omitted (admins only)
+    #[serde(default)]
+    pub account: Option<String>,
+
+    /// Events to receive, all events if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEventType>,
+
+    /// Credit balances that trigger `credits.threshold` when crossed
+    #[serde(default)]
+    pub credit_thresholds: Vec<u64>,
+}
+
+/// An invoice issued for an account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiInvoice {
+    /// Invoice ID in the payment provider
+    pub invoice_id: String,
+
+    /// Amount due in the smallest currency unit
+    pub amount_due: u64,
+
+    /// ISO currency code, e.g. "usd"
+    pub currency: String,
+
+    /// Link to the hosted invoice (optional)
+    #[serde(default)]
+    pub hosted_invoice_url: Option<String>,
+}
+
+fn webhook_error(status: StatusCode, error: impl Into<String>) -> (StatusCode, Json<serde_json::Value>) {
+    (status, Json(json!({ "success": false, "error": error.into() })))
+}
+
+/// Whether `caller` may manage webhooks scoped to `account`, None being global
+fn can_manage_webhooks(datastore: &DataStore, caller: &str, account: Option<&str>) -> bool {
+    datastore.network_state.is_admin_address(caller)
+        || account.is_some_and(|account| normalize_account_id(account) == normalize_account_id(caller))
+}
+
+/// Handler for registering a billing webhook. The signing secret is only
+/// returned here.
+pub async fn register_webhook(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<ApiRegisterWebhook>,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let mut datastore = state.lock().await;
+    if !can_manage_webhooks(&datastore, &caller, request.account.as_deref()) {
+        return webhook_error(StatusCode::FORBIDDEN, "Only admins can register webhooks for other or all accounts");
+    }
+    match reqwest::Url::parse(&request.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => return webhook_error(StatusCode::BAD_REQUEST, format!("{} is not an http(s) URL", request.url)),
+    }
+
+    let endpoint = datastore.webhooks.register(
+        request.url,
+        request.account,
+        request.events,
+        request.credit_thresholds,
+        caller,
+        chrono::Utc::now().timestamp(),
+    );
+    webhooks::persist(&datastore.webhooks);
+    log::info!("Registered billing webhook {} for {}", endpoint.id, endpoint.account.as_deref().unwrap_or("all accounts"));
+
+    (StatusCode::OK, Json(json!({ "success": true, "webhook": endpoint })))
+}
+
+/// Handler for listing the webhooks the caller manages, without secrets
+pub async fn list_webhooks(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let datastore = state.lock().await;
+    let webhooks: Vec<WebhookEndpoint> = datastore.webhooks.endpoints.values()
+        .filter(|endpoint| can_manage_webhooks(&datastore, &caller, endpoint.account.as_deref()))
+        .map(WebhookEndpoint::redacted)
+        .collect();
+
+    (StatusCode::OK, Json(json!({ "success": true, "webhooks": webhooks })))
+}
+
+/// Handler for removing a webhook and its undelivered events
+pub async fn delete_webhook(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let mut datastore = state.lock().await;
+    let Some(account) = datastore.webhooks.endpoints.get(&id).map(|endpoint| endpoint.account.clone()) else {
+        return webhook_error(StatusCode::NOT_FOUND, format!("Webhook {id} not found"));
+    };
+    if !can_manage_webhooks(&datastore, &caller, account.as_deref()) {
+        return webhook_error(StatusCode::FORBIDDEN, "Not allowed to remove this webhook");
+    }
+    datastore.webhooks.remove(&id);
+    webhooks::persist(&datastore.webhooks);
+
+    (StatusCode::OK, Json(json!({ "success": true })))
+}
+
+/// Handler for a webhook's delivery log, newest first
+pub async fn list_webhook_deliveries(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let datastore = state.lock().await;
+    let Some(endpoint) = datastore.webhooks.endpoints.get(&id) else {
+        return webhook_error(StatusCode::NOT_FOUND, format!("Webhook {id} not found"));
+    };
+    if !can_manage_webhooks(&datastore, &caller, endpoint.account.as_deref()) {
+        return webhook_error(StatusCode::FORBIDDEN, "Not allowed to view this webhook");
+    }
+    let pending = datastore.webhooks.pending.iter().filter(|delivery| delivery.endpoint_id == id).count();
+
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "pending": pending,
+        "deliveries": datastore.webhooks.deliveries_for(&id)
+    })))
+}
+
+/// Handler for recording an invoice issued to an account, which notifies
+/// the `invoice.created` webhooks. Admins only.
+pub async fn record_invoice(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(address): Path<String>,
+    Json(invoice): Json<ApiInvoice>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    if !datastore.network_state.is_admin_address(&recovered.as_hex()) {
+        return webhook_error(StatusCode::FORBIDDEN, "Only admins can record invoices");
+    }
+    if datastore.account_state.get_account(&address).is_none() {
+        return webhook_error(StatusCode::NOT_FOUND, format!("Account with address {address} not found"));
+    }
+    emit_invoice_created(&mut datastore, &address, &invoice);
+
+    (StatusCode::OK, Json(json!({ "success": true })))
+}
+
+fn emit_invoice_created(datastore: &mut DataStore, address: &str, invoice: &ApiInvoice) {
+    datastore.webhooks.emit(
+        WebhookEventType::InvoiceCreated,
+        address,
+        json!(invoice),
+        chrono::Utc::now().timestamp(),
+    );
+    webhooks::persist(&datastore.webhooks);
+}
+
+/// Handler for subscribing the caller's account to a scheduled report
+pub async fn create_report(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<ReportSubscriptionRequest>,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let mut datastore = state.lock().await;
+    if datastore.account_state.get_account(&caller).is_none() {
+        return webhook_error(StatusCode::NOT_FOUND, format!("Account with address {caller} not found"));
+    }
+    if request.email.is_some() && SmtpConfig::from_env().is_none() {
+        return webhook_error(StatusCode::BAD_REQUEST, "This node has no SMTP relay configured for email reports");
+    }
+    let subscription = match ReportSubscription::new(&caller, request, chrono::Utc::now().timestamp()) {
+        Ok(subscription) => subscription,
+        Err(e) => return webhook_error(StatusCode::BAD_REQUEST, e),
+    };
+    if let Err(e) = datastore.reports.add(subscription.clone()) {
+        return webhook_error(StatusCode::BAD_REQUEST, e);
+    }
+    reports::persist(&datastore.reports);
+    log::info!("Scheduled report {} ({}) for {}", subscription.id, subscription.schedule, caller);
+
+    (StatusCode::OK, Json(json!({ "success": true, "report": subscription })))
+}
+
+/// Handler for listing the caller's scheduled reports
+pub async fn list_reports(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let datastore = state.lock().await;
+    let reports: Vec<ReportSubscription> = datastore.reports.for_account(&caller).into_iter().cloned().collect();
+
+    (StatusCode::OK, Json(json!({ "success": true, "reports": reports })))
+}
+
+/// Handler for unsubscribing from a scheduled report
+pub async fn delete_report(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let mut datastore = state.lock().await;
+    let Some(account) = datastore.reports.subscriptions.get(&id).map(|subscription| subscription.account.clone()) else {
+        return webhook_error(StatusCode::NOT_FOUND, format!("Report {id} not found"));
+    };
+    if !can_manage_webhooks(&datastore, &caller, Some(&account)) {
+        return webhook_error(StatusCode::FORBIDDEN, "Not allowed to remove this report");
+    }
+    datastore.reports.remove(&id);
+    reports::persist(&datastore.reports);
+
+    (StatusCode::OK, Json(json!({ "success": true })))
+}
+
+/// Handler rendering a scheduled report for the window ending now, without
+/// delivering it
+pub async fn preview_report(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let caller = recovered.as_hex();
+    let datastore = state.lock().await;
+    let Some(subscription) = datastore.reports.subscriptions.get(&id) else {
+        return webhook_error(StatusCode::NOT_FOUND, format!("Report {id} not found"));
+    };
+    if !can_manage_webhooks(&datastore, &caller, Some(&subscription.account)) {
+        return webhook_error(StatusCode::FORBIDDEN, "Not allowed to view this report");
+    }
+    let report = reports::build_report(&datastore, subscription, chrono::Utc::now().timestamp());
+
+    (StatusCode::OK, Json(json!({ "success": true, "report": report })))
+}
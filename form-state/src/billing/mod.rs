@@ -13,6 +13,8 @@ use std::collections::{BTreeMap, HashMap};
 pub mod stripe;
 pub mod handlers;
 pub mod middleware;
+pub mod webhooks;
+pub mod reports;
 
 /// Subscription tier levels
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, PartialOrd, Ord)]
@@ -115,6 +117,23 @@ pub struct SubscriptionQuota {
     pub premium_agent_access: bool,
 }
 
+impl std::str::FromStr for SubscriptionTier {
+    type Err = String;
+
+    /// Reads `pro_plus`, `ProPlus`, `pro-plus` and the like
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name: String = s.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+        match name.as_str() {
+            "free" => Ok(Self::Free),
+            "pro" => Ok(Self::Pro),
+            "proplus" => Ok(Self::ProPlus),
+            "power" => Ok(Self::Power),
+            "powerplus" => Ok(Self::PowerPlus),
+            _ => Err(format!("Unknown subscription tier {s}")),
+        }
+    }
+}
+
 impl Default for SubscriptionTier {
     fn default() -> Self {
         Self::Free
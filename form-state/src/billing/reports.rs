@@ -0,0 +1,715 @@
+//! Scheduled account reports
+//!
+//! Accounts opt in to periodic summaries of their usage, billing state and
+//! instance uptime. Each subscription has a cron schedule and a window the
+//! report covers, and is delivered as a `report.generated` event to the
+//! account's billing webhooks, by email, or both.
+//!
+//! Like webhooks, subscriptions live on the node they were created on, which
+//! renders them from its own usage rollups. Email goes through an SMTP relay
+//! configured with `FORM_SMTP_HOST`; the relay is trusted to handle TLS and
+//! authentication towards the outside, so it should be local to the node.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Datelike, NaiveDate, Timelike};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use crate::billing::{SubscriptionStatus, SubscriptionTier};
+use crate::billing::webhooks::{self, WebhookEventType};
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::instances::{Instance, InstanceStatus};
+use crate::usage_rollups::{normalize_account_id, RollupPeriod, RollupQuery, UsageTotals};
+
+/// Key under which the report subscriptions are persisted
+pub const REPORTS_DB_KEY: &str = "billing/reports";
+
+/// Report subscriptions an account may hold
+pub const MAX_REPORTS_PER_ACCOUNT: usize = 10;
+
+const DEFAULT_TEMPLATE: &str = "\
+Formation report for {{account}}
+{{period_start}} to {{period_end}}
+
+Usage
+  CPU: {{cpu_hours}} hours
+  GPU: {{gpu_hours}} hours
+  RAM: {{ram_gb_hours}} GB-hours
+  Disk: {{disk_gb_months}} GB-months
+  Egress: {{egress_gb}} GB
+
+Billing
+  Tier: {{tier}} ({{subscription_status}})
+  Available credits: {{credits}}
+
+Uptime
+{{uptime}}
+";
+
+/// A five field cron expression, `minute hour day-of-month month day-of-week`
+/// in UTC, or one of `@hourly`, `@daily`, `@weekly` and `@monthly`. Fields
+/// take `*`, numbers, ranges, lists and `/step`s. Like cron, a day matches if
+/// either day field does when both are restricted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: BTreeSet<u32>,
+    hours: BTreeSet<u32>,
+    days_of_month: BTreeSet<u32>,
+    months: BTreeSet<u32>,
+    days_of_week: BTreeSet<u32>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<BTreeSet<u32>, String> {
+    let mut values = BTreeSet::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("Invalid step in {part}"))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("Step can't be 0 in {part}"));
+        }
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| format!("Invalid value in {part}"))?,
+                    end.parse().map_err(|_| format!("Invalid value in {part}"))?,
+                ),
+                None => {
+                    let value = range.parse().map_err(|_| format!("Invalid value in {part}"))?;
+                    // `5/10` runs from 5 to the end of the range
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("{part} is outside {min}-{max}"));
+        }
+        values.extend((start..=end).step_by(step as usize));
+    }
+    Ok(values)
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 1",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(format!("{expression} doesn't have 5 fields"));
+        };
+        // Sunday is both 0 and 7
+        let days_of_week = parse_field(day_of_week, 0, 7)?.into_iter().map(|day| day % 7).collect();
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days_of_month: parse_field(day_of_month, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            days_of_week,
+            any_day_of_month: day_of_month == "*",
+            any_day_of_week: day_of_week == "*",
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !self.months.contains(&date.month()) {
+            return false;
+        }
+        let day_of_month = self.days_of_month.contains(&date.day());
+        let day_of_week = self.days_of_week.contains(&date.weekday().num_days_from_sunday());
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+
+    /// The first time the schedule fires after `after`, searching up to five
+    /// years ahead so schedules like `0 0 29 2 *` are found
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let start = DateTime::from_timestamp(after - after.rem_euclid(60) + 60, 0)?.naive_utc();
+        let mut date = start.date();
+        for _ in 0..(5 * 366) {
+            if self.matches_day(date) {
+                for hour in &self.hours {
+                    for minute in &self.minutes {
+                        let time = date.and_hms_opt(*hour, *minute, 0)?;
+                        if time >= start {
+                            return Some(time.and_utc().timestamp());
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+/// What a report contains
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportSection {
+    Usage,
+    Billing,
+    Uptime,
+}
+
+/// The time a report covers, ending when it is generated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportWindow {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl ReportWindow {
+    pub fn seconds(&self) -> i64 {
+        match self {
+            ReportWindow::Day => 86_400,
+            ReportWindow::Week => 7 * 86_400,
+            ReportWindow::Month => 30 * 86_400,
+        }
+    }
+}
+
+fn all_sections() -> Vec<ReportSection> {
+    vec![ReportSection::Usage, ReportSection::Billing, ReportSection::Uptime]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// An account's opt-in to a periodic report
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportSubscription {
+    pub id: String,
+    pub account: String,
+    /// Cron expression, see `CronSchedule`
+    pub schedule: String,
+    pub window: ReportWindow,
+    pub sections: Vec<ReportSection>,
+    /// Deliver as a `report.generated` event to the account's webhooks
+    pub webhook: bool,
+    /// Address the report is emailed to
+    pub email: Option<String>,
+    /// Template the text of the report is rendered from, see `render`
+    pub template: Option<String>,
+    pub enabled: bool,
+    pub created_at: i64,
+    pub next_run: i64,
+    pub last_run: Option<i64>,
+    /// Why the last delivery failed
+    pub last_error: Option<String>,
+}
+
+/// Body of `POST /v1/billing/reports/create`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReportSubscriptionRequest {
+    pub schedule: String,
+    #[serde(default)]
+    pub window: ReportWindow,
+    #[serde(default = "all_sections")]
+    pub sections: Vec<ReportSection>,
+    #[serde(default = "default_true")]
+    pub webhook: bool,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+impl ReportSubscription {
+    pub fn new(account: &str, request: ReportSubscriptionRequest, now: i64) -> Result<Self, String> {
+        let schedule = CronSchedule::parse(&request.schedule)?;
+        let next_run = schedule.next_after(now).ok_or_else(|| format!("{} never fires", request.schedule))?;
+        if !request.webhook && request.email.is_none() {
+            return Err("A report needs a webhook or email delivery".to_string());
+        }
+        if let Some(email) = &request.email {
+            let valid = email.split_once('@').is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'))
+                && !email.contains(|c: char| c.is_whitespace() || c == '<' || c == '>');
+            if !valid {
+                return Err(format!("{email} is not an email address"));
+            }
+        }
+        if request.sections.is_empty() {
+            return Err("A report needs at least one section".to_string());
+        }
+        Ok(Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            account: account.to_string(),
+            schedule: request.schedule,
+            window: request.window,
+            sections: request.sections,
+            webhook: request.webhook,
+            email: request.email,
+            template: request.template,
+            enabled: true,
+            created_at: now,
+            next_run,
+            last_run: None,
+            last_error: None,
+        })
+    }
+
+    /// Moves `next_run` past `now`
+    fn reschedule(&mut self, now: i64) {
+        match CronSchedule::parse(&self.schedule).ok().and_then(|schedule| schedule.next_after(now)) {
+            Some(next_run) => self.next_run = next_run,
+            None => self.enabled = false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BillingSummary {
+    pub tier: Option<SubscriptionTier>,
+    pub status: Option<SubscriptionStatus>,
+    pub available_credits: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InstanceUptime {
+    pub instance_id: String,
+    /// Share of the window the instance was `Ready`, None if it didn't exist
+    pub uptime_percent: Option<f64>,
+    pub status: InstanceStatus,
+}
+
+/// A rendered report, the data of the `report.generated` event
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub subscription_id: String,
+    pub account: String,
+    pub period_start: i64,
+    pub period_end: i64,
+    pub usage: Option<UsageTotals>,
+    pub billing: Option<BillingSummary>,
+    pub uptime: Option<Vec<InstanceUptime>>,
+    pub text: String,
+}
+
+/// Share of `from..to` the instance spent `Ready`, going by its status history
+pub fn uptime_percent(instance: &Instance, from: i64, to: i64) -> Option<f64> {
+    let start = from.max(instance.created_at);
+    if to <= start {
+        return None;
+    }
+    let mut status = instance.status_history.first()
+        .map(|transition| transition.from.clone())
+        .unwrap_or_else(|| instance.status.clone());
+    let mut since = start;
+    let mut ready = 0;
+    for transition in &instance.status_history {
+        let at = transition.at.clamp(start, to);
+        if status == InstanceStatus::Ready {
+            ready += at - since;
+        }
+        since = at;
+        status = transition.to.clone();
+    }
+    if status == InstanceStatus::Ready {
+        ready += to - since;
+    }
+    Some(ready as f64 * 100.0 / (to - start) as f64)
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| format!("{}-{:02}-{:02} {:02}:{:02} UTC", time.year(), time.month(), time.day(), time.hour(), time.minute()))
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Fills a template's `{{placeholders}}` from the report. Placeholders of
+/// sections the report doesn't have render as `n/a`.
+pub fn render(template: &str, report: &Report) -> String {
+    let na = || "n/a".to_string();
+    let usage = |value: fn(&UsageTotals) -> String| report.usage.as_ref().map(value).unwrap_or_else(na);
+    let mut values: BTreeMap<&str, String> = BTreeMap::new();
+    values.insert("account", report.account.clone());
+    values.insert("period_start", format_time(report.period_start));
+    values.insert("period_end", format_time(report.period_end));
+    values.insert("cpu_hours", usage(|u| format!("{:.2}", u.cpu_seconds as f64 / 3_600.0)));
+    values.insert("gpu_hours", usage(|u| format!("{:.2}", u.gpu_seconds as f64 / 3_600.0)));
+    values.insert("ram_gb_hours", usage(|u| format!("{:.2}", u.ram_gb_hours)));
+    values.insert("disk_gb_months", usage(|u| format!("{:.4}", u.disk_gb_months)));
+    values.insert("egress_gb", usage(|u| format!("{:.3}", u.egress_bytes as f64 / 1_073_741_824.0)));
+    values.insert("credits", report.billing.as_ref().map(|b| b.available_credits.to_string()).unwrap_or_else(na));
+    values.insert("tier", report.billing.as_ref().and_then(|b| b.tier).map(|tier| format!("{tier:?}")).unwrap_or_else(na));
+    values.insert("subscription_status", report.billing.as_ref().and_then(|b| b.status).map(|status| format!("{status:?}")).unwrap_or_else(na));
+    values.insert("uptime", match &report.uptime {
+        Some(instances) if instances.is_empty() => "  No instances".to_string(),
+        Some(instances) => instances.iter()
+            .map(|instance| match instance.uptime_percent {
+                Some(percent) => format!("  {}: {:.2}% ({})", instance.instance_id, percent, instance.status),
+                None => format!("  {}: n/a ({})", instance.instance_id, instance.status),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => na(),
+    });
+
+    let mut text = template.to_string();
+    for (name, value) in values {
+        text = text.replace(&format!("{{{{{name}}}}}"), &value);
+    }
+    text
+}
+
+/// Renders a subscription's report for the window ending at `now`
+pub fn build_report(datastore: &DataStore, subscription: &ReportSubscription, now: i64) -> Report {
+    let from = now - subscription.window.seconds();
+    let wants = |section: ReportSection| subscription.sections.contains(&section);
+    let account = datastore.account_state.get_account(&subscription.account);
+
+    let usage = wants(ReportSection::Usage).then(|| {
+        datastore.usage_rollups.query(&RollupQuery {
+            period: RollupPeriod::All,
+            from: Some(from),
+            to: Some(now),
+            account_id: Some(subscription.account.clone()),
+            ..Default::default()
+        }).into_iter().fold(UsageTotals::default(), |mut totals, row| {
+            totals.add(&row.totals);
+            totals
+        })
+    });
+    let billing = wants(ReportSection::Billing).then(|| BillingSummary {
+        tier: account.as_ref().and_then(|a| a.subscription.as_ref()).map(|s| s.tier),
+        status: account.as_ref().and_then(|a| a.subscription.as_ref()).map(|s| s.status),
+        available_credits: account.as_ref().map_or(0, |a| a.available_credits()),
+    });
+    let uptime = wants(ReportSection::Uptime).then(|| {
+        datastore.instance_state.get_instances_by_owner(&subscription.account)
+            .into_iter()
+            .filter(|instance| instance.status != InstanceStatus::Deleted || instance.updated_at >= from)
+            .map(|instance| InstanceUptime {
+                uptime_percent: uptime_percent(&instance, from, now),
+                instance_id: instance.instance_id,
+                status: instance.status,
+            })
+            .collect()
+    });
+
+    let mut report = Report {
+        subscription_id: subscription.id.clone(),
+        account: subscription.account.clone(),
+        period_start: from,
+        period_end: now,
+        usage,
+        billing,
+        uptime,
+        text: String::new(),
+    };
+    report.text = render(subscription.template.as_deref().unwrap_or(DEFAULT_TEMPLATE), &report);
+    report
+}
+
+/// Report subscriptions of every account registered on this node
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportStore {
+    pub subscriptions: BTreeMap<String, ReportSubscription>,
+}
+
+impl ReportStore {
+    pub fn add(&mut self, subscription: ReportSubscription) -> Result<(), String> {
+        if self.for_account(&subscription.account).len() >= MAX_REPORTS_PER_ACCOUNT {
+            return Err(format!("An account can have at most {MAX_REPORTS_PER_ACCOUNT} reports"));
+        }
+        self.subscriptions.insert(subscription.id.clone(), subscription);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<ReportSubscription> {
+        self.subscriptions.remove(id)
+    }
+
+    pub fn for_account(&self, account: &str) -> Vec<&ReportSubscription> {
+        let account = normalize_account_id(account);
+        self.subscriptions.values()
+            .filter(|subscription| normalize_account_id(&subscription.account) == account)
+            .collect()
+    }
+
+    /// Subscriptions due at `now`, rescheduled to their next run
+    pub fn take_due(&mut self, now: i64) -> Vec<ReportSubscription> {
+        self.subscriptions.values_mut()
+            .filter(|subscription| subscription.enabled && subscription.next_run <= now)
+            .map(|subscription| {
+                subscription.last_run = Some(now);
+                subscription.reschedule(now);
+                subscription.clone()
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn persist(reports: &ReportStore) {
+    if let Err(e) = store_value(&DB_HANDLE, REPORTS_DB_KEY, reports) {
+        log::error!("Unable to persist report subscriptions: {e}");
+    }
+}
+
+/// Relay reports are emailed through
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+}
+
+impl SmtpConfig {
+    /// Reads `FORM_SMTP_HOST`, `FORM_SMTP_PORT` and `FORM_SMTP_FROM`, None
+    /// if no host is set
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("FORM_SMTP_HOST").ok().filter(|host| !host.is_empty())?;
+        Some(Self {
+            host,
+            port: std::env::var("FORM_SMTP_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(25),
+            from: std::env::var("FORM_SMTP_FROM").unwrap_or_else(|_| "reports@formation.cloud".to_string()),
+        })
+    }
+}
+
+async fn smtp_reply(reader: &mut BufReader<TcpStream>) -> Result<u16, String> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+            return Err("SMTP server closed the connection".to_string());
+        }
+        let code = line.get(..3).and_then(|code| code.parse().ok()).ok_or_else(|| format!("Invalid SMTP reply: {line}"))?;
+        // `250-` continues a multiline reply, `250 ` ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            if code >= 400 {
+                return Err(format!("SMTP server replied {}", line.trim_end()));
+            }
+            return Ok(code);
+        }
+    }
+}
+
+async fn smtp_command(reader: &mut BufReader<TcpStream>, command: &str) -> Result<u16, String> {
+    reader.get_mut().write_all(format!("{command}\r\n").as_bytes()).await.map_err(|e| e.to_string())?;
+    smtp_reply(reader).await
+}
+
+/// Sends a plain text email through the relay
+pub async fn send_email(config: &SmtpConfig, to: &str, subject: &str, body: &str) -> Result<(), String> {
+    let stream = tokio::time::timeout(Duration::from_secs(10), TcpStream::connect((config.host.as_str(), config.port)))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}", config.host))?
+        .map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+    smtp_reply(&mut reader).await?;
+    smtp_command(&mut reader, "EHLO formation").await?;
+    smtp_command(&mut reader, &format!("MAIL FROM:<{}>", config.from)).await?;
+    smtp_command(&mut reader, &format!("RCPT TO:<{to}>")).await?;
+    smtp_command(&mut reader, "DATA").await?;
+
+    let date = chrono::Utc::now().to_rfc2822();
+    // Lines starting with a dot are doubled so they don't end the message
+    let body: String = body.lines()
+        .map(|line| if line.starts_with('.') { format!(".{line}\r\n") } else { format!("{line}\r\n") })
+        .collect();
+    let message = format!(
+        "From: {}\r\nTo: {to}\r\nSubject: {subject}\r\nDate: {date}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{body}.",
+        config.from,
+    );
+    smtp_command(&mut reader, &message).await?;
+    let _ = smtp_command(&mut reader, "QUIT").await;
+    Ok(())
+}
+
+/// Configuration for the report scheduler
+#[derive(Clone, Debug)]
+pub struct ReportConfig {
+    pub poll_interval: Duration,
+    pub smtp: Option<SmtpConfig>,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(60),
+            smtp: None,
+        }
+    }
+}
+
+impl ReportConfig {
+    pub fn from_env() -> Self {
+        Self { smtp: SmtpConfig::from_env(), ..Default::default() }
+    }
+}
+
+/// Generates and delivers due reports until a shutdown signal is received
+pub async fn run_report_scheduler(
+    datastore: Arc<Mutex<DataStore>>,
+    config: ReportConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if config.smtp.is_none() {
+        log::info!("FORM_SMTP_HOST is not set, reports are only delivered to webhooks");
+    }
+    let mut interval = tokio::time::interval(config.poll_interval);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                run_due_reports(datastore.clone(), &config).await;
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders the due reports, queues their webhook events and sends their
+/// emails. The datastore isn't held while the emails are sent.
+pub async fn run_due_reports(datastore: Arc<Mutex<DataStore>>, config: &ReportConfig) {
+    let emails = {
+        let mut guard = datastore.lock().await;
+        let now = chrono::Utc::now().timestamp();
+        let due = guard.reports.take_due(now);
+        if due.is_empty() {
+            return;
+        }
+        let mut emails = Vec::new();
+        for subscription in due {
+            let report = build_report(&guard, &subscription, now);
+            if subscription.webhook {
+                let data = serde_json::to_value(&report).unwrap_or_else(|_| json!({}));
+                guard.webhooks.emit(WebhookEventType::ReportGenerated, &subscription.account, data, now);
+            }
+            if let Some(email) = subscription.email.clone() {
+                emails.push((subscription.id.clone(), email, report));
+            }
+        }
+        webhooks::persist(&guard.webhooks);
+        persist(&guard.reports);
+        emails
+    };
+
+    for (id, email, report) in emails {
+        let result = match &config.smtp {
+            Some(smtp) => {
+                let subject = format!("Formation report {} to {}", format_time(report.period_start), format_time(report.period_end));
+                send_email(smtp, &email, &subject, &report.text).await
+            }
+            None => Err("No SMTP relay is configured".to_string()),
+        };
+        if let Err(e) = &result {
+            log::warn!("Unable to email report {id} to {email}: {e}");
+        }
+        let mut guard = datastore.lock().await;
+        if let Some(subscription) = guard.reports.subscriptions.get_mut(&id) {
+            subscription.last_error = result.err();
+            persist(&guard.reports);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::StatusTransition;
+
+    // 2024-01-01 00:00 UTC, a Monday
+    const MONDAY: i64 = 1_704_067_200;
+
+    #[test]
+    fn test_cron_schedules() {
+        let daily = CronSchedule::parse("30 6 * * *").unwrap();
+        assert_eq!(daily.next_after(MONDAY), Some(MONDAY + 6 * 3_600 + 1_800));
+        assert_eq!(daily.next_after(MONDAY + 6 * 3_600 + 1_800), Some(MONDAY + 86_400 + 6 * 3_600 + 1_800));
+
+        let weekly = CronSchedule::parse("@weekly").unwrap();
+        assert_eq!(weekly.next_after(MONDAY), Some(MONDAY + 7 * 86_400));
+        let weekdays = CronSchedule::parse("0 9 * * 1-5/2").unwrap();
+        // Monday, Wednesday and Friday
+        assert_eq!(weekdays.next_after(MONDAY + 10 * 3_600), Some(MONDAY + 2 * 86_400 + 9 * 3_600));
+        let sunday = CronSchedule::parse("0 0 * * 7").unwrap();
+        assert_eq!(sunday.next_after(MONDAY), Some(MONDAY + 6 * 86_400));
+
+        assert!(CronSchedule::parse("* * *").is_err());
+        assert!(CronSchedule::parse("61 * * * *").is_err());
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert_eq!(CronSchedule::parse("0 0 30 2 *").unwrap().next_after(MONDAY), None);
+    }
+
+    #[test]
+    fn test_uptime_and_rendering() {
+        let transition = |from, to, at| StatusTransition { from, to, at };
+        let instance = Instance {
+            instance_id: "vm".to_string(),
+            created_at: MONDAY - 86_400,
+            status: InstanceStatus::Ready,
+            status_history: vec![
+                transition(InstanceStatus::Booting, InstanceStatus::Ready, MONDAY - 3_600),
+                transition(InstanceStatus::Ready, InstanceStatus::Stopped, MONDAY + 3_600),
+                transition(InstanceStatus::Stopped, InstanceStatus::Ready, MONDAY + 2 * 3_600),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(uptime_percent(&instance, MONDAY, MONDAY + 4 * 3_600), Some(75.0));
+        assert_eq!(uptime_percent(&instance, MONDAY - 2 * 86_400, MONDAY - 86_400), None);
+
+        let report = Report {
+            subscription_id: "sub".to_string(),
+            account: "abc".to_string(),
+            period_start: MONDAY,
+            period_end: MONDAY + 86_400,
+            usage: Some(UsageTotals { cpu_seconds: 7_200, ..Default::default() }),
+            billing: None,
+            uptime: Some(vec![InstanceUptime { instance_id: "vm".to_string(), uptime_percent: Some(75.0), status: InstanceStatus::Ready }]),
+            text: String::new(),
+        };
+        let text = render("{{account}} {{period_start}} cpu={{cpu_hours}} credits={{credits}}\n{{uptime}}", &report);
+        assert_eq!(text, "abc 2024-01-01 00:00 UTC cpu=2.00 credits=n/a\n  vm: 75.00% (Ready)");
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let request = |schedule: &str, email: Option<&str>, webhook| ReportSubscriptionRequest {
+            schedule: schedule.to_string(),
+            window: ReportWindow::Week,
+            sections: all_sections(),
+            webhook,
+            email: email.map(str::to_string),
+            template: None,
+        };
+        assert!(ReportSubscription::new("abc", request("@daily", None, false), MONDAY).is_err());
+        assert!(ReportSubscription::new("abc", request("@daily", Some("not-an-email"), true), MONDAY).is_err());
+
+        let mut store = ReportStore::default();
+        let subscription = ReportSubscription::new("0xABC", request("0 8 * * *", Some("ops@example.com"), true), MONDAY).unwrap();
+        assert_eq!(subscription.next_run, MONDAY + 8 * 3_600);
+        store.add(subscription).unwrap();
+        assert_eq!(store.for_account("abc").len(), 1);
+
+        assert!(store.take_due(MONDAY + 3_600).is_empty());
+        let due = store.take_due(MONDAY + 8 * 3_600);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].next_run, MONDAY + 86_400 + 8 * 3_600);
+        assert!(store.take_due(MONDAY + 8 * 3_600).is_empty());
+    }
+}
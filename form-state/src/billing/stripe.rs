@@ -3,12 +3,33 @@
 //! This module provides data structures for storing subscription information
 //! and checking eligibility for operations.
 //! 
-//! Instead of connecting directly to Stripe, it receives data from the frontend
-//! and provides eligibility checks based on stored data.
+//! Checkout happens in the frontend, which hands the result to form-state.
+//! Later changes to a subscription, renewals, failed payments, upgrades and
+//! cancellations, arrive from Stripe on `POST /billing/stripe/webhook`:
+//!
+//! - the `Stripe-Signature` header is checked against `STRIPE_WEBHOOK_SECRET`
+//!   and events signed more than `SIGNATURE_TOLERANCE_SECS` ago are refused
+//! - `customer.subscription.*` events update the `SubscriptionInfo` of the
+//!   account named by the subscription's `account_id` metadata, or the
+//!   account with its customer id. The tier is the `tier` metadata of the
+//!   subscription or its price, or else the price's lookup key.
+//! - event ids are recorded in a `StripeEventLog`, so an event Stripe
+//!   delivers again is acknowledged without being applied twice, and an
+//!   event older than one already applied to its subscription is skipped
+//!
+//! Tier and status changes go through the account CRDT, which fires the
+//! `subscription.*` billing webhooks.
 
-use crate::billing::{SubscriptionInfo, SubscriptionStatus};
-use chrono::{DateTime, Utc};
+use crate::auth::timing::ct_eq;
+use crate::billing::{SubscriptionInfo, SubscriptionStatus, SubscriptionTier};
+use crate::datastore::DB_HANDLE;
+use crate::db::store_value;
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 
 /// Error types for billing operations
@@ -197,4 +218,323 @@ pub struct BillingTransaction {
     
     /// Status (completed, failed, etc.)
     pub status: String,
-} 
\ No newline at end of file
+} 
+/// Key under which the processed Stripe events are persisted
+pub const STRIPE_EVENTS_DB_KEY: &str = "billing/stripe/events";
+
+/// Oldest signature accepted, Stripe's own libraries default to 5 minutes
+pub const SIGNATURE_TOLERANCE_SECS: i64 = 300;
+
+/// Event ids remembered, well over what Stripe retries within its 3 days
+const MAX_PROCESSED_EVENTS: usize = 10_000;
+
+/// Why a webhook's `Stripe-Signature` was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SignatureError {
+    #[error("Stripe-Signature header has no timestamp or v1 signature")]
+    Malformed,
+    #[error("Stripe-Signature timestamp is outside the tolerance")]
+    Expired,
+    #[error("No v1 signature matches the payload")]
+    Mismatch,
+}
+
+/// Checks `header` signs `payload` with `secret`, as Stripe computes it: an
+/// HMAC-SHA256 of `<t>.<payload>`. Every v1 signature is compared, there are
+/// several while a secret is rolled.
+pub fn verify_signature(secret: &str, header: &str, payload: &[u8], now: i64) -> Result<(), SignatureError> {
+    let mut timestamp = None;
+    let mut signatures = vec![];
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", t)) => timestamp = t.parse::<i64>().ok(),
+            Some(("v1", signature)) => signatures.extend(hex::decode(signature).ok()),
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or(SignatureError::Malformed)?;
+    if signatures.is_empty() {
+        return Err(SignatureError::Malformed);
+    }
+    if now - timestamp > SIGNATURE_TOLERANCE_SECS {
+        return Err(SignatureError::Expired);
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload);
+    let expected = mac.finalize().into_bytes();
+    let matched = signatures.iter().fold(false, |matched, signature| ct_eq(&expected, signature) | matched);
+    if matched {
+        Ok(())
+    } else {
+        Err(SignatureError::Mismatch)
+    }
+}
+
+/// The fields of a Stripe event form-state reads
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created: i64,
+    pub data: StripeEventData,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeEventData {
+    pub object: Value,
+}
+
+impl StripeEvent {
+    /// Whether the event carries a subscription to apply
+    pub fn is_subscription_lifecycle(&self) -> bool {
+        matches!(
+            self.event_type.as_str(),
+            "customer.subscription.created"
+                | "customer.subscription.updated"
+                | "customer.subscription.deleted"
+                | "customer.subscription.paused"
+                | "customer.subscription.resumed"
+        )
+    }
+}
+
+/// The fields of a Stripe subscription form-state reads
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeSubscription {
+    pub id: String,
+    pub customer: String,
+    pub status: String,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub created: Option<i64>,
+    #[serde(default)]
+    pub current_period_start: Option<i64>,
+    #[serde(default)]
+    pub current_period_end: Option<i64>,
+    #[serde(default)]
+    pub cancel_at_period_end: bool,
+    #[serde(default)]
+    pub items: StripeList<StripeSubscriptionItem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeList<T> {
+    pub data: Vec<T>,
+}
+
+impl<T> Default for StripeList<T> {
+    fn default() -> Self {
+        Self { data: vec![] }
+    }
+}
+
+/// Newer API versions carry the billing period on the items rather than
+/// the subscription
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeSubscriptionItem {
+    #[serde(default)]
+    pub price: Option<StripePrice>,
+    #[serde(default)]
+    pub current_period_start: Option<i64>,
+    #[serde(default)]
+    pub current_period_end: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripePrice {
+    pub id: String,
+    #[serde(default)]
+    pub lookup_key: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl StripeSubscription {
+    /// The account the subscription was created for, if the checkout set it
+    pub fn account_id(&self) -> Option<&str> {
+        self.metadata.get("account_id").map(String::as_str)
+    }
+
+    pub fn tier(&self) -> Option<SubscriptionTier> {
+        let price = self.items.data.iter().find_map(|item| item.price.as_ref());
+        self.metadata.get("tier")
+            .or_else(|| price.and_then(|price| price.metadata.get("tier")))
+            .or_else(|| price.and_then(|price| price.lookup_key.as_ref()))
+            .and_then(|name| name.parse().ok())
+    }
+
+    pub fn status(&self) -> SubscriptionStatus {
+        match self.status.as_str() {
+            "active" => SubscriptionStatus::Active,
+            "trialing" => SubscriptionStatus::Trial,
+            "past_due" | "unpaid" | "incomplete" => SubscriptionStatus::PastDue,
+            "canceled" => SubscriptionStatus::Canceled,
+            "incomplete_expired" | "paused" => SubscriptionStatus::Expired,
+            other => {
+                log::warn!("Unknown status {other} of Stripe subscription {}", self.id);
+                SubscriptionStatus::Error
+            }
+        }
+    }
+
+    /// The account's subscription after this one was applied to `current`.
+    /// Fails if the tier can't be told and `current` isn't this subscription.
+    pub fn apply_to(&self, current: Option<&SubscriptionInfo>) -> BillingResult<SubscriptionInfo> {
+        let current = current.filter(|info| info.stripe_subscription_id.as_deref() == Some(self.id.as_str()));
+        let tier = self.tier()
+            .or_else(|| current.map(|info| info.tier))
+            .ok_or_else(|| BillingError::Other(format!("Unable to tell the tier of Stripe subscription {}", self.id)))?;
+        let status = self.status();
+        let quota = tier.quota();
+        let item = self.items.data.first();
+        let time = |secs: Option<i64>| secs.and_then(|secs| Utc.timestamp_opt(secs, 0).single());
+        let now = Utc::now();
+        let period_start = time(self.current_period_start.or_else(|| item.and_then(|item| item.current_period_start)))
+            .or_else(|| current.map(|info| info.current_period_start))
+            .unwrap_or(now);
+        let period_end = time(self.current_period_end.or_else(|| item.and_then(|item| item.current_period_end)))
+            .or_else(|| current.map(|info| info.current_period_end))
+            .unwrap_or(period_start + chrono::Duration::days(30));
+
+        Ok(SubscriptionInfo {
+            stripe_customer_id: Some(self.customer.clone()),
+            stripe_subscription_id: Some(self.id.clone()),
+            tier,
+            status,
+            created_at: time(self.created).or_else(|| current.map(|info| info.created_at)).unwrap_or(now),
+            current_period_start: period_start,
+            current_period_end: period_end,
+            auto_renew: !self.cancel_at_period_end && status != SubscriptionStatus::Canceled,
+            max_agents: quota.max_agents,
+            inference_credits_per_period: quota.inference_credits,
+        })
+    }
+}
+
+/// Stripe events this node has processed, local to the node like the
+/// billing webhooks
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StripeEventLog {
+    /// Ids of processed events, oldest first
+    processed: VecDeque<String>,
+    /// `created` of the latest event applied to each subscription
+    applied: BTreeMap<String, i64>,
+}
+
+impl StripeEventLog {
+    pub fn is_processed(&self, event_id: &str) -> bool {
+        self.processed.iter().any(|id| id == event_id)
+    }
+
+    /// Whether an event applied to the subscription is newer. Stripe doesn't
+    /// deliver events in order, an `updated` can arrive after a `deleted`.
+    pub fn is_stale(&self, subscription_id: &str, created: i64) -> bool {
+        self.applied.get(subscription_id).is_some_and(|applied| *applied > created)
+    }
+
+    pub fn record(&mut self, event_id: &str) {
+        self.processed.push_back(event_id.to_string());
+        while self.processed.len() > MAX_PROCESSED_EVENTS {
+            self.processed.pop_front();
+        }
+    }
+
+    pub fn record_applied(&mut self, event_id: &str, subscription_id: &str, created: i64) {
+        self.record(event_id);
+        let applied = self.applied.entry(subscription_id.to_string()).or_insert(created);
+        *applied = (*applied).max(created);
+    }
+}
+
+pub(crate) fn persist_event_log(events: &StripeEventLog) {
+    if let Err(e) = store_value(&DB_HANDLE, STRIPE_EVENTS_DB_KEY, events) {
+        log::error!("Unable to persist processed Stripe events: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sign(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(payload);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn test_signature_verification() {
+        let payload = br#"{"id":"evt_1"}"#;
+        let now = 1_700_000_000;
+        let valid = sign("whsec_test", now, payload);
+        let header = format!("t={now},v1={valid},v0=ignored");
+        assert_eq!(verify_signature("whsec_test", &header, payload, now + 10), Ok(()));
+
+        // Any of several v1 signatures may match
+        let rolled = format!("t={now},v1={},v1={valid}", sign("whsec_old", now, payload));
+        assert_eq!(verify_signature("whsec_test", &rolled, payload, now), Ok(()));
+
+        assert_eq!(verify_signature("whsec_other", &header, payload, now), Err(SignatureError::Mismatch));
+        assert_eq!(verify_signature("whsec_test", &header, br#"{"id":"evt_2"}"#, now), Err(SignatureError::Mismatch));
+        assert_eq!(
+            verify_signature("whsec_test", &header, payload, now + SIGNATURE_TOLERANCE_SECS + 1),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(verify_signature("whsec_test", &format!("v1={valid}"), payload, now), Err(SignatureError::Malformed));
+    }
+
+    #[test]
+    fn test_subscription_applies_tier_and_status() {
+        let subscription: StripeSubscription = serde_json::from_value(json!({
+            "id": "sub_1",
+            "customer": "cus_1",
+            "status": "past_due",
+            "metadata": { "account_id": "abc" },
+            "cancel_at_period_end": true,
+            "items": { "data": [{
+                "price": { "id": "price_1", "lookup_key": "pro_plus" },
+                "current_period_start": 1_700_000_000,
+                "current_period_end": 1_702_592_000,
+            }]},
+        })).unwrap();
+        assert_eq!(subscription.account_id(), Some("abc"));
+
+        let info = subscription.apply_to(None).unwrap();
+        assert_eq!(info.tier, SubscriptionTier::ProPlus);
+        assert_eq!(info.status, SubscriptionStatus::PastDue);
+        assert_eq!(info.max_agents, SubscriptionTier::ProPlus.quota().max_agents);
+        assert_eq!(info.current_period_end.timestamp(), 1_702_592_000);
+        assert!(!info.auto_renew);
+
+        // Without a tier the one already held for the subscription is kept
+        let mut untiered = subscription.clone();
+        untiered.items = StripeList::default();
+        untiered.status = "canceled".to_string();
+        assert!(untiered.apply_to(None).is_err());
+        let canceled = untiered.apply_to(Some(&info)).unwrap();
+        assert_eq!((canceled.tier, canceled.status), (SubscriptionTier::ProPlus, SubscriptionStatus::Canceled));
+        assert_eq!(canceled.current_period_start, info.current_period_start);
+    }
+
+    #[test]
+    fn test_event_log_dedupes_and_orders() {
+        let mut log = StripeEventLog::default();
+        log.record_applied("evt_2", "sub_1", 200);
+        assert!(log.is_processed("evt_2"));
+        assert!(!log.is_processed("evt_1"));
+        assert!(log.is_stale("sub_1", 100));
+        assert!(!log.is_stale("sub_1", 200));
+        assert!(!log.is_stale("sub_2", 100));
+
+        // An older event recorded late doesn't move the subscription back
+        log.record_applied("evt_1", "sub_1", 100);
+        assert!(log.is_stale("sub_1", 150));
+    }
+}
@@ -0,0 +1,506 @@
+//! Billing webhooks
+//!
+//! External systems register URLs, either for one account or for every
+//! account, and receive a signed POST when an account's credits fall through
+//! a threshold or run out, when its subscription tier or status changes, and
+//! when an invoice is created.
+//!
+//! Registrations and delivery logs are local to the node they were made on.
+//! Account changes from every node reach it through the account CRDT, so a
+//! registration fires once per change no matter where the change was made.
+//!
+//! Every request carries an `X-Formation-Signature` header of the form
+//! `t=<unix seconds>,v1=<hex HMAC-SHA256>` computed with the endpoint's
+//! secret over `<t>.<body>`. Failed deliveries are retried with exponential
+//! backoff.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use crate::accounts::Account;
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::usage_rollups::normalize_account_id;
+
+/// Key under which registrations and delivery logs are persisted
+pub const WEBHOOKS_DB_KEY: &str = "billing/webhooks";
+
+/// Delivery log entries kept per node, oldest are dropped first
+const MAX_DELIVERY_LOG: usize = 1000;
+
+/// Events a webhook can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum WebhookEventType {
+    /// Available credits fell to or below one of the endpoint's thresholds
+    #[serde(rename = "credits.threshold")]
+    CreditThreshold,
+    /// Available credits reached zero
+    #[serde(rename = "credits.exhausted")]
+    CreditsExhausted,
+    /// The subscription moved to another tier
+    #[serde(rename = "subscription.tier_changed")]
+    SubscriptionTierChanged,
+    /// The subscription status changed, e.g. to past due or canceled
+    #[serde(rename = "subscription.status_changed")]
+    SubscriptionStatusChanged,
+    /// An invoice was created for the account
+    #[serde(rename = "invoice.created")]
+    InvoiceCreated,
+    /// A scheduled report was generated, see `billing::reports`
+    #[serde(rename = "report.generated")]
+    ReportGenerated,
+    /// Capacity was reserved and the term charged, see `reservations`
+    #[serde(rename = "reservation.created")]
+    ReservationCreated,
+    /// A reservation was cancelled, with any refund
+    #[serde(rename = "reservation.cancelled")]
+    ReservationCancelled,
+}
+
+/// A registered webhook URL
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEndpoint {
+    pub id: String,
+    pub url: String,
+    /// Account the endpoint receives events for, all accounts if None
+    pub account: Option<String>,
+    /// Events delivered to the endpoint, all events if empty
+    pub events: Vec<WebhookEventType>,
+    /// Credit balances that trigger `credits.threshold` when crossed
+    pub credit_thresholds: Vec<u64>,
+    /// Key the deliveries are signed with
+    pub secret: String,
+    /// Address that registered the endpoint
+    pub created_by: String,
+    pub created_at: i64,
+}
+
+impl WebhookEndpoint {
+    fn wants(&self, event_type: WebhookEventType, account: &str) -> bool {
+        let for_account = match &self.account {
+            Some(scope) => normalize_account_id(scope) == normalize_account_id(account),
+            None => true,
+        };
+        for_account && (self.events.is_empty() || self.events.contains(&event_type))
+    }
+
+    /// The endpoint without its secret, for listing
+    pub fn redacted(&self) -> Self {
+        Self { secret: String::new(), ..self.clone() }
+    }
+}
+
+/// A billing event, the body of every delivery
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: WebhookEventType,
+    pub account: String,
+    pub created_at: i64,
+    pub data: Value,
+}
+
+/// A delivery waiting for its next attempt
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingDelivery {
+    pub id: String,
+    pub endpoint_id: String,
+    pub event: WebhookEvent,
+    /// Attempts made so far
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+}
+
+/// One attempt to deliver an event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    pub delivery_id: String,
+    pub endpoint_id: String,
+    pub event_id: String,
+    pub event_type: WebhookEventType,
+    pub attempt: u32,
+    pub timestamp: i64,
+    /// HTTP status returned by the endpoint, None if it couldn't be reached
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub success: bool,
+    /// Whether another attempt is scheduled
+    pub will_retry: bool,
+}
+
+/// Configuration for the delivery task
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// How often due deliveries are sent
+    pub poll_interval: Duration,
+    /// Attempts before a delivery is given up
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub retry_base: Duration,
+    pub request_timeout: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(1),
+            max_attempts: 6,
+            retry_base: Duration::from_secs(30),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Registered endpoints, undelivered events and the delivery log
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WebhookStore {
+    pub endpoints: BTreeMap<String, WebhookEndpoint>,
+    pub pending: Vec<PendingDelivery>,
+    pub deliveries: VecDeque<DeliveryRecord>,
+}
+
+impl WebhookStore {
+    pub fn register(
+        &mut self,
+        url: String,
+        account: Option<String>,
+        events: Vec<WebhookEventType>,
+        credit_thresholds: Vec<u64>,
+        created_by: String,
+        now: i64,
+    ) -> WebhookEndpoint {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let endpoint = WebhookEndpoint {
+            id: uuid::Uuid::new_v4().to_string(),
+            url,
+            account,
+            events,
+            credit_thresholds,
+            secret: format!("whsec_{}", hex::encode(secret)),
+            created_by,
+            created_at: now,
+        };
+        self.endpoints.insert(endpoint.id.clone(), endpoint.clone());
+        endpoint
+    }
+
+    /// Removes an endpoint along with its undelivered events
+    pub fn remove(&mut self, id: &str) -> Option<WebhookEndpoint> {
+        self.pending.retain(|delivery| delivery.endpoint_id != id);
+        self.endpoints.remove(id)
+    }
+
+    /// Queues `event_type` for every endpoint that wants it. Credit threshold
+    /// events only go to endpoints watching the `threshold` in `data`.
+    pub fn emit(&mut self, event_type: WebhookEventType, account: &str, data: Value, now: i64) {
+        let event = WebhookEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            event_type,
+            account: account.to_string(),
+            created_at: now,
+            data,
+        };
+        let endpoints: Vec<String> = self.endpoints.values()
+            .filter(|endpoint| endpoint.wants(event_type, account))
+            .filter(|endpoint| event_type != WebhookEventType::CreditThreshold || event.data.get("threshold")
+                .and_then(Value::as_u64)
+                .is_some_and(|threshold| endpoint.credit_thresholds.contains(&threshold)))
+            .map(|endpoint| endpoint.id.clone())
+            .collect();
+        for endpoint_id in endpoints {
+            log::info!("Queuing {:?} for account {} to webhook {}", event_type, account, endpoint_id);
+            self.pending.push(PendingDelivery {
+                id: uuid::Uuid::new_v4().to_string(),
+                endpoint_id,
+                event: event.clone(),
+                attempts: 0,
+                next_attempt_at: now,
+            });
+        }
+    }
+
+    /// Emits the events implied by an account changing from `before` to `after`
+    pub fn account_changed(&mut self, before: Option<&Account>, after: &Account, now: i64) {
+        let credits_before = before.map(Account::available_credits);
+        let credits_after = after.available_credits();
+        if let Some(credits_before) = credits_before.filter(|before| *before > credits_after) {
+            let thresholds: Vec<u64> = self.endpoints.values()
+                .filter(|endpoint| endpoint.wants(WebhookEventType::CreditThreshold, &after.address))
+                .flat_map(|endpoint| endpoint.credit_thresholds.iter().copied())
+                .filter(|threshold| credits_before > *threshold && credits_after <= *threshold)
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            for threshold in thresholds {
+                self.emit(WebhookEventType::CreditThreshold, &after.address, json!({
+                    "threshold": threshold,
+                    "previous_credits": credits_before,
+                    "available_credits": credits_after,
+                }), now);
+            }
+            if credits_after == 0 {
+                self.emit(WebhookEventType::CreditsExhausted, &after.address, json!({
+                    "previous_credits": credits_before,
+                }), now);
+            }
+        }
+
+        let Some(before) = before else {
+            return;
+        };
+        let tier = |account: &Account| account.subscription.as_ref().map(|s| s.tier);
+        let status = |account: &Account| account.subscription.as_ref().map(|s| s.status);
+        if tier(before) != tier(after) {
+            self.emit(WebhookEventType::SubscriptionTierChanged, &after.address, json!({
+                "previous_tier": tier(before),
+                "tier": tier(after),
+            }), now);
+        }
+        if status(before) != status(after) {
+            self.emit(WebhookEventType::SubscriptionStatusChanged, &after.address, json!({
+                "previous_status": status(before),
+                "status": status(after),
+                "tier": tier(after),
+            }), now);
+        }
+    }
+
+    /// Takes the deliveries that are due, with the endpoint to send each to
+    pub fn take_due(&mut self, now: i64) -> Vec<(PendingDelivery, WebhookEndpoint)> {
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|delivery| delivery.next_attempt_at <= now);
+        self.pending = waiting;
+        due.into_iter()
+            .filter_map(|delivery| {
+                let endpoint = self.endpoints.get(&delivery.endpoint_id)?.clone();
+                Some((delivery, endpoint))
+            })
+            .collect()
+    }
+
+    /// Logs an attempt and schedules a retry if it failed and attempts remain
+    pub fn record_attempt(
+        &mut self,
+        mut delivery: PendingDelivery,
+        result: Result<u16, (Option<u16>, String)>,
+        now: i64,
+        config: &WebhookConfig,
+    ) {
+        delivery.attempts += 1;
+        let (status, error) = match &result {
+            Ok(status) => (Some(*status), None),
+            Err((status, error)) => (*status, Some(error.clone())),
+        };
+        let will_retry = result.is_err()
+            && delivery.attempts < config.max_attempts
+            && self.endpoints.contains_key(&delivery.endpoint_id);
+
+        self.deliveries.push_back(DeliveryRecord {
+            delivery_id: delivery.id.clone(),
+            endpoint_id: delivery.endpoint_id.clone(),
+            event_id: delivery.event.id.clone(),
+            event_type: delivery.event.event_type,
+            attempt: delivery.attempts,
+            timestamp: now,
+            status,
+            error,
+            success: result.is_ok(),
+            will_retry,
+        });
+        while self.deliveries.len() > MAX_DELIVERY_LOG {
+            self.deliveries.pop_front();
+        }
+
+        if will_retry {
+            let backoff = config.retry_base.as_secs() as i64 * (1i64 << (delivery.attempts - 1).min(16));
+            delivery.next_attempt_at = now + backoff;
+            self.pending.push(delivery);
+        } else if result.is_err() {
+            log::warn!("Giving up on webhook delivery {} to {} after {} attempts", delivery.id, delivery.endpoint_id, delivery.attempts);
+        }
+    }
+
+    /// Delivery log of an endpoint, newest first
+    pub fn deliveries_for(&self, endpoint_id: &str) -> Vec<DeliveryRecord> {
+        self.deliveries.iter().rev().filter(|record| record.endpoint_id == endpoint_id).cloned().collect()
+    }
+}
+
+/// `X-Formation-Signature` value for `body` sent at `timestamp`
+pub fn signature_header(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+}
+
+pub(crate) fn persist(webhooks: &WebhookStore) {
+    if let Err(e) = store_value(&DB_HANDLE, WEBHOOKS_DB_KEY, webhooks) {
+        log::error!("Unable to persist billing webhooks: {e}");
+    }
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    endpoint: &WebhookEndpoint,
+    delivery: &PendingDelivery,
+    now: i64,
+) -> Result<u16, (Option<u16>, String)> {
+    let body = serde_json::to_vec(&delivery.event).map_err(|e| (None, e.to_string()))?;
+    let response = client.post(&endpoint.url)
+        .header("Content-Type", "application/json")
+        .header("X-Formation-Event", serde_json::to_value(delivery.event.event_type)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default())
+        .header("X-Formation-Delivery", &delivery.id)
+        .header("X-Formation-Signature", signature_header(&endpoint.secret, now, &body))
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16())
+    } else {
+        Err((Some(status.as_u16()), format!("Endpoint returned {status}")))
+    }
+}
+
+/// Sends queued webhook deliveries until a shutdown signal is received
+pub async fn run_webhook_dispatcher(
+    datastore: Arc<Mutex<DataStore>>,
+    config: WebhookConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::builder().timeout(config.request_timeout).build()?;
+    let mut interval = tokio::time::interval(config.poll_interval);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let due = datastore.lock().await.webhooks.take_due(chrono::Utc::now().timestamp());
+                if due.is_empty() {
+                    continue;
+                }
+                let mut results = Vec::with_capacity(due.len());
+                for (delivery, endpoint) in due {
+                    let result = deliver(&client, &endpoint, &delivery, chrono::Utc::now().timestamp()).await;
+                    results.push((delivery, result));
+                }
+                let mut guard = datastore.lock().await;
+                let now = chrono::Utc::now().timestamp();
+                for (delivery, result) in results {
+                    guard.webhooks.record_attempt(delivery, result, now, &config);
+                }
+                persist(&guard.webhooks);
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::billing::{SubscriptionInfo, SubscriptionStatus, SubscriptionTier};
+
+    fn account(credits: u64) -> Account {
+        let mut account = Account::new("0xabc".to_string());
+        account.credits = credits;
+        account
+    }
+
+    #[test]
+    fn test_account_changes_emit_events() {
+        let mut store = WebhookStore::default();
+        let scoped = store.register("https://a.example/hook".into(), Some("ABC".into()), vec![], vec![50, 10], "abc".into(), 0);
+        let other = store.register("https://b.example/hook".into(), Some("def".into()), vec![], vec![50], "def".into(), 0);
+        let global = store.register(
+            "https://c.example/hook".into(), None, vec![WebhookEventType::SubscriptionTierChanged], vec![], "admin".into(), 0
+        );
+
+        // 100 -> 40 crosses 50 but not 10
+        store.account_changed(Some(&account(100)), &account(40), 1);
+        assert_eq!(store.pending.len(), 1);
+        assert_eq!(store.pending[0].endpoint_id, scoped.id);
+        assert_eq!(store.pending[0].event.data["threshold"], json!(50));
+
+        // 40 -> 0 crosses 10 and exhausts the credits
+        store.pending.clear();
+        store.account_changed(Some(&account(40)), &account(0), 2);
+        let types: Vec<_> = store.pending.iter().map(|d| d.event.event_type).collect();
+        assert_eq!(types, vec![WebhookEventType::CreditThreshold, WebhookEventType::CreditsExhausted]);
+
+        // Adding credits fires nothing
+        store.pending.clear();
+        store.account_changed(Some(&account(0)), &account(500), 3);
+        assert!(store.pending.is_empty());
+
+        let mut upgraded = account(500);
+        let mut subscription = SubscriptionInfo::new(SubscriptionTier::Pro);
+        subscription.status = SubscriptionStatus::Active;
+        upgraded.subscription = Some(subscription);
+        store.account_changed(Some(&account(500)), &upgraded, 4);
+        let mut received: Vec<_> = store.pending.iter().map(|d| (d.endpoint_id.clone(), d.event.event_type)).collect();
+        received.sort();
+        let mut expected = vec![
+            (scoped.id.clone(), WebhookEventType::SubscriptionTierChanged),
+            (scoped.id.clone(), WebhookEventType::SubscriptionStatusChanged),
+            (global.id.clone(), WebhookEventType::SubscriptionTierChanged),
+        ];
+        expected.sort();
+        assert_eq!(received, expected);
+        assert!(store.pending.iter().all(|d| d.endpoint_id != other.id));
+    }
+
+    #[test]
+    fn test_failed_deliveries_are_retried_with_backoff() {
+        let config = WebhookConfig { max_attempts: 3, retry_base: Duration::from_secs(10), ..Default::default() };
+        let mut store = WebhookStore::default();
+        let endpoint = store.register("https://a.example/hook".into(), None, vec![], vec![], "admin".into(), 0);
+        store.emit(WebhookEventType::InvoiceCreated, "abc", json!({ "invoice_id": "in_1" }), 100);
+
+        let (delivery, _) = store.take_due(100).pop().unwrap();
+        store.record_attempt(delivery, Err((Some(500), "Endpoint returned 500".into())), 100, &config);
+        assert_eq!(store.pending[0].next_attempt_at, 110);
+        assert!(store.take_due(105).is_empty());
+
+        let (delivery, _) = store.take_due(110).pop().unwrap();
+        store.record_attempt(delivery, Err((None, "connection refused".into())), 110, &config);
+        assert_eq!(store.pending[0].next_attempt_at, 130);
+
+        let (delivery, _) = store.take_due(130).pop().unwrap();
+        store.record_attempt(delivery, Err((Some(503), "Endpoint returned 503".into())), 130, &config);
+        assert!(store.pending.is_empty());
+
+        let log = store.deliveries_for(&endpoint.id);
+        assert_eq!(log.len(), 3);
+        assert!(!log[0].will_retry && log[1].will_retry);
+        assert_eq!(log[0].attempt, 3);
+    }
+
+    #[test]
+    fn test_signature_header() {
+        let header = signature_header("whsec_test", 1_700_000_000, b"{}");
+        let (timestamp, signature) = header.split_once(",v1=").unwrap();
+        assert_eq!(timestamp, "t=1700000000");
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"whsec_test").unwrap();
+        mac.update(b"1700000000.{}");
+        mac.verify_slice(&hex::decode(signature).unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,491 @@
+// form-state/src/build_manifests.rs
+// Signed build manifests tie a built image to the Formfile and base image it
+// was built from and to the node that built it. The builder signs the
+// manifest with its node key, vmm-service checks the image against it before
+// booting. The vulnerability scan of the image travels with the manifest, so
+// its findings are signed by the same key. Builders that format a dm-verity
+// hash tree for the image sign its root hash too, and each node that boots
+// the image reports how its check went.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use alloy_primitives::Address;
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+
+/// Key under which the build manifests are persisted in the node's db
+pub const BUILD_MANIFESTS_DB_KEY: &str = "build_manifests/all";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildManifest {
+    pub build_id: String,
+    /// Digest of the Formfile the image was built from, see `canonical_digest`
+    pub formfile_digest: String,
+    /// Digest of the builder image the build ran in
+    pub base_image_digest: String,
+    /// Digest of the produced disk image, see `file_digest`
+    pub image_digest: String,
+    /// Address of the node that built the image
+    pub builder_node: String,
+    pub built_at: i64,
+    /// Vulnerability scan of the image, absent if the builder didn't scan it.
+    /// Skipped when empty so manifests signed before scanning still verify.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scan: Option<ImageScan>,
+    /// dm-verity hash tree of the image, absent if the builder didn't
+    /// format one. Skipped when empty like `scan`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verity: Option<VerityInfo>,
+}
+
+impl BuildManifest {
+    /// The digest the builder signs
+    pub fn signing_hash(&self) -> Result<[u8; 32], serde_json::Error> {
+        let mut hasher = Sha3::v256();
+        let mut hash = [0u8; 32];
+        hasher.update(&serde_json::to_vec(self)?);
+        hasher.finalize(&mut hash);
+        Ok(hash)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VulnerabilitySeverity {
+    Unknown,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for VulnerabilitySeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self {
+            VulnerabilitySeverity::Unknown => "unknown",
+            VulnerabilitySeverity::Low => "low",
+            VulnerabilitySeverity::Medium => "medium",
+            VulnerabilitySeverity::High => "high",
+            VulnerabilitySeverity::Critical => "critical",
+        };
+        write!(f, "{severity}")
+    }
+}
+
+impl FromStr for VulnerabilitySeverity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "unknown" => Ok(VulnerabilitySeverity::Unknown),
+            "low" | "negligible" => Ok(VulnerabilitySeverity::Low),
+            "medium" | "moderate" => Ok(VulnerabilitySeverity::Medium),
+            "high" | "important" => Ok(VulnerabilitySeverity::High),
+            "critical" => Ok(VulnerabilitySeverity::Critical),
+            _ => Err(format!("Unknown vulnerability severity {s}")),
+        }
+    }
+}
+
+/// A known vulnerability in a package installed in the image
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vulnerability {
+    /// CVE or advisory id
+    pub id: String,
+    pub package: String,
+    pub installed_version: String,
+    #[serde(default)]
+    pub fixed_version: Option<String>,
+    pub severity: VulnerabilitySeverity,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// The findings of scanning an image's package database
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageScan {
+    /// Scanner and version that produced the findings
+    pub scanner: String,
+    pub scanned_at: i64,
+    pub findings: Vec<Vulnerability>,
+    /// Why the builder's scan policy rejected the image. vmm-service won't
+    /// boot an image whose scan has one.
+    #[serde(default)]
+    pub policy_violation: Option<String>,
+}
+
+impl ImageScan {
+    /// Number of findings of each severity
+    pub fn counts(&self) -> BTreeMap<VulnerabilitySeverity, usize> {
+        let mut counts = BTreeMap::new();
+        for finding in &self.findings {
+            *counts.entry(finding.severity).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// The findings at `severity` or worse, the worst first
+    pub fn at_least(&self, severity: VulnerabilitySeverity) -> Vec<&Vulnerability> {
+        let mut findings: Vec<_> = self.findings.iter().filter(|f| f.severity >= severity).collect();
+        findings.sort_by(|a, b| b.severity.cmp(&a.severity).then_with(|| a.id.cmp(&b.id)));
+        findings
+    }
+}
+
+/// Parameters of an image's dm-verity hash tree, as `veritysetup format`
+/// reports them. The tree is kept next to the image, see
+/// `verity_hash_tree_path`, and the root hash covers every block of the image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerityInfo {
+    pub root_hash: String,
+    pub salt: String,
+    pub hash_algorithm: String,
+    pub data_block_size: u64,
+    pub hash_block_size: u64,
+    pub data_blocks: u64,
+}
+
+impl VerityInfo {
+    /// Parses the header `veritysetup format` prints
+    pub fn from_format_output(output: &str) -> Result<Self, String> {
+        let fields: BTreeMap<&str, &str> = output.lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+        let field = |key: &str| fields.get(key).copied()
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| format!("veritysetup output has no {key}"));
+        let number = |key: &str| field(key)?.parse::<u64>().map_err(|e| format!("Invalid {key}: {e}"));
+
+        let root_hash = field("Root hash")?.to_ascii_lowercase();
+        if hex::decode(&root_hash).is_err() {
+            return Err(format!("Invalid root hash {root_hash}"));
+        }
+        Ok(Self {
+            root_hash,
+            salt: field("Salt")?.to_ascii_lowercase(),
+            hash_algorithm: field("Hash algorithm")?.to_string(),
+            data_block_size: number("Data block size")?,
+            hash_block_size: number("Hash block size")?,
+            data_blocks: number("Data blocks")?,
+        })
+    }
+
+    /// Size of the image the tree covers
+    pub fn data_bytes(&self) -> u64 {
+        self.data_blocks * self.data_block_size
+    }
+}
+
+/// Where the dm-verity hash tree of an image is kept
+pub fn verity_hash_tree_path(image: impl AsRef<Path>) -> PathBuf {
+    image.as_ref().with_extension("verity")
+}
+
+/// Outcome of a node's integrity check of an image before booting it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageVerification {
+    pub build_id: String,
+    /// Node that booted, or refused to boot, the image
+    pub node_id: String,
+    /// Root hash the image was checked against, `None` if it was checked
+    /// against the manifest's image digest
+    #[serde(default)]
+    pub root_hash: Option<String>,
+    pub verified: bool,
+    /// Why the image was refused
+    #[serde(default)]
+    pub error: Option<String>,
+    pub checked_at: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedBuildManifest {
+    pub manifest: BuildManifest,
+    /// Hex encoded ECDSA signature over `BuildManifest::signing_hash`
+    pub signature: String,
+    pub recovery_id: u8,
+}
+
+impl SignedBuildManifest {
+    pub fn sign(manifest: BuildManifest, signing_key: &SigningKey) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let (signature, recovery_id) = signing_key.sign_recoverable(&manifest.signing_hash()?)?;
+        Ok(Self {
+            manifest,
+            signature: hex::encode(signature.to_bytes()),
+            recovery_id: recovery_id.to_byte(),
+        })
+    }
+
+    /// Address of the key that signed the manifest, without a 0x prefix
+    pub fn signer(&self) -> Result<String, String> {
+        let hash = self.manifest.signing_hash().map_err(|e| e.to_string())?;
+        let signature = hex::decode(&self.signature)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Signature::from_slice(&bytes).map_err(|e| e.to_string()))?;
+        let recovery_id = RecoveryId::from_byte(self.recovery_id)
+            .ok_or_else(|| "Invalid recovery id".to_string())?;
+        let key = VerifyingKey::recover_from_msg(&hash, &signature, recovery_id)
+            .map_err(|e| e.to_string())?;
+        Ok(hex::encode(Address::from_public_key(&key)))
+    }
+
+    /// Checks that the manifest was signed by the node it names as builder
+    pub fn verify(&self) -> Result<(), String> {
+        let signer = self.signer()?;
+        if !signer.eq_ignore_ascii_case(self.manifest.builder_node.trim_start_matches("0x")) {
+            return Err(format!(
+                "Manifest for {} names {} as builder but was signed by {}",
+                self.manifest.build_id, self.manifest.builder_node, signer
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The latest signed manifest of every build
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BuildManifestStore {
+    manifests: BTreeMap<String, SignedBuildManifest>,
+    /// Latest check of each build's image by node
+    #[serde(default)]
+    verifications: BTreeMap<String, BTreeMap<String, ImageVerification>>,
+}
+
+impl BuildManifestStore {
+    pub fn get(&self, build_id: &str) -> Option<&SignedBuildManifest> {
+        self.manifests.get(build_id)
+    }
+
+    /// Stores a verified manifest unless a newer one for the build is held.
+    /// Returns true if the manifest was stored.
+    pub fn insert(&mut self, manifest: SignedBuildManifest) -> Result<bool, String> {
+        manifest.verify()?;
+        let build_id = manifest.manifest.build_id.clone();
+        if let Some(current) = self.manifests.get(&build_id) {
+            if current.manifest.built_at > manifest.manifest.built_at || current == &manifest {
+                return Ok(false);
+            }
+        }
+        self.manifests.insert(build_id, manifest);
+        Ok(true)
+    }
+
+    /// Inserts every manifest of `other`, keeping the newer one per build
+    pub fn merge(&mut self, other: BuildManifestStore) {
+        for manifest in other.manifests.into_values() {
+            if let Err(e) = self.insert(manifest) {
+                log::warn!("Dropping build manifest that failed verification: {e}");
+            }
+        }
+        for verification in other.verifications.into_values().flat_map(BTreeMap::into_values) {
+            self.record_verification(verification);
+        }
+    }
+
+    /// Stores a node's check of an image unless a later one is held.
+    /// Returns true if it was stored.
+    pub fn record_verification(&mut self, verification: ImageVerification) -> bool {
+        let by_node = self.verifications.entry(verification.build_id.clone()).or_default();
+        if let Some(current) = by_node.get(&verification.node_id) {
+            if current.checked_at > verification.checked_at || current == &verification {
+                return false;
+            }
+        }
+        by_node.insert(verification.node_id.clone(), verification);
+        true
+    }
+
+    /// The latest check of a build's image on each node that booted it
+    pub fn verifications(&self, build_id: &str) -> Vec<&ImageVerification> {
+        self.verifications.get(build_id).map(|by_node| by_node.values().collect()).unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.manifests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.manifests.is_empty()
+    }
+}
+
+/// Sha3-256 of a value's JSON with object keys sorted, so maps in the value
+/// don't change the digest
+pub fn canonical_digest<T: Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    let canonical = serde_json::to_vec(&serde_json::to_value(value)?)?;
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(&canonical);
+    hasher.finalize(&mut hash);
+    Ok(hex::encode(hash))
+}
+
+/// Sha3-256 of a file, read in chunks so disk images aren't held in memory
+pub fn file_digest(path: impl AsRef<Path>) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha3::v256();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+    Ok(hex::encode(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_manifest_verification() {
+        let signing_key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let builder = hex::encode(Address::from_private_key(&signing_key));
+        let manifest = BuildManifest {
+            build_id: "build".to_string(),
+            formfile_digest: "aa".to_string(),
+            base_image_digest: "sha256:bb".to_string(),
+            image_digest: "cc".to_string(),
+            builder_node: builder.clone(),
+            built_at: 10,
+            scan: None,
+            verity: None,
+        };
+
+        let signed = SignedBuildManifest::sign(manifest.clone(), &signing_key).unwrap();
+        assert_eq!(signed.signer().unwrap(), builder);
+        assert!(signed.verify().is_ok());
+
+        // Any change to the manifest breaks the signature
+        let mut tampered = signed.clone();
+        tampered.manifest.image_digest = "dd".to_string();
+        assert!(tampered.verify().is_err());
+
+        let mut store = BuildManifestStore::default();
+        assert!(store.insert(signed.clone()).unwrap());
+        assert!(!store.insert(signed).unwrap());
+        assert!(store.insert(tampered).is_err());
+
+        let older = SignedBuildManifest::sign(BuildManifest { built_at: 5, ..manifest }, &signing_key).unwrap();
+        assert!(!store.insert(older).unwrap());
+        assert_eq!(store.get("build").unwrap().manifest.built_at, 10);
+    }
+
+    #[test]
+    fn test_scan_is_signed_with_manifest() {
+        let signing_key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let manifest = BuildManifest {
+            build_id: "build".to_string(),
+            formfile_digest: "aa".to_string(),
+            base_image_digest: "sha256:bb".to_string(),
+            image_digest: "cc".to_string(),
+            builder_node: hex::encode(Address::from_private_key(&signing_key)),
+            built_at: 10,
+            scan: None,
+            verity: None,
+        };
+        // Unscanned manifests serialize as they did before scans existed
+        assert!(!serde_json::to_string(&manifest).unwrap().contains("scan"));
+        assert!(!serde_json::to_string(&manifest).unwrap().contains("verity"));
+
+        let finding = |id: &str, severity| Vulnerability {
+            id: id.to_string(),
+            package: "openssl".to_string(),
+            installed_version: "3.0.2".to_string(),
+            fixed_version: None,
+            severity,
+            title: None,
+        };
+        let scan = ImageScan {
+            scanner: "trivy".to_string(),
+            scanned_at: 10,
+            findings: vec![
+                finding("CVE-2", VulnerabilitySeverity::High),
+                finding("CVE-1", VulnerabilitySeverity::Low),
+                finding("CVE-3", VulnerabilitySeverity::Critical),
+            ],
+            policy_violation: None,
+        };
+        assert_eq!(scan.counts()[&VulnerabilitySeverity::High], 1);
+        let ids: Vec<_> = scan.at_least(VulnerabilitySeverity::High).iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, ["CVE-3", "CVE-2"]);
+
+        let signed = SignedBuildManifest::sign(BuildManifest { scan: Some(scan), ..manifest }, &signing_key).unwrap();
+        assert!(signed.verify().is_ok());
+        let mut tampered = signed.clone();
+        tampered.manifest.scan.as_mut().unwrap().findings.pop();
+        assert!(tampered.verify().is_err());
+    }
+
+    #[test]
+    fn test_verity_root_hash_is_signed_with_manifest() {
+        let output = "VERITY header information for image.verity\n\
+            UUID:            \t0b7f5a5e-4d1c-4bb2-9c1f-0e2f1c7a9d11\n\
+            Hash type:       \t1\n\
+            Data blocks:     \t2560\n\
+            Data block size: \t4096\n\
+            Hash blocks:     \t22\n\
+            Hash block size: \t4096\n\
+            Hash algorithm:  \tsha256\n\
+            Salt:            \t6F0C6A4D9B4F0A1E\n\
+            Root hash:      \t1F3E9B2C4D5A6B7C8D9E0F1A2B3C4D5E6F7A8B9C0D1E2F3A4B5C6D7E8F9A0B1C\n";
+        let verity = VerityInfo::from_format_output(output).unwrap();
+        assert_eq!(verity.root_hash, "1f3e9b2c4d5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c");
+        assert_eq!(verity.salt, "6f0c6a4d9b4f0a1e");
+        assert_eq!(verity.hash_algorithm, "sha256");
+        assert_eq!(verity.data_bytes(), 2560 * 4096);
+        assert!(VerityInfo::from_format_output("Root hash: not hex\n").is_err());
+        assert_eq!(verity_hash_tree_path("/images/build.raw"), PathBuf::from("/images/build.verity"));
+
+        let signing_key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let manifest = BuildManifest {
+            build_id: "build".to_string(),
+            formfile_digest: "aa".to_string(),
+            base_image_digest: "sha256:bb".to_string(),
+            image_digest: "cc".to_string(),
+            builder_node: hex::encode(Address::from_private_key(&signing_key)),
+            built_at: 10,
+            scan: None,
+            verity: Some(verity),
+        };
+        let signed = SignedBuildManifest::sign(manifest, &signing_key).unwrap();
+        assert!(signed.verify().is_ok());
+        let mut tampered = signed.clone();
+        tampered.manifest.verity.as_mut().unwrap().root_hash = "00".repeat(32);
+        assert!(tampered.verify().is_err());
+    }
+
+    #[test]
+    fn test_latest_verification_per_node_is_kept() {
+        let check = |node: &str, verified, checked_at| ImageVerification {
+            build_id: "build".to_string(),
+            node_id: node.to_string(),
+            root_hash: Some("aa".to_string()),
+            verified,
+            error: (!verified).then(|| "root hash mismatch".to_string()),
+            checked_at,
+        };
+        let mut store = BuildManifestStore::default();
+        assert!(store.record_verification(check("a", true, 10)));
+        assert!(!store.record_verification(check("a", true, 10)));
+        assert!(!store.record_verification(check("a", false, 5)));
+        assert!(store.record_verification(check("b", false, 12)));
+
+        let mut other = BuildManifestStore::default();
+        other.record_verification(check("a", false, 20));
+        store.merge(other);
+        let checks = store.verifications("build");
+        assert_eq!(checks.len(), 2);
+        assert!(checks.iter().all(|check| !check.verified));
+        assert!(store.verifications("other").is_empty());
+    }
+}
@@ -1,21 +1,22 @@
 use std::{collections::{HashMap, HashSet, BTreeSet}, path::PathBuf, sync::Arc};
 use axum::{extract::State, Json};
 use form_dns::{api::{DomainRequest, DomainResponse}, store::FormDnsRecord};
-use form_p2p::queue::{QueueRequest, QueueResponse, QUEUE_PORT};
+use form_p2p::{queue::{QueueRequest, QueueResponse, QUEUE_PORT}, status::QueueHealth};
 use rand::{seq::SliceRandom, thread_rng};
 use reqwest::Client;
-use form_node_metrics::{capabilities::NodeCapabilities, capacity::NodeCapacity, metrics::NodeMetrics, NodeMetricsRequest};
+use form_node_metrics::{capabilities::NodeCapabilities, capacity::NodeCapacity, connectivity::ConnectivityMetrics, metrics::NodeMetrics, NodeMetricsRequest};
 use serde_json::Value;
 use shared::{AssociationContents, Cidr, CidrContents, PeerContents};
-use tiny_keccak::{Hasher, Sha3};
 use tokio::sync::Mutex;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use crdts::{map::Op, BFTReg, CvRDT, Map, CmRDT};
-use crate::{accounts::{Account, AccountOp, AccountState, AuthorizationLevel}, agent::{AIAgent, AgentMap, AgentOp, AgentState}, db::{open_db, write_datastore, DbHandle}, instances::{ClusterMember, Instance, InstanceOp, InstanceState}, model::{AIModel, ModelMap, ModelOp, ModelState}, network::{AssocOp, CidrOp, CrdtAssociation, CrdtCidr, CrdtDnsRecord, CrdtPeer, DnsOp, NetworkState, PeerOp}, nodes::{Node, NodeOp, NodeState}, tasks::{TaskState, Task, TaskOp, TaskStatus, TaskId}};
+use crate::{accounts::{Account, AccountOp, AccountState, AuthorizationLevel}, agent::{AIAgent, AgentMap, AgentOp, AgentState}, db::{open_db, store_value, write_datastore, DbHandle}, instances::{ClusterMember, Instance, InstanceOp, InstanceState, InstanceStatus}, model::{AIModel, ModelMap, ModelOp, ModelState}, network::{AssocOp, CidrOp, CrdtAssociation, CrdtCidr, CrdtDnsRecord, CrdtPeer, DnsOp, NetworkState, PeerOp}, nodes::{Node, NodeMaintenance, NodeOp, NodeState}, organizations::{Organization, OrganizationMap, OrganizationOp, OrganizationState}, staking::StakingState, secrets::SecretStore, fleet_config::FleetConfigState, build_manifests::{BuildManifestStore, ImageVerification, SignedBuildManifest, BUILD_MANIFESTS_DB_KEY}, marketplace::MarketplaceStore, access_policies::{AccessPolicyStore, BalanceCache}, peer_expiry::PeerExpiryStore, maintenance::MaintenanceState, reservations::ReservationStore, domain_verification::DomainVerificationStore, account_deletion::AccountDeletionStore, usage_rollups::UsageRollups, billing::{webhooks::{self, WebhookStore}, reports::ReportStore, stripe::StripeEventLog}, tasks::{TaskState, Task, TaskOp, TaskStatus, TaskId}};
 use lazy_static::lazy_static;
 use url::Host;
 use hex;
 use sha2::Sha256;
+use form_config::remote::RemoteConfig;
+use crate::local_queue::{local_queue, queue_mode, topic_hash, QueueMode};
 
 lazy_static! {
     pub static ref DB_HANDLE: DbHandle = open_db(PathBuf::from("/var/lib/formation/db/form.db"));
@@ -58,7 +59,27 @@ pub struct MergeableState {
     nodes: NodeMap,
     accounts: AccountMap,
     agents: AgentMap,
-    models: ModelMap
+    models: ModelMap,
+    #[serde(default)]
+    organizations: OrganizationMap,
+    #[serde(default)]
+    fleet_config: Option<RemoteConfig>,
+    #[serde(default)]
+    build_manifests: BuildManifestStore,
+    #[serde(default)]
+    marketplace: MarketplaceStore,
+    #[serde(default)]
+    domain_verifications: DomainVerificationStore,
+    #[serde(default)]
+    account_deletions: AccountDeletionStore,
+    #[serde(default)]
+    access_policies: AccessPolicyStore,
+    #[serde(default)]
+    peer_expiry: PeerExpiryStore,
+    #[serde(default)]
+    maintenance: MaintenanceState,
+    #[serde(default)]
+    reservations: ReservationStore,
 }
 
 impl From<DataStore> for MergeableState {
@@ -73,6 +94,16 @@ impl From<DataStore> for MergeableState {
             accounts: value.account_state.map.clone(),
             agents: value.agent_state.map.clone(),
             models: value.model_state.map.clone(),
+            organizations: value.organization_state.map.clone(),
+            fleet_config: value.fleet_config.current().cloned(),
+            build_manifests: value.build_manifests.clone(),
+            marketplace: value.marketplace.clone(),
+            domain_verifications: value.domain_verifications.clone(),
+            account_deletions: value.account_deletions.clone(),
+            access_policies: value.access_policies.clone(),
+            peer_expiry: value.peer_expiry.clone(),
+            maintenance: value.maintenance.clone(),
+            reservations: value.reservations.clone(),
         }
     }
 }
@@ -86,6 +117,39 @@ pub struct DataStore {
     pub agent_state: AgentState,
     pub model_state: ModelState,
     pub task_state: TaskState,
+    pub organization_state: OrganizationState,
+    #[serde(default)]
+    pub staking_state: StakingState,
+    #[serde(skip)]
+    pub secret_state: SecretStore,
+    #[serde(default)]
+    pub fleet_config: FleetConfigState,
+    #[serde(default)]
+    pub build_manifests: BuildManifestStore,
+    #[serde(default)]
+    pub marketplace: MarketplaceStore,
+    #[serde(default)]
+    pub domain_verifications: DomainVerificationStore,
+    #[serde(default)]
+    pub account_deletions: AccountDeletionStore,
+    #[serde(default)]
+    pub access_policies: AccessPolicyStore,
+    #[serde(default)]
+    pub peer_expiry: PeerExpiryStore,
+    #[serde(default)]
+    pub maintenance: MaintenanceState,
+    #[serde(default)]
+    pub reservations: ReservationStore,
+    #[serde(skip)]
+    pub token_balances: BalanceCache,
+    #[serde(skip)]
+    pub usage_rollups: UsageRollups,
+    #[serde(skip)]
+    pub webhooks: WebhookStore,
+    #[serde(skip)]
+    pub reports: ReportStore,
+    #[serde(skip)]
+    pub stripe_events: StripeEventLog,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -190,6 +254,14 @@ pub enum AccountRequest {
     },
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum OrganizationRequest {
+    Op(OrganizationOp),
+    Create(Organization),
+    Update(Organization),
+    Delete(String),
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum AgentRequest {
     Op(AgentOp),
@@ -233,6 +305,7 @@ impl DataStore {
         let agent_state = AgentState::new(node_id.clone(), pk.clone());
         let model_state = ModelState::new(node_id.clone(), pk.clone());
         let task_state = TaskState::new(node_id.clone(), pk.clone());
+        let organization_state = OrganizationState::new(node_id.clone(), pk.clone());
 
 
         Self { 
@@ -243,6 +316,23 @@ impl DataStore {
             agent_state,
             model_state,
             task_state,
+            organization_state,
+            staking_state: StakingState::default(),
+            secret_state: SecretStore::new(&pk),
+            fleet_config: FleetConfigState::default(),
+            build_manifests: BuildManifestStore::default(),
+            marketplace: MarketplaceStore::default(),
+            domain_verifications: DomainVerificationStore::default(),
+            account_deletions: AccountDeletionStore::default(),
+            access_policies: AccessPolicyStore::default(),
+            peer_expiry: PeerExpiryStore::default(),
+            maintenance: MaintenanceState::default(),
+            reservations: ReservationStore::default(),
+            token_balances: BalanceCache::default(),
+            usage_rollups: UsageRollups::default(),
+            webhooks: WebhookStore::default(),
+            stripe_events: StripeEventLog::default(),
+            reports: ReportStore::default(),
         } 
     }
 
@@ -253,19 +343,36 @@ impl DataStore {
     ) -> Self {
         log::info!("Building new datastore from state...");
         let mut local = Self::new(node_id, pk); 
-        local.network_state.peers.merge(other.peers);
-        local.network_state.cidrs.merge(other.cidrs);
-        local.network_state.associations.merge(other.assocs);
-        local.network_state.dns_state.zones.merge(other.dns);
-        local.instance_state.map.merge(other.instances);
-        local.node_state.map.merge(other.nodes);
-        local.account_state.map.merge(other.accounts);
-        local.agent_state.map.merge(other.agents);
-        local.model_state.map.merge(other.models);
+        local.merge_state(other);
         log::info!("Built new datastore from state... Returning...");
         local
     }
 
+    /// Merges another node's state (or a restored backup) into this one
+    pub fn merge_state(&mut self, other: MergeableState) {
+        self.network_state.peers.merge(other.peers);
+        self.network_state.cidrs.merge(other.cidrs);
+        self.network_state.associations.merge(other.assocs);
+        self.network_state.dns_state.zones.merge(other.dns);
+        self.instance_state.map.merge(other.instances);
+        self.node_state.map.merge(other.nodes);
+        self.account_state.map.merge(other.accounts);
+        self.agent_state.map.merge(other.agents);
+        self.model_state.map.merge(other.models);
+        self.organization_state.map.merge(other.organizations);
+        if let Some(fleet_config) = other.fleet_config {
+            self.fleet_config.apply(fleet_config);
+        }
+        self.build_manifests.merge(other.build_manifests);
+        self.marketplace.merge(other.marketplace);
+        self.domain_verifications.merge(other.domain_verifications);
+        self.account_deletions.merge(other.account_deletions);
+        self.access_policies.merge(other.access_policies);
+        self.peer_expiry.merge(other.peer_expiry);
+        self.maintenance.merge(other.maintenance);
+        self.reservations.merge(other.reservations);
+    }
+
     pub fn get_all_users(&self) -> HashMap<String, CrdtPeer<String>> {
         log::info!("Getting all peers from datastore network state...");
         self.network_state.peers.iter().filter_map(|item| {
@@ -378,16 +485,8 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: PeerOp applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "PeerOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing PeerOp ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::PeerRequest::Op(op_to_propagate.clone()), 0, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing PeerOp ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::PeerRequest::Op(op_to_propagate.clone()), 0, &op_to_propagate, "PeerOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
 
@@ -395,8 +494,12 @@ impl DataStore {
     }
 
     pub async fn handle_peer_join(&mut self, contents: PeerContents<String>) -> Result<(), Box<dyn std::error::Error>> {
+        let (name, ip) = (contents.name.to_string(), contents.ip);
         let op = self.network_state.update_peer_local(contents);
         self.handle_peer_op(op).await?;
+        if let Err(e) = self.register_peer_dns(&name, ip).await {
+            log::error!("Unable to register DNS name for peer {name}: {e}");
+        }
 
         Ok(())
     }
@@ -409,8 +512,13 @@ impl DataStore {
     }
 
     pub async fn handle_peer_delete(&mut self, id: String) -> Result<(), Box<dyn std::error::Error>> {
+        let peer_ip = self.network_state.peers.get(&id).val
+            .and_then(|reg| reg.val().map(|v| v.value().ip()));
         let op = self.network_state.remove_peer_local(id);
         self.handle_peer_op(op).await?;
+        if let Some(ip) = peer_ip {
+            self.deregister_peer_dns(ip).await?;
+        }
 
         Ok(())
     }
@@ -449,16 +557,8 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: CIDR Op applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "CidrOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing CIDR Op ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::CidrRequest::Op(op_to_propagate.clone()), 1, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing CIDR Op ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::CidrRequest::Op(op_to_propagate.clone()), 1, &op_to_propagate, "CidrOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
         Ok(())
@@ -517,16 +617,8 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: Assoc Op applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "AssocOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing Assoc Op ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::AssocRequest::Op(op_to_propagate.clone()), 2, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing Assoc Op ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::AssocRequest::Op(op_to_propagate.clone()), 2, &op_to_propagate, "AssocOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
         Ok(())
@@ -580,16 +672,8 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: DNS Op applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "DnsOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing DNS Op ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::DnsRequest::Op(op_to_propagate.clone()), 3, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing DNS Op ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::DnsRequest::Op(op_to_propagate.clone()), 3, &op_to_propagate, "DnsOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
         Ok(())
@@ -686,6 +770,40 @@ impl DataStore {
         Ok(())
     }
 
+    /// Pushes the current record for `domain` to the local form-dns, or
+    /// removes it there if the record no longer exists
+    pub async fn sync_dns_record_to_resolver(&self, domain: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let record = self.network_state.dns_state.zones.get(&domain.to_string()).val
+            .and_then(|reg| reg.val().map(|v| v.value()));
+
+        match record {
+            Some(record) => {
+                let mut ip_addr = record.formnet_ip();
+                ip_addr.extend(record.public_ip());
+                let request = DomainRequest::Create {
+                    domain: record.domain(),
+                    record_type: record.record_type(),
+                    ip_addr,
+                    cname_target: record.cname_target(),
+                    ssl_cert: record.ssl_cert(),
+                };
+                Client::new()
+                    .post("http://127.0.0.1:3005/record/create")
+                    .json(&request)
+                    .send().await?
+                    .json::<DomainResponse>().await?;
+            }
+            None => {
+                Client::new()
+                    .delete(format!("http://127.0.0.1:3005/record/{}/delete", domain))
+                    .send().await?
+                    .json::<DomainResponse>().await?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn handle_instance_request(&mut self, instance_request: InstanceRequest) -> Result<(), Box<dyn std::error::Error>> {
         match instance_request {
             InstanceRequest::Op(op) => self.handle_instance_op(op).await?,
@@ -752,22 +870,16 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: Instance Op applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "InstanceOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing Instance Op ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::InstanceRequest::Op(op_to_propagate.clone()), 4, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing Instance Op ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::InstanceRequest::Op(op_to_propagate.clone()), 4, &op_to_propagate, "InstanceOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
         Ok(())
     }
 
     pub async fn handle_instance_create(&mut self, create: Instance) -> Result<(), Box<dyn std::error::Error>> {
+        self.instance_state.validate_transition(&create)?;
+        self.check_instance_quota(&create)?;
         let op = self.instance_state.update_instance_local(create);
         self.handle_instance_op(op).await?;
 
@@ -775,15 +887,34 @@ impl DataStore {
     }
 
     pub async fn handle_instance_update(&mut self, update: Instance) -> Result<(), Box<dyn std::error::Error>> {
+        self.instance_state.validate_transition(&update)?;
+        self.check_instance_quota(&update)?;
+        // boot_complete is the first update with a formnet address
+        let dns = update.formnet_ip.map(|ip| (update.instance_id.clone(), ip, update.status == InstanceStatus::Deleted));
         let op = self.instance_state.update_instance_local(update);
         self.handle_instance_op(op).await?;
+        match dns {
+            Some((_, ip, true)) => {
+                self.deregister_peer_dns(ip).await?;
+            }
+            Some((instance_id, ip, false)) => {
+                if let Err(e) = self.register_peer_dns(&instance_id, ip).await {
+                    log::error!("Unable to register DNS name for instance {instance_id}: {e}");
+                }
+            }
+            None => {}
+        }
 
         Ok(())
     }
 
     pub async fn handle_instance_delete(&mut self, delete: String) -> Result<(), Box<dyn std::error::Error>> {
+        let formnet_ip = self.instance_state.get_instance(delete.clone()).and_then(|instance| instance.formnet_ip);
         let op = self.instance_state.remove_instance_local(delete);
         self.handle_instance_op(op).await?;
+        if let Some(ip) = formnet_ip {
+            self.deregister_peer_dns(ip).await?;
+        }
 
         Ok(())
     }
@@ -801,14 +932,15 @@ impl DataStore {
     pub async fn handle_node_metrics_request(&mut self, node_metrics_request: NodeMetricsRequest) -> Result<(), Box<dyn std::error::Error>> {
         match node_metrics_request {
             NodeMetricsRequest::SetInitialMetrics { node_id, node_capabilities, node_capacity } => self.handle_node_initial_metrics(node_id, node_capabilities, node_capacity).await?,
-            NodeMetricsRequest::Heartbeat { node_id, timestamp } => self.handle_node_heartbeat(node_id, timestamp).await?,
+            NodeMetricsRequest::Heartbeat { node_id, timestamp, queue } => self.handle_node_heartbeat(node_id, timestamp, queue).await?,
             NodeMetricsRequest::UpdateMetrics { node_id, node_capacity, node_metrics } => self.handle_node_update_metrics(node_id, node_capacity, node_metrics).await?,
+            NodeMetricsRequest::Connectivity { node_id, connectivity } => self.handle_node_connectivity(node_id, connectivity).await?,
         }
         Ok(())
     }
 
-    pub async fn handle_node_heartbeat(&mut self, node_id: String, timestamp: i64) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(node_op) = self.node_state.update_node_heartbeat(node_id, timestamp) {
+    pub async fn handle_node_heartbeat(&mut self, node_id: String, timestamp: i64, queue: Option<QueueHealth>) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(node_op) = self.node_state.update_node_heartbeat(node_id, timestamp, queue) {
             self.handle_node_op(node_op).await?;
         }
         Ok(())
@@ -821,6 +953,20 @@ impl DataStore {
         Ok(())
     }
 
+    pub async fn handle_node_connectivity(&mut self, node_id: String, connectivity: ConnectivityMetrics) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(node_op) = self.node_state.update_node_connectivity(node_id, connectivity) {
+            self.handle_node_op(node_op).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn handle_node_maintenance(&mut self, node_id: String, maintenance: Option<NodeMaintenance>) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(node_op) = self.node_state.set_node_maintenance(node_id, maintenance) {
+            self.handle_node_op(node_op).await?;
+        }
+        Ok(())
+    }
+
     pub async fn handle_node_initial_metrics(&mut self, node_id: String, node_capabilities: NodeCapabilities, node_capacity: NodeCapacity) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(node_op) = self.node_state.set_initial_node_capabilities(node_id, node_capacity, node_capabilities) {
             self.handle_node_op(node_op).await?;
@@ -851,16 +997,8 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: Node Op applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "NodeOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing Node Op ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::NodeRequest::Op(op_to_propagate.clone()), 5, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing Node Op ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::NodeRequest::Op(op_to_propagate.clone()), 5, &op_to_propagate, "NodeOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
         Ok(())
@@ -940,10 +1078,18 @@ impl DataStore {
 
         match &account_op {
             Op::Up { dot: _, key, op } => {
+                let before = self.account_state.get_account(key);
                 self.account_state.account_op(account_op.clone()); // Apply locally
                 if let (true, _) = self.account_state.account_op_success(key.clone(), op.clone()) {
                     log::info!("Account Op::Up successfully applied locally.");
                     op_applied_successfully = true;
+                    if let Some(after) = self.account_state.get_account(key) {
+                        let queued = self.webhooks.pending.len();
+                        self.webhooks.account_changed(before.as_ref(), &after, chrono::Utc::now().timestamp());
+                        if self.webhooks.pending.len() != queued {
+                            webhooks::persist(&self.webhooks);
+                        }
+                    }
                 } else {
                     log::error!("Account Op::Up failed to apply locally or was a no-op.");
                     return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Account Op::Up failed local application")));
@@ -957,16 +1103,8 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: Account Op applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "AccountOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing Account Op ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::AccountRequest::Op(op_to_propagate.clone()), 7, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing Account Op ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::AccountRequest::Op(op_to_propagate.clone()), 7, &op_to_propagate, "AccountOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
         Ok(())
@@ -1100,6 +1238,79 @@ impl DataStore {
         Ok(())
     }
 
+    /// Stores a build manifest published by the node that built the image.
+    /// Only manifests signed by a registered node are accepted.
+    pub fn handle_build_manifest(&mut self, manifest: SignedBuildManifest) -> Result<(), Box<dyn std::error::Error>> {
+        let signer = manifest.signer()?;
+        if self.node_state.get_node(signer.clone()).is_none() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("Build manifest for {} was signed by unknown node {}", manifest.manifest.build_id, signer)
+            )));
+        }
+        if self.build_manifests.insert(manifest)? {
+            store_value(&DB_HANDLE, BUILD_MANIFESTS_DB_KEY, &self.build_manifests)?;
+        }
+        Ok(())
+    }
+
+    /// Records a node's integrity check of an image it was asked to boot
+    pub fn handle_image_verification(&mut self, verification: ImageVerification) -> Result<(), Box<dyn std::error::Error>> {
+        if !verification.verified {
+            log::warn!(
+                "Node {} refused to boot {}: {}",
+                verification.node_id, verification.build_id, verification.error.as_deref().unwrap_or("unknown reason")
+            );
+        }
+        if self.build_manifests.record_verification(verification) {
+            store_value(&DB_HANDLE, BUILD_MANIFESTS_DB_KEY, &self.build_manifests)?;
+        }
+        Ok(())
+    }
+
+    pub async fn handle_organization_request(&mut self, organization_request: OrganizationRequest) -> Result<(), Box<dyn std::error::Error>> {
+        match organization_request {
+            // Ops pulled from the queue were already propagated by the node that created them
+            OrganizationRequest::Op(op) => {
+                self.organization_state.organization_op(op);
+                write_datastore(&DB_HANDLE, &self.clone())?;
+            }
+            OrganizationRequest::Create(organization) | OrganizationRequest::Update(organization) => {
+                let op = self.organization_state.update_organization_local(organization);
+                self.handle_organization_op(op).await?;
+            }
+            OrganizationRequest::Delete(org_id) => {
+                if self.organization_state.get_organization(&org_id).is_none() {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("Organization {} does not exist", org_id)
+                    )));
+                }
+                let op = self.organization_state.remove_organization_local(org_id);
+                self.handle_organization_op(op).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply a locally created organization op and propagate it to the other nodes
+    pub async fn handle_organization_op(&mut self, organization_op: OrganizationOp) -> Result<(), Box<dyn std::error::Error>> {
+        if let Op::Up { key, op, .. } = &organization_op {
+            self.organization_state.organization_op(organization_op.clone());
+            if let (false, _) = self.organization_state.organization_op_success(key.clone(), op.clone()) {
+                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Organization Op::Up failed local application")));
+            }
+        } else {
+            self.organization_state.organization_op(organization_op.clone());
+        }
+
+        self.propagate_op(OrganizationRequest::Op(organization_op.clone()), 10, &organization_op, "OrganizationOp").await?;
+
+        write_datastore(&DB_HANDLE, &self.clone())?;
+        Ok(())
+    }
+
     pub async fn handle_agent_request(&mut self, account_request: AgentRequest) -> Result<(), Box<dyn std::error::Error>> {
         match account_request {
             AgentRequest::Op(op) => {
@@ -1311,85 +1522,90 @@ impl DataStore {
         }
 
         if op_applied_successfully {
-            #[cfg(feature = "devnet")]
-            {
-                log::info!("devnet mode: Task Op applied locally. Gossiping directly with op: {:?}", op_to_propagate);
-                self.gossip_op_directly(&op_to_propagate, "TaskOp").await?;
-            }
-            #[cfg(not(feature = "devnet"))]
-            {
-                log::info!("production mode: Queuing Task Op ({:?}).", op_to_propagate);
-                DataStore::write_to_queue(crate::datastore::TaskRequest::Op(op_to_propagate.clone()), 10, "global_crdt_ops".to_string()).await?;
-            }
+            log::info!("Queuing Task Op ({:?}).", op_to_propagate);
+            self.propagate_op(crate::datastore::TaskRequest::Op(op_to_propagate.clone()), 10, &op_to_propagate, "TaskOp").await?;
             write_datastore(&DB_HANDLE, &self.clone())?;
         }
 
         Ok(())
     }
-    #[cfg(not(feature = "devnet"))]
+    /// Queues an op for the other nodes. The local queue isn't shared with
+    /// them, in the local queue mode the op is gossiped to them as well.
+    async fn propagate_op<O: Serialize + Clone + std::fmt::Debug>(
+        &self,
+        request: impl Serialize + Clone + std::fmt::Debug,
+        sub_topic: u8,
+        op: &O,
+        op_type_description: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        DataStore::write_to_queue(request, sub_topic, "global_crdt_ops".to_string()).await?;
+        if queue_mode() == QueueMode::Local {
+            self.gossip_op_directly(op, op_type_description).await?;
+        }
+        Ok(())
+    }
+
     pub async fn write_to_queue(
         message: impl Serialize + Clone + std::fmt::Debug, 
         sub_topic: u8,
         topic_string: String, // New parameter
     ) -> Result<(), Box<dyn std::error::Error>> {
-        use reqwest::Client; 
-        use form_p2p::queue::{QueueRequest, QueueResponse, QUEUE_PORT};
-        use tiny_keccak::{Hasher, Sha3};
-        use hex;
-
-        let mut hasher = Sha3::v256();
-        let mut topic_hash_bytes = [0u8; 32];
-        hasher.update(topic_string.as_bytes()); // Use dynamic topic_string for hashing
-        hasher.finalize(&mut topic_hash_bytes);
-        
         let mut message_code = vec![sub_topic];
         message_code.extend(serde_json::to_vec(&message)?);
         
         let request_payload = QueueRequest::Write { 
             content: message_code, 
-            topic: hex::encode(topic_hash_bytes) 
+            topic: topic_hash(&topic_string),
         };
 
-        log::debug!("Writing to queue (topic: '{}', sub_topic: {}): {:?}", topic_string, sub_topic, request_payload);
-
-        match Client::new()
-            .post(format!("http://127.0.0.1:{}/queue/write_local", QUEUE_PORT))
-            .json(&request_payload)
-            .send().await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    match response.json::<QueueResponse>().await {
-                        Ok(QueueResponse::OpSuccess) => Ok(()),
-                        Ok(QueueResponse::Failure { reason }) => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write failed for topic '{}': {:?}", topic_string, reason)))),
-                        Ok(other_resp) => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write for topic '{}': Unexpected response variant: {:?}", topic_string, other_resp)))),
-                        Err(e) => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write for topic '{}': Failed to parse response JSON: {}", topic_string, e))))
-                    }
-                } else {
+        let mode = queue_mode();
+        log::debug!("Writing to the {} queue (topic: '{}', sub_topic: {}): {:?}", mode, topic_string, sub_topic, request_payload);
+
+        let response = match mode {
+            QueueMode::Local => local_queue().request(request_payload).await?,
+            QueueMode::Network => {
+                let response = Client::new()
+                    .post(format!("http://127.0.0.1:{}/queue/write_local", QUEUE_PORT))
+                    .json(&request_payload)
+                    .send().await?;
+                if !response.status().is_success() {
                     let status = response.status(); // Store status before consuming response for text
                     let err_text = response.text().await.unwrap_or_default();
-                    Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write for topic '{}' failed with status: {}, body: {}", topic_string, status, err_text))))
+                    return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write for topic '{}' failed with status: {}, body: {}", topic_string, status, err_text))));
                 }
+                response.json::<QueueResponse>().await
+                    .map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write for topic '{}': Failed to parse response JSON: {}", topic_string, e))))?
             }
-            Err(e) => Err(Box::new(e)), // This error already implies context of the call
+        };
+
+        match response {
+            QueueResponse::OpSuccess => Ok(()),
+            QueueResponse::Failure { reason } => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write failed for topic '{}': {:?}", topic_string, reason)))),
+            other_resp => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Queue write for topic '{}': Unexpected response variant: {:?}", topic_string, other_resp)))),
         }
     }
 
-    #[cfg(feature = "devnet")]
-    pub async fn write_to_queue(
-        message: impl Serialize + Clone + std::fmt::Debug,
-        sub_topic: u8,
-        topic_string: String, // New parameter, used in log
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        log::info!("DEVNET MODE: Skipped queue write for topic '{}', subtopic {}. Message: {:?}", topic_string, sub_topic, serde_json::to_string(&message).unwrap_or_else(|_| "<serialization error>".to_string()));
-        Ok(())
+    pub async fn read_from_queue(
+        last: Option<usize>,
+        n: Option<usize>,
+    ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        DataStore::read_topic_from_queue("state", last, n).await
     }
 
-    #[cfg(not(feature = "devnet"))]
-    pub async fn read_from_queue(
+    pub async fn read_topic_from_queue(
+        topic: &str,
         last: Option<usize>,
         n: Option<usize>,
     ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
-        let mut endpoint = format!("http://127.0.0.1:{}/queue/state", QUEUE_PORT);
+        if queue_mode() == QueueMode::Local {
+            return match local_queue().read(topic, last, n).await? {
+                QueueResponse::List(list) => Ok(list),
+                QueueResponse::Failure { reason } => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("{reason:?}")))),
+                _ => Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("Invalid response variant from the local queue for {topic}")))),
+            };
+        }
+
+        let mut endpoint = format!("http://127.0.0.1:{}/queue/{}", QUEUE_PORT, topic);
         if let Some(idx) = last {
             let idx = idx;
             endpoint.push_str(&format!("/{idx}"));
@@ -1421,16 +1637,6 @@ impl DataStore {
         }
     }
 
-    #[cfg(feature = "devnet")]
-    pub async fn read_from_queue(
-        _last: Option<usize>,
-        _n: Option<usize>,
-    ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
-        // In devnet mode, the queue reader doesn't process any messages
-        // Return an empty list since there are no messages to process in devnet mode
-        Ok(Vec::new())
-    }
-
     pub async fn broadcast<R: DeserializeOwned>(
         &mut self,
         request: impl Serialize + Clone,
@@ -1475,14 +1681,12 @@ impl DataStore {
         Ok(())
     }
 
-    #[cfg(feature = "devnet")]
     async fn gossip_op_directly<O: Serialize + Clone + std::fmt::Debug>(
         &self, 
         operation: &O, 
         op_type_description: &str
     ) -> Result<(), Box<dyn std::error::Error>> {
         use k256::ecdsa::SigningKey;
-        use k256::ecdsa::signature::Signer; // For sign_recoverable
         use sha2::{Sha256, Digest as Sha2Digest}; // Import and alias Digest
         use crate::api::DevnetGossipOpContainer; // Make sure this path is correct
 
@@ -1567,7 +1771,7 @@ impl DataStore {
     async fn dispatch_task_to_node(&self, task: &crate::tasks::Task, node: &crate::nodes::Node) -> Result<(), Box<dyn std::error::Error>> {
         log::info!("Dispatching task {} to node {}", task.task_id, node.node_id);
 
-        let client = reqwest::Client::new(); // Used for devnet direct HTTP calls
+        let client = reqwest::Client::new(); // Used for direct HTTP calls in the local queue mode
 
         match &task.task_variant {
             crate::tasks::TaskVariant::LaunchInstance(params) => {
@@ -1580,8 +1784,9 @@ impl DataStore {
                         runtime_env_vars: params.runtime_env_vars.clone(),
                     };
 
-                    #[cfg(feature = "devnet")]
-                    {
+                    // The local queue isn't read by the node's vmm-service, the task is sent to it directly
+                    if queue_mode() == QueueMode::Local {
+                        use sha2::Digest;
                         let target_url = format!("{}/internal/dispatch_launch_task", target_service_base_url); // Assuming this endpoint on vmm-service
                         log::info!("DEVNET: Dispatching LaunchInstance task {} to {} at {}", task.task_id, node.node_id, target_url);
                         
@@ -1616,9 +1821,7 @@ impl DataStore {
                                 log::error!("DEVNET: Error dispatching LaunchInstance task {} to {}: {}", task.task_id, target_url, e);
                             }
                         }
-                    }
-                    #[cfg(not(feature = "devnet"))]
-                    {
+                    } else {
                         log::info!("PRODUCTION: Dispatching LaunchInstance task {} to node {} via queue", task.task_id, node.node_id);
                         let vmm_event = form_types::VmmEvent::ProcessLaunchTask(launch_info); // launch_info was constructed above
                         
@@ -1743,7 +1946,19 @@ pub async fn process_message(message: Vec<u8>, state: Arc<Mutex<DataStore>>) ->
         3 => {
             log::info!("Pulled dns request from queue, processing...");
             let dns_request: DnsRequest = serde_json::from_slice(payload)?;
+            // Ops from other nodes only touch the CRDT, the local resolver
+            // has to be told about the records they change
+            let changed_domains = match &dns_request {
+                DnsRequest::Op(Op::Up { key, .. }) => vec![key.clone()],
+                DnsRequest::Op(Op::Rm { keyset, .. }) => keyset.iter().cloned().collect(),
+                _ => vec![],
+            };
             guard.handle_dns_request(dns_request).await?;
+            for domain in changed_domains {
+                if let Err(e) = guard.sync_dns_record_to_resolver(&domain).await {
+                    log::error!("Failed to sync DNS record for {domain} to form-dns: {e}");
+                }
+            }
         },
         4 => {
             log::info!("Pulled instance request from queue, processing...");
@@ -1775,6 +1990,21 @@ pub async fn process_message(message: Vec<u8>, state: Arc<Mutex<DataStore>>) ->
             let model_request: ModelRequest = serde_json::from_slice(payload)?;
             guard.handle_model_request(model_request).await?;
         }
+        10 => {
+            log::info!("Pulled organization request from queue, processing...");
+            let organization_request: OrganizationRequest = serde_json::from_slice(payload)?;
+            guard.handle_organization_request(organization_request).await?;
+        }
+        11 => {
+            log::info!("Pulled build manifest from queue, processing...");
+            let manifest: SignedBuildManifest = serde_json::from_slice(payload)?;
+            guard.handle_build_manifest(manifest)?;
+        }
+        12 => {
+            log::info!("Pulled image verification from queue, processing...");
+            let verification: ImageVerification = serde_json::from_slice(payload)?;
+            guard.handle_image_verification(verification)?;
+        }
         _ => unreachable!()
     }
 
@@ -1852,6 +2082,7 @@ mod tests {
             is_redeemed: false,
             invite_expires: None,
             candidates: vec![],
+            retiring_key: None,
         };
         let peer_ctx = peers.read_ctx().derive_add_ctx(actor.clone());
         let peer_op = peers.update("peer1".to_string(), peer_ctx, |reg, _| {
@@ -1915,7 +2146,9 @@ mod tests {
             last_snapshot: 0,
             formnet_ip: None,
             dns_record: None,
-            status: InstanceStatus::Created,
+            status: InstanceStatus::Booting,
+            status_history: Vec::new(),
+            revision: 0,
             host_region: "us-east".to_string(),
             resources: InstanceResources {
                 vcpus: 2,
@@ -1932,6 +2165,7 @@ mod tests {
             },
             formfile: "".to_string(),
             snapshots: None,
+            schedule: None,
             metadata: InstanceMetadata {
                 tags: vec!["tag1".to_string()],
                 description: "Fake instance".to_string(),
@@ -1951,6 +2185,7 @@ mod tests {
                 monitoring: InstanceMonitoring {
                     logging_enabled: false,
                     metrics_endpoint: "http://localhost".to_string(),
+                    readiness: None,
                 },
             },
         };
@@ -1988,6 +2223,9 @@ mod tests {
             },
             host: Host::Domain("example.com".to_string()),
             operator_keys: vec![],
+            connectivity: Default::default(),
+            queue_health: None,
+            maintenance: None,
         };
         let node_ctx = nodes.read_ctx().derive_add_ctx(actor.clone());
         let node_op = nodes.update("node1".to_string(), node_ctx, |reg, _| {
@@ -2007,6 +2245,16 @@ mod tests {
             accounts: Map::new(),
             agents: Map::new(),
             models: Map::new(),
+            organizations: Map::new(),
+            fleet_config: None,
+            build_manifests: Default::default(),
+            marketplace: Default::default(),
+            domain_verifications: Default::default(),
+            account_deletions: Default::default(),
+            access_policies: Default::default(),
+            peer_expiry: Default::default(),
+            maintenance: Default::default(),
+            reservations: Default::default(),
         };
 
         assert!(serde_json::to_string(&mergeable_state.peers).is_ok());
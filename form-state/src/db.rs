@@ -11,6 +11,7 @@ use crdts::VClock;
 use crdts::{CmRDT, ResetRemove};
 
 use crate::datastore::DataStore;
+use crate::secrets::SECRETS_DB_KEY;
 
 /// Database handle wrapped in Arc for sharing across threads.
 pub type DbHandle = Arc<Database>;
@@ -165,6 +166,29 @@ pub fn write_datastore(db: &Database, datastore: &DataStore) -> Result<(), Box<d
     store_map(db, "network_state/dns", &datastore.network_state.dns_state.zones)?;
     store_map(db, "instance_state/instances", &datastore.instance_state.map)?;
     store_map(db, "node_state/nodes", &datastore.node_state.map)?;
+    store_value(db, SECRETS_DB_KEY, datastore.secret_state.records())?;
 
     Ok(())
 }
+
+/// Stores a single serializable value to redb under `key`.
+pub fn store_value<T: Serialize>(db: &Database, key: &str, value: &T) -> Result<(), Box<dyn std::error::Error>> {
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(ENTRIES_TABLE)?;
+        let bytes = serialize(value)?;
+        table.insert(key.as_bytes(), &bytes[..])?;
+    }
+    write_txn.commit()?;
+    Ok(())
+}
+
+/// Loads a single value stored with `store_value`, `None` if it was never stored.
+pub fn load_value<T: DeserializeOwned>(db: &Database, key: &str) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    let read_txn = db.begin_read()?;
+    let table = read_txn.open_table(ENTRIES_TABLE)?;
+    match table.get(key.as_bytes())? {
+        Some(bytes) => Ok(Some(deserialize(bytes.value())?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,451 @@
+// form-state/src/domain_verification.rs
+// Ownership verification of custom domains. Before a domain outside of the
+// network can point at a build, its owner proves control of the zone by
+// publishing a challenge token in a TXT or CNAME record. Pending challenges
+// are checked periodically by the node that issued them; a domain is only
+// added to form-dns, and so to the TLS pipeline, once its challenge passed.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use trust_dns_client::client::{AsyncClient, ClientHandle};
+use trust_dns_client::rr::DNSClass;
+use trust_dns_client::udp::UdpClientStream;
+use trust_dns_proto::rr::{Name, RData, RecordType};
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::helpers::dns::VANITY_DOMAIN_SUFFIX;
+
+/// Key under which the domain verifications are persisted in the node's db
+pub const DOMAIN_VERIFICATIONS_DB_KEY: &str = "domains/verifications";
+
+/// Label the challenge record is published under, `_formation-challenge.<domain>`
+pub const CHALLENGE_LABEL: &str = "_formation-challenge";
+
+/// Prefix of the TXT challenge value, `formation-verification=<token>`
+pub const TXT_CHALLENGE_PREFIX: &str = "formation-verification=";
+
+/// Zone CNAME challenges point into, `<token>.verify.formation.cloud`. The
+/// target never has to resolve, only the CNAME itself is read.
+pub const CNAME_CHALLENGE_ZONE: &str = "verify.formation.cloud";
+
+/// How long a challenge stays open before it expires
+pub const CHALLENGE_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeMethod {
+    #[default]
+    Txt,
+    Cname,
+}
+
+impl ChallengeMethod {
+    fn record_type(&self) -> RecordType {
+        match self {
+            ChallengeMethod::Txt => RecordType::TXT,
+            ChallengeMethod::Cname => RecordType::CNAME,
+        }
+    }
+}
+
+/// Ordered so a verification that concluded wins over a concurrent update
+/// of the same second that didn't
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DomainVerificationStatus {
+    Pending,
+    Expired,
+    Verified,
+}
+
+/// The record the owner has to create
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChallengeInstructions {
+    pub record_type: String,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainVerification {
+    pub domain: String,
+    /// The build the domain may point at once verified
+    pub build_id: String,
+    pub owner: String,
+    pub method: ChallengeMethod,
+    pub token: String,
+    pub status: DomainVerificationStatus,
+    /// Whether a TLS certificate should be issued for the domain
+    #[serde(default)]
+    pub ssl_cert: bool,
+    /// Node that checks the challenge periodically
+    pub issued_by: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    #[serde(default)]
+    pub last_checked: Option<i64>,
+    #[serde(default)]
+    pub verified_at: Option<i64>,
+    #[serde(default)]
+    pub attempts: u32,
+    /// Why the last check didn't pass
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+impl DomainVerification {
+    pub fn new(
+        domain: String,
+        build_id: String,
+        owner: String,
+        method: ChallengeMethod,
+        ssl_cert: bool,
+        issued_by: String,
+        now: i64,
+    ) -> Self {
+        let mut token = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut token);
+        Self {
+            domain,
+            build_id,
+            owner,
+            method,
+            token: hex::encode(token),
+            status: DomainVerificationStatus::Pending,
+            ssl_cert,
+            issued_by,
+            created_at: now,
+            updated_at: now,
+            last_checked: None,
+            verified_at: None,
+            attempts: 0,
+            last_error: None,
+        }
+    }
+
+    pub fn record_name(&self) -> String {
+        format!("{CHALLENGE_LABEL}.{}", self.domain)
+    }
+
+    pub fn expected_value(&self) -> String {
+        match self.method {
+            ChallengeMethod::Txt => format!("{TXT_CHALLENGE_PREFIX}{}", self.token),
+            ChallengeMethod::Cname => format!("{}.{CNAME_CHALLENGE_ZONE}", self.token),
+        }
+    }
+
+    pub fn instructions(&self) -> ChallengeInstructions {
+        ChallengeInstructions {
+            record_type: self.method.record_type().to_string(),
+            name: self.record_name(),
+            value: self.expected_value(),
+        }
+    }
+
+    /// Whether any of the looked up values is the expected one. TXT values
+    /// may come back quoted and CNAME targets fully qualified.
+    pub fn matches(&self, answers: &[String]) -> bool {
+        let expected = self.expected_value();
+        answers.iter().any(|answer| {
+            answer.trim().trim_matches('"').trim_end_matches('.').eq_ignore_ascii_case(&expected)
+        })
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.status == DomainVerificationStatus::Pending && now - self.created_at > CHALLENGE_TTL_SECS
+    }
+
+    /// Records the outcome of a lookup of the challenge record
+    pub fn record_check(&mut self, answers: Result<Vec<String>, String>, now: i64) {
+        self.attempts += 1;
+        self.last_checked = Some(now);
+        self.updated_at = now;
+        match answers {
+            Ok(answers) if self.matches(&answers) => {
+                self.status = DomainVerificationStatus::Verified;
+                self.verified_at = Some(now);
+                self.last_error = None;
+            }
+            Ok(answers) if answers.is_empty() => {
+                self.last_error = Some(format!("No {} record found at {}", self.method.record_type(), self.record_name()));
+            }
+            Ok(answers) => {
+                self.last_error = Some(format!("{} holds {:?}, expected {}", self.record_name(), answers, self.expected_value()));
+            }
+            Err(e) => self.last_error = Some(e),
+        }
+        if self.is_expired(now) {
+            self.status = DomainVerificationStatus::Expired;
+        }
+    }
+}
+
+/// Validates a custom domain and returns it lowercased without a trailing dot
+pub fn normalize_domain(domain: &str) -> Result<String, String> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() < 2 || domain.len() > 253 {
+        return Err(format!("{domain} is not a fully qualified domain name"));
+    }
+    for label in &labels {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("{domain} has an empty or too long label"));
+        }
+        if !label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            || label.starts_with('-')
+            || label.ends_with('-')
+        {
+            return Err(format!("{domain} is not a valid domain name"));
+        }
+    }
+    if labels.last() == Some(&VANITY_DOMAIN_SUFFIX) {
+        return Err(format!(".{VANITY_DOMAIN_SUFFIX} domains are claimed with `form dns claim`, not verified"));
+    }
+    Ok(domain)
+}
+
+/// The verification of every custom domain, replicated between nodes with
+/// the newest update winning
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DomainVerificationStore {
+    verifications: BTreeMap<String, DomainVerification>,
+}
+
+impl DomainVerificationStore {
+    pub fn get(&self, domain: &str) -> Option<&DomainVerification> {
+        self.verifications.get(domain)
+    }
+
+    /// Stores a verification unless a newer one for the domain is held.
+    /// Returns true if it was stored.
+    pub fn upsert(&mut self, verification: DomainVerification) -> bool {
+        if let Some(current) = self.verifications.get(&verification.domain) {
+            if (current.updated_at, current.status) >= (verification.updated_at, verification.status) {
+                return false;
+            }
+        }
+        self.verifications.insert(verification.domain.clone(), verification);
+        true
+    }
+
+    pub fn remove(&mut self, domain: &str) -> Option<DomainVerification> {
+        self.verifications.remove(domain)
+    }
+
+    pub fn merge(&mut self, other: DomainVerificationStore) {
+        for verification in other.verifications.into_values() {
+            self.upsert(verification);
+        }
+    }
+
+    /// Whether `domain` passed its challenge for `build_id`
+    pub fn is_verified_for(&self, domain: &str, build_id: &str) -> bool {
+        self.get(domain).is_some_and(|v| v.status == DomainVerificationStatus::Verified && v.build_id == build_id)
+    }
+
+    /// Pending challenges `node_id` is responsible for checking
+    pub fn pending_for(&self, node_id: &str) -> Vec<DomainVerification> {
+        self.verifications.values()
+            .filter(|v| v.status == DomainVerificationStatus::Pending && v.issued_by == node_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn by_owner(&self, owner: &str) -> Vec<&DomainVerification> {
+        self.verifications.values().filter(|v| v.owner.eq_ignore_ascii_case(owner)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.verifications.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.verifications.is_empty()
+    }
+}
+
+/// Looks up the challenge record of a domain, returning TXT strings joined
+/// per record and CNAME targets
+pub async fn lookup_challenge(
+    resolver: SocketAddr,
+    verification: &DomainVerification,
+    timeout: Duration,
+) -> Result<Vec<String>, String> {
+    let stream = UdpClientStream::<tokio::net::UdpSocket>::with_timeout(resolver, timeout);
+    let (mut client, background) = AsyncClient::connect(stream)
+        .await
+        .map_err(|e| format!("Failed to create DNS client: {e}"))?;
+    tokio::spawn(background);
+
+    let name = Name::from_str(&verification.record_name())
+        .map_err(|e| format!("Invalid domain name: {e}"))?;
+    let response = client.query(name, DNSClass::IN, verification.method.record_type())
+        .await
+        .map_err(|e| format!("DNS query failed: {e}"))?;
+
+    Ok(response.answers().iter().filter_map(|record| match record.data()? {
+        RData::TXT(txt) => Some(txt.txt_data().iter().map(|part| String::from_utf8_lossy(part)).collect()),
+        RData::CNAME(cname) => Some(cname.to_utf8()),
+        _ => None,
+    }).collect())
+}
+
+/// Configuration for the periodic challenge checks
+#[derive(Clone, Debug)]
+pub struct DomainVerifierConfig {
+    pub check_interval: Duration,
+    /// Resolver the challenge records are looked up on, a public one so the
+    /// network's own form-dns isn't asked about zones it doesn't serve
+    pub resolver: SocketAddr,
+    pub lookup_timeout: Duration,
+}
+
+impl Default for DomainVerifierConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(60),
+            resolver: SocketAddr::from(([8, 8, 8, 8], 53)),
+            lookup_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl DomainVerifierConfig {
+    /// Reads `FORM_DOMAIN_VERIFY_RESOLVER` and
+    /// `FORM_DOMAIN_VERIFY_INTERVAL_SECS`, falling back to the defaults
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            check_interval: std::env::var("FORM_DOMAIN_VERIFY_INTERVAL_SECS").ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.check_interval),
+            resolver: std::env::var("FORM_DOMAIN_VERIFY_RESOLVER").ok()
+                .and_then(|addr| addr.parse().ok())
+                .unwrap_or(defaults.resolver),
+            ..defaults
+        }
+    }
+}
+
+/// Stores a verification, persists the store and sends it to the other nodes
+pub async fn save_verification(datastore: &mut DataStore, verification: DomainVerification) {
+    datastore.domain_verifications.upsert(verification.clone());
+    if let Err(e) = store_value(&DB_HANDLE, DOMAIN_VERIFICATIONS_DB_KEY, &datastore.domain_verifications) {
+        log::error!("Unable to persist domain verifications: {e}");
+    }
+    if let Err(e) = datastore.broadcast::<form_types::state::Response<DomainVerification>>(verification, "v1/dns/domain/replicate").await {
+        log::error!("Unable to replicate domain verification: {e}");
+    }
+}
+
+pub async fn run_domain_verifier(
+    datastore: Arc<Mutex<DataStore>>,
+    config: DomainVerifierConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting domain verifier, checking challenges on {} every {:?}", config.resolver, config.check_interval);
+    let mut check = tokio::time::interval(config.check_interval);
+
+    loop {
+        tokio::select! {
+            _ = check.tick() => {
+                check_pending(datastore.clone(), &config).await;
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up the challenge of every pending verification this node issued.
+/// The datastore isn't held while the lookups run.
+pub async fn check_pending(datastore: Arc<Mutex<DataStore>>, config: &DomainVerifierConfig) {
+    let pending = {
+        let guard = datastore.lock().await;
+        guard.domain_verifications.pending_for(&guard.node_state.node_id)
+    };
+
+    for mut verification in pending {
+        let answers = lookup_challenge(config.resolver, &verification, config.lookup_timeout).await;
+        verification.record_check(answers, chrono::Utc::now().timestamp());
+        match verification.status {
+            DomainVerificationStatus::Verified => log::info!("Domain {} verified for build {}", verification.domain, verification.build_id),
+            DomainVerificationStatus::Expired => log::info!("Challenge for {} expired unverified", verification.domain),
+            DomainVerificationStatus::Pending => log::debug!("Challenge for {} not passed yet: {:?}", verification.domain, verification.last_error),
+        }
+        let mut guard = datastore.lock().await;
+        save_verification(&mut guard, verification).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verification(method: ChallengeMethod) -> DomainVerification {
+        DomainVerification::new(
+            "app.example.com".to_string(),
+            "build".to_string(),
+            "owner".to_string(),
+            method,
+            true,
+            "node".to_string(),
+            1_000,
+        )
+    }
+
+    #[test]
+    fn test_domain_challenges() {
+        assert_eq!(normalize_domain("App.Example.com."), Ok("app.example.com".to_string()));
+        assert!(normalize_domain("localhost").is_err());
+        assert!(normalize_domain("my-app.fog").is_err());
+        assert!(normalize_domain("bad_label.example.com").is_err());
+
+        let mut txt = verification(ChallengeMethod::Txt);
+        assert_eq!(txt.token.len(), 32);
+        assert_eq!(txt.record_name(), "_formation-challenge.app.example.com");
+        let value = txt.expected_value();
+        assert!(txt.matches(&[format!("\"{value}\"")]));
+        assert!(!txt.matches(&["formation-verification=other".to_string()]));
+
+        txt.record_check(Ok(vec![]), 1_060);
+        assert_eq!(txt.status, DomainVerificationStatus::Pending);
+        assert!(txt.last_error.is_some());
+        txt.record_check(Ok(vec![value]), 1_120);
+        assert_eq!((txt.status, txt.verified_at, txt.attempts), (DomainVerificationStatus::Verified, Some(1_120), 2));
+
+        let mut cname = verification(ChallengeMethod::Cname);
+        assert!(cname.matches(&[format!("{}.verify.formation.cloud.", cname.token)]));
+        cname.record_check(Err("timed out".to_string()), 1_000 + CHALLENGE_TTL_SECS + 1);
+        assert_eq!(cname.status, DomainVerificationStatus::Expired);
+    }
+
+    #[test]
+    fn test_domain_verification_store() {
+        let mut store = DomainVerificationStore::default();
+        let mut pending = verification(ChallengeMethod::Txt);
+        assert!(store.upsert(pending.clone()));
+        assert_eq!(store.pending_for("node").len(), 1);
+        assert!(store.pending_for("other-node").is_empty());
+        assert!(!store.is_verified_for("app.example.com", "build"));
+
+        let mut verified = pending.clone();
+        verified.record_check(Ok(vec![verified.expected_value()]), pending.updated_at);
+        assert!(store.upsert(verified));
+        // A stale pending copy from another node doesn't undo the verification
+        pending.attempts = 5;
+        assert!(!store.upsert(pending));
+        assert!(store.is_verified_for("app.example.com", "build"));
+        assert!(!store.is_verified_for("app.example.com", "other-build"));
+    }
+}
@@ -0,0 +1,237 @@
+// form-state/src/failure_detector.rs
+// Heartbeat-driven failure detection: grades every node by the heartbeats it
+// has missed, publishes node-down/node-up events and re-creates the instances
+// of dead nodes through the autoscaler's vmm commands.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::autoscaler::{emit_decision, is_responsible_for_build, ScalingDecision};
+use crate::datastore::DataStore;
+use crate::instances::{Instance, InstanceStatus};
+use crate::nodes::Node;
+
+/// Queue topic node health transitions are published to
+pub const NODE_EVENTS_TOPIC: &str = "node_events";
+/// Subtopic for `NodeHealthEvent`
+pub const NODE_HEALTH_SUBTOPIC: u8 = 0;
+
+/// Configuration for the failure detector
+#[derive(Clone, Debug)]
+pub struct FailureDetectorConfig {
+    /// How often node liveness is evaluated
+    pub check_interval: Duration,
+    /// How often nodes are expected to heartbeat, form-node-metrics sends one every 30 seconds
+    pub heartbeat_interval_seconds: i64,
+    /// Missed heartbeats after which a node is suspected
+    pub suspect_after: u32,
+    /// Missed heartbeats after which a node is declared dead and its instances re-created
+    pub dead_after: u32,
+}
+
+impl Default for FailureDetectorConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(15),
+            heartbeat_interval_seconds: 30,
+            suspect_after: 2,
+            dead_after: 4,
+        }
+    }
+}
+
+/// How confident the detector is that a node has failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum NodeHealth {
+    /// The node has not sent a heartbeat yet
+    Unknown,
+    Alive,
+    Suspect { missed: u32 },
+    Dead { missed: u32 },
+}
+
+impl NodeHealth {
+    pub fn is_dead(&self) -> bool {
+        matches!(self, NodeHealth::Dead { .. })
+    }
+}
+
+/// Published to `NODE_EVENTS_TOPIC` whenever a node is declared dead or comes back
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeHealthEvent {
+    pub node_id: String,
+    pub health: NodeHealth,
+    pub last_heartbeat: i64,
+    /// Instances on the node that were scheduled for re-creation
+    pub rescheduled_instances: Vec<String>,
+    pub timestamp: i64,
+}
+
+/// Grades a node by the number of heartbeats it has missed
+pub fn node_health(node: &Node, now: i64, config: &FailureDetectorConfig) -> NodeHealth {
+    if node.last_heartbeat <= 0 {
+        return NodeHealth::Unknown;
+    }
+
+    let interval = config.heartbeat_interval_seconds.max(1);
+    let missed = (now.saturating_sub(node.last_heartbeat) / interval).clamp(0, u32::MAX as i64) as u32;
+    if missed >= config.dead_after {
+        NodeHealth::Dead { missed }
+    } else if missed >= config.suspect_after {
+        NodeHealth::Suspect { missed }
+    } else {
+        NodeHealth::Alive
+    }
+}
+
+/// Instances on a failed node that should be brought up elsewhere
+pub fn instances_to_reschedule<'a>(node_id: &str, instances: impl IntoIterator<Item = &'a Instance>) -> Vec<&'a Instance> {
+    instances.into_iter().filter(|i| {
+        i.node_id == node_id && i.status.is_running()
+    }).collect()
+}
+
+/// Runs the failure detector until a shutdown signal is received
+pub async fn run_failure_detector(
+    datastore: Arc<Mutex<DataStore>>,
+    config: FailureDetectorConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!(
+        "Starting failure detector (suspect after {} / dead after {} missed heartbeats)",
+        config.suspect_after, config.dead_after
+    );
+    let mut dead_nodes = HashSet::new();
+    let mut check = tokio::time::interval(config.check_interval);
+
+    loop {
+        tokio::select! {
+            _ = check.tick() => {
+                if let Err(e) = check_nodes(datastore.clone(), &mut dead_nodes, &config).await {
+                    log::error!("Failure detector check failed: {e}");
+                }
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates every node, announcing transitions in and out of the dead state
+/// and re-creating the instances of nodes that just died
+pub async fn check_nodes(
+    datastore: Arc<Mutex<DataStore>>,
+    dead_nodes: &mut HashSet<String>,
+    config: &FailureDetectorConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now().timestamp();
+    let mut guard = datastore.lock().await;
+    let local_node_id = guard.node_state.node_id.clone();
+    let nodes = guard.node_state.list_nodes();
+
+    let health: BTreeMap<String, (NodeHealth, i64)> = nodes.iter()
+        .map(|node| (node.node_id.clone(), (node_health(node, now, config), node.last_heartbeat)))
+        .collect();
+    let alive_nodes: Vec<String> = health.iter()
+        .filter(|(_, (h, _))| !h.is_dead())
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    for (node_id, (status, last_heartbeat)) in &health {
+        if let NodeHealth::Suspect { missed } = status {
+            log::warn!("Node {node_id} is suspected, {missed} heartbeats missed");
+        }
+
+        if !status.is_dead() {
+            if dead_nodes.remove(node_id) {
+                log::info!("Node {node_id} is heartbeating again");
+                publish(NodeHealthEvent {
+                    node_id: node_id.clone(),
+                    health: *status,
+                    last_heartbeat: *last_heartbeat,
+                    rescheduled_instances: vec![],
+                    timestamp: now,
+                }).await;
+            }
+            continue;
+        }
+
+        // A node can't observe its own death, its heartbeats only stop
+        // reaching everybody else
+        if node_id == &local_node_id || !dead_nodes.insert(node_id.clone()) {
+            continue;
+        }
+
+        log::warn!("Node {node_id} declared dead: {status:?}");
+        let instances: Vec<Instance> = guard.instance_state.map.iter().filter_map(|ctx| {
+            let (_, reg) = ctx.val;
+            reg.val().map(|val| val.value())
+        }).collect();
+        let affected: Vec<Instance> = instances_to_reschedule(node_id, &instances).into_iter().cloned().collect();
+
+        let mut rescheduled = Vec::new();
+        for mut instance in affected {
+            // Only one surviving node re-creates a build's instances
+            if !is_responsible_for_build(&instance.build_id, &local_node_id, &alive_nodes) {
+                continue;
+            }
+
+            let decision = ScalingDecision::ScaleOut { build_id: instance.build_id.clone(), count: 1 };
+            if let Err(e) = emit_decision(&decision, std::slice::from_ref(&instance)).await {
+                log::error!("Unable to re-create instance {} of dead node {node_id}: {e}", instance.instance_id);
+                continue;
+            }
+
+            log::info!("Re-creating instance {} of dead node {node_id}", instance.instance_id);
+            instance.status = InstanceStatus::Failed;
+            instance.updated_at = now;
+            rescheduled.push(instance.instance_id.clone());
+            guard.handle_instance_update(instance).await?;
+        }
+
+        publish(NodeHealthEvent {
+            node_id: node_id.clone(),
+            health: *status,
+            last_heartbeat: *last_heartbeat,
+            rescheduled_instances: rescheduled,
+            timestamp: now,
+        }).await;
+    }
+
+    Ok(())
+}
+
+async fn publish(event: NodeHealthEvent) {
+    if let Err(e) = DataStore::write_to_queue(event.clone(), NODE_HEALTH_SUBTOPIC, NODE_EVENTS_TOPIC.to_string()).await {
+        log::error!("Unable to publish node health event for {}: {e}", event.node_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_health_levels() {
+        let config = FailureDetectorConfig::default();
+        let node = |last_heartbeat| Node { last_heartbeat, ..Default::default() };
+
+        assert_eq!(node_health(&node(0), 1_000, &config), NodeHealth::Unknown);
+        assert_eq!(node_health(&node(990), 1_000, &config), NodeHealth::Alive);
+        assert_eq!(node_health(&node(930), 1_000, &config), NodeHealth::Suspect { missed: 2 });
+        assert_eq!(node_health(&node(800), 1_000, &config), NodeHealth::Dead { missed: 6 });
+
+        let instances = vec![
+            Instance { instance_id: "a".to_string(), node_id: "dead".to_string(), status: InstanceStatus::Ready, ..Default::default() },
+            Instance { instance_id: "b".to_string(), node_id: "dead".to_string(), status: InstanceStatus::Stopped, ..Default::default() },
+            Instance { instance_id: "c".to_string(), node_id: "alive".to_string(), status: InstanceStatus::Ready, ..Default::default() },
+        ];
+        let ids: Vec<&str> = instances_to_reschedule("dead", &instances).iter().map(|i| i.instance_id.as_str()).collect();
+        assert_eq!(ids, vec!["a"]);
+    }
+}
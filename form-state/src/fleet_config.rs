@@ -0,0 +1,67 @@
+// form-state/src/fleet_config.rs
+// Fleet-wide operator settings pushed by admins with `form-config push`.
+// Nodes fetch them at startup and poll for changes, see form_config::remote.
+
+use form_config::remote::{RemoteConfig, SharedConfig};
+use serde::{Deserialize, Serialize};
+
+/// Key under which the current fleet config is persisted in the node's db
+pub const FLEET_CONFIG_DB_KEY: &str = "fleet_config/current";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FleetConfigState {
+    current: Option<RemoteConfig>,
+}
+
+impl FleetConfigState {
+    pub fn current(&self) -> Option<&RemoteConfig> {
+        self.current.as_ref()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.current.as_ref().map(|config| config.version).unwrap_or(0)
+    }
+
+    /// Builds the next version of the fleet config for a local push
+    pub fn next(&self, config: SharedConfig, updated_by: &str, updated_at: i64) -> RemoteConfig {
+        RemoteConfig {
+            version: self.version() + 1,
+            config,
+            updated_by: updated_by.to_string(),
+            updated_at,
+        }
+    }
+
+    /// Stores a pushed or replicated config if it is newer than the current one
+    pub fn apply(&mut self, config: RemoteConfig) -> bool {
+        if self.current.as_ref().is_some_and(|current| !config.supersedes(current)) {
+            return false;
+        }
+        self.current = Some(config);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fleet_config_versions() {
+        let mut state = FleetConfigState::default();
+        let shared = SharedConfig { datastore_port: Some(4004), ..Default::default() };
+
+        let first = state.next(shared.clone(), "admin", 10);
+        assert_eq!(first.version, 1);
+        assert!(state.apply(first.clone()));
+        assert!(!state.apply(first));
+
+        let stale = RemoteConfig { version: 1, config: shared.clone(), updated_by: "admin".into(), updated_at: 5 };
+        assert!(!state.apply(stale));
+
+        let second = state.next(SharedConfig::default(), "other", 20);
+        assert!(state.apply(second));
+        assert_eq!(state.version(), 2);
+        assert_eq!(state.current().unwrap().updated_by, "other");
+    }
+}
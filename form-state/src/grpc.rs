@@ -0,0 +1,144 @@
+//! gRPC service for the hot read paths, served next to the HTTP API for
+//! consumers on the node (vmm-service, the scheduler) that look up
+//! instances and ownership on every request. It has no authentication of
+//! its own, so it only listens on the loopback interface.
+use std::sync::Arc;
+use form_types::grpc::{
+    self,
+    state_query_server::{StateQuery, StateQueryServer},
+    CheckOwnershipRequest, CheckOwnershipResponse, GetInstanceRequest, GetNodeCapacityRequest,
+    InstanceInfo, STATE_GRPC_PORT,
+};
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+use crate::accounts::AuthorizationLevel;
+use crate::datastore::DataStore;
+use crate::instances::Instance;
+use crate::nodes::Node;
+
+pub struct StateQueryService {
+    datastore: Arc<Mutex<DataStore>>,
+}
+
+impl StateQueryService {
+    pub fn new(datastore: Arc<Mutex<DataStore>>) -> Self {
+        Self { datastore }
+    }
+}
+
+#[tonic::async_trait]
+impl StateQuery for StateQueryService {
+    async fn get_instance(&self, request: Request<GetInstanceRequest>) -> Result<Response<InstanceInfo>, Status> {
+        let instance_id = request.into_inner().instance_id;
+        let instance = self.datastore.lock().await.instance_state.get_instance(instance_id.clone())
+            .ok_or_else(|| Status::not_found(format!("Instance {instance_id} not found")))?;
+        Ok(Response::new(instance_info(&instance)))
+    }
+
+    async fn check_ownership(&self, request: Request<CheckOwnershipRequest>) -> Result<Response<CheckOwnershipResponse>, Status> {
+        let CheckOwnershipRequest { instance_id, address } = request.into_inner();
+        let datastore = self.datastore.lock().await;
+        let instance = datastore.instance_state.get_instance(instance_id.clone())
+            .ok_or_else(|| Status::not_found(format!("Instance {instance_id} not found")))?;
+
+        let address = address.strip_prefix("0x").unwrap_or(&address);
+        let owner = instance.instance_owner.strip_prefix("0x").unwrap_or(&instance.instance_owner);
+        let is_owner = owner.eq_ignore_ascii_case(address);
+        let organization = datastore.organization_state.organization_owning_instance(&instance_id);
+        let level = if is_owner {
+            Some(AuthorizationLevel::Owner)
+        } else {
+            datastore.organization_state.instance_authorization(address, &instance_id)
+        };
+
+        Ok(Response::new(CheckOwnershipResponse {
+            is_owner,
+            organization_id: organization.map(|org| org.org_id),
+            level: authorization_level(level.as_ref()) as i32,
+        }))
+    }
+
+    async fn get_node_capacity(&self, request: Request<GetNodeCapacityRequest>) -> Result<Response<grpc::NodeCapacity>, Status> {
+        let node_id = request.into_inner().node_id;
+        let node = self.datastore.lock().await.node_state.get_node(node_id.clone())
+            .ok_or_else(|| Status::not_found(format!("Node {node_id} not found")))?;
+        Ok(Response::new(node_capacity(&node)))
+    }
+}
+
+fn instance_info(instance: &Instance) -> InstanceInfo {
+    InstanceInfo {
+        instance_id: instance.instance_id.clone(),
+        node_id: instance.node_id.clone(),
+        build_id: instance.build_id.clone(),
+        instance_owner: instance.instance_owner.clone(),
+        status: format!("{:?}", instance.status),
+        formnet_ip: instance.formnet_ip.map(|ip| ip.to_string()),
+        host_region: instance.host_region.clone(),
+        vcpus: u32::from(instance.resources.vcpus),
+        memory_mb: instance.resources.memory_mb,
+        bandwidth_mbps: instance.resources.bandwidth_mbps,
+        created_at: instance.created_at,
+        updated_at: instance.updated_at,
+    }
+}
+
+fn node_capacity(node: &Node) -> grpc::NodeCapacity {
+    let capacity = node.capacity();
+    grpc::NodeCapacity {
+        node_id: node.node_id.clone(),
+        cpu_total_cores: capacity.cpu_total_cores as u64,
+        cpu_available_cores: capacity.cpu_available_cores,
+        memory_total_bytes: capacity.memory_total_bytes,
+        memory_available_bytes: capacity.memory_available_bytes,
+        storage_total_bytes: capacity.storage_total_bytes,
+        storage_available_bytes: capacity.storage_available_bytes,
+        gpu_total_memory_bytes: capacity.gpu_total_memory_bytes,
+        gpu_available_memory_bytes: capacity.gpu_available_memory_bytes,
+        network_total_bandwidth: capacity.network_total_bandwidth,
+        network_available_bandwidth: capacity.network_available_bandwidth,
+        last_heartbeat: node.last_heartbeat,
+    }
+}
+
+fn authorization_level(level: Option<&AuthorizationLevel>) -> grpc::AuthorizationLevel {
+    match level {
+        Some(AuthorizationLevel::Owner) => grpc::AuthorizationLevel::Owner,
+        Some(AuthorizationLevel::Manager) => grpc::AuthorizationLevel::Manager,
+        Some(AuthorizationLevel::Operator) => grpc::AuthorizationLevel::Operator,
+        Some(AuthorizationLevel::ReadOnly) => grpc::AuthorizationLevel::ReadOnly,
+        None => grpc::AuthorizationLevel::None,
+    }
+}
+
+pub async fn run_grpc(datastore: Arc<Mutex<DataStore>>) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], STATE_GRPC_PORT));
+    log::info!("Running gRPC query service at {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(StateQueryServer::new(StateQueryService::new(datastore)))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_and_level_conversion() {
+        let instance = Instance {
+            instance_id: "instance".to_string(),
+            instance_owner: "0xabc".to_string(),
+            ..Default::default()
+        };
+        let info = instance_info(&instance);
+        assert_eq!(info.instance_id, "instance");
+        assert_eq!(info.status, format!("{:?}", instance.status));
+        assert_eq!(info.formnet_ip, None);
+
+        assert_eq!(authorization_level(None), grpc::AuthorizationLevel::None);
+        assert_eq!(authorization_level(Some(&AuthorizationLevel::Operator)), grpc::AuthorizationLevel::Operator);
+    }
+}
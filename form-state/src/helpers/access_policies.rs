@@ -0,0 +1,160 @@
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::auth::RecoveredAddress;
+use crate::access_policies::{
+    authorize_access, AccessPolicy, AccessPolicyRequest, PolicyTarget, ACCESS_POLICIES_DB_KEY,
+};
+use crate::helpers::instances::can_manage_build;
+use crate::staking::normalize_operator;
+use form_types::state::{Response, Success};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde_json::{json, Value};
+
+fn error(status: StatusCode, message: impl Into<String>) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "success": false, "error": message.into() })))
+}
+
+/// Stores a policy, persists the store and sends the policy to the other
+/// admin nodes
+async fn save_policy(datastore: &mut DataStore, policy: AccessPolicy) {
+    datastore.access_policies.upsert(policy.clone());
+    if let Err(e) = store_value(&DB_HANDLE, ACCESS_POLICIES_DB_KEY, &datastore.access_policies) {
+        log::error!("Unable to persist access policies: {e}");
+    }
+    if let Err(e) = datastore.broadcast::<Response<AccessPolicy>>(policy, "v1/access_policies/replicate").await {
+        log::error!("Unable to replicate access policy: {e}");
+    }
+}
+
+/// Whether the caller may set the policy of a target: the agent's owner, an
+/// owner or manager of the build, or an admin
+fn can_manage_target(datastore: &DataStore, target: &PolicyTarget, caller: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    if datastore.network_state.is_admin_address(caller) {
+        return Ok(());
+    }
+    let allowed = match target {
+        PolicyTarget::Agent(agent_id) => match datastore.agent_state.get_agent(agent_id) {
+            Some(agent) => normalize_operator(&agent.owner_id) == normalize_operator(caller),
+            None => return Err(error(StatusCode::NOT_FOUND, format!("Agent {agent_id} not found"))),
+        },
+        PolicyTarget::Build(build_id) => {
+            let instances = datastore.instance_state.get_instances_by_build_id(build_id.clone());
+            if instances.is_empty() {
+                return Err(error(StatusCode::NOT_FOUND, format!("No instances found for build {build_id}")));
+            }
+            can_manage_build(datastore, &instances, caller)
+        }
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(error(StatusCode::FORBIDDEN, format!("You can't manage access to {} {}", target.kind(), target.id())))
+    }
+}
+
+/// Policies the caller set
+pub async fn list_access_policies(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let policies: Vec<AccessPolicy> = datastore.access_policies.by_owner(&recovered.as_hex()).into_iter().cloned().collect();
+    (StatusCode::OK, Json(json!({ "success": true, "policies": policies })))
+}
+
+pub async fn get_access_policy(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(policy_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let Some(policy) = datastore.access_policies.get(&policy_id).cloned() else {
+        return error(StatusCode::NOT_FOUND, format!("Access policy {policy_id} not found"));
+    };
+    if let Err(e) = can_manage_target(&datastore, &policy.target, &recovered.as_hex()) {
+        return e;
+    }
+    (StatusCode::OK, Json(json!({ "success": true, "policy": policy })))
+}
+
+/// Sets the policy of an agent or build, replacing any it had
+pub async fn create_access_policy(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<AccessPolicyRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    if let Err(e) = can_manage_target(&datastore, &request.target, &caller) {
+        return e;
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let mut policy = match AccessPolicy::new(&caller, request, now) {
+        Ok(policy) => policy,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    // Updates must sort after what they replace, deleted or not
+    if let Some(current) = datastore.access_policies.get(&policy.policy_id) {
+        policy.created_at = current.created_at;
+    }
+    policy.updated_at = datastore.access_policies.latest_update(&policy.policy_id).map_or(now, |latest| now.max(latest + 1));
+    log::info!("{caller} set access policy {} with {} conditions", policy.policy_id, policy.conditions.len());
+    save_policy(&mut datastore, policy.clone()).await;
+
+    (StatusCode::OK, Json(json!({ "success": true, "policy": policy })))
+}
+
+pub async fn delete_access_policy(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(policy_id): Path<String>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let Some(mut policy) = datastore.access_policies.get(&policy_id).cloned() else {
+        return error(StatusCode::NOT_FOUND, format!("Access policy {policy_id} not found"));
+    };
+    if let Err(e) = can_manage_target(&datastore, &policy.target, &recovered.as_hex()) {
+        return e;
+    }
+
+    policy.deleted = true;
+    policy.updated_at = chrono::Utc::now().timestamp().max(policy.updated_at);
+    save_policy(&mut datastore, policy).await;
+    (StatusCode::OK, Json(json!({ "success": true, "policy_id": policy_id })))
+}
+
+/// Whether an address passes the policy of an agent or build, for services
+/// that gate access to instances themselves
+pub async fn check_access_policy(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path((kind, id, address)): Path<(String, String, String)>,
+) -> impl IntoResponse {
+    let Some(target) = PolicyTarget::parse(&kind, id) else {
+        return error(StatusCode::BAD_REQUEST, format!("Unknown policy target {kind}, expected agent or build"));
+    };
+    let result = authorize_access(&state, &target, &address).await;
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "allowed": result.is_ok(),
+        "reason": result.err()
+    })))
+}
+
+pub async fn replicate_access_policy(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(policy): Json<AccessPolicy>,
+) -> Json<Response<AccessPolicy>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated access policy {}", policy.policy_id);
+    if datastore.access_policies.upsert(policy) {
+        if let Err(e) = store_value(&DB_HANDLE, ACCESS_POLICIES_DB_KEY, &datastore.access_policies) {
+            log::error!("Unable to persist access policies: {e}");
+        }
+    }
+    Json(Response::Success(Success::None))
+}
@@ -1,11 +1,17 @@
 use crate::datastore::{DataStore, DB_HANDLE, AccountRequest};
-use crate::db::write_datastore;
+use crate::db::{store_value, write_datastore};
 use crate::accounts::*;
+use crate::account_deletion::{
+    build_archive, deletion_blocker, purge_local_data, save_deletion, AccountDeletion, DeletionStatus,
+    ACCOUNT_DELETIONS_DB_KEY, DEFAULT_GRACE_PERIOD_SECS,
+};
+use crate::organizations::normalize_address;
 use crate::auth::RecoveredAddress;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use axum::{extract::{State, Path, ConnectInfo}, Json, http::StatusCode, response::IntoResponse};
+use crate::revisions::expected_revision;
+use axum::{extract::{State, Path, ConnectInfo}, Json, http::{HeaderMap, StatusCode}, response::IntoResponse};
 use serde_json::json;
 
 pub async fn list_accounts(
@@ -222,6 +228,10 @@ pub async fn create_account(
         );
     }
     
+    // Quotas are set by admins through /quota/:address/set
+    let mut account_to_create = account_to_create;
+    account_to_create.resource_quota = None;
+
     // Create the account
     let op = datastore.account_state.update_account_local(account_to_create);
     
@@ -282,6 +292,7 @@ pub async fn update_account(
     State(state): State<Arc<Mutex<DataStore>>>,
     recovered: Option<RecoveredAddress>,
     ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(request): Json<AccountRequest>,
 ) -> impl IntoResponse {
     log::info!("Received account update request");
@@ -340,9 +351,9 @@ pub async fn update_account(
     let mut datastore = state.lock().await;
     
     match request {
-        AccountRequest::Update(account) => {
+        AccountRequest::Update(mut account) => {
             // Check if the account exists
-            if datastore.account_state.get_account(&account.address).is_none() {
+            let Some(existing) = datastore.account_state.get_account(&account.address) else {
                 return (
                     StatusCode::NOT_FOUND,
                     Json(json!({
@@ -350,6 +361,24 @@ pub async fn update_account(
                         "error": format!("Account with address {} does not exist", account.address)
                     }))
                 );
+            };
+
+            if let Err(e) = datastore.account_state.check_account_revision(&account.address, expected_revision(&headers, account.revision)) {
+                log::warn!("update_account: {e}");
+                return (
+                    StatusCode::CONFLICT,
+                    Json(json!({
+                        "success": false,
+                        "error": e.to_string(),
+                        "current_revision": e.current,
+                        "account": existing
+                    }))
+                );
+            }
+
+            // Users can't raise their own quota
+            if !is_localhost {
+                account.resource_quota = existing.resource_quota;
             }
             
             // Update the account
@@ -663,3 +692,131 @@ pub async fn transfer_instance_ownership(
     }
 }
 
+
+fn deletion_failure(status: StatusCode, error: impl ToString) -> (StatusCode, Json<serde_json::Value>) {
+    (status, Json(json!({ "success": false, "error": error.to_string() })))
+}
+
+/// Account the caller may manage its own deletion for
+fn own_account(datastore: &DataStore, recovered: &RecoveredAddress, address: &str) -> Result<Account, (StatusCode, Json<serde_json::Value>)> {
+    if normalize_address(&recovered.as_hex()) != normalize_address(address) {
+        return Err(deletion_failure(StatusCode::FORBIDDEN, "You can only manage the deletion of your own account"));
+    }
+    datastore.account_state.get_account(address)
+        .or_else(|| datastore.account_state.get_account(&format!("0x{}", normalize_address(address))))
+        .ok_or_else(|| deletion_failure(StatusCode::NOT_FOUND, format!("Account with address {} does not exist", address)))
+}
+
+/// Schedules the deletion of the caller's account. The data is purged once
+/// the grace period is over unless the deletion is cancelled before.
+pub async fn request_account_deletion(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(address): Path<String>,
+) -> impl IntoResponse {
+    // A passkey session alone can't delete the account it was opened for
+    if recovered.via_passkey {
+        return deletion_failure(StatusCode::FORBIDDEN, "Account deletion requires a signed request");
+    }
+
+    let mut datastore = state.lock().await;
+    let account = match own_account(&datastore, &recovered, &address) {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+    if let Some(existing) = datastore.account_deletions.get(&account.address).filter(|d| d.status == DeletionStatus::Scheduled) {
+        return (StatusCode::OK, Json(json!({ "success": true, "deletion": existing })));
+    }
+    if let Some(reason) = deletion_blocker(&datastore, &account.address) {
+        return deletion_failure(StatusCode::CONFLICT, reason);
+    }
+
+    let deletion = AccountDeletion::new(
+        &account.address,
+        datastore.node_state.node_id.clone(),
+        DEFAULT_GRACE_PERIOD_SECS,
+        chrono::Utc::now().timestamp(),
+    );
+    log::info!("Scheduled deletion of account {} for {}", account.address, deletion.purge_after);
+    save_deletion(&mut datastore, deletion.clone()).await;
+    (StatusCode::OK, Json(json!({ "success": true, "deletion": deletion })))
+}
+
+pub async fn cancel_account_deletion(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(address): Path<String>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let account = match own_account(&datastore, &recovered, &address) {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+    let Some(mut deletion) = datastore.account_deletions.get(&account.address).cloned() else {
+        return deletion_failure(StatusCode::NOT_FOUND, "No deletion is scheduled for this account");
+    };
+    if let Err(e) = deletion.cancel(chrono::Utc::now().timestamp()) {
+        return deletion_failure(StatusCode::CONFLICT, e);
+    }
+
+    log::info!("Cancelled deletion of account {}", account.address);
+    save_deletion(&mut datastore, deletion.clone()).await;
+    (StatusCode::OK, Json(json!({ "success": true, "deletion": deletion })))
+}
+
+/// Status of the caller's deletion. Purged accounts no longer exist, so
+/// only the tombstone is looked up.
+pub async fn account_deletion_status(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(address): Path<String>,
+) -> impl IntoResponse {
+    if normalize_address(&recovered.as_hex()) != normalize_address(&address) {
+        return deletion_failure(StatusCode::FORBIDDEN, "You can only view the deletion of your own account");
+    }
+    let datastore = state.lock().await;
+    match datastore.account_deletions.get(&address) {
+        Some(deletion) => (StatusCode::OK, Json(json!({ "success": true, "deletion": deletion }))),
+        None => deletion_failure(StatusCode::NOT_FOUND, "No deletion was requested for this account"),
+    }
+}
+
+/// Everything held about the caller's account, as a JSON download
+pub async fn export_account_data(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(address): Path<String>,
+) -> axum::response::Response {
+    let datastore = state.lock().await;
+    let account = match own_account(&datastore, &recovered, &address) {
+        Ok(account) => account,
+        Err(e) => return e.into_response(),
+    };
+    let Some(archive) = build_archive(&datastore, &account.address, chrono::Utc::now().timestamp()) else {
+        return deletion_failure(StatusCode::NOT_FOUND, "Account not found").into_response();
+    };
+    let disposition = format!("attachment; filename=\"formation-account-{}.json\"", normalize_address(&account.address));
+    (
+        StatusCode::OK,
+        [(axum::http::header::CONTENT_DISPOSITION, disposition)],
+        Json(archive),
+    ).into_response()
+}
+
+pub async fn replicate_account_deletion(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(deletion): Json<AccountDeletion>,
+) -> Json<form_types::state::Response<AccountDeletion>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated deletion of account {}", deletion.address);
+    if datastore.account_deletions.upsert(deletion.clone()) {
+        if let Err(e) = store_value(&DB_HANDLE, ACCOUNT_DELETIONS_DB_KEY, &datastore.account_deletions) {
+            log::error!("Unable to persist account deletions: {e}");
+        }
+        // Usage rollups and webhooks are kept per node, each purges its own
+        if deletion.status == DeletionStatus::Purged {
+            purge_local_data(&mut datastore, &deletion);
+        }
+    }
+    Json(form_types::state::Response::Success(form_types::state::Success::None))
+}
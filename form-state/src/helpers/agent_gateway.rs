@@ -21,6 +21,7 @@ use crate::agent::AIAgent; // Corrected path and struct name
 use crate::instances::{Instance, InstanceStatus}; // Import InstanceStatus as well
 use std::net::IpAddr;
 use super::agent_response::{RunTaskResponse as AgentRunTaskResponse, UsageInfo as AgentUsageInfo, TaskStreamChunk, ApiError}; // Added TaskStreamChunk and ApiError
+use crate::access_policies::{authorize_access, PolicyTarget};
 use crate::billing::{SubscriptionStatus, SubscriptionTier}; // For subscription logic
 // Import error types if you have a specific one for billing/authz failures
 // use crate::error::GatewayError; 
@@ -101,6 +102,16 @@ pub async fn run_agent_task_handler(
         // ds_guard lock is released when it goes out of scope here
     }
 
+    // Token-gated access, checked with the lock released since balances may be
+    // read from the chain. Both the agent's policy and its build's must pass.
+    let target_build_id = agent_details.metadata.get("build_id").map(|s| s.to_string()).unwrap_or_else(|| agent_details.agent_id.clone());
+    for target in [PolicyTarget::Agent(agent_id.clone()), PolicyTarget::Build(target_build_id.clone())] {
+        if let Err(reason) = authorize_access(&datastore, &target, &caller_address_hex).await {
+            log::warn!("Caller {} denied access to {} {}: {}", caller_address_hex, target.kind(), target.id(), reason);
+            return Err(ApiError::new("ACCESS_DENIED", &reason, StatusCode::FORBIDDEN.as_u16()).with_path(&request_path));
+        }
+    }
+
     // --- Sub-task 4.3 (continued): Instance Lookup --- 
     let instance_details: Instance;
     let agent_task_path: String;
@@ -115,10 +126,9 @@ pub async fn run_agent_task_handler(
         agent_task_path = agent_details.metadata.get("task_endpoint_path").map(|s| s.to_string()).unwrap_or_else(|| "/default_task".to_string());
         agent_task_port = agent_details.metadata.get("task_endpoint_port").and_then(|s| s.parse::<u16>().ok()).unwrap_or(8000);
 
-        let target_build_id = agent_details.metadata.get("build_id").map(|s| s.to_string()).unwrap_or_else(|| agent_details.agent_id.clone());
         let running_instance = ds.instance_state.map.iter()
             .filter_map(|ctx| { let (_id, reg) = ctx.val; reg.val().map(|v_reg| v_reg.value()) })
-            .find(|instance: &Instance| instance.build_id == target_build_id && instance.status == InstanceStatus::Started && instance.formnet_ip.is_some());
+            .find(|instance: &Instance| instance.build_id == target_build_id && instance.status == InstanceStatus::Ready && instance.formnet_ip.is_some());
         match running_instance {
             Some(instance) => {
                 instance_details = instance.clone();
@@ -0,0 +1,144 @@
+use crate::auth::RecoveredAddress;
+use crate::backup::{create_backup, diff_states, is_trusted_signer, BackupArchive, BackupError, BackupKey};
+use crate::build_manifests::BUILD_MANIFESTS_DB_KEY;
+use crate::datastore::{DataStore, MergeableState, DB_HANDLE};
+use crate::db::{store_value, write_datastore};
+use crate::fleet_config::FLEET_CONFIG_DB_KEY;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{ConnectInfo, Query, State}, Json};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use serde_json::json;
+
+fn error(status: StatusCode, message: impl ToString) -> Response {
+    (
+        status,
+        Json(json!({
+            "success": false,
+            "error": message.to_string()
+        }))
+    ).into_response()
+}
+
+/// Only network admins and the local operator may export or restore state
+fn authorize(datastore: &DataStore, recovered: &Option<RecoveredAddress>, connection_info: SocketAddr) -> Result<(), Response> {
+    match recovered {
+        Some(recovered) if datastore.network_state.is_admin_address(&recovered.as_hex()) => Ok(()),
+        None if connection_info.ip().is_loopback() => Ok(()),
+        _ => Err(error(StatusCode::FORBIDDEN, "Only network admins can back up or restore state")),
+    }
+}
+
+fn backup_key() -> Result<BackupKey, Response> {
+    BackupKey::from_env().map_err(|e| error(StatusCode::SERVICE_UNAVAILABLE, e))
+}
+
+/// A signed archive of the datastore, encrypted with `FORM_BACKUP_KEY`
+pub async fn export_backup(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+) -> Response {
+    if let Err(response) = authorize(&*state.lock().await, &recovered, connection_info) {
+        return response;
+    }
+    let key = match backup_key() {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+
+    let archive = match create_backup(&state, &key).await {
+        Ok(archive) => archive,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create backup: {e}")),
+    };
+    let body = match serde_json::to_vec(&archive) {
+        Ok(body) => body,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to encode backup: {e}")),
+    };
+    log::info!("Exported backup signed by {} with key {}", archive.header.signer, archive.header.key_id);
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::CONTENT_DISPOSITION, format!(
+                "attachment; filename=\"{}\"",
+                crate::backup::backup_file_name(archive.header.created_at)
+            )),
+        ],
+        body,
+    ).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RestoreQuery {
+    /// Only report what the restore would change
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Verifies an archive and merges it into the datastore, or with
+/// `?dry_run=true` returns what merging it would change
+pub async fn restore_backup(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+    Query(query): Query<RestoreQuery>,
+    Json(archive): Json<BackupArchive>,
+) -> Response {
+    let mut datastore = state.lock().await;
+    if let Err(response) = authorize(&datastore, &recovered, connection_info) {
+        return response;
+    }
+    let key = match backup_key() {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+
+    let restored = match archive.open(&key, |signer| is_trusted_signer(&datastore, signer)) {
+        Ok(restored) => restored,
+        Err(e @ (BackupError::UntrustedSigner(_) | BackupError::WrongKey { .. })) => {
+            return error(StatusCode::FORBIDDEN, e);
+        }
+        Err(e) => return error(StatusCode::BAD_REQUEST, e),
+    };
+    let current: MergeableState = datastore.clone().into();
+    let diff = match diff_states(&current, &restored) {
+        Ok(diff) => diff,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to compare states: {e}")),
+    };
+
+    if !query.dry_run {
+        datastore.merge_state(restored);
+        if let Err(e) = write_datastore(&DB_HANDLE, &datastore) {
+            return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Restored state could not be persisted: {e}"));
+        }
+        if let Err(e) = store_value(&DB_HANDLE, BUILD_MANIFESTS_DB_KEY, &datastore.build_manifests) {
+            log::error!("Unable to persist restored build manifests: {e}");
+        }
+        match serde_json::to_string(&datastore.fleet_config) {
+            Ok(json) => if let Err(e) = store_value(&DB_HANDLE, FLEET_CONFIG_DB_KEY, &json) {
+                log::error!("Unable to persist restored fleet config: {e}");
+            }
+            Err(e) => log::error!("Unable to encode restored fleet config: {e}"),
+        }
+        log::info!(
+            "Restored backup from {} made at {} into {} collections",
+            archive.header.signer, archive.header.created_at, diff.len()
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "dry_run": query.dry_run,
+            "signer": archive.header.signer,
+            "created_at": archive.header.created_at,
+            "diff": diff
+        }))
+    ).into_response()
+}
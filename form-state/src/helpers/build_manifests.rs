@@ -0,0 +1,45 @@
+use crate::datastore::DataStore;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde_json::json;
+
+/// The signed manifest of a build, vmm-service checks images against it
+/// before booting them
+pub async fn get_build_manifest(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(build_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    match datastore.build_manifests.get(&build_id) {
+        Some(manifest) => (
+            StatusCode::OK,
+            Json(json!({
+                "success": true,
+                "manifest": manifest
+            }))
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("No build manifest for {build_id}")
+            }))
+        ),
+    }
+}
+
+/// How each node that was asked to boot the build's image found it
+pub async fn get_image_verifications(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(build_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let verifications = datastore.build_manifests.verifications(&build_id);
+    Json(json!({
+        "success": true,
+        "verifications": verifications
+    }))
+}
@@ -0,0 +1,392 @@
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::auth::RecoveredAddress;
+use crate::domain_verification::{
+    lookup_challenge, normalize_domain, save_verification, ChallengeMethod, DomainVerification,
+    DomainVerificationStatus, DomainVerifierConfig, DOMAIN_VERIFICATIONS_DB_KEY,
+};
+use crate::helpers::instances::can_manage_build;
+use crate::instances::Instance;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use form_dns::{store::{FormDnsRecord, VerificationStatus}, api::{DomainResponse, Success as DnsSuccess}};
+use reqwest::Client;
+use serde::Deserialize;
+use form_types::state::{Response, Success};
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use trust_dns_proto::rr::RecordType;
+
+/// Zone vanity domains are issued under, form-dns and form-rplb serve `.fog`
+/// names from the formnet addresses of the instances
+pub const VANITY_DOMAIN_SUFFIX: &str = "fog";
+
+/// Names that cannot be claimed because the network uses them itself
+const RESERVED_VANITY_NAMES: &[&str] = &["bootstrap", "hello", "admin", "api", "www", "formation"];
+
+#[derive(Debug, Deserialize)]
+pub struct VanityClaimRequest {
+    /// The label to claim, `name` is issued as `name.fog`
+    pub name: String,
+    pub build_id: String,
+}
+
+/// Body of `POST /v1/dns/domain/verify`
+#[derive(Debug, Deserialize)]
+pub struct DomainVerificationRequest {
+    pub domain: String,
+    pub build_id: String,
+    #[serde(default)]
+    pub method: ChallengeMethod,
+    /// Issue a TLS certificate for the domain once it is added
+    #[serde(default)]
+    pub ssl_cert: bool,
+}
+
+type HandlerResponse = (StatusCode, Json<Value>);
+
+fn failure(status: StatusCode, error: impl ToString) -> HandlerResponse {
+    (status, Json(json!({ "success": false, "error": error.to_string() })))
+}
+
+/// Validates a vanity label and returns the fully qualified domain
+pub fn vanity_domain(name: &str) -> Result<String, String> {
+    let name = name.trim().to_lowercase();
+    let name = name.strip_suffix(&format!(".{VANITY_DOMAIN_SUFFIX}")).unwrap_or(&name);
+    if name.len() < 3 || name.len() > 63 {
+        return Err("Vanity names must be between 3 and 63 characters".to_string());
+    }
+    if !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        return Err("Vanity names may only contain letters, digits and hyphens".to_string());
+    }
+    if name.starts_with('-') || name.ends_with('-') {
+        return Err("Vanity names cannot start or end with a hyphen".to_string());
+    }
+    if RESERVED_VANITY_NAMES.contains(&name) {
+        return Err(format!("{name} is reserved"));
+    }
+    Ok(format!("{name}.{VANITY_DOMAIN_SUFFIX}"))
+}
+
+/// The build a domain currently points at, resolved through the formnet
+/// addresses of its record
+fn claimed_build(datastore: &DataStore, domain: &str) -> Option<(FormDnsRecord, Vec<Instance>)> {
+    let record: FormDnsRecord = datastore.network_state.dns_state.zones.get(&domain.to_string()).val?
+        .val()?
+        .value()
+        .into();
+    let build_id = record.formnet_ip.iter()
+        .find_map(|addr| datastore.instance_state.get_instance_by_ip(addr.ip()).ok())
+        .map(|instance| instance.build_id)?;
+    Some((record, datastore.instance_state.get_instances_by_build_id(build_id)))
+}
+
+/// Whether the local form-dns resolver is serving the domain
+async fn resolver_has_record(domain: &str) -> bool {
+    let resp = Client::new()
+        .get(format!("http://127.0.0.1:3005/record/{domain}/get"))
+        .send().await;
+    match resp {
+        Ok(resp) => matches!(resp.json::<DomainResponse>().await, Ok(DomainResponse::Success(DnsSuccess::Some(_)))),
+        Err(e) => {
+            log::warn!("Unable to reach form-dns to check {domain}: {e}");
+            false
+        }
+    }
+}
+
+/// Claims `<name>.fog` for a build. The request must be signed by an account
+/// that can manage the build, the domain is then pointed at the formnet
+/// addresses of the build's instances and replicated to every node's form-dns.
+/// Claiming a name again for another build of the same owner re-points it.
+pub async fn claim_vanity_domain(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<VanityClaimRequest>,
+) -> impl IntoResponse {
+    let domain = match vanity_domain(&request.name) {
+        Ok(domain) => domain,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, e),
+    };
+
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let instances = datastore.instance_state.get_instances_by_build_id(request.build_id.clone());
+    if instances.is_empty() {
+        return failure(StatusCode::NOT_FOUND, format!("No instances found for build {}", request.build_id));
+    }
+    if !can_manage_build(&datastore, &instances, &caller) {
+        log::warn!("claim_vanity_domain: {} does not own build {}", caller, request.build_id);
+        return failure(StatusCode::FORBIDDEN, "Vanity domains can only be claimed by the owner of the build");
+    }
+
+    let formnet_ip = instances.iter()
+        .filter_map(|instance| instance.formnet_ip)
+        .map(|ip: IpAddr| SocketAddr::new(ip, 80))
+        .collect::<Vec<SocketAddr>>();
+    if formnet_ip.is_empty() {
+        return failure(StatusCode::CONFLICT, "The build has no instances with a formnet address yet, try again once they are running");
+    }
+
+    let existing = claimed_build(&datastore, &domain);
+    if datastore.network_state.dns_state.zones.get(&domain).val.is_some() {
+        match &existing {
+            Some((_, current)) if can_manage_build(&datastore, current, &caller) => {
+                log::info!("claim_vanity_domain: {} re-pointing {} to build {}", caller, domain, request.build_id);
+                if let Err(e) = datastore.handle_dns_delete(domain.clone()).await {
+                    return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to release previous record: {e}"));
+                }
+            }
+            _ => return failure(StatusCode::CONFLICT, format!("{domain} is already claimed")),
+        }
+    }
+
+    let record = FormDnsRecord {
+        domain: domain.clone(),
+        record_type: RecordType::A,
+        formnet_ip: formnet_ip.clone(),
+        public_ip: vec![],
+        cname_target: None,
+        ssl_cert: false,
+        ttl: 3600,
+        verification_status: Some(VerificationStatus::Verified),
+        verification_timestamp: Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()),
+    };
+    if let Err(e) = datastore.handle_dns_create(record).await {
+        log::error!("claim_vanity_domain: failed to create record for {domain}: {e}");
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create DNS record: {e}"));
+    }
+
+    log::info!("claim_vanity_domain: {} claimed {} for build {}", caller, domain, request.build_id);
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "domain": domain,
+        "build_id": request.build_id,
+        "owner": caller,
+        "targets": formnet_ip,
+    })))
+}
+
+/// Releases a vanity domain so it can be claimed again
+pub async fn release_vanity_domain(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let domain = match vanity_domain(&name) {
+        Ok(domain) => domain,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, e),
+    };
+
+    let mut datastore = state.lock().await;
+    let authorized = match claimed_build(&datastore, &domain) {
+        Some((_, instances)) => can_manage_build(&datastore, &instances, &recovered.as_hex()),
+        // Records whose instances are gone can only be cleaned up by admins
+        None if datastore.network_state.dns_state.zones.get(&domain).val.is_some() => {
+            datastore.network_state.is_admin_address(&recovered.as_hex())
+        }
+        None => return failure(StatusCode::NOT_FOUND, format!("{domain} is not claimed")),
+    };
+    if !authorized {
+        return failure(StatusCode::FORBIDDEN, "Only the owner of the build can release its vanity domain");
+    }
+
+    if let Err(e) = datastore.handle_dns_delete(domain.clone()).await {
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete DNS record: {e}"));
+    }
+    (StatusCode::OK, Json(json!({ "success": true, "domain": domain })))
+}
+
+/// Claim and propagation status of a vanity domain
+pub async fn vanity_domain_status(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let domain = match vanity_domain(&name) {
+        Ok(domain) => domain,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, e),
+    };
+
+    let datastore = state.lock().await;
+    if datastore.network_state.dns_state.zones.get(&domain).val.is_none() {
+        return (StatusCode::OK, Json(json!({ "success": true, "domain": domain, "claimed": false })));
+    }
+    let claim = claimed_build(&datastore, &domain);
+    drop(datastore);
+
+    let (targets, build_id, owner) = match &claim {
+        Some((record, instances)) => (
+            record.formnet_ip.clone(),
+            instances.first().map(|i| i.build_id.clone()),
+            instances.first().map(|i| i.instance_owner.clone()),
+        ),
+        None => (vec![], None, None),
+    };
+    let propagated = resolver_has_record(&domain).await;
+
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "domain": domain,
+        "claimed": true,
+        "build_id": build_id,
+        "owner": owner,
+        "targets": targets,
+        // The record points at instances that no longer exist
+        "stale": claim.is_none(),
+        "propagated": propagated,
+    })))
+}
+
+fn verification_json(verification: &DomainVerification) -> Value {
+    json!({
+        "success": true,
+        "domain": verification.domain,
+        "build_id": verification.build_id,
+        "owner": verification.owner,
+        "status": verification.status,
+        "method": verification.method,
+        "challenge": verification.instructions(),
+        "attempts": verification.attempts,
+        "last_checked": verification.last_checked,
+        "verified_at": verification.verified_at,
+        "last_error": verification.last_error,
+    })
+}
+
+/// The verification of a domain if the caller owns it or is an admin
+fn owned_verification(datastore: &DataStore, domain: &str, caller: &str) -> Result<DomainVerification, HandlerResponse> {
+    let domain = normalize_domain(domain).map_err(|e| failure(StatusCode::BAD_REQUEST, e))?;
+    match datastore.domain_verifications.get(&domain) {
+        Some(v) if v.owner.eq_ignore_ascii_case(caller) || datastore.network_state.is_admin_address(caller) => Ok(v.clone()),
+        Some(_) => Err(failure(StatusCode::FORBIDDEN, format!("{domain} is being verified by another account"))),
+        None => Err(failure(StatusCode::NOT_FOUND, format!("No verification was requested for {domain}"))),
+    }
+}
+
+/// Issues a challenge for a custom domain. The caller has to manage the
+/// build, and publish the returned TXT or CNAME record before the domain
+/// can be pointed at the build with `request_public`. Asking again for the
+/// same domain, build and method returns the open challenge.
+pub async fn request_domain_verification(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<DomainVerificationRequest>,
+) -> impl IntoResponse {
+    let domain = match normalize_domain(&request.domain) {
+        Ok(domain) => domain,
+        Err(e) => return failure(StatusCode::BAD_REQUEST, e),
+    };
+
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let instances = datastore.instance_state.get_instances_by_build_id(request.build_id.clone());
+    if instances.is_empty() {
+        return failure(StatusCode::NOT_FOUND, format!("No instances found for build {}", request.build_id));
+    }
+    if !can_manage_build(&datastore, &instances, &caller) {
+        return failure(StatusCode::FORBIDDEN, "Domains can only be verified by the owner of the build");
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    if let Some(current) = datastore.domain_verifications.get(&domain) {
+        let same_owner = current.owner.eq_ignore_ascii_case(&caller);
+        if current.status == DomainVerificationStatus::Verified && !same_owner {
+            return failure(StatusCode::CONFLICT, format!("{domain} is already verified by another account"));
+        }
+        let open = match current.status {
+            DomainVerificationStatus::Pending => !current.is_expired(now),
+            DomainVerificationStatus::Verified => true,
+            DomainVerificationStatus::Expired => false,
+        };
+        if open && same_owner && current.build_id == request.build_id && current.method == request.method {
+            return (StatusCode::OK, Json(verification_json(current)));
+        }
+    }
+
+    let verification = DomainVerification::new(
+        domain.clone(),
+        request.build_id.clone(),
+        caller.clone(),
+        request.method,
+        request.ssl_cert,
+        datastore.node_state.node_id.clone(),
+        now,
+    );
+    log::info!("request_domain_verification: {} requested {} for build {}", caller, domain, request.build_id);
+    let response = verification_json(&verification);
+    save_verification(&mut datastore, verification).await;
+    (StatusCode::OK, Json(response))
+}
+
+/// Status of a domain's verification and the record it waits for
+pub async fn domain_verification_status(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(domain): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    match owned_verification(&datastore, &domain, &recovered.as_hex()) {
+        Ok(verification) => (StatusCode::OK, Json(verification_json(&verification))),
+        Err(e) => e,
+    }
+}
+
+/// Checks a pending challenge right away instead of waiting for the next
+/// periodic check
+pub async fn check_domain_verification(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(domain): Path<String>,
+) -> impl IntoResponse {
+    let mut verification = {
+        let datastore = state.lock().await;
+        match owned_verification(&datastore, &domain, &recovered.as_hex()) {
+            Ok(verification) => verification,
+            Err(e) => return e,
+        }
+    };
+    if verification.status != DomainVerificationStatus::Pending {
+        return (StatusCode::OK, Json(verification_json(&verification)));
+    }
+
+    let config = DomainVerifierConfig::from_env();
+    let answers = lookup_challenge(config.resolver, &verification, config.lookup_timeout).await;
+    verification.record_check(answers, chrono::Utc::now().timestamp());
+    let response = verification_json(&verification);
+    save_verification(&mut *state.lock().await, verification).await;
+    (StatusCode::OK, Json(response))
+}
+
+pub async fn replicate_domain_verification(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(verification): Json<DomainVerification>,
+) -> Json<Response<DomainVerification>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated verification of {}", verification.domain);
+    if datastore.domain_verifications.upsert(verification) {
+        if let Err(e) = store_value(&DB_HANDLE, DOMAIN_VERIFICATIONS_DB_KEY, &datastore.domain_verifications) {
+            log::error!("Unable to persist domain verifications: {e}");
+        }
+    }
+    Json(Response::Success(Success::None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vanity_domain_validation() {
+        assert_eq!(vanity_domain("My-App"), Ok("my-app.fog".to_string()));
+        assert_eq!(vanity_domain("my-app.fog"), Ok("my-app.fog".to_string()));
+        assert!(vanity_domain("ab").is_err());
+        assert!(vanity_domain("-app").is_err());
+        assert!(vanity_domain("my_app").is_err());
+        assert!(vanity_domain("sub.app").is_err());
+        assert!(vanity_domain("bootstrap").is_err());
+    }
+}
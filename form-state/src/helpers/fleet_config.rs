@@ -0,0 +1,114 @@
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::auth::RecoveredAddress;
+use crate::fleet_config::FLEET_CONFIG_DB_KEY;
+use form_config::remote::{PushConfigRequest, RemoteConfig};
+use form_types::state::{Response, Success};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, ConnectInfo}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde_json::json;
+
+/// Stored as JSON, the shared config skips unset fields which bincode can't read back
+fn persist(datastore: &DataStore) {
+    match serde_json::to_string(&datastore.fleet_config) {
+        Ok(json) => if let Err(e) = store_value(&DB_HANDLE, FLEET_CONFIG_DB_KEY, &json) {
+            log::error!("Unable to persist fleet config: {e}");
+        }
+        Err(e) => log::error!("Unable to encode fleet config: {e}"),
+    }
+}
+
+/// The current fleet config, it holds no secrets so any node may read it
+pub async fn get_fleet_config(
+    State(state): State<Arc<Mutex<DataStore>>>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "config": datastore.fleet_config.current()
+        }))
+    )
+}
+
+/// Replaces the fleet config, only admins and the local operator may do so
+pub async fn set_fleet_config(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+    Json(request): Json<PushConfigRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let is_localhost = connection_info.ip().is_loopback();
+    let updated_by = match &recovered {
+        Some(recovered) if datastore.network_state.is_admin_address(&recovered.as_hex()) => recovered.as_hex(),
+        None if is_localhost => datastore.node_state.node_id.clone(),
+        _ => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({
+                    "success": false,
+                    "error": "Only network admins can change the fleet config"
+                }))
+            );
+        }
+    };
+
+    if request.config.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "success": false,
+                "error": "The fleet config must set at least one value"
+            }))
+        );
+    }
+
+    if let Some(expected) = request.expected_version {
+        if expected != datastore.fleet_config.version() {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "success": false,
+                    "error": format!(
+                        "The fleet config is at version {}, not {}. Pull it and try again",
+                        datastore.fleet_config.version(), expected
+                    )
+                }))
+            );
+        }
+    }
+
+    let config = datastore.fleet_config.next(request.config, &updated_by, chrono::Utc::now().timestamp());
+    datastore.fleet_config.apply(config.clone());
+    persist(&datastore);
+    log::info!("Fleet config updated to version {} by {}", config.version, updated_by);
+    if let Err(e) = datastore.broadcast::<Response<RemoteConfig>>(config.clone(), "v1/config/replicate").await {
+        log::error!("Unable to replicate fleet config: {e}");
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "config": config
+        }))
+    )
+}
+
+pub async fn replicate_fleet_config(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(config): Json<RemoteConfig>,
+) -> Json<Response<RemoteConfig>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated fleet config version {}", config.version);
+    if datastore.fleet_config.apply(config) {
+        persist(&datastore);
+    }
+    Json(Response::Success(Success::None))
+}
@@ -1,17 +1,23 @@
 use crate::datastore::DataStore;
 use crate::instances::*;
+use crate::instance_health::{fetch_metrics, HealthConfig, InstanceHealth};
+use crate::nodes::Node;
+use crate::accounts::AuthorizationLevel;
 use crate::auth::RecoveredAddress;
 use reqwest::Client;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use form_types::state::{Response, Success};
-use axum::{extract::{State, Path, ConnectInfo}, Json};
+use axum::{extract::{State, Path, Query, ConnectInfo}, Json};
 use form_vm_metrics::system::SystemMetrics;
 use std::net::{IpAddr, SocketAddr};
+use serde::Deserialize;
 use serde_json::json;
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use std::time::{SystemTime, UNIX_EPOCH};
+use crate::revisions::expected_revision;
 
 pub async fn create_instance(
     State(state): State<Arc<Mutex<DataStore>>>,
@@ -46,6 +52,31 @@ pub async fn create_instance(
     
     let mut instance = payload.clone();
     instance.instance_owner = effective_address.to_lowercase();
+
+    // Local calls record instances vmm-service already admitted
+    if !is_localhost {
+        if let Err(freeze) = datastore.check_deployments(Some(&instance.node_id), chrono::Utc::now().timestamp()) {
+            log::warn!("create_instance: {freeze}");
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "error": freeze.to_string(),
+                    "code": "DEPLOYMENTS_FROZEN",
+                    "maintenance": freeze
+                })),
+            );
+        }
+    }
+
+    if let Err(e) = datastore.check_instance_quota(&instance) {
+        log::warn!("create_instance: {e}");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "error": e.to_string()
+            })),
+        );
+    }
             
     let op = datastore.instance_state.update_instance_local(instance.clone());
     if let Err(e) = datastore.handle_instance_op(op).await {
@@ -70,6 +101,7 @@ pub async fn update_instance(
     State(state): State<Arc<Mutex<DataStore>>>,
     recovered: Option<RecoveredAddress>,
     ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<Instance>,
 ) -> impl IntoResponse {
     log::info!("update_instance: Request from: {} for instance_id: {}", connection_info.to_string(), payload.instance_id);
@@ -159,6 +191,43 @@ pub async fn update_instance(
     let mut instance_to_update = payload; // payload is already the full Instance data
     instance_to_update.updated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64; // Ensure updated_at is fresh
 
+    // Someone else wrote the instance since the caller read it
+    let expected = expected_revision(&headers, instance_to_update.revision);
+    if let Err(e) = datastore.instance_state.check_instance_revision(&instance_to_update.instance_id, expected) {
+        log::warn!("update_instance: {e}");
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "success": false,
+                "error": e.to_string(),
+                "current_revision": e.current,
+                "instance": existing_instance
+            })),
+        );
+    }
+
+    if let Err(e) = datastore.instance_state.validate_transition(&instance_to_update) {
+        log::warn!("update_instance: {e}");
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "success": false,
+                "error": e.to_string()
+            })),
+        );
+    }
+
+    if let Err(e) = datastore.check_instance_quota(&instance_to_update) {
+        log::warn!("update_instance: {e}");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": e.to_string()
+            })),
+        );
+    }
+
     let op = datastore.instance_state.update_instance_local(instance_to_update.clone());
     if let Err(e) = datastore.handle_instance_op(op).await {
         log::error!("update_instance: Failed to update instance {}: {}", instance_to_update.instance_id, e);
@@ -172,18 +241,23 @@ pub async fn update_instance(
     }
     
     log::info!("update_instance: Instance {} updated successfully.", instance_to_update.instance_id);
-    (StatusCode::OK, Json(json!({ "success": true, "instance": instance_to_update })))
+    let stored = datastore.instance_state.get_instance(instance_to_update.instance_id.clone()).unwrap_or(instance_to_update);
+    (StatusCode::OK, Json(json!({ "success": true, "instance": stored })))
 }
 
 pub async fn get_instance(
     State(state): State<Arc<Mutex<DataStore>>>,
-    recovered: RecoveredAddress,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
     Path(id): Path<String>,
 ) -> impl IntoResponse {
     log::info!("Attempting to get instance {id}");
     
+    let remote_addr = connection_info.to_string();
+    let is_localhost = remote_addr.starts_with("127.0.0.1") || remote_addr.starts_with("::1");
+
     // Get the authenticated user's address
-    let authenticated_address = recovered.as_hex();
+    let authenticated_address = recovered.map(|r| r.as_hex()).unwrap_or_default();
     
     let datastore = state.lock().await;
     
@@ -192,10 +266,12 @@ pub async fn get_instance(
         let account = datastore.account_state.get_account(&authenticated_address);
         
         // For simplicity, we'll check if:
+        // 0. The request comes from a service on this node, which reads
+        //    instances to update them
         // 1. User is the owner
         // 2. User has any authorization level for this instance
         // 3. User is an admin (using the same helper we used for agents)
-        let is_authorized = match account {
+        let is_authorized = is_localhost || match account {
             Some(account) => {
                 account.owned_instances.contains(&id) || 
                 account.get_authorization_level(&id).is_some() ||
@@ -232,6 +308,46 @@ pub async fn get_instance(
     );
 }
 
+/// The instance's current status and its recorded status changes
+pub async fn get_instance_history(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let authenticated_address = recovered.as_hex();
+    let datastore = state.lock().await;
+
+    let Some(instance) = datastore.instance_state.get_instance(id.clone()) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("Unable to find instance with id: {}", id)
+            }))
+        );
+    };
+
+    if !can_manage_build(&datastore, std::slice::from_ref(&instance), &authenticated_address) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": "You don't have permission to access this instance"
+            }))
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "instance_id": instance.instance_id,
+            "status": instance.status,
+            "history": instance.status_history
+        }))
+    )
+}
+
 pub async fn get_instance_by_build_id(
     State(state): State<Arc<Mutex<DataStore>>>,
     Path(id): Path<String>,
@@ -290,7 +406,7 @@ pub async fn get_instance_metrics(
     if let Some(reg) = datastore.map.get(&id).val {
         if let Some(node) = reg.val() {
             let instance = node.value();
-            if instance.status == InstanceStatus::Started { 
+            if instance.status == InstanceStatus::Ready { 
                 if let Some(ip) = instance.formnet_ip {
                     let endpoint = format!("http://{ip}:63210/get");
                     match Client::new()
@@ -410,6 +526,61 @@ pub async fn get_cluster_metrics(
     }
 }
 
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct InstanceHealthQuery {
+    /// Only the instances of this build
+    #[serde(default)]
+    pub build_id: Option<String>,
+}
+
+/// Merges node liveness, boot status, probe results and metric anomalies of
+/// each instance. The datastore is only locked to copy the instances and
+/// nodes, metrics are fetched from the VMs after.
+async fn instance_health(state: &Arc<Mutex<DataStore>>, filter: impl Fn(&Instance) -> bool) -> Vec<InstanceHealth> {
+    let (instances, nodes) = {
+        let datastore = state.lock().await;
+        let instances: Vec<Instance> = datastore.instance_state.map().iter().filter_map(|ctx| {
+            let (_, reg) = ctx.val;
+            reg.val().map(|node| node.value())
+        }).filter(|instance| filter(instance)).collect();
+        let nodes: HashMap<String, Node> = instances.iter()
+            .filter_map(|instance| datastore.node_state.get_node(instance.node_id.clone()))
+            .map(|node| (node.node_id.clone(), node))
+            .collect();
+        (instances, nodes)
+    };
+
+    let client = Client::new();
+    let metrics = futures::future::join_all(instances.iter().map(|instance| fetch_metrics(&client, instance))).await;
+    let now = chrono::Utc::now().timestamp();
+    let config = HealthConfig::default();
+    instances.iter().zip(metrics).map(|(instance, metrics)| {
+        let metrics = metrics.as_ref().map(|metrics| metrics.as_ref().map_err(Clone::clone));
+        InstanceHealth::aggregate(instance, nodes.get(&instance.node_id), metrics, now, &config)
+    }).collect()
+}
+
+pub async fn get_instance_health(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(id): Path<String>,
+) -> Json<Response<InstanceHealth>> {
+    match instance_health(&state, |instance| instance.instance_id == id).await.pop() {
+        Some(health) => Json(Response::Success(Success::Some(health))),
+        None => Json(Response::Failure { reason: Some("No record of instance in datastore".to_string()) }),
+    }
+}
+
+pub async fn list_instance_health(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Query(query): Query<InstanceHealthQuery>,
+) -> Json<Response<InstanceHealth>> {
+    let health = instance_health(&state, |instance| {
+        instance.status != InstanceStatus::Deleted
+            && query.build_id.as_ref().map_or(true, |build_id| &instance.build_id == build_id)
+    }).await;
+    Json(Response::Success(Success::List(health)))
+}
+
 pub async fn delete_instance(
     State(state): State<Arc<Mutex<DataStore>>>,
     recovered: RecoveredAddress,
@@ -538,3 +709,273 @@ pub async fn list_instances(
         }))
     );
 }
+
+/// Returns true if the caller owns at least one instance of the build or is an admin
+pub(crate) fn can_manage_build(datastore: &DataStore, instances: &[Instance], address: &str) -> bool {
+    if datastore.network_state.is_admin_address(address) {
+        return true;
+    }
+
+    let normalized_address = address.strip_prefix("0x").unwrap_or(address).to_lowercase();
+    instances.iter().any(|instance| {
+        let owner = instance.instance_owner.strip_prefix("0x").unwrap_or(&instance.instance_owner).to_lowercase();
+        owner == normalized_address || matches!(
+            datastore.organization_state.instance_authorization(&normalized_address, &instance.instance_id),
+            Some(AuthorizationLevel::Owner | AuthorizationLevel::Manager)
+        )
+    })
+}
+
+pub async fn get_scaling_policy(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(build_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let instances = datastore.instance_state.get_instances_by_build_id(build_id.clone());
+    if instances.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("No instances found for build_id: {}", build_id)
+            }))
+        );
+    }
+
+    if !can_manage_build(&datastore, &instances, &recovered.as_hex()) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": "You don't have permission to access this build"
+            }))
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "build_id": build_id,
+            "instance_count": crate::autoscaler::active_instances(&instances).len(),
+            "scaling_policy": crate::autoscaler::build_scaling_policy(&instances)
+        }))
+    )
+}
+
+pub async fn update_scaling_policy(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(build_id): Path<String>,
+    Json(payload): Json<Option<ScalingPolicy>>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let instances = datastore.instance_state.get_instances_by_build_id(build_id.clone());
+    if instances.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("No instances found for build_id: {}", build_id)
+            }))
+        );
+    }
+
+    if !can_manage_build(&datastore, &instances, &recovered.as_hex()) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": "You don't have permission to modify this build"
+            }))
+        );
+    }
+
+    // A new policy would be acted on as soon as the freeze lifts, so it has
+    // to wait for it like any other scaling
+    if let Err(freeze) = datastore.check_deployments(None, chrono::Utc::now().timestamp()) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "success": false,
+                "error": freeze.to_string(),
+                "code": "DEPLOYMENTS_FROZEN",
+                "maintenance": freeze
+            }))
+        );
+    }
+
+    // Keep the recorded scale timestamps so an update can't be used to bypass cooldowns
+    let policy = match payload {
+        Some(mut policy) => {
+            if let Err(e) = policy.validate() {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "success": false,
+                        "error": format!("Invalid scaling policy: {}", e)
+                    }))
+                );
+            }
+            if let Some(existing) = crate::autoscaler::build_scaling_policy(&instances) {
+                policy.last_scale_in_time = existing.last_scale_in_time();
+                policy.last_scale_out_time = existing.last_scale_out_time();
+            }
+            Some(policy)
+        }
+        None => None,
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    for mut instance in instances {
+        instance.cluster.set_scaling_policy(policy.clone());
+        instance.updated_at = now;
+        let op = datastore.instance_state.update_instance_local(instance.clone());
+        if let Err(e) = datastore.handle_instance_op(op).await {
+            log::error!("update_scaling_policy: Failed to update instance {}: {}", instance.instance_id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success": false,
+                    "error": format!("Failed to update scaling policy: {}", e)
+                }))
+            );
+        }
+    }
+
+    log::info!("update_scaling_policy: Scaling policy for build {} set to {:?}", build_id, policy);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "build_id": build_id,
+            "scaling_policy": policy
+        }))
+    )
+}
+
+pub async fn get_instance_schedule(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(instance_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let Some(instance) = datastore.instance_state.get_instance(instance_id.clone()) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("Instance not found: {}", instance_id)
+            }))
+        );
+    };
+
+    if !can_manage_build(&datastore, std::slice::from_ref(&instance), &recovered.as_hex()) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": "You don't have permission to access this instance"
+            }))
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "instance_id": instance_id,
+            "status": instance.status,
+            "schedule": instance.schedule,
+            "revision": instance.revision
+        }))
+    )
+}
+
+pub async fn update_instance_schedule(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(instance_id): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<Option<InstanceSchedule>>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let Some(mut instance) = datastore.instance_state.get_instance(instance_id.clone()) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("Instance not found: {}", instance_id)
+            }))
+        );
+    };
+
+    if !can_manage_build(&datastore, std::slice::from_ref(&instance), &recovered.as_hex()) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": "You don't have permission to modify this instance"
+            }))
+        );
+    }
+
+    // The payload carries no revision, so only an If-Match header makes this conditional
+    if let Err(e) = datastore.instance_state.check_instance_revision(&instance_id, expected_revision(&headers, 0)) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "success": false,
+                "error": e.to_string(),
+                "current_revision": e.current,
+                "schedule": instance.schedule
+            }))
+        );
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let schedule = match payload {
+        Some(mut schedule) if !schedule.is_empty() => {
+            if let Err(e) = schedule.validate() {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "success": false,
+                        "error": format!("Invalid schedule: {}", e)
+                    }))
+                );
+            }
+            // Transitions that were already due when the schedule was set are not acted on
+            schedule.last_action_at = now;
+            Some(schedule)
+        }
+        _ => None,
+    };
+
+    instance.schedule = schedule.clone();
+    instance.updated_at = now;
+    let op = datastore.instance_state.update_instance_local(instance);
+    if let Err(e) = datastore.handle_instance_op(op).await {
+        log::error!("update_instance_schedule: Failed to update instance {}: {}", instance_id, e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "success": false,
+                "error": format!("Failed to update schedule: {}", e)
+            }))
+        );
+    }
+
+    log::info!("update_instance_schedule: Schedule for instance {} set to {:?}", instance_id, schedule);
+    let revision = datastore.instance_state.get_instance(instance_id.clone()).map_or(0, |i| i.revision);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "instance_id": instance_id,
+            "schedule": schedule,
+            "revision": revision
+        }))
+    )
+}
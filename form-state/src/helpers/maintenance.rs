@@ -0,0 +1,216 @@
+use crate::auth::RecoveredAddress;
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::maintenance::{DeploymentFreeze, MaintenanceWindow, MAINTENANCE_DB_KEY};
+use crate::nodes::{Node, NodeMaintenance};
+use form_types::state::{Response, Success};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{ConnectInfo, Path, Query, State}, Json};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Body of `/admin/maintenance` and `/admin/maintenance/node/:id`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SetMaintenanceRequest {
+    pub enabled: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Defaults to now
+    #[serde(default)]
+    pub starts_at: Option<i64>,
+    #[serde(default)]
+    pub ends_at: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MaintenanceCheckQuery {
+    #[serde(default)]
+    pub node_id: Option<String>,
+}
+
+fn error(status: StatusCode, message: impl ToString) -> HttpResponse {
+    (
+        status,
+        Json(json!({
+            "success": false,
+            "error": message.to_string()
+        }))
+    ).into_response()
+}
+
+/// The admin changing maintenance, or this node for the local operator
+fn authorize(datastore: &DataStore, recovered: &Option<RecoveredAddress>, connection_info: SocketAddr) -> Result<String, HttpResponse> {
+    match recovered {
+        Some(recovered) if datastore.network_state.is_admin_address(&recovered.as_hex()) => Ok(recovered.as_hex()),
+        None if connection_info.ip().is_loopback() => Ok(datastore.node_state.node_id.clone()),
+        _ => Err(error(StatusCode::FORBIDDEN, "Only network admins can change maintenance mode")),
+    }
+}
+
+/// The response every refused create or scale gets, 503 with the freeze and
+/// a Retry-After when it is known when the freeze lifts
+pub fn frozen_response(freeze: &DeploymentFreeze, now: i64) -> HttpResponse {
+    let body = Json(json!({
+        "success": false,
+        "error": freeze.to_string(),
+        "code": "DEPLOYMENTS_FROZEN",
+        "maintenance": freeze
+    }));
+    match freeze.until {
+        Some(until) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, (until - now).max(1).to_string())],
+            body,
+        ).into_response(),
+        None => (StatusCode::SERVICE_UNAVAILABLE, body).into_response(),
+    }
+}
+
+/// The network-wide window and every node in maintenance
+pub async fn get_maintenance(
+    State(state): State<Arc<Mutex<DataStore>>>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let now = chrono::Utc::now().timestamp();
+    let nodes: Vec<serde_json::Value> = datastore.node_state.list_nodes().into_iter().filter_map(|node| {
+        node.maintenance.map(|maintenance| json!({
+            "node_id": node.node_id,
+            "started_at": maintenance.started_at,
+            "reason": maintenance.reason
+        }))
+    }).collect();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "frozen": datastore.maintenance.active(now).is_some(),
+            "global": datastore.maintenance.global(),
+            "nodes": nodes
+        }))
+    )
+}
+
+/// Whether deployments, on `node_id` if given, are currently allowed.
+/// vmm-service and the MCP server ask this before creating instances.
+pub async fn check_maintenance(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Query(query): Query<MaintenanceCheckQuery>,
+) -> HttpResponse {
+    let datastore = state.lock().await;
+    let now = chrono::Utc::now().timestamp();
+    match datastore.check_deployments(query.node_id.as_deref(), now) {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!({
+                "success": true,
+                "frozen": false
+            }))
+        ).into_response(),
+        Err(freeze) => frozen_response(&freeze, now),
+    }
+}
+
+/// Starts or lifts the network-wide freeze
+pub async fn set_maintenance(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+    Json(request): Json<SetMaintenanceRequest>,
+) -> HttpResponse {
+    let mut datastore = state.lock().await;
+    let updated_by = match authorize(&datastore, &recovered, connection_info) {
+        Ok(updated_by) => updated_by,
+        Err(response) => return response,
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let starts_at = request.starts_at.unwrap_or(now);
+    if request.ends_at.map_or(false, |ends_at| ends_at <= starts_at) {
+        return error(StatusCode::BAD_REQUEST, "The maintenance window must end after it starts");
+    }
+
+    let window = MaintenanceWindow {
+        enabled: request.enabled,
+        reason: request.reason,
+        starts_at,
+        ends_at: request.ends_at,
+        updated_by: updated_by.clone(),
+        updated_at: now,
+    };
+    if !datastore.maintenance.apply(window.clone()) {
+        return error(StatusCode::CONFLICT, "A newer maintenance window is already set, fetch it and try again");
+    }
+    if let Err(e) = store_value(&DB_HANDLE, MAINTENANCE_DB_KEY, &datastore.maintenance) {
+        log::error!("Unable to persist maintenance window: {e}");
+    }
+    log::info!("Network maintenance {} by {}", if window.enabled { "started" } else { "lifted" }, updated_by);
+    if let Err(e) = datastore.broadcast::<Response<MaintenanceWindow>>(window.clone(), "v1/maintenance/replicate").await {
+        log::error!("Unable to replicate maintenance window: {e}");
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "global": window
+        }))
+    ).into_response()
+}
+
+/// Freezes or unfreezes a single node. The freeze is the node's maintenance
+/// mode, so placement avoids the node as well.
+pub async fn set_node_freeze(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+    Path(node_id): Path<String>,
+    Json(request): Json<SetMaintenanceRequest>,
+) -> HttpResponse {
+    let mut datastore = state.lock().await;
+    if let Err(response) = authorize(&datastore, &recovered, connection_info) {
+        return response;
+    }
+    let Some(node) = datastore.node_state.get_node(node_id.clone()) else {
+        return error(StatusCode::NOT_FOUND, format!("Unable to find node with id: {node_id}"));
+    };
+
+    let maintenance = match (request.enabled, node.maintenance) {
+        (true, Some(current)) => Some(NodeMaintenance { reason: request.reason.or(current.reason), ..current }),
+        (true, None) => Some(NodeMaintenance {
+            started_at: request.starts_at.unwrap_or_else(|| chrono::Utc::now().timestamp()),
+            reason: request.reason,
+        }),
+        (false, _) => None,
+    };
+    log::info!("Setting maintenance of node {node_id} to {maintenance:?}");
+    if let Err(e) = datastore.handle_node_maintenance(node_id.clone(), maintenance).await {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Unable to update node {node_id}: {e}"));
+    }
+
+    let node: Option<Node> = datastore.node_state.get_node(node_id);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "node": node
+        }))
+    ).into_response()
+}
+
+pub async fn replicate_maintenance(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(window): Json<MaintenanceWindow>,
+) -> Json<Response<MaintenanceWindow>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated maintenance window from {}", window.updated_by);
+    if datastore.maintenance.apply(window) {
+        if let Err(e) = store_value(&DB_HANDLE, MAINTENANCE_DB_KEY, &datastore.maintenance) {
+            log::error!("Unable to persist maintenance window: {e}");
+        }
+    }
+    Json(Response::Success(Success::None))
+}
@@ -0,0 +1,413 @@
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::auth::RecoveredAddress;
+use crate::accounts::Account;
+use crate::billing::middleware::{check_operation_credits, OperationType};
+use crate::access_policies::{authorize_access, PolicyTarget};
+use crate::marketplace::{
+    ListingKind, ListingPricing, ListingQuery, ListingStatus, MarketplaceListing, MARKETPLACE_DB_KEY,
+};
+use form_types::state::{Response, Success};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path, Query}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+/// Body of `POST /v1/marketplace/publish`. Anything left out is taken from
+/// the model or agent itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishListingRequest {
+    pub kind: ListingKind,
+    pub item_id: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub pricing: Option<ListingPricing>,
+    #[serde(default)]
+    pub required_capabilities: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerateListingRequest {
+    pub status: ListingStatus,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// Body of `POST /v1/marketplace/listing/:listing_id/usage`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListingUsageRequest {
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+    /// Hours an agent was used for
+    #[serde(default)]
+    pub hours: Option<f64>,
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "success": false, "error": message.into() })))
+}
+
+/// Stores a listing, persists the store and sends the listing to the other
+/// admin nodes
+async fn save_listing(datastore: &mut DataStore, listing: MarketplaceListing) {
+    datastore.marketplace.upsert(listing.clone());
+    if let Err(e) = store_value(&DB_HANDLE, MARKETPLACE_DB_KEY, &datastore.marketplace) {
+        log::error!("Unable to persist marketplace listings: {e}");
+    }
+    if let Err(e) = datastore.broadcast::<Response<MarketplaceListing>>(listing, "v1/marketplace/replicate").await {
+        log::error!("Unable to replicate marketplace listing: {e}");
+    }
+}
+
+/// An approved listing, or the error to answer with
+fn approved_listing(datastore: &DataStore, listing_id: &str) -> Result<MarketplaceListing, (StatusCode, Json<Value>)> {
+    match datastore.marketplace.get(listing_id) {
+        Some(listing) if listing.status == ListingStatus::Approved => Ok(listing.clone()),
+        _ => Err(error(StatusCode::NOT_FOUND, format!("Listing {listing_id} not found"))),
+    }
+}
+
+/// Publishes or updates the listing of a model or agent the caller owns.
+/// Every publish goes back to pending until an admin approves it.
+pub async fn publish_listing(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<PublishListingRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+
+    let (owner_id, is_private, title, summary, tags, pricing, capabilities) = match request.kind {
+        ListingKind::Model => match datastore.model_state.get_model(&request.item_id) {
+            Some(model) => (
+                model.owner_id,
+                model.is_private,
+                model.name,
+                model.description,
+                model.tags,
+                ListingPricing { per_1m_tokens: model.price_per_1m_tokens, per_hour: None },
+                model.resource_requirements.requires_gpu,
+            ),
+            None => return error(StatusCode::NOT_FOUND, format!("Model {} not found", request.item_id)),
+        },
+        ListingKind::Agent => match datastore.agent_state.get_agent(&request.item_id) {
+            Some(agent) => (
+                agent.owner_id,
+                agent.is_private,
+                agent.name,
+                agent.description,
+                agent.tags,
+                ListingPricing::default(),
+                agent.resource_requirements.requires_gpu,
+            ),
+            None => return error(StatusCode::NOT_FOUND, format!("Agent {} not found", request.item_id)),
+        },
+    };
+
+    if !owner_id.trim_start_matches("0x").eq_ignore_ascii_case(&caller) && !datastore.network_state.is_admin_address(&caller) {
+        return error(StatusCode::FORBIDDEN, format!("Only the owner can list this {}", request.kind.as_str()));
+    }
+    if is_private {
+        return error(StatusCode::BAD_REQUEST, format!("Private {}s can't be listed", request.kind.as_str()));
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let listing_id = MarketplaceListing::listing_id(request.kind, &request.item_id);
+    let existing = datastore.marketplace.get(&listing_id);
+    let created_at = existing.map(|l| l.created_at).unwrap_or(now);
+    let updated_at = existing.map(|l| now.max(l.updated_at + 1)).unwrap_or(now);
+    let listing = MarketplaceListing {
+        listing_id,
+        kind: request.kind,
+        item_id: request.item_id,
+        owner_id: owner_id.trim_start_matches("0x").to_lowercase(),
+        title: request.title.unwrap_or(title),
+        summary: request.summary.unwrap_or(summary),
+        tags: request.tags.unwrap_or(tags),
+        pricing: request.pricing.unwrap_or(pricing),
+        required_capabilities: request.required_capabilities
+            .unwrap_or_else(|| if capabilities { vec!["gpu".to_string()] } else { vec![] }),
+        status: ListingStatus::Pending,
+        moderation_note: None,
+        moderated_by: None,
+        created_at,
+        updated_at,
+    };
+    log::info!("{} published listing {} for review", caller, listing.listing_id);
+    save_listing(&mut datastore, listing.clone()).await;
+
+    (StatusCode::OK, Json(json!({ "success": true, "listing": listing })))
+}
+
+/// Approves or delists a listing, admins only
+pub async fn moderate_listing(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(listing_id): Path<String>,
+    Json(request): Json<ModerateListingRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    if !datastore.network_state.is_admin_address(&caller) {
+        return error(StatusCode::FORBIDDEN, "Only network admins can moderate listings");
+    }
+    let Some(mut listing) = datastore.marketplace.get(&listing_id).cloned() else {
+        return error(StatusCode::NOT_FOUND, format!("Listing {listing_id} not found"));
+    };
+    if !listing.status.can_moderate_to(request.status) {
+        return error(
+            StatusCode::CONFLICT,
+            format!("A {:?} listing can't be moved to {:?}", listing.status, request.status),
+        );
+    }
+
+    listing.status = request.status;
+    listing.moderation_note = request.note;
+    listing.moderated_by = Some(caller.clone());
+    listing.updated_at = chrono::Utc::now().timestamp().max(listing.updated_at + 1);
+    log::info!("{} moved listing {} to {:?}", caller, listing_id, listing.status);
+    save_listing(&mut datastore, listing.clone()).await;
+
+    (StatusCode::OK, Json(json!({ "success": true, "listing": listing })))
+}
+
+/// Takes the caller's own listing off the marketplace
+pub async fn withdraw_listing(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(listing_id): Path<String>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let Some(mut listing) = datastore.marketplace.get(&listing_id).cloned() else {
+        return error(StatusCode::NOT_FOUND, format!("Listing {listing_id} not found"));
+    };
+    if !listing.owner_id.eq_ignore_ascii_case(&caller) {
+        return error(StatusCode::FORBIDDEN, "Only the owner can withdraw a listing");
+    }
+    if listing.status != ListingStatus::Delisted {
+        listing.status = ListingStatus::Delisted;
+        listing.moderation_note = Some("Withdrawn by the owner".to_string());
+        listing.updated_at = chrono::Utc::now().timestamp().max(listing.updated_at + 1);
+        save_listing(&mut datastore, listing.clone()).await;
+    }
+
+    (StatusCode::OK, Json(json!({ "success": true, "listing": listing })))
+}
+
+/// Searches the approved listings
+pub async fn search_listings(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Query(mut query): Query<ListingQuery>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    query.status = Some(ListingStatus::Approved);
+    let (total, listings) = datastore.marketplace.search(&query);
+    (StatusCode::OK, Json(json!({ "success": true, "total": total, "listings": listings })))
+}
+
+/// An approved listing
+pub async fn get_listing(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(listing_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    match approved_listing(&datastore, &listing_id) {
+        Ok(listing) => (StatusCode::OK, Json(json!({ "success": true, "listing": listing }))),
+        Err(e) => e,
+    }
+}
+
+/// Listings in any status, pending ones unless the query asks for another
+/// status. Admins see every listing, anyone else only their own.
+pub async fn list_moderation_queue(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Query(mut query): Query<ListingQuery>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    if !datastore.network_state.is_admin_address(&caller) {
+        query.owner = Some(caller);
+    }
+    query.status.get_or_insert(ListingStatus::Pending);
+    let (total, listings) = datastore.marketplace.search(&query);
+    (StatusCode::OK, Json(json!({ "success": true, "total": total, "listings": listings })))
+}
+
+async fn account_for(datastore: &mut DataStore, address: &str) -> Result<Account, (StatusCode, Json<Value>)> {
+    if let Some(account) = datastore.account_state.get_account(address) {
+        return Ok(account);
+    }
+    let account = Account::new(address.to_string());
+    let op = datastore.account_state.update_account_local(account.clone());
+    if let Err(e) = datastore.handle_account_op(op).await {
+        log::error!("Failed to create account {address}: {e}");
+        return Err(error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create new account"));
+    }
+    Ok(account)
+}
+
+/// Charges the listing's price and saves the account
+async fn charge_listing(
+    datastore: &mut DataStore,
+    mut account: Account,
+    listing: &MarketplaceListing,
+    charge: u64,
+) -> Result<Account, (StatusCode, Json<Value>)> {
+    if charge > 0 && !account.deduct_credits(charge) {
+        return Err((
+            StatusCode::PAYMENT_REQUIRED,
+            Json(json!({
+                "success": false,
+                "error": format!("Listing {} costs {} credits", listing.listing_id, charge),
+                "available_credits": account.available_credits()
+            })),
+        ));
+    }
+    let op = datastore.account_state.update_account_local(account.clone());
+    if let Err(e) = datastore.handle_account_op(op).await {
+        log::error!("Failed to update account {} for listing {}: {}", account.address, listing.listing_id, e);
+        return Err(error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to update usage tracking"));
+    }
+    Ok(account)
+}
+
+/// Hires the agent of an approved listing. The hire is recorded in the
+/// account's usage tracker and the listing's hourly price is charged for
+/// the hours asked for, one by default.
+pub async fn hire_listing(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(listing_id): Path<String>,
+    usage: Option<Json<ListingUsageRequest>>,
+) -> impl IntoResponse {
+    let listing = match approved_listing(&*state.lock().await, &listing_id) {
+        Ok(listing) => listing,
+        Err(e) => return e,
+    };
+    if listing.kind != ListingKind::Agent {
+        return error(StatusCode::BAD_REQUEST, "Models are used through /marketplace/listing/:listing_id/usage, not hired");
+    }
+    if let Err(reason) = authorize_access(&state, &PolicyTarget::Agent(listing.item_id.clone()), &recovered.as_hex()).await {
+        return error(StatusCode::FORBIDDEN, reason);
+    }
+
+    let mut datastore = state.lock().await;
+    let mut account = match account_for(&mut datastore, &recovered.as_hex()).await {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+    if let Err(e) = check_operation_credits(&account, OperationType::AgentHire { agent_id: listing.item_id.clone() }) {
+        return error(StatusCode::PAYMENT_REQUIRED, e.to_string());
+    }
+
+    let hours = usage.and_then(|Json(usage)| usage.hours).unwrap_or(1.0).max(0.0);
+    account.hire_agent(listing.item_id.clone());
+    let metered = account.usage_tracker().record_agent_usage(&listing.item_id, hours);
+    let charge = listing.pricing.charge(0, hours);
+    let account = match charge_listing(&mut datastore, account, &listing, charge).await {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+    log::info!("{} hired agent {} through listing {}", account.address, listing.item_id, listing_id);
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "listing_id": listing_id,
+            "agent_id": listing.item_id,
+            "hours": hours,
+            "metered_credits": metered,
+            "listing_charge": charge,
+            "credits_remaining": account.available_credits(),
+            "hired_agent_count": account.hired_agent_count()
+        })),
+    )
+}
+
+/// Records use of an approved listing: tokens for a model, hours for an
+/// agent. The usage goes into the account's usage tracker and the listing's
+/// price is charged on top.
+pub async fn record_listing_usage(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(listing_id): Path<String>,
+    Json(usage): Json<ListingUsageRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let listing = match approved_listing(&datastore, &listing_id) {
+        Ok(listing) => listing,
+        Err(e) => return e,
+    };
+    let mut account = match account_for(&mut datastore, &recovered.as_hex()).await {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+
+    let (metered, charge) = match listing.kind {
+        ListingKind::Model => {
+            let operation = OperationType::TokenConsumption {
+                model_id: listing.item_id.clone(),
+                input_tokens: usage.input_tokens,
+                output_tokens: usage.output_tokens,
+            };
+            if let Err(e) = check_operation_credits(&account, operation) {
+                return error(StatusCode::PAYMENT_REQUIRED, e.to_string());
+            }
+            let metered = account.usage_tracker()
+                .record_token_usage(&listing.item_id, usage.input_tokens, usage.output_tokens);
+            (metered, listing.pricing.charge(usage.input_tokens + usage.output_tokens, 0.0))
+        }
+        ListingKind::Agent => {
+            if !account.hired_agents.contains(&listing.item_id) {
+                return error(StatusCode::FORBIDDEN, format!("Agent {} hasn't been hired", listing.item_id));
+            }
+            let Some(hours) = usage.hours.filter(|hours| *hours > 0.0) else {
+                return error(StatusCode::BAD_REQUEST, "Agent usage is recorded in hours");
+            };
+            let metered = account.usage_tracker().record_agent_usage(&listing.item_id, hours);
+            (metered, listing.pricing.charge(0, hours))
+        }
+    };
+    let account = match charge_listing(&mut datastore, account, &listing, charge).await {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "listing_id": listing_id,
+            "metered_credits": metered,
+            "listing_charge": charge,
+            "credits_remaining": account.available_credits()
+        })),
+    )
+}
+
+pub async fn replicate_listing(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(listing): Json<MarketplaceListing>,
+) -> Json<Response<MarketplaceListing>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated marketplace listing {}", listing.listing_id);
+    if datastore.marketplace.upsert(listing) {
+        if let Err(e) = store_value(&DB_HANDLE, MARKETPLACE_DB_KEY, &datastore.marketplace) {
+            log::error!("Unable to persist marketplace listings: {e}");
+        }
+    }
+    Json(Response::Success(Success::None))
+}
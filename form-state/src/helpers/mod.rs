@@ -5,6 +5,21 @@ pub mod agent;
 pub mod model;
 pub mod dns;
 pub mod nodes;
+pub mod staking;
+pub mod secrets;
+pub mod organizations;
 pub mod agent_request;
 pub mod agent_response;
 pub mod agent_gateway;
+pub mod passkeys;
+pub mod fleet_config;
+pub mod build_manifests;
+pub mod marketplace;
+pub mod usage;
+pub mod quotas;
+pub mod backup;
+pub mod access_policies;
+pub mod peer_expiry;
+pub mod maintenance;
+pub mod reservations;
+pub mod probe;
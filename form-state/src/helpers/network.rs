@@ -8,12 +8,14 @@ use serde::{Serialize, Deserialize};
 use axum::{extract::{State, Path}, Json};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use form_dns::{store::FormDnsRecord, api::{DomainResponse, DomainRequest}};
+use form_dns::{store::{FormDnsRecord, VerificationStatus}, api::{DomainResponse, DomainRequest}};
 use trust_dns_proto::rr::RecordType;
 use std::net::SocketAddr;
 use std::net::IpAddr;
 use url::Host;
 use crate::instances::Instance;
+use crate::peer_dns::records_for_ip;
+use crate::domain_verification::normalize_domain;
 use shared::{Cidr, Association, Peer};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -143,6 +145,9 @@ pub async fn create_user(
                     if let (true, v) = datastore.network_state.peer_op_success(key.clone(), op.clone()) {
                         log::info!("Map Op was successful, broadcasting...");
                         let request = PeerRequest::Op(map_op);
+                        if let Err(e) = datastore.register_peer_dns(&v.name, v.ip).await {
+                            log::error!("Unable to register DNS name for peer {}: {e}", v.name);
+                        }
                         match datastore.broadcast::<Response<Peer<String>>>(request, "/user/create").await {
                             Ok(()) => {
                                 let _ = write_datastore(&DB_HANDLE, &datastore.clone());
@@ -382,12 +387,19 @@ pub async fn delete_user(
         }
         PeerRequest::Delete(contents) => {
             log::info!("Delete user request was a direct request...");
+            let peer_ip = datastore.network_state.peers.get(&contents).val
+                .and_then(|reg| reg.val().map(|v| v.value().ip()));
             log::info!("Building Map Op...");
             let map_op = datastore.network_state.remove_peer_local(contents);
             datastore.network_state.peer_op(map_op.clone());
             match &map_op {
                 crdts::map::Op::Rm { .. } => {
                     let request = PeerRequest::Op(map_op);
+                    if let Some(ip) = peer_ip {
+                        if let Err(e) = datastore.deregister_peer_dns(ip).await {
+                            log::error!("Unable to remove DNS names of {ip}: {e}");
+                        }
+                    }
                     log::info!("Map Op was successful, broadcasting...");
                     match datastore.broadcast::<Response<Peer<String>>>(request, "/user/delete").await {
                         Ok(()) => return Json(Response::Success(Success::None)),
@@ -935,6 +947,20 @@ pub async fn request_public(
     Path((domain, build_id)): Path<(String, String)>,
 ) -> Json<Response<Host>> {
     let datastore = state.lock().await;
+    // Custom domains only reach form-dns, and the TLS pipeline behind it,
+    // once their owner has passed the challenge for this build
+    let domain = match normalize_domain(&domain) {
+        Ok(domain) => domain,
+        Err(e) => return Json(Response::Failure { reason: Some(e) }),
+    };
+    let ssl_cert = match datastore.domain_verifications.get(&domain) {
+        Some(verification) if datastore.domain_verifications.is_verified_for(&domain, &build_id) => verification.ssl_cert,
+        _ => {
+            return Json(Response::Failure {
+                reason: Some(format!("{domain} has not been verified for build {build_id}, run `form dns verify` first"))
+            })
+        }
+    };
     let assigned = datastore.network_state.dns_state.zones.iter().any(|ctx| {
         let (d, _) = ctx.val;
         if *d == domain {
@@ -1010,10 +1036,10 @@ pub async fn request_public(
             SocketAddr::new(*ip, 80)
         }).collect(),
         cname_target,
-        ssl_cert: false,
+        ssl_cert,
         ttl: 3600,
-        verification_status: None,
-        verification_timestamp: None
+        verification_status: Some(VerificationStatus::Verified),
+        verification_timestamp: Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
     };
 
     let request = DnsRequest::Create(dns_a_record.clone());
@@ -1271,6 +1297,19 @@ pub async fn get_dns_records_by_node_ip(
     Json(vec![])
 }
 
+/// Reverse lookup for diagnostics, every record that resolves to `ip`
+pub async fn get_dns_records_by_ip(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(ip): Path<String>
+) -> Json<Response<FormDnsRecord>> {
+    let ip = match ip.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(e) => return Json(Response::Failure { reason: Some(format!("Invalid ip {ip}: {e}")) }),
+    };
+    let datastore = state.lock().await;
+    Json(Response::Success(Success::List(records_for_ip(&datastore.network_state, ip))))
+}
+
 pub async fn list_dns_records(
     State(state): State<Arc<Mutex<DataStore>>>,
 ) -> Json<Response<FormDnsRecord>> {
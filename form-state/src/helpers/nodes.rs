@@ -1,11 +1,22 @@
 use crate::datastore::{DataStore, NodeRequest, DB_HANDLE};
 use crate::db::write_datastore;
-use crate::nodes::Node;
+use crate::instances::Instance;
+use crate::nodes::{Node, NodeMaintenance};
+use crate::node_matching::{match_unreserved_nodes, NodeMatch, NodeRequirements};
 use std::sync::Arc;
-use form_node_metrics::metrics::NodeMetrics;
+use form_node_metrics::{connectivity::ConnectivityMetrics, metrics::NodeMetrics};
 use tokio::sync::Mutex;
 use axum::{extract::{State, Path}, Json};
 use form_types::state::{Response, Success};
+use serde::{Deserialize, Serialize};
+
+/// Body of `/node/:id/maintenance`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeMaintenanceRequest {
+    pub enabled: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
 
 pub async fn create_node(
     State(state): State<Arc<Mutex<DataStore>>>,
@@ -44,6 +55,14 @@ pub async fn create_node(
         }
         NodeRequest::Create(mut contents) => {
             log::info!("Create Node request was a direct request...");
+
+            let admission = datastore.staking_state.admission(&contents.node_owner);
+            if !admission.admitted {
+                log::warn!("Rejecting node {} from unstaked operator {}", contents.node_id, admission.operator);
+                return Json(Response::Failure {
+                    reason: Some(format!("Operator {} is not admitted: {}", admission.operator, admission.reason.unwrap_or_default()))
+                });
+            }
             
             // Add initial operator keys from environment if available
             if !initial_operator_keys.is_empty() {
@@ -231,6 +250,30 @@ pub async fn list_node_metrics(
     return Json(Response::Success(Success::List(list)))
 }
 
+pub async fn get_node_connectivity(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(node_id): Path<String>,
+) -> Json<Response<ConnectivityMetrics>> {
+    let datastore = state.lock().await;
+    if let Some(node) = datastore.node_state.get_node(node_id.clone()) {
+        return Json(Response::Success(Success::Some(node.connectivity)))
+    }
+
+    return Json(Response::Failure { reason: Some(format!("Unable to find node with id: {node_id}"))})
+}
+
+pub async fn list_node_connectivity(
+    State(state): State<Arc<Mutex<DataStore>>>,
+) -> Json<Response<(String, ConnectivityMetrics)>> {
+    let datastore = state.lock().await;
+    let list: Vec<(String, ConnectivityMetrics)> = datastore.node_state.map().iter().filter_map(|ctx| {
+        let (node_id, value) = ctx.val;
+        value.val().map(|node| (node_id.clone(), node.value().connectivity.clone()))
+    }).collect();
+
+    return Json(Response::Success(Success::List(list)))
+}
+
 pub async fn list_nodes(
     State(state): State<Arc<Mutex<DataStore>>>,
 ) -> Json<Response<Node>> {
@@ -247,3 +290,65 @@ pub async fn list_nodes(
 
     return Json(Response::Success(Success::List(list)))
 }
+
+/// Puts a node into maintenance mode so placement avoids it, or takes it out.
+/// vmm-service calls this when an admin drains the node.
+pub async fn set_node_maintenance(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(node_id): Path<String>,
+    Json(request): Json<NodeMaintenanceRequest>,
+) -> Json<Response<Node>> {
+    let mut datastore = state.lock().await;
+    let Some(node) = datastore.node_state.get_node(node_id.clone()) else {
+        return Json(Response::Failure { reason: Some(format!("Unable to find node with id: {node_id}"))})
+    };
+
+    let maintenance = match (request.enabled, node.maintenance) {
+        // Keep the original start time when a drain is restarted
+        (true, Some(current)) => Some(NodeMaintenance { reason: request.reason.or(current.reason), ..current }),
+        (true, None) => Some(NodeMaintenance { started_at: chrono::Utc::now().timestamp(), reason: request.reason }),
+        (false, _) => None,
+    };
+    log::info!("Setting maintenance of node {node_id} to {maintenance:?}");
+    if let Err(e) = datastore.handle_node_maintenance(node_id.clone(), maintenance).await {
+        return Json(Response::Failure { reason: Some(format!("Unable to update node {node_id}: {e}"))})
+    }
+
+    match datastore.node_state.get_node(node_id) {
+        Some(node) => Json(Response::Success(Success::Some(node))),
+        None => Json(Response::Failure { reason: Some("Node was removed during the update".to_string())}),
+    }
+}
+
+pub async fn list_node_instances(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(node_id): Path<String>,
+) -> Json<Response<Instance>> {
+    let datastore = state.lock().await;
+    let list: Vec<Instance> = datastore.instance_state.map().iter().filter_map(|ctx| {
+        let (_, value) = ctx.val;
+        value.val().map(|instance| instance.value())
+    }).filter(|instance| instance.node_id == node_id).collect();
+
+    Json(Response::Success(Success::List(list)))
+}
+
+/// Nodes able to run a workload with the given requirements, ranked best
+/// first. Capacity reserved by accounts other than `requirements.account`
+/// is left out.
+pub async fn find_matching_nodes(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(requirements): Json<NodeRequirements>,
+) -> Json<Response<NodeMatch>> {
+    let datastore = state.lock().await;
+    let nodes: Vec<Node> = datastore.node_state.map().iter().filter_map(|ctx| {
+        let (_, value) = ctx.val;
+        value.val().map(|node| node.value())
+    }).collect();
+
+    let now = chrono::Utc::now().timestamp();
+    let unreserved = datastore.unreserved_capacity(requirements.account.as_deref(), now);
+    let matches = match_unreserved_nodes(&nodes, &requirements, &unreserved, now);
+    log::info!("{} of {} nodes match {requirements:?}", matches.len(), nodes.len());
+    Json(Response::Success(Success::List(matches)))
+}
@@ -0,0 +1,331 @@
+use crate::datastore::DataStore;
+use crate::auth::RecoveredAddress;
+use crate::organizations::{normalize_address, OrgInvitation, OrgRole, Organization};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path, ConnectInfo}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize)]
+pub struct CreateOrganizationRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleRequest {
+    pub role: OrgRole,
+}
+
+#[derive(Deserialize)]
+pub struct SetBillingAccountRequest {
+    pub billing_account: String,
+}
+
+#[derive(Deserialize)]
+pub struct OrgInstanceRequest {
+    pub instance_id: String,
+}
+
+type HandlerResponse = (StatusCode, Json<Value>);
+
+fn failure(status: StatusCode, error: impl ToString) -> HandlerResponse {
+    (status, Json(json!({ "success": false, "error": error.to_string() })))
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+/// Looks up the organization and checks the caller holds one of `roles` in it
+fn authorize_org(datastore: &DataStore, org_id: &str, caller: &str, roles: &[OrgRole]) -> Result<Organization, HandlerResponse> {
+    let Some(org) = datastore.organization_state.get_organization(org_id) else {
+        return Err(failure(StatusCode::NOT_FOUND, format!("Organization {} not found", org_id)));
+    };
+    match org.role_of(caller) {
+        Some(role) if roles.contains(role) => Ok(org),
+        _ => Err(failure(StatusCode::FORBIDDEN, "You don't have permission to perform this action on the organization")),
+    }
+}
+
+async fn commit(datastore: &mut DataStore, org: Organization) -> HandlerResponse {
+    let op = datastore.organization_state.update_organization_local(org.clone());
+    if let Err(e) = datastore.handle_organization_op(op).await {
+        log::error!("Failed to update organization {}: {}", org.org_id, e);
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update organization: {}", e));
+    }
+    (StatusCode::OK, Json(json!({ "success": true, "organization": org })))
+}
+
+pub async fn create_organization(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(payload): Json<CreateOrganizationRequest>,
+) -> impl IntoResponse {
+    if payload.name.trim().is_empty() {
+        return failure(StatusCode::BAD_REQUEST, "Organization name cannot be empty");
+    }
+
+    let mut datastore = state.lock().await;
+    let org_id = uuid::Uuid::new_v4().to_string();
+    let org = Organization::new(org_id, payload.name, &recovered.as_hex());
+    log::info!("create_organization: {} created organization {}", recovered.as_hex(), org.org_id);
+    commit(&mut datastore, org).await
+}
+
+pub async fn get_organization(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+    Path(org_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let Some(org) = datastore.organization_state.get_organization(&org_id) else {
+        return failure(StatusCode::NOT_FOUND, format!("Organization {} not found", org_id));
+    };
+
+    let is_member = recovered.is_some_and(|r| org.role_of(&r.as_hex()).is_some());
+    if !is_member && !connection_info.ip().is_loopback() {
+        return failure(StatusCode::FORBIDDEN, "Only members can view an organization");
+    }
+    (StatusCode::OK, Json(json!({ "success": true, "organization": org })))
+}
+
+/// Lists the caller's organizations, localhost sees all of them
+pub async fn list_organizations(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: Option<RecoveredAddress>,
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let organizations = match recovered {
+        Some(recovered) => datastore.organization_state.organizations_for_member(&recovered.as_hex()),
+        None if connection_info.ip().is_loopback() => datastore.organization_state.list_organizations(),
+        None => return failure(StatusCode::UNAUTHORIZED, "Authentication required to list organizations"),
+    };
+    (StatusCode::OK, Json(json!({ "success": true, "organizations": organizations, "total": organizations.len() })))
+}
+
+/// Stores an invitation signed by an owner or admin of the organization
+pub async fn invite_member(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(org_id): Path<String>,
+    Json(invitation): Json<OrgInvitation>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let mut org = match authorize_org(&datastore, &org_id, &caller, &[OrgRole::Owner, OrgRole::Admin]) {
+        Ok(org) => org,
+        Err(response) => return response,
+    };
+
+    if invitation.org_id != org_id {
+        return failure(StatusCode::BAD_REQUEST, "Invitation is for a different organization");
+    }
+    if normalize_address(&invitation.invited_by) != caller {
+        return failure(StatusCode::FORBIDDEN, "Invitations must be issued by the caller");
+    }
+    if let Err(e) = invitation.verify() {
+        return failure(StatusCode::UNAUTHORIZED, e);
+    }
+    if invitation.is_expired(now()) {
+        return failure(StatusCode::BAD_REQUEST, "Invitation has already expired");
+    }
+    if invitation.role == OrgRole::Owner && org.role_of(&caller) != Some(&OrgRole::Owner) {
+        return failure(StatusCode::FORBIDDEN, "Only owners can invite new owners");
+    }
+    if org.role_of(&invitation.address).is_some() {
+        return failure(StatusCode::CONFLICT, "Address is already a member of the organization");
+    }
+
+    log::info!("invite_member: {} invited {} to {} as {:?}", caller, invitation.address, org_id, invitation.role);
+    org.add_invitation(invitation);
+    commit(&mut datastore, org).await
+}
+
+pub async fn accept_invitation(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(org_id): Path<String>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let Some(mut org) = datastore.organization_state.get_organization(&org_id) else {
+        return failure(StatusCode::NOT_FOUND, format!("Organization {} not found", org_id));
+    };
+
+    match org.accept_invitation(&recovered.as_hex(), now()) {
+        Ok(role) => {
+            log::info!("accept_invitation: {} joined {} as {:?}", recovered.as_hex(), org_id, role);
+            commit(&mut datastore, org).await
+        }
+        Err(e) => failure(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Admins can remove members, any member can remove themselves
+pub async fn remove_member(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path((org_id, address)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let leaving = normalize_address(&address) == caller;
+    let roles: &[OrgRole] = if leaving {
+        &[OrgRole::Owner, OrgRole::Admin, OrgRole::Member, OrgRole::Viewer]
+    } else {
+        &[OrgRole::Owner, OrgRole::Admin]
+    };
+    let mut org = match authorize_org(&datastore, &org_id, &caller, roles) {
+        Ok(org) => org,
+        Err(response) => return response,
+    };
+    if !leaving && org.role_of(&address) == Some(&OrgRole::Owner) && org.role_of(&caller) != Some(&OrgRole::Owner) {
+        return failure(StatusCode::FORBIDDEN, "Only owners can remove other owners");
+    }
+
+    if let Err(e) = org.remove_member(&address) {
+        return failure(StatusCode::BAD_REQUEST, e);
+    }
+    commit(&mut datastore, org).await
+}
+
+pub async fn set_member_role(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path((org_id, address)): Path<(String, String)>,
+    Json(payload): Json<SetRoleRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let mut org = match authorize_org(&datastore, &org_id, &caller, &[OrgRole::Owner, OrgRole::Admin]) {
+        Ok(org) => org,
+        Err(response) => return response,
+    };
+
+    let current = match org.role_of(&address) {
+        Some(role) => role.clone(),
+        None => return failure(StatusCode::NOT_FOUND, "Address is not a member of this organization"),
+    };
+    let caller_is_owner = org.role_of(&caller) == Some(&OrgRole::Owner);
+    if (current == OrgRole::Owner || payload.role == OrgRole::Owner) && !caller_is_owner {
+        return failure(StatusCode::FORBIDDEN, "Only owners can grant or revoke ownership");
+    }
+    if current == OrgRole::Owner && org.members.values().filter(|r| **r == OrgRole::Owner).count() == 1 {
+        return failure(StatusCode::BAD_REQUEST, "An organization must keep at least one owner");
+    }
+
+    org.members.insert(normalize_address(&address), payload.role);
+    org.updated_at = now();
+    commit(&mut datastore, org).await
+}
+
+/// Changes the account that pays for org instances, it has to belong to a member
+pub async fn set_billing_account(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(org_id): Path<String>,
+    Json(payload): Json<SetBillingAccountRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let mut org = match authorize_org(&datastore, &org_id, &recovered.as_hex(), &[OrgRole::Owner]) {
+        Ok(org) => org,
+        Err(response) => return response,
+    };
+    if org.role_of(&payload.billing_account).is_none() {
+        return failure(StatusCode::BAD_REQUEST, "Billing account must belong to a member of the organization");
+    }
+    let billing_account = normalize_address(&payload.billing_account);
+    let account = datastore.account_state.get_account(&billing_account)
+        .or_else(|| datastore.account_state.get_account(&format!("0x{}", billing_account)));
+    if account.is_none() {
+        return failure(StatusCode::NOT_FOUND, "Billing account does not exist");
+    }
+
+    org.billing_account = billing_account;
+    org.updated_at = now();
+    commit(&mut datastore, org).await
+}
+
+/// Moves an instance the caller owns under the organization
+pub async fn add_org_instance(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(org_id): Path<String>,
+    Json(payload): Json<OrgInstanceRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let mut org = match authorize_org(&datastore, &org_id, &caller, &[OrgRole::Owner, OrgRole::Admin]) {
+        Ok(org) => org,
+        Err(response) => return response,
+    };
+
+    let Some(instance) = datastore.instance_state.get_instance(payload.instance_id.clone()) else {
+        return failure(StatusCode::NOT_FOUND, format!("Instance {} not found", payload.instance_id));
+    };
+    if normalize_address(&instance.instance_owner) != caller {
+        return failure(StatusCode::FORBIDDEN, "Only the instance owner can share it with an organization");
+    }
+    if let Some(other) = datastore.organization_state.organization_owning_instance(&payload.instance_id) {
+        return failure(StatusCode::CONFLICT, format!("Instance already belongs to organization {}", other.org_id));
+    }
+
+    org.add_owned_instance(payload.instance_id);
+    commit(&mut datastore, org).await
+}
+
+pub async fn remove_org_instance(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path((org_id, instance_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let mut org = match authorize_org(&datastore, &org_id, &recovered.as_hex(), &[OrgRole::Owner, OrgRole::Admin]) {
+        Ok(org) => org,
+        Err(response) => return response,
+    };
+    if !org.remove_owned_instance(&instance_id) {
+        return failure(StatusCode::NOT_FOUND, "Instance is not owned by this organization");
+    }
+    commit(&mut datastore, org).await
+}
+
+pub async fn delete_organization(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(org_id): Path<String>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    if let Err(response) = authorize_org(&datastore, &org_id, &recovered.as_hex(), &[OrgRole::Owner]) {
+        return response;
+    }
+
+    let op = datastore.organization_state.remove_organization_local(org_id.clone());
+    if let Err(e) = datastore.handle_organization_op(op).await {
+        log::error!("Failed to delete organization {}: {}", org_id, e);
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete organization: {}", e));
+    }
+    (StatusCode::OK, Json(json!({ "success": true, "org_id": org_id })))
+}
+
+/// The access `address` has to an instance through organization membership,
+/// used by vmm-service when the caller is not the instance owner
+pub async fn get_org_instance_authorization(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path((instance_id, address)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let org = datastore.organization_state.organization_owning_instance(&instance_id);
+    let level = datastore.organization_state.instance_authorization(&address, &instance_id);
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "org_id": org.map(|o| o.org_id),
+        "level": level
+    })))
+}
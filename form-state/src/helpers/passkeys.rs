@@ -0,0 +1,255 @@
+use crate::accounts::Account;
+use crate::auth::RecoveredAddress;
+use crate::auth::webauthn::{
+    self, AssertionResponse, ChallengePurpose, RegistrationResponse, WebAuthnConfig, COSE_ALG_ES256,
+    CHALLENGE_TTL_SECONDS,
+};
+use crate::datastore::DataStore;
+use crate::organizations::normalize_address;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Deserialize, Default)]
+pub struct PasskeyLoginBeginRequest {
+    /// Restricts the login to the passkeys of this account
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+type HandlerResponse = (StatusCode, Json<Value>);
+
+fn failure(status: StatusCode, error: impl ToString) -> HandlerResponse {
+    (status, Json(json!({ "success": false, "error": error.to_string() })))
+}
+
+fn find_account(datastore: &DataStore, address: &str) -> Option<Account> {
+    let address = normalize_address(address);
+    datastore.account_state.get_account(&address)
+        .or_else(|| datastore.account_state.get_account(&format!("0x{}", address)))
+}
+
+/// Linking and unlinking passkeys requires the account's own key, so a
+/// passkey session can't be used to add more passkeys
+fn signing_account(datastore: &DataStore, recovered: &RecoveredAddress) -> Result<Account, HandlerResponse> {
+    if recovered.via_passkey {
+        return Err(failure(StatusCode::FORBIDDEN, "Passkeys can only be managed with a signed request"));
+    }
+    find_account(datastore, &recovered.as_hex())
+        .ok_or_else(|| failure(StatusCode::NOT_FOUND, "Your account was not found"))
+}
+
+fn credential_descriptors(account: &Account) -> Vec<Value> {
+    account.passkeys.keys()
+        .map(|id| json!({ "type": "public-key", "id": id }))
+        .collect()
+}
+
+pub async fn passkey_register_begin(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let account = match signing_account(&datastore, &recovered) {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+
+    let config = WebAuthnConfig::from_env();
+    let challenge = webauthn::issue_challenge(ChallengePurpose::Registration { address: account.address.clone() });
+    log::info!("passkey_register_begin: issued registration challenge for {}", account.address);
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "public_key": {
+            "challenge": challenge,
+            "rp": { "id": config.rp_id, "name": config.rp_name },
+            "user": {
+                "id": URL_SAFE_NO_PAD.encode(account.address.as_bytes()),
+                "name": account.address,
+                "displayName": account.name.clone().unwrap_or_else(|| account.address.clone()),
+            },
+            "pubKeyCredParams": [{ "type": "public-key", "alg": COSE_ALG_ES256 }],
+            "timeout": CHALLENGE_TTL_SECONDS * 1000,
+            "attestation": "none",
+            "excludeCredentials": credential_descriptors(&account),
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": if config.require_user_verification { "required" } else { "preferred" },
+            },
+        }
+    })))
+}
+
+pub async fn passkey_register_finish(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(payload): Json<RegistrationResponse>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let mut account = match signing_account(&datastore, &recovered) {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+
+    let challenge = match webauthn::take_challenge(&payload.client_data_json) {
+        Ok((challenge, ChallengePurpose::Registration { address })) if address == account.address => challenge,
+        Ok(_) => return failure(StatusCode::BAD_REQUEST, "The challenge was not issued for registering a passkey on this account"),
+        Err(e) => return failure(StatusCode::BAD_REQUEST, e),
+    };
+
+    let credential = match webauthn::verify_registration(&WebAuthnConfig::from_env(), &challenge, &payload) {
+        Ok(credential) => credential,
+        Err(e) => {
+            log::warn!("passkey_register_finish: rejected registration for {}: {}", account.address, e);
+            return failure(StatusCode::BAD_REQUEST, e);
+        }
+    };
+
+    let in_use = datastore.account_state.list_accounts().iter()
+        .any(|other| other.passkeys.contains_key(&credential.credential_id));
+    if in_use {
+        return failure(StatusCode::CONFLICT, "This passkey is already linked to an account");
+    }
+
+    account.passkeys.insert(credential.credential_id.clone(), credential.clone());
+    account.updated_at = chrono::Utc::now().timestamp();
+    let address = account.address.clone();
+    if let Err(e) = datastore.handle_account_update(account).await {
+        log::error!("Failed to link passkey to account {}: {}", address, e);
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to link passkey: {}", e));
+    }
+
+    log::info!("passkey_register_finish: linked passkey {} to {}", credential.credential_id, address);
+    (StatusCode::OK, Json(json!({ "success": true, "passkey": credential })))
+}
+
+pub async fn passkey_list(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    match find_account(&datastore, &recovered.as_hex()) {
+        Some(account) => {
+            let passkeys: Vec<_> = account.passkeys.values().cloned().collect();
+            (StatusCode::OK, Json(json!({ "success": true, "total": passkeys.len(), "passkeys": passkeys })))
+        }
+        None => failure(StatusCode::NOT_FOUND, "Your account was not found"),
+    }
+}
+
+pub async fn passkey_remove(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(credential_id): Path<String>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let mut account = match signing_account(&datastore, &recovered) {
+        Ok(account) => account,
+        Err(e) => return e,
+    };
+
+    if account.passkeys.remove(&credential_id).is_none() {
+        return failure(StatusCode::NOT_FOUND, format!("Passkey {} not found", credential_id));
+    }
+    account.updated_at = chrono::Utc::now().timestamp();
+    let address = account.address.clone();
+    if let Err(e) = datastore.handle_account_update(account).await {
+        log::error!("Failed to remove passkey from account {}: {}", address, e);
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to remove passkey: {}", e));
+    }
+
+    // Sessions aren't tied to a credential, so end all of them
+    webauthn::revoke_sessions(&normalize_address(&address));
+    log::info!("passkey_remove: removed passkey {} from {}", credential_id, address);
+    (StatusCode::OK, Json(json!({ "success": true })))
+}
+
+pub async fn passkey_login_begin(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    payload: Option<Json<PasskeyLoginBeginRequest>>,
+) -> impl IntoResponse {
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+    let config = WebAuthnConfig::from_env();
+
+    // Without an address the browser offers its discoverable credentials
+    let allow_credentials = match &payload.address {
+        Some(address) => {
+            let datastore = state.lock().await;
+            match find_account(&datastore, address) {
+                Some(account) if !account.passkeys.is_empty() => credential_descriptors(&account),
+                _ => return failure(StatusCode::NOT_FOUND, "No passkeys are linked to this account"),
+            }
+        }
+        None => vec![],
+    };
+
+    let challenge = webauthn::issue_challenge(ChallengePurpose::Authentication {
+        address: payload.address.as_deref().map(normalize_address),
+    });
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "public_key": {
+            "challenge": challenge,
+            "rpId": config.rp_id,
+            "timeout": CHALLENGE_TTL_SECONDS * 1000,
+            "allowCredentials": allow_credentials,
+            "userVerification": if config.require_user_verification { "required" } else { "preferred" },
+        }
+    })))
+}
+
+pub async fn passkey_login_finish(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(payload): Json<AssertionResponse>,
+) -> impl IntoResponse {
+    let (challenge, expected_address) = match webauthn::take_challenge(&payload.client_data_json) {
+        Ok((challenge, ChallengePurpose::Authentication { address })) => (challenge, address),
+        Ok(_) => return failure(StatusCode::BAD_REQUEST, "The challenge was not issued for a passkey login"),
+        Err(e) => return failure(StatusCode::UNAUTHORIZED, e),
+    };
+
+    let mut datastore = state.lock().await;
+    let credential_id = payload.credential_id.trim_end_matches('=');
+    let Some(mut account) = datastore.account_state.list_accounts().into_iter()
+        .find(|account| account.passkeys.contains_key(credential_id)) else {
+        return failure(StatusCode::UNAUTHORIZED, "Unknown passkey");
+    };
+    let address = normalize_address(&account.address);
+    if expected_address.is_some_and(|expected| expected != address) {
+        return failure(StatusCode::UNAUTHORIZED, "The passkey does not belong to the requested account");
+    }
+
+    let Some(credential) = account.passkeys.get_mut(credential_id) else {
+        return failure(StatusCode::UNAUTHORIZED, "Unknown passkey");
+    };
+    let sign_count = match webauthn::verify_assertion(&WebAuthnConfig::from_env(), &challenge, credential, &payload) {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            log::warn!("passkey_login_finish: rejected assertion for {}: {}", address, e);
+            return failure(StatusCode::UNAUTHORIZED, e);
+        }
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    credential.sign_count = sign_count;
+    credential.last_used_at = Some(now);
+    account.updated_at = now;
+    if let Err(e) = datastore.handle_account_update(account).await {
+        log::error!("Failed to record passkey use for {}: {}", address, e);
+        return failure(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to record passkey use: {}", e));
+    }
+
+    let (token, expires_at) = webauthn::create_session(&address);
+    log::info!("passkey_login_finish: {} logged in with passkey {}", address, credential_id);
+    (StatusCode::OK, Json(json!({
+        "success": true,
+        "address": address,
+        "token": token,
+        "expires_at": expires_at,
+    })))
+}
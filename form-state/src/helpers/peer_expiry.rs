@@ -0,0 +1,60 @@
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::peer_expiry::{revive_peer, StaleMark, PEER_EXPIRY_DB_KEY};
+use form_types::state::{Response, Success};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::State, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResurrectRequest {
+    /// WireGuard public key of the peer, base64
+    pub public_key: String,
+}
+
+/// Peers expiry has disabled and not yet removed
+pub async fn list_stale_peers(
+    State(state): State<Arc<Mutex<DataStore>>>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let stale: Vec<StaleMark> = datastore.peer_expiry.stale().into_iter().cloned().collect();
+    (StatusCode::OK, Json(json!({ "success": true, "peers": stale })))
+}
+
+/// Called by formnet when a peer fetches the peer list, re-enables the peer
+/// if expiry had disabled it
+pub async fn resurrect_peer(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(request): Json<ResurrectRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let Some(peer_id) = datastore.peer_expiry.by_public_key(&request.public_key).map(|mark| mark.peer_id.clone()) else {
+        return (StatusCode::OK, Json(json!({ "success": true, "resurrected": false })));
+    };
+    let now = chrono::Utc::now().timestamp();
+    match revive_peer(&mut datastore, &peer_id, now).await {
+        Ok(resurrected) => (StatusCode::OK, Json(json!({ "success": true, "resurrected": resurrected, "peer_id": peer_id }))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "success": false, "error": format!("Unable to re-enable peer {peer_id}: {e}") })),
+        ),
+    }
+}
+
+pub async fn replicate_stale_mark(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(mark): Json<StaleMark>,
+) -> Json<Response<StaleMark>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated stale mark for peer {}", mark.peer_id);
+    if datastore.peer_expiry.upsert(mark) {
+        if let Err(e) = store_value(&DB_HANDLE, PEER_EXPIRY_DB_KEY, &datastore.peer_expiry) {
+            log::error!("Unable to persist peer expiry marks: {e}");
+        }
+    }
+    Json(Response::Success(Success::None))
+}
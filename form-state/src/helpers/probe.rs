@@ -0,0 +1,50 @@
+use std::net::SocketAddr;
+use axum::{body::Bytes, extract::{ConnectInfo, Query}, Json};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use form_node_metrics::network::MAX_PROBE_BYTES;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProbeDownloadQuery {
+    pub bytes: u64,
+}
+
+/// The address the caller connected from, nodes compare it with their own
+/// to tell whether they are behind NAT
+pub async fn probe_addr(
+    ConnectInfo(connection_info): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "addr": connection_info.to_string()
+        }))
+    )
+}
+
+/// Serves `bytes` of zeros, at most `MAX_PROBE_BYTES`, for nodes measuring
+/// their download bandwidth
+pub async fn probe_download(
+    Query(query): Query<ProbeDownloadQuery>,
+) -> impl IntoResponse {
+    let bytes = query.bytes.min(MAX_PROBE_BYTES) as usize;
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        vec![0u8; bytes],
+    )
+}
+
+/// Discards the body of nodes measuring their upload bandwidth
+pub async fn probe_upload(body: Bytes) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "bytes": body.len()
+        }))
+    )
+}
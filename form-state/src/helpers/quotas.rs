@@ -0,0 +1,141 @@
+use crate::datastore::DataStore;
+use crate::auth::RecoveredAddress;
+use crate::quotas::{ResourceQuota, ResourceUsage};
+use crate::pricing::pricing_table;
+use crate::usage_rollups::normalize_account_id;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{Path, Query, State}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct QuotaQuery {
+    pub account: Option<String>,
+}
+
+/// Quota, usage and remaining headroom of the caller's account, admins may
+/// query any account
+pub async fn get_quota(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Query(query): Query<QuotaQuery>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let account = match query.account {
+        Some(account) if normalize_account_id(&account) != normalize_account_id(&caller) => {
+            if !datastore.network_state.is_admin_address(&caller) {
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({
+                        "success": false,
+                        "error": "You can only view the quota of your own account"
+                    }))
+                );
+            }
+            account
+        }
+        _ => caller,
+    };
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "quota": datastore.quota_report(&account)
+        }))
+    )
+}
+
+/// Compute rates and resource quota of every subscription tier, public so
+/// costs can be estimated before signing up or deploying
+pub async fn get_pricing() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "pricing": pricing_table()
+        }))
+    )
+}
+
+/// Checks whether an account can take on more resources, used by nodes
+/// before they accept a new instance
+pub async fn check_account_quota(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(address): Path<String>,
+    Json(additional): Json<ResourceUsage>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    match datastore.check_quota(&address, &additional, None) {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!({
+                "success": true,
+                "quota": datastore.quota_report(&address)
+            }))
+        ),
+        Err(e) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": e.to_string()
+            }))
+        ),
+    }
+}
+
+/// Sets an account's resource quota, replacing its tier's. A `null` body
+/// goes back to the tier's quota. Admins only.
+pub async fn set_account_quota(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(address): Path<String>,
+    Json(quota): Json<Option<ResourceQuota>>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    if !datastore.network_state.is_admin_address(&caller) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": "Only admins can set account quotas"
+            }))
+        );
+    }
+
+    let Some(mut account) = datastore.account_state.get_account(&address) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("Account with address {address} not found")
+            }))
+        );
+    };
+
+    account.resource_quota = quota;
+    account.updated_at = chrono::Utc::now().timestamp();
+    let op = datastore.account_state.update_account_local(account);
+    if let Err(e) = datastore.handle_account_op(op).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "success": false,
+                "error": format!("Failed to set quota: {e}")
+            }))
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "quota": datastore.quota_report(&address)
+        }))
+    )
+}
@@ -0,0 +1,275 @@
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::auth::RecoveredAddress;
+use crate::accounts::Account;
+use crate::billing::webhooks::{self, WebhookEventType};
+use crate::node_matching::GpuRequirement;
+use crate::reservations::{
+    quote, RegionCapacity, Reservation, ReservationStatus, MAX_RESERVATION_HOURS, RESERVATIONS_DB_KEY,
+};
+use crate::usage_rollups::normalize_account_id;
+use form_types::state::{Response, Success};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path, Query}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+/// Body of `POST /v1/reservations/create` and `/v1/reservations/quote`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReservationRequest {
+    pub region: String,
+    #[serde(default)]
+    pub vcpus: u32,
+    #[serde(default)]
+    pub memory_mb: u64,
+    #[serde(default)]
+    pub gpus: Vec<GpuRequirement>,
+    /// Length of the term
+    pub hours: u64,
+    /// Defaults to now
+    #[serde(default)]
+    pub starts_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReservationListQuery {
+    /// Admins may list another account's reservations
+    #[serde(default)]
+    pub account: Option<String>,
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "success": false, "error": message.into() })))
+}
+
+/// The reserved capacity and the term of a request
+fn validate(request: &ReservationRequest, now: i64) -> Result<(RegionCapacity, i64, i64), (StatusCode, Json<Value>)> {
+    if request.region.trim().is_empty() {
+        return Err(error(StatusCode::BAD_REQUEST, "A reservation needs a region"));
+    }
+    let capacity = RegionCapacity::new(request.vcpus, request.memory_mb, &request.gpus);
+    if capacity.is_empty() {
+        return Err(error(StatusCode::BAD_REQUEST, "A reservation needs vCPUs, memory or GPUs"));
+    }
+    if request.hours == 0 || request.hours > MAX_RESERVATION_HOURS {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("Reservations run for 1 to {MAX_RESERVATION_HOURS} hours"),
+        ));
+    }
+    let starts_at = request.starts_at.unwrap_or(now).max(now);
+    Ok((capacity, starts_at, starts_at + request.hours as i64 * 3600))
+}
+
+/// Stores a reservation, persists the store and sends the reservation to
+/// the other admin nodes
+async fn save_reservation(datastore: &mut DataStore, reservation: Reservation) {
+    datastore.reservations.upsert(reservation.clone());
+    if let Err(e) = store_value(&DB_HANDLE, RESERVATIONS_DB_KEY, &datastore.reservations) {
+        log::error!("Unable to persist reservations: {e}");
+    }
+    if let Err(e) = datastore.broadcast::<Response<Reservation>>(reservation, "v1/reservations/replicate").await {
+        log::error!("Unable to replicate reservation: {e}");
+    }
+}
+
+async fn save_account(datastore: &mut DataStore, account: &Account, reservation_id: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    let op = datastore.account_state.update_account_local(account.clone());
+    if let Err(e) = datastore.handle_account_op(op).await {
+        log::error!("Failed to update account {} for reservation {}: {}", account.address, reservation_id, e);
+        return Err(error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to update the account's credits"));
+    }
+    Ok(())
+}
+
+fn emit(datastore: &mut DataStore, event_type: WebhookEventType, reservation: &Reservation) {
+    datastore.webhooks.emit(event_type, &reservation.account, json!(reservation), chrono::Utc::now().timestamp());
+    webhooks::persist(&datastore.webhooks);
+}
+
+/// A reservation the caller holds, or any for admins
+fn owned_reservation(datastore: &DataStore, reservation_id: &str, caller: &str) -> Result<Reservation, (StatusCode, Json<Value>)> {
+    match datastore.reservations.get(reservation_id) {
+        Some(reservation) if reservation.account == normalize_account_id(caller)
+            || datastore.network_state.is_admin_address(caller) => Ok(reservation.clone()),
+        _ => Err(error(StatusCode::NOT_FOUND, format!("Reservation {reservation_id} not found"))),
+    }
+}
+
+/// Prices a reservation at the caller's tier and tells whether the region
+/// has room for it
+pub async fn quote_reservation(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<ReservationRequest>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let now = chrono::Utc::now().timestamp();
+    let (capacity, starts_at, ends_at) = match validate(&request, now) {
+        Ok(term) => term,
+        Err(e) => return e,
+    };
+    let tier = datastore.account_tier(&recovered.as_hex());
+    let price = quote(&capacity, request.hours, &tier.pricing().rates);
+    let available = datastore.check_reservation_capacity(&request.region, &capacity, starts_at, ends_at, now);
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "tier": tier,
+            "quote": price,
+            "starts_at": starts_at,
+            "ends_at": ends_at,
+            "available": available.is_ok(),
+            "unavailable_reason": available.err()
+        })),
+    )
+}
+
+/// Reserves capacity in a region for the caller and charges the whole term
+/// up front
+pub async fn create_reservation(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Json(request): Json<ReservationRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let now = chrono::Utc::now().timestamp();
+    let (capacity, starts_at, ends_at) = match validate(&request, now) {
+        Ok(term) => term,
+        Err(e) => return e,
+    };
+    if let Err(reason) = datastore.check_reservation_capacity(&request.region, &capacity, starts_at, ends_at, now) {
+        return error(StatusCode::CONFLICT, reason);
+    }
+
+    let price = quote(&capacity, request.hours, &datastore.account_tier(&caller).pricing().rates);
+    let mut account = datastore.account_state.get_account(&caller).unwrap_or_else(|| Account::new(caller.clone()));
+    if !account.deduct_credits(price.total_credits) {
+        return (
+            StatusCode::PAYMENT_REQUIRED,
+            Json(json!({
+                "success": false,
+                "error": format!("The reservation costs {} credits", price.total_credits),
+                "available_credits": account.available_credits()
+            })),
+        );
+    }
+
+    let reservation = Reservation {
+        reservation_id: uuid::Uuid::new_v4().to_string(),
+        account: normalize_account_id(&caller),
+        region: request.region.trim().to_lowercase(),
+        capacity,
+        starts_at,
+        ends_at,
+        price,
+        status: ReservationStatus::Active,
+        refunded_credits: 0,
+        created_at: now,
+        updated_at: now,
+    };
+    if let Err(e) = save_account(&mut datastore, &account, &reservation.reservation_id).await {
+        return e;
+    }
+    log::info!(
+        "{} reserved {:?} in {} until {}, charged {} credits",
+        caller, reservation.capacity, reservation.region, reservation.ends_at, reservation.price.total_credits
+    );
+    save_reservation(&mut datastore, reservation.clone()).await;
+    emit(&mut datastore, WebhookEventType::ReservationCreated, &reservation);
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "reservation": reservation,
+            "credits_remaining": account.available_credits()
+        })),
+    )
+}
+
+/// The caller's reservations, latest start first
+pub async fn list_reservations(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Query(query): Query<ReservationListQuery>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let account = match query.account {
+        Some(account) if datastore.network_state.is_admin_address(&caller) => account,
+        Some(_) => return error(StatusCode::FORBIDDEN, "Only network admins can list other accounts' reservations"),
+        None => caller,
+    };
+    let reservations = datastore.reservations.for_account(&account);
+    (StatusCode::OK, Json(json!({ "success": true, "reservations": reservations })))
+}
+
+pub async fn get_reservation(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(reservation_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    match owned_reservation(&datastore, &reservation_id, &recovered.as_hex()) {
+        Ok(reservation) => (StatusCode::OK, Json(json!({ "success": true, "reservation": reservation }))),
+        Err(e) => e,
+    }
+}
+
+/// Cancels a reservation. One that hasn't started is refunded in full,
+/// once it has started the term is committed and nothing is refunded.
+pub async fn cancel_reservation(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(reservation_id): Path<String>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    let mut reservation = match owned_reservation(&datastore, &reservation_id, &caller) {
+        Ok(reservation) => reservation,
+        Err(e) => return e,
+    };
+    let now = chrono::Utc::now().timestamp();
+    if reservation.status == ReservationStatus::Cancelled || reservation.ends_at <= now {
+        return error(StatusCode::CONFLICT, format!("Reservation {reservation_id} is no longer active"));
+    }
+
+    let refund = if now < reservation.starts_at { reservation.price.total_credits } else { 0 };
+    if refund > 0 {
+        let mut account = datastore.account_state.get_account(&reservation.account)
+            .unwrap_or_else(|| Account::new(reservation.account.clone()));
+        account.add_credits(refund);
+        if let Err(e) = save_account(&mut datastore, &account, &reservation_id).await {
+            return e;
+        }
+    }
+    reservation.status = ReservationStatus::Cancelled;
+    reservation.refunded_credits = refund;
+    reservation.updated_at = now.max(reservation.updated_at + 1);
+    log::info!("{} cancelled reservation {}, refunded {} credits", caller, reservation_id, refund);
+    save_reservation(&mut datastore, reservation.clone()).await;
+    emit(&mut datastore, WebhookEventType::ReservationCancelled, &reservation);
+
+    (StatusCode::OK, Json(json!({ "success": true, "reservation": reservation })))
+}
+
+pub async fn replicate_reservation(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(reservation): Json<Reservation>,
+) -> Json<Response<Reservation>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated reservation {}", reservation.reservation_id);
+    if datastore.reservations.upsert(reservation) {
+        if let Err(e) = store_value(&DB_HANDLE, RESERVATIONS_DB_KEY, &datastore.reservations) {
+            log::error!("Unable to persist reservations: {e}");
+        }
+    }
+    Json(Response::Success(Success::None))
+}
@@ -0,0 +1,246 @@
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::helpers::instances::can_manage_build;
+use crate::auth::RecoveredAddress;
+use crate::secrets::{SecretError, SecretMetadata, SecretRequest, SecretStore, SECRETS_DB_KEY};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use form_types::state::{Response, Success};
+use axum::{extract::{State, Path}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize)]
+pub struct CreateSecretRequest {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+pub struct RotateSecretRequest {
+    pub value: String,
+}
+
+fn secret_error_status(e: &SecretError) -> StatusCode {
+    match e {
+        SecretError::InvalidName(_) | SecretError::TooLarge => StatusCode::BAD_REQUEST,
+        SecretError::AlreadyExists(_) => StatusCode::CONFLICT,
+        SecretError::NotFound(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Checks that the build exists and the caller may manage it
+fn authorize_build(datastore: &DataStore, build_id: &str, recovered: &RecoveredAddress) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let instances = datastore.instance_state.get_instances_by_build_id(build_id.to_string());
+    if instances.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": format!("No instances found for build_id: {}", build_id)
+            }))
+        ));
+    }
+
+    if !can_manage_build(datastore, &instances, &recovered.as_hex()) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "error": "You don't have permission to manage secrets for this build"
+            }))
+        ));
+    }
+
+    Ok(())
+}
+
+/// Persist the secret store and replicate the change to the other admin nodes
+pub(crate) async fn commit_secret_change(datastore: &mut DataStore, request: SecretRequest) {
+    if let Err(e) = store_value(&DB_HANDLE, SECRETS_DB_KEY, datastore.secret_state.records()) {
+        log::error!("Unable to persist secrets: {e}");
+    }
+    if let Err(e) = datastore.broadcast::<Response<SecretMetadata>>(request, "v1/secrets/replicate").await {
+        log::error!("Unable to replicate secret change: {e}");
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+pub async fn create_secret(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(build_id): Path<String>,
+    Json(payload): Json<CreateSecretRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    if let Err(response) = authorize_build(&datastore, &build_id, &recovered) {
+        return response;
+    }
+
+    let owner = recovered.as_hex();
+    let metadata = match datastore.secret_state.create(&build_id, &payload.name, &payload.value, &owner, now()) {
+        Ok(metadata) => metadata,
+        Err(e) => return (
+            secret_error_status(&e),
+            Json(json!({
+                "success": false,
+                "error": e.to_string()
+            }))
+        ),
+    };
+
+    commit_secret_change(&mut datastore, SecretStore::put_request(&metadata, &payload.value)).await;
+    log::info!("create_secret: Created secret {} for build {}", metadata.name, build_id);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "secret": metadata
+        }))
+    )
+}
+
+pub async fn rotate_secret(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path((build_id, name)): Path<(String, String)>,
+    Json(payload): Json<RotateSecretRequest>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    if let Err(response) = authorize_build(&datastore, &build_id, &recovered) {
+        return response;
+    }
+
+    let owner = recovered.as_hex();
+    let metadata = match datastore.secret_state.rotate(&build_id, &name, &payload.value, &owner, now()) {
+        Ok(metadata) => metadata,
+        Err(e) => return (
+            secret_error_status(&e),
+            Json(json!({
+                "success": false,
+                "error": e.to_string()
+            }))
+        ),
+    };
+
+    commit_secret_change(&mut datastore, SecretStore::put_request(&metadata, &payload.value)).await;
+    log::info!("rotate_secret: Rotated secret {} for build {} to version {}", name, build_id, metadata.version);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "secret": metadata
+        }))
+    )
+}
+
+pub async fn delete_secret(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path((build_id, name)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let mut datastore = state.lock().await;
+    if let Err(response) = authorize_build(&datastore, &build_id, &recovered) {
+        return response;
+    }
+
+    let Some(metadata) = datastore.secret_state.remove(&build_id, &name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success": false,
+                "error": SecretError::NotFound(name).to_string()
+            }))
+        );
+    };
+
+    let request = SecretRequest::Delete {
+        build_id: build_id.clone(),
+        name: name.clone(),
+        version: metadata.version,
+    };
+    commit_secret_change(&mut datastore, request).await;
+    log::info!("delete_secret: Deleted secret {} for build {}", name, build_id);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "secret": metadata
+        }))
+    )
+}
+
+pub async fn list_secrets(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Path(build_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    if let Err(response) = authorize_build(&datastore, &build_id, &recovered) {
+        return response;
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "build_id": build_id,
+            "secrets": datastore.secret_state.list_for_build(&build_id)
+        }))
+    )
+}
+
+/// Plaintext secrets of a build, only reachable by the local vmm-service and
+/// other nodes through the node auth middleware
+pub async fn resolve_secrets(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(build_id): Path<String>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    match datastore.secret_state.resolve_for_build(&build_id) {
+        Ok(secrets) => (
+            StatusCode::OK,
+            Json(json!({
+                "success": true,
+                "build_id": build_id,
+                "secrets": secrets
+            }))
+        ),
+        Err(e) => {
+            log::error!("resolve_secrets: Unable to resolve secrets for build {}: {}", build_id, e);
+            (
+                secret_error_status(&e),
+                Json(json!({
+                    "success": false,
+                    "error": e.to_string()
+                }))
+            )
+        }
+    }
+}
+
+pub async fn replicate_secret(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Json(request): Json<SecretRequest>,
+) -> Json<Response<SecretMetadata>> {
+    let mut datastore = state.lock().await;
+    log::info!("Received replicated secret change: {:?}", request);
+    match datastore.secret_state.apply(request) {
+        Ok(changed) => {
+            if changed {
+                if let Err(e) = store_value(&DB_HANDLE, SECRETS_DB_KEY, datastore.secret_state.records()) {
+                    log::error!("Unable to persist secrets: {e}");
+                }
+            }
+            Json(Response::Success(Success::None))
+        }
+        Err(e) => Json(Response::Failure { reason: Some(e.to_string()) }),
+    }
+}
@@ -0,0 +1,33 @@
+use crate::datastore::DataStore;
+use crate::staking::{OperatorStake, StakeAdmission};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{State, Path}, Json};
+use form_types::state::{Response, Success};
+
+pub async fn list_operator_stakes(
+    State(state): State<Arc<Mutex<DataStore>>>,
+) -> Json<Response<OperatorStake>> {
+    let datastore = state.lock().await;
+    Json(Response::Success(Success::List(datastore.staking_state.list())))
+}
+
+pub async fn get_operator_stake(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(operator): Path<String>,
+) -> Json<Response<OperatorStake>> {
+    let datastore = state.lock().await;
+    if let Some(stake) = datastore.staking_state.get(&operator) {
+        return Json(Response::Success(Success::Some(stake.clone())))
+    }
+
+    Json(Response::Failure { reason: Some(format!("No stake recorded for operator: {operator}")) })
+}
+
+pub async fn check_operator_admission(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    Path(operator): Path<String>,
+) -> Json<StakeAdmission> {
+    let datastore = state.lock().await;
+    Json(datastore.staking_state.admission(&operator))
+}
@@ -0,0 +1,44 @@
+use crate::datastore::DataStore;
+use crate::auth::RecoveredAddress;
+use crate::usage_rollups::{normalize_account_id, RollupQuery};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use axum::{extract::{Query, State}, Json};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde_json::json;
+
+/// Usage rollups for the caller's account, admins may query any account or all of them
+pub async fn get_usage_rollups(
+    State(state): State<Arc<Mutex<DataStore>>>,
+    recovered: RecoveredAddress,
+    Query(mut query): Query<RollupQuery>,
+) -> impl IntoResponse {
+    let datastore = state.lock().await;
+    let caller = recovered.as_hex();
+    if !datastore.network_state.is_admin_address(&caller) {
+        match &query.account_id {
+            Some(account_id) if normalize_account_id(account_id) != normalize_account_id(&caller) => {
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({
+                        "success": false,
+                        "error": "You can only view usage for your own account"
+                    }))
+                );
+            }
+            _ => query.account_id = Some(caller),
+        }
+    }
+
+    let rollups = datastore.usage_rollups.query(&query);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "period": query.period,
+            "group_by": query.group_by,
+            "rollups": rollups
+        }))
+    )
+}
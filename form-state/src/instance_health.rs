@@ -0,0 +1,336 @@
+// form-state/src/instance_health.rs
+// One health status per instance, merged from what is otherwise scattered:
+// the liveness of the node hosting it (heartbeats, see `failure_detector`),
+// its boot status, the result of its Formfile `HEALTHCHECK` and anomalies in
+// the metrics its VM reports. `form pack status` shows it and form-dns stops
+// answering with the addresses of unhealthy instances.
+
+use std::time::Duration;
+use form_vm_metrics::system::SystemMetrics;
+use serde::{Deserialize, Serialize};
+use crate::failure_detector::{node_health, FailureDetectorConfig, NodeHealth};
+use crate::instances::{Instance, InstanceStatus};
+use crate::nodes::Node;
+
+/// Port form-vm-metrics serves on inside every instance
+pub const VM_METRICS_PORT: u16 = 63210;
+/// How long fetching an instance's metrics may take
+pub const METRICS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Limits past which an instance is reported as degraded or unhealthy
+#[derive(Clone, Debug)]
+pub struct HealthConfig {
+    pub failure_detector: FailureDetectorConfig,
+    /// Seconds an instance may boot before its boot counts as slow, the
+    /// vmm-service boot watchdog restarts VMs after 300 by default
+    pub slow_boot_secs: i64,
+    /// Seconds after which metrics of a VM no longer count as recent
+    pub stale_metrics_secs: i64,
+    pub cpu_pct: i64,
+    pub memory_pct: u64,
+    pub swap_pct: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            failure_detector: FailureDetectorConfig::default(),
+            slow_boot_secs: 300,
+            stale_metrics_secs: 120,
+            cpu_pct: 95,
+            memory_pct: 95,
+            swap_pct: 50,
+        }
+    }
+}
+
+/// Overall health of an instance, ordered from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum HealthStatus {
+    Healthy,
+    /// Serving, with something worth a look
+    Degraded,
+    /// Not serving, traffic should go elsewhere
+    Unhealthy,
+}
+
+impl HealthStatus {
+    /// Whether the instance should keep receiving traffic
+    pub fn is_serving(&self) -> bool {
+        *self != HealthStatus::Unhealthy
+    }
+}
+
+impl std::fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthStatus::Healthy => write!(f, "Healthy"),
+            HealthStatus::Degraded => write!(f, "Degraded"),
+            HealthStatus::Unhealthy => write!(f, "Unhealthy"),
+        }
+    }
+}
+
+/// Where a reason comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthSource {
+    Node,
+    Boot,
+    Probe,
+    Metrics,
+}
+
+/// Why an instance isn't healthy
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReason {
+    pub source: HealthSource,
+    pub status: HealthStatus,
+    pub message: String,
+}
+
+/// The health of an instance as of `checked_at`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceHealth {
+    pub instance_id: String,
+    pub build_id: String,
+    pub node_id: String,
+    pub formnet_ip: Option<std::net::IpAddr>,
+    /// The worst status of any reason, Healthy without reasons
+    pub status: HealthStatus,
+    pub reasons: Vec<HealthReason>,
+    pub node_health: NodeHealth,
+    pub instance_status: InstanceStatus,
+    /// Timestamp of the metrics the anomalies were found in
+    pub metrics_at: Option<i64>,
+    pub checked_at: i64,
+}
+
+impl InstanceHealth {
+    /// Merges everything known about an instance. `node` is None if the
+    /// hosting node isn't known, `metrics` is None for instances that aren't
+    /// ready, `Some(Err)` if they couldn't be fetched.
+    pub fn aggregate(
+        instance: &Instance,
+        node: Option<&Node>,
+        metrics: Option<Result<&SystemMetrics, String>>,
+        now: i64,
+        config: &HealthConfig,
+    ) -> Self {
+        let mut reasons = Vec::new();
+        let mut reason = |source, status, message: String| reasons.push(HealthReason { source, status, message });
+
+        let node_health = node.map_or(NodeHealth::Unknown, |node| node_health(node, now, &config.failure_detector));
+        match (node, node_health) {
+            (None, _) => reason(HealthSource::Node, HealthStatus::Unhealthy, format!("Node {} is not known", instance.node_id)),
+            (Some(_), NodeHealth::Dead { missed }) => reason(
+                HealthSource::Node, HealthStatus::Unhealthy, format!("Node {} is down, {missed} heartbeats missed", instance.node_id),
+            ),
+            (Some(_), NodeHealth::Suspect { missed }) => reason(
+                HealthSource::Node, HealthStatus::Degraded, format!("Node {} is suspected, {missed} heartbeats missed", instance.node_id),
+            ),
+            (Some(_), NodeHealth::Unknown) => reason(
+                HealthSource::Node, HealthStatus::Degraded, format!("Node {} has not sent a heartbeat yet", instance.node_id),
+            ),
+            (Some(_), NodeHealth::Alive) => {}
+        }
+        if let Some(maintenance) = node.and_then(|node| node.maintenance.as_ref()) {
+            let why = maintenance.reason.as_deref().map(|why| format!(": {why}")).unwrap_or_default();
+            reason(HealthSource::Node, HealthStatus::Degraded, format!("Node {} is in maintenance{why}", instance.node_id));
+        }
+
+        match instance.status {
+            InstanceStatus::Ready => {}
+            InstanceStatus::Booting => {
+                let since = instance.status_history.iter().rev()
+                    .find(|transition| transition.to == InstanceStatus::Booting)
+                    .map_or(instance.updated_at, |transition| transition.at);
+                let booting_for = now.saturating_sub(since);
+                let message = if booting_for > config.slow_boot_secs {
+                    format!("Still booting after {booting_for}s")
+                } else {
+                    "Booting".to_string()
+                };
+                reason(HealthSource::Boot, HealthStatus::Unhealthy, message);
+            }
+            InstanceStatus::Failed => reason(HealthSource::Boot, HealthStatus::Unhealthy, "Failed".to_string()),
+            ref status => reason(HealthSource::Boot, HealthStatus::Unhealthy, format!("Not running, the instance is {status}")),
+        }
+
+        if let Some(readiness) = instance.metadata.monitoring.readiness() {
+            let detail = readiness.detail.as_deref().unwrap_or("no detail");
+            if !readiness.ready {
+                reason(HealthSource::Probe, HealthStatus::Unhealthy, format!(
+                    "Health check failing, {} times in a row: {detail}", readiness.consecutive_failures
+                ));
+            } else if readiness.consecutive_failures > 0 {
+                reason(HealthSource::Probe, HealthStatus::Degraded, format!(
+                    "Health check failed {} times in a row: {detail}", readiness.consecutive_failures
+                ));
+            }
+        }
+
+        let metrics_at = match metrics {
+            None => None,
+            Some(Err(e)) => {
+                reason(HealthSource::Metrics, HealthStatus::Degraded, format!("Metrics unavailable: {e}"));
+                None
+            }
+            Some(Ok(metrics)) => {
+                for message in metric_anomalies(metrics, now, config) {
+                    reason(HealthSource::Metrics, HealthStatus::Degraded, message);
+                }
+                Some(metrics.timestamp)
+            }
+        };
+
+        let status = reasons.iter().map(|reason| reason.status).max().unwrap_or(HealthStatus::Healthy);
+        Self {
+            instance_id: instance.instance_id.clone(),
+            build_id: instance.build_id.clone(),
+            node_id: instance.node_id.clone(),
+            formnet_ip: instance.formnet_ip,
+            status,
+            reasons,
+            node_health,
+            instance_status: instance.status.clone(),
+            metrics_at,
+            checked_at: now,
+        }
+    }
+}
+
+/// What is off about the metrics a VM reported
+pub fn metric_anomalies(metrics: &SystemMetrics, now: i64, config: &HealthConfig) -> Vec<String> {
+    let mut anomalies = Vec::new();
+    let age = now.saturating_sub(metrics.timestamp);
+    if age > config.stale_metrics_secs {
+        anomalies.push(format!("Metrics are {age}s old"));
+    }
+    if metrics.cpu.usage_pct() >= config.cpu_pct {
+        anomalies.push(format!("CPU at {}%", metrics.cpu.usage_pct()));
+    }
+    let memory = &metrics.memory;
+    if memory.total() > 0 {
+        let used_pct = memory.total().saturating_sub(memory.available()) * 100 / memory.total();
+        if used_pct >= config.memory_pct {
+            anomalies.push(format!("Memory at {used_pct}%"));
+        }
+    }
+    if memory.total_swap() > 0 {
+        let swap_pct = memory.used_swap() * 100 / memory.total_swap();
+        if swap_pct >= config.swap_pct {
+            anomalies.push(format!("Swap at {swap_pct}%"));
+        }
+    }
+    anomalies
+}
+
+/// Fetches the current metrics of a ready instance from its VM
+pub async fn fetch_metrics(client: &reqwest::Client, instance: &Instance) -> Option<Result<SystemMetrics, String>> {
+    if instance.status != InstanceStatus::Ready {
+        return None;
+    }
+    let Some(ip) = instance.formnet_ip else {
+        return Some(Err("Instance has no formnet ip".to_string()));
+    };
+    let result = async {
+        client.get(format!("http://{ip}:{VM_METRICS_PORT}/get"))
+            .timeout(METRICS_TIMEOUT)
+            .send().await?
+            .json::<SystemMetrics>().await
+    }.await;
+    Some(result.map_err(|e| e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instances::InstanceReadiness;
+    use crate::lifecycle::StatusTransition;
+
+    const NOW: i64 = 10_000;
+
+    fn instance(status: InstanceStatus) -> Instance {
+        Instance { instance_id: "i".to_string(), node_id: "n".to_string(), status, ..Default::default() }
+    }
+
+    fn node(last_heartbeat: i64) -> Node {
+        Node { node_id: "n".to_string(), last_heartbeat, ..Default::default() }
+    }
+
+    fn metrics(timestamp: i64, cpu: i64, available: u64) -> SystemMetrics {
+        serde_json::from_value(serde_json::json!({
+            "timestamp": timestamp,
+            "instance_id": null,
+            "account_id": null,
+            "cpu": { "usage_pct": cpu, "process_count": 10 },
+            "memory": { "total": 1000, "free": available, "available": available, "used": 1000 - available, "total_swap": 0, "used_swap": 0 },
+            "disks": [],
+            "network": { "interfaces": [] },
+            "gpus": [],
+            "load": { "load1": 0, "load5": 0, "load15": 0 }
+        })).unwrap()
+    }
+
+    fn sources(health: &InstanceHealth) -> Vec<(HealthSource, HealthStatus)> {
+        health.reasons.iter().map(|reason| (reason.source, reason.status)).collect()
+    }
+
+    #[test]
+    fn test_ready_instance_on_live_node_is_healthy() {
+        let config = HealthConfig::default();
+        let metrics = metrics(NOW - 10, 20, 500);
+        let health = InstanceHealth::aggregate(&instance(InstanceStatus::Ready), Some(&node(NOW - 5)), Some(Ok(&metrics)), NOW, &config);
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert!(health.reasons.is_empty());
+        assert_eq!(health.metrics_at, Some(NOW - 10));
+    }
+
+    #[test]
+    fn test_worst_reason_wins() {
+        let config = HealthConfig::default();
+
+        // Anomalies and a suspected node only degrade
+        let hot = metrics(NOW - 500, 99, 10);
+        let health = InstanceHealth::aggregate(&instance(InstanceStatus::Ready), Some(&node(NOW - 70)), Some(Ok(&hot)), NOW, &config);
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert!(health.status.is_serving());
+        assert_eq!(sources(&health), vec![
+            (HealthSource::Node, HealthStatus::Degraded),
+            (HealthSource::Metrics, HealthStatus::Degraded),
+            (HealthSource::Metrics, HealthStatus::Degraded),
+            (HealthSource::Metrics, HealthStatus::Degraded),
+        ]);
+
+        // A failing probe takes it out of rotation
+        let mut probed = instance(InstanceStatus::Ready);
+        probed.metadata.monitoring.readiness = Some(InstanceReadiness {
+            ready: false, consecutive_failures: 3, detail: Some("connection refused".to_string()), reported_at: NOW,
+        });
+        let health = InstanceHealth::aggregate(&probed, Some(&node(NOW)), Some(Err("timed out".to_string())), NOW, &config);
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert!(health.reasons[0].message.contains("connection refused"));
+        assert_eq!(sources(&health)[1], (HealthSource::Metrics, HealthStatus::Degraded));
+
+        // So does a dead or unknown node
+        let health = InstanceHealth::aggregate(&instance(InstanceStatus::Ready), Some(&node(NOW - 600)), None, NOW, &config);
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert_eq!(health.node_health, NodeHealth::Dead { missed: 20 });
+        let health = InstanceHealth::aggregate(&instance(InstanceStatus::Ready), None, None, NOW, &config);
+        assert_eq!(sources(&health), vec![(HealthSource::Node, HealthStatus::Unhealthy)]);
+    }
+
+    #[test]
+    fn test_boot_status() {
+        let config = HealthConfig::default();
+        let mut booting = instance(InstanceStatus::Booting);
+        booting.status_history.push(StatusTransition { from: InstanceStatus::Built, to: InstanceStatus::Booting, at: NOW - 1000 });
+        let health = InstanceHealth::aggregate(&booting, Some(&node(NOW)), None, NOW, &config);
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert_eq!(health.reasons[0].message, "Still booting after 1000s");
+
+        let health = InstanceHealth::aggregate(&instance(InstanceStatus::Stopped), Some(&node(NOW)), None, NOW, &config);
+        assert_eq!(health.reasons[0].message, "Not running, the instance is Stopped");
+    }
+}
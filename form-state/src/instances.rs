@@ -1,38 +1,47 @@
 use std::{collections::{btree_map::{Iter, IterMut}, BTreeMap, HashSet}, fmt::Display, net::IpAddr, time::{Duration, SystemTime, UNIX_EPOCH}};
 use crdts::{map::Op, merkle_reg::Sha3Hash, BFTReg, CmRDT, Map, bft_reg::Update};
 use form_dns::store::FormDnsRecord;
-use form_types::state::{Response, Success};
 use k256::ecdsa::SigningKey;
 use reqwest::Client;
 use serde::{Serialize, Deserialize};
 use tiny_keccak::Hasher;
 use crate::Actor;
+use crate::lifecycle::StatusTransition;
+use crate::revisions::MAX_CONFLICT_RETRIES;
 use crate::scaling::{ScalingManager, ScalingPhase, ScalingOperation, ScalingError, ScalingMetrics, ScalingResources};
 
 pub type InstanceOp = Op<String, BFTReg<Instance, Actor>, Actor>; 
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum InstanceStatus {
+    /// Recorded, the build hasn't started
+    Requested,
     Building,
     Built,
-    Created,
-    Started,
+    /// The VM is created and hasn't reported boot complete, or its
+    /// `HEALTHCHECK` hasn't passed yet
+    #[serde(alias = "Created")]
+    Booting,
+    #[serde(alias = "Started")]
+    Ready,
     Stopped,
-    Killed,
-    CriticalError,
+    #[serde(alias = "CriticalError")]
+    Failed,
+    #[serde(alias = "Killed")]
+    Deleted,
 }
 
 impl Display for InstanceStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            InstanceStatus::Building => writeln!(f, "{}", "Building"),
-            InstanceStatus::Built => writeln!(f, "{}", "Built"),
-            InstanceStatus::Created => writeln!(f, "{}", "Created"),
-            InstanceStatus::Started => writeln!(f, "{}", "Started"),
-            InstanceStatus::Stopped => writeln!(f, "{}", "Stopped"),
-            InstanceStatus::Killed => writeln!(f, "{}", "Killed"),
-            InstanceStatus::CriticalError => writeln!(f, "{}", "Critical Error"),
-
+            InstanceStatus::Requested => write!(f, "Requested"),
+            InstanceStatus::Building => write!(f, "Building"),
+            InstanceStatus::Built => write!(f, "Built"),
+            InstanceStatus::Booting => write!(f, "Booting"),
+            InstanceStatus::Ready => write!(f, "Ready"),
+            InstanceStatus::Stopped => write!(f, "Stopped"),
+            InstanceStatus::Failed => write!(f, "Failed"),
+            InstanceStatus::Deleted => write!(f, "Deleted"),
         }
     }
 }
@@ -56,6 +65,15 @@ pub struct Instance {
     pub formfile: String, 
     pub snapshots: Option<Snapshots>,
     pub metadata: InstanceMetadata,
+    /// Optional lifecycle schedule (auto-stop, auto-delete, start/stop windows)
+    #[serde(default)]
+    pub schedule: Option<InstanceSchedule>,
+    /// Latest status changes, oldest first, see `lifecycle`
+    #[serde(default)]
+    pub status_history: Vec<StatusTransition>,
+    /// Bumped by every write, see `revisions`
+    #[serde(default)]
+    pub revision: u64,
 }
 
 impl Default for Instance {
@@ -78,8 +96,10 @@ impl Default for Instance {
             cluster: Default::default(),
             formfile: String::new(),
             snapshots: None,
-            metadata: Default::default()
-
+            metadata: Default::default(),
+            schedule: None,
+            status_history: Vec::new(),
+            revision: 0,
         }
     }
 }
@@ -135,6 +155,10 @@ impl Instance {
         &self.metadata
     }
 
+    pub fn schedule(&self) -> &Option<InstanceSchedule> {
+        &self.schedule
+    }
+
     pub fn vcpus(&self) -> u8 {
         self.resources.vcpus()
     }
@@ -271,14 +295,49 @@ impl Instance {
     }
 
     pub async fn get(id: &str) -> Option<Self> {
-        let resp = Client::new()
-            .get(format!("http://127.0.0.1:3004/instance/{}/get", id))
+        let mut resp = Client::new()
+            .get(format!("http://127.0.0.1:3004/v1/instance/{}/get", id))
             .send().await.ok()?
-            .json::<Response<Self>>().await.ok()?;
+            .json::<serde_json::Value>().await.ok()?;
+
+        serde_json::from_value(resp.get_mut("instance")?.take()).ok()
+    }
+
+    /// Reads the instance from the local form-state, applies `change` and
+    /// writes it back conditioned on the revision that was read. If another
+    /// writer got there first, `change` is applied again to the fresh
+    /// instance, up to `MAX_CONFLICT_RETRIES` times.
+    pub async fn update_with_retry<F>(id: &str, mut change: F) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: FnMut(&mut Self),
+    {
+        let client = Client::new();
+        let mut conflicts = 0;
+        loop {
+            let mut instance = Self::get(id).await.ok_or_else(|| format!("Instance {id} doesn't exist"))?;
+            let read = instance.revision;
+            change(&mut instance);
+
+            let resp = client.post("http://127.0.0.1:3004/v1/instance/update")
+                .header(reqwest::header::IF_MATCH, read.to_string())
+                .json(&instance)
+                .send()
+                .await?;
+            let status = resp.status();
+            let mut body = resp.json::<serde_json::Value>().await?;
+            if status.is_success() {
+                return Ok(serde_json::from_value(body["instance"].take())?);
+            }
 
-        match resp {
-            Response::Success(Success::Some(instance)) => return Some(instance),
-            _ => return None,
+            // Invalid status transitions are 409s too, only stale writes carry the current revision
+            let stale = status == reqwest::StatusCode::CONFLICT && body.get("current_revision").is_some();
+            if !stale || conflicts >= MAX_CONFLICT_RETRIES {
+                let error = body.get("error").and_then(serde_json::Value::as_str).unwrap_or("unknown error");
+                return Err(format!("Unable to update instance {id}: {error}").into());
+            }
+            conflicts += 1;
+            log::info!("Instance {id} changed while it was being updated, retrying ({conflicts}/{MAX_CONFLICT_RETRIES})");
+            tokio::time::sleep(Duration::from_millis(50 * conflicts as u64)).await;
         }
     }
 }
@@ -4065,6 +4124,10 @@ impl InstanceSecurity {
 pub struct InstanceMonitoring {
     pub logging_enabled: bool,
     pub metrics_endpoint: String,
+    /// Latest result of the Formfile's `HEALTHCHECK`, None if the instance
+    /// doesn't declare one or hasn't reported yet
+    #[serde(default)]
+    pub readiness: Option<InstanceReadiness>,
 }
 
 impl InstanceMonitoring {
@@ -4075,6 +4138,19 @@ impl InstanceMonitoring {
     pub fn metrics_endpoint(&self) -> &str {
         &self.metrics_endpoint
     }
+
+    pub fn readiness(&self) -> Option<&InstanceReadiness> {
+        self.readiness.as_ref()
+    }
+}
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InstanceReadiness {
+    pub ready: bool,
+    pub consecutive_failures: u32,
+    /// Why the last probe failed
+    pub detail: Option<String>,
+    pub reported_at: i64,
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -4094,6 +4170,145 @@ impl InstanceEncryption {
 }
 
 
+/// Action the instance scheduler can take on an instance
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ScheduledAction {
+    Start,
+    Stop,
+    Delete,
+}
+
+/// A recurring window, in UTC, during which an instance should be running.
+///
+/// Windows where `end_minute` is before `start_minute` wrap past midnight.
+#[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScheduleWindow {
+    /// Days of the week the window starts on (0 = Sunday .. 6 = Saturday)
+    pub days: Vec<u8>,
+    /// Minute of the day (0-1439) the instance should be started
+    pub start_minute: u16,
+    /// Minute of the day (0-1439) the instance should be stopped
+    pub end_minute: u16,
+}
+
+/// Per-instance lifecycle schedule evaluated by the instance scheduler.
+#[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InstanceSchedule {
+    /// Minute of the day (0-1439, UTC) at which a running instance is stopped every day
+    pub stop_daily_at: Option<u16>,
+    /// Delete the instance once it has not been running for this many days
+    pub delete_after_idle_days: Option<u32>,
+    /// Recurring start/stop windows
+    pub windows: Vec<ScheduleWindow>,
+    /// Timestamp of the last action taken by the scheduler (Unix timestamp in seconds)
+    pub last_action_at: i64,
+}
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+impl InstanceSchedule {
+    /// Validates the schedule parameters.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(minute) = self.stop_daily_at {
+            if minute >= MINUTES_PER_DAY {
+                return Err(format!("stop_daily_at must be a minute of the day (0-{}), got {}", MINUTES_PER_DAY - 1, minute));
+            }
+        }
+
+        if self.delete_after_idle_days == Some(0) {
+            return Err("delete_after_idle_days must be greater than 0".to_string());
+        }
+
+        for window in &self.windows {
+            if window.days.is_empty() {
+                return Err("schedule windows must include at least one day".to_string());
+            }
+            if let Some(day) = window.days.iter().find(|d| **d > 6) {
+                return Err(format!("schedule window days must be 0 (Sunday) to 6 (Saturday), got {}", day));
+            }
+            if window.start_minute >= MINUTES_PER_DAY || window.end_minute >= MINUTES_PER_DAY {
+                return Err(format!("schedule window minutes must be between 0 and {}", MINUTES_PER_DAY - 1));
+            }
+            if window.start_minute == window.end_minute {
+                return Err("schedule window start and end must differ".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the schedule has nothing to enforce
+    pub fn is_empty(&self) -> bool {
+        self.stop_daily_at.is_none() && self.delete_after_idle_days.is_none() && self.windows.is_empty()
+    }
+
+    /// Returns the most recent scheduled start/stop transition at or before `now`.
+    ///
+    /// Only the last week is searched, which covers every recurring transition.
+    pub fn latest_transition(&self, now: i64) -> Option<(i64, ScheduledAction)> {
+        let today = now.div_euclid(SECONDS_PER_DAY);
+        let mut latest: Option<(i64, ScheduledAction)> = None;
+        let mut consider = |at: i64, action: ScheduledAction| {
+            if at <= now && latest.as_ref().map_or(true, |(t, _)| at >= *t) {
+                latest = Some((at, action));
+            }
+        };
+
+        for day in (today - 7)..=today {
+            let day_start = day * SECONDS_PER_DAY;
+            // 1970-01-01 was a Thursday
+            let weekday = (day + 4).rem_euclid(7) as u8;
+
+            if let Some(minute) = self.stop_daily_at {
+                consider(day_start + minute as i64 * 60, ScheduledAction::Stop);
+            }
+
+            for window in self.windows.iter().filter(|w| w.days.contains(&weekday)) {
+                let start = day_start + window.start_minute as i64 * 60;
+                let mut end = day_start + window.end_minute as i64 * 60;
+                if window.end_minute < window.start_minute {
+                    end += SECONDS_PER_DAY;
+                }
+                consider(start, ScheduledAction::Start);
+                consider(end, ScheduledAction::Stop);
+            }
+        }
+
+        latest
+    }
+}
+
+impl Instance {
+    /// Determines which scheduled action, if any, is due for this instance at `now`.
+    ///
+    /// Transitions are edge triggered: a start or stop is only issued if it became due
+    /// after the scheduler last acted and after the instance last changed, so a manual
+    /// start or stop by the owner is respected until the next transition.
+    pub fn due_scheduled_action(&self, now: i64) -> Option<ScheduledAction> {
+        let schedule = self.schedule.as_ref()?;
+
+        if let Some(days) = schedule.delete_after_idle_days {
+            let idle = !self.status.is_running() && self.status != InstanceStatus::Building;
+            if idle && now - self.updated_at >= days as i64 * SECONDS_PER_DAY {
+                return Some(ScheduledAction::Delete);
+            }
+        }
+
+        let (at, action) = schedule.latest_transition(now)?;
+        if at <= schedule.last_action_at || at <= self.updated_at {
+            return None;
+        }
+
+        match (&action, &self.status) {
+            (ScheduledAction::Stop, status) if status.is_running() => Some(action),
+            (ScheduledAction::Start, InstanceStatus::Stopped) => Some(action),
+            _ => None,
+        }
+    }
+}
+
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct InstanceState {
     node_id: String,
@@ -4124,6 +4339,8 @@ impl InstanceState {
             &hex::decode(self.pk.clone())
                 .expect("PANIC: Invalid SigningKey Cannot Decode from Hex"))
                 .expect("PANIC: Invalid SigningKey cannot recover ffrom Bytes");
+        let mut instance = self.with_status_history(instance);
+        instance.revision = self.next_instance_revision(instance.instance_id());
         log::info!("Creating op...");
         let op = self.map.update(instance.instance_id().to_string(), add_ctx, |reg, _ctx| {
             let op = reg.update(instance.into(), self.node_id.clone(), signing_key).expect("PANIC: Unable to sign updates");
@@ -4202,6 +4419,22 @@ impl InstanceState {
         instances
     }
 
+    pub fn get_instances_by_owner(&self, owner: &str) -> Vec<Instance> {
+        let owner = owner.trim_start_matches("0x").to_lowercase();
+        let mut instances = vec![];
+        for ctx in self.map.iter() {
+            let (_, reg) = ctx.val;
+            if let Some(val) = reg.val() {
+                let instance = val.value();
+                if instance.instance_owner.trim_start_matches("0x").to_lowercase() == owner {
+                    instances.push(instance)
+                }
+            }
+        }
+
+        instances
+    }
+
     pub fn get_instance_by_ip(&self, ip: IpAddr) -> Result<Instance, Box<dyn std::error::Error>> {
         let mut instance_opt: Option<Instance> = None; 
         for ctx in self.map.iter() {
@@ -4933,7 +5166,9 @@ mod tests {
             created_at: 0,
             updated_at: 0,
             last_snapshot: 0,
-            status: InstanceStatus::Created,
+            status: InstanceStatus::Booting,
+            status_history: Vec::new(),
+            revision: 0,
             host_region: "us-east".to_string(),
             resources: InstanceResources {
                 vcpus: 2,
@@ -4950,6 +5185,7 @@ mod tests {
             },
             formfile: "".to_string(),
             snapshots: None,
+            schedule: None,
             metadata: InstanceMetadata {
                 tags: vec![],
                 description: "".to_string(),
@@ -4969,6 +5205,7 @@ mod tests {
                 monitoring: InstanceMonitoring {
                     logging_enabled: false,
                     metrics_endpoint: "".to_string(),
+                    readiness: None,
                 },
             },
         };
@@ -5011,7 +5248,9 @@ mod tests {
             created_at: 0,
             updated_at: 0,
             last_snapshot: 0,
-            status: InstanceStatus::Created,
+            status: InstanceStatus::Booting,
+            status_history: Vec::new(),
+            revision: 0,
             host_region: "us-east".to_string(),
             resources: InstanceResources {
                 vcpus: 2,
@@ -5028,6 +5267,7 @@ mod tests {
             },
             formfile: "".to_string(),
             snapshots: None,
+            schedule: None,
             metadata: InstanceMetadata {
                 tags: vec![],
                 description: "".to_string(),
@@ -5047,6 +5287,7 @@ mod tests {
                 monitoring: InstanceMonitoring {
                     logging_enabled: false,
                     metrics_endpoint: "".to_string(),
+                    readiness: None,
                 },
             },
         };
@@ -5995,4 +6236,75 @@ mod tests {
                 "Error type should be ResourceCleanupError");
         }
     }
+
+    fn scheduled_instance(status: InstanceStatus, updated_at: i64, schedule: InstanceSchedule) -> Instance {
+        Instance {
+            status,
+            updated_at,
+            schedule: Some(schedule),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_schedule_validation() {
+        assert!(InstanceSchedule { stop_daily_at: Some(1440), ..Default::default() }.validate().is_err());
+        assert!(InstanceSchedule { delete_after_idle_days: Some(0), ..Default::default() }.validate().is_err());
+        let window = ScheduleWindow { days: vec![7], start_minute: 0, end_minute: 60 };
+        assert!(InstanceSchedule { windows: vec![window], ..Default::default() }.validate().is_err());
+        let window = ScheduleWindow { days: vec![1, 2, 3, 4, 5], start_minute: 8 * 60, end_minute: 18 * 60 };
+        assert!(InstanceSchedule { stop_daily_at: Some(22 * 60), windows: vec![window], ..Default::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn test_daily_stop_is_edge_triggered() {
+        // 1970-01-05 (a Monday) at 23:00 UTC
+        let now = 4 * 86400 + 23 * 3600;
+        let schedule = InstanceSchedule { stop_daily_at: Some(22 * 60), ..Default::default() };
+
+        let running = scheduled_instance(InstanceStatus::Ready, now - 7200, schedule.clone());
+        assert_eq!(running.due_scheduled_action(now), Some(ScheduledAction::Stop));
+
+        // Started manually after tonight's stop time: leave it alone until tomorrow
+        let restarted = scheduled_instance(InstanceStatus::Ready, now - 600, schedule.clone());
+        assert_eq!(restarted.due_scheduled_action(now), None);
+
+        let mut handled = schedule;
+        handled.last_action_at = now - 1800;
+        let running = scheduled_instance(InstanceStatus::Ready, now - 7200, handled);
+        assert_eq!(running.due_scheduled_action(now), None);
+    }
+
+    #[test]
+    fn test_schedule_window_start_and_stop() {
+        // Weekdays 08:00-18:00 UTC
+        let window = ScheduleWindow { days: vec![1, 2, 3, 4, 5], start_minute: 8 * 60, end_minute: 18 * 60 };
+        let schedule = InstanceSchedule { windows: vec![window], ..Default::default() };
+        let monday = 4 * 86400;
+
+        let stopped = scheduled_instance(InstanceStatus::Stopped, monday - 3600, schedule.clone());
+        assert_eq!(stopped.due_scheduled_action(monday + 9 * 3600), Some(ScheduledAction::Start));
+
+        let started = scheduled_instance(InstanceStatus::Ready, monday + 9 * 3600, schedule.clone());
+        assert_eq!(started.due_scheduled_action(monday + 19 * 3600), Some(ScheduledAction::Stop));
+
+        // Sunday is outside the window days, so Friday's stop is the latest transition
+        let sunday = 3 * 86400;
+        assert_eq!(schedule.latest_transition(sunday + 12 * 3600).map(|(_, a)| a), Some(ScheduledAction::Stop));
+    }
+
+    #[test]
+    fn test_delete_after_idle_days() {
+        let schedule = InstanceSchedule { delete_after_idle_days: Some(7), ..Default::default() };
+        let now = 30 * 86400;
+
+        let idle = scheduled_instance(InstanceStatus::Stopped, now - 8 * 86400, schedule.clone());
+        assert_eq!(idle.due_scheduled_action(now), Some(ScheduledAction::Delete));
+
+        let recent = scheduled_instance(InstanceStatus::Stopped, now - 86400, schedule.clone());
+        assert_eq!(recent.due_scheduled_action(now), None);
+
+        let running = scheduled_instance(InstanceStatus::Ready, now - 8 * 86400, schedule);
+        assert_eq!(running.due_scheduled_action(now), None);
+    }
 }
@@ -1,9 +1,15 @@
 pub mod network;
 pub mod datastore;
 pub mod instances;
+pub mod lifecycle;
+pub mod revisions;
+pub mod quotas;
+pub mod pricing;
 pub mod nodes;
+pub mod node_matching;
 pub mod db;
 pub mod accounts;
+pub mod organizations;
 pub mod scaling;
 pub mod verification;
 pub mod model;
@@ -13,8 +19,28 @@ pub mod api;
 pub mod auth;
 pub mod billing;
 pub mod tasks;
+pub mod autoscaler;
+pub mod failure_detector;
+pub mod instance_health;
+pub mod usage_rollups;
+pub mod staking;
+pub mod secrets;
+pub mod fleet_config;
+pub mod build_manifests;
+pub mod marketplace;
+pub mod domain_verification;
+pub mod grpc;
+pub mod backup;
+pub mod peer_dns;
+pub mod account_deletion;
+pub mod access_policies;
+pub mod peer_expiry;
+pub mod maintenance;
+pub mod reservations;
+pub mod versioning;
 
 pub type Actor = String;
 
 // Re-export key types for easier use, if necessary
 pub use datastore::DataStore;
+pub mod local_queue;
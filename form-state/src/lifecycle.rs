@@ -0,0 +1,147 @@
+// form-state/src/lifecycle.rs
+// Instance status state machine: which status changes are legal, and the
+// history of changes kept on each instance.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::instances::{Instance, InstanceState, InstanceStatus};
+
+/// Most status changes kept on an instance, older ones are dropped first
+pub const MAX_STATUS_HISTORY: usize = 50;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusTransition {
+    pub from: InstanceStatus,
+    pub to: InstanceStatus,
+    /// Unix timestamp in seconds
+    pub at: i64,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Instance {instance_id} cannot go from {from} to {to}")]
+pub struct InvalidTransition {
+    pub instance_id: String,
+    pub from: InstanceStatus,
+    pub to: InstanceStatus,
+}
+
+impl InstanceStatus {
+    /// Whether an instance in this status may be moved to `next`. Writing
+    /// the same status again is always allowed so other fields can change.
+    pub fn can_transition_to(&self, next: &InstanceStatus) -> bool {
+        use InstanceStatus::*;
+        if self == next {
+            return true;
+        }
+        match self {
+            Requested => matches!(next, Building | Failed | Deleted),
+            Building => matches!(next, Built | Failed | Deleted),
+            // A build can be redone before anything boots from it
+            Built => matches!(next, Booting | Building | Failed | Deleted),
+            Booting => matches!(next, Ready | Stopped | Failed | Deleted),
+            // Restarts go back through Booting
+            Ready => matches!(next, Booting | Stopped | Failed | Deleted),
+            // Instances without a HEALTHCHECK are Ready as soon as they start
+            Stopped => matches!(next, Booting | Ready | Failed | Deleted),
+            // Failed instances can be rebuilt or restarted
+            Failed => matches!(next, Building | Booting | Deleted),
+            Deleted => false,
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, InstanceStatus::Deleted)
+    }
+
+    /// Whether the instance's VM exists and is running
+    pub fn is_running(&self) -> bool {
+        matches!(self, InstanceStatus::Booting | InstanceStatus::Ready)
+    }
+}
+
+impl InstanceState {
+    /// Rejects an update that moves a stored instance to a status it can't
+    /// reach from its current one. New instances can start in any status.
+    pub fn validate_transition(&self, instance: &Instance) -> Result<(), InvalidTransition> {
+        match self.get_instance(instance.instance_id.clone()) {
+            Some(current) if !current.status.can_transition_to(&instance.status) => {
+                Err(InvalidTransition {
+                    instance_id: instance.instance_id.clone(),
+                    from: current.status,
+                    to: instance.status.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Carries the stored history over to `instance`, adding an entry if its
+    /// status changed. History sent by callers is ignored, only this node's
+    /// view of the stored instance is trusted.
+    pub fn with_status_history(&self, mut instance: Instance) -> Instance {
+        let (mut history, previous) = match self.get_instance(instance.instance_id.clone()) {
+            Some(current) => (current.status_history, Some(current.status)),
+            None => (Vec::new(), None),
+        };
+
+        if let Some(from) = previous.filter(|from| *from != instance.status) {
+            let at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(instance.updated_at);
+            history.push(StatusTransition { from, to: instance.status.clone(), at });
+        }
+
+        if history.len() > MAX_STATUS_HISTORY {
+            history.drain(..history.len() - MAX_STATUS_HISTORY);
+        }
+        instance.status_history = history;
+        instance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PK: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    fn instance(status: InstanceStatus) -> Instance {
+        Instance { instance_id: "vm".to_string(), status, ..Default::default() }
+    }
+
+    #[test]
+    fn test_transitions_are_validated_and_recorded() {
+        let mut state = InstanceState::new("node".to_string(), PK.to_string());
+        state.update_instance_local(instance(InstanceStatus::Building));
+        assert!(state.get_instance("vm".to_string()).unwrap().status_history.is_empty());
+
+        assert!(state.validate_transition(&instance(InstanceStatus::Built)).is_ok());
+        state.update_instance_local(instance(InstanceStatus::Built));
+        assert!(state.validate_transition(&instance(InstanceStatus::Built)).is_ok());
+        assert_eq!(
+            state.validate_transition(&instance(InstanceStatus::Ready)),
+            Err(InvalidTransition {
+                instance_id: "vm".to_string(),
+                from: InstanceStatus::Built,
+                to: InstanceStatus::Ready,
+            })
+        );
+
+        for status in [InstanceStatus::Booting, InstanceStatus::Ready, InstanceStatus::Deleted] {
+            assert!(state.validate_transition(&instance(status.clone())).is_ok());
+            state.update_instance_local(instance(status));
+        }
+        assert!(state.validate_transition(&instance(InstanceStatus::Booting)).is_err());
+
+        let stored = state.get_instance("vm".to_string()).unwrap();
+        let path: Vec<_> = stored.status_history.iter().map(|t| t.to.clone()).collect();
+        assert_eq!(path, vec![
+            InstanceStatus::Built,
+            InstanceStatus::Booting,
+            InstanceStatus::Ready,
+            InstanceStatus::Deleted,
+        ]);
+        assert_eq!(stored.status_history[0].from, InstanceStatus::Building);
+    }
+}
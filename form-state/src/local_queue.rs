@@ -0,0 +1,268 @@
+//! In-process stand-in for the form-p2p queue
+//!
+//! form-state writes the ops of API mutations to the queue and reads the
+//! `state` topic back from it. In the `network` queue mode both go over HTTP
+//! to the queue service on `QUEUE_PORT`. In the `local` mode they go over a
+//! channel to a task that holds the topics in memory and answers the same
+//! `QueueRequest`s with the same `QueueResponse`s, so a devnet without a
+//! queue service runs the write and read paths production does. The local
+//! queue isn't shared with other nodes, ops are gossiped to them directly
+//! on top of it.
+//!
+//! The mode is read from `FORM_STATE_QUEUE_MODE` (`network` or `local`) or
+//! `--queue-mode` at start and can be switched while running with
+//! `set_queue_mode`. Builds with the `devnet` feature default to `local`.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use form_p2p::queue::{QueueRequest, QueueResponse};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+use tokio::sync::{mpsc, oneshot};
+
+/// Requests the local queue buffers before writers wait on it
+const CHANNEL_CAPACITY: usize = 1024;
+const MODE_UNSET: u8 = u8::MAX;
+
+static MODE: AtomicU8 = AtomicU8::new(MODE_UNSET);
+static LOCAL_QUEUE: OnceCell<LocalQueue> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueMode {
+    /// The queue service of the node
+    Network,
+    /// The in-process queue
+    Local,
+}
+
+impl QueueMode {
+    /// `local` in builds with the `devnet` feature, `network` otherwise
+    pub fn build_default() -> Self {
+        if cfg!(feature = "devnet") {
+            QueueMode::Local
+        } else {
+            QueueMode::Network
+        }
+    }
+
+    /// Read from `FORM_STATE_QUEUE_MODE`, the build's default if it's unset
+    /// or invalid
+    pub fn from_env() -> Self {
+        match std::env::var("FORM_STATE_QUEUE_MODE") {
+            Ok(mode) => mode.parse().unwrap_or_else(|e| {
+                log::warn!("{e}, using the {} queue", Self::build_default());
+                Self::build_default()
+            }),
+            Err(_) => Self::build_default(),
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(QueueMode::Network),
+            1 => Some(QueueMode::Local),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            QueueMode::Network => 0,
+            QueueMode::Local => 1,
+        }
+    }
+}
+
+impl fmt::Display for QueueMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueMode::Network => write!(f, "network"),
+            QueueMode::Local => write!(f, "local"),
+        }
+    }
+}
+
+impl FromStr for QueueMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "network" => Ok(QueueMode::Network),
+            "local" => Ok(QueueMode::Local),
+            other => Err(format!("Unknown queue mode {other}, expected network or local")),
+        }
+    }
+}
+
+/// The mode of the process, read from the environment on first use
+pub fn queue_mode() -> QueueMode {
+    if let Some(mode) = QueueMode::from_u8(MODE.load(Ordering::Relaxed)) {
+        return mode;
+    }
+    let mode = QueueMode::from_env();
+    match MODE.compare_exchange(MODE_UNSET, mode.as_u8(), Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => mode,
+        Err(set) => QueueMode::from_u8(set).unwrap_or(mode),
+    }
+}
+
+/// Switches the mode. Messages already in one queue aren't moved to the
+/// other, readers continue from their index in the new one.
+pub fn set_queue_mode(mode: QueueMode) {
+    let previous = QueueMode::from_u8(MODE.swap(mode.as_u8(), Ordering::Relaxed));
+    if let Some(previous) = previous.filter(|previous| *previous != mode) {
+        log::info!("Switched from the {previous} queue to the {mode} queue");
+    }
+}
+
+/// The name a topic is stored under, the hex Sha3 hash of its name as the
+/// queue service keys them
+pub fn topic_hash(topic: &str) -> String {
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(topic.as_bytes());
+    hasher.finalize(&mut hash);
+    hex::encode(hash)
+}
+
+/// The process's local queue, started on the current runtime on first use
+pub fn local_queue() -> &'static LocalQueue {
+    LOCAL_QUEUE.get_or_init(LocalQueue::spawn)
+}
+
+enum Command {
+    Request(QueueRequest, oneshot::Sender<QueueResponse>),
+    Read {
+        topic: String,
+        last: Option<usize>,
+        n: Option<usize>,
+        reply: oneshot::Sender<QueueResponse>,
+    },
+}
+
+/// Handle of a local queue, cloned handles share the queue
+#[derive(Clone, Debug)]
+pub struct LocalQueue {
+    sender: mpsc::Sender<Command>,
+}
+
+impl LocalQueue {
+    /// Starts the task that holds the topics
+    pub fn spawn() -> Self {
+        let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(async move {
+            let mut topics = Topics::default();
+            while let Some(command) = receiver.recv().await {
+                let (response, reply) = match command {
+                    Command::Request(request, reply) => (topics.handle(request), reply),
+                    Command::Read { topic, last, n, reply } => (topics.read(&topic, last, n), reply),
+                };
+                let _ = reply.send(response);
+            }
+        });
+        Self { sender }
+    }
+
+    /// Answers a request as `POST /queue/write_local` does
+    pub async fn request(&self, request: QueueRequest) -> Result<QueueResponse, Box<dyn std::error::Error>> {
+        let (reply, response) = oneshot::channel();
+        self.sender.send(Command::Request(request, reply)).await
+            .map_err(|_| "The local queue has stopped")?;
+        Ok(response.await?)
+    }
+
+    /// Reads a topic by name as the `/queue/{topic}/..` routes do, from
+    /// index `last` if given and at most `n` messages if given
+    pub async fn read(&self, topic: &str, last: Option<usize>, n: Option<usize>) -> Result<QueueResponse, Box<dyn std::error::Error>> {
+        let (reply, response) = oneshot::channel();
+        self.sender.send(Command::Read { topic: topic.to_string(), last, n, reply }).await
+            .map_err(|_| "The local queue has stopped")?;
+        Ok(response.await?)
+    }
+}
+
+/// Messages of each topic by topic hash
+#[derive(Default)]
+struct Topics(BTreeMap<String, Vec<Vec<u8>>>);
+
+impl Topics {
+    fn handle(&mut self, request: QueueRequest) -> QueueResponse {
+        match request {
+            // Only this process writes to the queue, signatures aren't checked
+            QueueRequest::Write { content, topic } | QueueRequest::SignedWrite { content, topic, .. } => {
+                self.0.entry(topic).or_default().push(content);
+                QueueResponse::OpSuccess
+            }
+            QueueRequest::Op(_) => QueueResponse::Failure {
+                reason: Some("The local queue only takes writes".to_string()),
+            },
+        }
+    }
+
+    fn read(&self, topic: &str, last: Option<usize>, n: Option<usize>) -> QueueResponse {
+        let Some(contents) = self.0.get(&topic_hash(topic)) else {
+            return QueueResponse::Failure { reason: Some(format!("Unable to acquire messages for {topic}")) };
+        };
+        let start = last.unwrap_or(0);
+        if start >= contents.len() && last.is_some() {
+            return QueueResponse::Failure { reason: Some(format!("Queue is shorter than {start} for topic {topic}")) };
+        }
+        let after = &contents[start.min(contents.len())..];
+        let end = n.unwrap_or(after.len()).min(after.len());
+        QueueResponse::List(after[..end].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(topic: &str, content: &[u8]) -> QueueRequest {
+        QueueRequest::Write { content: content.to_vec(), topic: topic_hash(topic) }
+    }
+
+    fn list(response: QueueResponse) -> Vec<Vec<u8>> {
+        match response {
+            QueueResponse::List(list) => list,
+            other => panic!("expected a list, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_queue_reads_like_the_queue_service() {
+        let queue = LocalQueue::spawn();
+        for content in [b"a", b"b", b"c"] {
+            assert!(matches!(queue.request(write("state", content)).await.unwrap(), QueueResponse::OpSuccess));
+        }
+        queue.request(write("other", b"x")).await.unwrap();
+
+        assert_eq!(list(queue.read("state", None, None).await.unwrap()), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(list(queue.read("state", None, Some(2)).await.unwrap()), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(list(queue.read("state", Some(1), None).await.unwrap()), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(list(queue.read("state", Some(1), Some(1)).await.unwrap()), vec![b"b".to_vec()]);
+
+        // A reader that has seen every message gets a failure, as it does
+        // from the queue service, and an unknown topic too
+        assert!(matches!(queue.read("state", Some(3), None).await.unwrap(), QueueResponse::Failure { .. }));
+        assert!(matches!(queue.read("missing", None, None).await.unwrap(), QueueResponse::Failure { .. }));
+
+        // Handles share the queue
+        queue.clone().request(write("state", b"d")).await.unwrap();
+        assert_eq!(list(queue.read("state", Some(3), None).await.unwrap()), vec![b"d".to_vec()]);
+    }
+
+    #[test]
+    fn test_queue_mode_parses_and_switches() {
+        assert_eq!("Local".parse::<QueueMode>(), Ok(QueueMode::Local));
+        assert_eq!(" network ".parse::<QueueMode>(), Ok(QueueMode::Network));
+        assert!("devnet".parse::<QueueMode>().is_err());
+
+        set_queue_mode(QueueMode::Local);
+        assert_eq!(queue_mode(), QueueMode::Local);
+        set_queue_mode(QueueMode::Network);
+        assert_eq!(queue_mode(), QueueMode::Network);
+    }
+}
@@ -8,6 +8,7 @@ use k256::ecdsa::SigningKey;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use form_state::api::run;
+use form_state::local_queue::{queue_mode, set_queue_mode, QueueMode};
 use std::env;
 
 #[derive(Clone, Debug, Parser)]
@@ -32,6 +33,11 @@ pub struct Cli {
     jwt_leeway: Option<String>,
     #[clap(long)]
     env_file: Option<PathBuf>,
+    /// Queue API mutations are written to, `network` for the node's queue
+    /// service or `local` for an in-process one. Overrides
+    /// FORM_STATE_QUEUE_MODE.
+    #[clap(long)]
+    queue_mode: Option<QueueMode>,
 }
 
 #[tokio::main]
@@ -86,14 +92,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("  JWKS URL: {:?}", env::var("DYNAMIC_JWKS_URL").ok());
     log::info!("  Leeway: {:?}", env::var("DYNAMIC_JWT_LEEWAY").ok());
 
-    // Log compilation mode
-    #[cfg(feature = "devnet")]
-    log::info!("Running in DEVNET mode (queue operations disabled)");
-    
-    #[cfg(not(feature = "devnet"))]
-    log::info!("Running in PRODUCTION mode (queue operations enabled)");
+    // Read after the env file is loaded, it may set FORM_STATE_QUEUE_MODE
+    set_queue_mode(parser.queue_mode.unwrap_or_else(QueueMode::from_env));
+    match queue_mode() {
+        QueueMode::Local => log::info!("Running with the local queue, ops are also gossiped to peers directly"),
+        QueueMode::Network => log::info!("Running with the network queue"),
+    }
     
-    let config = OperatorConfig::from_file(parser.config_path, parser.encrypted, parser.password.as_deref()).ok(); 
+    let mut config = OperatorConfig::from_file(parser.config_path, parser.encrypted, parser.password.as_deref()).ok(); 
+    let remote_config_options = form_config::remote::RemoteConfigOptions::from_env();
+    let mut fleet_config = None;
+    if let (Some(config), Some(options)) = (config.as_mut(), remote_config_options.as_ref()) {
+        match form_config::remote::resolve_config(config, options).await {
+            Ok(remote) => fleet_config = remote,
+            Err(e) => log::error!("Unable to apply fleet config from {}: {e}", options.state_url),
+        }
+    }
     let private_key = if let Some(pk) = &parser.secret_key {
         pk.clone()
     } else {
@@ -161,12 +175,258 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     log::info!("Built data store, running...");
+
+    // Secrets are encrypted with this node's key and never gossiped, so they
+    // are restored from the local db rather than the bootstrap state
+    if let Some(ds) = datastore.as_mut() {
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::secrets::SECRETS_DB_KEY) {
+            Ok(Some(records)) => ds.secret_state.restore(records),
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load secrets from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::fleet_config::FLEET_CONFIG_DB_KEY) {
+            Ok(Some(stored)) => {
+                let stored: String = stored;
+                match serde_json::from_str::<form_state::fleet_config::FleetConfigState>(&stored) {
+                    Ok(stored) => if let Some(current) = stored.current() {
+                        ds.fleet_config.apply(current.clone());
+                    }
+                    Err(e) => log::error!("Unable to decode stored fleet config: {e}"),
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load fleet config from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::build_manifests::BUILD_MANIFESTS_DB_KEY) {
+            Ok(Some(manifests)) => ds.build_manifests = manifests,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load build manifests from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::marketplace::MARKETPLACE_DB_KEY) {
+            Ok(Some(listings)) => ds.marketplace = listings,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load marketplace listings from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::access_policies::ACCESS_POLICIES_DB_KEY) {
+            Ok(Some(policies)) => ds.access_policies = policies,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load access policies from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::peer_expiry::PEER_EXPIRY_DB_KEY) {
+            Ok(Some(marks)) => ds.peer_expiry = marks,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load peer expiry marks from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::maintenance::MAINTENANCE_DB_KEY) {
+            Ok(Some(maintenance)) => ds.maintenance = maintenance,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load maintenance window from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::reservations::RESERVATIONS_DB_KEY) {
+            Ok(Some(reservations)) => ds.reservations = reservations,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load reservations from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::domain_verification::DOMAIN_VERIFICATIONS_DB_KEY) {
+            Ok(Some(verifications)) => ds.domain_verifications = verifications,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load domain verifications from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::account_deletion::ACCOUNT_DELETIONS_DB_KEY) {
+            Ok(Some(deletions)) => ds.account_deletions = deletions,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load account deletions from db: {e}"),
+        }
+        // Rollups are derived locally from the usage event queue
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::usage_rollups::USAGE_ROLLUPS_DB_KEY) {
+            Ok(Some(rollups)) => ds.usage_rollups = rollups,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load usage rollups from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::billing::webhooks::WEBHOOKS_DB_KEY) {
+            Ok(Some(webhooks)) => ds.webhooks = webhooks,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load billing webhooks from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::billing::reports::REPORTS_DB_KEY) {
+            Ok(Some(reports)) => ds.reports = reports,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load report subscriptions from db: {e}"),
+        }
+        match form_state::db::load_value(&form_state::datastore::DB_HANDLE, form_state::billing::stripe::STRIPE_EVENTS_DB_KEY) {
+            Ok(Some(events)) => ds.stripe_events = events,
+            Ok(None) => {}
+            Err(e) => log::error!("Unable to load processed Stripe events from db: {e}"),
+        }
+    }
+
+    if let Some(options) = remote_config_options {
+        let mut fleet_config_rx = form_config::remote::watch_remote_config(options, fleet_config);
+        tokio::spawn(async move {
+            while fleet_config_rx.changed().await.is_ok() {
+                if let Some(config) = fleet_config_rx.borrow().as_ref() {
+                    log::warn!("Fleet config changed to version {}, restart form-state to apply it", config.version);
+                }
+            }
+        });
+    }
     
     let (tx, _rx) = tokio::sync::broadcast::channel(1024);
+    let datastore = Arc::new(Mutex::new(datastore.unwrap()));
+
+    let autoscaler_state = datastore.clone();
+    let autoscaler_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::autoscaler::run_autoscaler(
+            autoscaler_state,
+            form_state::autoscaler::AutoscalerConfig::default(),
+            autoscaler_shutdown,
+        ).await {
+            eprintln!("Error running autoscaler: {e}");
+        }
+    });
+
+    let rollup_state = datastore.clone();
+    let rollup_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::usage_rollups::run_usage_rollups(
+            rollup_state,
+            form_state::usage_rollups::UsageRollupConfig::default(),
+            rollup_shutdown,
+        ).await {
+            eprintln!("Error running usage rollups: {e}");
+        }
+    });
+
+    let webhook_state = datastore.clone();
+    let webhook_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::billing::webhooks::run_webhook_dispatcher(
+            webhook_state,
+            form_state::billing::webhooks::WebhookConfig::default(),
+            webhook_shutdown,
+        ).await {
+            eprintln!("Error running billing webhooks: {e}");
+        }
+    });
+
+    let report_state = datastore.clone();
+    let report_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::billing::reports::run_report_scheduler(
+            report_state,
+            form_state::billing::reports::ReportConfig::from_env(),
+            report_shutdown,
+        ).await {
+            eprintln!("Error running report scheduler: {e}");
+        }
+    });
+
+    let detector_state = datastore.clone();
+    let detector_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::failure_detector::run_failure_detector(
+            detector_state,
+            form_state::failure_detector::FailureDetectorConfig::default(),
+            detector_shutdown,
+        ).await {
+            eprintln!("Error running failure detector: {e}");
+        }
+    });
+
+    let expiry_state = datastore.clone();
+    let expiry_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::peer_expiry::run_peer_expiry(
+            expiry_state,
+            form_state::peer_expiry::PeerExpiryConfig::from_env(),
+            expiry_shutdown,
+        ).await {
+            eprintln!("Error running peer expiry: {e}");
+        }
+    });
+
+    let scheduler_state = datastore.clone();
+    let scheduler_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::tasks::run_instance_scheduler(
+            scheduler_state,
+            form_state::tasks::InstanceSchedulerConfig::default(),
+            scheduler_shutdown,
+        ).await {
+            eprintln!("Error running instance scheduler: {e}");
+        }
+    });
+
+    let verifier_state = datastore.clone();
+    let verifier_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::domain_verification::run_domain_verifier(
+            verifier_state,
+            form_state::domain_verification::DomainVerifierConfig::from_env(),
+            verifier_shutdown,
+        ).await {
+            eprintln!("Error running domain verifier: {e}");
+        }
+    });
+
+    let purger_state = datastore.clone();
+    let purger_shutdown = tx.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::account_deletion::run_account_purger(
+            purger_state,
+            form_state::account_deletion::AccountPurgeConfig::default(),
+            purger_shutdown,
+        ).await {
+            eprintln!("Error running account purger: {e}");
+        }
+    });
+
+    let contract_address = config.as_ref().and_then(|c| c.contract_address.clone());
+    match form_state::staking::StakingWatcherConfig::from_env(contract_address) {
+        Some(staking_config) => {
+            let staking_state = datastore.clone();
+            let staking_shutdown = tx.subscribe();
+            tokio::spawn(async move {
+                if let Err(e) = form_state::staking::run_staking_watcher(
+                    staking_state,
+                    staking_config,
+                    staking_shutdown,
+                ).await {
+                    eprintln!("Error running staking watcher: {e}");
+                }
+            });
+        }
+        None => log::warn!("STAKING_RPC_URL or staking contract address not set, node admission is not gated on stake"),
+    }
     
+    match form_state::backup::BackupScheduleConfig::from_env() {
+        Some(backup_config) => {
+            let backup_state = datastore.clone();
+            let backup_shutdown = tx.subscribe();
+            tokio::spawn(async move {
+                if let Err(e) = form_state::backup::run_backup_scheduler(
+                    backup_state,
+                    backup_config,
+                    backup_shutdown,
+                ).await {
+                    eprintln!("Error running backup scheduler: {e}");
+                }
+            });
+        }
+        None => log::info!("FORM_BACKUP_DIR not set, scheduled backups are disabled"),
+    }
+
+    let grpc_state = datastore.clone();
+    tokio::spawn(async move {
+        if let Err(e) = form_state::grpc::run_grpc(grpc_state).await {
+            eprintln!("Error running gRPC query service: {e}");
+        }
+    });
+
     // Always run in full mode, devnet feature controls queue behavior
     let handle = tokio::spawn(async move {
-        if let Err(e) = form_state::api::run_api(Arc::new(Mutex::new(datastore.unwrap()))).await {
+        if let Err(e) = form_state::api::run_api(datastore).await {
             eprintln!("Error running datastore: {e}");
         }
     });
@@ -0,0 +1,175 @@
+// form-state/src/maintenance.rs
+// Maintenance windows that freeze deployments. Admins set a network-wide
+// window during upgrades, single nodes are frozen through their
+// `Node::maintenance`. While frozen, new instances and scaling are refused,
+// reads, stops and deletes keep working.
+
+use serde::{Deserialize, Serialize};
+use crate::datastore::DataStore;
+
+/// Key under which the network-wide window is persisted in the node's db
+pub const MAINTENANCE_DB_KEY: &str = "maintenance/global";
+
+/// A network-wide maintenance window
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// False once an admin lifts the freeze
+    pub enabled: bool,
+    pub reason: Option<String>,
+    pub starts_at: i64,
+    /// The freeze lifts itself at this time if set
+    pub ends_at: Option<i64>,
+    pub updated_by: String,
+    pub updated_at: i64,
+}
+
+impl MaintenanceWindow {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.enabled && self.starts_at <= now && self.ends_at.map_or(true, |ends_at| now < ends_at)
+    }
+}
+
+/// The network-wide window, replicated between nodes with the newest update
+/// winning
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    global: Option<MaintenanceWindow>,
+}
+
+impl MaintenanceState {
+    pub fn global(&self) -> Option<&MaintenanceWindow> {
+        self.global.as_ref()
+    }
+
+    /// The network-wide window if it is in effect at `now`
+    pub fn active(&self, now: i64) -> Option<&MaintenanceWindow> {
+        self.global.as_ref().filter(|window| window.is_active(now))
+    }
+
+    /// Stores a window unless a newer one is held. Returns true if it was
+    /// stored.
+    pub fn apply(&mut self, window: MaintenanceWindow) -> bool {
+        if let Some(current) = &self.global {
+            if (current.updated_at, &current.updated_by) >= (window.updated_at, &window.updated_by) {
+                return false;
+            }
+        }
+        self.global = Some(window);
+        true
+    }
+
+    pub fn merge(&mut self, other: MaintenanceState) {
+        if let Some(window) = other.global {
+            self.apply(window);
+        }
+    }
+}
+
+/// Why a deployment was refused
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeploymentFreeze {
+    /// The frozen node, `None` when the whole network is frozen
+    pub node_id: Option<String>,
+    pub reason: Option<String>,
+    pub since: i64,
+    pub until: Option<i64>,
+}
+
+impl std::fmt::Display for DeploymentFreeze {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.node_id {
+            Some(node_id) => write!(f, "Node {node_id} is in maintenance, new deployments and scaling on it are frozen")?,
+            None => write!(f, "The network is in maintenance, new deployments and scaling are frozen")?,
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, " ({reason})")?;
+        }
+        match self.until.and_then(|until| chrono::DateTime::from_timestamp(until, 0)) {
+            Some(until) => write!(f, " until {}", until.format("%Y-%m-%d %H:%M:%S UTC")),
+            None => write!(f, " until an admin lifts the freeze"),
+        }
+    }
+}
+
+impl std::error::Error for DeploymentFreeze {}
+
+impl DataStore {
+    /// Whether new instances may be created or scaled, on `node_id` if given.
+    /// The network-wide window is checked first.
+    pub fn check_deployments(&self, node_id: Option<&str>, now: i64) -> Result<(), DeploymentFreeze> {
+        if let Some(window) = self.maintenance.active(now) {
+            return Err(DeploymentFreeze {
+                node_id: None,
+                reason: window.reason.clone(),
+                since: window.starts_at,
+                until: window.ends_at,
+            });
+        }
+        let maintenance = node_id
+            .and_then(|node_id| self.node_state.get_node(node_id.to_string()))
+            .and_then(|node| node.maintenance.map(|maintenance| (node.node_id, maintenance)));
+        if let Some((node_id, maintenance)) = maintenance {
+            return Err(DeploymentFreeze {
+                node_id: Some(node_id),
+                reason: maintenance.reason,
+                since: maintenance.started_at,
+                until: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(enabled: bool, updated_at: i64) -> MaintenanceWindow {
+        MaintenanceWindow {
+            enabled,
+            reason: Some("upgrade".into()),
+            starts_at: 100,
+            ends_at: Some(200),
+            updated_by: "admin".into(),
+            updated_at,
+        }
+    }
+
+    #[test]
+    fn test_window_is_active_between_start_and_end() {
+        let window = window(true, 1);
+        assert!(!window.is_active(99));
+        assert!(window.is_active(100));
+        assert!(window.is_active(199));
+        assert!(!window.is_active(200));
+        assert!(!MaintenanceWindow { enabled: false, ..window.clone() }.is_active(150));
+        assert!(MaintenanceWindow { ends_at: None, ..window }.is_active(10_000));
+    }
+
+    #[test]
+    fn test_newest_window_wins() {
+        let mut state = MaintenanceState::default();
+        assert!(state.apply(window(true, 10)));
+        assert!(!state.apply(window(false, 5)));
+        assert!(state.active(150).is_some());
+
+        let mut other = MaintenanceState::default();
+        other.apply(window(false, 20));
+        state.merge(other);
+        assert!(state.active(150).is_none());
+        assert_eq!(state.global().unwrap().updated_at, 20);
+    }
+
+    #[test]
+    fn test_network_freeze_rejects_deployments() {
+        let mut datastore = DataStore::new("node".to_string(), hex::encode([7u8; 32]));
+        assert!(datastore.check_deployments(None, 150).is_ok());
+
+        datastore.maintenance.apply(window(true, 1));
+        let freeze = datastore.check_deployments(Some("node"), 150).unwrap_err();
+        assert_eq!(freeze.node_id, None);
+        assert_eq!(freeze.until, Some(200));
+        assert!(freeze.to_string().starts_with("The network is in maintenance"));
+        assert!(datastore.check_deployments(None, 250).is_ok());
+    }
+}
@@ -0,0 +1,287 @@
+// form-state/src/marketplace.rs
+// Marketplace listings for models and agents. An owner publishes a listing
+// for one of their models or agents, it stays pending until an admin
+// approves it and only approved listings show up in search. Admins can
+// delist a listing at any time and owners can withdraw their own.
+
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Deserialize, Serialize};
+
+/// Key under which the marketplace listings are persisted in the node's db
+pub const MARKETPLACE_DB_KEY: &str = "marketplace/listings";
+
+/// Results per search page unless the query asks for fewer
+pub const DEFAULT_SEARCH_LIMIT: usize = 50;
+pub const MAX_SEARCH_LIMIT: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingKind {
+    Model,
+    Agent,
+}
+
+impl ListingKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingKind::Model => "model",
+            ListingKind::Agent => "agent",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingStatus {
+    /// Waiting for an admin to review it
+    #[default]
+    Pending,
+    /// Visible in search and can be hired
+    Approved,
+    /// Taken off the marketplace by an admin or withdrawn by the owner
+    Delisted,
+}
+
+impl ListingStatus {
+    /// Whether an admin may move a listing from this status to `to`
+    pub fn can_moderate_to(&self, to: ListingStatus) -> bool {
+        matches!(
+            (self, to),
+            (ListingStatus::Pending, ListingStatus::Approved)
+                | (ListingStatus::Pending, ListingStatus::Delisted)
+                | (ListingStatus::Approved, ListingStatus::Delisted)
+                | (ListingStatus::Delisted, ListingStatus::Approved)
+        )
+    }
+}
+
+/// What using a listing costs, in credits on top of the platform's metering
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListingPricing {
+    #[serde(default)]
+    pub per_1m_tokens: Option<u64>,
+    #[serde(default)]
+    pub per_hour: Option<u64>,
+}
+
+impl ListingPricing {
+    /// Credits charged for a use of the listing, rounded up
+    pub fn charge(&self, tokens: u64, hours: f64) -> u64 {
+        let tokens = self.per_1m_tokens
+            .map(|price| (tokens as u128 * price as u128).div_ceil(1_000_000) as u64)
+            .unwrap_or(0);
+        let hours = self.per_hour.map(|price| (hours.max(0.0) * price as f64).ceil() as u64).unwrap_or(0);
+        tokens + hours
+    }
+
+    /// The highest of the listed prices, used by the `max_price` filter
+    fn highest(&self) -> u64 {
+        self.per_1m_tokens.into_iter().chain(self.per_hour).max().unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketplaceListing {
+    /// `<kind>-<item id>`, a model or agent has at most one listing
+    pub listing_id: String,
+    pub kind: ListingKind,
+    /// Id of the listed model or agent
+    pub item_id: String,
+    pub owner_id: String,
+    pub title: String,
+    pub summary: String,
+    pub tags: Vec<String>,
+    pub pricing: ListingPricing,
+    /// Node capabilities the model or agent needs, e.g. `gpu`
+    pub required_capabilities: Vec<String>,
+    pub status: ListingStatus,
+    /// Why the listing was delisted, or any other note from the moderator
+    pub moderation_note: Option<String>,
+    pub moderated_by: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl MarketplaceListing {
+    pub fn listing_id(kind: ListingKind, item_id: &str) -> String {
+        format!("{}-{}", kind.as_str(), item_id)
+    }
+
+    fn matches(&self, query: &ListingQuery) -> bool {
+        if query.kind.is_some_and(|kind| kind != self.kind)
+            || query.status.is_some_and(|status| status != self.status)
+            || query.owner.as_ref().is_some_and(|owner| !owner.eq_ignore_ascii_case(&self.owner_id))
+            || query.max_price.is_some_and(|max| self.pricing.highest() > max)
+        {
+            return false;
+        }
+        if let Some(tag) = &query.tag {
+            if !self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                return false;
+            }
+        }
+        if let Some(capabilities) = &query.capabilities {
+            let available: BTreeSet<String> = capabilities.split(',')
+                .map(|c| c.trim().to_lowercase())
+                .filter(|c| !c.is_empty())
+                .collect();
+            if !self.required_capabilities.iter().all(|c| available.contains(&c.to_lowercase())) {
+                return false;
+            }
+        }
+        if let Some(q) = query.q.as_ref().map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty()) {
+            let found = self.title.to_lowercase().contains(&q)
+                || self.summary.to_lowercase().contains(&q)
+                || self.tags.iter().any(|t| t.to_lowercase().contains(&q));
+            if !found {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Filters of `GET /v1/marketplace/search` and the moderation queue
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ListingQuery {
+    /// Matched against the title, summary and tags
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub kind: Option<ListingKind>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Comma separated capabilities of the node the listing would run on,
+    /// only listings that need nothing else are returned
+    #[serde(default)]
+    pub capabilities: Option<String>,
+    /// Highest price per 1M tokens or per hour
+    #[serde(default)]
+    pub max_price: Option<u64>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub status: Option<ListingStatus>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: Option<usize>,
+}
+
+/// Every listing, replicated between nodes with the newest update winning
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MarketplaceStore {
+    listings: BTreeMap<String, MarketplaceListing>,
+}
+
+impl MarketplaceStore {
+    pub fn get(&self, listing_id: &str) -> Option<&MarketplaceListing> {
+        self.listings.get(listing_id)
+    }
+
+    /// Stores a listing unless a newer one is held. Updates made in the same
+    /// second are ordered by status so a delisting isn't lost to a
+    /// concurrent edit. Returns true if the listing was stored.
+    pub fn upsert(&mut self, listing: MarketplaceListing) -> bool {
+        if let Some(current) = self.listings.get(&listing.listing_id) {
+            if (current.updated_at, current.status) >= (listing.updated_at, listing.status) {
+                return false;
+            }
+        }
+        self.listings.insert(listing.listing_id.clone(), listing);
+        true
+    }
+
+    pub fn merge(&mut self, other: MarketplaceStore) {
+        for listing in other.listings.into_values() {
+            self.upsert(listing);
+        }
+    }
+
+    /// Listings matching `query`, newest first, and how many matched in total
+    pub fn search(&self, query: &ListingQuery) -> (usize, Vec<&MarketplaceListing>) {
+        let mut found: Vec<&MarketplaceListing> = self.listings.values().filter(|l| l.matches(query)).collect();
+        found.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.listing_id.cmp(&b.listing_id)));
+        let total = found.len();
+        let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_SEARCH_LIMIT);
+        let page = found.into_iter().skip(query.offset.unwrap_or(0)).take(limit).collect();
+        (total, page)
+    }
+
+    pub fn len(&self) -> usize {
+        self.listings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(kind: ListingKind, item_id: &str, status: ListingStatus, updated_at: i64) -> MarketplaceListing {
+        MarketplaceListing {
+            listing_id: MarketplaceListing::listing_id(kind, item_id),
+            kind,
+            item_id: item_id.to_string(),
+            owner_id: "owner".to_string(),
+            title: format!("{item_id} title"),
+            summary: String::new(),
+            tags: vec!["vision".to_string()],
+            pricing: ListingPricing { per_1m_tokens: Some(200), per_hour: None },
+            required_capabilities: vec!["gpu".to_string()],
+            status,
+            moderation_note: None,
+            moderated_by: None,
+            created_at: 0,
+            updated_at,
+        }
+    }
+
+    #[test]
+    fn test_marketplace_store() {
+        let mut store = MarketplaceStore::default();
+        assert!(store.upsert(listing(ListingKind::Model, "llama", ListingStatus::Pending, 10)));
+        assert!(store.upsert(listing(ListingKind::Model, "llama", ListingStatus::Approved, 11)));
+        assert!(!store.upsert(listing(ListingKind::Model, "llama", ListingStatus::Pending, 10)));
+        // Same second, the delisting wins
+        assert!(store.upsert(listing(ListingKind::Model, "llama", ListingStatus::Delisted, 11)));
+        assert!(!store.upsert(listing(ListingKind::Model, "llama", ListingStatus::Approved, 11)));
+        assert!(store.upsert(listing(ListingKind::Model, "llama", ListingStatus::Approved, 12)));
+        store.upsert(listing(ListingKind::Agent, "helper", ListingStatus::Approved, 13));
+        store.upsert(listing(ListingKind::Agent, "pending", ListingStatus::Pending, 14));
+
+        let approved = ListingQuery { status: Some(ListingStatus::Approved), ..Default::default() };
+        let (total, page) = store.search(&approved);
+        assert_eq!(total, 2);
+        assert_eq!(page[0].item_id, "helper");
+
+        let agents = ListingQuery { kind: Some(ListingKind::Agent), q: Some("HELP".into()), ..approved.clone() };
+        assert_eq!(store.search(&agents).0, 1);
+        let without_gpu = ListingQuery { capabilities: Some("cpu, sgx".into()), ..approved.clone() };
+        assert_eq!(store.search(&without_gpu).0, 0);
+        let with_gpu = ListingQuery { capabilities: Some("cpu,GPU".into()), max_price: Some(200), ..approved.clone() };
+        assert_eq!(store.search(&with_gpu).0, 2);
+        let cheap = ListingQuery { max_price: Some(100), ..approved.clone() };
+        assert_eq!(store.search(&cheap).0, 0);
+        let paged = ListingQuery { limit: Some(1), offset: Some(1), ..approved };
+        assert_eq!(store.search(&paged), (2, vec![store.get("model-llama").unwrap()]));
+    }
+
+    #[test]
+    fn test_listing_moderation_and_pricing() {
+        assert!(ListingStatus::Pending.can_moderate_to(ListingStatus::Approved));
+        assert!(ListingStatus::Approved.can_moderate_to(ListingStatus::Delisted));
+        assert!(ListingStatus::Delisted.can_moderate_to(ListingStatus::Approved));
+        assert!(!ListingStatus::Approved.can_moderate_to(ListingStatus::Pending));
+        assert!(!ListingStatus::Approved.can_moderate_to(ListingStatus::Approved));
+
+        let pricing = ListingPricing { per_1m_tokens: Some(300), per_hour: Some(5) };
+        assert_eq!(pricing.charge(1_000_000, 0.0), 300);
+        assert_eq!(pricing.charge(1, 0.0), 1);
+        assert_eq!(pricing.charge(0, 1.5), 8);
+        assert_eq!(ListingPricing::default().charge(5_000_000, 10.0), 0);
+    }
+}
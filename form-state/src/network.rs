@@ -2,7 +2,7 @@ use std::{net::{IpAddr, SocketAddr}, time::{Duration, SystemTime}};
 use crdts::{bft_reg::Update, map::Op, merkle_reg::Sha3Hash, BFTReg, CmRDT, Map};
 use ipnet::IpNet;
 use k256::ecdsa::SigningKey;
-use shared::{Association, AssociationContents, Cidr, CidrContents, Endpoint, Peer, PeerContents};
+use shared::{Association, AssociationContents, Cidr, CidrContents, Endpoint, Peer, PeerContents, RetiringKey};
 use serde::{Serialize, Deserialize};
 use tiny_keccak::{Hasher, Sha3};
 use trust_dns_proto::rr::RecordType;
@@ -29,6 +29,8 @@ pub struct CrdtPeer<T: Clone> {
     pub(crate) is_redeemed: bool,
     pub(crate) invite_expires: Option<u64>,
     pub(crate) candidates: Vec<Endpoint>,
+    #[serde(default)]
+    pub(crate) retiring_key: Option<RetiringKey>,
 }
 
 impl Sha3Hash for CrdtPeer<String> {
@@ -55,6 +57,7 @@ impl From<PeerContents<String>> for CrdtPeer<String> {
             .flatten()
             .map(|t| t.as_secs()),
             candidates: value.candidates,
+            retiring_key: value.retiring_key,
         }
     }
 }
@@ -75,7 +78,8 @@ impl From<CrdtPeer<String>> for Peer<String> {
                 is_redeemed: value.is_redeemed,
                 invite_expires: value.invite_expires.map(|time| {
                 SystemTime::UNIX_EPOCH + Duration::from_secs(time)}),
-                candidates: value.candidates
+                candidates: value.candidates,
+                retiring_key: value.retiring_key,
             } 
         }
     }
@@ -637,7 +641,7 @@ impl NetworkState {
                 // Check if this peer is an admin and if its address matches
                 if peer.is_admin() {
                     // Match against the peer's public key (assumed to be an address)
-                    if peer.id().to_lowercase() == address.to_lowercase() {
+                    if crate::auth::timing::addresses_eq(&peer.id(), address) {
                         return true;
                     }
                     
@@ -645,7 +649,7 @@ impl NetworkState {
                     // This is a temporary solution for compatibility
                     if let Ok(peer_addr_bytes) = hex::decode(peer.ip().to_string().replace(".", "")) {
                         let peer_addr_hex = hex::encode(peer_addr_bytes);
-                        if peer_addr_hex.to_lowercase() == address.to_lowercase() {
+                        if crate::auth::timing::addresses_eq(&peer_addr_hex, address) {
                             return true;
                         }
                     }
@@ -687,6 +691,7 @@ mod tests {
             is_redeemed: true,
             invite_expires: None,
             candidates: vec![],
+            retiring_key: None,
         };
 
         // Insert the peer
@@ -726,6 +731,7 @@ mod tests {
             is_redeemed: true,
             invite_expires: None,
             candidates: vec![],
+            retiring_key: None,
         };
 
         let op = state.update_peer_local(peer_contents.clone());
@@ -0,0 +1,406 @@
+// form-state/src/node_matching.rs
+// Matches a workload's requirements against the nodes of the network: nodes
+// that can't run it are filtered out, the rest are ranked by the room they
+// would have left, their benchmark scores and how busy they are. Capacity
+// other accounts have reserved in a region is kept out of the match.
+
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Serialize};
+use crate::failure_detector::{node_health, FailureDetectorConfig};
+use crate::nodes::Node;
+use crate::reservations::RegionCapacity;
+
+/// Weight of the capacity a node has left after placement
+const HEADROOM_WEIGHT: f64 = 0.4;
+/// Weight of the node's benchmark scores, relative to the best candidate
+const BENCHMARK_WEIGHT: f64 = 0.3;
+/// Weight of how idle the node is
+const IDLE_WEIGHT: f64 = 0.3;
+/// Benchmark score of nodes that haven't reported one
+const UNBENCHMARKED_SCORE: f64 = 0.5;
+
+const MB: u64 = 1024 * 1024;
+const GB: u64 = 1024 * MB;
+
+/// Confidential computing technology a workload needs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfidentialCompute {
+    SevSnp,
+    Tdx,
+}
+
+/// GPUs of one model a workload needs
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpuRequirement {
+    pub model: String,
+    #[serde(default = "one")]
+    pub count: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Body of `/nodes/match`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeRequirements {
+    #[serde(default)]
+    pub vcpus: u32,
+    #[serde(default)]
+    pub memory_mb: u64,
+    #[serde(default)]
+    pub disk_gb: u64,
+    #[serde(default)]
+    pub gpus: Vec<GpuRequirement>,
+    /// Only nodes whose host region matches, ignoring case
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub confidential: Option<ConfidentialCompute>,
+    /// Least public bandwidth in Mbit/s, as probed by the node or the link
+    /// speed of its fastest interface
+    #[serde(default)]
+    pub min_bandwidth_mbps: Option<u64>,
+    /// Only nodes with a public address that isn't behind NAT, for relays
+    /// and workloads peers must reach directly
+    #[serde(default)]
+    pub public_ip: bool,
+    /// The account the workload is for. Its own reservations are open to
+    /// it, everyone else's are held back.
+    #[serde(default)]
+    pub account: Option<String>,
+    /// Most candidates returned, all of them when unset
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Parts of a candidate's score, each between 0 and 1
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MatchScores {
+    /// Share of the node's CPU, memory and disk still free after placement
+    pub headroom: f64,
+    /// Benchmark scores relative to the best candidate
+    pub benchmark: f64,
+    /// One minus the node's load per core
+    pub idle: f64,
+}
+
+/// A node that can run the workload
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeMatch {
+    pub node_id: String,
+    pub host_region: String,
+    /// Weighted sum of `scores`, higher is better
+    pub score: f64,
+    pub scores: MatchScores,
+}
+
+/// Why a node can't run the workload, `None` if it can
+pub fn rejection_reason(node: &Node, requirements: &NodeRequirements, now: i64) -> Option<String> {
+    if !node.is_schedulable() {
+        return Some("Node is in maintenance".to_string());
+    }
+    if node_health(node, now, &FailureDetectorConfig::default()).is_dead() {
+        return Some("Node has stopped sending heartbeats".to_string());
+    }
+    if let Some(region) = &requirements.region {
+        if !node.host_region.eq_ignore_ascii_case(region) {
+            return Some(format!("Node is in region {}, not {region}", node.host_region));
+        }
+    }
+
+    let vcpus = requirements.vcpus as usize;
+    if node.capabilities.cpu_cores < vcpus {
+        return Some(format!("Node has {} CPU cores, but workload requires {vcpus}", node.capabilities.cpu_cores));
+    }
+    let available_cores = (node.capacity.cpu_available_cores / 1000).max(0) as usize;
+    if available_cores < vcpus {
+        return Some(format!("Node only has {available_cores} available CPU cores, but workload requires {vcpus}"));
+    }
+    if node.capacity.memory_available_bytes < requirements.memory_mb * MB {
+        return Some(format!("Node only has {} MB available memory, but workload requires {} MB",
+            node.capacity.memory_available_bytes / MB, requirements.memory_mb));
+    }
+    if node.capacity.storage_available_bytes < requirements.disk_gb * GB {
+        return Some(format!("Node only has {} GB available storage, but workload requires {} GB",
+            node.capacity.storage_available_bytes / GB, requirements.disk_gb));
+    }
+
+    if let Some(mode) = requirements.confidential {
+        let supported = match mode {
+            ConfidentialCompute::SevSnp => node.capabilities.sev.as_ref().is_some_and(|sev| sev.can_launch_snp()),
+            ConfidentialCompute::Tdx => node.capabilities.tdx.as_ref().is_some_and(|tdx| tdx.can_launch()),
+        };
+        if !supported {
+            return Some(format!("Node cannot launch {mode:?} guests"));
+        }
+    }
+
+    if let Some(required) = requirements.min_bandwidth_mbps {
+        match node.capabilities.bandwidth_mbps() {
+            Some(bandwidth) if bandwidth >= required => {}
+            Some(bandwidth) => return Some(format!("Node has {bandwidth} Mbit/s of bandwidth, but workload requires {required}")),
+            None => return Some("Node has not reported its bandwidth".to_string()),
+        }
+    }
+    if requirements.public_ip && !node.capabilities.is_publicly_reachable() {
+        return Some("Node is not reachable on a public address".to_string());
+    }
+
+    let mut available_gpus: HashMap<String, u32> = HashMap::new();
+    for gpu in &node.capabilities.gpu_models {
+        if let Some(model) = &gpu.model {
+            *available_gpus.entry(model.to_lowercase()).or_default() += gpu.count;
+        }
+    }
+    let mut required_gpus: HashMap<String, u32> = HashMap::new();
+    for gpu in &requirements.gpus {
+        *required_gpus.entry(gpu.model.to_lowercase()).or_default() += gpu.count;
+    }
+    for (model, count) in required_gpus {
+        match available_gpus.get(&model) {
+            Some(available) if *available >= count => {}
+            Some(available) => return Some(format!("Node has {available} of GPU {model}, but workload requires {count}")),
+            None => return Some(format!("Node has no GPU of model {model}")),
+        }
+    }
+
+    None
+}
+
+fn free_share(available: u64, required: u64, total: u64) -> Option<f64> {
+    (total > 0).then(|| available.saturating_sub(required) as f64 / total as f64)
+}
+
+fn headroom(node: &Node, requirements: &NodeRequirements) -> f64 {
+    let capacity = &node.capacity;
+    let shares: Vec<f64> = [
+        free_share(
+            capacity.cpu_available_cores.max(0) as u64,
+            requirements.vcpus as u64 * 1000,
+            capacity.cpu_total_cores as u64 * 1000,
+        ),
+        free_share(capacity.memory_available_bytes, requirements.memory_mb * MB, capacity.memory_total_bytes),
+        free_share(capacity.storage_available_bytes, requirements.disk_gb * GB, capacity.storage_total_bytes),
+    ].into_iter().flatten().map(|share| share.clamp(0.0, 1.0)).collect();
+
+    if shares.is_empty() {
+        0.0
+    } else {
+        shares.iter().sum::<f64>() / shares.len() as f64
+    }
+}
+
+fn idle(node: &Node) -> f64 {
+    let cores = node.capabilities.cpu_cores.max(node.capacity.cpu_total_cores).max(1) as f64;
+    let load_per_core = node.metrics.load_avg_1.max(0) as f64 / 1000.0 / cores;
+    (1.0 - load_per_core).clamp(0.0, 1.0)
+}
+
+/// Why the region of `node` can't take the workload without dipping into
+/// reserved capacity, `None` if it can. `unreserved` is the capacity left
+/// outside reservations in the regions that have any.
+pub fn reservation_conflict(
+    node: &Node,
+    requirements: &NodeRequirements,
+    unreserved: &BTreeMap<String, RegionCapacity>,
+) -> Option<String> {
+    let left = unreserved.get(&node.host_region.to_lowercase())?;
+    let wanted = RegionCapacity::new(requirements.vcpus, requirements.memory_mb, &requirements.gpus);
+    left.shortfall(&wanted)
+        .map(|shortfall| format!("Region {} is reserved by other accounts: {shortfall}", node.host_region))
+}
+
+/// Nodes that can run the workload, best first. Equal scores are ordered by
+/// node id so every caller sees the same ranking.
+pub fn match_nodes(nodes: &[Node], requirements: &NodeRequirements, now: i64) -> Vec<NodeMatch> {
+    match_unreserved_nodes(nodes, requirements, &BTreeMap::new(), now)
+}
+
+/// `match_nodes`, leaving out nodes in regions where the workload would
+/// take capacity reserved by other accounts, see `reservation_conflict`
+pub fn match_unreserved_nodes(
+    nodes: &[Node],
+    requirements: &NodeRequirements,
+    unreserved: &BTreeMap<String, RegionCapacity>,
+    now: i64,
+) -> Vec<NodeMatch> {
+    let candidates: Vec<&Node> = nodes.iter().filter(|node| {
+        let reason = rejection_reason(node, requirements, now)
+            .or_else(|| reservation_conflict(node, requirements, unreserved));
+        match reason {
+            Some(reason) => {
+                log::debug!("Node {} does not match: {reason}", node.node_id);
+                false
+            }
+            None => true,
+        }
+    }).collect();
+
+    let best_cpu = candidates.iter().filter_map(|node| node.capabilities.benchmark).map(|b| b.cpu_sha256_mbps).max().unwrap_or(0);
+    let best_memory = candidates.iter().filter_map(|node| node.capabilities.benchmark).map(|b| b.memory_copy_mbps).max().unwrap_or(0);
+    let relative = |score: u64, best: u64| if best == 0 { UNBENCHMARKED_SCORE } else { score as f64 / best as f64 };
+
+    let mut matches: Vec<NodeMatch> = candidates.into_iter().map(|node| {
+        let scores = MatchScores {
+            headroom: headroom(node, requirements),
+            benchmark: node.capabilities.benchmark.map_or(UNBENCHMARKED_SCORE, |b| {
+                (relative(b.cpu_sha256_mbps, best_cpu) + relative(b.memory_copy_mbps, best_memory)) / 2.0
+            }),
+            idle: idle(node),
+        };
+        NodeMatch {
+            node_id: node.node_id.clone(),
+            host_region: node.host_region.clone(),
+            score: HEADROOM_WEIGHT * scores.headroom + BENCHMARK_WEIGHT * scores.benchmark + IDLE_WEIGHT * scores.idle,
+            scores,
+        }
+    }).collect();
+
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.node_id.cmp(&b.node_id)));
+    if let Some(limit) = requirements.limit {
+        matches.truncate(limit);
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use form_node_metrics::benchmark::BenchmarkScores;
+    use form_node_metrics::capabilities::{GpuInfo, NetworkCapability};
+    use form_node_metrics::network::{NatType, NetworkProfile};
+    use crate::nodes::NodeMaintenance;
+
+    fn node(node_id: &str, region: &str, cores: usize, free_cores: i64, memory_gb: u64) -> Node {
+        let mut node = Node {
+            node_id: node_id.to_string(),
+            host_region: region.to_string(),
+            last_heartbeat: 1_000,
+            ..Default::default()
+        };
+        node.capabilities.cpu_cores = cores;
+        node.capacity.cpu_total_cores = cores;
+        node.capacity.cpu_available_cores = free_cores * 1000;
+        node.capacity.memory_total_bytes = memory_gb * GB;
+        node.capacity.memory_available_bytes = memory_gb * GB;
+        node.capacity.storage_total_bytes = 100 * GB;
+        node.capacity.storage_available_bytes = 100 * GB;
+        node
+    }
+
+    #[test]
+    fn test_filters_nodes_that_cannot_run_the_workload() {
+        let requirements = NodeRequirements {
+            vcpus: 4,
+            memory_mb: 8192,
+            gpus: vec![GpuRequirement { model: "RTX 4090".to_string(), count: 2 }],
+            region: Some("us-east".to_string()),
+            ..Default::default()
+        };
+        let mut gpu_node = node("gpu", "US-East", 16, 12, 64);
+        gpu_node.capabilities.gpu_models.push(GpuInfo {
+            vendor: "NVIDIA".to_string(),
+            model: Some("rtx 4090".to_string()),
+            count: 2,
+            total_memory_bytes: 48 * GB,
+            pci_bus_id: None,
+            cuda_enabled: None,
+            driver_version: None,
+        });
+        let mut draining = gpu_node.clone();
+        draining.node_id = "draining".to_string();
+        draining.maintenance = Some(NodeMaintenance::default());
+        let mut dead = gpu_node.clone();
+        dead.node_id = "dead".to_string();
+        dead.last_heartbeat = 1;
+
+        assert_eq!(rejection_reason(&gpu_node, &requirements, 1_000), None);
+        assert!(rejection_reason(&node("busy", "us-east", 16, 2, 64), &requirements, 1_000).unwrap().contains("available CPU"));
+        assert!(rejection_reason(&node("small", "us-east", 16, 12, 4), &requirements, 1_000).unwrap().contains("memory"));
+        assert!(rejection_reason(&node("west", "us-west", 16, 12, 64), &requirements, 1_000).unwrap().contains("region"));
+        assert!(rejection_reason(&node("no-gpu", "us-east", 16, 12, 64), &requirements, 1_000).unwrap().contains("GPU"));
+        assert!(rejection_reason(&draining, &requirements, 1_000).is_some());
+        assert!(rejection_reason(&dead, &requirements, 1_000).is_some());
+
+        let nodes = vec![gpu_node, draining, dead, node("west", "us-west", 16, 12, 64)];
+        let matches = match_nodes(&nodes, &requirements, 1_000);
+        assert_eq!(matches.iter().map(|m| m.node_id.as_str()).collect::<Vec<_>>(), vec!["gpu"]);
+    }
+
+    #[test]
+    fn test_filters_on_network_capabilities() {
+        let requirements = NodeRequirements { min_bandwidth_mbps: Some(500), public_ip: true, ..Default::default() };
+        let mut open = node("open", "eu", 8, 8, 32);
+        open.capabilities.network = Some(NetworkProfile {
+            nat: NatType::Open,
+            download_mbps: Some(900),
+            upload_mbps: Some(800),
+            ..Default::default()
+        });
+        let mut natted = open.clone();
+        natted.node_id = "natted".to_string();
+        natted.capabilities.network.as_mut().unwrap().nat = NatType::EndpointDependent;
+        let mut slow = open.clone();
+        slow.node_id = "slow".to_string();
+        slow.capabilities.network.as_mut().unwrap().upload_mbps = Some(100);
+        // Without a measurement the link speed counts
+        let mut unprobed = node("unprobed", "eu", 8, 8, 32);
+        unprobed.capabilities.network_interfaces.push(NetworkCapability {
+            interface_name: "eth0".to_string(),
+            link_speed_mbps: Some(1000),
+            is_active: true,
+            ..Default::default()
+        });
+
+        assert_eq!(rejection_reason(&open, &requirements, 1_000), None);
+        assert!(rejection_reason(&natted, &requirements, 1_000).unwrap().contains("public address"));
+        assert!(rejection_reason(&slow, &requirements, 1_000).unwrap().contains("100 Mbit/s"));
+        assert!(rejection_reason(&unprobed, &requirements, 1_000).unwrap().contains("public address"));
+        assert_eq!(rejection_reason(&unprobed, &NodeRequirements { public_ip: false, ..requirements.clone() }, 1_000), None);
+        assert!(rejection_reason(&node("bare", "eu", 8, 8, 32), &requirements, 1_000).unwrap().contains("bandwidth"));
+    }
+
+    #[test]
+    fn test_reserved_capacity_is_kept_from_other_accounts() {
+        let requirements = NodeRequirements { vcpus: 4, memory_mb: 4096, ..Default::default() };
+        let nodes = vec![node("east", "US-East", 16, 8, 64), node("west", "us-west", 16, 8, 64)];
+        let unreserved = BTreeMap::from([(
+            "us-east".to_string(),
+            RegionCapacity { vcpus: 2, memory_mb: 32768, gpus: BTreeMap::new() },
+        )]);
+
+        assert!(reservation_conflict(&nodes[0], &requirements, &unreserved).unwrap().contains("2 vCPUs left"));
+        assert_eq!(reservation_conflict(&nodes[1], &requirements, &unreserved), None);
+        let matches = match_unreserved_nodes(&nodes, &requirements, &unreserved, 1_000);
+        assert_eq!(matches.iter().map(|m| m.node_id.as_str()).collect::<Vec<_>>(), vec!["west"]);
+        assert_eq!(match_nodes(&nodes, &requirements, 1_000).len(), 2);
+    }
+
+    #[test]
+    fn test_ranks_by_headroom_benchmark_and_load() {
+        let requirements = NodeRequirements { vcpus: 2, memory_mb: 4096, ..Default::default() };
+        let mut fast = node("fast", "eu", 8, 8, 32);
+        fast.capabilities.benchmark = Some(BenchmarkScores { cpu_sha256_mbps: 2000, memory_copy_mbps: 20000 });
+        let mut slow = node("slow", "eu", 8, 8, 32);
+        slow.capabilities.benchmark = Some(BenchmarkScores { cpu_sha256_mbps: 1000, memory_copy_mbps: 10000 });
+        let mut loaded = fast.clone();
+        loaded.node_id = "loaded".to_string();
+        loaded.metrics.load_avg_1 = 8000;
+        let full = node("full", "eu", 8, 3, 8);
+
+        let nodes = vec![full, slow, loaded, fast];
+
+        let matches = match_nodes(&nodes, &requirements, 1_000);
+        let order: Vec<&str> = matches.iter().map(|m| m.node_id.as_str()).collect();
+        assert_eq!(order, vec!["fast", "slow", "full", "loaded"]);
+        assert_eq!(matches[0].scores.benchmark, 1.0);
+        assert_eq!(matches[1].scores.benchmark, 0.5);
+        assert_eq!(matches[2].scores.benchmark, UNBENCHMARKED_SCORE);
+        assert_eq!(matches[3].scores.idle, 0.0);
+
+        let limited = match_nodes(&nodes, &NodeRequirements { limit: Some(1), ..requirements }, 1_000);
+        assert_eq!(limited.iter().map(|m| m.node_id.as_str()).collect::<Vec<_>>(), vec!["fast"]);
+    }
+}
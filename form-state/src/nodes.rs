@@ -1,5 +1,6 @@
 use crdts::{map::Op, merkle_reg::Sha3Hash, BFTReg, CmRDT, Map, bft_reg::Update};
-use form_node_metrics::{capabilities::NodeCapabilities, capacity::NodeCapacity, metrics::NodeMetrics};
+use form_node_metrics::{capabilities::NodeCapabilities, capacity::NodeCapacity, connectivity::ConnectivityMetrics, metrics::NodeMetrics};
+use form_p2p::status::QueueHealth;
 use k256::ecdsa::SigningKey;
 use tiny_keccak::Hasher;
 use url::Host;
@@ -21,7 +22,24 @@ pub struct Node {
     pub metrics: NodeMetrics,
     pub metadata: NodeMetadata,
     pub host: Host,
-    pub operator_keys: Vec<String> // Array of operator keys that can authenticate this node
+    pub operator_keys: Vec<String>, // Array of operator keys that can authenticate this node
+    /// Per peer connection quality measured by formnet on this node
+    #[serde(default)]
+    pub connectivity: ConnectivityMetrics,
+    /// Replication health of the node's queue, from its last heartbeat
+    #[serde(default)]
+    pub queue_health: Option<QueueHealth>,
+    /// Set while the node is being drained or is down for maintenance, new
+    /// instances aren't placed on it
+    #[serde(default)]
+    pub maintenance: Option<NodeMaintenance>,
+}
+
+/// Why and since when a node is in maintenance mode
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeMaintenance {
+    pub started_at: i64,
+    pub reason: Option<String>,
 }
 
 impl Default for Node {
@@ -39,7 +57,10 @@ impl Default for Node {
             metrics: Default::default(),
             metadata: Default::default(),
             host: Host::Domain(Default::default()),
-            operator_keys: Vec::new()
+            operator_keys: Vec::new(),
+            connectivity: ConnectivityMetrics::default(),
+            queue_health: None,
+            maintenance: None,
         }
     }
 }
@@ -107,10 +128,15 @@ impl Node {
         self.operator_keys.retain(|k| k != key);
     }
 
+    /// Whether new instances may be placed on the node
+    pub fn is_schedulable(&self) -> bool {
+        self.maintenance.is_none()
+    }
+
     /// Check if an address is authorized as an admin for this node
     pub fn is_admin_address(&self, address: &str) -> bool {
         // First check if the address matches the node owner (always admin)
-        if self.node_owner.to_lowercase() == address.to_lowercase() {
+        if crate::auth::timing::addresses_eq(&self.node_owner, address) {
             return true;
         }
         
@@ -238,11 +264,15 @@ impl NodeState {
         op
     }
 
-    pub fn update_node_heartbeat(&mut self, node_id: String, timestamp: i64) -> Option<NodeOp> {
+    pub fn update_node_heartbeat(&mut self, node_id: String, timestamp: i64, queue: Option<QueueHealth>) -> Option<NodeOp> {
         if let Some(node_reg) = self.map.get(&node_id).val {
             if let Some(node_val) = node_reg.val() {
                 let mut node = node_val.value();
                 node.last_heartbeat = timestamp;
+                // Keep the last known queue health if the node couldn't read it
+                if queue.is_some() {
+                    node.queue_health = queue;
+                }
                 return Some(self.update_node_local(node))
             }
         }
@@ -263,6 +293,18 @@ impl NodeState {
         None
     }
 
+    pub fn update_node_connectivity(&mut self, node_id: String, connectivity: ConnectivityMetrics) -> Option<NodeOp> {
+        if let Some(node_reg) = self.map.get(&node_id).val {
+            if let Some(node_val) = node_reg.val() {
+                let mut node = node_val.value();
+                node.connectivity = connectivity;
+                return Some(self.update_node_local(node))
+            }
+        }
+
+        None
+    }
+
     pub fn set_initial_node_capabilities(&mut self, node_id: String, node_capacity: NodeCapacity, node_capabilities: NodeCapabilities) -> Option<NodeOp> {
         if let Some(node_reg) = self.map.get(&node_id).val {
             if let Some(node_val) = node_reg.val() {
@@ -275,6 +317,18 @@ impl NodeState {
         None
     }
 
+    /// Put a node into maintenance mode, or take it out with `None`
+    pub fn set_node_maintenance(&mut self, node_id: String, maintenance: Option<NodeMaintenance>) -> Option<NodeOp> {
+        if let Some(node_reg) = self.map.get(&node_id).val {
+            if let Some(node_val) = node_reg.val() {
+                let mut node = node_val.value();
+                node.maintenance = maintenance;
+                return Some(self.update_node_local(node))
+            }
+        }
+        None
+    }
+
     /// Remove a node record locally.
     pub fn remove_node_local(&mut self, id: String) -> NodeOp {
         log::info!("Acquiring remove context...");
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Serialize, Deserialize};
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use alloy_primitives::Address;
+use crdts::{Map, BFTReg, map::Op, bft_reg::Update, CmRDT};
+use tiny_keccak::{Hasher, Sha3};
+use chrono::Utc;
+use crate::accounts::AuthorizationLevel;
+use crate::Actor;
+
+pub type OrganizationOp = Op<String, BFTReg<Organization, Actor>, Actor>;
+pub type OrganizationMap = Map<String, BFTReg<Organization, String>, String>;
+
+/// Role of a member within an organization
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OrgRole {
+    /// Full control, including deleting the organization
+    Owner,
+    /// Can manage members, invitations and org instances
+    Admin,
+    /// Can operate org instances
+    Member,
+    /// Can only view org instances
+    Viewer,
+}
+
+impl OrgRole {
+    /// The authorization a member with this role has on instances owned by the organization
+    pub fn instance_level(&self) -> AuthorizationLevel {
+        match self {
+            OrgRole::Owner => AuthorizationLevel::Owner,
+            OrgRole::Admin => AuthorizationLevel::Manager,
+            OrgRole::Member => AuthorizationLevel::Operator,
+            OrgRole::Viewer => AuthorizationLevel::ReadOnly,
+        }
+    }
+
+    pub fn can_manage_members(&self) -> bool {
+        matches!(self, OrgRole::Owner | OrgRole::Admin)
+    }
+}
+
+/// An invitation to join an organization, signed by the owner or admin who issued it.
+/// The signature lets every node verify the invitation before the invitee redeems it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OrgInvitation {
+    pub org_id: String,
+    /// Address of the invited account
+    pub address: String,
+    pub role: OrgRole,
+    /// Address of the owner or admin that issued the invitation
+    pub invited_by: String,
+    /// Unix timestamp after which the invitation can no longer be accepted
+    pub expires_at: i64,
+    /// Hex encoded signature over `signing_payload`
+    pub signature: String,
+    pub recovery_id: u8,
+}
+
+impl OrgInvitation {
+    /// The sha3 digest the issuer signs
+    pub fn signing_payload(&self) -> [u8; 32] {
+        let mut hasher = Sha3::v256();
+        let mut hash = [0u8; 32];
+        hasher.update(self.org_id.as_bytes());
+        hasher.update(normalize_address(&self.address).as_bytes());
+        hasher.update(format!("{:?}", self.role).as_bytes());
+        hasher.update(&self.expires_at.to_be_bytes());
+        hasher.finalize(&mut hash);
+        hash
+    }
+
+    pub fn sign(&mut self, signing_key: &SigningKey) -> Result<(), String> {
+        let (signature, recovery_id) = signing_key.sign_prehash_recoverable(&self.signing_payload())
+            .map_err(|e| e.to_string())?;
+        self.invited_by = hex::encode(Address::from_private_key(signing_key));
+        self.signature = hex::encode(signature.to_bytes());
+        self.recovery_id = recovery_id.to_byte();
+        Ok(())
+    }
+
+    /// Recover the issuer and check it matches `invited_by`
+    pub fn verify(&self) -> Result<(), String> {
+        let signature_bytes = hex::decode(&self.signature).map_err(|e| e.to_string())?;
+        let signature = Signature::from_slice(&signature_bytes).map_err(|e| e.to_string())?;
+        let recovery_id = RecoveryId::from_byte(self.recovery_id)
+            .ok_or_else(|| "Invalid recovery id".to_string())?;
+        let verifying_key = VerifyingKey::recover_from_prehash(&self.signing_payload(), &signature, recovery_id)
+            .map_err(|e| e.to_string())?;
+        let signer = hex::encode(Address::from_public_key(&verifying_key));
+        if signer != normalize_address(&self.invited_by) {
+            return Err("Invitation was not signed by its issuer".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        now > self.expires_at
+    }
+}
+
+/// A group of accounts that share billing and instance ownership
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Organization {
+    pub org_id: String,
+    pub name: String,
+    /// Members by address
+    pub members: BTreeMap<String, OrgRole>,
+    /// Pending invitations by invited address
+    #[serde(default)]
+    pub invitations: BTreeMap<String, OrgInvitation>,
+    /// Account whose credits pay for usage of org instances
+    pub billing_account: String,
+    /// Instances owned by the organization rather than an individual member
+    #[serde(default)]
+    pub owned_instances: BTreeSet<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl AsRef<[u8]> for Organization {
+    fn as_ref(&self) -> &[u8] {
+        self.org_id.as_bytes()
+    }
+}
+
+impl Organization {
+    /// Create an organization owned and billed to `owner`
+    pub fn new(org_id: String, name: String, owner: &str) -> Self {
+        let now = Utc::now().timestamp();
+        let owner = normalize_address(owner);
+        Self {
+            org_id,
+            name,
+            members: BTreeMap::from([(owner.clone(), OrgRole::Owner)]),
+            invitations: BTreeMap::new(),
+            billing_account: owner,
+            owned_instances: BTreeSet::new(),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn role_of(&self, address: &str) -> Option<&OrgRole> {
+        self.members.get(&normalize_address(address))
+    }
+
+    pub fn add_invitation(&mut self, invitation: OrgInvitation) {
+        self.invitations.insert(normalize_address(&invitation.address), invitation);
+        self.updated_at = Utc::now().timestamp();
+    }
+
+    /// Turn a pending invitation for `address` into a membership
+    pub fn accept_invitation(&mut self, address: &str, now: i64) -> Result<OrgRole, String> {
+        let address = normalize_address(address);
+        let invitation = self.invitations.get(&address)
+            .ok_or_else(|| "No pending invitation for this address".to_string())?;
+        if invitation.is_expired(now) {
+            return Err("Invitation has expired".to_string());
+        }
+        invitation.verify()?;
+        // The issuer must still be allowed to invite when the invitation is redeemed
+        if !self.role_of(&invitation.invited_by).is_some_and(|r| r.can_manage_members()) {
+            return Err("Invitation issuer is no longer an organization admin".to_string());
+        }
+
+        let role = invitation.role.clone();
+        self.invitations.remove(&address);
+        self.members.insert(address, role.clone());
+        self.updated_at = now;
+        Ok(role)
+    }
+
+    /// Remove a member, the last owner cannot be removed
+    pub fn remove_member(&mut self, address: &str) -> Result<(), String> {
+        let address = normalize_address(address);
+        if self.members.get(&address) == Some(&OrgRole::Owner) &&
+            self.members.values().filter(|r| **r == OrgRole::Owner).count() == 1 {
+            return Err("Cannot remove the last owner of an organization".to_string());
+        }
+        if self.members.remove(&address).is_none() {
+            return Err("Address is not a member of this organization".to_string());
+        }
+        self.updated_at = Utc::now().timestamp();
+        Ok(())
+    }
+
+    pub fn add_owned_instance(&mut self, instance_id: String) {
+        self.owned_instances.insert(instance_id);
+        self.updated_at = Utc::now().timestamp();
+    }
+
+    pub fn remove_owned_instance(&mut self, instance_id: &str) -> bool {
+        let removed = self.owned_instances.remove(instance_id);
+        if removed {
+            self.updated_at = Utc::now().timestamp();
+        }
+        removed
+    }
+}
+
+pub fn normalize_address(address: &str) -> String {
+    address.strip_prefix("0x").unwrap_or(address).to_lowercase()
+}
+
+/// State container for organizations
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrganizationState {
+    node_id: String,
+    pk: String,
+    pub map: OrganizationMap,
+}
+
+impl OrganizationState {
+    pub fn new(node_id: String, pk: String) -> Self {
+        Self {
+            node_id,
+            pk,
+            map: Map::new()
+        }
+    }
+
+    pub fn map(&self) -> &OrganizationMap {
+        &self.map
+    }
+
+    /// Update an organization locally and return the operation
+    pub fn update_organization_local(&mut self, organization: Organization) -> OrganizationOp {
+        let add_ctx = self.map.read_ctx().derive_add_ctx(self.node_id.clone());
+        let signing_key = SigningKey::from_slice(
+            &hex::decode(self.pk.clone())
+                .expect("PANIC: Invalid SigningKey Cannot Decode from Hex"))
+                .expect("PANIC: Invalid SigningKey cannot recover from Bytes");
+
+        self.map.update(organization.org_id.clone(), add_ctx, |reg, _ctx| {
+            reg.update(organization, self.node_id.clone(), signing_key)
+                .expect("PANIC: Unable to sign updates")
+        })
+    }
+
+    pub fn organization_op(&mut self, op: OrganizationOp) -> Option<(String, String)> {
+        log::info!("Applying organization op");
+        self.map.apply(op.clone());
+        match op {
+            Op::Up { dot, key, op: _ } => Some((dot.actor, key)),
+            Op::Rm { .. } => None
+        }
+    }
+
+    pub fn organization_op_success(&self, key: String, update: Update<Organization, String>) -> (bool, Organization) {
+        if let Some(reg) = self.map.get(&key).val {
+            if let Some(v) = reg.val() {
+                if v.value() == update.op().value {
+                    return (true, v.value())
+                } else if reg.dag_contains(&update.hash()) && reg.is_head(&update.hash()) {
+                    return (true, v.value())
+                } else if reg.is_orphaned(&update.hash()) {
+                    return (true, v.value())
+                } else {
+                    return (false, v.value())
+                }
+            }
+        }
+        (false, update.op().value)
+    }
+
+    pub fn remove_organization_local(&mut self, org_id: String) -> OrganizationOp {
+        let rm_ctx = self.map.read_ctx().derive_rm_ctx();
+        self.map.rm(org_id, rm_ctx)
+    }
+
+    pub fn get_organization(&self, org_id: &str) -> Option<Organization> {
+        self.map.get(&org_id.to_string()).val
+            .and_then(|reg| reg.val().map(|v| v.value()))
+    }
+
+    pub fn list_organizations(&self) -> Vec<Organization> {
+        self.map.iter().filter_map(|ctx| {
+            let (_, reg) = ctx.val;
+            reg.val().map(|v| v.value())
+        }).collect()
+    }
+
+    /// Organizations `address` is a member of
+    pub fn organizations_for_member(&self, address: &str) -> Vec<Organization> {
+        self.list_organizations()
+            .into_iter()
+            .filter(|org| org.role_of(address).is_some())
+            .collect()
+    }
+
+    pub fn organization_owning_instance(&self, instance_id: &str) -> Option<Organization> {
+        self.list_organizations()
+            .into_iter()
+            .find(|org| org.owned_instances.contains(instance_id))
+    }
+
+    /// The authorization `address` has on an instance through org membership
+    pub fn instance_authorization(&self, address: &str, instance_id: &str) -> Option<AuthorizationLevel> {
+        self.organization_owning_instance(instance_id)
+            .and_then(|org| org.role_of(address).map(|role| role.instance_level()))
+    }
+
+    /// The account that pays for an org owned instance
+    pub fn billing_account_for_instance(&self, instance_id: &str) -> Option<String> {
+        self.organization_owning_instance(instance_id).map(|org| org.billing_account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_signed_invitation_flow() {
+        let owner = SigningKey::random(&mut thread_rng());
+        let owner_address = hex::encode(Address::from_private_key(&owner));
+        let invitee = hex::encode(Address::from_private_key(&SigningKey::random(&mut thread_rng())));
+        let mut org = Organization::new("acme".to_string(), "Acme".to_string(), &format!("0x{owner_address}"));
+        assert_eq!(org.role_of(&owner_address), Some(&OrgRole::Owner));
+
+        let now = Utc::now().timestamp();
+        let mut invitation = OrgInvitation {
+            org_id: org.org_id.clone(),
+            address: invitee.clone(),
+            role: OrgRole::Member,
+            invited_by: String::new(),
+            expires_at: now + 3600,
+            signature: String::new(),
+            recovery_id: 0,
+        };
+        invitation.sign(&owner).unwrap();
+
+        // Changing the role invalidates the issuer's signature
+        let mut tampered = invitation.clone();
+        tampered.role = OrgRole::Owner;
+        assert!(tampered.verify().is_err());
+
+        org.add_invitation(invitation.clone());
+        assert!(org.accept_invitation(&invitee, now + 7200).is_err());
+        assert_eq!(org.accept_invitation(&invitee, now), Ok(OrgRole::Member));
+        assert!(org.invitations.is_empty());
+
+        org.add_owned_instance("instance-1".to_string());
+        let mut state = OrganizationState::new(owner_address.clone(), hex::encode(owner.to_bytes()));
+        state.update_organization_local(org.clone());
+        assert_eq!(state.instance_authorization(&invitee, "instance-1"), Some(AuthorizationLevel::Operator));
+        assert_eq!(state.instance_authorization(&invitee, "instance-2"), None);
+        assert_eq!(state.billing_account_for_instance("instance-1"), Some(owner_address.clone()));
+
+        assert!(org.remove_member(&owner_address).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+// form-state/src/peer_dns.rs
+// Automatic `<name>.<network>.fog` records for formnet peers and instances.
+// A peer's name is registered when it joins and an instance's id when it
+// reports boot_complete. The records go away again when the peer leaves or
+// the instance is deleted.
+
+use std::net::{IpAddr, SocketAddr};
+use form_dns::store::FormDnsRecord;
+use trust_dns_proto::rr::RecordType;
+use crate::datastore::DataStore;
+use crate::helpers::dns::VANITY_DOMAIN_SUFFIX;
+use crate::instances::InstanceStatus;
+use crate::network::NetworkState;
+
+/// Network label used when the root CIDR has no usable name
+pub const DEFAULT_NETWORK_LABEL: &str = "formnet";
+
+/// Peers come and go, so their records are cached briefly
+pub const PEER_DNS_TTL: u32 = 60;
+
+/// Highest `-N` suffix tried when a name is taken by another live peer
+const MAX_COLLISION_SUFFIX: u32 = 100;
+
+/// Turns a peer or instance name into a DNS label: lowercase letters, digits
+/// and hyphens, at most 63 characters. Returns `None` if nothing is left.
+pub fn dns_label(name: &str) -> Option<String> {
+    let mut label = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9') => label.push(c),
+            _ if !label.ends_with('-') => label.push('-'),
+            _ => {}
+        }
+    }
+    let label = label.trim_matches('-');
+    let label = label[..label.len().min(63)].trim_end_matches('-');
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// The zone peer records live in, `<network>.fog`. The network is named
+/// after the root CIDR.
+pub fn network_zone(network: &NetworkState) -> String {
+    let root = network.cidrs.iter().find_map(|ctx| {
+        let (_, reg) = ctx.val;
+        let cidr = reg.val()?.value();
+        cidr.parent().is_none().then(|| cidr.name())
+    });
+    let label = root.as_deref().and_then(dns_label).unwrap_or_else(|| DEFAULT_NETWORK_LABEL.to_string());
+    format!("{label}.{VANITY_DOMAIN_SUFFIX}")
+}
+
+/// Picks the domain for `ip` under `label`. The plain name is used if it's
+/// free, already points at `ip` or only points at addresses that are no
+/// longer in use, otherwise `-2`, `-3`, ... is appended.
+pub fn choose_domain(
+    label: &str,
+    zone: &str,
+    ip: IpAddr,
+    existing: impl Fn(&str) -> Option<Vec<IpAddr>>,
+    is_live: impl Fn(IpAddr) -> bool,
+) -> Option<String> {
+    (1..=MAX_COLLISION_SUFFIX).find_map(|n| {
+        let domain = match n {
+            1 => format!("{label}.{zone}"),
+            n => format!("{label}-{n}.{zone}"),
+        };
+        match existing(&domain) {
+            None => Some(domain),
+            Some(ips) if ips.contains(&ip) || !ips.iter().any(|other| is_live(*other)) => Some(domain),
+            Some(_) => None,
+        }
+    })
+}
+
+/// Records whose formnet or public addresses include `ip`, for reverse lookups
+pub fn records_for_ip(network: &NetworkState, ip: IpAddr) -> Vec<FormDnsRecord> {
+    let mut records: Vec<FormDnsRecord> = network.dns_state.zones.iter().filter_map(|ctx| {
+        let (_, reg) = ctx.val;
+        let record: FormDnsRecord = reg.val()?.value().into();
+        record.formnet_ip.iter().chain(record.public_ip.iter())
+            .any(|addr| addr.ip() == ip)
+            .then_some(record)
+    }).collect();
+    records.sort_by(|a, b| a.domain.cmp(&b.domain));
+    records
+}
+
+fn record_ips(network: &NetworkState, domain: &str) -> Option<Vec<IpAddr>> {
+    let record = network.dns_state.zones.get(&domain.to_string()).val?.val()?.value();
+    Some(record.formnet_ip().iter().map(SocketAddr::ip).collect())
+}
+
+impl DataStore {
+    /// Whether `ip` still belongs to a peer or an instance that isn't deleted
+    fn is_live_address(&self, ip: IpAddr) -> bool {
+        self.network_state.get_peer_by_ip(ip.to_string()).is_some()
+            || self.instance_state.get_instance_by_ip(ip)
+                .is_ok_and(|instance| instance.status != InstanceStatus::Deleted)
+    }
+
+    /// Points `<name>.<network>.fog` at `ip` and returns the domain used.
+    /// Nothing is written if the record already exists.
+    pub async fn register_peer_dns(&mut self, name: &str, ip: IpAddr) -> Result<String, Box<dyn std::error::Error>> {
+        let label = dns_label(name).ok_or_else(|| format!("{name} has no characters usable in a DNS name"))?;
+        let zone = network_zone(&self.network_state);
+        let domain = choose_domain(
+            &label,
+            &zone,
+            ip,
+            |domain| record_ips(&self.network_state, domain),
+            |other| self.is_live_address(other),
+        ).ok_or_else(|| format!("{label}.{zone} and its first {MAX_COLLISION_SUFFIX} alternatives are taken"))?;
+
+        if record_ips(&self.network_state, &domain).is_some_and(|ips| ips == [ip]) {
+            return Ok(domain);
+        }
+
+        let record = FormDnsRecord {
+            domain: domain.clone(),
+            record_type: RecordType::A,
+            formnet_ip: vec![SocketAddr::new(ip, 80)],
+            public_ip: vec![],
+            cname_target: None,
+            ssl_cert: false,
+            ttl: PEER_DNS_TTL,
+            verification_status: None,
+            verification_timestamp: None,
+        };
+        let op = self.network_state.update_dns_local(record);
+        self.handle_dns_op(op).await?;
+        if let Err(e) = self.sync_dns_record_to_resolver(&domain).await {
+            log::error!("Failed to sync DNS record for {domain} to form-dns: {e}");
+        }
+        log::info!("Registered {domain} for {ip}");
+        Ok(domain)
+    }
+
+    /// Removes the automatic records that point at `ip` and nothing else
+    pub async fn deregister_peer_dns(&mut self, ip: IpAddr) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let suffix = format!(".{}", network_zone(&self.network_state));
+        let domains: Vec<String> = records_for_ip(&self.network_state, ip).into_iter()
+            .filter(|record| record.domain.ends_with(&suffix) && record.public_ip.is_empty())
+            .filter(|record| record.formnet_ip.iter().all(|addr| addr.ip() == ip))
+            .map(|record| record.domain)
+            .collect();
+
+        for domain in &domains {
+            let op = self.network_state.remove_dns_local(domain.clone());
+            self.handle_dns_op(op).await?;
+            if let Err(e) = self.sync_dns_record_to_resolver(domain).await {
+                log::error!("Failed to remove DNS record for {domain} from form-dns: {e}");
+            }
+            log::info!("Removed {domain} for {ip}");
+        }
+        Ok(domains)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_dns_label() {
+        assert_eq!(dns_label("My_Node 01"), Some("my-node-01".to_string()));
+        assert_eq!(dns_label("--edge--"), Some("edge".to_string()));
+        assert_eq!(dns_label("a..b"), Some("a-b".to_string()));
+        assert_eq!(dns_label("___"), None);
+        assert_eq!(dns_label(&"x".repeat(80)).map(|l| l.len()), Some(63));
+    }
+
+    #[test]
+    fn test_choose_domain_collisions() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let records: HashMap<&str, Vec<IpAddr>> = HashMap::from([
+            ("alice.formnet.fog", vec![ip("10.0.0.2")]),
+            ("alice-2.formnet.fog", vec![ip("10.0.0.3")]),
+            ("bob.formnet.fog", vec![ip("10.0.0.9")]),
+        ]);
+        let existing = |domain: &str| records.get(domain).cloned();
+        let live = |addr: IpAddr| addr != ip("10.0.0.9");
+
+        // Free, ours, taken twice over and stale
+        assert_eq!(choose_domain("carol", "formnet.fog", ip("10.0.0.4"), existing, live).unwrap(), "carol.formnet.fog");
+        assert_eq!(choose_domain("alice", "formnet.fog", ip("10.0.0.3"), existing, live).unwrap(), "alice-2.formnet.fog");
+        assert_eq!(choose_domain("alice", "formnet.fog", ip("10.0.0.4"), existing, live).unwrap(), "alice-3.formnet.fog");
+        assert_eq!(choose_domain("bob", "formnet.fog", ip("10.0.0.5"), existing, live).unwrap(), "bob.formnet.fog");
+    }
+}
@@ -0,0 +1,439 @@
+// form-state/src/peer_expiry.rs
+// Network-wide expiry of inactive formnet peers. A peer no node has shaken
+// hands with for `stale_after` is marked stale and disabled, which stops it
+// being advertised in WireGuard configs. A peer that stays stale for
+// `remove_after` is removed together with its DNS records. A stale peer that
+// fetches the peer list again, or is seen shaking hands, is re-enabled.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use shared::Peer;
+use tokio::sync::Mutex;
+use crate::autoscaler::is_responsible_for_build;
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+use crate::failure_detector::{node_health, FailureDetectorConfig};
+use crate::network::CrdtPeer;
+use crate::nodes::Node;
+use form_types::state::Response;
+
+/// Key under which the stale marks are persisted in the node's db
+pub const PEER_EXPIRY_DB_KEY: &str = "peer_expiry";
+
+/// Key the responsible node is chosen by, every node acts on all peers
+const RESPONSIBILITY_KEY: &str = "formnet-peer-expiry";
+
+/// Configuration for peer expiry
+#[derive(Clone, Debug)]
+pub struct PeerExpiryConfig {
+    /// How often peer activity is evaluated
+    pub check_interval: Duration,
+    /// Seconds without a handshake after which a peer is marked stale.
+    /// Handshakes reach form-state with the connectivity metrics formnet
+    /// publishes every 5 minutes, so this should be well above that.
+    pub stale_after_seconds: i64,
+    /// Seconds a peer stays stale before it is removed
+    pub remove_after_seconds: i64,
+}
+
+impl Default for PeerExpiryConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(300),
+            stale_after_seconds: 60 * 60,
+            remove_after_seconds: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl PeerExpiryConfig {
+    /// Defaults overridden by `FORMNET_PEER_STALE_AFTER_SECS` and
+    /// `FORMNET_PEER_REMOVE_AFTER_SECS`
+    pub fn from_env() -> Self {
+        let seconds = |name: &str| std::env::var(name).ok().and_then(|value| value.parse::<i64>().ok()).filter(|value| *value > 0);
+        let defaults = Self::default();
+        Self {
+            stale_after_seconds: seconds("FORMNET_PEER_STALE_AFTER_SECS").unwrap_or(defaults.stale_after_seconds),
+            remove_after_seconds: seconds("FORMNET_PEER_REMOVE_AFTER_SECS").unwrap_or(defaults.remove_after_seconds),
+            ..defaults
+        }
+    }
+}
+
+/// Records that expiry disabled a peer, so a peer an operator disabled is
+/// never re-enabled or removed by it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaleMark {
+    pub peer_id: String,
+    pub public_key: String,
+    /// Last handshake any node saw before the peer was marked stale
+    pub last_seen: i64,
+    pub stale_since: i64,
+    /// Set once the peer is re-enabled or removed
+    pub cleared: bool,
+    pub updated_at: i64,
+}
+
+/// Every stale mark, replicated between nodes with the newest update winning
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PeerExpiryStore {
+    marks: BTreeMap<String, StaleMark>,
+}
+
+impl PeerExpiryStore {
+    /// The mark of a peer that is currently stale
+    pub fn get(&self, peer_id: &str) -> Option<&StaleMark> {
+        self.marks.get(peer_id).filter(|mark| !mark.cleared)
+    }
+
+    pub fn by_public_key(&self, public_key: &str) -> Option<&StaleMark> {
+        self.marks.values().find(|mark| !mark.cleared && mark.public_key == public_key)
+    }
+
+    pub fn stale(&self) -> Vec<&StaleMark> {
+        self.marks.values().filter(|mark| !mark.cleared).collect()
+    }
+
+    /// When the peer's mark was last updated
+    pub fn latest_update(&self, peer_id: &str) -> Option<i64> {
+        self.marks.get(peer_id).map(|mark| mark.updated_at)
+    }
+
+    /// Stores a mark unless a newer one is held. Clearing wins over marking
+    /// in the same second. Returns true if it was stored.
+    pub fn upsert(&mut self, mark: StaleMark) -> bool {
+        if let Some(current) = self.marks.get(&mark.peer_id) {
+            if (current.updated_at, current.cleared) >= (mark.updated_at, mark.cleared) {
+                return false;
+            }
+        }
+        self.marks.insert(mark.peer_id.clone(), mark);
+        true
+    }
+
+    pub fn merge(&mut self, other: PeerExpiryStore) {
+        for mark in other.marks.into_values() {
+            self.upsert(mark);
+        }
+    }
+}
+
+/// What expiry knows about a peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerActivity {
+    pub peer_id: String,
+    pub public_key: String,
+    pub is_admin: bool,
+    pub is_disabled: bool,
+    pub is_redeemed: bool,
+    /// Newest handshake any node reported with the peer
+    pub last_seen: Option<i64>,
+    /// When this node first noticed the peer, stands in for a handshake for
+    /// peers nobody has shaken hands with yet
+    pub first_noticed: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "action")]
+pub enum ExpiryAction {
+    /// Disable the peer and mark it stale
+    MarkStale { peer_id: String, public_key: String, last_seen: i64 },
+    /// Remove the peer and its DNS records
+    Remove { peer_id: String },
+    /// Re-enable a stale peer that is active again
+    Revive { peer_id: String },
+}
+
+/// Newest handshake with each peer across the connectivity every node reported
+pub fn last_handshakes<'a>(nodes: impl IntoIterator<Item = &'a Node>) -> HashMap<String, i64> {
+    let mut seen: HashMap<String, i64> = HashMap::new();
+    for node in nodes {
+        for (peer_id, quality) in &node.connectivity.peers {
+            if let Some(handshake) = quality.last_handshake {
+                let last = seen.entry(peer_id.clone()).or_insert(handshake);
+                *last = (*last).max(handshake);
+            }
+        }
+    }
+    seen
+}
+
+/// Decides which peers become stale, are removed or come back. Admin peers
+/// and unredeemed invites are left alone, and a disabled peer without a
+/// stale mark was disabled by an operator.
+pub fn plan_expiry(peers: &[PeerActivity], marks: &PeerExpiryStore, now: i64, config: &PeerExpiryConfig) -> Vec<ExpiryAction> {
+    let mut actions = Vec::new();
+    for peer in peers {
+        if peer.is_admin || !peer.is_redeemed {
+            continue;
+        }
+
+        match marks.get(&peer.peer_id) {
+            Some(mark) => {
+                if peer.last_seen.map_or(false, |seen| seen > mark.last_seen) || !peer.is_disabled {
+                    actions.push(ExpiryAction::Revive { peer_id: peer.peer_id.clone() });
+                } else if now - mark.stale_since >= config.remove_after_seconds {
+                    actions.push(ExpiryAction::Remove { peer_id: peer.peer_id.clone() });
+                }
+            }
+            None if !peer.is_disabled => {
+                let last_seen = peer.last_seen.unwrap_or(peer.first_noticed);
+                if now - last_seen >= config.stale_after_seconds {
+                    actions.push(ExpiryAction::MarkStale {
+                        peer_id: peer.peer_id.clone(),
+                        public_key: peer.public_key.clone(),
+                        last_seen,
+                    });
+                }
+            }
+            None => {}
+        }
+    }
+    actions
+}
+
+/// Stores a mark, persists the store and sends the mark to the other admin nodes
+pub async fn save_mark(datastore: &mut DataStore, mark: StaleMark) {
+    datastore.peer_expiry.upsert(mark.clone());
+    if let Err(e) = store_value(&DB_HANDLE, PEER_EXPIRY_DB_KEY, &datastore.peer_expiry) {
+        log::error!("Unable to persist peer expiry marks: {e}");
+    }
+    if let Err(e) = datastore.broadcast::<Response<StaleMark>>(mark, "v1/peer/expiry/replicate").await {
+        log::error!("Unable to replicate peer expiry mark: {e}");
+    }
+}
+
+fn get_peer(datastore: &DataStore, peer_id: &str) -> Option<CrdtPeer<String>> {
+    datastore.network_state.peers.get(&peer_id.to_string()).val
+        .and_then(|reg| reg.val().map(|val| val.value()))
+}
+
+/// Enables or disables a peer through the peer CRDT
+async fn set_disabled(datastore: &mut DataStore, peer: CrdtPeer<String>, disabled: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut contents = Peer::from(peer).contents;
+    contents.is_disabled = disabled;
+    datastore.handle_peer_update(contents).await
+}
+
+/// Marks the mark of `peer_id` as cleared, so later checks start over
+async fn clear_mark(datastore: &mut DataStore, peer_id: &str, now: i64) {
+    if let Some(mut mark) = datastore.peer_expiry.get(peer_id).cloned() {
+        mark.cleared = true;
+        mark.updated_at = datastore.peer_expiry.latest_update(peer_id).map_or(now, |latest| now.max(latest + 1));
+        save_mark(datastore, mark).await;
+    }
+}
+
+/// Re-enables a stale peer. Returns false if the peer isn't stale.
+pub async fn revive_peer(datastore: &mut DataStore, peer_id: &str, now: i64) -> Result<bool, Box<dyn std::error::Error>> {
+    if datastore.peer_expiry.get(peer_id).is_none() {
+        return Ok(false);
+    }
+    if let Some(peer) = get_peer(datastore, peer_id) {
+        if peer.is_disabled {
+            set_disabled(datastore, peer, false).await?;
+        }
+    }
+    clear_mark(datastore, peer_id, now).await;
+    log::info!("Peer {peer_id} is active again, re-enabled");
+    Ok(true)
+}
+
+/// Carries out one action
+pub async fn apply_action(datastore: &mut DataStore, action: &ExpiryAction, now: i64) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        ExpiryAction::MarkStale { peer_id, public_key, last_seen } => {
+            let Some(peer) = get_peer(datastore, peer_id) else {
+                return Ok(());
+            };
+            set_disabled(datastore, peer, true).await?;
+            let updated_at = datastore.peer_expiry.latest_update(peer_id).map_or(now, |latest| now.max(latest + 1));
+            save_mark(datastore, StaleMark {
+                peer_id: peer_id.clone(),
+                public_key: public_key.clone(),
+                last_seen: *last_seen,
+                stale_since: now,
+                cleared: false,
+                updated_at,
+            }).await;
+            log::warn!("Peer {peer_id} has been inactive since {last_seen}, marked stale");
+        }
+        ExpiryAction::Remove { peer_id } => {
+            if get_peer(datastore, peer_id).is_some() {
+                datastore.handle_peer_delete(peer_id.clone()).await?;
+            }
+            clear_mark(datastore, peer_id, now).await;
+            log::warn!("Peer {peer_id} stayed stale, removed it and its DNS records");
+        }
+        ExpiryAction::Revive { peer_id } => {
+            revive_peer(datastore, peer_id, now).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs peer expiry until a shutdown signal is received
+pub async fn run_peer_expiry(
+    datastore: Arc<Mutex<DataStore>>,
+    config: PeerExpiryConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!(
+        "Starting peer expiry (stale after {}s / removed after {}s stale)",
+        config.stale_after_seconds, config.remove_after_seconds
+    );
+    let mut first_noticed = HashMap::new();
+    let mut check = tokio::time::interval(config.check_interval);
+
+    loop {
+        tokio::select! {
+            _ = check.tick() => {
+                if let Err(e) = check_peers(datastore.clone(), &mut first_noticed, &config).await {
+                    log::error!("Peer expiry check failed: {e}");
+                }
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates every peer and, if this node is the one responsible, acts on
+/// the result
+pub async fn check_peers(
+    datastore: Arc<Mutex<DataStore>>,
+    first_noticed: &mut HashMap<String, i64>,
+    config: &PeerExpiryConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now().timestamp();
+    let mut guard = datastore.lock().await;
+    let nodes = guard.node_state.list_nodes();
+    let detector = FailureDetectorConfig::default();
+    let alive_nodes: Vec<String> = nodes.iter()
+        .filter(|node| !node_health(node, now, &detector).is_dead())
+        .map(|node| node.node_id.clone())
+        .collect();
+
+    let handshakes = last_handshakes(&nodes);
+    let peers: Vec<PeerActivity> = guard.get_all_users().into_values().map(|peer| {
+        let first_noticed = *first_noticed.entry(peer.id.clone()).or_insert(now);
+        PeerActivity {
+            last_seen: handshakes.get(&peer.id).copied(),
+            peer_id: peer.id,
+            public_key: peer.public_key,
+            is_admin: peer.is_admin,
+            is_disabled: peer.is_disabled,
+            is_redeemed: peer.is_redeemed,
+            first_noticed,
+        }
+    }).collect();
+    first_noticed.retain(|peer_id, _| peers.iter().any(|peer| &peer.peer_id == peer_id));
+
+    if !is_responsible_for_build(RESPONSIBILITY_KEY, &guard.node_state.node_id, &alive_nodes) {
+        return Ok(());
+    }
+
+    for action in plan_expiry(&peers, &guard.peer_expiry, now, config) {
+        if let Err(e) = apply_action(&mut guard, &action, now).await {
+            log::error!("Unable to apply peer expiry action {action:?}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PeerExpiryConfig {
+        PeerExpiryConfig { check_interval: Duration::from_secs(1), stale_after_seconds: 100, remove_after_seconds: 1_000 }
+    }
+
+    fn peer(id: &str, last_seen: Option<i64>, is_disabled: bool) -> PeerActivity {
+        PeerActivity {
+            peer_id: id.to_string(),
+            public_key: format!("{id}-key"),
+            is_admin: false,
+            is_disabled,
+            is_redeemed: true,
+            last_seen,
+            first_noticed: 0,
+        }
+    }
+
+    fn mark(id: &str, last_seen: i64, stale_since: i64) -> StaleMark {
+        StaleMark {
+            peer_id: id.to_string(),
+            public_key: format!("{id}-key"),
+            last_seen,
+            stale_since,
+            cleared: false,
+            updated_at: stale_since,
+        }
+    }
+
+    #[test]
+    fn test_plan_expiry() {
+        let now = 5_000;
+        let mut marks = PeerExpiryStore::default();
+        marks.upsert(mark("stale", 3_000, 4_500));
+        marks.upsert(mark("expired", 1_000, 1_500));
+        marks.upsert(mark("returned", 3_000, 3_500));
+        marks.upsert(mark("enabled", 3_000, 3_500));
+
+        let mut admin = peer("admin", Some(0), false);
+        admin.is_admin = true;
+        let mut invite = peer("invite", None, false);
+        invite.is_redeemed = false;
+        let mut new = peer("new", None, false);
+        new.first_noticed = 4_950;
+        let peers = vec![
+            peer("active", Some(4_950), false),
+            peer("idle", Some(4_000), false),
+            peer("silent", None, false),
+            new,
+            peer("manual", Some(0), true),
+            peer("stale", Some(3_000), true),
+            peer("expired", Some(1_000), true),
+            peer("returned", Some(4_900), true),
+            peer("enabled", Some(3_000), false),
+            admin,
+            invite,
+        ];
+
+        let actions = plan_expiry(&peers, &marks, now, &config());
+        assert_eq!(actions, vec![
+            ExpiryAction::MarkStale { peer_id: "idle".to_string(), public_key: "idle-key".to_string(), last_seen: 4_000 },
+            ExpiryAction::MarkStale { peer_id: "silent".to_string(), public_key: "silent-key".to_string(), last_seen: 0 },
+            ExpiryAction::Remove { peer_id: "expired".to_string() },
+            ExpiryAction::Revive { peer_id: "returned".to_string() },
+            ExpiryAction::Revive { peer_id: "enabled".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_store_keeps_newest_mark() {
+        let mut store = PeerExpiryStore::default();
+        assert!(store.upsert(mark("peer", 10, 20)));
+        assert!(!store.upsert(mark("peer", 5, 15)));
+
+        let mut cleared = mark("peer", 10, 20);
+        cleared.cleared = true;
+        assert!(store.upsert(cleared));
+        assert!(store.get("peer").is_none());
+        assert!(store.by_public_key("peer-key").is_none());
+        assert!(!store.upsert(mark("peer", 10, 20)));
+
+        let mut other = PeerExpiryStore::default();
+        other.upsert(mark("peer", 30, 40));
+        store.merge(other);
+        assert_eq!(store.by_public_key("peer-key").map(|mark| mark.stale_since), Some(40));
+        assert_eq!(store.stale().len(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+// form-state/src/pricing.rs
+// Compute prices in credits per hour, used to estimate what a deployment
+// costs before it is built. Higher subscription tiers get a discount on the
+// base rates.
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::billing::SubscriptionTier;
+use crate::quotas::ResourceQuota;
+
+/// Average hours in a month, 365 * 24 / 12
+pub const HOURS_PER_MONTH: f64 = 730.0;
+
+const BASE_PER_VCPU_HOUR: f64 = 10.0;
+const BASE_PER_GB_MEMORY_HOUR: f64 = 2.5;
+const BASE_PER_GB_DISK_HOUR: f64 = 0.05;
+/// Charged for GPU models without a listed rate
+const BASE_PER_OTHER_GPU_HOUR: f64 = 150.0;
+const BASE_GPU_RATES: [(&str, f64); 4] = [
+    ("RTX5090", 60.0),
+    ("H100", 200.0),
+    ("H200", 260.0),
+    ("B200", 350.0),
+];
+
+/// Credits charged per hour of each resource
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ComputeRates {
+    pub per_vcpu_hour: f64,
+    pub per_gb_memory_hour: f64,
+    pub per_gb_disk_hour: f64,
+    /// Per GPU, by model
+    pub per_gpu_hour: BTreeMap<String, f64>,
+    pub per_other_gpu_hour: f64,
+}
+
+/// Resources of one instance to be priced
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PricedResources {
+    pub vcpus: u32,
+    pub memory_mb: u64,
+    pub disk_gb: u64,
+    /// Model and count of each GPU request
+    pub gpus: Vec<(String, u32)>,
+}
+
+/// What each resource contributes to the hourly cost of an instance
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct CostBreakdown {
+    pub vcpus: f64,
+    pub memory: f64,
+    pub disk: f64,
+    pub gpus: f64,
+}
+
+impl CostBreakdown {
+    pub fn total(&self) -> f64 {
+        self.vcpus + self.memory + self.disk + self.gpus
+    }
+}
+
+impl ComputeRates {
+    pub fn base() -> Self {
+        Self {
+            per_vcpu_hour: BASE_PER_VCPU_HOUR,
+            per_gb_memory_hour: BASE_PER_GB_MEMORY_HOUR,
+            per_gb_disk_hour: BASE_PER_GB_DISK_HOUR,
+            per_gpu_hour: BASE_GPU_RATES.iter().map(|(model, rate)| (model.to_string(), *rate)).collect(),
+            per_other_gpu_hour: BASE_PER_OTHER_GPU_HOUR,
+        }
+    }
+
+    /// The rates with a percentage taken off
+    pub fn discounted(&self, percent: u8) -> Self {
+        let factor = 1.0 - f64::from(percent.min(100)) / 100.0;
+        Self {
+            per_vcpu_hour: self.per_vcpu_hour * factor,
+            per_gb_memory_hour: self.per_gb_memory_hour * factor,
+            per_gb_disk_hour: self.per_gb_disk_hour * factor,
+            per_gpu_hour: self.per_gpu_hour.iter().map(|(model, rate)| (model.clone(), rate * factor)).collect(),
+            per_other_gpu_hour: self.per_other_gpu_hour * factor,
+        }
+    }
+
+    pub fn gpu_rate(&self, model: &str) -> f64 {
+        self.per_gpu_hour.iter()
+            .find(|(listed, _)| listed.eq_ignore_ascii_case(model))
+            .map(|(_, rate)| *rate)
+            .unwrap_or(self.per_other_gpu_hour)
+    }
+
+    /// Hourly cost of one instance, by resource
+    pub fn hourly(&self, resources: &PricedResources) -> CostBreakdown {
+        CostBreakdown {
+            vcpus: f64::from(resources.vcpus) * self.per_vcpu_hour,
+            memory: resources.memory_mb as f64 / 1024.0 * self.per_gb_memory_hour,
+            disk: resources.disk_gb as f64 * self.per_gb_disk_hour,
+            gpus: resources.gpus.iter().map(|(model, count)| f64::from(*count) * self.gpu_rate(model)).sum(),
+        }
+    }
+}
+
+/// Rates and resource quota of a subscription tier, what `/pricing` lists
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TierPricing {
+    pub tier: SubscriptionTier,
+    pub discount_percent: u8,
+    pub rates: ComputeRates,
+    pub quota: ResourceQuota,
+}
+
+impl SubscriptionTier {
+    pub fn compute_discount(&self) -> u8 {
+        match self {
+            Self::Free => 0,
+            Self::Pro => 5,
+            Self::ProPlus => 10,
+            Self::Power => 15,
+            Self::PowerPlus => 20,
+        }
+    }
+
+    pub fn pricing(&self) -> TierPricing {
+        TierPricing {
+            tier: *self,
+            discount_percent: self.compute_discount(),
+            rates: ComputeRates::base().discounted(self.compute_discount()),
+            quota: self.resource_quota(),
+        }
+    }
+}
+
+/// Pricing of every tier, cheapest first
+pub fn pricing_table() -> Vec<TierPricing> {
+    [
+        SubscriptionTier::Free,
+        SubscriptionTier::Pro,
+        SubscriptionTier::ProPlus,
+        SubscriptionTier::Power,
+        SubscriptionTier::PowerPlus,
+    ].iter().map(SubscriptionTier::pricing).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hourly_cost() {
+        let resources = PricedResources {
+            vcpus: 2,
+            memory_mb: 4096,
+            disk_gb: 20,
+            gpus: vec![("h100".to_string(), 1), ("MI300".to_string(), 2)],
+        };
+        let cost = ComputeRates::base().hourly(&resources);
+        assert_eq!(cost.vcpus, 20.0);
+        assert_eq!(cost.memory, 10.0);
+        assert_eq!(cost.disk, 1.0);
+        assert_eq!(cost.gpus, 200.0 + 2.0 * 150.0);
+        assert_eq!(cost.total(), 531.0);
+
+        let discounted = SubscriptionTier::PowerPlus.pricing().rates.hourly(&resources);
+        assert!((discounted.total() - 531.0 * 0.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_pricing_table() {
+        let table = pricing_table();
+        assert_eq!(table.len(), 5);
+        assert_eq!(table[0].tier, SubscriptionTier::Free);
+        assert_eq!(table[0].rates, ComputeRates::base());
+        assert!(table.windows(2).all(|pair| pair[0].discount_percent < pair[1].discount_percent));
+    }
+}
@@ -0,0 +1,233 @@
+// form-state/src/quotas.rs
+// Per-account caps on instances, vCPUs and memory. They come from the
+// account's subscription tier unless an admin set one on the account, and
+// are checked whenever an instance is created or grows.
+
+use serde::{Deserialize, Serialize};
+use crate::billing::SubscriptionTier;
+use crate::datastore::DataStore;
+use crate::instances::{Instance, InstanceResources, InstanceStatus};
+use crate::usage_rollups::normalize_account_id;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceQuota {
+    pub max_instances: u32,
+    pub max_vcpus: u32,
+    pub max_memory_mb: u64,
+}
+
+/// Resources held by an account's instances, or requested by new ones
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub instances: u32,
+    pub vcpus: u32,
+    pub memory_mb: u64,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Account {account} would use {requested} {resource}, over its quota of {limit}")]
+pub struct QuotaExceeded {
+    pub account: String,
+    pub resource: &'static str,
+    pub requested: u64,
+    pub limit: u64,
+}
+
+/// What `/quota` returns. `quota` and `remaining` are `None` for accounts
+/// without limits.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuotaReport {
+    pub account: String,
+    pub tier: SubscriptionTier,
+    pub quota: Option<ResourceQuota>,
+    pub usage: ResourceUsage,
+    pub remaining: Option<ResourceUsage>,
+}
+
+impl SubscriptionTier {
+    pub fn resource_quota(&self) -> ResourceQuota {
+        let (max_instances, max_vcpus, max_memory_mb) = match self {
+            Self::Free => (2, 4, 4_096),
+            Self::Pro => (10, 16, 32_768),
+            Self::ProPlus => (25, 48, 98_304),
+            Self::Power => (50, 128, 262_144),
+            Self::PowerPlus => (100, 256, 524_288),
+        };
+        ResourceQuota { max_instances, max_vcpus, max_memory_mb }
+    }
+}
+
+impl ResourceUsage {
+    pub fn of(resources: &InstanceResources) -> Self {
+        Self {
+            instances: 1,
+            vcpus: resources.vcpus as u32,
+            memory_mb: resources.memory_mb as u64,
+        }
+    }
+
+    pub fn times(&self, count: u32) -> Self {
+        Self {
+            instances: self.instances.saturating_mul(count),
+            vcpus: self.vcpus.saturating_mul(count),
+            memory_mb: self.memory_mb.saturating_mul(count as u64),
+        }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Self {
+            instances: self.instances.saturating_add(other.instances),
+            vcpus: self.vcpus.saturating_add(other.vcpus),
+            memory_mb: self.memory_mb.saturating_add(other.memory_mb),
+        }
+    }
+}
+
+impl ResourceQuota {
+    pub fn check(&self, account: &str, usage: &ResourceUsage) -> Result<(), QuotaExceeded> {
+        let limits = [
+            ("instances", usage.instances as u64, self.max_instances as u64),
+            ("vCPUs", usage.vcpus as u64, self.max_vcpus as u64),
+            ("MB of memory", usage.memory_mb, self.max_memory_mb),
+        ];
+        match limits.into_iter().find(|(_, requested, limit)| requested > limit) {
+            Some((resource, requested, limit)) => Err(QuotaExceeded {
+                account: account.to_string(),
+                resource,
+                requested,
+                limit,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn remaining(&self, usage: &ResourceUsage) -> ResourceUsage {
+        ResourceUsage {
+            instances: self.max_instances.saturating_sub(usage.instances),
+            vcpus: self.max_vcpus.saturating_sub(usage.vcpus),
+            memory_mb: self.max_memory_mb.saturating_sub(usage.memory_mb),
+        }
+    }
+}
+
+/// Deleted and failed instances don't hold resources
+pub(crate) fn holds_resources(status: &InstanceStatus) -> bool {
+    !matches!(status, InstanceStatus::Deleted | InstanceStatus::Failed)
+}
+
+impl DataStore {
+    pub fn account_tier(&self, account: &str) -> SubscriptionTier {
+        self.account_state.get_account(account)
+            .and_then(|account| account.subscription)
+            .map(|subscription| subscription.tier)
+            .unwrap_or_default()
+    }
+
+    /// The quota an admin set on the account, else its tier's. Global
+    /// admins have none.
+    pub fn resource_quota(&self, account: &str) -> Option<ResourceQuota> {
+        match self.account_state.get_account(account) {
+            Some(account) if account.is_global_admin => None,
+            Some(account) => Some(account.resource_quota.unwrap_or_else(|| {
+                account.subscription.map(|s| s.tier).unwrap_or_default().resource_quota()
+            })),
+            None => Some(SubscriptionTier::default().resource_quota()),
+        }
+    }
+
+    /// Resources held by the account's instances, leaving out `excluding`
+    pub fn resource_usage(&self, account: &str, excluding: Option<&str>) -> ResourceUsage {
+        let account = normalize_account_id(account);
+        self.instance_state.map.iter()
+            .filter_map(|ctx| {
+                let (_, reg) = ctx.val;
+                reg.val().map(|val| val.value())
+            })
+            .filter(|instance| {
+                holds_resources(&instance.status)
+                    && normalize_account_id(&instance.instance_owner) == account
+                    && Some(instance.instance_id.as_str()) != excluding
+            })
+            .fold(ResourceUsage::default(), |usage, instance| usage.add(&ResourceUsage::of(&instance.resources)))
+    }
+
+    /// Checks that the account can take on `additional` resources
+    pub fn check_quota(
+        &self,
+        account: &str,
+        additional: &ResourceUsage,
+        excluding: Option<&str>,
+    ) -> Result<(), QuotaExceeded> {
+        match self.resource_quota(account) {
+            Some(quota) => quota.check(account, &self.resource_usage(account, excluding).add(additional)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks a created or updated instance against its owner's quota. Only
+    /// new instances and ones that grow are checked, so an account over a
+    /// lowered quota can still stop, restart or shrink what it has.
+    pub fn check_instance_quota(&self, instance: &Instance) -> Result<(), QuotaExceeded> {
+        if instance.instance_owner.is_empty() || !holds_resources(&instance.status) {
+            return Ok(());
+        }
+
+        if let Some(current) = self.instance_state.get_instance(instance.instance_id.clone()) {
+            let unchanged = holds_resources(&current.status)
+                && normalize_account_id(&current.instance_owner) == normalize_account_id(&instance.instance_owner)
+                && instance.resources.vcpus <= current.resources.vcpus
+                && instance.resources.memory_mb <= current.resources.memory_mb;
+            if unchanged {
+                return Ok(());
+            }
+        }
+
+        self.check_quota(
+            &instance.instance_owner,
+            &ResourceUsage::of(&instance.resources),
+            Some(&instance.instance_id),
+        )
+    }
+
+    pub fn quota_report(&self, account: &str) -> QuotaReport {
+        let quota = self.resource_quota(account);
+        let usage = self.resource_usage(account, None);
+        QuotaReport {
+            account: account.to_string(),
+            tier: self.account_tier(account),
+            remaining: quota.as_ref().map(|quota| quota.remaining(&usage)),
+            quota,
+            usage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(id: &str, owner: &str, vcpus: u8, memory_mb: u32) -> Instance {
+        Instance {
+            instance_id: id.to_string(),
+            instance_owner: owner.to_string(),
+            status: InstanceStatus::Ready,
+            resources: InstanceResources { vcpus, memory_mb, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_instance_quota() {
+        let quota = SubscriptionTier::Free.resource_quota();
+        let within = ResourceUsage { instances: 2, vcpus: 4, memory_mb: 4_096 };
+        assert!(quota.check("acct", &within).is_ok());
+        assert_eq!(
+            quota.check("acct", &ResourceUsage { vcpus: 5, ..within.clone() }),
+            Err(QuotaExceeded { account: "acct".to_string(), resource: "vCPUs", requested: 5, limit: 4 })
+        );
+        assert_eq!(quota.remaining(&ResourceUsage::of(&instance("a", "acct", 3, 1_024).resources)),
+            ResourceUsage { instances: 1, vcpus: 1, memory_mb: 3_072 });
+        assert_eq!(ResourceUsage { instances: 1, vcpus: 2, memory_mb: 512 }.times(3),
+            ResourceUsage { instances: 3, vcpus: 6, memory_mb: 1_536 });
+    }
+}
@@ -0,0 +1,464 @@
+// form-state/src/reservations.rs
+// Capacity reservations. An account reserves vCPUs, memory and GPUs in a
+// region for a term and pays for the whole term up front, at a discount on
+// its tier's rates that grows with the term. While a reservation is in
+// effect the part of it the account's own instances in the region don't use
+// is held back from everyone else's placements, so the capacity is there
+// when the account scales into it.
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::datastore::DataStore;
+use crate::failure_detector::{node_health, FailureDetectorConfig};
+use crate::instances::InstanceResources;
+use crate::node_matching::GpuRequirement;
+use crate::nodes::Node;
+use crate::pricing::{ComputeRates, PricedResources};
+use crate::quotas::holds_resources;
+use crate::usage_rollups::normalize_account_id;
+
+/// Key under which the reservations are persisted in the node's db
+pub const RESERVATIONS_DB_KEY: &str = "reservations/all";
+
+/// Longest term that can be reserved, three years
+pub const MAX_RESERVATION_HOURS: u64 = 3 * 8760;
+
+/// Discount on the tier's rates for terms of at least so many hours,
+/// longest first
+const TERM_DISCOUNTS: [(u64, u8); 3] = [(8760, 30), (2160, 20), (720, 10)];
+
+const MB: u64 = 1024 * 1024;
+
+/// vCPUs, memory and GPUs reserved, used or free in a region
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionCapacity {
+    #[serde(default)]
+    pub vcpus: u64,
+    #[serde(default)]
+    pub memory_mb: u64,
+    /// GPU count by lowercased model
+    #[serde(default)]
+    pub gpus: BTreeMap<String, u64>,
+}
+
+impl RegionCapacity {
+    pub fn new(vcpus: u32, memory_mb: u64, gpus: &[GpuRequirement]) -> Self {
+        let mut capacity = Self { vcpus: vcpus.into(), memory_mb, gpus: BTreeMap::new() };
+        for gpu in gpus {
+            *capacity.gpus.entry(gpu.model.to_lowercase()).or_default() += u64::from(gpu.count);
+        }
+        capacity
+    }
+
+    /// What an instance holds
+    pub fn of(resources: &InstanceResources) -> Self {
+        let mut capacity = Self {
+            vcpus: resources.vcpus.into(),
+            memory_mb: resources.memory_mb.into(),
+            gpus: BTreeMap::new(),
+        };
+        if let Some(gpu) = resources.gpu.as_ref().filter(|gpu| gpu.count() > 0) {
+            capacity.gpus.insert(gpu.model.to_lowercase(), gpu.count().into());
+        }
+        capacity
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vcpus == 0 && self.memory_mb == 0 && self.gpus.values().all(|count| *count == 0)
+    }
+
+    pub fn add(&mut self, other: &RegionCapacity) {
+        self.vcpus += other.vcpus;
+        self.memory_mb += other.memory_mb;
+        for (model, count) in &other.gpus {
+            *self.gpus.entry(model.clone()).or_default() += count;
+        }
+    }
+
+    pub fn saturating_sub(&self, other: &RegionCapacity) -> Self {
+        Self {
+            vcpus: self.vcpus.saturating_sub(other.vcpus),
+            memory_mb: self.memory_mb.saturating_sub(other.memory_mb),
+            gpus: self.gpus.iter()
+                .map(|(model, count)| (model.clone(), count.saturating_sub(other.gpus.get(model).copied().unwrap_or(0))))
+                .collect(),
+        }
+    }
+
+    /// What this capacity lacks to hold `wanted`, `None` if it holds all of it
+    pub fn shortfall(&self, wanted: &RegionCapacity) -> Option<String> {
+        if self.vcpus < wanted.vcpus {
+            return Some(format!("{} vCPUs left, {} wanted", self.vcpus, wanted.vcpus));
+        }
+        if self.memory_mb < wanted.memory_mb {
+            return Some(format!("{} MB memory left, {} MB wanted", self.memory_mb, wanted.memory_mb));
+        }
+        for (model, count) in wanted.gpus.iter().filter(|(_, count)| **count > 0) {
+            let left = self.gpus.get(model).copied().unwrap_or(0);
+            if left < *count {
+                return Some(format!("{left} of GPU {model} left, {count} wanted"));
+            }
+        }
+        None
+    }
+
+    fn priced(&self) -> PricedResources {
+        PricedResources {
+            vcpus: self.vcpus.try_into().unwrap_or(u32::MAX),
+            memory_mb: self.memory_mb,
+            disk_gb: 0,
+            gpus: self.gpus.iter().map(|(model, count)| (model.clone(), (*count).try_into().unwrap_or(u32::MAX))).collect(),
+        }
+    }
+}
+
+/// Discount for reserving `hours` at once
+pub fn term_discount(hours: u64) -> u8 {
+    TERM_DISCOUNTS.iter()
+        .find(|(min_hours, _)| hours >= *min_hours)
+        .map_or(0, |(_, percent)| *percent)
+}
+
+/// What a reservation costs, in credits
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReservationQuote {
+    pub hours: u64,
+    /// Discount of the term, on top of the tier's
+    pub term_discount_percent: u8,
+    pub hourly_credits: u64,
+    /// Charged when the reservation is made
+    pub total_credits: u64,
+    /// What the same resources cost for the term on demand
+    pub on_demand_credits: u64,
+}
+
+/// Rounds a cost up to whole credits, ignoring float noise in the last
+/// digits so a discounted 21.0 isn't charged as 22
+fn whole_credits(cost: f64) -> u64 {
+    ((cost * 1e6).round() / 1e6).ceil() as u64
+}
+
+/// Prices `capacity` for `hours` from an account's compute `rates`, with
+/// the term discount taken off. Charges are rounded up to whole credits per
+/// hour.
+pub fn quote(capacity: &RegionCapacity, hours: u64, rates: &ComputeRates) -> ReservationQuote {
+    let resources = capacity.priced();
+    let term_discount_percent = term_discount(hours);
+    let on_demand = whole_credits(rates.hourly(&resources).total());
+    let hourly_credits = whole_credits(rates.discounted(term_discount_percent).hourly(&resources).total());
+    ReservationQuote {
+        hours,
+        term_discount_percent,
+        hourly_credits,
+        total_credits: hourly_credits.saturating_mul(hours),
+        on_demand_credits: on_demand.saturating_mul(hours),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReservationStatus {
+    #[default]
+    Active,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub reservation_id: String,
+    pub account: String,
+    /// Lowercased host region
+    pub region: String,
+    pub capacity: RegionCapacity,
+    pub starts_at: i64,
+    pub ends_at: i64,
+    pub price: ReservationQuote,
+    pub status: ReservationStatus,
+    /// Credits given back when the reservation was cancelled
+    #[serde(default)]
+    pub refunded_credits: u64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl Reservation {
+    /// Whether the reservation holds capacity at some point in `[from, until)`
+    pub fn overlaps(&self, from: i64, until: i64) -> bool {
+        self.status == ReservationStatus::Active && self.starts_at < until && from < self.ends_at
+    }
+
+    pub fn in_effect(&self, now: i64) -> bool {
+        self.overlaps(now, now + 1)
+    }
+}
+
+/// Every reservation, replicated between nodes with the newest update
+/// winning
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReservationStore {
+    reservations: BTreeMap<String, Reservation>,
+}
+
+impl ReservationStore {
+    pub fn get(&self, reservation_id: &str) -> Option<&Reservation> {
+        self.reservations.get(reservation_id)
+    }
+
+    /// Stores a reservation unless a newer one is held. Updates made in the
+    /// same second are ordered by status so a cancellation isn't lost.
+    /// Returns true if the reservation was stored.
+    pub fn upsert(&mut self, reservation: Reservation) -> bool {
+        if let Some(current) = self.reservations.get(&reservation.reservation_id) {
+            if (current.updated_at, current.status) >= (reservation.updated_at, reservation.status) {
+                return false;
+            }
+        }
+        self.reservations.insert(reservation.reservation_id.clone(), reservation);
+        true
+    }
+
+    pub fn merge(&mut self, other: ReservationStore) {
+        for reservation in other.reservations.into_values() {
+            self.upsert(reservation);
+        }
+    }
+
+    /// The account's reservations, latest start first
+    pub fn for_account(&self, account: &str) -> Vec<&Reservation> {
+        let account = normalize_account_id(account);
+        let mut found: Vec<&Reservation> = self.reservations.values()
+            .filter(|reservation| reservation.account == account)
+            .collect();
+        found.sort_by(|a, b| b.starts_at.cmp(&a.starts_at).then_with(|| a.reservation_id.cmp(&b.reservation_id)));
+        found
+    }
+
+    /// Reserved capacity the holders' instances don't use, by region, of
+    /// the reservations overlapping `[from, until)`. `usage` is what each
+    /// account's instances hold by account and region, `excluding` leaves
+    /// out an account's own reservations.
+    pub fn unused(
+        &self,
+        usage: &BTreeMap<(String, String), RegionCapacity>,
+        excluding: Option<&str>,
+        from: i64,
+        until: i64,
+    ) -> BTreeMap<String, RegionCapacity> {
+        let excluding = excluding.map(normalize_account_id);
+        let mut reserved: BTreeMap<(String, String), RegionCapacity> = BTreeMap::new();
+        for reservation in self.reservations.values() {
+            if reservation.overlaps(from, until) && excluding.as_ref() != Some(&reservation.account) {
+                reserved.entry((reservation.account.clone(), reservation.region.clone()))
+                    .or_default()
+                    .add(&reservation.capacity);
+            }
+        }
+
+        let mut unused: BTreeMap<String, RegionCapacity> = BTreeMap::new();
+        for (key, capacity) in reserved {
+            let left = match usage.get(&key) {
+                Some(used) => capacity.saturating_sub(used),
+                None => capacity,
+            };
+            if !left.is_empty() {
+                unused.entry(key.1).or_default().add(&left);
+            }
+        }
+        unused
+    }
+
+    pub fn len(&self) -> usize {
+        self.reservations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reservations.is_empty()
+    }
+}
+
+/// Free vCPUs, memory and GPUs of the nodes that take placements, by
+/// lowercased region. GPUs are the nodes' totals, the caller takes off
+/// what instances use.
+pub fn regional_free(nodes: &[Node], now: i64) -> BTreeMap<String, RegionCapacity> {
+    let mut free: BTreeMap<String, RegionCapacity> = BTreeMap::new();
+    let config = FailureDetectorConfig::default();
+    for node in nodes {
+        if !node.is_schedulable() || node_health(node, now, &config).is_dead() {
+            continue;
+        }
+        let region = free.entry(node.host_region.to_lowercase()).or_default();
+        region.vcpus += (node.capacity.cpu_available_cores / 1000).max(0) as u64;
+        region.memory_mb += node.capacity.memory_available_bytes / MB;
+        for gpu in &node.capabilities.gpu_models {
+            if let Some(model) = &gpu.model {
+                *region.gpus.entry(model.to_lowercase()).or_default() += u64::from(gpu.count);
+            }
+        }
+    }
+    free
+}
+
+impl DataStore {
+    /// What each account's instances hold, by account and lowercased region
+    pub fn regional_usage(&self) -> BTreeMap<(String, String), RegionCapacity> {
+        let mut usage: BTreeMap<(String, String), RegionCapacity> = BTreeMap::new();
+        let instances = self.instance_state.map.iter().filter_map(|ctx| {
+            let (_, reg) = ctx.val;
+            reg.val().map(|val| val.value())
+        });
+        for instance in instances.filter(|instance| holds_resources(&instance.status)) {
+            usage.entry((normalize_account_id(&instance.instance_owner), instance.host_region.to_lowercase()))
+                .or_default()
+                .add(&RegionCapacity::of(&instance.resources));
+        }
+        usage
+    }
+
+    fn free_capacity(&self, usage: &BTreeMap<(String, String), RegionCapacity>, now: i64) -> BTreeMap<String, RegionCapacity> {
+        let nodes: Vec<Node> = self.node_state.map.iter().filter_map(|ctx| {
+            let (_, reg) = ctx.val;
+            reg.val().map(|val| val.value())
+        }).collect();
+        let mut free = regional_free(&nodes, now);
+        for ((_, region), used) in usage {
+            if let Some(region) = free.get_mut(region) {
+                let gpus = RegionCapacity { gpus: used.gpus.clone(), ..Default::default() };
+                *region = region.saturating_sub(&gpus);
+            }
+        }
+        free
+    }
+
+    /// Capacity left to `account`'s placements in the regions where other
+    /// accounts hold reservations now. Regions that aren't listed are not
+    /// constrained. `None` for on-demand requests of no account.
+    pub fn unreserved_capacity(&self, account: Option<&str>, now: i64) -> BTreeMap<String, RegionCapacity> {
+        let usage = self.regional_usage();
+        let held = self.reservations.unused(&usage, account, now, now + 1);
+        if held.is_empty() {
+            return held;
+        }
+        let free = self.free_capacity(&usage, now);
+        held.into_iter()
+            .map(|(region, held)| {
+                let left = free.get(&region).map(|free| free.saturating_sub(&held)).unwrap_or_default();
+                (region, left)
+            })
+            .collect()
+    }
+
+    /// Checks that `region` has `capacity` free besides what reservations
+    /// overlapping `[from, until)` hold back
+    pub fn check_reservation_capacity(
+        &self,
+        region: &str,
+        capacity: &RegionCapacity,
+        from: i64,
+        until: i64,
+        now: i64,
+    ) -> Result<(), String> {
+        let region = region.to_lowercase();
+        let usage = self.regional_usage();
+        let free = self.free_capacity(&usage, now).remove(&region).unwrap_or_default();
+        let held = self.reservations.unused(&usage, None, from, until).remove(&region).unwrap_or_default();
+        match free.saturating_sub(&held).shortfall(capacity) {
+            Some(shortfall) => Err(format!("Region {region} can't hold the reservation: {shortfall}")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reservation(id: &str, account: &str, vcpus: u64, starts_at: i64, ends_at: i64, updated_at: i64) -> Reservation {
+        Reservation {
+            reservation_id: id.to_string(),
+            account: account.to_string(),
+            region: "us-east".to_string(),
+            capacity: RegionCapacity {
+                vcpus,
+                memory_mb: vcpus * 2048,
+                gpus: BTreeMap::from([("h100".to_string(), 1)]),
+            },
+            starts_at,
+            ends_at,
+            price: ReservationQuote::default(),
+            status: ReservationStatus::Active,
+            refunded_credits: 0,
+            created_at: 0,
+            updated_at,
+        }
+    }
+
+    #[test]
+    fn test_newest_reservation_wins_and_cancellation_wins_ties() {
+        let mut store = ReservationStore::default();
+        assert!(store.upsert(reservation("r1", "abc", 4, 100, 200, 10)));
+        assert!(!store.upsert(reservation("r1", "abc", 8, 100, 200, 9)));
+        let cancelled = Reservation { status: ReservationStatus::Cancelled, ..reservation("r1", "abc", 4, 100, 200, 10) };
+        assert!(store.upsert(cancelled));
+        assert!(!store.upsert(reservation("r1", "abc", 4, 100, 200, 10)));
+        assert!(!store.get("r1").unwrap().in_effect(150));
+
+        let mut other = ReservationStore::default();
+        other.upsert(reservation("r2", "abc", 2, 300, 400, 1));
+        store.merge(other);
+        assert_eq!(store.for_account("0xABC").iter().map(|r| r.reservation_id.as_str()).collect::<Vec<_>>(), vec!["r2", "r1"]);
+    }
+
+    #[test]
+    fn test_unused_capacity_leaves_out_what_the_holder_uses() {
+        let mut store = ReservationStore::default();
+        store.upsert(reservation("r1", "abc", 8, 100, 200, 1));
+        store.upsert(reservation("r2", "abc", 4, 100, 200, 1));
+        store.upsert(reservation("r3", "def", 2, 300, 400, 1));
+
+        let usage = BTreeMap::from([(
+            ("abc".to_string(), "us-east".to_string()),
+            RegionCapacity { vcpus: 10, memory_mb: 4096, gpus: BTreeMap::new() },
+        )]);
+        let unused = store.unused(&usage, None, 150, 151);
+        let held = &unused["us-east"];
+        assert_eq!(held.vcpus, 2);
+        assert_eq!(held.memory_mb, 12 * 2048 - 4096);
+        assert_eq!(held.gpus["h100"], 2);
+
+        // The holder's own reservations aren't held back from it
+        assert!(store.unused(&usage, Some("0xabc"), 150, 151).is_empty());
+        // Nor are reservations that haven't started or have ended
+        assert!(store.unused(&BTreeMap::new(), None, 200, 300).is_empty());
+        assert_eq!(store.unused(&BTreeMap::new(), None, 250, 350)["us-east"].vcpus, 2);
+    }
+
+    #[test]
+    fn test_capacity_shortfall() {
+        let free = RegionCapacity { vcpus: 16, memory_mb: 32768, gpus: BTreeMap::from([("h100".to_string(), 2)]) };
+        let wanted = RegionCapacity::new(8, 16384, &[GpuRequirement { model: "H100".to_string(), count: 2 }]);
+        assert_eq!(free.shortfall(&wanted), None);
+        assert!(free.shortfall(&RegionCapacity { vcpus: 17, ..wanted.clone() }).unwrap().contains("vCPUs"));
+        let more_gpus = RegionCapacity::new(8, 16384, &[GpuRequirement { model: "h100".to_string(), count: 3 }]);
+        assert!(free.shortfall(&more_gpus).unwrap().contains("GPU h100"));
+        assert_eq!(free.saturating_sub(&wanted).saturating_sub(&wanted).vcpus, 0);
+    }
+
+    #[test]
+    fn test_longer_terms_are_cheaper() {
+        let capacity = RegionCapacity::new(2, 4096, &[]);
+        let rates = ComputeRates::base();
+        let day = quote(&capacity, 24, &rates);
+        assert_eq!(day.term_discount_percent, 0);
+        assert_eq!(day.hourly_credits, 30);
+        assert_eq!(day.total_credits, 720);
+        assert_eq!(day.on_demand_credits, 720);
+
+        let year = quote(&capacity, 8760, &rates);
+        assert_eq!(year.term_discount_percent, 30);
+        assert_eq!(year.hourly_credits, 21);
+        assert_eq!(year.total_credits, 21 * 8760);
+        assert!(year.total_credits < year.on_demand_credits);
+        assert_eq!(term_discount(720), 10);
+        assert_eq!(term_discount(2159), 10);
+    }
+}
@@ -0,0 +1,121 @@
+// form-state/src/revisions.rs
+// Optimistic concurrency for instance and account writes. Every local write
+// bumps the record's revision, and API writers can make an update
+// conditional on the revision they read instead of clobbering whatever was
+// written in between.
+
+use axum::http::{header::IF_MATCH, HeaderMap};
+use crate::accounts::AccountState;
+use crate::instances::InstanceState;
+
+/// How many times `Instance::update_with_retry` re-reads and re-applies a
+/// change that lost a race before giving up
+pub const MAX_CONFLICT_RETRIES: usize = 5;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("{kind} {id} is at revision {current}, the update was made against revision {expected}")]
+pub struct StaleRevision {
+    pub kind: &'static str,
+    pub id: String,
+    pub expected: u64,
+    pub current: u64,
+}
+
+/// The revision a write was made against: the `If-Match` header if one was
+/// sent, otherwise the revision in the payload. `*` and revision 0, which
+/// records written before revisions existed carry, make the write
+/// unconditional.
+pub fn expected_revision(headers: &HeaderMap, payload_revision: u64) -> Option<u64> {
+    let if_match = headers.get(IF_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().trim_matches('"'));
+    match if_match {
+        Some("*") => None,
+        Some(value) => value.parse().ok().filter(|revision| *revision > 0),
+        None => Some(payload_revision).filter(|revision| *revision > 0),
+    }
+}
+
+fn check(kind: &'static str, id: &str, expected: Option<u64>, current: u64) -> Result<(), StaleRevision> {
+    match expected {
+        Some(expected) if expected != current => Err(StaleRevision {
+            kind,
+            id: id.to_string(),
+            expected,
+            current,
+        }),
+        _ => Ok(()),
+    }
+}
+
+impl InstanceState {
+    /// Rejects a write made against another revision than the stored one.
+    /// Instances that don't exist yet have nothing to conflict with.
+    pub fn check_instance_revision(&self, instance_id: &str, expected: Option<u64>) -> Result<(), StaleRevision> {
+        match self.get_instance(instance_id.to_string()) {
+            Some(current) => check("Instance", instance_id, expected, current.revision),
+            None => Ok(()),
+        }
+    }
+
+    /// The revision the next write of the instance gets
+    pub fn next_instance_revision(&self, instance_id: &str) -> u64 {
+        self.get_instance(instance_id.to_string()).map_or(0, |current| current.revision) + 1
+    }
+}
+
+impl AccountState {
+    /// Rejects a write made against another revision than the stored one
+    pub fn check_account_revision(&self, address: &str, expected: Option<u64>) -> Result<(), StaleRevision> {
+        match self.get_account(address) {
+            Some(current) => check("Account", address, expected, current.revision),
+            None => Ok(()),
+        }
+    }
+
+    /// The revision the next write of the account gets
+    pub fn next_account_revision(&self, address: &str) -> u64 {
+        self.get_account(address).map_or(0, |current| current.revision) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use crate::instances::Instance;
+
+    const PK: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    #[test]
+    fn test_expected_revision() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(expected_revision(&headers, 0), None);
+        assert_eq!(expected_revision(&headers, 4), Some(4));
+
+        headers.insert(IF_MATCH, HeaderValue::from_static("\"7\""));
+        assert_eq!(expected_revision(&headers, 4), Some(7));
+        headers.insert(IF_MATCH, HeaderValue::from_static("*"));
+        assert_eq!(expected_revision(&headers, 4), None);
+    }
+
+    #[test]
+    fn test_writes_bump_and_check_revisions() {
+        let mut state = InstanceState::new("node".to_string(), PK.to_string());
+        let instance = Instance { instance_id: "vm".to_string(), ..Default::default() };
+        assert!(state.check_instance_revision("vm", Some(3)).is_ok());
+
+        state.update_instance_local(instance.clone());
+        state.update_instance_local(instance.clone());
+        let stored = state.get_instance("vm".to_string()).unwrap();
+        assert_eq!(stored.revision, 2);
+        assert_eq!(state.next_instance_revision("vm"), 3);
+
+        assert!(state.check_instance_revision("vm", Some(2)).is_ok());
+        assert!(state.check_instance_revision("vm", None).is_ok());
+        assert_eq!(
+            state.check_instance_revision("vm", Some(1)),
+            Err(StaleRevision { kind: "Instance", id: "vm".to_string(), expected: 1, current: 2 })
+        );
+    }
+}
@@ -0,0 +1,387 @@
+// form-state/src/secrets.rs
+// Per-build secrets: values are encrypted at rest with a key derived from the
+// node's private key and are only ever returned in plaintext to the local
+// vmm-service when it injects them into an instance at boot.
+
+use std::collections::BTreeMap;
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Key under which the encrypted records are persisted in the node's db
+pub const SECRETS_DB_KEY: &str = "secret_state/records";
+/// Domain separator for deriving the secrets key from the node key
+const KEY_DERIVATION_CONTEXT: &[u8] = b"formation-secrets-v1";
+/// Largest accepted secret value
+pub const MAX_SECRET_SIZE: usize = 64 * 1024;
+/// Longest accepted secret name
+pub const MAX_SECRET_NAME_LEN: usize = 128;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum SecretError {
+    #[error("Secret store has no encryption key")]
+    NoKey,
+    #[error("Invalid secret name: {0}")]
+    InvalidName(String),
+    #[error("Secret value exceeds the 64 KiB limit")]
+    TooLarge,
+    #[error("Secret {0} already exists")]
+    AlreadyExists(String),
+    #[error("Secret {0} not found")]
+    NotFound(String),
+    #[error("Unable to encrypt secret {0}")]
+    Encryption(String),
+    #[error("Unable to decrypt secret {0}")]
+    Decryption(String),
+}
+
+/// A secret as stored on disk, the value is AES-256-GCM encrypted
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedSecret {
+    pub build_id: String,
+    pub name: String,
+    /// Address of the account that last wrote the secret
+    pub owner: String,
+    /// Incremented on every rotation
+    pub version: u64,
+    /// Hex encoded 96-bit nonce
+    pub nonce: String,
+    /// Hex encoded ciphertext and tag
+    pub ciphertext: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Everything about a secret except its value
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecretMetadata {
+    pub build_id: String,
+    pub name: String,
+    pub owner: String,
+    pub version: u64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl From<&EncryptedSecret> for SecretMetadata {
+    fn from(value: &EncryptedSecret) -> Self {
+        SecretMetadata {
+            build_id: value.build_id.clone(),
+            name: value.name.clone(),
+            owner: value.owner.clone(),
+            version: value.version,
+            created_at: value.created_at,
+            updated_at: value.updated_at,
+        }
+    }
+}
+
+/// Replicates a secret change to the other admin nodes. Each node encrypts
+/// the value with its own key, so the plaintext is only sent over formnet.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum SecretRequest {
+    Put {
+        build_id: String,
+        name: String,
+        owner: String,
+        version: u64,
+        value: String,
+        created_at: i64,
+        updated_at: i64,
+    },
+    Delete {
+        build_id: String,
+        name: String,
+        version: u64,
+    },
+}
+
+impl std::fmt::Debug for SecretRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretRequest::Put { build_id, name, version, .. } => f
+                .debug_struct("Put")
+                .field("build_id", build_id)
+                .field("name", name)
+                .field("version", version)
+                .finish_non_exhaustive(),
+            SecretRequest::Delete { build_id, name, version } => f
+                .debug_struct("Delete")
+                .field("build_id", build_id)
+                .field("name", name)
+                .field("version", version)
+                .finish(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SecretStore {
+    key: Option<[u8; 32]>,
+    records: BTreeMap<String, EncryptedSecret>,
+}
+
+impl std::fmt::Debug for SecretStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretStore")
+            .field("key", &self.key.map(|_| "<redacted>"))
+            .field("records", &self.records.len())
+            .finish()
+    }
+}
+
+impl SecretStore {
+    /// Build a store whose key is derived from the node's hex encoded private key
+    pub fn new(pk: &str) -> Self {
+        let pk_bytes = hex::decode(pk.strip_prefix("0x").unwrap_or(pk))
+            .unwrap_or_else(|_| pk.as_bytes().to_vec());
+        let mut hasher = Sha256::new();
+        hasher.update(KEY_DERIVATION_CONTEXT);
+        hasher.update(&pk_bytes);
+        Self {
+            key: Some(hasher.finalize().into()),
+            records: BTreeMap::new(),
+        }
+    }
+
+    fn record_key(build_id: &str, name: &str) -> String {
+        format!("{build_id}/{name}")
+    }
+
+    /// Secret names become environment variable names inside the instance
+    pub fn validate_name(name: &str) -> Result<(), SecretError> {
+        let mut chars = name.chars();
+        let valid_start = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+        if !valid_start
+            || name.len() > MAX_SECRET_NAME_LEN
+            || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(SecretError::InvalidName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn cipher(&self) -> Result<Aes256Gcm, SecretError> {
+        let key = self.key.as_ref().ok_or(SecretError::NoKey)?;
+        Aes256Gcm::new_from_slice(key).map_err(|_| SecretError::NoKey)
+    }
+
+    fn seal(&self, metadata: SecretMetadata, value: &str) -> Result<EncryptedSecret, SecretError> {
+        if value.len() > MAX_SECRET_SIZE {
+            return Err(SecretError::TooLarge);
+        }
+        let record_key = Self::record_key(&metadata.build_id, &metadata.name);
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        // Bind the ciphertext to its build and name so records can't be swapped
+        let ciphertext = self.cipher()?
+            .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: value.as_bytes(), aad: record_key.as_bytes() })
+            .map_err(|_| SecretError::Encryption(record_key))?;
+
+        Ok(EncryptedSecret {
+            build_id: metadata.build_id,
+            name: metadata.name,
+            owner: metadata.owner,
+            version: metadata.version,
+            nonce: hex::encode(nonce_bytes),
+            ciphertext: hex::encode(ciphertext),
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+        })
+    }
+
+    fn open(&self, record: &EncryptedSecret) -> Result<String, SecretError> {
+        let record_key = Self::record_key(&record.build_id, &record.name);
+        let err = || SecretError::Decryption(record_key.clone());
+        let nonce = hex::decode(&record.nonce).map_err(|_| err())?;
+        if nonce.len() != 12 {
+            return Err(err());
+        }
+        let ciphertext = hex::decode(&record.ciphertext).map_err(|_| err())?;
+        let plaintext = self.cipher()?
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: record_key.as_bytes() })
+            .map_err(|_| err())?;
+        String::from_utf8(plaintext).map_err(|_| err())
+    }
+
+    pub fn get(&self, build_id: &str, name: &str) -> Option<SecretMetadata> {
+        self.records.get(&Self::record_key(build_id, name)).map(SecretMetadata::from)
+    }
+
+    /// Create a new secret, fails if the build already has one with this name
+    pub fn create(&mut self, build_id: &str, name: &str, value: &str, owner: &str, now: i64) -> Result<SecretMetadata, SecretError> {
+        Self::validate_name(name)?;
+        if self.get(build_id, name).is_some() {
+            return Err(SecretError::AlreadyExists(name.to_string()));
+        }
+        let metadata = SecretMetadata {
+            build_id: build_id.to_string(),
+            name: name.to_string(),
+            owner: owner.to_string(),
+            version: 1,
+            created_at: now,
+            updated_at: now,
+        };
+        self.insert(metadata, value)
+    }
+
+    /// Replace the value of an existing secret and bump its version
+    pub fn rotate(&mut self, build_id: &str, name: &str, value: &str, owner: &str, now: i64) -> Result<SecretMetadata, SecretError> {
+        let existing = self.get(build_id, name).ok_or_else(|| SecretError::NotFound(name.to_string()))?;
+        let metadata = SecretMetadata {
+            owner: owner.to_string(),
+            version: existing.version + 1,
+            updated_at: now,
+            ..existing
+        };
+        self.insert(metadata, value)
+    }
+
+    pub fn remove(&mut self, build_id: &str, name: &str) -> Option<SecretMetadata> {
+        self.records.remove(&Self::record_key(build_id, name)).as_ref().map(SecretMetadata::from)
+    }
+
+    fn insert(&mut self, metadata: SecretMetadata, value: &str) -> Result<SecretMetadata, SecretError> {
+        let record = self.seal(metadata.clone(), value)?;
+        self.records.insert(Self::record_key(&record.build_id, &record.name), record);
+        Ok(metadata)
+    }
+
+    /// Metadata of every secret of a build, values are never listed
+    pub fn list_for_build(&self, build_id: &str) -> Vec<SecretMetadata> {
+        self.records.values()
+            .filter(|record| record.build_id == build_id)
+            .map(SecretMetadata::from)
+            .collect()
+    }
+
+    /// Decrypt every secret of a build for injection into its instances
+    pub fn resolve_for_build(&self, build_id: &str) -> Result<BTreeMap<String, String>, SecretError> {
+        self.records.values()
+            .filter(|record| record.build_id == build_id)
+            .map(|record| Ok((record.name.clone(), self.open(record)?)))
+            .collect()
+    }
+
+    /// The replication request announcing the current value of a secret
+    pub fn put_request(metadata: &SecretMetadata, value: &str) -> SecretRequest {
+        SecretRequest::Put {
+            build_id: metadata.build_id.clone(),
+            name: metadata.name.clone(),
+            owner: metadata.owner.clone(),
+            version: metadata.version,
+            value: value.to_string(),
+            created_at: metadata.created_at,
+            updated_at: metadata.updated_at,
+        }
+    }
+
+    /// Apply a change replicated from another node. Changes older than the
+    /// local version are ignored so out of order delivery can't roll back a
+    /// rotation. Returns whether the store changed.
+    pub fn apply(&mut self, request: SecretRequest) -> Result<bool, SecretError> {
+        match request {
+            SecretRequest::Put { build_id, name, owner, version, value, created_at, updated_at } => {
+                Self::validate_name(&name)?;
+                if self.get(&build_id, &name).is_some_and(|existing| existing.version >= version) {
+                    return Ok(false);
+                }
+                self.insert(SecretMetadata { build_id, name, owner, version, created_at, updated_at }, &value)?;
+                Ok(true)
+            }
+            SecretRequest::Delete { build_id, name, version } => {
+                if self.get(&build_id, &name).is_some_and(|existing| existing.version > version) {
+                    return Ok(false);
+                }
+                Ok(self.remove(&build_id, &name).is_some())
+            }
+        }
+    }
+
+    pub fn records(&self) -> &BTreeMap<String, EncryptedSecret> {
+        &self.records
+    }
+
+    /// Restore records persisted by this node
+    pub fn restore(&mut self, records: BTreeMap<String, EncryptedSecret>) {
+        self.records = records;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PK: &str = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+    #[test]
+    fn test_secret_lifecycle() {
+        let mut store = SecretStore::new(PK);
+        let created = store.create("build", "DATABASE_URL", "postgres://a", "0xabc", 1).unwrap();
+        assert_eq!(created.version, 1);
+        assert_eq!(
+            store.create("build", "DATABASE_URL", "postgres://b", "0xabc", 2),
+            Err(SecretError::AlreadyExists("DATABASE_URL".to_string()))
+        );
+
+        // Values are never stored in plaintext
+        let record = store.records().get("build/DATABASE_URL").unwrap();
+        assert!(!record.ciphertext.contains(&hex::encode("postgres://a")));
+
+        let rotated = store.rotate("build", "DATABASE_URL", "postgres://b", "0xabc", 3).unwrap();
+        assert_eq!(rotated.version, 2);
+        assert_eq!(rotated.created_at, 1);
+        assert_eq!(store.resolve_for_build("build").unwrap().get("DATABASE_URL").unwrap(), "postgres://b");
+        assert_eq!(store.list_for_build("build").len(), 1);
+        assert!(store.list_for_build("other").is_empty());
+
+        assert!(store.remove("build", "DATABASE_URL").is_some());
+        assert!(store.resolve_for_build("build").unwrap().is_empty());
+        assert!(matches!(store.rotate("build", "DATABASE_URL", "x", "0xabc", 4), Err(SecretError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_records_are_bound_to_node_key_and_name() {
+        let mut store = SecretStore::new(PK);
+        store.create("build", "TOKEN", "secret", "0xabc", 1).unwrap();
+
+        let mut other = SecretStore::new("01".repeat(32).as_str());
+        other.restore(store.records().clone());
+        assert!(matches!(other.resolve_for_build("build"), Err(SecretError::Decryption(_))));
+
+        // Moving a record to another name must fail authentication
+        let mut records = store.records().clone();
+        let mut moved = records.remove("build/TOKEN").unwrap();
+        moved.name = "OTHER".to_string();
+        records.insert("build/OTHER".to_string(), moved);
+        store.restore(records);
+        assert!(matches!(store.resolve_for_build("build"), Err(SecretError::Decryption(_))));
+    }
+
+    #[test]
+    fn test_replicated_changes_respect_versions() {
+        let mut store = SecretStore::new(PK);
+        let metadata = store.create("build", "TOKEN", "v1", "0xabc", 1).unwrap();
+        let stale = SecretStore::put_request(&metadata, "stale");
+
+        store.rotate("build", "TOKEN", "v2", "0xabc", 2).unwrap();
+        assert!(!store.apply(stale).unwrap());
+        assert!(!store.apply(SecretRequest::Delete { build_id: "build".into(), name: "TOKEN".into(), version: 1 }).unwrap());
+        assert_eq!(store.resolve_for_build("build").unwrap()["TOKEN"], "v2");
+
+        assert!(store.apply(SecretRequest::Delete { build_id: "build".into(), name: "TOKEN".into(), version: 2 }).unwrap());
+        assert!(store.get("build", "TOKEN").is_none());
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(SecretStore::validate_name("API_KEY").is_ok());
+        assert!(SecretStore::validate_name("_private2").is_ok());
+        assert!(SecretStore::validate_name("").is_err());
+        assert!(SecretStore::validate_name("2FAST").is_err());
+        assert!(SecretStore::validate_name("WITH-DASH").is_err());
+        assert!(SecretStore::validate_name("../etc").is_err());
+    }
+}
@@ -0,0 +1,507 @@
+// form-state/src/staking.rs
+// Operator staking and slashing: follows the AVS staking contract through an RPC
+// watcher, tracks per-operator stake/slash status and decides node admission.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use alloy_primitives::keccak256;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use crate::datastore::DataStore;
+
+/// `OperatorStaked(address indexed operator, uint256 amount)`
+pub const OPERATOR_STAKED_EVENT: &str = "OperatorStaked(address,uint256)";
+/// `UnstakeRequested(address indexed operator, uint256 amount)`
+pub const UNSTAKE_REQUESTED_EVENT: &str = "UnstakeRequested(address,uint256)";
+/// `StakeWithdrawn(address indexed operator, uint256 amount)`
+pub const STAKE_WITHDRAWN_EVENT: &str = "StakeWithdrawn(address,uint256)";
+/// `OperatorSlashed(address indexed operator, uint256 amount)`
+pub const OPERATOR_SLASHED_EVENT: &str = "OperatorSlashed(address,uint256)";
+/// `OperatorReinstated(address indexed operator)`
+pub const OPERATOR_REINSTATED_EVENT: &str = "OperatorReinstated(address)";
+
+/// Lifecycle of an operator's stake
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StakeStatus {
+    /// No stake bonded
+    #[default]
+    Unstaked,
+    /// Bonded stake meets the minimum, the operator may run nodes
+    Active,
+    /// Some stake is bonded, but less than the minimum
+    BelowMinimum,
+    /// All stake is unbonding and waiting to be withdrawn
+    Unbonding,
+    /// The operator was slashed and stays excluded until reinstated by the contract
+    Slashed,
+}
+
+/// An event emitted by the staking contract
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StakingEventKind {
+    Staked { amount: u128 },
+    UnstakeRequested { amount: u128 },
+    Withdrawn { amount: u128 },
+    Slashed { amount: u128 },
+    Reinstated,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StakingEvent {
+    /// Operator address, lowercase hex without `0x`
+    pub operator: String,
+    pub kind: StakingEventKind,
+    pub block_number: u64,
+    pub log_index: u64,
+    pub tx_hash: Option<String>,
+}
+
+/// A slash applied to an operator
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlashRecord {
+    pub amount: u128,
+    pub block_number: u64,
+    pub tx_hash: Option<String>,
+}
+
+/// Stake and slash status of a single operator
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperatorStake {
+    pub operator: String,
+    /// Currently bonded stake (wei)
+    pub bonded: u128,
+    /// Stake that is unbonding and not yet withdrawn (wei)
+    pub unbonding: u128,
+    /// Total amount ever slashed (wei)
+    pub slashed_total: u128,
+    pub slashes: Vec<SlashRecord>,
+    pub status: StakeStatus,
+    pub jailed: bool,
+    pub last_event_block: u64,
+}
+
+impl OperatorStake {
+    pub fn new(operator: String) -> Self {
+        Self { operator, ..Default::default() }
+    }
+
+    fn refresh_status(&mut self, min_stake: u128) {
+        self.status = if self.jailed {
+            StakeStatus::Slashed
+        } else if self.bonded == 0 {
+            if self.unbonding > 0 { StakeStatus::Unbonding } else { StakeStatus::Unstaked }
+        } else if self.bonded < min_stake {
+            StakeStatus::BelowMinimum
+        } else {
+            StakeStatus::Active
+        };
+    }
+}
+
+/// Result of an admission check for an operator
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StakeAdmission {
+    pub operator: String,
+    pub admitted: bool,
+    pub status: StakeStatus,
+    pub reason: Option<String>,
+}
+
+/// Staking state derived from the contract's event log. Every node follows the
+/// chain itself, so this state is rebuilt by the watcher rather than gossiped.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StakingState {
+    /// Whether admission is gated on stake. Stays false unless a watcher is configured.
+    pub enforced: bool,
+    /// Minimum bonded stake (wei) for an operator to be admitted
+    pub min_stake: u128,
+    pub operators: BTreeMap<String, OperatorStake>,
+    /// Last block whose events have all been applied
+    pub last_processed_block: u64,
+    /// (block, log index) of the last applied event, used to skip replays
+    last_event: Option<(u64, u64)>,
+}
+
+impl StakingState {
+    pub fn new(enforced: bool, min_stake: u128) -> Self {
+        Self { enforced, min_stake, ..Default::default() }
+    }
+
+    pub fn get(&self, operator: &str) -> Option<&OperatorStake> {
+        self.operators.get(&normalize_operator(operator))
+    }
+
+    pub fn list(&self) -> Vec<OperatorStake> {
+        self.operators.values().cloned().collect()
+    }
+
+    /// Applies a contract event. Events must be applied in chain order, events
+    /// at or before the last applied one are ignored so a re-scanned block
+    /// range is harmless. Returns whether the event was applied.
+    pub fn apply(&mut self, event: &StakingEvent) -> bool {
+        let position = (event.block_number, event.log_index);
+        if self.last_event.is_some_and(|last| position <= last) {
+            return false;
+        }
+        self.last_event = Some(position);
+
+        let operator = normalize_operator(&event.operator);
+        let stake = self.operators
+            .entry(operator.clone())
+            .or_insert_with(|| OperatorStake::new(operator));
+
+        match &event.kind {
+            StakingEventKind::Staked { amount } => {
+                stake.bonded = stake.bonded.saturating_add(*amount);
+            }
+            StakingEventKind::UnstakeRequested { amount } => {
+                let amount = (*amount).min(stake.bonded);
+                stake.bonded -= amount;
+                stake.unbonding = stake.unbonding.saturating_add(amount);
+            }
+            StakingEventKind::Withdrawn { amount } => {
+                stake.unbonding = stake.unbonding.saturating_sub(*amount);
+            }
+            StakingEventKind::Slashed { amount } => {
+                // Bonded stake is slashed first, then stake that is still unbonding
+                let from_bonded = (*amount).min(stake.bonded);
+                stake.bonded -= from_bonded;
+                stake.unbonding = stake.unbonding.saturating_sub(amount - from_bonded);
+                stake.slashed_total = stake.slashed_total.saturating_add(*amount);
+                stake.slashes.push(SlashRecord {
+                    amount: *amount,
+                    block_number: event.block_number,
+                    tx_hash: event.tx_hash.clone(),
+                });
+                stake.jailed = true;
+                log::warn!("Operator {} slashed {} wei at block {}", stake.operator, amount, event.block_number);
+            }
+            StakingEventKind::Reinstated => {
+                stake.jailed = false;
+            }
+        }
+
+        stake.last_event_block = event.block_number;
+        stake.refresh_status(self.min_stake);
+        true
+    }
+
+    /// Decides whether an operator may join the network
+    pub fn admission(&self, operator: &str) -> StakeAdmission {
+        let operator = normalize_operator(operator);
+        let status = self.operators.get(&operator).map(|s| s.status).unwrap_or_default();
+
+        let reason = if !self.enforced {
+            None
+        } else {
+            match status {
+                StakeStatus::Active => None,
+                StakeStatus::Unstaked => Some("operator has no stake".to_string()),
+                StakeStatus::BelowMinimum => Some(format!("operator stake is below the minimum of {} wei", self.min_stake)),
+                StakeStatus::Unbonding => Some("operator stake is unbonding".to_string()),
+                StakeStatus::Slashed => Some("operator has been slashed".to_string()),
+            }
+        };
+
+        StakeAdmission {
+            operator,
+            admitted: reason.is_none(),
+            status,
+            reason,
+        }
+    }
+
+    pub fn is_admitted(&self, operator: &str) -> bool {
+        self.admission(operator).admitted
+    }
+}
+
+/// Normalizes an operator address to lowercase hex without `0x`, the format
+/// node owners and peer ids use throughout form-state
+pub fn normalize_operator(operator: &str) -> String {
+    operator.trim().trim_start_matches("0x").trim_start_matches("0X").to_lowercase()
+}
+
+/// Configuration for the staking contract watcher
+#[derive(Clone, Debug)]
+pub struct StakingWatcherConfig {
+    /// Ethereum JSON-RPC endpoint
+    pub rpc_url: String,
+    /// Address of the AVS staking contract
+    pub contract_address: String,
+    /// Minimum bonded stake (wei) for admission
+    pub min_stake: u128,
+    /// Block to start scanning from
+    pub start_block: u64,
+    /// Blocks to wait before events are considered final
+    pub confirmations: u64,
+    /// Maximum block range per `eth_getLogs` request
+    pub max_block_range: u64,
+    pub poll_interval: Duration,
+}
+
+impl StakingWatcherConfig {
+    /// Builds the watcher configuration from `STAKING_*` environment variables,
+    /// falling back to the operator config's contract address. Returns `None`
+    /// when no RPC endpoint or contract is configured, in which case staking
+    /// is not enforced.
+    pub fn from_env(contract_address: Option<String>) -> Option<Self> {
+        let rpc_url = std::env::var("STAKING_RPC_URL").ok().filter(|s| !s.is_empty())?;
+        let contract_address = std::env::var("STAKING_CONTRACT_ADDRESS").ok()
+            .filter(|s| !s.is_empty())
+            .or(contract_address)?;
+
+        let parse = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        Some(Self {
+            rpc_url,
+            contract_address,
+            min_stake: std::env::var("STAKING_MIN_STAKE").ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            start_block: parse("STAKING_START_BLOCK").unwrap_or(0),
+            confirmations: parse("STAKING_CONFIRMATIONS").unwrap_or(6),
+            max_block_range: parse("STAKING_MAX_BLOCK_RANGE").unwrap_or(2_000).max(1),
+            poll_interval: Duration::from_secs(parse("STAKING_POLL_INTERVAL_SECS").unwrap_or(15)),
+        })
+    }
+}
+
+/// Follows the staking contract and applies its events to the datastore's staking state
+pub async fn run_staking_watcher(
+    datastore: Arc<Mutex<DataStore>>,
+    config: StakingWatcherConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting staking watcher for contract {} (min stake: {} wei)", config.contract_address, config.min_stake);
+    {
+        let mut guard = datastore.lock().await;
+        guard.staking_state.enforced = true;
+        guard.staking_state.min_stake = config.min_stake;
+    }
+
+    let client = reqwest::Client::new();
+    let topics = event_topics();
+    let mut next_block = config.start_block;
+    let mut poll = tokio::time::interval(config.poll_interval);
+
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {
+                match poll_once(&client, &config, &topics, next_block, datastore.clone()).await {
+                    Ok(Some(last)) => next_block = last + 1,
+                    Ok(None) => {}
+                    Err(e) => log::error!("Staking watcher failed to poll {}: {e}", config.rpc_url),
+                }
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Scans one block range starting at `from_block`, returning the last block scanned
+async fn poll_once(
+    client: &reqwest::Client,
+    config: &StakingWatcherConfig,
+    topics: &[String],
+    from_block: u64,
+    datastore: Arc<Mutex<DataStore>>,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let head = parse_quantity(&rpc_call(client, &config.rpc_url, "eth_blockNumber", json!([])).await?)
+        .ok_or("invalid eth_blockNumber response")?;
+    let finalized = head.saturating_sub(config.confirmations);
+    if finalized < from_block {
+        return Ok(None);
+    }
+    let to_block = finalized.min(from_block + config.max_block_range - 1);
+
+    let logs = rpc_call(client, &config.rpc_url, "eth_getLogs", json!([{
+        "address": config.contract_address,
+        "fromBlock": format!("0x{from_block:x}"),
+        "toBlock": format!("0x{to_block:x}"),
+        "topics": [topics],
+    }])).await?;
+
+    let mut events: Vec<StakingEvent> = logs.as_array()
+        .map(|logs| logs.iter().filter_map(decode_log).collect())
+        .unwrap_or_default();
+    events.sort_by_key(|e| (e.block_number, e.log_index));
+
+    let mut guard = datastore.lock().await;
+    let applied = events.iter().filter(|e| guard.staking_state.apply(e)).count();
+    guard.staking_state.last_processed_block = to_block;
+    drop(guard);
+
+    if applied > 0 {
+        log::info!("Applied {applied} staking events from blocks {from_block}..={to_block}");
+    }
+    Ok(Some(to_block))
+}
+
+pub(crate) async fn rpc_call(
+    client: &reqwest::Client,
+    url: &str,
+    method: &str,
+    params: Value,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let response: Value = client.post(url)
+        .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if let Some(error) = response.get("error") {
+        return Err(format!("{method} failed: {error}").into());
+    }
+    response.get("result").cloned().ok_or_else(|| format!("{method} returned no result").into())
+}
+
+fn topic_for(signature: &str) -> String {
+    format!("0x{}", hex::encode(keccak256(signature.as_bytes())))
+}
+
+/// topic0 of every staking event the watcher follows
+pub fn event_topics() -> Vec<String> {
+    [
+        OPERATOR_STAKED_EVENT,
+        UNSTAKE_REQUESTED_EVENT,
+        STAKE_WITHDRAWN_EVENT,
+        OPERATOR_SLASHED_EVENT,
+        OPERATOR_REINSTATED_EVENT,
+    ].iter().map(|s| topic_for(s)).collect()
+}
+
+fn parse_quantity(value: &Value) -> Option<u64> {
+    u64::from_str_radix(value.as_str()?.trim_start_matches("0x"), 16).ok()
+}
+
+/// Decodes a uint256 word, saturating at `u128::MAX`
+pub(crate) fn decode_amount(data: &str) -> Option<u128> {
+    let bytes = hex::decode(data.trim_start_matches("0x")).ok()?;
+    let word = bytes.get(..32)?;
+    if word[..16].iter().any(|b| *b != 0) {
+        return Some(u128::MAX);
+    }
+    Some(u128::from_be_bytes(word[16..].try_into().ok()?))
+}
+
+/// Decodes an `eth_getLogs` entry into a staking event
+pub fn decode_log(log: &Value) -> Option<StakingEvent> {
+    if log.get("removed").and_then(Value::as_bool).unwrap_or(false) {
+        return None;
+    }
+
+    let topics: Vec<&str> = log.get("topics")?.as_array()?.iter().filter_map(Value::as_str).collect();
+    let topic0 = topics.first()?.to_lowercase();
+    // The operator is the first indexed argument: the low 20 bytes of topic1
+    let operator_topic = topics.get(1)?.trim_start_matches("0x");
+    let operator = normalize_operator(operator_topic.get(operator_topic.len().checked_sub(40)?..)?);
+    let data = log.get("data").and_then(Value::as_str).unwrap_or("0x");
+
+    let kind = if topic0 == topic_for(OPERATOR_STAKED_EVENT) {
+        StakingEventKind::Staked { amount: decode_amount(data)? }
+    } else if topic0 == topic_for(UNSTAKE_REQUESTED_EVENT) {
+        StakingEventKind::UnstakeRequested { amount: decode_amount(data)? }
+    } else if topic0 == topic_for(STAKE_WITHDRAWN_EVENT) {
+        StakingEventKind::Withdrawn { amount: decode_amount(data)? }
+    } else if topic0 == topic_for(OPERATOR_SLASHED_EVENT) {
+        StakingEventKind::Slashed { amount: decode_amount(data)? }
+    } else if topic0 == topic_for(OPERATOR_REINSTATED_EVENT) {
+        StakingEventKind::Reinstated
+    } else {
+        return None;
+    };
+
+    Some(StakingEvent {
+        operator,
+        kind,
+        block_number: parse_quantity(log.get("blockNumber")?)?,
+        log_index: parse_quantity(log.get("logIndex")?)?,
+        tx_hash: log.get("transactionHash").and_then(Value::as_str).map(str::to_string),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATOR: &str = "0x00000000000000000000000000000000000000aa";
+
+    fn event(kind: StakingEventKind, block_number: u64, log_index: u64) -> StakingEvent {
+        StakingEvent { operator: OPERATOR.to_string(), kind, block_number, log_index, tx_hash: None }
+    }
+
+    #[test]
+    fn test_stake_lifecycle() {
+        let mut state = StakingState::new(true, 100);
+        assert!(!state.is_admitted(OPERATOR));
+
+        state.apply(&event(StakingEventKind::Staked { amount: 60 }, 1, 0));
+        assert_eq!(state.admission(OPERATOR).status, StakeStatus::BelowMinimum);
+
+        state.apply(&event(StakingEventKind::Staked { amount: 60 }, 2, 0));
+        assert!(state.is_admitted(OPERATOR));
+
+        state.apply(&event(StakingEventKind::UnstakeRequested { amount: 120 }, 3, 0));
+        assert_eq!(state.admission(OPERATOR).status, StakeStatus::Unbonding);
+
+        state.apply(&event(StakingEventKind::Withdrawn { amount: 120 }, 4, 0));
+        assert_eq!(state.admission(OPERATOR).status, StakeStatus::Unstaked);
+    }
+
+    #[test]
+    fn test_slash_jails_until_reinstated() {
+        let mut state = StakingState::new(true, 100);
+        state.apply(&event(StakingEventKind::Staked { amount: 500 }, 1, 0));
+        state.apply(&event(StakingEventKind::Slashed { amount: 50 }, 2, 0));
+
+        let stake = state.get(OPERATOR).unwrap();
+        assert_eq!(stake.bonded, 450);
+        assert_eq!(stake.slashed_total, 50);
+        assert!(!state.is_admitted(OPERATOR));
+
+        state.apply(&event(StakingEventKind::Reinstated, 3, 0));
+        assert!(state.is_admitted(OPERATOR));
+    }
+
+    #[test]
+    fn test_replayed_events_are_ignored() {
+        let mut state = StakingState::new(true, 0);
+        let staked = event(StakingEventKind::Staked { amount: 10 }, 5, 1);
+        assert!(state.apply(&staked));
+        assert!(!state.apply(&staked));
+        assert!(!state.apply(&event(StakingEventKind::Staked { amount: 10 }, 5, 0)));
+        assert_eq!(state.get(OPERATOR).unwrap().bonded, 10);
+    }
+
+    #[test]
+    fn test_not_enforced_admits_everyone() {
+        let state = StakingState::default();
+        assert!(state.is_admitted("deadbeef"));
+    }
+
+    #[test]
+    fn test_decode_log() {
+        let log = json!({
+            "topics": [
+                topic_for(OPERATOR_SLASHED_EVENT),
+                "0x000000000000000000000000000000000000000000000000000000000000AAaa",
+            ],
+            "data": format!("0x{:064x}", 1_000u64),
+            "blockNumber": "0x10",
+            "logIndex": "0x2",
+            "transactionHash": "0xabc",
+        });
+
+        let event = decode_log(&log).unwrap();
+        assert_eq!(event.operator, "000000000000000000000000000000000000aaaa");
+        assert_eq!(event.kind, StakingEventKind::Slashed { amount: 1_000 });
+        assert_eq!((event.block_number, event.log_index), (16, 2));
+    }
+}
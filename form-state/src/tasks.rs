@@ -252,10 +252,10 @@ pub fn determine_responsible_nodes(
     _datastore: &crate::datastore::DataStore, // Marked as unused for now if all_nodes is sufficient
 ) -> BTreeSet<String> {
     
-    // 1. Filter nodes by required capabilities
+    // 1. Filter nodes by required capabilities, skipping nodes in maintenance
     let capable_nodes: Vec<&Node> = all_nodes.iter().filter(|node| {
         // Check against node.metadata.annotations.roles()
-        task.required_capabilities.iter().all(|cap| node.metadata.annotations().roles().contains(cap))
+        node.is_schedulable() && task.required_capabilities.iter().all(|cap| node.metadata.annotations().roles().contains(cap))
     }).collect();
 
     if capable_nodes.is_empty() {
@@ -279,4 +279,102 @@ pub fn determine_responsible_nodes(
     responsible_node_ids
 }
 
-// Further definitions will go here based on subsequent sub-tasks. 
\ No newline at end of file
+/// Configuration for the instance lifecycle scheduler
+#[derive(Clone, Debug)]
+pub struct InstanceSchedulerConfig {
+    /// How often instance schedules are evaluated
+    pub evaluation_interval: std::time::Duration,
+}
+
+impl Default for InstanceSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            evaluation_interval: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+/// Runs the instance lifecycle scheduler (auto-stop, auto-delete, start/stop windows)
+/// until a shutdown signal is received
+pub async fn run_instance_scheduler(
+    datastore: std::sync::Arc<tokio::sync::Mutex<crate::datastore::DataStore>>,
+    config: InstanceSchedulerConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Starting instance scheduler (evaluation interval: {:?})", config.evaluation_interval);
+    let mut evaluation = tokio::time::interval(config.evaluation_interval);
+
+    loop {
+        tokio::select! {
+            _ = evaluation.tick() => {
+                let mut guard = datastore.lock().await;
+                if let Err(e) = evaluate_instance_schedules(&mut guard, chrono::Utc::now().timestamp()).await {
+                    log::error!("Instance scheduler evaluation failed: {e}");
+                }
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates every scheduled instance this node is responsible for and issues the due
+/// start/stop/delete commands to the vmm queue
+pub async fn evaluate_instance_schedules(
+    datastore: &mut crate::datastore::DataStore,
+    now: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::autoscaler::{is_responsible_for_build, VMM_DELETE_SUBTOPIC, VMM_START_SUBTOPIC, VMM_STOP_SUBTOPIC, VMM_TOPIC};
+    use crate::datastore::DataStore;
+    use crate::instances::ScheduledAction;
+    use form_types::{DeleteVmRequest, StartVmRequest, StopVmRequest};
+
+    let local_node_id = datastore.node_state.node_id.clone();
+    let node_ids: Vec<String> = datastore.node_state.list_nodes().into_iter().map(|n| n.node_id).collect();
+
+    let mut due = Vec::new();
+    for ctx in datastore.instance_state.map.iter() {
+        let (_, reg) = ctx.val;
+        if let Some(val) = reg.val() {
+            let instance = val.value();
+            if let Some(action) = instance.due_scheduled_action(now) {
+                if is_responsible_for_build(&instance.instance_id, &local_node_id, &node_ids) {
+                    due.push((instance, action));
+                }
+            }
+        }
+    }
+
+    for (mut instance, action) in due {
+        log::info!("Instance scheduler issuing {action:?} for instance {}", instance.instance_id);
+        let id = instance.instance_id.clone();
+        let name = instance.build_id.clone();
+        let issued = match action {
+            ScheduledAction::Start => {
+                DataStore::write_to_queue(StartVmRequest { id, name }, VMM_START_SUBTOPIC, VMM_TOPIC.to_string()).await
+            }
+            ScheduledAction::Stop => {
+                DataStore::write_to_queue(StopVmRequest { id, name }, VMM_STOP_SUBTOPIC, VMM_TOPIC.to_string()).await
+            }
+            ScheduledAction::Delete => {
+                DataStore::write_to_queue(DeleteVmRequest { id, name }, VMM_DELETE_SUBTOPIC, VMM_TOPIC.to_string()).await
+            }
+        }.map_err(|e| e.to_string());
+
+        if let Err(e) = issued {
+            log::error!("Instance scheduler failed to issue {action:?} for instance {}: {e}", instance.instance_id);
+            continue;
+        }
+
+        if let Some(schedule) = instance.schedule.as_mut() {
+            schedule.last_action_at = now;
+        }
+        datastore.handle_instance_update(instance).await?;
+    }
+
+    Ok(())
+}
+ 
\ No newline at end of file
@@ -0,0 +1,509 @@
+// form-state/src/usage_rollups.rs
+// Per-day usage totals for every instance and account, folded from the usage
+// event stream so billing can query aggregates instead of replaying raw events.
+// Only events signed by the node hosting the instance are counted, and gaps in
+// each node's sequence numbers are recorded next to the totals.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Datelike, NaiveDate};
+use form_usage_events::{SignedUsageEvent, UsageEvent};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::autoscaler::USAGE_EVENTS_TOPIC;
+use crate::datastore::{DataStore, DB_HANDLE};
+use crate::db::store_value;
+
+/// Key under which the rollup tables and queue cursor are persisted
+pub const USAGE_ROLLUPS_DB_KEY: &str = "usage_rollups/daily";
+
+const SECONDS_PER_DAY: i64 = 86_400;
+const SECONDS_PER_HOUR: f64 = 3_600.0;
+/// Disk is billed per 30 day month
+const SECONDS_PER_MONTH: f64 = 30.0 * 86_400.0;
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+
+/// Configuration for the rollup task
+#[derive(Clone, Debug)]
+pub struct UsageRollupConfig {
+    /// How often the usage event queue is polled
+    pub poll_interval: Duration,
+    /// Days of daily rollups kept before they are pruned
+    pub retention_days: i64,
+}
+
+impl Default for UsageRollupConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(1),
+            retention_days: 400,
+        }
+    }
+}
+
+/// Billable usage accumulated over a period
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub cpu_seconds: u64,
+    pub gpu_seconds: u64,
+    pub ram_gb_hours: f64,
+    pub disk_gb_months: f64,
+    pub egress_bytes: u64,
+    /// Number of usage events folded into these totals
+    pub events: u64,
+    /// Signed events that never arrived, going by the sequence numbers of
+    /// the ones that did
+    #[serde(default)]
+    pub missed_events: u64,
+    /// Seconds GPUs were attached, by GPU model
+    #[serde(default)]
+    pub gpu_attached_seconds: BTreeMap<String, u64>,
+}
+
+impl UsageTotals {
+    pub fn from_event(event: &UsageEvent) -> Self {
+        let seconds = (event.period.end - event.period.start).max(0) as f64;
+        Self {
+            cpu_seconds: event.metrics.cpu_seconds,
+            gpu_seconds: event.metrics.gpu_seconds,
+            ram_gb_hours: event.metrics.memory_gb * seconds / SECONDS_PER_HOUR,
+            disk_gb_months: event.metrics.storage_gb * seconds / SECONDS_PER_MONTH,
+            egress_bytes: (event.metrics.network_egress_mb * BYTES_PER_MB).round() as u64,
+            events: 1,
+            missed_events: 0,
+            gpu_attached_seconds: event.metrics.gpus.iter().fold(BTreeMap::new(), |mut seconds, gpu| {
+                *seconds.entry(gpu.model.clone()).or_insert(0) += gpu.attached_seconds;
+                seconds
+            }),
+        }
+    }
+
+    pub fn add(&mut self, other: &UsageTotals) {
+        self.cpu_seconds += other.cpu_seconds;
+        self.gpu_seconds += other.gpu_seconds;
+        self.ram_gb_hours += other.ram_gb_hours;
+        self.disk_gb_months += other.disk_gb_months;
+        self.egress_bytes += other.egress_bytes;
+        self.events += other.events;
+        self.missed_events += other.missed_events;
+        for (model, seconds) in &other.gpu_attached_seconds {
+            *self.gpu_attached_seconds.entry(model.clone()).or_insert(0) += seconds;
+        }
+    }
+}
+
+/// Identifies one row of the daily rollup table
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RollupKey {
+    /// Unix timestamp of the start of the UTC day
+    pub day: i64,
+    pub account_id: String,
+    pub instance_id: String,
+}
+
+/// Length of the buckets rollups are reported in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RollupPeriod {
+    #[default]
+    Day,
+    Month,
+    /// A single bucket covering the whole queried range
+    All,
+}
+
+/// What rollups are aggregated by within each period
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RollupGrouping {
+    #[default]
+    Account,
+    Instance,
+}
+
+/// Query parameters accepted by `/usage/rollups`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RollupQuery {
+    #[serde(default)]
+    pub period: RollupPeriod,
+    #[serde(default)]
+    pub group_by: RollupGrouping,
+    /// Inclusive unix timestamp, rows for days before it are skipped
+    pub from: Option<i64>,
+    /// Exclusive unix timestamp, rows for days at or after it are skipped
+    pub to: Option<i64>,
+    pub account_id: Option<String>,
+    pub instance_id: Option<String>,
+}
+
+/// One aggregated row returned by `/usage/rollups`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RollupRow {
+    /// Start of the bucket, 0 for `period=all`
+    pub period_start: i64,
+    pub account_id: String,
+    /// Only set when grouping by instance
+    pub instance_id: Option<String>,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+/// Last signed event seen from a node for an instance
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageStream {
+    pub epoch: i64,
+    pub sequence: u64,
+}
+
+/// Why a signed usage event isn't billed
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum AttestationRejected {
+    #[error("{0}")]
+    InvalidSignature(String),
+    #[error("Instance {instance_id} is not hosted by node {node_id}")]
+    WrongNode { instance_id: String, node_id: String },
+    #[error("Event {sequence} of epoch {epoch} from node {node_id} was already counted")]
+    Replayed { node_id: String, epoch: i64, sequence: u64 },
+}
+
+/// Daily usage totals per account and instance, with the position in the usage
+/// event topic they were built up to
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageRollups {
+    daily: BTreeMap<RollupKey, UsageTotals>,
+    cursor: usize,
+    /// Sequence position of each node's events, by `node_id/instance_id`
+    #[serde(default)]
+    streams: BTreeMap<String, UsageStream>,
+}
+
+/// Account ids arrive with and without the `0x` prefix and in mixed case
+pub fn normalize_account_id(account_id: &str) -> String {
+    account_id.trim_start_matches("0x").to_lowercase()
+}
+
+fn start_of_day(timestamp: i64) -> i64 {
+    timestamp - timestamp.rem_euclid(SECONDS_PER_DAY)
+}
+
+fn start_of_month(timestamp: i64) -> i64 {
+    DateTime::from_timestamp(timestamp, 0)
+        .and_then(|dt| NaiveDate::from_ymd_opt(dt.year(), dt.month(), 1))
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|dt| dt.and_utc().timestamp())
+        .unwrap_or_else(|| start_of_day(timestamp))
+}
+
+impl UsageRollups {
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn len(&self) -> usize {
+        self.daily.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.daily.is_empty()
+    }
+
+    /// Adds an event to the day its period started in
+    pub fn record(&mut self, event: &UsageEvent) {
+        self.record_with_missed(event, 0);
+    }
+
+    fn record_with_missed(&mut self, event: &UsageEvent, missed_events: u64) {
+        let key = RollupKey {
+            day: start_of_day(event.period.start),
+            account_id: normalize_account_id(&event.user_id),
+            instance_id: event.instance_id.clone(),
+        };
+        let mut totals = UsageTotals::from_event(event);
+        totals.missed_events = missed_events;
+        self.daily.entry(key).or_default().add(&totals);
+    }
+
+    /// Verifies a signed event came from `host_node`, the node running its
+    /// instance, and records it. Returns how many events from that node were
+    /// skipped before this one.
+    pub fn record_signed(
+        &mut self,
+        signed: &SignedUsageEvent,
+        host_node: Option<&str>,
+    ) -> Result<u64, AttestationRejected> {
+        signed.verify().map_err(|e| AttestationRejected::InvalidSignature(e.to_string()))?;
+        let node_id = normalize_account_id(&signed.node_id);
+        if host_node.map(normalize_account_id).as_deref() != Some(node_id.as_str()) {
+            return Err(AttestationRejected::WrongNode {
+                instance_id: signed.event.instance_id.clone(),
+                node_id,
+            });
+        }
+
+        let stream_key = format!("{node_id}/{}", signed.event.instance_id);
+        let missed = match self.streams.get(&stream_key) {
+            Some(last) if signed.epoch < last.epoch
+                || (signed.epoch == last.epoch && signed.sequence <= last.sequence) => {
+                return Err(AttestationRejected::Replayed {
+                    node_id,
+                    epoch: signed.epoch,
+                    sequence: signed.sequence,
+                });
+            }
+            Some(last) if signed.epoch == last.epoch => signed.sequence - last.sequence - 1,
+            // A new epoch means the publisher restarted and numbers from 1 again
+            _ => signed.sequence.saturating_sub(1),
+        };
+
+        self.streams.insert(stream_key, UsageStream { epoch: signed.epoch, sequence: signed.sequence });
+        self.record_with_missed(&signed.event, missed);
+        Ok(missed)
+    }
+
+    /// Records the number of queue messages consumed so far
+    pub fn advance(&mut self, consumed: usize) {
+        self.cursor += consumed;
+    }
+
+    /// Drops days older than `retention_days`
+    pub fn prune(&mut self, now: i64, retention_days: i64) {
+        let cutoff = start_of_day(now) - retention_days * SECONDS_PER_DAY;
+        self.daily.retain(|key, _| key.day >= cutoff);
+    }
+
+    /// Moves the rows of an account to `replacement`, keeping the totals for
+    /// billing retention without the address. Returns the rows moved.
+    pub fn anonymize_account(&mut self, account_id: &str, replacement: &str) -> usize {
+        let account_id = normalize_account_id(account_id);
+        let keys: Vec<RollupKey> = self.daily.keys().filter(|key| key.account_id == account_id).cloned().collect();
+        for key in &keys {
+            if let Some(totals) = self.daily.remove(key) {
+                let anonymized = RollupKey { account_id: replacement.to_string(), ..key.clone() };
+                self.daily.entry(anonymized).or_default().add(&totals);
+            }
+        }
+        keys.len()
+    }
+
+    /// Aggregates the daily table into the buckets and groups asked for
+    pub fn query(&self, query: &RollupQuery) -> Vec<RollupRow> {
+        let account_id = query.account_id.as_deref().map(normalize_account_id);
+        let mut rows: BTreeMap<(i64, String, Option<String>), UsageTotals> = BTreeMap::new();
+
+        for (key, totals) in &self.daily {
+            if query.from.is_some_and(|from| key.day < start_of_day(from)) {
+                continue;
+            }
+            if query.to.is_some_and(|to| key.day >= to) {
+                continue;
+            }
+            if account_id.as_ref().is_some_and(|account_id| &key.account_id != account_id) {
+                continue;
+            }
+            if query.instance_id.as_ref().is_some_and(|instance_id| &key.instance_id != instance_id) {
+                continue;
+            }
+
+            let period_start = match query.period {
+                RollupPeriod::Day => key.day,
+                RollupPeriod::Month => start_of_month(key.day),
+                RollupPeriod::All => 0,
+            };
+            let instance_id = match query.group_by {
+                RollupGrouping::Account => None,
+                RollupGrouping::Instance => Some(key.instance_id.clone()),
+            };
+            rows.entry((period_start, key.account_id.clone(), instance_id))
+                .or_default()
+                .add(totals);
+        }
+
+        rows.into_iter()
+            .map(|((period_start, account_id, instance_id), totals)| RollupRow {
+                period_start,
+                account_id,
+                instance_id,
+                totals,
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn persist(rollups: &UsageRollups) {
+    if let Err(e) = store_value(&DB_HANDLE, USAGE_ROLLUPS_DB_KEY, rollups) {
+        log::error!("Unable to persist usage rollups: {e}");
+    }
+}
+
+/// Folds usage events into the datastore's rollup tables until a shutdown signal is received
+pub async fn run_usage_rollups(
+    datastore: Arc<Mutex<DataStore>>,
+    config: UsageRollupConfig,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut n = datastore.lock().await.usage_rollups.cursor();
+    log::info!("Starting usage rollups from usage event {n}");
+    let mut pruning = tokio::time::interval(Duration::from_secs(SECONDS_PER_HOUR as u64));
+
+    loop {
+        tokio::select! {
+            Ok(messages) = DataStore::read_topic_from_queue(USAGE_EVENTS_TOPIC, Some(n), None) => {
+                if messages.is_empty() {
+                    tokio::time::sleep(config.poll_interval).await;
+                    continue;
+                }
+                n += messages.len();
+                let mut guard = datastore.lock().await;
+                for message in &messages {
+                    if message.is_empty() {
+                        continue;
+                    }
+                    let signed = match serde_json::from_slice::<SignedUsageEvent>(&message[1..]) {
+                        Ok(signed) => signed,
+                        Err(e) => {
+                            log::warn!("Usage rollups dropping unsigned or malformed usage event: {e}");
+                            continue;
+                        }
+                    };
+                    let host_node = guard.instance_state.get_instance(signed.event.instance_id.clone())
+                        .map(|instance| instance.node_id);
+                    match guard.usage_rollups.record_signed(&signed, host_node.as_deref()) {
+                        Ok(0) => {}
+                        Ok(missed) => log::warn!(
+                            "{missed} usage events for instance {} from node {} never arrived",
+                            signed.event.instance_id, signed.node_id
+                        ),
+                        Err(e) => log::warn!("Usage rollups rejected usage event: {e}"),
+                    }
+                }
+                guard.usage_rollups.advance(messages.len());
+                persist(&guard.usage_rollups);
+            }
+            _ = pruning.tick() => {
+                let mut guard = datastore.lock().await;
+                guard.usage_rollups.prune(chrono::Utc::now().timestamp(), config.retention_days);
+                persist(&guard.usage_rollups);
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use form_usage_events::{GpuUsage, UsageMetrics, UsagePeriod};
+
+    fn event(instance_id: &str, user_id: &str, start: i64) -> UsageEvent {
+        UsageEvent {
+            event_type: "resource_usage".to_string(),
+            version: "1.0".to_string(),
+            timestamp: start + 3600,
+            instance_id: instance_id.to_string(),
+            user_id: user_id.to_string(),
+            org_id: None,
+            metrics: UsageMetrics {
+                cpu_seconds: 60,
+                cpu_percent_avg: 50.0,
+                memory_gb: 2.0,
+                memory_percent: 50.0,
+                storage_gb: 30.0,
+                network_egress_mb: 1.0,
+                network_ingress_mb: 0.0,
+                gpu_seconds: 0,
+                disk_read_bytes_per_sec: 0,
+                disk_write_bytes_per_sec: 0,
+                disk_iops: 0,
+                network_egress_bytes_per_sec: 0,
+                network_ingress_bytes_per_sec: 0,
+                gpus: Vec::new(),
+            },
+            period: UsagePeriod { start, end: start + 3600 },
+        }
+    }
+
+    #[test]
+    fn test_rollups_group_by_period_and_account() {
+        // 2024-01-31 and 2024-02-01, both at 12:00 UTC
+        let jan = 1_706_702_400;
+        let feb = jan + SECONDS_PER_DAY;
+        let mut rollups = UsageRollups::default();
+        rollups.record(&event("a", "0xABC", jan));
+        rollups.record(&event("b", "abc", jan));
+        rollups.record(&event("a", "abc", feb));
+        rollups.record(&event("c", "def", feb));
+
+        let daily = rollups.query(&RollupQuery { account_id: Some("0xabc".into()), ..Default::default() });
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily[0].period_start, start_of_day(jan));
+        assert_eq!(daily[0].totals.cpu_seconds, 120);
+        assert_eq!(daily[0].totals.ram_gb_hours, 4.0);
+        assert_eq!(daily[0].totals.egress_bytes, 2 * 1024 * 1024);
+
+        let monthly = rollups.query(&RollupQuery {
+            period: RollupPeriod::Month,
+            group_by: RollupGrouping::Instance,
+            account_id: Some("abc".into()),
+            ..Default::default()
+        });
+        assert_eq!(monthly.len(), 3);
+        assert!(monthly.iter().all(|row| row.instance_id.is_some()));
+
+        let all = rollups.query(&RollupQuery { period: RollupPeriod::All, from: Some(feb), ..Default::default() });
+        assert_eq!(all.len(), 2);
+        assert_eq!(all.iter().map(|row| row.totals.events).sum::<u64>(), 2);
+
+        assert_eq!(rollups.anonymize_account("0xABC", "deleted-1"), 3);
+        assert!(rollups.query(&RollupQuery { account_id: Some("abc".into()), ..Default::default() }).is_empty());
+        let anonymized = rollups.query(&RollupQuery { period: RollupPeriod::All, account_id: Some("deleted-1".into()), ..Default::default() });
+        assert_eq!(anonymized[0].totals.events, 3);
+
+        rollups.prune(feb, 0);
+        assert_eq!(rollups.len(), 2);
+    }
+
+    #[test]
+    fn test_gpu_time_by_model() {
+        let gpu = |model: &str| GpuUsage { model: model.to_string(), attached_seconds: 3600, ..Default::default() };
+        let mut with_gpus = event("a", "abc", 1_706_702_400);
+        with_gpus.metrics.gpus = vec![gpu("H100"), gpu("H100"), gpu("A10")];
+
+        let mut totals = UsageTotals::from_event(&with_gpus);
+        totals.add(&UsageTotals::from_event(&with_gpus));
+        assert_eq!(totals.gpu_attached_seconds.get("H100"), Some(&(4 * 3600)));
+        assert_eq!(totals.gpu_attached_seconds.get("A10"), Some(&(2 * 3600)));
+    }
+
+    #[test]
+    fn test_signed_events_and_gaps() {
+        let signer = form_usage_events::UsageSigner::from_hex(&hex::encode([5u8; 32])).unwrap();
+        let node = signer.node_id().to_string();
+        let day = 1_706_702_400;
+        let first = signer.sign(event("a", "abc", day)).unwrap();
+        let _lost = signer.sign(event("a", "abc", day)).unwrap();
+        let third = signer.sign(event("a", "abc", day)).unwrap();
+
+        let mut rollups = UsageRollups::default();
+        assert!(matches!(
+            rollups.record_signed(&first, Some("0000000000000000000000000000000000000000")),
+            Err(AttestationRejected::WrongNode { .. })
+        ));
+        assert_eq!(rollups.record_signed(&first, Some(&node)), Ok(0));
+        assert_eq!(rollups.record_signed(&third, Some(&node)), Ok(1));
+        assert!(matches!(rollups.record_signed(&first, Some(&node)), Err(AttestationRejected::Replayed { .. })));
+
+        let mut forged = third.clone();
+        forged.sequence = 4;
+        forged.event.metrics.cpu_seconds = 0;
+        assert!(matches!(rollups.record_signed(&forged, Some(&node)), Err(AttestationRejected::InvalidSignature(_))));
+
+        let rows = rollups.query(&RollupQuery::default());
+        assert_eq!(rows[0].totals.events, 2);
+        assert_eq!(rows[0].totals.missed_events, 1);
+    }
+}
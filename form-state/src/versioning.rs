@@ -0,0 +1,119 @@
+// form-state/src/versioning.rs
+// API versions and how they are retired. Every route is served under a
+// version prefix. The unversioned paths the API had before are still served
+// by the current version's handlers, with Deprecation, Sunset and Link
+// headers telling clients where to go and until when.
+
+use axum::{
+    body::Body,
+    http::{header::LINK, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+
+/// A deprecated version keeps working for at least this many releases
+pub const SUPPORT_RELEASES: u32 = 2;
+
+/// and for no less than 180 days after it was deprecated
+pub const MIN_SUPPORT_SECONDS: i64 = 180 * 24 * 60 * 60;
+
+pub const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+pub const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApiVersion {
+    /// Path prefix, empty for the unversioned paths
+    pub prefix: &'static str,
+    /// Unix timestamp the version was deprecated at
+    pub deprecated_at: Option<i64>,
+    /// Unix timestamp after which the version may be removed
+    pub sunset_at: Option<i64>,
+}
+
+/// The version new clients should use
+pub const CURRENT: ApiVersion = ApiVersion { prefix: "/v1", deprecated_at: None, sunset_at: None };
+
+/// Paths without a version, served as `CURRENT`. Deprecated 2026-10-16,
+/// removed no earlier than 2027-04-16.
+pub const UNVERSIONED: ApiVersion = ApiVersion {
+    prefix: "",
+    deprecated_at: Some(1_792_108_800),
+    sunset_at: Some(1_807_833_600),
+};
+
+/// Every version that is served
+pub const VERSIONS: [ApiVersion; 2] = [CURRENT, UNVERSIONED];
+
+/// Formats a timestamp as an HTTP date, e.g. `Fri, 16 Apr 2027 00:00:00 GMT`
+pub fn http_date(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+impl ApiVersion {
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated_at.is_some()
+    }
+
+    /// Headers of a response to `path` served through this version. Only
+    /// deprecated versions have any.
+    pub fn headers(&self, path: &str) -> Vec<(HeaderName, String)> {
+        let mut headers = Vec::new();
+        if let Some(deprecated_at) = self.deprecated_at {
+            headers.push((DEPRECATION, format!("@{deprecated_at}")));
+        }
+        if let Some(sunset_at) = self.sunset_at {
+            headers.push((SUNSET, http_date(sunset_at)));
+        }
+        if self.is_deprecated() {
+            let successor = format!("{}{}", CURRENT.prefix, path.strip_prefix(self.prefix).unwrap_or(path));
+            headers.push((LINK, format!("<{successor}>; rel=\"successor-version\"")));
+        }
+        headers
+    }
+}
+
+/// Marks responses to the unversioned paths as deprecated
+pub async fn unversioned_headers(req: Request<Body>, next: Next) -> Response {
+    let path = req.uri().path().to_string();
+    let mut response = next.run(req).await;
+    for (name, value) in UNVERSIONED.headers(&path) {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deprecation_headers() {
+        assert!(CURRENT.headers("/v1/ping").is_empty());
+
+        let headers = UNVERSIONED.headers("/user/list");
+        assert_eq!(headers, vec![
+            (DEPRECATION, "@1792108800".to_string()),
+            (SUNSET, "Fri, 16 Apr 2027 00:00:00 GMT".to_string()),
+            (LINK, "</v1/user/list>; rel=\"successor-version\"".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_versions_are_supported_long_enough() {
+        for version in VERSIONS {
+            match (version.deprecated_at, version.sunset_at) {
+                (Some(deprecated_at), Some(sunset_at)) => assert!(
+                    sunset_at - deprecated_at >= MIN_SUPPORT_SECONDS,
+                    "{:?} is removed too soon after it was deprecated", version.prefix
+                ),
+                (None, None) => {}
+                _ => panic!("{:?} needs both a deprecation and a sunset date", version.prefix),
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Serves the form-state API on a local port and checks that every version
+//! that hasn't reached its sunset keeps answering, and that deprecated
+//! versions say so.
+
+use std::sync::Arc;
+use form_state::datastore::DataStore;
+use form_state::versioning::{CURRENT, UNVERSIONED, VERSIONS};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+async fn serve() -> String {
+    let datastore = DataStore::new("versioning-test-node".to_string(), hex::encode([7u8; 32]));
+    let app = form_state::api::app(Arc::new(Mutex::new(datastore)));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await.unwrap();
+    });
+    format!("http://{addr}")
+}
+
+#[tokio::test]
+async fn test_supported_versions_keep_working() {
+    let base = serve().await;
+    let client = reqwest::Client::new();
+    let now = unix_now();
+
+    for version in VERSIONS {
+        if version.sunset_at.map_or(false, |sunset| sunset <= now) {
+            continue;
+        }
+        for path in ["/ping", "/health", "/node/list"] {
+            let resp = client.get(format!("{base}{}{path}", version.prefix)).send().await.unwrap();
+            assert!(resp.status().is_success(), "{}{path} answered {}", version.prefix, resp.status());
+            assert_eq!(resp.headers().contains_key("deprecation"), version.is_deprecated(), "{}{path}", version.prefix);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unversioned_paths_match_current_version() {
+    let base = serve().await;
+    let client = reqwest::Client::new();
+
+    let current = client.get(format!("{base}{}/ping", CURRENT.prefix)).send().await.unwrap();
+    assert!(current.headers().get("sunset").is_none());
+    let current: Value = current.json().await.unwrap();
+
+    let legacy = client.get(format!("{base}/ping")).send().await.unwrap();
+    let headers = legacy.headers().clone();
+    assert_eq!(headers["deprecation"], format!("@{}", UNVERSIONED.deprecated_at.unwrap()).as_str());
+    assert_eq!(headers["sunset"], "Fri, 16 Apr 2027 00:00:00 GMT");
+    assert_eq!(headers["link"], "</v1/ping>; rel=\"successor-version\"");
+    assert_eq!(legacy.json::<Value>().await.unwrap(), current);
+}
+
+#[tokio::test]
+async fn test_unknown_version_is_not_found() {
+    let base = serve().await;
+    let resp = reqwest::get(format!("{base}/v0/ping")).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,54 @@
+//! Side-channel regression check of request verification, run with
+//! `cargo test -p form-state --features auth-timing --test auth_timing`.
+//!
+//! Compares inputs that differ in their first byte against inputs that
+//! differ in their last. A comparison that returns at the first difference
+//! is many times faster on the first kind, a constant time one isn't.
+#![cfg(feature = "auth-timing")]
+
+use form_state::auth::timing::{addresses_eq, ct_eq, measure, reset_timing, timing_report};
+
+const ROUNDS: usize = 2_000;
+/// Slowest median allowed relative to the fastest. Generous, scheduling
+/// noise on shared CI runners is a few percent, an early exit on 64 KiB is
+/// well over 10x.
+const MAX_MEDIAN_RATIO: f64 = 1.5;
+
+fn differing_at(len: usize, index: usize) -> (Vec<u8>, Vec<u8>) {
+    let a = vec![0x5a; len];
+    let mut b = a.clone();
+    b[index] ^= 0xff;
+    (a, b)
+}
+
+#[test]
+fn test_comparisons_take_as_long_wherever_inputs_differ() {
+    reset_timing();
+    let len = 64 * 1024;
+    let (a, first) = differing_at(len, 0);
+    let (_, last) = differing_at(len, len - 1);
+    let address = "ab".repeat(len / 2);
+    let first_address = format!("cd{}", &address[2..]);
+    let last_address = format!("{}cd", &address[..address.len() - 2]);
+
+    // Interleaved so drift in the machine's speed hits both alike
+    for _ in 0..ROUNDS {
+        assert!(!measure(|_| "bytes/first".to_string(), || ct_eq(&a, &first)));
+        assert!(!measure(|_| "bytes/last".to_string(), || ct_eq(&a, &last)));
+        assert!(!measure(|_| "address/first".to_string(), || addresses_eq(&address, &first_address)));
+        assert!(!measure(|_| "address/last".to_string(), || addresses_eq(&address, &last_address)));
+    }
+
+    let report = timing_report();
+    for kind in ["bytes", "address"] {
+        let first = &report[&format!("{kind}/first")];
+        let last = &report[&format!("{kind}/last")];
+        assert_eq!(first.samples, ROUNDS);
+        let ratio = first.median_ratio(last);
+        assert!(
+            ratio < MAX_MEDIAN_RATIO,
+            "{kind} comparison leaks the position of the difference: medians {}ns and {}ns",
+            first.median_ns, last.median_ns
+        );
+    }
+}
@@ -0,0 +1,11 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The gRPC schemas are only compiled for crates that enable `grpc`, so
+    // the rest of the workspace doesn't need protoc
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/state.proto");
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::configure().compile_protos(&["proto/state.proto"], &["proto"])?;
+    }
+    Ok(())
+}
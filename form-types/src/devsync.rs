@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+use serde::{Serialize, Deserialize};
+
+/// Where the instance's dev sync settings are written in the image
+pub const DEV_SYNC_CONFIG_PATH: &str = "/etc/form-dev-sync.json";
+
+/// Port the in-guest sync agent listens on
+pub const DEV_SYNC_PORT: u16 = 3012;
+
+/// How far a sync request's timestamp may be from the receiver's clock
+pub const DEV_SYNC_MAX_SKEW_SECS: u64 = 300;
+
+/// What happens after a delta is applied to a dev instance, declared with
+/// `DEVSYNC` in the Formfile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DevRestart {
+    /// Restart the `ENTRYPOINT` service, form-app.service
+    Service,
+    /// Run a shell command in the guest, e.g. a hot reload hook
+    Command(String),
+    /// Leave the app running, for apps that watch their own files
+    None,
+}
+
+/// Enables incremental file sync into the instance's WORKDIR
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DevSync {
+    pub restart: DevRestart,
+    /// Address allowed to sync, filled in by form-pack with the build's owner
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+/// Settings the sync agent reads from `DEV_SYNC_CONFIG_PATH`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DevSyncConfig {
+    /// Directory synced files are written under
+    pub root: String,
+    pub restart: DevRestart,
+    /// Hex address whose signature a sync request must carry
+    pub owner: String,
+}
+
+/// Hex SHA-256 of every file under a sync root, keyed by relative path
+/// with `/` separators
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub files: BTreeMap<String, String>,
+}
+
+impl FileManifest {
+    /// Paths that have to be sent for `remote` to match this manifest, and
+    /// the paths `remote` has that this one doesn't
+    pub fn diff(&self, remote: &FileManifest) -> (Vec<String>, Vec<String>) {
+        let changed = self.files.iter()
+            .filter(|(path, hash)| remote.files.get(*path) != Some(*hash))
+            .map(|(path, _)| path.clone())
+            .collect();
+        let deleted = remote.files.keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .cloned()
+            .collect();
+        (changed, deleted)
+    }
+}
+
+/// A changed file, `data` is base64 encoded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncFile {
+    pub path: String,
+    /// Unix permission bits
+    pub mode: u32,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncAction {
+    /// Return the instance's current `FileManifest`
+    Manifest,
+    /// Write `files`, remove `deleted` and optionally restart the app
+    Apply {
+        files: Vec<SyncFile>,
+        deleted: Vec<String>,
+        restart: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub build_id: String,
+    /// Unix seconds the request was made at, stale requests are refused
+    pub timestamp: u64,
+    pub action: SyncAction,
+}
+
+/// A `SyncRequest` signed by the build's owner. The signature covers the
+/// request's JSON encoding, so the receiver checks exactly the bytes sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedSyncRequest {
+    /// JSON encoded `SyncRequest`
+    pub request: String,
+    /// Hex encoded recoverable ECDSA signature over `request`
+    pub signature: String,
+    pub recovery_id: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncResponse {
+    Manifest(FileManifest),
+    Applied {
+        written: usize,
+        deleted: usize,
+        restarted: bool,
+    },
+    Failure {
+        reason: String,
+    },
+}
+
+/// Whether `path` stays inside the sync root it's joined to
+pub fn is_safe_sync_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| matches!(c, Component::Normal(_)))
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/// What happens to each instance when a node is drained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainPolicy {
+    /// Bring up a replacement on another node, then delete the local
+    /// instance. Falls back to stopping it if no replacement is ready in time.
+    #[default]
+    Migrate,
+    /// Gracefully stop every instance on the node
+    Stop,
+}
+
+/// Body of vmm-service's `POST /v1/admin/drain`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrainRequest {
+    #[serde(default)]
+    pub policy: DrainPolicy,
+    /// Seconds to wait for each instance's replacement to become ready
+    #[serde(default)]
+    pub migrate_timeout_secs: Option<u64>,
+    /// Recorded on the node in form-state, e.g. "kernel upgrade"
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainPhase {
+    /// Instances are still being moved off the node
+    Draining,
+    /// Every instance was handled, the node stays in maintenance mode
+    Drained,
+    /// The drain was cancelled and the node accepts instances again
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum InstanceDrainState {
+    Pending,
+    /// Waiting for a replacement on another node to become ready
+    Migrating,
+    Migrated { node_id: String },
+    Stopped,
+    Failed { reason: String },
+}
+
+impl InstanceDrainState {
+    pub fn is_done(&self) -> bool {
+        !matches!(self, Self::Pending | Self::Migrating)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceDrain {
+    pub build_id: String,
+    pub state: InstanceDrainState,
+    /// Why the instance ended up in `state`, e.g. a missed migration deadline
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/// Progress of a node drain, returned by `GET /v1/admin/drain`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrainStatus {
+    pub node_id: String,
+    pub policy: DrainPolicy,
+    pub phase: DrainPhase,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// Whether form-state marked the node as in maintenance
+    pub node_marked: bool,
+    /// Keyed by instance id
+    pub instances: BTreeMap<String, InstanceDrain>,
+}
+
+impl DrainStatus {
+    /// Instances that were migrated, stopped or failed, and the total
+    pub fn progress(&self) -> (usize, usize) {
+        let done = self.instances.values().filter(|i| i.state.is_done()).count();
+        (done, self.instances.len())
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Machine readable reason a vmm-service request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum VmmErrorCode {
+    /// The signature is missing or invalid, or the signer lacks access to the instance
+    AuthFailed,
+    /// The owner's account has no room left for the instance
+    QuotaExceeded,
+    /// The build's disk image doesn't exist or failed verification
+    ImageNotFound,
+    /// The node doesn't have the resources to run the instance
+    NodeCapacity,
+    /// The instance isn't known to the network
+    InstanceNotFound,
+    /// The node is being drained and doesn't take new instances
+    NodeDraining,
+    /// Admins froze new deployments on the network or the node for maintenance
+    DeploymentsFrozen,
+    /// The request itself is malformed
+    InvalidRequest,
+    Internal,
+}
+
+impl VmmErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AuthFailed => "AUTH_FAILED",
+            Self::QuotaExceeded => "QUOTA_EXCEEDED",
+            Self::ImageNotFound => "IMAGE_NOT_FOUND",
+            Self::NodeCapacity => "NODE_CAPACITY",
+            Self::InstanceNotFound => "INSTANCE_NOT_FOUND",
+            Self::NodeDraining => "NODE_DRAINING",
+            Self::DeploymentsFrozen => "DEPLOYMENTS_FROZEN",
+            Self::InvalidRequest => "INVALID_REQUEST",
+            Self::Internal => "INTERNAL",
+        }
+    }
+}
+
+impl fmt::Display for VmmErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The body of `VmmResponse::Failure`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VmmFailure {
+    pub code: VmmErrorCode,
+    pub message: String,
+}
+
+impl VmmFailure {
+    pub fn new(code: VmmErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for VmmFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for VmmFailure {}
+
+// Services that predate error codes answer with a bare message
+impl<'de> Deserialize<'de> for VmmFailure {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Coded { code: VmmErrorCode, message: String },
+            Message(String),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Coded { code, message } => Self { code, message },
+            Repr::Message(message) => Self { code: VmmErrorCode::Internal, message },
+        })
+    }
+}
+
+/// Queue topic `VmmNack`s are published on
+pub const VMM_NACK_TOPIC: &str = "vmm_nack";
+
+/// Published on the `vmm_nack` queue topic when a queued vmm request fails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmmNack {
+    /// Subtopic the failed request was read from
+    pub subtopic: u8,
+    pub failure: VmmFailure,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VmmResponse;
+
+    #[test]
+    fn test_failure_wire_format() {
+        let failure = VmmFailure::new(VmmErrorCode::NodeCapacity, "not enough memory");
+        let json = serde_json::to_string(&VmmResponse::Failure(failure.clone())).unwrap();
+        assert_eq!(json, r#"{"Failure":{"code":"NODE_CAPACITY","message":"not enough memory"}}"#);
+        match serde_json::from_str::<VmmResponse>(&json).unwrap() {
+            VmmResponse::Failure(decoded) => assert_eq!(decoded, failure),
+            other => panic!("unexpected response {other:?}"),
+        }
+
+        match serde_json::from_str::<VmmResponse>(r#"{"Failure":"boom"}"#).unwrap() {
+            VmmResponse::Failure(decoded) => assert_eq!(decoded.code, VmmErrorCode::Internal),
+            other => panic!("unexpected response {other:?}"),
+        }
+    }
+}
@@ -255,6 +255,29 @@ pub enum VmmEvent {
         #[cfg(any(feature = "testnet", feature = "mainnet"))]
         recovery_id: u32,
     },
+    /// The instance's `HEALTHCHECK` passed or started failing
+    Readiness {
+        id: String,
+        build_id: String,
+        ready: bool,
+        consecutive_failures: u32,
+        detail: Option<String>,
+    },
+    /// Replace the bandwidth caps and firewall rules of a running instance,
+    /// `policy` is a JSON encoded `NetworkPolicy`
+    UpdateNetworkPolicy {
+        id: String,
+        policy: String,
+    },
+    /// Copy the disk of `source` and boot it as the instance `build_id`
+    CloneInstance {
+        source: String,
+        build_id: String,
+        name: String,
+        owner: String,
+        formfile: String,
+        reset_identity: bool,
+    },
     Migrate,
     Copy,
     Snapshot,
@@ -0,0 +1,10 @@
+//! Generated types for the form-state gRPC query service, see
+//! `proto/state.proto`.
+tonic::include_proto!("formation.state.v1");
+
+/// Port form-state serves the query service on, loopback only
+pub const STATE_GRPC_PORT: u16 = 3014;
+
+pub fn state_grpc_endpoint() -> String {
+    format!("http://127.0.0.1:{STATE_GRPC_PORT}")
+}
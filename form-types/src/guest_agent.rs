@@ -0,0 +1,209 @@
+//! Protocol of the guest agent, a small service inside every instance that
+//! the host talks to over virtio-vsock instead of the network.
+//!
+//! The host connects to the agent on `GUEST_AGENT_PORT` to run commands,
+//! read files and check on the guest, and the guest connects back to the
+//! host on `HOST_REPORT_PORT` to report events such as a finished boot.
+//! Either side sends one JSON message per line and gets one JSON line back,
+//! except for `ExecStream`, which is answered with an `Output` line for every
+//! piece of output the command writes and an `Exit` line once it ends.
+//!
+//! Every message carries the instance's agent token, which vmm-service
+//! generates when it creates the instance and writes to
+//! `GUEST_AGENT_TOKEN_PATH` in the guest. A token is only good for the one
+//! instance it was issued to.
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+/// CID of the guest end of the vsock device
+pub const GUEST_AGENT_CID: u32 = 3;
+
+/// CID every guest reaches its host on
+pub const HOST_CID: u32 = 2;
+
+/// Port the agent accepts host requests on
+pub const GUEST_AGENT_PORT: u32 = 1024;
+
+/// Port the host accepts guest reports on
+pub const HOST_REPORT_PORT: u32 = 1025;
+
+/// Where the instance's agent token is written in the guest
+pub const GUEST_AGENT_TOKEN_PATH: &str = "/etc/formation/guest-agent.token";
+
+/// Longest message either side reads
+pub const MAX_GUEST_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Bytes of a file returned unless the request asks for fewer
+pub const DEFAULT_FETCH_LIMIT: u64 = 1024 * 1024;
+
+/// Seconds a command may run unless the request says otherwise
+pub const DEFAULT_EXEC_TIMEOUT_SECS: u64 = 30;
+
+/// Bytes of caller data an attestation report can be bound to, the size of
+/// the REPORT_DATA field of both SEV-SNP and TDX reports
+pub const ATTESTATION_REPORT_DATA_SIZE: usize = 64;
+
+/// A host request to the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestRequest {
+    pub token: String,
+    pub command: GuestCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum GuestCommand {
+    /// Run a program without a shell and wait for it to exit
+    Exec {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        timeout_secs: Option<u64>,
+    },
+    /// Run a program like `Exec`, sending its output as it's written
+    ExecStream {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        timeout_secs: Option<u64>,
+    },
+    /// Read up to `max_bytes` of a file
+    FetchFile {
+        path: String,
+        #[serde(default)]
+        max_bytes: Option<u64>,
+    },
+    Health,
+    /// Produce a hardware attestation report through the kernel's
+    /// configfs-tsm interface, binding `report_data` into it
+    AttestationReport {
+        /// Base64 encoded, at most `ATTESTATION_REPORT_DATA_SIZE` bytes, zero
+        /// padded by the guest
+        #[serde(default)]
+        report_data: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum GuestResponse {
+    Exec {
+        /// `None` if the program was killed by a signal or timed out
+        exit_code: Option<i32>,
+        stdout: String,
+        stderr: String,
+        timed_out: bool,
+    },
+    /// Output of an `ExecStream` command
+    Output {
+        stream: OutputStream,
+        data: String,
+    },
+    /// Last answer to an `ExecStream` command
+    Exit {
+        /// `None` if the program was killed by a signal or timed out
+        exit_code: Option<i32>,
+        timed_out: bool,
+    },
+    File {
+        path: String,
+        /// Base64 encoded contents
+        content: String,
+        /// Size of the whole file, larger than the content if it was cut off
+        size: u64,
+        truncated: bool,
+    },
+    Health(GuestHealth),
+    Attestation {
+        /// TSM provider that signed the report, `sev_guest` or `tdx_guest`
+        provider: String,
+        /// Base64 encoded report, an SNP ATTESTATION_REPORT or a TDX quote
+        report: String,
+        /// Base64 encoded certificate chain, when the host provides one
+        #[serde(default)]
+        certificates: Option<String>,
+    },
+    /// A report was accepted
+    Ack,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuestHealth {
+    pub agent_version: String,
+    pub uptime_secs: u64,
+    /// 1, 5 and 15 minute load averages
+    pub load_average: [f64; 3],
+    pub memory_total_kb: u64,
+    pub memory_available_kb: u64,
+    /// Whether the formnet interface is up
+    pub formnet_up: bool,
+}
+
+/// A guest report to the host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestReport {
+    pub token: String,
+    pub event: GuestEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum GuestEvent {
+    BootComplete {
+        name: String,
+        build_id: String,
+        formnet_ip: String,
+    },
+}
+
+/// Compares tokens in constant time
+pub fn guest_token_matches(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.trim().as_bytes(), given.trim().as_bytes());
+    if expected.is_empty() || expected.len() != given.len() {
+        return false;
+    }
+    expected.iter().zip(given).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_messages() {
+        let request: GuestRequest = serde_json::from_str(
+            r#"{"token":"t","command":{"type":"exec","program":"uname","args":["-a"]}}"#
+        ).unwrap();
+        assert_eq!(request.command, GuestCommand::Exec {
+            program: "uname".to_string(),
+            args: vec!["-a".to_string()],
+            env: BTreeMap::new(),
+            timeout_secs: None,
+        });
+        let line = serde_json::to_string(&GuestResponse::Ack).unwrap();
+        assert_eq!(line, r#"{"type":"ack"}"#);
+        let line = serde_json::to_string(&GuestResponse::Output { stream: OutputStream::Stderr, data: "e".to_string() }).unwrap();
+        assert_eq!(line, r#"{"type":"output","stream":"stderr","data":"e"}"#);
+
+        assert!(guest_token_matches("secret", "secret\n"));
+        assert!(!guest_token_matches("secret", "secreT"));
+        assert!(!guest_token_matches("secret", "secret2"));
+        assert!(!guest_token_matches("", ""));
+    }
+}
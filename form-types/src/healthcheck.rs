@@ -0,0 +1,40 @@
+use serde::{Serialize, Deserialize};
+
+/// Where the instance's health check definition is written in the image
+pub const HEALTH_CHECK_PATH: &str = "/etc/form-health.json";
+
+/// How the boot tooling decides an instance's app is ready, declared with
+/// `HEALTHCHECK` in the Formfile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub probe: HealthProbe,
+    /// Seconds between probes
+    pub interval_secs: u64,
+    /// Seconds a single probe may take before it counts as failed
+    pub timeout_secs: u64,
+    /// Consecutive failures before a ready instance is reported unhealthy
+    pub retries: u32,
+    /// Seconds after boot during which failures aren't counted
+    pub start_period_secs: u64,
+}
+
+impl HealthCheck {
+    pub fn new(probe: HealthProbe) -> Self {
+        Self {
+            probe,
+            interval_secs: 30,
+            timeout_secs: 5,
+            retries: 3,
+            start_period_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthProbe {
+    /// Shell command run in the guest, healthy on exit status 0
+    Command(String),
+    /// GET against the guest's loopback, healthy on a 2xx or 3xx
+    Http { port: u16, path: String },
+}
@@ -4,8 +4,20 @@ pub mod state;
 pub mod request;
 pub mod event; 
 pub mod pubsub;
+pub mod healthcheck;
+pub mod devsync;
+pub mod drain;
+pub mod guest_agent;
+pub mod error;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 
 pub use request::*; 
 pub use topic::*;
 pub use event::*;
 pub use pubsub::*;
+pub use healthcheck::*;
+pub use devsync::*;
+pub use drain::*;
+pub use guest_agent::*;
+pub use error::*;
@@ -1,5 +1,6 @@
 use serde::{Serialize, Deserialize};
 use clap::Args;
+use crate::error::{VmmErrorCode, VmmFailure};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Args)]
 pub struct PingVmmRequest {
@@ -14,6 +15,17 @@ pub struct BootCompleteRequest {
     pub formnet_ip: String,
 }
 
+/// Sent by the instance's health probe whenever its readiness changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadinessRequest {
+    pub build_id: String,
+    pub name: String,
+    pub ready: bool,
+    pub consecutive_failures: u32,
+    /// Output of the last failed probe
+    pub detail: Option<String>,
+}
+
 /// Request to create a new VM instance
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateVmRequest {
@@ -22,6 +34,19 @@ pub struct CreateVmRequest {
     pub owner: String,
 }
 
+/// Request to boot a copy of an instance's disk as a new instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneVmRequest {
+    /// Build id of the instance to copy, it has to run on the node asked
+    pub source: String,
+    /// Name of the copy, also its hostname
+    pub name: String,
+    /// Keep the source's machine-id and SSH host keys instead of generating
+    /// new ones on the copy's first boot
+    #[serde(default)]
+    pub keep_identity: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StartVmRequest {
     pub id: String,
@@ -63,5 +88,11 @@ pub struct VmResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum VmmResponse {
     Success(VmResponse),
-    Failure(String),
+    Failure(VmmFailure),
+}
+
+impl VmmResponse {
+    pub fn failure(code: VmmErrorCode, message: impl Into<String>) -> Self {
+        Self::Failure(VmmFailure::new(code, message))
+    }
 }
@@ -0,0 +1,165 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use alloy_primitives::Address;
+use k256::ecdsa::{RecoveryId, Signature, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+
+use crate::{errors::UsageEventError, events::UsageEvent};
+
+/// A usage event signed by the node that measured it.
+///
+/// Each signer numbers its events from 1 within an `epoch`, the unix time
+/// it started at, so a consumer can tell dropped or withheld events apart
+/// from a restarted publisher.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedUsageEvent {
+    pub event: UsageEvent,
+    /// Hex address of the node's operator key
+    pub node_id: String,
+    pub epoch: i64,
+    pub sequence: u64,
+    pub signature: String,
+    pub recovery_id: u8,
+}
+
+#[derive(Serialize)]
+struct AttestedFields<'a> {
+    event: &'a UsageEvent,
+    node_id: &'a str,
+    epoch: i64,
+    sequence: u64,
+}
+
+fn digest(event: &UsageEvent, node_id: &str, epoch: i64, sequence: u64) -> Result<[u8; 32], UsageEventError> {
+    let payload = serde_json::to_vec(&AttestedFields { event, node_id, epoch, sequence })?;
+    let mut hasher = Sha3::v256();
+    let mut hash = [0u8; 32];
+    hasher.update(&payload);
+    hasher.finalize(&mut hash);
+    Ok(hash)
+}
+
+impl SignedUsageEvent {
+    /// Checks the signature and returns the address that made it, which has
+    /// to match `node_id`
+    pub fn verify(&self) -> Result<Address, UsageEventError> {
+        let invalid = |reason: String| UsageEventError::Other(format!("Invalid usage attestation: {reason}"));
+
+        let hash = digest(&self.event, &self.node_id, self.epoch, self.sequence)?;
+        let signature = hex::decode(&self.signature).map_err(|e| invalid(e.to_string()))?;
+        let signature = Signature::try_from(signature.as_slice()).map_err(|e| invalid(e.to_string()))?;
+        let recovery_id = RecoveryId::from_byte(self.recovery_id)
+            .ok_or_else(|| invalid(format!("bad recovery id {}", self.recovery_id)))?;
+        let key = VerifyingKey::recover_from_msg(&hash, &signature, recovery_id)
+            .map_err(|e| invalid(e.to_string()))?;
+
+        let signer = Address::from_public_key(&key);
+        if hex::encode(signer) != self.node_id.trim_start_matches("0x").to_lowercase() {
+            return Err(invalid(format!("signed by {signer:x}, not {}", self.node_id)));
+        }
+        Ok(signer)
+    }
+}
+
+/// Signs usage events with a node's operator key, numbering them in order
+#[derive(Clone)]
+pub struct UsageSigner {
+    signing_key: SigningKey,
+    node_id: String,
+    epoch: i64,
+    next_sequence: Arc<AtomicU64>,
+}
+
+impl UsageSigner {
+    pub fn new(signing_key: SigningKey) -> Self {
+        let node_id = hex::encode(Address::from_private_key(&signing_key));
+        Self {
+            signing_key,
+            node_id,
+            epoch: chrono::Utc::now().timestamp(),
+            next_sequence: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// Creates a signer from a hex encoded secret key
+    pub fn from_hex(secret_key: &str) -> Result<Self, UsageEventError> {
+        let bytes = hex::decode(secret_key.trim().trim_start_matches("0x"))
+            .map_err(|e| UsageEventError::Other(format!("Invalid operator key: {e}")))?;
+        let signing_key = SigningKey::from_slice(&bytes)
+            .map_err(|e| UsageEventError::Other(format!("Invalid operator key: {e}")))?;
+        Ok(Self::new(signing_key))
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Signs the event under the next sequence number
+    pub fn sign(&self, event: UsageEvent) -> Result<SignedUsageEvent, UsageEventError> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        let hash = digest(&event, &self.node_id, self.epoch, sequence)?;
+        let (signature, recovery_id) = self.signing_key.sign_recoverable(&hash)
+            .map_err(|e| UsageEventError::Other(format!("Unable to sign usage event: {e}")))?;
+
+        Ok(SignedUsageEvent {
+            event,
+            node_id: self.node_id.clone(),
+            epoch: self.epoch,
+            sequence,
+            signature: hex::encode(signature.to_bytes()),
+            recovery_id: recovery_id.to_byte(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{UsageMetrics, UsagePeriod};
+
+    fn event() -> UsageEvent {
+        UsageEvent::new(
+            "instance".to_string(),
+            "account".to_string(),
+            None,
+            UsageMetrics {
+                cpu_seconds: 30,
+                cpu_percent_avg: 100.0,
+                memory_gb: 1.5,
+                memory_percent: 50.0,
+                storage_gb: 10.0,
+                network_egress_mb: 1.0,
+                network_ingress_mb: 2.0,
+                gpu_seconds: 0,
+                disk_read_bytes_per_sec: 0,
+                disk_write_bytes_per_sec: 0,
+                disk_iops: 0,
+                network_egress_bytes_per_sec: 0,
+                network_ingress_bytes_per_sec: 0,
+                gpus: Vec::new(),
+            },
+            UsagePeriod { start: 0, end: 30 },
+        )
+    }
+
+    #[test]
+    fn test_signed_usage_event() {
+        let signer = UsageSigner::from_hex(&hex::encode([9u8; 32])).unwrap();
+        let first = signer.sign(event()).unwrap();
+        let second = signer.sign(event()).unwrap();
+        assert_eq!((first.sequence, second.sequence), (1, 2));
+
+        let decoded: SignedUsageEvent = serde_json::from_slice(&serde_json::to_vec(&first).unwrap()).unwrap();
+        assert_eq!(hex::encode(decoded.verify().unwrap()), signer.node_id());
+
+        let mut underreported = first.clone();
+        underreported.event.metrics.cpu_seconds = 1;
+        assert!(underreported.verify().is_err());
+
+        let mut renumbered = first;
+        renumbered.sequence = 3;
+        assert!(renumbered.verify().is_err());
+    }
+}
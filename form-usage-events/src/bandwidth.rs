@@ -0,0 +1,95 @@
+use serde::{Serialize, Deserialize};
+
+use crate::events::UsagePeriod;
+
+/// Topic the peer bandwidth summaries are published on, kept apart from the
+/// per instance usage events so billing doesn't have to skip them
+pub const PEER_BANDWIDTH_TOPIC: &str = "peer_bandwidth";
+
+/// Overlay traffic exchanged with one formnet peer during a period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerBandwidth {
+    /// Formnet id of the peer, or its public key if it isn't known
+    pub peer_id: String,
+
+    /// Formnet address of the peer, if known
+    pub formnet_ip: Option<String>,
+
+    /// Bytes received from the peer during the period
+    pub rx_bytes: u64,
+
+    /// Bytes sent to the peer during the period
+    pub tx_bytes: u64,
+}
+
+/// Per peer overlay bandwidth seen by a node, published periodically so
+/// fair-use policies can be enforced across the network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerBandwidthEvent {
+    /// Type of event, always "peer_bandwidth" for these events
+    pub event_type: String,
+
+    /// Schema version for forward compatibility
+    pub version: String,
+
+    /// Unix timestamp when the event was created
+    pub timestamp: i64,
+
+    /// Formnet id of the node that measured the traffic
+    pub node_id: String,
+
+    /// Time period the totals cover
+    pub period: UsagePeriod,
+
+    /// Traffic per peer, heaviest first
+    pub peers: Vec<PeerBandwidth>,
+}
+
+impl PeerBandwidthEvent {
+    /// Creates a new PeerBandwidthEvent with the current timestamp
+    pub fn new(node_id: String, period: UsagePeriod, mut peers: Vec<PeerBandwidth>) -> Self {
+        peers.sort_by(|a, b| (b.rx_bytes + b.tx_bytes).cmp(&(a.rx_bytes + a.tx_bytes)));
+        Self {
+            event_type: "peer_bandwidth".to_string(),
+            version: "1.0".to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            node_id,
+            period,
+            peers,
+        }
+    }
+
+    /// Bytes exchanged with all peers in both directions
+    pub fn total_bytes(&self) -> u64 {
+        self.peers.iter().map(|peer| peer.rx_bytes + peer.tx_bytes).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_bandwidth_event() {
+        let peer = |peer_id: &str, rx_bytes, tx_bytes| PeerBandwidth {
+            peer_id: peer_id.to_string(),
+            formnet_ip: None,
+            rx_bytes,
+            tx_bytes,
+        };
+        let event = PeerBandwidthEvent::new(
+            "node-1".to_string(),
+            UsagePeriod { start: 1626350400, end: 1626350700 },
+            vec![peer("light", 10, 5), peer("heavy", 500, 700)],
+        );
+
+        assert_eq!(event.event_type, "peer_bandwidth");
+        assert_eq!(event.peers[0].peer_id, "heavy");
+        assert_eq!(event.total_bytes(), 1215);
+
+        let json = serde_json::to_string(&event).unwrap();
+        let deserialized: PeerBandwidthEvent = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized.peers, event.peers);
+        assert_eq!(deserialized.period.end, 1626350700);
+    }
+}
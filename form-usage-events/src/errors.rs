@@ -23,6 +23,15 @@ pub enum UsageEventError {
     #[error("HTTP request failed: {0}")]
     HttpError(#[from] reqwest::Error),
     
+    /// Error when a threshold rule is malformed
+    #[error("Invalid threshold rule: {0}")]
+    InvalidThreshold(String),
+    
+    /// Error reading or writing persisted threshold rules, sink configs or
+    /// a file sink
+    #[error("File operation failed: {0}")]
+    PersistenceError(#[from] std::io::Error),
+    
     /// Generic error type for other failures
     #[error("Operation failed: {0}")]
     Other(String),
@@ -52,8 +52,64 @@ pub struct UsageMetrics {
     /// Network ingress in MB
     pub network_ingress_mb: f64,
     
-    /// GPU usage in seconds (0 if no GPU is used)
+    /// GPU usage in seconds, weighted by utilization and summed over the
+    /// GPUs (0 if no GPU is used)
     pub gpu_seconds: u64,
+
+    /// Disk read throughput in bytes/sec over the period
+    #[serde(default)]
+    pub disk_read_bytes_per_sec: u64,
+
+    /// Disk write throughput in bytes/sec over the period
+    #[serde(default)]
+    pub disk_write_bytes_per_sec: u64,
+
+    /// Disk read and write operations per second over the period
+    #[serde(default)]
+    pub disk_iops: u64,
+
+    /// Network egress in bytes/sec over the period
+    #[serde(default)]
+    pub network_egress_bytes_per_sec: u64,
+
+    /// Network ingress in bytes/sec over the period
+    #[serde(default)]
+    pub network_ingress_bytes_per_sec: u64,
+
+    /// Each GPU attached to the instance
+    #[serde(default)]
+    pub gpus: Vec<GpuUsage>,
+}
+
+/// Usage of one GPU during the period
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GpuUsage {
+    /// Index of the GPU in the instance
+    pub index: u32,
+
+    /// GPU model, as reported by the driver
+    pub model: String,
+
+    /// NVML UUID or PCI address of the GPU
+    pub uuid: Option<String>,
+
+    /// Seconds the GPU was attached during the period, what GPU time is billed by
+    pub attached_seconds: u64,
+
+    /// GPU utilization percentage
+    pub utilization_percent: f64,
+
+    /// VRAM in use in MB
+    pub memory_used_mb: f64,
+
+    /// VRAM of the GPU in MB
+    pub memory_total_mb: f64,
+
+    /// Temperature in °C
+    pub temperature_c: f64,
+
+    /// Power draw in watts
+    pub power_watts: f64,
 }
 
 /// Represents the time period that the usage metrics cover
@@ -107,6 +163,12 @@ mod tests {
             network_egress_mb: 15.2,
             network_ingress_mb: 8.7,
             gpu_seconds: 0,
+            disk_read_bytes_per_sec: 0,
+            disk_write_bytes_per_sec: 0,
+            disk_iops: 0,
+            network_egress_bytes_per_sec: 0,
+            network_ingress_bytes_per_sec: 0,
+            gpus: Vec::new(),
         };
         
         // Create sample period
@@ -1,12 +1,18 @@
+pub mod attestation;
+pub mod bandwidth;
 pub mod events;
 pub mod errors;
 pub mod publish;
 pub mod retry;
 pub mod circuit_breaker;
+pub mod sinks;
 pub mod threshold;
 
 // Re-export key types
-pub use events::{UsageEvent, UsageMetrics, UsagePeriod};
+pub use attestation::{SignedUsageEvent, UsageSigner};
+pub use bandwidth::{PeerBandwidth, PeerBandwidthEvent, PEER_BANDWIDTH_TOPIC};
+pub use events::{GpuUsage, UsageEvent, UsageMetrics, UsagePeriod};
 pub use errors::UsageEventError;
 pub use publish::EventPublisher;
 pub use retry::RetryConfig;
+pub use sinks::{EventSink, FileSink, HttpSink, QueueSink, SinkConfig, SinkRoute};
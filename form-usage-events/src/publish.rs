@@ -1,16 +1,15 @@
-use std::time::Duration;
 use std::sync::Arc;
 
-use form_p2p::queue::{QueueRequest, QueueResponse, QUEUE_PORT};
-use reqwest::Client;
-use serde::Serialize;
-use tiny_keccak::{Hasher, Sha3};
+use form_p2p::queue::QUEUE_PORT;
 
 use crate::{
+    attestation::UsageSigner,
+    bandwidth::{PeerBandwidthEvent, PEER_BANDWIDTH_TOPIC},
     events::UsageEvent,
     errors::UsageEventError,
-    retry::{RetryConfig, with_retry},
+    retry::RetryConfig,
     circuit_breaker::{CircuitBreaker, CircuitBreakerConfig},
+    sinks::{QueueSink, SinkRoute},
     threshold::ThresholdManager,
 };
 
@@ -18,16 +17,21 @@ const DEFAULT_TOPIC: &str = "usage_events";
 const DEFAULT_ENDPOINT: &str = "127.0.0.1";
 const DEFAULT_SUBTOPIC: u8 = 0; // Using 0 for usage events (arbitrary choice)
 
-/// Handles the publishing of usage events to the message queue
+/// Handles the publishing of usage events to the message queue and any
+/// extra sinks
 #[derive(Clone)]
 pub struct EventPublisher {
-    client: Client,
-    queue_endpoint: String,
+    queue: Arc<QueueSink>,
+    /// Whether events go to the message queue, off for sink-only publishers
+    queue_enabled: bool,
     topic: String,
-    sub_topic: u8,
+    /// Retry config and circuit breaker of the message queue. Extra sinks
+    /// carry their own.
     retry_config: RetryConfig,
     circuit_breaker: Option<Arc<CircuitBreaker>>,
+    sinks: Vec<SinkRoute>,
     threshold_manager: Option<Arc<ThresholdManager>>,
+    signer: Option<UsageSigner>,
 }
 
 impl EventPublisher {
@@ -38,21 +42,15 @@ impl EventPublisher {
     
     /// Creates a new EventPublisher with custom configuration
     pub fn with_config(endpoint: String, port: u16, topic: String, sub_topic: u8) -> Self {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(5))
-            .build()
-            .unwrap_or_default();
-            
-        let queue_endpoint = format!("http://{}:{}/queue/write_local", endpoint, port);
-        
         Self {
-            client,
-            queue_endpoint,
+            queue: Arc::new(QueueSink::new(&endpoint, port, sub_topic)),
+            queue_enabled: true,
             topic,
-            sub_topic,
             retry_config: RetryConfig::default(),
             circuit_breaker: None,
+            sinks: Vec::new(),
             threshold_manager: None,
+            signer: None,
         }
     }
     
@@ -74,12 +72,37 @@ impl EventPublisher {
         self
     }
     
+    /// Also delivers every event to `route`
+    pub fn with_sink(mut self, route: SinkRoute) -> Self {
+        self.sinks.push(route);
+        self
+    }
+    
+    /// Also delivers every event to each of `routes`
+    pub fn with_sinks(mut self, routes: impl IntoIterator<Item = SinkRoute>) -> Self {
+        self.sinks.extend(routes);
+        self
+    }
+    
+    /// Stops writing events to the message queue, leaving only the extra sinks
+    pub fn without_queue(mut self) -> Self {
+        self.queue_enabled = false;
+        self
+    }
+    
     /// Sets a threshold manager for checking metrics against thresholds
     pub fn with_threshold_manager(mut self, manager: Arc<ThresholdManager>) -> Self {
         self.threshold_manager = Some(manager);
         self
     }
     
+    /// Signs every published event, so form-state can verify it before
+    /// billing for it
+    pub fn with_signer(mut self, signer: UsageSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+    
     /// Creates a threshold manager with the given config source and adds it to this publisher
     pub async fn with_new_threshold_manager(mut self, config_source: String) -> Result<Self, UsageEventError> {
         let manager = Arc::new(ThresholdManager::new(config_source));
@@ -98,85 +121,67 @@ impl EventPublisher {
             manager.check_event(&event).await?;
         }
         
-        // Check circuit breaker state before proceeding
-        if let Some(ref cb) = self.circuit_breaker {
-            if !cb.allow_request().await {
-                return Err(UsageEventError::CircuitBreakerOpen);
-            }
+        // Check the circuit breakers before proceeding, so no sequence
+        // number is spent on an event no sink would take
+        if !self.any_route_allows().await {
+            return Err(UsageEventError::CircuitBreakerOpen);
         }
         
-        let publisher = self.clone();
-        let event_clone = event.clone();
-        let circuit_breaker = self.circuit_breaker.clone();
-        
-        let result = with_retry(
-            || {
-                // Clone these values before moving them into the async block
-                let pub_clone = publisher.clone();
-                let evt_clone = event_clone.clone();
-                
-                async move {
-                    pub_clone.publish_without_retry(evt_clone).await
-                }
-            },
-            &self.retry_config
-        ).await;
+        // Sign once, so retries and every sink get the same sequence number
+        let message = match &self.signer {
+            Some(signer) => serde_json::to_value(signer.sign(event)?)?,
+            None => serde_json::to_value(event)?,
+        };
         
-        // Update circuit breaker based on result
-        if let Some(cb) = circuit_breaker {
-            match &result {
-                Ok(_) => cb.record_success().await,
-                Err(_) => cb.record_failure().await,
-            }
+        self.publish_value(&self.topic, message).await
+    }
+
+    /// Publishes a peer bandwidth summary with retries. Summaries always go
+    /// to `PEER_BANDWIDTH_TOPIC`, whatever topic the publisher was built with.
+    pub async fn publish_bandwidth(&self, event: PeerBandwidthEvent) -> Result<(), UsageEventError> {
+        self.publish_value(PEER_BANDWIDTH_TOPIC, serde_json::to_value(event)?).await
+    }
+
+    /// The message queue and the extra sinks
+    fn routes(&self) -> Vec<SinkRoute> {
+        let mut routes = Vec::with_capacity(self.sinks.len() + 1);
+        if self.queue_enabled {
+            routes.push(SinkRoute::from_arc(self.queue.clone())
+                .with_policy(self.retry_config.clone(), self.circuit_breaker.clone()));
         }
-        
-        result
+        routes.extend(self.sinks.iter().cloned());
+        routes
     }
-    
-    /// Publishes a usage event to the message queue without retries
-    async fn publish_without_retry(&self, event: UsageEvent) -> Result<(), UsageEventError> {
-        self.publish_message(event).await
+
+    async fn any_route_allows(&self) -> bool {
+        for route in self.routes() {
+            if route.allows_request().await {
+                return true;
+            }
+        }
+        false
     }
-    
-    /// Internal method to publish a serializable message
-    async fn publish_message<T: Serialize + Clone>(&self, message: T) -> Result<(), UsageEventError> {
-        // Create topic hash
-        let mut hasher = Sha3::v256();
-        let mut topic_hash = [0u8; 32];
-        hasher.update(self.topic.as_bytes());
-        hasher.finalize(&mut topic_hash);
-        
-        // Create message with sub_topic prefix
-        let mut message_code = vec![self.sub_topic];
-        message_code.extend(serde_json::to_vec(&message).map_err(UsageEventError::SerializationError)?);
-        
-        // Create queue request
-        let request = QueueRequest::Write { 
-            content: message_code, 
-            topic: hex::encode(topic_hash) 
-        };
 
-        // Send request to queue
-        let response = self.client
-            .post(&self.queue_endpoint)
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| UsageEventError::ConnectionError(e.to_string()))?
-            .json::<QueueResponse>()
-            .await
-            .map_err(|e| UsageEventError::ConnectionError(e.to_string()))?;
-            
-        // Handle response
-        match response {
-            QueueResponse::OpSuccess => Ok(()),
-            QueueResponse::Failure { reason } => {
-                Err(UsageEventError::PublishError(format!("{reason:?}")))
-            },
-            _ => Err(UsageEventError::PublishError(
-                "Invalid response variant for write_local endpoint".to_string()
-            )),
+    /// Delivers a message to every sink at once, each with its own retries
+    /// and circuit breaker. A failing sink doesn't keep the message from the
+    /// others; the first failure is returned once all have finished.
+    pub async fn publish_value(&self, topic: &str, message: serde_json::Value) -> Result<(), UsageEventError> {
+        let routes = self.routes();
+        if routes.is_empty() {
+            return Err(UsageEventError::Other("Publisher has no sinks".to_string()));
+        }
+        let results = futures::future::join_all(
+            routes.iter().map(|route| route.deliver(topic, &message))
+        ).await;
+        
+        let mut first_error = None;
+        for (route, result) in routes.iter().zip(results) {
+            if let Err(e) = result {
+                eprintln!("Unable to deliver event on {topic} to sink {}: {e}", route.name());
+                first_error.get_or_insert(e);
+            }
         }
+        first_error.map_or(Ok(()), Err)
     }
 }
 
@@ -192,8 +197,8 @@ mod tests {
     async fn test_event_publisher_creation() {
         let publisher = EventPublisher::new();
         assert_eq!(publisher.topic, DEFAULT_TOPIC);
-        assert_eq!(publisher.sub_topic, DEFAULT_SUBTOPIC);
-        assert!(publisher.queue_endpoint.contains(DEFAULT_ENDPOINT));
+        assert_eq!(publisher.queue.sub_topic, DEFAULT_SUBTOPIC);
+        assert!(publisher.queue.endpoint.contains(DEFAULT_ENDPOINT));
     }
     
     #[tokio::test]
@@ -428,6 +433,12 @@ mod tests {
                 network_egress_mb: 100.0,
                 network_ingress_mb: 50.0,
                 gpu_seconds: 0,
+                disk_read_bytes_per_sec: 0,
+                disk_write_bytes_per_sec: 0,
+                disk_iops: 0,
+                network_egress_bytes_per_sec: 0,
+                network_ingress_bytes_per_sec: 0,
+                gpus: Vec::new(),
             },
             period: UsagePeriod {
                 start: chrono::Utc::now().timestamp() - 30,
@@ -455,6 +466,12 @@ mod tests {
                 network_egress_mb: 50.0,
                 network_ingress_mb: 25.0,
                 gpu_seconds: 0,
+                disk_read_bytes_per_sec: 0,
+                disk_write_bytes_per_sec: 0,
+                disk_iops: 0,
+                network_egress_bytes_per_sec: 0,
+                network_ingress_bytes_per_sec: 0,
+                gpus: Vec::new(),
             },
             period: UsagePeriod {
                 start: chrono::Utc::now().timestamp() - 30,
@@ -484,6 +501,12 @@ mod tests {
                 network_egress_mb: 15.2,
                 network_ingress_mb: 8.7,
                 gpu_seconds: 0,
+                disk_read_bytes_per_sec: 0,
+                disk_write_bytes_per_sec: 0,
+                disk_iops: 0,
+                network_egress_bytes_per_sec: 0,
+                network_ingress_bytes_per_sec: 0,
+                gpus: Vec::new(),
             },
             period: UsagePeriod {
                 start: 1234567800,
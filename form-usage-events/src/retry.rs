@@ -75,6 +75,8 @@ where
                         return Err(error);
                     },
                     UsageEventError::SerializationError(_) |
+                    UsageEventError::InvalidThreshold(_) |
+                    UsageEventError::PersistenceError(_) |
                     UsageEventError::Other(_) => {
                         // Don't retry for these errors as they're not likely
                         // to be resolved by retrying
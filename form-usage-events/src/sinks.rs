@@ -0,0 +1,592 @@
+//! Destinations for published events
+//!
+//! An `EventPublisher` hands every event to the form-p2p queue and to any
+//! number of extra `EventSink`s. Each sink is wrapped in a `SinkRoute` with its
+//! own retry config and circuit breaker, so a slow collector or a full disk
+//! doesn't hold up delivery to the others.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use form_p2p::queue::{QueueRequest, QueueResponse, QUEUE_PORT};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Sha3};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitBreakerConfig},
+    errors::UsageEventError,
+    retry::{with_retry, RetryConfig},
+};
+
+/// Header the HTTP sink puts the topic of an event in
+pub const TOPIC_HEADER: &str = "X-Formation-Topic";
+
+const DEFAULT_MAX_FILE_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 5;
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 5;
+
+/// Future returned by `EventSink::send`
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<(), UsageEventError>> + Send + 'a>>;
+
+/// Somewhere events can be delivered to
+pub trait EventSink: Send + Sync {
+    /// Name the sink is logged under
+    fn name(&self) -> &str;
+
+    /// Delivers one message published under `topic`. Errors that a retry can
+    /// fix should be `ConnectionError` or `PublishError`.
+    fn send<'a>(&'a self, topic: &'a str, message: &'a serde_json::Value) -> SinkFuture<'a>;
+}
+
+/// Writes events to the local form-p2p message queue
+pub struct QueueSink {
+    client: Client,
+    pub(crate) endpoint: String,
+    pub(crate) sub_topic: u8,
+}
+
+impl QueueSink {
+    pub fn new(endpoint: &str, port: u16, sub_topic: u8) -> Self {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            client,
+            endpoint: format!("http://{}:{}/queue/write_local", endpoint, port),
+            sub_topic,
+        }
+    }
+
+    async fn write(&self, topic: &str, message: &serde_json::Value) -> Result<(), UsageEventError> {
+        // Create topic hash
+        let mut hasher = Sha3::v256();
+        let mut topic_hash = [0u8; 32];
+        hasher.update(topic.as_bytes());
+        hasher.finalize(&mut topic_hash);
+
+        // Create message with sub_topic prefix
+        let mut message_code = vec![self.sub_topic];
+        message_code.extend(serde_json::to_vec(message).map_err(UsageEventError::SerializationError)?);
+
+        let request = QueueRequest::Write {
+            content: message_code,
+            topic: hex::encode(topic_hash),
+        };
+
+        let response = self.client
+            .post(&self.endpoint)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| UsageEventError::ConnectionError(e.to_string()))?
+            .json::<QueueResponse>()
+            .await
+            .map_err(|e| UsageEventError::ConnectionError(e.to_string()))?;
+
+        match response {
+            QueueResponse::OpSuccess => Ok(()),
+            QueueResponse::Failure { reason } => {
+                Err(UsageEventError::PublishError(format!("{reason:?}")))
+            },
+            _ => Err(UsageEventError::PublishError(
+                "Invalid response variant for write_local endpoint".to_string()
+            )),
+        }
+    }
+}
+
+impl EventSink for QueueSink {
+    fn name(&self) -> &str {
+        "queue"
+    }
+
+    fn send<'a>(&'a self, topic: &'a str, message: &'a serde_json::Value) -> SinkFuture<'a> {
+        Box::pin(self.write(topic, message))
+    }
+}
+
+/// Appends events to a newline-delimited JSON file, one
+/// `{"topic": .., "event": ..}` object per line. Once the file would grow past
+/// `max_bytes` it is renamed to `<path>.1`, older files shift up and the
+/// oldest past `max_files` is removed.
+pub struct FileSink {
+    name: String,
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    lock: Mutex<()>,
+}
+
+impl FileSink {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64, max_files: usize) -> Self {
+        let path = path.into();
+        Self {
+            name: format!("file:{}", path.display()),
+            path,
+            max_bytes,
+            max_files,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Path of the `n`th rotated file
+    pub fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    async fn rotate(&self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            return tokio::fs::remove_file(&self.path).await;
+        }
+        let _ = tokio::fs::remove_file(self.rotated_path(self.max_files)).await;
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated_path(n);
+            if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+                tokio::fs::rename(&from, self.rotated_path(n + 1)).await?;
+            }
+        }
+        tokio::fs::rename(&self.path, self.rotated_path(1)).await
+    }
+
+    async fn append(&self, topic: &str, message: &serde_json::Value) -> Result<(), UsageEventError> {
+        let mut line = serde_json::to_vec(&serde_json::json!({ "topic": topic, "event": message }))?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().await;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let size = tokio::fs::metadata(&self.path).await.map(|meta| meta.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+impl EventSink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a self, topic: &'a str, message: &'a serde_json::Value) -> SinkFuture<'a> {
+        Box::pin(self.append(topic, message))
+    }
+}
+
+/// POSTs each event as JSON to a URL, with the topic in `TOPIC_HEADER`.
+/// Server errors are retried, client errors are not.
+pub struct HttpSink {
+    name: String,
+    client: Client,
+    url: String,
+    bearer_token: Option<String>,
+}
+
+impl HttpSink {
+    pub fn new(url: impl Into<String>, bearer_token: Option<String>, timeout: Duration) -> Self {
+        let url = url.into();
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            name: format!("http:{url}"),
+            client,
+            url,
+            bearer_token,
+        }
+    }
+
+    async fn post(&self, topic: &str, message: &serde_json::Value) -> Result<(), UsageEventError> {
+        let mut request = self.client
+            .post(&self.url)
+            .header(TOPIC_HEADER, topic)
+            .json(message);
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| UsageEventError::ConnectionError(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_client_error() {
+            Err(UsageEventError::Other(format!("{} rejected the event with {status}", self.url)))
+        } else {
+            Err(UsageEventError::PublishError(format!("{} answered {status}", self.url)))
+        }
+    }
+}
+
+impl EventSink for HttpSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a self, topic: &'a str, message: &'a serde_json::Value) -> SinkFuture<'a> {
+        Box::pin(self.post(topic, message))
+    }
+}
+
+/// A sink together with how delivery to it is retried and when it is given up
+/// on for a while
+#[derive(Clone)]
+pub struct SinkRoute {
+    sink: Arc<dyn EventSink>,
+    retry_config: RetryConfig,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl SinkRoute {
+    /// Routes to `sink` with the default retry config and no circuit breaker
+    pub fn new(sink: impl EventSink + 'static) -> Self {
+        Self::from_arc(Arc::new(sink))
+    }
+
+    pub fn from_arc(sink: Arc<dyn EventSink>) -> Self {
+        Self {
+            sink,
+            retry_config: RetryConfig::default(),
+            circuit_breaker: None,
+        }
+    }
+
+    /// Uses an existing retry config and circuit breaker, for the publisher's
+    /// own queue route whose breaker outlives each publish
+    pub(crate) fn with_policy(mut self, retry_config: RetryConfig, circuit_breaker: Option<Arc<CircuitBreaker>>) -> Self {
+        self.retry_config = retry_config;
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    pub fn with_retry_config(mut self, config: RetryConfig) -> Self {
+        self.retry_config = config;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = Some(Arc::new(CircuitBreaker::new(config)));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        self.sink.name()
+    }
+
+    /// Whether the circuit breaker lets a request through
+    pub(crate) async fn allows_request(&self) -> bool {
+        match &self.circuit_breaker {
+            Some(cb) => cb.allow_request().await,
+            None => true,
+        }
+    }
+
+    /// Sends a message, retrying per the retry config and recording the
+    /// outcome with the circuit breaker
+    pub(crate) async fn deliver(&self, topic: &str, message: &serde_json::Value) -> Result<(), UsageEventError> {
+        if !self.allows_request().await {
+            return Err(UsageEventError::CircuitBreakerOpen);
+        }
+
+        let result = with_retry(|| self.sink.send(topic, message), &self.retry_config).await;
+
+        if let Some(cb) = &self.circuit_breaker {
+            match &result {
+                Ok(_) => cb.record_success().await,
+                Err(_) => cb.record_failure().await,
+            }
+        }
+        result
+    }
+}
+
+/// Retry settings of a configured sink. Unset fields keep the defaults of
+/// `RetryConfig`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetrySettings {
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+    #[serde(default)]
+    pub initial_backoff_ms: Option<u64>,
+    #[serde(default)]
+    pub max_backoff_ms: Option<u64>,
+}
+
+impl RetrySettings {
+    pub fn to_config(&self) -> RetryConfig {
+        let mut config = RetryConfig::default();
+        if let Some(max_retries) = self.max_retries {
+            config.max_retries = max_retries;
+        }
+        if let Some(ms) = self.initial_backoff_ms {
+            config.initial_backoff = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.max_backoff_ms {
+            config.max_backoff = Duration::from_millis(ms);
+        }
+        config
+    }
+}
+
+/// Circuit breaker settings of a configured sink. Unset fields keep the
+/// defaults of `CircuitBreakerConfig`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BreakerSettings {
+    #[serde(default)]
+    pub failure_threshold: Option<usize>,
+    #[serde(default)]
+    pub reset_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub half_open_allowed_calls: Option<usize>,
+}
+
+impl BreakerSettings {
+    pub fn to_config(&self) -> CircuitBreakerConfig {
+        let mut config = CircuitBreakerConfig::default();
+        if let Some(threshold) = self.failure_threshold {
+            config.failure_threshold = threshold;
+        }
+        if let Some(secs) = self.reset_timeout_secs {
+            config.reset_timeout = Duration::from_secs(secs);
+        }
+        if let Some(calls) = self.half_open_allowed_calls {
+            config.half_open_allowed_calls = calls;
+        }
+        config
+    }
+}
+
+/// Where a configured sink delivers to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    /// A message queue other than the publisher's own
+    Queue {
+        endpoint: String,
+        #[serde(default = "default_queue_port")]
+        port: u16,
+        #[serde(default)]
+        sub_topic: u8,
+    },
+    File {
+        path: PathBuf,
+        #[serde(default = "default_max_file_bytes")]
+        max_bytes: u64,
+        #[serde(default = "default_max_files")]
+        max_files: usize,
+    },
+    Http {
+        url: String,
+        #[serde(default)]
+        bearer_token: Option<String>,
+        #[serde(default = "default_http_timeout_secs")]
+        timeout_secs: u64,
+    },
+}
+
+fn default_queue_port() -> u16 {
+    QUEUE_PORT
+}
+
+fn default_max_file_bytes() -> u64 {
+    DEFAULT_MAX_FILE_BYTES
+}
+
+fn default_max_files() -> usize {
+    DEFAULT_MAX_FILES
+}
+
+fn default_http_timeout_secs() -> u64 {
+    DEFAULT_HTTP_TIMEOUT_SECS
+}
+
+/// An extra sink as written in a sinks config file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    #[serde(default)]
+    pub retry: RetrySettings,
+    /// No circuit breaker when unset
+    #[serde(default)]
+    pub circuit_breaker: Option<BreakerSettings>,
+}
+
+impl SinkConfig {
+    /// Checks the config for values no sink can work with
+    pub fn validate(&self) -> Result<(), UsageEventError> {
+        match &self.kind {
+            SinkKind::Queue { endpoint, .. } if endpoint.is_empty() => {
+                Err(UsageEventError::Other("Queue sink needs an endpoint".to_string()))
+            }
+            SinkKind::File { path, max_bytes, .. } if path.as_os_str().is_empty() || *max_bytes == 0 => {
+                Err(UsageEventError::Other("File sink needs a path and a max_bytes above 0".to_string()))
+            }
+            SinkKind::Http { url, .. } if !(url.starts_with("http://") || url.starts_with("https://")) => {
+                Err(UsageEventError::Other(format!("HTTP sink url {url} must start with http:// or https://")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn build(&self) -> Result<SinkRoute, UsageEventError> {
+        self.validate()?;
+        let route = match &self.kind {
+            SinkKind::Queue { endpoint, port, sub_topic } => SinkRoute::new(QueueSink::new(endpoint, *port, *sub_topic)),
+            SinkKind::File { path, max_bytes, max_files } => SinkRoute::new(FileSink::new(path, *max_bytes, *max_files)),
+            SinkKind::Http { url, bearer_token, timeout_secs } => {
+                SinkRoute::new(HttpSink::new(url, bearer_token.clone(), Duration::from_secs(*timeout_secs)))
+            }
+        };
+        let route = route.with_retry_config(self.retry.to_config());
+        Ok(match &self.circuit_breaker {
+            Some(settings) => route.with_circuit_breaker(settings.to_config()),
+            None => route,
+        })
+    }
+}
+
+/// Reads a JSON array of `SinkConfig`s and builds their routes
+pub async fn load_sinks(path: &Path) -> Result<Vec<SinkRoute>, UsageEventError> {
+    let configs: Vec<SinkConfig> = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+    configs.iter().map(SinkConfig::build).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publish::EventPublisher;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the messages it is sent, failing while `fail` is set
+    struct RecordingSink {
+        sent: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    impl EventSink for RecordingSink {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn send<'a>(&'a self, _topic: &'a str, _message: &'a serde_json::Value) -> SinkFuture<'a> {
+            Box::pin(async move {
+                self.sent.fetch_add(1, Ordering::SeqCst);
+                if self.fail {
+                    Err(UsageEventError::ConnectionError("down".to_string()))
+                } else {
+                    Ok(())
+                }
+            })
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("form-sinks-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_rotates() {
+        let dir = temp_path("rotate");
+        let _ = std::fs::remove_dir_all(&dir);
+        let sink = FileSink::new(dir.join("events.ndjson"), 60, 2);
+        let message = serde_json::json!({ "n": 1 });
+
+        // Each line is 40 bytes, so every write after the first rotates
+        for _ in 0..4 {
+            sink.send("usage_events", &message).await.unwrap();
+        }
+
+        let current = std::fs::read_to_string(dir.join("events.ndjson")).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(current.trim_end()).unwrap();
+        assert_eq!(line, serde_json::json!({ "topic": "usage_events", "event": message }));
+        assert!(sink.rotated_path(1).exists());
+        assert!(sink.rotated_path(2).exists());
+        assert!(!sink.rotated_path(3).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_isolates_failing_sinks() {
+        let ok_sent = Arc::new(AtomicUsize::new(0));
+        let failing_sent = Arc::new(AtomicUsize::new(0));
+        let publisher = EventPublisher::new()
+            .without_queue()
+            .with_sink(SinkRoute::new(RecordingSink { sent: ok_sent.clone(), fail: false }))
+            .with_sink(
+                SinkRoute::new(RecordingSink { sent: failing_sent.clone(), fail: true })
+                    .with_retry_config(RetryConfig {
+                        max_retries: 1,
+                        initial_backoff: Duration::from_millis(1),
+                        ..RetryConfig::default()
+                    })
+                    .with_circuit_breaker(CircuitBreakerConfig {
+                        failure_threshold: 1,
+                        reset_timeout: Duration::from_secs(60),
+                        half_open_allowed_calls: 1,
+                    }),
+            );
+        let message = serde_json::json!({ "n": 1 });
+
+        assert!(publisher.publish_value("usage_events", message.clone()).await.is_err());
+        assert_eq!(ok_sent.load(Ordering::SeqCst), 1);
+        assert_eq!(failing_sent.load(Ordering::SeqCst), 2);
+
+        // The failing sink's breaker is open now, the other sink still gets events
+        assert!(matches!(
+            publisher.publish_value("usage_events", message).await,
+            Err(UsageEventError::CircuitBreakerOpen)
+        ));
+        assert_eq!(ok_sent.load(Ordering::SeqCst), 2);
+        assert_eq!(failing_sent.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_parse_sink_configs() {
+        let configs: Vec<SinkConfig> = serde_json::from_str(r#"[
+            { "type": "file", "path": "/var/log/formation/usage.ndjson" },
+            {
+                "type": "http",
+                "url": "https://collector.example.com/events",
+                "retry": { "max_retries": 5 },
+                "circuit_breaker": { "failure_threshold": 3 }
+            },
+            { "type": "http", "url": "collector.example.com" }
+        ]"#).unwrap();
+
+        assert_eq!(configs[0].kind, SinkKind::File {
+            path: PathBuf::from("/var/log/formation/usage.ndjson"),
+            max_bytes: DEFAULT_MAX_FILE_BYTES,
+            max_files: DEFAULT_MAX_FILES,
+        });
+        assert!(configs[0].circuit_breaker.is_none());
+        assert_eq!(configs[1].retry.to_config().max_retries, 5);
+        assert_eq!(configs[1].circuit_breaker.as_ref().unwrap().to_config().failure_threshold, 3);
+        assert!(configs[1].build().is_ok());
+        assert!(configs[2].build().is_err());
+    }
+}
@@ -2,6 +2,7 @@ use crate::errors::UsageEventError;
 use crate::events::{UsageEvent, UsageMetrics};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -20,6 +21,14 @@ pub enum ResourceType {
     NetworkIngress,
     /// GPU usage (percentage or absolute seconds)
     Gpu,
+    /// Network egress rate (bytes/sec)
+    NetworkEgressRate,
+    /// Network ingress rate (bytes/sec)
+    NetworkIngressRate,
+    /// Disk read and write throughput (bytes/sec)
+    DiskThroughput,
+    /// Disk read and write operations per second
+    DiskIops,
 }
 
 /// Types of thresholds that can be defined
@@ -36,6 +45,37 @@ pub enum ThresholdType {
     },
 }
 
+/// Which side of the threshold value is a violation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    /// Values above the threshold violate it
+    #[default]
+    Above,
+    /// Values below the threshold violate it, e.g. for minimum throughput
+    Below,
+}
+
+/// How urgent a violation of a threshold is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+/// Who a threshold applies to. A rule for an instance overrides the rules
+/// of its account for the same resource, which override the global ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdScope {
+    Global,
+    Account,
+    Instance,
+}
+
 /// Types of actions to take when a threshold is exceeded
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ActionType {
@@ -51,6 +91,7 @@ pub enum ActionType {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThresholdConfig {
     /// Unique identifier for this threshold
+    #[serde(default)]
     pub id: String,
     
     /// Type of resource to monitor
@@ -73,6 +114,71 @@ pub struct ThresholdConfig {
     
     /// Human-readable description of this threshold
     pub description: Option<String>,
+    
+    /// Whether values above or below the threshold violate it
+    #[serde(default)]
+    pub comparison: Comparison,
+    
+    /// How long, in seconds, the threshold has to be violated before it is
+    /// reported. 0 reports the first violating sample.
+    #[serde(default)]
+    pub duration_secs: u64,
+    
+    /// How urgent a violation is
+    #[serde(default)]
+    pub severity: Severity,
+}
+
+impl ThresholdConfig {
+    /// Who this threshold applies to
+    pub fn scope(&self) -> ThresholdScope {
+        if self.instance_id.is_some() {
+            ThresholdScope::Instance
+        } else if self.user_id != "*" {
+            ThresholdScope::Account
+        } else {
+            ThresholdScope::Global
+        }
+    }
+    
+    /// Whether this threshold applies to the instance of the user
+    pub fn applies_to(&self, instance_id: &str, user_id: &str) -> bool {
+        (self.user_id == "*" || self.user_id == user_id) &&
+            self.instance_id.as_deref().map_or(true, |id| id == instance_id)
+    }
+    
+    /// The value the threshold is set at
+    pub fn threshold_value(&self) -> f64 {
+        match &self.threshold_type {
+            ThresholdType::Absolute { value, .. } => *value,
+            ThresholdType::Percentage { value } => *value,
+        }
+    }
+    
+    /// Rejects rules that could never be evaluated
+    pub fn validate(&self) -> Result<(), UsageEventError> {
+        if self.id.trim().is_empty() {
+            return Err(UsageEventError::InvalidThreshold("id is required".to_string()));
+        }
+        if self.user_id.trim().is_empty() {
+            return Err(UsageEventError::InvalidThreshold("user_id is required, use * for all accounts".to_string()));
+        }
+        let value = self.threshold_value();
+        if !value.is_finite() || value < 0.0 {
+            return Err(UsageEventError::InvalidThreshold(format!("{} is not a valid threshold value", value)));
+        }
+        if matches!(self.threshold_type, ThresholdType::Percentage { .. }) && value > 100.0 {
+            return Err(UsageEventError::InvalidThreshold(format!("{}% is over 100%", value)));
+        }
+        Ok(())
+    }
+    
+    fn is_violated_by(&self, current_value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => current_value > self.threshold_value(),
+            Comparison::Below => current_value < self.threshold_value(),
+        }
+    }
 }
 
 /// Information about a threshold violation
@@ -98,6 +204,10 @@ pub struct ThresholdViolation {
     
     /// User ID associated with the instance
     pub user_id: String,
+    
+    /// Timestamp since when the threshold has been violated
+    #[serde(default)]
+    pub violated_since: i64,
 }
 
 /// Manager for threshold configuration and checking
@@ -108,7 +218,11 @@ pub struct ThresholdManager {
     /// Last time configs were loaded
     last_config_load: Arc<RwLock<i64>>,
     
-    /// Source for loading configs (file path or API URL)
+    /// Since when each threshold has been violated, keyed by threshold
+    /// and instance ID
+    violated_since: Arc<RwLock<HashMap<(String, String), i64>>>,
+    
+    /// File the configs are loaded from and persisted to
     config_source: String,
 }
 
@@ -118,27 +232,51 @@ impl ThresholdManager {
         Self {
             configs: Arc::new(RwLock::new(HashMap::new())),
             last_config_load: Arc::new(RwLock::new(0)),
+            violated_since: Arc::new(RwLock::new(HashMap::new())),
             config_source,
         }
     }
     
-    /// Load configurations from the source
+    /// Load configurations from the source. The source is a JSON file
+    /// holding a list of thresholds; until one has been written, the default
+    /// thresholds are used.
     pub async fn load_configs(&self) -> Result<(), UsageEventError> {
-        // For now, we'll just load a hardcoded set of thresholds
-        // In a real implementation, this would load from a file or API
-        // based on the config_source
-        
         // Log that we're loading from the config source
         println!("Loading threshold configurations from source: {}", self.config_source);
         
+        let loaded = if Path::new(&self.config_source).is_file() {
+            let stored: Vec<ThresholdConfig> = serde_json::from_slice(&tokio::fs::read(&self.config_source).await?)?;
+            for config in &stored {
+                config.validate()?;
+            }
+            stored
+        } else {
+            Self::default_configs()
+        };
+        
         // Get write access to the configs
         let mut configs_lock = self.configs.write().await;
         
         // Clear existing configs
         configs_lock.clear();
         
-        // Create some example thresholds
-        let example_configs = vec![
+        // Add configs to the map
+        for config in loaded {
+            configs_lock.insert(config.id.clone(), config);
+        }
+        
+        // Violations of thresholds that are gone or changed start over
+        self.violated_since.write().await.clear();
+        
+        // Update last load time
+        *self.last_config_load.write().await = chrono::Utc::now().timestamp();
+        
+        Ok(())
+    }
+    
+    /// Thresholds used until any have been configured
+    fn default_configs() -> Vec<ThresholdConfig> {
+        vec![
             ThresholdConfig {
                 id: "cpu-high".to_string(),
                 resource_type: ResourceType::Cpu,
@@ -148,6 +286,9 @@ impl ThresholdManager {
                 instance_id: None,
                 notification_channels: vec!["email".to_string()],
                 description: Some("High CPU usage alert".to_string()),
+                comparison: Comparison::Above,
+                duration_secs: 0,
+                severity: Severity::Warning,
             },
             ThresholdConfig {
                 id: "memory-critical".to_string(),
@@ -158,6 +299,9 @@ impl ThresholdManager {
                 instance_id: None,
                 notification_channels: vec!["email".to_string(), "sms".to_string()],
                 description: Some("Critical memory usage alert".to_string()),
+                comparison: Comparison::Above,
+                duration_secs: 0,
+                severity: Severity::Critical,
             },
             ThresholdConfig {
                 id: "storage-warning".to_string(),
@@ -171,76 +315,158 @@ impl ThresholdManager {
                 instance_id: None,
                 notification_channels: vec!["email".to_string()],
                 description: Some("Storage usage warning".to_string()),
+                comparison: Comparison::Above,
+                duration_secs: 0,
+                severity: Severity::Warning,
             },
-        ];
-        
-        // Add configs to the map
-        for config in example_configs {
-            configs_lock.insert(config.id.clone(), config);
+        ]
+    }
+    
+    /// All thresholds, ordered by ID
+    pub async fn list_configs(&self) -> Vec<ThresholdConfig> {
+        let mut configs: Vec<_> = self.configs.read().await.values().cloned().collect();
+        configs.sort_by(|a, b| a.id.cmp(&b.id));
+        configs
+    }
+    
+    /// The threshold with the given ID, if there is one
+    pub async fn get_config(&self, id: &str) -> Option<ThresholdConfig> {
+        self.configs.read().await.get(id).cloned()
+    }
+    
+    /// Adds or replaces a threshold and persists the thresholds. Returns
+    /// whether the threshold is new. Takes effect with the next check.
+    pub async fn upsert_config(&self, config: ThresholdConfig) -> Result<bool, UsageEventError> {
+        config.validate()?;
+        let mut configs = self.configs.write().await;
+        let id = config.id.clone();
+        let previous = configs.insert(id.clone(), config);
+        if let Err(e) = self.persist(&configs).await {
+            // Keep memory and disk in agreement
+            match previous {
+                Some(previous) => configs.insert(id, previous),
+                None => configs.remove(&id),
+            };
+            return Err(e);
         }
+        self.violated_since.write().await.retain(|(config_id, _), _| *config_id != id);
+        Ok(previous.is_none())
+    }
+    
+    /// Removes a threshold and persists the thresholds
+    pub async fn remove_config(&self, id: &str) -> Result<Option<ThresholdConfig>, UsageEventError> {
+        let mut configs = self.configs.write().await;
+        let Some(removed) = configs.remove(id) else {
+            return Ok(None);
+        };
+        if let Err(e) = self.persist(&configs).await {
+            configs.insert(id.to_string(), removed);
+            return Err(e);
+        }
+        self.violated_since.write().await.retain(|(config_id, _), _| config_id != id);
+        Ok(Some(removed))
+    }
+    
+    /// Writes the thresholds to the config source, replacing it atomically
+    async fn persist(&self, configs: &HashMap<String, ThresholdConfig>) -> Result<(), UsageEventError> {
+        let mut sorted: Vec<_> = configs.values().collect();
+        sorted.sort_by(|a, b| a.id.cmp(&b.id));
+        let path = Path::new(&self.config_source);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(&sorted)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+    
+    /// The thresholds that apply to the instance of the user. For each
+    /// resource, only the most specific scope that has thresholds for it
+    /// applies, so instance rules override account rules, which override
+    /// the global ones.
+    pub async fn effective_configs(&self, instance_id: &str, user_id: &str) -> Vec<ThresholdConfig> {
+        let configs = self.configs.read().await;
+        let applicable: Vec<_> = configs.values()
+            .filter(|config| config.applies_to(instance_id, user_id))
+            .collect();
         
-        // Update last load time
-        *self.last_config_load.write().await = chrono::Utc::now().timestamp();
+        let mut narrowest: HashMap<ResourceType, ThresholdScope> = HashMap::new();
+        for config in &applicable {
+            let scope = narrowest.entry(config.resource_type).or_insert(config.scope());
+            *scope = (*scope).max(config.scope());
+        }
         
-        Ok(())
+        let mut effective: Vec<_> = applicable.into_iter()
+            .filter(|config| narrowest.get(&config.resource_type) == Some(&config.scope()))
+            .cloned()
+            .collect();
+        effective.sort_by(|a, b| a.id.cmp(&b.id));
+        effective
     }
     
-    /// Check if metrics violate any thresholds
+    /// Check if metrics violate any thresholds. A threshold with a duration
+    /// is only reported once it has been violated by every check for that
+    /// long.
     pub async fn check_thresholds(
         &self, 
         metrics: &UsageMetrics,
         instance_id: &str,
         user_id: &str,
     ) -> Result<Vec<ThresholdViolation>, UsageEventError> {
-        let configs = self.configs.read().await;
+        let now = chrono::Utc::now().timestamp();
+        let effective = self.effective_configs(instance_id, user_id).await;
+        let mut violated_since = self.violated_since.write().await;
         let mut violations = Vec::new();
         
-        // Iterate through all configs
-        for (_, config) in configs.iter() {
-            // Check if this config applies to this instance/user
-            if (config.user_id == "*" || config.user_id == user_id) &&
-               (config.instance_id.is_none() || config.instance_id.as_ref().unwrap() == instance_id) {
-                
-                // Get the current value for this resource type
-                let current_value = match config.resource_type {
-                    ResourceType::Cpu => metrics.cpu_percent_avg,
-                    ResourceType::Memory => {
-                        match &config.threshold_type {
-                            ThresholdType::Absolute { .. } => metrics.memory_gb,
-                            ThresholdType::Percentage { .. } => metrics.memory_percent,
-                        }
-                    },
-                    ResourceType::Storage => metrics.storage_gb,
-                    ResourceType::NetworkEgress => metrics.network_egress_mb,
-                    ResourceType::NetworkIngress => metrics.network_ingress_mb,
-                    ResourceType::Gpu => metrics.gpu_seconds as f64,
-                };
-                
-                // Get threshold value
-                let threshold_value = match &config.threshold_type {
-                    ThresholdType::Absolute { value, .. } => *value,
-                    ThresholdType::Percentage { value } => *value,
-                };
-                
-                // Check if threshold is exceeded
-                if current_value > threshold_value {
-                    // Calculate percentage over threshold
-                    let percentage = (current_value - threshold_value) / threshold_value * 100.0;
-                    
-                    // Create violation
-                    let violation = ThresholdViolation {
-                        config: config.clone(),
-                        current_value,
-                        threshold_value,
-                        percentage,
-                        timestamp: chrono::Utc::now().timestamp(),
-                        instance_id: instance_id.to_string(),
-                        user_id: user_id.to_string(),
-                    };
-                    
-                    violations.push(violation);
-                }
+        for config in effective {
+            // Get the current value for this resource type
+            let current_value = match config.resource_type {
+                ResourceType::Cpu => metrics.cpu_percent_avg,
+                ResourceType::Memory => {
+                    match &config.threshold_type {
+                        ThresholdType::Absolute { .. } => metrics.memory_gb,
+                        ThresholdType::Percentage { .. } => metrics.memory_percent,
+                    }
+                },
+                ResourceType::Storage => metrics.storage_gb,
+                ResourceType::NetworkEgress => metrics.network_egress_mb,
+                ResourceType::NetworkIngress => metrics.network_ingress_mb,
+                ResourceType::Gpu => metrics.gpu_seconds as f64,
+                ResourceType::NetworkEgressRate => metrics.network_egress_bytes_per_sec as f64,
+                ResourceType::NetworkIngressRate => metrics.network_ingress_bytes_per_sec as f64,
+                ResourceType::DiskThroughput => (metrics.disk_read_bytes_per_sec + metrics.disk_write_bytes_per_sec) as f64,
+                ResourceType::DiskIops => metrics.disk_iops as f64,
+            };
+            
+            let key = (config.id.clone(), instance_id.to_string());
+            if !config.is_violated_by(current_value) {
+                violated_since.remove(&key);
+                continue;
+            }
+            let since = *violated_since.entry(key).or_insert(now);
+            if now - since < config.duration_secs as i64 {
+                continue;
             }
+            
+            // Calculate how far past the threshold the value is
+            let threshold_value = config.threshold_value();
+            let percentage = if threshold_value == 0.0 {
+                0.0
+            } else {
+                (current_value - threshold_value).abs() * 100.0 / threshold_value
+            };
+            
+            violations.push(ThresholdViolation {
+                config,
+                current_value,
+                threshold_value,
+                percentage,
+                timestamp: now,
+                instance_id: instance_id.to_string(),
+                user_id: user_id.to_string(),
+                violated_since: since,
+            });
         }
         
         Ok(violations)
@@ -265,6 +491,10 @@ impl ThresholdManager {
                             ResourceType::NetworkEgress => "Network Egress",
                             ResourceType::NetworkIngress => "Network Ingress",
                             ResourceType::Gpu => "GPU",
+                            ResourceType::NetworkEgressRate => "Network Egress Rate",
+                            ResourceType::NetworkIngressRate => "Network Ingress Rate",
+                            ResourceType::DiskThroughput => "Disk Throughput",
+                            ResourceType::DiskIops => "Disk IOPS",
                         },
                         violation.percentage
                     );
@@ -282,6 +512,10 @@ impl ThresholdManager {
                             ResourceType::NetworkEgress => "Network Egress",
                             ResourceType::NetworkIngress => "Network Ingress",
                             ResourceType::Gpu => "GPU",
+                            ResourceType::NetworkEgressRate => "Network Egress Rate",
+                            ResourceType::NetworkIngressRate => "Network Ingress Rate",
+                            ResourceType::DiskThroughput => "Disk Throughput",
+                            ResourceType::DiskIops => "Disk IOPS",
                         },
                         violation.percentage,
                         violation.config.notification_channels
@@ -300,6 +534,10 @@ impl ThresholdManager {
                             ResourceType::NetworkEgress => "Network Egress",
                             ResourceType::NetworkIngress => "Network Ingress",
                             ResourceType::Gpu => "GPU",
+                            ResourceType::NetworkEgressRate => "Network Egress Rate",
+                            ResourceType::NetworkIngressRate => "Network Ingress Rate",
+                            ResourceType::DiskThroughput => "Disk Throughput",
+                            ResourceType::DiskIops => "Disk IOPS",
                         },
                         violation.percentage,
                         action
@@ -311,8 +549,8 @@ impl ThresholdManager {
         Ok(())
     }
     
-    /// Check thresholds for a usage event
-    pub async fn check_event(&self, event: &UsageEvent) -> Result<(), UsageEventError> {
+    /// Check thresholds for a usage event, returning the violations found
+    pub async fn check_event(&self, event: &UsageEvent) -> Result<Vec<ThresholdViolation>, UsageEventError> {
         // Check thresholds for the event
         let violations = self.check_thresholds(
             &event.metrics,
@@ -322,10 +560,10 @@ impl ThresholdManager {
         
         // Process any violations
         if !violations.is_empty() {
-            self.process_violations(violations).await?;
+            self.process_violations(violations.clone()).await?;
         }
         
-        Ok(())
+        Ok(violations)
     }
 }
 
@@ -361,6 +599,12 @@ mod tests {
             network_egress_mb: 100.0,
             network_ingress_mb: 50.0,
             gpu_seconds: 0,
+            disk_read_bytes_per_sec: 0,
+            disk_write_bytes_per_sec: 0,
+            disk_iops: 0,
+            network_egress_bytes_per_sec: 0,
+            network_ingress_bytes_per_sec: 0,
+            gpus: Vec::new(),
         };
         
         let violations = manager.check_thresholds(
@@ -390,6 +634,12 @@ mod tests {
             network_egress_mb: 100.0,
             network_ingress_mb: 50.0,
             gpu_seconds: 0,
+            disk_read_bytes_per_sec: 0,
+            disk_write_bytes_per_sec: 0,
+            disk_iops: 0,
+            network_egress_bytes_per_sec: 0,
+            network_ingress_bytes_per_sec: 0,
+            gpus: Vec::new(),
         };
         
         let violations = manager.check_thresholds(
@@ -433,6 +683,12 @@ mod tests {
                 network_egress_mb: 100.0,
                 network_ingress_mb: 50.0,
                 gpu_seconds: 0,
+                disk_read_bytes_per_sec: 0,
+                disk_write_bytes_per_sec: 0,
+                disk_iops: 0,
+                network_egress_bytes_per_sec: 0,
+                network_ingress_bytes_per_sec: 0,
+                gpus: Vec::new(),
             },
             period: UsagePeriod {
                 start: chrono::Utc::now().timestamp() - 30,
@@ -443,4 +699,109 @@ mod tests {
         // Should not error
         manager.check_event(&event).await.unwrap();
     }
-} 
\ No newline at end of file
+    
+    fn rule(id: &str, resource_type: ResourceType, value: f64, user_id: &str, instance_id: Option<&str>) -> ThresholdConfig {
+        ThresholdConfig {
+            id: id.to_string(),
+            resource_type,
+            threshold_type: ThresholdType::Percentage { value },
+            action: ActionType::Log,
+            user_id: user_id.to_string(),
+            instance_id: instance_id.map(String::from),
+            notification_channels: vec![],
+            description: None,
+            comparison: Comparison::Above,
+            duration_secs: 0,
+            severity: Severity::Warning,
+        }
+    }
+    
+    fn temp_source(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("form-thresholds-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+    
+    #[tokio::test]
+    async fn test_instance_and_account_overrides() {
+        let source = temp_source("overrides");
+        let manager = ThresholdManager::new(source.clone());
+        manager.load_configs().await.unwrap();
+        
+        manager.upsert_config(rule("account-cpu", ResourceType::Cpu, 50.0, "alice", None)).await.unwrap();
+        manager.upsert_config(rule("vm-cpu", ResourceType::Cpu, 99.0, "*", Some("vm-1"))).await.unwrap();
+        
+        let ids = |configs: Vec<ThresholdConfig>| configs.into_iter().map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(ids(manager.effective_configs("vm-1", "alice").await), vec!["memory-critical", "storage-warning", "vm-cpu"]);
+        assert_eq!(ids(manager.effective_configs("vm-2", "alice").await), vec!["account-cpu", "memory-critical", "storage-warning"]);
+        assert_eq!(ids(manager.effective_configs("vm-2", "bob").await), vec!["cpu-high", "memory-critical", "storage-warning"]);
+        
+        // The rules were persisted and survive a restart
+        let restarted = ThresholdManager::new(source.clone());
+        restarted.load_configs().await.unwrap();
+        assert_eq!(restarted.list_configs().await.len(), 5);
+        assert!(restarted.remove_config("vm-cpu").await.unwrap().is_some());
+        assert!(restarted.remove_config("vm-cpu").await.unwrap().is_none());
+        
+        let mut invalid = rule("bad", ResourceType::Cpu, 150.0, "*", None);
+        assert!(restarted.upsert_config(invalid.clone()).await.is_err());
+        invalid.id = String::new();
+        assert!(restarted.upsert_config(invalid).await.is_err());
+        
+        let _ = std::fs::remove_file(&source);
+    }
+    
+    #[tokio::test]
+    async fn test_comparison_and_duration() {
+        let source = temp_source("duration");
+        let manager = ThresholdManager::new(source.clone());
+        
+        let mut low_egress = rule("low-egress", ResourceType::NetworkEgressRate, 1000.0, "*", None);
+        low_egress.threshold_type = ThresholdType::Absolute { value: 1000.0, unit: "B/s".to_string() };
+        low_egress.comparison = Comparison::Below;
+        manager.upsert_config(low_egress).await.unwrap();
+        
+        let mut sustained = rule("sustained-cpu", ResourceType::Cpu, 80.0, "*", None);
+        sustained.duration_secs = 300;
+        sustained.severity = Severity::Critical;
+        manager.upsert_config(sustained).await.unwrap();
+        
+        let metrics = UsageMetrics {
+            cpu_seconds: 30,
+            cpu_percent_avg: 95.0,
+            memory_gb: 1.0,
+            memory_percent: 10.0,
+            storage_gb: 1.0,
+            network_egress_mb: 0.0,
+            network_ingress_mb: 0.0,
+            gpu_seconds: 0,
+            disk_read_bytes_per_sec: 0,
+            disk_write_bytes_per_sec: 0,
+            disk_iops: 0,
+            network_egress_bytes_per_sec: 100,
+            network_ingress_bytes_per_sec: 0,
+            gpus: Vec::new(),
+        };
+        
+        // The CPU rule has to be violated for 5 minutes first
+        let violations = manager.check_thresholds(&metrics, "vm", "alice").await.unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].config.id, "low-egress");
+        assert_eq!(violations[0].percentage, 90.0);
+        
+        let started = chrono::Utc::now().timestamp() - 301;
+        manager.violated_since.write().await.insert(("sustained-cpu".to_string(), "vm".to_string()), started);
+        let violations = manager.check_thresholds(&metrics, "vm", "alice").await.unwrap();
+        let sustained = violations.iter().find(|v| v.config.id == "sustained-cpu").unwrap();
+        assert_eq!(sustained.config.severity, Severity::Critical);
+        assert_eq!(sustained.violated_since, started);
+        
+        // A sample under the threshold resets the clock
+        let calm = UsageMetrics { cpu_percent_avg: 10.0, ..metrics.clone() };
+        manager.check_thresholds(&calm, "vm", "alice").await.unwrap();
+        let violations = manager.check_thresholds(&metrics, "vm", "alice").await.unwrap();
+        assert!(violations.iter().all(|v| v.config.id != "sustained-cpu"));
+        
+        let _ = std::fs::remove_file(&source);
+    }
+}
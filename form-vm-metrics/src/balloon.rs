@@ -0,0 +1,52 @@
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+
+/// Present when the host attached a virtio-balloon device to this VM
+const BALLOON_DRIVER_PATH: &str = "/sys/bus/virtio/drivers/virtio_balloon";
+const PAGE_SIZE: u64 = 4096;
+
+/// Memory the host has reclaimed from this VM through the virtio balloon
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BalloonMetrics {
+    /// Whether the guest has a balloon device bound
+    pub present: bool,
+    /// Bytes currently held by the balloon and unavailable to the guest
+    pub inflated_bytes: u64,
+    /// Pages the balloon has taken since boot
+    pub inflated_pages_total: u64,
+    /// Pages the balloon has returned since boot
+    pub deflated_pages_total: u64,
+}
+
+/// Reads the balloon counters from `/proc/vmstat` contents
+pub fn parse_vmstat(vmstat: &str) -> (u64, u64) {
+    let mut inflated = 0;
+    let mut deflated = 0;
+    for line in vmstat.lines() {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next().and_then(|v| v.parse::<u64>().ok())) {
+            (Some("balloon_inflate"), Some(value)) => inflated = value,
+            (Some("balloon_deflate"), Some(value)) => deflated = value,
+            _ => {}
+        }
+    }
+    (inflated, deflated)
+}
+
+pub fn collect_balloon_metrics() -> BalloonMetrics {
+    let present = Path::new(BALLOON_DRIVER_PATH).exists();
+    if !present {
+        return BalloonMetrics::default();
+    }
+
+    let (inflated, deflated) = std::fs::read_to_string("/proc/vmstat")
+        .map(|vmstat| parse_vmstat(&vmstat))
+        .unwrap_or_default();
+
+    BalloonMetrics {
+        present,
+        inflated_bytes: inflated.saturating_sub(deflated) * PAGE_SIZE,
+        inflated_pages_total: inflated,
+        deflated_pages_total: deflated,
+    }
+}
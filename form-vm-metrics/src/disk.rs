@@ -2,7 +2,11 @@
 use procfs::diskstats;
 use serde::{Serialize, Deserialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Size of a sector in `/proc/diskstats`, regardless of the device's own
+/// sector size
+pub const DISKSTATS_SECTOR_SIZE: u64 = 512;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct DiskMetrics {
     pub device_name: String,
     pub reads_completed: u64,
@@ -16,6 +20,30 @@ pub struct DiskMetrics {
     pub io_in_progress: u64,
     pub time_doing_io: u64,
     pub weighted_time_doing_io: u64,
+    /// Rates over the last collection interval, 0 on the first collection
+    #[serde(default)]
+    pub read_bytes_per_sec: u64,
+    #[serde(default)]
+    pub write_bytes_per_sec: u64,
+    #[serde(default)]
+    pub read_iops: u64,
+    #[serde(default)]
+    pub write_iops: u64,
+}
+
+impl DiskMetrics {
+    /// Fills in the rates from the counters of the previous collection,
+    /// `elapsed` seconds ago. Counters that went backwards (device reset)
+    /// give a rate of 0.
+    pub fn compute_rates(&mut self, previous: &DiskMetrics, elapsed: u64) {
+        if elapsed == 0 {
+            return;
+        }
+        self.read_bytes_per_sec = self.sectors_read.saturating_sub(previous.sectors_read) * DISKSTATS_SECTOR_SIZE / elapsed;
+        self.write_bytes_per_sec = self.sectors_written.saturating_sub(previous.sectors_written) * DISKSTATS_SECTOR_SIZE / elapsed;
+        self.read_iops = self.reads_completed.saturating_sub(previous.reads_completed) / elapsed;
+        self.write_iops = self.writes_completed.saturating_sub(previous.writes_completed) / elapsed;
+    }
 }
 
 pub fn collect_disk_metrics() -> Vec<DiskMetrics> {
@@ -35,6 +63,7 @@ pub fn collect_disk_metrics() -> Vec<DiskMetrics> {
                 io_in_progress: stat.in_progress,
                 time_doing_io: stat.time_in_progress,
                 weighted_time_doing_io: stat.weighted_time_in_progress,
+                ..Default::default()
             }).collect(),
             Err(_) => Vec::new(),
         }
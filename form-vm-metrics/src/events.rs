@@ -1,8 +1,10 @@
 use form_usage_events::{
-    events::{UsageEvent, UsageMetrics, UsagePeriod},
+    attestation::UsageSigner,
+    events::{GpuUsage, UsageEvent, UsageMetrics, UsagePeriod},
     publish::EventPublisher,
     circuit_breaker::CircuitBreakerConfig,
-    threshold::ThresholdManager,
+    sinks::SinkRoute,
+    threshold::{ThresholdManager, ThresholdViolation},
 };
 
 use crate::system::SystemMetrics;
@@ -11,6 +13,10 @@ use std::sync::Arc;
 #[derive(Clone)]
 pub struct MetricsPublisher {
     publisher: EventPublisher,
+    /// Thresholds the metrics are checked against. Kept here rather than on
+    /// the publisher so violations reach the webhooks and the rules can be
+    /// edited through the API.
+    thresholds: Option<Arc<ThresholdManager>>,
 }
 
 impl MetricsPublisher {
@@ -18,6 +24,7 @@ impl MetricsPublisher {
     pub fn new() -> Self {
         Self {
             publisher: EventPublisher::new().with_default_circuit_breaker(),
+            thresholds: None,
         }
     }
     
@@ -26,6 +33,7 @@ impl MetricsPublisher {
         Self {
             publisher: EventPublisher::with_config(endpoint, port, topic, sub_topic)
                 .with_default_circuit_breaker(),
+            thresholds: None,
         }
     }
     
@@ -40,6 +48,7 @@ impl MetricsPublisher {
         Self {
             publisher: EventPublisher::with_config(endpoint, port, topic, sub_topic)
                 .with_circuit_breaker(circuit_breaker_config),
+            thresholds: None,
         }
     }
     
@@ -48,21 +57,51 @@ impl MetricsPublisher {
         mut self,
         config_source: String,
     ) -> Result<Self, String> {
-        // Create a threshold manager and add it to the publisher
-        self.publisher = self.publisher
-            .with_new_threshold_manager(config_source)
+        // Create a threshold manager and load the persisted rules
+        let manager = Arc::new(ThresholdManager::new(config_source));
+        manager.load_configs()
             .await
             .map_err(|e| format!("Failed to create threshold manager: {}", e))?;
+        self.thresholds = Some(manager);
             
         Ok(self)
     }
     
+    /// Signs published metrics with the operator key of the node hosting
+    /// the instance, which form-state requires before billing for them
+    pub fn with_signer(mut self, signer: UsageSigner) -> Self {
+        self.publisher = self.publisher.with_signer(signer);
+        self
+    }
+    
+    /// Delivers metrics to extra sinks besides the message queue
+    pub fn with_sinks(mut self, sinks: Vec<SinkRoute>) -> Self {
+        self.publisher = self.publisher.with_sinks(sinks);
+        self
+    }
+    
     /// Adds a pre-configured threshold manager to the metrics publisher
     pub fn with_threshold_manager(mut self, manager: Arc<ThresholdManager>) -> Self {
-        self.publisher = self.publisher.with_threshold_manager(manager);
+        self.thresholds = Some(manager);
         self
     }
     
+    /// The threshold manager, if threshold detection is enabled
+    pub fn threshold_manager(&self) -> Option<Arc<ThresholdManager>> {
+        self.thresholds.clone()
+    }
+    
+    /// Checks metrics against the thresholds that apply to the instance
+    pub async fn check_thresholds(&self, metrics: &SystemMetrics) -> Result<Vec<ThresholdViolation>, String> {
+        let Some(manager) = &self.thresholds else {
+            return Ok(vec![]);
+        };
+        let usage_event = self.metrics_to_event(metrics)?;
+        manager.check_event(&usage_event)
+            .await
+            .map_err(|e| format!("Failed to check thresholds: {}", e))
+    }
+    
     /// Publishes metrics to the message queue
     pub async fn publish_metrics(&self, metrics: &SystemMetrics) -> Result<(), String> {
         if metrics.instance_id.is_none() || metrics.account_id.is_none() {
@@ -117,12 +156,25 @@ impl MetricsPublisher {
             network_ingress_mb += interface.bytes_received as f64 / 1024.0 / 1024.0;
         }
         
-        // Calculate GPU metrics
-        let mut gpu_seconds = 0;
-        for gpu in &metrics.gpus {
-            // utilization_bps is in basis points (0-10000), need to convert to percentage
-            gpu_seconds += (30.0 * (gpu.utilization_bps as f64 / 10000.0)) as u64;
-        }
+        // Calculate GPU metrics. Busy time is summed before rounding so
+        // lightly used GPUs still add up to billable seconds.
+        let period_seconds = (end_time - start_time) as u64;
+        let gpus: Vec<GpuUsage> = metrics.gpus.iter().map(|gpu| GpuUsage {
+            index: gpu.index as u32,
+            model: gpu.model.clone(),
+            uuid: gpu.uuid.clone(),
+            attached_seconds: period_seconds,
+            // utilization_bps is in basis points (0-10000)
+            utilization_percent: gpu.utilization_bps as f64 / 100.0,
+            memory_used_mb: gpu.memory_used_bytes as f64 / 1024.0 / 1024.0,
+            memory_total_mb: gpu.memory_total_bytes as f64 / 1024.0 / 1024.0,
+            temperature_c: gpu.temperature_deci_c as f64 / 10.0,
+            power_watts: gpu.power_draw_deci_w as f64 / 10.0,
+        }).collect();
+        let gpu_seconds = gpus.iter()
+            .map(|gpu| gpu.attached_seconds as f64 * gpu.utilization_percent / 100.0)
+            .sum::<f64>()
+            .round() as u64;
         
         // Create the usage event
         let event = UsageEvent {
@@ -141,6 +193,12 @@ impl MetricsPublisher {
                 network_egress_mb,
                 network_ingress_mb,
                 gpu_seconds,
+                disk_read_bytes_per_sec: metrics.disk_read_bytes_per_sec(),
+                disk_write_bytes_per_sec: metrics.disk_write_bytes_per_sec(),
+                disk_iops: metrics.disk_iops(),
+                network_egress_bytes_per_sec: metrics.network_tx_bytes_per_sec(),
+                network_ingress_bytes_per_sec: metrics.network_rx_bytes_per_sec(),
+                gpus,
             },
             period: UsagePeriod {
                 start: start_time,
@@ -187,6 +245,10 @@ mod tests {
             io_in_progress: 10,
             time_doing_io: 200,
             weighted_time_doing_io: 300,
+            read_bytes_per_sec: 4096,
+            write_bytes_per_sec: 8192,
+            read_iops: 1,
+            write_iops: 2,
         }];
         
         // Add a test network interface
@@ -202,6 +264,10 @@ mod tests {
                 drops_in: 0,
                 drops_out: 0,
                 speed: 1000 * 1000 * 1000, // 1 Gbps
+                tx_bytes_per_sec: 1024,
+                rx_bytes_per_sec: 2048,
+                tx_packets_per_sec: 1,
+                rx_packets_per_sec: 2,
             }],
         };
         
@@ -211,8 +277,11 @@ mod tests {
             model: "Test GPU".to_string(),
             utilization_bps: 5000, // 50% in basis points (0-10000)
             memory_usage_bps: 5000, // 50% in basis points
+            memory_used_bytes: 8 * 1024 * 1024 * 1024,
+            memory_total_bytes: 16 * 1024 * 1024 * 1024,
             temperature_deci_c: 700, // 70.0°C
             power_draw_deci_w: 1500, // 150.0W
+            ..Default::default()
         }];
         
         // Set load metrics
@@ -243,6 +312,35 @@ mod tests {
             // This is expected since our test metrics lack CPU and Memory data
             assert!(e.contains("Missing") || e.contains("division by zero") || e.contains("NaN"));
         }
+
+        // Rates are summed over all disks and interfaces
+        if let Ok(event) = publisher.metrics_to_event(&system_metrics) {
+            assert_eq!(event.metrics.disk_read_bytes_per_sec, 4096);
+            assert_eq!(event.metrics.disk_iops, 3);
+            assert_eq!(event.metrics.network_egress_bytes_per_sec, 1024);
+            assert_eq!(event.metrics.network_ingress_bytes_per_sec, 2048);
+            assert_eq!(event.metrics.gpu_seconds, 15);
+            assert_eq!(event.metrics.gpus.len(), 1);
+            assert_eq!(event.metrics.gpus[0].attached_seconds, 30);
+            assert_approx_eq(event.metrics.gpus[0].memory_used_mb, 8192.0, 0.001);
+            assert_approx_eq(event.metrics.gpus[0].power_watts, 150.0, 0.001);
+        }
+    }
+
+    #[test]
+    fn test_light_gpu_use_is_billed() {
+        let mut metrics = create_test_metrics();
+        metrics.gpus = (0..3).map(|index| GpuMetrics {
+            index,
+            model: "Test GPU".to_string(),
+            utilization_bps: 200, // 2%, 0.6 seconds each
+            ..Default::default()
+        }).collect();
+
+        if let Ok(event) = MetricsPublisher::new().metrics_to_event(&metrics) {
+            assert_eq!(event.metrics.gpu_seconds, 2);
+            assert_eq!(event.metrics.gpus.iter().map(|gpu| gpu.attached_seconds).sum::<u64>(), 90);
+        }
     }
     
     fn assert_approx_eq(a: f64, b: f64, epsilon: f64) {
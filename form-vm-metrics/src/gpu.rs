@@ -1,40 +1,221 @@
+use std::path::Path;
+use std::sync::OnceLock;
 use nvml_wrapper::{enum_wrappers::device::TemperatureSensor, Nvml};
 use serde::{Serialize, Deserialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Where the DRM devices of the guest are listed
+pub const DRM_CLASS_DIR: &str = "/sys/class/drm";
+
+const NVIDIA_VENDOR_ID: &str = "0x10de";
+
+/// How a GPU's metrics were read
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum GpuSource {
+    /// NVIDIA management library
+    #[default]
+    Nvml,
+    /// The DRM driver's sysfs attributes, for AMD, Intel and NVIDIA GPUs NVML can't reach
+    Sysfs,
+}
+
+/// One GPU attached to the instance. Values a driver doesn't expose are 0.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct GpuMetrics {
     pub index: usize,
     pub model: String,
+    /// PCI vendor id, e.g. `0x10de`
+    #[serde(default)]
+    pub vendor_id: String,
+    /// NVML UUID, or the PCI address for GPUs read from sysfs
+    #[serde(default)]
+    pub uuid: Option<String>,
     pub utilization_bps: u32, // GPU utilization in basis points (0-10000)
     pub memory_usage_bps: u32, // GPU memory usage in basis points (0-10000)
+    #[serde(default)]
+    pub memory_used_bytes: u64,
+    #[serde(default)]
+    pub memory_total_bytes: u64,
     pub temperature_deci_c: u32, // Temperature in 0.1°C units
     pub power_draw_deci_w: u32, // Power draw in 0.1W units
+    #[serde(default)]
+    pub source: GpuSource,
 }
 
-pub async fn collect_gpu_metrics() -> Result<Vec<GpuMetrics>, Box<dyn std::error::Error>> {
-    let nvml = Nvml::init()?;
+fn usage_bps(used: u64, total: u64) -> u32 {
+    if total == 0 {
+        return 0;
+    }
+    ((used as f64 / total as f64) * 10000.0).min(10000.0) as u32
+}
+
+/// NVML is loaded once, a guest without the NVIDIA driver never has it
+fn nvml() -> Option<&'static Nvml> {
+    static NVML: OnceLock<Option<Nvml>> = OnceLock::new();
+    NVML.get_or_init(|| Nvml::init().ok()).as_ref()
+}
+
+/// Every GPU NVML can see. A device that fails to answer is left out rather
+/// than failing the collection.
+pub fn collect_nvml_metrics(nvml: &Nvml) -> Result<Vec<GpuMetrics>, Box<dyn std::error::Error>> {
     let device_count = nvml.device_count()?;
     let mut gpus = Vec::new();
 
     for i in 0..device_count {
-        let device = nvml.device_by_index(i)?;
-        let index = i as usize;
-        let model = device.name()?;
-        let utilization = device.utilization_rates()?.gpu;
-        let memory_info = device.memory_info()?;
-        let memory_usage = ((memory_info.used as f64 / memory_info.total as f64) * 10000.0) as u32;
-        let temperature = device.temperature(TemperatureSensor::Gpu)? * 10; // Deci-degrees
-        let power_draw = (device.power_usage()? as f32 / 100.0) as u32;     // Deci-watts
-
-        let gpu = GpuMetrics {
+        let device = match nvml.device_by_index(i) {
+            Ok(device) => device,
+            Err(e) => {
+                eprintln!("Failed to open GPU {i} through NVML: {e}");
+                continue;
+            }
+        };
+        let utilization = device.utilization_rates().map(|rates| rates.gpu).unwrap_or(0);
+        let (memory_used_bytes, memory_total_bytes) = device.memory_info()
+            .map(|info| (info.used, info.total))
+            .unwrap_or((0, 0));
+
+        gpus.push(GpuMetrics {
+            index: i as usize,
+            model: device.name().unwrap_or_default(),
+            vendor_id: NVIDIA_VENDOR_ID.to_string(),
+            uuid: device.uuid().ok(),
+            utilization_bps: utilization.min(100) * 100, // Convert to basis points
+            memory_usage_bps: usage_bps(memory_used_bytes, memory_total_bytes),
+            memory_used_bytes,
+            memory_total_bytes,
+            temperature_deci_c: device.temperature(TemperatureSensor::Gpu).unwrap_or(0) * 10, // Deci-degrees
+            power_draw_deci_w: device.power_usage().unwrap_or(0) / 100, // Milliwatts to deci-watts
+            source: GpuSource::Nvml,
+        });
+    }
+    Ok(gpus)
+}
+
+fn read_attr(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    read_attr(path)?.parse().ok()
+}
+
+/// First value of a hwmon attribute across the device's hwmon directories
+fn read_hwmon(device: &Path, attributes: &[&str]) -> Option<u64> {
+    let mut dirs: Vec<_> = std::fs::read_dir(device.join("hwmon")).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    dirs.sort();
+    dirs.iter().find_map(|dir| attributes.iter().find_map(|attribute| read_number(&dir.join(attribute))))
+}
+
+fn vendor_name(vendor_id: &str) -> &'static str {
+    match vendor_id {
+        "0x1002" => "AMD",
+        NVIDIA_VENDOR_ID => "NVIDIA",
+        "0x8086" => "Intel",
+        _ => "GPU",
+    }
+}
+
+/// Reads the GPUs listed under a DRM class directory. Busy percentage, VRAM
+/// and hwmon readings are what amdgpu exposes; other drivers report fewer
+/// of them.
+pub fn collect_sysfs_metrics(drm_dir: &Path) -> Vec<GpuMetrics> {
+    let mut cards: Vec<(usize, std::path::PathBuf)> = std::fs::read_dir(drm_dir)
+        .map(|entries| entries.filter_map(|entry| {
+            let entry = entry.ok()?;
+            let index = entry.file_name().to_str()?.strip_prefix("card")?.parse().ok()?;
+            Some((index, entry.path()))
+        }).collect())
+        .unwrap_or_default();
+    cards.sort();
+
+    cards.into_iter().filter_map(|(index, card)| {
+        let device = card.join("device");
+        let vendor_id = read_attr(&device.join("vendor"))?;
+        let device_id = read_attr(&device.join("device")).unwrap_or_default();
+        let memory_used_bytes = read_number(&device.join("mem_info_vram_used")).unwrap_or(0);
+        let memory_total_bytes = read_number(&device.join("mem_info_vram_total")).unwrap_or(0);
+        let uuid = std::fs::canonicalize(&device).ok()
+            .and_then(|path| path.file_name().and_then(|name| name.to_str()).map(str::to_string));
+        let model = read_attr(&device.join("product_name"))
+            .unwrap_or_else(|| format!("{} {device_id}", vendor_name(&vendor_id)));
+
+        Some(GpuMetrics {
             index,
             model,
-            utilization_bps: utilization * 100, // Convert to basis points
-            memory_usage_bps: memory_usage,
-            temperature_deci_c: temperature,
-            power_draw_deci_w: power_draw,
-        };
+            uuid,
+            utilization_bps: read_number(&device.join("gpu_busy_percent")).unwrap_or(0).min(100) as u32 * 100,
+            memory_usage_bps: usage_bps(memory_used_bytes, memory_total_bytes),
+            memory_used_bytes,
+            memory_total_bytes,
+            // Millidegrees and microwatts
+            temperature_deci_c: (read_hwmon(&device, &["temp1_input"]).unwrap_or(0) / 100) as u32,
+            power_draw_deci_w: (read_hwmon(&device, &["power1_average", "power1_input"]).unwrap_or(0) / 100_000) as u32,
+            source: GpuSource::Sysfs,
+            vendor_id,
+        })
+    }).collect()
+}
+
+/// Collects the GPUs attached to the instance through NVML, and every other
+/// GPU through sysfs. NVIDIA GPUs are only read from sysfs when NVML isn't
+/// available.
+pub async fn collect_gpu_metrics() -> Result<Vec<GpuMetrics>, Box<dyn std::error::Error>> {
+    let mut gpus = match nvml() {
+        Some(nvml) => collect_nvml_metrics(nvml)?,
+        None => Vec::new(),
+    };
+    let nvml_available = !gpus.is_empty();
+    let next_index = gpus.len();
+    let others = collect_sysfs_metrics(Path::new(DRM_CLASS_DIR)).into_iter()
+        .filter(|gpu| !(nvml_available && gpu.vendor_id == NVIDIA_VENDOR_ID));
+    for (offset, mut gpu) in others.enumerate() {
+        gpu.index = next_index + offset;
         gpus.push(gpu);
     }
     Ok(gpus)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sysfs_metrics() {
+        let root = std::env::temp_dir().join(format!("form-vm-metrics-gpu-{}", std::process::id()));
+        let amd = root.join("card0/device");
+        let intel = root.join("card1/device");
+        std::fs::create_dir_all(amd.join("hwmon/hwmon2")).unwrap();
+        std::fs::create_dir_all(&intel).unwrap();
+        std::fs::create_dir_all(root.join("card0-DP-1")).unwrap();
+        for (path, value) in [
+            (amd.join("vendor"), "0x1002\n"),
+            (amd.join("device"), "0x744c\n"),
+            (amd.join("gpu_busy_percent"), "37\n"),
+            (amd.join("mem_info_vram_used"), "4294967296\n"),
+            (amd.join("mem_info_vram_total"), "17179869184\n"),
+            (amd.join("hwmon/hwmon2/temp1_input"), "65500\n"),
+            (amd.join("hwmon/hwmon2/power1_average"), "212000000\n"),
+            (intel.join("vendor"), "0x8086\n"),
+            (intel.join("device"), "0x56a0\n"),
+        ] {
+            std::fs::write(path, value).unwrap();
+        }
+
+        let gpus = collect_sysfs_metrics(&root);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(gpus.len(), 2);
+        assert_eq!(gpus[0].model, "AMD 0x744c");
+        assert_eq!(gpus[0].utilization_bps, 3700);
+        assert_eq!(gpus[0].memory_usage_bps, 2500);
+        assert_eq!(gpus[0].memory_total_bytes, 16 * 1024 * 1024 * 1024);
+        assert_eq!(gpus[0].temperature_deci_c, 655);
+        assert_eq!(gpus[0].power_draw_deci_w, 2120);
+        assert_eq!(gpus[0].source, GpuSource::Sysfs);
+        assert_eq!(gpus[1].index, 1);
+        assert_eq!(gpus[1].model, "Intel 0x56a0");
+        assert_eq!(gpus[1].utilization_bps, 0);
+    }
+}
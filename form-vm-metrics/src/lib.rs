@@ -4,5 +4,6 @@ pub mod disk;
 pub mod network;
 pub mod gpu;
 pub mod load;
+pub mod balloon;
 pub mod system;
 pub mod events;
@@ -1,7 +1,8 @@
 use std::{sync::Arc, time::{Duration, Instant}, collections::HashMap};
 
-use axum::{extract::State, routing::{get, post}, Json, Router};
+use axum::{extract::{Path, Query, State}, http::StatusCode, routing::{get, post}, Json, Router};
 use clap::Parser;
+use form_usage_events::{sinks::load_sinks, threshold::{ThresholdConfig, ThresholdManager}, UsageEventError, UsageSigner};
 use form_vm_metrics::{
     system::{collect_system_metrics, SystemMetrics},
     events::MetricsPublisher,
@@ -28,9 +29,20 @@ struct Args {
     #[arg(long, default_value_t = 3003)]
     queue_port: u16,
     
-    /// Optional path or URL to threshold configuration source
+    /// File threshold rules are loaded from and saved to when they are
+    /// changed through the API. The default rules apply until it exists.
+    #[arg(long, default_value = "/var/lib/formation/metrics/thresholds.json")]
+    threshold_config: String,
+    
+    /// File holding the hex encoded operator key of the node hosting the
+    /// instance. Usage events are only billed when signed with it.
+    #[arg(long)]
+    signing_key_file: Option<std::path::PathBuf>,
+    
+    /// JSON file listing sinks usage events are delivered to besides the
+    /// message queue, e.g. a local NDJSON file or an HTTP collector
     #[arg(long)]
-    threshold_config: Option<String>,
+    sinks_config: Option<std::path::PathBuf>,
     
     /// Port to serve metrics API on
     #[arg(long, default_value_t = 8080)]
@@ -123,22 +135,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         0,
     );
     
-    // Add threshold detection if config source is provided
-    if let Some(config_source) = args.threshold_config {
-        println!("Initializing threshold detection with config source: {}", config_source);
-        // Clone before calling to avoid move
-        metrics_publisher = match metrics_publisher.clone().with_threshold_detection(config_source).await {
-            Ok(publisher) => {
-                println!("Threshold detection enabled");
-                publisher
-            },
-            Err(e) => {
-                eprintln!("Failed to initialize threshold detection: {}", e);
-                metrics_publisher
-            }
-        };
+    if let Some(path) = &args.signing_key_file {
+        let signer = UsageSigner::from_hex(&std::fs::read_to_string(path)?)?;
+        println!("Signing usage events as node {}", signer.node_id());
+        metrics_publisher = metrics_publisher.with_signer(signer);
+    } else {
+        eprintln!("No --signing-key-file given, usage events will not be billed");
+    }
+    
+    if let Some(path) = &args.sinks_config {
+        let sinks = load_sinks(path).await?;
+        println!("Delivering usage events to {} extra sinks", sinks.len());
+        metrics_publisher = metrics_publisher.with_sinks(sinks);
     }
     
+    // Add threshold detection
+    println!("Initializing threshold detection with config source: {}", args.threshold_config);
+    // Clone before calling to avoid move
+    metrics_publisher = match metrics_publisher.clone().with_threshold_detection(args.threshold_config.clone()).await {
+        Ok(publisher) => {
+            println!("Threshold detection enabled");
+            publisher
+        },
+        Err(e) => {
+            eprintln!("Failed to initialize threshold detection: {}", e);
+            metrics_publisher
+        }
+    };
+    let thresholds = metrics_publisher.threshold_manager();
+    
     // Channel for signaling collector to stop
     let (collector_sender, mut collector_receiver) = oneshot::channel();
     
@@ -163,12 +188,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                     
                     // Publish to registered webhooks
-                    if let Err(e) = publish_to_webhooks(&metrics_guard, "metrics").await {
+                    if let Err(e) = publish_to_webhooks(&*metrics_guard, "metrics").await {
                         eprintln!("Failed to publish to webhooks: {}", e);
                     }
                     
-                    // Process any threshold violations
-                    // (This would be implemented as part of the threshold manager)
+                    // Check the current rules and report violations to webhooks
+                    match metrics_publisher.check_thresholds(&metrics_guard).await {
+                        Ok(violations) => for violation in violations {
+                            if let Err(e) = publish_to_webhooks(&violation, "threshold_violation").await {
+                                eprintln!("Failed to publish threshold violation to webhooks: {}", e);
+                            }
+                        },
+                        Err(e) => eprintln!("{}", e),
+                    }
                 } => {}
             }
         }
@@ -179,7 +211,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     // Start the metrics API server
     let server_metrics = metrics.clone();
-    let server = serve(server_metrics, args.port, server_shutdown_rx);
+    let server = serve(server_metrics, thresholds, args.port, server_shutdown_rx);
     
     println!("Starting metrics service");
     println!("API available at http://localhost:{}/get", args.port);
@@ -209,10 +241,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 async fn serve(
     metrics: Arc<Mutex<SystemMetrics>>,
+    thresholds: Option<Arc<ThresholdManager>>,
     port: u16,
     mut shutdown_rx: mpsc::Receiver<()>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let app = Router::new()
+    let mut app = Router::new()
         // Get current system metrics
         .route("/get", get(get_metrics))
         // Simple health check for liveness probes
@@ -224,6 +257,15 @@ async fn serve(
         .route("/api/v1/webhooks", get(list_webhooks))
         .route("/api/v1/webhooks/:id", axum::routing::delete(delete_webhook))
         .with_state(metrics);
+    
+    if let Some(thresholds) = thresholds {
+        app = app.merge(Router::new()
+            // Threshold rules, applied from the next check on
+            .route("/api/v1/thresholds", get(list_thresholds).post(create_threshold))
+            .route("/api/v1/thresholds/effective", get(effective_thresholds))
+            .route("/api/v1/thresholds/:id", get(get_threshold).put(update_threshold).delete(delete_threshold))
+            .with_state(thresholds));
+    }
         
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
     axum::serve(listener, app)
@@ -484,11 +526,130 @@ async fn delete_webhook(
     }
 }
 
+/// Error responses of the threshold endpoints
+type ThresholdError = (StatusCode, Json<serde_json::Value>);
+
+fn threshold_error(e: UsageEventError) -> ThresholdError {
+    let status = match e {
+        UsageEventError::InvalidThreshold(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(serde_json::json!({ "error": e.to_string() })))
+}
+
+fn threshold_not_found(id: &str) -> ThresholdError {
+    (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": format!("No threshold with id {}", id) })))
+}
+
+/// Instance whose effective thresholds are requested
+#[derive(Deserialize)]
+struct EffectiveThresholdsQuery {
+    instance_id: String,
+    account_id: String,
+}
+
+/// List threshold rules
+///
+/// Returns every threshold rule, global, per-account and per-instance,
+/// ordered by ID.
+async fn list_thresholds(
+    State(thresholds): State<Arc<ThresholdManager>>,
+) -> Json<Vec<ThresholdConfig>> {
+    Json(thresholds.list_configs().await)
+}
+
+/// List the threshold rules that apply to an instance
+///
+/// For each resource only the most specific rules apply: rules for the
+/// instance override rules for its account, which override global rules.
+///
+/// # Query Parameters
+///
+/// `?instance_id=instance-abc123&account_id=account-xyz789`
+async fn effective_thresholds(
+    State(thresholds): State<Arc<ThresholdManager>>,
+    Query(query): Query<EffectiveThresholdsQuery>,
+) -> Json<Vec<ThresholdConfig>> {
+    Json(thresholds.effective_configs(&query.instance_id, &query.account_id).await)
+}
+
+/// Get a threshold rule by ID
+async fn get_threshold(
+    State(thresholds): State<Arc<ThresholdManager>>,
+    Path(id): Path<String>,
+) -> Result<Json<ThresholdConfig>, ThresholdError> {
+    thresholds.get_config(&id).await.map(Json).ok_or_else(|| threshold_not_found(&id))
+}
+
+/// Create a threshold rule
+///
+/// Adds a rule and persists it. An ID is generated if none is given. The
+/// rule is applied from the next metrics collection on.
+///
+/// # Request Format
+///
+/// ```json
+/// {
+///   "id": "vm-cpu-sustained",
+///   "resource_type": "Cpu",
+///   "threshold_type": { "Percentage": { "value": 95.0 } },
+///   "comparison": "above",
+///   "duration_secs": 300,
+///   "severity": "critical",
+///   "action": "Notify",
+///   "user_id": "*",
+///   "instance_id": "instance-abc123",
+///   "notification_channels": ["email"],
+///   "description": "CPU pinned for 5 minutes"
+/// }
+/// ```
+///
+/// Returns `201 Created` with the rule, `400 Bad Request` if it is invalid
+/// and `409 Conflict` if a rule with the ID exists.
+async fn create_threshold(
+    State(thresholds): State<Arc<ThresholdManager>>,
+    Json(mut config): Json<ThresholdConfig>,
+) -> Result<(StatusCode, Json<ThresholdConfig>), ThresholdError> {
+    if config.id.trim().is_empty() {
+        config.id = format!("threshold_{}", uuid::Uuid::new_v4().to_string().replace("-", "").chars().take(8).collect::<String>());
+    }
+    if thresholds.get_config(&config.id).await.is_some() {
+        return Err((StatusCode::CONFLICT, Json(serde_json::json!({ "error": format!("A threshold with id {} exists", config.id) }))));
+    }
+    thresholds.upsert_config(config.clone()).await.map_err(threshold_error)?;
+    Ok((StatusCode::CREATED, Json(config)))
+}
+
+/// Create or replace the threshold rule with the given ID
+///
+/// Takes the same body as creating a rule, the ID in the path wins.
+async fn update_threshold(
+    State(thresholds): State<Arc<ThresholdManager>>,
+    Path(id): Path<String>,
+    Json(mut config): Json<ThresholdConfig>,
+) -> Result<(StatusCode, Json<ThresholdConfig>), ThresholdError> {
+    config.id = id;
+    let created = thresholds.upsert_config(config.clone()).await.map_err(threshold_error)?;
+    let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+    Ok((status, Json(config)))
+}
+
+/// Delete a threshold rule by ID
+async fn delete_threshold(
+    State(thresholds): State<Arc<ThresholdManager>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ThresholdError> {
+    match thresholds.remove_config(&id).await.map_err(threshold_error)? {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err(threshold_not_found(&id)),
+    }
+}
+
 /// Publish events to registered webhooks
 ///
 /// Sends the events to all registered webhooks that are interested
 /// in the specified event type.
-async fn publish_to_webhooks(metrics: &SystemMetrics, event_type: &str) -> Result<(), String> {
+async fn publish_to_webhooks<T: Serialize>(data: &T, event_type: &str) -> Result<(), String> {
     let webhooks = WEBHOOKS.lock().await.clone();
     
     if webhooks.is_empty() {
@@ -503,7 +664,7 @@ async fn publish_to_webhooks(metrics: &SystemMetrics, event_type: &str) -> Resul
             let payload = serde_json::json!({
                 "event_type": event_type,
                 "timestamp": chrono::Utc::now().timestamp(),
-                "data": metrics
+                "data": data
             });
             
             // Build the request
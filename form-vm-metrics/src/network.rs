@@ -15,6 +15,30 @@ pub struct NetworkInterfaceMetrics {
     pub drops_in: u64,
     pub drops_out: u64,
     pub speed: u64, // in bits per second
+    /// Rates over the last collection interval, 0 on the first collection
+    #[serde(default)]
+    pub tx_bytes_per_sec: u64,
+    #[serde(default)]
+    pub rx_bytes_per_sec: u64,
+    #[serde(default)]
+    pub tx_packets_per_sec: u64,
+    #[serde(default)]
+    pub rx_packets_per_sec: u64,
+}
+
+impl NetworkInterfaceMetrics {
+    /// Fills in the rates from the counters of the previous collection,
+    /// `elapsed` seconds ago. Counters that went backwards (interface
+    /// recreated) give a rate of 0.
+    pub fn compute_rates(&mut self, previous: &NetworkInterfaceMetrics, elapsed: u64) {
+        if elapsed == 0 {
+            return;
+        }
+        self.tx_bytes_per_sec = self.bytes_sent.saturating_sub(previous.bytes_sent) / elapsed;
+        self.rx_bytes_per_sec = self.bytes_received.saturating_sub(previous.bytes_received) / elapsed;
+        self.tx_packets_per_sec = self.packets_sent.saturating_sub(previous.packets_sent) / elapsed;
+        self.rx_packets_per_sec = self.packets_received.saturating_sub(previous.packets_received) / elapsed;
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)] 
@@ -45,6 +69,7 @@ pub fn collect_network_metrics() -> NetworkMetrics {
             drops_in: 0,  // Not available in sysinfo
             drops_out: 0, // Not available in sysinfo
             speed: 0,     // Not available in sysinfo
+            ..Default::default()
         })
         .collect();
     NetworkMetrics { interfaces }
@@ -72,6 +97,7 @@ fn parse_proc_net_dev() -> Result<Vec<NetworkInterfaceMetrics>, Box<dyn Error>>
                 errors_out: parts[11].parse()?,
                 drops_out: parts[12].parse()?,
                 speed,
+                ..Default::default()
             });
         }
     }
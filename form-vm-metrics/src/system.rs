@@ -4,6 +4,7 @@ use serde::{Serialize, Deserialize};
 use sysinfo::System;
 
 use crate::{
+    balloon::{collect_balloon_metrics, BalloonMetrics},
     cpu::{collect_cpu, CpuMetrics}, 
     disk::{collect_disk_metrics, DiskMetrics}, 
     gpu::{collect_gpu_metrics, GpuMetrics}, 
@@ -23,6 +24,49 @@ pub struct SystemMetrics {
     pub network: NetworkMetrics,
     pub gpus: Vec<GpuMetrics>,
     pub load: LoadMetrics,
+    #[serde(default)]
+    pub balloon: BalloonMetrics,
+}
+
+impl SystemMetrics {
+    /// Computes the disk and network rates against the previous collection.
+    /// Devices and interfaces are matched by name, new ones start at 0.
+    pub fn compute_rates(&mut self, previous: &SystemMetrics) {
+        let elapsed = match self.timestamp - previous.timestamp {
+            elapsed if elapsed > 0 && previous.timestamp > 0 => elapsed as u64,
+            _ => return,
+        };
+        for disk in &mut self.disks {
+            if let Some(last) = previous.disks.iter().find(|last| last.device_name == disk.device_name) {
+                disk.compute_rates(last, elapsed);
+            }
+        }
+        for interface in &mut self.network.interfaces {
+            if let Some(last) = previous.network.interfaces.iter().find(|last| last.name == interface.name) {
+                interface.compute_rates(last, elapsed);
+            }
+        }
+    }
+
+    pub fn disk_read_bytes_per_sec(&self) -> u64 {
+        self.disks.iter().map(|disk| disk.read_bytes_per_sec).sum()
+    }
+
+    pub fn disk_write_bytes_per_sec(&self) -> u64 {
+        self.disks.iter().map(|disk| disk.write_bytes_per_sec).sum()
+    }
+
+    pub fn disk_iops(&self) -> u64 {
+        self.disks.iter().map(|disk| disk.read_iops + disk.write_iops).sum()
+    }
+
+    pub fn network_tx_bytes_per_sec(&self) -> u64 {
+        self.network.interfaces.iter().map(|interface| interface.tx_bytes_per_sec).sum()
+    }
+
+    pub fn network_rx_bytes_per_sec(&self) -> u64 {
+        self.network.interfaces.iter().map(|interface| interface.rx_bytes_per_sec).sum()
+    }
 }
 
 pub async fn collect_system_metrics(
@@ -40,6 +84,7 @@ pub async fn collect_system_metrics(
         Vec::new()
     });
     let load = collect_load_metrics();
+    let balloon = collect_balloon_metrics();
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Something is seriously wrong with the system")
@@ -51,7 +96,7 @@ pub async fn collect_system_metrics(
     let instance_id = guard.instance_id.clone();
     let account_id = guard.account_id.clone();
     
-    *guard = SystemMetrics {
+    let mut metrics = SystemMetrics {
         timestamp,
         instance_id,
         account_id,
@@ -61,7 +106,10 @@ pub async fn collect_system_metrics(
         network,
         gpus,
         load,
+        balloon,
     };
+    metrics.compute_rates(&guard);
+    *guard = metrics;
     drop(guard);
 
     system_metrics
@@ -70,6 +118,7 @@ pub async fn collect_system_metrics(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::NetworkInterfaceMetrics;
 
     #[tokio::test]
     async fn test_preserve_instance_and_account_id() {
@@ -91,4 +140,27 @@ mod tests {
         assert_eq!(guard.instance_id.as_deref(), Some(test_instance_id));
         assert_eq!(guard.account_id.as_deref(), Some(test_account_id));
     }
+
+    #[test]
+    fn test_rates_between_collections() {
+        let mut previous = SystemMetrics { timestamp: 100, ..Default::default() };
+        previous.disks = vec![DiskMetrics { device_name: "vda".to_string(), sectors_read: 1000, reads_completed: 10, ..Default::default() }];
+        previous.network.interfaces = vec![NetworkInterfaceMetrics { name: "eth0".to_string(), bytes_sent: 5000, ..Default::default() }];
+
+        let mut current = SystemMetrics { timestamp: 110, ..Default::default() };
+        current.disks = vec![
+            DiskMetrics { device_name: "vda".to_string(), sectors_read: 3000, reads_completed: 60, ..Default::default() },
+            DiskMetrics { device_name: "vdb".to_string(), sectors_read: 500, ..Default::default() },
+        ];
+        current.network.interfaces = vec![NetworkInterfaceMetrics { name: "eth0".to_string(), bytes_sent: 1000, ..Default::default() }];
+        current.compute_rates(&previous);
+
+        assert_eq!(current.disks[0].read_bytes_per_sec, 2000 * 512 / 10);
+        assert_eq!(current.disks[0].read_iops, 5);
+        // A device that wasn't there before has no rate yet
+        assert_eq!(current.disks[1].read_bytes_per_sec, 0);
+        // A counter that went backwards isn't a negative rate
+        assert_eq!(current.network_tx_bytes_per_sec(), 0);
+        assert_eq!(current.disk_iops(), 5);
+    }
 }
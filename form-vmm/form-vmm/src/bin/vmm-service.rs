@@ -1,5 +1,6 @@
 use clap::Parser;
-use vmm_service::{CliArgs, CliCommand, VmManager}; 
+use std::time::Duration;
+use vmm_service::{BootWatchdogConfig, CliArgs, CliCommand, VmManager}; 
 use form_config::OperatorConfig;
 
 #[tokio::main]
@@ -12,7 +13,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = CliArgs::parse();
     let config = OperatorConfig::from_file(args.config, args.encrypted, args.password.as_deref()).ok();
     match args.command {
-        CliCommand::Run { signing_key, sub_addr, pub_addr } => {
+        CliCommand::Run { signing_key, sub_addr, pub_addr, boot_timeout, boot_restarts, mock, require_verity } => {
             let signing_key = if signing_key.is_none() {
                 let config = config.unwrap();
                 config.secret_key.unwrap()
@@ -27,7 +28,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     shutdown_rx,
                     manager_shutdown,
                     sub_addr.as_deref(), 
-                    pub_addr
+                    pub_addr,
+                    BootWatchdogConfig {
+                        boot_timeout: Duration::from_secs(boot_timeout),
+                        max_restarts: boot_restarts,
+                    },
+                    mock,
+                    require_verity,
                 ).await {
                     log::error!("{e}");
                 }
@@ -48,7 +55,10 @@ async fn run_vm_manager(
     shutdown_rx: tokio::sync::broadcast::Receiver<()>,
     manager_shutdown: tokio::sync::broadcast::Receiver<()>,
     subscriber_uri: Option<&str>,
-    publisher_uri: Option<String>
+    publisher_uri: Option<String>,
+    boot_watchdog: BootWatchdogConfig,
+    mock: bool,
+    require_verity: bool,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let (event_sender, event_receiver) = tokio::sync::mpsc::channel(1024);
     let api_addr = "0.0.0.0:3002".parse()?;
@@ -61,7 +71,7 @@ async fn run_vm_manager(
         subscriber_uri,
         publisher_uri,
         manager_shutdown
-    ).await?;
+    ).await?.with_boot_watchdog(boot_watchdog).with_mock(mock).with_require_verity(require_verity);
 
     vm_manager.run(shutdown_rx, event_receiver).await 
 }
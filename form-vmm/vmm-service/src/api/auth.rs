@@ -1,7 +1,9 @@
 use alloy_primitives::Address;
 use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
-use form_state::instances::Instance;
-use form_types::state::{Response, Success};
+use form_state::accounts::AuthorizationLevel;
+use form_state::auth::cache::cached_recover;
+use form_types::grpc::{self, state_query_client::StateQueryClient};
+use form_types::{VmmErrorCode, VmmResponse};
 use axum::{
     async_trait,
     extract::{FromRequestParts, Request},
@@ -9,13 +11,10 @@ use axum::{
     response::{IntoResponse, Response as AxumResponse},
     Json,
 };
-use serde_json::json;
-use reqwest::Client;
 use std::sync::Arc;
 use log;
 use hex;
-use serde::{Deserialize, Serialize};
-use tiny_keccak::{self, Hasher};
+use serde::Serialize;
 
 use crate::error::VmmError;
 
@@ -38,10 +37,12 @@ impl IntoResponse for SignatureError {
             Self::RecoveryFailed => (StatusCode::UNAUTHORIZED, "Failed to recover public key from signature"),
             Self::InvalidFormat => (StatusCode::BAD_REQUEST, "Invalid signature header format"),
         };
-        let body = Json(json!({
-            "error": error_message_str,
-        }));
-        (status, body).into_response()
+        let code = if status == StatusCode::UNAUTHORIZED {
+            VmmErrorCode::AuthFailed
+        } else {
+            VmmErrorCode::InvalidRequest
+        };
+        (status, Json(VmmResponse::failure(code, error_message_str))).into_response()
     }
 }
 
@@ -95,6 +96,12 @@ pub fn extract_x_signature_parts(headers: &HeaderMap) -> Result<(Vec<u8>, Recove
 
 /// Recover an address from a signature, recovery ID, and the message hash
 pub fn recover_address_from_hash(signature_bytes: &[u8], recovery_id: RecoveryId, message_hash_bytes: &[u8]) -> Result<Address, SignatureError> {
+    cached_recover("vmm", signature_bytes, recovery_id.to_byte(), message_hash_bytes, || {
+        recover_uncached(signature_bytes, recovery_id, message_hash_bytes)
+    })
+}
+
+fn recover_uncached(signature_bytes: &[u8], recovery_id: RecoveryId, message_hash_bytes: &[u8]) -> Result<Address, SignatureError> {
     let signature = Signature::try_from(signature_bytes)
         .map_err(|_| SignatureError::InvalidSignature)?;
 
@@ -162,8 +169,8 @@ pub async fn ecdsa_auth_middleware_x_headers(
     Ok(next.run(request_with_ext).await)
 }
 
-/// Permission levels for VM operations
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Permission levels for VM operations, ordered from least to most privileged
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Permission {
     /// Can only view instance details
     ReadOnly,
@@ -175,6 +182,17 @@ pub enum Permission {
     Owner,
 }
 
+impl From<AuthorizationLevel> for Permission {
+    fn from(level: AuthorizationLevel) -> Self {
+        match level {
+            AuthorizationLevel::Owner => Permission::Owner,
+            AuthorizationLevel::Manager => Permission::Manager,
+            AuthorizationLevel::Operator => Permission::Operator,
+            AuthorizationLevel::ReadOnly => Permission::ReadOnly,
+        }
+    }
+}
+
 /// Error type for authorization failures
 #[derive(Debug, thiserror::Error, Serialize)]
 pub enum AuthorizationError {
@@ -191,98 +209,75 @@ pub enum AuthorizationError {
     Unknown(String),
 }
 
-/// Need a function to sign a message (hash) with form-vmm's operator key
-/// This is a placeholder for where this utility would live or be accessed from.
-/// For now, assume it exists and can be called.
-async fn sign_message_with_operator_key(message_hash_hex: &str) -> Result<(String, String), String> {
-    // In a real scenario, this function would:
-    // 1. Access the VMM operator's private signing key.
-    // 2. Hex-decode message_hash_hex to bytes.
-    // 3. Sign the hash bytes.
-    // 4. Return (hex_signature, recovery_id_string).
-    // Placeholder implementation:
-    log::warn!("sign_message_with_operator_key is a placeholder and not signing with a real key!");
-    Ok(("placeholder_signature_hex".to_string(), "0".to_string()))
+impl Permission {
+    /// The permission granted by a level returned from form-state's query service
+    pub fn from_grpc(level: grpc::AuthorizationLevel) -> Option<Self> {
+        match level {
+            grpc::AuthorizationLevel::Owner => Some(Permission::Owner),
+            grpc::AuthorizationLevel::Manager => Some(Permission::Manager),
+            grpc::AuthorizationLevel::Operator => Some(Permission::Operator),
+            grpc::AuthorizationLevel::ReadOnly => Some(Permission::ReadOnly),
+            grpc::AuthorizationLevel::None => None,
+        }
+    }
 }
 
 /// Utilities for verifying instance ownership and authorization
 pub struct OwnershipVerifier;
 
 impl OwnershipVerifier {
-    /// Verifies if an address is authorized to perform an operation on an instance
+    /// Verifies if an address is authorized to perform an operation on an instance.
+    /// Owners are always authorized, other addresses are authorized through the
+    /// role they hold in the organization that owns the instance.
     pub async fn verify_authorization(
         instance_id: &str,
         address: &str,
-        _required_permission: Permission
+        required_permission: Permission
     ) -> Result<bool, VmmError> {
         log::debug!("Verifying authorization for instance '{}', address '{}'", instance_id, address);
-        match Self::get_instance(instance_id).await {
-            Ok(instance) => {
-                if instance.instance_owner.eq_ignore_ascii_case(address) {
-                    log::debug!("Authorization successful: Address matches instance owner.");
-                    Ok(true)
-                } else {
-                    log::warn!("Authorization failed: Address '{}' does not match instance owner '{}' for instance '{}'", address, instance.instance_owner, instance_id);
-                    Ok(false)
-                }
-            },
-            Err(e) => {
-                log::error!("Error retrieving instance '{}' for authorization: {}. Assuming unauthorized.", instance_id, e);
-                Err(VmmError::Config(format!("Failed to retrieve instance '{}' for auth check: {}", instance_id, e)))
+        let ownership = match Self::check_ownership(instance_id, address).await {
+            Ok(ownership) => ownership,
+            Err(status) if status.code() == tonic::Code::NotFound => {
+                return Err(VmmError::VmNotFound(format!("Instance '{}' not found in form-state", instance_id)));
             }
-        }
-    }
-    
-    /// Retrieves an instance by ID from the state store
-    async fn get_instance(instance_id: &str) -> Result<Instance, Box<dyn std::error::Error + Send + Sync>> {
-        let client = reqwest::Client::new();
-        let path = format!("/instance/{}/get", instance_id);
-        let url = format!("http://127.0.0.1:3004{}", path);
-        log::debug!("OwnershipVerifier: Getting instance from {}", url);
-
-        // Hash the path for X-Message (assuming Keccak256 for Ethereum-style, or SHA256 if that's the standard)
-        // For consistency with README example, let's use a placeholder for hashing for now.
-        // In form-state auth, X-Message is typically hash of body for POST, or path for GET.
-        // Let's assume client for form-state expects path hash for GET /instance/{id}/get.
-        // Hashing mechanism should align with form-state's expectation for this endpoint.
-        let mut hasher = tiny_keccak::Keccak::v256(); // Or ::Sha256 if that's used by form-state for GET paths
-        let mut output = [0u8; 32];
-        hasher.update(path.as_bytes());
-        hasher.finalize(&mut output);
-        let path_hash_hex = format!("0x{}", hex::encode(output));
-
-        // Sign this path_hash_hex with form-vmm's operator key
-        let (operator_signature_hex, operator_recovery_id_str) = 
-            sign_message_with_operator_key(&path_hash_hex).await.map_err(|e| Box::new(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
-
-        let response = client.get(&url)
-            .header("X-Message", path_hash_hex)
-            .header("X-Signature", operator_signature_hex)
-            .header("X-Recovery-Id", operator_recovery_id_str)
-            .send().await?;
-
-        if !response.status().is_success() {
-            let err_msg = format!("Error retrieving instance from form-state: Status {}, Body: {}", response.status(), response.text().await.unwrap_or_default());
-            log::error!("{}", err_msg);
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, err_msg)));
+            Err(status) => {
+                log::error!("Error checking ownership of instance '{}': {}. Assuming unauthorized.", instance_id, status);
+                return Err(VmmError::Config(format!("Failed to check ownership of instance '{}': {}", instance_id, status.message())));
+            }
+        };
+
+        if ownership.is_owner {
+            log::debug!("Authorization successful: Address matches instance owner.");
+            return Ok(true);
         }
 
-        let state_response = response.json::<form_types::state::Response<form_state::instances::Instance>>().await?;
-        
-        match state_response {
-            form_types::state::Response::Success(success_payload) => match success_payload {
-                form_types::state::Success::Some(instance_data) => Ok(instance_data),
-                form_types::state::Success::List(_) => Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Expected single instance from form-state, got a list"))),
-                form_types::state::Success::Relationships(_) => Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Expected single instance from form-state, got relationships"))),
-                form_types::state::Success::None => Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "Instance not found in form-state (Success::None)"))),
-            },
-            form_types::state::Response::Failure { reason } => {
-                 let err_msg = format!("form-state failed to get instance '{}': {:?}", instance_id, reason);
-                 log::error!("{}", err_msg);
-                 Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, err_msg)))
+        let org_id = ownership.organization_id.clone().unwrap_or_default();
+        match Permission::from_grpc(ownership.level()) {
+            Some(granted) if granted >= required_permission => {
+                log::debug!("Authorization successful: '{}' has {:?} on instance '{}' through organization '{}'", address, granted, instance_id, org_id);
+                Ok(true)
+            }
+            Some(granted) => {
+                log::warn!("Authorization failed: '{}' has {:?} on instance '{}' through organization '{}', {:?} is required", address, granted, instance_id, org_id, required_permission);
+                Ok(false)
+            }
+            None => {
+                log::warn!("Authorization failed: Address '{}' is neither the owner of nor a member with access to instance '{}'", address, instance_id);
+                Ok(false)
             }
         }
     }
+
+    /// Asks form-state's gRPC query service what access an address has to an instance
+    async fn check_ownership(instance_id: &str, address: &str) -> Result<grpc::CheckOwnershipResponse, tonic::Status> {
+        let mut client = StateQueryClient::connect(grpc::state_grpc_endpoint()).await
+            .map_err(|e| tonic::Status::unavailable(format!("Unable to connect to form-state: {e}")))?;
+        let response = client.check_ownership(grpc::CheckOwnershipRequest {
+            instance_id: instance_id.to_string(),
+            address: address.to_string(),
+        }).await?;
+        Ok(response.into_inner())
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,143 @@
+//! Cloning an instance from its disk.
+//!
+//! `POST /v1/clone` copies the disk of an instance running on this node and
+//! boots the copy as a new instance of the caller, with the source's
+//! Formfile and secrets. The copy gets its own build id, formnet peer and
+//! IP, and unless `keep_identity` is set a new machine-id and SSH host keys.
+//! Cloning needs the Manager permission on the source and is admitted like
+//! any other create.
+use std::path::PathBuf;
+use std::sync::Arc;
+use axum::{extract::State, Extension, Json};
+use reqwest::Client;
+use tokio::sync::Mutex;
+use form_state::instances::Instance;
+use form_types::{CloneVmRequest, VmResponse, VmmErrorCode, VmmEvent, VmmFailure, VmmResponse};
+use crate::guest_agent::GuestAgentRegistry;
+use crate::{VmmError, IMAGE_DIR};
+use super::auth::{OwnershipVerifier, Permission, RecoveredAddress};
+use super::drain::DrainController;
+use super::{admit_create, VmmApi, VmmApiChannel};
+
+/// Clone names become hostnames and build ids
+fn validate_name(name: &str) -> Result<(), VmmError> {
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(VmmError::Config(format!(
+            "Invalid clone name {name}: use up to 63 lowercase letters, digits and dashes"
+        )))
+    }
+}
+
+/// The record of the source instance, read from the local form-state
+async fn source_instance(instance_id: &str) -> Result<Instance, VmmError> {
+    let resp: serde_json::Value = Client::new()
+        .get(format!("http://127.0.0.1:3004/v1/instance/{instance_id}/get"))
+        .send().await
+        .map_err(|e| VmmError::SystemError(format!("Unable to reach form-state: {e}")))?
+        .json().await
+        .map_err(|e| VmmError::SystemError(format!("Invalid response from form-state: {e}")))?;
+    if !resp["success"].as_bool().unwrap_or(false) {
+        return Err(VmmError::VmNotFound(format!("Unable to find instance {instance_id}: {}", resp["error"])));
+    }
+    serde_json::from_value(resp["instance"].clone())
+        .map_err(|e| VmmError::SystemError(format!("Invalid instance {instance_id} from form-state: {e}")))
+}
+
+/// Checks the clone may be made and returns its build id and the source's
+/// Formfile
+async fn admit_clone(
+    request: &CloneVmRequest,
+    owner: &str,
+    agents: &GuestAgentRegistry,
+    drain: &DrainController,
+) -> Result<(String, String), VmmError> {
+    validate_name(&request.name)?;
+    if !PathBuf::from(IMAGE_DIR).join(&request.source).with_extension("raw").exists() {
+        return Err(VmmError::ImageNotFound(format!(
+            "No disk for {} on this node, clone it on the node it runs on", request.source
+        )));
+    }
+
+    let instance_id = agents.instance_id(&request.source)?;
+    if !OwnershipVerifier::verify_authorization(&instance_id, owner, Permission::Manager).await? {
+        return Err(VmmError::Unauthorized(format!(
+            "{owner} is not permitted to clone {}", request.source
+        )));
+    }
+
+    let build_id = VmmApi::extract_build_id(request.name.clone(), owner.to_string())?;
+    if PathBuf::from(IMAGE_DIR).join(&build_id).with_extension("raw").exists() {
+        return Err(VmmError::Config(format!(
+            "An instance named {} already exists on this node", request.name
+        )));
+    }
+
+    let source = source_instance(&instance_id).await?;
+    drain.admit().await?;
+    drain.admit_deployment().await?;
+    admit_create(&source.formfile, owner).await?;
+    Ok((build_id, source.formfile))
+}
+
+pub async fn clone_vm(
+    State(channel): State<Arc<Mutex<VmmApiChannel>>>,
+    Extension(drain): Extension<DrainController>,
+    Extension(agents): Extension<GuestAgentRegistry>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+    Json(request): Json<CloneVmRequest>,
+) -> Json<VmmResponse> {
+    let owner = recovered_address.as_hex();
+    log::info!("Received clone request: source={}, name={}, owner={owner}", request.source, request.name);
+
+    let (build_id, formfile) = match admit_clone(&request, &owner, &agents, &drain).await {
+        Ok(admitted) => admitted,
+        Err(e) => {
+            log::warn!("Rejecting clone of {} as {}: {e}", request.source, request.name);
+            return Json(VmmResponse::Failure(VmmFailure::from(&e)));
+        }
+    };
+    let event = VmmEvent::CloneInstance {
+        source: request.source.clone(),
+        build_id: build_id.clone(),
+        name: request.name.clone(),
+        owner,
+        formfile,
+        reset_identity: !request.keep_identity,
+    };
+
+    let guard = channel.lock().await;
+    if let Err(e) = guard.send(event).await {
+        log::error!("Error sending VmmEvent::CloneInstance for {}: {e}", request.source);
+        return Json(VmmResponse::failure(VmmErrorCode::Internal,
+            format!("Error queueing clone of {}: {e}", request.source)
+        ));
+    }
+    drop(guard);
+
+    Json(VmmResponse::Success(VmResponse {
+        id: build_id,
+        name: request.name,
+        state: "CLONE_REQUESTED".to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clone_names_must_be_hostnames() {
+        assert!(validate_name("web-2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("-web").is_err());
+        assert!(validate_name("Web").is_err());
+        assert!(validate_name("web_2").is_err());
+        assert!(validate_name(&"a".repeat(64)).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! Node maintenance mode.
+//!
+//! `POST /v1/admin/drain` marks the node as in maintenance in form-state so
+//! placement skips it, makes this vmm-service refuse create requests and then
+//! works through the node's instances one at a time. Under the `migrate`
+//! policy a replacement is requested through the vmm queue and the local
+//! instance is deleted once the replacement is ready on another node, under
+//! `stop` instances are stopped. The node stays in maintenance after the drain
+//! finishes, until `POST /v1/admin/drain/cancel`.
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use reqwest::Client;
+use tokio::sync::{Mutex, RwLock};
+use form_state::instances::{Instance, InstanceStatus};
+use form_state::nodes::Node;
+use form_types::state::{Response as StateResponse, Success};
+use form_types::{
+    CreateVmRequest, DrainPhase, DrainPolicy, DrainRequest, DrainStatus, InstanceDrain,
+    InstanceDrainState, VmmEvent, VmmFailure,
+};
+use crate::VmmError;
+use super::{auth::RecoveredAddress, VmmApi, VmmApiChannel};
+
+/// How long a replacement gets to become ready unless the request says otherwise
+const DEFAULT_MIGRATE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often form-state is asked whether a replacement is ready
+const MIGRATE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+const STATE_API: &str = "http://127.0.0.1:3004/v1";
+
+type DrainResult = Result<Json<DrainStatus>, (StatusCode, Json<VmmFailure>)>;
+
+/// Shared drain state of this node
+#[derive(Clone, Debug)]
+pub struct DrainController {
+    node_id: String,
+    status: Arc<RwLock<Option<DrainStatus>>>,
+}
+
+impl DrainController {
+    pub fn new(node_id: String) -> Self {
+        Self { node_id, status: Arc::new(RwLock::new(None)) }
+    }
+
+    pub async fn status(&self) -> Option<DrainStatus> {
+        self.status.read().await.clone()
+    }
+
+    /// Refuses new instances while the node is draining or drained
+    pub async fn admit(&self) -> Result<(), VmmError> {
+        match self.status.read().await.as_ref().map(|s| s.phase) {
+            Some(DrainPhase::Draining) | Some(DrainPhase::Drained) => Err(VmmError::NodeDraining(
+                format!("node {} is in maintenance and doesn't accept new instances", self.node_id)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuses new instances while admins have frozen deployments on the
+    /// network or on this node. An unreachable form-state doesn't block the
+    /// request, form-state checks the freeze again when it's recorded.
+    pub async fn admit_deployment(&self) -> Result<(), VmmError> {
+        let resp = Client::new()
+            .get(format!("{STATE_API}/maintenance/check"))
+            .query(&[("node_id", &self.node_id)])
+            .send()
+            .await;
+        match resp {
+            Ok(resp) if resp.status() == StatusCode::SERVICE_UNAVAILABLE => {
+                let body = resp.json::<serde_json::Value>().await.unwrap_or_default();
+                let reason = body["error"].as_str().unwrap_or("deployments are frozen for maintenance");
+                Err(VmmError::DeploymentsFrozen(reason.to_string()))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::warn!("Unable to check for a deployment freeze: {e}");
+                Ok(())
+            }
+        }
+    }
+
+    async fn is_cancelled(&self) -> bool {
+        self.status.read().await.as_ref().is_some_and(|s| s.phase == DrainPhase::Cancelled)
+    }
+
+    async fn set_instance(&self, instance_id: &str, state: InstanceDrainState, detail: Option<String>) {
+        if let Some(status) = self.status.write().await.as_mut() {
+            if let Some(instance) = status.instances.get_mut(instance_id) {
+                instance.state = state;
+                instance.detail = detail;
+            }
+        }
+    }
+}
+
+fn failure(status: StatusCode, error: VmmError) -> (StatusCode, Json<VmmFailure>) {
+    (status, Json(VmmFailure::from(&error)))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Starts draining the node, admins only
+pub async fn start_drain(
+    State(channel): State<Arc<Mutex<VmmApiChannel>>>,
+    Extension(drain): Extension<DrainController>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+    Json(request): Json<DrainRequest>,
+) -> DrainResult {
+    verify_admin(&drain.node_id, &recovered_address.as_hex()).await
+        .map_err(|e| failure(StatusCode::FORBIDDEN, e))?;
+    if drain.admit().await.is_err() {
+        return Err(failure(StatusCode::CONFLICT, VmmError::NodeDraining(
+            "a drain was already started, cancel it first to start another".to_string()
+        )));
+    }
+
+    let instances = node_instances(&drain.node_id).await
+        .map_err(|e| failure(StatusCode::BAD_GATEWAY, e))?;
+    let status = DrainStatus {
+        node_id: drain.node_id.clone(),
+        policy: request.policy,
+        phase: DrainPhase::Draining,
+        started_at: now(),
+        finished_at: None,
+        node_marked: false,
+        instances: instances.iter().map(|instance| (instance.instance_id.clone(), InstanceDrain {
+            build_id: instance.build_id.clone(),
+            state: InstanceDrainState::Pending,
+            detail: None,
+        })).collect(),
+    };
+    log::warn!(
+        "{} started draining node {} ({:?}, {} instances)",
+        recovered_address.as_hex(), drain.node_id, request.policy, instances.len()
+    );
+    *drain.status.write().await = Some(status.clone());
+
+    tokio::spawn(run_drain(drain, channel, request, instances));
+    Ok(Json(status))
+}
+
+/// Reports the progress of the current or last drain, admins only
+pub async fn drain_status(
+    Extension(drain): Extension<DrainController>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+) -> DrainResult {
+    verify_admin(&drain.node_id, &recovered_address.as_hex()).await
+        .map_err(|e| failure(StatusCode::FORBIDDEN, e))?;
+    drain.status().await
+        .map(Json)
+        .ok_or_else(|| failure(StatusCode::NOT_FOUND, VmmError::Config("the node has not been drained".to_string())))
+}
+
+/// Stops a drain in progress and takes the node out of maintenance. Instances
+/// that were already migrated or stopped stay that way.
+pub async fn cancel_drain(
+    Extension(drain): Extension<DrainController>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+) -> DrainResult {
+    verify_admin(&drain.node_id, &recovered_address.as_hex()).await
+        .map_err(|e| failure(StatusCode::FORBIDDEN, e))?;
+
+    let status = {
+        let mut guard = drain.status.write().await;
+        let Some(status) = guard.as_mut().filter(|s| s.phase != DrainPhase::Cancelled) else {
+            return Err(failure(StatusCode::CONFLICT, VmmError::Config("the node is not in maintenance".to_string())));
+        };
+        status.phase = DrainPhase::Cancelled;
+        status.finished_at.get_or_insert_with(now);
+        status.clone()
+    };
+    log::warn!("{} took node {} out of maintenance", recovered_address.as_hex(), drain.node_id);
+
+    if let Err(e) = set_node_maintenance(&drain.node_id, false, None).await {
+        log::error!("Unable to clear maintenance of node {} in form-state: {e}", drain.node_id);
+    }
+    Ok(Json(status))
+}
+
+async fn run_drain(
+    drain: DrainController,
+    channel: Arc<Mutex<VmmApiChannel>>,
+    request: DrainRequest,
+    instances: Vec<Instance>,
+) {
+    match set_node_maintenance(&drain.node_id, true, request.reason.clone()).await {
+        Ok(()) => {
+            if let Some(status) = drain.status.write().await.as_mut() {
+                status.node_marked = true;
+            }
+        }
+        // The drain goes on, this node refuses creates either way
+        Err(e) => log::error!("Unable to mark node {} as in maintenance: {e}", drain.node_id),
+    }
+
+    let timeout = request.migrate_timeout_secs.map(Duration::from_secs).unwrap_or(DEFAULT_MIGRATE_TIMEOUT);
+    for instance in instances {
+        if drain.is_cancelled().await {
+            return;
+        }
+        let (state, detail) = match request.policy {
+            DrainPolicy::Stop => stop_instance(&channel, &instance.build_id).await,
+            DrainPolicy::Migrate => {
+                drain.set_instance(&instance.instance_id, InstanceDrainState::Migrating, None).await;
+                migrate_instance(&drain, &channel, &instance, timeout).await
+            }
+        };
+        log::info!("Drained instance {}: {state:?} {detail:?}", instance.instance_id);
+        drain.set_instance(&instance.instance_id, state, detail).await;
+    }
+
+    if let Some(status) = drain.status.write().await.as_mut() {
+        if status.phase == DrainPhase::Draining {
+            status.phase = DrainPhase::Drained;
+            status.finished_at = Some(now());
+            log::warn!("Node {} is drained", drain.node_id);
+        }
+    }
+}
+
+async fn stop_instance(channel: &Arc<Mutex<VmmApiChannel>>, build_id: &str) -> (InstanceDrainState, Option<String>) {
+    match channel.lock().await.send(VmmEvent::Stop { id: build_id.to_string() }).await {
+        Ok(()) => (InstanceDrainState::Stopped, None),
+        Err(e) => (InstanceDrainState::Failed { reason: format!("Unable to stop instance: {e}") }, None),
+    }
+}
+
+async fn migrate_instance(
+    drain: &DrainController,
+    channel: &Arc<Mutex<VmmApiChannel>>,
+    instance: &Instance,
+    timeout: Duration,
+) -> (InstanceDrainState, Option<String>) {
+    let requested_at = now();
+    let request = CreateVmRequest {
+        name: instance.build_id.clone(),
+        formfile: instance.formfile.clone(),
+        owner: instance.instance_owner.clone(),
+    };
+    if let Err(e) = VmmApi::write_to_queue(request, 0, "vmm").await {
+        let (state, _) = stop_instance(channel, &instance.build_id).await;
+        return (state, Some(format!("Unable to request a replacement, stopped instead: {e}")));
+    }
+
+    let deadline = requested_at + timeout.as_secs();
+    while now() < deadline {
+        tokio::time::sleep(MIGRATE_POLL_INTERVAL).await;
+        if drain.is_cancelled().await {
+            return (InstanceDrainState::Failed { reason: "the drain was cancelled".to_string() }, None);
+        }
+        let Some(node_id) = ready_replacement(&drain.node_id, &instance.build_id, requested_at).await else {
+            continue;
+        };
+        return match channel.lock().await.send(VmmEvent::Delete { id: instance.build_id.clone() }).await {
+            Ok(()) => (InstanceDrainState::Migrated { node_id }, None),
+            Err(e) => (InstanceDrainState::Failed { reason: format!("Replacement is ready on {node_id} but the local instance couldn't be deleted: {e}") }, None),
+        };
+    }
+
+    let (state, _) = stop_instance(channel, &instance.build_id).await;
+    (state, Some(format!("No replacement was ready within {}s, stopped instead", timeout.as_secs())))
+}
+
+/// A node other than this one running a replacement created after `since`
+async fn ready_replacement(local_node_id: &str, build_id: &str, since: u64) -> Option<String> {
+    let resp = Client::new()
+        .get(format!("{STATE_API}/instance/{build_id}/get_by_build_id"))
+        .send().await.ok()?
+        .json::<StateResponse<Instance>>().await.ok()?;
+    let StateResponse::Success(Success::List(instances)) = resp else {
+        return None;
+    };
+    instances.into_iter()
+        .find(|i| i.node_id != local_node_id && i.status == InstanceStatus::Ready && i.created_at >= since as i64)
+        .map(|i| i.node_id)
+}
+
+/// The instances on `node_id` that are booting or ready
+async fn node_instances(node_id: &str) -> Result<Vec<Instance>, VmmError> {
+    let resp = Client::new()
+        .get(format!("{STATE_API}/node/{node_id}/instances"))
+        .send().await
+        .map_err(|e| VmmError::SystemError(format!("Unable to reach form-state: {e}")))?
+        .json::<StateResponse<Instance>>().await
+        .map_err(|e| VmmError::SystemError(format!("Invalid response from form-state: {e}")))?;
+    match resp {
+        StateResponse::Success(Success::List(instances)) => Ok(instances.into_iter()
+            .filter(|i| matches!(i.status, InstanceStatus::Booting | InstanceStatus::Ready))
+            .collect()),
+        other => Err(VmmError::SystemError(format!("Unable to list the instances of node {node_id}: {other:?}"))),
+    }
+}
+
+async fn set_node_maintenance(node_id: &str, enabled: bool, reason: Option<String>) -> Result<(), VmmError> {
+    let resp = Client::new()
+        .post(format!("{STATE_API}/node/{node_id}/maintenance"))
+        .json(&serde_json::json!({ "enabled": enabled, "reason": reason }))
+        .send().await
+        .map_err(|e| VmmError::SystemError(e.to_string()))?
+        .json::<StateResponse<Node>>().await
+        .map_err(|e| VmmError::SystemError(e.to_string()))?;
+    match resp {
+        StateResponse::Success(_) => Ok(()),
+        other => Err(VmmError::SystemError(format!("{other:?}"))),
+    }
+}
+
+/// Drains are allowed for the node's owner and operator keys and for
+/// network admins
+async fn verify_admin(node_id: &str, address: &str) -> Result<(), VmmError> {
+    let client = Client::new();
+    let node = client.get(format!("{STATE_API}/node/{node_id}/get"))
+        .send().await
+        .map_err(|e| VmmError::SystemError(format!("Unable to reach form-state: {e}")))?
+        .json::<StateResponse<Node>>().await
+        .map_err(|e| VmmError::SystemError(format!("Invalid response from form-state: {e}")))?;
+    if let StateResponse::Success(Success::Some(node)) = node {
+        if node.is_admin_address(address) {
+            return Ok(());
+        }
+    }
+
+    let is_global_admin = match client.get(format!("{STATE_API}/account/{address}/is_global_admin")).send().await {
+        Ok(resp) => resp.json::<serde_json::Value>().await
+            .ok()
+            .and_then(|body| body["is_global_admin"].as_bool())
+            .unwrap_or(false),
+        Err(_) => false,
+    };
+    if is_global_admin {
+        Ok(())
+    } else {
+        Err(VmmError::Unauthorized(format!("{address} is not an admin of node {node_id}")))
+    }
+}
@@ -0,0 +1,277 @@
+//! Owner access to the guest agent of an instance.
+//!
+//! `POST /v1/guest/exec`, `/v1/guest/file` and `/v1/guest/health` forward a
+//! command over vsock to the agent inside the VM of `build_id` and return
+//! its answer. Running commands and reading files need the Manager
+//! permission on the instance, health only ReadOnly.
+//!
+//! `POST /v1/guest/exec/stream` runs a command like `/v1/guest/exec` but
+//! answers with a line of JSON for every piece of output as the command
+//! writes it, and an `exit` line at the end.
+//!
+//! `POST /v1/guest/attestation` asks the agent of a confidential instance for
+//! a SEV-SNP or TDX report bound to the caller's nonce, which needs ReadOnly.
+//!
+//! Every command run, or refused, is appended to `GUEST_EXEC_AUDIT_LOG`.
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use axum::{body::Body, http::{header, StatusCode}, response::Response, Extension, Json};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use form_types::{GuestCommand, GuestResponse, VmmFailure};
+use crate::guest_agent::GuestAgentRegistry;
+use crate::VmmError;
+use super::auth::{OwnershipVerifier, Permission, RecoveredAddress};
+
+/// Where the commands run in guests are recorded, one JSON object per line
+pub const GUEST_EXEC_AUDIT_LOG: &str = "/var/log/formation/guest-exec.log";
+
+type GuestFailure = (StatusCode, Json<VmmFailure>);
+type GuestResult = Result<Json<GuestResponse>, GuestFailure>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestExecRequest {
+    pub build_id: String,
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// A command run in a guest, or refused. The values of its environment
+/// aren't kept, they may hold secrets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestExecAudit {
+    pub at: u64,
+    pub address: String,
+    pub build_id: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub env_keys: Vec<String>,
+    pub streamed: bool,
+    /// `refused`, `started`, `exited`, `timed_out` or `failed`
+    pub outcome: String,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl GuestExecAudit {
+    fn new(address: &str, request: &GuestExecRequest, streamed: bool) -> Self {
+        Self {
+            at: 0,
+            address: address.to_string(),
+            build_id: request.build_id.clone(),
+            program: request.program.clone(),
+            args: request.args.clone(),
+            env_keys: request.env.keys().cloned().collect(),
+            streamed,
+            outcome: String::new(),
+            exit_code: None,
+            error: None,
+        }
+    }
+
+    /// Records the outcome the response stands for
+    async fn record_response(&self, response: &GuestResponse) {
+        match response {
+            GuestResponse::Exec { exit_code, timed_out, .. } | GuestResponse::Exit { exit_code, timed_out } => {
+                self.record(if *timed_out { "timed_out" } else { "exited" }, *exit_code, None).await
+            }
+            GuestResponse::Error { message } => self.record("failed", None, Some(message.clone())).await,
+            _ => {}
+        }
+    }
+
+    async fn record(&self, outcome: &str, exit_code: Option<i32>, error: Option<String>) {
+        let entry = Self {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            outcome: outcome.to_string(),
+            exit_code,
+            error,
+            ..self.clone()
+        };
+        log::info!(
+            "Guest exec audit: {} ran {} {:?} on {}: {}",
+            entry.address, entry.program, entry.args, entry.build_id, entry.outcome
+        );
+        if let Err(e) = append_audit(&entry).await {
+            log::error!("Unable to write to {GUEST_EXEC_AUDIT_LOG}: {e}");
+        }
+    }
+}
+
+async fn append_audit(entry: &GuestExecAudit) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    if let Some(dir) = std::path::Path::new(GUEST_EXEC_AUDIT_LOG).parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(GUEST_EXEC_AUDIT_LOG).await?;
+    file.write_all(&line).await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestFileRequest {
+    pub build_id: String,
+    pub path: String,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestHealthRequest {
+    pub build_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestAttestationRequest {
+    pub build_id: String,
+    /// Base64 encoded nonce of up to 64 bytes the report is bound to
+    #[serde(default)]
+    pub report_data: String,
+}
+
+pub async fn guest_exec(
+    Extension(agents): Extension<GuestAgentRegistry>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+    Json(request): Json<GuestExecRequest>,
+) -> GuestResult {
+    let audit = GuestExecAudit::new(&recovered_address.as_hex(), &request, false);
+    let command = GuestCommand::Exec {
+        program: request.program,
+        args: request.args,
+        env: request.env,
+        timeout_secs: request.timeout_secs,
+    };
+    let result = forward(&agents, &recovered_address, &request.build_id, Permission::Manager, command).await;
+    match &result {
+        Ok(Json(response)) => audit.record_response(response).await,
+        Err((status, _)) if *status == StatusCode::FORBIDDEN => audit.record("refused", None, None).await,
+        Err((_, Json(failure))) => audit.record("failed", None, Some(failure.message.clone())).await,
+    }
+    result
+}
+
+/// Answers with `application/x-ndjson`, one `GuestResponse` per line. Closing
+/// the connection kills the command.
+pub async fn guest_exec_stream(
+    Extension(agents): Extension<GuestAgentRegistry>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+    Json(request): Json<GuestExecRequest>,
+) -> Result<Response, GuestFailure> {
+    let audit = GuestExecAudit::new(&recovered_address.as_hex(), &request, true);
+    if let Err(e) = authorize(&agents, &recovered_address, &request.build_id, Permission::Manager).await {
+        let outcome = if e.0 == StatusCode::FORBIDDEN { "refused" } else { "failed" };
+        audit.record(outcome, None, Some(e.1.message.clone())).await;
+        return Err(e);
+    }
+
+    let command = GuestCommand::ExecStream {
+        program: request.program,
+        args: request.args,
+        env: request.env,
+        timeout_secs: request.timeout_secs,
+    };
+    let responses = match agents.request_stream(&request.build_id, command).await {
+        Ok(responses) => responses,
+        Err(e) => {
+            audit.record("failed", None, Some(e.to_string())).await;
+            return Err(agent_failure(e));
+        }
+    };
+    audit.record("started", None, None).await;
+
+    let lines = futures::stream::unfold((responses, audit), |(mut responses, audit)| async move {
+        let response = responses.recv().await?;
+        audit.record_response(&response).await;
+        let mut line = serde_json::to_vec(&response).unwrap_or_default();
+        line.push(b'\n');
+        Some((Ok::<_, Infallible>(line), (responses, audit)))
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(lines))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, Json(VmmFailure::from(&VmmError::OperationFailed(e.to_string())))))
+}
+
+pub async fn guest_fetch_file(
+    Extension(agents): Extension<GuestAgentRegistry>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+    Json(request): Json<GuestFileRequest>,
+) -> GuestResult {
+    let command = GuestCommand::FetchFile { path: request.path, max_bytes: request.max_bytes };
+    forward(&agents, &recovered_address, &request.build_id, Permission::Manager, command).await
+}
+
+pub async fn guest_health(
+    Extension(agents): Extension<GuestAgentRegistry>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+    Json(request): Json<GuestHealthRequest>,
+) -> GuestResult {
+    forward(&agents, &recovered_address, &request.build_id, Permission::ReadOnly, GuestCommand::Health).await
+}
+
+pub async fn guest_attestation(
+    Extension(agents): Extension<GuestAgentRegistry>,
+    Extension(recovered_address): Extension<Arc<RecoveredAddress>>,
+    Json(request): Json<GuestAttestationRequest>,
+) -> GuestResult {
+    let command = GuestCommand::AttestationReport { report_data: request.report_data };
+    forward(&agents, &recovered_address, &request.build_id, Permission::ReadOnly, command).await
+}
+
+async fn forward(
+    agents: &GuestAgentRegistry,
+    recovered_address: &RecoveredAddress,
+    build_id: &str,
+    permission: Permission,
+    command: GuestCommand,
+) -> GuestResult {
+    authorize(agents, recovered_address, build_id, permission).await?;
+    log::info!("Forwarding {command:?} from {} to the guest agent of {build_id}", recovered_address.as_hex());
+    agents.request(build_id, command).await
+        .map(Json)
+        .map_err(agent_failure)
+}
+
+fn failure(status: StatusCode, error: VmmError) -> GuestFailure {
+    (status, Json(VmmFailure::from(&error)))
+}
+
+fn agent_failure(error: VmmError) -> GuestFailure {
+    match error {
+        VmmError::VmNotFound(_) => failure(StatusCode::NOT_FOUND, error),
+        e => failure(StatusCode::BAD_GATEWAY, e),
+    }
+}
+
+/// Checks the caller holds `permission` on the instance of `build_id` on
+/// this node
+async fn authorize(
+    agents: &GuestAgentRegistry,
+    recovered_address: &RecoveredAddress,
+    build_id: &str,
+    permission: Permission,
+) -> Result<(), GuestFailure> {
+    let address = recovered_address.as_hex();
+    let instance_id = agents.instance_id(build_id).map_err(|e| failure(StatusCode::BAD_REQUEST, e))?;
+
+    match OwnershipVerifier::verify_authorization(&instance_id, &address, permission).await {
+        Ok(true) => {}
+        Ok(false) => {
+            log::warn!("Unauthorized guest agent request for {build_id} by {address}");
+            return Err(failure(StatusCode::FORBIDDEN, VmmError::Unauthorized(
+                format!("{address} is not permitted to use the guest agent of {build_id}")
+            )));
+        }
+        Err(e) => return Err(failure(StatusCode::BAD_GATEWAY, e)),
+    }
+    Ok(())
+}
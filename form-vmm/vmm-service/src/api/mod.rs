@@ -13,9 +13,18 @@ use std::{sync::Arc, time::Duration};
 use std::net::SocketAddr;
 
 use crate::VmmError;
-use form_types::{BootCompleteRequest, CreateVmRequest, DeleteVmRequest, GetVmRequest, PingVmmRequest, StartVmRequest, StopVmRequest, VmResponse, VmmEvent, VmmResponse};
+use crate::instance::balloon::read_host_memory;
+use crate::instance::network_policy::NetworkPolicy;
+use drain::DrainController;
+use crate::guest_agent::GuestAgentRegistry;
+use form_state::auth::cache::{verify_cache_stats, VerifyCacheStats};
+use form_pack::formfile::Formfile;
+use form_types::{BootCompleteRequest, CreateVmRequest, DeleteVmRequest, GetVmRequest, PingVmmRequest, ReadinessRequest, StartVmRequest, StopVmRequest, VmResponse, VmmErrorCode, VmmEvent, VmmFailure, VmmNack, VmmResponse, VMM_NACK_TOPIC};
 
 pub mod auth;
+pub mod clone;
+pub mod drain;
+pub mod guest;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -25,6 +34,13 @@ pub enum HealthStatus {
     Unhealthy { reason: String }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateNetworkPolicyRequest {
+    pub id: String,
+    pub name: String,
+    pub policy: NetworkPolicy,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HealthResponse {
     status: HealthStatus,
@@ -82,20 +98,27 @@ pub struct VmmApi {
     channel: Arc<Mutex<VmmApiChannel>>,
     /// Server address
     addr: SocketAddr,
+    /// Maintenance state of the node, shared with the queue reader
+    drain: DrainController,
+    /// Guest agents of the instances on this node
+    guest_agents: GuestAgentRegistry,
 }
 
 impl VmmApi {
     pub fn new(
         api_channel: Arc<Mutex<VmmApiChannel>>,
-        addr: SocketAddr
+        addr: SocketAddr,
+        drain: DrainController,
+        guest_agents: GuestAgentRegistry,
     ) -> Self {
         Self {
-            channel: api_channel, addr
+            channel: api_channel, addr, drain, guest_agents
         }
     }
 
     pub async fn start_queue_reader(
         channel: Arc<Mutex<VmmApiChannel>>,
+        drain: DrainController,
         mut shutdown: tokio::sync::broadcast::Receiver<()>
     ) -> Result<(), VmmError> { 
         let mut n = 0;
@@ -104,8 +127,14 @@ impl VmmApi {
             tokio::select! {
                 Ok(messages) = Self::read_from_queue(Some(n), None) => {
                     for message in &messages {
-                        if let Err(e) = Self::handle_message(message.to_vec(), channel.clone()).await {
-                            eprintln!("Error handling message in queue reader: {e}");
+                        let correlation_id = form_p2p::trace::record(message, "vmm-service", "received", None).await;
+                        let trace = correlation_id.as_deref().unwrap_or("untraced");
+                        if let Err(e) = Self::handle_message(message.to_vec(), channel.clone(), &drain).await {
+                            eprintln!("Error handling message {trace} in queue reader: [{}] {e}", e.code());
+                            form_p2p::trace::record(message, "vmm-service", "failed", Some(format!("[{}] {e}", e.code()))).await;
+                            Self::nack(message[0], &e).await;
+                        } else {
+                            form_p2p::trace::record(message, "vmm-service", "processed", None).await;
                         }
                     }
                     n += messages.len();
@@ -119,12 +148,12 @@ impl VmmApi {
         Ok(())
     }
 
-    pub async fn handle_message(message: Vec<u8>, channel: Arc<Mutex<VmmApiChannel>>) -> Result<(), VmmError> {
+    pub async fn handle_message(message: Vec<u8>, channel: Arc<Mutex<VmmApiChannel>>, drain: &DrainController) -> Result<(), VmmError> {
         let subtopic = message[0];
         log::info!("Received subtopic: {subtopic}");
         let msg = &message[1..];
         match subtopic {
-            0 => Self::handle_create_vm_message(msg, channel.clone()).await?,
+            0 => Self::handle_create_vm_message(msg, channel.clone(), drain).await?,
             1 => Self::handle_boot_vm_message(msg, channel.clone()).await?, 
             2 => Self::handle_delete_vm_message(msg, channel.clone()).await?,
             3 => Self::handle_stop_vm_message(msg, channel.clone()).await?,
@@ -135,6 +164,14 @@ impl VmmApi {
         Ok(())
     }
 
+    /// Publishes the failure of a queued request so the sender can act on it
+    async fn nack(subtopic: u8, error: &VmmError) {
+        let nack = VmmNack { subtopic, failure: VmmFailure::from(error) };
+        if let Err(e) = Self::write_to_queue(nack, 0, VMM_NACK_TOPIC).await {
+            log::error!("Unable to publish nack for subtopic {subtopic}: {e}");
+        }
+    }
+
     pub fn extract_build_id(name: String, owner: String) -> Result<String, VmmError> {
         let mut hasher = Sha3::v256();
         let mut hash = [0u8; 32];
@@ -147,12 +184,14 @@ impl VmmApi {
         Ok(hex::encode(hash))
     }
 
-    pub async fn handle_create_vm_message(msg: &[u8], channel: Arc<Mutex<VmmApiChannel>>) -> Result<(), VmmError> {
+    pub async fn handle_create_vm_message(msg: &[u8], channel: Arc<Mutex<VmmApiChannel>>, drain: &DrainController) -> Result<(), VmmError> {
         log::info!("Received create request from queue..");
         let request: CreateVmRequest = serde_json::from_slice(msg).map_err(|e| {
             VmmError::Config(format!("Failed to deserialize CreateVmRequest from queue: {}",e.to_string())) // More specific error
         })?;
         log::info!("Deserialized create request for name: {}, owner: {}", request.name, request.owner);
+        drain.admit().await?;
+        admit_create(&request.formfile, &request.owner).await?;
         
         // Owner is now directly from the trusted queue message
         let event = VmmEvent::Create { 
@@ -350,10 +389,13 @@ impl VmmApi {
         // Define protected routes that require authentication
         let protected_routes = Router::new()
             .route("/create", post(create))
+            .route("/clone", post(clone::clone_vm))
             .route("/boot_complete", post(boot_complete))
+            .route("/readiness", post(readiness))
             .route("/start", post(start))
             .route("/stop", post(stop))
             .route("/delete", post(delete))
+            .route("/network_policy", post(update_network_policy))
             .route("/get_vm", post(get_vm))
             .route("/list", get(list))
             .route("/power_button", post(power_button))
@@ -370,13 +412,23 @@ impl VmmApi {
             .route("/remove_device", post(remove_device))
             .route("/migrate_to", post(migrate_to))
             .route("/migrate_from", post(migrate_from))
+            .route("/admin/drain", post(drain::start_drain).get(drain::drain_status))
+            .route("/admin/drain/cancel", post(drain::cancel_drain))
+            .route("/guest/exec", post(guest::guest_exec))
+            .route("/guest/exec/stream", post(guest::guest_exec_stream))
+            .route("/guest/file", post(guest::guest_fetch_file))
+            .route("/guest/health", post(guest::guest_health))
+            .route("/guest/attestation", post(guest::guest_attestation))
             .layer(axum::middleware::from_fn(auth::ecdsa_auth_middleware_x_headers))
+            .layer(Extension(self.drain.clone()))
+            .layer(Extension(self.guest_agents.clone()))
             .with_state(channel.clone());
         
         // Define public routes that don't require authentication
         let public_routes = Router::new()
             .route("/health", get(health_check))
             .route("/ping", post(ping))
+            .route("/auth/verify_cache", get(verify_cache))
             .with_state(channel.clone());
         
         let v1_routes = Router::new()
@@ -411,6 +463,11 @@ async fn health_check() -> Json<HealthResponse> {
     })
 }
 
+/// Hit rate and size of the signature verification cache
+async fn verify_cache() -> Json<VerifyCacheStats> {
+    Json(verify_cache_stats())
+}
+
 async fn ping(
     State(channel): State<Arc<Mutex<VmmApiChannel>>>,
     Json(request): Json<PingVmmRequest>
@@ -421,6 +478,7 @@ async fn ping(
 
 async fn create(
     State(channel): State<Arc<Mutex<VmmApiChannel>>>,
+    Extension(drain): Extension<DrainController>,
     Extension(recovered_address): Extension<Arc<auth::RecoveredAddress>>,
     Json(request): Json<CreateVmRequest>,
 ) -> Json<VmmResponse> {
@@ -432,6 +490,23 @@ async fn create(
 
     let owner_hex = recovered_address.as_hex();
 
+    if let Err(e) = drain.admit().await {
+        log::warn!("Rejecting VM create request for {}: {e}", request.name);
+        return Json(VmmResponse::Failure(VmmFailure::from(&e)));
+    }
+
+    // Only user requests are frozen, replacements a drain requests through
+    // the queue still have to land while the network is in maintenance
+    if let Err(e) = drain.admit_deployment().await {
+        log::warn!("Rejecting VM create request for {}: {e}", request.name);
+        return Json(VmmResponse::Failure(VmmFailure::from(&e)));
+    }
+
+    if let Err(e) = admit_create(&request.formfile, &owner_hex).await {
+        log::warn!("Rejecting VM create request for {}: {e}", request.name);
+        return Json(VmmResponse::Failure(VmmFailure::from(&e)));
+    }
+
     let event = VmmEvent::Create {
         formfile: request.formfile.clone(),
         name: request.name.clone(),
@@ -443,7 +518,7 @@ async fn create(
     if let Err(e) = guard.send(event.clone()).await {
         log::error!("Error sending VmmEvent::Create for {}: {}", request.name, e);
         return Json(
-            VmmResponse::Failure(
+            VmmResponse::failure(VmmErrorCode::Internal,
                 format!(
                     "Error queueing creation for vm {}: {}",
                     request.name,
@@ -462,6 +537,49 @@ async fn create(
     }))
 }
 
+/// Rejects create requests the owner's quota or this host can't take.
+/// Formfiles that don't parse are left for the create path to report.
+async fn admit_create(formfile: &str, owner: &str) -> Result<(), VmmError> {
+    let Ok(formfile) = serde_json::from_str::<Formfile>(formfile) else {
+        return Ok(());
+    };
+
+    let requested = formfile.get_memory() as u64 * 1024 * 1024;
+    let (_, available) = read_host_memory()?;
+    if requested > available {
+        return Err(VmmError::InsufficientCapacity(format!(
+            "{} MiB of memory requested, {} MiB available",
+            requested / (1024 * 1024),
+            available / (1024 * 1024)
+        )));
+    }
+
+    let usage = serde_json::json!({
+        "instances": 1,
+        "vcpus": formfile.get_vcpus(),
+        "memory_mb": formfile.get_memory(),
+    });
+    let resp = Client::new()
+        .post(format!("http://127.0.0.1:3004/v1/quota/{owner}/check"))
+        .json(&usage)
+        .send()
+        .await;
+    // form-state enforces the quota again when the instance is recorded, so
+    // an unreachable form-state doesn't block the request here
+    match resp {
+        Ok(resp) if resp.status() == reqwest::StatusCode::FORBIDDEN => {
+            let body = resp.json::<serde_json::Value>().await.unwrap_or_default();
+            let reason = body["error"].as_str().unwrap_or("account quota exceeded");
+            Err(VmmError::QuotaExceeded(reason.to_string()))
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::warn!("Unable to check quota of {owner}: {e}");
+            Ok(())
+        }
+    }
+}
+
 async fn boot_complete(
     State(channel): State<Arc<Mutex<VmmApiChannel>>>,
     Json(request): Json<BootCompleteRequest>,
@@ -478,7 +596,7 @@ async fn boot_complete(
     if let Err(e) = guard.send(event.clone()).await {
         log::info!("Error receiving response back from API channel: {e}");
         return Json(
-            VmmResponse::Failure(
+            VmmResponse::failure(VmmErrorCode::Internal,
                 format!("Error recording BootComplete event {event:?}: {e}")
             )
         )
@@ -494,6 +612,35 @@ async fn boot_complete(
     ))
 }
 
+async fn readiness(
+    State(channel): State<Arc<Mutex<VmmApiChannel>>>,
+    Json(request): Json<ReadinessRequest>,
+) -> Json<VmmResponse> {
+    log::info!("Received ReadinessRequest for VM {}: ready={}", request.name, request.ready);
+    let event = VmmEvent::Readiness {
+        id: request.name.clone(),
+        build_id: request.build_id.clone(),
+        ready: request.ready,
+        consecutive_failures: request.consecutive_failures,
+        detail: request.detail,
+    };
+
+    let guard = channel.lock().await;
+    if let Err(e) = guard.send(event.clone()).await {
+        log::error!("Error sending VmmEvent::Readiness for {}: {e}", request.name);
+        return Json(VmmResponse::failure(VmmErrorCode::Internal, format!("Error recording Readiness event {event:?}: {e}")));
+    }
+    drop(guard);
+
+    Json(VmmResponse::Success(
+        VmResponse {
+            id: request.name.clone(),
+            name: request.name,
+            state: if request.ready { "ready" } else { "unhealthy" }.to_string(),
+        }
+    ))
+}
+
 async fn start(
     State(channel): State<Arc<Mutex<VmmApiChannel>>>,
     Extension(recovered_address): Extension<Arc<auth::RecoveredAddress>>,
@@ -508,14 +655,14 @@ async fn start(
         },
         Ok(false) => {
             log::warn!("Unauthorized start request on instance {} by address {}", request.id, recovered_address.as_hex());
-            return Json(VmmResponse::Failure(
+            return Json(VmmResponse::failure(VmmErrorCode::AuthFailed,
                 format!("Unauthorized: Address {} is not permitted to start instance {}", 
                        recovered_address.as_hex(), request.id)
             ));
         },
         Err(e) => {
             log::error!("Error checking authorization for start request on instance {}: {}", request.id, e);
-            return Json(VmmResponse::Failure(
+            return Json(VmmResponse::failure(e.code(),
                 format!("Authorization check failed for instance {}: {}", request.id, e)
             ));
         }
@@ -528,7 +675,7 @@ async fn start(
     let guard = channel.lock().await;
     if let Err(e) = guard.send(event).await {
         log::error!("Error sending VmmEvent::Start for {}: {}", request.id, e);
-        return Json(VmmResponse::Failure(format!("Error queueing start for vm {}: {}", request.id, e)));
+        return Json(VmmResponse::failure(VmmErrorCode::Internal, format!("Error queueing start for vm {}: {}", request.id, e)));
     }
     drop(guard);
 
@@ -554,14 +701,14 @@ async fn stop(
         },
         Ok(false) => {
             log::warn!("Unauthorized stop request on instance {} by address {}", request.id, recovered_address.as_hex());
-            return Json(VmmResponse::Failure(
+            return Json(VmmResponse::failure(VmmErrorCode::AuthFailed,
                 format!("Unauthorized: Address {} is not permitted to stop instance {}", 
                        recovered_address.as_hex(), request.id)
             ));
         },
         Err(e) => {
             log::error!("Error checking authorization for stop request on instance {}: {}", request.id, e);
-            return Json(VmmResponse::Failure(
+            return Json(VmmResponse::failure(e.code(),
                 format!("Authorization check failed for instance {}: {}", request.id, e)
             ));
         }
@@ -574,7 +721,7 @@ async fn stop(
     let guard = channel.lock().await;
     if let Err(e) = guard.send(event).await {
         log::error!("Error sending VmmEvent::Stop for {}: {}", request.id, e);
-        return Json(VmmResponse::Failure(format!("Error queueing stop for vm {}: {}", request.id, e)));
+        return Json(VmmResponse::failure(VmmErrorCode::Internal, format!("Error queueing stop for vm {}: {}", request.id, e)));
     }
     drop(guard);
 
@@ -601,15 +748,14 @@ async fn delete(
         },
         Ok(false) => {
             log::warn!("Unauthorized delete request on instance {} by address {}", request.id, recovered_address.as_hex());
-            return Json(VmmResponse::Failure(
+            return Json(VmmResponse::failure(VmmErrorCode::AuthFailed,
                 format!("Unauthorized: Address {} is not permitted to delete instance {}", 
                        recovered_address.as_hex(), request.id)
             ));
         },
         Err(e) => {
             log::error!("Error checking authorization for delete request on instance {}: {}", request.id, e);
-            // Ensure VmmError is converted to string for the VmmResponse::Failure
-            return Json(VmmResponse::Failure(
+            return Json(VmmResponse::failure(e.code(),
                 format!("Authorization check failed for instance {}: {}", request.id, e.to_string())
             ));
         }
@@ -622,7 +768,7 @@ async fn delete(
     let guard = channel.lock().await;
     if let Err(e) = guard.send(event).await {
         log::error!("Error sending VmmEvent::Delete for {}: {}", request.id, e);
-        return Json(VmmResponse::Failure(format!("Error queueing delete for vm {}: {}", request.id, e)));
+        return Json(VmmResponse::failure(VmmErrorCode::Internal, format!("Error queueing delete for vm {}: {}", request.id, e)));
     }
     drop(guard);
 
@@ -634,6 +780,61 @@ async fn delete(
     }))
 }
 
+async fn update_network_policy(
+    State(channel): State<Arc<Mutex<VmmApiChannel>>>,
+    Extension(recovered_address): Extension<Arc<auth::RecoveredAddress>>,
+    Json(request): Json<UpdateNetworkPolicyRequest>,
+) -> Json<VmmResponse> {
+    log::info!("Received network policy update: id={}, name={}, owner={}",
+        request.id, request.name, recovered_address.as_hex());
+
+    match auth::OwnershipVerifier::verify_authorization(&request.id, &recovered_address.as_hex(), auth::Permission::Manager).await {
+        Ok(true) => {
+            log::info!("Authorization successful for network policy update on instance {}", request.id);
+        },
+        Ok(false) => {
+            log::warn!("Unauthorized network policy update on instance {} by address {}", request.id, recovered_address.as_hex());
+            return Json(VmmResponse::failure(VmmErrorCode::AuthFailed,
+                format!("Unauthorized: Address {} is not permitted to change the network policy of instance {}",
+                       recovered_address.as_hex(), request.id)
+            ));
+        },
+        Err(e) => {
+            log::error!("Error checking authorization for network policy update on instance {}: {}", request.id, e);
+            return Json(VmmResponse::failure(e.code(),
+                format!("Authorization check failed for instance {}: {}", request.id, e)
+            ));
+        }
+    }
+
+    if let Err(e) = request.policy.validate() {
+        return Json(VmmResponse::failure(VmmErrorCode::InvalidRequest, format!("Invalid network policy: {e}")));
+    }
+
+    let policy = match serde_json::to_string(&request.policy) {
+        Ok(policy) => policy,
+        Err(e) => return Json(VmmResponse::failure(VmmErrorCode::Internal, format!("Unable to serialize network policy: {e}"))),
+    };
+    let event = VmmEvent::UpdateNetworkPolicy {
+        id: request.id.clone(),
+        policy,
+    };
+
+    let guard = channel.lock().await;
+    if let Err(e) = guard.send(event).await {
+        log::error!("Error sending VmmEvent::UpdateNetworkPolicy for {}: {}", request.id, e);
+        return Json(VmmResponse::failure(VmmErrorCode::Internal, format!("Error queueing network policy update for vm {}: {}", request.id, e)));
+    }
+    drop(guard);
+
+    Json(VmmResponse::Success(
+        VmResponse {
+            id: request.id,
+            name: request.name,
+            state: "NETWORK_POLICY_UPDATE_REQUESTED".to_string()
+    }))
+}
+
 async fn get_vm(
     State(channel): State<Arc<Mutex<VmmApiChannel>>>,
     Extension(recovered_address): Extension<Arc<auth::RecoveredAddress>>,
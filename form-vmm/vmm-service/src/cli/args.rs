@@ -32,6 +32,20 @@ pub enum CliCommand {
         /// Message broker Publish Address
         #[arg(long, short)]
         pub_addr: Option<String>,
+        /// Seconds an instance may take to report boot complete before it is restarted
+        #[arg(long, default_value="300")]
+        boot_timeout: u64,
+        /// Restarts attempted before an instance that never boots is marked failed
+        #[arg(long, default_value="2")]
+        boot_restarts: u32,
+        /// Accept instances without launching VMs, for local development
+        /// on machines without KVM. Instances are reported booted right away.
+        #[arg(long)]
+        mock: bool,
+        /// Refuse to boot images whose build manifest has no dm-verity root
+        /// hash, instead of checking them against the image digest
+        #[arg(long)]
+        require_verity: bool,
     },
     /// Show service status
     #[command(name = "status")]
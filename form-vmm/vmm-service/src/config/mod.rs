@@ -3,7 +3,7 @@ use net_util::MacAddr;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-use super::{VmInstanceConfig, ConsoleType}; 
+use super::{VmInstanceConfig, ConsoleType, BalloonPolicy}; 
 use vmm::vm_config::{
     ConsoleConfig,
     ConsoleOutputMode,
@@ -15,12 +15,19 @@ use vmm::vm_config::{
     RngConfig, 
     VhostMode, 
     VmConfig,
+    VsockConfig,
     DeviceConfig,
+    PlatformConfig,
+    default_platformconfig_num_pci_segments,
 };
+use form_pack::formfile::ConfidentialMode;
+use crate::instance::config::TDVF_PATH;
+use form_types::GUEST_AGENT_CID;
+use crate::guest_agent::vsock_socket_path;
 
 pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
 
-    let disks = vec![DiskConfig {
+    let mut disks = vec![DiskConfig {
         // This needs to be a copied disk, raw cannot use backing file
         path: Some(config.rootfs_path.clone()),
         readonly: false,
@@ -40,6 +47,28 @@ pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
         disable_aio: false,       // New field
     }];
 
+    // The NoCloud seed is picked up by cloud-init through its `cidata` label
+    if let Some(cloud_init_path) = &config.cloud_init_path {
+        disks.push(DiskConfig {
+            path: Some(cloud_init_path.clone()),
+            readonly: true,
+            direct: false,
+            vhost_user: false,
+            vhost_socket: None,
+            rate_limiter_config: None,
+            queue_size: 128,
+            num_queues: 1,
+            queue_affinity: None,
+            id: Some(format!("cidata_{}", config.name)),
+            rate_limit_group: None,
+            pci_segment: 0,
+            iommu: false,
+            serial: None,
+            disable_io_uring: false,
+            disable_aio: false,
+        });
+    }
+
     let (serial, console) = match config.console_type {
         ConsoleType::Serial => (
             ConsoleConfig {
@@ -75,7 +104,9 @@ pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
         tap: Some(config.tap_device.to_string()),
         ip: config.ip_addr.parse().unwrap(),  // Use our bridge IP as gateway
         mask: "255.255.255.0".parse().unwrap(),
-        mac: MacAddr::local_random(),  // Default MAC, can be configured
+        mac: config.mac_addr.as_deref()
+            .and_then(|mac| MacAddr::parse_str(mac).ok())
+            .unwrap_or_else(MacAddr::local_random),
         host_mac: None,
         mtu: Some(1500),
         iommu: false,
@@ -86,7 +117,7 @@ pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
         vhost_mode: VhostMode::Client,
         id: Some(format!("net_{}", config.name)),
         fds: None,
-        rate_limiter_config: None,
+        rate_limiter_config: config.network_policy.rate_limiter_config(),
         pci_segment: 0,
         offload_tso: true,
         offload_ufo: true,
@@ -148,19 +179,14 @@ pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
             size: config.memory_mb << 20, // Convert MB to bytes
             ..MemoryConfig::default()
         },
-        payload: Some(PayloadConfig {
-            kernel: Some(config.kernel_path.clone()),
-            initramfs: None,
-            cmdline: None, 
-            firmware: None,
-        }),
+        payload: Some(payload_config(config)),
         disks: Some(disks),
         net,
         rng: RngConfig {
             src: config.rng_source.clone().unwrap_or_else(|| "/dev/urandom".to_string()).into(),
             iommu: false,
         },
-        balloon: None,
+        balloon: BalloonPolicy::new(config.memory_mb, config.memory_tier).balloon_config(),
         fs: None,
         pmem: None,
         serial,
@@ -170,7 +196,14 @@ pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
         devices, // Use our configured GPU devices
         user_devices: None,
         vdpa: None,
-        vsock: None,
+        // Channel to the guest agent
+        vsock: Some(VsockConfig {
+            cid: GUEST_AGENT_CID,
+            socket: vsock_socket_path(&config.name),
+            iommu: false,
+            id: None,
+            pci_segment: 0,
+        }),
         pvpanic: false,
         #[cfg(feature = "pvmemcontrol")]
         pvmemcontrol: None,
@@ -181,7 +214,7 @@ pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
         watchdog: false,
         #[cfg(feature = "guest_debug")]
         gdb: false,
-        platform: None,
+        platform: config.confidential.map(platform_config),
         tpm: None,
         preserved_fds: None,
         landlock_enable: false,
@@ -191,6 +224,43 @@ pub fn create_vm_config(config: &VmInstanceConfig) -> VmConfig {
     }
 }
 
+/// Confidential guests don't boot the kernel directly, TDX guests start from
+/// TDVF and SEV-SNP guests from an IGVM file that gets measured at launch
+fn payload_config(config: &VmInstanceConfig) -> PayloadConfig {
+    let (kernel, firmware) = match config.confidential {
+        Some(ConfidentialMode::Tdx) => (None, Some(TDVF_PATH.into())),
+        Some(ConfidentialMode::SevSnp) => (None, None),
+        None => (Some(config.kernel_path.clone()), None),
+    };
+    PayloadConfig {
+        kernel,
+        initramfs: None,
+        cmdline: None,
+        firmware,
+        #[cfg(feature = "igvm")]
+        igvm: (config.confidential == Some(ConfidentialMode::SevSnp)).then(|| crate::instance::config::SNP_IGVM_PATH.into()),
+        #[cfg(feature = "sev_snp")]
+        host_data: None,
+    }
+}
+
+/// `VmInstanceConfig::validate` has already rejected technologies vmm
+/// wasn't built with
+#[cfg_attr(not(any(feature = "tdx", feature = "sev_snp")), allow(unused_variables))]
+fn platform_config(mode: ConfidentialMode) -> PlatformConfig {
+    PlatformConfig {
+        num_pci_segments: default_platformconfig_num_pci_segments(),
+        iommu_segments: None,
+        serial_number: None,
+        uuid: None,
+        oem_strings: None,
+        #[cfg(feature = "tdx")]
+        tdx: mode == ConfidentialMode::Tdx,
+        #[cfg(feature = "sev_snp")]
+        sev_snp: mode == ConfidentialMode::SevSnp,
+    }
+}
+
 /// Default paths for VMM Service
 pub struct ServicePaths;
 
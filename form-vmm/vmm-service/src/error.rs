@@ -1,4 +1,5 @@
 use thiserror::Error;
+use form_types::{VmmErrorCode, VmmFailure};
 use vmm::landlock::LandlockError;
 
 #[derive(Error, Debug)]
@@ -85,6 +86,47 @@ pub enum VmmError {
 
     #[error("Network error: {0}")]
     NetworkError(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Image not found: {0}")]
+    ImageNotFound(String),
+
+    #[error("Insufficient node capacity: {0}")]
+    InsufficientCapacity(String),
+
+    #[error("Node is draining: {0}")]
+    NodeDraining(String),
+
+    #[error("Deployments are frozen: {0}")]
+    DeploymentsFrozen(String),
+}
+
+impl VmmError {
+    /// The code reported to API and queue clients for this error
+    pub fn code(&self) -> VmmErrorCode {
+        match self {
+            VmmError::Unauthorized(_) => VmmErrorCode::AuthFailed,
+            VmmError::QuotaExceeded(_) => VmmErrorCode::QuotaExceeded,
+            VmmError::ImageNotFound(_) => VmmErrorCode::ImageNotFound,
+            VmmError::InsufficientCapacity(_) => VmmErrorCode::NodeCapacity,
+            VmmError::NodeDraining(_) => VmmErrorCode::NodeDraining,
+            VmmError::DeploymentsFrozen(_) => VmmErrorCode::DeploymentsFrozen,
+            VmmError::VmNotFound(_) => VmmErrorCode::InstanceNotFound,
+            VmmError::InvalidPath(_) => VmmErrorCode::InvalidRequest,
+            _ => VmmErrorCode::Internal,
+        }
+    }
+}
+
+impl From<&VmmError> for VmmFailure {
+    fn from(error: &VmmError) -> Self {
+        VmmFailure::new(error.code(), error.to_string())
+    }
 }
 
 unsafe impl Send for VmmError {}
@@ -0,0 +1,273 @@
+//! Host end of the guest agent channel
+//!
+//! Every VM gets a vsock device backed by a unix socket under `VSOCK_DIR`.
+//! Cloud Hypervisor forwards a connection to that socket into the guest
+//! once it's told the port with `CONNECT <port>`, and forwards connections
+//! the guest makes to the host's port `P` to `<socket>_P`. The registry
+//! hands out the per-instance token that is written into the guest, listens
+//! for the guest's reports and sends requests to the agent in the guest.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use form_pack::helpers::utils::build_instance_id;
+use form_types::{
+    guest_token_matches, GuestCommand, GuestEvent, GuestReport, GuestRequest, GuestResponse,
+    VmmEvent, DEFAULT_EXEC_TIMEOUT_SECS, GUEST_AGENT_PORT, HOST_REPORT_PORT, MAX_GUEST_MESSAGE_SIZE,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use crate::VmmError;
+
+/// Directory the vsock sockets of the VMs live in
+pub const VSOCK_DIR: &str = "/run/form-vmm/vsock";
+
+/// Time allowed on top of a command's own timeout for the round trip
+const REQUEST_OVERHEAD: Duration = Duration::from_secs(10);
+
+/// Time a guest gets to send its report once it has connected
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Unix socket backing the vsock device of VM `name`
+pub fn vsock_socket_path(name: &str) -> PathBuf {
+    PathBuf::from(VSOCK_DIR).join(format!("{name}.sock"))
+}
+
+/// Unix socket the guest's reports to `HOST_REPORT_PORT` arrive on
+pub fn report_socket_path(name: &str) -> PathBuf {
+    PathBuf::from(VSOCK_DIR).join(format!("{name}.sock_{HOST_REPORT_PORT}"))
+}
+
+struct GuestAgent {
+    token: String,
+    listener: JoinHandle<()>,
+}
+
+/// Guest agents of the instances running on this node
+#[derive(Clone)]
+pub struct GuestAgentRegistry {
+    node_id: String,
+    agents: Arc<RwLock<HashMap<String, GuestAgent>>>,
+    events: mpsc::Sender<VmmEvent>,
+}
+
+impl GuestAgentRegistry {
+    /// Reports from guests are turned into events sent on `events`
+    pub fn new(node_id: String, events: mpsc::Sender<VmmEvent>) -> Self {
+        Self { node_id, agents: Arc::new(RwLock::new(HashMap::new())), events }
+    }
+
+    /// Id of the instance VM `name` runs on this node
+    pub fn instance_id(&self, name: &str) -> Result<String, VmmError> {
+        build_instance_id(self.node_id.clone(), name.to_string())
+            .map_err(|e| VmmError::Config(format!("Invalid build id {name}: {e}")))
+    }
+
+    /// Issues the token of VM `name` and starts listening for its reports.
+    /// Must be called before the VM is created so the sockets are in place.
+    pub async fn register(&self, name: &str) -> Result<String, VmmError> {
+        self.deregister(name).await;
+        std::fs::create_dir_all(VSOCK_DIR)?;
+        let listener = UnixListener::bind(report_socket_path(name))?;
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(48)
+            .map(char::from)
+            .collect();
+        let listener = tokio::spawn(listen_for_reports(listener, name.to_string(), token.clone(), self.events.clone()));
+        self.agents.write().await.insert(name.to_string(), GuestAgent { token: token.clone(), listener });
+        Ok(token)
+    }
+
+    /// Stops listening to VM `name` and removes its sockets
+    pub async fn deregister(&self, name: &str) {
+        if let Some(agent) = self.agents.write().await.remove(name) {
+            agent.listener.abort();
+        }
+        let _ = std::fs::remove_file(vsock_socket_path(name));
+        let _ = std::fs::remove_file(report_socket_path(name));
+    }
+
+    async fn token(&self, name: &str) -> Result<String, VmmError> {
+        self.agents.read().await.get(name).map(|agent| agent.token.clone())
+            .ok_or_else(|| VmmError::VmNotFound(format!("No guest agent registered for {name}")))
+    }
+
+    /// Sends `command` to the agent in VM `name`
+    pub async fn request(&self, name: &str, command: GuestCommand) -> Result<GuestResponse, VmmError> {
+        let token = self.token(name).await?;
+        let timeout = request_timeout(&command);
+        let send = async {
+            let mut stream = send_request(name, GuestRequest { token, command }).await?;
+            read_message(&mut stream).await
+        };
+        tokio::time::timeout(timeout, send).await
+            .map_err(|_| VmmError::OperationFailed(format!("Guest agent of {name} didn't answer within {}s", timeout.as_secs())))?
+    }
+
+    /// Sends an `ExecStream` command to the agent in VM `name`. The answers
+    /// arrive on the receiver as the agent sends them, it closes after the
+    /// `Exit` or an `Error`. Dropping the receiver kills the command.
+    pub async fn request_stream(&self, name: &str, command: GuestCommand) -> Result<mpsc::Receiver<GuestResponse>, VmmError> {
+        let token = self.token(name).await?;
+        let timeout = request_timeout(&command);
+        let mut stream = tokio::time::timeout(REQUEST_OVERHEAD, send_request(name, GuestRequest { token, command })).await
+            .map_err(|_| VmmError::OperationFailed(format!("Guest agent of {name} didn't accept the command")))??;
+
+        let (tx, rx) = mpsc::channel(64);
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let relay = async {
+                loop {
+                    let response = read_message::<GuestResponse>(&mut stream).await
+                        .unwrap_or_else(|e| GuestResponse::Error { message: e.to_string() });
+                    let last = matches!(response, GuestResponse::Exit { .. } | GuestResponse::Error { .. });
+                    if tx.send(response).await.is_err() || last {
+                        return;
+                    }
+                }
+            };
+            if tokio::time::timeout(timeout, relay).await.is_err() {
+                let message = format!("Guest agent of {name} didn't finish within {}s", timeout.as_secs());
+                let _ = tx.send(GuestResponse::Error { message }).await;
+            }
+        });
+        Ok(rx)
+    }
+}
+
+/// Time a request may take, commands get their own timeout on top
+fn request_timeout(command: &GuestCommand) -> Duration {
+    match command {
+        GuestCommand::Exec { timeout_secs, .. } | GuestCommand::ExecStream { timeout_secs, .. } => {
+            Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_EXEC_TIMEOUT_SECS)) + REQUEST_OVERHEAD
+        }
+        _ => REQUEST_OVERHEAD,
+    }
+}
+
+/// Connects to the agent in VM `name` and sends it `request`, returning the
+/// connection its answer arrives on
+async fn send_request(name: &str, request: GuestRequest) -> Result<BufReader<UnixStream>, VmmError> {
+    let stream = UnixStream::connect(vsock_socket_path(name)).await
+        .map_err(|e| VmmError::OperationFailed(format!("Unable to reach the vsock device of {name}: {e}")))?;
+    let mut stream = BufReader::new(stream);
+
+    stream.get_mut().write_all(format!("CONNECT {GUEST_AGENT_PORT}\n").as_bytes()).await?;
+    let mut reply = String::new();
+    (&mut stream).take(64).read_line(&mut reply).await?;
+    if !reply.starts_with("OK") {
+        return Err(VmmError::OperationFailed(format!("Guest agent of {name} isn't listening: {}", reply.trim())));
+    }
+
+    write_message(stream.get_mut(), &request).await?;
+    Ok(stream)
+}
+
+async fn listen_for_reports(listener: UnixListener, name: String, token: String, events: mpsc::Sender<VmmEvent>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::error!("Guest report listener of {name} stopped: {e}");
+                return;
+            }
+        };
+        let (name, token, events) = (name.clone(), token.clone(), events.clone());
+        tokio::spawn(async move {
+            if let Err(e) = tokio::time::timeout(REPORT_TIMEOUT, handle_report(stream, &name, &token, &events)).await {
+                log::warn!("Guest report from {name} timed out: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_report(stream: UnixStream, name: &str, token: &str, events: &mpsc::Sender<VmmEvent>) {
+    let mut stream = BufReader::new(stream);
+    let response = match read_message::<GuestReport>(&mut stream).await {
+        Ok(report) => match check_report(name, token, report) {
+            Ok(event) => {
+                log::info!("Received {event:?} from the guest agent of {name}");
+                match events.send(event).await {
+                    Ok(()) => GuestResponse::Ack,
+                    Err(e) => GuestResponse::Error { message: format!("Unable to record report: {e}") },
+                }
+            }
+            Err(message) => {
+                log::warn!("Rejected report from the guest agent of {name}: {message}");
+                GuestResponse::Error { message }
+            }
+        },
+        Err(e) => GuestResponse::Error { message: e.to_string() },
+    };
+    if let Err(e) = write_message(stream.get_mut(), &response).await {
+        log::warn!("Unable to answer the guest agent of {name}: {e}");
+    }
+}
+
+/// Turns a report from VM `name` into the event it stands for, if it's
+/// signed with the VM's token and only speaks for that VM
+pub fn check_report(name: &str, token: &str, report: GuestReport) -> Result<VmmEvent, String> {
+    if !guest_token_matches(token, &report.token) {
+        return Err("invalid guest agent token".to_string());
+    }
+    match report.event {
+        GuestEvent::BootComplete { build_id, formnet_ip, .. } if build_id == name => Ok(VmmEvent::BootComplete {
+            id: name.to_string(),
+            build_id,
+            formnet_ip,
+        }),
+        GuestEvent::BootComplete { build_id, .. } => Err(format!("report for {build_id} sent by {name}")),
+    }
+}
+
+async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> Result<(), VmmError> {
+    let mut line = serde_json::to_vec(message).map_err(|e| VmmError::Config(e.to_string()))?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_message<T: DeserializeOwned>(reader: &mut BufReader<UnixStream>) -> Result<T, VmmError> {
+    let mut line = String::new();
+    reader.take(MAX_GUEST_MESSAGE_SIZE as u64).read_line(&mut line).await?;
+    if !line.ends_with('\n') {
+        return Err(VmmError::OperationFailed("Guest agent message is incomplete or too large".to_string()));
+    }
+    serde_json::from_str(&line).map_err(|e| VmmError::OperationFailed(format!("Invalid guest agent message: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_report() {
+        let report = |token: &str, build_id: &str| GuestReport {
+            token: token.to_string(),
+            event: GuestEvent::BootComplete {
+                name: "vm".to_string(),
+                build_id: build_id.to_string(),
+                formnet_ip: "10.0.0.5".to_string(),
+            },
+        };
+
+        match check_report("build-1", "token", report("token", "build-1")) {
+            Ok(VmmEvent::BootComplete { id, build_id, formnet_ip }) => {
+                assert_eq!((id.as_str(), build_id.as_str(), formnet_ip.as_str()), ("build-1", "build-1", "10.0.0.5"));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(check_report("build-1", "token", report("other", "build-1")).is_err());
+        // A guest can't report for another instance
+        assert!(check_report("build-1", "token", report("token", "build-2")).is_err());
+        assert_eq!(report_socket_path("build-1"), PathBuf::from("/run/form-vmm/vsock/build-1.sock_1025"));
+    }
+}
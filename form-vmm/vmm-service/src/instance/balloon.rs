@@ -0,0 +1,115 @@
+use form_pack::formfile::MemoryTier;
+use serde::{Deserialize, Serialize};
+use vmm::vm_config::BalloonConfig;
+use crate::error::VmmError;
+
+/// Below this share of available host memory the balloons are fully inflated
+const HIGH_PRESSURE_AVAILABLE: f64 = 0.05;
+/// Above this share of available host memory the balloons are fully deflated
+const LOW_PRESSURE_AVAILABLE: f64 = 0.25;
+/// Balloons are only resized when the target moves by at least this much,
+/// so small swings in host memory don't resize every VM on each tick
+const RESIZE_STEP_BYTES: u64 = 64 << 20;
+
+/// How an instance's memory balloon follows host memory pressure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalloonPolicy {
+    pub memory_mb: u64,
+    pub tier: MemoryTier,
+}
+
+impl BalloonPolicy {
+    pub fn new(memory_mb: u64, tier: MemoryTier) -> Self {
+        Self { memory_mb, tier }
+    }
+
+    /// Share of the instance's memory reclaimed at full pressure
+    pub fn reclaimable_fraction(&self) -> f64 {
+        match self.tier {
+            MemoryTier::Guaranteed => 0.0,
+            MemoryTier::Standard => 0.25,
+            MemoryTier::Burstable => 0.5,
+        }
+    }
+
+    /// Guaranteed instances get no balloon device, everyone else boots with
+    /// an empty balloon the guest can take back when it runs out of memory
+    pub fn balloon_config(&self) -> Option<BalloonConfig> {
+        if self.tier == MemoryTier::Guaranteed {
+            return None;
+        }
+        Some(BalloonConfig {
+            size: 0,
+            deflate_on_oom: true,
+            free_page_reporting: true,
+        })
+    }
+
+    /// Balloon size in bytes for a host memory pressure between 0 and 1
+    pub fn target_bytes(&self, pressure: f64) -> u64 {
+        let reclaimable = (self.memory_mb << 20) as f64 * self.reclaimable_fraction();
+        let target = (reclaimable * pressure.clamp(0.0, 1.0)) as u64;
+        // Round down to a whole step so the target is stable between ticks
+        target - target % RESIZE_STEP_BYTES
+    }
+
+    pub fn should_resize(&self, current: u64, target: u64) -> bool {
+        current.abs_diff(target) >= RESIZE_STEP_BYTES
+    }
+}
+
+/// Host memory pressure, 0 while enough memory is available and 1 once
+/// available memory drops to the high pressure watermark
+pub fn memory_pressure(available: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    let available = available as f64 / total as f64;
+    ((LOW_PRESSURE_AVAILABLE - available) / (LOW_PRESSURE_AVAILABLE - HIGH_PRESSURE_AVAILABLE)).clamp(0.0, 1.0)
+}
+
+/// Total and available host memory in bytes, read from `/proc/meminfo`
+pub fn read_host_memory() -> Result<(u64, u64), VmmError> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")
+        .map_err(|e| VmmError::SystemError(format!("Unable to read /proc/meminfo: {e}")))?;
+    parse_meminfo(&meminfo)
+        .ok_or_else(|| VmmError::SystemError("MemTotal or MemAvailable missing from /proc/meminfo".into()))
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| {
+        meminfo.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    Some((field("MemTotal:")?, field("MemAvailable:")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balloon_follows_pressure_and_tier() {
+        assert_eq!(memory_pressure(50, 100), 0.0);
+        assert_eq!(memory_pressure(2, 100), 1.0);
+        assert!((memory_pressure(15, 100) - 0.5).abs() < 1e-9);
+
+        let standard = BalloonPolicy::new(4096, MemoryTier::Standard);
+        let burstable = BalloonPolicy::new(4096, MemoryTier::Burstable);
+        let guaranteed = BalloonPolicy::new(4096, MemoryTier::Guaranteed);
+        assert_eq!(standard.target_bytes(1.0), 1024 << 20);
+        assert_eq!(burstable.target_bytes(1.0), 2048 << 20);
+        assert_eq!(standard.target_bytes(0.0), 0);
+        assert_eq!(guaranteed.target_bytes(1.0), 0);
+        assert!(guaranteed.balloon_config().is_none());
+        assert!(standard.balloon_config().is_some_and(|config| config.deflate_on_oom));
+
+        assert!(!standard.should_resize(0, standard.target_bytes(0.01)));
+        assert!(standard.should_resize(0, standard.target_bytes(0.5)));
+
+        let meminfo = "MemTotal:       16384000 kB\nMemFree:         1000 kB\nMemAvailable:    4096000 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some((16384000 * 1024, 4096000 * 1024)));
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+/// How long an instance may take to report `boot_complete`
+pub const DEFAULT_BOOT_TIMEOUT: Duration = Duration::from_secs(300);
+/// Restarts attempted before an instance that never boots is marked failed
+pub const DEFAULT_BOOT_RESTARTS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootWatchdogConfig {
+    pub boot_timeout: Duration,
+    pub max_restarts: u32,
+}
+
+impl Default for BootWatchdogConfig {
+    fn default() -> Self {
+        Self {
+            boot_timeout: DEFAULT_BOOT_TIMEOUT,
+            max_restarts: DEFAULT_BOOT_RESTARTS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootAction {
+    /// Still within the boot timeout, or already booted
+    Wait,
+    /// Timed out, restart the VM. `attempt` counts from 1.
+    Restart { attempt: u32 },
+    /// Timed out with no restarts left
+    Fail,
+}
+
+/// Tracks whether an instance has reported `boot_complete` in time
+#[derive(Debug, Clone)]
+pub struct BootWatch {
+    /// None once the instance has booted
+    deadline: Option<Instant>,
+    restarts: u32,
+}
+
+impl BootWatch {
+    pub fn new(now: Instant, config: &BootWatchdogConfig) -> Self {
+        Self { deadline: Some(now + config.boot_timeout), restarts: 0 }
+    }
+
+    pub fn complete(&mut self) {
+        self.deadline = None;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.deadline.is_none()
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Decides what to do with the instance at `now`. A restart re-arms the
+    /// deadline, so each attempt gets the full boot timeout.
+    pub fn check(&mut self, now: Instant, config: &BootWatchdogConfig) -> BootAction {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {
+                if self.restarts >= config.max_restarts {
+                    return BootAction::Fail;
+                }
+                self.restarts += 1;
+                self.deadline = Some(now + config.boot_timeout);
+                BootAction::Restart { attempt: self.restarts }
+            }
+            _ => BootAction::Wait,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_watch_restarts_then_fails() {
+        let config = BootWatchdogConfig { boot_timeout: Duration::from_secs(60), max_restarts: 2 };
+        let start = Instant::now();
+        let mut watch = BootWatch::new(start, &config);
+
+        assert_eq!(watch.check(start + Duration::from_secs(30), &config), BootAction::Wait);
+        let first = start + Duration::from_secs(60);
+        assert_eq!(watch.check(first, &config), BootAction::Restart { attempt: 1 });
+        // The restart gets a fresh timeout
+        assert_eq!(watch.check(first + Duration::from_secs(59), &config), BootAction::Wait);
+        let second = first + Duration::from_secs(60);
+        assert_eq!(watch.check(second, &config), BootAction::Restart { attempt: 2 });
+        assert_eq!(watch.check(second + Duration::from_secs(60), &config), BootAction::Fail);
+
+        let mut booted = BootWatch::new(start, &config);
+        booted.complete();
+        assert!(booted.is_complete());
+        assert_eq!(booted.check(start + Duration::from_secs(3600), &config), BootAction::Wait);
+        assert_eq!(booted.restarts(), 0);
+    }
+}
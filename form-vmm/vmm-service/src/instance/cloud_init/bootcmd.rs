@@ -0,0 +1,9 @@
+/// Commands giving a cloned disk its own machine-id and SSH host keys. They
+/// run through `cloud-init-per instance` so only the clone's first boot
+/// regenerates them, before sshd starts.
+pub fn generate_identity_reset_bootcmds() -> Vec<String> {
+    vec![
+        "cloud-init-per instance formation-machine-id sh -c 'rm -f /etc/machine-id /var/lib/dbus/machine-id && systemd-machine-id-setup'".to_string(),
+        "cloud-init-per instance formation-ssh-host-keys sh -c 'rm -f /etc/ssh/ssh_host_* && ssh-keygen -A'".to_string(),
+    ]
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf; 
 use std::process::Command;
@@ -16,7 +17,8 @@ use crate::{
 use crate::Distro;
 
 use super::runcmd::generate_default_runcmds;
-use super::write_files::generate_invite_file;
+use super::bootcmd::generate_identity_reset_bootcmds;
+use super::write_files::{generate_clone_identity_files, generate_guest_agent_token_file, generate_invite_file, generate_secrets_file};
 
 pub struct CloudInit {
     temp_dir: TempDir,
@@ -91,6 +93,67 @@ impl CloudInit {
         })
     }
 
+    /// Create a seed that only writes the secrets of a build into the guest,
+    /// leaving users, packages and networking to the image itself. No
+    /// secrets file is written if there are none.
+    pub fn for_secrets(build_id: &str, secrets: &BTreeMap<String, String>) -> Result<Self, CloudInitError> {
+        // Build ids are too long to be valid hostnames
+        let hostname = format!("formation-{}", build_id.chars().take(12).collect::<String>());
+        let user_data = UserData {
+            hostname: hostname.clone(),
+            users: None,
+            chpasswd: None,
+            ssh_pwauth: None,
+            disable_root: None,
+            package_update: None,
+            package_upgrade: None,
+            packages: None,
+            write_files: (!secrets.is_empty()).then(|| vec![generate_secrets_file(secrets)]),
+            runcmd: None,
+            bootcmd: None,
+        };
+        let meta_data = MetaData {
+            instance_id: build_id.to_string(),
+            local_hostname: hostname,
+        };
+
+        Ok(Self {
+            temp_dir: TempDir::new()?,
+            user_data,
+            meta_data,
+        })
+    }
+
+    /// Add the secrets file to the files written at first boot
+    pub fn add_secrets(&mut self, secrets: &BTreeMap<String, String>) {
+        self.user_data.write_files
+            .get_or_insert_with(Vec::new)
+            .push(generate_secrets_file(secrets));
+    }
+
+    /// Add the guest agent token to the files written at first boot
+    pub fn add_guest_agent_token(&mut self, token: &str) {
+        self.user_data.write_files
+            .get_or_insert_with(Vec::new)
+            .push(generate_guest_agent_token_file(token));
+    }
+
+    /// Make a seed for a disk copied from another instance. The copy gets its
+    /// own instance id and hostname, and with `reset_identity` a new
+    /// machine-id and SSH host keys on first boot.
+    pub fn add_clone_identity(&mut self, instance_id: &str, build_id: &str, hostname: &str, reset_identity: bool) {
+        self.user_data.hostname = hostname.to_string();
+        self.meta_data.local_hostname = hostname.to_string();
+        self.user_data.write_files
+            .get_or_insert_with(Vec::new)
+            .extend(generate_clone_identity_files(instance_id, build_id));
+        if reset_identity {
+            self.user_data.bootcmd
+                .get_or_insert_with(Vec::new)
+                .extend(generate_identity_reset_bootcmds());
+        }
+    }
+
     /// Write cloud-init files to the temporary directory
     fn write_files(&self) -> Result<(), CloudInitError> {
         // Write user-data
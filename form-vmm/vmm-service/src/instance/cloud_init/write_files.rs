@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use serde::{Serialize, Deserialize};
 use shared::interface_config::InterfaceConfig;
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
@@ -28,3 +29,80 @@ pub fn generate_invite_file(invite: InterfaceConfig) -> Result<WriteFile, CloudI
         content: Some(base64_invite)
     })
 }
+
+/// Path of the env file instance secrets are written to inside the guest
+pub const SECRETS_FILE_PATH: &str = "/etc/formation/secrets.env";
+
+/// Render secrets as an env file, values are single quoted so the file can be
+/// sourced by a shell without expanding them
+pub fn render_secrets_env(secrets: &BTreeMap<String, String>) -> String {
+    secrets.iter().map(|(name, value)| {
+        format!("{name}='{}'\n", value.replace('\'', "'\\''"))
+    }).collect()
+}
+
+pub fn generate_secrets_file(secrets: &BTreeMap<String, String>) -> WriteFile {
+    WriteFile {
+        path: SECRETS_FILE_PATH.to_string(),
+        owner: Some("root:root".to_string()),
+        permissions: Some("0600".to_string()),
+        encoding: Some("b64".to_string()),
+        content: Some(BASE64.encode(render_secrets_env(secrets).as_bytes()))
+    }
+}
+
+/// Writes the guest agent token of the instance, see `form_types::guest_agent`
+pub fn generate_guest_agent_token_file(token: &str) -> WriteFile {
+    WriteFile {
+        path: form_types::GUEST_AGENT_TOKEN_PATH.to_string(),
+        owner: Some("root:root".to_string()),
+        permissions: Some("0600".to_string()),
+        encoding: Some("b64".to_string()),
+        content: Some(BASE64.encode(token.as_bytes()))
+    }
+}
+
+/// Rewrites the instance id and build id a cloned disk still carries from its
+/// source, so the clone joins formnet as a new peer and reports as itself
+pub fn generate_clone_identity_files(instance_id: &str, build_id: &str) -> Vec<WriteFile> {
+    [("/etc/vm_name", instance_id), ("/etc/build_id", build_id)].into_iter().map(|(path, content)| {
+        WriteFile {
+            path: path.to_string(),
+            owner: Some("root:root".to_string()),
+            permissions: Some("0644".to_string()),
+            encoding: Some("b64".to_string()),
+            content: Some(BASE64.encode(content.as_bytes()))
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_secrets_env_quotes_values() {
+        let mut secrets = BTreeMap::new();
+        secrets.insert("API_KEY".to_string(), "abc123".to_string());
+        secrets.insert("PASSWORD".to_string(), "it's $HOME".to_string());
+
+        assert_eq!(
+            render_secrets_env(&secrets),
+            "API_KEY='abc123'\nPASSWORD='it'\\''s $HOME'\n"
+        );
+
+        let file = generate_secrets_file(&secrets);
+        assert_eq!(file.path, SECRETS_FILE_PATH);
+        assert_eq!(file.permissions.as_deref(), Some("0600"));
+    }
+
+    #[test]
+    fn test_clone_identity_files_replace_vm_name_and_build_id() {
+        let files = generate_clone_identity_files("instance", "build");
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "/etc/vm_name");
+        assert_eq!(files[0].content.as_deref(), Some(BASE64.encode("instance").as_str()));
+        assert_eq!(files[1].path, "/etc/build_id");
+        assert_eq!(files[1].content.as_deref(), Some(BASE64.encode("build").as_str()));
+    }
+}
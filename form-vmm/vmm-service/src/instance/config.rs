@@ -1,16 +1,28 @@
 use std::net::Ipv4Addr;
 use std::str::FromStr;
 use std::path::PathBuf;
-use form_pack::formfile::Formfile;
+use form_pack::formfile::{ConfidentialMode, Formfile, MemoryTier};
 use net_util::MacAddr;
 use serde::{Deserialize, Serialize};
 use crate::error::VmmError;
+use crate::instance::network_policy::NetworkPolicy;
 use form_types::VmmEvent;
 use rand::{thread_rng, Rng};
 use gabble::Gab;
 
 pub const IMAGE_DIR: &str = "/var/lib/formation/vm-images";
 
+/// TDVF firmware trust domains boot from
+pub const TDVF_PATH: &str = "/var/lib/formation/kernel/tdvf.fd";
+
+/// IGVM file SEV-SNP guests are launched from
+pub const SNP_IGVM_PATH: &str = "/var/lib/formation/kernel/snp-fw.igvm";
+
+/// Cloud-init seed image carrying the secrets of an instance
+pub fn secrets_image_path(name: &str) -> PathBuf {
+    PathBuf::from(IMAGE_DIR).join(format!("{name}-secrets")).with_extension("img")
+}
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VmInstanceConfig {
@@ -28,6 +40,34 @@ pub struct VmInstanceConfig {
     pub owner: String,
     /// List of GPU device configurations
     pub gpu_devices: Option<Vec<GpuConfig>>,
+    /// Cloud-init seed image attached read-only, used to inject secrets
+    #[serde(default)]
+    pub cloud_init_path: Option<PathBuf>,
+    /// Guest MAC address, used by the metadata service to identify the instance
+    #[serde(default)]
+    pub mac_addr: Option<String>,
+    /// Bandwidth caps and firewall rules enforced on the TAP device
+    #[serde(default)]
+    pub network_policy: NetworkPolicy,
+    /// How much memory the host may reclaim through the balloon device
+    #[serde(default)]
+    pub memory_tier: MemoryTier,
+    /// Launch as a SEV-SNP or TDX guest with encrypted memory
+    #[serde(default)]
+    pub confidential: Option<ConfidentialMode>,
+    /// Set when the instance boots from a copy of another instance's disk
+    #[serde(default)]
+    pub clone_of: Option<CloneOrigin>,
+}
+
+/// Where a cloned instance's disk was copied from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneOrigin {
+    pub source_build_id: String,
+    /// Name the clone was given, used as its hostname
+    pub name: String,
+    /// Generate a new machine-id and SSH host keys on first boot
+    pub reset_identity: bool,
 }
 
 /// Configuration for a GPU device to be passed through to a VM
@@ -71,6 +111,12 @@ impl Default for VmInstanceConfig {
             console_type: ConsoleType::Virtio,
             owner: String::new(),
             gpu_devices: None,
+            cloud_init_path: None,
+            mac_addr: None,
+            network_policy: NetworkPolicy::default(),
+            memory_tier: MemoryTier::default(),
+            confidential: None,
+            clone_of: None,
         }
     }
 }
@@ -119,6 +165,30 @@ impl VmInstanceConfig {
             }
         }
 
+        self.network_policy.validate()?;
+
+        if let Some(mode) = self.confidential {
+            let (compiled, firmware) = match mode {
+                ConfidentialMode::SevSnp => (cfg!(feature = "sev_snp"), SNP_IGVM_PATH),
+                ConfidentialMode::Tdx => (cfg!(feature = "tdx"), TDVF_PATH),
+            };
+            if !compiled {
+                return Err(VmmError::Config(
+                    format!("This vmm-service was built without {mode} support")
+                ));
+            }
+            if !PathBuf::from(firmware).exists() {
+                return Err(VmmError::InvalidPath(
+                    format!("Firmware for {mode} guests does not exist: {firmware}")
+                ));
+            }
+            if self.gpu_devices.as_ref().is_some_and(|gpus| !gpus.is_empty()) {
+                return Err(VmmError::Config(
+                    format!("GPU passthrough is not supported for {mode} guests")
+                ));
+            }
+        }
+
         Ok(())
     }
 
@@ -168,52 +238,23 @@ impl TryFrom<&VmmEvent> for VmInstanceConfig {
                 name,
                 owner,
                 ..
-            } => { 
-
-                let rootfs_path = PathBuf::from(IMAGE_DIR).join(name).with_extension("raw"); 
-                let formfile: Formfile = serde_json::from_str(&formfile).map_err(|e| {
-                    VmmError::Config(e.to_string())
-                })?; 
-                let memory_mb = formfile.get_memory();
-                let vcpu_count = formfile.get_vcpus();
-
-                // Extract GPU device configurations from the Formfile if available
-                let gpu_configs = formfile.get_gpu_devices().map(|devices| {
-                    devices.iter()
-                        .map(|gpu_str| {
-                            // Parse the format "MODEL:COUNT"
-                            let parts: Vec<&str> = gpu_str.split(':').collect();
-                            let model = parts[0].to_string();
-                            let count = if parts.len() > 1 {
-                                parts[1].parse::<u8>().unwrap_or(1)
-                            } else {
-                                1
-                            };
-                            
-                            GpuConfig {
-                                model,
-                                count,
-                                assigned_devices: Vec::new(),
-                            }
-                        })
-                        .collect::<Vec<GpuConfig>>()
-                });
-
-                Ok(VmInstanceConfig {
-                    rootfs_path,
-                    memory_mb: memory_mb.try_into().map_err(|_| {
-                        VmmError::Config(
-                            "unable to convert memory into u64".to_string()
-                        )
-                    })?,
-                    vcpu_count,
+            } => VmInstanceConfig::from_formfile(name, owner, formfile),
+            VmmEvent::CloneInstance {
+                source,
+                build_id,
+                name,
+                owner,
+                formfile,
+                reset_identity,
+            } => {
+                let mut config = VmInstanceConfig::from_formfile(build_id, owner, formfile)?;
+                config.clone_of = Some(CloneOrigin {
+                    source_build_id: source.clone(),
                     name: name.clone(),
-                    owner: owner.to_string(),
-                    formfile: serde_json::to_string(&formfile).map_err(|e| VmmError::Config(e.to_string()))?,
-                    gpu_devices: gpu_configs,
-                    ..Default::default()
-                })
-            },
+                    reset_identity: *reset_identity,
+                });
+                Ok(config)
+            }
             _ => {
                 return Err(
                     VmmError::Config(
@@ -225,6 +266,67 @@ impl TryFrom<&VmmEvent> for VmInstanceConfig {
     }
 }
 
+impl VmInstanceConfig {
+    /// The config of the instance `name` booting `IMAGE_DIR/<name>.raw` with
+    /// the resources its Formfile asks for
+    fn from_formfile(name: &str, owner: &str, formfile: &str) -> Result<Self, VmmError> {
+        let rootfs_path = PathBuf::from(IMAGE_DIR).join(name).with_extension("raw"); 
+        let formfile: Formfile = serde_json::from_str(formfile).map_err(|e| {
+            VmmError::Config(e.to_string())
+        })?; 
+        let memory_mb = formfile.get_memory();
+        let vcpu_count = formfile.get_vcpus();
+
+        // Extract GPU device configurations from the Formfile if available
+        let gpu_configs = formfile.get_gpu_devices().map(|devices| {
+            devices.iter()
+                .map(|gpu_str| {
+                    // Parse the format "MODEL:COUNT"
+                    let parts: Vec<&str> = gpu_str.split(':').collect();
+                    let model = parts[0].to_string();
+                    let count = if parts.len() > 1 {
+                        parts[1].parse::<u8>().unwrap_or(1)
+                    } else {
+                        1
+                    };
+                    
+                    GpuConfig {
+                        model,
+                        count,
+                        assigned_devices: Vec::new(),
+                    }
+                })
+                .collect::<Vec<GpuConfig>>()
+        });
+
+        let network_policy = NetworkPolicy::from_formfile(&formfile);
+        let confidential = formfile.get_confidential();
+        // The host can't reclaim encrypted guest memory
+        let memory_tier = match confidential {
+            Some(_) => MemoryTier::Guaranteed,
+            None => formfile.get_memory_tier(),
+        };
+
+        Ok(VmInstanceConfig {
+            rootfs_path,
+            memory_mb: memory_mb.try_into().map_err(|_| {
+                VmmError::Config(
+                    "unable to convert memory into u64".to_string()
+                )
+            })?,
+            vcpu_count,
+            name: name.to_string(),
+            owner: owner.to_string(),
+            formfile: serde_json::to_string(&formfile).map_err(|e| VmmError::Config(e.to_string()))?,
+            gpu_devices: gpu_configs,
+            network_policy,
+            memory_tier,
+            confidential,
+            ..Default::default()
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkConfig {
     pub ip: Option<Ipv4Addr>,
@@ -1,7 +1,16 @@
 pub mod config;
 pub mod distro;
 pub mod cloud_init;
+pub mod network_policy;
+pub mod balloon;
+pub mod boot_watchdog;
+pub mod readiness;
+pub mod reconcile;
 
 pub use config::*;
 pub use distro::*;
 pub use cloud_init::*;
+pub use network_policy::*;
+pub use balloon::*;
+pub use boot_watchdog::*;
+pub use readiness::*;
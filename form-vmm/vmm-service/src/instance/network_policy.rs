@@ -0,0 +1,266 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use form_pack::formfile::{Formfile, FirewallAction, FirewallProtocol, FirewallRule, PortRange, TrafficDirection};
+use serde::{Deserialize, Serialize};
+use virtio_devices::{RateLimiterConfig, TokenBucketConfig};
+use crate::error::VmmError;
+
+/// Address ranges reachable in formnet-only mode, the bridge network and
+/// formnet itself are both carved out of private space
+const FORMNET_ONLY_CIDRS_V4: &[&str] = &["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"];
+const FORMNET_ONLY_CIDRS_V6: &[&str] = &["fd00::/8", "fe80::/10"];
+
+/// Ports the guest needs in formnet-only mode: DNS, DHCP and the formnet
+/// WireGuard tunnel and bootstrap API
+const FORMNET_ONLY_UDP_PORTS: &str = "53, 67-68, 51820";
+const FORMNET_ONLY_TCP_PORTS: &str = "53, 51820";
+
+/// Bandwidth caps and firewall rules applied to an instance's TAP device
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkPolicy {
+    /// Traffic towards the instance, in Mbps
+    #[serde(default)]
+    pub ingress_mbps: Option<u32>,
+    /// Traffic leaving the instance, in Mbps
+    #[serde(default)]
+    pub egress_mbps: Option<u32>,
+    /// Drop everything that isn't formnet, bridge local, DNS or DHCP traffic
+    #[serde(default)]
+    pub formnet_only: bool,
+    /// Evaluated in order, the first matching rule wins and unmatched
+    /// traffic is accepted
+    #[serde(default)]
+    pub rules: Vec<FirewallRule>,
+}
+
+impl NetworkPolicy {
+    pub fn from_formfile(formfile: &Formfile) -> Self {
+        let bandwidth = formfile.get_bandwidth();
+        Self {
+            ingress_mbps: bandwidth.ingress_mbps,
+            egress_mbps: bandwidth.egress_mbps,
+            formnet_only: formfile.is_formnet_only(),
+            rules: formfile.get_firewall_rules(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), VmmError> {
+        if self.ingress_mbps == Some(0) || self.egress_mbps == Some(0) {
+            return Err(VmmError::Config("Bandwidth caps must be at least 1 Mbps".into()));
+        }
+        for rule in &self.rules {
+            if rule.ports.iter().any(|range| range.start == 0 || range.start > range.end) {
+                return Err(VmmError::Config(format!("Invalid port range in firewall rule {rule:?}")));
+            }
+            if rule.ports.is_empty() && rule.protocol != FirewallProtocol::Any {
+                return Err(VmmError::Config(format!("Firewall rule {rule:?} must list at least one port")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Cloud Hypervisor applies one token bucket to each of the rx and tx
+    /// queues, so the virtio device is capped at the larger of the two limits
+    /// and the per direction caps are enforced by nftables.
+    pub fn rate_limiter_config(&self) -> Option<RateLimiterConfig> {
+        let mbps = self.ingress_mbps.into_iter().chain(self.egress_mbps).max()?;
+        Some(RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                // Bytes per second, refilled every second
+                size: u64::from(mbps) * 125_000,
+                one_time_burst: None,
+                refill_time: 1000,
+            }),
+            ops: None,
+        })
+    }
+
+    fn table_name(tap: &str) -> String {
+        format!("formvm_{}", tap.replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+    }
+
+    fn chain_rules(&self, direction: TrafficDirection) -> Vec<String> {
+        let mut rules = Vec::new();
+        let cap = match direction {
+            TrafficDirection::Ingress => self.ingress_mbps,
+            TrafficDirection::Egress => self.egress_mbps,
+        };
+        if let Some(mbps) = cap {
+            rules.push(format!("limit rate over {} kbytes/second drop", u64::from(mbps) * 125));
+        }
+
+        let filters = self.rules.iter().filter(|rule| rule.direction == direction).collect::<Vec<_>>();
+        if filters.is_empty() && !self.formnet_only {
+            return rules;
+        }
+
+        rules.push("ct state established,related accept".to_string());
+        for rule in filters {
+            rules.push(rule_statement(rule));
+        }
+
+        if self.formnet_only {
+            let addr = match direction {
+                TrafficDirection::Ingress => "saddr",
+                TrafficDirection::Egress => "daddr",
+            };
+            rules.push("ether type arp accept".to_string());
+            rules.push(format!("ip {addr} {{ {} }} accept", FORMNET_ONLY_CIDRS_V4.join(", ")));
+            rules.push(format!("ip6 {addr} {{ {} }} accept", FORMNET_ONLY_CIDRS_V6.join(", ")));
+            rules.push("meta l4proto ipv6-icmp accept".to_string());
+            rules.push(format!("udp dport {{ {FORMNET_ONLY_UDP_PORTS} }} accept"));
+            rules.push(format!("tcp dport {{ {FORMNET_ONLY_TCP_PORTS} }} accept"));
+            rules.push("drop".to_string());
+        }
+
+        rules
+    }
+
+    /// nftables ruleset enforcing the policy on a TAP device. The table lives
+    /// in the bridge family since the TAP is a port of `br0`, prerouting sees
+    /// what the guest sends and postrouting what is delivered to it.
+    pub fn nftables_ruleset(&self, tap: &str) -> String {
+        let table = Self::table_name(tap);
+        let mut ruleset = format!("table bridge {table} {{\n");
+        for (chain, hook, iface, direction) in [
+            ("from_guest", "prerouting", "iifname", TrafficDirection::Egress),
+            ("to_guest", "postrouting", "oifname", TrafficDirection::Ingress),
+        ] {
+            ruleset.push_str(&format!("    chain {chain} {{\n"));
+            ruleset.push_str(&format!("        type filter hook {hook} priority 0; policy accept;\n"));
+            ruleset.push_str(&format!("        {iface} != \"{tap}\" return\n"));
+            for rule in self.chain_rules(direction) {
+                ruleset.push_str(&format!("        {rule}\n"));
+            }
+            ruleset.push_str("    }\n");
+        }
+        ruleset.push_str("}\n");
+        ruleset
+    }
+
+    /// Replaces the rules on the TAP device with this policy
+    pub fn apply(&self, tap: &str) -> Result<(), VmmError> {
+        Self::remove(tap)?;
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Applying network policy to {tap}: {self:?}");
+        let mut child = Command::new("nft")
+            .args(["-f", "-"])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| VmmError::NetworkError(format!("Unable to run nft: {e}")))?;
+        child.stdin.take()
+            .ok_or_else(|| VmmError::NetworkError("Unable to write to nft stdin".into()))?
+            .write_all(self.nftables_ruleset(tap).as_bytes())
+            .map_err(|e| VmmError::NetworkError(e.to_string()))?;
+        let output = child.wait_with_output().map_err(|e| VmmError::NetworkError(e.to_string()))?;
+        if !output.status.success() {
+            return Err(VmmError::NetworkError(format!(
+                "Unable to apply network policy to {tap}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Removes any rules installed for the TAP device
+    pub fn remove(tap: &str) -> Result<(), VmmError> {
+        let table = Self::table_name(tap);
+        let exists = Command::new("nft")
+            .args(["list", "table", "bridge", &table])
+            .output()
+            .map_err(|e| VmmError::NetworkError(format!("Unable to run nft: {e}")))?
+            .status
+            .success();
+        if exists {
+            let output = Command::new("nft")
+                .args(["delete", "table", "bridge", &table])
+                .output()
+                .map_err(|e| VmmError::NetworkError(format!("Unable to run nft: {e}")))?;
+            if !output.status.success() {
+                return Err(VmmError::NetworkError(format!(
+                    "Unable to remove network policy from {tap}: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ports_set(ports: &[PortRange]) -> String {
+    ports.iter().map(|range| {
+        if range.start == range.end {
+            range.start.to_string()
+        } else {
+            format!("{}-{}", range.start, range.end)
+        }
+    }).collect::<Vec<_>>().join(", ")
+}
+
+fn rule_statement(rule: &FirewallRule) -> String {
+    let verdict = match rule.action {
+        FirewallAction::Allow => "accept",
+        FirewallAction::Deny => "drop",
+    };
+    match (rule.protocol, rule.ports.is_empty()) {
+        (FirewallProtocol::Any, true) => verdict.to_string(),
+        (FirewallProtocol::Any, false) => {
+            format!("meta l4proto {{ tcp, udp }} th dport {{ {} }} {verdict}", ports_set(&rule.ports))
+        }
+        (FirewallProtocol::Tcp, _) => format!("tcp dport {{ {} }} {verdict}", ports_set(&rule.ports)),
+        (FirewallProtocol::Udp, _) => format!("udp dport {{ {} }} {verdict}", ports_set(&rule.ports)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nftables_ruleset() {
+        let policy = NetworkPolicy {
+            ingress_mbps: Some(100),
+            egress_mbps: Some(20),
+            formnet_only: false,
+            rules: vec![
+                FirewallRule {
+                    action: FirewallAction::Allow,
+                    direction: TrafficDirection::Ingress,
+                    protocol: FirewallProtocol::Tcp,
+                    ports: vec![PortRange { start: 80, end: 80 }, PortRange { start: 8000, end: 8100 }],
+                },
+                FirewallRule {
+                    action: FirewallAction::Deny,
+                    direction: TrafficDirection::Ingress,
+                    protocol: FirewallProtocol::Any,
+                    ports: vec![],
+                },
+            ],
+        };
+        assert!(policy.validate().is_ok());
+
+        let ruleset = policy.nftables_ruleset("vmnet0");
+        assert!(ruleset.contains("table bridge formvm_vmnet0"));
+        assert!(ruleset.contains("limit rate over 12500 kbytes/second drop"));
+        assert!(ruleset.contains("limit rate over 2500 kbytes/second drop"));
+        assert!(ruleset.contains("tcp dport { 80, 8000-8100 } accept"));
+        // Ingress rules stay out of the chain for traffic the guest sends
+        let from_guest = ruleset.split("chain to_guest").next().unwrap();
+        assert!(!from_guest.contains("dport"));
+
+        let bucket = policy.rate_limiter_config().unwrap().bandwidth.unwrap();
+        assert_eq!(bucket.size, 12_500_000);
+        assert!(NetworkPolicy::default().rate_limiter_config().is_none());
+
+        let formnet_only = NetworkPolicy { formnet_only: true, ..Default::default() };
+        assert!(formnet_only.nftables_ruleset("vmnet1").contains("ip daddr { 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16 } accept"));
+    }
+}
@@ -0,0 +1,73 @@
+use form_types::HealthCheck;
+
+/// Holds back the DNS record of an instance that declares a `HEALTHCHECK`
+/// until it has booted and its probe has passed. Instances without one are
+/// published as soon as they boot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadinessGate {
+    required: bool,
+    booted: bool,
+    ready: bool,
+    published: bool,
+}
+
+impl ReadinessGate {
+    pub fn new(health_check: Option<&HealthCheck>) -> Self {
+        Self { required: health_check.is_some(), ..Default::default() }
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.booted && (self.ready || !self.required)
+    }
+
+    /// Records `boot_complete`, returns true if DNS should be published now
+    pub fn booted(&mut self) -> bool {
+        self.booted = true;
+        self.take_publish()
+    }
+
+    /// Records a readiness report, returns true if DNS should be published now.
+    /// The probe can report before `boot_complete` arrives.
+    pub fn report(&mut self, ready: bool) -> bool {
+        self.ready = ready;
+        self.take_publish()
+    }
+
+    fn take_publish(&mut self) -> bool {
+        if self.is_ready() && !self.published {
+            self.published = true;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use form_types::HealthProbe;
+
+    #[test]
+    fn test_dns_waits_for_boot_and_readiness() {
+        let mut ungated = ReadinessGate::new(None);
+        assert!(ungated.booted());
+        assert!(!ungated.booted());
+
+        let check = HealthCheck::new(HealthProbe::Http { port: 8080, path: "/".to_string() });
+        let mut gate = ReadinessGate::new(Some(&check));
+        assert!(!gate.booted());
+        assert!(!gate.report(false));
+        assert!(gate.report(true));
+        // Published once, later reports don't publish again
+        assert!(!gate.report(false));
+        assert!(!gate.report(true));
+
+        let mut early = ReadinessGate::new(Some(&check));
+        assert!(!early.report(true));
+        assert!(early.booted());
+    }
+}
@@ -0,0 +1,306 @@
+//! Startup reconciliation of the VMs on this node
+//!
+//! The service only keeps its VMs in memory, so after a restart the API
+//! sockets, TAP devices and disk images on the host can disagree with
+//! form-state. `HostInventory` lists what is on the host and `plan` decides
+//! which VMs are adopted, what is cleaned up and which records are corrected.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use form_state::instances::{Instance, InstanceStatus};
+use serde::Serialize;
+
+/// Prefix of the TAP devices the service creates, followed by a counter
+pub const TAP_PREFIX: &str = "vmnet";
+
+/// Directory the network interfaces of the host are listed in
+pub const NET_CLASS_DIR: &str = "/sys/class/net";
+
+const SECRETS_SUFFIX: &str = "-secrets";
+
+/// Directory the API sockets of the VMs are created in
+pub fn api_socket_dir() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(path) => PathBuf::from(path).join("form-vmm"),
+        Err(_) => PathBuf::from("/run/form-vmm"),
+    }
+}
+
+/// API socket of VM `name`
+pub fn api_socket_path(name: &str) -> PathBuf {
+    api_socket_dir().join(format!("{name}.sock"))
+}
+
+/// Counter of a TAP device the service created
+pub fn tap_index(tap: &str) -> Option<u32> {
+    tap.strip_prefix(TAP_PREFIX)?.parse().ok()
+}
+
+/// What the host has for VMs, whether or not they are running
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostInventory {
+    /// API sockets by VM name
+    pub sockets: BTreeMap<String, PathBuf>,
+    pub taps: BTreeSet<String>,
+    /// VMs with a `<name>.raw` disk image
+    pub images: BTreeSet<String>,
+    /// VMs with a secrets seed image
+    pub secrets: BTreeSet<String>,
+}
+
+fn file_names(dir: &Path) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).collect()
+        })
+        .unwrap_or_default()
+}
+
+impl HostInventory {
+    /// Lists the sockets, TAP devices and images. Missing directories are
+    /// treated as empty.
+    pub fn scan(socket_dir: &Path, image_dir: &Path, net_dir: &Path) -> Self {
+        let mut inventory = Self::default();
+        for name in file_names(socket_dir) {
+            if let Some(vm) = name.strip_suffix(".sock") {
+                inventory.sockets.insert(vm.to_string(), socket_dir.join(&name));
+            }
+        }
+        for name in file_names(net_dir) {
+            if tap_index(&name).is_some() {
+                inventory.taps.insert(name);
+            }
+        }
+        for name in file_names(image_dir) {
+            if let Some(vm) = name.strip_suffix(".raw") {
+                inventory.images.insert(vm.to_string());
+            } else if let Some(vm) = name.strip_suffix(".img").and_then(|name| name.strip_suffix(SECRETS_SUFFIX)) {
+                inventory.secrets.insert(vm.to_string());
+            }
+        }
+        inventory
+    }
+}
+
+/// The parts of a form-state instance reconciliation looks at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceRecord {
+    pub instance_id: String,
+    pub build_id: String,
+    pub status: InstanceStatus,
+}
+
+impl From<&Instance> for InstanceRecord {
+    fn from(instance: &Instance) -> Self {
+        Self {
+            instance_id: instance.instance_id.clone(),
+            build_id: instance.build_id.clone(),
+            status: instance.status.clone(),
+        }
+    }
+}
+
+/// A VM whose API socket answered
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveVm {
+    pub tap: Option<String>,
+    pub mac: Option<String>,
+    /// Whether the guest is running or paused, rather than created or shut down
+    pub running: bool,
+}
+
+/// What reconciliation does, and what it found wrong
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReconcilePlan {
+    /// VMs that are still up and expected on this node, taken back under
+    /// management
+    pub adopt: Vec<String>,
+    /// VMs that are up but form-state has no instance for, or a deleted one.
+    /// They are shut down and removed.
+    pub orphaned_vms: Vec<String>,
+    /// Sockets nothing answers on
+    pub stale_sockets: Vec<String>,
+    /// Ids of instances form-state has as running whose VM is gone. They are
+    /// set to `Stopped`.
+    pub lost_instances: Vec<String>,
+    /// TAP devices no adopted VM uses
+    pub orphaned_taps: Vec<String>,
+    /// Secrets images no adopted VM uses
+    pub orphaned_secrets: Vec<String>,
+    /// Disk images no instance on this node uses. They are only reported, an
+    /// image can arrive before the create that uses it.
+    pub unclaimed_images: Vec<String>,
+    /// Where the TAP counter continues from, past the adopted VMs' devices
+    pub next_tap: u32,
+    /// Whether form-state could be asked about this node's instances
+    pub records_checked: bool,
+}
+
+impl ReconcilePlan {
+    /// Whether the host and form-state disagreed about anything
+    pub fn has_discrepancies(&self) -> bool {
+        !(self.orphaned_vms.is_empty()
+            && self.stale_sockets.is_empty()
+            && self.lost_instances.is_empty()
+            && self.orphaned_taps.is_empty()
+            && self.orphaned_secrets.is_empty()
+            && self.unclaimed_images.is_empty())
+    }
+}
+
+/// Decides what to do with what is on the host. `live` are the VMs whose
+/// socket answered, `records` this node's instances in form-state, None if
+/// form-state couldn't be reached. Without records every live VM is adopted
+/// and no record is changed.
+pub fn plan(inventory: &HostInventory, live: &BTreeMap<String, LiveVm>, records: Option<&[InstanceRecord]>) -> ReconcilePlan {
+    let mut plan = ReconcilePlan { records_checked: records.is_some(), ..Default::default() };
+    let expected: BTreeMap<&str, &InstanceRecord> = records.unwrap_or_default().iter()
+        .filter(|instance| instance.status != InstanceStatus::Deleted)
+        .map(|instance| (instance.build_id.as_str(), instance))
+        .collect();
+
+    for (name, vm) in live {
+        match (records.is_some(), expected.get(name.as_str())) {
+            (true, None) => plan.orphaned_vms.push(name.clone()),
+            (_, record) => {
+                plan.adopt.push(name.clone());
+                if let Some(record) = record {
+                    if record.status.is_running() && !vm.running {
+                        plan.lost_instances.push(record.instance_id.clone());
+                    }
+                }
+            }
+        }
+    }
+    plan.stale_sockets = inventory.sockets.keys()
+        .filter(|name| !live.contains_key(*name))
+        .cloned()
+        .collect();
+    for (name, record) in &expected {
+        if record.status.is_running() && !live.contains_key(*name) {
+            plan.lost_instances.push(record.instance_id.clone());
+        }
+    }
+
+    let adopted_taps: BTreeSet<&String> = plan.adopt.iter()
+        .filter_map(|name| live.get(name)?.tap.as_ref())
+        .collect();
+    plan.orphaned_taps = inventory.taps.iter()
+        .filter(|tap| !adopted_taps.contains(tap))
+        .cloned()
+        .collect();
+    plan.next_tap = adopted_taps.iter()
+        .filter_map(|tap| tap_index(tap))
+        .max()
+        .map_or(0, |index| index + 1);
+    plan.orphaned_secrets = inventory.secrets.iter()
+        .filter(|name| !plan.adopt.contains(name))
+        .cloned()
+        .collect();
+    if records.is_some() {
+        plan.unclaimed_images = inventory.images.iter()
+            .filter(|name| !expected.contains_key(name.as_str()) && !plan.adopt.contains(name))
+            .cloned()
+            .collect();
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(build_id: &str, status: InstanceStatus) -> InstanceRecord {
+        InstanceRecord {
+            instance_id: format!("{build_id}-instance"),
+            build_id: build_id.to_string(),
+            status,
+        }
+    }
+
+    fn live(tap: &str, running: bool) -> LiveVm {
+        LiveVm { tap: Some(tap.to_string()), mac: None, running }
+    }
+
+    #[test]
+    fn test_plan_adopts_and_cleans_up() {
+        let inventory = HostInventory {
+            sockets: ["web", "ghost", "stale", "halted"].iter()
+                .map(|name| (name.to_string(), PathBuf::from(format!("/run/form-vmm/{name}.sock"))))
+                .collect(),
+            taps: ["vmnet0", "vmnet1", "vmnet2", "vmnet5", "vmnet7"].iter().map(|tap| tap.to_string()).collect(),
+            images: ["web", "ghost", "gone", "pending"].iter().map(|name| name.to_string()).collect(),
+            secrets: ["web", "stale"].iter().map(|name| name.to_string()).collect(),
+        };
+        let live_vms = BTreeMap::from([
+            ("web".to_string(), live("vmnet1", true)),
+            ("ghost".to_string(), live("vmnet2", true)),
+            ("halted".to_string(), live("vmnet5", false)),
+        ]);
+        let records = vec![
+            record("web", InstanceStatus::Ready),
+            record("ghost", InstanceStatus::Deleted),
+            record("halted", InstanceStatus::Ready),
+            record("gone", InstanceStatus::Booting),
+            record("pending", InstanceStatus::Built),
+        ];
+
+        let plan = plan(&inventory, &live_vms, Some(&records));
+        assert_eq!(plan.adopt, vec!["halted".to_string(), "web".to_string()]);
+        assert_eq!(plan.orphaned_vms, vec!["ghost".to_string()]);
+        assert_eq!(plan.stale_sockets, vec!["stale".to_string()]);
+        assert_eq!(plan.lost_instances, vec!["halted-instance".to_string(), "gone-instance".to_string()]);
+        assert_eq!(plan.orphaned_taps, vec!["vmnet0".to_string(), "vmnet2".to_string(), "vmnet7".to_string()]);
+        assert_eq!(plan.orphaned_secrets, vec!["stale".to_string()]);
+        assert_eq!(plan.unclaimed_images, vec!["ghost".to_string()]);
+        assert_eq!(plan.next_tap, 6);
+        assert!(plan.has_discrepancies());
+    }
+
+    #[test]
+    fn test_plan_without_records_changes_nothing_in_form_state() {
+        let inventory = HostInventory {
+            sockets: BTreeMap::from([("web".to_string(), PathBuf::from("/run/form-vmm/web.sock"))]),
+            taps: ["vmnet3".to_string()].into_iter().collect(),
+            images: ["other".to_string()].into_iter().collect(),
+            secrets: BTreeSet::new(),
+        };
+        let live_vms = BTreeMap::from([("web".to_string(), live("vmnet3", true))]);
+
+        let plan = plan(&inventory, &live_vms, None);
+        assert_eq!(plan.adopt, vec!["web".to_string()]);
+        assert!(plan.orphaned_vms.is_empty());
+        assert!(plan.lost_instances.is_empty());
+        assert!(plan.unclaimed_images.is_empty());
+        assert_eq!(plan.next_tap, 4);
+        assert!(!plan.records_checked);
+        assert!(!plan.has_discrepancies());
+    }
+
+    #[test]
+    fn test_scan_host() {
+        let root = tempfile::tempdir().unwrap();
+        let (sockets, images, net) = (root.path().join("sockets"), root.path().join("images"), root.path().join("net"));
+        for dir in [&sockets, &images, &net] {
+            std::fs::create_dir(dir).unwrap();
+        }
+        for path in [
+            sockets.join("web.sock"),
+            sockets.join("notes.txt"),
+            images.join("web.raw"),
+            images.join("web-secrets.img"),
+            net.join("vmnet0"),
+            net.join("br0"),
+        ] {
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let inventory = HostInventory::scan(&sockets, &images, &net);
+        assert_eq!(inventory.sockets.keys().collect::<Vec<_>>(), vec!["web"]);
+        assert_eq!(inventory.taps.iter().collect::<Vec<_>>(), vec!["vmnet0"]);
+        assert_eq!(inventory.images.iter().collect::<Vec<_>>(), vec!["web"]);
+        assert_eq!(inventory.secrets.iter().collect::<Vec<_>>(), vec!["web"]);
+
+        assert_eq!(HostInventory::scan(&root.path().join("missing"), &images, &net).sockets.len(), 0);
+    }
+}
@@ -8,6 +8,8 @@ pub mod cli;
 pub mod api;
 pub mod util;
 pub mod gpu;
+pub mod metadata;
+pub mod guest_agent;
 
 pub use config::{NetworkConfig, DefaultVmParams, ResourceLimits, ServicePaths};
 pub use service::*;
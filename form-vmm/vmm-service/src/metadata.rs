@@ -0,0 +1,374 @@
+//! Instance metadata service
+//!
+//! Serves per-instance metadata to guests on a link-local address bound to
+//! the VM bridge. A request is attributed to an instance by mapping its
+//! source IP to a MAC address through the host's neighbour table, and the MAC
+//! to the instance that was created with it. Identity and network metadata
+//! are readable by any process in the guest; secrets additionally require a
+//! session token obtained with a `PUT` request, so a guest application that
+//! can be tricked into issuing `GET` requests can't leak them.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, put},
+    Json, Router,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use crate::VmmError;
+
+/// Link-local address the metadata service listens on
+pub const METADATA_ADDR: Ipv4Addr = Ipv4Addr::new(169, 254, 169, 254);
+pub const METADATA_PORT: u16 = 80;
+/// Bridge the guests' taps are attached to
+pub const METADATA_BRIDGE: &str = "br0";
+/// Header carrying a session token
+pub const TOKEN_HEADER: &str = "x-formation-metadata-token";
+/// Header requesting a session token lifetime in seconds
+pub const TOKEN_TTL_HEADER: &str = "x-formation-metadata-token-ttl-seconds";
+/// Longest (and default) session token lifetime
+pub const MAX_TOKEN_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Everything the metadata service knows about an instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceMetadataRecord {
+    pub instance_id: String,
+    pub build_id: String,
+    pub node_id: String,
+    pub owner: String,
+    pub tap_device: String,
+    pub mac_addr: String,
+    pub formnet_ip: Option<IpAddr>,
+    /// Formfile the instance was created from
+    pub user_data: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceIdentity {
+    pub instance_id: String,
+    pub build_id: String,
+    pub node_id: String,
+    pub owner: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceNetwork {
+    pub formnet_ip: Option<IpAddr>,
+    pub mac_addr: String,
+    pub tap_device: String,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    /// Instances keyed by lowercase MAC address
+    instances: HashMap<String, InstanceMetadataRecord>,
+    /// Session tokens mapped to the MAC they were issued to and their expiry
+    tokens: HashMap<String, (String, Instant)>,
+}
+
+/// Shared map of the instances running on this node
+#[derive(Clone, Default)]
+pub struct MetadataRegistry {
+    inner: Arc<RwLock<RegistryInner>>,
+}
+
+impl MetadataRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn register(&self, record: InstanceMetadataRecord) {
+        let mac = record.mac_addr.to_lowercase();
+        self.inner.write().await.instances.insert(mac, record);
+    }
+
+    /// Remove the instance created for a build along with its tokens
+    pub async fn deregister(&self, build_id: &str) {
+        let mut inner = self.inner.write().await;
+        let macs: Vec<String> = inner.instances.iter()
+            .filter(|(_, record)| record.build_id == build_id)
+            .map(|(mac, _)| mac.clone())
+            .collect();
+        for mac in &macs {
+            inner.instances.remove(mac);
+        }
+        inner.tokens.retain(|_, (mac, _)| !macs.contains(mac));
+    }
+
+    pub async fn set_formnet_ip(&self, build_id: &str, formnet_ip: IpAddr) {
+        let mut inner = self.inner.write().await;
+        for record in inner.instances.values_mut().filter(|record| record.build_id == build_id) {
+            record.formnet_ip = Some(formnet_ip);
+        }
+    }
+
+    pub async fn get_by_mac(&self, mac: &str) -> Option<InstanceMetadataRecord> {
+        self.inner.read().await.instances.get(&mac.to_lowercase()).cloned()
+    }
+
+    /// Issue a session token to an instance
+    pub async fn issue_token(&self, mac: &str, ttl: Duration) -> String {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(48)
+            .map(char::from)
+            .collect();
+        let now = Instant::now();
+        let mut inner = self.inner.write().await;
+        inner.tokens.retain(|_, (_, expires)| *expires > now);
+        inner.tokens.insert(token.clone(), (mac.to_lowercase(), now + ttl.min(MAX_TOKEN_TTL)));
+        token
+    }
+
+    /// Whether a token is valid for the instance with the given MAC
+    pub async fn verify_token(&self, mac: &str, token: &str) -> bool {
+        self.inner.read().await.tokens.get(token)
+            .is_some_and(|(owner, expires)| *owner == mac.to_lowercase() && *expires > Instant::now())
+    }
+}
+
+/// Parse `/proc/net/arp` into a map of IP to (MAC, device)
+pub fn parse_arp_table(contents: &str) -> HashMap<IpAddr, (String, String)> {
+    contents.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 {
+                return None;
+            }
+            let ip = fields[0].parse::<IpAddr>().ok()?;
+            let mac = fields[3].to_lowercase();
+            // Incomplete entries have an all-zero hardware address
+            if mac == "00:00:00:00:00:00" {
+                return None;
+            }
+            Some((ip, (mac, fields[5].to_string())))
+        })
+        .collect()
+}
+
+/// Look up the MAC of a guest on the bridge by its IP
+async fn resolve_mac(ip: IpAddr) -> Option<String> {
+    let contents = tokio::fs::read_to_string("/proc/net/arp").await.ok()?;
+    parse_arp_table(&contents)
+        .remove(&ip)
+        .filter(|(_, device)| device == METADATA_BRIDGE)
+        .map(|(mac, _)| mac)
+}
+
+fn error(status: StatusCode, message: &str) -> axum::response::Response {
+    (status, Json(serde_json::json!({ "success": false, "error": message }))).into_response()
+}
+
+async fn caller(registry: &MetadataRegistry, addr: SocketAddr) -> Result<InstanceMetadataRecord, axum::response::Response> {
+    let Some(mac) = resolve_mac(addr.ip()).await else {
+        log::warn!("Metadata request from {} which is not a known guest", addr.ip());
+        return Err(error(StatusCode::FORBIDDEN, "Requests must come from an instance on this node"));
+    };
+    registry.get_by_mac(&mac).await
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "No instance is registered for this address"))
+}
+
+async fn identity(
+    State(registry): State<MetadataRegistry>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> axum::response::Response {
+    match caller(&registry, addr).await {
+        Ok(record) => Json(InstanceIdentity {
+            instance_id: record.instance_id,
+            build_id: record.build_id,
+            node_id: record.node_id,
+            owner: record.owner,
+        }).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn network(
+    State(registry): State<MetadataRegistry>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> axum::response::Response {
+    match caller(&registry, addr).await {
+        Ok(record) => Json(InstanceNetwork {
+            formnet_ip: record.formnet_ip,
+            mac_addr: record.mac_addr,
+            tap_device: record.tap_device,
+        }).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn user_data(
+    State(registry): State<MetadataRegistry>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> axum::response::Response {
+    match caller(&registry, addr).await {
+        Ok(record) => record.user_data.into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn token(
+    State(registry): State<MetadataRegistry>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    // Requests relayed by a proxy in the guest must not be able to mint tokens
+    if headers.contains_key("x-forwarded-for") {
+        return error(StatusCode::FORBIDDEN, "Forwarded requests can't request a token");
+    }
+    let ttl = headers.get(TOKEN_TTL_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(MAX_TOKEN_TTL);
+    if ttl.is_zero() || ttl > MAX_TOKEN_TTL {
+        return error(StatusCode::BAD_REQUEST, "Token TTL must be between 1 and 21600 seconds");
+    }
+
+    match caller(&registry, addr).await {
+        Ok(record) => registry.issue_token(&record.mac_addr, ttl).await.into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn secrets(
+    State(registry): State<MetadataRegistry>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let record = match caller(&registry, addr).await {
+        Ok(record) => record,
+        Err(response) => return response,
+    };
+    let token = headers.get(TOKEN_HEADER).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if !registry.verify_token(&record.mac_addr, token).await {
+        return error(StatusCode::UNAUTHORIZED, "A valid session token is required, request one with PUT /v1/token");
+    }
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://127.0.0.1:3004/v1/secrets/{}/resolve", record.build_id))
+        .send()
+        .await;
+    match resp {
+        Ok(resp) => match resp.json::<serde_json::Value>().await {
+            Ok(value) if value["success"].as_bool().unwrap_or(false) => Json(value["secrets"].clone()).into_response(),
+            Ok(value) => {
+                log::error!("Unable to resolve secrets for {}: {}", record.build_id, value["error"]);
+                error(StatusCode::BAD_GATEWAY, "Unable to resolve secrets")
+            }
+            Err(e) => {
+                log::error!("Invalid secrets response for {}: {e}", record.build_id);
+                error(StatusCode::BAD_GATEWAY, "Unable to resolve secrets")
+            }
+        },
+        Err(e) => {
+            log::error!("Unable to reach form-state for secrets of {}: {e}", record.build_id);
+            error(StatusCode::BAD_GATEWAY, "Unable to resolve secrets")
+        }
+    }
+}
+
+pub fn metadata_router(registry: MetadataRegistry) -> Router {
+    let v1 = Router::new()
+        .route("/instance", get(identity))
+        .route("/network", get(network))
+        .route("/user-data", get(user_data))
+        .route("/token", put(token))
+        .route("/secrets", get(secrets));
+
+    Router::new()
+        .nest("/v1", v1)
+        .with_state(registry)
+}
+
+/// Add the metadata address to the bridge and serve the metadata API on it
+pub async fn run_metadata_service(
+    registry: MetadataRegistry,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> Result<(), VmmError> {
+    let status = tokio::process::Command::new("ip")
+        .args(["addr", "replace", &format!("{METADATA_ADDR}/32"), "dev", METADATA_BRIDGE])
+        .status()
+        .await
+        .map_err(|e| VmmError::NetworkError(format!("Unable to run ip addr: {e}")))?;
+    if !status.success() {
+        return Err(VmmError::NetworkError(
+            format!("Unable to add {METADATA_ADDR} to {METADATA_BRIDGE}")
+        ));
+    }
+
+    let addr = SocketAddr::new(IpAddr::V4(METADATA_ADDR), METADATA_PORT);
+    let listener = tokio::net::TcpListener::bind(addr).await
+        .map_err(|e| VmmError::NetworkError(format!("Unable to bind metadata service to {addr}: {e}")))?;
+    log::info!("Instance metadata service listening on {addr}");
+
+    axum::serve(listener, metadata_router(registry).into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.recv().await;
+        })
+        .await
+        .map_err(|e| VmmError::NetworkError(format!("Metadata service failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(build_id: &str, mac: &str) -> InstanceMetadataRecord {
+        InstanceMetadataRecord {
+            instance_id: format!("{build_id}-instance"),
+            build_id: build_id.to_string(),
+            node_id: "node".to_string(),
+            owner: "owner".to_string(),
+            tap_device: "vmnet0".to_string(),
+            mac_addr: mac.to_string(),
+            formnet_ip: None,
+            user_data: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_arp_table() {
+        let contents = "IP address       HW type     Flags       HW address            Mask     Device\n\
+            192.168.0.11     0x1         0x2         52:54:00:AB:CD:EF     *        br0\n\
+            192.168.0.12     0x1         0x0         00:00:00:00:00:00     *        br0\n\
+            10.0.0.1         0x1         0x2         aa:bb:cc:dd:ee:ff     *        eth0\n";
+        let table = parse_arp_table(contents);
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            table.get(&"192.168.0.11".parse().unwrap()),
+            Some(&("52:54:00:ab:cd:ef".to_string(), "br0".to_string()))
+        );
+        assert!(!table.contains_key(&"192.168.0.12".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_bound_to_instance() {
+        let registry = MetadataRegistry::new();
+        registry.register(record("build-a", "52:54:00:00:00:01")).await;
+        registry.register(record("build-b", "52:54:00:00:00:02")).await;
+
+        let token = registry.issue_token("52:54:00:00:00:01", Duration::from_secs(60)).await;
+        assert!(registry.verify_token("52:54:00:00:00:01", &token).await);
+        assert!(!registry.verify_token("52:54:00:00:00:02", &token).await);
+        assert!(!registry.verify_token("52:54:00:00:00:01", "guess").await);
+
+        registry.set_formnet_ip("build-a", "10.0.0.5".parse().unwrap()).await;
+        assert_eq!(
+            registry.get_by_mac("52:54:00:00:00:01").await.unwrap().formnet_ip,
+            Some("10.0.0.5".parse().unwrap())
+        );
+
+        registry.deregister("build-a").await;
+        assert!(registry.get_by_mac("52:54:00:00:00:01").await.is_none());
+        assert!(!registry.verify_token("52:54:00:00:00:01", &token).await);
+    }
+}
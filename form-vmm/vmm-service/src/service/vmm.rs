@@ -4,11 +4,12 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, path::PathBuf};
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
 use alloy_primitives::Address;
 use form_pack::formfile::Formfile;
 use form_state::datastore::InstanceRequest;
-use form_state::instances::{ClusterMember, Instance, InstanceAnnotations, InstanceCluster, InstanceEncryption, InstanceMetadata, InstanceMonitoring, InstanceResources, InstanceSecurity, InstanceStatus};
+use form_state::build_manifests::{canonical_digest, file_digest, verity_hash_tree_path, ImageVerification, SignedBuildManifest, VerityInfo};
+use form_state::instances::{ClusterMember, Instance, InstanceAnnotations, InstanceCluster, InstanceEncryption, InstanceMetadata, InstanceMonitoring, InstanceReadiness, InstanceResources, InstanceSecurity, InstanceStatus};
 use formnet::{JoinRequest, JoinResponse, VmJoinRequest};
 use formnet_server::db::CrdtMap;
 use formnet_server::DatabasePeer;
@@ -29,26 +30,48 @@ use tokio::sync::{mpsc, Mutex};
 use tokio::sync::broadcast;
 use tokio::time::interval;
 use vmm_sys_util::signal::block_signal;
-use vmm::{api::{VmAddDevice, VmAddUserDevice, VmCoredumpData, VmCounters, VmInfo, VmReceiveMigrationData, VmRemoveDevice, VmResize, VmResizeZone, VmSendMigrationData, VmSnapshotConfig, VmmPingResponse}, config::RestoreConfig, vm_config::{DiskConfig, FsConfig, NetConfig, PmemConfig, VdpaConfig, VsockConfig}, PciDeviceInfo, VmmThreadHandle};
+use vmm::vm::VmState;
+use form_types::state::{Response as StateResponse, Success};
+use vmm::{api::{VmAddDevice, VmAddUserDevice, VmCoredumpData, VmCounters, VmInfoResponse, VmReceiveMigrationData, VmRemoveDevice, VmResizeData, VmResizeZone, VmSendMigrationData, VmSnapshotConfig, VmmPingResponse}, config::RestoreConfig, vm_config::{DiskConfig, FsConfig, NetConfig, PmemConfig, VdpaConfig, VsockConfig}, PciDeviceInfo, VmmThreadHandle};
 use vmm_sys_util::eventfd::EventFd;
 use seccompiler::SeccompAction;
 use tokio::task::JoinHandle;
 use form_types::{FormnetMessage, FormnetTopic, GenericPublisher, PeerType, VmmEvent, VmmSubscriber};
 use form_broker::{subscriber::SubStream, publisher::PubStream};
 use futures::future::join_all;
-use crate::api::VmmApiChannel;
+use crate::api::{drain::DrainController, VmmApiChannel};
 use crate::{api::VmmApi, util::ensure_directory};
-use crate::util::add_tap_to_bridge;
+use crate::util::{add_tap_to_bridge, delete_tap};
 use crate::{
     error::VmmError,
     config::create_vm_config,
     instance::config::VmInstanceConfig,
+    instance::network_policy::NetworkPolicy,
+    instance::balloon::{memory_pressure, read_host_memory, BalloonPolicy},
+    instance::boot_watchdog::{BootAction, BootWatch, BootWatchdogConfig},
+    instance::readiness::ReadinessGate,
+    instance::reconcile::{self, api_socket_dir, api_socket_path, HostInventory, InstanceRecord, LiveVm, ReconcilePlan, NET_CLASS_DIR},
 };
+
+/// How often VM balloons are resized to follow host memory pressure
+const BALLOON_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for a build's manifest to reach form-state. The image
+/// can show up on disk before its builder has published the manifest.
+const MANIFEST_WAIT: Duration = Duration::from_secs(120);
+/// How often instances are checked for a missed `boot_complete`
+const BOOT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Times form-state is asked for this node's instances at startup
+const RECONCILE_ATTEMPTS: u32 = 5;
+const RECONCILE_RETRY_INTERVAL: Duration = Duration::from_secs(3);
 use std::io::{Cursor, Write};
 use std::convert::TryFrom;
 use std::error::Error;
 use crate::ChError;
 use crate::IMAGE_DIR;
+use crate::{secrets_image_path, CloudInit};
+use crate::metadata::{run_metadata_service, InstanceMetadataRecord, MetadataRegistry};
+use crate::guest_agent::GuestAgentRegistry;
+use net_util::MacAddr;
 use form_pack::helpers::utils::build_instance_id;
 
 type VmmResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
@@ -73,19 +96,80 @@ pub struct FormVmm {
     socket_path: String,
     thread: Option<VmmThreadHandle>,
     api: FormVmApi,
+    tap_device: String,
+    network_policy: NetworkPolicy,
+    balloon: Option<BalloonPolicy>,
+    /// Last balloon size requested from the VM, in bytes
+    balloon_bytes: u64,
+    boot: BootWatch,
+    readiness: ReadinessGate,
 }
 
 impl FormVmm {
     fn new(
         socket_path: &str,
-        thread: VmmThreadHandle
+        thread: VmmThreadHandle,
+        tap_device: &str,
+        network_policy: NetworkPolicy,
+        balloon: Option<BalloonPolicy>,
+        boot: BootWatch,
+        readiness: ReadinessGate,
     ) -> Self {
-        Self { socket_path: socket_path.to_string(), thread: Some(thread), api: FormVmApi::new(socket_path) }
+        Self {
+            socket_path: socket_path.to_string(),
+            thread: Some(thread),
+            api: FormVmApi::new(socket_path),
+            tap_device: tap_device.to_string(),
+            network_policy,
+            balloon,
+            balloon_bytes: 0,
+            boot,
+            readiness,
+        }
+    }
+
+    /// A VM left running by an earlier run of the service. There's no thread
+    /// to join, it is only reached through its API socket.
+    fn adopted(
+        socket_path: &str,
+        tap_device: &str,
+        network_policy: NetworkPolicy,
+        balloon: Option<BalloonPolicy>,
+        boot: BootWatch,
+        readiness: ReadinessGate,
+    ) -> Self {
+        Self {
+            socket_path: socket_path.to_string(),
+            thread: None,
+            api: FormVmApi::new(socket_path),
+            tap_device: tap_device.to_string(),
+            network_policy,
+            balloon,
+            balloon_bytes: 0,
+            boot,
+            readiness,
+        }
     }
 
     pub fn socket_path(&self) -> &str {
         &self.socket_path
     }
+
+    pub fn network_policy(&self) -> &NetworkPolicy {
+        &self.network_policy
+    }
+
+    pub fn balloon_bytes(&self) -> u64 {
+        self.balloon_bytes
+    }
+
+    pub fn boot(&self) -> &BootWatch {
+        &self.boot
+    }
+
+    pub fn readiness(&self) -> &ReadinessGate {
+        &self.readiness
+    }
     
     pub async fn join(&mut self) -> VmmResult<()> {
         let handle = self.thread.take();
@@ -178,7 +262,7 @@ impl FormVmApi {
         self.body_request("vm.restore", body).await
     }
 
-    pub async fn resize(&self, data: &VmResize) -> ApiResult<()> {
+    pub async fn resize(&self, data: &VmResizeData) -> ApiResult<()> {
         let body = serde_json::to_string(data)?;
         self.body_request("vm.resize", body).await
     }
@@ -188,8 +272,8 @@ impl FormVmApi {
         self.body_request("vm.resize-zone", body).await
     }
 
-    pub async fn info(&self) -> ApiResult<VmInfo> {
-        self.get::<VmInfo>("vm.info").await
+    pub async fn info(&self) -> ApiResult<VmInfoResponse> {
+        self.get::<VmInfoResponse>("vm.info").await
     }
 
     pub async fn add_device(&self, data: &VmAddDevice) -> ApiResult<PciDeviceInfo> {
@@ -393,6 +477,13 @@ pub struct VmManager {
     subscriber: Option<VmmSubscriber>,
     signing_key: String,
     publisher_addr: Option<String>,
+    metadata: MetadataRegistry,
+    metadata_server: JoinHandle<()>,
+    guest_agents: GuestAgentRegistry,
+    boot_watchdog: BootWatchdogConfig,
+    mock: bool,
+    /// Refuse images without a dm-verity root hash in their manifest
+    require_verity: bool,
     create_futures: Arc<Mutex<FuturesUnordered<Pin<Box<dyn Future<Output = Result<VmmEvent, Box<dyn std::error::Error + Send + Sync>>> + Send + 'static>>>>>
 }
 
@@ -410,19 +501,32 @@ impl VmManager {
             &hex::decode(&signing_key)?
         )?;
 
-        let _node_id = hex::encode(Address::from_private_key(&pk));
+        let node_id = hex::encode(Address::from_private_key(&pk));
+        let drain = DrainController::new(node_id.clone());
+        let server_drain = drain.clone();
         let (resp_tx, resp_rx) = tokio::sync::mpsc::channel(1024);
+        let guest_agents = GuestAgentRegistry::new(node_id.clone(), event_sender.clone());
+        let server_guest_agents = guest_agents.clone();
         let api_channel = Arc::new(Mutex::new(VmmApiChannel::new(
             event_sender,
             resp_rx,
         )));
         let api_channel_server = api_channel.clone();
         let server = tokio::task::spawn(async move {
-            let server = VmmApi::new(api_channel_server.clone(), addr);
+            let server = VmmApi::new(api_channel_server.clone(), addr, server_drain, server_guest_agents);
             server.start_api_server().await?;
             Ok::<(), Box<dyn std::error::Error + Send + Sync + 'static>>(())
         });
 
+        let metadata = MetadataRegistry::new();
+        let metadata_registry = metadata.clone();
+        let metadata_shutdown = shutdown_rx.resubscribe();
+        let metadata_server = tokio::task::spawn(async move {
+            if let Err(e) = run_metadata_service(metadata_registry, metadata_shutdown).await {
+                log::error!("Instance metadata service stopped: {e}");
+            }
+        });
+
         let subscriber = if let Some(uri) = subscriber_uri {
             let subscriber = if let Ok(subscriber) = VmmSubscriber::new(uri).await {
                 Some(subscriber)
@@ -436,7 +540,7 @@ impl VmManager {
 
         #[cfg(not(feature = "devnet"))]
         let queue_handle = tokio::task::spawn(async move {
-            if let Err(e) = VmmApi::start_queue_reader(api_channel.clone(), shutdown_rx).await {
+            if let Err(e) = VmmApi::start_queue_reader(api_channel.clone(), drain, shutdown_rx).await {
                 eprintln!("Error in queue_reader: {e}");
             }
         });
@@ -450,12 +554,38 @@ impl VmManager {
             api_response_sender: resp_tx,
             subscriber,
             publisher_addr,
+            metadata,
+            metadata_server,
+            guest_agents,
+            boot_watchdog: BootWatchdogConfig::default(),
+            mock: false,
+            require_verity: false,
             #[cfg(not(feature = "devnet"))]
             queue_reader: queue_handle,
             create_futures: Arc::new(Mutex::new(FuturesUnordered::new())),
         })
     }
 
+    pub fn with_boot_watchdog(mut self, config: BootWatchdogConfig) -> Self {
+        self.boot_watchdog = config;
+        self
+    }
+
+    /// In mock mode no VMs are launched, a created instance is reported as
+    /// booted on the loopback address instead
+    pub fn with_mock(mut self, mock: bool) -> Self {
+        if mock {
+            log::warn!("Running in mock mode, instances won't be launched");
+        }
+        self.mock = mock;
+        self
+    }
+
+    pub fn with_require_verity(mut self, require_verity: bool) -> Self {
+        self.require_verity = require_verity;
+        self
+    }
+
     pub async fn derive_address(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
         let pk = SigningKey::from_slice(
             &hex::decode(&self.signing_key)?
@@ -464,38 +594,226 @@ impl VmManager {
         Ok(hex::encode(Address::from_private_key(&pk)))
     }
 
+    /// Fetch the secrets of a build from form-state and write them into a
+    /// cloud-init seed image attached to the VM, so they never have to be
+    /// baked into the Formfile or the rootfs. The seed also carries the
+    /// instance's guest agent token, and for clones the identity replacing
+    /// the one copied from the source. Clones get the secrets of their
+    /// source's build.
+    pub async fn prepare_secrets(
+        &self,
+        config: &mut VmInstanceConfig,
+        guest_agent_token: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let secrets_build_id = config.clone_of.as_ref()
+            .map_or(config.name.as_str(), |origin| origin.source_build_id.as_str());
+        let resp: serde_json::Value = reqwest::Client::new()
+            .get(format!("http://127.0.0.1:3004/v1/secrets/{secrets_build_id}/resolve"))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        if !resp["success"].as_bool().unwrap_or(false) {
+            return Err(Box::new(VmmError::Config(
+                format!("Unable to resolve secrets for {}: {}", config.name, resp["error"])
+            )));
+        }
+
+        let secrets: BTreeMap<String, String> = serde_json::from_value(resp["secrets"].clone())?;
+        let seed_path = secrets_image_path(&config.name);
+        let mut seed = CloudInit::for_secrets(&config.name, &secrets)?;
+        seed.add_guest_agent_token(guest_agent_token);
+        if let Some(origin) = &config.clone_of {
+            let instance_id = build_instance_id(self.derive_address().await?, config.name.clone())?;
+            seed.add_clone_identity(&instance_id, &config.name, &origin.name, origin.reset_identity);
+        }
+        seed.create_image(&seed_path)?;
+        std::fs::set_permissions(&seed_path, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        log::info!("Injecting {} secrets and the guest agent token into {} via {}", secrets.len(), config.name, seed_path.display());
+        config.cloud_init_path = Some(seed_path);
+
+        Ok(())
+    }
+
+    /// Check an image against the manifest its builder signed, so images or
+    /// Formfiles changed after the build are never booted. The outcome is
+    /// reported to form-state either way.
+    pub async fn verify_build_manifest(
+        &self,
+        name: &str,
+        formfile: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let result = self.check_build_manifest(name, formfile).await;
+        let (root_hash, error) = match &result {
+            Ok(root_hash) => (root_hash.clone(), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let verification = ImageVerification {
+            build_id: name.to_string(),
+            node_id: self.derive_address().await?,
+            root_hash,
+            verified: error.is_none(),
+            error,
+            checked_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+        };
+        if let Err(e) = VmmApi::write_to_queue(verification, 12, "state").await {
+            log::error!("Unable to report the image verification of {name}: {e}");
+        }
+        result.map(|_| ())
+    }
+
+    /// The checks of `verify_build_manifest`, returns the root hash the
+    /// image was checked against if it has a hash tree
+    async fn check_build_manifest(
+        &self,
+        name: &str,
+        formfile: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let started = tokio::time::Instant::now();
+        let signed: SignedBuildManifest = loop {
+            let resp: serde_json::Value = reqwest::Client::new()
+                .get(format!("http://127.0.0.1:3004/v1/build/{name}/manifest"))
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            if resp["success"].as_bool().unwrap_or(false) {
+                break serde_json::from_value(resp["manifest"].clone())?;
+            }
+            if started.elapsed() >= MANIFEST_WAIT {
+                return Err(Box::new(VmmError::ImageNotFound(
+                    format!("No build manifest for {name}, refusing to boot an unverified image: {}", resp["error"])
+                )));
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        };
+
+        signed.verify().map_err(VmmError::Config)?;
+        if let Some(violation) = signed.manifest.scan.as_ref().and_then(|scan| scan.policy_violation.as_ref()) {
+            return Err(Box::new(VmmError::Config(
+                format!("Image for {name} failed its builder's vulnerability policy: {violation}")
+            )));
+        }
+
+        let formfile: Formfile = serde_json::from_str(formfile)?;
+        if canonical_digest(&formfile)? != signed.manifest.formfile_digest {
+            return Err(Box::new(VmmError::Config(
+                format!("Formfile for {name} does not match its build manifest")
+            )));
+        }
+
+        let image_path = PathBuf::from(IMAGE_DIR).join(name).with_extension("raw");
+        if let Some(verity) = &signed.manifest.verity {
+            verify_hash_tree(name, &image_path, verity).await?;
+            log::info!(
+                "Image for {name} matches the root hash {} signed by builder {}",
+                verity.root_hash, signed.manifest.builder_node
+            );
+            return Ok(Some(verity.root_hash.clone()));
+        }
+        if self.require_verity {
+            return Err(Box::new(VmmError::Config(
+                format!("Build manifest for {name} has no dm-verity root hash, which this node requires")
+            )));
+        }
+
+        let image_digest = tokio::task::spawn_blocking(move || file_digest(image_path)).await??;
+        if image_digest != signed.manifest.image_digest {
+            return Err(Box::new(VmmError::ImageNotFound(
+                format!("Image for {name} does not match its build manifest, it may have been tampered with")
+            )));
+        }
+
+        log::info!("Image for {name} matches the manifest signed by builder {}", signed.manifest.builder_node);
+        Ok(None)
+    }
+
+    /// Gives the instance of a `Create` or `CloneInstance` event its TAP
+    /// device, MAC address, guest agent token and seed, then creates it
+    async fn launch(&mut self, event: &VmmEvent) -> VmmResult<()> {
+        let mut instance_config: VmInstanceConfig = event.try_into()
+            .map_err(|e: VmmError| {
+                VmmError::Config(e.to_string())
+            })?;
+
+        log::info!("Built VmInstanceConfig... Adding TAP device name");
+        instance_config.tap_device = format!("vmnet{}", self.tap_counter);
+        log::info!("Added TAP device name... Incrementing TAP counter...");
+        self.tap_counter += 1;
+        instance_config.mac_addr = Some(MacAddr::local_random().to_string());
+        let guest_agent_token = self.guest_agents.register(&instance_config.name).await?;
+        self.prepare_secrets(&mut instance_config, &guest_agent_token).await?;
+        log::info!("Incremented TAP counter... Attempting to create VM");
+        // TODO: return Future, and stash future in a `FuturesUnordered`
+        // to be awaited asynchronously.
+        self.create(&mut instance_config).await?;
+        log::info!("Created VM");
+        Ok(())
+    }
+
+    /// Copies the disk of `source` to the image of the new instance
+    /// `build_id`. A running source is paused for the copy so the clone gets
+    /// a consistent disk, reflinks keep the pause short where the filesystem
+    /// supports them.
+    async fn clone_disk(&self, source: &str, build_id: &str) -> VmmResult<()> {
+        let source_path = PathBuf::from(IMAGE_DIR).join(source).with_extension("raw");
+        let target_path = PathBuf::from(IMAGE_DIR).join(build_id).with_extension("raw");
+        if !source_path.exists() {
+            return Err(Box::new(VmmError::ImageNotFound(
+                format!("No disk for {source} on this node, clone it on the node it runs on")
+            )));
+        }
+        if target_path.exists() {
+            return Err(Box::new(VmmError::Config(
+                format!("An image for {build_id} already exists")
+            )));
+        }
+
+        let paused = match self.pause(&source.to_string()).await {
+            Ok(ApiResponse::SuccessNoContent { .. }) => true,
+            result => {
+                log::warn!("Copying the disk of {source} without pausing it: {result:?}");
+                false
+            }
+        };
+        let partial_path = target_path.with_extension("raw.partial");
+        let copied = tokio::process::Command::new("cp")
+            .arg("--reflink=auto")
+            .arg("--sparse=always")
+            .arg(&source_path)
+            .arg(&partial_path)
+            .status()
+            .await;
+        if paused {
+            if let Err(e) = self.resume(&source.to_string()).await {
+                log::error!("Unable to resume {source} after copying its disk: {e}");
+            }
+        }
+
+        match copied {
+            Ok(status) if status.success() => {
+                std::fs::rename(&partial_path, &target_path)?;
+                log::info!("Copied the disk of {source} to {}", target_path.display());
+                Ok(())
+            }
+            result => {
+                let _ = std::fs::remove_file(&partial_path);
+                Err(Box::new(VmmError::OperationFailed(
+                    format!("Unable to copy the disk of {source}: {result:?}")
+                )))
+            }
+        }
+    }
+
     pub async fn create(
         &mut self,
         config: &VmInstanceConfig
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
         log::info!("Received create request to create vm instance {}...", config.name);
-        let (api_socket_path, api_socket_fd) = if let Ok(path) = std::env::var("XDG_RUNTIME_DIR") {
-            let sock_path = format!("{path}/form-vmm/{}.sock", config.name);
-            ensure_directory(
-                PathBuf::from(&sock_path).parent().ok_or(
-                    Box::new(
-                        std::io::Error::new(
-                            std::io::ErrorKind::NotFound,
-                            format!("Parent directory for {sock_path} not found")
-                        )
-                    )
-                )?
-            )?;
-            (Some(format!("{path}/form-vmm/{}.sock", config.name)), None)
-        } else {
-            let sock_path = format!("/run/form-vmm/{}.sock", config.name);
-            ensure_directory(
-                PathBuf::from(&sock_path).parent().ok_or(
-                    Box::new(
-                        std::io::Error::new(
-                            std::io::ErrorKind::NotFound,
-                            format!("Parent directory for {sock_path} not found")
-                        )
-                    )
-                )?
-            )?;
-            (Some(format!("/run/form-vmm/{}.sock", config.name)), None) 
-        };
+        ensure_directory(api_socket_dir())?;
+        let (api_socket_path, api_socket_fd) = (Some(api_socket_path(&config.name).to_string_lossy().into_owned()), None);
         log::info!("Established API Socket for vm instance {}: {:?}...", config.name, api_socket_path);
 
         // Create channels and EventFDs
@@ -559,10 +877,18 @@ impl VmManager {
 
         // At this point api_socket_path is always Some
         // we can safely unwrap
+        let formfile: Formfile = serde_json::from_str(&config.formfile)?;
+
         log::info!("Creating new FormVmm");
         let vmm = FormVmm::new(
             &api_socket_path.unwrap(),
-            vmm_thread_handle
+            vmm_thread_handle,
+            &config.tap_device,
+            config.network_policy.clone(),
+            Some(BalloonPolicy::new(config.memory_mb, config.memory_tier))
+                .filter(|policy| policy.balloon_config().is_some()),
+            BootWatch::new(std::time::Instant::now(), &self.boot_watchdog),
+            ReadinessGate::new(formfile.get_health_check().as_ref()),
         );
 
         log::info!("Created new FormVmm");
@@ -575,8 +901,6 @@ impl VmManager {
             )
         })?;
 
-        let formfile: Formfile = serde_json::from_str(&config.formfile)?;
-
         let node_id = self.derive_address().await?;
         let build_id_param = config.name.clone();
         log::info!("Deriving instance id from node_id: {node_id} and build_id_param: {build_id_param}");
@@ -590,7 +914,9 @@ impl VmManager {
             instance_owner: config.owner.clone(),
             created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
             updated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
-            status: InstanceStatus::Created,
+            status: InstanceStatus::Booting,
+            status_history: Vec::new(),
+            revision: 0,
             last_snapshot: 0,
             host_region: String::new(),
             formfile: config.formfile.clone(),
@@ -602,16 +928,18 @@ impl VmManager {
                 scaling_manager: None,
             },
             snapshots: None,
+            schedule: None,
             metadata: InstanceMetadata {
                 annotations: InstanceAnnotations {
                     deployed_by: config.owner.clone(),
                     build_commit: None,
                     network_id: 0,
                 },
-                description: String::new(),
+                description: config.clone_of.as_ref().map(|origin| origin.name.clone()).unwrap_or_default(),
                 monitoring: InstanceMonitoring {
                     logging_enabled: false,
-                    metrics_endpoint: String::new()
+                    metrics_endpoint: String::new(),
+                    readiness: None,
                 },
                 security: InstanceSecurity {
                     encryption: InstanceEncryption {
@@ -621,7 +949,9 @@ impl VmManager {
                     hsm: false,
                     tee: false
                 },
-                tags: vec![]
+                tags: config.clone_of.iter()
+                    .map(|origin| format!("cloned_from:{}", origin.source_build_id))
+                    .collect()
             },
             resources: InstanceResources {
                 vcpus: formfile.get_vcpus(),
@@ -642,18 +972,35 @@ impl VmManager {
             .json()
             .await?;
 
+        if let Some(mac_addr) = &config.mac_addr {
+            self.metadata.register(InstanceMetadataRecord {
+                instance_id: instance.instance_id.clone(),
+                build_id: instance.build_id.clone(),
+                node_id: instance.node_id.clone(),
+                owner: instance.instance_owner.clone(),
+                tap_device: config.tap_device.clone(),
+                mac_addr: mac_addr.clone(),
+                formnet_ip: None,
+                user_data: config.formfile.clone(),
+            }).await;
+        }
+
         log::info!("Inserting Form VMM into vm_monitoris map");
         self.vm_monitors.insert(config.name.clone(), vmm);
         log::info!("Calling `boot` on FormVmm");
         self.boot(&config.name).await?;
 
-        instance.status = InstanceStatus::Started;
+        instance.status = InstanceStatus::Booting;
         instance.updated_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
 
         if let Err(e) = add_tap_to_bridge("br0", &config.tap_device.clone()).await {
             log::error!("Error attempting to add tap device {} to bridge: {e}", &config.tap_device)
         };
 
+        if let Err(e) = config.network_policy.apply(&config.tap_device) {
+            log::error!("Error attempting to apply network policy to {}: {e}", &config.tap_device)
+        }
+
         #[cfg(feature = "devnet")]
         reqwest::Client::new().post("http://127.0.0.1:3004/instance/update")
             .json(&InstanceRequest::Update(instance.clone()))
@@ -693,11 +1040,18 @@ impl VmManager {
     }
 
     pub async fn delete(&mut self, name: &String) -> ApiResult<()> {
-        let api = &self.get_vmm(name)?.api;
+        let vmm = self.get_vmm(name)?;
+        let api = &vmm.api;
         let resp = api.delete().await?;
         match &resp {
             ApiResponse::SuccessNoContent { .. } => {
+                if let Err(e) = NetworkPolicy::remove(&vmm.tap_device) {
+                    log::error!("Error removing network policy from {}: {e}", vmm.tap_device);
+                }
                 std::fs::remove_file(&api.socket_path)?;
+                let _ = std::fs::remove_file(secrets_image_path(name));
+                self.metadata.deregister(name).await;
+                self.guest_agents.deregister(name).await;
                 self.remove_vmm(&name)?;
                 return Ok(resp.clone())
             }
@@ -716,7 +1070,54 @@ impl VmManager {
         }
     }
 
-    pub async fn info(&self, name: &String) -> ApiResult<VmInfo> {
+    /// Replaces the bandwidth caps and firewall rules of a running instance.
+    /// The virtio rate limiter is fixed at boot, so only the nftables rules
+    /// change until the instance is restarted.
+    pub async fn update_network_policy(&mut self, name: &str, policy: NetworkPolicy) -> VmmResult<()> {
+        policy.validate()?;
+        let vmm = self.vm_monitors.get_mut(name).ok_or(
+            VmmError::VmNotFound(format!("Unable to find Vm Monitor for {name}"))
+        )?;
+        policy.apply(&vmm.tap_device)?;
+        vmm.network_policy = policy;
+        Ok(())
+    }
+
+    /// Asks form-state for the instance's `<build_id>.fog` vanity domain
+    async fn publish_vanity_domain(&self, id: &str, build_id: &str) {
+        log::info!("Starting automatic DNS provisioning for instance: {id}");
+
+        let domain_name = format!("{}.fog", build_id);
+        log::info!("Generated vanity domain: {domain_name}");
+
+        let dns_provider = self.publisher_addr.clone().unwrap_or_else(|| "127.0.0.1".to_string());
+        let dns_endpoint = format!("http://{dns_provider}:3004/dns/{domain_name}/{build_id}/request_vanity");
+
+        log::info!("Sending request to DNS API at: {dns_endpoint}");
+        match reqwest::Client::new()
+            .post(&dns_endpoint)
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.status() {
+                        reqwest::StatusCode::OK => {
+                            log::info!("Successfully provisioned vanity domain: {domain_name} for instance: {id}");
+                            // The DNS record will be stored automatically by the DNS service
+                            log::info!("Instance {id} is now accessible at {domain_name}");
+                        },
+                        _ => {
+                            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+                            log::error!("Failed to provision vanity domain: {domain_name}. Error: {error_text}");
+                        }
+                    }
+                },
+                Err(e) => {
+                    log::error!("Failed to send request to DNS API for domain: {domain_name}. Error: {e}");
+                }
+            }
+    }
+
+    pub async fn info(&self, name: &String) -> ApiResult<VmInfoResponse> {
         self.get_vmm(name)?.api.info().await
     }
 
@@ -724,14 +1125,287 @@ impl VmManager {
         self.get_vmm(name)?.api.power_button().await
     }
 
+    /// Inflates or deflates every VM's balloon to match host memory pressure
+    pub async fn adjust_balloons(&mut self) {
+        let pressure = match read_host_memory() {
+            Ok((total, available)) => memory_pressure(available, total),
+            Err(e) => {
+                log::warn!("Skipping balloon adjustment: {e}");
+                return;
+            }
+        };
+
+        for (name, vmm) in self.vm_monitors.iter_mut() {
+            let Some(policy) = vmm.balloon else {
+                continue;
+            };
+            let target = policy.target_bytes(pressure);
+            if !policy.should_resize(vmm.balloon_bytes, target) {
+                continue;
+            }
+
+            let data = VmResizeData {
+                desired_vcpus: None,
+                desired_ram: None,
+                desired_balloon: Some(target),
+            };
+            match vmm.api.resize(&data).await {
+                Ok(_) => {
+                    log::info!(
+                        "Resized balloon of {name} from {} MiB to {} MiB at host memory pressure {pressure:.2}",
+                        vmm.balloon_bytes >> 20, target >> 20
+                    );
+                    vmm.balloon_bytes = target;
+                }
+                Err(e) => log::error!("Unable to resize balloon of {name}: {e}"),
+            }
+        }
+    }
+
+    /// Restarts instances that haven't reported `boot_complete` within the
+    /// boot timeout, and tears down the ones that are out of restarts
+    pub async fn check_boot_watchdog(&mut self) {
+        let now = std::time::Instant::now();
+        let config = self.boot_watchdog;
+        let actions: Vec<(String, BootAction)> = self.vm_monitors.iter_mut()
+            .map(|(name, vmm)| (name.clone(), vmm.boot.check(now, &config)))
+            .filter(|(_, action)| *action != BootAction::Wait)
+            .collect();
+
+        for (name, action) in actions {
+            match action {
+                BootAction::Restart { attempt } => {
+                    log::warn!(
+                        "{name} did not report boot complete within {}s, restarting (attempt {attempt} of {})",
+                        config.boot_timeout.as_secs(), config.max_restarts
+                    );
+                    if let Err(e) = self.reboot(&name).await {
+                        log::error!("Unable to restart {name}: {e}");
+                    }
+                }
+                BootAction::Fail => {
+                    log::error!("{name} never reported boot complete after {} restarts, marking it failed", config.max_restarts);
+                    if let Err(e) = self.fail_boot(&name).await {
+                        log::error!("Error cleaning up {name} after failed boot: {e}");
+                    }
+                }
+                BootAction::Wait => {}
+            }
+        }
+    }
+
+    /// Removes an instance that never booted and frees its TAP device and
+    /// disk image, then records it as failed in form-state
+    async fn fail_boot(&mut self, name: &str) -> VmmResult<()> {
+        let name = name.to_string();
+        let tap_device = self.get_vmm(&name)?.tap_device.clone();
+        match self.delete(&name).await {
+            Ok(ApiResponse::SuccessNoContent { .. }) => {}
+            result => {
+                // The VM may be wedged, clean up what delete would have
+                log::warn!("Unable to delete {name} through its api: {result:?}");
+                if let Ok(vmm) = self.get_vmm(&name) {
+                    let _ = NetworkPolicy::remove(&vmm.tap_device);
+                    let _ = std::fs::remove_file(&vmm.socket_path);
+                }
+                let _ = std::fs::remove_file(secrets_image_path(&name));
+                self.metadata.deregister(&name).await;
+                self.guest_agents.deregister(&name).await;
+                self.remove_vmm(&name)?;
+            }
+        }
+        if let Err(e) = delete_tap(&tap_device).await {
+            log::warn!("Unable to remove TAP device {tap_device}: {e}");
+        }
+        let image_path = PathBuf::from(IMAGE_DIR).join(&name).with_extension("raw");
+        if let Err(e) = std::fs::remove_file(&image_path) {
+            log::warn!("Unable to remove disk image {}: {e}", image_path.display());
+        }
+
+        let instance_id = build_instance_id(self.derive_address().await?, name.clone())?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        Instance::update_with_retry(&instance_id, |instance| {
+            instance.status = InstanceStatus::Failed;
+            instance.updated_at = timestamp;
+        }).await?;
+
+        Ok(())
+    }
+
+    /// This node's instances in form-state, None if form-state can't be
+    /// reached. It may still be starting, so it's asked a few times.
+    async fn node_instances(&self) -> Option<Vec<Instance>> {
+        let node_id = self.derive_address().await.ok()?;
+        let client = reqwest::Client::new();
+        for attempt in 1..=RECONCILE_ATTEMPTS {
+            let resp = client.get(format!("http://127.0.0.1:3004/v1/node/{node_id}/instances"))
+                .send()
+                .await;
+            match resp {
+                Ok(resp) => match resp.json::<StateResponse<Instance>>().await {
+                    Ok(StateResponse::Success(Success::List(instances))) => return Some(instances),
+                    Ok(other) => log::warn!("Unexpected response listing this node's instances: {other:?}"),
+                    Err(e) => log::warn!("Invalid response listing this node's instances: {e}"),
+                },
+                Err(e) => log::warn!("Unable to reach form-state (attempt {attempt} of {RECONCILE_ATTEMPTS}): {e}"),
+            }
+            tokio::time::sleep(RECONCILE_RETRY_INTERVAL).await;
+        }
+        None
+    }
+
+    /// Takes a VM left running by an earlier run of the service back under
+    /// management. Its guest agent token was lost with that run, so the
+    /// guest agent is unavailable until the VM is recreated.
+    async fn adopt_vm(&mut self, name: &str, socket: &std::path::Path, vm: &LiveVm, instance: Option<&Instance>) {
+        let formfile = instance.and_then(|instance| serde_json::from_str::<Formfile>(&instance.formfile).ok());
+        let network_policy = formfile.as_ref().map(NetworkPolicy::from_formfile).unwrap_or_default();
+        let balloon = formfile.as_ref()
+            .filter(|formfile| formfile.get_confidential().is_none())
+            .map(|formfile| BalloonPolicy::new(formfile.get_memory() as u64, formfile.get_memory_tier()))
+            .filter(|policy| policy.balloon_config().is_some());
+        let mut boot = BootWatch::new(std::time::Instant::now(), &self.boot_watchdog);
+        let mut readiness = ReadinessGate::new(formfile.as_ref().and_then(|formfile| formfile.get_health_check()).as_ref());
+        // A VM form-state has as Ready won't send boot_complete again, and its
+        // DNS record was published by the earlier run
+        if instance.map_or(true, |instance| instance.status != InstanceStatus::Booting) {
+            boot.complete();
+            readiness.booted();
+            readiness.report(true);
+        }
+
+        let tap_device = vm.tap.clone().unwrap_or_default();
+        if !tap_device.is_empty() {
+            if let Err(e) = add_tap_to_bridge("br0", &tap_device).await {
+                log::error!("Error attempting to add tap device {tap_device} to bridge: {e}");
+            }
+            if let Err(e) = network_policy.apply(&tap_device) {
+                log::error!("Error attempting to apply network policy to {tap_device}: {e}");
+            }
+        }
+        if let (Some(instance), Some(mac_addr)) = (instance, &vm.mac) {
+            self.metadata.register(InstanceMetadataRecord {
+                instance_id: instance.instance_id.clone(),
+                build_id: instance.build_id.clone(),
+                node_id: instance.node_id.clone(),
+                owner: instance.instance_owner.clone(),
+                tap_device: tap_device.clone(),
+                mac_addr: mac_addr.clone(),
+                formnet_ip: instance.formnet_ip,
+                user_data: instance.formfile.clone(),
+            }).await;
+        }
+
+        let vmm = FormVmm::adopted(&socket.to_string_lossy(), &tap_device, network_policy, balloon, boot, readiness);
+        self.vm_monitors.insert(name.to_string(), vmm);
+        log::info!("Adopted {name} (tap {tap_device})");
+    }
+
+    /// Shuts down and removes a VM form-state doesn't expect on this node
+    async fn remove_orphaned_vm(&self, name: &str, socket: &std::path::Path) {
+        let api = FormVmApi::new(&socket.to_string_lossy());
+        if let Err(e) = api.shutdown().await {
+            log::warn!("Unable to shut down orphaned VM {name}: {e}");
+        }
+        if let Err(e) = api.delete().await {
+            log::warn!("Unable to delete orphaned VM {name}: {e}");
+        }
+        let _ = std::fs::remove_file(socket);
+    }
+
+    /// Compares what an earlier run of the service left on the host with
+    /// form-state. VMs still expected on this node are adopted, everything
+    /// else is cleaned up and records of VMs that are gone are set to
+    /// `Stopped`. Runs once, before any event is handled.
+    pub async fn reconcile(&mut self) -> ReconcilePlan {
+        let inventory = HostInventory::scan(&api_socket_dir(), std::path::Path::new(IMAGE_DIR), std::path::Path::new(NET_CLASS_DIR));
+        let mut live = BTreeMap::new();
+        for (name, socket) in &inventory.sockets {
+            let api = FormVmApi::new(&socket.to_string_lossy());
+            if api.ping().await.is_err() {
+                continue;
+            }
+            let info = match api.info().await {
+                Ok(ApiResponse::Success { content: Some(info), .. }) => Some(info),
+                _ => None,
+            };
+            let net = info.as_ref().and_then(|info| info.config.net.as_ref()?.first().cloned());
+            live.insert(name.clone(), LiveVm {
+                tap: net.as_ref().and_then(|net| net.tap.clone()),
+                mac: net.as_ref().map(|net| net.mac.to_string()),
+                running: info.as_ref().map_or(false, |info| matches!(info.state, VmState::Running | VmState::Paused)),
+            });
+        }
+
+        let instances = self.node_instances().await;
+        let records: Option<Vec<InstanceRecord>> = instances.as_ref()
+            .map(|instances| instances.iter().map(InstanceRecord::from).collect());
+        let plan = reconcile::plan(&inventory, &live, records.as_deref());
+
+        for name in &plan.adopt {
+            let instance = instances.iter().flatten()
+                .find(|instance| instance.build_id == *name && instance.status != InstanceStatus::Deleted);
+            self.adopt_vm(name, &inventory.sockets[name], &live[name], instance).await;
+        }
+        for name in &plan.orphaned_vms {
+            log::warn!("Removing {name}: it is running but form-state has no instance for it on this node");
+            self.remove_orphaned_vm(name, &inventory.sockets[name]).await;
+        }
+        for name in &plan.stale_sockets {
+            log::warn!("Removing stale API socket of {name}");
+            let _ = std::fs::remove_file(&inventory.sockets[name]);
+        }
+        for tap in &plan.orphaned_taps {
+            log::warn!("Removing TAP device {tap}: no running VM uses it");
+            let _ = NetworkPolicy::remove(tap);
+            if let Err(e) = delete_tap(tap).await {
+                log::warn!("Unable to remove TAP device {tap}: {e}");
+            }
+        }
+        for name in &plan.orphaned_secrets {
+            log::warn!("Removing the secrets image of {name}: it isn't running");
+            let _ = std::fs::remove_file(secrets_image_path(name));
+        }
+        for name in &plan.unclaimed_images {
+            log::warn!("Disk image of {name} isn't used by any instance on this node");
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default();
+        for instance_id in &plan.lost_instances {
+            log::warn!("Instance {instance_id} is running in form-state but its VM is gone, marking it stopped");
+            let result = Instance::update_with_retry(instance_id, |instance| {
+                instance.status = InstanceStatus::Stopped;
+                instance.updated_at = timestamp;
+            }).await;
+            if let Err(e) = result {
+                log::error!("Unable to mark {instance_id} stopped: {e}");
+            }
+        }
+
+        self.tap_counter = self.tap_counter.max(plan.next_tap);
+        if !plan.records_checked {
+            log::warn!("form-state couldn't be reached, adopted every running VM without checking its instance");
+        }
+        if plan.has_discrepancies() {
+            log::warn!("Reconciled with discrepancies: {}", serde_json::to_string(&plan).unwrap_or_default());
+        } else {
+            log::info!("Reconciled {} running VMs, no discrepancies", plan.adopt.len());
+        }
+        plan
+    }
+
     pub async fn run(
         mut self,
         mut shutdown_rx: broadcast::Receiver<()>,
         mut api_rx: mpsc::Receiver<VmmEvent>
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        if !self.mock {
+            self.reconcile().await;
+        }
         if let Some(mut subscriber) = self.subscriber.take() {
             let futures_clone = self.create_futures.clone();
             let mut interval = interval(Duration::from_secs(20));
+            let mut balloon_interval = interval(BALLOON_INTERVAL);
+            let mut watchdog_interval = interval(BOOT_WATCHDOG_INTERVAL);
             loop {
                 tokio::select! {
                     res = shutdown_rx.recv() => {
@@ -757,6 +1431,12 @@ impl VmManager {
                             }
                         }
                     }
+                    _ = balloon_interval.tick() => {
+                        self.adjust_balloons().await;
+                    }
+                    _ = watchdog_interval.tick() => {
+                        self.check_boot_watchdog().await;
+                    }
                     _ = interval.tick() => {
                         let mut guard = futures_clone.lock().await;
                         while let Some(Ok(event)) = guard.next().await {
@@ -771,6 +1451,8 @@ impl VmManager {
         } else {
             let futures_clone = self.create_futures.clone();
             let mut interval = interval(Duration::from_secs(20));
+            let mut balloon_interval = interval(BALLOON_INTERVAL);
+            let mut watchdog_interval = interval(BOOT_WATCHDOG_INTERVAL);
             loop {
                 tokio::select! {
                     res = shutdown_rx.recv() => {
@@ -789,6 +1471,12 @@ impl VmManager {
                             log::error!("Error while handling event: {event:?}: {e}"); 
                         }
                     }
+                    _ = balloon_interval.tick() => {
+                        self.adjust_balloons().await;
+                    }
+                    _ = watchdog_interval.tick() => {
+                        self.check_boot_watchdog().await;
+                    }
                     _ = interval.tick() => {
                         let mut guard = futures_clone.lock().await;
                         while let Some(Ok(event)) = guard.next().await {
@@ -815,24 +1503,25 @@ impl VmManager {
             }
             VmmEvent::Create { 
                 ref name, 
+                ref formfile,
                 ..
             } => {
                 log::info!("Instance name: {name}");
-                if PathBuf::from(IMAGE_DIR).join(name).with_extension("raw").exists() {
-                    let mut instance_config: VmInstanceConfig = event.try_into()
-                        .map_err(|e: VmmError| {
-                            VmmError::Config(e.to_string())
-                        })?;
-
-                    log::info!("Built VmInstanceConfig... Adding TAP device name");
-                    instance_config.tap_device = format!("vmnet{}", self.tap_counter);
-                    log::info!("Added TAP device name... Incrementing TAP counter...");
-                    self.tap_counter += 1;
-                    log::info!("Incremented TAP counter... Attempting to create VM");
-                    // TODO: return Future, and stash future in a `FuturesUnordered`
-                    // to be awaited asynchronously.
-                    self.create(&mut instance_config).await?;
-                    log::info!("Created VM");
+                if self.mock {
+                    log::info!("Mock mode: reporting {name} booted without launching it");
+                    let booted = VmmEvent::BootComplete {
+                        id: name.clone(),
+                        build_id: name.clone(),
+                        formnet_ip: "127.0.0.1".to_string(),
+                    };
+                    self.create_futures.lock().await.push(Box::pin(async move { Ok(booted) }));
+                } else if PathBuf::from(IMAGE_DIR).join(name).with_extension("raw").exists() {
+                    #[cfg(not(feature = "devnet"))]
+                    self.verify_build_manifest(name, formfile).await?;
+                    #[cfg(feature = "devnet")]
+                    let _ = formfile;
+
+                    self.launch(event).await?;
                 } else {
                     let await_event = event.clone();
                     let await_res = Box::pin(async {
@@ -873,19 +1562,43 @@ Formpack for {name} doesn't exist:
 "#);
                 }
             }
+            VmmEvent::CloneInstance { ref source, ref build_id, ref name, .. } => {
+                log::info!("Cloning {source} into {build_id} as {name}");
+                if self.mock {
+                    log::info!("Mock mode: reporting clone {build_id} booted without launching it");
+                    let booted = VmmEvent::BootComplete {
+                        id: build_id.clone(),
+                        build_id: build_id.clone(),
+                        formnet_ip: "127.0.0.1".to_string(),
+                    };
+                    self.create_futures.lock().await.push(Box::pin(async move { Ok(booted) }));
+                } else {
+                    // The source's image was verified when it was launched,
+                    // the copy is booted without a manifest of its own
+                    self.clone_disk(source, build_id).await?;
+                    if let Err(e) = self.launch(event).await {
+                        let _ = std::fs::remove_file(PathBuf::from(IMAGE_DIR).join(build_id).with_extension("raw"));
+                        return Err(e);
+                    }
+                }
+            }
             VmmEvent::BootComplete { id, formnet_ip, build_id, .. } => {
+                // Instances this process didn't create aren't gated
+                let (publish_dns, ready) = match self.vm_monitors.get_mut(build_id) {
+                    Some(vmm) => {
+                        vmm.boot.complete();
+                        (vmm.readiness.booted(), vmm.readiness.is_ready())
+                    }
+                    None => (true, true),
+                };
                 //TODO: Write this information into State so that 
                 //users/developers can "get" the IP address
                 log::info!("Received boot complete event, getting self");
                 let me = DatabasePeer::<String, CrdtMap>::get(self.derive_address().await?).await?.inner.ip;
-                log::info!("Getting instance: {id}");
-        
-                let mut instance = Instance::get(id).await.ok_or(
-                    Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Instance doesn't exist")))?;
                 log::info!("Adding cluster member to instance...");
 
                 let cluster_member = ClusterMember {
-                    instance_id: instance.instance_id.clone(),
+                    instance_id: id.to_string(),
                     node_id: self.derive_address().await?,
                     node_public_ip: publicip::get_any(Preference::Ipv4).ok_or(
                         Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Unable to get node public ip"))
@@ -913,129 +1626,92 @@ Formpack for {name} doesn't exist:
                     .await?;
 
                 log::info!("Adding formnet_ip to instance");
-                instance.formnet_ip = Some(formnet_ip.parse()?);
-                instance.status = InstanceStatus::Started;
+                let instance_formnet_ip: std::net::IpAddr = formnet_ip.parse()?;
+                self.metadata.set_formnet_ip(build_id, instance_formnet_ip).await;
+                let status = if ready { InstanceStatus::Ready } else { InstanceStatus::Booting };
                 let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64; 
-                instance.updated_at = timestamp;
-                
-                // Automatic DNS Provisioning
-                log::info!("Starting automatic DNS provisioning for instance: {id}");
-                
-                // Create a vanity domain based on the build ID
-                let domain_name = format!("{}.fog", build_id);
-                log::info!("Generated vanity domain: {domain_name}");
-                
-                // Create the DNS record pointing to the instance
-                let parsed_formnet_ip = formnet_ip.parse::<IpAddr>()?;
-                let socket_addr = SocketAddr::new(parsed_formnet_ip, 22); // Default port for SSH
-                
-                // Construct request to the DNS API
-                let dns_provider = self.publisher_addr.clone().unwrap_or_else(|| "127.0.0.1".to_string());
-                let dns_endpoint = format!("http://{dns_provider}:3004/dns/{domain_name}/{build_id}/request_vanity");
                 
-                log::info!("Sending request to DNS API at: {dns_endpoint}");
-                
-                // Make the API call
-                match reqwest::Client::new()
-                    .post(&dns_endpoint)
-                    .send()
-                    .await {
-                        Ok(response) => {
-                            match response.status() {
-                                reqwest::StatusCode::OK => {
-                                    log::info!("Successfully provisioned vanity domain: {domain_name} for instance: {id}");
-                                    
-                                    // The DNS record will be stored automatically by the DNS service
-                                    // We just inform the user that the domain has been provisioned in the logs
-                                    log::info!("Instance {id} is now accessible at {domain_name}");
-                                },
-                                _ => {
-                                    let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-                                    log::error!("Failed to provision vanity domain: {domain_name}. Error: {error_text}");
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            log::error!("Failed to send request to DNS API for domain: {domain_name}. Error: {e}");
-                        }
-                    }
+                if publish_dns {
+                    self.publish_vanity_domain(id, build_id).await;
+                } else {
+                    log::info!("Holding back DNS for {id} until its health check passes");
+                }
 
-                log::info!("Updating instance...");
-                let request = InstanceRequest::Update(instance);
+                log::info!("Updating instance with formnet IP...");
+                Instance::update_with_retry(id, |instance| {
+                    instance.formnet_ip = Some(instance_formnet_ip);
+                    instance.status = status.clone();
+                    instance.updated_at = timestamp;
+                }).await?;
 
-                log::info!("Writing Update request with formnet IP to queue...");
-                #[cfg(not(feature = "devnet"))]
-                VmmApi::write_to_queue(request.clone(), 4, "state").await?; 
+                log::info!("Boot Complete for {id}: formnet id: {formnet_ip}");
+            }
+            VmmEvent::Readiness { id, build_id, ready, consecutive_failures, detail } => {
+                let (publish_dns, gate_open) = match self.vm_monitors.get_mut(build_id) {
+                    Some(vmm) => (vmm.readiness.report(*ready), vmm.readiness.is_ready()),
+                    None => (false, false),
+                };
+                if *ready {
+                    log::info!("{id} reported ready");
+                } else {
+                    log::warn!("{id} failed {consecutive_failures} health checks: {detail:?}");
+                }
 
-                #[cfg(feature = "devnet")]
-                reqwest::Client::new().post("http://127.0.0.1:3004/instance/update")
-                    .json(&request)
-                    .send()
-                    .await?
-                    .json()
-                    .await?;
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+                let readiness = InstanceReadiness {
+                    ready: *ready,
+                    consecutive_failures: *consecutive_failures,
+                    detail: detail.clone(),
+                    reported_at: timestamp,
+                };
+                let updated = Instance::update_with_retry(id, |instance| {
+                    instance.metadata.monitoring.readiness = Some(readiness.clone());
+                    if gate_open && instance.status == InstanceStatus::Booting {
+                        instance.status = InstanceStatus::Ready;
+                    }
+                    instance.updated_at = timestamp;
+                }).await;
+                if let Err(e) = updated {
+                    log::warn!("Unable to record readiness of {id}: {e}");
+                }
 
-                log::info!("Boot Complete for {id}: formnet id: {formnet_ip}");
+                if publish_dns {
+                    self.publish_vanity_domain(id, build_id).await;
+                }
             }
             VmmEvent::Stop { id, .. } => {
                 //TODO: verify ownership/authorization, etc.
                 self.pause(id).await?;
                 let instance_id_val = build_instance_id(self.derive_address().await?, id.to_string())?;
-                let mut instance = Instance::get(&instance_id_val).await.ok_or(
-                    Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Instance doesn't exist"))
-                )?;
-                instance.status = InstanceStatus::Stopped;
                 let node_id = self.derive_address().await?;
-                instance.cluster.members = instance.cluster.members.iter_mut().map(|(k, v)| {
-                    if v.node_id == node_id {
-                        v.status = "Stopped".to_string();
-                    }
-                    (k.clone(), v.clone())
-                }).collect();
-                let request = InstanceRequest::Update(instance);
-                #[cfg(not(feature = "devnet"))]
-                VmmApi::write_to_queue(request.clone(), 4, "state").await?; 
-
-                #[cfg(feature = "devnet")]
-                reqwest::Client::new().post("http://127.0.0.1:3004/instance/update")
-                    .json(&request)
-                    .send()
-                    .await?
-                    .json()
-                    .await?;
+                Instance::update_with_retry(&instance_id_val, |instance| {
+                    instance.status = InstanceStatus::Stopped;
+                    instance.cluster.members.values_mut()
+                        .filter(|member| member.node_id == node_id)
+                        .for_each(|member| member.status = "Stopped".to_string());
+                }).await?;
 
             }
             VmmEvent::Start {  id, .. } => {
                 //TODO: verify ownership/authorization, etc.
                 self.boot(id).await?;
                 let instance_id_val = build_instance_id(self.derive_address().await?, id.to_string())?;
-                let mut instance = Instance::get(&instance_id_val).await.ok_or(
-                    Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Instance doesn't exist"))
-                )?;
-                instance.status = InstanceStatus::Started;
                 let node_id = self.derive_address().await?;
-                instance.cluster.members = instance.cluster.members.iter_mut().map(|(k, v)| {
-                    if v.node_id == node_id {
-                        v.status = "Started".to_string();
-                    }
-                    (k.clone(), v.clone())
-                }).collect();
-                let request = InstanceRequest::Update(instance);
-                #[cfg(not(feature = "devnet"))]
-                VmmApi::write_to_queue(request.clone(), 4, "state").await?; 
-
-                #[cfg(feature = "devnet")]
-                reqwest::Client::new().post("http://127.0.0.1:3004/instance/update")
-                    .json(&request)
-                    .send()
-                    .await?
-                    .json()
-                    .await?;
+                Instance::update_with_retry(&instance_id_val, |instance| {
+                    instance.status = InstanceStatus::Ready;
+                    instance.cluster.members.values_mut()
+                        .filter(|member| member.node_id == node_id)
+                        .for_each(|member| member.status = "Started".to_string());
+                }).await?;
 
             }
             VmmEvent::Delete { id, .. } => {
                 self.delete(id).await?;
             }
+            VmmEvent::UpdateNetworkPolicy { id, policy } => {
+                let policy: NetworkPolicy = serde_json::from_str(policy)?;
+                self.update_network_policy(id, policy).await?;
+            }
             VmmEvent::Get { id, .. } => {
                 let resp = serde_json::to_string(&self.info(id).await?)?;
                 self.api_response_sender.send(
@@ -1162,3 +1838,41 @@ Formpack for {name} doesn't exist:
         Ok(())
     }
 }
+
+/// Checks every block of an image against the hash tree formatted for it
+/// at build time and the root hash its builder signed
+async fn verify_hash_tree(
+    name: &str,
+    image_path: &std::path::Path,
+    verity: &VerityInfo,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let tree_path = verity_hash_tree_path(image_path);
+    if !tree_path.exists() {
+        return Err(Box::new(VmmError::ImageNotFound(
+            format!("No hash tree for {name} at {}, refusing to boot an unverified image", tree_path.display())
+        )));
+    }
+    // The tree only covers its data blocks, anything appended to the image
+    // would go unchecked
+    let size = tokio::fs::metadata(image_path).await?.len();
+    if size != verity.data_bytes() {
+        return Err(Box::new(VmmError::Config(
+            format!("Image for {name} is {size} bytes but its hash tree covers {}, it may have been tampered with", verity.data_bytes())
+        )));
+    }
+
+    let output = tokio::process::Command::new("veritysetup")
+        .arg("verify")
+        .arg(image_path)
+        .arg(&tree_path)
+        .arg(&verity.root_hash)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(Box::new(VmmError::Config(format!(
+            "Image for {name} does not match its signed root hash, it may have been tampered with: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(())
+}
@@ -502,6 +502,21 @@ pub async fn add_tap_to_bridge(bridge_name: &str, tap: &str) -> Result<(), UtilE
     Ok(())
 }
 
+/// Removes a TAP device, doing nothing if it is already gone
+pub async fn delete_tap(tap: &str) -> Result<(), UtilError> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let mut links = handle.link().get().match_name(tap.to_string()).execute();
+    match links.try_next().await {
+        Ok(Some(link)) => handle.link().del(link.header.index).execute().await?,
+        // Looking up a missing link fails with ENODEV
+        Ok(None) | Err(_) => {}
+    }
+
+    Ok(())
+}
+
 fn mount_base_image(image_path: &str) -> Result<(), UtilError> {
     log::info!("Mounting {image_path} to {PREP_MOUNT_POINT}");
     let status = Command::new("guestmount")
@@ -1,6 +1,6 @@
 #!/bin/bash
 
-./target/release/form-p2p --config ./secrets/.operator-config.json -e -p fatdog run > ./logs/form-p2p.log 2>&1 &
+./target/release/form-p2p --config ./secrets/.operator-config.json -e -p fatdog run --permissive > ./logs/form-p2p.log 2>&1 &
 sleep 1
 ./target/release/form-state -e -p fatdog -C ./secrets/.operator-config.json > ./logs/form-state.log 2>&1 &
 sleep 1